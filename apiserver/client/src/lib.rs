@@ -70,6 +70,7 @@ impl MononokeAPIClient {
     pub fn get_branches(&self) -> BoxFuture<MononokeBranches, failure_ext::Error> {
         self.inner.get_branches(&MononokeGetBranchesParams {
             repo: self.repo.clone(),
+            ..Default::default()
         })
     }
 
@@ -82,6 +83,7 @@ impl MononokeAPIClient {
             repo: self.repo.clone(),
             revision: MononokeRevision::commit_hash(revision),
             path: path.into_bytes(),
+            ..Default::default()
         })
     }
 
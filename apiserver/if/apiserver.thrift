@@ -8,6 +8,8 @@ enum MononokeAPIExceptionKind {
   NotFound = 2,
   InternalError = 3,
   BookmarkNotFound = 4,
+  ContentTombstoned = 5,
+  Forbidden = 6,
 }
 
 exception MononokeAPIException {
@@ -40,14 +42,45 @@ struct MononokeGetChangesetParams {
     3: MononokeRevision revision,
 }
 
+# Bookmarks are listed in order of their names. Clients that set neither of the optional fields
+# get all bookmarks, the others get them in pages: every page but the last gives the token to get
+# the next one with.
 struct MononokeGetBranchesParams{
   1: string repo,
+  # Not set for the first page
+  2: optional string continuation_token,
+  # At most 10000, which is also the default
+  3: optional i32 max_entries,
 }
 
 struct MononokeListDirectoryParams{
   1: string repo,
   2: MononokeRevision revision,
   3: binary path,
+  4: optional bool include_symlink_targets,
+  5: optional bool follow_symlinks,
+  6: optional bool include_submodules,
+}
+
+# Files too large for get_raw are fetched in chunks: the first chunk is at offset 0, and every
+# chunk gives the offset of the next one until the end of the file.
+struct MononokeGetRawChunkParams {
+  1: MononokeGetRawParams params,
+  2: i64 offset,
+  # At most 64MiB, which is also the default
+  3: optional i64 max_size,
+}
+
+# Directories too large for list_directory are listed in pages, in order of the names of their
+# entries: every page but the last gives the token to get the next one with. All pages list the
+# directory at the changeset the first page was listed at, even if the revision is a bookmark that
+# moved since.
+struct MononokeListDirectoryPageParams {
+  1: MononokeListDirectoryParams params,
+  # Not set for the first page
+  2: optional string continuation_token,
+  # At most 10000, which is also the default
+  3: optional i32 max_entries,
 }
 
 struct MononokeIsAncestorParams {
@@ -56,6 +89,12 @@ struct MononokeIsAncestorParams {
   3: MononokeRevision descendant,
 }
 
+struct MononokeMergeConflictsParams {
+  1: string repo,
+  2: MononokeRevision left,
+  3: MononokeRevision right,
+}
+
 struct MononokeGetBlobParams {
   1: string repo,
   2: MononokeNodeHash blob_hash,
@@ -66,6 +105,13 @@ struct MononokeGetTreeParams {
   2: MononokeTreeHash tree_hash,
 }
 
+struct MononokePathsExistParams {
+  1: string repo,
+  2: MononokeRevision revision,
+  # At most 10000 paths, the empty path being the root of the repo
+  3: list<binary> paths,
+}
+
 struct MononokeChangeset {
   1: string commit_hash,
   2: string message,
@@ -74,10 +120,16 @@ struct MononokeChangeset {
   5: list<string> parents
   6: map<string, binary> extra,
   7: MononokeTreeHash manifest,
+  // Whether the message or the author isn't UTF-8, so that its invalid bytes were replaced
+  8: bool lossy,
+  9: optional binary message_bytes,
+  10: optional binary author_bytes,
 }
 
 struct MononokeBranches {
   1: map<string, string> branches,
+  # Set if this is a page that isn't the last one
+  2: optional string continuation_token,
 }
 
 struct MononokeDirectory {
@@ -90,6 +142,49 @@ struct MononokeFile {
   3: MononokeNodeHash hash,
   4: optional i64 size,
   5: optional string content_sha1,
+  6: optional string symlink_target,
+  7: optional string submodule_commit,
+  # Hex encoded, set instead of symlink_target if the target isn't valid UTF-8
+  8: optional string symlink_target_hex,
+}
+
+struct MononokeDirectoryPage {
+  1: list<MononokeFile> files,
+  # Not set if this is the last page
+  2: optional string continuation_token,
+}
+
+struct MononokeRawChunk {
+  1: binary content,
+  # Size of the whole file
+  2: i64 file_size,
+  # Not set if this chunk ends the file
+  3: optional i64 next_offset,
+}
+
+struct MononokePathExistence {
+  1: binary path,
+  2: bool exists,
+  # The following are only set if the path exists
+  3: optional MononokeFileType file_type,
+  # Only set for files
+  4: optional i64 size,
+}
+
+struct MononokePathsExistence {
+  1: list<MononokePathExistence> paths,
+}
+
+struct MononokeMergeConflict {
+  1: binary path,
+  2: MononokeMergeConflictKind kind,
+}
+
+struct MononokeMergeConflicts {
+  # Not set if the changesets have no common ancestor
+  1: optional string merge_base,
+  # Ordered by path
+  2: list<MononokeMergeConflict> conflicts,
 }
 
 struct MononokeBlob {
@@ -101,6 +196,16 @@ enum MononokeFileType {
   TREE = 1,
   EXECUTABLE = 2,
   SYMLINK = 3,
+  SUBMODULE = 4,
+}
+
+enum MononokeMergeConflictKind {
+  # Changed differently on both sides
+  BOTH_MODIFIED = 0,
+  # Not in the merge base, added differently on both sides
+  ADD_ADD = 1,
+  # Deleted on one side, changed on the other
+  DELETE_MODIFY = 2,
 }
 
 service MononokeAPIService extends fb303.FacebookService {
@@ -124,4 +229,16 @@ service MononokeAPIService extends fb303.FacebookService {
 
   MononokeDirectory get_tree(1: MononokeGetTreeParams params)
     throws (1: MononokeAPIException e),
+
+  MononokePathsExistence paths_exist(1: MononokePathsExistParams params)
+    throws (1: MononokeAPIException e),
+
+  MononokeMergeConflicts merge_conflicts(1: MononokeMergeConflictsParams params)
+    throws (1: MononokeAPIException e),
+
+  MononokeRawChunk get_raw_chunk(1: MononokeGetRawChunkParams params)
+    throws (1: MononokeAPIException e),
+
+  MononokeDirectoryPage list_directory_page(1: MononokeListDirectoryPageParams params)
+    throws (1: MononokeAPIException e),
 }
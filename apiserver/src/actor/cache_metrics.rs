@@ -0,0 +1,145 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use stats::define_stats;
+use stats::prelude::*;
+
+use super::model::{CachePoolUsage, CacheReport, RouteCacheUsage};
+
+define_stats! {
+    prefix = "mononoke.apiserver.content_sha1_cache";
+    hit: dynamic_timeseries("{}.hit", (route: &'static str); RATE, SUM),
+    miss: dynamic_timeseries("{}.miss", (route: &'static str); RATE, SUM),
+    fill_ms: dynamic_timeseries("{}.fill_ms", (route: &'static str); AVG),
+}
+
+/// The cachelib pools of the server, as created by cmdlib. The ones that weren't created are
+/// left out of reports.
+const CACHE_POOLS: &[&str] = &[
+    "blobstore-blobs",
+    "blobstore-presence",
+    "changesets",
+    "changeset_fetcher",
+    "filenodes",
+    "bonsai_hg_mapping",
+    "content-sha1",
+];
+
+#[derive(Clone, Copy, Default)]
+struct RouteCounts {
+    hits: u64,
+    misses: u64,
+    fill_time: Duration,
+}
+
+/// Hits and misses of the content-sha1 cache by route. They're exported as stats, and also kept
+/// here since the server started so that the debug endpoint can report them along with how full
+/// the cache pools are.
+#[derive(Default)]
+pub struct CacheMetrics {
+    routes: Mutex<BTreeMap<&'static str, RouteCounts>>,
+}
+
+impl CacheMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An entry of `route` was found in the cache
+    pub fn record_hit(&self, route: &'static str) {
+        STATS::hit.add_value(1, (route,));
+        let mut routes = self.routes.lock().expect("lock poisoned");
+        routes.entry(route).or_default().hits += 1;
+    }
+
+    /// An entry of `route` wasn't in the cache, and computing it to fill the cache took
+    /// `fill_time`
+    pub fn record_miss(&self, route: &'static str, fill_time: Duration) {
+        STATS::miss.add_value(1, (route,));
+        STATS::fill_ms.add_value(duration_to_ms(fill_time) as i64, (route,));
+        let mut routes = self.routes.lock().expect("lock poisoned");
+        let counts = routes.entry(route).or_default();
+        counts.misses += 1;
+        counts.fill_time += fill_time;
+    }
+
+    pub fn report(&self) -> CacheReport {
+        CacheReport {
+            pools: pool_usage(),
+            routes: self.route_usage(),
+        }
+    }
+
+    fn route_usage(&self) -> Vec<RouteCacheUsage> {
+        let routes = self.routes.lock().expect("lock poisoned");
+        routes
+            .iter()
+            .map(|(route, counts)| {
+                // Routes are only listed once they looked something up
+                let lookups = counts.hits + counts.misses;
+                RouteCacheUsage {
+                    route: route.to_string(),
+                    hits: counts.hits,
+                    misses: counts.misses,
+                    hit_ratio: counts.hits as f64 / lookups as f64,
+                    avg_fill_ms: if counts.misses > 0 {
+                        Some(duration_to_ms(counts.fill_time) / counts.misses)
+                    } else {
+                        None
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+fn pool_usage() -> Vec<CachePoolUsage> {
+    CACHE_POOLS
+        .iter()
+        .filter_map(|name| {
+            cachelib::get_pool(name).map(|pool| CachePoolUsage {
+                name: name.to_string(),
+                size_bytes: pool.get_size() as u64,
+                used_bytes: pool.get_used_size() as u64,
+            })
+        })
+        .collect()
+}
+
+fn duration_to_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn routes_are_counted_separately() {
+        let metrics = CacheMetrics::new();
+        metrics.record_hit("get_tree");
+        metrics.record_hit("get_tree");
+        metrics.record_hit("get_tree");
+        metrics.record_miss("get_tree", Duration::from_millis(10));
+        metrics.record_miss("list", Duration::from_millis(10));
+        metrics.record_miss("list", Duration::from_millis(20));
+
+        let routes = metrics.route_usage();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].route, "get_tree");
+        assert_eq!((routes[0].hits, routes[0].misses), (3, 1));
+        assert_eq!(routes[0].hit_ratio, 0.75);
+        assert_eq!(routes[0].avg_fill_ms, Some(10));
+        assert_eq!(routes[1].route, "list");
+        assert_eq!((routes[1].hits, routes[1].misses), (0, 2));
+        assert_eq!(routes[1].hit_ratio, 0.0);
+        assert_eq!(routes[1].avg_fill_ms, Some(15));
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bookmarks::Bookmark;
+use mononoke_types::ChangesetId;
+
+/// Remembers whether commits are contained in bookmarks, both positive and negative answers.
+///
+/// Answers are only valid for the changeset the bookmark pointed to when they were computed, so
+/// all answers for a bookmark are dropped as soon as it is seen pointing somewhere else. Once
+/// `max_bookmarks` bookmarks have answers, the answers for one of them are dropped to make room
+/// for another.
+pub struct ContainsCache {
+    max_bookmarks: usize,
+    max_entries_per_bookmark: usize,
+    bookmarks: Mutex<HashMap<Bookmark, BookmarkAnswers>>,
+}
+
+struct BookmarkAnswers {
+    tip: ChangesetId,
+    answers: HashMap<ChangesetId, bool>,
+}
+
+impl ContainsCache {
+    pub fn new(max_bookmarks: usize, max_entries_per_bookmark: usize) -> Self {
+        Self {
+            max_bookmarks,
+            max_entries_per_bookmark,
+            bookmarks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `cs_id` is contained in `bookmark` pointing at `tip`, if known.
+    pub fn get(&self, bookmark: &Bookmark, tip: ChangesetId, cs_id: ChangesetId) -> Option<bool> {
+        let mut bookmarks = self.bookmarks.lock().expect("lock poisoned");
+        match bookmarks.get(bookmark) {
+            Some(entry) if entry.tip == tip => entry.answers.get(&cs_id).cloned(),
+            Some(_) => {
+                // The bookmark moved, nothing we know about it holds anymore
+                bookmarks.remove(bookmark);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, bookmark: Bookmark, tip: ChangesetId, cs_id: ChangesetId, answer: bool) {
+        let mut bookmarks = self.bookmarks.lock().expect("lock poisoned");
+        if !bookmarks.contains_key(&bookmark) && bookmarks.len() >= self.max_bookmarks {
+            let evicted = bookmarks.keys().next().cloned();
+            if let Some(evicted) = evicted {
+                bookmarks.remove(&evicted);
+            }
+        }
+        let entry = bookmarks
+            .entry(bookmark)
+            .or_insert_with(|| BookmarkAnswers {
+                tip,
+                answers: HashMap::new(),
+            });
+        if entry.tip != tip {
+            entry.tip = tip;
+            entry.answers.clear();
+        }
+        if entry.answers.len() >= self.max_entries_per_bookmark {
+            entry.answers.clear();
+        }
+        entry.answers.insert(cs_id, answer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mononoke_types_mocks::changesetid::{ONES_CSID, THREES_CSID, TWOS_CSID};
+
+    #[test]
+    fn answers_are_dropped_when_bookmark_moves() {
+        let cache = ContainsCache::new(10, 10);
+        let master = Bookmark::new("master").unwrap();
+        let release = Bookmark::new("releases/1.2").unwrap();
+
+        cache.insert(master.clone(), ONES_CSID, TWOS_CSID, true);
+        cache.insert(master.clone(), ONES_CSID, THREES_CSID, false);
+        cache.insert(release.clone(), ONES_CSID, TWOS_CSID, false);
+
+        assert_eq!(cache.get(&master, ONES_CSID, TWOS_CSID), Some(true));
+        assert_eq!(cache.get(&master, ONES_CSID, THREES_CSID), Some(false));
+        assert_eq!(cache.get(&release, ONES_CSID, TWOS_CSID), Some(false));
+
+        // master moved
+        assert_eq!(cache.get(&master, THREES_CSID, TWOS_CSID), None);
+        assert_eq!(cache.get(&master, ONES_CSID, TWOS_CSID), None);
+        // other bookmarks are unaffected
+        assert_eq!(cache.get(&release, ONES_CSID, TWOS_CSID), Some(false));
+    }
+
+    #[test]
+    fn size_is_bounded() {
+        let cache = ContainsCache::new(10, 2);
+        let master = Bookmark::new("master").unwrap();
+
+        cache.insert(master.clone(), ONES_CSID, ONES_CSID, true);
+        cache.insert(master.clone(), ONES_CSID, TWOS_CSID, true);
+        cache.insert(master.clone(), ONES_CSID, THREES_CSID, false);
+
+        assert_eq!(cache.get(&master, ONES_CSID, ONES_CSID), None);
+        assert_eq!(cache.get(&master, ONES_CSID, THREES_CSID), Some(false));
+    }
+
+    #[test]
+    fn bookmarks_are_bounded() {
+        let cache = ContainsCache::new(2, 10);
+        let bookmarks: Vec<_> = ["master", "releases/1.1", "releases/1.2"]
+            .iter()
+            .map(|name| Bookmark::new(*name).unwrap())
+            .collect();
+
+        for bookmark in &bookmarks {
+            cache.insert(bookmark.clone(), ONES_CSID, TWOS_CSID, true);
+        }
+
+        let remembered = bookmarks
+            .iter()
+            .filter(|bookmark| cache.get(bookmark, ONES_CSID, TWOS_CSID).is_some())
+            .count();
+        assert_eq!(remembered, 2);
+        assert_eq!(cache.get(&bookmarks[2], ONES_CSID, TWOS_CSID), Some(true));
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+
+use blobstore_sync_queue::{BlobstoreSyncQueue, SqlBlobstoreSyncQueue};
+use bookmarks::Bookmarks;
+use context::CoreContext;
+use dbbookmarks::SqlBookmarks;
+use failure::Error;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use hg_derivation_queue::{HgDerivationQueue, SqlHgDerivationQueue};
+use metaconfig_types::RepoType;
+use mononoke_types::{DateTime, RepositoryId};
+use mutable_counters::{MutableCounters, SqlMutableCounters};
+use push_usage::{PushUsageStore, SqlConstructors, SqlPushUsageStore};
+
+use super::model::{Backlog, RepoHealth};
+
+/// Counter of the last bookmark move that the hg sync job replayed to hg
+const LATEST_REPLAYED_REQUEST_KEY: &str = "latest-replayed-request";
+
+/// The stores that operational signals of a repo are read from, for dashboards to get them all
+/// from one place: when the repo was last pushed to, and how far behind the jobs that process
+/// its pushes are. Each job works from a queue, or a log and a counter of how much of it was
+/// done, so how far behind it is is how old the oldest work it hasn't done yet is.
+pub struct RepoHealthStores {
+    repo_id: RepositoryId,
+    push_usage: Arc<PushUsageStore>,
+    bookmarks: Arc<Bookmarks>,
+    mutable_counters: Arc<MutableCounters>,
+    hg_derivation_queue: Arc<HgDerivationQueue>,
+    /// Only repos with remote blobstores write blobs through a sync queue
+    blobstore_sync_queue: Option<Arc<BlobstoreSyncQueue>>,
+}
+
+impl RepoHealthStores {
+    pub fn open(
+        repo_id: RepositoryId,
+        repotype: &RepoType,
+        myrouter_port: Option<u16>,
+    ) -> Result<Self, Error> {
+        let blobstore_sync_queue: Option<Arc<BlobstoreSyncQueue>> = match repotype {
+            RepoType::BlobRemote { .. } => Some(Arc::new(SqlBlobstoreSyncQueue::with_repo_type(
+                repotype,
+                myrouter_port,
+                "blobstore_sync_queue",
+            )?)),
+            _ => None,
+        };
+        Ok(Self {
+            repo_id,
+            push_usage: Arc::new(SqlPushUsageStore::with_repo_type(
+                repotype,
+                myrouter_port,
+                "push_usage",
+            )?),
+            bookmarks: Arc::new(SqlBookmarks::with_repo_type(
+                repotype,
+                myrouter_port,
+                "books",
+            )?),
+            mutable_counters: Arc::new(SqlMutableCounters::with_repo_type(
+                repotype,
+                myrouter_port,
+                "mutable_counters",
+            )?),
+            hg_derivation_queue: Arc::new(SqlHgDerivationQueue::with_repo_type(
+                repotype,
+                myrouter_port,
+                "hg_derivation_queue",
+            )?),
+            blobstore_sync_queue,
+        })
+    }
+
+    pub fn report(&self, ctx: CoreContext) -> BoxFuture<RepoHealth, Error> {
+        let now = DateTime::new(ctx.now());
+        let blobstore_sync = match self.blobstore_sync_queue {
+            Some(ref queue) => queue
+                .get_backlog(ctx.clone(), self.repo_id)
+                .map(Some)
+                .left_future(),
+            None => future::ok(None).right_future(),
+        };
+
+        self.push_usage
+            .get_last_push(ctx.clone(), self.repo_id)
+            .join4(
+                self.hg_sync_backlog(ctx.clone()),
+                self.hg_derivation_queue.get_backlog(ctx, self.repo_id),
+                blobstore_sync,
+            )
+            .map(
+                move |(last_push, hg_sync, hg_derivation, blobstore_sync)| RepoHealth {
+                    last_push: last_push.map(DateTime::into_chrono),
+                    hg_sync: hg_sync.map(|backlog| backlog_at(now, backlog)),
+                    hg_derivation: backlog_at(now, hg_derivation),
+                    blobstore_sync: blobstore_sync.map(|backlog| backlog_at(now, backlog)),
+                },
+            )
+            .boxify()
+    }
+
+    /// Bookmark moves that the hg sync job hasn't replayed to hg yet, if it runs for the repo.
+    /// While repos are migrated, hg is their source of truth, and its bookmarks are stale by
+    /// these moves.
+    fn hg_sync_backlog(
+        &self,
+        ctx: CoreContext,
+    ) -> BoxFuture<Option<(u64, Option<DateTime>)>, Error> {
+        let repo_id = self.repo_id;
+        let bookmarks = self.bookmarks.clone();
+        self.mutable_counters
+            .get_counter(ctx.clone(), repo_id, LATEST_REPLAYED_REQUEST_KEY)
+            .and_then(move |maybe_counter| match maybe_counter {
+                Some(counter) => {
+                    let replayed = counter as u64;
+                    bookmarks
+                        .count_further_bookmark_log_entries(ctx.clone(), replayed, repo_id)
+                        .join(bookmarks.read_next_bookmark_log_entry(ctx, replayed, repo_id))
+                        .map(|(entries, oldest)| {
+                            Some((entries, oldest.map(|entry| entry.timestamp.into())))
+                        })
+                        .left_future()
+                }
+                None => future::ok(None).right_future(),
+            })
+            .boxify()
+    }
+}
+
+fn backlog_at(now: DateTime, (entries, oldest): (u64, Option<DateTime>)) -> Backlog {
+    let lag_secs = oldest.as_ref().map_or(0, |oldest| {
+        (now.timestamp_secs() - oldest.timestamp_secs()).max(0)
+    });
+    Backlog::new(entries, oldest.map(DateTime::into_chrono), lag_secs)
+}
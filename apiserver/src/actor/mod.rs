@@ -5,30 +5,44 @@
 // GNU General Public License version 2 or any later version.
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use context::CoreContext;
+use cloned::cloned;
+use context::{CoreContext, TimeUuidProvider};
 use failure::Error;
 use futures::{future::join_all, Future, IntoFuture};
 use futures_ext::{BoxFuture, FutureExt};
 use slog::Logger;
 
+use mercurial_types::TextDecoding;
 use metaconfig_parser::RepoConfigs;
 
 use crate::errors::ErrorKind;
 
+use self::model::CacheReport;
+
+mod cache_metrics;
+mod contains_cache;
+mod health;
 mod lfs;
-mod model;
+pub(crate) mod model;
+mod paging;
 mod query;
 mod repo;
+mod repo_view;
 mod response;
 
+pub use self::cache_metrics::CacheMetrics;
 pub use self::lfs::BatchRequest;
-pub use self::query::{MononokeQuery, MononokeRepoQuery, Revision};
+pub use self::paging::PageRequest;
+pub use self::query::{ListDirectoryOptions, MononokeQuery, MononokeRepoQuery, Revision};
 pub use self::repo::MononokeRepo;
 pub use self::response::MononokeRepoResponse;
 
 pub struct Mononoke {
     repos: HashMap<String, MononokeRepo>,
+    cache_metrics: Arc<CacheMetrics>,
+    time_uuid: Arc<TimeUuidProvider>,
 }
 
 impl Mononoke {
@@ -37,31 +51,65 @@ impl Mononoke {
         config: RepoConfigs,
         myrouter_port: Option<u16>,
         with_skiplist: bool,
+        text_decoding: TextDecoding,
+        time_uuid: Arc<TimeUuidProvider>,
     ) -> impl Future<Item = Self, Error = Error> {
+        let cache_metrics = Arc::new(CacheMetrics::new());
         join_all(
             config
                 .repos
                 .into_iter()
                 .filter(move |&(_, ref config)| config.enabled)
                 .map({
+                    cloned!(cache_metrics, time_uuid);
                     move |(name, config)| {
-                        MononokeRepo::new(logger.clone(), config, myrouter_port, with_skiplist)
-                            .map(|repo| (name, repo))
+                        MononokeRepo::new(
+                            logger.clone(),
+                            config,
+                            myrouter_port,
+                            with_skiplist,
+                            text_decoding,
+                            cache_metrics.clone(),
+                            time_uuid.clone(),
+                        )
+                        .map(|repo| (name, repo))
                     }
                 }),
         )
         .map(move |repos| Self {
             repos: repos.into_iter().collect(),
+            cache_metrics,
+            time_uuid,
         })
     }
 
+    /// Where the contexts of requests get the current time and new uuids from
+    pub fn time_uuid(&self) -> Arc<TimeUuidProvider> {
+        self.time_uuid.clone()
+    }
+
+    /// How full the cache pools are and how well they serve each route
+    pub fn cache_report(&self) -> CacheReport {
+        self.cache_metrics.report()
+    }
+
     pub fn send_query(
         &self,
         ctx: CoreContext,
+        query: MononokeQuery,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        self.send_query_with_qos(ctx, None, query)
+    }
+
+    /// Send a query that declared the class `qos`
+    pub fn send_query_with_qos(
+        &self,
+        ctx: CoreContext,
+        qos: Option<&str>,
         MononokeQuery { repo, kind, .. }: MononokeQuery,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
         match self.repos.get(&repo) {
-            Some(repo) => repo.send_query(ctx, kind),
+            Some(repo) => repo.send_query_with_qos(ctx, qos, kind),
             None => match kind {
                 MononokeRepoQuery::LfsBatch { .. } => {
                     // LFS batch request require error in the different format:
@@ -9,7 +9,9 @@
 use std::{
     collections::BTreeMap,
     convert::{Into, TryFrom},
-    str,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+    time::Instant,
 };
 
 use abomonation_derive::Abomonation;
@@ -18,17 +20,29 @@ use failure::{err_msg, Error};
 use serde_derive::Serialize;
 
 use apiserver_thrift::types::{
-    MononokeChangeset, MononokeFile, MononokeFileType, MononokeNodeHash, MononokeTreeHash,
+    MononokeChangeset, MononokeFile, MononokeFileType, MononokeMergeConflict,
+    MononokeMergeConflictKind, MononokeMergeConflicts, MononokeNodeHash, MononokePathExistence,
+    MononokeTreeHash,
 };
 use blobrepo::HgBlobChangeset;
 use cachelib::{get_cached_or_fill, LruCachePool};
+use cloned::cloned;
 use context::CoreContext;
 use futures::prelude::*;
 use futures_ext::{spawn_future, try_boxfuture, BoxFuture, FutureExt};
 use mercurial_types::hash::Sha1;
 use mercurial_types::manifest::Content;
-use mercurial_types::{Changeset as HgChangeset, Entry as HgEntry, Type};
-use mononoke_types::RepositoryId;
+use mercurial_types::{Changeset as HgChangeset, Entry as HgEntry, TextDecoding, Type};
+use mononoke_api::exists::PathEntry;
+use mononoke_api::merge_conflicts::{ConflictKind, FileConflict};
+use mononoke_api::sizes::PathSummary;
+use mononoke_types::{ContentId, RepositoryId};
+use phases::Phase;
+use push_usage::{IdentityUsage, TeamUsage, UsageReport};
+use repo_maintenance::MaintenanceWindow;
+use sql_replicas::ReplicaStatus;
+
+use super::cache_metrics::CacheMetrics;
 
 #[derive(Abomonation, Clone, Serialize)]
 pub enum FileType {
@@ -40,6 +54,8 @@ pub enum FileType {
     Executable,
     #[serde(rename = "symlink")]
     Symlink,
+    #[serde(rename = "submodule")]
+    Submodule,
 }
 
 impl From<Type> for FileType {
@@ -64,6 +80,7 @@ impl From<FileType> for MononokeFileType {
             FileType::Tree => MononokeFileType::TREE,
             FileType::Executable => MononokeFileType::EXECUTABLE,
             FileType::Symlink => MononokeFileType::SYMLINK,
+            FileType::Submodule => MononokeFileType::SUBMODULE,
         }
     }
 }
@@ -73,6 +90,9 @@ impl From<Entry> for MononokeFile {
         Self {
             name: entry.name,
             file_type: entry.ttype.into(),
+            symlink_target: entry.symlink_target,
+            symlink_target_hex: entry.symlink_target_hex,
+            submodule_commit: entry.submodule_commit,
             ..Default::default()
         }
     }
@@ -84,6 +104,46 @@ pub struct Entry {
     #[serde(rename = "type")]
     ttype: FileType,
     hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    symlink_target: Option<String>,
+    /// Set instead of `symlink_target` if the target isn't valid UTF-8, which JSON can't carry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    symlink_target_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submodule_commit: Option<String>,
+}
+
+impl Entry {
+    /// Submodules are not part of Mercurial manifests, so they have no hash of their own. The
+    /// commit they point to is reported instead.
+    pub fn submodule(name: String, commit: String) -> Self {
+        Entry {
+            name,
+            ttype: FileType::Submodule,
+            hash: String::new(),
+            symlink_target: None,
+            symlink_target_hex: None,
+            submodule_commit: Some(commit),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Targets that aren't valid UTF-8 are hex encoded, so that they aren't mangled.
+    pub fn with_symlink_target(self, target: &[u8]) -> Self {
+        match String::from_utf8(target.to_vec()) {
+            Ok(target) => Entry {
+                symlink_target: Some(target),
+                ..self
+            },
+            Err(_) => Entry {
+                symlink_target_hex: Some(target.iter().map(|b| format!("{:02x}", b)).collect()),
+                ..self
+            },
+        }
+    }
 }
 
 impl TryFrom<Box<dyn HgEntry + Sync>> for Entry {
@@ -98,7 +158,14 @@ impl TryFrom<Box<dyn HgEntry + Sync>> for Entry {
         let ttype = entry.get_type().into();
         let hash = entry.get_hash().to_string();
 
-        Ok(Entry { name, ttype, hash })
+        Ok(Entry {
+            name,
+            ttype,
+            hash,
+            symlink_target: None,
+            symlink_target_hex: None,
+            submodule_commit: None,
+        })
     }
 }
 
@@ -117,11 +184,15 @@ impl EntryWithSizeAndContentHash {
         format!("{}:{}", repoid.prefix(), hash)
     }
 
+    /// The entry, from the content-sha1 `cache` if there's one. Whether it was found there is
+    /// recorded in `metrics` under `route`.
     pub fn materialize_future(
         ctx: CoreContext,
         repoid: RepositoryId,
         entry: Box<dyn HgEntry + Sync>,
         cache: Option<LruCachePool>,
+        metrics: Arc<CacheMetrics>,
+        route: &'static str,
     ) -> BoxFuture<Self, Error> {
         let name = try_boxfuture!(entry
             .get_name()
@@ -134,42 +205,59 @@ impl EntryWithSizeAndContentHash {
 
         let cache_key = Self::get_cache_key(repoid, hash.as_str());
 
-        // this future computes SHA1 based on content
-        let future = spawn_future(entry.get_content(ctx).and_then({
+        // this future computes SHA1 based on content. It's only started if the entry isn't
+        // cached, so that hits don't fetch any content.
+        let compute = {
             let hash = hash.clone();
-            move |content| {
-                let size = match &content {
-                    Content::File(contents)
-                    | Content::Executable(contents)
-                    | Content::Symlink(contents) => Some(contents.size()),
-                    Content::Tree(manifest) => Some(manifest.list().count()),
-                };
-                Ok(EntryWithSizeAndContentHash {
-                    name,
-                    ttype,
-                    hash: hash.to_string(),
-                    size,
-                    content_sha1: match content {
+            move || {
+                spawn_future(entry.get_content(ctx).and_then(move |content| {
+                    let size = match &content {
                         Content::File(contents)
                         | Content::Executable(contents)
-                        | Content::Symlink(contents) => {
-                            let sha1 = Sha1::from(contents.as_bytes().as_ref());
-                            Some(sha1.to_hex().to_string())
-                        }
-                        Content::Tree(_) => None,
-                    },
-                })
+                        | Content::Symlink(contents) => Some(contents.size()),
+                        Content::Tree(manifest) => Some(manifest.list().count()),
+                    };
+                    Ok(EntryWithSizeAndContentHash {
+                        name,
+                        ttype,
+                        hash: hash.to_string(),
+                        size,
+                        content_sha1: match content {
+                            Content::File(contents)
+                            | Content::Executable(contents)
+                            | Content::Symlink(contents) => {
+                                let sha1 = Sha1::from(contents.as_bytes().as_ref());
+                                Some(sha1.to_hex().to_string())
+                            }
+                            Content::Tree(_) => None,
+                        },
+                    })
+                }))
             }
-        }));
+        };
 
         if let Some(cache) = cache {
-            get_cached_or_fill(&cache, cache_key, || {
-                future.map(|entry| Some(entry)).boxify()
+            let missed = Arc::new(AtomicBool::new(false));
+            get_cached_or_fill(&cache, cache_key, {
+                cloned!(metrics, missed);
+                move || {
+                    missed.store(true, Ordering::Relaxed);
+                    let start = Instant::now();
+                    compute().map(move |entry| {
+                        metrics.record_miss(route, start.elapsed());
+                        Some(entry)
+                    })
+                }
+            })
+            .and_then(move |entry| {
+                if !missed.load(Ordering::Relaxed) {
+                    metrics.record_hit(route);
+                }
+                entry.ok_or(err_msg(format!("Entry {} not found", hash)))
             })
-            .and_then(move |entry| entry.ok_or(err_msg(format!("Entry {} not found", hash))))
             .boxify()
         } else {
-            future.boxify()
+            compute().boxify()
         }
     }
 }
@@ -182,10 +270,427 @@ impl From<EntryWithSizeAndContentHash> for MononokeFile {
             hash: MononokeNodeHash { hash: entry.hash },
             size: entry.size.map(|size| size as i64),
             content_sha1: entry.content_sha1,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CachePoolUsage {
+    pub name: String,
+    pub size_bytes: u64,
+    pub used_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct RouteCacheUsage {
+    pub route: String,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: f64,
+    /// How long computing an entry that wasn't cached took on average, None if none was missed
+    pub avg_fill_ms: Option<u64>,
+}
+
+/// How full the cache pools are, and how well the content-sha1 cache serves each route since the
+/// server started
+#[derive(Serialize)]
+pub struct CacheReport {
+    pub pools: Vec<CachePoolUsage>,
+    pub routes: Vec<RouteCacheUsage>,
+}
+
+#[derive(Serialize)]
+pub struct Maintenance {
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+    message: String,
+}
+
+impl From<MaintenanceWindow> for Maintenance {
+    fn from(window: MaintenanceWindow) -> Self {
+        Self {
+            start: window.start.into_chrono(),
+            end: window.end.into_chrono(),
+            message: window.message,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RepoStatus {
+    /// The maintenance window the repo is in, if any. Writes are refused during maintenance.
+    pub maintenance: Option<Maintenance>,
+    /// The tunables of the repo that are overridden, by name, as this server last polled them
+    pub overrides: BTreeMap<String, String>,
+}
+
+/// Work that a job of a repo hasn't done yet
+#[derive(Serialize)]
+pub struct Backlog {
+    entries: u64,
+    /// When the oldest entry was added
+    oldest: Option<DateTime<FixedOffset>>,
+    /// How many seconds ago the oldest entry was added, 0 if there are no entries
+    lag_secs: i64,
+}
+
+impl Backlog {
+    pub fn new(entries: u64, oldest: Option<DateTime<FixedOffset>>, lag_secs: i64) -> Self {
+        Self {
+            entries,
+            oldest,
+            lag_secs,
+        }
+    }
+}
+
+/// Operational signals of a repo, for dashboards
+#[derive(Serialize)]
+pub struct RepoHealth {
+    pub last_push: Option<DateTime<FixedOffset>>,
+    /// Bookmark moves not replayed to hg yet, if the repo is synced to hg
+    pub hg_sync: Option<Backlog>,
+    /// Changesets whose hg changesets and filenodes weren't derived yet
+    pub hg_derivation: Backlog,
+    /// Blobs not written to all blobstores yet, if the repo has remote blobstores. Writes that
+    /// failed on some blobstores stay here until the healer fixes them.
+    pub blobstore_sync: Option<Backlog>,
+}
+
+/// One replica of a SQL store of a repo, as seen by this server
+#[derive(Serialize)]
+pub struct Replica {
+    address: String,
+    primary: bool,
+    healthy: bool,
+    consecutive_failures: usize,
+    last_error: Option<String>,
+}
+
+impl From<ReplicaStatus> for Replica {
+    fn from(status: ReplicaStatus) -> Self {
+        Self {
+            address: status.address,
+            primary: status.primary,
+            healthy: status.healthy,
+            consecutive_failures: status.consecutive_failures,
+            last_error: status.last_error,
+        }
+    }
+}
+
+/// What one user pushed to a repo over the push quota window
+#[derive(Serialize)]
+pub struct IdentityPushUsage {
+    identity: String,
+    bytes: u64,
+    files: u64,
+    pushes: u64,
+}
+
+impl From<IdentityUsage> for IdentityPushUsage {
+    fn from(usage: IdentityUsage) -> Self {
+        Self {
+            identity: usage.identity,
+            bytes: usage.bytes,
+            files: usage.files,
+            pushes: usage.pushes,
+        }
+    }
+}
+
+/// What one team pushed to a repo over the push quota window, and its limits
+#[derive(Serialize)]
+pub struct TeamPushUsage {
+    name: String,
+    bytes: u64,
+    files: u64,
+    pushes: u64,
+    soft_limit_bytes: Option<u64>,
+    hard_limit_bytes: Option<u64>,
+}
+
+impl From<TeamUsage> for TeamPushUsage {
+    fn from(usage: TeamUsage) -> Self {
+        Self {
+            name: usage.name,
+            bytes: usage.bytes,
+            files: usage.files,
+            pushes: usage.pushes,
+            soft_limit_bytes: usage.limits.soft_limit_bytes,
+            hard_limit_bytes: usage.limits.hard_limit_bytes,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PushUsageReport {
+    window_days: u64,
+    /// Users, the ones who pushed the most bytes first
+    identities: Vec<IdentityPushUsage>,
+    teams: Vec<TeamPushUsage>,
+}
+
+impl From<UsageReport> for PushUsageReport {
+    fn from(report: UsageReport) -> Self {
+        Self {
+            window_days: report.window_days,
+            identities: report.identities.into_iter().map(From::from).collect(),
+            teams: report.teams.into_iter().map(From::from).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PathSize {
+    path: String,
+    total_size: u64,
+    file_count: u64,
+    dir_count: u64,
+}
+
+impl PathSize {
+    pub fn new(path: String, summary: PathSummary) -> Self {
+        Self {
+            path,
+            total_size: summary.total_size,
+            file_count: summary.file_count,
+            dir_count: summary.dir_count,
+        }
+    }
+}
+
+/// One file of a multiget response. Entries are sent in the order they were requested.
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum MultiGetEntry {
+    Ok {
+        /// The path or content id the file was requested by
+        key: String,
+        content_id: String,
+        size: u64,
+        /// Only known for files requested by path
+        #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+        ttype: Option<FileType>,
+        #[serde(with = "serde_bytes")]
+        content: Vec<u8>,
+    },
+    Error {
+        key: String,
+        message: String,
+    },
+    /// The file was not sent because the response reached its byte budget. All files after it
+    /// are skipped as well, so that clients can ask for the rest starting from this one.
+    OverBudget {
+        key: String,
+    },
+}
+
+impl MultiGetEntry {
+    pub fn file(key: String, content_id: ContentId, ttype: Option<Type>, content: &[u8]) -> Self {
+        MultiGetEntry::Ok {
+            key,
+            content_id: content_id.to_string(),
+            size: content.len() as u64,
+            ttype: ttype.map(FileType::from),
+            content: content.to_vec(),
+        }
+    }
+
+    pub fn error(key: String, error: impl ToString) -> Self {
+        MultiGetEntry::Error {
+            key,
+            message: error.to_string(),
+        }
+    }
+
+    pub fn over_budget(key: String) -> Self {
+        MultiGetEntry::OverBudget { key }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PathExistence {
+    path: String,
+    exists: bool,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    ttype: Option<FileType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+impl PathExistence {
+    pub fn new(path: String, entry: Option<PathEntry>) -> Self {
+        Self {
+            path,
+            exists: entry.is_some(),
+            ttype: entry.as_ref().map(|entry| entry.ttype.into()),
+            size: entry.and_then(|entry| entry.size),
+        }
+    }
+}
+
+impl From<PathExistence> for MononokePathExistence {
+    fn from(existence: PathExistence) -> Self {
+        Self {
+            path: existence.path.into_bytes(),
+            exists: existence.exists,
+            file_type: existence.ttype.map(|ttype| ttype.into()),
+            size: existence.size.map(|size| size as i64),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Ancestry {
+    ancestor: String,
+    descendant: String,
+    is_ancestor: bool,
+}
+
+impl Ancestry {
+    pub fn new(ancestor: String, descendant: String, is_ancestor: bool) -> Self {
+        Self {
+            ancestor,
+            descendant,
+            is_ancestor,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CommonAncestor {
+    left: String,
+    right: String,
+    /// None if the changesets have no common ancestor
+    ancestor: Option<String>,
+}
+
+impl CommonAncestor {
+    pub fn new(left: String, right: String, ancestor: Option<String>) -> Self {
+        Self {
+            left,
+            right,
+            ancestor,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub enum MergeConflictKind {
+    #[serde(rename = "both_modified")]
+    BothModified,
+    #[serde(rename = "add_add")]
+    AddAdd,
+    #[serde(rename = "delete_modify")]
+    DeleteModify,
+}
+
+impl From<ConflictKind> for MergeConflictKind {
+    fn from(kind: ConflictKind) -> Self {
+        match kind {
+            ConflictKind::BothModified => MergeConflictKind::BothModified,
+            ConflictKind::AddAdd => MergeConflictKind::AddAdd,
+            ConflictKind::DeleteModify => MergeConflictKind::DeleteModify,
+        }
+    }
+}
+
+impl From<MergeConflictKind> for MononokeMergeConflictKind {
+    fn from(kind: MergeConflictKind) -> Self {
+        match kind {
+            MergeConflictKind::BothModified => MononokeMergeConflictKind::BOTH_MODIFIED,
+            MergeConflictKind::AddAdd => MononokeMergeConflictKind::ADD_ADD,
+            MergeConflictKind::DeleteModify => MononokeMergeConflictKind::DELETE_MODIFY,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MergeConflict {
+    path: String,
+    kind: MergeConflictKind,
+}
+
+impl From<FileConflict> for MergeConflict {
+    fn from(conflict: FileConflict) -> Self {
+        Self {
+            path: conflict.path.to_string(),
+            kind: conflict.kind.into(),
         }
     }
 }
 
+#[derive(Serialize)]
+pub struct MergeConflictReport {
+    /// Mercurial hash of the merge base, None if the changesets have no common ancestor
+    pub merge_base: Option<String>,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl From<MergeConflictReport> for MononokeMergeConflicts {
+    fn from(report: MergeConflictReport) -> Self {
+        Self {
+            merge_base: report.merge_base,
+            conflicts: report
+                .conflicts
+                .into_iter()
+                .map(|conflict| MononokeMergeConflict {
+                    path: conflict.path.into_bytes(),
+                    kind: conflict.kind.into(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphPhase {
+    Public,
+    Draft,
+}
+
+impl From<Phase> for GraphPhase {
+    fn from(phase: Phase) -> Self {
+        match phase {
+            Phase::Public => GraphPhase::Public,
+            Phase::Draft => GraphPhase::Draft,
+        }
+    }
+}
+
+/// A changeset of the commit graph, with what UIs label it with
+#[derive(Serialize)]
+pub struct GraphNode {
+    /// Mercurial hash of the changeset
+    pub hash: String,
+    pub parents: Vec<String>,
+    pub generation: u64,
+    pub bookmarks: Vec<String>,
+    pub phase: GraphPhase,
+}
+
+/// What the SQL stores of a repo have about a changeset, for the servers that pull through from
+/// the repo
+#[derive(Serialize)]
+pub struct RawChangeset {
+    /// Mercurial hash of the changeset
+    pub hg: String,
+    /// Bonsai ids of the parents
+    pub parents: Vec<String>,
+    pub generation: u64,
+}
+
+/// A changeset of a range of the commit graph
+#[derive(Serialize)]
+pub struct RangeChangeset {
+    /// Mercurial hash of the changeset
+    pub hash: String,
+    pub generation: u64,
+}
+
 #[derive(Serialize)]
 pub struct Changeset {
     commit_hash: String,
@@ -195,17 +700,24 @@ pub struct Changeset {
     author: String,
     parents: Vec<String>,
     extra: BTreeMap<String, Vec<u8>>,
+    /// Whether the comment or the author isn't UTF-8, so that it was decoded lossily
+    lossy: bool,
+    /// The bytes of the comment, if it isn't UTF-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment_bytes: Option<Vec<u8>>,
+    /// The bytes of the author, if it isn't UTF-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author_bytes: Option<Vec<u8>>,
 }
 
-impl TryFrom<HgBlobChangeset> for Changeset {
-    type Error = str::Utf8Error;
-
-    fn try_from(changeset: HgBlobChangeset) -> Result<Changeset, Self::Error> {
+impl Changeset {
+    /// The model of `changeset`, whose comment and author are decoded with `decoding`
+    pub fn new(changeset: HgBlobChangeset, decoding: TextDecoding) -> Result<Changeset, Error> {
         let commit_hash = changeset.get_changeset_id().to_hex().to_string();
         let manifest = changeset.manifestid().to_string();
-        let comment = str::from_utf8(changeset.comments())?.to_string();
+        let comment = changeset.comments_text(decoding)?;
         let date = changeset.time().into_chrono();
-        let author = str::from_utf8(changeset.user())?.to_string();
+        let author = changeset.user_text(decoding)?;
         let parents: Vec<_> = vec![changeset.p1(), changeset.p2()]
             .into_iter()
             .flat_map(|p| p.map(|p| p.to_hex().to_string()))
@@ -220,11 +732,14 @@ impl TryFrom<HgBlobChangeset> for Changeset {
         Ok(Changeset {
             commit_hash,
             manifest,
-            comment,
+            comment: comment.text,
             date,
-            author,
+            author: author.text,
             parents,
             extra,
+            lossy: comment.raw.is_some() || author.raw.is_some(),
+            comment_bytes: comment.raw,
+            author_bytes: author.raw,
         })
     }
 }
@@ -241,6 +756,9 @@ impl From<Changeset> for MononokeChangeset {
             manifest: MononokeTreeHash {
                 hash: changeset.manifest,
             },
+            lossy: changeset.lossy,
+            message_bytes: changeset.comment_bytes,
+            author_bytes: changeset.author_bytes,
         }
     }
 }
@@ -0,0 +1,247 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Pages of listings too large for one response: directories, bookmarks and file histories.
+//
+// Directories and bookmarks are sorted by a key that is unique within them, their names, and the
+// page that doesn't end the listing comes with a token to get the next one with. The
+// token records the key of the last entry of its page, so the next page starts right after it
+// even if entries were added or removed in between: entries that exist on both requests are
+// listed exactly once. When the listing was made from something immutable, the token records it
+// too, and later pages are listed from it: the pages of a directory listed at a bookmark keep
+// listing the changeset of the first page after the bookmark moves.
+//
+// File histories are walked rather than sorted: the position of their tokens is where the walk
+// stopped, and their snapshot the filenode the history is of.
+//
+// Tokens are opaque to clients, which only send them back as they were received.
+
+use std::cmp;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::errors::ErrorKind;
+
+/// The most entries a page carries, whatever the client asks for
+pub const MAX_PAGE_ENTRIES: usize = 10000;
+
+/// Version of the format of tokens, so that tokens of older servers can be told apart
+const TOKEN_VERSION: &str = "1";
+
+/// The page of a listing to return
+#[derive(Debug, Clone, Default)]
+pub struct PageRequest {
+    /// Token of the previous page, not set for the first page
+    pub token: Option<String>,
+    /// Most entries of the page, `MAX_PAGE_ENTRIES` if not set
+    pub limit: Option<usize>,
+}
+
+impl PageRequest {
+    /// The most entries of the page, if the client asked for a valid number of them
+    pub fn limit(&self) -> Result<usize, ErrorKind> {
+        match self.limit {
+            Some(0) => Err(ErrorKind::InvalidInput(
+                "pages must have at least 1 entry, not 0".to_string(),
+                None,
+            )),
+            Some(limit) => Ok(cmp::min(limit, MAX_PAGE_ENTRIES)),
+            None => Ok(MAX_PAGE_ENTRIES),
+        }
+    }
+
+    /// The token of the previous page, checked to be a token
+    pub fn token(&self) -> Result<Option<PageToken>, ErrorKind> {
+        self.token.as_ref().map(|token| token.parse()).transpose()
+    }
+}
+
+/// Where the next page of a listing starts
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PageToken {
+    /// What the listing was made from, if the following pages must be listed from it too
+    pub snapshot: Option<String>,
+    /// Key of the last entry of the page
+    pub position: String,
+}
+
+impl PageToken {
+    pub fn new(snapshot: Option<String>, position: String) -> Self {
+        Self { snapshot, position }
+    }
+}
+
+impl fmt::Display for PageToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let token = format!(
+            "{}\n{}\n{}",
+            TOKEN_VERSION,
+            self.snapshot.as_ref().map_or("", String::as_str),
+            self.position
+        );
+        for byte in token.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PageToken {
+    type Err = ErrorKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ErrorKind::InvalidInput(format!("page token {}", s), None);
+
+        if s.len() % 2 != 0 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let token = String::from_utf8(bytes).map_err(|_| invalid())?;
+
+        let mut parts = token.splitn(3, '\n');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(TOKEN_VERSION), Some(snapshot), Some(position)) => Ok(Self {
+                snapshot: if snapshot.is_empty() {
+                    None
+                } else {
+                    Some(snapshot.to_string())
+                },
+                position: position.to_string(),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The page of `entries`, sorted by `key`, that follows the entry whose key is `after`, and the
+/// key of its last entry if more entries follow it
+pub fn page<T, K>(
+    entries: impl IntoIterator<Item = T>,
+    key: K,
+    after: Option<&str>,
+    limit: usize,
+) -> (Vec<T>, Option<String>)
+where
+    K: Fn(&T) -> &str,
+{
+    let mut entries: Vec<_> = entries
+        .into_iter()
+        .skip_while(|entry| match after {
+            Some(after) => key(entry) <= after,
+            None => false,
+        })
+        .take(limit + 1)
+        .collect();
+    if entries.len() > limit {
+        entries.truncate(limit);
+        let last = entries.last().map(|entry| key(entry).to_string());
+        (entries, last)
+    } else {
+        (entries, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn page_of<'a>(
+        entries: &[&'a str],
+        token: Option<&str>,
+        limit: usize,
+    ) -> (Vec<&'a str>, Option<String>) {
+        let token = token.map(|token| token.parse::<PageToken>().unwrap());
+        let after = token.as_ref().map(|token| token.position.as_str());
+        let (entries, last) = page(entries.iter().cloned(), |entry| *entry, after, limit);
+        let token = last.map(|last| PageToken::new(None, last).to_string());
+        (entries, token)
+    }
+
+    #[test]
+    fn tokens() {
+        for token in vec![
+            PageToken::new(None, "".to_string()),
+            PageToken::new(None, "master".to_string()),
+            PageToken::new(Some("abcd".to_string()), "dir\nwith newline".to_string()),
+            PageToken::new(Some("abcd".to_string()), "ünïcode".to_string()),
+        ] {
+            let encoded = token.to_string();
+            assert_eq!(encoded.parse::<PageToken>().unwrap(), token);
+            // The same position of the same snapshot is always the same token
+            assert_eq!(encoded, token.clone().to_string());
+        }
+
+        assert!("".parse::<PageToken>().is_err());
+        assert!("master".parse::<PageToken>().is_err());
+        assert!("abc".parse::<PageToken>().is_err());
+        // Valid hex, but not of a token
+        assert!("6d6173746572".parse::<PageToken>().is_err());
+        // A token of an unknown version
+        assert!("320a0a61".parse::<PageToken>().is_err());
+    }
+
+    #[test]
+    fn pages() {
+        let all = ["a", "b", "c", "d", "e"];
+
+        let (entries, token) = page_of(&all, None, 2);
+        assert_eq!(entries, vec!["a", "b"]);
+        let (entries, token) = page_of(&all, token.as_ref().map(String::as_str), 2);
+        assert_eq!(entries, vec!["c", "d"]);
+        let (entries, token) = page_of(&all, token.as_ref().map(String::as_str), 2);
+        assert_eq!(entries, vec!["e"]);
+        assert_eq!(token, None);
+
+        // A page that exactly ends the listing is the last one
+        let (entries, token) = page_of(&all, None, 5);
+        assert_eq!(entries.len(), 5);
+        assert_eq!(token, None);
+    }
+
+    #[test]
+    fn pages_of_changing_listings() {
+        let (entries, token) = page_of(&["a", "b", "c", "d"], None, 2);
+        assert_eq!(entries, vec!["a", "b"]);
+        let token = token.unwrap();
+
+        // The last entry of the page was removed, and entries were added before and after it:
+        // the next page starts after it all the same, without repeating or skipping entries
+        let (entries, next) = page_of(&["a", "aa", "ba", "c", "d"], Some(&token), 2);
+        assert_eq!(entries, vec!["ba", "c"]);
+
+        // Pages of the same listing are always the same
+        assert_eq!(
+            page_of(&["a", "aa", "ba", "c", "d"], Some(&token), 2).1,
+            next
+        );
+    }
+
+    #[test]
+    fn page_requests() {
+        let request = PageRequest {
+            token: None,
+            limit: Some(0),
+        };
+        assert!(request.limit().is_err());
+
+        let request = PageRequest {
+            token: None,
+            limit: Some(MAX_PAGE_ENTRIES + 1),
+        };
+        assert_eq!(request.limit().unwrap(), MAX_PAGE_ENTRIES);
+        assert_eq!(PageRequest::default().limit().unwrap(), MAX_PAGE_ENTRIES);
+
+        let request = PageRequest {
+            token: Some("master".to_string()),
+            limit: None,
+        };
+        assert!(request.token().is_err());
+    }
+}
@@ -4,7 +4,9 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::cmp;
 use std::convert::{TryFrom, TryInto};
+use std::fmt;
 
 use crate::errors::ErrorKind;
 use apiserver_thrift::MononokeRevision::UnknownField;
@@ -14,11 +16,13 @@ use http::uri::Uri;
 
 use apiserver_thrift::types::{
     MononokeGetBlobParams, MononokeGetBranchesParams, MononokeGetChangesetParams,
-    MononokeGetRawParams, MononokeGetTreeParams, MononokeIsAncestorParams,
-    MononokeListDirectoryParams, MononokeRevision,
+    MononokeGetRawChunkParams, MononokeGetRawParams, MononokeGetTreeParams,
+    MononokeIsAncestorParams, MononokeListDirectoryPageParams, MononokeListDirectoryParams,
+    MononokeMergeConflictsParams, MononokePathsExistParams, MononokeRevision,
 };
 
 use super::lfs::BatchRequest;
+use super::paging::PageRequest;
 
 #[derive(Debug, Clone)]
 pub enum Revision {
@@ -26,12 +30,41 @@ pub enum Revision {
     Bookmark(String),
 }
 
+impl fmt::Display for Revision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Revision::CommitHash(hash) => write!(f, "{}", hash),
+            Revision::Bookmark(bookmark) => write!(f, "{}", bookmark),
+        }
+    }
+}
+
+/// Controls how symlinks and submodules are represented in directory listings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListDirectoryOptions {
+    /// Include the target of every symlink entry in the listing.
+    pub symlink_targets: bool,
+    /// Expand symlinks in the requested path, so listing a symlink to a directory lists the
+    /// directory it points to.
+    pub follow_symlinks: bool,
+    /// Report submodules (recorded in .hgsubstate by git imports) as entries of their own.
+    pub submodules: bool,
+}
+
 #[derive(Debug)]
 pub enum MononokeRepoQuery {
     GetRawFile {
         path: String,
         revision: Revision,
     },
+    GetRawFileChunk {
+        path: String,
+        revision: Revision,
+        /// Where the chunk starts in the file
+        offset: i64,
+        /// The most bytes the chunk carries, capped by the server
+        max_size: Option<i64>,
+    },
     GetHgFile {
         filenode: String,
     },
@@ -39,25 +72,94 @@ pub enum MononokeRepoQuery {
         filenode: String,
         path: String,
         depth: Option<u32>,
+        /// Most entries to return
+        limit: Option<usize>,
+        /// Where to continue the history from, as returned by the previous request
+        cursor: Option<String>,
     },
     ListDirectory {
         path: String,
         revision: Revision,
+        options: ListDirectoryOptions,
+        /// The whole directory if not set
+        page: Option<PageRequest>,
     },
     GetBlobContent {
         hash: String,
     },
+    GetRawBlob {
+        /// Blobstore key, without the prefix of the repo
+        key: String,
+    },
+    GetRawBookmarks,
+    GetRawChangeset {
+        /// Bonsai changeset id
+        changeset: String,
+    },
+    MultiGet {
+        revision: Revision,
+        /// Files to fetch by their path at `revision`
+        paths: Vec<String>,
+        /// Files to fetch by content id, whatever their path
+        content_ids: Vec<String>,
+        /// Stop sending file contents once this many bytes were sent
+        max_bytes: Option<u64>,
+    },
     GetTree {
         hash: String,
     },
     GetChangeset {
         revision: Revision,
     },
-    GetBranches,
+    GetBranches {
+        /// All bookmarks if not set
+        page: Option<PageRequest>,
+    },
+    GetStatus,
+    GetHealth,
+    GetReplicas,
+    GetPushUsage,
+    GetSizes {
+        /// Paths to summarize, the empty string being the root of the repo
+        paths: Vec<String>,
+        revision: Revision,
+    },
+    PathsExist {
+        /// Paths to look up, the empty string being the root of the repo
+        paths: Vec<String>,
+        revision: Revision,
+    },
     IsAncestor {
         ancestor: Revision,
         descendant: Revision,
     },
+    IsAncestorBatch {
+        /// Pairs of (ancestor, descendant) to check, answered in this order
+        pairs: Vec<(Revision, Revision)>,
+    },
+    CommonAncestor {
+        left: Revision,
+        right: Revision,
+    },
+    Contains {
+        bookmark: String,
+        revision: Revision,
+    },
+    MergeConflicts {
+        left: Revision,
+        right: Revision,
+    },
+    GetGraph {
+        revision: Revision,
+        /// Most changesets to return
+        limit: Option<usize>,
+    },
+    Range {
+        from: Revision,
+        to: Revision,
+        /// Most changesets to return
+        limit: Option<usize>,
+    },
     DownloadLargeFile {
         oid: String,
     },
@@ -93,6 +195,27 @@ impl TryFrom<MononokeGetRawParams> for MononokeQuery {
     }
 }
 
+impl TryFrom<MononokeGetRawChunkParams> for MononokeQuery {
+    type Error = Error;
+
+    fn try_from(params: MononokeGetRawChunkParams) -> Result<MononokeQuery, Self::Error> {
+        let offset = params.offset;
+        let max_size = params.max_size;
+        let params = params.params;
+        let repo = params.repo;
+        let path = String::from_utf8(params.path)?;
+        params.revision.try_into().map(|rev| MononokeQuery {
+            repo,
+            kind: MononokeRepoQuery::GetRawFileChunk {
+                path,
+                revision: rev,
+                offset,
+                max_size,
+            },
+        })
+    }
+}
+
 impl TryFrom<MononokeGetChangesetParams> for MononokeQuery {
     type Error = Error;
 
@@ -109,9 +232,15 @@ impl TryFrom<MononokeGetBranchesParams> for MononokeQuery {
     type Error = Error;
 
     fn try_from(params: MononokeGetBranchesParams) -> Result<MononokeQuery, Self::Error> {
+        // Clients that ask for neither a token nor a size get all bookmarks, as they used to
+        let page = if params.continuation_token.is_some() || params.max_entries.is_some() {
+            Some(page_request(params.continuation_token, params.max_entries))
+        } else {
+            None
+        };
         Ok(MononokeQuery {
             repo: params.repo,
-            kind: MononokeRepoQuery::GetBranches,
+            kind: MononokeRepoQuery::GetBranches { page },
         })
     }
 }
@@ -120,15 +249,46 @@ impl TryFrom<MononokeListDirectoryParams> for MononokeQuery {
     type Error = Error;
 
     fn try_from(params: MononokeListDirectoryParams) -> Result<MononokeQuery, Self::Error> {
-        let repo = params.repo;
-        let path = String::from_utf8(params.path)?;
-        params.revision.try_into().map(|rev| MononokeQuery {
-            repo,
-            kind: MononokeRepoQuery::ListDirectory {
-                path,
-                revision: rev,
-            },
-        })
+        list_directory_query(params, None)
+    }
+}
+
+impl TryFrom<MononokeListDirectoryPageParams> for MononokeQuery {
+    type Error = Error;
+
+    fn try_from(params: MononokeListDirectoryPageParams) -> Result<MononokeQuery, Self::Error> {
+        let page = page_request(params.continuation_token, params.max_entries);
+        list_directory_query(params.params, Some(page))
+    }
+}
+
+fn list_directory_query(
+    params: MononokeListDirectoryParams,
+    page: Option<PageRequest>,
+) -> Result<MononokeQuery, Error> {
+    let repo = params.repo;
+    let path = String::from_utf8(params.path)?;
+    let options = ListDirectoryOptions {
+        symlink_targets: params.include_symlink_targets.unwrap_or(false),
+        follow_symlinks: params.follow_symlinks.unwrap_or(false),
+        submodules: params.include_submodules.unwrap_or(false),
+    };
+    params.revision.try_into().map(|rev| MononokeQuery {
+        repo,
+        kind: MononokeRepoQuery::ListDirectory {
+            path,
+            revision: rev,
+            options,
+            page,
+        },
+    })
+}
+
+fn page_request(token: Option<String>, max_entries: Option<i32>) -> PageRequest {
+    PageRequest {
+        token,
+        // Thrift has no unsigned integers: negative sizes are as invalid as 0
+        limit: max_entries.map(|limit| cmp::max(limit, 0) as usize),
     }
 }
 
@@ -150,6 +310,20 @@ impl TryFrom<MononokeIsAncestorParams> for MononokeQuery {
     }
 }
 
+impl TryFrom<MononokeMergeConflictsParams> for MononokeQuery {
+    type Error = Error;
+
+    fn try_from(params: MononokeMergeConflictsParams) -> Result<MononokeQuery, Self::Error> {
+        let repo = params.repo;
+        let left = params.left.try_into()?;
+        let right = params.right.try_into()?;
+        Ok(MononokeQuery {
+            repo,
+            kind: MononokeRepoQuery::MergeConflicts { left, right },
+        })
+    }
+}
+
 impl TryFrom<MononokeGetBlobParams> for MononokeQuery {
     type Error = Error;
 
@@ -176,6 +350,26 @@ impl TryFrom<MononokeGetTreeParams> for MononokeQuery {
     }
 }
 
+impl TryFrom<MononokePathsExistParams> for MononokeQuery {
+    type Error = Error;
+
+    fn try_from(params: MononokePathsExistParams) -> Result<MononokeQuery, Self::Error> {
+        let repo = params.repo;
+        let paths = params
+            .paths
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<Result<_, _>>()?;
+        params.revision.try_into().map(|rev| MononokeQuery {
+            repo,
+            kind: MononokeRepoQuery::PathsExist {
+                paths,
+                revision: rev,
+            },
+        })
+    }
+}
+
 impl TryFrom<MononokeRevision> for Revision {
     type Error = Error;
 
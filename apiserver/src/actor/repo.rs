@@ -4,50 +4,357 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
+use std::{
+    cmp,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    convert::TryInto,
+    iter,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use blobrepo::{get_sha256_alias, get_sha256_alias_key, BlobRepo};
-use blobrepo_factory::open_blobrepo;
+use blobrepo::{get_sha256_alias, get_sha256_alias_key, BlobRepo, FileContentsStream};
+use blobrepo_factory::open_blobrepo_with_replicas;
 use blobstore::Blobstore;
 use bookmarks::Bookmark;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use cachelib::LruCachePool;
+use changeset_fetcher::ChangesetFetcher;
 use cloned::cloned;
-use context::CoreContext;
-use failure::Error;
-use futures::future::{join_all, ok};
-use futures::Stream;
-use futures::{Future, IntoFuture};
-use futures_ext::{try_boxfuture, BoxFuture, FutureExt, StreamExt};
+use context::{CoreContext, TimeUuidProvider};
+use failure::{err_msg, format_err, Error};
+use futures::future::{self, join_all, loop_fn, ok, Loop};
+use futures::{stream, Future, IntoFuture, Stream};
+use futures_ext::{try_boxfuture, BoxFuture, BoxStream, FutureExt, StreamExt};
 use http::uri::Uri;
 use mercurial_types::manifest::Content;
-use mononoke_api;
-use remotefilelog;
+use mononoke_api::{
+    self, exists::get_path_entries, merge_conflicts::find_merge_conflicts, sizes::get_path_summary,
+    submodules::get_submodules, symlinks::get_content_by_path_following_symlinks,
+};
+use push_usage::{PushQuota, SqlPushUsageStore};
+use qos::QosPools;
+use remotefilelog::{self, HistoryCursor, HistoryLimits};
 use scuba_ext::ScubaSampleBuilder;
-use slog::Logger;
+use slog::{error, info, warn, Logger};
 use sshrelay::SshEnvVars;
+use tokio::util::FutureExt as TokioFutureExt;
 use tracing::TraceContext;
-use uuid::Uuid;
 
-use mercurial_types::{HgChangesetId, HgFileNodeId, HgManifestId};
+use mercurial_types::{Changeset, HgChangesetId, HgFileNodeId, HgManifestId, TextDecoding, Type};
 use metaconfig_types::RepoConfig;
 use types::WireHistoryEntry;
 
-use mononoke_types::{FileContents, RepositoryId};
-use reachabilityindex::ReachabilityIndex;
+use mononoke_types::{
+    ChangesetId, ContentId, DateTime, FileContents, FileType, Generation, MPath, RepositoryId,
+};
+use path_acl::{PathAccess, PathAcls};
+use phases::{HintPhases, Phase, Phases, SqlPhases};
+use reachabilityindex::{LeastCommonAncestorsHint, ReachabilityIndex};
+use repo_maintenance::{MaintenanceStore, SqlConstructors, SqlMaintenanceStore};
+use repo_overrides::{OverridesStore, RepoOverrides, SqlOverridesStore};
+use revset::{greatest_common_ancestor_with_hint, RangeNodeStream};
 use skiplist::{deserialize_skiplist_map, SkiplistIndex};
+use sql_replicas::ReplicaManager;
 
 use crate::errors::ErrorKind;
 use crate::from_string as FS;
 
+use super::cache_metrics::CacheMetrics;
+use super::contains_cache::ContainsCache;
+use super::health::RepoHealthStores;
 use super::lfs::{build_response, BatchRequest};
-use super::model::{Entry, EntryWithSizeAndContentHash};
-use super::{MononokeRepoQuery, MononokeRepoResponse, Revision};
+use super::model::{
+    Ancestry, CommonAncestor, Entry, EntryWithSizeAndContentHash, GraphNode, MergeConflictReport,
+    MultiGetEntry, PathExistence, PathSize, RangeChangeset, RawChangeset, Replica, RepoStatus,
+};
+use super::paging::{self, PageRequest, PageToken};
+use super::repo_view::RepoView;
+use super::{ListDirectoryOptions, MononokeRepoQuery, MononokeRepoResponse, Revision};
+
+/// The most file history entries a single response carries, whatever the client asks for
+const MAX_FILE_HISTORY_ENTRIES: usize = 100_000;
+
+/// The most paths a single sizes request may ask about
+const MAX_SIZES_PATHS: usize = 1000;
+
+/// The most paths a single exists request may ask about
+const MAX_EXISTS_PATHS: usize = 10000;
+
+/// The most pairs of changesets a single batch of ancestry checks may ask about
+const MAX_IS_ANCESTOR_PAIRS: usize = 10000;
+
+/// How many bookmarks answers to "is this commit in this bookmark" are remembered for
+const CONTAINS_CACHE_BOOKMARKS: usize = 1000;
+
+/// How many answers to "is this commit in this bookmark" are remembered per bookmark
+const CONTAINS_CACHE_ENTRIES_PER_BOOKMARK: usize = 1000;
+
+/// The most files a single multiget request may ask for
+const MAX_MULTI_GET_KEYS: usize = 10000;
+
+/// The most file content bytes a single multiget response carries, whatever the client asks for
+const MAX_MULTI_GET_BYTES: u64 = 512 * 1024 * 1024;
+
+/// How many files of a multiget request are fetched at once, unless it's overridden
+const MULTI_GET_CONCURRENCY: usize = 20;
+
+/// How often the overrides of the tunables of the repo are polled
+const OVERRIDES_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// Tunables that can be overridden without restarting the apiserver
+const MULTI_GET_CONCURRENCY_OVERRIDE: &str = "apiserver_multi_get_concurrency";
+
+/// How many changesets a graph response has if the client doesn't say
+const DEFAULT_GRAPH_NODES: usize = 100;
+
+/// The most changesets a single graph response has, whatever the client asks for
+const MAX_GRAPH_NODES: usize = 5000;
+
+/// How many changesets a range response has if the client doesn't say
+const DEFAULT_RANGE_CHANGESETS: usize = 100;
+
+/// The most changesets a single range response has, whatever the client asks for
+const MAX_RANGE_CHANGESETS: usize = 5000;
+
+/// The most generations a range may span, as the whole range is walked to answer for it
+const MAX_RANGE_GENERATIONS: u64 = 100_000;
+
+/// The most bytes a chunk of a file carries, whatever the client asks for
+const MAX_RAW_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
 
 pub struct MononokeRepo {
     repo: BlobRepo,
     skiplist_index: Arc<SkiplistIndex>,
     sha1_cache: Option<LruCachePool>,
+    cache_metrics: Arc<CacheMetrics>,
+    maintenance_store: Arc<MaintenanceStore>,
+    overrides: RepoOverrides,
+    phases: Arc<Phases>,
+    contains_cache: Arc<ContainsCache>,
+    replica_manager: Option<Arc<ReplicaManager>>,
+    health_stores: RepoHealthStores,
+    qos: QosPools,
+    push_quota: PushQuota,
+    path_access: PathAccess,
+    text_decoding: TextDecoding,
+}
+
+fn open_push_quota(config: &RepoConfig, myrouter_port: Option<u16>) -> Result<PushQuota, Error> {
+    let store = SqlPushUsageStore::with_repo_type(&config.repotype, myrouter_port, "push_usage")?;
+    Ok(PushQuota::new(
+        RepositoryId::new(config.repoid),
+        config.push_quota.clone(),
+        Arc::new(store),
+    ))
+}
+
+/// Fails unless `path` may be read with `access`
+fn check_path_access(access: &PathAccess, path: &MPath) -> Result<(), ErrorKind> {
+    if access.can_read(Some(path)) {
+        Ok(())
+    } else {
+        Err(ErrorKind::Forbidden(
+            path.to_string(),
+            "it is restricted to some users".to_string(),
+        ))
+    }
+}
+
+/// Fails unless `access` is to the whole repo. The path of a file or tree that is asked for by
+/// hash isn't known, so it can't be told whether it may be read otherwise.
+fn check_hash_access(access: &PathAccess, hash: &str) -> Result<(), ErrorKind> {
+    if access.is_unrestricted() {
+        Ok(())
+    } else {
+        Err(ErrorKind::Forbidden(
+            hash.to_string(),
+            "some paths of this repo are restricted, ask for it by path".to_string(),
+        ))
+    }
+}
+
+/// The `limit` ancestors of `start`, itself included, with the highest generation numbers, with
+/// their parents and generation numbers. Children come before their parents.
+fn walk_graph(
+    ctx: CoreContext,
+    fetcher: Arc<ChangesetFetcher>,
+    start: ChangesetId,
+    limit: usize,
+) -> BoxFuture<Vec<(ChangesetId, Vec<ChangesetId>, Generation)>, Error> {
+    fetcher
+        .get_generation_number(ctx.clone(), start)
+        .and_then(move |generation| {
+            let mut queue = BinaryHeap::new();
+            queue.push((generation, start));
+            let seen: HashSet<_> = iter::once(start).collect();
+
+            loop_fn(
+                (queue, seen, Vec::new()),
+                move |(mut queue, mut seen, mut nodes)| {
+                    let (generation, cs_id) = match queue.pop() {
+                        Some(next) if nodes.len() < limit => next,
+                        _ => return ok(Loop::Break(nodes)).left_future(),
+                    };
+                    cloned!(ctx, fetcher);
+                    fetcher
+                        .get_parents(ctx.clone(), cs_id)
+                        .and_then(move |parents| {
+                            let unseen: Vec<_> = parents
+                                .iter()
+                                .filter(|parent| seen.insert(**parent))
+                                .map(|parent| {
+                                    let parent = *parent;
+                                    fetcher
+                                        .get_generation_number(ctx.clone(), parent)
+                                        .map(move |generation| (generation, parent))
+                                })
+                                .collect();
+                            join_all(unseen).map(move |unseen| {
+                                queue.extend(unseen);
+                                nodes.push((cs_id, parents, generation));
+                                Loop::Continue((queue, seen, nodes))
+                            })
+                        })
+                        .right_future()
+                },
+            )
+        })
+        .boxify()
+}
+
+/// How many changesets a graph response has when the client asks for `limit`
+fn graph_limit(limit: Option<usize>) -> usize {
+    cmp::min(limit.unwrap_or(DEFAULT_GRAPH_NODES), MAX_GRAPH_NODES)
+}
+
+/// Where the chunk of a file of `file_size` bytes that a client asks for, at most `max_size`
+/// bytes from `offset`, starts and ends
+fn chunk_range(
+    offset: i64,
+    max_size: Option<i64>,
+    file_size: u64,
+) -> Result<(u64, u64), ErrorKind> {
+    if offset < 0 || offset as u64 > file_size {
+        return Err(ErrorKind::InvalidInput(
+            format!(
+                "offset {} is out of the file of {} bytes",
+                offset, file_size
+            ),
+            None,
+        ));
+    }
+    let max_size = match max_size {
+        Some(max_size) if max_size <= 0 => {
+            return Err(ErrorKind::InvalidInput(
+                format!("chunks must be at least 1 byte, not {}", max_size),
+                None,
+            ));
+        }
+        Some(max_size) => cmp::min(max_size as u64, MAX_RAW_CHUNK_SIZE),
+        None => MAX_RAW_CHUNK_SIZE,
+    };
+    let offset = offset as u64;
+    Ok((offset, cmp::min(offset + max_size, file_size)))
+}
+
+/// The bytes of `content` from `start` to `end`. Reading stops at the first chunk of the stream
+/// that starts at `end` or after, so the rest of the file isn't fetched.
+fn read_range(
+    content: BoxStream<Bytes, Error>,
+    start: u64,
+    end: u64,
+) -> impl Future<Item = Bytes, Error = Error> {
+    let mut next_start = 0;
+    content
+        .map(move |chunk| {
+            let chunk_start = next_start;
+            next_start += chunk.len() as u64;
+            (chunk_start, chunk)
+        })
+        .take_while(move |(chunk_start, _)| Ok(*chunk_start < end))
+        .fold(BytesMut::new(), move |mut range, (chunk_start, chunk)| {
+            let chunk_end = chunk_start + chunk.len() as u64;
+            if chunk_end > start {
+                let from = start.saturating_sub(chunk_start) as usize;
+                let to = (cmp::min(chunk_end, end) - chunk_start) as usize;
+                range.extend_from_slice(&chunk[from..to]);
+            }
+            Ok::<_, Error>(range)
+        })
+        .map(BytesMut::freeze)
+}
+
+/// Labels the `nodes` found by `walk_graph` with their Mercurial hashes, their bookmarks and
+/// their phases, the public heads being the changesets of `bookmarks`.
+fn label_graph(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    phases: Arc<Phases>,
+    nodes: Vec<(ChangesetId, Vec<ChangesetId>, Generation)>,
+    bookmarks: HashMap<Bookmark, ChangesetId>,
+) -> BoxFuture<Vec<GraphNode>, Error> {
+    let mut labels: HashMap<ChangesetId, Vec<String>> = HashMap::new();
+    for (bookmark, cs_id) in bookmarks.iter() {
+        labels
+            .entry(*cs_id)
+            .or_insert_with(Vec::new)
+            .push(bookmark.to_string());
+    }
+    let public_heads = bookmarks.values().cloned().collect();
+
+    let cs_ids: Vec<_> = nodes.iter().map(|(cs_id, _, _)| *cs_id).collect();
+    let all_cs_ids: Vec<_> = nodes
+        .iter()
+        .flat_map(|(cs_id, parents, _)| iter::once(cs_id).chain(parents))
+        .cloned()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    let phases = phases.get_all_with_bookmarks(
+        ctx.clone(),
+        repo.clone(),
+        cs_ids,
+        Some(Arc::new(public_heads)),
+    );
+    let hg_cs_ids = repo.get_hg_bonsai_mapping(ctx, all_cs_ids).map(|mapping| {
+        mapping
+            .into_iter()
+            .map(|(hg_cs_id, cs_id)| (cs_id, hg_cs_id.to_string()))
+            .collect::<HashMap<_, _>>()
+    });
+
+    phases
+        .join(hg_cs_ids)
+        .and_then(move |(mut phases, hg_cs_ids)| {
+            let hg_cs_id = |cs_id: &ChangesetId| {
+                hg_cs_ids
+                    .get(cs_id)
+                    .cloned()
+                    .ok_or_else(|| format_err!("no mercurial changeset for {}", cs_id))
+            };
+            nodes
+                .into_iter()
+                .map(|(cs_id, parents, generation)| {
+                    let phase = phases.calculated.remove(&cs_id).unwrap_or(Phase::Draft);
+                    Ok(GraphNode {
+                        hash: hg_cs_id(&cs_id)?,
+                        parents: parents
+                            .iter()
+                            .map(&hg_cs_id)
+                            .collect::<Result<_, Error>>()?,
+                        generation: generation.value(),
+                        bookmarks: labels.remove(&cs_id).unwrap_or_default(),
+                        phase: phase.into(),
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()
+        })
+        .boxify()
 }
 
 impl MononokeRepo {
@@ -56,23 +363,94 @@ impl MononokeRepo {
         config: RepoConfig,
         myrouter_port: Option<u16>,
         with_skiplist: bool,
+        text_decoding: TextDecoding,
+        cache_metrics: Arc<CacheMetrics>,
+        time_uuid: Arc<TimeUuidProvider>,
     ) -> impl Future<Item = Self, Error = Error> {
         let ctx = CoreContext::new(
-            Uuid::new_v4(),
+            time_uuid.new_uuid(),
             logger.clone(),
             ScubaSampleBuilder::with_discard(),
             None,
             TraceContext::default(),
             None,
             SshEnvVars::default(),
-        );
+        )
+        .with_time_uuid_provider(time_uuid);
 
         let skiplist_index_blobstore_key = config.skiplist_index_blobstore_key.clone();
+        let qos = QosPools::new(&config.qos);
+        // The apiserver doesn't know who sends requests, so they may only read what everyone may
+        let path_access =
+            PathAcls::new(&config.path_acls).map(|path_acls| path_acls.access(iter::empty()));
 
         let repoid = RepositoryId::new(config.repoid);
         let sha1_cache = cachelib::get_pool("content-sha1");
-        open_blobrepo(logger.clone(), config.repotype, repoid, myrouter_port)
-            .map(move |repo| {
+        open_push_quota(&config, myrouter_port)
+            .and_then(|push_quota| {
+                let maintenance_store: Arc<MaintenanceStore> =
+                    Arc::new(SqlMaintenanceStore::with_repo_type_write_lock_db(
+                        &config.repotype,
+                        myrouter_port,
+                        "maintenance",
+                    )?);
+                let phases_store: Arc<Phases> = Arc::new(SqlPhases::with_repo_type(
+                    &config.repotype,
+                    myrouter_port,
+                    "phases",
+                )?);
+                let health_stores = RepoHealthStores::open(repoid, &config.repotype, myrouter_port)?;
+                let overrides_store: Arc<OverridesStore> =
+                    Arc::new(SqlOverridesStore::with_repo_type(
+                        &config.repotype,
+                        myrouter_port,
+                        "repo_overrides",
+                    )?);
+                Ok((
+                    maintenance_store,
+                    push_quota,
+                    phases_store,
+                    health_stores,
+                    overrides_store,
+                    path_access?,
+                ))
+            })
+            .into_future()
+            .and_then({
+                cloned!(logger);
+                move |sql_stores| {
+                    open_blobrepo_with_replicas(logger, config.repotype, repoid, myrouter_port)
+                        .map(move |(repo, replica_manager)| (repo, replica_manager, sql_stores))
+                }
+            })
+            .map(move |(repo, replica_manager, sql_stores)| {
+                let (
+                    maintenance_store,
+                    push_quota,
+                    phases_store,
+                    health_stores,
+                    overrides_store,
+                    path_access,
+                ) = sql_stores;
+                if let Some(ref replica_manager) = replica_manager {
+                    tokio::spawn(replica_manager.reresolve_periodically().map_err({
+                        cloned!(logger);
+                        move |err| error!(logger, "Re-resolving replicas failed: {}", err)
+                    }));
+                }
+
+                // Requests are served with the configured tunables until the overrides are polled
+                let overrides = RepoOverrides::new(repoid, overrides_store, logger.clone());
+                tokio::spawn(
+                    overrides
+                        .clone()
+                        .refresh_periodically(ctx.clone(), OVERRIDES_POLL_INTERVAL),
+                );
+                tokio::spawn(overrides.refresh(ctx.clone()).map_err({
+                    cloned!(logger);
+                    move |err| warn!(logger, "failed to poll overrides: {:?}", err)
+                }));
+
                 let skiplist_index = {
                     if !with_skiplist {
                         ok(Arc::new(SkiplistIndex::new())).right_future()
@@ -80,15 +458,25 @@ impl MononokeRepo {
                         match skiplist_index_blobstore_key.clone() {
                             Some(skiplist_index_blobstore_key) => repo
                                 .get_blobstore()
-                                .get(ctx.clone(), skiplist_index_blobstore_key)
-                                .and_then(|maybebytes| {
+                                .get(ctx.clone(), skiplist_index_blobstore_key.clone())
+                                .and_then(move |maybebytes| {
+                                    // Without a prebuilt index, ancestry queries start slow and
+                                    // get faster as nodes are indexed lazily
                                     let map = match maybebytes {
                                         Some(bytes) => {
                                             let bytes = bytes.into_bytes();
                                             try_boxfuture!(deserialize_skiplist_map(bytes))
                                         }
-                                        None => HashMap::new(),
+                                        None => {
+                                            warn!(
+                                                logger,
+                                                "No skiplist index under {}, it will be built lazily",
+                                                skiplist_index_blobstore_key
+                                            );
+                                            HashMap::new()
+                                        }
                                     };
+                                    info!(logger, "Loaded {} skiplist nodes", map.len());
                                     ok(Arc::new(SkiplistIndex::new_with_skiplist_graph(map)))
                                         .boxify()
                                 })
@@ -99,8 +487,22 @@ impl MononokeRepo {
                 };
                 skiplist_index.map(|skiplist_index| Self {
                     repo,
+                    phases: Arc::new(HintPhases::new(phases_store, skiplist_index.clone())),
                     skiplist_index,
                     sha1_cache,
+                    cache_metrics,
+                    maintenance_store,
+                    overrides,
+                    contains_cache: Arc::new(ContainsCache::new(
+                        CONTAINS_CACHE_BOOKMARKS,
+                        CONTAINS_CACHE_ENTRIES_PER_BOOKMARK,
+                    )),
+                    replica_manager,
+                    health_stores,
+                    qos,
+                    push_quota,
+                    path_access,
+                    text_decoding,
                 })
             })
             .flatten()
@@ -109,9 +511,71 @@ impl MononokeRepo {
     fn get_hgchangesetid_from_revision(
         &self,
         ctx: CoreContext,
+        view: &RepoView,
+        revision: Revision,
+    ) -> BoxFuture<HgChangesetId, Error> {
+        let (revision, steps) = match revision {
+            Revision::CommitHash(hash) => {
+                let (hash, steps) = try_boxfuture!(FS::get_ancestry_suffix(hash));
+                (Revision::CommitHash(hash), steps)
+            }
+            Revision::Bookmark(bookmark) => {
+                let (bookmark, steps) = try_boxfuture!(FS::get_ancestry_suffix(bookmark));
+                (Revision::Bookmark(bookmark), steps)
+            }
+        };
+
+        let changesetid = self.resolve_revision(ctx.clone(), view, revision);
+        if steps == 0 {
+            return changesetid.boxify();
+        }
+
+        cloned!(self.repo);
+        changesetid
+            .and_then({
+                cloned!(ctx, repo);
+                move |hg_cs_id| {
+                    repo.get_bonsai_from_hg(ctx, hg_cs_id)
+                        .and_then(move |maybe_cs_id| {
+                            maybe_cs_id.ok_or_else(|| {
+                                ErrorKind::NotFound(hg_cs_id.to_string(), None).into()
+                            })
+                        })
+                }
+            })
+            .and_then({
+                cloned!(ctx, repo);
+                move |cs_id| {
+                    let fetcher = repo.get_changeset_fetcher_for("revsets");
+                    loop_fn((cs_id, steps), move |(cs_id, steps)| {
+                        if steps == 0 {
+                            return ok(Loop::Break(cs_id)).left_future();
+                        }
+                        fetcher
+                            .get_parents(ctx.clone(), cs_id)
+                            .and_then(move |parents| match parents.first() {
+                                Some(p1) => Ok(Loop::Continue((*p1, steps - 1))),
+                                None => Err(ErrorKind::NotFound(
+                                    format!("ancestor of root changeset {}", cs_id),
+                                    None,
+                                )
+                                .into()),
+                            })
+                            .right_future()
+                    })
+                }
+            })
+            .and_then(move |cs_id| repo.get_hg_from_bonsai_changeset(ctx, cs_id))
+            .boxify()
+    }
+
+    fn resolve_revision(
+        &self,
+        ctx: CoreContext,
+        view: &RepoView,
         revision: Revision,
     ) -> impl Future<Item = HgChangesetId, Error = Error> {
-        let repo = self.repo.clone();
+        cloned!(view);
         match revision {
             Revision::CommitHash(hash) => FS::get_changeset_id(hash)
                 .into_future()
@@ -121,7 +585,7 @@ impl MononokeRepo {
                 .into_future()
                 .from_err()
                 .and_then(move |bookmark| {
-                    repo.get_bookmark(ctx, &bookmark).and_then(move |opt| {
+                    view.get_bookmark(ctx, &bookmark).and_then(move |opt| {
                         opt.ok_or_else(|| ErrorKind::BookmarkNotFound(bookmark.to_string()).into())
                     })
                 })
@@ -132,28 +596,91 @@ impl MononokeRepo {
     fn get_raw_file(
         &self,
         ctx: CoreContext,
+        view: RepoView,
         revision: Revision,
         path: String,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
         let mpath = try_boxfuture!(FS::get_mpath(path.clone()));
+        try_boxfuture!(check_path_access(&self.path_access, &mpath));
 
         let repo = self.repo.clone();
-        self.get_hgchangesetid_from_revision(ctx.clone(), revision)
+        self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision)
             .and_then(|changesetid| {
                 mononoke_api::get_content_by_path(ctx, repo, changesetid, Some(mpath))
             })
             .and_then(move |content| match content {
                 Content::File(content)
                 | Content::Executable(content)
-                | Content::Symlink(content) => Ok(MononokeRepoResponse::GetRawFile {
-                    content: content.into_bytes(),
-                }),
+                | Content::Symlink(content) => match content {
+                    FileContents::Bytes(content) => {
+                        Ok(MononokeRepoResponse::GetRawFile { content })
+                    }
+                    FileContents::Tombstone(tombstone) => Err(ErrorKind::ContentTombstoned(
+                        path,
+                        tombstone.reason().to_string(),
+                    )
+                    .into()),
+                },
                 _ => Err(ErrorKind::InvalidInput(path.to_string(), None).into()),
             })
             .from_err()
             .boxify()
     }
 
+    /// A chunk of the content of a file, for files too large to fetch at once. Only the content
+    /// up to the end of the chunk is read from the blobstore.
+    fn get_raw_file_chunk(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        revision: Revision,
+        path: String,
+        offset: i64,
+        max_size: Option<i64>,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let mpath = try_boxfuture!(FS::get_mpath(path.clone()));
+        try_boxfuture!(check_path_access(&self.path_access, &mpath));
+
+        let repo = self.repo.clone();
+        self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision)
+            .and_then({
+                cloned!(ctx, repo);
+                move |changesetid| repo.get_changeset_by_changesetid(ctx, changesetid)
+            })
+            .and_then({
+                cloned!(ctx, repo, path);
+                move |changeset| {
+                    repo.find_file_in_manifest(ctx, &mpath, changeset.manifestid())
+                        .and_then(move |maybe_file| {
+                            maybe_file.ok_or_else(|| ErrorKind::NotFound(path, None).into())
+                        })
+                }
+            })
+            .and_then(move |(_, filenode)| {
+                repo.get_file_size(ctx.clone(), filenode)
+                    .join(repo.get_file_content_stream(ctx, filenode))
+            })
+            .from_err()
+            .and_then(move |(file_size, content)| match content {
+                FileContentsStream::Bytes(content) => {
+                    let (start, end) = try_boxfuture!(chunk_range(offset, max_size, file_size));
+                    read_range(content, start, end)
+                        .map(move |content| MononokeRepoResponse::GetRawFileChunk {
+                            content,
+                            offset: start,
+                            file_size,
+                        })
+                        .from_err()
+                        .boxify()
+                }
+                FileContentsStream::Tombstone(tombstone) => future::err(
+                    ErrorKind::ContentTombstoned(path, tombstone.reason().to_string()),
+                )
+                .boxify(),
+            })
+            .boxify()
+    }
+
     /// Given a Mercurial filenode hash, return the raw content of the file in the format
     /// expected by the Mercurial client. This includes the raw bytes of the file content,
     /// optionally prefixed with a header containing copy-from information. Content in
@@ -163,6 +690,7 @@ impl MononokeRepo {
         ctx: CoreContext,
         filenode: String,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        try_boxfuture!(check_hash_access(&self.path_access, &filenode));
         let filenode = try_boxfuture!(FS::get_nodehash(&filenode));
         self.repo
             .get_raw_hg_content(ctx, HgFileNodeId::new(filenode))
@@ -179,30 +707,63 @@ impl MononokeRepo {
         filenode: String,
         path: String,
         depth: Option<u32>,
+        limit: Option<usize>,
+        cursor: Option<String>,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
         let filenode = try_boxfuture!(FS::get_filenode_id(&filenode));
         let path = try_boxfuture!(FS::get_mpath(path));
+        try_boxfuture!(check_path_access(&self.path_access, &path));
+        let start = match cursor {
+            Some(cursor) => {
+                let token = try_boxfuture!(cursor.parse::<PageToken>());
+                // Cursors are only valid for the history they were returned with
+                if token.snapshot != Some(filenode.to_string()) {
+                    return future::err(ErrorKind::InvalidInput(cursor, None)).boxify();
+                }
+                try_boxfuture!(token
+                    .position
+                    .parse::<HistoryCursor>()
+                    .map_err(|e| ErrorKind::InvalidInput(cursor, Some(e))))
+            }
+            None => HistoryCursor::new(vec![filenode]),
+        };
+        let limits = HistoryLimits {
+            max_depth: depth,
+            max_entries: Some(limit.map_or(MAX_FILE_HISTORY_ENTRIES, |limit| {
+                cmp::min(limit, MAX_FILE_HISTORY_ENTRIES)
+            })),
+        };
 
-        let history =
-            remotefilelog::get_file_history(ctx, self.repo.clone(), filenode, path.clone(), depth)
-                .and_then(move |entry| {
-                    let entry = WireHistoryEntry::from(entry);
-                    Ok(Bytes::from(serde_json::to_vec(&entry)?))
+        remotefilelog::get_file_history_page(ctx, self.repo.clone(), path, start, limits)
+            .and_then(move |page| {
+                let entries = page
+                    .entries
+                    .into_iter()
+                    .map(|entry| {
+                        let entry = WireHistoryEntry::from(entry);
+                        Ok(Bytes::from(serde_json::to_vec(&entry)?))
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok(MononokeRepoResponse::GetFileHistory {
+                    history: stream::iter_ok(entries).boxify(),
+                    next: page.next.map(|cursor| {
+                        PageToken::new(Some(filenode.to_string()), cursor.to_string()).to_string()
+                    }),
                 })
-                .from_err()
-                .boxify();
-
-        ok(MononokeRepoResponse::GetFileHistory { history }).boxify()
+            })
+            .from_err()
+            .boxify()
     }
 
     fn is_ancestor(
         &self,
         ctx: CoreContext,
+        view: RepoView,
         ancestor: Revision,
         descendant: Revision,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
         let descendant_future = self
-            .get_hgchangesetid_from_revision(ctx.clone(), descendant.clone())
+            .get_hgchangesetid_from_revision(ctx.clone(), &view, descendant.clone())
             .from_err()
             .and_then({
                 cloned!(ctx, self.repo);
@@ -213,7 +774,7 @@ impl MononokeRepo {
             });
 
         let ancestor_future = self
-            .get_hgchangesetid_from_revision(ctx.clone(), ancestor.clone())
+            .get_hgchangesetid_from_revision(ctx.clone(), &view, ancestor.clone())
             .from_err()
             .and_then({
                 cloned!(ctx, self.repo);
@@ -228,7 +789,12 @@ impl MononokeRepo {
             .map({
                 cloned!(self.repo, self.skiplist_index);
                 move |(desc, anc)| {
-                    skiplist_index.query_reachability(ctx, repo.get_changeset_fetcher(), desc, anc)
+                    skiplist_index.query_reachability(
+                        ctx,
+                        repo.get_changeset_fetcher_for("reachability"),
+                        desc,
+                        anc,
+                    )
                 }
             })
             .flatten()
@@ -237,11 +803,398 @@ impl MononokeRepo {
             .boxify()
     }
 
+    /// Whether each ancestor is an ancestor of its descendant, answering all the pairs with one
+    /// walk of the commit graph rather than one walk per pair.
+    fn is_ancestor_batch(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        pairs: Vec<(Revision, Revision)>,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        if pairs.len() > MAX_IS_ANCESTOR_PAIRS {
+            return Err(ErrorKind::InvalidInput(
+                format!(
+                    "{} pairs requested, at most {} are allowed",
+                    pairs.len(),
+                    MAX_IS_ANCESTOR_PAIRS
+                ),
+                None,
+            ))
+            .into_future()
+            .boxify();
+        }
+
+        let bonsai = |revision: Revision| {
+            self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision.clone())
+                .from_err()
+                .and_then({
+                    cloned!(ctx, self.repo);
+                    move |hg_cs_id| repo.get_bonsai_from_hg(ctx, hg_cs_id).from_err()
+                })
+                .and_then(move |maybenode| {
+                    maybenode.ok_or(ErrorKind::NotFound(format!("{:?}", revision), None))
+                })
+        };
+        let resolved: Vec<_> = pairs
+            .iter()
+            .map(|(ancestor, descendant)| bonsai(descendant.clone()).join(bonsai(ancestor.clone())))
+            .collect();
+
+        join_all(resolved)
+            .and_then({
+                cloned!(self.repo, self.skiplist_index);
+                move |resolved| {
+                    skiplist_index
+                        .query_reachability_many(
+                            ctx,
+                            repo.get_changeset_fetcher_for("reachability"),
+                            resolved,
+                        )
+                        .from_err()
+                }
+            })
+            .map(move |answers| {
+                let name = |revision| match revision {
+                    Revision::CommitHash(name) | Revision::Bookmark(name) => name,
+                };
+                MononokeRepoResponse::IsAncestorBatch {
+                    answers: pairs
+                        .into_iter()
+                        .zip(answers)
+                        .map(|((ancestor, descendant), answer)| {
+                            Ancestry::new(name(ancestor), name(descendant), answer)
+                        })
+                        .collect(),
+                }
+            })
+            .boxify()
+    }
+
+    /// Whether the commit at `revision` is reachable from `bookmark`.
+    fn contains(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        bookmark: String,
+        revision: Revision,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let bookmark = try_boxfuture!(Bookmark::new(bookmark.clone())
+            .map_err(|err| ErrorKind::InvalidInput(bookmark, Some(err))));
+
+        let tip = view.get_bonsai_bookmark(&bookmark).from_err().and_then({
+            cloned!(bookmark);
+            move |maybe_tip| {
+                maybe_tip.ok_or_else(|| ErrorKind::BookmarkNotFound(bookmark.to_string()))
+            }
+        });
+
+        let cs_id = self
+            .get_hgchangesetid_from_revision(ctx.clone(), &view, revision.clone())
+            .from_err()
+            .and_then({
+                cloned!(ctx, self.repo);
+                move |hg_cs_id| repo.get_bonsai_from_hg(ctx, hg_cs_id).from_err()
+            })
+            .and_then(move |maybe_cs_id| {
+                maybe_cs_id.ok_or_else(|| ErrorKind::NotFound(revision.to_string(), None))
+            });
+
+        tip.join(cs_id)
+            .and_then({
+                cloned!(self.repo, self.skiplist_index, self.contains_cache);
+                move |(tip, cs_id)| {
+                    if tip == cs_id {
+                        return ok(true).left_future();
+                    }
+                    if let Some(answer) = contains_cache.get(&bookmark, tip, cs_id) {
+                        return ok(answer).left_future();
+                    }
+                    skiplist_index
+                        .is_ancestor(
+                            ctx,
+                            repo.get_changeset_fetcher_for("reachability"),
+                            cs_id,
+                            tip,
+                        )
+                        .map(move |answer| {
+                            contains_cache.insert(bookmark, tip, cs_id, answer);
+                            answer
+                        })
+                        .from_err()
+                        .right_future()
+                }
+            })
+            .map(|answer| MononokeRepoResponse::Contains { answer })
+            .boxify()
+    }
+
+    /// The common ancestor of `left` and `right` that is the closest to them, their merge base.
+    /// The skiplist index skips over the ancestors that only one of them has.
+    fn common_ancestor(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        left: Revision,
+        right: Revision,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let get_bonsai = |revision: Revision| {
+            self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision.clone())
+                .from_err()
+                .and_then({
+                    cloned!(ctx, self.repo);
+                    move |hg_cs_id| repo.get_bonsai_from_hg(ctx, hg_cs_id).from_err()
+                })
+                .and_then(move |maybenode| {
+                    maybenode.ok_or(ErrorKind::NotFound(format!("{:?}", revision), None))
+                })
+        };
+
+        get_bonsai(left.clone())
+            .join(get_bonsai(right.clone()))
+            .and_then({
+                cloned!(ctx, self.repo, self.skiplist_index);
+                move |(left, right)| {
+                    greatest_common_ancestor_with_hint(
+                        ctx,
+                        repo.get_changeset_fetcher_for("reachability"),
+                        skiplist_index,
+                        left,
+                        right,
+                    )
+                    .from_err()
+                }
+            })
+            .and_then({
+                cloned!(self.repo);
+                move |ancestor| match ancestor {
+                    Some(ancestor) => repo
+                        .get_hg_from_bonsai_changeset(ctx, ancestor)
+                        .map(|hg_cs_id| Some(hg_cs_id.to_string()))
+                        .from_err()
+                        .left_future(),
+                    None => Ok(None).into_future().right_future(),
+                }
+            })
+            .map(move |ancestor| {
+                let name = |revision| match revision {
+                    Revision::CommitHash(name) | Revision::Bookmark(name) => name,
+                };
+                MononokeRepoResponse::CommonAncestor {
+                    ancestor: CommonAncestor::new(name(left), name(right), ancestor),
+                }
+            })
+            .boxify()
+    }
+
+    /// The merge base of `left` and `right`, and the files that would conflict if they were
+    /// merged.
+    fn merge_conflicts(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        left: Revision,
+        right: Revision,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let get_bonsai = |revision: Revision| {
+            self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision.clone())
+                .from_err()
+                .and_then({
+                    cloned!(ctx, self.repo);
+                    move |hg_cs_id| repo.get_bonsai_from_hg(ctx, hg_cs_id).from_err()
+                })
+                .and_then(move |maybenode| {
+                    maybenode.ok_or(ErrorKind::NotFound(format!("{:?}", revision), None))
+                })
+        };
+
+        get_bonsai(left)
+            .join(get_bonsai(right))
+            .and_then({
+                cloned!(ctx, self.repo);
+                move |(left, right)| find_merge_conflicts(ctx, repo, left, right).from_err()
+            })
+            .and_then({
+                cloned!(self.repo);
+                move |result| {
+                    let merge_base = match result.merge_base {
+                        Some(merge_base) => repo
+                            .get_hg_from_bonsai_changeset(ctx, merge_base)
+                            .map(|hg_cs_id| Some(hg_cs_id.to_string()))
+                            .from_err()
+                            .left_future(),
+                        None => Ok(None).into_future().right_future(),
+                    };
+                    merge_base.map(move |merge_base| MononokeRepoResponse::MergeConflicts {
+                        report: MergeConflictReport {
+                            merge_base,
+                            conflicts: result.conflicts.into_iter().map(|c| c.into()).collect(),
+                        },
+                    })
+                }
+            })
+            .boxify()
+    }
+
+    /// The commit graph near `revision`: its ancestors with the highest generation numbers,
+    /// children first, labelled with their bookmarks and phases.
+    fn get_graph(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        revision: Revision,
+        limit: Option<usize>,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let limit = graph_limit(limit);
+
+        let nodes = self
+            .get_hgchangesetid_from_revision(ctx.clone(), &view, revision.clone())
+            .and_then({
+                cloned!(ctx, self.repo);
+                move |hg_cs_id| repo.get_bonsai_from_hg(ctx, hg_cs_id)
+            })
+            .from_err()
+            .and_then(move |maybenode| {
+                maybenode.ok_or(ErrorKind::NotFound(format!("{:?}", revision), None))
+            })
+            .and_then({
+                cloned!(ctx);
+                let fetcher = self.repo.get_changeset_fetcher();
+                move |cs_id| walk_graph(ctx, fetcher, cs_id, limit).from_err()
+            });
+
+        // Bookmarks and public heads come from the same snapshot, so that they agree
+        nodes
+            .join(view.get_bonsai_bookmarks().from_err())
+            .and_then({
+                cloned!(self.repo, self.phases);
+                move |(nodes, bookmarks)| {
+                    label_graph(ctx, repo, phases, nodes, bookmarks)
+                        .map(|nodes| MononokeRepoResponse::GetGraph { nodes })
+                        .from_err()
+                }
+            })
+            .boxify()
+    }
+
+    /// The changesets of `from::to`, the descendants of `from` that are ancestors of `to`, with
+    /// the highest generation numbers first and changesets of the same generation by id. Empty
+    /// if `from` isn't an ancestor of `to`. The whole range is walked even if only its first
+    /// `limit` changesets are returned, so ranges that span too many generations are refused.
+    fn range(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        from: Revision,
+        to: Revision,
+        limit: Option<usize>,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let limit = cmp::min(
+            limit.unwrap_or(DEFAULT_RANGE_CHANGESETS),
+            MAX_RANGE_CHANGESETS,
+        );
+        let fetcher = self.repo.get_changeset_fetcher();
+        let get_bonsai = |revision: Revision| {
+            self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision.clone())
+                .from_err()
+                .and_then({
+                    cloned!(ctx, self.repo);
+                    move |hg_cs_id| repo.get_bonsai_from_hg(ctx, hg_cs_id).from_err()
+                })
+                .and_then(move |maybenode| {
+                    maybenode.ok_or(ErrorKind::NotFound(format!("{:?}", revision), None))
+                })
+        };
+        let get_generation = {
+            cloned!(ctx, fetcher);
+            move |cs_id| {
+                fetcher
+                    .get_generation_number(ctx.clone(), cs_id)
+                    .map(move |generation| (generation, cs_id))
+            }
+        };
+
+        get_bonsai(from)
+            .join(get_bonsai(to))
+            .and_then({
+                cloned!(get_generation);
+                move |(from, to)| {
+                    get_generation(from)
+                        .join(get_generation(to))
+                        .from_err()
+                        .and_then(|((from_gen, from), (to_gen, to))| {
+                            let span = to_gen.value().saturating_sub(from_gen.value());
+                            if span > MAX_RANGE_GENERATIONS {
+                                Err(ErrorKind::InvalidInput(
+                                    format!(
+                                        "range spans {} generations, at most {} are allowed",
+                                        span, MAX_RANGE_GENERATIONS
+                                    ),
+                                    None,
+                                ))
+                            } else {
+                                Ok((from, to))
+                            }
+                        })
+                }
+            })
+            .and_then({
+                cloned!(ctx);
+                move |(from, to)| {
+                    // Generations come out highest first, but in no order within each, so whole
+                    // generations are taken to pick their first changesets by id
+                    let mut taken = 0;
+                    let mut last_gen = None;
+                    RangeNodeStream::new(ctx, fetcher.clone(), from, to)
+                        .and_then(get_generation)
+                        .take_while(move |(generation, _)| {
+                            let take = taken < limit || last_gen == Some(*generation);
+                            taken += 1;
+                            last_gen = Some(*generation);
+                            Ok(take)
+                        })
+                        .collect()
+                        .from_err()
+                }
+            })
+            .and_then({
+                cloned!(self.repo);
+                move |mut changesets| {
+                    changesets.sort_by(|(gen1, cs_id1), (gen2, cs_id2)| {
+                        gen2.cmp(gen1).then(cs_id1.cmp(cs_id2))
+                    });
+                    changesets.truncate(limit);
+                    let cs_ids: Vec<_> = changesets.iter().map(|(_, cs_id)| *cs_id).collect();
+                    repo.get_hg_bonsai_mapping(ctx, cs_ids)
+                        .and_then(move |mapping| {
+                            let mut hg_cs_ids: HashMap<_, _> = mapping
+                                .into_iter()
+                                .map(|(hg_cs_id, cs_id)| (cs_id, hg_cs_id.to_string()))
+                                .collect();
+                            changesets
+                                .into_iter()
+                                .map(|(generation, cs_id)| {
+                                    Ok(RangeChangeset {
+                                        hash: hg_cs_ids.remove(&cs_id).ok_or_else(|| {
+                                            format_err!("no mercurial changeset for {}", cs_id)
+                                        })?,
+                                        generation: generation.value(),
+                                    })
+                                })
+                                .collect::<Result<Vec<_>, Error>>()
+                        })
+                        .from_err()
+                }
+            })
+            .map(|changesets| MononokeRepoResponse::Range { changesets })
+            .boxify()
+    }
+
     fn get_blob_content(
         &self,
         ctx: CoreContext,
         hash: String,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        try_boxfuture!(check_hash_access(&self.path_access, &hash));
         let blobhash = try_boxfuture!(FS::get_nodehash(&hash));
 
         self.repo
@@ -250,38 +1203,384 @@ impl MononokeRepo {
                 FileContents::Bytes(content) => {
                     Ok(MononokeRepoResponse::GetBlobContent { content })
                 }
+                FileContents::Tombstone(tombstone) => Err(ErrorKind::ContentTombstoned(
+                    hash,
+                    tombstone.reason().to_string(),
+                )
+                .into()),
             })
             .from_err()
             .boxify()
     }
 
+    /// Serves the blobs of this repo to the servers that pull through from it
+    fn get_raw_blob(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        try_boxfuture!(check_hash_access(&self.path_access, &key));
+
+        self.repo
+            .get_blobstore()
+            .get(ctx, key.clone())
+            .from_err()
+            .and_then(move |blob| match blob {
+                Some(blob) => Ok(MononokeRepoResponse::GetRawBlob {
+                    content: blob.into_bytes(),
+                }),
+                None => Err(ErrorKind::NotFound(key, None)),
+            })
+            .boxify()
+    }
+
+    /// Serves the bookmarks of this repo, by the bonsai changesets they point to, to the servers
+    /// that pull through from it
+    fn get_raw_bookmarks(&self, ctx: CoreContext) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        self.repo
+            .get_bonsai_bookmarks(ctx)
+            .map(|(bookmark, cs_id)| (bookmark.to_string(), cs_id.to_hex().to_string()))
+            .collect()
+            .map(|bookmarks| MononokeRepoResponse::GetRawBookmarks {
+                bookmarks: bookmarks.into_iter().collect(),
+            })
+            .from_err()
+            .boxify()
+    }
+
+    /// Serves what the SQL stores of this repo have about a changeset to the servers that pull
+    /// through from it
+    fn get_raw_changeset(
+        &self,
+        ctx: CoreContext,
+        changeset: String,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let cs_id = try_boxfuture!(FS::get_bonsai_changeset_id(changeset.clone()));
+        let repo = self.repo.clone();
+
+        self.repo
+            .get_generation_number_by_bonsai(ctx.clone(), cs_id)
+            .and_then(move |generation| match generation {
+                Some(generation) => repo
+                    .get_changeset_parents_by_bonsai(ctx.clone(), cs_id)
+                    .join(repo.get_hg_from_bonsai_changeset(ctx, cs_id))
+                    .map(move |(parents, hg_cs_id)| {
+                        Some(MononokeRepoResponse::GetRawChangeset {
+                            changeset: RawChangeset {
+                                hg: hg_cs_id.to_hex().to_string(),
+                                parents: parents
+                                    .into_iter()
+                                    .map(|parent| parent.to_hex().to_string())
+                                    .collect(),
+                                generation: generation.value(),
+                            },
+                        })
+                    })
+                    .left_future(),
+                None => ok(None).right_future(),
+            })
+            .from_err()
+            .and_then(move |response| response.ok_or(ErrorKind::NotFound(changeset, None)))
+            .boxify()
+    }
+
+    fn multi_get(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        revision: Revision,
+        paths: Vec<String>,
+        content_ids: Vec<String>,
+        max_bytes: Option<u64>,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let keys = paths.len() + content_ids.len();
+        if keys > MAX_MULTI_GET_KEYS {
+            return Err(ErrorKind::InvalidInput(
+                format!(
+                    "{} files requested, at most {} are allowed",
+                    keys, MAX_MULTI_GET_KEYS
+                ),
+                None,
+            ))
+            .into_future()
+            .boxify();
+        }
+        let max_bytes = cmp::min(
+            max_bytes.unwrap_or(MAX_MULTI_GET_BYTES),
+            MAX_MULTI_GET_BYTES,
+        );
+        let concurrency = cmp::max(
+            self.overrides
+                .get_or(MULTI_GET_CONCURRENCY_OVERRIDE, MULTI_GET_CONCURRENCY),
+            1,
+        );
+
+        let repo = self.repo.clone();
+        let path_access = self.path_access.clone();
+        self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision)
+            .and_then({
+                cloned!(ctx, repo);
+                move |changesetid| repo.get_changeset_by_changesetid(ctx, changesetid)
+            })
+            .map(move |changeset| {
+                let manifestid = changeset.manifestid();
+                // Set once a file doesn't fit in the budget. Files are fetched lazily, so none of
+                // the following ones is loaded from the blobstore after that.
+                let over_budget = Arc::new(AtomicBool::new(false));
+
+                // Failing to fetch one file doesn't fail the request, the error is sent in place
+                // of the file instead.
+                let by_path = paths.into_iter().map({
+                    cloned!(ctx, repo, path_access, over_budget);
+                    move |path| {
+                        if over_budget.load(Ordering::Relaxed) {
+                            return ok((path, Ok(None))).boxify();
+                        }
+                        cloned!(ctx, repo, over_budget);
+                        FS::get_mpath(path.clone())
+                            .and_then(|mpath| {
+                                check_path_access(&path_access, &mpath)?;
+                                Ok(mpath)
+                            })
+                            .into_future()
+                            .from_err()
+                            .and_then({
+                                cloned!(ctx, repo);
+                                move |mpath| {
+                                    repo.find_file_in_manifest(ctx, &mpath, manifestid)
+                                        .and_then(move |maybe_file| {
+                                            maybe_file.ok_or_else(|| {
+                                                ErrorKind::NotFound(mpath.to_string(), None).into()
+                                            })
+                                        })
+                                }
+                            })
+                            .and_then({
+                                cloned!(ctx, repo);
+                                move |(file_type, filenode)| {
+                                    repo.get_file_content_id(ctx, filenode)
+                                        .map(move |content_id| (Some(file_type), content_id))
+                                }
+                            })
+                            .and_then(move |(file_type, content_id)| {
+                                if over_budget.load(Ordering::Relaxed) {
+                                    return ok(None).left_future();
+                                }
+                                repo.get_file_content_by_content_id(ctx, content_id)
+                                    .map(move |content| Some((file_type, content_id, content)))
+                                    .right_future()
+                            })
+                            .then(move |result| Ok::<_, Error>((path, result)))
+                            .boxify()
+                    }
+                });
+
+                let by_content_id = content_ids.into_iter().map({
+                    cloned!(over_budget);
+                    move |key| {
+                        if over_budget.load(Ordering::Relaxed) {
+                            return ok((key, Ok(None))).boxify();
+                        }
+                        cloned!(ctx, repo);
+                        check_hash_access(&path_access, &key)
+                            .map_err(Error::from)
+                            .and_then(|()| ContentId::from_str(&key))
+                            .into_future()
+                            .and_then(move |content_id| {
+                                repo.get_file_content_by_content_id(ctx, content_id)
+                                    .map(move |content| Some((None, content_id, content)))
+                            })
+                            .then(move |result| Ok::<_, Error>((key, result)))
+                            .boxify()
+                    }
+                });
+
+                let mut sent_bytes = 0;
+                stream::iter_ok(by_path.chain(by_content_id))
+                    .buffered(concurrency)
+                    .map(move |(key, result)| {
+                        if over_budget.load(Ordering::Relaxed) {
+                            return MultiGetEntry::over_budget(key);
+                        }
+                        match result {
+                            Ok(Some((file_type, content_id, FileContents::Bytes(content)))) => {
+                                let size = content.len() as u64;
+                                if sent_bytes + size > max_bytes {
+                                    over_budget.store(true, Ordering::Relaxed);
+                                    MultiGetEntry::over_budget(key)
+                                } else {
+                                    sent_bytes += size;
+                                    let ttype = file_type.map(Type::File);
+                                    MultiGetEntry::file(key, content_id, ttype, &content)
+                                }
+                            }
+                            Ok(Some((_, _, FileContents::Tombstone(tombstone)))) => {
+                                let reason = tombstone.reason().to_string();
+                                let error = ErrorKind::ContentTombstoned(key.clone(), reason);
+                                MultiGetEntry::error(key, error)
+                            }
+                            // Only skipped files have no contents, and those come after the
+                            // budget was reached
+                            Ok(None) => MultiGetEntry::over_budget(key),
+                            Err(error) => MultiGetEntry::error(key, error),
+                        }
+                    })
+                    .and_then(|entry| Ok(Bytes::from(rmp_serde::to_vec_named(&entry)?)))
+                    .from_err()
+                    .boxify()
+            })
+            .map(|entries| MononokeRepoResponse::MultiGet { entries })
+            .from_err()
+            .boxify()
+    }
+
     fn list_directory(
         &self,
         ctx: CoreContext,
+        view: RepoView,
         revision: Revision,
         path: String,
+        options: ListDirectoryOptions,
+        page: Option<PageRequest>,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let page = match page {
+            Some(page) => Some((try_boxfuture!(page.token()), try_boxfuture!(page.limit()))),
+            None => None,
+        };
+        // Later pages list the changeset the first one was listed at
+        let revision = match page {
+            Some((Some(ref token), _)) => token
+                .snapshot
+                .clone()
+                .map_or(revision, Revision::CommitHash),
+            _ => revision,
+        };
+
         let mpath = if path.is_empty() {
             None
         } else {
-            Some(try_boxfuture!(FS::get_mpath(path.clone())))
+            let mpath = try_boxfuture!(FS::get_mpath(path.clone()));
+            try_boxfuture!(check_path_access(&self.path_access, &mpath));
+            Some(mpath)
         };
 
         let repo = self.repo.clone();
-        self.get_hgchangesetid_from_revision(ctx.clone(), revision)
-            .and_then(move |changesetid| {
-                mononoke_api::get_content_by_path(ctx, repo, changesetid, mpath)
+        let path_access = self.path_access.clone();
+        self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision)
+            .and_then({
+                cloned!(ctx);
+                move |changesetid| {
+                    let content = if options.follow_symlinks {
+                        get_content_by_path_following_symlinks(
+                            ctx.clone(),
+                            repo.clone(),
+                            changesetid,
+                            mpath,
+                        )
+                        .left_future()
+                    } else {
+                        mononoke_api::get_content_by_path(
+                            ctx.clone(),
+                            repo.clone(),
+                            changesetid,
+                            mpath.clone(),
+                        )
+                        .map(move |content| (mpath, content))
+                        .right_future()
+                    };
+
+                    let submodules = if options.submodules {
+                        get_submodules(ctx, repo, changesetid).left_future()
+                    } else {
+                        ok(BTreeMap::new()).right_future()
+                    };
+
+                    content
+                        .join(submodules)
+                        .map(move |(content, submodules)| (changesetid, content, submodules))
+                }
             })
-            .and_then(move |content| match content {
-                Content::Tree(tree) => Ok(tree),
-                _ => Err(ErrorKind::NotADirectory(path.to_string()).into()),
+            .and_then({
+                cloned!(path_access);
+                move |(changesetid, (dir, content), submodules)| {
+                    // Symlinks may have led somewhere else than where the client asked for
+                    if let Some(ref dir) = dir {
+                        check_path_access(&path_access, dir)?;
+                    }
+                    match content {
+                        Content::Tree(tree) => Ok((changesetid, dir, tree, submodules)),
+                        _ => Err(ErrorKind::NotADirectory(path.to_string()).into()),
+                    }
+                }
             })
-            .map(|tree| {
-                tree.list()
-                    .filter_map(|entry| -> Option<Entry> { entry.try_into().ok() })
+            .and_then(move |(changesetid, dir, tree, submodules)| {
+                // Entries that can't be read are left out of the listing
+                let entries = tree.list().filter_map({
+                    cloned!(dir, path_access);
+                    move |entry| {
+                        let path = MPath::join_element_opt(dir.as_ref(), entry.get_name());
+                        if !path_access.can_read(path.as_ref()) {
+                            return None;
+                        }
+                        let is_symlink = entry.get_type() == Type::File(FileType::Symlink);
+                        let content = if options.symlink_targets && is_symlink {
+                            Some(entry.get_content(ctx.clone()))
+                        } else {
+                            None
+                        };
+                        let entry: Entry = entry.try_into().ok()?;
+
+                        Some(match content {
+                            Some(content) => content
+                                .map(|content| match content {
+                                    Content::Symlink(target) => {
+                                        entry.with_symlink_target(target.as_bytes())
+                                    }
+                                    _ => entry,
+                                })
+                                .left_future(),
+                            None => ok(entry).right_future(),
+                        })
+                    }
+                });
+
+                let submodules: Vec<_> = submodules
+                    .into_iter()
+                    .filter_map(move |(path, commit)| {
+                        if !path_access.can_read(Some(&path)) {
+                            return None;
+                        }
+                        let (parent, name) = path.split_dirname();
+                        if parent != dir {
+                            return None;
+                        }
+                        let name = String::from_utf8(name.to_bytes()).ok()?;
+                        Some(Entry::submodule(name, commit))
+                    })
+                    .collect();
+
+                join_all(entries).map(move |mut entries| {
+                    entries.extend(submodules);
+                    entries.sort_by(|a, b| a.name().cmp(b.name()));
+                    (changesetid, entries)
+                })
             })
-            .map(|files| MononokeRepoResponse::ListDirectory {
-                files: Box::new(files),
+            .map(move |(changesetid, files)| {
+                let (files, next) = match page {
+                    Some((token, limit)) => {
+                        let after = token.as_ref().map(|token| token.position.as_str());
+                        let (files, last) = paging::page(files, Entry::name, after, limit);
+                        let next = last.map(|last| {
+                            PageToken::new(Some(changesetid.to_string()), last).to_string()
+                        });
+                        (files, next)
+                    }
+                    None => (files, None),
+                };
+                MononokeRepoResponse::ListDirectory {
+                    files: Box::new(files.into_iter()),
+                    next,
+                }
             })
             .from_err()
             .boxify()
@@ -292,6 +1591,7 @@ impl MononokeRepo {
         ctx: CoreContext,
         hash: String,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        try_boxfuture!(check_hash_access(&self.path_access, &hash));
         let treehash = try_boxfuture!(FS::get_nodehash(&hash));
         let treemanifestid = HgManifestId::new(treehash);
         let repoid = self.repo.get_repoid();
@@ -299,7 +1599,7 @@ impl MononokeRepo {
         self.repo
             .get_manifest_by_nodeid(ctx.clone(), treemanifestid)
             .map({
-                cloned!(self.sha1_cache);
+                cloned!(self.sha1_cache, self.cache_metrics);
                 move |tree| {
                     join_all(tree.list().map(move |entry| {
                         EntryWithSizeAndContentHash::materialize_future(
@@ -307,6 +1607,8 @@ impl MononokeRepo {
                             repoid.clone(),
                             entry,
                             sha1_cache.clone(),
+                            cache_metrics.clone(),
+                            "get_tree",
                         )
                     }))
                 }
@@ -320,24 +1622,197 @@ impl MononokeRepo {
     fn get_changeset(
         &self,
         ctx: CoreContext,
+        view: RepoView,
         revision: Revision,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
         let repo = self.repo.clone();
-        self.get_hgchangesetid_from_revision(ctx.clone(), revision)
+        let text_decoding = self.text_decoding;
+        self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision)
             .and_then(move |changesetid| repo.get_changeset_by_changesetid(ctx, changesetid))
-            .and_then(|changeset| changeset.try_into().map_err(From::from))
+            .and_then(move |changeset| super::model::Changeset::new(changeset, text_decoding))
             .map(|changeset| MononokeRepoResponse::GetChangeset { changeset })
             .from_err()
             .boxify()
     }
 
-    fn get_branches(&self, ctx: CoreContext) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+    fn get_branches(
+        &self,
+        ctx: CoreContext,
+        page: Option<PageRequest>,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let page = match page {
+            Some(page) => Some((try_boxfuture!(page.token()), try_boxfuture!(page.limit()))),
+            None => None,
+        };
+
         self.repo
             .get_bookmarks_maybe_stale(ctx)
             .map(|(bookmark, changesetid)| (bookmark.to_string(), changesetid.to_hex().to_string()))
             .collect()
-            .map(|vec| MononokeRepoResponse::GetBranches {
-                branches: vec.into_iter().collect(),
+            .map(move |vec| {
+                let branches: BTreeMap<_, _> = vec.into_iter().collect();
+                match page {
+                    // Past states of bookmarks aren't kept, so pages have no snapshot to be
+                    // listed from: every page lists the bookmarks as they are when it is asked for
+                    Some((token, limit)) => {
+                        let after = token.as_ref().map(|token| token.position.as_str());
+                        let (branches, last) =
+                            paging::page(branches, |(bookmark, _)| bookmark.as_str(), after, limit);
+                        MononokeRepoResponse::GetBranches {
+                            branches: branches.into_iter().collect(),
+                            next: last.map(|last| PageToken::new(None, last).to_string()),
+                        }
+                    }
+                    None => MononokeRepoResponse::GetBranches {
+                        branches,
+                        next: None,
+                    },
+                }
+            })
+            .from_err()
+            .boxify()
+    }
+
+    fn get_status(&self, ctx: CoreContext) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let now = DateTime::new(ctx.now());
+        self.maintenance_store
+            .get_active(ctx, self.repo.get_repoid(), now)
+            .map({
+                cloned!(self.overrides);
+                move |maybe_window| MononokeRepoResponse::GetStatus {
+                    status: RepoStatus {
+                        maintenance: maybe_window.map(From::from),
+                        overrides: overrides.get_all(),
+                    },
+                }
+            })
+            .from_err()
+            .boxify()
+    }
+
+    fn get_health(&self, ctx: CoreContext) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        self.health_stores
+            .report(ctx)
+            .map(|health| MononokeRepoResponse::GetHealth { health })
+            .from_err()
+            .boxify()
+    }
+
+    fn get_replicas(&self) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let stores = match self.replica_manager {
+            Some(ref replica_manager) => replica_manager
+                .topology()
+                .into_iter()
+                .map(|(store, replicas)| (store, replicas.into_iter().map(Replica::from).collect()))
+                .collect(),
+            None => BTreeMap::new(),
+        };
+        ok(MononokeRepoResponse::GetReplicas { stores }).boxify()
+    }
+
+    fn get_push_usage(&self, ctx: CoreContext) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        self.push_quota
+            .report(ctx)
+            .map(|report| MononokeRepoResponse::GetPushUsage {
+                report: report.into(),
+            })
+            .from_err()
+            .boxify()
+    }
+
+    fn get_sizes(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        revision: Revision,
+        paths: Vec<String>,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        if paths.len() > MAX_SIZES_PATHS {
+            return Err(ErrorKind::InvalidInput(
+                format!(
+                    "{} paths requested, at most {} are allowed",
+                    paths.len(),
+                    MAX_SIZES_PATHS
+                ),
+                None,
+            ))
+            .into_future()
+            .boxify();
+        }
+
+        let mpaths: Vec<_> = try_boxfuture!(paths
+            .iter()
+            .map(|path| {
+                if path.is_empty() {
+                    Ok(None)
+                } else {
+                    let mpath = FS::get_mpath(path.clone())?;
+                    check_path_access(&self.path_access, &mpath)?;
+                    Ok(Some(mpath))
+                }
+            })
+            .collect::<Result<_, _>>());
+
+        let repo = self.repo.clone();
+        self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision)
+            .and_then(move |changesetid| {
+                join_all(mpaths.into_iter().map(move |mpath| {
+                    get_path_summary(ctx.clone(), repo.clone(), changesetid, mpath)
+                }))
+            })
+            .map(move |summaries| MononokeRepoResponse::GetSizes {
+                sizes: paths
+                    .into_iter()
+                    .zip(summaries)
+                    .map(|(path, summary)| PathSize::new(path, summary))
+                    .collect(),
+            })
+            .from_err()
+            .boxify()
+    }
+
+    fn paths_exist(
+        &self,
+        ctx: CoreContext,
+        view: RepoView,
+        revision: Revision,
+        paths: Vec<String>,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        if paths.len() > MAX_EXISTS_PATHS {
+            return Err(ErrorKind::InvalidInput(
+                format!(
+                    "{} paths requested, at most {} are allowed",
+                    paths.len(),
+                    MAX_EXISTS_PATHS
+                ),
+                None,
+            ))
+            .into_future()
+            .boxify();
+        }
+
+        let mpaths: Vec<_> = try_boxfuture!(paths
+            .iter()
+            .map(|path| {
+                if path.is_empty() {
+                    Ok(None)
+                } else {
+                    let mpath = FS::get_mpath(path.clone())?;
+                    check_path_access(&self.path_access, &mpath)?;
+                    Ok(Some(mpath))
+                }
+            })
+            .collect::<Result<_, _>>());
+
+        let repo = self.repo.clone();
+        self.get_hgchangesetid_from_revision(ctx.clone(), &view, revision)
+            .and_then(move |changesetid| get_path_entries(ctx, repo, changesetid, mpaths))
+            .map(move |entries| MononokeRepoResponse::PathsExist {
+                paths: paths
+                    .into_iter()
+                    .zip(entries)
+                    .map(|(path, entry)| PathExistence::new(path, entry))
+                    .collect(),
             })
             .from_err()
             .boxify()
@@ -348,13 +1823,18 @@ impl MononokeRepo {
         ctx: CoreContext,
         oid: String,
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
-        let sha256_oid = try_boxfuture!(FS::get_sha256_oid(oid));
+        try_boxfuture!(check_hash_access(&self.path_access, &oid));
+        let sha256_oid = try_boxfuture!(FS::get_sha256_oid(oid.clone()));
 
+        // The file is sent in the chunks the blobstore reads it in
         self.repo
-            .get_file_content_by_alias(ctx, sha256_oid)
+            .get_file_content_stream_by_alias(ctx, sha256_oid)
             .and_then(move |content| match content {
-                FileContents::Bytes(content) => {
-                    Ok(MononokeRepoResponse::DownloadLargeFile { content })
+                FileContentsStream::Bytes(content) => Ok(MononokeRepoResponse::DownloadLargeFile {
+                    content: content.from_err().boxify(),
+                }),
+                FileContentsStream::Tombstone(tombstone) => {
+                    Err(ErrorKind::ContentTombstoned(oid, tombstone.reason().to_string()).into())
                 }
             })
             .from_err()
@@ -404,6 +1884,33 @@ impl MononokeRepo {
             .boxify()
     }
 
+    /// Serve `msg` from the pool of its class. The apiserver doesn't know who sends requests, so
+    /// only the class they declare (`qos`) decides it.
+    pub fn send_query_with_qos(
+        &self,
+        ctx: CoreContext,
+        qos: Option<&str>,
+        msg: MononokeRepoQuery,
+    ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
+        let pool = self.qos.pool(self.qos.classify(qos, iter::empty()));
+        // Errors of the query are kept as they are, only the ones of the pool are converted
+        let query = pool.run(self.send_query(ctx, msg).then(|res| Ok(res)));
+        let query = match pool.timeout() {
+            Some(timeout) => query
+                .timeout(timeout)
+                .map_err(move |err| {
+                    if err.is_elapsed() {
+                        return format_err!("request took longer than {:?}", timeout);
+                    }
+                    err.into_inner()
+                        .unwrap_or_else(|| err_msg("timer failed while serving request"))
+                })
+                .boxify(),
+            None => query,
+        };
+        query.map_err(ErrorKind::from).and_then(|res| res).boxify()
+    }
+
     pub fn send_query(
         &self,
         ctx: CoreContext,
@@ -411,23 +1918,60 @@ impl MononokeRepo {
     ) -> BoxFuture<MononokeRepoResponse, ErrorKind> {
         use crate::MononokeRepoQuery::*;
 
+        // All revisions of one request are resolved against the same bookmarks.
+        let view = RepoView::new(ctx.clone(), self.repo.clone());
+
         match msg {
-            GetRawFile { revision, path } => self.get_raw_file(ctx, revision, path),
+            GetRawFile { revision, path } => self.get_raw_file(ctx, view, revision, path),
+            GetRawFileChunk {
+                revision,
+                path,
+                offset,
+                max_size,
+            } => self.get_raw_file_chunk(ctx, view, revision, path, offset, max_size),
             GetHgFile { filenode } => self.get_hg_file(ctx, filenode),
             GetFileHistory {
                 filenode,
                 path,
                 depth,
-            } => self.get_file_history(ctx, filenode, path, depth),
+                limit,
+                cursor,
+            } => self.get_file_history(ctx, filenode, path, depth, limit, cursor),
             GetBlobContent { hash } => self.get_blob_content(ctx, hash),
-            ListDirectory { revision, path } => self.list_directory(ctx, revision, path),
+            GetRawBlob { key } => self.get_raw_blob(ctx, key),
+            GetRawBookmarks => self.get_raw_bookmarks(ctx),
+            GetRawChangeset { changeset } => self.get_raw_changeset(ctx, changeset),
+            MultiGet {
+                revision,
+                paths,
+                content_ids,
+                max_bytes,
+            } => self.multi_get(ctx, view, revision, paths, content_ids, max_bytes),
+            ListDirectory {
+                revision,
+                path,
+                options,
+                page,
+            } => self.list_directory(ctx, view, revision, path, options, page),
             GetTree { hash } => self.get_tree(ctx, hash),
-            GetChangeset { revision } => self.get_changeset(ctx, revision),
-            GetBranches => self.get_branches(ctx),
+            GetChangeset { revision } => self.get_changeset(ctx, view, revision),
+            GetBranches { page } => self.get_branches(ctx, page),
+            GetStatus => self.get_status(ctx),
+            GetHealth => self.get_health(ctx),
+            GetReplicas => self.get_replicas(),
+            GetPushUsage => self.get_push_usage(ctx),
+            GetSizes { revision, paths } => self.get_sizes(ctx, view, revision, paths),
+            PathsExist { revision, paths } => self.paths_exist(ctx, view, revision, paths),
             IsAncestor {
                 ancestor,
                 descendant,
-            } => self.is_ancestor(ctx, ancestor, descendant),
+            } => self.is_ancestor(ctx, view, ancestor, descendant),
+            IsAncestorBatch { pairs } => self.is_ancestor_batch(ctx, view, pairs),
+            CommonAncestor { left, right } => self.common_ancestor(ctx, view, left, right),
+            Contains { bookmark, revision } => self.contains(ctx, view, bookmark, revision),
+            MergeConflicts { left, right } => self.merge_conflicts(ctx, view, left, right),
+            GetGraph { revision, limit } => self.get_graph(ctx, view, revision, limit),
+            Range { from, to, limit } => self.range(ctx, view, from, to, limit),
 
             DownloadLargeFile { oid } => self.download_large_file(ctx, oid),
             LfsBatch {
@@ -439,3 +1983,186 @@ impl MononokeRepo {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use fixtures::{linear, merge_even};
+    use tokio::runtime::Runtime;
+
+    use super::super::model::GraphPhase;
+
+    fn bonsai(rt: &mut Runtime, repo: &BlobRepo, hg_cs_id: &str) -> ChangesetId {
+        let hg_cs_id = HgChangesetId::from_str(hg_cs_id).unwrap();
+        rt.block_on(repo.get_bonsai_from_hg(CoreContext::test_mock(), hg_cs_id))
+            .unwrap()
+            .unwrap()
+    }
+
+    fn walk(
+        rt: &mut Runtime,
+        repo: &BlobRepo,
+        start: ChangesetId,
+        limit: usize,
+    ) -> Vec<(ChangesetId, Vec<ChangesetId>, Generation)> {
+        let ctx = CoreContext::test_mock();
+        rt.block_on(walk_graph(ctx, repo.get_changeset_fetcher(), start, limit))
+            .unwrap()
+    }
+
+    #[test]
+    fn chunk_ranges() {
+        assert_eq!(chunk_range(0, Some(4), 10).unwrap(), (0, 4));
+        assert_eq!(chunk_range(8, Some(4), 10).unwrap(), (8, 10));
+        assert_eq!(chunk_range(10, None, 10).unwrap(), (10, 10));
+        let file_size = 2 * MAX_RAW_CHUNK_SIZE;
+        assert_eq!(
+            chunk_range(0, None, file_size).unwrap(),
+            (0, MAX_RAW_CHUNK_SIZE)
+        );
+        assert!(chunk_range(11, None, 10).is_err());
+        assert!(chunk_range(-1, None, 10).is_err());
+        assert!(chunk_range(0, Some(0), 10).is_err());
+    }
+
+    #[test]
+    fn read_ranges() {
+        let read = |start, end| {
+            let chunks = vec![Bytes::from("0123"), Bytes::from("4567"), Bytes::from("89")];
+            read_range(stream::iter_ok(chunks).boxify(), start, end)
+                .wait()
+                .unwrap()
+        };
+        assert_eq!(read(0, 10), Bytes::from("0123456789"));
+        assert_eq!(read(2, 6), Bytes::from("2345"));
+        assert_eq!(read(4, 8), Bytes::from("4567"));
+        assert_eq!(read(10, 10), Bytes::new());
+
+        // The content past the range isn't read
+        let chunks = vec![
+            Ok(Bytes::from("0123")),
+            Ok(Bytes::from("4567")),
+            Err(err_msg("read past the range")),
+        ];
+        let range = read_range(stream::iter_result(chunks).boxify(), 0, 4).wait();
+        assert_eq!(range.unwrap(), Bytes::from("0123"));
+    }
+
+    #[test]
+    fn graph_limit_is_capped() {
+        assert_eq!(graph_limit(None), DEFAULT_GRAPH_NODES);
+        assert_eq!(graph_limit(Some(10)), 10);
+        assert_eq!(graph_limit(Some(MAX_GRAPH_NODES + 1)), MAX_GRAPH_NODES);
+    }
+
+    #[test]
+    fn walk_linear_graph() {
+        let mut rt = Runtime::new().unwrap();
+        let repo = linear::getrepo(None);
+        let head = bonsai(&mut rt, &repo, "79a13814c5ce7330173ec04d279bf95ab3f652fb");
+        let parent = bonsai(&mut rt, &repo, "a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        let grandparent = bonsai(&mut rt, &repo, "3c15267ebf11807f3d772eb891272b911ec68759");
+        let great_grandparent = bonsai(&mut rt, &repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157");
+
+        assert_eq!(
+            walk(&mut rt, &repo, head, 3),
+            vec![
+                (head, vec![parent], Generation::new(11)),
+                (parent, vec![grandparent], Generation::new(10)),
+                (grandparent, vec![great_grandparent], Generation::new(9)),
+            ]
+        );
+        assert_eq!(walk(&mut rt, &repo, head, MAX_GRAPH_NODES).len(), 11);
+    }
+
+    #[test]
+    fn walk_merge_graph() {
+        let mut rt = Runtime::new().unwrap();
+        let repo = merge_even::getrepo(None);
+        let merge = bonsai(&mut rt, &repo, "6120679e1fedb0b2f3717bbf042e5fd718763042");
+        let root = bonsai(&mut rt, &repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c");
+
+        let nodes = walk(&mut rt, &repo, merge, MAX_GRAPH_NODES);
+        // The ancestors shared by both sides of the merge are only listed once
+        assert_eq!(nodes.len(), 8);
+        let cs_ids: HashSet<_> = nodes.iter().map(|(cs_id, _, _)| *cs_id).collect();
+        assert_eq!(cs_ids.len(), 8);
+        assert_eq!(nodes[0].0, merge);
+        assert_eq!(nodes[0].1.len(), 2);
+        assert_eq!(nodes[7], (root, vec![], Generation::new(1)));
+        // Children come before their parents
+        assert!(nodes.windows(2).all(|pair| pair[0].2 >= pair[1].2));
+
+        // Both sides are walked down to the same generation
+        let generations: Vec<_> = walk(&mut rt, &repo, merge, 5)
+            .into_iter()
+            .map(|(_, _, generation)| generation.value())
+            .collect();
+        assert_eq!(generations, vec![5, 4, 4, 3, 3]);
+    }
+
+    #[test]
+    fn graph_labels() {
+        let mut rt = Runtime::new().unwrap();
+        let ctx = CoreContext::test_mock();
+        let repo = linear::getrepo(None);
+        let phases: Arc<Phases> = Arc::new(HintPhases::new(
+            Arc::new(SqlPhases::with_sqlite_in_memory().unwrap()),
+            Arc::new(SkiplistIndex::new()),
+        ));
+        let head = bonsai(&mut rt, &repo, "79a13814c5ce7330173ec04d279bf95ab3f652fb");
+        let release = bonsai(&mut rt, &repo, "3c15267ebf11807f3d772eb891272b911ec68759");
+        let bookmarks = vec![
+            (Bookmark::new("release").unwrap(), release),
+            (Bookmark::new("stable").unwrap(), release),
+        ]
+        .into_iter()
+        .collect();
+
+        let nodes = walk(&mut rt, &repo, head, 4);
+        let labelled = rt
+            .block_on(label_graph(ctx, repo, phases, nodes, bookmarks))
+            .unwrap();
+
+        let labels: Vec<_> = labelled
+            .into_iter()
+            .map(|node| {
+                let mut bookmarks = node.bookmarks;
+                bookmarks.sort();
+                (node.hash, node.parents, bookmarks, node.phase)
+            })
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                (
+                    "79a13814c5ce7330173ec04d279bf95ab3f652fb".to_string(),
+                    vec!["a5ffa77602a066db7d5cfb9fb5823a0895717c5a".to_string()],
+                    vec![],
+                    GraphPhase::Draft,
+                ),
+                (
+                    "a5ffa77602a066db7d5cfb9fb5823a0895717c5a".to_string(),
+                    vec!["3c15267ebf11807f3d772eb891272b911ec68759".to_string()],
+                    vec![],
+                    GraphPhase::Draft,
+                ),
+                (
+                    "3c15267ebf11807f3d772eb891272b911ec68759".to_string(),
+                    vec!["a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157".to_string()],
+                    vec!["release".to_string(), "stable".to_string()],
+                    GraphPhase::Public,
+                ),
+                (
+                    "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157".to_string(),
+                    vec!["0ed509bf086fadcb8a8a5384dc3b550729b0fc17".to_string()],
+                    vec![],
+                    GraphPhase::Public,
+                ),
+            ]
+        );
+    }
+}
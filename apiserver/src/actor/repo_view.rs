@@ -0,0 +1,87 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+
+use blobrepo::BlobRepo;
+use bookmarks::Bookmark;
+use cloned::cloned;
+use context::CoreContext;
+use failure::{format_err, Compat, Error};
+use futures::future::{ok, Shared};
+use futures::{Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::HgChangesetId;
+use mononoke_types::ChangesetId;
+
+/// The state of a repo as seen by a single request.
+///
+/// One request can resolve several revisions: `is_ancestor` resolves two, and `master~10`
+/// resolves a bookmark and then walks its ancestors. If every step read bookmarks from the repo, a
+/// push landing in the middle of the request could make the steps disagree. The view reads all
+/// bookmarks at most once, when the request first needs one, and answers every bookmark lookup of
+/// the request from that snapshot.
+#[derive(Clone)]
+pub struct RepoView {
+    repo: BlobRepo,
+    // Shared so that all lookups of the request wait for the same read. The future doesn't
+    // start until the first lookup polls it, so requests that don't use bookmarks don't pay for
+    // it. Compat<Error> because SharedError requires the wrapped error to be Clone.
+    bookmarks: Shared<BoxFuture<HashMap<Bookmark, ChangesetId>, Compat<Error>>>,
+}
+
+impl RepoView {
+    pub fn new(ctx: CoreContext, repo: BlobRepo) -> Self {
+        let bookmarks = repo
+            .get_bonsai_bookmarks(ctx)
+            .collect()
+            .map(|bookmarks| bookmarks.into_iter().collect())
+            .map_err(Error::compat)
+            .boxify()
+            .shared();
+        Self { repo, bookmarks }
+    }
+
+    /// The changeset `bookmark` pointed to when this view first read bookmarks.
+    pub fn get_bookmark(
+        &self,
+        ctx: CoreContext,
+        bookmark: &Bookmark,
+    ) -> BoxFuture<Option<HgChangesetId>, Error> {
+        cloned!(self.repo);
+        self.get_bonsai_bookmark(bookmark)
+            .and_then(move |maybe_cs_id| match maybe_cs_id {
+                Some(cs_id) => repo
+                    .get_hg_from_bonsai_changeset(ctx, cs_id)
+                    .map(Some)
+                    .left_future(),
+                None => ok(None).right_future(),
+            })
+            .boxify()
+    }
+
+    /// Like `get_bookmark`, but returns the bonsai changeset.
+    pub fn get_bonsai_bookmark(
+        &self,
+        bookmark: &Bookmark,
+    ) -> BoxFuture<Option<ChangesetId>, Error> {
+        cloned!(bookmark);
+        self.bookmarks
+            .clone()
+            .map_err(|err| format_err!("failed to read bookmarks: {}", *err))
+            .map(move |bookmarks| bookmarks.get(&bookmark).cloned())
+            .boxify()
+    }
+
+    /// All bookmarks, as this view first read them.
+    pub fn get_bonsai_bookmarks(&self) -> BoxFuture<HashMap<Bookmark, ChangesetId>, Error> {
+        self.bookmarks
+            .clone()
+            .map_err(|err| format_err!("failed to read bookmarks: {}", *err))
+            .map(|bookmarks| (*bookmarks).clone())
+            .boxify()
+    }
+}
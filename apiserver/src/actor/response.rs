@@ -6,12 +6,25 @@
 
 use std::collections::BTreeMap;
 
-use actix_web::{self, dev::BodyStream, Body, HttpRequest, HttpResponse, Json, Responder};
+use actix_web::{
+    self, dev::BodyStream, http::header::HeaderValue, Body, HttpRequest, HttpResponse, Json,
+    Responder,
+};
 use bytes::Bytes;
 use futures::Stream;
 
 use super::lfs::BatchResponse;
-use super::model::{Changeset, Entry, EntryWithSizeAndContentHash};
+use super::model::{
+    Ancestry, Changeset, CommonAncestor, Entry, EntryWithSizeAndContentHash, GraphNode,
+    MergeConflictReport, PathExistence, PathSize, PushUsageReport, RangeChangeset, RawChangeset,
+    Replica, RepoHealth, RepoStatus,
+};
+
+/// Header of file history responses that carries where the rest of the history continues from
+pub const HISTORY_CURSOR_HEADER: &str = "x-mononoke-history-cursor";
+
+/// Header of pages of listings that carries the token of the next page
+pub const CONTINUATION_TOKEN_HEADER: &str = "x-mononoke-continuation-token";
 
 type SendBodyStream = Box<Stream<Item = Bytes, Error = actix_web::Error> + Send + 'static>;
 
@@ -19,17 +32,41 @@ pub enum MononokeRepoResponse {
     GetRawFile {
         content: Bytes,
     },
+    GetRawFileChunk {
+        content: Bytes,
+        /// Where `content` starts in the file
+        offset: u64,
+        /// Size of the whole file
+        file_size: u64,
+    },
     GetHgFile {
         content: Bytes,
     },
     GetFileHistory {
         history: SendBodyStream,
+        /// Where the rest of the history continues from, if it didn't fit
+        next: Option<String>,
     },
     GetBlobContent {
         content: Bytes,
     },
+    GetRawBlob {
+        content: Bytes,
+    },
+    GetRawBookmarks {
+        /// Bonsai changeset ids, by bookmark
+        bookmarks: BTreeMap<String, String>,
+    },
+    GetRawChangeset {
+        changeset: RawChangeset,
+    },
+    MultiGet {
+        entries: SendBodyStream,
+    },
     ListDirectory {
         files: Box<dyn Iterator<Item = Entry> + Send>,
+        /// Token of the next page, if this is a page that isn't the last one
+        next: Option<String>,
     },
     GetTree {
         files: Vec<EntryWithSizeAndContentHash>,
@@ -39,12 +76,51 @@ pub enum MononokeRepoResponse {
     },
     GetBranches {
         branches: BTreeMap<String, String>,
+        /// Token of the next page, if this is a page that isn't the last one
+        next: Option<String>,
+    },
+    GetStatus {
+        status: RepoStatus,
+    },
+    GetHealth {
+        health: RepoHealth,
+    },
+    GetReplicas {
+        /// The replicas of every SQL store, by store name. Empty if the repo has no replicas
+        stores: BTreeMap<String, Vec<Replica>>,
+    },
+    GetPushUsage {
+        report: PushUsageReport,
+    },
+    GetSizes {
+        sizes: Vec<PathSize>,
+    },
+    PathsExist {
+        paths: Vec<PathExistence>,
     },
     IsAncestor {
         answer: bool,
     },
+    IsAncestorBatch {
+        answers: Vec<Ancestry>,
+    },
+    CommonAncestor {
+        ancestor: CommonAncestor,
+    },
+    Contains {
+        answer: bool,
+    },
+    MergeConflicts {
+        report: MergeConflictReport,
+    },
+    GetGraph {
+        nodes: Vec<GraphNode>,
+    },
+    Range {
+        changesets: Vec<RangeChangeset>,
+    },
     DownloadLargeFile {
-        content: Bytes,
+        content: SendBodyStream,
     },
     LfsBatch {
         response: BatchResponse,
@@ -64,6 +140,12 @@ fn streaming_response(stream: SendBodyStream) -> HttpResponse {
         .body(Body::Streaming(stream as BodyStream))
 }
 
+fn msgpack_streaming_response(stream: SendBodyStream) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/x-msgpack")
+        .body(Body::Streaming(stream as BodyStream))
+}
+
 impl Responder for MononokeRepoResponse {
     type Item = HttpResponse;
     type Error = actix_web::Error;
@@ -72,22 +154,54 @@ impl Responder for MononokeRepoResponse {
         use self::MononokeRepoResponse::*;
 
         match self {
-            GetRawFile { content } | GetBlobContent { content } | GetHgFile { content } => {
-                Ok(binary_response(content))
+            GetRawFile { content }
+            | GetRawFileChunk { content, .. }
+            | GetBlobContent { content }
+            | GetHgFile { content }
+            | GetRawBlob { content } => Ok(binary_response(content)),
+            GetFileHistory { history, next } => {
+                let mut response = streaming_response(history);
+                if let Some(next) = next {
+                    response
+                        .headers_mut()
+                        .insert(HISTORY_CURSOR_HEADER, HeaderValue::from_str(&next)?);
+                }
+                Ok(response)
+            }
+            MultiGet { entries } => Ok(msgpack_streaming_response(entries)),
+            ListDirectory { files, next } => {
+                let mut response = Json(files.collect::<Vec<_>>()).respond_to(req)?;
+                if let Some(next) = next {
+                    response
+                        .headers_mut()
+                        .insert(CONTINUATION_TOKEN_HEADER, HeaderValue::from_str(&next)?);
+                }
+                Ok(response)
             }
-            GetFileHistory { history } => Ok(streaming_response(history)),
-            ListDirectory { files } => Json(files.collect::<Vec<_>>()).respond_to(req),
             GetTree { files } => Json(files).respond_to(req),
             GetChangeset { changeset } => Json(changeset).respond_to(req),
-            GetBranches { branches } => Json(branches).respond_to(req),
-            IsAncestor { answer } => Ok(binary_response({
+            GetRawBookmarks { bookmarks } => Json(bookmarks).respond_to(req),
+            GetRawChangeset { changeset } => Json(changeset).respond_to(req),
+            GetBranches { branches, .. } => Json(branches).respond_to(req),
+            GetStatus { status } => Json(status).respond_to(req),
+            GetHealth { health } => Json(health).respond_to(req),
+            GetReplicas { stores } => Json(stores).respond_to(req),
+            GetPushUsage { report } => Json(report).respond_to(req),
+            GetSizes { sizes } => Json(sizes).respond_to(req),
+            PathsExist { paths } => Json(paths).respond_to(req),
+            IsAncestor { answer } | Contains { answer } => Ok(binary_response({
                 if answer {
                     "true".into()
                 } else {
                     "false".into()
                 }
             })),
-            DownloadLargeFile { content } => Ok(binary_response(content.into())),
+            IsAncestorBatch { answers } => Json(answers).respond_to(req),
+            CommonAncestor { ancestor } => Json(ancestor).respond_to(req),
+            MergeConflicts { report } => Json(report).respond_to(req),
+            GetGraph { nodes } => Json(nodes).respond_to(req),
+            Range { changesets } => Json(changesets).respond_to(req),
+            DownloadLargeFile { content } => Ok(streaming_response(content)),
             LfsBatch { response } => Json(response).respond_to(req),
             UploadLargeFile {} => Ok(HttpResponse::Ok().into()),
         }
@@ -18,6 +18,7 @@ use serde_derive::Serialize;
 use apiserver_thrift::types::{MononokeAPIException, MononokeAPIExceptionKind};
 use blobrepo::ErrorKind as BlobRepoError;
 use mononoke_api::errors::ErrorKind as ApiError;
+use mononoke_errors::ErrorCategory;
 use reachabilityindex::errors::ErrorKind as ReachabilityIndexError;
 
 #[derive(Serialize, Debug)]
@@ -27,15 +28,51 @@ enum ErrorResponse {
     LFSErrorResponse(LFSErrorResponse),
 }
 
+/// The JSON envelope every API error is returned in.
 #[derive(Serialize, Debug)]
 struct APIErrorResponse {
+    /// Machine readable error code, stable across releases.
+    code: &'static str,
+    /// Human readable description of the error.
     message: String,
     causes: Vec<String>,
+    /// Identifier of the request, also returned in the `x-request-id` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// Whether the client may expect a different outcome when retrying the same request.
+    retryable: bool,
 }
 
+// Format defined by the git-lfs batch API, which allows an optional request id as well.
 #[derive(Serialize, Debug)]
 struct LFSErrorResponse {
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+/// Build an error envelope for failures that did not originate from our handlers (e.g. request
+/// parsing in actix extractors), so that clients see the same format for every error.
+pub fn generic_error_response(
+    status: StatusCode,
+    message: String,
+    request_id: Option<&str>,
+) -> HttpResponse {
+    let code = if status.is_server_error() {
+        "INTERNAL_ERROR"
+    } else if status == StatusCode::NOT_FOUND {
+        "NOT_FOUND"
+    } else {
+        "INVALID_REQUEST"
+    };
+
+    HttpResponse::build(status).json(ErrorResponse::APIErrorResponse(APIErrorResponse {
+        code,
+        message,
+        causes: vec![],
+        request_id: request_id.map(|id| id.to_string()),
+        retryable: status.is_server_error(),
+    }))
 }
 
 #[derive(Debug)]
@@ -46,6 +83,10 @@ pub enum ErrorKind {
     LFSNotFound(String),
     NotADirectory(String),
     BookmarkNotFound(String),
+    /// The content was intentionally removed; holds the id and the reason.
+    ContentTombstoned(String, String),
+    /// The client may not read what it asked for; holds what it asked for and why.
+    Forbidden(String, String),
 }
 
 impl ErrorKind {
@@ -59,28 +100,83 @@ impl ErrorKind {
             LFSNotFound(_) => StatusCode::NOT_FOUND,
             NotADirectory(_) => StatusCode::BAD_REQUEST,
             BookmarkNotFound(_) => StatusCode::BAD_REQUEST,
+            ContentTombstoned(..) => StatusCode::GONE,
+            Forbidden(..) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        use crate::errors::ErrorKind::*;
+
+        match self {
+            NotFound(..) => "NOT_FOUND",
+            InvalidInput(..) => "INVALID_INPUT",
+            InternalError(_) => "INTERNAL_ERROR",
+            LFSNotFound(_) => "LFS_NOT_FOUND",
+            NotADirectory(_) => "NOT_A_DIRECTORY",
+            BookmarkNotFound(_) => "BOOKMARK_NOT_FOUND",
+            ContentTombstoned(..) => "CONTENT_TOMBSTONED",
+            Forbidden(..) => "FORBIDDEN",
+        }
+    }
+
+    /// Only failures of the server itself may go away on retry, everything else is caused by
+    /// the request. Of those, failures whose cause is known to be missing or corrupt data, or a
+    /// bug, won't go away either.
+    pub fn is_retryable(&self) -> bool {
+        use crate::errors::ErrorKind::*;
+
+        match self {
+            InternalError(err) => blobrepo::error_categorizer()
+                .with_default(ErrorCategory::Transient)
+                .is_transient(err),
+            NotFound(..)
+            | InvalidInput(..)
+            | LFSNotFound(_)
+            | NotADirectory(_)
+            | BookmarkNotFound(_)
+            | ContentTombstoned(..)
+            | Forbidden(..) => false,
         }
     }
 
     #[allow(deprecated)] // self.causes()
-    fn into_error_response(&self) -> ErrorResponse {
+    fn into_error_response(&self, request_id: Option<&str>) -> ErrorResponse {
         use crate::errors::ErrorKind::*;
 
+        let request_id = request_id.map(|id| id.to_string());
         match &self {
-            NotFound(..) | InvalidInput(..) | InternalError(_) | NotADirectory(_)
-            | BookmarkNotFound(_) => ErrorResponse::APIErrorResponse(APIErrorResponse {
+            NotFound(..)
+            | InvalidInput(..)
+            | InternalError(_)
+            | NotADirectory(_)
+            | BookmarkNotFound(_)
+            | ContentTombstoned(..)
+            | Forbidden(..) => ErrorResponse::APIErrorResponse(APIErrorResponse {
+                code: self.error_code(),
                 message: self.to_string(),
                 causes: self
                     .causes()
                     .skip(1)
                     .map(|cause| cause.to_string())
                     .collect(),
+                request_id,
+                retryable: self.is_retryable(),
             }),
             LFSNotFound(_) => ErrorResponse::LFSErrorResponse(LFSErrorResponse {
                 message: self.to_string(),
+                request_id,
             }),
         }
     }
+
+    /// Render this error with the id of the request that caused it. `ResponseError` has no
+    /// access to the request, so the request id middleware calls this to fill the id in.
+    pub fn error_response_with_request_id(&self, request_id: Option<&str>) -> HttpResponse {
+        let err = self.unwrap_errorkind();
+        HttpResponse::build(err.status_code()).json(err.into_error_response(request_id))
+    }
+
     // Since all non-ErrorKind error including `Context<ErrorKind>` is wrapped in `InternalError`
     // automatically at `From<Error>::from`, we need to downcast the `Context` retrieve the
     // `ErrorKind` in the `Context`.
@@ -103,7 +199,11 @@ impl Fail for ErrorKind {
         match self {
             NotFound(_, cause) | InvalidInput(_, cause) => cause.as_ref().map(|e| e.as_fail()),
             InternalError(err) => Some(err.as_fail()),
-            LFSNotFound(_) | NotADirectory(_) | BookmarkNotFound(_) => None,
+            LFSNotFound(_)
+            | NotADirectory(_)
+            | BookmarkNotFound(_)
+            | ContentTombstoned(..)
+            | Forbidden(..) => None,
         }
     }
 }
@@ -119,14 +219,15 @@ impl fmt::Display for ErrorKind {
             LFSNotFound(_0) => write!(f, "{} is not found on LFS request", _0),
             NotADirectory(_0) => write!(f, "{} is not a directory", _0),
             BookmarkNotFound(_0) => write!(f, "{} is not a valid bookmark", _0),
+            ContentTombstoned(_0, _1) => write!(f, "{} is not available: {}", _0, _1),
+            Forbidden(_0, _1) => write!(f, "{} cannot be read: {}", _0, _1),
         }
     }
 }
 
 impl ResponseError for ErrorKind {
     fn error_response(&self) -> HttpResponse {
-        let err = self.unwrap_errorkind();
-        HttpResponse::build(err.status_code()).json(err.into_error_response())
+        self.error_response_with_request_id(None)
     }
 }
 
@@ -162,6 +263,7 @@ impl From<ApiError> for ErrorKind {
         match e {
             NotFound(t) => ErrorKind::NotFound(t, None),
             InvalidInput(t) => ErrorKind::InvalidInput(t, None),
+            SymlinkLoop(t) => ErrorKind::InvalidInput(t.clone(), Some(SymlinkLoop(t).into())),
         }
     }
 }
@@ -231,6 +333,14 @@ impl From<ErrorKind> for MononokeAPIException {
                 kind: MononokeAPIExceptionKind::BookmarkNotFound,
                 reason: e.to_string(),
             },
+            e @ ContentTombstoned(..) => MononokeAPIException {
+                kind: MononokeAPIExceptionKind::ContentTombstoned,
+                reason: e.to_string(),
+            },
+            e @ Forbidden(..) => MononokeAPIException {
+                kind: MononokeAPIExceptionKind::Forbidden,
+                reason: e.to_string(),
+            },
         }
     }
 }
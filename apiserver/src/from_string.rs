@@ -9,7 +9,7 @@
 use std::{convert::TryFrom, str::FromStr};
 
 use mercurial_types::{HgChangesetId, HgFileNodeId, HgNodeHash};
-use mononoke_types::{hash::Sha256, MPath};
+use mononoke_types::{hash::Sha256, ChangesetId, MPath};
 
 use crate::errors::ErrorKind;
 
@@ -21,6 +21,10 @@ pub fn get_changeset_id(changesetid: String) -> Result<HgChangesetId, ErrorKind>
     HgChangesetId::from_str(&changesetid).map_err(|e| ErrorKind::InvalidInput(changesetid, Some(e)))
 }
 
+pub fn get_bonsai_changeset_id(changesetid: String) -> Result<ChangesetId, ErrorKind> {
+    ChangesetId::from_str(&changesetid).map_err(|e| ErrorKind::InvalidInput(changesetid, Some(e)))
+}
+
 pub fn get_nodehash(hash: &str) -> Result<HgNodeHash, ErrorKind> {
     HgNodeHash::from_str(hash).map_err(|e| ErrorKind::InvalidInput(hash.to_string(), Some(e)))
 }
@@ -32,3 +36,89 @@ pub fn get_filenode_id(hash: &str) -> Result<HgFileNodeId, ErrorKind> {
 pub fn get_sha256_oid(oid: String) -> Result<Sha256, ErrorKind> {
     Sha256::from_str(&oid).map_err(|e| ErrorKind::InvalidInput(oid.to_string(), Some(e.into())))
 }
+
+/// Upper bound on the number of first-parent steps a revision selector may ask for, so that a
+/// single request can't make us walk the entire history.
+pub const MAX_ANCESTRY_STEPS: u64 = 10_000;
+
+/// Split git/hg style ancestry suffixes off a revision. `rev^` selects the first parent and
+/// `rev~N` the N-th first-parent ancestor; suffixes can be chained (`rev~2^` is `rev~3`).
+/// Returns the base revision and the total number of first-parent steps to take from it.
+pub fn get_ancestry_suffix(revision: String) -> Result<(String, u64), ErrorKind> {
+    let mut base = revision.as_str();
+    let mut steps: u64 = 0;
+
+    loop {
+        if base.ends_with('^') {
+            base = &base[..base.len() - 1];
+            steps = steps.saturating_add(1);
+            continue;
+        }
+
+        let (prefix, digits) =
+            base.split_at(base.trim_end_matches(|c: char| c.is_ascii_digit()).len());
+        if prefix.ends_with('~') {
+            let n = if digits.is_empty() {
+                1
+            } else {
+                digits
+                    .parse::<u64>()
+                    .map_err(|e| ErrorKind::InvalidInput(revision.clone(), Some(e.into())))?
+            };
+            base = &prefix[..prefix.len() - 1];
+            steps = steps.saturating_add(n);
+            continue;
+        }
+
+        break;
+    }
+
+    if base.is_empty() {
+        return Err(ErrorKind::InvalidInput(revision.clone(), None));
+    }
+
+    if steps > MAX_ANCESTRY_STEPS {
+        return Err(ErrorKind::InvalidInput(
+            format!(
+                "{} (at most {} ancestry steps are allowed)",
+                revision, MAX_ANCESTRY_STEPS
+            ),
+            None,
+        ));
+    }
+
+    Ok((base.to_string(), steps))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(revision: &str) -> Option<(String, u64)> {
+        get_ancestry_suffix(revision.to_string()).ok()
+    }
+
+    #[test]
+    fn no_suffix() {
+        assert_eq!(parse("master"), Some(("master".to_string(), 0)));
+        assert_eq!(parse("abcdef"), Some(("abcdef".to_string(), 0)));
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(parse("master^"), Some(("master".to_string(), 1)));
+        assert_eq!(parse("master^^"), Some(("master".to_string(), 2)));
+        assert_eq!(parse("master~"), Some(("master".to_string(), 1)));
+        assert_eq!(parse("master~10"), Some(("master".to_string(), 10)));
+        assert_eq!(parse("master~2^~3"), Some(("master".to_string(), 6)));
+        assert_eq!(parse("release-2"), Some(("release-2".to_string(), 0)));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse("^"), None);
+        assert_eq!(parse("~3"), None);
+        assert_eq!(parse("master~10001"), None);
+        assert_eq!(parse("master~99999999999999999999999"), None);
+    }
+}
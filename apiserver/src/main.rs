@@ -9,17 +9,23 @@
 
 use actix_web::{http::header, server, App, HttpRequest, HttpResponse, Json, Path, State};
 use bytes::Bytes;
-use clap::{value_t, Arg};
-use failure::Fallible;
+use clap::{value_t, Arg, ArgMatches};
+use failure::{err_msg, Fallible};
 use futures::Future;
 use http::uri::{Authority, Parts, PathAndQuery, Scheme, Uri};
+use openssl::ssl::SslAcceptorBuilder;
+use std::collections::HashMap;
 use std::sync::Arc;
-use uuid::Uuid;
 
-use context::CoreContext;
+use audit_log::Auditor;
+use cmdlib::startup::{check_myrouter_port, StartupErrors};
+use context::{CoreContext, SystemTimeUuidProvider};
+use mercurial_types::TextDecoding;
 use metaconfig_parser::RepoConfigs;
+use mononoke_types::RepositoryId;
 use panichandler::Fate;
 use percent_encoding::percent_decode;
+use qos::QOS_HEADER;
 use scuba_ext::ScubaSampleBuilder;
 use serde_derive::Deserialize;
 use slog::{info, o, Drain, Level, Logger};
@@ -33,13 +39,18 @@ mod actor;
 mod errors;
 mod from_string;
 mod middleware;
+mod openapi;
 mod thrift;
 
 use crate::actor::{
-    BatchRequest, Mononoke, MononokeQuery, MononokeRepoQuery, MononokeRepoResponse, Revision,
+    BatchRequest, ListDirectoryOptions, Mononoke, MononokeQuery, MononokeRepoQuery,
+    MononokeRepoResponse, PageRequest, Revision,
 };
 use crate::errors::ErrorKind;
-use crate::middleware::ScubaMiddleware;
+use crate::middleware::{
+    AuditMiddleware, CompressionConfig, CompressionMiddleware, RequestId, RequestIdMiddleware,
+    ScubaMiddleware,
+};
 
 mod config {
     pub const SCUBA_TABLE: &str = "mononoke_apiserver";
@@ -47,16 +58,24 @@ mod config {
 
 // Currently logging and scuba is handled using the middleware service
 // so we pass on a fake context
-fn prepare_fake_ctx(state: &State<HttpServerState>) -> CoreContext {
+fn prepare_fake_ctx(req: &HttpRequest<HttpServerState>) -> CoreContext {
     CoreContext::new(
-        Uuid::new_v4(),
-        state.logger.clone(),
+        RequestId::get(req),
+        req.state().logger.clone(),
         ScubaSampleBuilder::with_discard(),
         None,
         TraceContext::default(),
         None,
         SshEnvVars::default(),
     )
+    .with_time_uuid_provider(req.state().mononoke.time_uuid())
+}
+
+// The class of service that the client declared for a request
+fn declared_qos(req: &HttpRequest<HttpServerState>) -> Option<&str> {
+    req.headers()
+        .get(QOS_HEADER)
+        .and_then(|value| value.to_str().ok())
 }
 
 #[derive(Deserialize)]
@@ -69,14 +88,20 @@ struct GetRawFileParams {
 // The argument of this function is because the trait `actix_web::FromRequest` is implemented
 // for tuple (A, B, ...) (up to 9 elements) [1]. These arguments must implement
 // `actix_web::FromRequest` as well so actix-web will try to extract them from `actix::HttpRequest`
-// for us. In this case, the `State<HttpServerState>` and `Path<GetRawFileParams>`.
+// for us. In this case, the `State<HttpServerState>`, `HttpRequest<HttpServerState>` and
+// `Path<GetRawFileParams>`.
 // [1] https://docs.rs/actix-web/0.6.11/actix_web/trait.FromRequest.html#impl-FromRequest%3CS%3E-3
 fn get_raw_file(
-    (state, params): (State<HttpServerState>, Path<GetRawFileParams>),
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetRawFileParams>,
+    ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::GetRawFile {
@@ -87,6 +112,192 @@ fn get_raw_file(
     )
 }
 
+#[derive(Deserialize)]
+struct GetStatusParams {
+    repo: String,
+}
+
+fn get_status(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetStatusParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetStatus,
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct GetHealthParams {
+    repo: String,
+}
+
+fn get_health(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetHealthParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetHealth,
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct GetReplicasParams {
+    repo: String,
+}
+
+fn get_replicas(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetReplicasParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetReplicas,
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct GetPushUsageParams {
+    repo: String,
+}
+
+fn get_push_usage(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetPushUsageParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetPushUsage,
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct GetSizeParams {
+    repo: String,
+    changeset: String,
+    path: String,
+}
+
+fn get_size(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetSizeParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetSizes {
+                revision: Revision::CommitHash(params.changeset),
+                paths: vec![params.path],
+            },
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct GetSizesParams {
+    repo: String,
+    changeset: String,
+}
+
+#[derive(Deserialize)]
+struct GetSizesBody {
+    paths: Vec<String>,
+}
+
+fn get_sizes(
+    (state, req, body, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Json<GetSizesBody>,
+        Path<GetSizesParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetSizes {
+                revision: Revision::CommitHash(params.changeset),
+                paths: body.into_inner().paths,
+            },
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct PathsExistParams {
+    repo: String,
+    changeset: String,
+}
+
+#[derive(Deserialize)]
+struct PathsExistBody {
+    paths: Vec<String>,
+}
+
+fn paths_exist(
+    (state, req, body, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Json<PathsExistBody>,
+        Path<PathsExistParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::PathsExist {
+                revision: Revision::CommitHash(params.changeset),
+                paths: body.into_inner().paths,
+            },
+        },
+    )
+}
+
 #[derive(Deserialize)]
 struct GetHgFileParams {
     repo: String,
@@ -94,11 +305,16 @@ struct GetHgFileParams {
 }
 
 fn get_hg_file(
-    (state, params): (State<HttpServerState>, Path<GetHgFileParams>),
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetHgFileParams>,
+    ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::GetHgFile {
@@ -123,14 +339,17 @@ fn get_file_history(
     ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::GetFileHistory {
                 filenode: params.filenode,
                 path: params.path,
                 depth: req.query().get("depth").and_then(|d| d.parse().ok()),
+                limit: req.query().get("limit").and_then(|l| l.parse().ok()),
+                cursor: req.query().get("cursor").cloned(),
             },
         },
     )
@@ -144,7 +363,11 @@ struct IsAncestorParams {
 }
 
 fn is_ancestor(
-    (state, params): (State<HttpServerState>, Path<IsAncestorParams>),
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<IsAncestorParams>,
+    ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
     let ancestor_parsed = percent_decode(params.ancestor.as_bytes())
@@ -153,8 +376,9 @@ fn is_ancestor(
     let descendant_parsed = percent_decode(params.descendant.as_bytes())
         .decode_utf8_lossy()
         .to_string();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::IsAncestor {
@@ -165,6 +389,196 @@ fn is_ancestor(
     )
 }
 
+#[derive(Deserialize)]
+struct IsAncestorBatchParams {
+    repo: String,
+}
+
+#[derive(Deserialize)]
+struct AncestryPair {
+    ancestor: String,
+    descendant: String,
+}
+
+#[derive(Deserialize)]
+struct IsAncestorBatchBody {
+    pairs: Vec<AncestryPair>,
+}
+
+fn is_ancestor_batch(
+    (state, req, body, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Json<IsAncestorBatchBody>,
+        Path<IsAncestorBatchParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    let pairs = body
+        .into_inner()
+        .pairs
+        .into_iter()
+        .map(|pair| {
+            (
+                Revision::CommitHash(pair.ancestor),
+                Revision::CommitHash(pair.descendant),
+            )
+        })
+        .collect();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::IsAncestorBatch { pairs },
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct CommonAncestorParams {
+    repo: String,
+    cs1: String,
+    cs2: String,
+}
+
+fn common_ancestor(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<CommonAncestorParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::CommonAncestor {
+                left: Revision::CommitHash(params.cs1),
+                right: Revision::CommitHash(params.cs2),
+            },
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct MergeConflictsParams {
+    repo: String,
+    left: String,
+    right: String,
+}
+
+fn merge_conflicts(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<MergeConflictsParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::MergeConflicts {
+                left: Revision::CommitHash(params.left),
+                right: Revision::CommitHash(params.right),
+            },
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct GetGraphParams {
+    repo: String,
+    revision: String,
+}
+
+fn get_graph(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetGraphParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetGraph {
+                revision: Revision::CommitHash(params.revision),
+                limit: req.query().get("limit").and_then(|l| l.parse().ok()),
+            },
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct RangeParams {
+    repo: String,
+    from: String,
+    to: String,
+}
+
+fn range(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<RangeParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::Range {
+                from: Revision::CommitHash(params.from),
+                to: Revision::CommitHash(params.to),
+                limit: req.query().get("limit").and_then(|l| l.parse().ok()),
+            },
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct ContainsParams {
+    repo: String,
+    bookmark: String,
+    hash: String,
+}
+
+fn contains(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<ContainsParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    // Release bookmarks usually have slashes in them, which have to be escaped in the url
+    let bookmark = percent_decode(params.bookmark.as_bytes())
+        .decode_utf8_lossy()
+        .to_string();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::Contains {
+                bookmark,
+                revision: Revision::CommitHash(params.hash),
+            },
+        },
+    )
+}
+
 #[derive(Deserialize)]
 struct ListDirectoryParams {
     repo: String,
@@ -173,16 +587,41 @@ struct ListDirectoryParams {
 }
 
 fn list_directory(
-    (state, params): (State<HttpServerState>, Path<ListDirectoryParams>),
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<ListDirectoryParams>,
+    ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    let flag = |name| {
+        req.query()
+            .get(name)
+            .map(|value| value == "1" || value == "true")
+            .unwrap_or(false)
+    };
+    let options = ListDirectoryOptions {
+        symlink_targets: flag("symlink_targets"),
+        follow_symlinks: flag("follow_symlinks"),
+        submodules: flag("submodules"),
+    };
+    let token = req.query().get("token").cloned();
+    let limit = req.query().get("limit").and_then(|l| l.parse().ok());
+    let page = if token.is_some() || limit.is_some() {
+        Some(PageRequest { token, limit })
+    } else {
+        None
+    };
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::ListDirectory {
                 revision: Revision::CommitHash(params.changeset),
                 path: params.path,
+                options,
+                page,
             },
         },
     )
@@ -195,11 +634,16 @@ struct GetBlobParams {
 }
 
 fn get_blob_content(
-    (state, params): (State<HttpServerState>, Path<GetBlobParams>),
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetBlobParams>,
+    ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::GetBlobContent { hash: params.hash },
@@ -207,6 +651,119 @@ fn get_blob_content(
     )
 }
 
+#[derive(Deserialize)]
+struct GetRawBlobParams {
+    repo: String,
+    key: String,
+}
+
+fn get_raw_blob(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetRawBlobParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetRawBlob { key: params.key },
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct GetRawBookmarksParams {
+    repo: String,
+}
+
+fn get_raw_bookmarks(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetRawBookmarksParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetRawBookmarks,
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct GetRawChangesetParams {
+    repo: String,
+    changeset: String,
+}
+
+fn get_raw_changeset(
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetRawChangesetParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::GetRawChangeset {
+                changeset: params.changeset,
+            },
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct MultiGetParams {
+    repo: String,
+    changeset: String,
+}
+
+#[derive(Deserialize)]
+struct MultiGetBody {
+    #[serde(default)]
+    paths: Vec<String>,
+    #[serde(default)]
+    content_ids: Vec<String>,
+    max_bytes: Option<u64>,
+}
+
+fn multi_get(
+    (state, req, body, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Json<MultiGetBody>,
+        Path<MultiGetParams>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let params = params.into_inner();
+    let body = body.into_inner();
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
+        MononokeQuery {
+            repo: params.repo,
+            kind: MononokeRepoQuery::MultiGet {
+                revision: Revision::CommitHash(params.changeset),
+                paths: body.paths,
+                content_ids: body.content_ids,
+                max_bytes: body.max_bytes,
+            },
+        },
+    )
+}
+
 #[derive(Deserialize)]
 struct GetTreeParams {
     repo: String,
@@ -214,11 +771,16 @@ struct GetTreeParams {
 }
 
 fn get_tree(
-    (state, params): (State<HttpServerState>, Path<GetTreeParams>),
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetTreeParams>,
+    ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::GetTree { hash: params.hash },
@@ -233,11 +795,16 @@ struct GetChangesetParams {
 }
 
 fn get_changeset(
-    (state, params): (State<HttpServerState>, Path<GetChangesetParams>),
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<GetChangesetParams>,
+    ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::GetChangeset {
@@ -254,11 +821,16 @@ struct DownloadLargeFileParams {
 }
 
 fn download_large_file(
-    (state, params): (State<HttpServerState>, Path<DownloadLargeFileParams>),
+    (state, req, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Path<DownloadLargeFileParams>,
+    ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::DownloadLargeFile { oid: params.oid },
@@ -300,8 +872,9 @@ fn lfs_batch(
             Uri::from_parts(parts).ok()
         });
 
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo.clone(),
             kind: MononokeRepoQuery::LfsBatch {
@@ -321,11 +894,17 @@ struct UploadLargeFileParams {
 
 // TODO(anastasiyaz): T32937714 Bytes -> Streaming
 fn upload_large_file(
-    (state, body, params): (State<HttpServerState>, Bytes, Path<UploadLargeFileParams>),
+    (state, req, body, params): (
+        State<HttpServerState>,
+        HttpRequest<HttpServerState>,
+        Bytes,
+        Path<UploadLargeFileParams>,
+    ),
 ) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
     let params = params.into_inner();
-    state.mononoke.send_query(
-        prepare_fake_ctx(&state),
+    state.mononoke.send_query_with_qos(
+        prepare_fake_ctx(&req),
+        declared_qos(&req),
         MononokeQuery {
             repo: params.repo,
             kind: MononokeRepoQuery::UploadLargeFile {
@@ -354,6 +933,70 @@ fn setup_logger(debug: bool) -> Logger {
     )
 }
 
+/// The ssl config and the path to the ticket seeds, if the server should use ssl
+fn parse_ssl_config<'a>(
+    matches: &ArgMatches<'a>,
+) -> Fallible<Option<(secure_utils::SslConfig, String)>> {
+    let cert = match matches.value_of("ssl-certificate") {
+        Some(cert) => cert.to_string(),
+        None => return Ok(None),
+    };
+    let private_key = matches
+        .value_of("ssl-private-key")
+        .ok_or_else(|| err_msg("--ssl-certificate requires --ssl-private-key"))?
+        .to_string();
+    let ca_pem = matches
+        .value_of("ssl-ca")
+        .ok_or_else(|| err_msg("--ssl-certificate requires --ssl-ca"))?
+        .to_string();
+    let ticket_seed = matches
+        .value_of("ssl-ticket-seeds")
+        .unwrap_or(secure_utils::fb_tls::SEED_PATH)
+        .to_string();
+
+    let ssl = secure_utils::SslConfig {
+        cert,
+        private_key,
+        ca_pem,
+    };
+    Ok(Some((ssl, ticket_seed)))
+}
+
+/// How responses should be compressed, if at all
+fn parse_compression_config<'a>(matches: &ArgMatches<'a>) -> Fallible<Option<CompressionConfig>> {
+    if matches.is_present("without-compression") {
+        return Ok(None);
+    }
+    let mut compression = CompressionConfig::default();
+    if let Some(min_size) = matches.value_of("compression-min-size") {
+        compression.min_size = min_size.parse().map_err(|_| {
+            err_msg(format!(
+                "--compression-min-size must be a number of bytes, got {:?}",
+                min_size
+            ))
+        })?;
+    }
+    if let Some(content_types) = matches.value_of("compression-content-types") {
+        compression.content_types = content_types
+            .split(',')
+            .map(|content_type| content_type.trim().to_string())
+            .filter(|content_type| !content_type.is_empty())
+            .collect();
+    }
+    Ok(Some(compression))
+}
+
+fn build_ssl_acceptor(
+    logger: &Logger,
+    ssl: secure_utils::SslConfig,
+    ticket_seed: String,
+) -> Fallible<SslAcceptorBuilder> {
+    let acceptor = secure_utils::build_tls_acceptor_builder(ssl.clone())?;
+    let acceptor =
+        secure_utils::fb_tls::tls_acceptor_builder(logger.clone(), ssl, acceptor, ticket_seed)?;
+    Ok(acceptor)
+}
+
 #[derive(Clone)]
 struct HttpServerState {
     mononoke: Arc<Mononoke>,
@@ -391,8 +1034,30 @@ fn main() -> Fallible<()> {
                 .help("Thrift port"),
         )
         .arg(Arg::with_name("with-scuba").long("with-scuba"))
+        .arg(
+            Arg::with_name("without-compression")
+                .long("without-compression")
+                .help("never compress responses, regardless of Accept-Encoding"),
+        )
+        .arg(
+            Arg::with_name("compression-min-size")
+                .long("compression-min-size")
+                .value_name("BYTES")
+                .help("responses smaller than this are not compressed"),
+        )
+        .arg(
+            Arg::with_name("compression-content-types")
+                .long("compression-content-types")
+                .value_name("TYPES")
+                .help("comma separated list of content type prefixes that may be compressed"),
+        )
         .arg(Arg::with_name("debug").short("p").long("debug"))
         .arg(Arg::with_name("without-skiplist").long("without-skiplist"))
+        .arg(
+            Arg::with_name("strict-utf8")
+                .long("strict-utf8")
+                .help("fail on commit messages and authors that aren't UTF-8"),
+        )
         .arg(
             Arg::with_name("stdlog")
                 .long("stdlog")
@@ -446,7 +1111,11 @@ fn main() -> Fallible<()> {
         .expect("must set config path");
     let with_scuba = matches.is_present("with-scuba");
     let with_skiplist = !matches.is_present("without-skiplist");
-    let myrouter_port = cmdlib::args::parse_myrouter_port(&matches);
+    let text_decoding = if matches.is_present("strict-utf8") {
+        TextDecoding::Strict
+    } else {
+        TextDecoding::Lossy
+    };
 
     let address = format!("{}:{}", host, port);
 
@@ -467,38 +1136,77 @@ fn main() -> Fallible<()> {
         (None, None)
     };
 
-    let mut runtime = Runtime::new().expect("tokio runtime for blocking jobs");
-    let repo_configs = RepoConfigs::read_configs(config_path)?;
-
-    let ssl_acceptor = if let Some(cert) = matches.value_of("ssl-certificate") {
-        let cert = cert.to_string();
-        let private_key = matches
-            .value_of("ssl-private-key")
-            .expect("must specify ssl private key")
-            .to_string();
-        let ca_pem = matches
-            .value_of("ssl-ca")
-            .expect("must specify CA")
-            .to_string();
-        let ticket_seed = matches
-            .value_of("ssl-ticket-seeds")
-            .unwrap_or(secure_utils::fb_tls::SEED_PATH)
-            .to_string();
-
-        let ssl = secure_utils::SslConfig {
-            cert,
-            private_key,
-            ca_pem,
-        };
-        let acceptor = secure_utils::build_tls_acceptor_builder(ssl.clone())?;
-        Some(secure_utils::fb_tls::tls_acceptor_builder(
-            root_logger.clone(),
-            ssl.clone(),
-            acceptor,
-            ticket_seed,
-        )?)
-    } else {
-        None
+    let mut errors = StartupErrors::new();
+
+    let myrouter_port = errors.config(
+        "parsing the myrouter port",
+        cmdlib::args::try_parse_myrouter_port(&matches),
+    );
+    let repo_configs = errors.config(
+        "reading the repo configs",
+        RepoConfigs::read_configs(config_path),
+    );
+    if let (Some(repo_configs), Some(myrouter_port)) = (&repo_configs, myrouter_port) {
+        errors.config(
+            "checking the myrouter port",
+            check_myrouter_port(repo_configs, myrouter_port),
+        );
+    }
+    let ssl_acceptor = match errors.config("parsing the ssl config", parse_ssl_config(&matches)) {
+        Some(Some((ssl, ticket_seed))) => errors
+            .init(
+                "building the ssl acceptor",
+                build_ssl_acceptor(&root_logger, ssl, ticket_seed),
+            )
+            .map(Some),
+        Some(None) => Some(None),
+        None => None,
+    };
+    let compression = errors.config(
+        "parsing the compression config",
+        parse_compression_config(&matches),
+    );
+    let runtime = errors.init("creating the tokio runtime", Runtime::new());
+
+    let (myrouter_port, repo_configs, ssl_acceptor, compression, mut runtime) = match (
+        myrouter_port,
+        repo_configs,
+        ssl_acceptor,
+        compression,
+        runtime,
+    ) {
+        (
+            Some(myrouter_port),
+            Some(repo_configs),
+            Some(ssl_acceptor),
+            Some(compression),
+            Some(runtime),
+        ) => (
+            myrouter_port,
+            repo_configs,
+            ssl_acceptor,
+            compression,
+            runtime,
+        ),
+        _ => errors.exit(&root_logger),
+    };
+
+    let auditors = repo_configs
+        .repos
+        .iter()
+        .filter(|(_, config)| config.enabled)
+        .filter_map(|(name, config)| {
+            config.audit.as_ref().map(|params| {
+                Auditor::new(params, &config.repotype, myrouter_port).map(|auditor| {
+                    let repo_id = RepositoryId::new(config.repoid);
+                    (name.clone(), (repo_id, auditor))
+                })
+            })
+        })
+        .collect::<Result<HashMap<_, _>, _>>();
+    let auditors = match errors.init("setting up the audit logs", auditors) {
+        Some(auditors) => auditors,
+        None => errors.exit(&root_logger),
     };
 
     let mut scuba_builder = if with_scuba {
@@ -516,8 +1224,13 @@ fn main() -> Fallible<()> {
         repo_configs,
         myrouter_port,
         with_skiplist,
-    ))?;
-    let mononoke = Arc::new(mononoke);
+        text_decoding,
+        Arc::new(SystemTimeUuidProvider),
+    ));
+    let mononoke = match errors.init("opening the repos", mononoke) {
+        Some(mononoke) => Arc::new(mononoke),
+        None => errors.exit(&root_logger),
+    };
 
     if let Ok(port) = thrift_port {
         thrift::make_thrift(
@@ -537,59 +1250,137 @@ fn main() -> Fallible<()> {
     };
 
     let server = server::new(move || {
-        App::with_state(state.clone())
+        let app = App::with_state(state.clone())
+            .middleware(RequestIdMiddleware)
             .middleware(middleware::SLogger::new(actix_logger.clone()))
             .middleware(ScubaMiddleware::new(scuba_builder.clone()))
-            .route(
-                "/health_check",
-                http::Method::GET,
-                |req: HttpRequest<HttpServerState>| {
-                    // removing ScubaSampleBuilder will disable scuba logging for this request.
-                    req.extensions_mut().remove::<ScubaSampleBuilder>();
-                    HttpResponse::Ok().body("I_AM_ALIVE")
-                },
-            )
-            .scope("/{repo}", |repo| {
-                repo.resource("/raw/{changeset}/{path:.*}", |r| {
-                    r.method(http::Method::GET).with_async(get_raw_file)
-                })
-                .resource("/gethgfile/{filenode}", |r| {
-                    r.method(http::Method::GET).with_async(get_hg_file)
-                })
-                .resource("/getfilehistory/{filenode}/{path:.*}", |r| {
-                    r.method(http::Method::GET).with_async(get_file_history)
-                })
-                .resource("/is_ancestor/{ancestor}/{descendant}", |r| {
-                    r.method(http::Method::GET).with_async(is_ancestor)
-                })
-                .resource("/list/{changeset}/{path:.*}", |r| {
-                    r.method(http::Method::GET).with_async(list_directory)
-                })
-                .resource("/blob/{hash}", |r| {
-                    r.method(http::Method::GET).with_async(get_blob_content)
-                })
-                .resource("/tree/{hash}", |r| {
-                    r.method(http::Method::GET).with_async(get_tree)
-                })
-                .resource("/changeset/{hash}", |r| {
-                    r.method(http::Method::GET).with_async(get_changeset)
-                })
-                .resource("/lfs/download/{oid}", |r| {
-                    r.method(http::Method::GET).with_async(download_large_file)
-                })
-                .resource("/objects/batch", |r| {
-                    r.method(http::Method::POST).with_async(lfs_batch)
-                })
-                .resource("/lfs/upload/{oid}", |r| {
-                    r.method(http::Method::PUT).with_async(upload_large_file)
-                })
+            .middleware(AuditMiddleware::new(
+                actix_logger.clone(),
+                auditors.clone(),
+                state.mononoke.time_uuid(),
+            ));
+        let app = match compression.clone() {
+            Some(compression) => app.middleware(CompressionMiddleware::new(compression)),
+            None => app,
+        };
+
+        app.route(
+            "/health_check",
+            http::Method::GET,
+            |req: HttpRequest<HttpServerState>| {
+                // removing ScubaSampleBuilder will disable scuba logging for this request.
+                req.extensions_mut().remove::<ScubaSampleBuilder>();
+                HttpResponse::Ok().body("I_AM_ALIVE")
+            },
+        )
+        .route(
+            "/openapi.json",
+            http::Method::GET,
+            |_: HttpRequest<HttpServerState>| HttpResponse::Ok().json(openapi::document()),
+        )
+        .route(
+            "/debug/cache",
+            http::Method::GET,
+            |req: HttpRequest<HttpServerState>| {
+                HttpResponse::Ok().json(req.state().mononoke.cache_report())
+            },
+        )
+        .scope("/{repo}", |repo| {
+            repo.resource("/raw/{changeset}/{path:.*}", |r| {
+                r.method(http::Method::GET).with_async(get_raw_file)
+            })
+            .resource("/gethgfile/{filenode}", |r| {
+                r.method(http::Method::GET).with_async(get_hg_file)
             })
+            .resource("/getfilehistory/{filenode}/{path:.*}", |r| {
+                r.method(http::Method::GET).with_async(get_file_history)
+            })
+            .resource("/is_ancestor/{ancestor}/{descendant}", |r| {
+                r.method(http::Method::GET).with_async(is_ancestor)
+            })
+            .resource("/is_ancestor_batch", |r| {
+                r.method(http::Method::POST).with_async(is_ancestor_batch)
+            })
+            .resource("/contains/{bookmark}/{hash}", |r| {
+                r.method(http::Method::GET).with_async(contains)
+            })
+            .resource("/common_ancestor/{cs1}/{cs2}", |r| {
+                r.method(http::Method::GET).with_async(common_ancestor)
+            })
+            .resource("/merge_conflicts/{left}/{right}", |r| {
+                r.method(http::Method::GET).with_async(merge_conflicts)
+            })
+            .resource("/graph/{revision}", |r| {
+                r.method(http::Method::GET).with_async(get_graph)
+            })
+            .resource("/range/{from}/{to}", |r| {
+                r.method(http::Method::GET).with_async(range)
+            })
+            .resource("/list/{changeset}/{path:.*}", |r| {
+                r.method(http::Method::GET).with_async(list_directory)
+            })
+            .resource("/blob/{hash}", |r| {
+                r.method(http::Method::GET).with_async(get_blob_content)
+            })
+            .resource("/rawblob/{key}", |r| {
+                r.method(http::Method::GET).with_async(get_raw_blob)
+            })
+            .resource("/rawbookmarks", |r| {
+                r.method(http::Method::GET).with_async(get_raw_bookmarks)
+            })
+            .resource("/rawchangeset/{changeset}", |r| {
+                r.method(http::Method::GET).with_async(get_raw_changeset)
+            })
+            .resource("/multiget/{changeset}", |r| {
+                r.method(http::Method::POST).with_async(multi_get)
+            })
+            .resource("/tree/{hash}", |r| {
+                r.method(http::Method::GET).with_async(get_tree)
+            })
+            .resource("/changeset/{hash}", |r| {
+                r.method(http::Method::GET).with_async(get_changeset)
+            })
+            .resource("/lfs/download/{oid}", |r| {
+                r.method(http::Method::GET).with_async(download_large_file)
+            })
+            .resource("/objects/batch", |r| {
+                r.method(http::Method::POST).with_async(lfs_batch)
+            })
+            .resource("/lfs/upload/{oid}", |r| {
+                r.method(http::Method::PUT).with_async(upload_large_file)
+            })
+            .resource("/status", |r| {
+                r.method(http::Method::GET).with_async(get_status)
+            })
+            .resource("/health", |r| {
+                r.method(http::Method::GET).with_async(get_health)
+            })
+            .resource("/replicas", |r| {
+                r.method(http::Method::GET).with_async(get_replicas)
+            })
+            .resource("/push_usage", |r| {
+                r.method(http::Method::GET).with_async(get_push_usage)
+            })
+            .resource("/sizes/{changeset}", |r| {
+                r.method(http::Method::POST).with_async(get_sizes)
+            })
+            .resource("/sizes/{changeset}/{path:.*}", |r| {
+                r.method(http::Method::GET).with_async(get_size)
+            })
+            .resource("/exists/{changeset}", |r| {
+                r.method(http::Method::POST).with_async(paths_exist)
+            })
+        })
     });
 
     let server = if let Some(acceptor) = ssl_acceptor {
-        server.bind_ssl(address, acceptor)?
+        server.bind_ssl(address.clone(), acceptor)
     } else {
-        server.bind(address)?
+        server.bind(address.clone())
+    };
+    let server = match errors.init(format!("binding to {}", address), server) {
+        Some(server) => server,
+        None => errors.exit(&root_logger),
     };
 
     let address = server.addrs()[0];
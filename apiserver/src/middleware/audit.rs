@@ -0,0 +1,96 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_web::{
+    middleware::{Finished, Middleware},
+    HttpRequest, HttpResponse,
+};
+use futures::Future;
+use serde_json::json;
+use slog::{warn, Logger};
+
+use audit_log::{AuditRecord, Auditor};
+use context::{CoreContext, TimeUuidProvider};
+use mononoke_types::{DateTime, RepositoryId};
+use scuba_ext::ScubaSampleBuilder;
+use sshrelay::SshEnvVars;
+use tracing::TraceContext;
+
+use super::request_id::RequestId;
+
+/// Writes a record of every request to an audited repo to the repo's audit log. Requests to
+/// other repos are ignored.
+pub struct AuditMiddleware {
+    logger: Logger,
+    // Keyed by repo name, which is the first component of the request path
+    auditors: HashMap<String, (RepositoryId, Auditor)>,
+    time_uuid: Arc<TimeUuidProvider>,
+}
+
+impl AuditMiddleware {
+    pub fn new(
+        logger: Logger,
+        auditors: HashMap<String, (RepositoryId, Auditor)>,
+        time_uuid: Arc<TimeUuidProvider>,
+    ) -> Self {
+        AuditMiddleware {
+            logger,
+            auditors,
+            time_uuid,
+        }
+    }
+}
+
+impl<S> Middleware<S> for AuditMiddleware {
+    fn finish(&self, req: &HttpRequest<S>, resp: &HttpResponse) -> Finished {
+        let reponame = req.path().trim_start_matches('/').split('/').next();
+        let (repo_id, auditor) = match reponame.and_then(|name| self.auditors.get(name)) {
+            Some((repo_id, auditor)) => (*repo_id, auditor),
+            None => return Finished::Done,
+        };
+
+        let ctx = CoreContext::new(
+            RequestId::get(req),
+            self.logger.clone(),
+            ScubaSampleBuilder::with_discard(),
+            None,
+            TraceContext::default(),
+            None,
+            SshEnvVars::default(),
+        )
+        .with_time_uuid_provider(self.time_uuid.clone());
+
+        // The apiserver doesn't know who its clients are beyond their address
+        let record = AuditRecord {
+            repo_id,
+            timestamp: DateTime::new(ctx.now()),
+            identity: None,
+            client: req
+                .connection_info()
+                .remote()
+                .map(|remote| remote.to_string()),
+            command: format!("{} {}", req.method(), req.path()),
+            accessed: json!({
+                "path": req.path(),
+                "query": req.query_string(),
+                "status_code": resp.status().as_u16(),
+            }),
+            bytes_served: resp.response_size(),
+        };
+
+        let logger = self.logger.clone();
+        tokio::spawn(
+            auditor
+                .audit(ctx, record)
+                .map_err(move |err| warn!(logger, "failed to write audit record: {:?}", err)),
+        );
+
+        Finished::Done
+    }
+}
@@ -0,0 +1,295 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use actix_web::{
+    dev::BodyStream,
+    error::{ErrorInternalServerError, Result},
+    http::{
+        header::{self, HeaderValue},
+        ContentEncoding,
+    },
+    middleware::{Middleware, Response},
+    Body, HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use futures::{future, Future, Stream};
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    fn header_value(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    /// Binary responses smaller than this are sent uncompressed. Streaming responses are always
+    /// compressed since their size is unknown upfront.
+    pub min_size: usize,
+    /// Only responses whose Content-Type starts with one of these are compressed.
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 1024,
+            content_types: vec![
+                "application/json".to_string(),
+                "application/octet-stream".to_string(),
+                "text/".to_string(),
+            ],
+        }
+    }
+}
+
+/// Pick the encoding to use from an Accept-Encoding header value. The encoding with the highest
+/// quality value wins; zstd is preferred over gzip on ties since it is cheaper to produce.
+pub fn negotiate_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, u32)> = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';').map(|part| part.trim());
+        let encoding = match parts.next() {
+            Some(name) if name.eq_ignore_ascii_case("zstd") => Encoding::Zstd,
+            Some(name) if name.eq_ignore_ascii_case("gzip") => Encoding::Gzip,
+            _ => continue,
+        };
+        // Quality values have at most three decimal digits, so compare them as integers.
+        let quality = parts
+            .filter_map(|param| {
+                if param.starts_with("q=") {
+                    param[2..].parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .map(|q| (q * 1000.0) as u32)
+            .unwrap_or(1000);
+
+        if quality == 0 {
+            continue;
+        }
+
+        best = match best {
+            Some((_, best_quality)) if best_quality > quality => best,
+            Some((Encoding::Zstd, best_quality)) if best_quality == quality => best,
+            _ => Some((encoding, quality)),
+        };
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(
+                Vec::new(),
+                ZSTD_LEVEL,
+            )?),
+        })
+    }
+
+    /// Compress a chunk and return whatever output the encoder produced so far.
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let output = match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                mem::replace(encoder.get_mut(), Vec::new())
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                mem::replace(encoder.get_mut(), Vec::new())
+            }
+        };
+        Ok(Bytes::from(output))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+pub fn compress(encoding: Encoding, content: &[u8]) -> io::Result<Bytes> {
+    let mut encoder = Encoder::new(encoding)?;
+    let mut output = encoder.write(content)?.to_vec();
+    output.extend_from_slice(&encoder.finish()?);
+    Ok(Bytes::from(output))
+}
+
+fn compress_stream(encoding: Encoding, stream: BodyStream) -> io::Result<BodyStream> {
+    let encoder = Arc::new(Mutex::new(Some(Encoder::new(encoding)?)));
+
+    let tail = future::lazy({
+        let encoder = encoder.clone();
+        move || {
+            let encoder = encoder
+                .lock()
+                .expect("lock poisoned")
+                .take()
+                .expect("encoder finished twice");
+            encoder.finish().map_err(ErrorInternalServerError)
+        }
+    })
+    .into_stream();
+
+    let body = stream
+        .and_then(move |chunk| {
+            let mut encoder = encoder.lock().expect("lock poisoned");
+            encoder
+                .as_mut()
+                .expect("encoder already finished")
+                .write(&chunk)
+                .map_err(ErrorInternalServerError)
+        })
+        .chain(tail)
+        .filter(|chunk| !chunk.is_empty());
+
+    Ok(Box::new(body))
+}
+
+pub struct CompressionMiddleware {
+    config: CompressionConfig,
+}
+
+impl CompressionMiddleware {
+    pub fn new(config: CompressionConfig) -> Self {
+        Self { config }
+    }
+
+    fn is_compressible(&self, resp: &HttpResponse) -> bool {
+        if resp.headers().contains_key(header::CONTENT_ENCODING) {
+            return false;
+        }
+
+        let content_type = match resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+
+        self.config
+            .content_types
+            .iter()
+            .any(|allowed| content_type.starts_with(allowed.as_str()))
+    }
+}
+
+impl<S> Middleware<S> for CompressionMiddleware {
+    fn response(&self, req: &HttpRequest<S>, mut resp: HttpResponse) -> Result<Response> {
+        let encoding = req
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .and_then(negotiate_encoding);
+
+        let encoding = match encoding {
+            Some(encoding) if self.is_compressible(&resp) => encoding,
+            _ => return Ok(Response::Done(resp)),
+        };
+
+        let body = match resp.replace_body(Body::Empty) {
+            Body::Binary(content) => {
+                if content.len() < self.config.min_size {
+                    resp.set_body(Body::Binary(content));
+                    return Ok(Response::Done(resp));
+                }
+                Body::Binary(compress(encoding, content.as_ref())?.into())
+            }
+            Body::Streaming(stream) => Body::Streaming(compress_stream(encoding, stream)?),
+            body => {
+                resp.set_body(body);
+                return Ok(Response::Done(resp));
+            }
+        };
+
+        resp.set_body(body);
+        // We did the encoding ourselves, make sure actix doesn't do it again.
+        resp.set_content_encoding(ContentEncoding::Identity);
+        resp.headers_mut().insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.header_value()),
+        );
+        resp.headers_mut().insert(
+            header::VARY,
+            HeaderValue::from_static("Accept-Encoding"),
+        );
+
+        Ok(Response::Done(resp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(negotiate_encoding("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_encoding("gzip, zstd"), Some(Encoding::Zstd));
+        assert_eq!(negotiate_encoding("zstd;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_encoding("gzip;q=0, zstd;q=0"), None);
+        assert_eq!(negotiate_encoding("br, deflate"), None);
+        assert_eq!(negotiate_encoding(""), None);
+    }
+
+    #[test]
+    fn gzip_roundtrip() {
+        let content = b"hello hello hello hello hello".to_vec();
+        let compressed = compress(Encoding::Gzip, &content).unwrap();
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(compressed.as_ref())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, content);
+    }
+
+    #[test]
+    fn zstd_roundtrip() {
+        let content = b"hello hello hello hello hello".to_vec();
+        let compressed = compress(Encoding::Zstd, &content).unwrap();
+
+        let decompressed = zstd::stream::decode_all(compressed.as_ref()).unwrap();
+        assert_eq!(decompressed, content);
+    }
+}
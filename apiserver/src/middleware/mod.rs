@@ -4,9 +4,15 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+mod audit;
+mod compression;
+mod request_id;
 mod response_time;
 mod scuba;
 mod slogger;
 
+pub use self::audit::AuditMiddleware;
+pub use self::compression::{CompressionConfig, CompressionMiddleware};
+pub use self::request_id::{RequestId, RequestIdMiddleware};
 pub use self::scuba::ScubaMiddleware;
 pub use self::slogger::SLogger;
@@ -0,0 +1,87 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::str::FromStr;
+
+use actix_web::{
+    error::Result,
+    http::header::{self, HeaderName, HeaderValue},
+    middleware::{Middleware, Response, Started},
+    HttpRequest, HttpResponse,
+};
+use uuid::Uuid;
+
+use crate::errors::{generic_error_response, ErrorKind};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Identifier of a single HTTP request. It is used as the session id of the request's
+/// `CoreContext`, logged to scuba and echoed back to the client so that support requests can be
+/// correlated with our logs.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestId(pub Uuid);
+
+impl RequestId {
+    /// Request id of the given request. Requests that did not go through the middleware (which
+    /// should only happen in tests) get a fresh id.
+    pub fn get<S>(req: &HttpRequest<S>) -> Uuid {
+        req.extensions()
+            .get::<RequestId>()
+            .map(|id| id.0)
+            .unwrap_or_else(Uuid::new_v4)
+    }
+}
+
+pub struct RequestIdMiddleware;
+
+impl<S> Middleware<S> for RequestIdMiddleware {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        // Proxies in front of us may have assigned an id already, keep using it if it's valid.
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| Uuid::from_str(value).ok())
+            .unwrap_or_else(Uuid::new_v4);
+
+        req.extensions_mut().insert(RequestId(request_id));
+
+        Ok(Started::Done)
+    }
+
+    fn response(&self, req: &HttpRequest<S>, resp: HttpResponse) -> Result<Response> {
+        let request_id = RequestId::get(req).to_string();
+
+        let mut resp = match resp.error() {
+            Some(err) => {
+                let mut new_resp = match err.as_fail().downcast_ref::<ErrorKind>() {
+                    Some(err) => err.error_response_with_request_id(Some(&request_id)),
+                    None => {
+                        generic_error_response(resp.status(), err.to_string(), Some(&request_id))
+                    }
+                };
+                // The body is replaced, so any header describing its encoding is stale.
+                for (name, value) in resp.headers() {
+                    if name != header::CONTENT_ENCODING
+                        && name != header::CONTENT_LENGTH
+                        && !new_resp.headers().contains_key(name)
+                    {
+                        new_resp.headers_mut().insert(name.clone(), value.clone());
+                    }
+                }
+                new_resp
+            }
+            None => resp,
+        };
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            resp.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+
+        Ok(Response::Done(resp))
+    }
+}
@@ -11,6 +11,7 @@ use actix_web::{
 };
 use scuba_ext::ScubaSampleBuilder;
 
+use super::request_id::RequestId;
 use super::response_time::ResponseTime;
 
 pub struct ScubaMiddleware {
@@ -38,6 +39,7 @@ impl<S> Middleware<S> for ScubaMiddleware {
         }
 
         scuba
+            .add("request_id", RequestId::get(req).to_string())
             .add("type", "http")
             .add("method", req.method().to_string())
             .add("path", req.path());
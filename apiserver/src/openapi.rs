@@ -0,0 +1,1056 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// The OpenAPI 3 description of the HTTP API, served at /openapi.json.
+//
+// The document is built from the table of routes and the schemas of the response models below,
+// which are compiled in with the server. The tests check the table against the routes that
+// main.rs registers, and the schemas against what the models serialize to, so that neither can
+// drift from the server without failing the build.
+
+use serde_json::{json, Map, Value};
+
+const OPENAPI_VERSION: &str = "3.0.2";
+const API_VERSION: &str = "0.0.1";
+
+/// What a route responds with on success
+enum Body {
+    /// JSON document of the component schema with this name
+    Json(&'static str),
+    /// JSON array of the component schema with this name
+    JsonArray(&'static str),
+    /// JSON object whose values are arrays of the component schema with this name
+    JsonMapOfArrays(&'static str),
+    /// Raw bytes of the given content type
+    Bytes(&'static str),
+    /// Nothing
+    Empty,
+}
+
+struct Route {
+    method: &'static str,
+    /// Path below the repo, with parameters in braces
+    path: &'static str,
+    summary: &'static str,
+    /// Component schema of the JSON body of the request, if any
+    request: Option<&'static str>,
+    response: Body,
+}
+
+const ROUTES: &[Route] = &[
+    Route {
+        method: "get",
+        path: "/raw/{changeset}/{path}",
+        summary: "Content of a file at a changeset",
+        request: None,
+        response: Body::Bytes("application/octet-stream"),
+    },
+    Route {
+        method: "get",
+        path: "/gethgfile/{filenode}",
+        summary: "Mercurial envelope of a file",
+        request: None,
+        response: Body::Bytes("application/octet-stream"),
+    },
+    Route {
+        method: "get",
+        path: "/getfilehistory/{filenode}/{path}",
+        summary: "History of a file, in pages. The x-mononoke-history-cursor header of a page \
+                  that is not the last one is the cursor query parameter of the next one",
+        request: None,
+        response: Body::Bytes("application/octet-stream"),
+    },
+    Route {
+        method: "get",
+        path: "/is_ancestor/{ancestor}/{descendant}",
+        summary: "Whether a changeset is an ancestor of another, as `true` or `false`",
+        request: None,
+        response: Body::Bytes("application/octet-stream"),
+    },
+    Route {
+        method: "post",
+        path: "/is_ancestor_batch",
+        summary: "Whether each changeset of many pairs is an ancestor of the other",
+        request: Some("AncestryRequest"),
+        response: Body::JsonArray("Ancestry"),
+    },
+    Route {
+        method: "get",
+        path: "/contains/{bookmark}/{hash}",
+        summary: "Whether a bookmark contains a changeset, as `true` or `false`",
+        request: None,
+        response: Body::Bytes("application/octet-stream"),
+    },
+    Route {
+        method: "get",
+        path: "/common_ancestor/{cs1}/{cs2}",
+        summary: "The closest common ancestor of two changesets, their merge base",
+        request: None,
+        response: Body::Json("CommonAncestor"),
+    },
+    Route {
+        method: "get",
+        path: "/merge_conflicts/{left}/{right}",
+        summary: "Merge base of two changesets, and the files that would conflict if they were \
+                  merged",
+        request: None,
+        response: Body::Json("MergeConflictReport"),
+    },
+    Route {
+        method: "get",
+        path: "/graph/{revision}",
+        summary: "The commit graph near a changeset: at most `limit` (100 by default) of its \
+                  ancestors, children first",
+        request: None,
+        response: Body::JsonArray("GraphNode"),
+    },
+    Route {
+        method: "get",
+        path: "/range/{from}/{to}",
+        summary: "Changesets of `from::to`, the descendants of `from` that are ancestors of `to`, \
+                  highest generation first: at most `limit` (100 by default) of them. Ranges \
+                  that span more than 100000 generations are refused",
+        request: None,
+        response: Body::JsonArray("RangeChangeset"),
+    },
+    Route {
+        method: "get",
+        path: "/list/{changeset}/{path}",
+        summary: "Entries of a directory at a changeset, in order of their names. With a `limit` \
+                  or a `token`, in pages: the x-mononoke-continuation-token header of a page \
+                  that is not the last one is the token query parameter of the next one",
+        request: None,
+        response: Body::JsonArray("Entry"),
+    },
+    Route {
+        method: "get",
+        path: "/blob/{hash}",
+        summary: "Content of a file by its hash",
+        request: None,
+        response: Body::Bytes("application/octet-stream"),
+    },
+    Route {
+        method: "get",
+        path: "/rawblob/{key}",
+        summary: "A blob of the repo's blobstore as it is stored, by its key",
+        request: None,
+        response: Body::Bytes("application/octet-stream"),
+    },
+    Route {
+        method: "get",
+        path: "/rawbookmarks",
+        summary: "All the bookmarks of the repo, by the bonsai changesets they point to",
+        request: None,
+        response: Body::Json("RawBookmarks"),
+    },
+    Route {
+        method: "get",
+        path: "/rawchangeset/{changeset}",
+        summary: "What the repo's SQL stores have about a bonsai changeset",
+        request: None,
+        response: Body::Json("RawChangeset"),
+    },
+    Route {
+        method: "post",
+        path: "/multiget/{changeset}",
+        summary: "Content of many files at a changeset, streamed as msgpack",
+        request: Some("MultiGetRequest"),
+        response: Body::Bytes("application/x-msgpack"),
+    },
+    Route {
+        method: "get",
+        path: "/tree/{hash}",
+        summary: "Entries of a tree, with their sizes and content hashes",
+        request: None,
+        response: Body::JsonArray("EntryWithSizeAndContentHash"),
+    },
+    Route {
+        method: "get",
+        path: "/changeset/{hash}",
+        summary: "A changeset",
+        request: None,
+        response: Body::Json("Changeset"),
+    },
+    Route {
+        method: "get",
+        path: "/lfs/download/{oid}",
+        summary: "Content of a large file",
+        request: None,
+        response: Body::Bytes("application/octet-stream"),
+    },
+    Route {
+        method: "post",
+        path: "/objects/batch",
+        summary: "git-lfs batch API",
+        request: Some("LfsBatchRequest"),
+        response: Body::Json("LfsBatchResponse"),
+    },
+    Route {
+        method: "put",
+        path: "/lfs/upload/{oid}",
+        summary: "Upload a large file",
+        request: None,
+        response: Body::Empty,
+    },
+    Route {
+        method: "get",
+        path: "/status",
+        summary: "Status of the repo",
+        request: None,
+        response: Body::Json("RepoStatus"),
+    },
+    Route {
+        method: "get",
+        path: "/health",
+        summary: "Operational signals of the repo: its last push, and the backlogs of the jobs \
+                  that process its pushes",
+        request: None,
+        response: Body::Json("RepoHealth"),
+    },
+    Route {
+        method: "get",
+        path: "/replicas",
+        summary: "Replicas of the SQL stores of the repo, by store name",
+        request: None,
+        response: Body::JsonMapOfArrays("Replica"),
+    },
+    Route {
+        method: "get",
+        path: "/push_usage",
+        summary: "What users and teams pushed to the repo over the push quota window",
+        request: None,
+        response: Body::Json("PushUsageReport"),
+    },
+    Route {
+        method: "post",
+        path: "/sizes/{changeset}",
+        summary: "Total size of many paths at a changeset",
+        request: Some("PathsRequest"),
+        response: Body::JsonArray("PathSize"),
+    },
+    Route {
+        method: "get",
+        path: "/sizes/{changeset}/{path}",
+        summary: "Total size of a path at a changeset",
+        request: None,
+        response: Body::JsonArray("PathSize"),
+    },
+    Route {
+        method: "post",
+        path: "/exists/{changeset}",
+        summary: "Whether many paths exist at a changeset",
+        request: Some("PathsRequest"),
+        response: Body::JsonArray("PathExistence"),
+    },
+];
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn parameter(name: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" },
+    })
+}
+
+fn operation(route: &Route) -> Value {
+    let mut parameters = vec![parameter("repo")];
+    for segment in route.path.split('/') {
+        if segment.starts_with('{') && segment.ends_with('}') {
+            parameters.push(parameter(&segment[1..segment.len() - 1]));
+        }
+    }
+
+    let success = match route.response {
+        Body::Json(name) => json!({
+            "description": "OK",
+            "content": { "application/json": { "schema": schema_ref(name) } },
+        }),
+        Body::JsonArray(name) => json!({
+            "description": "OK",
+            "content": {
+                "application/json": { "schema": { "type": "array", "items": schema_ref(name) } },
+            },
+        }),
+        Body::JsonMapOfArrays(name) => json!({
+            "description": "OK",
+            "content": {
+                "application/json": {
+                    "schema": {
+                        "type": "object",
+                        "additionalProperties": { "type": "array", "items": schema_ref(name) },
+                    },
+                },
+            },
+        }),
+        Body::Bytes(content_type) => json!({
+            "description": "OK",
+            "content": { content_type: { "schema": { "type": "string", "format": "binary" } } },
+        }),
+        Body::Empty => json!({ "description": "OK" }),
+    };
+    let error = json!({
+        "description": "Error",
+        "content": { "application/json": { "schema": schema_ref("Error") } },
+    });
+
+    let mut operation = json!({
+        "summary": route.summary,
+        "parameters": parameters,
+        "responses": { "200": success, "default": error },
+    });
+    if let Some(request) = route.request {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": schema_ref(request) } },
+        });
+    }
+    operation
+}
+
+fn schemas() -> Value {
+    json!({
+        "FileType": {
+            "type": "string",
+            "enum": ["file", "tree", "executable", "symlink", "submodule"],
+        },
+        "Entry": {
+            "type": "object",
+            "required": ["name", "type", "hash"],
+            "properties": {
+                "name": { "type": "string" },
+                "type": schema_ref("FileType"),
+                "hash": { "type": "string", "description": "Empty for submodules" },
+                "symlink_target": {
+                    "type": "string",
+                    "description": "Only listed if symlink targets are asked for",
+                },
+                "symlink_target_hex": {
+                    "type": "string",
+                    "description": "Listed instead of symlink_target if it isn't valid UTF-8",
+                },
+                "submodule_commit": {
+                    "type": "string",
+                    "description": "Only listed if submodules are asked for",
+                },
+            },
+        },
+        "EntryWithSizeAndContentHash": {
+            "type": "object",
+            "required": ["name", "type", "hash", "size", "content_sha1"],
+            "properties": {
+                "name": { "type": "string" },
+                "type": schema_ref("FileType"),
+                "hash": { "type": "string" },
+                "size": {
+                    "type": "integer",
+                    "nullable": true,
+                    "description": "Size of a file, or number of entries of a tree",
+                },
+                "content_sha1": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Null for trees",
+                },
+            },
+        },
+        "Changeset": {
+            "type": "object",
+            "required": [
+                "commit_hash", "manifest", "comment", "date", "author", "parents", "extra",
+                "lossy",
+            ],
+            "properties": {
+                "commit_hash": { "type": "string" },
+                "manifest": { "type": "string" },
+                "comment": { "type": "string" },
+                "date": { "type": "string", "format": "date-time" },
+                "author": { "type": "string" },
+                "parents": { "type": "array", "items": { "type": "string" } },
+                "extra": {
+                    "type": "object",
+                    "description": "Values are bytes",
+                    "additionalProperties": { "type": "array", "items": { "type": "integer" } },
+                },
+                "lossy": {
+                    "type": "boolean",
+                    "description": "Whether the comment or the author isn't UTF-8, in which case \
+                                    its invalid bytes are replaced with U+FFFD",
+                },
+                "comment_bytes": {
+                    "type": "array",
+                    "items": { "type": "integer" },
+                    "description": "The bytes of the comment, only if it isn't UTF-8",
+                },
+                "author_bytes": {
+                    "type": "array",
+                    "items": { "type": "integer" },
+                    "description": "The bytes of the author, only if it isn't UTF-8",
+                },
+            },
+        },
+        "Maintenance": {
+            "type": "object",
+            "required": ["start", "end", "message"],
+            "properties": {
+                "start": { "type": "string", "format": "date-time" },
+                "end": { "type": "string", "format": "date-time" },
+                "message": { "type": "string" },
+            },
+        },
+        "RepoStatus": {
+            "type": "object",
+            "required": ["maintenance", "overrides"],
+            "properties": {
+                "maintenance": {
+                    "allOf": [schema_ref("Maintenance")],
+                    "nullable": true,
+                    "description": "Writes are refused during maintenance",
+                },
+                "overrides": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string" },
+                    "description": "Overridden tunables, by name, as this server last polled them",
+                },
+            },
+        },
+        "Backlog": {
+            "type": "object",
+            "required": ["entries", "oldest", "lag_secs"],
+            "properties": {
+                "entries": { "type": "integer" },
+                "oldest": { "type": "string", "format": "date-time", "nullable": true },
+                "lag_secs": {
+                    "type": "integer",
+                    "description": "How long ago the oldest entry was added, 0 without entries",
+                },
+            },
+        },
+        "RepoHealth": {
+            "type": "object",
+            "required": ["last_push", "hg_sync", "hg_derivation", "blobstore_sync"],
+            "properties": {
+                "last_push": { "type": "string", "format": "date-time", "nullable": true },
+                "hg_sync": {
+                    "allOf": [schema_ref("Backlog")],
+                    "nullable": true,
+                    "description": "Bookmark moves not replayed to hg yet, only for repos \
+                                    synced to hg",
+                },
+                "hg_derivation": {
+                    "allOf": [schema_ref("Backlog")],
+                    "description": "Changesets whose hg data wasn't derived yet",
+                },
+                "blobstore_sync": {
+                    "allOf": [schema_ref("Backlog")],
+                    "nullable": true,
+                    "description": "Blobs not written to all blobstores yet, only for repos \
+                                    with remote blobstores",
+                },
+            },
+        },
+        "Replica": {
+            "type": "object",
+            "required": ["address", "primary", "healthy", "consecutive_failures", "last_error"],
+            "properties": {
+                "address": { "type": "string" },
+                "primary": { "type": "boolean" },
+                "healthy": { "type": "boolean" },
+                "consecutive_failures": { "type": "integer" },
+                "last_error": { "type": "string", "nullable": true },
+            },
+        },
+        "IdentityPushUsage": {
+            "type": "object",
+            "required": ["identity", "bytes", "files", "pushes"],
+            "properties": {
+                "identity": { "type": "string" },
+                "bytes": { "type": "integer" },
+                "files": { "type": "integer" },
+                "pushes": { "type": "integer" },
+            },
+        },
+        "TeamPushUsage": {
+            "type": "object",
+            "required": [
+                "name", "bytes", "files", "pushes", "soft_limit_bytes", "hard_limit_bytes",
+            ],
+            "properties": {
+                "name": { "type": "string" },
+                "bytes": { "type": "integer" },
+                "files": { "type": "integer" },
+                "pushes": { "type": "integer" },
+                "soft_limit_bytes": { "type": "integer", "nullable": true },
+                "hard_limit_bytes": { "type": "integer", "nullable": true },
+            },
+        },
+        "PushUsageReport": {
+            "type": "object",
+            "required": ["window_days", "identities", "teams"],
+            "properties": {
+                "window_days": { "type": "integer" },
+                "identities": {
+                    "type": "array",
+                    "items": schema_ref("IdentityPushUsage"),
+                    "description": "The users who pushed the most bytes first",
+                },
+                "teams": { "type": "array", "items": schema_ref("TeamPushUsage") },
+            },
+        },
+        "PathSize": {
+            "type": "object",
+            "required": ["path", "total_size", "file_count", "dir_count"],
+            "properties": {
+                "path": { "type": "string" },
+                "total_size": { "type": "integer" },
+                "file_count": { "type": "integer" },
+                "dir_count": { "type": "integer" },
+            },
+        },
+        "PathExistence": {
+            "type": "object",
+            "required": ["path", "exists"],
+            "properties": {
+                "path": { "type": "string" },
+                "exists": { "type": "boolean" },
+                "type": schema_ref("FileType"),
+                "size": { "type": "integer", "description": "Only listed for files" },
+            },
+        },
+        "MergeConflict": {
+            "type": "object",
+            "required": ["path", "kind"],
+            "properties": {
+                "path": { "type": "string" },
+                "kind": {
+                    "type": "string",
+                    "enum": ["both_modified", "add_add", "delete_modify"],
+                },
+            },
+        },
+        "MergeConflictReport": {
+            "type": "object",
+            "required": ["merge_base", "conflicts"],
+            "properties": {
+                "merge_base": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Null if the changesets have no common ancestor",
+                },
+                "conflicts": {
+                    "type": "array",
+                    "items": schema_ref("MergeConflict"),
+                    "description": "Ordered by path",
+                },
+            },
+        },
+        "GraphNode": {
+            "type": "object",
+            "required": ["hash", "parents", "generation", "bookmarks", "phase"],
+            "properties": {
+                "hash": { "type": "string" },
+                "parents": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Also lists the parents that aren't in the response",
+                },
+                "generation": { "type": "integer" },
+                "bookmarks": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Bookmarks that point to the changeset",
+                },
+                "phase": { "type": "string", "enum": ["public", "draft"] },
+            },
+        },
+        "RangeChangeset": {
+            "type": "object",
+            "required": ["hash", "generation"],
+            "properties": {
+                "hash": { "type": "string" },
+                "generation": { "type": "integer" },
+            },
+        },
+        "RawBookmarks": {
+            "type": "object",
+            "additionalProperties": { "type": "string" },
+            "description": "Bonsai changeset ids, by bookmark",
+        },
+        "RawChangeset": {
+            "type": "object",
+            "required": ["hg", "parents", "generation"],
+            "properties": {
+                "hg": { "type": "string", "description": "Mercurial hash of the changeset" },
+                "parents": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Bonsai ids of the parents",
+                },
+                "generation": { "type": "integer" },
+            },
+        },
+        "MultiGetRequest": {
+            "type": "object",
+            "properties": {
+                "paths": { "type": "array", "items": { "type": "string" } },
+                "content_ids": { "type": "array", "items": { "type": "string" } },
+                "max_bytes": { "type": "integer" },
+            },
+        },
+        "Ancestry": {
+            "type": "object",
+            "required": ["ancestor", "descendant", "is_ancestor"],
+            "properties": {
+                "ancestor": { "type": "string" },
+                "descendant": { "type": "string" },
+                "is_ancestor": { "type": "boolean" },
+            },
+        },
+        "CommonAncestor": {
+            "type": "object",
+            "required": ["left", "right", "ancestor"],
+            "properties": {
+                "left": { "type": "string" },
+                "right": { "type": "string" },
+                "ancestor": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Null if the changesets have no common ancestor",
+                },
+            },
+        },
+        "PathsRequest": {
+            "type": "object",
+            "required": ["paths"],
+            "properties": {
+                "paths": { "type": "array", "items": { "type": "string" } },
+            },
+        },
+        "AncestryRequest": {
+            "type": "object",
+            "required": ["pairs"],
+            "properties": {
+                "pairs": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["ancestor", "descendant"],
+                        "properties": {
+                            "ancestor": { "type": "string" },
+                            "descendant": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        },
+        "LfsBatchRequest": {
+            "type": "object",
+            "description": "See https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md",
+        },
+        "LfsBatchResponse": {
+            "type": "object",
+            "description": "See https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md",
+        },
+        "CachePoolUsage": {
+            "type": "object",
+            "required": ["name", "size_bytes", "used_bytes"],
+            "properties": {
+                "name": { "type": "string" },
+                "size_bytes": { "type": "integer" },
+                "used_bytes": { "type": "integer" },
+            },
+        },
+        "RouteCacheUsage": {
+            "type": "object",
+            "required": ["route", "hits", "misses", "hit_ratio", "avg_fill_ms"],
+            "properties": {
+                "route": { "type": "string" },
+                "hits": { "type": "integer" },
+                "misses": { "type": "integer" },
+                "hit_ratio": { "type": "number" },
+                "avg_fill_ms": {
+                    "type": "integer",
+                    "nullable": true,
+                    "description": "Average time to compute an entry that wasn't cached",
+                },
+            },
+        },
+        "CacheReport": {
+            "type": "object",
+            "required": ["pools", "routes"],
+            "properties": {
+                "pools": { "type": "array", "items": schema_ref("CachePoolUsage") },
+                "routes": { "type": "array", "items": schema_ref("RouteCacheUsage") },
+            },
+        },
+        "Error": {
+            "type": "object",
+            "required": ["code", "message", "causes", "retryable"],
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "description": "Machine readable error code, stable across releases",
+                },
+                "message": { "type": "string" },
+                "causes": { "type": "array", "items": { "type": "string" } },
+                "request_id": {
+                    "type": "string",
+                    "description": "Also returned in the x-request-id header",
+                },
+                "retryable": {
+                    "type": "boolean",
+                    "description": "Whether retrying the same request may succeed",
+                },
+            },
+        },
+    })
+}
+
+/// The OpenAPI document of the API
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for route in ROUTES {
+        let path = paths
+            .entry(format!("/{{repo}}{}", route.path))
+            .or_insert_with(|| json!({}));
+        path[route.method] = operation(route);
+    }
+    paths.insert(
+        "/openapi.json".to_string(),
+        json!({
+            "get": {
+                "summary": "This document",
+                "responses": {
+                    "200": {
+                        "description": "OK",
+                        "content": { "application/json": { "schema": { "type": "object" } } },
+                    },
+                },
+            },
+        }),
+    );
+    paths.insert(
+        "/health_check".to_string(),
+        json!({
+            "get": {
+                "summary": "Whether the server is up, as `I_AM_ALIVE`",
+                "responses": {
+                    "200": {
+                        "description": "OK",
+                        "content": { "text/plain": { "schema": { "type": "string" } } },
+                    },
+                },
+            },
+        }),
+    );
+
+    paths.insert(
+        "/debug/cache".to_string(),
+        json!({
+            "get": {
+                "summary": "How full the cache pools are, and the hits and misses of the \
+                            content-sha1 cache by route since the server started",
+                "responses": {
+                    "200": {
+                        "description": "OK",
+                        "content": {
+                            "application/json": { "schema": schema_ref("CacheReport") },
+                        },
+                    },
+                },
+            },
+        }),
+    );
+
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": { "title": "Mononoke API Server", "version": API_VERSION },
+        "paths": paths,
+        "components": { "schemas": schemas() },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use actix_web::{http::StatusCode, Body as HttpBody};
+    use serde::Serialize;
+
+    use blobrepo::{ChangesetMetadata, HgBlobChangeset, HgChangesetContent};
+    use mercurial_types::{HgManifestId, HgParents, TextDecoding, Type, NULL_HASH};
+    use metaconfig_types::PushQuotaLimits;
+    use mononoke_api::exists::PathEntry;
+    use mononoke_api::merge_conflicts::{ConflictKind, FileConflict};
+    use mononoke_api::sizes::PathSummary;
+    use mononoke_types::{DateTime, FileType, MPath, RepositoryId};
+    use push_usage::{IdentityUsage, TeamUsage, UsageReport};
+    use repo_maintenance::MaintenanceWindow;
+    use sql_replicas::ReplicaStatus;
+
+    use crate::actor::model::{
+        Ancestry, Backlog, CachePoolUsage, CacheReport, Changeset, CommonAncestor, Entry,
+        GraphNode, GraphPhase, Maintenance, MergeConflict, MergeConflictReport, PathExistence,
+        PathSize, PushUsageReport, RangeChangeset, RawChangeset, Replica, RepoHealth, RepoStatus,
+    };
+    use crate::errors::generic_error_response;
+
+    /// Routes registered in main.rs, as (method, path) with the parameters in OpenAPI syntax
+    fn registered_routes() -> BTreeSet<(String, String)> {
+        let main = include_str!("main.rs");
+        let mut routes = BTreeSet::new();
+        let mut path = None;
+        for line in main.lines() {
+            if let Some(start) = line.find(".resource(\"") {
+                let rest = &line[start + ".resource(\"".len()..];
+                path = rest.split('"').next().map(|path| path.replace(":.*", ""));
+            }
+            if let (Some(start), Some(current)) = (line.find("http::Method::"), path.as_ref()) {
+                let method = &line[start + "http::Method::".len()..];
+                let method: String = method
+                    .chars()
+                    .take_while(char::is_ascii_uppercase)
+                    .collect();
+                routes.insert((method.to_lowercase(), format!("/{{repo}}{}", current)));
+            }
+        }
+        routes
+    }
+
+    #[test]
+    fn routes_are_documented() {
+        let document = document();
+        let mut documented = BTreeSet::new();
+        for (path, operations) in document["paths"].as_object().unwrap() {
+            if !path.starts_with("/{repo}") {
+                continue;
+            }
+            for method in operations.as_object().unwrap().keys() {
+                documented.insert((method.clone(), path.clone()));
+            }
+        }
+        assert_eq!(documented, registered_routes());
+    }
+
+    #[test]
+    fn schema_refs_resolve() {
+        let document = document();
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        let text = document.to_string();
+        for reference in text.split("\"#/components/schemas/").skip(1) {
+            let name = reference.split('"').next().unwrap();
+            assert!(schemas.contains_key(name), "unknown schema {}", name);
+        }
+    }
+
+    fn check_schema(name: &str, value: Value) {
+        let document = document();
+        let schema = &document["components"]["schemas"][name];
+        let properties = schema["properties"].as_object().unwrap();
+        let fields = value.as_object().unwrap();
+        for field in fields.keys() {
+            assert!(
+                properties.contains_key(field),
+                "{}.{} is not documented",
+                name,
+                field
+            );
+        }
+        for required in schema["required"].as_array().unwrap() {
+            let required = required.as_str().unwrap();
+            assert!(
+                fields.contains_key(required),
+                "{}.{} is documented but not sent",
+                name,
+                required
+            );
+        }
+    }
+
+    fn check_model<T: Serialize>(name: &str, model: T) {
+        check_schema(name, serde_json::to_value(model).unwrap());
+    }
+
+    #[test]
+    fn models_match_schemas() {
+        check_model(
+            "Entry",
+            Entry::submodule("sub".to_string(), "abcd".to_string()),
+        );
+
+        let summary = PathSummary {
+            total_size: 10,
+            file_count: 2,
+            dir_count: 1,
+        };
+        check_model("PathSize", PathSize::new("dir".to_string(), summary));
+
+        let entry = PathEntry {
+            ttype: Type::File(FileType::Regular),
+            size: Some(10),
+        };
+        check_model(
+            "PathExistence",
+            PathExistence::new("file".to_string(), Some(entry)),
+        );
+        check_model(
+            "Ancestry",
+            Ancestry::new("abcd".to_string(), "ef01".to_string(), true),
+        );
+        check_model(
+            "CommonAncestor",
+            CommonAncestor::new("abcd".to_string(), "ef01".to_string(), None),
+        );
+
+        let conflict = MergeConflict::from(FileConflict {
+            path: MPath::new("file").unwrap(),
+            kind: ConflictKind::AddAdd,
+        });
+        check_model("MergeConflict", &conflict);
+        check_model(
+            "MergeConflictReport",
+            MergeConflictReport {
+                merge_base: None,
+                conflicts: vec![conflict],
+            },
+        );
+
+        check_model(
+            "GraphNode",
+            GraphNode {
+                hash: "abcd".to_string(),
+                parents: vec!["ef01".to_string()],
+                generation: 2,
+                bookmarks: vec!["master".to_string()],
+                phase: GraphPhase::Public,
+            },
+        );
+        check_model(
+            "RangeChangeset",
+            RangeChangeset {
+                hash: "abcd".to_string(),
+                generation: 2,
+            },
+        );
+        check_model(
+            "RawChangeset",
+            RawChangeset {
+                hg: "abcd".to_string(),
+                parents: vec!["ef01".to_string()],
+                generation: 2,
+            },
+        );
+
+        check_model(
+            "CacheReport",
+            CacheReport {
+                pools: vec![CachePoolUsage {
+                    name: "content-sha1".to_string(),
+                    size_bytes: 1000,
+                    used_bytes: 10,
+                }],
+                routes: vec![],
+            },
+        );
+
+        let replica = ReplicaStatus {
+            address: "localhost".to_string(),
+            primary: true,
+            healthy: false,
+            consecutive_failures: 1,
+            last_error: Some("timeout".to_string()),
+        };
+        check_model("Replica", Replica::from(replica));
+
+        let report = UsageReport {
+            window_days: 30,
+            identities: vec![IdentityUsage {
+                identity: "alice".to_string(),
+                bytes: 100,
+                files: 2,
+                pushes: 1,
+            }],
+            teams: vec![TeamUsage {
+                name: "infra".to_string(),
+                bytes: 100,
+                files: 2,
+                pushes: 1,
+                limits: PushQuotaLimits {
+                    soft_limit_bytes: Some(1000),
+                    hard_limit_bytes: None,
+                },
+            }],
+        };
+        check_model("PushUsageReport", PushUsageReport::from(report));
+
+        let window = MaintenanceWindow {
+            repo_id: RepositoryId::new(0),
+            start: DateTime::from_timestamp(0, 0).unwrap(),
+            end: DateTime::from_timestamp(3600, 0).unwrap(),
+            message: "upgrade".to_string(),
+        };
+        let maintenance = Maintenance::from(window);
+        check_model("Maintenance", &maintenance);
+        check_model(
+            "RepoStatus",
+            RepoStatus {
+                maintenance: Some(maintenance),
+                overrides: BTreeMap::new(),
+            },
+        );
+        let backlog = |entries| {
+            let oldest = DateTime::from_timestamp(0, 0).unwrap().into_chrono();
+            Backlog::new(entries, Some(oldest), 60)
+        };
+        check_model("Backlog", backlog(1));
+        check_model(
+            "RepoHealth",
+            RepoHealth {
+                last_push: None,
+                hg_sync: Some(backlog(2)),
+                hg_derivation: backlog(3),
+                blobstore_sync: None,
+            },
+        );
+
+        let metadata = ChangesetMetadata {
+            user: "author".to_string(),
+            time: DateTime::from_timestamp(0, 0).unwrap(),
+            extra: vec![(b"key".to_vec(), b"value".to_vec())]
+                .into_iter()
+                .collect(),
+            comments: "message".to_string(),
+        };
+        let content = HgChangesetContent::new_from_parts(
+            HgParents::None,
+            HgManifestId::new(NULL_HASH),
+            metadata,
+            vec![],
+        );
+        let changeset = HgBlobChangeset::new(content).unwrap();
+        check_model(
+            "Changeset",
+            Changeset::new(changeset, TextDecoding::Lossy).unwrap(),
+        );
+    }
+
+    #[test]
+    fn error_envelope_matches_schema() {
+        let response =
+            generic_error_response(StatusCode::BAD_REQUEST, "bad".to_string(), Some("request"));
+        let body = match response.body() {
+            HttpBody::Binary(body) => serde_json::from_slice(body.as_ref()).unwrap(),
+            _ => panic!("error envelope is not a JSON body"),
+        };
+        check_schema("Error", body);
+    }
+}
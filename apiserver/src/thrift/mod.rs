@@ -24,6 +24,7 @@ use scuba_ext::ScubaSampleBuilder;
 mod dispatcher;
 mod facebook;
 mod mononoke;
+mod paging;
 
 pub fn make_thrift(
     logger: Logger,
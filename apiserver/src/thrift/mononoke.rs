@@ -9,13 +9,16 @@ use std::{convert::TryInto, mem::size_of, sync::Arc};
 use crate::errors::ErrorKind;
 use apiserver_thrift::server::MononokeApiservice;
 use apiserver_thrift::services::mononoke_apiservice::{
-    GetBlobExn, GetBranchesExn, GetChangesetExn, GetRawExn, GetTreeExn, IsAncestorExn,
-    ListDirectoryExn,
+    GetBlobExn, GetBranchesExn, GetChangesetExn, GetRawChunkExn, GetRawExn, GetTreeExn,
+    IsAncestorExn, ListDirectoryExn, ListDirectoryPageExn, MergeConflictsExn, PathsExistExn,
 };
 use apiserver_thrift::types::{
-    MononokeBlob, MononokeBranches, MononokeChangeset, MononokeDirectory, MononokeGetBlobParams,
-    MononokeGetBranchesParams, MononokeGetChangesetParams, MononokeGetRawParams,
-    MononokeGetTreeParams, MononokeIsAncestorParams, MononokeListDirectoryParams, MononokeRevision,
+    MononokeBlob, MononokeBranches, MononokeChangeset, MononokeDirectory, MononokeDirectoryPage,
+    MononokeGetBlobParams, MononokeGetBranchesParams, MononokeGetChangesetParams,
+    MononokeGetRawChunkParams, MononokeGetRawParams, MononokeGetTreeParams,
+    MononokeIsAncestorParams, MononokeListDirectoryPageParams, MononokeListDirectoryParams,
+    MononokeMergeConflicts, MononokeMergeConflictsParams, MononokePathsExistParams,
+    MononokePathsExistence, MononokeRawChunk, MononokeRevision,
 };
 use apiserver_thrift::MononokeRevision::UnknownField;
 use cloned::cloned;
@@ -30,9 +33,9 @@ use slog::Logger;
 use sshrelay::SshEnvVars;
 use time_ext::DurationExt;
 use tracing::TraceContext;
-use uuid::Uuid;
 
 use super::super::actor::{Mononoke, MononokeRepoResponse};
+use super::paging::raw_chunk;
 
 #[derive(Clone)]
 pub struct MononokeAPIServiceImpl {
@@ -89,10 +92,10 @@ impl MononokeAPIServiceImpl {
     }
 
     fn create_ctx(&self) -> CoreContext {
-        let session_uuid = Uuid::new_v4();
+        let time_uuid = self.addr.time_uuid();
 
         CoreContext::new(
-            session_uuid,
+            time_uuid.new_uuid(),
             self.logger.clone(),
             //this ctx is passed into Mononoke, we do not want to pass in a Scuba Builder with the mononoke-api table set in case scuba.log is called
             ScubaSampleBuilder::with_discard(),
@@ -101,6 +104,7 @@ impl MononokeAPIServiceImpl {
             None,
             SshEnvVars::default(),
         )
+        .with_time_uuid_provider(time_uuid)
     }
 }
 
@@ -232,7 +236,10 @@ impl MononokeApiservice for MononokeAPIServiceImpl {
                 move |param| addr.send_query(ctx, param)
             })
             .and_then(|resp: MononokeRepoResponse| match resp {
-                MononokeRepoResponse::GetBranches { branches } => Ok(MononokeBranches { branches }),
+                MononokeRepoResponse::GetBranches { branches, next } => Ok(MononokeBranches {
+                    branches,
+                    continuation_token: next,
+                }),
                 _ => Err(ErrorKind::InternalError(err_msg(
                     "Actor returned wrong response type to query".to_string(),
                 ))),
@@ -280,7 +287,7 @@ impl MononokeApiservice for MononokeAPIServiceImpl {
                 move |param| addr.send_query(ctx, param)
             })
             .and_then(|resp: MononokeRepoResponse| match resp {
-                MononokeRepoResponse::ListDirectory { files } => Ok(MononokeDirectory {
+                MononokeRepoResponse::ListDirectory { files, .. } => Ok(MononokeDirectory {
                     files: files.map(|f| f.into()).collect(),
                 }),
                 _ => Err(ErrorKind::InternalError(err_msg(
@@ -437,4 +444,202 @@ impl MononokeApiservice for MononokeAPIServiceImpl {
                 }
             })
     }
+
+    fn paths_exist(
+        &self,
+        params: MononokePathsExistParams,
+    ) -> BoxFuture<MononokePathsExistence, PathsExistExn> {
+        let ctx = self.create_ctx();
+
+        let mut scuba =
+            self.create_scuba_logger("paths_exist", &params, None, Some(params.revision.clone()));
+
+        params
+            .try_into()
+            .into_future()
+            .from_err()
+            .and_then({
+                cloned!(self.addr);
+                move |param| addr.send_query(ctx, param)
+            })
+            .and_then(|resp: MononokeRepoResponse| match resp {
+                MononokeRepoResponse::PathsExist { paths } => Ok(MononokePathsExistence {
+                    paths: paths.into_iter().map(|path| path.into()).collect(),
+                }),
+                _ => Err(ErrorKind::InternalError(err_msg(
+                    "Actor returned wrong response type to query".to_string(),
+                ))),
+            })
+            .map_err(move |e| PathsExistExn::e(e.into()))
+            .timed({
+                move |stats, resp| {
+                    log_time(
+                        &mut scuba,
+                        &stats,
+                        resp,
+                        resp.map(|resp| {
+                            resp.paths
+                                .iter()
+                                .map(|path| path.path.len() + 1) // 1 byte for exists
+                                .sum()
+                        })
+                        .unwrap_or(0),
+                    );
+
+                    Ok(())
+                }
+            })
+    }
+
+    fn merge_conflicts(
+        &self,
+        params: MononokeMergeConflictsParams,
+    ) -> BoxFuture<MononokeMergeConflicts, MergeConflictsExn> {
+        let ctx = self.create_ctx();
+
+        let mut scuba =
+            self.create_scuba_logger("merge_conflicts", &params, None, Some(params.left.clone()));
+        let right = match params.right.clone() {
+            MononokeRevision::commit_hash(hash) => hash,
+            MononokeRevision::bookmark(bookmark) => bookmark,
+            UnknownField(_) => "Not a valid MononokeRevision".to_string(),
+        };
+
+        scuba.add("right", right);
+
+        params
+            .try_into()
+            .into_future()
+            .from_err()
+            .and_then({
+                cloned!(self.addr, ctx);
+                move |param| addr.send_query(ctx, param)
+            })
+            .and_then(|resp: MononokeRepoResponse| match resp {
+                MononokeRepoResponse::MergeConflicts { report } => Ok(report.into()),
+                _ => Err(ErrorKind::InternalError(err_msg(
+                    "Actor returned wrong response type to query".to_string(),
+                ))),
+            })
+            .map_err(move |e| MergeConflictsExn::e(e.into()))
+            .timed({
+                move |stats, resp| {
+                    if let Ok(counters) = serde_json::to_string(&ctx.perf_counters()) {
+                        scuba.add("extra_context", counters);
+                    };
+                    log_time(
+                        &mut scuba,
+                        &stats,
+                        resp,
+                        resp.map(|resp| {
+                            resp.conflicts
+                                .iter()
+                                .map(|conflict| conflict.path.len() + size_of::<i32>())
+                                .sum()
+                        })
+                        .unwrap_or(0),
+                    );
+
+                    Ok(())
+                }
+            })
+    }
+
+    fn get_raw_chunk(
+        &self,
+        params: MononokeGetRawChunkParams,
+    ) -> BoxFuture<MononokeRawChunk, GetRawChunkExn> {
+        let ctx = self.create_ctx();
+
+        let mut scuba = self.create_scuba_logger(
+            "get_raw_chunk",
+            &params,
+            Some(params.params.path.clone()),
+            Some(params.params.revision.clone()),
+        );
+
+        params
+            .try_into()
+            .into_future()
+            .from_err()
+            .and_then({
+                cloned!(self.addr);
+                move |param| addr.send_query(ctx, param)
+            })
+            .and_then(|resp: MononokeRepoResponse| match resp {
+                MononokeRepoResponse::GetRawFileChunk {
+                    content,
+                    offset,
+                    file_size,
+                } => Ok(raw_chunk(content, offset, file_size)),
+                _ => Err(ErrorKind::InternalError(err_msg(
+                    "Actor returned wrong response type to query".to_string(),
+                ))),
+            })
+            .map_err(move |e| GetRawChunkExn::e(e.into()))
+            .timed({
+                move |stats, resp| {
+                    log_time(
+                        &mut scuba,
+                        &stats,
+                        resp,
+                        resp.map(|chunk| chunk.content.len()).unwrap_or(0),
+                    );
+
+                    Ok(())
+                }
+            })
+    }
+
+    fn list_directory_page(
+        &self,
+        params: MononokeListDirectoryPageParams,
+    ) -> BoxFuture<MononokeDirectoryPage, ListDirectoryPageExn> {
+        let ctx = self.create_ctx();
+
+        let mut scuba = self.create_scuba_logger(
+            "list_directory_page",
+            &params,
+            Some(params.params.path.clone()),
+            Some(params.params.revision.clone()),
+        );
+        params
+            .try_into()
+            .into_future()
+            .from_err()
+            .and_then({
+                cloned!(self.addr);
+                move |param| addr.send_query(ctx, param)
+            })
+            .and_then(|resp: MononokeRepoResponse| match resp {
+                MononokeRepoResponse::ListDirectory { files, next } => Ok(MononokeDirectoryPage {
+                    files: files.map(|f| f.into()).collect(),
+                    continuation_token: next,
+                }),
+                _ => Err(ErrorKind::InternalError(err_msg(
+                    "Actor returned wrong response type to query".to_string(),
+                ))),
+            })
+            .map_err(move |e| ListDirectoryPageExn::e(e.into()))
+            .timed({
+                move |stats, resp| {
+                    log_time(
+                        &mut scuba,
+                        &stats,
+                        resp,
+                        resp.map(|resp| {
+                            resp.files
+                                .iter()
+                                .map(
+                                    |file| file.name.len() + 1, // 1 byte for the filetype
+                                )
+                                .sum()
+                        })
+                        .unwrap_or(0),
+                    );
+
+                    Ok(())
+                }
+            })
+    }
 }
@@ -0,0 +1,47 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Chunks of files, for files too large to send in one response. The actor reads the chunks, and
+// makes the pages of directories, as for the other listings.
+
+use bytes::Bytes;
+
+use apiserver_thrift::types::MononokeRawChunk;
+
+/// The chunk of a file of `file_size` bytes whose `content` starts at `offset`
+pub fn raw_chunk(content: Bytes, offset: u64, file_size: u64) -> MononokeRawChunk {
+    let end = offset + content.len() as u64;
+    MononokeRawChunk {
+        content: content.to_vec(),
+        file_size: file_size as i64,
+        next_offset: if end < file_size {
+            Some(end as i64)
+        } else {
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn raw_chunks() {
+        let chunk = raw_chunk(Bytes::from("0123"), 0, 10);
+        assert_eq!(chunk.content, b"0123".to_vec());
+        assert_eq!(chunk.file_size, 10);
+        assert_eq!(chunk.next_offset, Some(4));
+
+        let chunk = raw_chunk(Bytes::from("89"), 8, 10);
+        assert_eq!(chunk.content, b"89".to_vec());
+        assert_eq!(chunk.next_offset, None);
+
+        let chunk = raw_chunk(Bytes::new(), 0, 0);
+        assert_eq!(chunk.content, Vec::<u8>::new());
+        assert_eq!(chunk.next_offset, None);
+    }
+}
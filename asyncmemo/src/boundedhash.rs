@@ -40,6 +40,11 @@ where
         self.entrysizes
     }
 
+    #[inline]
+    pub fn weight_limit(&self) -> usize {
+        self.weightlimit
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         self.hash.len()
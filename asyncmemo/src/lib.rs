@@ -78,6 +78,8 @@ define_stats! {
         "per_shard.total_weight.{}", (tag: &'static str); AVG),
     entry_num: dynamic_timeseries(
         "per_shard.entry_num.{}", (tag: &'static str); AVG),
+    weight_occupancy_pct: dynamic_timeseries(
+        "per_shard.weight_occupancy_pct.{}", (tag: &'static str); AVG),
 }
 
 const SHARD_NUM: usize = 1000;
@@ -111,6 +113,16 @@ where
     }
 }
 
+/// Percentage of `weight_limit` that `weight` is, reported per shard.
+fn weight_pct(weight: usize, weight_limit: usize) -> usize {
+    if weight_limit == 0 {
+        // Shards get a share of the limit rounded down, so this shard can't hold anything.
+        100
+    } else {
+        weight.saturating_mul(100) / weight_limit
+    }
+}
+
 /// Generate a result for the cache.
 ///
 /// The function implemented by `fill()` should be referentially transparent - the output
@@ -355,6 +367,10 @@ where
         );
         STATS::total_weight.add_value(hash.total_weight() as i64, (self.cache.stats_tag,));
         STATS::entry_num.add_value(hash.len() as i64, (self.cache.stats_tag,));
+        STATS::weight_occupancy_pct.add_value(
+            weight_pct(hash.total_weight(), hash.weight_limit()) as i64,
+            (self.cache.stats_tag,),
+        );
     }
 
     fn poll_real_future(
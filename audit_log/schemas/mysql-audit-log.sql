@@ -0,0 +1,12 @@
+CREATE TABLE `audit_log` (
+  `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `timestamp` BIGINT NOT NULL,
+  `identity` VARCHAR(255),
+  `client` VARCHAR(255),
+  `command` VARCHAR(255) NOT NULL,
+  `accessed` MEDIUMTEXT NOT NULL,
+  `bytes_served` BIGINT UNSIGNED NOT NULL,
+  PRIMARY KEY (`id`),
+  KEY `repo_timestamp` (`repo_id`, `timestamp`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
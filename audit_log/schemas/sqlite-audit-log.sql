@@ -0,0 +1,12 @@
+CREATE TABLE `audit_log` (
+  `id` INTEGER PRIMARY KEY,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `timestamp` BIGINT NOT NULL,
+  `identity` VARCHAR(255),
+  `client` VARCHAR(255),
+  `command` VARCHAR(255) NOT NULL,
+  `accessed` MEDIUMTEXT NOT NULL,
+  `bytes_served` BIGINT UNSIGNED NOT NULL
+);
+
+CREATE INDEX `repo_timestamp` ON `audit_log` (`repo_id`, `timestamp`);
@@ -0,0 +1,374 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Records who read what from repos that are configured as audited.
+//!
+//! Audit records are kept apart from perf logging: they go either to an `audit_log` table in the
+//! repo's database or to a dedicated scribe category, so that they can be retained and accessed
+//! under their own rules.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate sql;
+#[macro_use]
+extern crate stats;
+
+use std::sync::Arc;
+
+use failure_ext::Error;
+use futures::{Future, IntoFuture};
+use futures_ext::{BoxFuture, FutureExt};
+use rand::Rng;
+use scribe::ScribeClient;
+use scribe_cxx::ScribeCxxClient;
+use serde_json::json;
+use sql::Connection;
+use stats::Timeseries;
+
+use context::CoreContext;
+use metaconfig_types::{AuditParams, AuditSink, RepoType};
+use mononoke_types::{DateTime, RepositoryId, Timestamp};
+use sql_ext::migrations::{Migration, SqlMigrations};
+pub use sql_ext::SqlConstructors;
+
+define_stats! {
+    prefix = "mononoke.audit_log";
+    logged: timeseries(RATE, SUM),
+    skipped: timeseries(RATE, SUM),
+    failed: timeseries(RATE, SUM),
+}
+
+/// One read from an audited repo
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditRecord {
+    pub repo_id: RepositoryId,
+    pub timestamp: DateTime,
+    /// Who made the request, if known
+    pub identity: Option<String>,
+    /// Where the request came from, e.g. the client's address
+    pub client: Option<String>,
+    /// The wireproto command or HTTP request that was served
+    pub command: String,
+    /// The paths and hashes that were asked for, as a JSON value
+    pub accessed: serde_json::Value,
+    pub bytes_served: u64,
+}
+
+impl AuditRecord {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "repo_id": self.repo_id.id(),
+            "timestamp": self.timestamp.as_chrono().timestamp(),
+            "identity": self.identity,
+            "client": self.client,
+            "command": self.command,
+            "accessed": self.accessed,
+            "bytes_served": self.bytes_served,
+        })
+    }
+}
+
+pub trait AuditLog: Send + Sync {
+    fn log(&self, ctx: CoreContext, record: AuditRecord) -> BoxFuture<(), Error>;
+}
+
+queries! {
+    write InsertRecord(values: (
+        repo_id: RepositoryId,
+        timestamp: Timestamp,
+        identity: Option<String>,
+        client: Option<String>,
+        command: str,
+        accessed: str,
+        bytes_served: u64,
+    )) {
+        none,
+        "INSERT INTO audit_log
+         (repo_id, timestamp, identity, client, command, accessed, bytes_served)
+         VALUES {values}"
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlAuditLog {
+    write_connection: Connection,
+}
+
+impl SqlConstructors for SqlAuditLog {
+    fn from_connections(
+        write_connection: Connection,
+        _read_connection: Connection,
+        _read_master_connection: Connection,
+    ) -> Self {
+        Self { write_connection }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-audit-log.sql")
+    }
+}
+
+impl SqlMigrations for SqlAuditLog {
+    const STORE: &'static str = "audit_log";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the audit_log table",
+            sqlite: "CREATE TABLE `audit_log` (
+                       `id` INTEGER PRIMARY KEY,
+                       `repo_id` INT UNSIGNED NOT NULL,
+                       `timestamp` BIGINT NOT NULL,
+                       `identity` VARCHAR(255),
+                       `client` VARCHAR(255),
+                       `command` VARCHAR(255) NOT NULL,
+                       `accessed` MEDIUMTEXT NOT NULL,
+                       `bytes_served` BIGINT UNSIGNED NOT NULL
+                     );
+
+                     CREATE INDEX `repo_timestamp` ON `audit_log` (`repo_id`, `timestamp`);",
+            mysql: "CREATE TABLE `audit_log` (
+                      `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+                      `repo_id` INT UNSIGNED NOT NULL,
+                      `timestamp` BIGINT NOT NULL,
+                      `identity` VARCHAR(255),
+                      `client` VARCHAR(255),
+                      `command` VARCHAR(255) NOT NULL,
+                      `accessed` MEDIUMTEXT NOT NULL,
+                      `bytes_served` BIGINT UNSIGNED NOT NULL,
+                      PRIMARY KEY (`id`),
+                      KEY `repo_timestamp` (`repo_id`, `timestamp`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+impl AuditLog for SqlAuditLog {
+    fn log(&self, _ctx: CoreContext, record: AuditRecord) -> BoxFuture<(), Error> {
+        let timestamp = Timestamp::from(record.timestamp);
+        let accessed = record.accessed.to_string();
+        InsertRecord::query(
+            &self.write_connection,
+            &[(
+                &record.repo_id,
+                &timestamp,
+                &record.identity,
+                &record.client,
+                record.command.as_str(),
+                accessed.as_str(),
+                &record.bytes_served,
+            )],
+        )
+        .map(|_| ())
+        .boxify()
+    }
+}
+
+pub struct ScribeAuditLog<C: ScribeClient + Send + Sync> {
+    client: C,
+    category: String,
+}
+
+impl ScribeAuditLog<ScribeCxxClient> {
+    pub fn new_with_default_scribe(category: String) -> Self {
+        Self::new(ScribeCxxClient::new(), category)
+    }
+}
+
+impl<C: ScribeClient + Send + Sync> ScribeAuditLog<C> {
+    pub fn new(client: C, category: String) -> Self {
+        Self { client, category }
+    }
+}
+
+impl<C: ScribeClient + Send + Sync> AuditLog for ScribeAuditLog<C> {
+    fn log(&self, _ctx: CoreContext, record: AuditRecord) -> BoxFuture<(), Error> {
+        self.client
+            .offer(&self.category, &record.to_json().to_string())
+            .into_future()
+            .from_err()
+            .boxify()
+    }
+}
+
+/// Writes a sample of the reads from an audited repo to its audit log.
+#[derive(Clone)]
+pub struct Auditor {
+    log: Arc<AuditLog>,
+    sample_percentage: usize,
+}
+
+impl Auditor {
+    pub fn new(
+        params: &AuditParams,
+        repotype: &RepoType,
+        myrouter_port: Option<u16>,
+    ) -> Result<Self, Error> {
+        let log: Arc<AuditLog> = match params.sink {
+            AuditSink::Sql => Arc::new(SqlAuditLog::with_repo_type(
+                repotype,
+                myrouter_port,
+                "audit_log",
+            )?),
+            AuditSink::Scribe { ref category } => {
+                Arc::new(ScribeAuditLog::new_with_default_scribe(category.clone()))
+            }
+        };
+        Ok(Self::with_log(log, params.sample_percentage))
+    }
+
+    pub fn with_log(log: Arc<AuditLog>, sample_percentage: usize) -> Self {
+        Self {
+            log,
+            sample_percentage,
+        }
+    }
+
+    /// Whether the next read should be recorded. Callers that have to do work to build a record
+    /// should ask first.
+    pub fn should_audit(&self) -> bool {
+        self.sample_percentage >= 100
+            || rand::thread_rng().gen_range(0, 100) < self.sample_percentage
+    }
+
+    /// Record a read, unless it falls outside the sample.
+    pub fn audit(&self, ctx: CoreContext, record: AuditRecord) -> BoxFuture<(), Error> {
+        if !self.should_audit() {
+            STATS::skipped.add_value(1);
+            return Ok(()).into_future().boxify();
+        }
+        self.log
+            .log(ctx, record)
+            .then(|result| {
+                match result {
+                    Ok(()) => STATS::logged.add_value(1),
+                    Err(_) => STATS::failed.add_value(1),
+                }
+                result
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct RecordingLog {
+        records: Mutex<Vec<AuditRecord>>,
+    }
+
+    impl AuditLog for RecordingLog {
+        fn log(&self, _ctx: CoreContext, record: AuditRecord) -> BoxFuture<(), Error> {
+            self.records.lock().unwrap().push(record);
+            Ok(()).into_future().boxify()
+        }
+    }
+
+    fn record(command: &str) -> AuditRecord {
+        AuditRecord {
+            repo_id: RepositoryId::new(1),
+            timestamp: DateTime::from_rfc3339("2019-03-01T12:00:00.00Z").unwrap(),
+            identity: Some("user".to_string()),
+            client: Some("::1".to_string()),
+            command: command.to_string(),
+            accessed: json!(["dir/file"]),
+            bytes_served: 1024,
+        }
+    }
+
+    #[test]
+    fn sampling() {
+        let ctx = CoreContext::test_mock();
+
+        let log = Arc::new(RecordingLog::default());
+        let auditor = Auditor::with_log(log.clone(), 100);
+        for _ in 0..10 {
+            auditor
+                .audit(ctx.clone(), record("getfiles"))
+                .wait()
+                .unwrap();
+        }
+        assert_eq!(log.records.lock().unwrap().len(), 10);
+
+        let log = Arc::new(RecordingLog::default());
+        let auditor = Auditor::with_log(log.clone(), 0);
+        for _ in 0..10 {
+            auditor
+                .audit(ctx.clone(), record("getfiles"))
+                .wait()
+                .unwrap();
+        }
+        assert!(log.records.lock().unwrap().is_empty());
+    }
+
+    queries! {
+        read SelectRecords() -> (
+            RepositoryId,
+            Timestamp,
+            Option<String>,
+            Option<String>,
+            String,
+            String,
+            u64,
+        ) {
+            "SELECT repo_id, timestamp, identity, client, command, accessed, bytes_served
+             FROM audit_log
+             ORDER BY id"
+        }
+    }
+
+    #[test]
+    fn sql_log() {
+        let ctx = CoreContext::test_mock();
+        let log = SqlAuditLog::with_sqlite_in_memory().unwrap();
+        let mut anonymous = record("gettreepack");
+        anonymous.identity = None;
+        anonymous.client = None;
+        log.log(ctx.clone(), record("getfiles")).wait().unwrap();
+        log.log(ctx, anonymous.clone()).wait().unwrap();
+
+        let records: Vec<_> = SelectRecords::query(&log.write_connection)
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(
+                |(repo_id, timestamp, identity, client, command, accessed, bytes_served)| {
+                    AuditRecord {
+                        repo_id,
+                        timestamp: DateTime::from(timestamp),
+                        identity,
+                        client,
+                        command,
+                        accessed: serde_json::from_str(&accessed).unwrap(),
+                        bytes_served,
+                    }
+                },
+            )
+            .collect();
+        assert_eq!(records, vec![record("getfiles"), anonymous]);
+    }
+
+    #[test]
+    fn json_records() {
+        assert_eq!(
+            record("getpackv1").to_json(),
+            json!({
+                "repo_id": 1,
+                "timestamp": 1551441600,
+                "identity": "user",
+                "client": "::1",
+                "command": "getpackv1",
+                "accessed": ["dir/file"],
+                "bytes_served": 1024,
+            })
+        );
+    }
+}
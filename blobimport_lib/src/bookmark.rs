@@ -117,9 +117,17 @@ pub fn upload_bookmarks(
                 let mut count = 0;
                 for (key, value) in vec {
                     let key = Bookmark::new_ascii(try_boxfuture!(AsciiString::from_ascii(key)));
-                    if mononoke_bookmarks.get(&key) != Some(&value) {
+                    let old_value = mononoke_bookmarks.get(&key).cloned();
+                    if old_value != Some(value) {
                         count += 1;
-                        try_boxfuture!(transaction.force_set(&key, value, BookmarkUpdateReason::Blobimport))
+                        // Only move bookmarks from where they were when the import started, so
+                        // that pushes that happened since then are not clobbered
+                        try_boxfuture!(transaction.move_bookmark(
+                            &key,
+                            Some(value),
+                            old_value,
+                            BookmarkUpdateReason::Blobimport,
+                        ))
                     }
                 }
 
@@ -129,7 +137,10 @@ pub fn upload_bookmarks(
                             if ok {
                                 Ok(count)
                             } else {
-                                Err(format_err!("Bookmark transaction failed"))
+                                Err(format_err!(
+                                    "Bookmark transaction failed: bookmarks were moved since the \
+                                     import started"
+                                ))
                             }
                         })
                         .boxify()
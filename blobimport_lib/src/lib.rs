@@ -34,7 +34,7 @@ mod changeset;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use failure::{err_msg, Error};
+use failure::{err_msg, Error, ResultExt};
 use futures::{future, Future, Stream};
 use futures_ext::{BoxFuture, FutureExt, StreamExt};
 use slog::Logger;
@@ -73,12 +73,10 @@ impl Blobimport {
             phases_store,
         } = self;
 
-        let stale_bookmarks = {
-            let revlogrepo = RevlogRepo::open(&revlogrepo_path).expect("cannot open revlogrepo");
-            bookmark::read_bookmarks(revlogrepo)
-        };
+        let revlogrepo = try_boxfuture!(RevlogRepo::open(&revlogrepo_path)
+            .with_context(|_| format!("cannot open revlog repo {}", revlogrepo_path.display())));
 
-        let revlogrepo = RevlogRepo::open(revlogrepo_path).expect("cannot open revlogrepo");
+        let stale_bookmarks = bookmark::read_bookmarks(revlogrepo.clone());
 
         let upload_changesets = UploadChangesets {
             ctx: ctx.clone(),
@@ -0,0 +1,239 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use abomonation_derive::Abomonation;
+use changesets::Changesets;
+use cloned::cloned;
+use context::CoreContext;
+use failure::{err_msg, Error};
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use mononoke_types::{ChangesetId, Generation, RepositoryId};
+use stats::define_stats;
+use stats::prelude::*;
+
+use crate::ChangesetFetcher;
+
+define_stats! {
+    prefix = "mononoke.changeset_fetcher";
+    hit: dynamic_timeseries("{}.hit", (subsystem: &'static str); RATE, SUM),
+    miss: dynamic_timeseries("{}.miss", (subsystem: &'static str); RATE, SUM),
+}
+
+/// What a `ChangesetFetcher` knows about a changeset. The cache pool is bounded by bytes rather
+/// than entries, and only keeping this much of a changeset entry fits more of them in it.
+#[derive(Abomonation, Clone)]
+struct ChangesetNode {
+    gen: u64,
+    parents: Vec<ChangesetId>,
+}
+
+/// `ChangesetFetcher` whose answers are cached in a cachelib pool. There is one per repo, and its
+/// cache is shared by all the subsystems that walk the commit graph, each of them getting its
+/// own handle from `for_subsystem` so that hits and misses are counted per subsystem.
+#[derive(Clone)]
+pub struct CachingChangesetFetcher {
+    changesets: Arc<Changesets>,
+    repo_id: RepositoryId,
+    cache_pool: cachelib::LruCachePool,
+}
+
+impl CachingChangesetFetcher {
+    pub fn new(
+        changesets: Arc<Changesets>,
+        repo_id: RepositoryId,
+        cache_pool: cachelib::LruCachePool,
+    ) -> Self {
+        Self {
+            changesets,
+            repo_id,
+            cache_pool,
+        }
+    }
+
+    /// Fetcher for `subsystem`, which shares the cache of this fetcher
+    pub fn for_subsystem(&self, subsystem: &'static str) -> Arc<ChangesetFetcher + Send + Sync> {
+        Arc::new(SubsystemChangesetFetcher {
+            fetcher: self.clone(),
+            subsystem,
+        })
+    }
+
+    fn get_node(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+        subsystem: &'static str,
+    ) -> BoxFuture<ChangesetNode, Error> {
+        let cache_key = format!("{}.{}", self.repo_id.prefix(), cs_id);
+        let missed = Arc::new(AtomicBool::new(false));
+
+        cloned!(self.changesets, self.repo_id);
+        cachelib::get_cached_or_fill(&self.cache_pool, cache_key, {
+            cloned!(missed);
+            move || {
+                missed.store(true, Ordering::Relaxed);
+                changesets.get(ctx, repo_id, cs_id).map(|maybe_cs| {
+                    maybe_cs.map(|cs| ChangesetNode {
+                        gen: cs.gen,
+                        parents: cs.parents,
+                    })
+                })
+            }
+        })
+        .and_then(move |maybe_node| {
+            if missed.load(Ordering::Relaxed) {
+                STATS::miss.add_value(1, (subsystem,));
+            } else {
+                STATS::hit.add_value(1, (subsystem,));
+            }
+            maybe_node.ok_or_else(|| err_msg(format!("{} not found", cs_id)))
+        })
+        .boxify()
+    }
+}
+
+struct SubsystemChangesetFetcher {
+    fetcher: CachingChangesetFetcher,
+    subsystem: &'static str,
+}
+
+impl ChangesetFetcher for SubsystemChangesetFetcher {
+    fn get_generation_number(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> BoxFuture<Generation, Error> {
+        self.fetcher
+            .get_node(ctx, cs_id, self.subsystem)
+            .map(|node| Generation::new(node.gen))
+            .boxify()
+    }
+
+    fn get_parents(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> BoxFuture<Vec<ChangesetId>, Error> {
+        self.fetcher
+            .get_node(ctx, cs_id, self.subsystem)
+            .map(|node| node.parents)
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Once;
+
+    use changesets::{ChangesetEntry, ChangesetInsert, SqlChangesets, SqlConstructors};
+    use mononoke_types_mocks::changesetid::{ONES_CSID, THREES_CSID, TWOS_CSID};
+
+    /// Counts the changesets that are looked up in the store, i.e. the cache misses
+    struct CountingChangesets {
+        changesets: SqlChangesets,
+        gets: AtomicUsize,
+    }
+
+    impl Changesets for CountingChangesets {
+        fn add(&self, ctx: CoreContext, cs: ChangesetInsert) -> BoxFuture<bool, Error> {
+            self.changesets.add(ctx, cs)
+        }
+
+        fn get(
+            &self,
+            ctx: CoreContext,
+            repo_id: RepositoryId,
+            cs_id: ChangesetId,
+        ) -> BoxFuture<Option<ChangesetEntry>, Error> {
+            self.gets.fetch_add(1, Ordering::Relaxed);
+            self.changesets.get(ctx, repo_id, cs_id)
+        }
+
+        fn get_many(
+            &self,
+            ctx: CoreContext,
+            repo_id: RepositoryId,
+            cs_ids: Vec<ChangesetId>,
+        ) -> BoxFuture<Vec<ChangesetEntry>, Error> {
+            self.changesets.get_many(ctx, repo_id, cs_ids)
+        }
+    }
+
+    fn cache_pool() -> cachelib::LruCachePool {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            cachelib::init_cache_once(cachelib::LruCacheConfig::new(64 * 1024 * 1024)).unwrap();
+        });
+        cachelib::get_or_create_pool("changeset_fetcher", 16 * 1024 * 1024).unwrap()
+    }
+
+    /// A fetcher of a repo where TWOS_CSID is the child of ONES_CSID. Tests use different repos,
+    /// as they share the cache pool.
+    fn test_fetcher(repo_id: RepositoryId) -> (CachingChangesetFetcher, Arc<CountingChangesets>) {
+        let ctx = CoreContext::test_mock();
+        let changesets = Arc::new(CountingChangesets {
+            changesets: SqlChangesets::with_sqlite_in_memory().unwrap(),
+            gets: AtomicUsize::new(0),
+        });
+        for (cs_id, parents) in vec![(ONES_CSID, vec![]), (TWOS_CSID, vec![ONES_CSID])] {
+            let insert = ChangesetInsert {
+                repo_id,
+                cs_id,
+                parents,
+            };
+            changesets.add(ctx.clone(), insert).wait().unwrap();
+        }
+        let fetcher = CachingChangesetFetcher::new(changesets.clone(), repo_id, cache_pool());
+        (fetcher, changesets)
+    }
+
+    #[test]
+    fn subsystems_share_cache() {
+        let ctx = CoreContext::test_mock();
+        let (fetcher, changesets) = test_fetcher(RepositoryId::new(1));
+        let revsets = fetcher.for_subsystem("revsets");
+        let phases = fetcher.for_subsystem("phases");
+
+        let parents = revsets.get_parents(ctx.clone(), TWOS_CSID).wait().unwrap();
+        assert_eq!(parents, vec![ONES_CSID]);
+        assert_eq!(changesets.gets.load(Ordering::Relaxed), 1);
+
+        // Filled by revsets, so phases doesn't look it up again
+        let gen = phases
+            .get_generation_number(ctx.clone(), TWOS_CSID)
+            .wait()
+            .unwrap();
+        assert_eq!(gen, Generation::new(2));
+        let parents = phases.get_parents(ctx, TWOS_CSID).wait().unwrap();
+        assert_eq!(parents, vec![ONES_CSID]);
+        assert_eq!(changesets.gets.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn missing_changeset_not_cached() {
+        let ctx = CoreContext::test_mock();
+        let (fetcher, changesets) = test_fetcher(RepositoryId::new(2));
+        let revsets = fetcher.for_subsystem("revsets");
+
+        assert!(revsets
+            .get_parents(ctx.clone(), THREES_CSID)
+            .wait()
+            .is_err());
+        assert!(revsets
+            .get_generation_number(ctx, THREES_CSID)
+            .wait()
+            .is_err());
+        // Both calls went to the store, as there was nothing to cache the first time
+        assert_eq!(changesets.gets.load(Ordering::Relaxed), 2);
+    }
+}
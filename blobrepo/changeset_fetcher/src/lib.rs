@@ -15,6 +15,9 @@ use std::any::Any;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+mod caching;
+pub use crate::caching::CachingChangesetFetcher;
+
 /// Trait that knows how to fetch DAG info about commits. Primary user is revsets
 /// Concrete implementation may add more efficient caching logic to make request faster
 pub trait ChangesetFetcher: Send + Sync {
@@ -7,13 +7,13 @@
 use std::fmt;
 
 use ascii::AsciiString;
-use bincode;
 use failure_ext::failure;
 use failure_ext::Fail;
 
 use mercurial_types::{
     HgBlob, HgChangesetId, HgFileNodeId, HgManifestId, HgNodeHash, HgParents, MPath, RepoPath, Type,
 };
+use mononoke_errors::{Categorize, ErrorCategory};
 use mononoke_types::{hash::Sha256, ChangesetId, ContentId};
 
 use blob_changeset::HgBlobChangeset;
@@ -69,6 +69,10 @@ pub enum ErrorKind {
     FileNodeDeserializeFailed(String),
     #[fail(display = "Manifest id {} is missing", _0)]
     ManifestMissing(HgManifestId),
+    #[fail(display = "Shard {} of manifest {} is missing", _1, _0)]
+    ManifestShardMissing(HgNodeHash, String),
+    #[fail(display = "Shard {} of manifest {} doesn't match its hash", _1, _0)]
+    ManifestShardCorrupt(HgNodeHash, String),
     #[fail(display = "Node id {} is missing", _0)]
     NodeMissing(HgNodeHash),
     #[fail(display = "Mercurial content missing for node {} (type {})", _0, _1)]
@@ -86,8 +90,6 @@ pub enum ErrorKind {
     BadUploadBlob(HgBlob),
     #[fail(display = "HgParents are not in blob store {:?}", _0)]
     ParentsUnknown(HgParents),
-    #[fail(display = "Serialization of node failed {} ({})", _0, _1)]
-    SerializationFailed(HgNodeHash, bincode::Error),
     #[fail(display = "Root manifest is not a manifest (type {})", _0)]
     BadRootManifest(Type),
     #[fail(display = "Manifest type {} does not match uploaded type {}", _0, _1)]
@@ -136,6 +138,11 @@ pub enum ErrorKind {
     EmptyFilePath,
     #[fail(display = "Memory manifest conflict can not contain single entry")]
     SingleEntryConflict,
+    #[fail(
+        display = "Conflict resolution for {} picked a candidate that does not exist",
+        _0
+    )]
+    InvalidConflictResolution(MPath),
     #[fail(display = "Cannot find cache pool {}", _0)]
     MissingCachePool(String),
     #[fail(display = "Bonsai cs {} not found", _0)]
@@ -157,3 +164,56 @@ pub enum ErrorKind {
     #[fail(display = "Case conflict in a commit")]
     CaseConflict(MPath),
 }
+
+impl Categorize for ErrorKind {
+    fn category(&self) -> Option<ErrorCategory> {
+        use self::ErrorKind::*;
+
+        match self {
+            MissingTypedKeyEntry(_)
+            | ChangesetMissing(_)
+            | ManifestMissing(_)
+            | ManifestShardMissing(..)
+            | NodeMissing(_)
+            | HgContentMissing(..)
+            | ContentMissing(_)
+            | ContentBlobMissing(_)
+            | ParentsUnknown(_)
+            | MissingFilenode(..)
+            | MissingManifests
+            | BookmarkNotFound(_)
+            | PathNotFound(_)
+            | BonsaiNotFound(_)
+            | BonsaiMappingNotFound(_) => Some(ErrorCategory::NotFound),
+            IncorrectAliasBlobContent(_)
+            | ChangesetDeserializeFailed(_)
+            | ManifestDeserializeFailed(_)
+            | ManifestShardCorrupt(..)
+            | FileNodeDeserializeFailed(_)
+            | FileContentsDeserializeFailed(_)
+            | BadRootManifest(_)
+            | NotAManifest(..) => Some(ErrorCategory::Corrupt),
+            BadUploadBlob(_)
+            | ManifestTypeMismatch(..)
+            | DuplicateEntry(_)
+            | DuplicateManifest(_)
+            | MissingEntries(_)
+            | InconsistentEntryHash(..)
+            | InconsistentChangesetHash(..)
+            | IncorrectCopyInfo { .. }
+            | CaseConflict(_) => Some(ErrorCategory::InvalidRequest),
+            // Whether the state can be opened later depends on why it couldn't be now
+            StateOpen(_) => None,
+            NodeGenerationFailed
+            | UnresolvedConflicts
+            | UnchangedManifest
+            | ManifestAlreadyAMerge(..)
+            | NotADirectory
+            | EmptyFilePath
+            | SingleEntryConflict
+            | InvalidConflictResolution(_)
+            | MissingCachePool(_)
+            | UnexpectedRootPath => Some(ErrorCategory::Internal),
+        }
+    }
+}
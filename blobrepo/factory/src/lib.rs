@@ -21,13 +21,13 @@ use sqlfilenodes::{SqlConstructors, SqlFilenodes};
 use std::path::Path;
 use std::sync::Arc;
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, HedgeDelay};
 use blobstore_sync_queue::{BlobstoreSyncQueue, SqlBlobstoreSyncQueue};
-use bonsai_hg_mapping::{CachingBonsaiHgMapping, SqlBonsaiHgMapping};
-use cacheblob::{new_cachelib_blobstore, new_memcache_blobstore};
-use changeset_fetcher::{ChangesetFetcher, SimpleChangesetFetcher};
-use changesets::{CachingChangests, SqlChangesets};
-use filenodes::CachingFilenodes;
+use bonsai_hg_mapping::{BonsaiHgMapping, CachingBonsaiHgMapping, SqlBonsaiHgMapping};
+use cacheblob::{new_cachelib_blobstore, new_memcache_blobstore, CacheTtls};
+use changeset_fetcher::CachingChangesetFetcher;
+use changesets::{CachingChangests, Changesets, SqlChangesets};
+use filenodes::{CachingFilenodes, Filenodes};
 use memblob::EagerMemblob;
 use prefixblob::PrefixBlobstore;
 
@@ -39,11 +39,17 @@ use delayblob::DelayBlob;
 use failure_ext::prelude::*;
 use glusterblob::Glusterblob;
 use manifoldblob::ThriftManifoldBlob;
-use metaconfig_types::RemoteBlobstoreArgs;
-use multiplexedblob::MultiplexedBlobstore;
+use metaconfig_types::{
+    BlobstoreCacheTtls, BlobstoreScrubAction, ReadReplicaParams, RemoteBlobstoreArgs,
+};
+use multiplexedblob::{MultiplexedBlobstore, ScrubAction, ScrubBlobstore};
 use rocksblob::Rocksblob;
 use rocksdb;
 use scuba::ScubaClient;
+use sql_replicas::{
+    ReplicaManager, ReplicaSet, ReplicatedBonsaiHgMapping, ReplicatedChangesets,
+    ReplicatedFilenodes,
+};
 
 /// Create a new BlobRepo with purely local state.
 pub fn new_local(
@@ -126,16 +132,31 @@ pub fn open_blobrepo(
     repoid: RepositoryId,
     myrouter_port: Option<u16>,
 ) -> impl Future<Item = BlobRepo, Error = Error> {
+    open_blobrepo_with_replicas(logger, repotype, repoid, myrouter_port).map(|(repo, _)| repo)
+}
+
+/// Like `open_blobrepo`, but also returns the manager of the replicas of the SQL database of
+/// remote repos that have standby replicas. Long running callers should re-resolve the replicas
+/// periodically with it.
+pub fn open_blobrepo_with_replicas(
+    logger: slog::Logger,
+    repotype: RepoType,
+    repoid: RepositoryId,
+    myrouter_port: Option<u16>,
+) -> impl Future<Item = (BlobRepo, Option<Arc<ReplicaManager>>), Error = Error> {
     use metaconfig_types::RepoType::*;
 
     match repotype {
         BlobFiles(ref path) => new_files(logger, &path, repoid)
+            .map(|repo| (repo, None))
             .into_future()
             .left_future(),
         BlobRocks(ref path) => new_rocksdb(logger, &path, repoid)
+            .map(|repo| (repo, None))
             .into_future()
             .left_future(),
         BlobSqlite(ref path) => new_sqlite(logger, &path, repoid)
+            .map(|repo| (repo, None))
             .into_future()
             .left_future(),
         BlobRemote {
@@ -143,6 +164,8 @@ pub fn open_blobrepo(
             ref db_address,
             write_lock_db_address: _,
             ref filenode_shards,
+            ref read_replicas,
+            ref blobstore_cache_ttls,
         } => {
             let myrouter_port = match myrouter_port {
                 None => {
@@ -153,11 +176,13 @@ pub fn open_blobrepo(
                 }
                 Some(myrouter_port) => myrouter_port,
             };
-            new_remote(
+            new_remote_with_replicas(
                 logger,
                 blobstores_args,
                 db_address.clone(),
                 filenode_shards.clone(),
+                read_replicas.clone(),
+                blobstore_cache_ttls.clone(),
                 repoid,
                 myrouter_port,
             )
@@ -198,6 +223,67 @@ pub fn new_remote(
     repoid: RepositoryId,
     myrouter_port: u16,
 ) -> impl Future<Item = BlobRepo, Error = Error> {
+    new_remote_with_replicas(
+        logger,
+        args,
+        db_address,
+        filenode_shards,
+        None,
+        None,
+        repoid,
+        myrouter_port,
+    )
+    .map(|(repo, _)| repo)
+}
+
+/// The TTLs of the blobstore caches, as configured
+fn cache_ttls(config: BlobstoreCacheTtls) -> CacheTtls {
+    let ttl = |secs| match secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    CacheTtls {
+        default: config.default_ttl_secs.and_then(ttl),
+        by_type: config
+            .ttl_secs_by_type
+            .into_iter()
+            .map(|(key_type, secs)| (key_type, ttl(secs)))
+            .collect(),
+    }
+}
+
+/// Connect a SQL store to `db_address`, or to all the replicas of the database if there is a
+/// replica manager
+fn connect_replicated<T, C, W>(
+    replica_manager: &mut Option<ReplicaManager>,
+    name: &str,
+    db_address: &str,
+    connect: C,
+    replicated: W,
+) -> Arc<T>
+where
+    T: ?Sized + Send + Sync + 'static,
+    C: Fn(&str) -> Arc<T> + Send + Sync + 'static,
+    W: FnOnce(Arc<ReplicaSet<T>>) -> Arc<T>,
+{
+    match replica_manager {
+        Some(manager) => replicated(manager.replica_set(name, connect)),
+        None => connect(db_address),
+    }
+}
+
+/// Like `new_remote`, but reads from the SQL stores fail over to the standby replicas of the
+/// database, if any. The manager of the replicas is returned along with the repo.
+pub fn new_remote_with_replicas(
+    logger: Logger,
+    args: &RemoteBlobstoreArgs,
+    db_address: String,
+    filenode_shards: Option<usize>,
+    read_replicas: Option<ReadReplicaParams>,
+    blobstore_cache_ttls: Option<BlobstoreCacheTtls>,
+    repoid: RepositoryId,
+    myrouter_port: u16,
+) -> impl Future<Item = (BlobRepo, Option<Arc<ReplicaManager>>), Error = Error> {
     // recursively construct blobstore from arguments
     fn eval_remote_args(
         args: RemoteBlobstoreArgs,
@@ -219,16 +305,30 @@ pub fn new_remote(
                     .boxify()
             }
             RemoteBlobstoreArgs::Mysql(args) => {
-                let blobstore: Arc<Blobstore> = Arc::new(Sqlblob::with_myrouter(
+                let blobstore = Sqlblob::with_myrouter(
                     repoid,
                     args.shardmap,
                     myrouter_port,
                     args.shard_num,
-                ));
+                );
+                let blobstore = match args.hedging {
+                    Some(hedging) => blobstore.with_hedged_reads(
+                        hedging.delay_percentile,
+                        Duration::from_millis(hedging.min_delay_ms),
+                    ),
+                    None => blobstore,
+                };
+                let blobstore: Arc<Blobstore> = match args.compression_level {
+                    Some(level) => Arc::new(blobstore.with_compressed_chunks(level)),
+                    None => Arc::new(blobstore),
+                };
                 future::ok(blobstore).boxify()
             }
             RemoteBlobstoreArgs::Multiplexed {
                 scuba_table,
+                hedging,
+                write_quorum,
+                scrub_action,
                 blobstores,
             } => {
                 let blobstores: Vec<_> = blobstores
@@ -240,15 +340,39 @@ pub fn new_remote(
                     .collect();
                 future::join_all(blobstores)
                     .map(move |blobstores| {
+                        let scuba_logger =
+                            scuba_table.map(|table| Arc::new(ScubaClient::new(table)));
                         if blobstores.len() == 1 {
                             let (_, blobstore) = blobstores.into_iter().next().unwrap();
                             blobstore
+                        } else if let Some(scrub_action) = scrub_action {
+                            // Reads go to all blobstores anyway, so hedging doesn't apply
+                            let action = match scrub_action {
+                                BlobstoreScrubAction::ReportOnly => ScrubAction::ReportOnly,
+                                BlobstoreScrubAction::Repair => ScrubAction::Repair,
+                            };
+                            Arc::new(ScrubBlobstore::new(
+                                repoid,
+                                blobstores,
+                                write_quorum,
+                                queue.clone(),
+                                scuba_logger,
+                                action,
+                            ))
                         } else {
                             Arc::new(MultiplexedBlobstore::new(
                                 repoid,
                                 blobstores,
+                                write_quorum,
                                 queue.clone(),
-                                scuba_table.map(|table| Arc::new(ScubaClient::new(table))),
+                                scuba_logger,
+                                hedging.map(|hedging| {
+                                    HedgeDelay::new(
+                                        "multiplexed".to_string(),
+                                        hedging.delay_percentile,
+                                        Duration::from_millis(hedging.min_delay_ms),
+                                    )
+                                }),
                             ))
                         }
                     })
@@ -262,7 +386,8 @@ pub fn new_remote(
     );
     eval_remote_args(args.clone(), repoid, myrouter_port, blobstore_sync_queue).and_then(
         move |blobstore| {
-            let blobstore = new_memcache_blobstore(blobstore, "multiplexed", "")?;
+            let ttls = blobstore_cache_ttls.map(cache_ttls);
+            let blobstore = new_memcache_blobstore(blobstore, "multiplexed", "", ttls.clone())?;
             let blob_pool = Arc::new(cachelib::get_pool("blobstore-blobs").ok_or(Error::from(
                 ErrorKind::MissingCachePool("blobstore-blobs".to_string()),
             ))?);
@@ -270,16 +395,33 @@ pub fn new_remote(
                 Arc::new(cachelib::get_pool("blobstore-presence").ok_or(Error::from(
                     ErrorKind::MissingCachePool("blobstore-presence".to_string()),
                 ))?);
-            let blobstore = Arc::new(new_cachelib_blobstore(blobstore, blob_pool, presence_pool));
+            let blobstore = Arc::new(new_cachelib_blobstore(
+                blobstore,
+                blob_pool,
+                presence_pool,
+                ttls,
+            ));
 
-            let filenodes = match filenode_shards {
-                Some(shards) => {
-                    SqlFilenodes::with_sharded_myrouter(&db_address, myrouter_port, shards)
-                }
-                None => SqlFilenodes::with_myrouter(&db_address, myrouter_port),
-            };
+            let mut replica_manager = read_replicas
+                .as_ref()
+                .map(|params| ReplicaManager::new(db_address.clone(), params));
+
+            let filenodes = connect_replicated(
+                &mut replica_manager,
+                "filenodes",
+                &db_address,
+                move |address| -> Arc<Filenodes> {
+                    Arc::new(match filenode_shards {
+                        Some(shards) => {
+                            SqlFilenodes::with_sharded_myrouter(address, myrouter_port, shards)
+                        }
+                        None => SqlFilenodes::with_myrouter(address, myrouter_port),
+                    })
+                },
+                |replicas| -> Arc<Filenodes> { Arc::new(ReplicatedFilenodes::new(replicas)) },
+            );
             let filenodes = CachingFilenodes::new(
-                Arc::new(filenodes),
+                filenodes,
                 cachelib::get_pool("filenodes").ok_or(Error::from(ErrorKind::MissingCachePool(
                     "filenodes".to_string(),
                 )))?,
@@ -289,33 +431,55 @@ pub fn new_remote(
 
             let bookmarks = SqlBookmarks::with_myrouter(&db_address, myrouter_port);
 
-            let changesets = SqlChangesets::with_myrouter(&db_address, myrouter_port);
+            let changesets = connect_replicated(
+                &mut replica_manager,
+                "changesets",
+                &db_address,
+                move |address| -> Arc<Changesets> {
+                    Arc::new(SqlChangesets::with_myrouter(address, myrouter_port))
+                },
+                |replicas| -> Arc<Changesets> { Arc::new(ReplicatedChangesets::new(replicas)) },
+            );
             let changesets_cache_pool = cachelib::get_pool("changesets").ok_or(Error::from(
                 ErrorKind::MissingCachePool("changesets".to_string()),
             ))?;
-            let changesets =
-                CachingChangests::new(Arc::new(changesets), changesets_cache_pool.clone());
+            // The fetcher below has a cache of its own, so it reads from the SQL store directly
+            // rather than caching the same changesets twice
+            let uncached_changesets = changesets.clone();
+            let changesets = CachingChangests::new(changesets, changesets_cache_pool.clone());
             let changesets = Arc::new(changesets);
 
-            let bonsai_hg_mapping = SqlBonsaiHgMapping::with_myrouter(&db_address, myrouter_port);
+            let bonsai_hg_mapping = connect_replicated(
+                &mut replica_manager,
+                "bonsai_hg_mapping",
+                &db_address,
+                move |address| -> Arc<BonsaiHgMapping> {
+                    Arc::new(SqlBonsaiHgMapping::with_myrouter(address, myrouter_port))
+                },
+                |replicas| -> Arc<BonsaiHgMapping> {
+                    Arc::new(ReplicatedBonsaiHgMapping::new(replicas))
+                },
+            );
             let bonsai_hg_mapping = CachingBonsaiHgMapping::new(
-                Arc::new(bonsai_hg_mapping),
+                bonsai_hg_mapping,
                 cachelib::get_pool("bonsai_hg_mapping").ok_or(Error::from(
                     ErrorKind::MissingCachePool("bonsai_hg_mapping".to_string()),
                 ))?,
             );
 
-            let changeset_fetcher_factory = {
-                cloned!(changesets, repoid);
-                move || {
-                    let res: Arc<ChangesetFetcher + Send + Sync> = Arc::new(
-                        SimpleChangesetFetcher::new(changesets.clone(), repoid.clone()),
-                    );
-                    res
-                }
-            };
+            // One fetcher for the whole repo, so that revsets, reachability and phases don't
+            // each fetch the same parents and generation numbers
+            let changeset_fetcher = CachingChangesetFetcher::new(
+                uncached_changesets,
+                repoid,
+                cachelib::get_pool("changeset_fetcher").ok_or(Error::from(
+                    ErrorKind::MissingCachePool("changeset_fetcher".to_string()),
+                ))?,
+            );
+            let changeset_fetcher_factory =
+                move |subsystem: &'static str| changeset_fetcher.for_subsystem(subsystem);
 
-            Ok(BlobRepo::new_with_changeset_fetcher_factory(
+            let repo = BlobRepo::new_with_changeset_fetcher_factory(
                 logger,
                 Arc::new(bookmarks),
                 blobstore,
@@ -324,7 +488,8 @@ pub fn new_remote(
                 Arc::new(bonsai_hg_mapping),
                 repoid,
                 Arc::new(changeset_fetcher_factory),
-            ))
+            );
+            Ok((repo, replica_manager.map(Arc::new)))
         },
     )
 }
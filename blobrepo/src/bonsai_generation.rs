@@ -17,7 +17,7 @@ use bonsai_utils;
 use context::CoreContext;
 use mercurial_types::{Changeset, Entry, HgFileNodeId, HgManifestId, MPath};
 use mononoke_types::{
-    BlobstoreValue, BonsaiChangeset, BonsaiChangesetMut, ChangesetId, FileChange, MononokeId,
+    BlobstoreValue, BonsaiChangeset, BonsaiChangesetBuilder, ChangesetId, FileChange, MononokeId,
 };
 
 use crate::errors::*;
@@ -44,29 +44,26 @@ pub fn create_bonsai_changeset_object(
         let cs = cs.clone();
         let parents = bonsai_parents.clone();
         move |file_changes| {
-            let mut extra = BTreeMap::new();
+            let author = String::from_utf8(cs.user().to_vec())
+                .with_context(|_| format!("While converting author name {:?}", cs.user()))?;
+            let message = String::from_utf8(cs.comments().to_vec())
+                .with_context(|_| format!("While converting commit message {:?}", cs.comments()))?;
+
+            let mut builder = BonsaiChangesetBuilder::new(author, *cs.time());
+            builder.set_parents(parents).set_message(message);
             for (key, value) in cs.extra() {
                 // Hg changesets can have non-utf8 extras, but we don't allow them in Bonsai
                 // In that case convert them lossy.
                 let key = String::from_utf8(key.clone())?.to_string();
-                extra.insert(key, value.clone());
+                builder.add_extra(key, value.clone());
             }
-
-            let author = String::from_utf8(cs.user().to_vec())
-                .with_context(|_| format!("While converting author name {:?}", cs.user()))?;
-            let message = String::from_utf8(cs.comments().to_vec())
-                .with_context(|_| format!("While converting commit message {:?}", cs.comments()))?;
-            BonsaiChangesetMut {
-                parents,
-                author,
-                author_date: *cs.time(),
-                committer: None,
-                committer_date: None,
-                message,
-                extra,
-                file_changes,
+            for (path, change) in file_changes {
+                match change {
+                    Some(change) => builder.add_file_change(path, change),
+                    None => builder.delete_file(path),
+                };
             }
-            .freeze()
+            builder.freeze()
         }
     })
 }
@@ -6,9 +6,13 @@
 
 //! Plain files, symlinks
 
+use std::cmp;
+
 use crate::failure::{Error, FutureFailureErrorExt};
-use futures::future::{self, Future};
-use futures_ext::{BoxFuture, FutureExt};
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, loop_fn, Future, Loop};
+use futures::{stream, Async, Poll, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use super::alias::get_sha256;
 
@@ -19,7 +23,7 @@ use mercurial_types::{
     FileType, HgBlob, HgFileEnvelope, HgFileNodeId, HgManifestId, HgNodeHash, HgParents, MPath,
     MPathElement,
 };
-use mononoke_types::{hash::Sha256, ContentId, FileContents, MononokeId};
+use mononoke_types::{hash::Sha256, ContentId, FileContents, MononokeId, Tombstone};
 
 use blobstore::Blobstore;
 use context::CoreContext;
@@ -193,6 +197,167 @@ pub fn fetch_file_contents(
         .from_err()
 }
 
+/// The contents of a file, with the bytes in the chunks the blobstore reads them in
+pub enum FileContentsStream {
+    Bytes(BoxStream<Bytes, Error>),
+    Tombstone(Tombstone),
+}
+
+/// Compact protocol header of the field that holds the bytes of a file: field 1, binary
+const BYTES_FIELD_HEADER: u8 = 0x18;
+/// Compact protocol byte that ends a struct
+const STOP_FIELD: u8 = 0x00;
+
+enum ContentsHeader {
+    /// More bytes are needed to tell
+    Incomplete,
+    /// The bytes of a file, of length `len`, start after `header_len` bytes
+    Bytes { header_len: usize, len: u64 },
+    /// Anything else, e.g. a tombstone, which is decoded whole
+    Other,
+}
+
+fn parse_contents_header(buf: &[u8]) -> ContentsHeader {
+    match buf.first() {
+        None => return ContentsHeader::Incomplete,
+        Some(&byte) if byte != BYTES_FIELD_HEADER => return ContentsHeader::Other,
+        Some(_) => {}
+    }
+    // The length is a varint of at most 10 bytes
+    let mut len = 0;
+    for (i, byte) in buf[1..].iter().enumerate().take(10) {
+        len |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return ContentsHeader::Bytes {
+                header_len: i + 2,
+                len,
+            };
+        }
+    }
+    if buf.len() > 10 {
+        ContentsHeader::Other
+    } else {
+        ContentsHeader::Incomplete
+    }
+}
+
+/// The bytes field of an encoded `FileContents`, from right after its header. Checks that the
+/// field has the length from the header and is followed by the end of the struct.
+struct BytesField {
+    inner: BoxStream<Bytes, Error>,
+    remaining: u64,
+    stopped: bool,
+    blobstore_key: String,
+}
+
+impl BytesField {
+    fn malformed(&self) -> Error {
+        ErrorKind::FileContentsDeserializeFailed(self.blobstore_key.clone()).into()
+    }
+}
+
+impl Stream for BytesField {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        loop {
+            let mut chunk = match try_ready!(self.inner.poll()) {
+                Some(chunk) => chunk,
+                None if self.remaining == 0 && self.stopped => return Ok(Async::Ready(None)),
+                None => return Err(self.malformed()),
+            };
+            let len = cmp::min(self.remaining, chunk.len() as u64) as usize;
+            let data = chunk.split_to(len);
+            self.remaining -= len as u64;
+            if !chunk.is_empty() {
+                if self.stopped || chunk.as_ref() != [STOP_FIELD] {
+                    return Err(self.malformed());
+                }
+                self.stopped = true;
+            }
+            if !data.is_empty() {
+                return Ok(Async::Ready(Some(data)));
+            }
+        }
+    }
+}
+
+/// Decode an encoded `FileContents` as it's streamed. The bytes of a file are passed on as they
+/// come, everything else is decoded once it's all there.
+fn decode_file_contents_stream(
+    blobstore_key: String,
+    encoded: BoxStream<Bytes, Error>,
+) -> impl Future<Item = FileContentsStream, Error = Error> {
+    // Read until the header is complete, or the stream ends
+    loop_fn((BytesMut::new(), encoded), |(mut buf, encoded)| {
+        encoded
+            .into_future()
+            .map_err(|(err, _)| err)
+            .map(move |(chunk, encoded)| match chunk {
+                Some(chunk) => {
+                    buf.extend_from_slice(&chunk);
+                    match parse_contents_header(&buf) {
+                        ContentsHeader::Incomplete => Loop::Continue((buf, encoded)),
+                        header => Loop::Break((buf, encoded, header)),
+                    }
+                }
+                None => Loop::Break((buf, stream::empty().boxify(), ContentsHeader::Other)),
+            })
+    })
+    .and_then(move |(buf, encoded, header)| match header {
+        ContentsHeader::Bytes { header_len, len } => {
+            let rest = buf.freeze().slice_from(header_len);
+            let bytes = BytesField {
+                inner: stream::once(Ok(rest)).chain(encoded).boxify(),
+                remaining: len,
+                stopped: false,
+                blobstore_key,
+            };
+            future::ok(FileContentsStream::Bytes(bytes.boxify())).left_future()
+        }
+        _ => encoded
+            .fold(buf, |mut buf, chunk| {
+                buf.extend_from_slice(&chunk);
+                Ok::<_, Error>(buf)
+            })
+            .and_then(
+                |buf| match FileContents::from_encoded_bytes(buf.freeze())? {
+                    FileContents::Bytes(bytes) => {
+                        Ok(FileContentsStream::Bytes(stream::once(Ok(bytes)).boxify()))
+                    }
+                    FileContents::Tombstone(tombstone) => {
+                        Ok(FileContentsStream::Tombstone(tombstone))
+                    }
+                },
+            )
+            .right_future(),
+    })
+}
+
+pub fn fetch_file_contents_stream(
+    ctx: CoreContext,
+    blobstore: &RepoBlobstore,
+    content_id: ContentId,
+) -> impl Future<Item = FileContentsStream, Error = Error> {
+    let blobstore_key = content_id.blobstore_key();
+    blobstore
+        .get_stream(ctx, blobstore_key.clone())
+        .context("While fetching content blob")
+        .map_err(Error::from)
+        .and_then({
+            cloned!(blobstore_key);
+            move |encoded| match encoded {
+                Some(encoded) => decode_file_contents_stream(blobstore_key, encoded).left_future(),
+                None => {
+                    future::err(ErrorKind::ContentBlobMissing(content_id).into()).right_future()
+                }
+            }
+        })
+        .with_context(|_| ErrorKind::FileContentsDeserializeFailed(blobstore_key))
+        .from_err()
+}
+
 pub(crate) fn get_rename_from_envelope(
     envelope: HgFileEnvelope,
 ) -> Result<Option<(MPath, HgFileNodeId)>, Error> {
@@ -334,3 +499,69 @@ impl Entry for HgBlobEntry {
         self.name.as_ref()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mononoke_types::BlobstoreValue;
+
+    fn decode_in_chunks(encoded: &Bytes, chunk_size: usize) -> Result<FileContentsStream, Error> {
+        let chunks: Vec<_> = encoded.chunks(chunk_size).map(Bytes::from).collect();
+        decode_file_contents_stream("key".to_string(), stream::iter_ok(chunks).boxify()).wait()
+    }
+
+    fn concat(contents: FileContentsStream) -> Result<Bytes, Error> {
+        match contents {
+            FileContentsStream::Bytes(bytes) => bytes.concat2().wait(),
+            FileContentsStream::Tombstone(_) => panic!("unexpected tombstone"),
+        }
+    }
+
+    #[test]
+    fn stream_bytes() {
+        // Long enough for a length of several varint bytes
+        let data = Bytes::from(vec![b'x'; 1000]);
+        let encoded = FileContents::new_bytes(data.clone())
+            .into_blob()
+            .data()
+            .clone();
+        for chunk_size in vec![1, 2, 3, 100, 2000] {
+            let contents = decode_in_chunks(&encoded, chunk_size).expect("decoding failed");
+            assert_eq!(concat(contents).expect("streaming failed"), data);
+        }
+
+        let encoded = FileContents::new_bytes(Bytes::new())
+            .into_blob()
+            .data()
+            .clone();
+        let contents = decode_in_chunks(&encoded, 1).expect("decoding failed");
+        assert_eq!(concat(contents).expect("streaming failed"), Bytes::new());
+    }
+
+    #[test]
+    fn stream_tombstone() {
+        let encoded = FileContents::new_tombstone("removed")
+            .into_blob()
+            .data()
+            .clone();
+        match decode_in_chunks(&encoded, 1).expect("decoding failed") {
+            FileContentsStream::Tombstone(tombstone) => {
+                assert_eq!(tombstone, Tombstone::new("removed"))
+            }
+            FileContentsStream::Bytes(_) => panic!("expected a tombstone"),
+        }
+    }
+
+    #[test]
+    fn stream_malformed() {
+        let encoded = FileContents::new_bytes("foobar").into_blob().data().clone();
+        let truncated = encoded.slice_to(encoded.len() - 2);
+        let contents = decode_in_chunks(&truncated, 3).expect("decoding failed");
+        assert!(concat(contents).is_err());
+
+        let mut trailing = BytesMut::from(encoded);
+        trailing.extend_from_slice(b"more");
+        let contents = decode_in_chunks(&trailing.freeze(), 3).expect("decoding failed");
+        assert!(concat(contents).is_err());
+    }
+}
@@ -31,18 +31,22 @@ pub mod alias;
 mod bonsai_generation;
 mod file;
 mod manifest;
+mod manifest_sharding;
 mod memory_manifest;
+mod pull_through;
 mod repo;
 mod repo_commit;
 mod utils;
 
 pub use crate::alias::*;
 pub use crate::errors::*;
-pub use crate::file::HgBlobEntry;
+pub use crate::file::{FileContentsStream, HgBlobEntry};
 pub use crate::manifest::BlobManifest;
+pub use crate::pull_through::PullThroughSync;
 pub use crate::repo::{
     save_bonsai_changesets, BlobRepo, ContentBlobInfo, ContentBlobMeta, CreateChangeset,
     UploadHgFileContents, UploadHgFileEntry, UploadHgNodeHash, UploadHgTreeEntry,
+    STEPPARENTS_EXTRA,
 };
 pub use crate::repo_commit::ChangesetHandle;
 pub use blob_changeset::{ChangesetMetadata, HgBlobChangeset, HgChangesetContent};
@@ -51,6 +55,22 @@ pub use changeset_fetcher::ChangesetFetcher;
 pub use crate::repo_commit::compute_changed_files;
 
 pub mod internal {
-    pub use crate::memory_manifest::{MemoryManifestEntry, MemoryRootManifest};
+    pub use crate::memory_manifest::{
+        ConflictCandidate, ConflictResolution, ManifestConflict, MemoryManifestEntry,
+        MemoryRootManifest,
+    };
     pub use crate::utils::{IncompleteFilenodeInfo, IncompleteFilenodes};
 }
+
+/// Knows the errors of blobrepo and of the stores it's built on
+pub fn error_categorizer() -> mononoke_errors::Categorizer {
+    let categorizer = mononoke_errors::Categorizer::default()
+        .with::<ErrorKind>()
+        .with::<blobstore::ErrorKind>()
+        .with::<multiplexedblob::base::ErrorKind>()
+        .with::<changesets::ErrorKind>()
+        .with::<bonsai_hg_mapping::ErrorKind>();
+    #[cfg(feature = "fault_injection")]
+    let categorizer = categorizer.with::<fault_injection::ErrorKind>();
+    categorizer
+}
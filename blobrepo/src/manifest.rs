@@ -10,7 +10,7 @@ use std::collections::BTreeMap;
 use std::str;
 
 use failure::{Error, FutureFailureErrorExt, Result, ResultExt};
-use futures::future::{Future, IntoFuture};
+use futures::future::{self, Future, IntoFuture};
 use futures_ext::{BoxFuture, FutureExt};
 
 use context::CoreContext;
@@ -20,6 +20,7 @@ use mercurial_types::{Entry, FileType, HgBlob, HgManifestEnvelope, MPathElement,
 use blobstore::Blobstore;
 
 use crate::file::HgBlobEntry;
+use crate::manifest_sharding::unshard_manifest_envelope;
 use blob_changeset::RepoBlobstore;
 use errors::*;
 
@@ -112,10 +113,28 @@ pub fn fetch_manifest_envelope(
 }
 
 /// Like `fetch_manifest_envelope`, but returns None if the manifest wasn't found.
+/// Sharded manifests are returned with their contents in one piece.
 pub fn fetch_manifest_envelope_opt(
     ctx: CoreContext,
     blobstore: &RepoBlobstore,
     node_id: HgNodeHash,
+) -> impl Future<Item = Option<HgManifestEnvelope>, Error = Error> {
+    fetch_stored_manifest_envelope_opt(ctx.clone(), blobstore, node_id).and_then({
+        let blobstore = blobstore.clone();
+        move |envelope| match envelope {
+            Some(envelope) => unshard_manifest_envelope(ctx, &blobstore, envelope)
+                .map(Some)
+                .left_future(),
+            None => future::ok(None).right_future(),
+        }
+    })
+}
+
+/// The manifest envelope as it's stored, which may be sharded.
+pub fn fetch_stored_manifest_envelope_opt(
+    ctx: CoreContext,
+    blobstore: &RepoBlobstore,
+    node_id: HgNodeHash,
 ) -> impl Future<Item = Option<HgManifestEnvelope>, Error = Error> {
     let blobstore_key = HgManifestId::new(node_id).blobstore_key();
     blobstore
@@ -0,0 +1,208 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Sharded storage of the manifests of large directories.
+//!
+//! A directory with hundreds of thousands of entries has a manifest of several megabytes, which
+//! every read has to fetch as one blob. Past a configured size, the entries of a manifest are
+//! instead cut into shards by name range and each shard is stored as a blob of its own, keyed by
+//! the hash of its entries. The envelope then only lists the shards.
+//!
+//! Sharding is a storage detail: manifests are reassembled when they're fetched, so neither the
+//! `Manifest` trait nor clients see it. The node hash of a manifest is the one of its full
+//! contents either way.
+
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, Future};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+use context::CoreContext;
+use mercurial_types::{HgManifestEnvelope, HgManifestEnvelopeMut, HgManifestShard, MPathElement};
+use metaconfig_types::ManifestShardingParams;
+use mononoke_types::hash::{Blake2, Context};
+use mononoke_types::BlobstoreBytes;
+
+use crate::failure::{Error, Result};
+use blob_changeset::RepoBlobstore;
+use errors::*;
+
+/// Shards fetched at the same time while reassembling a manifest
+const SHARD_FETCH_CONCURRENCY: usize = 100;
+
+fn shard_id(entries: &[u8]) -> Blake2 {
+    let mut context = Context::new(b"hgmanifestshard");
+    context.update(entries);
+    context.finish()
+}
+
+/// Cut manifest `contents` into ranges of entries of at least `shard_size` bytes, except for the
+/// last one. Each range comes with the name of its first entry.
+pub fn split_manifest_contents(
+    contents: &Bytes,
+    shard_size: usize,
+) -> Result<Vec<(MPathElement, Bytes)>> {
+    let mut shards = vec![];
+    let mut start = 0;
+    while start < contents.len() {
+        let mut end = start;
+        loop {
+            end = match contents[end..].iter().position(|b| *b == b'\n') {
+                Some(newline) => end + newline + 1,
+                None => contents.len(),
+            };
+            if end == contents.len() || end - start >= shard_size {
+                break;
+            }
+        }
+
+        let entries = contents.slice(start, end);
+        let first_name = match entries.iter().position(|b| *b == 0) {
+            Some(nul) => MPathElement::new(entries[..nul].to_vec())?,
+            None => bail_msg!("Malformed entry: no \\0"),
+        };
+        shards.push((first_name, entries));
+        start = end;
+    }
+    Ok(shards)
+}
+
+/// Store the contents of `envelope` as shards if they're larger than `params` allow, and return
+/// the envelope to store in its place.
+pub fn shard_manifest_envelope(
+    ctx: CoreContext,
+    blobstore: &RepoBlobstore,
+    envelope: HgManifestEnvelopeMut,
+    params: ManifestShardingParams,
+) -> BoxFuture<HgManifestEnvelopeMut, Error> {
+    if !envelope.shards.is_empty() || envelope.contents.len() as u64 <= params.threshold_bytes {
+        return future::ok(envelope).boxify();
+    }
+
+    let entries = try_boxfuture!(split_manifest_contents(
+        &envelope.contents,
+        params.shard_size_bytes as usize,
+    ));
+    let (shards, puts): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .map(|(first_name, entries)| {
+            let shard = HgManifestShard {
+                first_name,
+                id: shard_id(entries.as_ref()),
+            };
+            let put = blobstore.put(
+                ctx.clone(),
+                shard.blobstore_key(),
+                BlobstoreBytes::from_bytes(entries),
+            );
+            (shard, put)
+        })
+        .unzip();
+
+    future::join_all(puts)
+        .map(move |_| HgManifestEnvelopeMut {
+            contents: Bytes::new(),
+            shards,
+            ..envelope
+        })
+        .boxify()
+}
+
+/// Fetch the shards of `envelope` and return it with its contents in one piece. Envelopes that
+/// aren't sharded are returned as they are.
+pub fn unshard_manifest_envelope(
+    ctx: CoreContext,
+    blobstore: &RepoBlobstore,
+    envelope: HgManifestEnvelope,
+) -> BoxFuture<HgManifestEnvelope, Error> {
+    if !envelope.is_sharded() {
+        return future::ok(envelope).boxify();
+    }
+
+    let node_id = envelope.node_id();
+    let fetches: Vec<_> = envelope
+        .shards()
+        .iter()
+        .map(|shard| {
+            let key = shard.blobstore_key();
+            let id = shard.id;
+            blobstore
+                .get(ctx.clone(), key.clone())
+                .and_then(move |bytes| {
+                    let entries = bytes
+                        .ok_or_else(|| ErrorKind::ManifestShardMissing(node_id, key.clone()))?
+                        .into_bytes();
+                    if shard_id(entries.as_ref()) != id {
+                        return Err(ErrorKind::ManifestShardCorrupt(node_id, key).into());
+                    }
+                    Ok(entries)
+                })
+        })
+        .collect();
+
+    stream::iter_ok(fetches)
+        .buffered(SHARD_FETCH_CONCURRENCY)
+        .fold(BytesMut::new(), |mut contents, entries| {
+            contents.extend_from_slice(entries.as_ref());
+            Ok::<_, Error>(contents)
+        })
+        .map(move |contents| {
+            HgManifestEnvelopeMut {
+                contents: contents.freeze(),
+                shards: vec![],
+                ..envelope.into_mut()
+            }
+            .freeze()
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn split(contents: &'static [u8], shard_size: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        split_manifest_contents(&Bytes::from_static(contents), shard_size)
+            .expect("split failed")
+            .into_iter()
+            .map(|(first_name, entries)| (first_name.to_bytes(), entries.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_split_between_entries() {
+        let contents = b"a\x001111111111111111111111111111111111111111\n\
+                         b\x002222222222222222222222222222222222222222x\n\
+                         c\x003333333333333333333333333333333333333333\n";
+        let shards = split(contents, 40);
+        assert_eq!(
+            shards,
+            vec![
+                (b"a".to_vec(), contents[..43].to_vec()),
+                (b"b".to_vec(), contents[43..87].to_vec()),
+                (b"c".to_vec(), contents[87..].to_vec()),
+            ]
+        );
+
+        let shards = split(contents, 80);
+        assert_eq!(
+            shards,
+            vec![
+                (b"a".to_vec(), contents[..87].to_vec()),
+                (b"c".to_vec(), contents[87..].to_vec()),
+            ]
+        );
+
+        assert_eq!(split(contents, 1000).len(), 1);
+        assert!(split(b"", 50).is_empty());
+    }
+
+    #[test]
+    fn test_split_malformed() {
+        assert!(split_manifest_contents(&Bytes::from_static(b"no nul\n"), 50).is_err());
+    }
+}
@@ -22,12 +22,14 @@ use blob_changeset::RepoBlobstore;
 use context::CoreContext;
 use mercurial_types::manifest::Content;
 use mercurial_types::{
-    Entry, HgFileNodeId, HgManifestId, HgNodeHash, MPath, MPathElement, Manifest, RepoPath, Type,
+    Entry, HgFileNodeId, HgManifestId, HgNodeHash, HgParents, MPath, MPathElement, Manifest,
+    RepoPath, Type,
 };
 use mononoke_types::{FileContents, FileType};
 
 use crate::file::HgBlobEntry;
 use crate::repo::{UploadHgFileContents, UploadHgFileEntry, UploadHgNodeHash, UploadHgTreeEntry};
+use crate::repo_commit::compute_copy_from_info;
 
 use super::utils::{IncompleteFilenodeInfo, IncompleteFilenodes};
 use super::BlobRepo;
@@ -82,6 +84,123 @@ impl Debug for MemoryManifestEntry {
     }
 }
 
+/// What one parent of a merge has at a conflicting path
+#[derive(Clone, Debug)]
+pub enum ConflictCandidate {
+    /// A file. `parents` are the parents of its filenode, and `copy_from` is set if this version
+    /// of the file was copied or moved from another path.
+    File {
+        entry: HgBlobEntry,
+        parents: HgParents,
+        copy_from: Option<(RepoPath, HgFileNodeId)>,
+    },
+    /// A directory. `manifest_id` is None if the directory has been modified in memory.
+    Tree { manifest_id: Option<HgNodeHash> },
+}
+
+/// A path the parents of a merge disagree on. There is one candidate per parent, in parent order.
+#[derive(Clone, Debug)]
+pub struct ManifestConflict {
+    pub path: MPath,
+    pub candidates: Vec<ConflictCandidate>,
+}
+
+/// How to resolve a `ManifestConflict`
+#[derive(Clone, Debug)]
+pub enum ConflictResolution {
+    /// Leave the conflict in place - saving the manifest will fail until it's resolved
+    Unresolved,
+    /// Take the candidate with this index as it is
+    UseCandidate(usize),
+    /// Use a different entry, e.g. a merged file uploaded with the candidates as its parents
+    UseEntry(HgBlobEntry),
+    /// Remove the path
+    Delete,
+}
+
+/// Where a conflict lives in the in-memory tree, so that it can be replaced once resolved
+struct ConflictLocation {
+    changes: Arc<Mutex<BTreeMap<MPathElement, Option<MemoryManifestEntry>>>>,
+    name: MPathElement,
+    path: MPath,
+    entries: Vec<MemoryManifestEntry>,
+}
+
+impl ConflictLocation {
+    fn to_conflict(&self, ctx: CoreContext) -> impl Future<Item = ManifestConflict, Error = Error> {
+        let path = self.path.clone();
+        future::join_all(self.entries.iter().map({
+            cloned!(path);
+            move |entry| Self::candidate(ctx.clone(), path.clone(), entry.clone())
+        }))
+        .map(move |candidates| ManifestConflict { path, candidates })
+    }
+
+    fn candidate(
+        ctx: CoreContext,
+        path: MPath,
+        entry: MemoryManifestEntry,
+    ) -> BoxFuture<ConflictCandidate, Error> {
+        let modified = entry.is_modified();
+        match entry {
+            MemoryManifestEntry::Blob(blob) => {
+                if blob.get_type() == Type::Tree {
+                    return future::ok(ConflictCandidate::Tree {
+                        manifest_id: Some(blob.get_hash().into_nodehash()),
+                    })
+                    .boxify();
+                }
+                blob.get_parents(ctx.clone())
+                    .and_then(move |parents| {
+                        compute_copy_from_info(ctx, &RepoPath::FilePath(path), &blob, &parents).map(
+                            move |copy_from| ConflictCandidate::File {
+                                entry: blob,
+                                parents,
+                                copy_from,
+                            },
+                        )
+                    })
+                    .boxify()
+            }
+            MemoryManifestEntry::MemTree {
+                base_manifest_id, ..
+            } => future::ok(ConflictCandidate::Tree {
+                manifest_id: if modified { None } else { base_manifest_id },
+            })
+            .boxify(),
+            // merge_with_conflicts refuses to merge conflicts, so they can't nest
+            MemoryManifestEntry::Conflict(_) => {
+                future::err(ErrorKind::UnresolvedConflicts.into()).boxify()
+            }
+        }
+    }
+
+    fn resolve(self, resolution: ConflictResolution) -> Result<()> {
+        let entry = match resolution {
+            ConflictResolution::Unresolved => return Ok(()),
+            ConflictResolution::UseCandidate(index) => Some(
+                self.entries
+                    .get(index)
+                    .cloned()
+                    .ok_or(ErrorKind::InvalidConflictResolution(self.path))?,
+            ),
+            ConflictResolution::UseEntry(entry) => {
+                if entry.get_type() == Type::Tree {
+                    Some(MemoryManifestEntry::convert_treenode(
+                        entry.get_hash().into_nodehash(),
+                    ))
+                } else {
+                    Some(MemoryManifestEntry::Blob(entry))
+                }
+            }
+            ConflictResolution::Delete => None,
+        };
+        let mut changes = self.changes.lock().expect("lock poisoned");
+        changes.insert(self.name, entry);
+        Ok(())
+    }
+}
+
 // This is tied to the implementation of MemoryManifestEntry::save below
 fn extend_repopath_with_dir(path: &RepoPath, dir: &MPathElement) -> RepoPath {
     assert!(path.is_dir() || path.is_root(), "Cannot extend a filepath");
@@ -257,7 +376,7 @@ impl MemoryManifestEntry {
                                             path,
                                         };
                                         upload_manifest
-                                            .upload_to_blobstore(ctx, &blobstore, &logger)
+                                            .upload_to_blobstore(ctx, &blobstore, &logger, None)
                                             .map(|(_hash, future)| future)
                                             .into_future()
                                             .flatten()
@@ -593,6 +712,29 @@ impl MemoryManifestEntry {
         .boxify()
     }
 
+    // Collect the conflicts in this tree and its modified subtrees. Unmodified subtrees come
+    // straight from a parent manifest, so they can't contain conflicts.
+    fn find_conflicts(&self, path: Option<&MPath>, found: &mut Vec<ConflictLocation>) {
+        if let MemoryManifestEntry::MemTree { changes, .. } = self {
+            let changes_guard = changes.lock().expect("lock poisoned");
+            for (name, child) in changes_guard.iter() {
+                let child_path = MPath::join_opt_element(path, name);
+                match child {
+                    Some(MemoryManifestEntry::Conflict(entries)) => {
+                        found.push(ConflictLocation {
+                            changes: changes.clone(),
+                            name: name.clone(),
+                            path: child_path,
+                            entries: entries.clone(),
+                        });
+                    }
+                    Some(child) => child.find_conflicts(Some(&child_path), found),
+                    None => {}
+                }
+            }
+        }
+    }
+
     // Only for use in find_mut_helper
     fn conflict_to_memtree(&mut self) -> Self {
         let new = if let MemoryManifestEntry::Conflict(conflicts) = self {
@@ -1063,6 +1205,40 @@ impl MemoryRootManifest {
         )
     }
 
+    fn conflict_locations(&self) -> Vec<ConflictLocation> {
+        let mut found = Vec::new();
+        self.root_entry.find_conflicts(None, &mut found);
+        found
+    }
+
+    /// List the paths that the parents of this manifest disagree on, with what each parent has
+    /// at that path. Call after resolve_trivial_conflicts to only see the conflicts that need a
+    /// decision.
+    pub fn conflicts(&self, ctx: CoreContext) -> BoxFuture<Vec<ManifestConflict>, Error> {
+        future::join_all(
+            self.conflict_locations()
+                .into_iter()
+                .map(move |location| location.to_conflict(ctx.clone())),
+        )
+        .boxify()
+    }
+
+    /// Resolve conflicts by calling `resolve` once for each conflicting path, and applying the
+    /// resolution it returns. Conflicts that it leaves `Unresolved` stay in the manifest.
+    pub fn resolve_conflicts<F>(&self, ctx: CoreContext, resolve: F) -> BoxFuture<(), Error>
+    where
+        F: Fn(&ManifestConflict) -> ConflictResolution + Send + 'static,
+    {
+        stream::iter_ok(self.conflict_locations())
+            .and_then(move |location| {
+                location
+                    .to_conflict(ctx.clone())
+                    .map(move |conflict| (location, conflict))
+            })
+            .for_each(move |(location, conflict)| location.resolve(resolve(&conflict)))
+            .boxify()
+    }
+
     pub fn unittest_root(&self) -> &MemoryManifestEntry {
         &self.root_entry
     }
@@ -0,0 +1,234 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The SQL side of pulling a repo through from upstream. Blobs are fetched as they are read, see
+//! pullthroughblob, but the bookmarks, changesets and hg mappings are what tell a server which
+//! blobs there are, so they are copied from upstream ahead of time.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use bonsai_hg_mapping::{BonsaiHgMapping, BonsaiHgMappingEntry};
+use bookmarks::{Bookmark, BookmarkPrefix, BookmarkUpdateReason, Bookmarks};
+use changesets::{ChangesetInsert, Changesets};
+use context::CoreContext;
+use futures::future::{self, loop_fn, Future, Loop};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use mononoke_types::{ChangesetId, RepositoryId};
+use pullthroughblob::{Upstream, UpstreamChangeset};
+use stats::Timeseries;
+
+use crate::failure::{Error, Result};
+
+define_stats! {
+    prefix = "mononoke.blobrepo.pull_through_sync";
+    changesets_added: timeseries(RATE, SUM),
+    bookmarks_moved: timeseries(RATE, SUM),
+}
+
+/// Copies the bookmarks of upstream to the SQL stores of a repo that pulls its blobs through
+/// from upstream, with the changesets and hg mappings of the commits they point to.
+///
+/// Upstream is the source of truth: local bookmarks that upstream doesn't have are deleted.
+#[derive(Clone)]
+pub struct PullThroughSync {
+    repoid: RepositoryId,
+    upstream: Arc<Upstream>,
+    bookmarks: Arc<Bookmarks>,
+    changesets: Arc<Changesets>,
+    bonsai_hg_mapping: Arc<BonsaiHgMapping>,
+}
+
+impl PullThroughSync {
+    pub fn new(
+        repoid: RepositoryId,
+        upstream: Arc<Upstream>,
+        bookmarks: Arc<Bookmarks>,
+        changesets: Arc<Changesets>,
+        bonsai_hg_mapping: Arc<BonsaiHgMapping>,
+    ) -> Self {
+        Self {
+            repoid,
+            upstream,
+            bookmarks,
+            changesets,
+            bonsai_hg_mapping,
+        }
+    }
+
+    /// Move the local bookmarks to where they are upstream, after adding the changesets they
+    /// need. Returns how many bookmarks moved.
+    pub fn sync(&self, ctx: CoreContext) -> BoxFuture<usize, Error> {
+        let local = self
+            .bookmarks
+            .list_by_prefix(ctx.clone(), &BookmarkPrefix::empty(), self.repoid)
+            .collect();
+        let this = self.clone();
+        self.upstream
+            .bookmarks()
+            .join(local)
+            .and_then(move |(upstream, local)| {
+                let upstream = try_boxfuture!(upstream
+                    .into_iter()
+                    .map(|(name, cs_id)| Ok((Bookmark::new(name)?, cs_id)))
+                    .collect::<Result<HashMap<_, _>>>());
+                let local: HashMap<_, _> = local.into_iter().collect();
+                let heads = upstream.values().cloned().collect();
+                this.add_missing_changesets(ctx.clone(), heads)
+                    .and_then(move |()| this.move_bookmarks(ctx, upstream, local))
+                    .boxify()
+            })
+            .boxify()
+    }
+
+    /// Add `heads` and their ancestors that are missing locally. The walk stops at changesets
+    /// that are there, as their ancestors are too. Changesets are added parents first.
+    fn add_missing_changesets(
+        &self,
+        ctx: CoreContext,
+        heads: Vec<ChangesetId>,
+    ) -> BoxFuture<(), Error> {
+        let seen: HashSet<_> = heads.iter().cloned().collect();
+        loop_fn((heads, seen, Vec::new()), {
+            cloned!(ctx);
+            let this = self.clone();
+            move |(to_visit, mut seen, mut missing)| {
+                let lookups = to_visit
+                    .into_iter()
+                    .map(|cs_id| this.fetch_if_missing(ctx.clone(), cs_id));
+                future::join_all(lookups).map(move |fetched| {
+                    let mut to_visit = vec![];
+                    for (cs_id, upstream_cs) in fetched.into_iter().flatten() {
+                        for parent in &upstream_cs.parents {
+                            if seen.insert(*parent) {
+                                to_visit.push(*parent);
+                            }
+                        }
+                        missing.push((cs_id, upstream_cs));
+                    }
+                    if to_visit.is_empty() {
+                        Loop::Break(missing)
+                    } else {
+                        Loop::Continue((to_visit, seen, missing))
+                    }
+                })
+            }
+        })
+        .and_then({
+            let this = self.clone();
+            move |mut missing| {
+                missing.sort_by_key(|(_, upstream_cs)| upstream_cs.gen);
+                STATS::changesets_added.add_value(missing.len() as i64);
+                stream::iter_ok(missing).for_each(move |(cs_id, upstream_cs)| {
+                    this.add_changeset(ctx.clone(), cs_id, upstream_cs)
+                })
+            }
+        })
+        .boxify()
+    }
+
+    /// What upstream has about `cs_id`, if it's missing locally
+    fn fetch_if_missing(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> BoxFuture<Option<(ChangesetId, UpstreamChangeset)>, Error> {
+        let upstream = self.upstream.clone();
+        self.changesets
+            .get(ctx, self.repoid, cs_id)
+            .and_then(move |entry| {
+                if entry.is_some() {
+                    return future::ok(None).left_future();
+                }
+                upstream
+                    .changeset(cs_id)
+                    .and_then(move |upstream_cs| match upstream_cs {
+                        Some(upstream_cs) => Ok(Some((cs_id, upstream_cs))),
+                        None => Err(format_err!("upstream has no changeset {}", cs_id)),
+                    })
+                    .right_future()
+            })
+            .boxify()
+    }
+
+    /// Add the hg mapping of `cs_id`, then its changeset entry, as changesets are taken to be
+    /// complete once they have one
+    fn add_changeset(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+        upstream_cs: UpstreamChangeset,
+    ) -> BoxFuture<(), Error> {
+        let mapping_entry = BonsaiHgMappingEntry {
+            repo_id: self.repoid,
+            hg_cs_id: upstream_cs.hg_cs_id,
+            bcs_id: cs_id,
+        };
+        let insert = ChangesetInsert {
+            repo_id: self.repoid,
+            cs_id,
+            parents: upstream_cs.parents,
+        };
+        let changesets = self.changesets.clone();
+        self.bonsai_hg_mapping
+            .add(ctx.clone(), mapping_entry)
+            .and_then(move |_| changesets.add(ctx, insert))
+            .map(|_| ())
+            .boxify()
+    }
+
+    /// Move the bookmarks in `local` to where they are in `upstream`, deleting the ones that
+    /// upstream doesn't have. Fails if a bookmark moved locally meanwhile; the next sync
+    /// starts over from the bookmarks as they are then.
+    fn move_bookmarks(
+        &self,
+        ctx: CoreContext,
+        upstream: HashMap<Bookmark, ChangesetId>,
+        local: HashMap<Bookmark, ChangesetId>,
+    ) -> BoxFuture<usize, Error> {
+        let mut transaction = self.bookmarks.create_transaction(ctx, self.repoid);
+        let mut moved = 0;
+        for (bookmark, cs_id) in &upstream {
+            let old_cs_id = local.get(bookmark).cloned();
+            if old_cs_id != Some(*cs_id) {
+                try_boxfuture!(transaction.move_bookmark(
+                    bookmark,
+                    Some(*cs_id),
+                    old_cs_id,
+                    BookmarkUpdateReason::Blobimport
+                ));
+                moved += 1;
+            }
+        }
+        for (bookmark, cs_id) in &local {
+            if !upstream.contains_key(bookmark) {
+                try_boxfuture!(transaction.move_bookmark(
+                    bookmark,
+                    None,
+                    Some(*cs_id),
+                    BookmarkUpdateReason::Blobimport
+                ));
+                moved += 1;
+            }
+        }
+        if moved == 0 {
+            return future::ok(0).boxify();
+        }
+
+        transaction
+            .commit()
+            .and_then(move |committed| {
+                if committed {
+                    STATS::bookmarks_moved.add_value(moved as i64);
+                    Ok(moved)
+                } else {
+                    Err(format_err!("bookmarks moved locally while syncing them"))
+                }
+            })
+            .boxify()
+    }
+}
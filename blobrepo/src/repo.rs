@@ -11,15 +11,18 @@ use crate::errors::*;
 use crate::failure::{prelude::*, Error, FutureFailureErrorExt, FutureFailureExt, Result};
 use crate::file::{
     fetch_file_content_from_blobstore, fetch_file_content_id_from_blobstore,
-    fetch_file_content_sha256_from_blobstore, fetch_file_contents, fetch_file_envelope,
-    fetch_file_size_from_blobstore, fetch_raw_filenode_bytes, fetch_rename_from_blobstore,
-    get_rename_from_envelope, HgBlobEntry,
+    fetch_file_content_sha256_from_blobstore, fetch_file_contents, fetch_file_contents_stream,
+    fetch_file_envelope, fetch_file_size_from_blobstore, fetch_raw_filenode_bytes,
+    fetch_rename_from_blobstore, get_rename_from_envelope, FileContentsStream, HgBlobEntry,
 };
+use crate::manifest::fetch_stored_manifest_envelope_opt;
+use crate::manifest_sharding::shard_manifest_envelope;
 use crate::memory_manifest::MemoryRootManifest;
+use crate::pull_through::PullThroughSync;
 use crate::repo_commit::*;
 use crate::{BlobManifest, HgBlobChangeset};
 use blob_changeset::{ChangesetMetadata, HgChangesetContent, RepoBlobstore};
-use blobstore::Blobstore;
+use blobstore::{Blobstore, RetryPolicy, RetryingBlobstore};
 use bonsai_hg_mapping::{BonsaiHgMapping, BonsaiHgMappingEntry, BonsaiOrHgChangesetIds};
 use bookmarks::{self, Bookmark, BookmarkPrefix, Bookmarks};
 use bytes::Bytes;
@@ -27,6 +30,11 @@ use cacheblob::MemWritesBlobstore;
 use changeset_fetcher::{ChangesetFetcher, SimpleChangesetFetcher};
 use changesets::{ChangesetEntry, ChangesetInsert, Changesets};
 use context::CoreContext;
+#[cfg(feature = "fault_injection")]
+use fault_injection::{
+    FaultInjectBlobstore, FaultInjectBonsaiHgMapping, FaultInjectChangesets, FaultInjectFilenodes,
+    FaultInjector,
+};
 use filenodes::{FilenodeInfo, Filenodes};
 use futures::future::{self, loop_fn, ok, Either, Future, Loop};
 use futures::stream::{FuturesUnordered, Stream};
@@ -40,22 +48,26 @@ use mercurial_types::{
     Changeset, Entry, HgBlob, HgBlobNode, HgChangesetId, HgFileEnvelopeMut, HgFileNodeId,
     HgManifestEnvelopeMut, HgManifestId, HgNodeHash, HgParents, Manifest, RepoPath, Type,
 };
+#[cfg(feature = "fault_injection")]
+use metaconfig_types::FaultInjectionParams;
+use metaconfig_types::{ManifestShardingParams, PullThroughParams};
 use mononoke_types::{
     hash::Blake2, hash::Sha256, Blob, BlobstoreBytes, BlobstoreValue, BonsaiChangeset, ChangesetId,
     ContentId, FileChange, FileContents, FileType, Generation, MPath, MPathElement, MononokeId,
     RepositoryId,
 };
 use prefixblob::PrefixBlobstore;
+use pullthroughblob::{ApiserverUpstream, PullThroughBlobstore, PullThroughOptions, Upstream};
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 use slog::Logger;
 use stats::Timeseries;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::From;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use time_ext::DurationExt;
 use tracing::{trace_args, EventId, Traced};
-use uuid::Uuid;
 
 define_stats! {
     prefix = "mononoke.blobrepo";
@@ -74,6 +86,7 @@ define_stats! {
     get_hg_file_copy_from_blobstore: timeseries(RATE, SUM),
     get_hg_from_bonsai_changeset: timeseries(RATE, SUM),
     get_manifest_by_nodeid: timeseries(RATE, SUM),
+    shard_manifest: timeseries(RATE, SUM),
     get_root_entry: timeseries(RATE, SUM),
     get_bookmark: timeseries(RATE, SUM),
     get_bookmarks: timeseries(RATE, SUM),
@@ -95,6 +108,23 @@ define_stats! {
     create_changeset_cf_count: timeseries("create_changeset.changed_files_count"; AVG, SUM),
 }
 
+/// Hg changeset extra that lists the parents of an octopus merge that don't fit into p1 and p2.
+///
+/// An hg changeset derived from a bonsai changeset with more than two parents gets the first two
+/// parents as p1 and p2, and its manifest is the merge of their manifests with the bonsai file
+/// changes applied. The hashes of the remaining hg parents are stored in this extra, in order,
+/// separated by commas.
+pub const STEPPARENTS_EXTRA: &str = "stepparents";
+
+fn encode_stepparents(stepparents: &[HgChangesetId]) -> Vec<u8> {
+    stepparents
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+        .into_bytes()
+}
+
 pub struct BlobRepo {
     logger: Logger,
     blobstore: RepoBlobstore,
@@ -104,8 +134,11 @@ pub struct BlobRepo {
     bonsai_hg_mapping: Arc<BonsaiHgMapping>,
     repoid: RepositoryId,
     // Returns new ChangesetFetcher that can be used by operation that work with commit graph
-    // (for example, revsets).
-    changeset_fetcher_factory: Arc<Fn() -> Arc<ChangesetFetcher + Send + Sync> + Send + Sync>,
+    // (for example, revsets). Takes the name of the subsystem the fetcher is for.
+    changeset_fetcher_factory:
+        Arc<Fn(&'static str) -> Arc<ChangesetFetcher + Send + Sync> + Send + Sync>,
+    // If set, uploaded manifests that are too large are stored as shards
+    manifest_sharding: Option<ManifestShardingParams>,
 }
 
 impl BlobRepo {
@@ -120,7 +153,7 @@ impl BlobRepo {
     ) -> Self {
         let changeset_fetcher_factory = {
             cloned!(changesets, repoid);
-            move || {
+            move |_subsystem: &'static str| {
                 let res: Arc<ChangesetFetcher + Send + Sync> = Arc::new(
                     SimpleChangesetFetcher::new(changesets.clone(), repoid.clone()),
                 );
@@ -137,6 +170,7 @@ impl BlobRepo {
             bonsai_hg_mapping,
             repoid,
             changeset_fetcher_factory: Arc::new(changeset_fetcher_factory),
+            manifest_sharding: None,
         }
     }
 
@@ -148,7 +182,9 @@ impl BlobRepo {
         changesets: Arc<Changesets>,
         bonsai_hg_mapping: Arc<BonsaiHgMapping>,
         repoid: RepositoryId,
-        changeset_fetcher_factory: Arc<Fn() -> Arc<ChangesetFetcher + Send + Sync> + Send + Sync>,
+        changeset_fetcher_factory: Arc<
+            Fn(&'static str) -> Arc<ChangesetFetcher + Send + Sync> + Send + Sync,
+        >,
     ) -> Self {
         BlobRepo {
             logger,
@@ -159,6 +195,17 @@ impl BlobRepo {
             bonsai_hg_mapping,
             repoid,
             changeset_fetcher_factory,
+            manifest_sharding: None,
+        }
+    }
+
+    /// Store the manifests uploaded to this repo that are larger than `params` allow as shards.
+    /// Manifests that are built in memory, e.g. by merges, are stored flat, and can be sharded
+    /// with `shard_manifest` later.
+    pub fn with_manifest_sharding(self, params: ManifestShardingParams) -> BlobRepo {
+        BlobRepo {
+            manifest_sharding: Some(params),
+            ..self
         }
     }
 
@@ -197,6 +244,151 @@ impl BlobRepo {
         )
     }
 
+    /// Make the storage of this repo fail and slow down as configured. Only meant for test
+    /// servers, to exercise error handling, so it's only built with the `fault_injection` feature.
+    /// The changeset fetchers of the repo are kept, so they don't see the faults of changesets.
+    #[cfg(feature = "fault_injection")]
+    pub fn with_fault_injection(self, params: &FaultInjectionParams) -> BlobRepo {
+        let BlobRepo {
+            logger,
+            bookmarks,
+            blobstore,
+            filenodes,
+            changesets,
+            bonsai_hg_mapping,
+            repoid,
+            changeset_fetcher_factory,
+            manifest_sharding,
+        } = self;
+
+        let blobstore_injector =
+            Arc::new(FaultInjector::new(params.blobstore.clone(), params.seed));
+        let sql_injector = Arc::new(FaultInjector::new(params.sql.clone(), params.seed));
+
+        // Drop the PrefixBlobstore (it will be wrapped up in one again by BlobRepo::new)
+        let blobstore = blobstore.into_inner();
+        let blobstore = Arc::new(FaultInjectBlobstore::new(blobstore, blobstore_injector));
+
+        let repo = BlobRepo::new_with_changeset_fetcher_factory(
+            logger,
+            bookmarks,
+            blobstore,
+            Arc::new(FaultInjectFilenodes::new(filenodes, sql_injector.clone())),
+            Arc::new(FaultInjectChangesets::new(changesets, sql_injector.clone())),
+            Arc::new(FaultInjectBonsaiHgMapping::new(
+                bonsai_hg_mapping,
+                sql_injector,
+            )),
+            repoid,
+            changeset_fetcher_factory,
+        );
+        BlobRepo {
+            manifest_sharding,
+            ..repo
+        }
+    }
+
+    /// Fetch the blobs missing from the storage of this repo from the apiserver of an upstream
+    /// server, and keep them. Only blobs are fetched as they are read: the SQL stores of the repo
+    /// are kept in sync with upstream by running the returned `PullThroughSync`.
+    pub fn with_pull_through(
+        self,
+        params: &PullThroughParams,
+    ) -> Result<(BlobRepo, PullThroughSync)> {
+        let BlobRepo {
+            logger,
+            bookmarks,
+            blobstore,
+            filenodes,
+            changesets,
+            bonsai_hg_mapping,
+            repoid,
+            changeset_fetcher_factory,
+            manifest_sharding,
+        } = self;
+
+        let upstream: Arc<Upstream> = Arc::new(ApiserverUpstream::new(
+            &params.upstream_url,
+            &params.upstream_repo,
+        )?);
+        let sync = PullThroughSync::new(
+            repoid,
+            upstream.clone(),
+            bookmarks.clone(),
+            changesets.clone(),
+            bonsai_hg_mapping.clone(),
+        );
+        let options = PullThroughOptions {
+            negative_cache_ttl: Duration::from_secs(params.negative_cache_ttl_secs),
+            prefetch_parents_depth: params.prefetch_parents_depth,
+        };
+
+        // Drop the PrefixBlobstore (it will be wrapped up in one again by BlobRepo::new)
+        let blobstore = blobstore.into_inner();
+        let blobstore = Arc::new(PullThroughBlobstore::new(
+            blobstore,
+            upstream,
+            repoid.prefix(),
+            options,
+        ));
+
+        let repo = BlobRepo::new_with_changeset_fetcher_factory(
+            logger,
+            bookmarks,
+            blobstore,
+            filenodes,
+            changesets,
+            bonsai_hg_mapping,
+            repoid,
+            changeset_fetcher_factory,
+        );
+        let repo = BlobRepo {
+            manifest_sharding,
+            ..repo
+        };
+        Ok((repo, sync))
+    }
+
+    /// Send blob puts that fail transiently again, as `policy` says. Only errors known to be
+    /// transient are retried, e.g. timeouts or multiplexed puts that didn't reach their quorum;
+    /// the untyped errors of blobstore backends are not.
+    pub fn with_put_retries(self, policy: RetryPolicy) -> BlobRepo {
+        let BlobRepo {
+            logger,
+            bookmarks,
+            blobstore,
+            filenodes,
+            changesets,
+            bonsai_hg_mapping,
+            repoid,
+            changeset_fetcher_factory,
+            manifest_sharding,
+        } = self;
+
+        // Drop the PrefixBlobstore (it will be wrapped up in one again by BlobRepo::new)
+        let blobstore = blobstore.into_inner();
+        let blobstore = Arc::new(RetryingBlobstore::new(
+            blobstore,
+            policy,
+            crate::error_categorizer(),
+        ));
+
+        let repo = BlobRepo::new_with_changeset_fetcher_factory(
+            logger,
+            bookmarks,
+            blobstore,
+            filenodes,
+            changesets,
+            bonsai_hg_mapping,
+            repoid,
+            changeset_fetcher_factory,
+        );
+        BlobRepo {
+            manifest_sharding,
+            ..repo
+        }
+    }
+
     fn fetch<K>(
         &self,
         ctx: CoreContext,
@@ -260,6 +452,35 @@ impl BlobRepo {
         fetch_file_content_from_blobstore(ctx, &self.blobstore, key).boxify()
     }
 
+    /// Like `get_file_content`, but the bytes of the file are passed on in the chunks the
+    /// blobstore reads them in. Only blobstores that override `get_stream` read in chunks.
+    pub fn get_file_content_stream(
+        &self,
+        ctx: CoreContext,
+        key: HgFileNodeId,
+    ) -> impl Future<Item = FileContentsStream, Error = Error> {
+        STATS::get_file_content.add_value(1);
+        let blobstore = self.blobstore.clone();
+
+        fetch_file_envelope(ctx.clone(), &blobstore, key).and_then(move |envelope| {
+            fetch_file_contents_stream(ctx, &blobstore, envelope.content_id())
+        })
+    }
+
+    /// Whether the content of a file was replaced by a tombstone. Only the start of the content
+    /// is needed to tell.
+    pub fn is_file_content_tombstoned(
+        &self,
+        ctx: CoreContext,
+        key: HgFileNodeId,
+    ) -> impl Future<Item = bool, Error = Error> {
+        self.get_file_content_stream(ctx, key)
+            .map(|contents| match contents {
+                FileContentsStream::Tombstone(_) => true,
+                FileContentsStream::Bytes(_) => false,
+            })
+    }
+
     pub fn get_file_content_by_content_id(
         &self,
         ctx: CoreContext,
@@ -366,6 +587,18 @@ impl BlobRepo {
             .from_err()
     }
 
+    /// Like `get_file_content_by_alias`, with the bytes of the file streamed
+    pub fn get_file_content_stream_by_alias(
+        &self,
+        ctx: CoreContext,
+        alias: Sha256,
+    ) -> impl Future<Item = FileContentsStream, Error = Error> {
+        let blobstore = self.blobstore.clone();
+
+        self.get_file_content_id_by_alias(ctx.clone(), alias)
+            .and_then(move |content_id| fetch_file_contents_stream(ctx, &blobstore, content_id))
+    }
+
     pub fn get_file_content_id_by_alias(
         &self,
         ctx: CoreContext,
@@ -599,6 +832,40 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// Store the manifest `manifestid` as shards if it's stored flat and is larger than `params`
+    /// allow. Returns whether it was sharded. The sharded envelope replaces the flat one under
+    /// the same key: both stand for the same manifest, and readers handle either.
+    pub fn shard_manifest(
+        &self,
+        ctx: CoreContext,
+        manifestid: HgManifestId,
+        params: ManifestShardingParams,
+    ) -> BoxFuture<bool, Error> {
+        STATS::shard_manifest.add_value(1);
+        let node_id = manifestid.into_nodehash();
+        let blobstore = self.blobstore.clone();
+        fetch_stored_manifest_envelope_opt(ctx.clone(), &self.blobstore, node_id)
+            .and_then(move |envelope| {
+                let envelope = envelope.ok_or(ErrorKind::ManifestMissing(manifestid))?;
+                let keep = envelope.is_sharded()
+                    || envelope.contents().len() as u64 <= params.threshold_bytes;
+                Ok((envelope, keep))
+            })
+            .and_then(move |(envelope, keep)| {
+                if keep {
+                    return future::ok(false).left_future();
+                }
+                shard_manifest_envelope(ctx.clone(), &blobstore, envelope.into_mut(), params)
+                    .and_then(move |envelope| {
+                        let envelope_blob = envelope.freeze().into_blob();
+                        blobstore.put(ctx, manifestid.blobstore_key(), envelope_blob.into())
+                    })
+                    .map(|()| true)
+                    .right_future()
+            })
+            .boxify()
+    }
+
     pub fn get_root_entry(&self, manifestid: HgManifestId) -> HgBlobEntry {
         STATS::get_root_entry.add_value(1);
         HgBlobEntry::new_root(self.blobstore.clone(), manifestid)
@@ -857,7 +1124,14 @@ impl BlobRepo {
     }
 
     pub fn get_changeset_fetcher(&self) -> Arc<ChangesetFetcher> {
-        (self.changeset_fetcher_factory)()
+        self.get_changeset_fetcher_for("default")
+    }
+
+    /// ChangesetFetcher for a subsystem that walks the commit graph, like "revsets" or "phases".
+    /// Depending on the repo, the fetchers of all subsystems may share a cache, in which case
+    /// the subsystem is used to tell them apart in the cache stats.
+    pub fn get_changeset_fetcher_for(&self, subsystem: &'static str) -> Arc<ChangesetFetcher> {
+        (self.changeset_fetcher_factory)(subsystem)
     }
 
     fn upload_blobstore_bytes(
@@ -1378,10 +1652,10 @@ impl BlobRepo {
                                 let mf_p1 = p1.map(|p| p.manifestid());
                                 let mf_p2 = p2.map(|p| p.manifestid());
 
-                                assert!(
-                                    parents.next().is_none(),
-                                    "more than 2 parents are not supported by hg"
-                                );
+                                // Hg changesets have at most two parents, the rest of an octopus
+                                // merge's parents are recorded in the extras.
+                                let stepparents: Vec<_> =
+                                    parents.map(|p| p.get_changeset_id()).collect();
                                 let hg_parents = HgParents::new(
                                     p1_hash.map(|h| h.into_nodehash()),
                                     p2_hash.map(|h| h.into_nodehash()),
@@ -1390,7 +1664,13 @@ impl BlobRepo {
                                     .and_then(move |(manifest_id, incomplete_filenodes)| {
                                         compute_changed_files(ctx, repo, manifest_id.clone(), mf_p1.as_ref(), mf_p2.as_ref())
                                             .map(move |files| {
-                                                (manifest_id, incomplete_filenodes, hg_parents, files)
+                                                (
+                                                    manifest_id,
+                                                    incomplete_filenodes,
+                                                    hg_parents,
+                                                    stepparents,
+                                                    files,
+                                                )
                                             })
 
                                     })
@@ -1399,15 +1679,28 @@ impl BlobRepo {
                         // create changeset
                         .and_then({
                             cloned!(ctx, repo, bcs);
-                            move |(manifest_id, incomplete_filenodes, parents, files)| {
+                            move |(
+                                manifest_id,
+                                incomplete_filenodes,
+                                parents,
+                                stepparents,
+                                files,
+                            )| {
+                                let mut extra: BTreeMap<_, _> = bcs.extra()
+                                    .map(|(k, v)| {
+                                        (k.as_bytes().to_vec(), v.to_vec())
+                                    })
+                                    .collect();
+                                if !stepparents.is_empty() {
+                                    extra.insert(
+                                        STEPPARENTS_EXTRA.as_bytes().to_vec(),
+                                        encode_stepparents(&stepparents),
+                                    );
+                                }
                                 let metadata = ChangesetMetadata {
                                     user: bcs.author().to_string(),
                                     time: *bcs.author_date(),
-                                    extra: bcs.extra()
-                                        .map(|(k, v)| {
-                                            (k.as_bytes().to_vec(), v.to_vec())
-                                        })
-                                        .collect(),
+                                    extra,
                                     comments: bcs.message().to_string(),
                                 };
                                 let content = HgChangesetContent::new_from_parts(
@@ -1492,7 +1785,7 @@ impl UploadHgTreeEntry {
         ctx: CoreContext,
         repo: &BlobRepo,
     ) -> Result<(HgNodeHash, BoxFuture<(HgBlobEntry, RepoPath), Error>)> {
-        self.upload_to_blobstore(ctx, &repo.blobstore, &repo.logger)
+        self.upload_to_blobstore(ctx, &repo.blobstore, &repo.logger, repo.manifest_sharding)
     }
 
     pub(crate) fn upload_to_blobstore(
@@ -1500,6 +1793,7 @@ impl UploadHgTreeEntry {
         ctx: CoreContext,
         blobstore: &RepoBlobstore,
         logger: &Logger,
+        manifest_sharding: Option<ManifestShardingParams>,
     ) -> Result<(HgNodeHash, BoxFuture<(HgBlobEntry, RepoPath), Error>)> {
         STATS::upload_hg_tree_entry.add_value(1);
         let UploadHgTreeEntry {
@@ -1527,15 +1821,19 @@ impl UploadHgTreeEntry {
         };
 
         // This is the blob that gets uploaded. Manifest contents are usually small so they're
-        // stored inline.
+        // stored inline, unless sharding is configured and they're too large.
         let envelope = HgManifestEnvelopeMut {
             node_id,
             p1,
             p2,
             computed_node_id,
             contents,
+            shards: vec![],
+        };
+        let envelope = match manifest_sharding {
+            Some(params) => shard_manifest_envelope(ctx.clone(), blobstore, envelope, params),
+            None => future::ok(envelope).boxify(),
         };
-        let envelope_blob = envelope.freeze().into_blob();
 
         let manifest_id = HgManifestId::new(node_id);
         let blobstore_key = manifest_id.blobstore_key();
@@ -1567,8 +1865,14 @@ impl UploadHgTreeEntry {
         }
 
         // Upload the blob.
-        let upload = blobstore
-            .put(ctx, blobstore_key, envelope_blob.into())
+        let upload = envelope
+            .and_then({
+                cloned!(blobstore);
+                move |envelope| {
+                    let envelope_blob = envelope.freeze().into_blob();
+                    blobstore.put(ctx, blobstore_key, envelope_blob.into())
+                }
+            })
             .map({
                 let path = path.clone();
                 move |()| (blob_entry, path)
@@ -1944,7 +2248,7 @@ impl CreateChangeset {
         STATS::create_changeset.add_value(1);
         // This is used for logging, so that we can tie up all our pieces without knowing about
         // the final commit hash
-        let uuid = Uuid::new_v4();
+        let uuid = ctx.new_uuid();
         scuba_logger.add("changeset_uuid", format!("{}", uuid));
         let event_id = EventId::new();
 
@@ -2231,6 +2535,7 @@ impl Clone for BlobRepo {
             bonsai_hg_mapping: self.bonsai_hg_mapping.clone(),
             repoid: self.repoid.clone(),
             changeset_fetcher_factory: self.changeset_fetcher_factory.clone(),
+            manifest_sharding: self.manifest_sharding,
         }
     }
 }
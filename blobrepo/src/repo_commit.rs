@@ -40,6 +40,9 @@ use crate::HgBlobChangeset;
 use blob_changeset::HgChangesetContent;
 use blob_changeset::{ChangesetMetadata, RepoBlobstore};
 
+/// How many filenodes have their copy info computed concurrently when a changeset is finalized.
+const FILENODE_INFO_CONCURRENCY: usize = 100;
+
 define_stats! {
     prefix = "mononoke.blobrepo_commit";
     process_file_entry: timeseries(RATE, SUM),
@@ -421,31 +424,37 @@ impl UploadEntries {
             if inner.draft {
                 future::ok(()).left_future()
             } else {
-                let filenodeinfos = stream::futures_unordered(uploaded_entries.into_iter().map(
-                    |(path, blobentry)| {
-                        blobentry.get_parents(ctx.clone()).and_then({
-                            cloned!(ctx);
-                            move |parents| {
-                                compute_copy_from_info(ctx, &path, &blobentry, &parents).map(
-                                    move |copyfrom| {
-                                        let (p1, p2) = parents.get_nodes();
-                                        FilenodeInfo {
-                                            path,
-                                            filenode: HgFileNodeId::new(
-                                                blobentry.get_hash().into_nodehash(),
-                                            ),
-                                            p1: p1.map(HgFileNodeId::new),
-                                            p2: p2.map(HgFileNodeId::new),
-                                            copyfrom,
-                                            linknode: HgChangesetId::new(cs_id),
-                                        }
-                                    },
-                                )
-                            }
-                        })
-                    },
-                ))
-                .boxify();
+                // Copy info needs the file content, so don't fetch it for all files of a large
+                // push at once. Filenodes are handed to the store as soon as they're ready, and
+                // it batches them into multi-row inserts.
+                let filenodeinfos = stream::iter_ok(uploaded_entries.into_iter())
+                    .map({
+                        cloned!(ctx);
+                        move |(path, blobentry)| {
+                            blobentry.get_parents(ctx.clone()).and_then({
+                                cloned!(ctx);
+                                move |parents| {
+                                    compute_copy_from_info(ctx, &path, &blobentry, &parents).map(
+                                        move |copyfrom| {
+                                            let (p1, p2) = parents.get_nodes();
+                                            FilenodeInfo {
+                                                path,
+                                                filenode: HgFileNodeId::new(
+                                                    blobentry.get_hash().into_nodehash(),
+                                                ),
+                                                p1: p1.map(HgFileNodeId::new),
+                                                p2: p2.map(HgFileNodeId::new),
+                                                copyfrom,
+                                                linknode: HgChangesetId::new(cs_id),
+                                            }
+                                        },
+                                    )
+                                }
+                            })
+                        }
+                    })
+                    .buffer_unordered(FILENODE_INFO_CONCURRENCY)
+                    .boxify();
 
                 filenodes
                     .add_filenodes(ctx, filenodeinfos, inner.repoid)
@@ -471,7 +480,7 @@ impl UploadEntries {
     }
 }
 
-fn compute_copy_from_info(
+pub(crate) fn compute_copy_from_info(
     ctx: CoreContext,
     path: &RepoPath,
     blobentry: &HgBlobEntry,
@@ -1180,3 +1180,60 @@ fn test_no_case_conflict_removal_dir() {
         );
     });
 }
+
+#[test]
+fn test_octopus_merge_to_hg() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let repo = get_empty_lazy_repo();
+
+        let roots: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|path| {
+                create_commit(
+                    ctx.clone(),
+                    repo.clone(),
+                    vec![],
+                    store_files(ctx.clone(), btreemap! {*path => Some(*path)}, repo.clone()),
+                )
+            })
+            .collect();
+        // Only the first two parents become hg parents, so the merge brings in "c" itself.
+        let merge = create_commit(
+            ctx.clone(),
+            repo.clone(),
+            roots.clone(),
+            store_files(ctx.clone(), btreemap! {"c" => Some("c")}, repo.clone()),
+        );
+
+        let hg_roots: Vec<_> = roots
+            .iter()
+            .map(|root| {
+                run_future(repo.get_hg_from_bonsai_changeset(ctx.clone(), *root)).unwrap()
+            })
+            .collect();
+        let hg_merge = run_future(repo.get_hg_from_bonsai_changeset(ctx.clone(), merge)).unwrap();
+        let hg_cs = run_future(repo.get_changeset_by_changesetid(ctx.clone(), hg_merge)).unwrap();
+
+        assert_eq!(hg_cs.p1(), Some(hg_roots[0].into_nodehash()));
+        assert_eq!(hg_cs.p2(), Some(hg_roots[1].into_nodehash()));
+        assert_eq!(
+            hg_cs.extra().get(blobrepo::STEPPARENTS_EXTRA.as_bytes()),
+            Some(&hg_roots[2].to_string().into_bytes()),
+        );
+
+        for path in &["a", "b", "c"] {
+            assert!(
+                run_future(repo.find_file_in_manifest(
+                    ctx.clone(),
+                    &MPath::new(path).unwrap(),
+                    hg_cs.manifestid(),
+                ))
+                .unwrap()
+                .is_some(),
+                "{} missing from the octopus merge",
+                path
+            );
+        }
+    });
+}
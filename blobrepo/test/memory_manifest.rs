@@ -11,11 +11,12 @@ use std::sync::{Arc, Mutex};
 use utils::run_future;
 
 use blobrepo::HgBlobEntry;
-use blobrepo::internal::{IncompleteFilenodes, MemoryManifestEntry, MemoryRootManifest};
+use blobrepo::internal::{ConflictCandidate, ConflictResolution, IncompleteFilenodes,
+                         MemoryManifestEntry, MemoryRootManifest};
 use context::CoreContext;
 use fixtures::many_files_dirs;
-use mercurial_types::{Entry, FileType, HgManifestId, HgNodeHash, MPath, MPathElement, Type,
-                      nodehash::HgEntryId};
+use mercurial_types::{Entry, FileType, HgManifestId, HgNodeHash, MPath, MPathElement, Manifest,
+                      Type, nodehash::HgEntryId};
 use mercurial_types_mocks::nodehash;
 use mononoke_types::RepoPath;
 
@@ -617,3 +618,105 @@ fn merge_manifests() {
         }
     })
 }
+
+#[test]
+fn resolve_conflicts_with_callback() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let repo = many_files_dirs::getrepo(None);
+        let blobstore = repo.get_blobstore();
+
+        let manifest_id = HgNodeHash::from_static_str("907f5b20e06dfb91057861d984423e84b64b5b7b")
+            .expect("Could not get nodehash");
+        let files: Vec<_> = repo
+            .get_manifest_by_nodeid(ctx.clone(), HgManifestId::new(manifest_id))
+            .wait()
+            .expect("Could not load manifest")
+            .list()
+            .filter(|entry| entry.get_type() != Type::Tree)
+            .take(2)
+            .map(|entry| entry.get_hash().into_nodehash())
+            .collect();
+        assert_eq!(files.len(), 2, "Need two files for a conflict");
+
+        let conflict = MPathElement::new(b"conflict".to_vec()).unwrap();
+        let side = |hash| {
+            let mut changes = BTreeMap::new();
+            changes.insert(
+                conflict.clone(),
+                Some(MemoryManifestEntry::Blob(HgBlobEntry::new(
+                    blobstore.clone(),
+                    conflict.clone(),
+                    hash,
+                    Type::File(FileType::Regular),
+                ))),
+            );
+            MemoryManifestEntry::MemTree {
+                base_manifest_id: None,
+                p1: None,
+                p2: None,
+                changes: Arc::new(Mutex::new(changes)),
+            }
+        };
+
+        let memory_manifest = MemoryRootManifest::new(
+            ctx.clone(),
+            repo.clone(),
+            IncompleteFilenodes::new(),
+            None,
+            None,
+        )
+        .wait()
+        .expect("Could not create empty manifest");
+        let merged = run_future(side(files[0]).merge_with_conflicts(
+            ctx.clone(),
+            side(files[1]),
+            blobstore.clone(),
+            repo.get_logger(),
+            IncompleteFilenodes::new(),
+            RepoPath::root(),
+        ))
+        .unwrap();
+        insert_entry(&memory_manifest.unittest_root(), conflict.clone(), merged);
+
+        let conflicts = run_future(memory_manifest.conflicts(ctx.clone())).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(
+            conflicts[0].path,
+            MPath::new("conflict/conflict").unwrap(),
+            "Wrong conflict path"
+        );
+        let candidate_hashes: Vec<_> = conflicts[0]
+            .candidates
+            .iter()
+            .map(|candidate| match candidate {
+                ConflictCandidate::File { entry, .. } => entry.get_hash().into_nodehash(),
+                ConflictCandidate::Tree { .. } => panic!("File candidate expected"),
+            })
+            .collect();
+        assert_eq!(candidate_hashes, files);
+
+        // Leaving the conflict unresolved keeps it
+        run_future(
+            memory_manifest.resolve_conflicts(ctx.clone(), |_| ConflictResolution::Unresolved),
+        )
+        .unwrap();
+        assert!(run_future(memory_manifest.save(ctx.clone())).is_err());
+
+        // An index that isn't a candidate is an error
+        assert!(run_future(
+            memory_manifest.resolve_conflicts(ctx.clone(), |_| ConflictResolution::UseCandidate(2))
+        )
+        .is_err());
+
+        // Take p2's version
+        run_future(
+            memory_manifest.resolve_conflicts(ctx.clone(), |_| ConflictResolution::UseCandidate(1)),
+        )
+        .unwrap();
+        assert!(run_future(memory_manifest.conflicts(ctx.clone()))
+            .unwrap()
+            .is_empty());
+        run_future(memory_manifest.save(ctx.clone())).expect("Resolved manifest should save");
+    })
+}
@@ -14,25 +14,44 @@ use cachelib::LruCachePool;
 use futures::IntoFuture;
 use futures_ext::{BoxFuture, FutureExt};
 use mononoke_types::BlobstoreBytes;
+use stats::Timeseries;
 
 use blobstore::{Blobstore, CountedBlobstore};
 
 use crate::dummy::DummyLease;
 use crate::in_process_lease::InProcessLease;
 use crate::locking_cache::CacheBlobstore;
+use crate::ttls::{decode_expiring, encode_expiring, CacheTtls};
+
+define_stats! {
+    prefix = "mononoke.blobstore.cachelib";
+    blob_hit: timeseries("blob_hit"; RATE, SUM),
+    blob_miss: timeseries("blob_miss"; RATE, SUM),
+    blob_expired: timeseries("blob_expired"; RATE, SUM),
+    blob_put_uncached: timeseries("blob_put_uncached"; RATE, SUM),
+}
 
 /// A caching layer over an existing blobstore, backed by cachelib
 #[derive(Clone)]
 pub struct CachelibOps {
     blob_pool: Arc<LruCachePool>,
     presence_pool: Arc<LruCachePool>,
+    /// If set, blobs are only cached for the TTLs of their key types, and carry the time they
+    /// expire at, as cachelib evicts but doesn't expire entries. Otherwise they're cached until
+    /// evicted.
+    ttls: Option<Arc<CacheTtls>>,
 }
 
 impl CachelibOps {
-    pub fn new(blob_pool: Arc<LruCachePool>, presence_pool: Arc<LruCachePool>) -> Self {
+    pub fn new(
+        blob_pool: Arc<LruCachePool>,
+        presence_pool: Arc<LruCachePool>,
+        ttls: Option<CacheTtls>,
+    ) -> Self {
         Self {
             blob_pool,
             presence_pool,
+            ttls: ttls.map(Arc::new),
         }
     }
 }
@@ -41,11 +60,12 @@ pub fn new_cachelib_blobstore_no_lease<T>(
     blobstore: T,
     blob_pool: Arc<LruCachePool>,
     presence_pool: Arc<LruCachePool>,
+    ttls: Option<CacheTtls>,
 ) -> CountedBlobstore<CacheBlobstore<CachelibOps, DummyLease, T>>
 where
     T: Blobstore + Clone,
 {
-    let cache_ops = CachelibOps::new(blob_pool, presence_pool);
+    let cache_ops = CachelibOps::new(blob_pool, presence_pool, ttls);
     CountedBlobstore::new(
         "cachelib".to_string(),
         CacheBlobstore::new(cache_ops, DummyLease {}, blobstore),
@@ -56,11 +76,12 @@ pub fn new_cachelib_blobstore<T>(
     blobstore: T,
     blob_pool: Arc<LruCachePool>,
     presence_pool: Arc<LruCachePool>,
+    ttls: Option<CacheTtls>,
 ) -> CountedBlobstore<CacheBlobstore<CachelibOps, InProcessLease, T>>
 where
     T: Blobstore + Clone,
 {
-    let cache_ops = CachelibOps::new(blob_pool, presence_pool);
+    let cache_ops = CachelibOps::new(blob_pool, presence_pool, ttls);
     CountedBlobstore::new(
         "cachelib".to_string(),
         CacheBlobstore::new(cache_ops, InProcessLease::new(), blobstore),
@@ -69,10 +90,27 @@ where
 
 impl CacheOps for CachelibOps {
     fn get(&self, key: &str) -> BoxFuture<Option<BlobstoreBytes>, ()> {
+        let ttls = self.ttls.clone();
         self.blob_pool
             .get(key)
             .map_err(|_| ())
-            .map(|opt| opt.map(BlobstoreBytes::from_bytes))
+            .map(move |opt| {
+                let blob = match ttls {
+                    Some(_) => opt.and_then(|entry| {
+                        let blob = decode_expiring(entry);
+                        if blob.is_none() {
+                            STATS::blob_expired.add_value(1);
+                        }
+                        blob
+                    }),
+                    None => opt.map(BlobstoreBytes::from_bytes),
+                };
+                match blob {
+                    Some(_) => STATS::blob_hit.add_value(1),
+                    None => STATS::blob_miss.add_value(1),
+                }
+                blob
+            })
             .into_future()
             .boxify()
     }
@@ -80,8 +118,18 @@ impl CacheOps for CachelibOps {
     fn put(&self, key: &str, value: BlobstoreBytes) -> BoxFuture<(), ()> {
         // A failure to set presence is considered fine, here.
         let _ = self.presence_pool.set(key, Bytes::from(b"P".as_ref()));
+        let entry = match self.ttls {
+            Some(ref ttls) => match ttls.ttl(key) {
+                Some(ttl) => encode_expiring(value, ttl),
+                None => {
+                    STATS::blob_put_uncached.add_value(1);
+                    return Ok(()).into_future().boxify();
+                }
+            },
+            None => value.into_bytes(),
+        };
         self.blob_pool
-            .set(key, value.into_bytes())
+            .set(key, entry)
             .map(|_| ())
             .map_err(|_| ())
             .into_future()
@@ -28,3 +28,6 @@ pub use crate::memcache_cache_lease::{
 
 mod mem_writes;
 pub use crate::mem_writes::MemWritesBlobstore;
+
+mod ttls;
+pub use crate::ttls::CacheTtls;
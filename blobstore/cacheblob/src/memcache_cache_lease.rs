@@ -4,6 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::sync::Arc;
 use std::time::Duration;
 
 use failure_ext::{err_msg, Error};
@@ -19,6 +20,7 @@ use mononoke_types::BlobstoreBytes;
 use stats::Timeseries;
 
 use crate::dummy::DummyLease;
+use crate::ttls::CacheTtls;
 use crate::CacheBlobstore;
 use crate::CacheOps;
 use crate::LeaseOps;
@@ -28,6 +30,7 @@ define_stats! {
     prefix = "mononoke.blobstore.memcache";
     blob_put: timeseries("blob_put"; RATE, SUM),
     blob_put_err: timeseries("blob_put_err"; RATE, SUM),
+    blob_put_uncached: timeseries("blob_put_uncached"; RATE, SUM),
     presence_put: timeseries("presence_put"; RATE, SUM),
     presence_put_err: timeseries("presence_put_err"; RATE, SUM),
     lease_claim: timeseries("lease_claim"; RATE, SUM),
@@ -55,6 +58,9 @@ pub struct MemcacheOps {
     keygen: KeyGen,
     presence_keygen: KeyGen,
     hostname: String,
+    /// If set, blobs are only cached for the TTLs of their key types. Otherwise they're cached
+    /// until evicted.
+    ttls: Option<Arc<CacheTtls>>,
 }
 
 const MEMCACHE_MAX_SIZE: usize = 1024000;
@@ -67,6 +73,7 @@ fn mc_raw_put(
     key: String,
     value: BlobstoreBytes,
     presence_key: String,
+    ttl: Option<Duration>,
 ) -> impl Future<Item = (), Error = ()> {
     let uploaded = compact_protocol::serialize(&LockState::uploaded_key(orig_key));
 
@@ -77,7 +84,13 @@ fn mc_raw_put(
         }
         if value.len() < MEMCACHE_MAX_SIZE {
             STATS::blob_put.add_value(1);
-            Either::A(memcache.set(key, value.into_bytes()).or_else(|_| {
+            let put = match ttl {
+                Some(ttl) => memcache
+                    .set_with_ttl(key, value.into_bytes(), ttl)
+                    .left_future(),
+                None => memcache.set(key, value.into_bytes()).right_future(),
+            };
+            Either::A(put.or_else(|_| {
                 STATS::blob_put_err.add_value(1);
                 Ok(()).into_future()
             }))
@@ -91,6 +104,7 @@ impl MemcacheOps {
     pub fn new(
         backing_store_name: impl ToString,
         backing_store_params: impl ToString,
+        ttls: Option<CacheTtls>,
     ) -> Result<Self, Error> {
         let hostname = FbWhoAmI::new()?
             .get_name()
@@ -114,6 +128,7 @@ impl MemcacheOps {
             keygen: KeyGen::new(blob_key, MC_CODEVER, MC_SITEVER),
             presence_keygen: KeyGen::new(presence_key, MC_CODEVER, MC_SITEVER),
             hostname,
+            ttls: ttls.map(Arc::new),
         })
     }
 
@@ -152,11 +167,12 @@ pub fn new_memcache_blobstore<T>(
     blobstore: T,
     backing_store_name: impl ToString,
     backing_store_params: impl ToString,
+    ttls: Option<CacheTtls>,
 ) -> Result<CountedBlobstore<CacheBlobstore<MemcacheOps, MemcacheOps, T>>, Error>
 where
     T: Blobstore + Clone,
 {
-    let cache_ops = MemcacheOps::new(backing_store_name, backing_store_params)?;
+    let cache_ops = MemcacheOps::new(backing_store_name, backing_store_params, ttls)?;
     Ok(CountedBlobstore::new(
         "memcache".to_string(),
         CacheBlobstore::new(cache_ops.clone(), cache_ops, blobstore),
@@ -167,11 +183,12 @@ pub fn new_memcache_blobstore_no_lease<T>(
     blobstore: T,
     backing_store_name: impl ToString,
     backing_store_params: impl ToString,
+    ttls: Option<CacheTtls>,
 ) -> Result<CountedBlobstore<CacheBlobstore<MemcacheOps, DummyLease, T>>, Error>
 where
     T: Blobstore + Clone,
 {
-    let cache_ops = MemcacheOps::new(backing_store_name, backing_store_params)?;
+    let cache_ops = MemcacheOps::new(backing_store_name, backing_store_params, ttls)?;
     Ok(CountedBlobstore::new(
         "memcache".to_string(),
         CacheBlobstore::new(cache_ops, DummyLease {}, blobstore),
@@ -189,11 +206,29 @@ impl CacheOps for MemcacheOps {
     }
 
     fn put(&self, key: &str, value: BlobstoreBytes) -> BoxFuture<(), ()> {
+        let ttl = match self.ttls {
+            Some(ref ttls) => match ttls.ttl(key) {
+                Some(ttl) => Some(ttl),
+                None => {
+                    STATS::blob_put_uncached.add_value(1);
+                    return Ok(()).into_future().boxify();
+                }
+            },
+            None => None,
+        };
         let mc_key = self.keygen.key(key);
         let presence_key = self.presence_keygen.key(key);
         let orig_key = key.to_string();
 
-        mc_raw_put(self.memcache.clone(), orig_key, mc_key, value, presence_key).boxify()
+        mc_raw_put(
+            self.memcache.clone(),
+            orig_key,
+            mc_key,
+            value,
+            presence_key,
+            ttl,
+        )
+        .boxify()
     }
 
     fn check_present(&self, key: &str) -> BoxFuture<bool, ()> {
@@ -0,0 +1,120 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+
+use mononoke_types::BlobstoreBytes;
+
+/// How long blobs are cached, by the type of their key. The type of a key is its first
+/// component after the repo prefix, e.g. `hgmanifest` for `repo0000.hgmanifest.sha1.<hash>`.
+#[derive(Clone, Debug, Default)]
+pub struct CacheTtls {
+    /// TTL of the types that aren't in `by_type`. `None` means they aren't cached.
+    pub default: Option<Duration>,
+    /// TTLs of key types, e.g. `content` or `hgmanifest`. `None` means they aren't cached.
+    pub by_type: HashMap<String, Option<Duration>>,
+}
+
+impl CacheTtls {
+    /// The TTL of the blob under `key`, or `None` if it isn't cached
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        match self.by_type.get(key_type(key)) {
+            Some(ttl) => *ttl,
+            None => self.default,
+        }
+    }
+}
+
+/// The type of `key`: its first component, or its second one if the first is a repo prefix
+fn key_type(key: &str) -> &str {
+    let mut components = key.split('.');
+    let first = components.next().unwrap_or("");
+    let is_repo_prefix = first.len() > "repo".len()
+        && first.starts_with("repo")
+        && first["repo".len()..].bytes().all(|b| b.is_ascii_digit());
+    if is_repo_prefix {
+        components.next().unwrap_or("")
+    } else {
+        first
+    }
+}
+
+/// Prefix `value` with the time it expires at, a big-endian u64 of milliseconds since the epoch,
+/// for caches that can't expire entries themselves
+pub(crate) fn encode_expiring(value: BlobstoreBytes, ttl: Duration) -> Bytes {
+    let mut out = vec![0; 8];
+    BigEndian::write_u64(&mut out, now_millis() + duration_millis(ttl));
+    out.extend_from_slice(value.as_bytes());
+    Bytes::from(out)
+}
+
+/// The value of an entry written by `encode_expiring`, or `None` if it has expired
+pub(crate) fn decode_expiring(entry: Bytes) -> Option<BlobstoreBytes> {
+    if entry.len() < 8 || BigEndian::read_u64(&entry[..8]) <= now_millis() {
+        return None;
+    }
+    Some(BlobstoreBytes::from_bytes(entry.slice_from(8)))
+}
+
+fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    duration_millis(now)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::hashmap;
+
+    #[test]
+    fn test_key_type() {
+        assert_eq!(key_type("repo0000.hgmanifest.sha1.abcd"), "hgmanifest");
+        assert_eq!(key_type("content.blake2.abcd"), "content");
+        assert_eq!(key_type("repository.x"), "repository");
+        assert_eq!(key_type(""), "");
+    }
+
+    #[test]
+    fn test_ttl_by_type() {
+        let ttls = CacheTtls {
+            default: Some(Duration::from_secs(60)),
+            by_type: hashmap! {
+                "content".to_string() => None,
+                "hgmanifest".to_string() => Some(Duration::from_secs(3600)),
+            },
+        };
+        assert_eq!(
+            ttls.ttl("repo0000.hgmanifest.sha1.abcd"),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(ttls.ttl("repo0000.content.blake2.abcd"), None);
+        assert_eq!(
+            ttls.ttl("repo0000.hgfilenode.sha1.abcd"),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn test_expiring() {
+        let value = BlobstoreBytes::from_bytes("tree");
+        let entry = encode_expiring(value.clone(), Duration::from_secs(60));
+        assert_eq!(decode_expiring(entry), Some(value.clone()));
+
+        let entry = encode_expiring(value, Duration::from_secs(0));
+        assert_eq!(decode_expiring(entry), None);
+        assert_eq!(decode_expiring(Bytes::from("short")), None);
+    }
+}
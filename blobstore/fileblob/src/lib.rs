@@ -4,27 +4,45 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+//! Blobs stored as files, so that repos can be used locally without a remote blobstore.
+//!
+//! Each blob is a file named after its key, in a directory picked by the hash of the key so that
+//! no directory gets too many files: `<base>/<2 hex digits>/<2 hex digits>/blob-<key>`. Blobs are
+//! written to a temporary file that is then renamed, so that readers never see half a blob. The
+//! blobs that were stored directly under `<base>` before are still read. Streamed gets read
+//! blobs in chunks rather than whole.
+
 #![deny(warnings)]
 
 #[macro_use]
 extern crate failure_ext as failure;
 
-use std::fs::{create_dir_all, File};
+use std::fs::{create_dir_all, remove_file, rename, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::failure::{Error, Result};
+use bytes::Bytes;
 use futures::future::{poll_fn, Future};
-use futures::Async;
+use futures::{stream, Async};
 use url::percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
 
-use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use blobstore::Blobstore;
 use context::CoreContext;
+use mononoke_types::hash::Context;
 use mononoke_types::BlobstoreBytes;
 
 const PREFIX: &str = "blob";
+const TEMP_PREFIX: &str = ".tmp";
+/// Most bytes in a chunk of a streamed get
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Makes the names of the temporary files of the process unique
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Debug, Clone)]
 pub struct Fileblob {
@@ -50,37 +68,129 @@ impl Fileblob {
         Self::open(base)
     }
 
-    fn path(&self, key: &String) -> PathBuf {
+    fn file_name(key: &String) -> String {
         let key = percent_encode(key.as_bytes(), DEFAULT_ENCODE_SET);
-        self.base.join(format!("{}-{}", PREFIX, key))
+        format!("{}-{}", PREFIX, key)
+    }
+
+    fn path(&self, key: &String) -> PathBuf {
+        let mut context = Context::new(b"fileblob");
+        context.update(key.as_bytes());
+        let hash = context.finish().to_hex();
+        let hash = hash.as_str();
+        self.base
+            .join(&hash[0..2])
+            .join(&hash[2..4])
+            .join(Self::file_name(key))
+    }
+
+    /// Where the blob of `key` was stored before blobs were spread over directories
+    fn legacy_path(&self, key: &String) -> PathBuf {
+        self.base.join(Self::file_name(key))
     }
 }
 
+fn open_file(path: &Path) -> io::Result<Option<File>> {
+    match File::open(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+        Ok(f) => Ok(Some(f)),
+    }
+}
+
+fn read_file(path: &Path) -> io::Result<Option<BlobstoreBytes>> {
+    match open_file(path)? {
+        None => Ok(None),
+        Some(mut f) => {
+            let mut v = Vec::new();
+            f.read_to_end(&mut v)?;
+            Ok(Some(BlobstoreBytes::from_bytes(v)))
+        }
+    }
+}
+
+/// The content of `file` in chunks of up to `READ_CHUNK_SIZE` bytes
+fn read_chunks(mut file: File) -> BoxStream<Bytes, Error> {
+    stream::poll_fn(move || {
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        let len = loop {
+            match file.read(&mut chunk) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => break res?,
+            }
+        };
+        if len == 0 {
+            return Ok(Async::Ready(None));
+        }
+        chunk.truncate(len);
+        Ok(Async::Ready(Some(Bytes::from(chunk))))
+    })
+    .boxify()
+}
+
 impl Blobstore for Fileblob {
     fn get(&self, _ctx: CoreContext, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
         let p = self.path(&key);
+        let legacy_p = self.legacy_path(&key);
 
         poll_fn(move || {
-            let mut v = Vec::new();
-            let ret = match File::open(&p) {
-                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
-                Err(e) => return Err(e),
-                Ok(mut f) => {
-                    f.read_to_end(&mut v)?;
-                    Some(BlobstoreBytes::from_bytes(v))
-                }
+            let ret = match read_file(&p)? {
+                Some(value) => Some(value),
+                None => read_file(&legacy_p)?,
             };
             Ok(Async::Ready(ret))
-        }).from_err()
-            .boxify()
+        })
+        .from_err()
+        .boxify()
     }
 
     fn put(&self, _ctx: CoreContext, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
         let p = self.path(&key);
 
         poll_fn::<_, Error, _>(move || {
-            File::create(&p)?.write_all(value.as_bytes().as_ref())?;
+            let dir = p.parent().expect("blob paths have a parent");
+            create_dir_all(dir)?;
+            let temp = dir.join(format!(
+                "{}-{}-{}",
+                TEMP_PREFIX,
+                process::id(),
+                TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let written = File::create(&temp)
+                .and_then(|mut f| f.write_all(value.as_bytes().as_ref()))
+                .and_then(|()| rename(&temp, &p));
+            if let Err(e) = written {
+                let _ = remove_file(&temp);
+                return Err(e.into());
+            }
             Ok(Async::Ready(()))
-        }).boxify()
+        })
+        .boxify()
+    }
+
+    fn get_stream(
+        &self,
+        _ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<Option<BoxStream<Bytes, Error>>, Error> {
+        let p = self.path(&key);
+        let legacy_p = self.legacy_path(&key);
+
+        poll_fn::<_, Error, _>(move || {
+            // The blob stays readable from the open file even if it's replaced meanwhile
+            let file = match open_file(&p)? {
+                Some(file) => Some(file),
+                None => open_file(&legacy_p)?,
+            };
+            Ok(Async::Ready(file.map(read_chunks)))
+        })
+        .boxify()
+    }
+
+    fn is_present(&self, _ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
+        let p = self.path(&key);
+        let legacy_p = self.legacy_path(&key);
+
+        poll_fn::<_, Error, _>(move || Ok(Async::Ready(p.is_file() || legacy_p.is_file()))).boxify()
     }
 }
@@ -8,16 +8,17 @@ use rand::{thread_rng, Rng};
 use std::collections::HashMap;
 use std::env;
 use std::fmt;
+use std::io;
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use cloned::cloned;
-use failure::err_msg;
 use failure_ext::{Error, Fail};
-use futures::future::{self, Future, Loop};
+use futures::future::{self, Either, Future, Loop, Shared};
+use futures::sync::oneshot;
 use futures_ext::{BoxFuture, FutureExt};
 use futures_stats::Timed;
 use lazy_static::lazy_static;
@@ -26,10 +27,12 @@ use time_ext::DurationExt;
 use tokio::executor::spawn;
 use tokio::prelude::FutureExt as TokioFutureExt;
 use tokio::timer::timeout::Error as TimeoutError;
+use tokio::timer::Delay;
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, HedgeDelay};
 use context::CoreContext;
 use metaconfig_types::BlobstoreId;
+use mononoke_errors::{Categorize, ErrorCategory};
 use mononoke_types::BlobstoreBytes;
 
 const SLOW_REQUEST_THRESHOLD: Duration = Duration::from_secs(5);
@@ -62,6 +65,29 @@ pub enum ErrorKind {
     SomeFailedOthersNone(Arc<HashMap<BlobstoreId, Error>>),
     #[fail(display = "All blobstores failed: {:?}", _0)]
     AllFailed(Arc<HashMap<BlobstoreId, Error>>),
+    #[fail(
+        display = "Only {} blobstores stored the blob, {} needed: {:?}",
+        _0, _1, _2
+    )]
+    QuorumNotReached(usize, usize, Arc<HashMap<BlobstoreId, Error>>),
+    #[fail(
+        display = "No blob of {} is had by more blobstores than the others",
+        _0
+    )]
+    NoMajority(String),
+}
+
+impl Categorize for ErrorKind {
+    fn category(&self) -> Option<ErrorCategory> {
+        match self {
+            // Blobstores fail on their own, e.g. while one restarts, so the operation as a whole
+            // may succeed when it's tried again
+            ErrorKind::SomeFailedOthersNone(_)
+            | ErrorKind::AllFailed(_)
+            | ErrorKind::QuorumNotReached(..) => Some(ErrorCategory::Transient),
+            ErrorKind::NoMajority(_) => Some(ErrorCategory::Corrupt),
+        }
+    }
 }
 
 /// This handler is called on each successful put to underlying blobstore,
@@ -78,87 +104,161 @@ pub trait MultiplexedBlobstorePutHandler: Send + Sync {
 
 pub struct MultiplexedBlobstoreBase {
     blobstores: Arc<[(BlobstoreId, Arc<dyn Blobstore>)]>,
+    write_quorum: usize,
     handler: Arc<dyn MultiplexedBlobstorePutHandler>,
     scuba_logger: Option<Arc<ScubaClient>>,
+    hedge_delay: Option<Arc<HedgeDelay>>,
 }
 
 impl MultiplexedBlobstoreBase {
+    /// Puts succeed once `write_quorum` of `blobstores` stored the blob and its put was handled
+    /// by `handler`, which must be between 1 and the number of blobstores.
     pub fn new(
         blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
+        write_quorum: usize,
         handler: Arc<dyn MultiplexedBlobstorePutHandler>,
         scuba_logger: Option<Arc<ScubaClient>>,
+        hedge_delay: Option<HedgeDelay>,
     ) -> Self {
         Self {
             blobstores: blobstores.into(),
+            write_quorum,
             handler,
             scuba_logger,
+            hedge_delay: hedge_delay.map(Arc::new),
         }
     }
 }
 
+fn is_found<E>(result: &Result<Option<BlobstoreBytes>, E>) -> bool {
+    match result {
+        Ok(Some(_)) => true,
+        _ => false,
+    }
+}
+
+/// Resolves to true once a hedged read should be sent: either the hedge delay has passed, or the
+/// primary read finished without finding the blob. Resolves to false if the primary found it.
+fn hedge_gate<E>(
+    hedge_delay: Arc<HedgeDelay>,
+    primary_done: Shared<oneshot::Receiver<()>>,
+) -> impl Future<Item = bool, Error = E> {
+    let timer = match hedge_delay.delay() {
+        Some(delay) => Delay::new(Instant::now() + delay)
+            .then(|_| Ok::<_, ()>(()))
+            .left_future(),
+        None => future::empty().right_future(),
+    };
+    timer.select2(primary_done).then(move |result| match result {
+        Err(Either::B(_)) => Ok(false),
+        _ => {
+            hedge_delay.on_hedge_started();
+            Ok(true)
+        }
+    })
+}
+
 fn remap_timeout_error(err: TimeoutError<Error>) -> Error {
     match err.into_inner() {
         Some(err) => err,
-        None => err_msg("blobstore operation timeout"),
+        None => io::Error::new(io::ErrorKind::TimedOut, "blobstore operation timeout").into(),
     }
 }
 
 impl Blobstore for MultiplexedBlobstoreBase {
     fn get(&self, ctx: CoreContext, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
         let should_log = thread_rng().gen::<f32>() > SAMPLING_THRESHOLD;
+        let started = Instant::now();
+        // With hedging, one randomly chosen blobstore is asked first, and the others are only
+        // asked if it is slow, fails or doesn't have the blob.
+        let (primary, mut primary_done, primary_done_recv) = match self.hedge_delay {
+            Some(_) => {
+                let (send, recv) = oneshot::channel();
+                let primary = thread_rng().gen_range(0, self.blobstores.len());
+                (Some(primary), Some(send), Some(recv.shared()))
+            }
+            None => (None, None, None),
+        };
         let requests: Vec<_> = self
             .blobstores
             .iter()
-            .map(|&(blobstore_id, ref blobstore)| {
-                blobstore
-                    .get(ctx.clone(), key.clone())
-                    .timeout(REQUEST_TIMEOUT)
-                    .map_err({
-                        cloned!(blobstore_id);
-                        move |error| (blobstore_id, remap_timeout_error(error))
-                    })
-                    .timed({
-                        let session = ctx.session().clone();
-                        cloned!(self.scuba_logger);
-                        move |stats, result| {
-                            if !should_log {
-                                return future::ok(());
+            .enumerate()
+            .map(|(index, &(blobstore_id, ref blobstore))| {
+                let request = {
+                    cloned!(ctx, key, blobstore);
+                    future::lazy(move || blobstore.get(ctx, key))
+                }
+                .timeout(REQUEST_TIMEOUT)
+                .map_err({
+                    cloned!(blobstore_id);
+                    move |error| (blobstore_id, remap_timeout_error(error))
+                })
+                .timed({
+                    let session = ctx.session().clone();
+                    cloned!(self.scuba_logger);
+                    move |stats, result| {
+                        if !should_log {
+                            return future::ok(());
+                        }
+
+                        if let (Ok(Some(data)), Some(ref scuba_logger)) = (result, scuba_logger) {
+                            let mut sample = ScubaSample::new();
+                            sample
+                                .add("operation", "get")
+                                .add("blobstore_id", blobstore_id)
+                                .add("size", data.len())
+                                .add(
+                                    "completion_time",
+                                    stats.completion_time.as_micros_unchecked(),
+                                );
+                            for (key, value) in TW_STATS.iter() {
+                                sample.add(*key, value.clone());
+                            }
+                            // logging session uuid only for slow requests
+                            if stats.completion_time >= SLOW_REQUEST_THRESHOLD {
+                                sample.add("session", session.to_string());
                             }
 
-                            if let (Ok(Some(data)), Some(ref scuba_logger)) = (result, scuba_logger)
-                            {
-                                let mut sample = ScubaSample::new();
-                                sample
-                                    .add("operation", "get")
-                                    .add("blobstore_id", blobstore_id)
-                                    .add("size", data.len())
-                                    .add(
-                                        "completion_time",
-                                        stats.completion_time.as_micros_unchecked(),
-                                    );
-                                for (key, value) in TW_STATS.iter() {
-                                    sample.add(*key, value.clone());
-                                }
-                                // logging session uuid only for slow requests
-                                if stats.completion_time >= SLOW_REQUEST_THRESHOLD {
-                                    sample.add("session", session.to_string());
+                            match result {
+                                Ok(Some(data)) => {
+                                    sample.add("size", data.len());
                                 }
-
-                                match result {
-                                    Ok(Some(data)) => {
-                                        sample.add("size", data.len());
-                                    }
-                                    Err((_, error)) => {
-                                        sample.add("error", error.to_string());
-                                    }
-                                    Ok(None) => {}
+                                Err((_, error)) => {
+                                    sample.add("error", error.to_string());
                                 }
-                                scuba_logger.log(&sample);
+                                Ok(None) => {}
                             }
-
-                            future::ok(())
+                            scuba_logger.log(&sample);
                         }
-                    })
+
+                        future::ok(())
+                    }
+                });
+
+                let is_hedge = primary.map_or(false, |primary| primary != index);
+                let done = if is_hedge { None } else { primary_done.take() };
+                let request = request.then(move |result| {
+                    // Let the hedges go as soon as the primary is known not to have the blob.
+                    // Dropping `done` without sending tells them they aren't needed.
+                    if let (Some(done), false) = (done, is_found(&result)) {
+                        let _ = done.send(());
+                    }
+                    result.map(move |value| (value, is_hedge))
+                });
+
+                let gate = match (is_hedge, &primary_done_recv, &self.hedge_delay) {
+                    (true, Some(primary_done), Some(hedge_delay)) => {
+                        hedge_gate(hedge_delay.clone(), primary_done.clone()).left_future()
+                    }
+                    _ => future::ok(true).right_future(),
+                };
+                gate.and_then(move |start| {
+                    if start {
+                        request.left_future()
+                    } else {
+                        future::ok((None, false)).right_future()
+                    }
+                })
             })
             .collect();
         let state = (
@@ -166,11 +266,19 @@ impl Blobstore for MultiplexedBlobstoreBase {
             HashMap::<BlobstoreId, Error>::new(), // previous errors
         );
         let blobstores_count = self.blobstores.len();
+        let hedge_delay = self.hedge_delay.clone();
         future::loop_fn(state, move |(requests, mut errors)| {
             future::select_all(requests).then({
+                cloned!(hedge_delay);
                 move |result| {
                     let requests = match result {
-                        Ok((value @ Some(_), _, requests)) => {
+                        Ok(((value @ Some(_), is_hedge), _, requests)) => {
+                            if let Some(hedge_delay) = hedge_delay {
+                                hedge_delay.record(started.elapsed());
+                                if is_hedge {
+                                    hedge_delay.on_hedge_won();
+                                }
+                            }
                             if should_log {
                                 // Allow the other requests to complete so that we can record some
                                 // metrics for the blobstore.
@@ -182,7 +290,7 @@ impl Blobstore for MultiplexedBlobstoreBase {
                             }
                             return future::ok(Loop::Break(value));
                         }
-                        Ok((None, _, requests)) => requests,
+                        Ok(((None, _), _, requests)) => requests,
                         Err(((blobstore_id, error), _, requests)) => {
                             errors.insert(blobstore_id, error);
                             requests
@@ -213,62 +321,99 @@ impl Blobstore for MultiplexedBlobstoreBase {
         let write_order = Arc::new(AtomicUsize::new(0));
         let should_log = thread_rng().gen::<f32>() > SAMPLING_THRESHOLD;
 
-        let requests = self.blobstores.iter().map(|(blobstore_id, blobstore)| {
-            blobstore
-                .put(ctx.clone(), key.clone(), value.clone())
-                .timeout(REQUEST_TIMEOUT)
-                .map_err({
-                    move |error| remap_timeout_error(error)
-                })
-                .and_then({
-                    cloned!(ctx, key, blobstore_id, self.handler);
-                    move |_| handler.on_put(ctx, blobstore_id, key)
-                })
-                .timed({
-                    let session = ctx.session().clone();
-                    cloned!(blobstore_id, write_order, size, self.scuba_logger);
-                    move |stats, result| {
-                        if should_log {
-                            if let Some(scuba_logger) = scuba_logger {
-                                let mut sample = ScubaSample::new();
-                                sample
-                                    .add("operation", "put")
-                                    .add("blobstore_id", blobstore_id)
-                                    .add("size", size)
-                                    .add(
-                                        "completion_time",
-                                        stats.completion_time.as_micros_unchecked(),
-                                    );
-                                match result {
-                                    Ok(_) => sample.add(
-                                        "write_order",
-                                        write_order.fetch_add(1, Ordering::SeqCst),
-                                    ),
-                                    Err(error) => sample.add("error", error.to_string()),
-                                };
-                                for (key, value) in TW_STATS.iter() {
-                                    sample.add(*key, value.clone());
-                                }
-                                // logging session uuid only for slow requests
-                                if stats.completion_time >= SLOW_REQUEST_THRESHOLD {
-                                    sample.add("session", session.to_string());
+        let requests: Vec<_> = self
+            .blobstores
+            .iter()
+            .map(|(blobstore_id, blobstore)| {
+                blobstore
+                    .put(ctx.clone(), key.clone(), value.clone())
+                    .timeout(REQUEST_TIMEOUT)
+                    .map_err({ move |error| remap_timeout_error(error) })
+                    .and_then({
+                        cloned!(ctx, key, blobstore_id, self.handler);
+                        move |_| handler.on_put(ctx, blobstore_id, key)
+                    })
+                    .timed({
+                        let session = ctx.session().clone();
+                        cloned!(blobstore_id, write_order, size, self.scuba_logger);
+                        move |stats, result| {
+                            if should_log {
+                                if let Some(scuba_logger) = scuba_logger {
+                                    let mut sample = ScubaSample::new();
+                                    sample
+                                        .add("operation", "put")
+                                        .add("blobstore_id", blobstore_id)
+                                        .add("size", size)
+                                        .add(
+                                            "completion_time",
+                                            stats.completion_time.as_micros_unchecked(),
+                                        );
+                                    match result {
+                                        Ok(_) => sample.add(
+                                            "write_order",
+                                            write_order.fetch_add(1, Ordering::SeqCst),
+                                        ),
+                                        Err(error) => sample.add("error", error.to_string()),
+                                    };
+                                    for (key, value) in TW_STATS.iter() {
+                                        sample.add(*key, value.clone());
+                                    }
+                                    // logging session uuid only for slow requests
+                                    if stats.completion_time >= SLOW_REQUEST_THRESHOLD {
+                                        sample.add("session", session.to_string());
+                                    }
+                                    scuba_logger.log(&sample);
                                 }
-                                scuba_logger.log(&sample);
                             }
+                            future::ok(())
                         }
-                        future::ok(())
+                    })
+                    .map_err({
+                        cloned!(blobstore_id);
+                        move |error| (blobstore_id, error)
+                    })
+            })
+            .collect();
+
+        let state = (
+            requests,                             // pending requests
+            0,                                    // blobstores that stored the blob
+            HashMap::<BlobstoreId, Error>::new(), // previous errors
+        );
+        let write_quorum = self.write_quorum;
+        let blobstores_count = self.blobstores.len();
+        future::loop_fn(state, move |(requests, stored, mut errors)| {
+            future::select_all(requests).then(move |result| {
+                let (stored, requests) = match result {
+                    Ok((_, _, requests)) => (stored + 1, requests),
+                    Err(((blobstore_id, error), _, requests)) => {
+                        errors.insert(blobstore_id, error);
+                        (stored, requests)
                     }
-                })
-        });
+                };
+                let done = stored >= write_quorum || stored + requests.len() < write_quorum;
+                if !done {
+                    return future::ok(Loop::Continue((requests, stored, errors)));
+                }
 
-        future::select_ok(requests)
-            .map(|(_, requests)| {
+                // The puts that are still pending carry on in the background. The blobstores
+                // that miss the blob are healed from the sync queue.
                 let requests_fut =
                     future::join_all(requests.into_iter().map(|request| request.then(|_| Ok(()))))
                         .map(|_| ());
                 spawn(requests_fut);
+
+                if stored >= write_quorum {
+                    future::ok(Loop::Break(()))
+                } else if errors.len() == blobstores_count {
+                    future::err(ErrorKind::AllFailed(errors.into()).into())
+                } else {
+                    let error = ErrorKind::QuorumNotReached(stored, write_quorum, errors.into());
+                    future::err(error.into())
+                }
             })
-            .boxify()
+        })
+        .boxify()
     }
 
     fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
@@ -8,8 +8,10 @@
 
 pub mod base;
 pub mod queue;
+pub mod scrub;
 
 pub use crate::queue::MultiplexedBlobstore;
+pub use crate::scrub::{ScrubAction, ScrubBlobstore, ScrubCounts};
 
 #[cfg(test)]
 mod test;
@@ -13,7 +13,7 @@ use futures::future::{self, Future};
 use futures_ext::{BoxFuture, FutureExt};
 use scuba::ScubaClient;
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, HedgeDelay};
 use blobstore_sync_queue::{BlobstoreSyncQueue, BlobstoreSyncQueueEntry};
 use context::CoreContext;
 use metaconfig_types::BlobstoreId;
@@ -32,8 +32,10 @@ impl MultiplexedBlobstore {
     pub fn new(
         repo_id: RepositoryId,
         blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
+        write_quorum: usize,
         queue: Arc<dyn BlobstoreSyncQueue>,
         scuba_logger: Option<Arc<ScubaClient>>,
+        hedge_delay: Option<HedgeDelay>,
     ) -> Self {
         let put_handler = Arc::new(QueueBlobstorePutHandler {
             repo_id,
@@ -43,8 +45,10 @@ impl MultiplexedBlobstore {
             repo_id,
             blobstore: Arc::new(MultiplexedBlobstoreBase::new(
                 blobstores,
+                write_quorum,
                 put_handler,
                 scuba_logger,
+                hedge_delay,
             )),
             queue,
         }
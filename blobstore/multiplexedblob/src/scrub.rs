@@ -0,0 +1,245 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use cloned::cloned;
+use failure_ext::Error;
+use futures::future::{self, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use scuba::ScubaClient;
+use slog::{info, warn};
+
+use blobstore::Blobstore;
+use blobstore_sync_queue::BlobstoreSyncQueue;
+use context::CoreContext;
+use metaconfig_types::BlobstoreId;
+use mononoke_types::hash::{Blake2, Context};
+use mononoke_types::{BlobstoreBytes, RepositoryId};
+
+use crate::base::ErrorKind;
+use crate::queue::MultiplexedBlobstore;
+
+/// What a scrub does with the blobstores that have a different blob than the others
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScrubAction {
+    ReportOnly,
+    /// Overwrite their blob with the one that most blobstores have
+    Repair,
+}
+
+/// Counts of the gets of a scrub
+#[derive(Debug, Default)]
+pub struct ScrubCounts {
+    checked: AtomicUsize,
+    divergent: AtomicUsize,
+    repaired: AtomicUsize,
+    repair_failed: AtomicUsize,
+}
+
+impl ScrubCounts {
+    /// Keys that were compared across blobstores
+    pub fn checked(&self) -> usize {
+        self.checked.load(Ordering::SeqCst)
+    }
+
+    /// Keys that some blobstores had a different blob for, or didn't have
+    pub fn divergent(&self) -> usize {
+        self.divergent.load(Ordering::SeqCst)
+    }
+
+    /// Divergent keys that all blobstores now have the same blob for
+    pub fn repaired(&self) -> usize {
+        self.repaired.load(Ordering::SeqCst)
+    }
+
+    /// Divergent keys that couldn't be repaired
+    pub fn repair_failed(&self) -> usize {
+        self.repair_failed.load(Ordering::SeqCst)
+    }
+}
+
+/// A multiplexed blobstore whose gets read the blob from all blobstores and compare them, to
+/// find the blobstores that silently lost or corrupted it. The blob that most blobstores have
+/// is returned. Gets fail if no blob is had by more blobstores than any other. Puts and
+/// presence checks are those of the multiplexed blobstore.
+#[derive(Clone)]
+pub struct ScrubBlobstore {
+    inner: MultiplexedBlobstore,
+    blobstores: Arc<[(BlobstoreId, Arc<dyn Blobstore>)]>,
+    action: ScrubAction,
+    counts: Arc<ScrubCounts>,
+}
+
+impl ScrubBlobstore {
+    pub fn new(
+        repo_id: RepositoryId,
+        blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
+        write_quorum: usize,
+        queue: Arc<dyn BlobstoreSyncQueue>,
+        scuba_logger: Option<Arc<ScubaClient>>,
+        action: ScrubAction,
+    ) -> Self {
+        let inner = MultiplexedBlobstore::new(
+            repo_id,
+            blobstores.clone(),
+            write_quorum,
+            queue,
+            scuba_logger,
+            None,
+        );
+        Self {
+            inner,
+            blobstores: blobstores.into(),
+            action,
+            counts: Arc::new(ScrubCounts::default()),
+        }
+    }
+
+    pub fn counts(&self) -> Arc<ScrubCounts> {
+        self.counts.clone()
+    }
+}
+
+fn content_hash(value: &BlobstoreBytes) -> Blake2 {
+    let mut context = Context::new(b"scrub");
+    context.update(value.as_bytes());
+    context.finish()
+}
+
+impl Blobstore for ScrubBlobstore {
+    fn get(&self, ctx: CoreContext, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+        let gets = self.blobstores.iter().map(|(blobstore_id, blobstore)| {
+            cloned!(blobstore_id);
+            blobstore
+                .get(ctx.clone(), key.clone())
+                .then(move |result| Ok::<_, Error>((blobstore_id, result)))
+        });
+        let blobstores = self.blobstores.clone();
+        cloned!(self.inner, self.action, self.counts);
+
+        future::join_all(gets)
+            .and_then(move |results| {
+                let mut errors = HashMap::new();
+                let mut missing = vec![];
+                // Blobs by their hash, with the blobstores that have them
+                let mut values: HashMap<Blake2, (BlobstoreBytes, Vec<BlobstoreId>)> =
+                    HashMap::new();
+                for (blobstore_id, result) in results {
+                    match result {
+                        Ok(Some(value)) => {
+                            values
+                                .entry(content_hash(&value))
+                                .or_insert_with(|| (value, vec![]))
+                                .1
+                                .push(blobstore_id);
+                        }
+                        Ok(None) => missing.push(blobstore_id),
+                        Err(error) => {
+                            errors.insert(blobstore_id, error);
+                        }
+                    }
+                }
+
+                if errors.len() == blobstores.len() {
+                    return future::err(ErrorKind::AllFailed(errors.into()).into()).boxify();
+                }
+                if values.is_empty() {
+                    if errors.is_empty() {
+                        return future::ok(None).boxify();
+                    }
+                    // Tell a blob that was never stored from one that only the failed
+                    // blobstores have
+                    return inner.get(ctx, key);
+                }
+                counts.checked.fetch_add(1, Ordering::SeqCst);
+
+                let mut values: Vec<_> = values.into_iter().map(|(_, value)| value).collect();
+                values.sort_by_key(|(_, blobstore_ids)| blobstore_ids.len());
+                let (value, agreeing) = values.pop().expect("values is not empty");
+                let mut divergent = missing.clone();
+                for (_, blobstore_ids) in &values {
+                    divergent.extend(blobstore_ids);
+                }
+                if divergent.is_empty() {
+                    return future::ok(Some(value)).boxify();
+                }
+
+                counts.divergent.fetch_add(1, Ordering::SeqCst);
+                let different: Vec<_> = values
+                    .iter()
+                    .flat_map(|(_, blobstore_ids)| blobstore_ids.iter())
+                    .collect();
+                warn!(
+                    ctx.logger(),
+                    "scrub: blobstores {:?} have the blob of {}, {:?} don't have it, {:?} have a different one",
+                    agreeing,
+                    key,
+                    missing,
+                    different
+                );
+                if values
+                    .iter()
+                    .any(|(_, blobstore_ids)| blobstore_ids.len() == agreeing.len())
+                {
+                    return future::err(ErrorKind::NoMajority(key).into()).boxify();
+                }
+                if action == ScrubAction::ReportOnly {
+                    return future::ok(Some(value)).boxify();
+                }
+
+                let repairs = blobstores
+                    .iter()
+                    .filter(|(blobstore_id, _)| divergent.contains(blobstore_id))
+                    .map(|(_, blobstore)| blobstore.put(ctx.clone(), key.clone(), value.clone()))
+                    .collect::<Vec<_>>();
+                future::join_all(repairs)
+                    .then(move |result| {
+                        match result {
+                            Ok(_) => {
+                                counts.repaired.fetch_add(1, Ordering::SeqCst);
+                                info!(
+                                    ctx.logger(),
+                                    "scrub: repaired the blob of {} in {:?}", key, divergent
+                                );
+                            }
+                            Err(error) => {
+                                counts.repair_failed.fetch_add(1, Ordering::SeqCst);
+                                warn!(
+                                    ctx.logger(),
+                                    "scrub: repairing the blob of {} failed: {}", key, error
+                                );
+                            }
+                        }
+                        Ok(Some(value))
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }
+
+    fn put(&self, ctx: CoreContext, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
+        self.inner.put(ctx, key, value)
+    }
+
+    fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
+        self.inner.is_present(ctx, key)
+    }
+}
+
+impl fmt::Debug for ScrubBlobstore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScrubBlobstore")
+            .field("inner", &self.inner)
+            .field("action", &self.action)
+            .finish()
+    }
+}
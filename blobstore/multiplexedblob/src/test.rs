@@ -8,6 +8,7 @@ use std::borrow::BorrowMut;
 use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_unit;
 use failure_ext::{err_msg, Error};
@@ -16,7 +17,7 @@ use futures::sync::oneshot;
 use futures::Async;
 use futures_ext::{BoxFuture, FutureExt};
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, HedgeDelay};
 use blobstore_sync_queue::{BlobstoreSyncQueue, SqlBlobstoreSyncQueue, SqlConstructors};
 use context::CoreContext;
 use metaconfig_types::BlobstoreId;
@@ -24,6 +25,7 @@ use mononoke_types::{BlobstoreBytes, RepositoryId};
 
 use crate::base::{MultiplexedBlobstoreBase, MultiplexedBlobstorePutHandler};
 use crate::queue::MultiplexedBlobstore;
+use crate::scrub::{ScrubAction, ScrubBlobstore};
 
 fn with<T, F, V>(value: &Arc<Mutex<T>>, scope: F) -> V
 where
@@ -133,8 +135,10 @@ fn base() {
                 (BlobstoreId::new(0), bs0.clone()),
                 (BlobstoreId::new(1), bs1.clone()),
             ],
+            1,
             log.clone(),
             None,
+            None,
         );
         let ctx = CoreContext::test_mock();
 
@@ -260,6 +264,124 @@ fn base() {
     });
 }
 
+#[test]
+fn write_quorum() {
+    async_unit::tokio_unit_test(|| {
+        let bs0 = Arc::new(TickBlobstore::new());
+        let bs1 = Arc::new(TickBlobstore::new());
+        let bs2 = Arc::new(TickBlobstore::new());
+        let log = Arc::new(LogHandler::new());
+        let bs = MultiplexedBlobstoreBase::new(
+            vec![
+                (BlobstoreId::new(0), bs0.clone()),
+                (BlobstoreId::new(1), bs1.clone()),
+                (BlobstoreId::new(2), bs2.clone()),
+            ],
+            2,
+            log.clone(),
+            None,
+            None,
+        );
+        let ctx = CoreContext::test_mock();
+
+        // succeed once two blobstores stored the blob, whatever the third one does
+        {
+            let v0 = make_value("v0");
+            let k0 = String::from("k0");
+
+            let mut put_fut = bs.put(ctx.clone(), k0.clone(), v0.clone());
+            assert_eq!(put_fut.poll().unwrap(), Async::NotReady);
+            bs0.tick(None);
+            assert_eq!(put_fut.poll().unwrap(), Async::NotReady);
+            bs1.tick(Some("bs1 failed"));
+            assert_eq!(put_fut.poll().unwrap(), Async::NotReady);
+            bs2.tick(None);
+            put_fut.wait().unwrap();
+            assert!(with(&log.log, |log| log
+                == &vec![
+                    (BlobstoreId::new(0), k0.clone()),
+                    (BlobstoreId::new(2), k0.clone())
+                ]));
+            assert!(with(&bs1.storage, |s| s.is_empty()));
+
+            log.clear();
+        }
+
+        // fail as soon as the quorum can't be reached anymore
+        {
+            let v1 = make_value("v1");
+            let k1 = String::from("k1");
+
+            let mut put_fut = bs.put(ctx.clone(), k1.clone(), v1.clone());
+            assert_eq!(put_fut.poll().unwrap(), Async::NotReady);
+            bs0.tick(Some("bs0 failed"));
+            assert_eq!(put_fut.poll().unwrap(), Async::NotReady);
+            bs1.tick(Some("bs1 failed"));
+            assert!(put_fut.wait().is_err());
+
+            // the put that was pending still completes in the background
+            bs2.tick(None);
+            while with(&log.log, |log| log.is_empty()) {}
+            assert_eq!(
+                with(&bs2.storage, |s| s.get(&k1).cloned()),
+                Some(v1.clone())
+            );
+        }
+    });
+}
+
+#[test]
+fn hedged_get() {
+    async_unit::tokio_unit_test(|| {
+        let bs0 = Arc::new(TickBlobstore::new());
+        let bs1 = Arc::new(TickBlobstore::new());
+        // No latencies are recorded yet, so hedges are only sent if the primary misses.
+        let hedge_delay = HedgeDelay::new("test".to_string(), 90, Duration::from_millis(1));
+        let bs = MultiplexedBlobstoreBase::new(
+            vec![
+                (BlobstoreId::new(0), bs0.clone()),
+                (BlobstoreId::new(1), bs1.clone()),
+            ],
+            1,
+            Arc::new(LogHandler::new()),
+            None,
+            Some(hedge_delay),
+        );
+        let ctx = CoreContext::test_mock();
+        let pending = || with(&bs0.queue, |q| q.len()) + with(&bs1.queue, |q| q.len());
+
+        // primary has the blob => nobody else is asked
+        {
+            let v0 = make_value("v0");
+            let k0 = String::from("k0");
+            with(&bs0.storage, |s| s.insert(k0.clone(), v0.clone()));
+            with(&bs1.storage, |s| s.insert(k0.clone(), v0.clone()));
+
+            let mut get_fut = bs.get(ctx.clone(), k0);
+            assert_eq!(get_fut.poll().unwrap(), Async::NotReady);
+            assert_eq!(pending(), 1);
+            bs0.tick(None);
+            bs1.tick(None);
+            assert_eq!(get_fut.wait().unwrap(), Some(v0));
+        }
+
+        // primary misses => the other blobstore is asked
+        {
+            let k1 = String::from("k1");
+            let mut get_fut = bs.get(ctx.clone(), k1);
+            assert_eq!(get_fut.poll().unwrap(), Async::NotReady);
+            assert_eq!(pending(), 1);
+            bs0.tick(None);
+            bs1.tick(None);
+            assert_eq!(get_fut.poll().unwrap(), Async::NotReady);
+            assert_eq!(pending(), 1);
+            bs0.tick(None);
+            bs1.tick(None);
+            assert_eq!(get_fut.wait().unwrap(), None);
+        }
+    });
+}
+
 #[test]
 fn multiplexed() {
     async_unit::tokio_unit_test(|| {
@@ -274,8 +396,10 @@ fn multiplexed() {
         let bs = MultiplexedBlobstore::new(
             repoid,
             vec![(bid0, bs0.clone()), (bid1, bs1.clone())],
+            1,
             queue.clone(),
             None,
+            None,
         );
 
         // non-existing key when one blobstore failing
@@ -332,3 +456,88 @@ fn multiplexed() {
         }
     });
 }
+
+#[test]
+fn scrub() {
+    async_unit::tokio_unit_test(|| {
+        let repoid = RepositoryId::new(0);
+        let ctx = CoreContext::test_mock();
+        let queue = Arc::new(SqlBlobstoreSyncQueue::with_sqlite_in_memory().unwrap());
+
+        let bs0 = Arc::new(TickBlobstore::new());
+        let bs1 = Arc::new(TickBlobstore::new());
+        let bs2 = Arc::new(TickBlobstore::new());
+        let bs = ScrubBlobstore::new(
+            repoid,
+            vec![
+                (BlobstoreId::new(0), bs0.clone()),
+                (BlobstoreId::new(1), bs1.clone()),
+                (BlobstoreId::new(2), bs2.clone()),
+            ],
+            1,
+            queue,
+            None,
+            ScrubAction::Repair,
+        );
+        let counts = bs.counts();
+        let set = |bs: &Arc<TickBlobstore>, key: &str, value: &str| {
+            with(&bs.storage, |s| {
+                s.insert(key.to_string(), make_value(value))
+            });
+        };
+
+        // all replicas agree
+        {
+            let k0 = String::from("k0");
+            set(&bs0, &k0, "v0");
+            set(&bs1, &k0, "v0");
+            set(&bs2, &k0, "v0");
+
+            let mut get_fut = bs.get(ctx.clone(), k0.clone());
+            assert_eq!(get_fut.poll().unwrap(), Async::NotReady);
+            bs0.tick(None);
+            bs1.tick(None);
+            bs2.tick(None);
+            assert_eq!(get_fut.wait().unwrap(), Some(make_value("v0")));
+            assert_eq!((counts.checked(), counts.divergent()), (1, 0));
+        }
+
+        // one replica has a corrupt blob, which is repaired
+        {
+            let k1 = String::from("k1");
+            set(&bs0, &k1, "v1");
+            set(&bs1, &k1, "v1");
+            set(&bs2, &k1, "corrupt");
+
+            let mut get_fut = bs.get(ctx.clone(), k1.clone());
+            assert_eq!(get_fut.poll().unwrap(), Async::NotReady);
+            bs0.tick(None);
+            bs1.tick(None);
+            bs2.tick(None);
+            // waiting for the repair
+            assert_eq!(get_fut.poll().unwrap(), Async::NotReady);
+            bs2.tick(None);
+            assert_eq!(get_fut.wait().unwrap(), Some(make_value("v1")));
+            assert_eq!(
+                with(&bs2.storage, |s| s.get(&k1).cloned()),
+                Some(make_value("v1"))
+            );
+            assert_eq!((counts.divergent(), counts.repaired()), (1, 1));
+        }
+
+        // no blob is had by a majority of the replicas
+        {
+            let k2 = String::from("k2");
+            set(&bs0, &k2, "v2");
+            set(&bs1, &k2, "other");
+
+            let mut get_fut = bs.get(ctx.clone(), k2.clone());
+            assert_eq!(get_fut.poll().unwrap(), Async::NotReady);
+            bs0.tick(None);
+            bs1.tick(None);
+            bs2.tick(None);
+            assert!(get_fut.wait().is_err());
+            assert_eq!((counts.divergent(), counts.repaired()), (2, 1));
+        }
+    });
+}
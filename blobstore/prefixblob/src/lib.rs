@@ -4,10 +4,11 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use bytes::Bytes;
 use failure_ext::Error;
 use inlinable_string::InlinableString;
 
-use futures_ext::BoxFuture;
+use futures_ext::{BoxFuture, BoxStream};
 
 use context::CoreContext;
 
@@ -54,6 +55,15 @@ impl<T: Blobstore + Clone> Blobstore for PrefixBlobstore<T> {
         self.blobstore.put(ctx, self.prepend(key), value)
     }
 
+    #[inline]
+    fn get_stream(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<Option<BoxStream<Bytes, Error>>, Error> {
+        self.blobstore.get_stream(ctx, self.prepend(key))
+    }
+
     #[inline]
     fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
         self.blobstore.is_present(ctx, self.prepend(key))
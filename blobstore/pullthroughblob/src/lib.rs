@@ -0,0 +1,683 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A blobstore that fetches the blobs missing from a local store from an upstream Mononoke, and
+//! keeps them. This lets a server near its users act as a cache of a central one.
+//!
+//! Keys that upstream doesn't have either are remembered for a while, so that lookups of blobs
+//! that don't exist yet (e.g. before a push) don't all go upstream. When a changeset is fetched,
+//! its ancestors are fetched in the background too, as clients usually ask for them next.
+//!
+//! Blobs that name their contents (changesets, file contents and hg envelopes) are checked
+//! against their key before they are kept, so a corrupt upstream can't poison the local store.
+//!
+//! Only blobs are pulled through. The SQL stores of the repo (bookmarks, changesets, mappings)
+//! are kept in sync with upstream by polling it, see `PullThroughSync` in blobrepo, which uses
+//! the other methods of `Upstream`.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate stats;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cloned::cloned;
+use failure_ext::{format_err, Error};
+use futures::{future, Future, Stream};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use hyper::client::HttpConnector;
+use hyper::{Body, Chunk, Client, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use serde_derive::Deserialize;
+use stats::Timeseries;
+
+use blobstore::Blobstore;
+use context::CoreContext;
+use mercurial_types::{
+    HgBlobNode, HgChangesetEnvelope, HgChangesetId, HgFileEnvelope, HgManifestEnvelope,
+    HgManifestId,
+};
+use mononoke_types::{
+    BlobstoreBytes, BlobstoreValue, BonsaiChangeset, ChangesetBlob, ChangesetId, ContentBlob,
+    ContentId, FileContents, MononokeId,
+};
+
+define_stats! {
+    prefix = "mononoke.blobstore.pullthrough";
+    local_miss: timeseries("local_miss"; RATE, SUM),
+    upstream_hit: timeseries("upstream.hit"; RATE, SUM),
+    upstream_miss: timeseries("upstream.miss"; RATE, SUM),
+    upstream_err: timeseries("upstream.err"; RATE, SUM),
+    upstream_corrupt: timeseries("upstream.corrupt"; RATE, SUM),
+    negative_hit: timeseries("negative.hit"; RATE, SUM),
+    prefetched: timeseries("prefetched"; RATE, SUM),
+}
+
+/// Most keys remembered as missing upstream. When there are more, the expired ones are
+/// forgotten, and all of them if none expired.
+const MAX_NEGATIVE_ENTRIES: usize = 100000;
+
+/// What upstream's SQL stores know about a changeset
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamChangeset {
+    pub hg_cs_id: HgChangesetId,
+    pub parents: Vec<ChangesetId>,
+    pub gen: u64,
+}
+
+/// Where missing blobs, and the SQL stores of the repo, are fetched from
+pub trait Upstream: fmt::Debug + Send + Sync + 'static {
+    /// Fetch the blob of `key`, a key without repo prefix, or None if upstream doesn't have it
+    fn fetch(&self, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error>;
+
+    /// Fetch all the bookmarks, with the changesets they point to
+    fn bookmarks(&self) -> BoxFuture<Vec<(String, ChangesetId)>, Error>;
+
+    /// Fetch the changeset entry and hg mapping of `cs_id`, or None if upstream doesn't have it
+    fn changeset(&self, cs_id: ChangesetId) -> BoxFuture<Option<UpstreamChangeset>, Error>;
+}
+
+/// A changeset as the `rawchangeset` endpoint serves it
+#[derive(Deserialize)]
+struct RawChangeset {
+    hg: String,
+    parents: Vec<String>,
+    generation: u64,
+}
+
+/// A repo served by an apiserver, whose blobs are fetched from its `rawblob` endpoint
+pub struct ApiserverUpstream {
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// Url of the repo, e.g. `https://mononoke.example.com/fbsource`
+    repo_url: String,
+}
+
+impl ApiserverUpstream {
+    pub fn new(url: &str, repo: &str) -> Result<Self, Error> {
+        let connector = HttpsConnector::new(1)?;
+        Ok(Self {
+            client: Client::builder().build(connector),
+            repo_url: format!("{}/{}", url.trim_end_matches('/'), repo),
+        })
+    }
+}
+
+impl fmt::Debug for ApiserverUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ApiserverUpstream")
+            .field("repo_url", &self.repo_url)
+            .finish()
+    }
+}
+
+impl ApiserverUpstream {
+    /// The body of the response to a GET of `path` in the repo, or None if it isn't found
+    fn get(&self, path: &str) -> BoxFuture<Option<Chunk>, Error> {
+        let url = format!("{}/{}", self.repo_url, path);
+        let request = try_boxfuture!(Request::get(url.as_str()).body(Body::empty()));
+
+        self.client
+            .request(request)
+            .from_err()
+            .and_then(move |response| {
+                let status = response.status();
+                if status == StatusCode::NOT_FOUND {
+                    future::ok(None).left_future()
+                } else if status.is_success() {
+                    response
+                        .into_body()
+                        .concat2()
+                        .from_err()
+                        .map(Some)
+                        .right_future()
+                } else {
+                    future::err(format_err!("upstream {} responded with {}", url, status))
+                        .left_future()
+                }
+            })
+            .boxify()
+    }
+}
+
+impl Upstream for ApiserverUpstream {
+    fn fetch(&self, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+        self.get(&format!("rawblob/{}", key))
+            .map(|body| body.map(|body| BlobstoreBytes::from_bytes(body.into_bytes())))
+            .boxify()
+    }
+
+    fn bookmarks(&self) -> BoxFuture<Vec<(String, ChangesetId)>, Error> {
+        self.get("rawbookmarks")
+            .and_then(|body| -> Result<_, Error> {
+                let body = body.ok_or_else(|| format_err!("upstream has no bookmarks endpoint"))?;
+                let bookmarks: BTreeMap<String, String> = serde_json::from_slice(&body)?;
+                bookmarks
+                    .into_iter()
+                    .map(|(name, cs_id)| ChangesetId::from_str(&cs_id).map(|cs_id| (name, cs_id)))
+                    .collect()
+            })
+            .boxify()
+    }
+
+    fn changeset(&self, cs_id: ChangesetId) -> BoxFuture<Option<UpstreamChangeset>, Error> {
+        self.get(&format!("rawchangeset/{}", cs_id))
+            .and_then(|body| -> Result<_, Error> {
+                let body = match body {
+                    Some(body) => body,
+                    None => return Ok(None),
+                };
+                let raw: RawChangeset = serde_json::from_slice(&body)?;
+                let parents = raw
+                    .parents
+                    .iter()
+                    .map(|parent| ChangesetId::from_str(parent))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok(Some(UpstreamChangeset {
+                    hg_cs_id: raw.hg.parse()?,
+                    parents,
+                    gen: raw.generation,
+                }))
+            })
+            .boxify()
+    }
+}
+
+/// How a `PullThroughBlobstore` uses its upstream
+#[derive(Clone, Debug)]
+pub struct PullThroughOptions {
+    /// How long keys that upstream doesn't have are not asked for again
+    pub negative_cache_ttl: Duration,
+    /// Generations of ancestors fetched in the background when a changeset is fetched
+    pub prefetch_parents_depth: usize,
+}
+
+impl Default for PullThroughOptions {
+    fn default() -> Self {
+        Self {
+            negative_cache_ttl: Duration::from_secs(60),
+            prefetch_parents_depth: 10,
+        }
+    }
+}
+
+/// Keys that were missing upstream, and when they were looked up
+#[derive(Debug)]
+struct NegativeCache {
+    ttl: Duration,
+    misses: Mutex<HashMap<String, Instant>>,
+}
+
+impl NegativeCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            misses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_missing(&self, key: &str) -> bool {
+        let mut misses = self.misses.lock().expect("lock poisoned");
+        match misses.get(key) {
+            Some(looked_up) if looked_up.elapsed() < self.ttl => true,
+            Some(_) => {
+                misses.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&self, key: String) {
+        let mut misses = self.misses.lock().expect("lock poisoned");
+        if misses.len() >= MAX_NEGATIVE_ENTRIES {
+            let ttl = self.ttl;
+            misses.retain(|_, looked_up| looked_up.elapsed() < ttl);
+            if misses.len() >= MAX_NEGATIVE_ENTRIES {
+                misses.clear();
+            }
+        }
+        misses.insert(key, Instant::now());
+    }
+
+    fn remove(&self, key: &str) {
+        self.misses.lock().expect("lock poisoned").remove(key);
+    }
+}
+
+/// A blobstore whose gets fall back to an upstream when the local store misses. Blobs found
+/// upstream are written to the local store. Keys are expected to start with `prefix`, the repo
+/// prefix of the local store, which upstream keys don't have.
+#[derive(Clone, Debug)]
+pub struct PullThroughBlobstore {
+    local: Arc<Blobstore>,
+    upstream: Arc<Upstream>,
+    prefix: String,
+    negative_cache: Arc<NegativeCache>,
+    prefetch_parents_depth: usize,
+}
+
+impl PullThroughBlobstore {
+    pub fn new(
+        local: Arc<Blobstore>,
+        upstream: Arc<Upstream>,
+        prefix: String,
+        options: PullThroughOptions,
+    ) -> Self {
+        Self {
+            local,
+            upstream,
+            prefix,
+            negative_cache: Arc::new(NegativeCache::new(options.negative_cache_ttl)),
+            prefetch_parents_depth: options.prefetch_parents_depth,
+        }
+    }
+
+    /// Fetch `key`, a key without prefix, from upstream and keep it locally
+    fn pull(&self, ctx: CoreContext, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+        if self.negative_cache.is_missing(&key) {
+            STATS::negative_hit.add_value(1);
+            return future::ok(None).boxify();
+        }
+
+        cloned!(self.local, self.negative_cache);
+        let local_key = format!("{}{}", self.prefix, key);
+        self.upstream
+            .fetch(key.clone())
+            .then(move |result| match result {
+                Ok(Some(value)) => {
+                    STATS::upstream_hit.add_value(1);
+                    if let Err(err) = verify(&key, &value) {
+                        STATS::upstream_corrupt.add_value(1);
+                        return future::err(err).right_future();
+                    }
+                    local
+                        .put(ctx, local_key, value.clone())
+                        .map(move |()| Some(value))
+                        .left_future()
+                }
+                Ok(None) => {
+                    STATS::upstream_miss.add_value(1);
+                    negative_cache.insert(key);
+                    future::ok(None).right_future()
+                }
+                Err(err) => {
+                    STATS::upstream_err.add_value(1);
+                    future::err(err).right_future()
+                }
+            })
+            .boxify()
+    }
+
+    /// Fetch the ancestors of the changeset in `value`, stored under `key`, that are missing
+    /// locally, `depth` generations deep. Fetching stops at ancestors that are already there, as
+    /// their own ancestors most likely are too.
+    fn prefetch_parents(
+        &self,
+        ctx: CoreContext,
+        key: &str,
+        value: &BlobstoreBytes,
+        depth: usize,
+    ) -> BoxFuture<(), Error> {
+        if depth == 0 {
+            return future::ok(()).boxify();
+        }
+        let parents = try_boxfuture!(parent_keys(key, value));
+
+        let this = self.clone();
+        let prefetches = parents.into_iter().map(move |parent| {
+            cloned!(ctx, this);
+            this.local
+                .is_present(ctx.clone(), format!("{}{}", this.prefix, parent))
+                .and_then(move |is_present| {
+                    if is_present {
+                        return future::ok(()).left_future();
+                    }
+                    this.pull(ctx.clone(), parent.clone())
+                        .and_then(move |value| match value {
+                            Some(value) => {
+                                STATS::prefetched.add_value(1);
+                                this.prefetch_parents(ctx, &parent, &value, depth - 1)
+                            }
+                            None => future::ok(()).boxify(),
+                        })
+                        .right_future()
+                })
+        });
+        future::join_all(prefetches).map(|_| ()).boxify()
+    }
+}
+
+/// Check that `value` is the blob that `key`, a key without prefix, names. Tombstones are stored
+/// under the id of the content they replace, and the other keys don't name their blobs, so
+/// those are taken as they are.
+fn verify(key: &str, value: &BlobstoreBytes) -> Result<(), Error> {
+    let actual_key = if key.starts_with(&ChangesetId::blobstore_key_prefix()) {
+        ChangesetId::from_data(value.as_bytes()).blobstore_key()
+    } else if key.starts_with(&ContentId::blobstore_key_prefix()) {
+        match FileContents::from_blob(ContentBlob::from(value.clone()))? {
+            FileContents::Bytes(bytes) => ContentId::from_data(bytes).blobstore_key(),
+            FileContents::Tombstone(_) => return Ok(()),
+        }
+    } else if key.starts_with("hgchangeset.") {
+        // Manifests and filenodes may keep their contents in other blobs, so only the node id
+        // of changesets can be computed from the envelope alone
+        let envelope = HgChangesetEnvelope::from_blob(value.clone().into())?;
+        let (p1, p2) = envelope.parents();
+        let node_id = HgBlobNode::new(envelope.contents().clone(), p1, p2).nodeid();
+        if node_id != envelope.node_id() {
+            return Err(format_err!(
+                "upstream blob {} has contents of hg changeset {}",
+                key,
+                node_id
+            ));
+        }
+        HgChangesetId::new(node_id).blobstore_key()
+    } else if key.starts_with("hgmanifest.") {
+        let envelope = HgManifestEnvelope::from_blob(value.clone().into())?;
+        HgManifestId::new(envelope.node_id()).blobstore_key()
+    } else if key.starts_with("hgfilenode.") {
+        let envelope = HgFileEnvelope::from_blob(value.clone().into())?;
+        envelope.node_id().blobstore_key()
+    } else {
+        return Ok(());
+    };
+
+    if actual_key == key {
+        Ok(())
+    } else {
+        Err(format_err!(
+            "upstream blob {} is the blob of {}",
+            key,
+            actual_key
+        ))
+    }
+}
+
+/// The keys of the parents of the changeset stored under `key`, which is a key without prefix.
+/// Empty if `key` isn't the key of a changeset.
+fn parent_keys(key: &str, value: &BlobstoreBytes) -> Result<Vec<String>, Error> {
+    if key.starts_with("changeset.") {
+        let bcs = BonsaiChangeset::from_blob(ChangesetBlob::from(value.clone()))?;
+        Ok(bcs.parents().map(|parent| parent.blobstore_key()).collect())
+    } else if key.starts_with("hgchangeset.") {
+        let envelope = HgChangesetEnvelope::from_blob(value.clone().into())?;
+        let (p1, p2) = envelope.parents();
+        Ok(p1
+            .into_iter()
+            .chain(p2)
+            .map(|parent| HgChangesetId::new(parent).blobstore_key())
+            .collect())
+    } else {
+        Ok(vec![])
+    }
+}
+
+impl Blobstore for PullThroughBlobstore {
+    fn get(&self, ctx: CoreContext, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+        let this = self.clone();
+        self.local
+            .get(ctx.clone(), key.clone())
+            .and_then(move |value| {
+                if value.is_some() {
+                    return future::ok(value).left_future();
+                }
+                STATS::local_miss.add_value(1);
+                let key = match key.get(this.prefix.len()..) {
+                    Some(unprefixed) if key.starts_with(&this.prefix) => unprefixed.to_string(),
+                    _ => {
+                        return future::err(format_err!(
+                            "key {} lacks prefix {}",
+                            key,
+                            this.prefix
+                        ))
+                        .left_future();
+                    }
+                };
+
+                this.pull(ctx.clone(), key.clone())
+                    .map(move |value| {
+                        if let Some(ref value) = value {
+                            let depth = this.prefetch_parents_depth;
+                            let prefetch = this
+                                .prefetch_parents(ctx, &key, value, depth)
+                                .map_err(|_| ());
+                            tokio::spawn(prefetch);
+                        }
+                        value
+                    })
+                    .right_future()
+            })
+            .boxify()
+    }
+
+    fn put(&self, ctx: CoreContext, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
+        if let Some(unprefixed) = key.get(self.prefix.len()..) {
+            self.negative_cache.remove(unprefixed);
+        }
+        self.local.put(ctx, key, value)
+    }
+
+    fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
+        let this = self.clone();
+        self.local
+            .is_present(ctx.clone(), key.clone())
+            .and_then(move |is_present| {
+                if is_present {
+                    future::ok(true).left_future()
+                } else {
+                    this.get(ctx, key)
+                        .map(|value| value.is_some())
+                        .right_future()
+                }
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use memblob::EagerMemblob;
+    use mononoke_types::{BonsaiChangesetMut, DateTime};
+    use tokio::runtime::Runtime;
+
+    /// An upstream that serves the blobs of a memblob and counts the fetches
+    #[derive(Debug)]
+    struct FakeUpstream {
+        blobstore: EagerMemblob,
+        fetches: AtomicUsize,
+    }
+
+    impl FakeUpstream {
+        fn new() -> Self {
+            Self {
+                blobstore: EagerMemblob::new(),
+                fetches: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl Upstream for FakeUpstream {
+        fn fetch(&self, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            self.blobstore.get(CoreContext::test_mock(), key)
+        }
+
+        fn bookmarks(&self) -> BoxFuture<Vec<(String, ChangesetId)>, Error> {
+            future::ok(vec![]).boxify()
+        }
+
+        fn changeset(&self, _cs_id: ChangesetId) -> BoxFuture<Option<UpstreamChangeset>, Error> {
+            future::ok(None).boxify()
+        }
+    }
+
+    fn setup(depth: usize) -> (Arc<EagerMemblob>, Arc<FakeUpstream>, PullThroughBlobstore) {
+        let local = Arc::new(EagerMemblob::new());
+        let upstream = Arc::new(FakeUpstream::new());
+        let blobstore = PullThroughBlobstore::new(
+            local.clone(),
+            upstream.clone(),
+            "repo0001.".to_string(),
+            PullThroughOptions {
+                negative_cache_ttl: Duration::from_secs(3600),
+                prefetch_parents_depth: depth,
+            },
+        );
+        (local, upstream, blobstore)
+    }
+
+    /// Store a changeset upstream and return its id
+    fn put_changeset(
+        upstream: &FakeUpstream,
+        message: &str,
+        parents: Vec<ChangesetId>,
+    ) -> ChangesetId {
+        let bcs = BonsaiChangesetMut {
+            parents,
+            author: "author".to_string(),
+            author_date: DateTime::from_timestamp(0, 0).unwrap(),
+            committer: None,
+            committer_date: None,
+            message: message.to_string(),
+            extra: Default::default(),
+            file_changes: Default::default(),
+        }
+        .freeze()
+        .unwrap();
+        let blob = bcs.into_blob();
+        let id = *blob.id();
+        upstream
+            .blobstore
+            .put(
+                CoreContext::test_mock(),
+                id.blobstore_key(),
+                BlobstoreBytes::from(blob),
+            )
+            .wait()
+            .unwrap();
+        id
+    }
+
+    fn has_local(local: &EagerMemblob, key: &str) -> bool {
+        local
+            .is_present(CoreContext::test_mock(), format!("repo0001.{}", key))
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pulls_missing_blobs() {
+        let mut rt = Runtime::new().unwrap();
+        let ctx = CoreContext::test_mock();
+        let (local, upstream, blobstore) = setup(0);
+        let blob = FileContents::new_bytes("file").into_blob();
+        let key = blob.id().blobstore_key();
+        let value = BlobstoreBytes::from(blob);
+        upstream
+            .blobstore
+            .put(ctx.clone(), key.clone(), value.clone())
+            .wait()
+            .unwrap();
+
+        for _ in 0..2 {
+            let fetched = rt
+                .block_on(blobstore.get(ctx.clone(), format!("repo0001.{}", key)))
+                .unwrap();
+            assert_eq!(fetched, Some(value.clone()));
+        }
+        assert_eq!(upstream.fetches.load(Ordering::SeqCst), 1);
+        assert!(has_local(&local, &key));
+    }
+
+    #[test]
+    fn test_rejects_corrupt_blobs() {
+        let mut rt = Runtime::new().unwrap();
+        let ctx = CoreContext::test_mock();
+        let (local, upstream, blobstore) = setup(0);
+        let key = FileContents::new_bytes("file")
+            .into_blob()
+            .id()
+            .blobstore_key();
+        let other = BlobstoreBytes::from(FileContents::new_bytes("other").into_blob());
+        upstream
+            .blobstore
+            .put(ctx.clone(), key.clone(), other)
+            .wait()
+            .unwrap();
+
+        let result = rt.block_on(blobstore.get(ctx.clone(), format!("repo0001.{}", key)));
+        assert!(result.is_err());
+        assert!(!has_local(&local, &key));
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let mut rt = Runtime::new().unwrap();
+        let ctx = CoreContext::test_mock();
+        let (_local, upstream, blobstore) = setup(0);
+        let key = "repo0001.content.blake2.abcd".to_string();
+
+        for _ in 0..2 {
+            let value = rt
+                .block_on(blobstore.get(ctx.clone(), key.clone()))
+                .unwrap();
+            assert_eq!(value, None);
+        }
+        assert_eq!(upstream.fetches.load(Ordering::SeqCst), 1);
+
+        // Puts make the key visible at once
+        rt.block_on(blobstore.put(ctx.clone(), key.clone(), BlobstoreBytes::from_bytes("file")))
+            .unwrap();
+        let value = rt.block_on(blobstore.get(ctx.clone(), key)).unwrap();
+        assert_eq!(value, Some(BlobstoreBytes::from_bytes("file")));
+    }
+
+    #[test]
+    fn test_prefetch_parents() {
+        let rt = Runtime::new().unwrap();
+        let ctx = CoreContext::test_mock();
+        let (local, upstream, blobstore) = setup(2);
+        let root = put_changeset(&upstream, "root", vec![]);
+        let p1 = put_changeset(&upstream, "p1", vec![root]);
+        let p2 = put_changeset(&upstream, "p2", vec![]);
+        let merge = put_changeset(&upstream, "merge", vec![p1, p2]);
+
+        let get = blobstore.get(ctx, format!("repo0001.{}", merge.blobstore_key()));
+        let value = rt.block_on_all(get).unwrap();
+        assert!(value.is_some());
+
+        // The two generations of ancestors were fetched in the background
+        assert!(has_local(&local, &merge.blobstore_key()));
+        assert!(has_local(&local, &p1.blobstore_key()));
+        assert!(has_local(&local, &p2.blobstore_key()));
+        assert!(has_local(&local, &root.blobstore_key()));
+        assert_eq!(upstream.fetches.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_prefetch_depth() {
+        let rt = Runtime::new().unwrap();
+        let ctx = CoreContext::test_mock();
+        let (local, upstream, blobstore) = setup(1);
+        let root = put_changeset(&upstream, "root", vec![]);
+        let parent = put_changeset(&upstream, "parent", vec![root]);
+        let child = put_changeset(&upstream, "child", vec![parent]);
+
+        let get = blobstore.get(ctx, format!("repo0001.{}", child.blobstore_key()));
+        rt.block_on_all(get).unwrap();
+
+        assert!(has_local(&local, &parent.blobstore_key()));
+        assert!(!has_local(&local, &root.blobstore_key()));
+    }
+}
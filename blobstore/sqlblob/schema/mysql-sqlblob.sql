@@ -0,0 +1,16 @@
+CREATE TABLE `data` (
+  `repo_id` INT UNSIGNED NOT NULL,
+  `id` VARCHAR(255) NOT NULL,
+  `type` TINYINT NOT NULL,
+  `value` LONGBLOB NOT NULL,
+  PRIMARY KEY (`repo_id`, `id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE `chunk` (
+  `repo_id` INT UNSIGNED NOT NULL,
+  `id` VARCHAR(255) NOT NULL,
+  `chunk_id` INT UNSIGNED NOT NULL,
+  `codec` TINYINT NOT NULL DEFAULT 0,
+  `value` LONGBLOB NOT NULL,
+  PRIMARY KEY (`repo_id`, `id`, `chunk_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -10,6 +10,7 @@ CREATE TABLE `chunk` (
   `repo_id` INT UNSIGNED NOT NULL,
   `id` VARCHAR(255) NOT NULL,
   `chunk_id` INT UNSIGNED NOT NULL,
+  `codec` TINYINT NOT NULL DEFAULT 0,
   `value` BLOB NOT NULL,
   PRIMARY KEY (`repo_id`, `id`, `chunk_id`)
 );
@@ -17,7 +17,7 @@ mod store;
 
 use crate::cache::{ChunkCacheTranslator, DataCacheTranslator, SqlblobCacheOps};
 use crate::store::{ChunkSqlStore, DataSqlStore};
-use blobstore::Blobstore;
+use blobstore::{Blobstore, HedgeDelay};
 use cacheblob::{dummy::DummyCache, MemcacheOps};
 use cloned::cloned;
 use context::CoreContext;
@@ -28,12 +28,14 @@ use futures_ext::{BoxFuture, FutureExt};
 use memcache::MEMCACHE_VALUE_MAX_SIZE;
 use mononoke_types::{BlobstoreBytes, RepositoryId};
 use sql::{rusqlite::Connection as SqliteConnection, Connection};
+use sql_ext::migrations::{Migration, SqlMigrations};
 use sql_ext::{create_myrouter_connections, PoolSizeConfig, SqlConnections};
 use stats::Timeseries;
 use std::fmt;
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 // Leaving some space for metadata
 const MAX_KEY_SIZE: usize = 200;
@@ -126,14 +128,14 @@ impl Sqlblob {
             ),
             data_cache: SqlblobCacheOps::new(
                 Arc::new(
-                    MemcacheOps::new("sqlblob.data", repo_id.id())
+                    MemcacheOps::new("sqlblob.data", repo_id.id(), None)
                         .expect("failed to create MemcacheOps"),
                 ),
                 DataCacheTranslator::new(repo_id),
             ),
             chunk_cache: SqlblobCacheOps::new(
                 Arc::new(
-                    MemcacheOps::new("sqlblob.chunk", repo_id.id())
+                    MemcacheOps::new("sqlblob.chunk", repo_id.id(), None)
                         .expect("failed to create MemcacheOps"),
                 ),
                 ChunkCacheTranslator::new(repo_id),
@@ -141,6 +143,35 @@ impl Sqlblob {
         }
     }
 
+    /// Hedge reads from replicas by also reading from the master once a read has taken longer
+    /// than `percentile` percent of recent reads, but not sooner than `min_delay`.
+    pub fn with_hedged_reads(self, percentile: u8, min_delay: Duration) -> Self {
+        Self {
+            data_store: self.data_store.with_hedged_reads(HedgeDelay::new(
+                "sqlblob.data".to_string(),
+                percentile,
+                min_delay,
+            )),
+            chunk_store: self.chunk_store.with_hedged_reads(HedgeDelay::new(
+                "sqlblob.chunk".to_string(),
+                percentile,
+                min_delay,
+            )),
+            ..self
+        }
+    }
+
+    /// Compress the chunks of blobs that are too large to be stored whole with zstd at `level`.
+    /// This needs the codec column that migration 2 adds to the chunk table of every shard.
+    /// Without it, the column isn't used and chunks are read back as they are, so once chunks
+    /// were compressed this can't be turned off again.
+    pub fn with_compressed_chunks(self, level: i32) -> Self {
+        Self {
+            chunk_store: self.chunk_store.with_compression(level),
+            ..self
+        }
+    }
+
     pub fn with_sqlite_in_memory(repo_id: RepositoryId) -> Result<Self> {
         Self::with_sqlite(repo_id, |_| {
             let con = SqliteConnection::open_in_memory()?;
@@ -152,7 +183,7 @@ impl Sqlblob {
     pub fn with_sqlite_path<P: Into<PathBuf>>(repo_id: RepositoryId, path: P) -> Result<Self> {
         let path = path.into();
         Self::with_sqlite(repo_id, move |shard_id| {
-            let con = SqliteConnection::open(path.join(format!("shard_{}.sqlite", shard_id)))?;
+            let con = SqliteConnection::open(Self::sqlite_shard_path(&path, shard_id))?;
             // When opening an sqlite database we might already have the proper tables in it, so ignore
             // errors from table creation
             let _ = con.execute_batch(Self::get_up_query());
@@ -198,11 +229,71 @@ impl Sqlblob {
         })
     }
 
+    /// Paths of the SQLite databases of the shards of a blobstore at `path`
+    pub fn sqlite_shard_paths<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
+        (1..=SQLITE_SHARD_NUM.get())
+            .map(|shard_id| Self::sqlite_shard_path(path.as_ref(), shard_id))
+            .collect()
+    }
+
+    fn sqlite_shard_path(path: &Path, shard_id: usize) -> PathBuf {
+        path.join(format!("shard_{}.sqlite", shard_id))
+    }
+
     fn get_up_query() -> &'static str {
         include_str!("../schema/sqlite-sqlblob.sql")
     }
 }
 
+/// Every shard has the tables of the whole schema, so migrations are applied to each of them.
+impl SqlMigrations for Sqlblob {
+    const STORE: &'static str = "sqlblob";
+
+    fn migrations() -> &'static [Migration] {
+        &[
+            Migration {
+                version: 1,
+                description: "create the data and chunk tables",
+                sqlite: "CREATE TABLE `data` (
+                           `repo_id` INT UNSIGNED NOT NULL,
+                           `id` VARCHAR(255) NOT NULL,
+                           `type` TINYINT NOT NULL,
+                           `value` BLOB NOT NULL,
+                           PRIMARY KEY (`repo_id`, `id`)
+                         );
+                         CREATE TABLE `chunk` (
+                           `repo_id` INT UNSIGNED NOT NULL,
+                           `id` VARCHAR(255) NOT NULL,
+                           `chunk_id` INT UNSIGNED NOT NULL,
+                           `value` BLOB NOT NULL,
+                           PRIMARY KEY (`repo_id`, `id`, `chunk_id`)
+                         );",
+                mysql: "CREATE TABLE `data` (
+                          `repo_id` INT UNSIGNED NOT NULL,
+                          `id` VARCHAR(255) NOT NULL,
+                          `type` TINYINT NOT NULL,
+                          `value` LONGBLOB NOT NULL,
+                          PRIMARY KEY (`repo_id`, `id`)
+                        ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+                        CREATE TABLE `chunk` (
+                          `repo_id` INT UNSIGNED NOT NULL,
+                          `id` VARCHAR(255) NOT NULL,
+                          `chunk_id` INT UNSIGNED NOT NULL,
+                          `value` LONGBLOB NOT NULL,
+                          PRIMARY KEY (`repo_id`, `id`, `chunk_id`)
+                        ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+            },
+            Migration {
+                version: 2,
+                description: "add the codec of compressed chunks",
+                sqlite: "ALTER TABLE `chunk` ADD COLUMN `codec` TINYINT NOT NULL DEFAULT 0;",
+                mysql: "ALTER TABLE `chunk` ADD COLUMN `codec` TINYINT NOT NULL DEFAULT 0 \
+                        AFTER `chunk_id`;",
+            },
+        ]
+    }
+}
+
 impl fmt::Debug for Sqlblob {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Sqlblob").finish()
@@ -367,4 +458,64 @@ mod tests {
 
         tokio::run(fut);
     }
+    #[test]
+    fn compressed_chunks() {
+        let ctx = CoreContext::test_mock();
+        let key = "compressed_chunks_test".to_string();
+
+        let bs = Arc::new(
+            Sqlblob::with_sqlite_in_memory(RepositoryId::new(1234))
+                .unwrap()
+                .with_compressed_chunks(3),
+        );
+
+        // Large enough to be chunked, and compressible apart from the random tail
+        let mut bytes_in = vec![b'a'; 2 * CHUNK_SIZE];
+        let mut tail = [0u8; 64];
+        thread_rng().fill_bytes(&mut tail);
+        bytes_in.extend_from_slice(&tail);
+
+        let blobstore_bytes = BlobstoreBytes::from_bytes(bytes_in.clone());
+
+        let fut = bs
+            .put(ctx.clone(), key.clone(), blobstore_bytes)
+            .and_then(move |()| bs.get(ctx, key))
+            .map(move |bytes_out| {
+                assert_eq!(&bytes_in, bytes_out.unwrap().as_bytes());
+            })
+            .map_err(|err| panic!("{:#?}", err));
+
+        tokio::run(fut);
+    }
+
+    #[test]
+    fn chunks_without_codec_column() {
+        let ctx = CoreContext::test_mock();
+        let key = "chunks_without_codec_column_test".to_string();
+
+        // The schema before migration 2, which must keep working while compression is off
+        let bs = Arc::new(
+            Sqlblob::with_sqlite(RepositoryId::new(1234), |_| {
+                let con = SqliteConnection::open_in_memory()?;
+                con.execute_batch(Sqlblob::migrations()[0].sqlite)?;
+                Ok(con)
+            })
+            .unwrap(),
+        );
+
+        let mut bytes_in = vec![0u8; 2 * CHUNK_SIZE];
+        thread_rng().fill_bytes(&mut bytes_in);
+
+        let blobstore_bytes = BlobstoreBytes::from_bytes(bytes_in.clone());
+
+        let fut = bs
+            .put(ctx.clone(), key.clone(), blobstore_bytes)
+            .and_then(move |()| bs.get(ctx, key))
+            .map(move |bytes_out| {
+                assert_eq!(&bytes_in, bytes_out.unwrap().as_bytes());
+            })
+            .map_err(|err| panic!("{:#?}", err));
+
+        tokio::run(fut);
+    }
 }
@@ -11,11 +11,12 @@ use std::sync::Arc;
 use cloned::cloned;
 use failure_ext::{err_msg, format_err, Error};
 use futures::prelude::*;
-use futures_ext::FutureExt;
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
 use rust_thrift::compact_protocol;
 use sql::Connection;
 use twox_hash::XxHash32;
 
+use blobstore::{hedged, HedgeDelay};
 use mononoke_types::{BlobstoreBytes, RepositoryId};
 use sqlblob_thrift::InChunk;
 
@@ -67,9 +68,71 @@ mod types {
     impl FromValue for DataType {
         type Intermediate = DataType;
     }
+
+    /// How the value of a chunk is encoded
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub enum ChunkCodec {
+        Raw,
+        Zstd,
+    }
+
+    impl From<ChunkCodec> for Value {
+        fn from(codec: ChunkCodec) -> Self {
+            match codec {
+                ChunkCodec::Raw => Value::Int(0),
+                ChunkCodec::Zstd => Value::Int(1),
+            }
+        }
+    }
+
+    impl ConvIr<ChunkCodec> for ChunkCodec {
+        fn new(v: Value) -> FromValueResult<Self> {
+            match v {
+                Value::Int(0) => Ok(ChunkCodec::Raw),
+                Value::Bytes(ref b) if b == b"0" => Ok(ChunkCodec::Raw),
+                Value::Int(1) => Ok(ChunkCodec::Zstd),
+                Value::Bytes(ref b) if b == b"1" => Ok(ChunkCodec::Zstd),
+                v => Err(FromValueError(v)),
+            }
+        }
+
+        fn commit(self) -> ChunkCodec {
+            self
+        }
+
+        fn rollback(self) -> Value {
+            self.into()
+        }
+    }
+
+    impl FromValue for ChunkCodec {
+        type Intermediate = ChunkCodec;
+    }
 }
 
-use self::types::DataType;
+use self::types::{ChunkCodec, DataType};
+
+/// Read from a replica, and if hedging is enabled and the replica is slow, from the master too.
+/// The flag returned alongside the rows is true if they came from the master.
+fn hedged_read<T, R, M, MF>(
+    hedge_delay: &Option<Arc<HedgeDelay>>,
+    replica: R,
+    master: M,
+) -> impl Future<Item = (T, bool), Error = Error>
+where
+    R: Future<Item = T, Error = Error>,
+    M: FnOnce() -> MF,
+    MF: Future<Item = T, Error = Error>,
+{
+    let replica = replica.map(|rows| (rows, false));
+    match hedge_delay {
+        Some(hedge_delay) => hedged(hedge_delay.clone(), replica, move || {
+            master().map(|rows| (rows, true))
+        })
+        .left_future(),
+        None => replica.right_future(),
+    }
+}
 
 queries! {
     write InsertData(values: (repo_id: RepositoryId, id: &str, dtype: DataType, value: &[u8])) {
@@ -82,7 +145,31 @@ queries! {
         ) VALUES {values}"
     }
 
-    write InsertChunk(values: (repo_id: RepositoryId, id: &str, chunk_id: u32, value: &[u8])) {
+    write InsertChunk(values: (
+        repo_id: RepositoryId,
+        id: &str,
+        chunk_id: u32,
+        codec: ChunkCodec,
+        value: &[u8],
+    )) {
+        insert_or_ignore,
+        "{insert_or_ignore} INTO chunk (
+            repo_id
+            , id
+            , chunk_id
+            , codec
+            , value
+        ) VALUES {values}"
+    }
+
+    // Chunk queries of schemas without the codec column, which sqlblob migration 2 adds. They are
+    // used while compression is disabled, so that it can be deployed before the migration runs.
+    write InsertUncompressedChunk(values: (
+        repo_id: RepositoryId,
+        id: &str,
+        chunk_id: u32,
+        value: &[u8],
+    )) {
         insert_or_ignore,
         "{insert_or_ignore} INTO chunk (
             repo_id
@@ -106,7 +193,15 @@ queries! {
            AND id = {id}"
     }
 
-    read SelectChunk(repo_id: RepositoryId, id: String, chunk_id: u32) -> (Vec<u8>) {
+    read SelectChunk(repo_id: RepositoryId, id: String, chunk_id: u32) -> (Vec<u8>, ChunkCodec) {
+        "SELECT value, codec
+         FROM chunk
+         WHERE repo_id = {repo_id}
+           AND id = {id}
+           AND chunk_id = {chunk_id}"
+    }
+
+    read SelectUncompressedChunk(repo_id: RepositoryId, id: String, chunk_id: u32) -> (Vec<u8>) {
         "SELECT value
          FROM chunk
          WHERE repo_id = {repo_id}
@@ -122,6 +217,7 @@ pub(crate) struct DataSqlStore {
     write_connection: Arc<Vec<Connection>>,
     read_connection: Arc<Vec<Connection>>,
     read_master_connection: Arc<Vec<Connection>>,
+    hedge_delay: Option<Arc<HedgeDelay>>,
 }
 
 impl DataSqlStore {
@@ -138,6 +234,15 @@ impl DataSqlStore {
             write_connection,
             read_connection,
             read_master_connection,
+            hedge_delay: None,
+        }
+    }
+
+    /// Send reads to the master as well if the replica is slow to answer.
+    pub(crate) fn with_hedged_reads(self, hedge_delay: HedgeDelay) -> Self {
+        Self {
+            hedge_delay: Some(Arc::new(hedge_delay)),
+            ..self
         }
     }
 
@@ -148,13 +253,21 @@ impl DataSqlStore {
         let shard_id = self.shard(&key);
         let read_master_connection = self.read_master_connection[shard_id - 1].clone();
 
-        SelectData::query(&self.read_connection[shard_id - 1], &repo_id, &key)
-            .and_then(move |rows| match rows.into_iter().next() {
-                Some(row) => Ok(Some(row)).into_future().left_future(),
-                None => SelectData::query(&read_master_connection, &repo_id, &key)
-                    .map(|rows| rows.into_iter().next())
-                    .right_future(),
-            })
+        hedged_read(
+            &self.hedge_delay,
+            SelectData::query(&self.read_connection[shard_id - 1], &repo_id, &key),
+            {
+                cloned!(read_master_connection, repo_id, key);
+                move || SelectData::query(&read_master_connection, &repo_id, &key)
+            },
+        )
+        .and_then(move |(rows, from_master)| match rows.into_iter().next() {
+            Some(row) => Ok(Some(row)).into_future().left_future(),
+            None if from_master => Ok(None).into_future().left_future(),
+            None => SelectData::query(&read_master_connection, &repo_id, &key)
+                .map(|rows| rows.into_iter().next())
+                .right_future(),
+        })
             .and_then(move |rows| match rows.into_iter().next() {
                 None => Ok(None),
                 Some((DataType::Data, value)) => {
@@ -232,6 +345,9 @@ pub(crate) struct ChunkSqlStore {
     write_connection: Arc<Vec<Connection>>,
     read_connection: Arc<Vec<Connection>>,
     read_master_connection: Arc<Vec<Connection>>,
+    hedge_delay: Option<Arc<HedgeDelay>>,
+    /// The zstd level that chunks are compressed with, if they are
+    compression_level: Option<i32>,
 }
 
 impl ChunkSqlStore {
@@ -248,6 +364,26 @@ impl ChunkSqlStore {
             write_connection,
             read_connection,
             read_master_connection,
+            hedge_delay: None,
+            compression_level: None,
+        }
+    }
+
+    /// Send reads to the master as well if the replica is slow to answer.
+    pub(crate) fn with_hedged_reads(self, hedge_delay: HedgeDelay) -> Self {
+        Self {
+            hedge_delay: Some(Arc::new(hedge_delay)),
+            ..self
+        }
+    }
+
+    /// Compress the chunks that are put with zstd at `level`. Chunks are stored uncompressed
+    /// when that's no larger. The codec column of chunks is only used when this is set, so the
+    /// schema must have been migrated first.
+    pub(crate) fn with_compression(self, level: i32) -> Self {
+        Self {
+            compression_level: Some(level),
+            ..self
         }
     }
 
@@ -256,48 +392,88 @@ impl ChunkSqlStore {
         key: &str,
         chunk_id: u32,
     ) -> impl Future<Item = BlobstoreBytes, Error = Error> {
-        cloned!(self.repo_id);
-
         let key = key.to_owned();
         let shard_id = self.shard(&key, chunk_id);
         let read_master_connection = self.read_master_connection[shard_id - 1].clone();
-
-        SelectChunk::query(
-            &self.read_connection[shard_id - 1],
-            &repo_id,
-            &key,
-            &chunk_id,
+        let store = self.clone();
+
+        hedged_read(
+            &self.hedge_delay,
+            self.select_chunk(&self.read_connection[shard_id - 1], &key, chunk_id),
+            {
+                cloned!(store, read_master_connection, key);
+                move || store.select_chunk(&read_master_connection, &key, chunk_id)
+            },
         )
-        .and_then(move |rows| match rows.into_iter().next() {
-            Some((value,)) => Ok(BlobstoreBytes::from_bytes(value))
-                .into_future()
-                .left_future(),
-            None => SelectChunk::query(&read_master_connection, &repo_id, &key, &chunk_id)
-                .and_then(move |rows| match rows.into_iter().next() {
-                    Some((value,)) => Ok(BlobstoreBytes::from_bytes(value)),
-                    None => Err(format_err!(
-                        "Missing chunk with id {} shard {}",
-                        chunk_id,
-                        shard_id
-                    )),
-                })
-                .right_future(),
+        .and_then(move |(row, from_master)| {
+            let missing =
+                move || format_err!("Missing chunk with id {} shard {}", chunk_id, shard_id);
+            match row {
+                Some((value, codec)) => decode_chunk(value, codec).into_future().left_future(),
+                None if from_master => Err(missing()).into_future().left_future(),
+                None => store
+                    .select_chunk(&read_master_connection, &key, chunk_id)
+                    .and_then(move |row| match row {
+                        Some((value, codec)) => decode_chunk(value, codec),
+                        None => Err(missing()),
+                    })
+                    .right_future(),
+            }
         })
     }
 
-    pub(crate) fn put(
+    /// Read a chunk and the codec it is encoded with. Without compression, the codec column isn't
+    /// read as it may not exist yet, and chunks are raw.
+    fn select_chunk(
         &self,
+        connection: &Connection,
         key: &str,
         chunk_id: u32,
-        value: &[u8],
-    ) -> impl Future<Item = (), Error = Error> {
+    ) -> impl Future<Item = Option<(Vec<u8>, ChunkCodec)>, Error = Error> {
+        let key = key.to_owned();
+        if self.compression_level.is_some() {
+            SelectChunk::query(connection, &self.repo_id, &key, &chunk_id)
+                .map(|rows| rows.into_iter().next())
+                .left_future()
+        } else {
+            SelectUncompressedChunk::query(connection, &self.repo_id, &key, &chunk_id)
+                .map(|rows| {
+                    rows.into_iter()
+                        .next()
+                        .map(|(value,)| (value, ChunkCodec::Raw))
+                })
+                .right_future()
+        }
+    }
+
+    pub(crate) fn put(&self, key: &str, chunk_id: u32, value: &[u8]) -> BoxFuture<(), Error> {
         let shard_id = self.shard(key, chunk_id);
+        let write_connection = &self.write_connection[shard_id - 1];
+
+        let level = match self.compression_level {
+            Some(level) => level,
+            None => {
+                return InsertUncompressedChunk::query(
+                    write_connection,
+                    &[(&self.repo_id, &key, &chunk_id, &value)],
+                )
+                .map(|_| ())
+                .boxify();
+            }
+        };
+        let compressed = try_boxfuture!(zstd::stream::encode_all(value, level));
+        let (codec, value) = if compressed.len() < value.len() {
+            (ChunkCodec::Zstd, compressed.as_slice())
+        } else {
+            (ChunkCodec::Raw, value)
+        };
 
         InsertChunk::query(
-            &self.write_connection[shard_id - 1],
-            &[(&self.repo_id, &key, &chunk_id, &value)],
+            write_connection,
+            &[(&self.repo_id, &key, &chunk_id, &codec, &value)],
         )
         .map(|_| ())
+        .boxify()
     }
 
     fn shard(&self, key: &str, chunk_id: u32) -> usize {
@@ -308,3 +484,13 @@ impl ChunkSqlStore {
         ((hasher.finish() % self.shard_num.get() as u64) + 1) as usize
     }
 }
+
+fn decode_chunk(value: Vec<u8>, codec: ChunkCodec) -> Result<BlobstoreBytes, Error> {
+    match codec {
+        ChunkCodec::Raw => Ok(BlobstoreBytes::from_bytes(value)),
+        ChunkCodec::Zstd => {
+            let value = zstd::stream::decode_all(value.as_slice())?;
+            Ok(BlobstoreBytes::from_bytes(value))
+        }
+    }
+}
@@ -7,9 +7,10 @@
 use std::ops::Deref;
 use std::sync::Arc;
 
+use bytes::Bytes;
 use failure::Error;
 use futures::Future;
-use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
 use stats::Timeseries;
 
 use context::CoreContext;
@@ -89,6 +90,26 @@ impl<T: Blobstore> Blobstore for CountedBlobstore<T> {
             .boxify()
     }
 
+    fn get_stream(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<Option<BoxStream<Bytes, Error>>, Error> {
+        let stats = self.stats.clone();
+        stats.get.add_value(1);
+        self.blobstore
+            .get_stream(ctx, key)
+            .then(move |res| {
+                match res {
+                    Ok(Some(_)) => stats.get_hit.add_value(1),
+                    Ok(None) => stats.get_miss.add_value(1),
+                    Err(_) => stats.get_err.add_value(1),
+                }
+                res
+            })
+            .boxify()
+    }
+
     fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
         let stats = self.stats.clone();
         stats.is_present.add_value(1);
@@ -4,7 +4,17 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use mononoke_errors::{Categorize, ErrorCategory};
+
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "Blob {} not found in blobstore", _0)] NotFound(String),
 }
+
+impl Categorize for ErrorKind {
+    fn category(&self) -> Option<ErrorCategory> {
+        match self {
+            ErrorKind::NotFound(_) => Some(ErrorCategory::NotFound),
+        }
+    }
+}
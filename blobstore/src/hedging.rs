@@ -0,0 +1,242 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Hedged reads: if a read from one replica takes longer than most reads do, send the same read
+//! to another replica and use whichever answer arrives first.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, IntoFuture, Poll};
+use stats::Timeseries;
+use tokio::timer::Delay;
+
+/// Number of recent latencies the hedge delay is computed from.
+const LATENCY_WINDOW: usize = 1000;
+/// The hedge delay is recomputed after this many latencies have been recorded.
+const RECOMPUTE_INTERVAL: usize = 100;
+
+define_stats_struct! {
+    HedgeStats("mononoke.blobstore.hedge.{}", name: String),
+    hedge_started: timeseries(RATE, SUM),
+    hedge_won: timeseries(RATE, SUM),
+    hedge_delay_us: timeseries(AVG),
+}
+
+fn duration_to_us(duration: Duration) -> usize {
+    (duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())) as usize
+}
+
+/// Tracks the latency of recent reads, and decides how long to wait for a read before hedging it.
+///
+/// The delay is the configured percentile of the recent latencies, but no shorter than
+/// `min_delay`. Until enough latencies have been recorded reads are not hedged at all.
+pub struct HedgeDelay {
+    percentile: u8,
+    min_delay: Duration,
+    latencies: Mutex<VecDeque<Duration>>,
+    recorded: AtomicUsize,
+    // Current delay in microseconds, 0 if not known yet.
+    delay_us: AtomicUsize,
+    stats: HedgeStats,
+}
+
+impl HedgeDelay {
+    pub fn new(name: String, percentile: u8, min_delay: Duration) -> Self {
+        assert!(
+            percentile > 0 && percentile < 100,
+            "hedge percentile must be between 1 and 99"
+        );
+        Self {
+            percentile,
+            min_delay,
+            latencies: Mutex::new(VecDeque::with_capacity(LATENCY_WINDOW)),
+            recorded: AtomicUsize::new(0),
+            delay_us: AtomicUsize::new(0),
+            stats: HedgeStats::new(name),
+        }
+    }
+
+    /// How long to wait for a read before hedging it, or None if reads shouldn't be hedged yet.
+    pub fn delay(&self) -> Option<Duration> {
+        match self.delay_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us as u64)),
+        }
+    }
+
+    /// Record the latency of a successful read.
+    pub fn record(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().expect("lock poisoned");
+        if latencies.len() == LATENCY_WINDOW {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+
+        let recorded = self.recorded.fetch_add(1, Ordering::Relaxed) + 1;
+        if recorded % RECOMPUTE_INTERVAL == 0 {
+            let mut sorted: Vec<_> = latencies.iter().cloned().collect();
+            drop(latencies);
+            sorted.sort();
+            let index = (sorted.len() - 1) * self.percentile as usize / 100;
+            let delay = ::std::cmp::max(sorted[index], self.min_delay);
+            let delay_us = ::std::cmp::max(duration_to_us(delay), 1);
+            self.delay_us.store(delay_us, Ordering::Relaxed);
+            self.stats.hedge_delay_us.add_value(delay_us as i64);
+        }
+    }
+
+    /// Call when a hedged read is sent.
+    pub fn on_hedge_started(&self) {
+        self.stats.hedge_started.add_value(1);
+    }
+
+    /// Call when a hedged read answers before the read it was hedging.
+    pub fn on_hedge_won(&self) {
+        self.stats.hedge_won.add_value(1);
+    }
+}
+
+/// Run `primary`, and if it hasn't finished after the current hedge delay, or if it fails, start
+/// the future returned by `hedge` too. Resolves to the result of whichever succeeds first; if both
+/// fail, the error from `primary` is returned.
+pub fn hedged<F, H, HF>(delay: Arc<HedgeDelay>, primary: F, hedge: H) -> Hedged<F, H, HF::Future>
+where
+    F: Future,
+    H: FnOnce() -> HF,
+    HF: IntoFuture<Item = F::Item, Error = F::Error>,
+{
+    let started = Instant::now();
+    let timer = delay
+        .delay()
+        .map(|duration| Delay::new(started + duration));
+    Hedged {
+        delay,
+        started,
+        primary: Some(primary),
+        primary_error: None,
+        timer,
+        make_hedge: Some(hedge),
+        hedge: None,
+    }
+}
+
+/// Future returned by `hedged`.
+pub struct Hedged<F: Future, H, HF> {
+    delay: Arc<HedgeDelay>,
+    started: Instant,
+    primary: Option<F>,
+    primary_error: Option<F::Error>,
+    timer: Option<Delay>,
+    make_hedge: Option<H>,
+    hedge: Option<HF>,
+}
+
+impl<F, H, HF> Future for Hedged<F, H, HF>
+where
+    F: Future,
+    H: FnOnce() -> HF,
+    HF: Future<Item = F::Item, Error = F::Error>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(mut primary) = self.primary.take() {
+            match primary.poll() {
+                Ok(Async::Ready(item)) => {
+                    self.delay.record(self.started.elapsed());
+                    return Ok(Async::Ready(item));
+                }
+                Ok(Async::NotReady) => self.primary = Some(primary),
+                Err(err) => self.primary_error = Some(err),
+            }
+        }
+
+        if let Some(make_hedge) = self.make_hedge.take() {
+            let start_hedge = self.primary.is_none()
+                || match self.timer {
+                    // Timer errors only happen if the timer is gone; hedge rather than hang.
+                    Some(ref mut timer) => match timer.poll() {
+                        Ok(Async::NotReady) => false,
+                        Ok(Async::Ready(())) | Err(_) => true,
+                    },
+                    None => false,
+                };
+            if start_hedge {
+                self.timer = None;
+                self.delay.on_hedge_started();
+                self.hedge = Some(make_hedge().into_future());
+            } else {
+                self.make_hedge = Some(make_hedge);
+            }
+        }
+
+        if let Some(mut hedge) = self.hedge.take() {
+            match hedge.poll() {
+                Ok(Async::Ready(item)) => {
+                    // The primary took at least this long, so record it as its latency.
+                    self.delay.record(self.started.elapsed());
+                    if self.primary.is_some() {
+                        self.delay.on_hedge_won();
+                    }
+                    return Ok(Async::Ready(item));
+                }
+                Ok(Async::NotReady) => self.hedge = Some(hedge),
+                Err(err) => {
+                    if self.primary.is_none() {
+                        return Err(self.primary_error.take().unwrap_or(err));
+                    }
+                }
+            }
+        }
+
+        match (&self.primary, &self.make_hedge, &self.hedge) {
+            (None, None, None) => Err(self
+                .primary_error
+                .take()
+                .expect("primary failed, but its error is gone")),
+            _ => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_delay_until_enough_latencies() {
+        let delay = HedgeDelay::new("test".to_string(), 90, Duration::from_millis(1));
+        for _ in 1..RECOMPUTE_INTERVAL {
+            delay.record(Duration::from_millis(5));
+        }
+        assert_eq!(delay.delay(), None);
+        delay.record(Duration::from_millis(5));
+        assert_eq!(delay.delay(), Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn delay_is_percentile_of_latencies() {
+        let delay = HedgeDelay::new("test".to_string(), 90, Duration::from_millis(1));
+        for ms in 1..=(RECOMPUTE_INTERVAL as u64) {
+            delay.record(Duration::from_millis(ms * 10));
+        }
+        // The 90th percentile of 10ms, 20ms, ..., 1000ms.
+        assert_eq!(delay.delay(), Some(Duration::from_millis(900)));
+    }
+
+    #[test]
+    fn delay_is_at_least_min_delay() {
+        let delay = HedgeDelay::new("test".to_string(), 50, Duration::from_millis(20));
+        for _ in 0..RECOMPUTE_INTERVAL {
+            delay.record(Duration::from_millis(2));
+        }
+        assert_eq!(delay.delay(), Some(Duration::from_millis(20)));
+    }
+}
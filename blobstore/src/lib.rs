@@ -17,8 +17,8 @@ use std::sync::Arc;
 use crate::failure::Error;
 use asyncmemo::Weight;
 use bytes::Bytes;
-use futures::{future, Future};
-use futures_ext::{BoxFuture, FutureExt};
+use futures::{future, stream, Future};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use context::CoreContext;
 
@@ -28,6 +28,12 @@ pub use crate::counted_blobstore::CountedBlobstore;
 mod errors;
 pub use crate::errors::ErrorKind;
 
+mod hedging;
+pub use crate::hedging::{hedged, HedgeDelay, Hedged};
+
+mod retrying;
+pub use crate::retrying::{RetryPolicy, RetryingBlobstore};
+
 /// A type representing bytes written to or read from a blobstore. The goal here is to ensure
 /// that only types that implement `From<BlobstoreBytes>` and `Into<BlobstoreBytes>` can be
 /// stored in the blob store.
@@ -97,6 +103,18 @@ pub trait Blobstore: fmt::Debug + Send + Sync + 'static {
     /// for the same key, the implementation may return any `value` it's been given in response
     /// to a `get` for that `key`.
     fn put(&self, ctx: CoreContext, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error>;
+    /// Fetch the value associated with `key` as a stream of chunks, or None if no value is
+    /// present. The provided implementation fetches the whole value with `get`, so it is held in
+    /// memory at once all the same; this can be overridden by backends that read values in parts.
+    fn get_stream(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<Option<BoxStream<Bytes, Error>>, Error> {
+        self.get(ctx, key)
+            .map(|value| value.map(|value| stream::once(Ok(value.into_bytes())).boxify()))
+            .boxify()
+    }
     /// Check that `get` will return a value for a given `key`, and not None. The provided
     /// implentation just calls `get`, and discards the return value; this can be overridden to
     /// avoid transferring data. In the absence of concurrent `put` calls, this must return
@@ -126,6 +144,13 @@ impl Blobstore for Arc<dyn Blobstore> {
     fn put(&self, ctx: CoreContext, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
         self.as_ref().put(ctx, key, value)
     }
+    fn get_stream(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<Option<BoxStream<Bytes, Error>>, Error> {
+        self.as_ref().get_stream(ctx, key)
+    }
     fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
         self.as_ref().is_present(ctx, key)
     }
@@ -141,6 +166,13 @@ impl Blobstore for Box<dyn Blobstore> {
     fn put(&self, ctx: CoreContext, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
         self.as_ref().put(ctx, key, value)
     }
+    fn get_stream(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<Option<BoxStream<Bytes, Error>>, Error> {
+        self.as_ref().get_stream(ctx, key)
+    }
     fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
         self.as_ref().is_present(ctx, key)
     }
@@ -0,0 +1,274 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Retries of puts that failed transiently. A key is only ever associated with one value, so
+//! sending a put again can't store anything that the first attempt wouldn't have.
+
+use std::cmp;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use futures::future::{self, loop_fn, Loop};
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use mononoke_errors::Categorizer;
+use rand::{thread_rng, Rng};
+use stats::Timeseries;
+use tokio::timer::Delay;
+
+use context::CoreContext;
+
+use crate::{Blobstore, BlobstoreBytes};
+
+define_stats! {
+    prefix = "mononoke.blobstore.put_retry";
+    first_try_ok: timeseries(RATE, SUM),
+    retried_ok: timeseries(RATE, SUM),
+    retries: timeseries(RATE, SUM),
+    gave_up: timeseries(RATE, SUM),
+}
+
+/// How often and how long to wait before sending a failed put again
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts of a put, including the first one
+    pub max_attempts: usize,
+    /// Backoff after the first attempt. It doubles after every attempt, up to `max_backoff`
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait after `attempt` failed. Half of the backoff is random, so that the puts
+    /// of a push that failed together, e.g. when a backend restarted, aren't all retried at the
+    /// same time.
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let initial_ms = duration_to_ms(self.initial_backoff);
+        let exponent = cmp::min(attempt.saturating_sub(1), 32) as u32;
+        let backoff_ms = cmp::min(
+            initial_ms.saturating_mul(1 << exponent),
+            duration_to_ms(self.max_backoff),
+        );
+        let jitter_ms = thread_rng().gen_range(0, backoff_ms / 2 + 1);
+        Duration::from_millis(backoff_ms - backoff_ms / 2 + jitter_ms)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+fn duration_to_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+/// Blobstore that sends puts again when they fail with an error that `categorizer` considers
+/// transient. Other operations go straight to the inner blobstore.
+#[derive(Clone)]
+pub struct RetryingBlobstore<T: Blobstore> {
+    blobstore: Arc<T>,
+    policy: RetryPolicy,
+    categorizer: Categorizer,
+}
+
+impl<T: Blobstore> RetryingBlobstore<T> {
+    pub fn new(blobstore: T, policy: RetryPolicy, categorizer: Categorizer) -> Self {
+        Self {
+            blobstore: Arc::new(blobstore),
+            policy,
+            categorizer,
+        }
+    }
+}
+
+impl<T: Blobstore> fmt::Debug for RetryingBlobstore<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryingBlobstore")
+            .field("blobstore", &self.blobstore)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl<T: Blobstore> Blobstore for RetryingBlobstore<T> {
+    fn get(&self, ctx: CoreContext, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+        self.blobstore.get(ctx, key)
+    }
+
+    fn put(&self, ctx: CoreContext, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
+        let blobstore = self.blobstore.clone();
+        let policy = self.policy;
+        let categorizer = self.categorizer.clone();
+
+        loop_fn(1, move |attempt| {
+            let categorizer = categorizer.clone();
+            blobstore
+                .put(ctx.clone(), key.clone(), value.clone())
+                .then(move |res| match res {
+                    Ok(()) => {
+                        if attempt == 1 {
+                            STATS::first_try_ok.add_value(1);
+                        } else {
+                            STATS::retried_ok.add_value(1);
+                        }
+                        future::ok(Loop::Break(())).left_future()
+                    }
+                    Err(err) => {
+                        if attempt >= policy.max_attempts || !categorizer.is_transient(&err) {
+                            if attempt > 1 {
+                                STATS::gave_up.add_value(1);
+                            }
+                            return future::err(err).left_future();
+                        }
+                        STATS::retries.add_value(1);
+                        Delay::new(Instant::now() + policy.backoff(attempt))
+                            .then(move |_| Ok(Loop::Continue(attempt + 1)))
+                            .right_future()
+                    }
+                })
+        })
+        .boxify()
+    }
+
+    fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.is_present(ctx, key)
+    }
+
+    fn assert_present(&self, ctx: CoreContext, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.assert_present(ctx, key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use failure::err_msg;
+    use mononoke_errors::ErrorCategory;
+    use tokio::runtime::Runtime;
+
+    use crate::ErrorKind;
+
+    /// Fails the first `failures` puts with `make_err`
+    #[derive(Debug)]
+    struct FlakyBlobstore {
+        failures: usize,
+        puts: AtomicUsize,
+        make_err: fn() -> Error,
+    }
+
+    impl FlakyBlobstore {
+        fn new(failures: usize, make_err: fn() -> Error) -> Self {
+            Self {
+                failures,
+                puts: AtomicUsize::new(0),
+                make_err,
+            }
+        }
+    }
+
+    impl Blobstore for FlakyBlobstore {
+        fn get(&self, _ctx: CoreContext, _key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+            future::ok(None).boxify()
+        }
+
+        fn put(
+            &self,
+            _ctx: CoreContext,
+            _key: String,
+            _value: BlobstoreBytes,
+        ) -> BoxFuture<(), Error> {
+            if self.puts.fetch_add(1, Ordering::Relaxed) < self.failures {
+                future::err((self.make_err)()).boxify()
+            } else {
+                future::ok(()).boxify()
+            }
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+        }
+    }
+
+    fn put(blobstore: &RetryingBlobstore<FlakyBlobstore>) -> Result<(), Error> {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(blobstore.put(
+            CoreContext::test_mock(),
+            "key".to_string(),
+            BlobstoreBytes::from_bytes("value"),
+        ))
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let blobstore = RetryingBlobstore::new(
+            FlakyBlobstore::new(2, || err_msg("backend timed out")),
+            policy(),
+            Categorizer::new(ErrorCategory::Transient),
+        );
+        assert!(put(&blobstore).is_ok());
+        assert_eq!(blobstore.blobstore.puts.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn attempts_are_bounded() {
+        let blobstore = RetryingBlobstore::new(
+            FlakyBlobstore::new(3, || err_msg("backend timed out")),
+            policy(),
+            Categorizer::new(ErrorCategory::Transient),
+        );
+        assert!(put(&blobstore).is_err());
+        assert_eq!(blobstore.blobstore.puts.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn other_failures_are_not_retried() {
+        let blobstore = RetryingBlobstore::new(
+            FlakyBlobstore::new(1, || ErrorKind::NotFound("key".to_string()).into()),
+            policy(),
+            Categorizer::new(ErrorCategory::Transient).with::<ErrorKind>(),
+        );
+        assert!(put(&blobstore).is_err());
+        assert_eq!(blobstore.blobstore.puts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn unknown_failures_are_not_retried() {
+        let blobstore = RetryingBlobstore::new(
+            FlakyBlobstore::new(1, || err_msg("backend timed out")),
+            policy(),
+            Categorizer::default(),
+        );
+        assert!(put(&blobstore).is_err());
+        assert_eq!(blobstore.blobstore.puts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn backoff_is_jittered_and_capped() {
+        let policy = policy();
+        for _ in 0..100 {
+            assert_eq!(policy.backoff(1), Duration::from_millis(1));
+            let second = policy.backoff(2);
+            assert!(second >= Duration::from_millis(1) && second <= Duration::from_millis(2));
+            let late = policy.backoff(10);
+            assert!(late >= Duration::from_millis(2) && late <= Duration::from_millis(4));
+        }
+    }
+}
@@ -79,6 +79,83 @@ where
     assert!(out.is_none());
 }
 
+fn present<B>(blobstore: B)
+where
+    B: IntoFuture,
+    B::Item: Blobstore,
+    B::Future: Send + 'static,
+    Error: From<B::Error>,
+{
+    let ctx = CoreContext::test_mock();
+    let blobstore = blobstore.into_future().map_err(|err| err.into());
+
+    let foo = "foo".to_string();
+
+    let fut = future::lazy(|| {
+        blobstore.and_then(|blobstore| {
+            blobstore
+                .put(
+                    ctx.clone(),
+                    foo.clone(),
+                    BlobstoreBytes::from_bytes(&b"bar"[..]),
+                )
+                .and_then(move |_| {
+                    blobstore
+                        .is_present(ctx.clone(), foo)
+                        .and_then(move |present| {
+                            blobstore
+                                .is_present(ctx, "missing".to_string())
+                                .map(move |missing| (present, missing))
+                        })
+                })
+        })
+    });
+
+    let mut runtime = Runtime::new().expect("runtime creation failed");
+    let (present, missing) = runtime.block_on(fut).expect("is_present failed");
+
+    assert!(present);
+    assert!(!missing);
+}
+
+fn streaming<B>(blobstore: B)
+where
+    B: IntoFuture,
+    B::Item: Blobstore,
+    B::Future: Send + 'static,
+    Error: From<B::Error>,
+{
+    let ctx = CoreContext::test_mock();
+    let blobstore = blobstore.into_future().map_err(|err| err.into());
+
+    let foo = "foo".to_string();
+    let value = BlobstoreBytes::from_bytes(&b"bar"[..]);
+
+    let fut = future::lazy(|| {
+        blobstore.and_then(|blobstore| {
+            let blobstore: Arc<dyn Blobstore> = Arc::new(blobstore);
+            blobstore
+                .put(ctx.clone(), foo.clone(), value)
+                .and_then({
+                    let (ctx, blobstore) = (ctx.clone(), blobstore.clone());
+                    move |_| blobstore.get_stream(ctx, foo)
+                })
+                .and_then(|value| value.expect("missing").concat2())
+                .and_then(move |value| {
+                    blobstore
+                        .get_stream(ctx, "missing".to_string())
+                        .map(move |missing| (value, missing.is_none()))
+                })
+        })
+    });
+
+    let mut runtime = Runtime::new().expect("runtime creation failed");
+    let (value, missing) = runtime.block_on(fut).expect("streaming get failed");
+
+    assert_eq!(value, Bytes::from_static(b"bar"));
+    assert!(missing);
+}
+
 fn boxable<B>(blobstore: B)
 where
     B: IntoFuture,
@@ -133,6 +210,18 @@ macro_rules! blobstore_test_impl {
                 missing($new_cb(state.clone()));
             }
 
+            #[test]
+            fn test_present() {
+                let state = $state;
+                present($new_cb(state.clone()));
+            }
+
+            #[test]
+            fn test_streaming() {
+                let state = $state;
+                streaming($new_cb(state.clone()));
+            }
+
             #[test]
             fn test_boxable() {
                 let state = $state;
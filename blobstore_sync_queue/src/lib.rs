@@ -91,6 +91,14 @@ pub trait BlobstoreSyncQueue: Send + Sync {
         repo_id: RepositoryId,
         key: String,
     ) -> BoxFuture<Vec<BlobstoreSyncQueueEntry>, Error>;
+
+    /// The number of blobs queued for the repo, and when the oldest of them was added. Blobs stay
+    /// queued until they were written to all blobstores.
+    fn get_backlog(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<(u64, Option<DateTime>), Error>;
 }
 
 impl BlobstoreSyncQueue for Arc<BlobstoreSyncQueue> {
@@ -120,6 +128,14 @@ impl BlobstoreSyncQueue for Arc<BlobstoreSyncQueue> {
     ) -> BoxFuture<Vec<BlobstoreSyncQueueEntry>, Error> {
         (**self).get(ctx, repo_id, key)
     }
+
+    fn get_backlog(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<(u64, Option<DateTime>), Error> {
+        (**self).get_backlog(ctx, repo_id)
+    }
 }
 
 #[derive(Clone)]
@@ -185,6 +201,12 @@ queries! {
          WHERE repo_id = {repo_id}
          AND blobstore_key = {key}"
     }
+
+    read GetBacklog(repo_id: RepositoryId) -> (u64, Option<Timestamp>) {
+        "SELECT COUNT(DISTINCT blobstore_key), MIN(add_timestamp)
+         FROM blobstore_sync_queue
+         WHERE repo_id = {repo_id}"
+    }
 }
 
 impl SqlConstructors for SqlBlobstoreSyncQueue {
@@ -307,4 +329,20 @@ impl BlobstoreSyncQueue for SqlBlobstoreSyncQueue {
             })
             .boxify()
     }
+
+    fn get_backlog(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<(u64, Option<DateTime>), Error> {
+        GetBacklog::query(&self.read_connection, &repo_id)
+            .map(|rows| {
+                rows.into_iter()
+                    .next()
+                    .map_or((0, None), |(count, oldest)| {
+                        (count, oldest.map(DateTime::from))
+                    })
+            })
+            .boxify()
+    }
 }
@@ -51,6 +51,12 @@ fn test_simple() {
         .block_on(queue.add(ctx.clone(), entry2.clone()))
         .expect("Adding yet another entry"));
 
+    // backlog, of blobs rather than entries
+    let backlog = rt
+        .block_on(queue.get_backlog(ctx.clone(), repo_id))
+        .expect("Getting the backlog failed");
+    assert_eq!(backlog, (2, Some(t0)));
+
     // get
     let entries1 = rt
         .block_on(queue.get(ctx.clone(), repo_id, key0.clone()))
@@ -91,5 +97,9 @@ fn test_simple() {
     let entries = rt
         .block_on(queue.iter(ctx.clone(), repo_id, t1, 100))
         .expect("Iterating over entries failed");
-    assert_eq!(entries.len(), 0)
+    assert_eq!(entries.len(), 0);
+    let backlog = rt
+        .block_on(queue.get_backlog(ctx.clone(), repo_id))
+        .expect("Getting the backlog failed");
+    assert_eq!(backlog, (0, None));
 }
@@ -0,0 +1,7 @@
+CREATE TABLE bonsai_hg_mapping (
+  repo_id INT UNSIGNED NOT NULL,
+  hg_cs_id BINARY(20) NOT NULL,
+  bcs_id BINARY(32) NOT NULL,
+  PRIMARY KEY (repo_id, bcs_id),
+  UNIQUE KEY repo_id_hg_cs_id (repo_id, hg_cs_id)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -6,6 +6,7 @@
 
 use super::BonsaiHgMappingEntry;
 pub use failure::{Error, Result};
+use mononoke_errors::{Categorize, ErrorCategory};
 
 #[derive(Debug, Eq, Fail, PartialEq)]
 pub enum ErrorKind {
@@ -19,3 +20,14 @@ pub enum ErrorKind {
     )]
     RaceConditionWithDelete(BonsaiHgMappingEntry),
 }
+
+impl Categorize for ErrorKind {
+    fn category(&self) -> Option<ErrorCategory> {
+        match self {
+            ErrorKind::ConnectionError | ErrorKind::RaceConditionWithDelete(_) => {
+                Some(ErrorCategory::Transient)
+            }
+            ErrorKind::ConflictingEntries(..) => Some(ErrorCategory::Internal),
+        }
+    }
+}
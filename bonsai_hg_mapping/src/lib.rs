@@ -28,6 +28,7 @@ extern crate cloned;
 extern crate context;
 extern crate futures_ext;
 extern crate mercurial_types;
+extern crate mononoke_errors;
 extern crate mononoke_types;
 extern crate rust_thrift;
 #[macro_use]
@@ -40,6 +41,7 @@ use std::collections::HashSet;
 use std::sync::Arc;
 
 use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
 pub use sql_ext::SqlConstructors;
 
 use context::CoreContext;
@@ -252,6 +254,31 @@ impl SqlConstructors for SqlBonsaiHgMapping {
     }
 }
 
+impl SqlMigrations for SqlBonsaiHgMapping {
+    const STORE: &'static str = "bonsai_hg_mapping";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the bonsai_hg_mapping table",
+            sqlite: "CREATE TABLE bonsai_hg_mapping (
+                       repo_id INTEGER NOT NULL,
+                       hg_cs_id BINARY(20) NOT NULL,
+                       bcs_id BINARY(32) NOT NULL,
+                       UNIQUE (repo_id, hg_cs_id),
+                       PRIMARY KEY (repo_id, bcs_id)
+                     );",
+            mysql: "CREATE TABLE bonsai_hg_mapping (
+                      repo_id INT UNSIGNED NOT NULL,
+                      hg_cs_id BINARY(20) NOT NULL,
+                      bcs_id BINARY(32) NOT NULL,
+                      PRIMARY KEY (repo_id, bcs_id),
+                      UNIQUE KEY repo_id_hg_cs_id (repo_id, hg_cs_id)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
 impl BonsaiHgMapping for SqlBonsaiHgMapping {
     fn add(&self, _ctxt: CoreContext, entry: BonsaiHgMappingEntry) -> BoxFuture<bool, Error> {
         STATS::adds.add_value(1);
@@ -0,0 +1,28 @@
+CREATE TABLE bookmarks (
+  repo_id INT UNSIGNED NOT NULL,
+  name VARCHAR(512) NOT NULL,
+  changeset_id VARBINARY(32) NOT NULL,
+  publishing TINYINT(1) NOT NULL DEFAULT '1',
+  pull_default TINYINT(1) NOT NULL DEFAULT '1',
+  PRIMARY KEY (repo_id, name),
+  KEY repo_id_publishing (repo_id, publishing),
+  KEY repo_id_pull_default (repo_id, pull_default)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE bookmarks_update_log (
+  id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  repo_id INT UNSIGNED NOT NULL,
+  name VARCHAR(512) NOT NULL,
+  from_changeset_id VARBINARY(32),
+  to_changeset_id VARBINARY(32),
+  reason ENUM('pushrebase', 'push', 'blobimport', 'manualmove', 'testmove') NOT NULL,
+  timestamp BIGINT NOT NULL,
+  PRIMARY KEY (id)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE bundle_replay_data (
+  bookmark_update_log_id BIGINT UNSIGNED NOT NULL,
+  bundle_handle VARCHAR(256) NOT NULL,
+  commit_hashes_json MEDIUMTEXT NOT NULL,
+  PRIMARY KEY (bookmark_update_log_id)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -35,6 +35,7 @@ use futures::{
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use mononoke_types::Timestamp;
 use sql::{Connection, Transaction as SqlTransaction};
+use sql_ext::migrations::{Migration, SqlMigrations};
 pub use sql_ext::SqlConstructors;
 use stats::Timeseries;
 use std::collections::HashMap;
@@ -189,6 +190,72 @@ impl SqlConstructors for SqlBookmarks {
     }
 }
 
+impl SqlMigrations for SqlBookmarks {
+    const STORE: &'static str = "bookmarks";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the bookmarks, bookmarks_update_log and bundle_replay_data tables",
+            sqlite: "CREATE TABLE bookmarks (
+                       repo_id INT UNSIGNED NOT NULL,
+                       name VARCHAR(512) NOT NULL,
+                       changeset_id VARBINARY(32) NOT NULL,
+                       publishing tinyint(1) NOT NULL DEFAULT '1', --bookmark can be public or scratch'
+                       pull_default tinyint(1) NOT NULL DEFAULT '1', --bookmark can be pulled by default or not'
+                       PRIMARY KEY (repo_id, name)
+                     );
+
+                     CREATE INDEX repo_id_publishing ON bookmarks (repo_id, publishing);
+                     CREATE INDEX repo_id_pull_default ON bookmarks (repo_id, pull_default);
+
+                     CREATE TABLE bookmarks_update_log (
+                       id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                       repo_id INT UNSIGNED NOT NULL,
+                       name VARCHAR(512) NOT NULL,
+                       from_changeset_id VARBINARY(32),
+                       to_changeset_id VARBINARY(32),
+                       reason VARCHAR(32) NOT NULL, -- enum is used in mysql
+                       timestamp BIGINT NOT NULL
+                     );
+
+                     CREATE TABLE bundle_replay_data (
+                       bookmark_update_log_id INTEGER PRIMARY KEY NOT NULL,
+                       bundle_handle VARCHAR(256) NOT NULL,
+                       commit_hashes_json MEDIUMTEXT NOT NULL
+                     );",
+            mysql: "CREATE TABLE bookmarks (
+                      repo_id INT UNSIGNED NOT NULL,
+                      name VARCHAR(512) NOT NULL,
+                      changeset_id VARBINARY(32) NOT NULL,
+                      publishing TINYINT(1) NOT NULL DEFAULT '1',
+                      pull_default TINYINT(1) NOT NULL DEFAULT '1',
+                      PRIMARY KEY (repo_id, name),
+                      KEY repo_id_publishing (repo_id, publishing),
+                      KEY repo_id_pull_default (repo_id, pull_default)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+                    CREATE TABLE bookmarks_update_log (
+                      id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+                      repo_id INT UNSIGNED NOT NULL,
+                      name VARCHAR(512) NOT NULL,
+                      from_changeset_id VARBINARY(32),
+                      to_changeset_id VARBINARY(32),
+                      reason ENUM('pushrebase', 'push', 'blobimport', 'manualmove', 'testmove') NOT NULL,
+                      timestamp BIGINT NOT NULL,
+                      PRIMARY KEY (id)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+                    CREATE TABLE bundle_replay_data (
+                      bookmark_update_log_id BIGINT UNSIGNED NOT NULL,
+                      bundle_handle VARCHAR(256) NOT NULL,
+                      commit_hashes_json MEDIUMTEXT NOT NULL,
+                      PRIMARY KEY (bookmark_update_log_id)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
 impl SqlBookmarks {
     fn list_by_prefix_impl(
         &self,
@@ -745,6 +745,83 @@ fn test_delete_incorrect_hash() {
     assert_eq!(txn.commit().wait().unwrap(), false);
 }
 
+#[test]
+fn test_move_bookmarks() {
+    let ctx = CoreContext::test_mock();
+    let bookmarks = SqlBookmarks::with_sqlite_in_memory().unwrap();
+    let name_1 = create_bookmark("book1");
+    let name_2 = create_bookmark("book2");
+    let name_3 = create_bookmark("book3");
+    let reason = BookmarkUpdateReason::TestMove {
+        bundle_replay_data: None,
+    };
+
+    let mut txn = bookmarks.create_transaction(ctx.clone(), REPO_ZERO);
+    txn.move_bookmark(&name_1, Some(ONES_CSID), None, reason.clone())
+        .unwrap();
+    txn.move_bookmark(&name_2, Some(ONES_CSID), None, reason.clone())
+        .unwrap();
+    assert!(txn.commit().wait().unwrap());
+
+    let mut txn = bookmarks.create_transaction(ctx.clone(), REPO_ZERO);
+    txn.move_bookmark(&name_1, Some(TWOS_CSID), Some(ONES_CSID), reason.clone())
+        .unwrap();
+    txn.move_bookmark(&name_2, None, Some(ONES_CSID), reason.clone())
+        .unwrap();
+    txn.move_bookmark(&name_3, Some(THREES_CSID), None, reason.clone())
+        .unwrap();
+    assert!(txn.commit().wait().unwrap());
+
+    let get = |name: &Bookmark| bookmarks.get(ctx.clone(), name, REPO_ZERO).wait().unwrap();
+    assert_eq!(get(&name_1), Some(TWOS_CSID));
+    assert_eq!(get(&name_2), None);
+    assert_eq!(get(&name_3), Some(THREES_CSID));
+}
+
+#[test]
+fn test_move_bookmarks_atomically() {
+    let ctx = CoreContext::test_mock();
+    let bookmarks = SqlBookmarks::with_sqlite_in_memory().unwrap();
+    let name_1 = create_bookmark("book1");
+    let name_2 = create_bookmark("book2");
+    let name_3 = create_bookmark("book3");
+    let reason = BookmarkUpdateReason::TestMove {
+        bundle_replay_data: None,
+    };
+
+    let mut txn = bookmarks.create_transaction(ctx.clone(), REPO_ZERO);
+    txn.create(&name_1, ONES_CSID, reason.clone()).unwrap();
+    txn.create(&name_2, ONES_CSID, reason.clone()).unwrap();
+    assert!(txn.commit().wait().unwrap());
+
+    // book2 was moved by someone else since it was read at ONES_CSID
+    let mut txn = bookmarks.create_transaction(ctx.clone(), REPO_ZERO);
+    txn.update(&name_2, TWOS_CSID, ONES_CSID, reason.clone())
+        .unwrap();
+    assert!(txn.commit().wait().unwrap());
+
+    // None of the moves happen if one of them fails
+    let mut txn = bookmarks.create_transaction(ctx.clone(), REPO_ZERO);
+    txn.move_bookmark(&name_1, Some(THREES_CSID), Some(ONES_CSID), reason.clone())
+        .unwrap();
+    txn.move_bookmark(&name_2, Some(THREES_CSID), Some(ONES_CSID), reason.clone())
+        .unwrap();
+    txn.move_bookmark(&name_3, Some(THREES_CSID), None, reason.clone())
+        .unwrap();
+    assert_eq!(txn.commit().wait().unwrap(), false);
+
+    let get = |name: &Bookmark| bookmarks.get(ctx.clone(), name, REPO_ZERO).wait().unwrap();
+    assert_eq!(get(&name_1), Some(ONES_CSID));
+    assert_eq!(get(&name_2), Some(TWOS_CSID));
+    assert_eq!(get(&name_3), None);
+    // Nor are they logged
+    assert!(bookmarks
+        .read_next_bookmark_log_entry(ctx.clone(), 3, REPO_ZERO)
+        .wait()
+        .unwrap()
+        .is_none());
+}
+
 #[test]
 fn test_list_by_prefix() {
     let ctx = CoreContext::test_mock();
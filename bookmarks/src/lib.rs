@@ -345,6 +345,25 @@ pub trait Transaction: Send + Sync + 'static {
     /// Deletes bookmark unconditionally.
     fn force_delete(&mut self, key: &Bookmark, reason: BookmarkUpdateReason) -> Result<()>;
 
+    /// Adds the operation that moves a bookmark from `old_cs` to `new_cs`, where None means that
+    /// the bookmark doesn't exist: create(), update() or delete(). Committing the transaction
+    /// fails if the bookmark is not at `old_cs` anymore, so that a move doesn't clobber a
+    /// concurrent one.
+    fn move_bookmark(
+        &mut self,
+        key: &Bookmark,
+        new_cs: Option<ChangesetId>,
+        old_cs: Option<ChangesetId>,
+        reason: BookmarkUpdateReason,
+    ) -> Result<()> {
+        match (new_cs, old_cs) {
+            (Some(new_cs), Some(old_cs)) => self.update(key, new_cs, old_cs, reason),
+            (Some(new_cs), None) => self.create(key, new_cs, reason),
+            (None, Some(old_cs)) => self.delete(key, old_cs, reason),
+            (None, None) => Ok(()),
+        }
+    }
+
     /// Commits the transaction. Future succeeds if transaction has been
     /// successful, or errors if transaction has failed. Logical failure is indicated by
     /// returning a successful `false` value; infrastructure failure is reported via an Error.
@@ -54,10 +54,10 @@ use futures::{Future, IntoFuture, Stream};
 use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
 use maplit::hashmap;
 use mercurial_types::{Changeset, HgChangesetId, MPath};
-use metaconfig_types::PushrebaseParams;
+use metaconfig_types::{CommitRewriter, PushrebaseParams};
 use mononoke_types::{
-    check_case_conflicts, BonsaiChangeset, ChangesetId, DateTime, FileChange, RawBundle2Id,
-    Timestamp,
+    check_case_conflicts, commit_flags::strip_unknown_commit_flags, BonsaiChangeset, ChangesetId,
+    CommitFlags, DateTime, FileChange, RawBundle2Id, Timestamp,
 };
 
 use revset::RangeNodeStream;
@@ -65,6 +65,8 @@ use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::iter::FromIterator;
 
+mod rewrite;
+
 const MAX_REBASE_ATTEMPTS: usize = 100;
 
 #[derive(Debug, Fail)]
@@ -125,6 +127,8 @@ type RebasedChangesets = HashMap<ChangesetId, (ChangesetId, Timestamp)>;
 
 pub struct PushrebaseSuccessResult {
     pub head: ChangesetId,
+    /// Where the bookmark pointed before it was moved to `head`
+    pub old_bookmark_value: Option<ChangesetId>,
     pub retry_num: usize,
     pub rebased_changesets: Vec<ChangesetId>,
 }
@@ -251,6 +255,7 @@ fn rebase_in_loop(
                             Some((head, rebased_changesets)) => {
                                 ok(Loop::Break(PushrebaseSuccessResult {
                                     head,
+                                    old_bookmark_value: bookmark_val,
                                     retry_num,
                                     rebased_changesets,
                                 }))
@@ -507,7 +512,7 @@ fn fetch_bonsai_range(
     cloned!(repo);
     RangeNodeStream::new(
         ctx.clone(),
-        repo.get_changeset_fetcher(),
+        repo.get_changeset_fetcher_for("revsets"),
         ancestor,
         descendant,
     )
@@ -527,7 +532,7 @@ fn find_changed_files(
     cloned!(repo);
     RangeNodeStream::new(
         ctx.clone(),
-        repo.get_changeset_fetcher(),
+        repo.get_changeset_fetcher_for("revsets"),
         ancestor,
         descendant,
     )
@@ -550,33 +555,47 @@ fn find_changed_files(
                 let parents: Vec<_> = bcs.parents().collect();
                 match *parents {
                     [] | [_] => ok(extract_conflict_files_from_bonsai_changeset(bcs)).left_future(),
-                    [p0_id, p1_id] => {
+                    _ => {
                         if reject_merges {
                             return err(PushrebaseError::RebaseOverMerge).left_future();
                         }
-                        match (ids.get(&p0_id), ids.get(&p1_id)) {
-                            (Some(_), Some(_)) => {
-                                // both parents are in the rebase set, so we can just take
-                                // filechanges from bonsai changeset
-                                ok(extract_conflict_files_from_bonsai_changeset(bcs)).left_future()
-                            }
-                            (Some(p_id), None) | (None, Some(p_id)) => {
-                                // TODO(stash, T40460159) - include copy sources in the list of
-                                // conflict files
-
-                                // one of the parents is not in the rebase set, to calculate
-                                // changed files in this case we will compute manifest diff
-                                // between elements that are in rebase set.
-                                find_changed_files_between_manfiests(ctx.clone(), &repo, id, *p_id)
-                                    .right_future()
-                            }
-                            (None, None) => panic!(
+                        let parents_in_range: Vec<_> = parents
+                            .iter()
+                            .filter(|p_id| ids.contains(*p_id))
+                            .cloned()
+                            .collect();
+                        if parents_in_range.is_empty() {
+                            panic!(
                                 "`RangeNodeStream` produced invalid result for: ({}, {})",
                                 descendant, ancestor,
-                            ),
+                            );
+                        }
+                        if parents_in_range.len() == parents.len() {
+                            // all parents are in the rebase set, so we can just take
+                            // filechanges from bonsai changeset
+                            ok(extract_conflict_files_from_bonsai_changeset(bcs)).left_future()
+                        } else {
+                            // TODO(stash, T40460159) - include copy sources in the list of
+                            // conflict files
+
+                            // some of the parents are not in the rebase set, to calculate
+                            // changed files in this case we will compute manifest diffs
+                            // against the parents that are in rebase set.
+                            join_all(parents_in_range.into_iter().map({
+                                cloned!(ctx, repo);
+                                move |p_id| {
+                                    find_changed_files_between_manfiests(
+                                        ctx.clone(),
+                                        &repo,
+                                        id,
+                                        p_id,
+                                    )
+                                }
+                            }))
+                            .map(|changed| changed.into_iter().flatten().collect())
+                            .right_future()
                         }
                     }
-                    _ => panic!("pushrebase supports only two parents"),
                 }
             })
             .collect();
@@ -698,21 +717,23 @@ fn create_rebased_changesets(
     onto: ChangesetId,
 ) -> impl Future<Item = (ChangesetId, RebasedChangesets), Error = PushrebaseError> {
     find_rebased_set(ctx.clone(), repo.clone(), root, head.clone()).and_then(move |rebased_set| {
-        let date = if config.rewritedates {
-            Some(Timestamp::now())
-        } else {
-            None
-        };
+        let now = Timestamp::from(DateTime::new(ctx.now()));
+        let date = if config.rewritedates { Some(now) } else { None };
 
         // rebased_set already sorted in reverse topological order, which guarantees
         // that all required nodes will be updated by the time they are needed
 
         // Create a fake timestamp, it doesn't matter what timestamp root has
-        let mut remapping = hashmap! { root => (onto, Timestamp::now()) };
+        let mut remapping = hashmap! { root => (onto, now) };
         let mut rebased = Vec::new();
         for bcs_old in rebased_set {
             let id_old = bcs_old.get_changeset_id();
-            let bcs_new = match rebase_changeset(bcs_old, &remapping, date.as_ref()) {
+            let bcs_new = match rebase_changeset(
+                bcs_old,
+                &remapping,
+                date.as_ref(),
+                &config.commit_rewriters,
+            ) {
                 Ok(bcs_new) => bcs_new,
                 Err(e) => return err(e.into()).left_future(),
             };
@@ -745,8 +766,21 @@ fn rebase_changeset(
     bcs: BonsaiChangeset,
     remapping: &HashMap<ChangesetId, (ChangesetId, Timestamp)>,
     timestamp: Option<&Timestamp>,
+    rewriters: &[CommitRewriter],
 ) -> Result<BonsaiChangeset> {
+    let pushed = bcs.get_changeset_id();
     let mut bcs = bcs.into_mut();
+    // Commits uploaded before flags were checked at push time may still have unknown ones
+    strip_unknown_commit_flags(&mut bcs.extra);
+    let flags = CommitFlags::from_extras(&bcs.extra)?;
+    let timestamp = if flags.skip_date_rewrite {
+        None
+    } else {
+        timestamp
+    };
+    // Imported commits land as they were pushed
+    let rewriters: &[CommitRewriter] = if flags.import { &[] } else { rewriters };
+
     bcs.parents = bcs
         .parents
         .into_iter()
@@ -767,6 +801,8 @@ fn rebase_changeset(
         bcs.extra.remove(*key);
     }
 
+    rewrite::rewrite_commit(&mut bcs, pushed, rewriters);
+
     // Copy information in bonsai changeset contains a commit parent. So parent changes, then
     // copy information for all copied/moved files needs to be updated
     bcs.file_changes = bcs
@@ -802,7 +838,8 @@ fn find_rebased_set(
     root: ChangesetId,
     head: ChangesetId,
 ) -> impl Future<Item = Vec<BonsaiChangeset>, Error = PushrebaseError> {
-    RangeNodeStream::new(ctx.clone(), repo.get_changeset_fetcher(), root, head)
+    let changeset_fetcher = repo.get_changeset_fetcher_for("revsets");
+    RangeNodeStream::new(ctx.clone(), changeset_fetcher, root, head)
         .map({
             cloned!(repo);
             move |bcs_id| repo.get_bonsai_changeset(ctx.clone(), bcs_id)
@@ -941,13 +978,16 @@ mod tests {
 
     use super::*;
     use async_unit;
+    use context::MockTimeUuidProvider;
     use failure::err_msg;
     use fixtures::{linear, many_files_dirs};
     use futures::future::join_all;
     use futures_ext::spawn_future;
     use maplit::{btreemap, hashset};
+    use mononoke_types::{commit_flags, BonsaiChangesetBuilder};
     use mononoke_types_mocks::hash::AS;
     use std::str::FromStr;
+    use std::sync::Arc;
     use tests_utils::{create_commit, create_commit_with_date, store_files, store_rename};
 
     fn set_bookmark(ctx: CoreContext, repo: BlobRepo, book: &Bookmark, cs_id: &str) {
@@ -1696,6 +1736,191 @@ mod tests {
         })
     }
 
+    #[test]
+    fn pushrebase_rewritedates_frozen_time() {
+        async_unit::tokio_unit_test(|| {
+            let now = DateTime::from_rfc3339("2019-03-01T12:00:00.00Z").unwrap();
+            let ctx = CoreContext::test_mock()
+                .with_time_uuid_provider(Arc::new(MockTimeUuidProvider::new(*now.as_chrono())));
+            let repo = linear::getrepo(None);
+            let root = repo
+                .get_bonsai_from_hg(
+                    ctx.clone(),
+                    HgChangesetId::from_str("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").unwrap(),
+                )
+                .wait()
+                .unwrap()
+                .unwrap();
+            let book = master_bookmark();
+            let bcs = create_commit_with_date(
+                ctx.clone(),
+                repo.clone(),
+                vec![root],
+                store_files(
+                    ctx.clone(),
+                    btreemap! {"file" => Some("data")},
+                    repo.clone(),
+                ),
+                DateTime::from_timestamp(0, 3600).unwrap(),
+            );
+            let hgcss = vec![repo
+                .get_hg_from_bonsai_changeset(ctx.clone(), bcs)
+                .wait()
+                .unwrap()];
+
+            let config = PushrebaseParams {
+                rewritedates: true,
+                ..Default::default()
+            };
+            let result = do_pushrebase(ctx.clone(), repo.clone(), config, book, hgcss, None)
+                .wait()
+                .expect("push-rebase failed");
+
+            let bcs_rebased = repo
+                .get_bonsai_changeset(ctx.clone(), result.head)
+                .wait()
+                .unwrap();
+            // The date is the frozen time, in the timezone of the pushed commit
+            assert_eq!(
+                *bcs_rebased.author_date(),
+                DateTime::from_timestamp(now.timestamp_secs(), 3600).unwrap()
+            );
+        })
+    }
+
+    #[test]
+    fn pushrebase_commit_rewriters() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let repo = linear::getrepo(None);
+            let root = repo
+                .get_bonsai_from_hg(
+                    ctx.clone(),
+                    HgChangesetId::from_str("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").unwrap(),
+                )
+                .wait()
+                .unwrap()
+                .unwrap();
+            let book = master_bookmark();
+            let bcs = create_commit(
+                ctx.clone(),
+                repo.clone(),
+                vec![root],
+                store_files(
+                    ctx.clone(),
+                    btreemap! {"file" => Some("data")},
+                    repo.clone(),
+                ),
+            );
+            let hgcss = vec![repo
+                .get_hg_from_bonsai_changeset(ctx.clone(), bcs)
+                .wait()
+                .unwrap()];
+
+            let config = PushrebaseParams {
+                commit_rewriters: vec![
+                    CommitRewriter::MapUsernames {
+                        map: hashmap! {
+                            "author".to_string() => "Author <author@example.com>".to_string(),
+                        },
+                    },
+                    CommitRewriter::LandedFromTrailer {
+                        trailer: "Landed-From".to_string(),
+                    },
+                ],
+                ..Default::default()
+            };
+            let result = do_pushrebase(ctx.clone(), repo.clone(), config, book, hgcss, None)
+                .wait()
+                .expect("push-rebase failed");
+
+            let bcs_rebased = repo
+                .get_bonsai_changeset(ctx.clone(), result.head)
+                .wait()
+                .unwrap();
+            assert_eq!(bcs_rebased.author(), "Author <author@example.com>");
+            assert_eq!(
+                bcs_rebased.message(),
+                format!("message\n\nLanded-From: {}", bcs).as_str()
+            );
+            let extra: HashMap<_, _> = bcs_rebased.extra().collect();
+            assert_eq!(extra.get("original_author"), Some(&&b"author"[..]));
+            assert_eq!(extra.get("original_message"), Some(&&b"message"[..]));
+        })
+    }
+
+    #[test]
+    fn pushrebase_commit_flags() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let repo = linear::getrepo(None);
+            let root = repo
+                .get_bonsai_from_hg(
+                    ctx.clone(),
+                    HgChangesetId::from_str("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").unwrap(),
+                )
+                .wait()
+                .unwrap()
+                .unwrap();
+            let book = master_bookmark();
+            let file_changes = store_files(
+                ctx.clone(),
+                btreemap! {"file" => Some("data")},
+                repo.clone(),
+            );
+            let mut builder =
+                BonsaiChangesetBuilder::new("author", DateTime::from_timestamp(0, 0).unwrap());
+            builder
+                .set_parents(vec![root])
+                .set_message("message")
+                .add_extra(commit_flags::SKIP_DATE_REWRITE, "1")
+                .add_extra(commit_flags::IMPORT, "1")
+                .add_extra("mononoke:unknown", "1");
+            for (path, file_change) in file_changes {
+                builder.add_file_change(path, file_change.unwrap());
+            }
+            let bcs = builder.freeze().unwrap();
+            let bcs_id = bcs.get_changeset_id();
+            save_bonsai_changesets(vec![bcs.clone()], ctx.clone(), repo.clone())
+                .wait()
+                .unwrap();
+            let hgcss = vec![repo
+                .get_hg_from_bonsai_changeset(ctx.clone(), bcs_id)
+                .wait()
+                .unwrap()];
+
+            let config = PushrebaseParams {
+                rewritedates: true,
+                commit_rewriters: vec![CommitRewriter::MapUsernames {
+                    map: hashmap! {
+                        "author".to_string() => "Author <author@example.com>".to_string(),
+                    },
+                }],
+                ..Default::default()
+            };
+            let result = do_pushrebase(ctx.clone(), repo.clone(), config, book, hgcss, None)
+                .wait()
+                .expect("push-rebase failed");
+
+            let bcs_rebased = repo
+                .get_bonsai_changeset(ctx.clone(), result.head)
+                .wait()
+                .unwrap();
+            // The date is kept, and the imported commit isn't rewritten
+            assert_eq!(bcs_rebased.author_date(), bcs.author_date());
+            assert_eq!(bcs_rebased.author(), "author");
+            // Known flags are kept, unknown ones are stripped
+            let extra: HashMap<_, _> = bcs_rebased.extra().collect();
+            assert_eq!(
+                extra,
+                hashmap! {
+                    commit_flags::SKIP_DATE_REWRITE => &b"1"[..],
+                    commit_flags::IMPORT => &b"1"[..],
+                }
+            );
+        })
+    }
+
     #[test]
     fn pushrebase_case_conflict() {
         async_unit::tokio_unit_test(|| {
@@ -0,0 +1,237 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Rewrites the messages and authors of commits landed by pushrebase, using the rewriters from
+//! the repo's pushrebase config in order. Values that a rewrite changed are kept in the extras of
+//! the landed commit, so that what the client pushed can always be recovered.
+
+use metaconfig_types::{CommitField, CommitRewriter};
+use mononoke_types::{BonsaiChangesetMut, ChangesetId};
+
+pub const ORIGINAL_MESSAGE: &str = "original_message";
+pub const ORIGINAL_AUTHOR: &str = "original_author";
+pub const ORIGINAL_COMMITTER: &str = "original_committer";
+
+/// Apply `rewriters` to a commit that is being rebased. `pushed` is the id of the commit the
+/// client pushed, which is what landed-from trailers refer to.
+pub fn rewrite_commit(
+    bcs: &mut BonsaiChangesetMut,
+    pushed: ChangesetId,
+    rewriters: &[CommitRewriter],
+) {
+    if rewriters.is_empty() {
+        return;
+    }
+
+    let message = bcs.message.clone();
+    let author = bcs.author.clone();
+    let committer = bcs.committer.clone();
+
+    for rewriter in rewriters {
+        match rewriter {
+            CommitRewriter::StripLocalMarkers { markers } => {
+                bcs.message = strip_local_markers(&bcs.message, markers);
+            }
+            CommitRewriter::MapUsernames { map } => {
+                rewrite_users(bcs, |user| map.get(user).cloned());
+            }
+            CommitRewriter::LandedFromTrailer { trailer } => {
+                bcs.message = add_trailer(&bcs.message, trailer, &pushed.to_string());
+            }
+            CommitRewriter::RegexReplace {
+                field,
+                regex,
+                replacement,
+            } => match field {
+                CommitField::Message => {
+                    bcs.message = regex
+                        .replace_all(&bcs.message, replacement.as_str())
+                        .into_owned();
+                }
+                CommitField::Author => rewrite_users(bcs, |user| {
+                    Some(regex.replace_all(user, replacement.as_str()).into_owned())
+                }),
+            },
+        }
+    }
+
+    if bcs.message != message {
+        bcs.extra
+            .insert(ORIGINAL_MESSAGE.to_string(), message.into_bytes());
+    }
+    if bcs.author != author {
+        bcs.extra
+            .insert(ORIGINAL_AUTHOR.to_string(), author.into_bytes());
+    }
+    if let Some(committer) = committer {
+        if bcs.committer.as_ref() != Some(&committer) {
+            bcs.extra
+                .insert(ORIGINAL_COMMITTER.to_string(), committer.into_bytes());
+        }
+    }
+}
+
+fn rewrite_users<F>(bcs: &mut BonsaiChangesetMut, rewrite: F)
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if let Some(author) = rewrite(&bcs.author) {
+        bcs.author = author;
+    }
+    if let Some(ref mut committer) = bcs.committer {
+        if let Some(new_committer) = rewrite(committer) {
+            *committer = new_committer;
+        }
+    }
+}
+
+fn strip_local_markers(message: &str, markers: &[String]) -> String {
+    let lines: Vec<_> = message
+        .lines()
+        .filter(|line| {
+            !markers
+                .iter()
+                .any(|marker| line.starts_with(marker.as_str()))
+        })
+        .collect();
+    // Markers are usually at the end of a message, don't leave empty lines behind them
+    lines.join("\n").trim_end().to_string()
+}
+
+fn add_trailer(message: &str, trailer: &str, value: &str) -> String {
+    let message = message.trim_end();
+    let last_line = message.lines().last().unwrap_or("");
+    // Trailers go in one block at the end of the message
+    let separator = if message.is_empty() {
+        ""
+    } else if is_trailer(last_line) {
+        "\n"
+    } else {
+        "\n\n"
+    };
+    format!("{}{}{}: {}", message, separator, trailer, value)
+}
+
+fn is_trailer(line: &str) -> bool {
+    match line.find(": ") {
+        Some(idx) => {
+            let key = &line[..idx];
+            !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '-')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use maplit::hashmap;
+    use mononoke_types::DateTime;
+    use mononoke_types_mocks::changesetid::ONES_CSID;
+    use regex::Regex;
+    use std::collections::BTreeMap;
+
+    fn commit(author: &str, message: &str) -> BonsaiChangesetMut {
+        BonsaiChangesetMut {
+            parents: vec![],
+            author: author.to_string(),
+            author_date: DateTime::from_timestamp(0, 0).unwrap(),
+            committer: None,
+            committer_date: None,
+            message: message.to_string(),
+            extra: BTreeMap::new(),
+            file_changes: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn rewriters_apply_in_order() {
+        let rewriters = vec![
+            CommitRewriter::StripLocalMarkers {
+                markers: vec!["Local-Only:".to_string()],
+            },
+            CommitRewriter::RegexReplace {
+                field: CommitField::Message,
+                regex: Regex::new(r"^\[WIP\] ").unwrap(),
+                replacement: "".to_string(),
+            },
+            CommitRewriter::MapUsernames {
+                map: hashmap! {
+                    "jdoe".to_string() => "John Doe <jdoe@example.com>".to_string(),
+                },
+            },
+            CommitRewriter::LandedFromTrailer {
+                trailer: "Landed-From".to_string(),
+            },
+        ];
+
+        let mut bcs = commit(
+            "jdoe",
+            "[WIP] fix things\n\nReviewed-By: someone\nLocal-Only: my-branch\n",
+        );
+        rewrite_commit(&mut bcs, ONES_CSID, &rewriters);
+
+        assert_eq!(
+            bcs.message,
+            format!(
+                "fix things\n\nReviewed-By: someone\nLanded-From: {}",
+                ONES_CSID
+            )
+        );
+        assert_eq!(bcs.author, "John Doe <jdoe@example.com>");
+        assert_eq!(
+            bcs.extra.get(ORIGINAL_MESSAGE),
+            Some(&b"[WIP] fix things\n\nReviewed-By: someone\nLocal-Only: my-branch\n".to_vec())
+        );
+        assert_eq!(bcs.extra.get(ORIGINAL_AUTHOR), Some(&b"jdoe".to_vec()));
+        assert_eq!(bcs.extra.get(ORIGINAL_COMMITTER), None);
+    }
+
+    #[test]
+    fn unchanged_values_are_not_recorded() {
+        let rewriters = vec![
+            CommitRewriter::MapUsernames {
+                map: hashmap! {
+                    "jdoe".to_string() => "John Doe <jdoe@example.com>".to_string(),
+                },
+            },
+            CommitRewriter::RegexReplace {
+                field: CommitField::Author,
+                regex: Regex::new("@example.org").unwrap(),
+                replacement: "@example.com".to_string(),
+            },
+        ];
+
+        let mut bcs = commit("Jane <jane@example.com>", "message");
+        bcs.committer = Some("Bob <bob@example.org>".to_string());
+        rewrite_commit(&mut bcs, ONES_CSID, &rewriters);
+
+        assert_eq!(bcs.author, "Jane <jane@example.com>");
+        assert_eq!(bcs.committer, Some("Bob <bob@example.com>".to_string()));
+        assert_eq!(
+            bcs.extra.keys().collect::<Vec<_>>(),
+            vec![ORIGINAL_COMMITTER]
+        );
+    }
+
+    #[test]
+    fn trailers() {
+        assert_eq!(add_trailer("", "Landed-From", "x"), "Landed-From: x");
+        assert_eq!(
+            add_trailer("title\n\nbody.\n", "Landed-From", "x"),
+            "title\n\nbody.\n\nLanded-From: x"
+        );
+        assert_eq!(
+            add_trailer("title\n\nTest Plan: ran it", "Landed-From", "x"),
+            "title\n\nTest Plan: ran it\n\nLanded-From: x"
+        );
+        assert_eq!(
+            add_trailer("title\n\nDifferential-Revision: D1", "Landed-From", "x"),
+            "title\n\nDifferential-Revision: D1\nLanded-From: x"
+        );
+    }
+}
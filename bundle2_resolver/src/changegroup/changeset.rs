@@ -44,7 +44,10 @@ where
             Ok((
                 HgChangesetId::new(chunk.node),
                 RevlogChangeset::new(HgBlobNode::new(
-                    HgBlob::from(Bytes::from(delta::apply(b"", &chunk.delta)?)),
+                    HgBlob::from(Bytes::from(
+                        delta::apply(b"", &chunk.delta)
+                            .context(ErrorKind::CorruptDelta(chunk.node))?,
+                    )),
                     chunk.p1.into_option(),
                     chunk.p2.into_option(),
                 ))?,
@@ -57,10 +60,12 @@ where
 mod tests {
     use super::*;
 
+    use failure::Context;
     use futures::stream::iter_ok;
     use futures::Future;
     use itertools::equal;
     use mercurial_types::HgNodeHash;
+    use mercurial_types_mocks::nodehash::ONES_HASH;
 
     enum CheckResult {
         ExpectedOk(bool),
@@ -109,6 +114,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn corrupt_delta() {
+        let node = ONES_HASH;
+        // Replaces bytes of an empty base
+        let delta = delta::Delta::new(vec![delta::Fragment {
+            start: 0,
+            end: 10,
+            content: b"changeset".to_vec(),
+        }])
+        .unwrap();
+        let chunk = CgDeltaChunk {
+            node,
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            base: NULL_HASH,
+            linknode: node,
+            delta,
+            flags: None,
+        };
+
+        let err = convert_to_revlog_changesets(iter_ok(vec![ChangesetDeltaed { chunk }]))
+            .collect()
+            .wait()
+            .unwrap_err();
+        let kind = err
+            .downcast_ref::<Context<ErrorKind>>()
+            .map(|err| err.get_context());
+        match kind {
+            Some(ErrorKind::CorruptDelta(corrupt)) => assert_eq!(*corrupt, node),
+            _ => panic!("unexpected error: {:?}", err),
+        }
+    }
+
     quickcheck! {
         fn null_changeset_random(
             node: HgNodeHash,
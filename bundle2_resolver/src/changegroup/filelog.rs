@@ -23,9 +23,10 @@ use blobrepo::{
 };
 use mercurial::file::File;
 use mercurial_bundles::changegroup::CgDeltaChunk;
+use mercurial_types::manifest::Entry;
 use mercurial_types::{
-    delta, parse_rev_flags, Delta, FileType, HgFileNodeId, HgNodeHash, HgNodeKey, MPath, RepoPath,
-    RevFlags, NULL_HASH,
+    delta, parse_rev_flags, Delta, FileType, HgBlob, HgFileNodeId, HgManifestId, HgNodeHash,
+    HgNodeKey, MPath, RepoPath, RevFlags, NULL_HASH,
 };
 
 use errors::*;
@@ -41,7 +42,18 @@ pub struct FilelogDeltaed {
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum FilelogData {
     RawBytes(Bytes),
-    LfsMetaData(ContentBlobMeta),
+    /// Metadata of LFS content, and its size
+    LfsMetaData(ContentBlobMeta, u64),
+}
+
+impl FilelogData {
+    /// Bytes of file content, including the content of LFS files that was uploaded apart
+    pub fn size(&self) -> u64 {
+        match self {
+            FilelogData::RawBytes(bytes) => bytes.len() as u64,
+            FilelogData::LfsMetaData(_, size) => *size,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -75,7 +87,7 @@ impl UploadableHgBlob for Filelog {
         // If LFSMetaData
         let contents = match self.data {
             FilelogData::RawBytes(bytes) => UploadHgFileContents::RawBytes(bytes),
-            FilelogData::LfsMetaData(meta) => UploadHgFileContents::ContentUploaded(meta),
+            FilelogData::LfsMetaData(meta, _) => UploadHgFileContents::ContentUploaded(meta),
         };
 
         let upload = UploadHgFileEntry {
@@ -157,7 +169,7 @@ fn generate_lfs_meta_data(
     ctx: CoreContext,
     repo: Arc<BlobRepo>,
     data: Bytes,
-) -> impl Future<Item = ContentBlobMeta, Error = Error> {
+) -> impl Future<Item = (ContentBlobMeta, u64), Error = Error> {
     // TODO(anastasiyaz): check size
     File::data_only(data)
         .get_lfs_content()
@@ -166,11 +178,15 @@ fn generate_lfs_meta_data(
             (
                 repo.get_file_content_id_by_alias(ctx, lfs_content.oid()),
                 Ok(lfs_content.copy_from()),
+                Ok(lfs_content.size()),
             )
         })
-        .map(move |(content_id, copy_from)| ContentBlobMeta {
-            id: content_id,
-            copy_from,
+        .map(move |(content_id, copy_from, size)| {
+            let meta = ContentBlobMeta {
+                id: content_id,
+                copy_from,
+            };
+            (meta, size)
         })
 }
 
@@ -182,15 +198,18 @@ fn get_filelog_data(
 ) -> impl Future<Item = FilelogData, Error = Error> {
     if flags.contains(RevFlags::REVIDX_EXTSTORED) {
         generate_lfs_meta_data(ctx, repo, data)
-            .map(|cbmeta| FilelogData::LfsMetaData(cbmeta))
+            .map(|(cbmeta, size)| FilelogData::LfsMetaData(cbmeta, size))
             .left_future()
     } else {
         Ok(FilelogData::RawBytes(data)).into_future().right_future()
     }
 }
 
-struct DeltaCache {
+/// Applies the deltas of a changegroup section to get fulltexts. Bases are either earlier entries
+/// of the same section or entries stored in the repo.
+pub(super) struct DeltaCache {
     repo: Arc<BlobRepo>,
+    trees: bool,
     bytes_cache: HashMap<HgNodeHash, Shared<BoxFuture<Bytes, Compat<Error>>>>,
 }
 
@@ -198,11 +217,30 @@ impl DeltaCache {
     fn new(repo: Arc<BlobRepo>) -> Self {
         Self {
             repo,
+            trees: false,
             bytes_cache: HashMap::new(),
         }
     }
 
-    fn decode(
+    pub(super) fn new_for_trees(repo: Arc<BlobRepo>) -> Self {
+        Self {
+            repo,
+            trees: true,
+            bytes_cache: HashMap::new(),
+        }
+    }
+
+    fn get_raw_content(&self, ctx: CoreContext, node: HgNodeHash) -> BoxFuture<HgBlob, Error> {
+        if self.trees {
+            self.repo
+                .get_root_entry(HgManifestId::new(node))
+                .get_raw_content(ctx)
+        } else {
+            self.repo.get_raw_hg_content(ctx, HgFileNodeId::new(node))
+        }
+    }
+
+    pub(super) fn decode(
         &mut self,
         ctx: CoreContext,
         node: HgNodeHash,
@@ -218,7 +256,7 @@ impl DeltaCache {
 
                 let vec = match base {
                     None => delta::apply(b"", &delta)
-                        .with_context(|_| format!("File content empty, delta: {:?}", delta))
+                        .context(ErrorKind::CorruptDelta(node))
                         .map_err(Error::from)
                         .map_err(Error::compat)
                         .into_future()
@@ -230,21 +268,16 @@ impl DeltaCache {
                                 .map_err(Error::from)
                                 .and_then(move |bytes| {
                                     delta::apply(&bytes, &delta)
-                                        .with_context(|_| {
-                                            format!("File content: {:?} delta: {:?}", bytes, delta)
-                                        })
+                                        .context(ErrorKind::CorruptDelta(node))
                                         .map_err(Error::from)
                                 })
                                 .boxify(),
                             None => self
-                                .repo
-                                .get_raw_hg_content(ctx, HgFileNodeId::new(base))
+                                .get_raw_content(ctx, base)
                                 .and_then(move |blob| {
                                     let bytes = blob.into_inner();
                                     delta::apply(bytes.as_ref(), &delta)
-                                        .with_context(|_| {
-                                            format!("File content: {:?} delta: {:?}", bytes, delta)
-                                        })
+                                        .context(ErrorKind::CorruptDelta(node))
                                         .map_err(Error::from)
                                 })
                                 .boxify(),
@@ -0,0 +1,166 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+
+use context::CoreContext;
+use futures::{Future, IntoFuture, Stream};
+use futures_ext::{BoxStream, FutureExt, StreamExt};
+
+use blobrepo::BlobRepo;
+use mercurial_bundles::changegroup::CgDeltaChunk;
+use mercurial_types::{parse_rev_flags, HgNodeKey, RepoPath, RevFlags};
+use wirepack::TreemanifestEntry;
+
+use changegroup::filelog::DeltaCache;
+use errors::*;
+
+/// A tree manifest sent in a version 03 changegroup: root trees come in the manifest section,
+/// the other trees in the directory groups that follow it.
+#[derive(Debug, Eq, PartialEq)]
+pub struct TreemanifestDeltaed {
+    pub path: RepoPath,
+    pub chunk: CgDeltaChunk,
+}
+
+pub fn convert_to_revlog_manifests<S>(
+    ctx: CoreContext,
+    repo: Arc<BlobRepo>,
+    deltaed: S,
+) -> BoxStream<TreemanifestEntry, Error>
+where
+    S: Stream<Item = TreemanifestDeltaed, Error = Error> + Send + 'static,
+{
+    let mut delta_cache = DeltaCache::new_for_trees(repo);
+    deltaed
+        .and_then(move |TreemanifestDeltaed { path, chunk }| {
+            let CgDeltaChunk {
+                node,
+                base,
+                delta,
+                p1,
+                p2,
+                linknode: _,
+                flags,
+            } = chunk;
+
+            // Only file contents can be stored externally
+            let flags = try_boxfuture!(parse_rev_flags(flags));
+            if flags != RevFlags::REVIDX_DEFAULT_FLAGS {
+                return Err(format_err!(
+                    "tree manifest {} for {} has unexpected flags {:?}",
+                    node,
+                    path,
+                    flags
+                ))
+                .into_future()
+                .boxify();
+            }
+
+            delta_cache
+                .decode(ctx.clone(), node, base.into_option(), delta)
+                .and_then({
+                    cloned!(path);
+                    move |data| {
+                        let node_key = HgNodeKey { path, hash: node };
+                        TreemanifestEntry::new(node_key, data, p1, p2)
+                    }
+                })
+                .with_context(move |_| {
+                    format!(
+                        "While decoding delta cache for tree id {}, path {}",
+                        node, path
+                    )
+                })
+                .from_err()
+                .boxify()
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use futures::stream::iter_ok;
+
+    use blobrepo_factory::new_memblob_empty;
+    use mercurial::manifest::ManifestContent;
+    use mercurial_types::{Delta, HgNodeHash, MPath, NULL_HASH};
+    use mercurial_types_mocks::nodehash::*;
+
+    fn chunk(node: HgNodeHash, base: HgNodeHash, delta: Delta, flags: u16) -> CgDeltaChunk {
+        CgDeltaChunk {
+            node,
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            base,
+            linknode: ONES_HASH,
+            delta,
+            flags: Some(flags),
+        }
+    }
+
+    #[test]
+    fn deltas_against_earlier_trees() {
+        let ctx = CoreContext::test_mock();
+        let repo = Arc::new(new_memblob_empty(None, None).unwrap());
+        let content = format!("file\0{}\n", ONES_HASH);
+        let dir = RepoPath::dir(MPath::new("dir").unwrap()).unwrap();
+
+        let deltaed = vec![
+            TreemanifestDeltaed {
+                path: dir.clone(),
+                chunk: chunk(
+                    TWOS_HASH,
+                    NULL_HASH,
+                    Delta::new_fulltext(content.clone()),
+                    0,
+                ),
+            },
+            // Same content as its base
+            TreemanifestDeltaed {
+                path: dir.clone(),
+                chunk: chunk(THREES_HASH, TWOS_HASH, Delta::new(vec![]).unwrap(), 0),
+            },
+        ];
+
+        let entries = convert_to_revlog_manifests(ctx, repo, iter_ok(deltaed))
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].node_key.path, dir);
+        assert_eq!(entries[0].data, Bytes::from(content.clone()));
+        assert_eq!(
+            entries[0].manifest_content,
+            ManifestContent::parse(content.as_bytes()).unwrap()
+        );
+        assert_eq!(entries[1].node_key.hash, THREES_HASH);
+        assert_eq!(entries[1].data, entries[0].data);
+    }
+
+    #[test]
+    fn external_trees_are_rejected() {
+        let ctx = CoreContext::test_mock();
+        let repo = Arc::new(new_memblob_empty(None, None).unwrap());
+        let deltaed = vec![TreemanifestDeltaed {
+            path: RepoPath::root(),
+            chunk: chunk(
+                TWOS_HASH,
+                NULL_HASH,
+                Delta::new_fulltext(vec![]),
+                RevFlags::REVIDX_EXTSTORED.bits(),
+            ),
+        }];
+
+        let res = convert_to_revlog_manifests(ctx, repo, iter_ok(deltaed))
+            .collect()
+            .wait();
+        assert!(res.is_err());
+    }
+}
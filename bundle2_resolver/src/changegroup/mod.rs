@@ -6,8 +6,10 @@
 
 mod changeset;
 mod filelog;
+mod manifest;
 mod split;
 
 pub(crate) use self::changeset::convert_to_revlog_changesets;
 pub(crate) use self::filelog::convert_to_revlog_filelog;
-pub(crate) use self::split::split_changegroup;
+pub(crate) use self::manifest::convert_to_revlog_manifests;
+pub(crate) use self::split::{split_changegroup, split_tree_changegroup};
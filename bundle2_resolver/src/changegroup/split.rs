@@ -4,13 +4,15 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use futures::{Async, Future, Poll, Stream};
+use futures::{stream, Async, Future, Poll, Stream};
 use futures_ext::{BoxStream, StreamExt};
 
 use mercurial_bundles::changegroup::{Part, Section};
+use mercurial_types::RepoPath;
 
 use changegroup::changeset::ChangesetDeltaed;
 use changegroup::filelog::FilelogDeltaed;
+use changegroup::manifest::TreemanifestDeltaed;
 use errors::*;
 
 pub fn split_changegroup<S>(
@@ -19,6 +21,36 @@ pub fn split_changegroup<S>(
     BoxStream<ChangesetDeltaed, Error>,
     BoxStream<FilelogDeltaed, Error>,
 )
+where
+    S: Stream<Item = Part, Error = Error> + Send + 'static,
+{
+    let (changesets, _manifests, filelogs) = split(cg2s, false);
+    (changesets, filelogs)
+}
+
+/// Like `split_changegroup`, for version 03 changegroups that carry tree manifests. Manifests
+/// have to be consumed before filelogs.
+pub fn split_tree_changegroup<S>(
+    cg3s: S,
+) -> (
+    BoxStream<ChangesetDeltaed, Error>,
+    BoxStream<TreemanifestDeltaed, Error>,
+    BoxStream<FilelogDeltaed, Error>,
+)
+where
+    S: Stream<Item = Part, Error = Error> + Send + 'static,
+{
+    split(cg3s, true)
+}
+
+fn split<S>(
+    cg2s: S,
+    treemanifest: bool,
+) -> (
+    BoxStream<ChangesetDeltaed, Error>,
+    BoxStream<TreemanifestDeltaed, Error>,
+    BoxStream<FilelogDeltaed, Error>,
+)
 where
     S: Stream<Item = Part, Error = Error> + Send + 'static,
 {
@@ -43,25 +75,71 @@ where
         })
         .boxify();
 
-    let filelogs = remainder
+    let remainder = remainder
         .from_err()
         .map(|take_while_stream| take_while_stream.into_inner())
-        .flatten_stream()
-        .skip_while({
-            let mut seen_manifest_end = false;
-            move |part| match part {
-                &Part::SectionEnd(Section::Manifest) if !seen_manifest_end => {
-                    seen_manifest_end = true;
-                    Ok(true)
+        .flatten_stream();
+
+    let (manifests, remainder) = if treemanifest {
+        let (manifests, remainder) = remainder
+            .take_while(|part| match part {
+                &Part::CgChunk(Section::Manifest, _)
+                | &Part::SectionEnd(Section::Manifest)
+                | &Part::CgChunk(Section::TreemanifestDir(_), _)
+                | &Part::SectionEnd(Section::TreemanifestDir(_)) => Ok(true),
+                &Part::SectionEnd(Section::Treemanifest) => Ok(false),
+                bad => bail_msg!("Expected Treemanifest chunk or end, found: {:?}", bad),
+            })
+            .return_remainder();
+
+        let manifests = manifests
+            .filter_map(|part| match part {
+                Part::CgChunk(Section::Manifest, chunk) => Some(TreemanifestDeltaed {
+                    path: RepoPath::root(),
+                    chunk,
+                }),
+                Part::CgChunk(Section::TreemanifestDir(path), chunk) => Some(TreemanifestDeltaed {
+                    path: RepoPath::DirectoryPath(path),
+                    chunk,
+                }),
+                _ => None,
+            })
+            .map_err(|err| {
+                err.context("While extracting Treemanifests from Changegroup")
+                    .into()
+            })
+            .boxify();
+
+        let remainder = remainder
+            .from_err()
+            .map(|take_while_stream| take_while_stream.into_inner())
+            .flatten_stream()
+            .boxify();
+
+        (manifests, remainder)
+    } else {
+        let remainder = remainder
+            .skip_while({
+                let mut seen_manifest_end = false;
+                move |part| match part {
+                    &Part::SectionEnd(Section::Manifest) if !seen_manifest_end => {
+                        seen_manifest_end = true;
+                        Ok(true)
+                    }
+                    _ if seen_manifest_end => Ok(false),
+                    bad => bail_msg!("Expected Manifest end, found: {:?}", bad),
                 }
-                _ if seen_manifest_end => Ok(false),
-                bad => bail_msg!("Expected Manifest end, found: {:?}", bad),
-            }
-        })
-        .map_err(|err| {
-            err.context("While skipping Manifests in Changegroup")
-                .into()
-        })
+            })
+            .map_err(|err| {
+                err.context("While skipping Manifests in Changegroup")
+                    .into()
+            })
+            .boxify();
+
+        (stream::empty().boxify(), remainder)
+    };
+
+    let filelogs = remainder
         .and_then({
             let mut seen_path = None;
             move |part| {
@@ -92,6 +170,11 @@ where
                         Ok(None)
                     }
                     Part::SectionEnd(Section::Treemanifest) => Ok(None),
+                    Part::CgChunk(Section::TreemanifestDir(_), _)
+                    | Part::SectionEnd(Section::TreemanifestDir(_)) => bail_msg!(
+                        "Changegroup has tree manifests but isn't marked as a treemanifest \
+                         changegroup: pushes mixing flat and tree manifests are not supported"
+                    ),
                     // Checking that there is exactly one Part::end is is covered by CheckEnd
                     // wrapper
                     Part::End if seen_path.is_none() => Ok(None),
@@ -120,7 +203,7 @@ where
         .filter_map(|x| x)
         .boxify();
 
-    (changesets, filelogs)
+    (changesets, manifests, filelogs)
 }
 
 /// Wrapper for Stream of Part that is supposed to ensure that there is exactly one Part::End in
@@ -456,4 +539,73 @@ mod tests {
             assert!(fs.collect().wait().is_err());
         }
     }
+
+    quickcheck! {
+        fn splitting_tree_changegroup(
+            c: CgDeltaChunk,
+            m: CgDeltaChunk,
+            f: CgDeltaChunk,
+            dir: MPath,
+            f_p: MPath
+        ) -> bool {
+            let (cs, ms, fs) = split_tree_changegroup(iter_ok(
+                vec![
+                    Part::CgChunk(Section::Changeset, c.clone()),
+                    Part::SectionEnd(Section::Changeset),
+                    Part::CgChunk(Section::Manifest, m.clone()),
+                    Part::SectionEnd(Section::Manifest),
+                    Part::CgChunk(Section::TreemanifestDir(dir.clone()), m.clone()),
+                    Part::SectionEnd(Section::TreemanifestDir(dir.clone())),
+                    Part::SectionEnd(Section::Treemanifest),
+                    Part::CgChunk(Section::Filelog(f_p.clone()), f.clone()),
+                    Part::SectionEnd(Section::Filelog(f_p.clone())),
+                    Part::End,
+                ].into_iter(),
+            ));
+
+            let cs = cs.collect().wait().expect("error in changesets");
+            let ms = ms.collect().wait().expect("error in manifests");
+            let fs = fs.collect().wait().expect("error in filelogs");
+
+            equal(cs, vec![ChangesetDeltaed { chunk: c.clone() }])
+                && equal(
+                    ms,
+                    vec![
+                        TreemanifestDeltaed {
+                            path: RepoPath::root(),
+                            chunk: m.clone(),
+                        },
+                        TreemanifestDeltaed {
+                            path: RepoPath::DirectoryPath(dir.clone()),
+                            chunk: m.clone(),
+                        },
+                    ],
+                )
+                && equal(
+                    fs,
+                    vec![FilelogDeltaed {
+                        path: f_p.clone(),
+                        chunk: f.clone(),
+                    }],
+                )
+        }
+
+        fn splitting_mixed_manifests(c: CgDeltaChunk, m: CgDeltaChunk, dir: MPath) -> bool {
+            // Tree manifests in a changegroup that isn't marked as a treemanifest one
+            let (cs, fs) = split_changegroup(iter_ok(
+                vec![
+                    Part::CgChunk(Section::Changeset, c.clone()),
+                    Part::SectionEnd(Section::Changeset),
+                    Part::CgChunk(Section::Manifest, m.clone()),
+                    Part::SectionEnd(Section::Manifest),
+                    Part::CgChunk(Section::TreemanifestDir(dir.clone()), m.clone()),
+                    Part::SectionEnd(Section::TreemanifestDir(dir.clone())),
+                    Part::SectionEnd(Section::Treemanifest),
+                    Part::End,
+                ].into_iter(),
+            ));
+
+            cs.collect().wait().is_ok() && fs.collect().wait().is_err()
+        }
+    }
 }
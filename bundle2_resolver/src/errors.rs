@@ -8,8 +8,10 @@ use std::collections::HashSet;
 
 pub use failure::prelude::*;
 
+use blobrepo;
 use bookmarks::Bookmark;
-use mercurial_types::HgChangesetId;
+use mercurial_types::{HgChangesetId, HgNodeHash};
+use mononoke_errors::{Categorize, Categorizer, ErrorCategory};
 use mononoke_types::ChangesetId;
 
 #[derive(Debug, Fail)]
@@ -34,4 +36,43 @@ pub enum ErrorKind {
     PushrebaseNoCommonRoot(Bookmark, HashSet<ChangesetId>),
     #[fail(display = "Repo is marked as read-only: {}", _0)]
     RepoReadOnly(String),
+    #[fail(display = "Invalid commit flags in changeset {}", _0)]
+    InvalidCommitFlags(HgChangesetId),
+    #[fail(display = "Push quota exceeded: {}", _0)]
+    PushQuotaExceeded(String),
+    #[fail(display = "Corrupt delta received for {}", _0)]
+    CorruptDelta(HgNodeHash),
+    #[fail(
+        display = "Only phases admins may make {} public, as no publishing bookmark points to it \
+                   or to its descendants",
+        _0
+    )]
+    PhasePushDenied(HgChangesetId),
+}
+
+impl Categorize for ErrorKind {
+    fn category(&self) -> Option<ErrorCategory> {
+        use self::ErrorKind::*;
+
+        match self {
+            BonsaiNotFoundForHgChangeset(_) | PushrebaseBookmarkNotFound(_) => {
+                Some(ErrorCategory::NotFound)
+            }
+            MalformedTreemanifestPart(_)
+            | PushrebaseTooManyHeads
+            | PushrebaseNoCommonRoot(..)
+            | RepoReadOnly(_)
+            | InvalidCommitFlags(_)
+            | PushQuotaExceeded(_)
+            | CorruptDelta(_)
+            | PhasePushDenied(_) => Some(ErrorCategory::InvalidRequest),
+            // The upload failed because of its cause
+            WhileUploadingData(_) => None,
+        }
+    }
+}
+
+/// Knows the errors of pushes, and of the repo they go to
+pub fn error_categorizer() -> Categorizer {
+    blobrepo::error_categorizer().with::<ErrorKind>()
 }
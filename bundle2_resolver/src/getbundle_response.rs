@@ -32,24 +32,30 @@ pub fn create_getbundle_response(
     lca_hint: Arc<LeastCommonAncestorsHint>,
     phases_hint: Option<Arc<Phases>>,
 ) -> Result<Vec<PartEncodeBuilder>> {
+    let commits = find_commits_to_send(
+        ctx.clone(),
+        blobrepo.clone(),
+        common,
+        heads.clone(),
+        lca_hint,
+    )?;
+    create_getbundle_response_for_commits(ctx, blobrepo, heads, commits, phases_hint)
+}
+
+/// The commits that are ancestors of `heads` but not of `common`, oldest first, which is the
+/// order they are sent to the client in.
+pub fn find_commits_to_send(
+    ctx: CoreContext,
+    blobrepo: BlobRepo,
+    common: Vec<HgChangesetId>,
+    heads: Vec<HgChangesetId>,
+    lca_hint: Arc<LeastCommonAncestorsHint>,
+) -> Result<BoxFuture<Vec<ChangesetId>, Error>> {
     if common.is_empty() {
         return Err(err_msg("no 'common' heads specified. Pull will be very inefficient. Please use hg clone instead"));
     }
 
-    let changesets_buffer_size = 1000; // TODO(stash): make it configurable
-    let heads_len = heads.len();
-
-    let phases_part = if let Some(phases_hint) = phases_hint {
-        // Phases were requested
-        Some(parts::phases_part(
-            ctx.clone(),
-            prepare_phases_stream(ctx.clone(), blobrepo.clone(), heads.clone(), phases_hint),
-        ))
-    } else {
-        None
-    };
-
-    let blobrepo = Arc::new(blobrepo.clone());
+    let blobrepo = Arc::new(blobrepo);
     let common_heads: HashSet<_> = HashSet::from_iter(common.iter());
 
     let heads = hg_to_bonsai_stream(
@@ -72,7 +78,7 @@ pub fn create_getbundle_response(
             .collect(),
     );
 
-    let changeset_fetcher = blobrepo.get_changeset_fetcher();
+    let changeset_fetcher = blobrepo.get_changeset_fetcher_for("revsets");
     let nodes_to_send = heads
         .join(excludes)
         .map({
@@ -90,17 +96,41 @@ pub fn create_getbundle_response(
         .flatten_stream();
 
     // TODO(stash): avoid collecting all the changelogs in the vector - T25767311
-    let nodes_to_send = nodes_to_send
+    Ok(nodes_to_send
         .collect()
-        .inspect({
-            cloned!(ctx);
-            move |nodes| {
-                ctx.perf_counters()
-                    .add_to_counter("getbundle_num_commits", nodes.len() as i64);
-            }
+        .map(move |mut nodes| {
+            ctx.perf_counters()
+                .add_to_counter("getbundle_num_commits", nodes.len() as i64);
+            nodes.reverse();
+            nodes
         })
-        .map(|nodes| stream::iter_ok(nodes.into_iter().rev()))
-        .flatten_stream();
+        .boxify())
+}
+
+/// Like `create_getbundle_response`, but sends `commits` rather than finding which commits the
+/// client is missing. `commits` must be in the order `find_commits_to_send` returns.
+pub fn create_getbundle_response_for_commits(
+    ctx: CoreContext,
+    blobrepo: BlobRepo,
+    heads: Vec<HgChangesetId>,
+    commits: BoxFuture<Vec<ChangesetId>, Error>,
+    phases_hint: Option<Arc<Phases>>,
+) -> Result<Vec<PartEncodeBuilder>> {
+    let changesets_buffer_size = 1000; // TODO(stash): make it configurable
+    let heads_len = heads.len();
+
+    let phases_part = if let Some(phases_hint) = phases_hint {
+        // Phases were requested
+        Some(parts::phases_part(
+            ctx.clone(),
+            prepare_phases_stream(ctx.clone(), blobrepo.clone(), heads, phases_hint),
+        ))
+    } else {
+        None
+    };
+
+    let blobrepo = Arc::new(blobrepo);
+    let nodes_to_send = commits.map(stream::iter_ok).flatten_stream();
 
     let changelogentries = nodes_to_send
         .map({
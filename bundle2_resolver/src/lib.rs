@@ -25,6 +25,7 @@ extern crate futures;
 extern crate futures_ext;
 extern crate futures_stats;
 extern crate heapsize;
+extern crate hg_derivation_queue;
 #[cfg(test)]
 extern crate itertools;
 #[cfg(not(test))]
@@ -32,23 +33,36 @@ extern crate quickcheck;
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
+extern crate push_usage;
 extern crate pushrebase;
 extern crate reachabilityindex;
 extern crate revset;
 extern crate scuba_ext;
+#[cfg(test)]
+extern crate skiplist;
+#[cfg(test)]
+extern crate sshrelay;
 #[macro_use]
 extern crate slog;
 #[macro_use]
 extern crate stats as stats_crate;
 #[cfg(test)]
 extern crate tests_utils;
+extern crate tokio;
 extern crate tokio_io;
+#[cfg(test)]
+extern crate tracing;
+#[cfg(test)]
+extern crate uuid;
+extern crate webhook_dispatcher;
 
 extern crate blobrepo;
 extern crate blobrepo_factory;
 extern crate bonsai_utils;
 extern crate bookmarks;
+extern crate content_refs;
 extern crate context;
+extern crate event_bus;
 extern crate hooks;
 extern crate mercurial;
 extern crate mercurial_bundles;
@@ -56,6 +70,7 @@ extern crate mercurial_types;
 #[cfg(test)]
 extern crate mercurial_types_mocks;
 extern crate metaconfig_types;
+extern crate mononoke_errors;
 extern crate mononoke_types;
 extern crate phases;
 extern crate wirepack;
@@ -67,5 +82,8 @@ mod resolver;
 mod stats;
 mod upload_blobs;
 
-pub use getbundle_response::create_getbundle_response;
+pub use getbundle_response::{
+    create_getbundle_response, create_getbundle_response_for_commits, find_commits_to_send,
+};
+pub use errors::error_categorizer;
 pub use resolver::resolve;
@@ -4,46 +4,61 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Cursor;
 use std::ops::AddAssign;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use ascii::AsciiString;
 use blobrepo::{
     BlobRepo, ChangesetHandle, ChangesetMetadata, ContentBlobInfo, CreateChangeset, HgBlobEntry,
 };
-use bookmarks::{Bookmark, BookmarkUpdateReason, BundleReplayData, Transaction};
+use bookmarks::{Bookmark, BookmarkUpdateReason, BundleReplayData};
 use bytes::{Bytes, BytesMut};
+use content_refs::{ContentRef, ContentRefsIndex};
 use context::CoreContext;
 use failure::{err_msg, Compat, FutureFailureErrorExt, StreamFailureErrorExt};
-use futures::future::{self, err, ok, Shared};
+use futures::future::{self, err, loop_fn, ok, Loop, Shared};
 use futures::stream;
 use futures::{Future, IntoFuture, Stream};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use futures_stats::Timed;
 use getbundle_response;
+use hg_derivation_queue::{HgDerivationQueue, HgDerivationQueueEntry};
 use mercurial::changeset::RevlogChangeset;
 use mercurial::manifest::{Details, ManifestContent};
+use mercurial_bundles::changegroup::CG_PART_VERSION_HEADER_NAME;
 use mercurial_bundles::{
-    create_bundle_stream, parts, Bundle2EncodeBuilder, Bundle2Item, PartHeaderType,
+    create_bundle_stream, parts, Bundle2EncodeBuilder, Bundle2Item, PartHeader, PartHeaderType,
 };
 use mercurial_types::{
-    HgChangesetId, HgManifestId, HgNodeHash, HgNodeKey, MPath, RepoPath, NULL_HASH,
+    HgChangesetId, HgFileNodeId, HgManifestId, HgNodeHash, HgNodeKey, HgPhase, MPath, RepoPath,
+    NULL_HASH,
 };
 use metaconfig_types::{BookmarkOrRegex, PushrebaseParams, RepoReadOnly};
-use mononoke_types::{BlobstoreValue, ChangesetId, RawBundle2, RawBundle2Id};
+use mononoke_types::{
+    BlobstoreValue, BonsaiChangeset, ChangesetId, CommitFlags, ContentId, DateTime, RawBundle2,
+    RawBundle2Id,
+};
+use push_usage::{PushQuota, QuotaCheck};
 use pushrebase;
 use reachabilityindex::LeastCommonAncestorsHint;
+use revset::DifferenceOfUnionsOfAncestorsNodeStream;
 use scribe_commit_queue::{self, ScribeCommitQueue};
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 use stats::*;
 
-use changegroup::{convert_to_revlog_changesets, convert_to_revlog_filelog, split_changegroup};
+use changegroup::{
+    convert_to_revlog_changesets, convert_to_revlog_filelog, convert_to_revlog_manifests,
+    split_changegroup, split_tree_changegroup,
+};
 use errors::*;
+use event_bus::{EventBus, RepoEvent};
 use hooks::{ChangesetHookExecutionID, FileHookExecutionID, HookExecution, HookManager};
 use phases::{Phase, Phases};
 use upload_blobs::{upload_hg_blobs, UploadBlobsType, UploadableHgBlob};
+use webhook_dispatcher::WebhookDispatcher;
 use wirepack::{TreemanifestBundle2Parser, TreemanifestEntry};
 
 type PartId = u32;
@@ -53,6 +68,11 @@ type ContentBlobs = HashMap<HgNodeKey, ContentBlobInfo>;
 type Manifests = HashMap<HgNodeKey, <TreemanifestEntry as UploadableHgBlob>::Value>;
 type UploadedChangesets = HashMap<HgChangesetId, ChangesetHandle>;
 
+/// At most this many of the changesets that landed on a bookmark get post-commit hooks queued
+const MAX_POST_COMMIT_CHANGESETS: usize = 1000;
+/// Number of hg changesets derived at once to queue post-commit hooks
+const POST_COMMIT_DERIVE_CONCURRENCY: usize = 10;
+
 /// The resolve function takes a bundle2, interprets it's content as Changesets, Filelogs and
 /// Manifests and uploades all of them to the provided BlobRepo in the correct order.
 /// It returns a Future that contains the response that should be send back to the requester.
@@ -66,8 +86,14 @@ pub fn resolve(
     hook_manager: Arc<HookManager>,
     lca_hint: Arc<LeastCommonAncestorsHint>,
     phases_hint: Arc<Phases>,
+    hg_derivation_queue: Option<Arc<HgDerivationQueue>>,
+    webhook_dispatcher: Arc<WebhookDispatcher>,
+    event_bus: EventBus,
     readonly: RepoReadOnly,
     maybe_full_content: Option<Arc<Mutex<Bytes>>>,
+    push_quota: PushQuota,
+    content_refs: Option<ContentRefsIndex>,
+    phases_admin_identities: Vec<String>,
 ) -> BoxFuture<Bytes, Error> {
     let resolver = Bundle2Resolver::new(
         ctx.clone(),
@@ -75,6 +101,12 @@ pub fn resolve(
         pushrebase,
         fastforward_only_bookmarks,
         hook_manager,
+        hg_derivation_queue,
+        webhook_dispatcher,
+        event_bus,
+        push_quota,
+        content_refs,
+        phases_admin_identities,
     );
     let bundle2 = resolver.resolve_start_and_replycaps(bundle2);
 
@@ -135,6 +167,7 @@ pub fn resolve(
                             allow_non_fast_forward,
                             maybe_full_content,
                             lca_hint,
+                            phases_hint,
                         )
                     } else {
                         resolve_pushrebase(
@@ -156,6 +189,7 @@ pub fn resolve(
                         allow_non_fast_forward,
                         maybe_full_content,
                         lca_hint,
+                        phases_hint,
                     )
                 }
             },
@@ -170,6 +204,7 @@ fn resolve_push(
     allow_non_fast_forward: bool,
     maybe_full_content: Option<Arc<Mutex<Bytes>>>,
     lca_hint: Arc<LeastCommonAncestorsHint>,
+    phases_hint: Arc<Phases>,
 ) -> BoxFuture<Bytes, Error> {
     resolver
         .maybe_resolve_changegroup(ctx.clone(), bundle2)
@@ -179,70 +214,88 @@ fn resolve_push(
                 resolver
                     .resolve_multiple_parts(bundle2, Bundle2Resolver::maybe_resolve_pushkey)
                     .map(move |(pushkeys, bundle2)| {
-                        let bookmark_push: Vec<_> = pushkeys
-                            .into_iter()
-                            .filter_map(|pushkey| match pushkey {
-                                Pushkey::Phases => None,
-                                Pushkey::BookmarkPush(bp) => Some(bp),
-                            })
-                            .collect();
+                        // Infinitepush commits stay draft whatever the client says
+                        let draft = cg_push.as_ref().map_or(false, |cg_push| cg_push.draft);
+                        let public_heads = if draft {
+                            vec![]
+                        } else {
+                            public_heads(&pushkeys)
+                        };
+
+                        let mut bookmark_push = vec![];
+                        let mut phase_push = vec![];
+                        for pushkey in pushkeys {
+                            match pushkey {
+                                Pushkey::BookmarkPush(bp) => bookmark_push.push(bp),
+                                Pushkey::PhasePush(pp) => phase_push.push(pp),
+                                Pushkey::PhaseHeads(_) => {}
+                            }
+                        }
 
                         STATS::bookmark_pushkeys_count.add_value(bookmark_push.len() as i64);
 
-                        (cg_push, bookmark_push, bundle2)
+                        let phases = (public_heads, phase_push);
+                        (cg_push, bookmark_push, phases, bundle2)
                     })
             }
         })
         .and_then({
             cloned!(ctx, resolver);
-            move |(cg_push, bookmark_push, bundle2)| {
-                if let Some(cg_push) = cg_push {
+            move |(cg_push, bookmark_push, phases, bundle2)| {
+                if let Some(mut cg_push) = cg_push {
+                    let cg_manifests = cg_push.manifests.take();
                     resolver
-                        .resolve_b2xtreegroup2(ctx, bundle2)
-                        .map(|(manifests, bundle2)| {
-                            (Some((cg_push, manifests)), bookmark_push, bundle2)
+                        .maybe_resolve_b2xtreegroup2(ctx, bundle2)
+                        .and_then(move |(tg_manifests, bundle2)| {
+                            let manifests = pushed_manifests(tg_manifests, cg_manifests)?;
+                            Ok::<_, Error>((
+                                Some((cg_push, manifests)),
+                                bookmark_push,
+                                phases,
+                                bundle2,
+                            ))
                         })
                         .boxify()
                 } else {
-                    ok((None, bookmark_push, bundle2)).boxify()
+                    ok((None, bookmark_push, phases, bundle2)).boxify()
                 }
             }
         })
         .and_then({
             cloned!(ctx, resolver);
-            move |(cg_and_manifests, bookmark_push, bundle2)| {
+            move |(cg_and_manifests, bookmark_push, phases, bundle2)| {
                 if let Some((cg_push, manifests)) = cg_and_manifests {
                     let changegroup_id = Some(cg_push.part_id);
                     resolver
                         .upload_changesets(ctx, cg_push, manifests)
-                        .map(move |()| (changegroup_id, bookmark_push, bundle2))
+                        .map(move |()| (changegroup_id, bookmark_push, phases, bundle2))
                         .boxify()
                 } else {
-                    ok((None, bookmark_push, bundle2)).boxify()
+                    ok((None, bookmark_push, phases, bundle2)).boxify()
                 }
             }
         })
         .and_then({
             cloned!(resolver);
-            move |(changegroup_id, bookmark_push, bundle2)| {
+            move |(changegroup_id, bookmark_push, phases, bundle2)| {
                 resolver
                     .maybe_resolve_infinitepush_bookmarks(bundle2)
-                    .map(move |((), bundle2)| (changegroup_id, bookmark_push, bundle2))
+                    .map(move |((), bundle2)| (changegroup_id, bookmark_push, phases, bundle2))
             }
         })
         .and_then({
             cloned!(resolver);
-            move |(changegroup_id, bookmark_push, bundle2)| {
+            move |(changegroup_id, bookmark_push, phases, bundle2)| {
                 resolver
                     .ensure_stream_finished(bundle2, maybe_full_content)
                     .map(move |maybe_raw_bundle2_id| {
-                        (changegroup_id, bookmark_push, maybe_raw_bundle2_id)
+                        (changegroup_id, bookmark_push, phases, maybe_raw_bundle2_id)
                     })
             }
         })
         .and_then({
             cloned!(resolver);
-            move |(changegroup_id, bookmark_push, maybe_raw_bundle2_id)| {
+            move |(changegroup_id, bookmark_push, phases, maybe_raw_bundle2_id)| {
                 (move || {
                     let bookmark_ids: Vec<_> = bookmark_push.iter().map(|bp| bp.part_id).collect();
                     let reason = BookmarkUpdateReason::Push {
@@ -257,15 +310,31 @@ fn resolve_push(
                             lca_hint,
                             allow_non_fast_forward,
                         )
-                        .map(move |()| (changegroup_id, bookmark_ids))
+                        .map(move |()| (changegroup_id, bookmark_ids, phases))
                         .boxify()
                 })()
                 .context("While updating Bookmarks")
                 .from_err()
             }
         })
-        .and_then(move |(changegroup_id, bookmark_ids)| {
-            resolver.prepare_push_response(changegroup_id, bookmark_ids)
+        .and_then({
+            cloned!(ctx, resolver);
+            move |(changegroup_id, bookmark_ids, (public_heads, phase_push))| {
+                // Bookmarks are moved first, so that the commits they point to are already public
+                // and walking the ancestors of the public heads stops early
+                let mut pushkey_ids = bookmark_ids;
+                pushkey_ids.extend(phase_push.iter().map(|pp| pp.part_id));
+                let mut heads = public_heads;
+                heads.extend(phase_push.iter().map(|pp| pp.node));
+                resolver
+                    .publish(ctx, phases_hint, heads)
+                    .map(move |()| (changegroup_id, pushkey_ids))
+                    .context("While updating Phases")
+                    .from_err()
+            }
+        })
+        .and_then(move |(changegroup_id, pushkey_ids)| {
+            resolver.prepare_push_response(changegroup_id, pushkey_ids)
         })
         .context("bundle2_resolver error")
         .from_err()
@@ -283,7 +352,7 @@ fn resolve_pushrebase(
     maybe_full_content: Option<Arc<Mutex<Bytes>>>,
 ) -> BoxFuture<Bytes, Error> {
     resolver
-        .resolve_b2xtreegroup2(ctx.clone(), bundle2)
+        .maybe_resolve_b2xtreegroup2(ctx.clone(), bundle2)
         .and_then({
             cloned!(ctx, resolver);
             move |(manifests, bundle2)| {
@@ -292,11 +361,10 @@ fn resolve_pushrebase(
                     .map(move |(cg_push, bundle2)| (cg_push, manifests, bundle2))
             }
         })
-        .and_then(|(cg_push, manifests, bundle2)| {
-            cg_push
-                .ok_or(err_msg("Empty pushrebase"))
-                .into_future()
-                .map(move |cg_push| (cg_push, manifests, bundle2))
+        .and_then(|(cg_push, tg_manifests, bundle2)| {
+            let mut cg_push = cg_push.ok_or(err_msg("Empty pushrebase"))?;
+            let manifests = pushed_manifests(tg_manifests, cg_push.manifests.take())?;
+            Ok::<_, Error>((cg_push, manifests, bundle2))
         })
         .and_then(
             |(cg_push, manifests, bundle2)| match cg_push.mparams.get("onto").cloned() {
@@ -334,7 +402,9 @@ fn resolve_pushrebase(
                             let bookmark_pushes: Vec<_> = pushkeys
                                 .into_iter()
                                 .filter_map(|pushkey| match pushkey {
-                                    Pushkey::Phases => None,
+                                    // The pushed commits are rebased, and it's the rebased ones
+                                    // that are made public once pushrebase succeeds
+                                    Pushkey::PhasePush(_) | Pushkey::PhaseHeads(_) => None,
                                     Pushkey::BookmarkPush(bp) => Some(bp),
                                 })
                                 .collect();
@@ -410,7 +480,7 @@ fn resolve_pushrebase(
                     })
                     .and_then(move |()| {
                         resolver
-                            .pushrebase(ctx, changesets.clone(), &onto_params, maybe_raw_bundle2_id)
+                            .pushrebase(ctx, changesets, &onto_params, maybe_raw_bundle2_id)
                             .map(move |pushrebased_rev| {
                                 (pushrebased_rev, onto_params, bookmark_push_part_id)
                             })
@@ -419,23 +489,60 @@ fn resolve_pushrebase(
         })
         .and_then(
             move |(
-                (pushrebased_rev, pushrebased_changesets),
+                (pushrebased_rev, old_bookmark_value, pushrebased_changesets),
                 onto_params,
                 bookmark_push_part_id,
             )| {
+                resolver.webhook_dispatcher.notify(
+                    ctx.clone(),
+                    resolver.repo.clone(),
+                    lca_hint.clone(),
+                    onto_params.bookmark.clone(),
+                    old_bookmark_value,
+                    Some(pushrebased_rev),
+                );
+                resolver.publish_event(RepoEvent::BookmarkMoved {
+                    repo_id: resolver.repo.get_repoid(),
+                    bookmark: onto_params.bookmark.to_string(),
+                    from: old_bookmark_value,
+                    to: Some(pushrebased_rev),
+                });
+                let post_commit_hooks_queued = resolver.queue_post_commit_hooks(
+                    ctx.clone(),
+                    &onto_params.bookmark,
+                    stream::iter_ok(pushrebased_changesets.clone()).boxify(),
+                );
+
                 // TODO: (dbudischek) T41565649 log pushed changesets as well, not only pushrebased
                 resolver
-                    .log_commits_to_scribe(ctx.clone(), pushrebased_changesets)
-                    .and_then(move |_| {
-                        resolver.prepare_pushrebase_response(
-                            ctx,
-                            commonheads,
-                            pushrebased_rev,
-                            onto_params.bookmark,
-                            lca_hint,
-                            phases_hint,
-                            bookmark_push_part_id,
-                        )
+                    .log_commits_to_scribe(
+                        ctx.clone(),
+                        &onto_params.bookmark,
+                        pushrebased_changesets.clone(),
+                    )
+                    .join(post_commit_hooks_queued)
+                    .and_then(move |_| match resolver.hg_derivation_queue.clone() {
+                        Some(queue) => resolver
+                            .prepare_deferred_pushrebase_response(
+                                ctx,
+                                queue,
+                                pushrebased_rev,
+                                pushrebased_changesets,
+                                phases_hint,
+                                bookmark_push_part_id,
+                            )
+                            .left_future(),
+                        None => resolver
+                            .prepare_pushrebase_response(
+                                ctx,
+                                commonheads,
+                                pushrebased_rev,
+                                onto_params.bookmark,
+                                lca_hint,
+                                phases_hint,
+                                bookmark_push_part_id,
+                            )
+                            .right_future(),
                     })
             },
         )
@@ -450,6 +557,7 @@ fn resolve_bookmark_only_pushrebase(
     allow_non_fast_forward: bool,
     maybe_full_content: Option<Arc<Mutex<Bytes>>>,
     lca_hint: Arc<LeastCommonAncestorsHint>,
+    phases_hint: Arc<Phases>,
 ) -> BoxFuture<Bytes, Error> {
     // TODO: we probably run hooks even if no changesets are pushed?
     //       however, current run_hooks implementation will no-op such thing
@@ -458,11 +566,19 @@ fn resolve_bookmark_only_pushrebase(
         .and_then({
             cloned!(resolver);
             move |(pushkeys, bundle2)| {
+                let public_heads = public_heads(&pushkeys);
+                let pushkeys: Vec<_> = pushkeys
+                    .into_iter()
+                    .filter(|pushkey| match pushkey {
+                        Pushkey::PhaseHeads(_) => false,
+                        _ => true,
+                    })
+                    .collect();
                 let pushkeys_len = pushkeys.len();
                 let bookmark_pushes: Vec<_> = pushkeys
                     .into_iter()
                     .filter_map(|pushkey| match pushkey {
-                        Pushkey::Phases => None,
+                        Pushkey::PhasePush(_) | Pushkey::PhaseHeads(_) => None,
                         Pushkey::BookmarkPush(bp) => Some(bp),
                     })
                     .collect();
@@ -484,14 +600,16 @@ fn resolve_bookmark_only_pushrebase(
                 let bookmark_push = bookmark_pushes.into_iter().nth(0).unwrap();
                 resolver
                     .ensure_stream_finished(bundle2, maybe_full_content)
-                    .map(move |maybe_raw_bundle2_id| (bookmark_push, maybe_raw_bundle2_id))
+                    .map(move |maybe_raw_bundle2_id| {
+                        (bookmark_push, public_heads, maybe_raw_bundle2_id)
+                    })
                     .boxify()
             }
         })
         //TODO (ikostia, T40115672): add hook run here. Make sure hooks run even without changesets
         .and_then({
-            cloned!(resolver);
-            move |(bookmark_push, maybe_raw_bundle2_id)| {
+            cloned!(ctx, resolver);
+            move |(bookmark_push, public_heads, maybe_raw_bundle2_id)| {
                 let part_id = bookmark_push.part_id;
                 let pushes = vec![bookmark_push];
                 let reason = BookmarkUpdateReason::Pushrebase {
@@ -500,7 +618,11 @@ fn resolve_bookmark_only_pushrebase(
                 };
                 resolver
                     .resolve_bookmark_pushes(pushes, reason, lca_hint, allow_non_fast_forward)
-                    .and_then(move |_| ok(part_id).boxify())
+                    .and_then({
+                        cloned!(resolver);
+                        move |()| resolver.publish(ctx, phases_hint, public_heads)
+                    })
+                    .and_then(move |()| ok(part_id).boxify())
             }
         })
         .and_then({
@@ -521,19 +643,48 @@ fn next_item(
 struct ChangegroupPush {
     part_id: PartId,
     changesets: Changesets,
+    /// Tree manifests sent in the changegroup itself rather than in a b2x:treegroup2 part
+    manifests: Option<Manifests>,
     filelogs: Filelogs,
     content_blobs: ContentBlobs,
     mparams: HashMap<String, Bytes>,
     draft: bool,
+    /// Bytes of file content in the push, which count against the quota of the pusher
+    pushed_bytes: u64,
 }
 
 struct CommonHeads {
     heads: Vec<HgChangesetId>,
 }
 
+#[derive(Debug)]
 enum Pushkey {
     BookmarkPush(BookmarkPush),
-    Phases,
+    PhasePush(PhasePush),
+    /// Content of a phase-heads part. Newer clients send it instead of phases pushkeys.
+    PhaseHeads(Vec<(HgChangesetId, HgPhase)>),
+}
+
+/// Heads that the client wants to be public. Draft heads need no action: commits are draft until
+/// something makes them public, and a public commit never goes back to draft.
+fn public_heads(pushkeys: &[Pushkey]) -> Vec<HgChangesetId> {
+    pushkeys
+        .iter()
+        .filter_map(|pushkey| match pushkey {
+            Pushkey::PhaseHeads(heads) => Some(heads),
+            _ => None,
+        })
+        .flatten()
+        .filter(|(_, phase)| *phase == HgPhase::Public)
+        .map(|(head, _)| *head)
+        .collect()
+}
+
+/// A phases pushkey, which older clients send to make a commit public
+#[derive(Debug)]
+struct PhasePush {
+    part_id: PartId,
+    node: HgChangesetId,
 }
 
 #[derive(Debug)]
@@ -592,6 +743,12 @@ struct Bundle2Resolver {
     fastforward_only_bookmarks: Vec<BookmarkOrRegex>,
     hook_manager: Arc<HookManager>,
     scribe_commit_queue: Arc<ScribeCommitQueue>,
+    hg_derivation_queue: Option<Arc<HgDerivationQueue>>,
+    webhook_dispatcher: Arc<WebhookDispatcher>,
+    event_bus: EventBus,
+    push_quota: PushQuota,
+    content_refs: Option<ContentRefsIndex>,
+    phases_admin_identities: Arc<Vec<String>>,
 }
 
 impl Bundle2Resolver {
@@ -601,6 +758,12 @@ impl Bundle2Resolver {
         pushrebase: PushrebaseParams,
         fastforward_only_bookmarks: Vec<BookmarkOrRegex>,
         hook_manager: Arc<HookManager>,
+        hg_derivation_queue: Option<Arc<HgDerivationQueue>>,
+        webhook_dispatcher: Arc<WebhookDispatcher>,
+        event_bus: EventBus,
+        push_quota: PushQuota,
+        content_refs: Option<ContentRefsIndex>,
+        phases_admin_identities: Vec<String>,
     ) -> Self {
         let scribe_commit_queue = match pushrebase.commit_scribe_category.clone() {
             Some(category) => Arc::new(scribe_commit_queue::LogToScribe::new_with_default_scribe(
@@ -616,6 +779,12 @@ impl Bundle2Resolver {
             fastforward_only_bookmarks,
             hook_manager,
             scribe_commit_queue,
+            hg_derivation_queue,
+            webhook_dispatcher,
+            event_bus,
+            push_quota,
+            content_refs,
+            phases_admin_identities: Arc::new(phases_admin_identities),
         }
     }
 
@@ -634,46 +803,81 @@ impl Bundle2Resolver {
 
         let bookmarks_push_fut = bookmark_pushes
             .into_iter()
-            .map(move |bp| {
-                BonsaiBookmarkPush::new(ctx.clone(), &repo, bp).and_then({
-                    cloned!(repo, ctx, lca_hint, fastforward_only_bookmarks);
-                    move |bp| {
-                        check_bookmark_push_allowed(
-                            ctx.clone(),
-                            repo.clone(),
-                            fastforward_only_bookmarks.clone(),
-                            allow_non_fast_forward,
-                            bp,
-                            lca_hint,
-                        )
-                    }
-                })
+            .map({
+                cloned!(lca_hint);
+                move |bp| {
+                    BonsaiBookmarkPush::new(ctx.clone(), &repo, bp).and_then({
+                        cloned!(repo, ctx, lca_hint, fastforward_only_bookmarks);
+                        move |bp| {
+                            check_bookmark_push_allowed(
+                                ctx.clone(),
+                                repo.clone(),
+                                fastforward_only_bookmarks.clone(),
+                                allow_non_fast_forward,
+                                bp,
+                                lca_hint,
+                            )
+                        }
+                    })
+                }
             })
             .collect::<Vec<_>>();
 
         future::join_all(bookmarks_push_fut).and_then({
             cloned!(resolver);
             move |bonsai_bookmark_pushes| {
+                let moves: Vec<_> = bonsai_bookmark_pushes
+                    .iter()
+                    .map(|bp| (bp.name.clone(), bp.old, bp.new))
+                    .collect();
                 let mut txn = resolver
                     .repo
                     .update_bookmark_transaction(resolver.ctx.clone());
                 for bp in bonsai_bookmark_pushes {
-                    try_boxfuture!(add_bookmark_to_transaction(&mut txn, bp, reason.clone(),));
+                    try_boxfuture!(txn.move_bookmark(&bp.name, bp.new, bp.old, reason.clone()));
                 }
                 txn.commit()
-                    .and_then(|ok| {
-                        if ok {
-                            Ok(())
-                        } else {
-                            Err(format_err!("Bookmark transaction failed"))
+                    .and_then(move |ok| {
+                        if !ok {
+                            return Err(format_err!("Bookmark transaction failed"))
+                                .into_future()
+                                .left_future();
                         }
+                        let mut post_commit_hooks_queued = vec![];
+                        for (name, old, new) in moves {
+                            post_commit_hooks_queued.push(resolver.queue_moved_post_commit_hooks(
+                                lca_hint.clone(),
+                                &name,
+                                old,
+                                new,
+                            ));
+                            let event = RepoEvent::BookmarkMoved {
+                                repo_id: resolver.repo.get_repoid(),
+                                bookmark: name.to_string(),
+                                from: old,
+                                to: new,
+                            };
+                            resolver.webhook_dispatcher.notify(
+                                resolver.ctx.clone(),
+                                resolver.repo.clone(),
+                                lca_hint.clone(),
+                                name,
+                                old,
+                                new,
+                            );
+                            resolver.publish_event(event);
+                        }
+                        future::join_all(post_commit_hooks_queued)
+                            .map(|_| ())
+                            .right_future()
                     })
                     .boxify()
             }
         })
     }
 
-    /// Peek at the next `bundle2` item and check if it is a `Pushkey` part
+    /// Peek at the next `bundle2` item and check if it is a `Pushkey` part, or a `PhaseHeads` part
+    /// which is sent together with pushkeys
     /// Return unchanged `bundle2`
     fn is_next_part_pushkey(
         &self,
@@ -682,17 +886,11 @@ impl Bundle2Resolver {
         next_item(bundle2)
             .and_then(|(start, bundle2)| match start {
                 Some(part) => {
-                    if let Bundle2Item::Pushkey(header, box_future) = part {
-                        ok((
-                            true,
-                            stream::once(Ok(Bundle2Item::Pushkey(header, box_future)))
-                                .chain(bundle2)
-                                .boxify(),
-                        ))
-                        .boxify()
-                    } else {
-                        ok((false, stream::once(Ok(part)).chain(bundle2).boxify())).boxify()
-                    }
+                    let pushkey_next = match part {
+                        Bundle2Item::Pushkey(..) | Bundle2Item::PhaseHeads(..) => true,
+                        _ => false,
+                    };
+                    ok((pushkey_next, stream::once(Ok(part)).chain(bundle2).boxify())).boxify()
                 }
                 _ => ok((false, bundle2)).boxify(),
             })
@@ -808,36 +1006,74 @@ impl Bundle2Resolver {
                 | Some(Bundle2Item::B2xRebase(header, parts)) => {
                     let part_id = header.part_id();
                     let draft = *header.part_type() == PartHeaderType::B2xInfinitepush;
-                    let (c, f) = split_changegroup(parts);
+                    let pushed_bytes = Arc::new(AtomicUsize::new(0));
+                    let treemanifest = try_boxfuture!(is_treemanifest_changegroup(&header));
+                    let (c, m, f) = if treemanifest {
+                        let (c, m, f) = split_tree_changegroup(parts);
+                        (c, Some(m), f)
+                    } else {
+                        let (c, f) = split_changegroup(parts);
+                        (c, None, f)
+                    };
                     convert_to_revlog_changesets(c)
                         .collect()
-                        .and_then(move |changesets| {
-                            upload_hg_blobs(
-                                ctx.clone(),
-                                Arc::new(repo.clone()),
-                                convert_to_revlog_filelog(ctx.clone(), Arc::new(repo), f),
-                                UploadBlobsType::EnsureNoDuplicates,
-                            )
-                            .map(move |upload_map| {
-                                let mut filelogs = HashMap::new();
-                                let mut content_blobs = HashMap::new();
-                                for (node_key, (cbinfo, file_upload)) in upload_map {
-                                    filelogs.insert(node_key.clone(), file_upload);
-                                    content_blobs.insert(node_key, cbinfo);
-                                }
-                                (changesets, filelogs, content_blobs)
-                            })
-                            .context("While uploading File Blobs")
-                            .from_err()
+                        .and_then({
+                            cloned!(ctx, repo);
+                            move |changesets| match m {
+                                Some(m) => upload_hg_blobs(
+                                    ctx.clone(),
+                                    Arc::new(repo.clone()),
+                                    convert_to_revlog_manifests(ctx, Arc::new(repo), m),
+                                    UploadBlobsType::IgnoreDuplicates,
+                                )
+                                .context("While uploading Manifest Blobs")
+                                .from_err()
+                                .map(move |manifests| (changesets, Some(manifests)))
+                                .left_future(),
+                                None => ok((changesets, None)).right_future(),
+                            }
+                        })
+                        .and_then({
+                            cloned!(pushed_bytes);
+                            move |(changesets, manifests)| {
+                                let filelogs = convert_to_revlog_filelog(
+                                    ctx.clone(),
+                                    Arc::new(repo.clone()),
+                                    f,
+                                )
+                                .inspect(move |filelog| {
+                                    pushed_bytes
+                                        .fetch_add(filelog.data.size() as usize, Ordering::Relaxed);
+                                });
+                                upload_hg_blobs(
+                                    ctx.clone(),
+                                    Arc::new(repo),
+                                    filelogs,
+                                    UploadBlobsType::EnsureNoDuplicates,
+                                )
+                                .map(move |upload_map| {
+                                    let mut filelogs = HashMap::new();
+                                    let mut content_blobs = HashMap::new();
+                                    for (node_key, (cbinfo, file_upload)) in upload_map {
+                                        filelogs.insert(node_key.clone(), file_upload);
+                                        content_blobs.insert(node_key, cbinfo);
+                                    }
+                                    (changesets, manifests, filelogs, content_blobs)
+                                })
+                                .context("While uploading File Blobs")
+                                .from_err()
+                            }
                         })
-                        .map(move |(changesets, filelogs, content_blobs)| {
+                        .map(move |(changesets, manifests, filelogs, content_blobs)| {
                             let cg_push = ChangegroupPush {
                                 part_id,
                                 changesets,
+                                manifests,
                                 filelogs,
                                 content_blobs,
                                 mparams: header.mparams().clone(),
                                 draft,
+                                pushed_bytes: pushed_bytes.load(Ordering::Relaxed) as u64,
                             };
                             (Some(cg_push), bundle2)
                         })
@@ -851,7 +1087,7 @@ impl Bundle2Resolver {
             .boxify()
     }
 
-    /// Parses pushkey part if it exists
+    /// Parses pushkey or phase-heads part if it exists
     /// Returns an error if the pushkey namespace is unknown
     fn maybe_resolve_pushkey(
         &self,
@@ -866,7 +1102,27 @@ impl Bundle2Resolver {
                         .ok_or(format_err!("pushkey: `namespace` parameter is not set")));
 
                     let pushkey = match &namespace[..] {
-                        b"phases" => Pushkey::Phases,
+                        b"phases" => {
+                            let mparams = header.mparams();
+                            let node = try_boxfuture!(get_optional_changeset_param(mparams, "key"));
+                            let node = try_boxfuture!(
+                                node.ok_or(format_err!("pushkey: `key` parameter is empty"))
+                            );
+                            // Mercurial phases are numbered from public, which is 0
+                            let new = try_boxfuture!(get_ascii_param(mparams, "new"));
+                            if new.as_str() != "0" {
+                                return err(format_err!(
+                                    "pushkey: commits can only be made public, not phase {}",
+                                    new
+                                ))
+                                .boxify();
+                            }
+
+                            Pushkey::PhasePush(PhasePush {
+                                part_id: header.part_id(),
+                                node,
+                            })
+                        }
                         b"bookmarks" => {
                             let part_id = header.part_id();
                             let mparams = header.mparams();
@@ -893,6 +1149,10 @@ impl Bundle2Resolver {
 
                     emptypart.map(move |_| (Some(pushkey), bundle2)).boxify()
                 }
+                Some(Bundle2Item::PhaseHeads(_header, heads)) => heads
+                    .collect()
+                    .map(move |heads| (Some(Pushkey::PhaseHeads(heads)), bundle2))
+                    .boxify(),
                 Some(part) => ok((None, stream::once(Ok(part)).chain(bundle2).boxify())).boxify(),
                 None => ok((None, bundle2)).boxify(),
             })
@@ -901,14 +1161,15 @@ impl Bundle2Resolver {
             .boxify()
     }
 
-    /// Parse b2xtreegroup2.
+    /// Parse b2xtreegroup2 if it exists. It is absent when tree manifests are sent in the
+    /// changegroup.
     /// The Manifests should be scheduled for uploading to BlobRepo and the Future resolving in
     /// their upload as well as their parsed content should be used for uploading changesets.
-    fn resolve_b2xtreegroup2(
+    fn maybe_resolve_b2xtreegroup2(
         &self,
         ctx: CoreContext,
         bundle2: BoxStream<Bundle2Item, Error>,
-    ) -> BoxFuture<(Manifests, BoxStream<Bundle2Item, Error>), Error> {
+    ) -> BoxFuture<(Option<Manifests>, BoxStream<Bundle2Item, Error>), Error> {
         let repo = self.repo.clone();
 
         next_item(bundle2)
@@ -922,9 +1183,10 @@ impl Bundle2Resolver {
                 )
                 .context("While uploading Manifest Blobs")
                 .from_err()
-                .map(move |manifests| (manifests, bundle2))
+                .map(move |manifests| (Some(manifests), bundle2))
                 .boxify(),
-                _ => err(format_err!("Expected Bundle2 B2xTreegroup2")).boxify(),
+                Some(part) => ok((None, stream::once(Ok(part)).chain(bundle2).boxify())).boxify(),
+                None => ok((None, bundle2)).boxify(),
             })
             .context("While resolving B2xTreegroup2")
             .from_err()
@@ -960,6 +1222,8 @@ impl Bundle2Resolver {
     /// Manifests and Filelogs it adds.
     /// The Changesets are scheduled for uploading and a Future is returned, whose completion means
     /// that the changesets were uploaded
+    /// The push is checked against the quota of the pusher first, and counts against it once
+    /// uploaded
     fn upload_changesets(
         &self,
         ctx: CoreContext,
@@ -970,6 +1234,8 @@ impl Bundle2Resolver {
         let filelogs = cg_push.filelogs;
         let content_blobs = cg_push.content_blobs;
         let draft = cg_push.draft;
+        let pushed_bytes = cg_push.pushed_bytes;
+        let pushed_files = filelogs.len() as u64;
 
         self.ctx
             .scuba()
@@ -977,6 +1243,7 @@ impl Bundle2Resolver {
             .add("changeset_count", changesets.len())
             .add("manifests_count", manifests.len())
             .add("filelogs_count", filelogs.len())
+            .add("pushed_bytes", pushed_bytes)
             .log_with_msg("Size of unbundle", None);
 
         STATS::changesets_count.add_value(changesets.len() as i64);
@@ -996,6 +1263,10 @@ impl Bundle2Resolver {
             content_blobs: &ContentBlobs,
             draft: bool,
         ) -> BoxFuture<UploadedChangesets, Error> {
+            // Flags change how the server handles the commit, so unknown ones are rejected
+            try_boxfuture!(CommitFlags::from_extras(revlog_cs.extra())
+                .with_context(|_| ErrorKind::InvalidCommitFlags(node)));
+
             let (p1, p2) = {
                 (
                     get_parent(ctx.clone(), &repo, &uploaded_changesets, revlog_cs.p1),
@@ -1058,8 +1329,15 @@ impl Bundle2Resolver {
             content_blobs.keys()
         );
 
+        let pushed_filenodes = if self.content_refs.is_some() {
+            filenodes_by_content(&content_blobs)
+        } else {
+            HashMap::new()
+        };
+
         let scuba_logger = self.ctx.scuba().clone();
-        stream::iter_ok(changesets)
+        cloned!(ctx);
+        let upload = stream::iter_ok(changesets)
             .fold(
                 HashMap::new(),
                 move |uploaded_changesets, (node, revlog_cs)| {
@@ -1083,23 +1361,224 @@ impl Bundle2Resolver {
                         .into_iter()
                         .map(|(_, cs)| cs.get_completed_changeset()),
                 )
+                .map(|completed| completed.0.clone())
                 .map_err(Error::from)
-                .for_each(|_| Ok(()))
+                .collect()
             })
             .chain_err(ErrorKind::WhileUploadingData(changesets_hashes))
-            .from_err()
+            .from_err();
+
+        let resolver = self.clone();
+        self.check_push_quota(ctx.clone(), pushed_bytes)
+            .and_then(move |()| upload)
+            .and_then({
+                cloned!(ctx, resolver);
+                move |bonsais| resolver.record_content_refs(ctx, bonsais, pushed_filenodes)
+            })
+            .and_then(move |()| resolver.record_push_usage(ctx, pushed_bytes, pushed_files))
             .boxify()
     }
 
+    /// Record which files of the pushed changesets reference which contents, if the repo keeps
+    /// an index of them. The push has already succeeded at this point, so a failure to record
+    /// them is logged rather than returned to the client.
+    fn record_content_refs(
+        &self,
+        ctx: CoreContext,
+        bonsais: Vec<BonsaiChangeset>,
+        pushed_filenodes: HashMap<(MPath, ContentId), Vec<HgFileNodeId>>,
+    ) -> BoxFuture<(), Error> {
+        let content_refs = match self.content_refs {
+            Some(ref content_refs) => content_refs.clone(),
+            None => return ok(()).boxify(),
+        };
+
+        let mut refs = vec![];
+        for bcs in bonsais {
+            let changeset_id = bcs.get_changeset_id();
+            for (path, change) in bcs.file_changes() {
+                let content_id = match change {
+                    Some(change) => change.content_id(),
+                    None => continue,
+                };
+                // Only the files uploaded by this push have a known filenode
+                let key = (path.clone(), content_id);
+                for filenode in pushed_filenodes.get(&key).into_iter().flatten() {
+                    refs.push(ContentRef {
+                        content_id,
+                        path: path.clone(),
+                        filenode: *filenode,
+                        changeset_id,
+                    });
+                }
+            }
+        }
+
+        let logger = ctx.logger().clone();
+        content_refs
+            .record(ctx, refs)
+            .then(move |res| {
+                if let Err(err) = res {
+                    warn!(logger, "failed to record content references: {:?}", err);
+                }
+                Ok(())
+            })
+            .boxify()
+    }
+
+    /// Reject the push if it takes the pusher over their hard limit, and warn them if it takes
+    /// them over their soft limit. Pushes whose pusher is unknown aren't limited, and a failure
+    /// to check the quota doesn't fail the push.
+    fn check_push_quota(&self, ctx: CoreContext, pushed_bytes: u64) -> BoxFuture<(), Error> {
+        let identity = match PushQuota::identity(&ctx) {
+            Some(identity) => identity,
+            None => return ok(()).boxify(),
+        };
+        let logger = ctx.logger().clone();
+        self.push_quota
+            .check(ctx, &identity, pushed_bytes)
+            .then(move |res| match res {
+                Ok(QuotaCheck::WithinQuota) => Ok(()),
+                Ok(QuotaCheck::OverSoftLimit(msg)) => {
+                    warn!(logger, "Push quota: {}", msg; "remote" => "true");
+                    Ok(())
+                }
+                Ok(QuotaCheck::OverHardLimit(msg)) => Err(ErrorKind::PushQuotaExceeded(msg).into()),
+                Err(err) => {
+                    warn!(logger, "failed to check push quota: {:?}", err);
+                    Ok(())
+                }
+            })
+            .boxify()
+    }
+
+    /// Count the push against the quota of the pusher. The push has already succeeded at this
+    /// point, so a failure to record it is logged rather than returned to the client.
+    fn record_push_usage(
+        &self,
+        ctx: CoreContext,
+        pushed_bytes: u64,
+        pushed_files: u64,
+    ) -> impl Future<Item = (), Error = Error> {
+        let identity = PushQuota::identity(&ctx).unwrap_or_else(|| "unknown".to_string());
+        let logger = ctx.logger().clone();
+        self.push_quota
+            .record(ctx, identity, pushed_bytes, pushed_files)
+            .then(move |res| {
+                if let Err(err) = res {
+                    warn!(logger, "failed to record push usage: {:?}", err);
+                }
+                Ok(())
+            })
+    }
+
+    /// Publish `event` in the background. The push has already succeeded at this point, so it
+    /// doesn't wait for the event bus, and a failure to publish is logged rather than returned to
+    /// the client.
+    fn publish_event(&self, event: RepoEvent) {
+        let logger = self.ctx.logger().clone();
+        tokio::spawn(self.event_bus.publish(event).then(move |res| {
+            if let Err(err) = res {
+                warn!(logger, "failed to publish repo event: {:?}", err);
+            }
+            Ok(())
+        }));
+    }
+
+    /// Queue the post-commit hooks of `bookmark` to run on `changesets`, which landed on it. At
+    /// most `MAX_POST_COMMIT_CHANGESETS` of them are queued, so that creating a bookmark on old
+    /// history doesn't run the hooks on all of it. The bookmark already moved, so failing to queue
+    /// the hooks doesn't fail the push.
+    fn queue_post_commit_hooks(
+        &self,
+        ctx: CoreContext,
+        bookmark: &Bookmark,
+        changesets: BoxStream<ChangesetId, Error>,
+    ) -> impl Future<Item = (), Error = Error> {
+        if !self.hook_manager.has_post_commit_hooks(bookmark) {
+            return ok(()).left_future();
+        }
+
+        let logger = self.ctx.logger().clone();
+        let repo = self.repo.clone();
+        let hook_manager = self.hook_manager.clone();
+        let bookmark = bookmark.clone();
+        // Post-commit hooks run on hg changesets, so they are derived now even if derivation is
+        // deferred
+        changesets
+            .take(MAX_POST_COMMIT_CHANGESETS as u64 + 1)
+            .map({
+                cloned!(ctx, repo);
+                move |cs_id| repo.get_hg_from_bonsai_changeset(ctx.clone(), cs_id)
+            })
+            .buffered(POST_COMMIT_DERIVE_CONCURRENCY)
+            .collect()
+            .and_then({
+                cloned!(logger, bookmark);
+                move |mut cs_ids| {
+                    if cs_ids.len() > MAX_POST_COMMIT_CHANGESETS {
+                        warn!(
+                            logger,
+                            "more than {} changesets landed on {}, post-commit hooks are only \
+                             queued for the newest",
+                            MAX_POST_COMMIT_CHANGESETS,
+                            bookmark
+                        );
+                        cs_ids.truncate(MAX_POST_COMMIT_CHANGESETS);
+                    }
+                    hook_manager.queue_post_commit_hooks(ctx, repo.get_repoid(), cs_ids, &bookmark)
+                }
+            })
+            .then(move |res| {
+                if let Err(err) = res {
+                    error!(
+                        logger,
+                        "failed to queue post-commit hooks of {}: {:?}", bookmark, err
+                    );
+                }
+                Ok(())
+            })
+            .right_future()
+    }
+
+    /// Queue the post-commit hooks of `bookmark` to run on the changesets that moving it from `old`
+    /// to `new` added to it
+    fn queue_moved_post_commit_hooks(
+        &self,
+        lca_hint: Arc<LeastCommonAncestorsHint>,
+        bookmark: &Bookmark,
+        old: Option<ChangesetId>,
+        new: Option<ChangesetId>,
+    ) -> impl Future<Item = (), Error = Error> {
+        let new = match new {
+            Some(new) => new,
+            None => return ok(()).left_future(),
+        };
+        let added = DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes(
+            self.ctx.clone(),
+            &self.repo.get_changeset_fetcher_for("revsets"),
+            lca_hint,
+            vec![new],
+            old.into_iter().collect(),
+        );
+        self.queue_post_commit_hooks(self.ctx.clone(), bookmark, added.boxify())
+            .right_future()
+    }
+
+    /// Log the changesets that landed on `bookmark` to scribe, and publish them as
+    /// `CommitLanded` events.
     fn log_commits_to_scribe(
         &self,
         ctx: CoreContext,
+        bookmark: &Bookmark,
         changesets: Vec<ChangesetId>,
     ) -> BoxFuture<(), Error> {
         let repo = self.repo.clone();
         let queue = self.scribe_commit_queue.clone();
+        let resolver = self.clone();
+        let bookmark = bookmark.to_string();
         let futs = changesets.into_iter().map(move |changeset_id| {
-            cloned!(ctx, repo, queue, changeset_id);
+            cloned!(ctx, repo, queue, resolver, bookmark, changeset_id);
             let generation = repo
                 .get_generation_number_by_bonsai(ctx.clone(), changeset_id)
                 .and_then(|maybe_gen| maybe_gen.ok_or(err_msg("No generation number found")));
@@ -1110,12 +1589,20 @@ impl Bundle2Resolver {
             generation
                 .join(parents)
                 .and_then(move |(generation, parents)| {
+                    let event = RepoEvent::CommitLanded {
+                        repo_id,
+                        changeset_id,
+                        generation,
+                        parents: parents.clone(),
+                        bookmark,
+                    };
                     let ci = scribe_commit_queue::CommitInfo::new(
                         repo_id,
                         generation,
                         changeset_id,
                         parents,
                     );
+                    resolver.publish_event(event);
                     queue.queue_commit(ci)
                 })
         });
@@ -1145,12 +1632,162 @@ impl Bundle2Resolver {
             .boxify()
     }
 
-    /// Takes a changegroup id and prepares a Bytes response containing Bundle2 with reply to
-    /// changegroup part saying that the push was successful
+    /// Whether the pusher may make any commit public
+    fn is_phases_admin(&self) -> bool {
+        self.ctx
+            .user_identities()
+            .iter()
+            .any(|identity| self.phases_admin_identities.contains(identity))
+    }
+
+    /// Make `heads` public if the pusher may, whether they were sent in phases pushkeys or in
+    /// phase-heads parts. Bookmarks must be moved first, so that the commits they point to are
+    /// public already.
+    fn publish(
+        &self,
+        ctx: CoreContext,
+        phases_hint: Arc<Phases>,
+        heads: Vec<HgChangesetId>,
+    ) -> BoxFuture<(), Error> {
+        let resolver = self.clone();
+        self.check_publish(ctx.clone(), phases_hint.clone(), &heads)
+            .and_then(move |()| resolver.mark_public(ctx, phases_hint, heads))
+            .boxify()
+    }
+
+    /// Phases admins may make any commit public. Other pushers may only make public the commits
+    /// that are ancestors of publishing bookmarks, which are public already.
+    fn check_publish(
+        &self,
+        ctx: CoreContext,
+        phases_hint: Arc<Phases>,
+        heads: &[HgChangesetId],
+    ) -> BoxFuture<(), Error> {
+        if heads.is_empty() || self.is_phases_admin() {
+            return ok(()).boxify();
+        }
+        let nodes = heads.to_vec();
+        let repo = self.repo.clone();
+
+        repo.get_hg_bonsai_mapping(ctx.clone(), nodes.clone())
+            .and_then(move |mapping| {
+                let hg_cs_ids: HashMap<_, _> = mapping
+                    .into_iter()
+                    .map(|(hg_cs_id, cs_id)| (cs_id, hg_cs_id))
+                    .collect();
+                if let Some(unknown) = nodes
+                    .iter()
+                    .find(|node| !hg_cs_ids.values().any(|hg_cs_id| hg_cs_id == *node))
+                {
+                    return err(format_err!("phases: unknown changeset {}", unknown)).left_future();
+                }
+
+                phases_hint
+                    .get_all(ctx, repo, hg_cs_ids.keys().cloned().collect())
+                    .and_then(move |phases| {
+                        let not_public = phases
+                            .calculated
+                            .into_iter()
+                            .filter(|(_, phase)| *phase != Phase::Public)
+                            .map(|(cs_id, _)| cs_id)
+                            .chain(phases.unknown)
+                            .next();
+                        match not_public {
+                            Some(cs_id) => {
+                                Err(ErrorKind::PhasePushDenied(hg_cs_ids[&cs_id]).into())
+                            }
+                            None => Ok(()),
+                        }
+                    })
+                    .right_future()
+            })
+            .boxify()
+    }
+
+    /// Make `heads` and all their ancestors public. Ancestors are only walked until commits that
+    /// are public already, so this writes just the commits that were draft until now.
+    fn mark_public(
+        &self,
+        ctx: CoreContext,
+        phases_hint: Arc<Phases>,
+        heads: Vec<HgChangesetId>,
+    ) -> BoxFuture<(), Error> {
+        if heads.is_empty() {
+            return ok(()).boxify();
+        }
+        let heads: Vec<_> = heads
+            .into_iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let repo = self.repo.clone();
+
+        repo.get_hg_bonsai_mapping(ctx.clone(), heads.clone())
+            .and_then(move |mapping| {
+                if mapping.len() != heads.len() {
+                    let known: HashSet<_> = mapping.iter().map(|(hg_cs_id, _)| *hg_cs_id).collect();
+                    let unknown: Vec<_> = heads
+                        .into_iter()
+                        .filter(|head| !known.contains(head))
+                        .collect();
+                    return err(format_err!("phase-heads: unknown changesets {:?}", unknown))
+                        .left_future();
+                }
+                let heads: Vec<_> = mapping.into_iter().map(|(_, cs_id)| cs_id).collect();
+
+                loop_fn((heads, HashSet::new()), {
+                    cloned!(ctx, repo, phases_hint);
+                    move |(to_visit, mut drafts): (Vec<ChangesetId>, HashSet<ChangesetId>)| {
+                        if to_visit.is_empty() {
+                            return ok(Loop::Break(drafts)).left_future();
+                        }
+                        phases_hint
+                            .get_all(ctx.clone(), repo.clone(), to_visit)
+                            .and_then({
+                                cloned!(ctx, repo);
+                                move |phases| {
+                                    let new_drafts: Vec<_> = phases
+                                        .calculated
+                                        .into_iter()
+                                        .filter(|(_, phase)| *phase != Phase::Public)
+                                        .map(|(cs_id, _)| cs_id)
+                                        .chain(phases.unknown)
+                                        .filter(|cs_id| drafts.insert(*cs_id))
+                                        .collect();
+                                    let parents = new_drafts.into_iter().map(move |cs_id| {
+                                        repo.get_changeset_parents_by_bonsai(ctx.clone(), cs_id)
+                                    });
+                                    future::join_all(parents).map(move |parents| {
+                                        let to_visit = parents
+                                            .into_iter()
+                                            .flatten()
+                                            .filter(|cs_id| !drafts.contains(cs_id))
+                                            .collect();
+                                        Loop::Continue((to_visit, drafts))
+                                    })
+                                }
+                            })
+                            .right_future()
+                    }
+                })
+                .and_then(move |drafts| {
+                    let phases = drafts
+                        .into_iter()
+                        .map(|cs_id| (cs_id, Phase::Public))
+                        .collect();
+                    phases_hint.add_all(ctx, repo, phases)
+                })
+                .right_future()
+            })
+            .boxify()
+    }
+
+    /// Takes a changegroup id and pushkey part ids and prepares a Bytes response containing
+    /// Bundle2 with replies to those parts saying that the push was successful
     fn prepare_push_response(
         &self,
         changegroup_id: Option<PartId>,
-        bookmark_ids: Vec<PartId>,
+        pushkey_ids: Vec<PartId>,
     ) -> BoxFuture<Bytes, Error> {
         let writer = Cursor::new(Vec::new());
         let mut bundle = Bundle2EncodeBuilder::new(writer);
@@ -1164,7 +1801,7 @@ impl Bundle2Resolver {
                 changegroup_id,
             )));
         }
-        for part_id in bookmark_ids {
+        for part_id in pushkey_ids {
             bundle.add_part(try_boxfuture!(parts::replypushkey_part(true, part_id)));
         }
         bundle
@@ -1258,6 +1895,60 @@ impl Bundle2Resolver {
             })
     }
 
+    /// Sending the rebased commits back to the client means deriving hg changesets for them, so
+    /// with deferred derivation the client doesn't get them, and picks them up on its next pull.
+    /// The commits are queued for derivation instead.
+    fn prepare_deferred_pushrebase_response(
+        &self,
+        ctx: CoreContext,
+        queue: Arc<HgDerivationQueue>,
+        pushrebased_rev: ChangesetId,
+        pushrebased_changesets: Vec<ChangesetId>,
+        phases_hint: Arc<Phases>,
+        bookmark_push_part_id: Option<PartId>,
+    ) -> BoxFuture<Bytes, Error> {
+        let repo_id = self.repo.get_repoid();
+        let now = DateTime::new(ctx.now());
+        let entries = pushrebased_changesets
+            .into_iter()
+            .map(|cs_id| HgDerivationQueueEntry::new(repo_id, cs_id, now))
+            .collect();
+
+        let mut scuba_logger = self.ctx.scuba().clone();
+        queue
+            .add(ctx.clone(), entries)
+            .and_then({
+                cloned!(self.repo);
+                // write phase as public for this commit
+                move |()| phases_hint.add(ctx, repo, pushrebased_rev, Phase::Public)
+            })
+            .and_then(move |_| {
+                let writer = Cursor::new(Vec::new());
+                let mut bundle = Bundle2EncodeBuilder::new(writer);
+                // Mercurial currently hangs while trying to read compressed bundles over the wire:
+                // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
+                bundle.set_compressor_type(None);
+                if let Some(part_id) = bookmark_push_part_id {
+                    bundle.add_part(try_boxfuture!(parts::replypushkey_part(true, part_id)));
+                }
+                bundle
+                    .build()
+                    .map(|cursor| Bytes::from(cursor.into_inner()))
+                    .context("While preparing response")
+                    .from_err()
+                    .boxify()
+            })
+            .timed(move |stats, result| {
+                if result.is_ok() {
+                    scuba_logger
+                        .add_future_stats(&stats)
+                        .log_with_msg("Pushrebase: prepared the deferred response", None);
+                }
+                Ok(())
+            })
+            .boxify()
+    }
+
     fn prepare_push_bookmark_response(
         &self,
         _ctx: CoreContext,
@@ -1319,7 +2010,8 @@ impl Bundle2Resolver {
         changesets: Changesets,
         onto_bookmark: &pushrebase::OntoBookmarkParams,
         maybe_raw_bundle2_id: Option<RawBundle2Id>,
-    ) -> impl Future<Item = (ChangesetId, Vec<ChangesetId>), Error = Error> {
+    ) -> impl Future<Item = (ChangesetId, Option<ChangesetId>, Vec<ChangesetId>), Error = Error>
+    {
         let block_merges = self.pushrebase.block_merges.clone();
         if block_merges
             && changesets
@@ -1364,7 +2056,7 @@ impl Bundle2Resolver {
                 Ok(())
             }
         })
-        .map(|res| (res.head, res.rebased_changesets))
+        .map(|res| (res.head, res.old_bookmark_value, res.rebased_changesets))
         .boxify()
     }
 
@@ -1464,7 +2156,12 @@ fn check_bookmark_push_allowed(
 
     match (bp.old, bp.new) {
         (Some(old), Some(new)) if block_non_fast_forward && old != new => lca_hint
-            .is_ancestor(ctx, repo.get_changeset_fetcher(), old, new)
+            .is_ancestor(
+                ctx,
+                repo.get_changeset_fetcher_for("reachability"),
+                old,
+                new,
+            )
             .and_then(|is_ancestor| {
                 if is_ancestor {
                     Ok(bp)
@@ -1483,17 +2180,19 @@ fn check_bookmark_push_allowed(
     }
 }
 
-fn add_bookmark_to_transaction(
-    txn: &mut Box<Transaction>,
-    bookmark_push: BonsaiBookmarkPush,
-    bookmark_update_reason: BookmarkUpdateReason,
-) -> Result<()> {
-    match (bookmark_push.new, bookmark_push.old) {
-        (Some(new), Some(old)) => txn.update(&bookmark_push.name, new, old, bookmark_update_reason),
-        (Some(new), None) => txn.create(&bookmark_push.name, new, bookmark_update_reason),
-        (None, Some(old)) => txn.delete(&bookmark_push.name, old, bookmark_update_reason),
-        _ => Ok(()),
+/// Filenodes uploaded by a push, by path and content. The same content at the same path can
+/// have several filenodes if it has several histories.
+fn filenodes_by_content(
+    content_blobs: &ContentBlobs,
+) -> HashMap<(MPath, ContentId), Vec<HgFileNodeId>> {
+    let mut filenodes = HashMap::new();
+    for (node_key, info) in content_blobs {
+        filenodes
+            .entry((info.path.clone(), info.meta.id))
+            .or_insert_with(Vec::new)
+            .push(HgFileNodeId::new(node_key.hash));
     }
+    filenodes
 }
 
 /// Retrieves the parent from uploaded changesets, if it is missing then fetches it from BlobRepo
@@ -1735,6 +2434,42 @@ fn is_entry_present_in_parent(
     }
 }
 
+/// Whether the changegroup carries tree manifests, which only version 03 changegroups can do
+fn is_treemanifest_changegroup(header: &PartHeader) -> Result<bool> {
+    let treemanifest = match header.mparams().get("treemanifest") {
+        Some(value) => match &value[..] {
+            b"1" | b"True" | b"true" => true,
+            _ => false,
+        },
+        None => false,
+    };
+    if treemanifest {
+        let version = header.aparams().get(CG_PART_VERSION_HEADER_NAME);
+        if version.map(|version| &version[..]) != Some(b"03") {
+            bail_msg!(
+                "tree manifests are only supported in version 03 changegroups, got version {:?}",
+                version
+            );
+        }
+    }
+    Ok(treemanifest)
+}
+
+/// Tree manifests come either in a b2x:treegroup2 part or, for treemanifest changegroups, in the
+/// changegroup itself
+fn pushed_manifests(
+    treegroup_manifests: Option<Manifests>,
+    changegroup_manifests: Option<Manifests>,
+) -> Result<Manifests> {
+    match (treegroup_manifests, changegroup_manifests) {
+        (Some(_), Some(_)) => bail_msg!(
+            "tree manifests were sent both in the changegroup and in a b2x:treegroup2 part"
+        ),
+        (Some(manifests), None) | (None, Some(manifests)) => Ok(manifests),
+        (None, None) => bail_msg!("Expected Bundle2 B2xTreegroup2"),
+    }
+}
+
 fn get_ascii_param(params: &HashMap<String, Bytes>, param: &str) -> Result<AsciiString> {
     let val = params
         .get(param)
@@ -1755,3 +2490,524 @@ fn get_optional_changeset_param(
         Ok(Some(HgChangesetId::from_ascii_str(&val)?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+    use std::time::Instant;
+
+    use async_unit;
+    use fixtures::linear;
+    use hooks::{
+        Hook, HookChangeset, HookContext, InMemoryChangesetStore, InMemoryFileContentStore,
+        PostCommitQueue, SqlPostCommitQueue,
+    };
+    use mercurial_bundles::bundle2::{Bundle2Stream, StreamEvent};
+    use mercurial_bundles::part_encode::PartEncodeBuilder;
+    use mercurial_bundles::PartHeaderBuilder;
+    use phases::{HintPhases, SqlConstructors, SqlPhases};
+    use push_usage::SqlPushUsageStore;
+    use skiplist::SkiplistIndex;
+    use slog::{Discard, Logger};
+    use sshrelay::SshEnvVars;
+    use tracing::TraceContext;
+    use uuid::Uuid;
+
+    /*
+        The linear fixture, with master moved back to eed3a8c0 so that the commits above it can
+        be pushed:
+
+            o  a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157
+            |
+            o  0ed509bf086fadcb8a8a5384dc3b550729b0fc17
+            |
+            o  eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b  master
+            |
+            ~
+    */
+    const MASTER: &str = "eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b";
+    const CHILD: &str = "0ed509bf086fadcb8a8a5384dc3b550729b0fc17";
+    const GRANDCHILD: &str = "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157";
+
+    fn hg(cs_id: &str) -> HgChangesetId {
+        HgChangesetId::from_str(cs_id).unwrap()
+    }
+
+    fn bonsai(ctx: CoreContext, repo: &BlobRepo, cs_id: &str) -> ChangesetId {
+        repo.get_bonsai_from_hg(ctx, hg(cs_id))
+            .wait()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn linear_with_master() -> BlobRepo {
+        let ctx = CoreContext::test_mock();
+        let repo = linear::getrepo(None);
+        let mut txn = repo.update_bookmark_transaction(ctx.clone());
+        for (bookmark, _) in repo
+            .get_bonsai_bookmarks(ctx.clone())
+            .collect()
+            .wait()
+            .unwrap()
+        {
+            txn.force_delete(
+                &bookmark,
+                BookmarkUpdateReason::TestMove {
+                    bundle_replay_data: None,
+                },
+            )
+            .unwrap();
+        }
+        txn.commit().wait().unwrap();
+
+        let mut txn = repo.update_bookmark_transaction(ctx.clone());
+        txn.force_set(
+            &Bookmark::new("master").unwrap(),
+            bonsai(ctx, &repo, MASTER),
+            BookmarkUpdateReason::TestMove {
+                bundle_replay_data: None,
+            },
+        )
+        .unwrap();
+        txn.commit().wait().unwrap();
+        repo
+    }
+
+    fn ctx_with_user(user: &str) -> CoreContext {
+        let session = Uuid::new_v4();
+        CoreContext::new(
+            session,
+            Logger::root(Discard, o!()),
+            ScubaSampleBuilder::with_discard(),
+            None,
+            TraceContext::new(session, Instant::now()),
+            Some(user.to_string()),
+            SshEnvVars::default(),
+        )
+    }
+
+    fn test_hook_manager(ctx: CoreContext) -> Arc<HookManager> {
+        Arc::new(HookManager::new(
+            ctx,
+            Box::new(InMemoryChangesetStore::new()),
+            Arc::new(InMemoryFileContentStore::new()),
+            Default::default(),
+            Logger::root(Discard, o!()),
+        ))
+    }
+
+    fn test_push_quota(repo: &BlobRepo) -> PushQuota {
+        PushQuota::new(
+            repo.get_repoid(),
+            Default::default(),
+            Arc::new(SqlPushUsageStore::with_sqlite_in_memory().unwrap()),
+        )
+    }
+
+    fn test_resolver(
+        ctx: CoreContext,
+        repo: BlobRepo,
+        phases_admin_identities: Vec<String>,
+    ) -> Bundle2Resolver {
+        let push_quota = test_push_quota(&repo);
+        Bundle2Resolver::new(
+            ctx.clone(),
+            repo,
+            PushrebaseParams::default(),
+            vec![],
+            test_hook_manager(ctx),
+            None,
+            Arc::new(WebhookDispatcher::new(vec![]).unwrap()),
+            EventBus::discard(),
+            push_quota,
+            None,
+            phases_admin_identities,
+        )
+    }
+
+    fn bookmark_pushkey_part(name: &str, old: &str, new: &str) -> PartEncodeBuilder {
+        let mut part = PartEncodeBuilder::mandatory(PartHeaderType::Pushkey).unwrap();
+        part.add_mparam("namespace", "bookmarks").unwrap();
+        part.add_mparam("key", name.to_string()).unwrap();
+        part.add_mparam("old", old.to_string()).unwrap();
+        part.add_mparam("new", new.to_string()).unwrap();
+        part
+    }
+
+    fn phases_pushkey_part(node: &str) -> PartEncodeBuilder {
+        let mut part = PartEncodeBuilder::mandatory(PartHeaderType::Pushkey).unwrap();
+        part.add_mparam("namespace", "phases").unwrap();
+        part.add_mparam("key", node.to_string()).unwrap();
+        part.add_mparam("old", "1").unwrap();
+        part.add_mparam("new", "0").unwrap();
+        part
+    }
+
+    fn phase_heads_part(ctx: CoreContext, heads: Vec<(&str, HgPhase)>) -> PartEncodeBuilder {
+        let heads: Vec<_> = heads
+            .into_iter()
+            .map(|(head, phase)| (hg(head), phase))
+            .collect();
+        parts::phases_part(ctx, stream::iter_ok(heads)).unwrap()
+    }
+
+    /// Encodes a push of `parts` the way hg does, and resolves it like the server does
+    struct AcceptingHook;
+
+    impl Hook<HookChangeset> for AcceptingHook {
+        fn run(
+            &self,
+            _ctx: CoreContext,
+            _context: HookContext<HookChangeset>,
+        ) -> BoxFuture<HookExecution, Error> {
+            ok(HookExecution::Accepted).boxify()
+        }
+    }
+
+    fn push_parts(
+        ctx: CoreContext,
+        repo: &BlobRepo,
+        phases_hint: Arc<Phases>,
+        phases_admin_identities: Vec<String>,
+        parts: Vec<PartEncodeBuilder>,
+    ) -> Result<Bytes> {
+        let hook_manager = test_hook_manager(ctx.clone());
+        push_parts_with_hooks(
+            ctx,
+            repo,
+            phases_hint,
+            phases_admin_identities,
+            hook_manager,
+            parts,
+        )
+    }
+
+    fn push_parts_with_hooks(
+        ctx: CoreContext,
+        repo: &BlobRepo,
+        phases_hint: Arc<Phases>,
+        phases_admin_identities: Vec<String>,
+        hook_manager: Arc<HookManager>,
+        parts: Vec<PartEncodeBuilder>,
+    ) -> Result<Bytes> {
+        let mut builder = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
+        builder.set_compressor_type(None);
+        let mut replycaps = PartEncodeBuilder::mandatory(PartHeaderType::Replycaps).unwrap();
+        replycaps.set_data_bytes("HG20").unwrap();
+        builder.add_part(replycaps);
+        for part in parts {
+            builder.add_part(part);
+        }
+        let bundle = builder.build().wait()?.into_inner();
+
+        let bundle2 = Bundle2Stream::new(ctx.clone(), Cursor::new(bundle))
+            .filter_map(|event| match event {
+                StreamEvent::Next(item) => Some(item),
+                StreamEvent::Done(_) => None,
+            })
+            .boxify();
+        resolve(
+            ctx.clone(),
+            repo.clone(),
+            PushrebaseParams::default(),
+            vec![],
+            vec![],
+            bundle2,
+            hook_manager,
+            Arc::new(SkiplistIndex::new()),
+            phases_hint,
+            None,
+            Arc::new(WebhookDispatcher::new(vec![]).unwrap()),
+            EventBus::discard(),
+            RepoReadOnly::ReadWrite,
+            None,
+            test_push_quota(repo),
+            None,
+            phases_admin_identities,
+        )
+        .wait()
+    }
+
+    fn phases_of(
+        ctx: CoreContext,
+        repo: &BlobRepo,
+        phases: &Phases,
+        cs_ids: &[&str],
+    ) -> Vec<Option<Phase>> {
+        let bonsais: Vec<_> = cs_ids
+            .iter()
+            .map(|cs_id| bonsai(ctx.clone(), repo, cs_id))
+            .collect();
+        let mut mapping = phases
+            .get_all(ctx, repo.clone(), bonsais.clone())
+            .wait()
+            .unwrap();
+        bonsais
+            .iter()
+            .map(|cs_id| mapping.calculated.remove(cs_id))
+            .collect()
+    }
+
+    fn assert_denied(res: Result<Bytes>, node: &str) {
+        let denied = res
+            .unwrap_err()
+            .iter_chain()
+            .filter_map(|cause| cause.downcast_ref::<ErrorKind>())
+            .any(|err| match err {
+                ErrorKind::PhasePushDenied(cs_id) => *cs_id == hg(node),
+                _ => false,
+            });
+        assert!(denied, "publishing {} should be denied", node);
+    }
+
+    #[test]
+    fn test_bookmark_push_marks_commits_public() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let repo = linear_with_master();
+            let phases_store = Arc::new(SqlPhases::with_sqlite_in_memory().unwrap());
+            let phases_hint: Arc<Phases> = Arc::new(HintPhases::new(
+                phases_store.clone(),
+                Arc::new(SkiplistIndex::new()),
+            ));
+
+            assert_eq!(
+                phases_of(ctx.clone(), &repo, &*phases_hint, &[CHILD, GRANDCHILD]),
+                vec![Some(Phase::Draft), Some(Phase::Draft)]
+            );
+
+            // What hg sends to move master to the commits it pushes
+            let parts = vec![
+                bookmark_pushkey_part("master", MASTER, GRANDCHILD),
+                phase_heads_part(ctx.clone(), vec![(GRANDCHILD, HgPhase::Public)]),
+            ];
+            push_parts(ctx.clone(), &repo, phases_hint.clone(), vec![], parts).unwrap();
+
+            assert_eq!(
+                phases_of(ctx.clone(), &repo, &*phases_hint, &[CHILD, GRANDCHILD]),
+                vec![Some(Phase::Public), Some(Phase::Public)]
+            );
+            assert_eq!(
+                repo.get_bookmark(ctx, &Bookmark::new("master").unwrap())
+                    .wait()
+                    .unwrap(),
+                Some(hg(GRANDCHILD))
+            );
+        });
+    }
+
+    #[test]
+    fn test_bookmark_push_queues_post_commit_hooks() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let repo = linear_with_master();
+            let phases_hint: Arc<Phases> = Arc::new(HintPhases::new(
+                Arc::new(SqlPhases::with_sqlite_in_memory().unwrap()),
+                Arc::new(SkiplistIndex::new()),
+            ));
+            let queue: Arc<PostCommitQueue> =
+                Arc::new(SqlPostCommitQueue::with_sqlite_in_memory().unwrap());
+            let mut hook_manager = HookManager::new(
+                ctx.clone(),
+                Box::new(InMemoryChangesetStore::new()),
+                Arc::new(InMemoryFileContentStore::new()),
+                Default::default(),
+                Logger::root(Discard, o!()),
+            );
+            hook_manager.register_post_commit_hook(
+                "notify",
+                Arc::new(AcceptingHook),
+                Default::default(),
+            );
+            hook_manager.set_hooks_for_bookmark(
+                Bookmark::new("master").unwrap().into(),
+                vec!["notify".to_string()],
+            );
+            hook_manager.set_post_commit_queue(queue.clone());
+
+            let parts = vec![
+                bookmark_pushkey_part("master", MASTER, GRANDCHILD),
+                phase_heads_part(ctx.clone(), vec![(GRANDCHILD, HgPhase::Public)]),
+            ];
+            push_parts_with_hooks(
+                ctx.clone(),
+                &repo,
+                phases_hint,
+                vec![],
+                Arc::new(hook_manager),
+                parts,
+            )
+            .unwrap();
+
+            // The hooks are queued for the commits the bookmark moved over, and not for the ones
+            // it was on already
+            let queued: HashSet<_> = queue
+                .list(ctx, repo.get_repoid(), 10)
+                .wait()
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.cs_id)
+                .collect();
+            assert_eq!(
+                queued,
+                vec![hg(CHILD), hg(GRANDCHILD)].into_iter().collect()
+            );
+        });
+    }
+
+    #[test]
+    fn test_phase_heads_mark_ancestors_public() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = ctx_with_user("releng");
+            let repo = linear_with_master();
+            let phases_store = Arc::new(SqlPhases::with_sqlite_in_memory().unwrap());
+            let phases_hint: Arc<Phases> = Arc::new(HintPhases::new(
+                phases_store.clone(),
+                Arc::new(SkiplistIndex::new()),
+            ));
+
+            // No bookmark points to the heads, so their phases can only come from the store
+            let parts = vec![phase_heads_part(
+                ctx.clone(),
+                vec![(GRANDCHILD, HgPhase::Public), (MASTER, HgPhase::Draft)],
+            )];
+            let admins = vec!["releng".to_string()];
+            push_parts(ctx.clone(), &repo, phases_hint, admins, parts).unwrap();
+
+            assert_eq!(
+                phases_of(ctx.clone(), &repo, &*phases_store, &[CHILD, GRANDCHILD]),
+                vec![Some(Phase::Public), Some(Phase::Public)]
+            );
+        });
+    }
+
+    #[test]
+    fn test_phase_heads_of_draft_commit_denied() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = ctx_with_user("alice");
+            let repo = linear_with_master();
+            let phases_store = Arc::new(SqlPhases::with_sqlite_in_memory().unwrap());
+            let phases_hint: Arc<Phases> = Arc::new(HintPhases::new(
+                phases_store.clone(),
+                Arc::new(SkiplistIndex::new()),
+            ));
+
+            let parts = vec![phase_heads_part(
+                ctx.clone(),
+                vec![(GRANDCHILD, HgPhase::Public)],
+            )];
+            let admins = vec!["releng".to_string()];
+            let res = push_parts(ctx.clone(), &repo, phases_hint.clone(), admins, parts);
+            assert_denied(res, GRANDCHILD);
+            assert_eq!(
+                phases_of(ctx, &repo, &*phases_hint, &[CHILD, GRANDCHILD]),
+                vec![Some(Phase::Draft), Some(Phase::Draft)]
+            );
+        });
+    }
+
+    #[test]
+    fn test_admin_phase_push_marks_commits_public() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = ctx_with_user("releng");
+            let repo = linear_with_master();
+            let phases_store = Arc::new(SqlPhases::with_sqlite_in_memory().unwrap());
+            let phases_hint: Arc<Phases> = Arc::new(HintPhases::new(
+                phases_store.clone(),
+                Arc::new(SkiplistIndex::new()),
+            ));
+
+            let parts = vec![phases_pushkey_part(GRANDCHILD)];
+            let admins = vec!["releng".to_string()];
+            push_parts(ctx.clone(), &repo, phases_hint, admins, parts).unwrap();
+
+            assert_eq!(
+                phases_of(ctx, &repo, &*phases_store, &[CHILD, GRANDCHILD]),
+                vec![Some(Phase::Public), Some(Phase::Public)]
+            );
+        });
+    }
+
+    #[test]
+    fn test_phase_push_of_draft_commit_denied() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = ctx_with_user("alice");
+            let repo = linear_with_master();
+            let phases_store = Arc::new(SqlPhases::with_sqlite_in_memory().unwrap());
+            let phases_hint: Arc<Phases> = Arc::new(HintPhases::new(
+                phases_store.clone(),
+                Arc::new(SkiplistIndex::new()),
+            ));
+            let admins = vec!["releng".to_string()];
+
+            let parts = vec![phases_pushkey_part(GRANDCHILD)];
+            let res = push_parts(
+                ctx.clone(),
+                &repo,
+                phases_hint.clone(),
+                admins.clone(),
+                parts,
+            );
+            assert_denied(res, GRANDCHILD);
+            assert_eq!(
+                phases_of(ctx.clone(), &repo, &*phases_hint, &[CHILD, GRANDCHILD]),
+                vec![Some(Phase::Draft), Some(Phase::Draft)]
+            );
+
+            // Commits that are public already may be published by anyone
+            let parts = vec![phases_pushkey_part(MASTER)];
+            push_parts(ctx, &repo, phases_hint, admins, parts).unwrap();
+        });
+    }
+
+    fn resolve_pushkey_part(params: &[(&str, &str)]) -> Result<Option<Pushkey>> {
+        let ctx = CoreContext::test_mock();
+        let resolver = test_resolver(ctx, linear::getrepo(None), vec![]);
+        let mut header = PartHeaderBuilder::new(PartHeaderType::Pushkey, true).unwrap();
+        for (key, val) in params {
+            header.add_mparam(*key, val.as_bytes().to_vec()).unwrap();
+        }
+        let part = Bundle2Item::Pushkey(header.build(1), ok(()).boxify());
+        resolver
+            .maybe_resolve_pushkey(stream::once(Ok(part)).boxify())
+            .map(|(pushkey, _)| pushkey)
+            .wait()
+    }
+
+    #[test]
+    fn test_malformed_phases_pushkey() {
+        async_unit::tokio_unit_test(|| {
+            match resolve_pushkey_part(&[
+                ("namespace", "phases"),
+                ("key", GRANDCHILD),
+                ("new", "0"),
+            ]) {
+                Ok(Some(Pushkey::PhasePush(pp))) => assert_eq!(pp.node, hg(GRANDCHILD)),
+                res => panic!("unexpected result {:?}", res),
+            }
+
+            // Commits can't be made draft again
+            assert!(resolve_pushkey_part(&[
+                ("namespace", "phases"),
+                ("key", GRANDCHILD),
+                ("new", "1"),
+            ])
+            .is_err());
+            // The commit is missing or isn't a hash
+            assert!(
+                resolve_pushkey_part(&[("namespace", "phases"), ("key", ""), ("new", "0")])
+                    .is_err()
+            );
+            assert!(resolve_pushkey_part(&[
+                ("namespace", "phases"),
+                ("key", "not a hash"),
+                ("new", "0"),
+            ])
+            .is_err());
+            assert!(resolve_pushkey_part(&[("namespace", "phases"), ("new", "0")]).is_err());
+            assert!(resolve_pushkey_part(&[("namespace", "obsolete"), ("key", "")]).is_err());
+        });
+    }
+}
@@ -0,0 +1,592 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Sends push events to the webhooks configured for a repo.
+//!
+//! When a push moves a bookmark that a webhook is interested in, a JSON `PushEvent` is POSTed to
+//! the webhook's URL. If the webhook has a secret, the body is signed with HMAC-SHA256 and the
+//! hex digest is sent in the `X-Mononoke-Signature` header as `sha256=<digest>`, so that the
+//! receiver can check where the event came from.
+//!
+//! Events are sent in the background and never fail the push. Failed deliveries are retried with
+//! exponential backoff; an event that still can't be delivered is logged with its full payload
+//! as a dead letter, so that it can be resent by hand.
+
+#![deny(warnings)]
+
+extern crate blobrepo;
+extern crate bookmarks;
+extern crate bytes;
+extern crate cloned;
+extern crate context;
+extern crate crypto;
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate hyper;
+extern crate hyper_tls;
+extern crate mercurial_types;
+extern crate metaconfig_types;
+extern crate mononoke_types;
+extern crate reachabilityindex;
+extern crate revset;
+extern crate serde_derive;
+extern crate serde_json;
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate stats;
+extern crate tokio;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use blobrepo::BlobRepo;
+use bookmarks::Bookmark;
+use bytes::Bytes;
+use cloned::cloned;
+use context::CoreContext;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use failure::{err_msg, format_err, Error};
+use futures::future::{self, loop_fn, Loop};
+use futures::{Future, IntoFuture, Stream};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use mercurial_types::HgChangesetId;
+use metaconfig_types::WebhookParams;
+use mononoke_types::{ChangesetId, RepositoryId};
+use reachabilityindex::LeastCommonAncestorsHint;
+use revset::DifferenceOfUnionsOfAncestorsNodeStream;
+use serde_derive::Serialize;
+use slog::Logger;
+use stats::Timeseries;
+use tokio::timer::Delay;
+use tokio::util::FutureExt as TokioFutureExt;
+
+pub const SIGNATURE_HEADER: &str = "X-Mononoke-Signature";
+
+/// At most this many of the commits a bookmark move added are listed in an event.
+const MAX_COMMITS_PER_EVENT: usize = 100;
+/// Number of commits whose summaries are fetched at once.
+const COMMIT_SUMMARY_CONCURRENCY: usize = 10;
+const DEFAULT_MAX_ATTEMPTS: usize = 5;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// A webhook that doesn't respond within this long is retried, like one that failed.
+const DEFAULT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+define_stats! {
+    prefix = "mononoke.webhooks";
+    events: timeseries(RATE, SUM),
+    deliveries: timeseries(RATE, SUM),
+    retries: timeseries(RATE, SUM),
+    timeouts: timeseries(RATE, SUM),
+    dead_letters: timeseries(RATE, SUM),
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommitSummary {
+    pub hash: HgChangesetId,
+    pub author: String,
+    /// The first line of the commit message
+    pub summary: String,
+}
+
+/// A bookmark move, as sent to webhooks.
+#[derive(Debug, Serialize)]
+pub struct PushEvent {
+    pub repo_id: RepositoryId,
+    pub bookmark: String,
+    /// None if the bookmark was created
+    pub old: Option<HgChangesetId>,
+    /// None if the bookmark was deleted
+    pub new: Option<HgChangesetId>,
+    /// Commits that are ancestors of `new` but not of `old`, newest first
+    pub commits: Vec<CommitSummary>,
+    /// True if there were more than `MAX_COMMITS_PER_EVENT` commits, and only the newest are
+    /// listed
+    pub commits_truncated: bool,
+}
+
+impl PushEvent {
+    pub fn new(
+        ctx: CoreContext,
+        repo: BlobRepo,
+        lca_hint: Arc<LeastCommonAncestorsHint>,
+        bookmark: Bookmark,
+        old: Option<ChangesetId>,
+        new: Option<ChangesetId>,
+    ) -> BoxFuture<Self, Error> {
+        let commits = match new {
+            Some(new) => DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes(
+                ctx.clone(),
+                &repo.get_changeset_fetcher_for("revsets"),
+                lca_hint,
+                vec![new],
+                old.into_iter().collect(),
+            )
+            .take((MAX_COMMITS_PER_EVENT + 1) as u64)
+            .map({
+                cloned!(ctx, repo);
+                move |cs_id| commit_summary(ctx.clone(), repo.clone(), cs_id)
+            })
+            .buffered(COMMIT_SUMMARY_CONCURRENCY)
+            .collect()
+            .left_future(),
+            None => future::ok(vec![]).right_future(),
+        };
+
+        (
+            hg_changeset_opt(ctx.clone(), repo.clone(), old),
+            hg_changeset_opt(ctx, repo.clone(), new),
+            commits,
+        )
+            .into_future()
+            .map(move |(old, new, mut commits)| {
+                let commits_truncated = commits.len() > MAX_COMMITS_PER_EVENT;
+                commits.truncate(MAX_COMMITS_PER_EVENT);
+                PushEvent {
+                    repo_id: repo.get_repoid(),
+                    bookmark: bookmark.to_string(),
+                    old,
+                    new,
+                    commits,
+                    commits_truncated,
+                }
+            })
+            .boxify()
+    }
+}
+
+fn hg_changeset_opt(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    cs_id: Option<ChangesetId>,
+) -> impl Future<Item = Option<HgChangesetId>, Error = Error> {
+    match cs_id {
+        Some(cs_id) => repo
+            .get_hg_from_bonsai_changeset(ctx, cs_id)
+            .map(Some)
+            .left_future(),
+        None => future::ok(None).right_future(),
+    }
+}
+
+fn commit_summary(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    cs_id: ChangesetId,
+) -> impl Future<Item = CommitSummary, Error = Error> {
+    repo.get_bonsai_changeset(ctx.clone(), cs_id)
+        .join(repo.get_hg_from_bonsai_changeset(ctx, cs_id))
+        .map(|(bcs, hash)| CommitSummary {
+            hash,
+            author: bcs.author().to_string(),
+            summary: bcs.message().lines().next().unwrap_or("").to_string(),
+        })
+}
+
+/// Hex-encoded HMAC-SHA256 of `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(body);
+    hmac.result()
+        .code()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub trait WebhookClient: Send + Sync {
+    /// POST `body` to `url`. Fails unless the response status is a success.
+    fn post(
+        &self,
+        url: &str,
+        headers: Vec<(&'static str, String)>,
+        body: Bytes,
+    ) -> BoxFuture<(), Error>;
+}
+
+pub struct HyperWebhookClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl HyperWebhookClient {
+    pub fn new() -> Result<Self, Error> {
+        let connector = HttpsConnector::new(1)?;
+        Ok(Self {
+            client: Client::builder().build(connector),
+        })
+    }
+}
+
+impl WebhookClient for HyperWebhookClient {
+    fn post(
+        &self,
+        url: &str,
+        headers: Vec<(&'static str, String)>,
+        body: Bytes,
+    ) -> BoxFuture<(), Error> {
+        let mut request = Request::post(url);
+        request.header(CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request.header(name, value);
+        }
+        let request = try_boxfuture!(request.body(Body::from(body)));
+
+        let url = url.to_string();
+        self.client
+            .request(request)
+            .from_err()
+            .and_then(move |response| {
+                let status = response.status();
+                if status.is_success() {
+                    Ok(())
+                } else {
+                    Err(format_err!("webhook {} responded with {}", url, status))
+                }
+            })
+            .boxify()
+    }
+}
+
+/// Sends push events to the webhooks of a single repo.
+pub struct WebhookDispatcher {
+    webhooks: Vec<WebhookParams>,
+    client: Option<Arc<WebhookClient>>,
+    max_attempts: usize,
+    initial_backoff: Duration,
+    attempt_timeout: Duration,
+}
+
+impl WebhookDispatcher {
+    pub fn new(webhooks: Vec<WebhookParams>) -> Result<Self, Error> {
+        // Don't start the TLS machinery for the many repos without webhooks.
+        let client: Option<Arc<WebhookClient>> = if webhooks.is_empty() {
+            None
+        } else {
+            Some(Arc::new(HyperWebhookClient::new()?))
+        };
+        Ok(Self {
+            webhooks,
+            client,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            attempt_timeout: DEFAULT_ATTEMPT_TIMEOUT,
+        })
+    }
+
+    pub fn with_client(webhooks: Vec<WebhookParams>, client: Arc<WebhookClient>) -> Self {
+        Self {
+            webhooks,
+            client: Some(client),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            attempt_timeout: DEFAULT_ATTEMPT_TIMEOUT,
+        }
+    }
+
+    /// A delivery is attempted at most `max_attempts` times. The wait before the first retry
+    /// is `initial_backoff`, and it doubles after every failed retry.
+    pub fn with_retries(self, max_attempts: usize, initial_backoff: Duration) -> Self {
+        assert!(
+            max_attempts > 0,
+            "webhook deliveries need at least one attempt"
+        );
+        Self {
+            max_attempts,
+            initial_backoff,
+            ..self
+        }
+    }
+
+    /// Give up on a delivery attempt that didn't get a response within `attempt_timeout`.
+    pub fn with_attempt_timeout(self, attempt_timeout: Duration) -> Self {
+        Self {
+            attempt_timeout,
+            ..self
+        }
+    }
+
+    /// Send an event about `bookmark` moving from `old` to `new` to every webhook interested in
+    /// it. The event is built and delivered in the background, so this returns immediately.
+    pub fn notify(
+        &self,
+        ctx: CoreContext,
+        repo: BlobRepo,
+        lca_hint: Arc<LeastCommonAncestorsHint>,
+        bookmark: Bookmark,
+        old: Option<ChangesetId>,
+        new: Option<ChangesetId>,
+    ) {
+        if self
+            .webhooks
+            .iter()
+            .any(|webhook| webhook.matches(&bookmark))
+        {
+            tokio::spawn(self.dispatch(ctx, repo, lca_hint, bookmark, old, new));
+        }
+    }
+
+    /// Build the event for a bookmark move, and deliver it to every interested webhook.
+    /// Resolves once every delivery either succeeded or was given up on.
+    pub fn dispatch(
+        &self,
+        ctx: CoreContext,
+        repo: BlobRepo,
+        lca_hint: Arc<LeastCommonAncestorsHint>,
+        bookmark: Bookmark,
+        old: Option<ChangesetId>,
+        new: Option<ChangesetId>,
+    ) -> BoxFuture<(), ()> {
+        let webhooks: Vec<_> = self
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.matches(&bookmark))
+            .cloned()
+            .collect();
+        let client = match self.client {
+            Some(ref client) if !webhooks.is_empty() => client.clone(),
+            _ => return future::ok(()).boxify(),
+        };
+        STATS::events.add_value(1);
+
+        let logger = ctx.logger().clone();
+        let max_attempts = self.max_attempts;
+        let initial_backoff = self.initial_backoff;
+        let attempt_timeout = self.attempt_timeout;
+        PushEvent::new(ctx, repo, lca_hint, bookmark.clone(), old, new)
+            .and_then(|event| Ok(Bytes::from(serde_json::to_vec(&event)?)))
+            .then(move |body| match body {
+                Ok(body) => {
+                    let deliveries = webhooks.into_iter().map(move |webhook| {
+                        deliver(
+                            logger.clone(),
+                            client.clone(),
+                            webhook,
+                            body.clone(),
+                            max_attempts,
+                            initial_backoff,
+                            attempt_timeout,
+                        )
+                    });
+                    future::join_all(deliveries).map(|_| ()).left_future()
+                }
+                Err(err) => {
+                    STATS::dead_letters.add_value(1);
+                    error!(
+                        logger,
+                        "failed to build webhook event for {} moving from {:?} to {:?}: {:?}",
+                        bookmark,
+                        old,
+                        new,
+                        err
+                    );
+                    future::ok(()).right_future()
+                }
+            })
+            .boxify()
+    }
+}
+
+/// POST `body` to `webhook`, retrying failures and attempts that time out. Never fails: if the
+/// last attempt fails, the event is logged as a dead letter.
+fn deliver(
+    logger: Logger,
+    client: Arc<WebhookClient>,
+    webhook: WebhookParams,
+    body: Bytes,
+    max_attempts: usize,
+    initial_backoff: Duration,
+    attempt_timeout: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    let headers = match webhook.secret {
+        Some(ref secret) => vec![(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)))],
+        None => vec![],
+    };
+
+    loop_fn((1, initial_backoff), move |(attempt, backoff)| {
+        client
+            .post(&webhook.url, headers.clone(), body.clone())
+            .timeout(attempt_timeout)
+            .map_err(move |err| {
+                if err.is_elapsed() {
+                    STATS::timeouts.add_value(1);
+                    return format_err!("no response within {:?}", attempt_timeout);
+                }
+                err.into_inner()
+                    .unwrap_or_else(|| err_msg("timer failed while posting to webhook"))
+            })
+            .then({
+                cloned!(logger, webhook, body);
+                move |result| match result {
+                    Ok(()) => {
+                        STATS::deliveries.add_value(1);
+                        future::ok(Loop::Break(())).left_future()
+                    }
+                    Err(err) if attempt < max_attempts => {
+                        STATS::retries.add_value(1);
+                        warn!(
+                            logger,
+                            "webhook delivery to {} failed (attempt {} of {}), retrying: {:?}",
+                            webhook.url,
+                            attempt,
+                            max_attempts,
+                            err
+                        );
+                        Delay::new(Instant::now() + backoff)
+                            .then(move |_| Ok(Loop::Continue((attempt + 1, backoff * 2))))
+                            .right_future()
+                    }
+                    Err(err) => {
+                        STATS::dead_letters.add_value(1);
+                        error!(
+                            logger,
+                            "webhook delivery to {} failed after {} attempts, dropping event: {:?}",
+                            webhook.url,
+                            attempt,
+                            err;
+                            "dead_letter" => String::from_utf8_lossy(&body).into_owned()
+                        );
+                        future::ok(Loop::Break(())).left_future()
+                    }
+                }
+            })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use slog::Discard;
+
+    /// Fails the first `failures` posts, never responds to the `hangs` posts after them, and
+    /// records every post.
+    struct FakeClient {
+        failures: usize,
+        hangs: usize,
+        posts: Mutex<Vec<(String, Vec<(&'static str, String)>, Bytes)>>,
+    }
+
+    impl FakeClient {
+        fn new(failures: usize) -> Self {
+            Self {
+                failures,
+                hangs: 0,
+                posts: Mutex::new(vec![]),
+            }
+        }
+
+        fn hanging(hangs: usize) -> Self {
+            Self {
+                failures: 0,
+                hangs,
+                posts: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    impl WebhookClient for FakeClient {
+        fn post(
+            &self,
+            url: &str,
+            headers: Vec<(&'static str, String)>,
+            body: Bytes,
+        ) -> BoxFuture<(), Error> {
+            let mut posts = self.posts.lock().unwrap();
+            posts.push((url.to_string(), headers, body));
+            if posts.len() <= self.failures {
+                future::err(format_err!("unavailable")).boxify()
+            } else if posts.len() <= self.failures + self.hangs {
+                future::empty().boxify()
+            } else {
+                future::ok(()).boxify()
+            }
+        }
+    }
+
+    fn webhook(secret: Option<&str>) -> WebhookParams {
+        WebhookParams {
+            url: "https://example.com/hook".to_string(),
+            bookmarks: vec![],
+            secret: secret.map(String::from),
+        }
+    }
+
+    fn run_deliver(client: Arc<FakeClient>, webhook: WebhookParams, max_attempts: usize) {
+        let logger = Logger::root(Discard, o!());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(deliver(
+            logger,
+            client,
+            webhook,
+            Bytes::from("{}"),
+            max_attempts,
+            Duration::from_millis(1),
+            Duration::from_millis(100),
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn sign_matches_rfc4231() {
+        // Test case 2 of RFC 4231.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn deliver_retries_failures() {
+        let client = Arc::new(FakeClient::new(2));
+        run_deliver(client.clone(), webhook(Some("secret")), 5);
+
+        let posts = client.posts.lock().unwrap();
+        assert_eq!(posts.len(), 3);
+        let (ref url, ref headers, ref body) = posts[2];
+        assert_eq!(url, "https://example.com/hook");
+        assert_eq!(body, &Bytes::from("{}"));
+        assert_eq!(
+            headers,
+            &vec![(
+                SIGNATURE_HEADER,
+                format!("sha256={}", sign("secret", b"{}"))
+            )]
+        );
+    }
+
+    #[test]
+    fn deliver_gives_up_after_max_attempts() {
+        let client = Arc::new(FakeClient::new(10));
+        run_deliver(client.clone(), webhook(None), 3);
+
+        let posts = client.posts.lock().unwrap();
+        assert_eq!(posts.len(), 3);
+        assert!(posts.iter().all(|(_, headers, _)| headers.is_empty()));
+    }
+
+    #[test]
+    fn deliver_retries_timeouts() {
+        let client = Arc::new(FakeClient::hanging(2));
+        run_deliver(client.clone(), webhook(None), 5);
+        assert_eq!(client.posts.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn deliver_gives_up_on_hanging_webhook() {
+        let client = Arc::new(FakeClient::hanging(10));
+        run_deliver(client.clone(), webhook(None), 2);
+        assert_eq!(client.posts.lock().unwrap().len(), 2);
+    }
+}
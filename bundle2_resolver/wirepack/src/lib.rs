@@ -8,7 +8,7 @@ use std::fmt::Debug;
 use std::mem;
 
 use bytes::Bytes;
-use failure::{Error, Fail};
+use failure::{Error, Fail, ResultExt};
 use failure_ext::Result;
 use futures::{Poll, Stream};
 
@@ -21,6 +21,8 @@ use mercurial_types::{delta, HgNodeHash, HgNodeKey, RepoPath, NULL_HASH};
 pub enum ErrorKind {
     #[fail(display = "Malformed treemanifest part: {}", _0)]
     MalformedTreemanifestPart(String),
+    #[fail(display = "Corrupt delta received for {} at {}", _0, _1)]
+    CorruptDelta(HgNodeHash, RepoPath),
 }
 
 /// Parser for wirepack tree part. It returns a stream of TreemanifestEntry, that can be used by
@@ -66,7 +68,7 @@ pub struct TreemanifestEntry {
 }
 
 impl TreemanifestEntry {
-    fn new(node_key: HgNodeKey, data: Bytes, p1: HgNodeHash, p2: HgNodeHash) -> Result<Self> {
+    pub fn new(node_key: HgNodeKey, data: Bytes, p1: HgNodeHash, p2: HgNodeHash) -> Result<Self> {
         let manifest_content = ManifestContent::parse(data.as_ref())?;
 
         Ok(Self {
@@ -138,7 +140,10 @@ impl WirePackPartProcessor for TreemanifestPartProcessor {
             path: unwrap_field(&mut self.path, "path")?,
             hash: unwrap_field(&mut self.node, "node")?,
         };
-        let bytes = Bytes::from(delta::apply("".as_bytes(), &data_entry.delta)?);
+        let bytes = delta::apply("".as_bytes(), &data_entry.delta).context(
+            ErrorKind::CorruptDelta(node_key.hash, node_key.path.clone()),
+        )?;
+        let bytes = Bytes::from(bytes);
         let p1 = unwrap_field(&mut self.p1, "p1")?;
         let p2 = unwrap_field(&mut self.p2, "p2")?;
 
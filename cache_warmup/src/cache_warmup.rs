@@ -6,6 +6,8 @@
 
 extern crate bookmarks;
 #[macro_use]
+extern crate cloned;
+#[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_ext;
@@ -21,7 +23,7 @@ extern crate revset;
 use blobrepo::BlobRepo;
 use bookmarks::Bookmark;
 use context::CoreContext;
-use futures::{Future, IntoFuture, Stream};
+use futures::{future, stream, Future, IntoFuture, Stream};
 use futures_ext::{spawn_future, BoxFuture, FutureExt};
 use mercurial_types::manifest::{Entry, Type};
 use mercurial_types::manifest_utils::recursive_entry_stream;
@@ -95,6 +97,96 @@ fn blobstore_and_filenodes_warmup(
         .boxify()
 }
 
+// Fetches the root manifests of the tips of frequently used bookmarks. Unlike the main warmup
+// bookmark, missing bookmarks are only logged.
+fn root_manifests_warmup(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    bookmarks: Vec<Bookmark>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let warmups = bookmarks.into_iter().map(move |bookmark| {
+        repo.get_bookmark(ctx.clone(), &bookmark).and_then({
+            cloned!(ctx, repo, logger);
+            move |bookmark_rev| match bookmark_rev {
+                Some(bookmark_rev) => repo
+                    .get_changeset_by_changesetid(ctx.clone(), bookmark_rev)
+                    .and_then(move |cs| repo.get_root_entry(cs.manifestid()).get_content(ctx))
+                    .map(|_| ())
+                    .left_future(),
+                None => {
+                    info!(logger, "{} bookmark not found, skipping its warmup", bookmark);
+                    Ok(()).into_future().right_future()
+                }
+            }
+        })
+    });
+
+    future::join_all(warmups).map(|_| ()).boxify()
+}
+
+// Fetches the filenodes of the files changed in the most recent `cs_limit` commits
+fn filenodes_warmup(
+    ctx: CoreContext,
+    start_rev: HgChangesetId,
+    repo: BlobRepo,
+    cs_limit: usize,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    // TODO(stash): Arbitrary number. Tweak somehow?
+    let buffer_size = 100;
+    if cs_limit == 0 {
+        return Ok(()).into_future().boxify();
+    }
+    info!(logger, "about to start warming up filenodes cache");
+
+    repo.get_bonsai_from_hg(ctx.clone(), start_rev)
+        .and_then(move |maybe_node| {
+            maybe_node.ok_or(errors::ErrorKind::BookmarkValueNotFound(start_rev).into())
+        })
+        .and_then(move |start_rev| {
+            AncestorsNodeStream::new(ctx.clone(), &repo.get_changeset_fetcher(), start_rev)
+                .take(cs_limit as u64)
+                .map({
+                    cloned!(ctx, repo);
+                    move |cs_id| {
+                        repo.get_hg_from_bonsai_changeset(ctx.clone(), cs_id)
+                            .and_then({
+                                cloned!(ctx, repo);
+                                move |hg_cs_id| repo.get_changeset_by_changesetid(ctx, hg_cs_id)
+                            })
+                    }
+                })
+                .buffered(buffer_size)
+                .map(move |cs| {
+                    let manifest_id = cs.manifestid();
+                    let files = cs.files().to_vec();
+                    cloned!(ctx, repo);
+                    stream::iter_ok(files).map(move |path| {
+                        repo.find_file_in_manifest(ctx.clone(), &path, manifest_id)
+                            .and_then({
+                                cloned!(ctx, repo);
+                                move |maybe_file| match maybe_file {
+                                    // Deleted files have no filenode in this commit
+                                    None => Ok(()).into_future().left_future(),
+                                    Some((_, filenode)) => repo
+                                        .get_linknode(ctx, &RepoPath::FilePath(path), filenode)
+                                        .map(|_| ())
+                                        .right_future(),
+                                }
+                            })
+                    })
+                })
+                .flatten()
+                .buffer_unordered(buffer_size)
+                .for_each(|()| Ok(()))
+                .map(move |()| {
+                    debug!(logger, "finished filenodes warmup");
+                })
+        })
+        .boxify()
+}
+
 // Iterate over first parents, and fetch them
 fn changesets_warmup(
     ctx: CoreContext,
@@ -125,10 +217,23 @@ fn changesets_warmup(
 fn do_cache_warmup(
     ctx: CoreContext,
     repo: BlobRepo,
-    bookmark: Bookmark,
-    commit_limit: usize,
+    params: CacheWarmupParams,
     logger: Logger,
 ) -> BoxFuture<(), Error> {
+    let CacheWarmupParams {
+        bookmark,
+        commit_limit,
+        hot_bookmarks,
+        filenodes_commit_limit,
+    } = params;
+
+    let hot_bookmarks_warmup = spawn_future(root_manifests_warmup(
+        ctx.clone(),
+        repo.clone(),
+        hot_bookmarks,
+        logger.clone(),
+    ));
+
     repo.get_bookmark(ctx.clone(), &bookmark)
         .and_then({
             let logger = logger.clone();
@@ -142,13 +247,23 @@ fn do_cache_warmup(
                         logger.clone(),
                     ));
                     let cs_warmup = spawn_future(changesets_warmup(
+                        ctx.clone(),
+                        bookmark_rev,
+                        repo.clone(),
+                        commit_limit,
+                        logger.clone(),
+                    ));
+                    let filenodes_warmup = spawn_future(filenodes_warmup(
                         ctx,
                         bookmark_rev,
                         repo,
-                        commit_limit,
+                        filenodes_commit_limit,
                         logger,
                     ));
-                    blobstore_warmup.join(cs_warmup).map(|_| ()).boxify()
+                    blobstore_warmup
+                        .join3(cs_warmup, filenodes_warmup)
+                        .map(|_| ())
+                        .boxify()
                 }
                 None => {
                     info!(logger, "{} bookmark not found!", bookmark);
@@ -158,7 +273,8 @@ fn do_cache_warmup(
                 }
             }
         })
-        .map(move |()| {
+        .join(hot_bookmarks_warmup)
+        .map(move |_| {
             info!(logger, "finished initial warmup");
             ()
         })
@@ -166,7 +282,8 @@ fn do_cache_warmup(
 }
 
 /// Fetch all manifest entries for a bookmark, and fetches up to `commit_warmup_limit`
-/// ancestors of the bookmark.
+/// ancestors of the bookmark. Also fetches the filenodes changed in the most recent
+/// `filenodes_commit_limit` of those ancestors, and the root manifests of the hot bookmarks.
 pub fn cache_warmup(
     ctx: CoreContext,
     repo: BlobRepo,
@@ -174,13 +291,7 @@ pub fn cache_warmup(
     logger: Logger,
 ) -> BoxFuture<(), Error> {
     match cache_warmup {
-        Some(cache_warmup) => do_cache_warmup(
-            ctx,
-            repo,
-            cache_warmup.bookmark,
-            cache_warmup.commit_limit,
-            logger.clone(),
-        ),
+        Some(cache_warmup) => do_cache_warmup(ctx, repo, cache_warmup, logger.clone()),
         None => Ok(()).into_future().boxify(),
     }
 }
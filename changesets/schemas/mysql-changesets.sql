@@ -0,0 +1,15 @@
+CREATE TABLE changesets (
+  id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  repo_id INT UNSIGNED NOT NULL,
+  cs_id VARBINARY(32) NOT NULL,
+  gen BIGINT UNSIGNED NOT NULL,
+  PRIMARY KEY (id),
+  UNIQUE KEY repo_id_cs_id (repo_id, cs_id)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE csparents (
+  cs_id BIGINT UNSIGNED NOT NULL,
+  parent_id BIGINT UNSIGNED NOT NULL,
+  seq INT UNSIGNED NOT NULL,
+  PRIMARY KEY (cs_id, seq)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -6,6 +6,7 @@
 
 pub use failure::{Error, Result};
 
+use mononoke_errors::{Categorize, ErrorCategory};
 use mononoke_types::ChangesetId;
 
 #[derive(Debug, Eq, Fail, PartialEq)]
@@ -14,3 +15,12 @@ pub enum ErrorKind {
     DuplicateInsertionInconsistency(ChangesetId, Vec<ChangesetId>, Vec<ChangesetId>),
     #[fail(display = "Missing parents")] MissingParents(Vec<ChangesetId>),
 }
+
+impl Categorize for ErrorKind {
+    fn category(&self) -> Option<ErrorCategory> {
+        match self {
+            ErrorKind::DuplicateInsertionInconsistency(..) => Some(ErrorCategory::Internal),
+            ErrorKind::MissingParents(_) => Some(ErrorCategory::InvalidRequest),
+        }
+    }
+}
@@ -29,6 +29,7 @@ extern crate changeset_entry_thrift;
 extern crate context;
 #[macro_use]
 extern crate futures_ext;
+extern crate mononoke_errors;
 extern crate mononoke_types;
 #[cfg(test)]
 extern crate mononoke_types_mocks;
@@ -42,6 +43,7 @@ use std::result;
 use bytes::Bytes;
 
 use sql::{Connection, Transaction};
+use sql_ext::migrations::{Migration, SqlMigrations};
 pub use sql_ext::SqlConstructors;
 
 use context::CoreContext;
@@ -228,6 +230,24 @@ queries! {
          WHERE repo_id = {repo_id}"
     }
 
+    read SelectChangesetsInRange(repo_id: RepositoryId, min_id: u64, max_id: u64) -> (u64, ChangesetId, u64, Option<ChangesetId>) {
+        "SELECT cs.id, cs.cs_id, cs.gen, pcs.cs_id
+         FROM changesets cs
+         LEFT JOIN (csparents p, changesets pcs)
+         ON (cs.id = p.cs_id AND p.parent_id = pcs.id)
+         WHERE cs.repo_id = {repo_id}
+           AND cs.id BETWEEN {min_id} AND {max_id}
+         ORDER BY cs.id ASC, p.seq ASC"
+    }
+
+    write UpdateGeneration(repo_id: RepositoryId, cs_id: ChangesetId, gen: u64) {
+        none,
+        "UPDATE changesets
+         SET gen = {gen}
+         WHERE repo_id = {repo_id}
+           AND cs_id = {cs_id}"
+    }
+
 }
 
 impl SqlConstructors for SqlChangesets {
@@ -248,6 +268,47 @@ impl SqlConstructors for SqlChangesets {
     }
 }
 
+impl SqlMigrations for SqlChangesets {
+    const STORE: &'static str = "changesets";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the changesets and csparents tables",
+            sqlite: "CREATE TABLE changesets (
+                       -- Sqlite doesn't support autoincrement UNSIGNED BIGINT
+                       id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                       repo_id INTEGER NOT NULL,
+                       cs_id VARBINARY(32) NOT NULL,
+                       gen BIGINT NOT NULL,
+                       UNIQUE (repo_id, cs_id)
+                     );
+
+                     CREATE TABLE csparents (
+                       cs_id BIGINT NOT NULL,
+                       parent_id BIGINT NOT NULL,
+                       seq INTEGER NOT NULL,
+                       PRIMARY KEY (cs_id, seq)
+                     );",
+            mysql: "CREATE TABLE changesets (
+                      id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+                      repo_id INT UNSIGNED NOT NULL,
+                      cs_id VARBINARY(32) NOT NULL,
+                      gen BIGINT UNSIGNED NOT NULL,
+                      PRIMARY KEY (id),
+                      UNIQUE KEY repo_id_cs_id (repo_id, cs_id)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+                    CREATE TABLE csparents (
+                      cs_id BIGINT UNSIGNED NOT NULL,
+                      parent_id BIGINT UNSIGNED NOT NULL,
+                      seq INT UNSIGNED NOT NULL,
+                      PRIMARY KEY (cs_id, seq)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
 impl Changesets for SqlChangesets {
     fn add(&self, _ctxt: CoreContext, cs: ChangesetInsert) -> BoxFuture<bool, Error> {
         STATS::adds.add_value(1);
@@ -400,6 +461,54 @@ impl SqlChangesets {
             })
             .boxify()
     }
+
+    /// The changesets with ids in [min_id, max_id), in the order they were added, so that
+    /// parents come before their children
+    pub fn get_entries_in_range(
+        &self,
+        repo_id: RepositoryId,
+        min_id: u64,
+        max_id: u64,
+    ) -> BoxFuture<Vec<ChangesetEntry>, Error> {
+        cloned!(self.read_master_connection);
+        // As SQL request is BETWEEN, both bounds including
+        let max_id = max_id - 1;
+
+        SelectChangesetsInRange::query(&read_master_connection, &repo_id, &min_id, &max_id)
+            .map(move |rows| {
+                let mut entries: Vec<ChangesetEntry> = vec![];
+                let mut last_id = None;
+                for (id, cs_id, gen, maybe_parent) in rows {
+                    if last_id != Some(id) {
+                        last_id = Some(id);
+                        entries.push(ChangesetEntry {
+                            repo_id,
+                            cs_id,
+                            parents: vec![],
+                            gen,
+                        });
+                    }
+                    if let Some(entry) = entries.last_mut() {
+                        entry.parents.extend(maybe_parent);
+                    }
+                }
+                entries
+            })
+            .boxify()
+    }
+
+    /// Overwrite the generation number of `cs_id`, to repair one that was stored wrong. Returns
+    /// whether a row changed. Caches of changesets aren't updated.
+    pub fn update_generation_number(
+        &self,
+        repo_id: RepositoryId,
+        cs_id: ChangesetId,
+        gen: u64,
+    ) -> BoxFuture<bool, Error> {
+        UpdateGeneration::query(&self.write_connection, &repo_id, &cs_id, &gen)
+            .map(|result| result.affected_rows() > 0)
+            .boxify()
+    }
 }
 
 fn check_missing_rows(
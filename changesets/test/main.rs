@@ -451,6 +451,82 @@ fn get_many<C: Changesets>(changesets: C) {
     );
 }
 
+fn entries_in_range_and_repair(changesets: SqlChangesets) {
+    let ctx = CoreContext::test_mock();
+
+    let rows = vec![
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![ONES_CSID]),
+        (THREES_CSID, vec![TWOS_CSID, ONES_CSID]),
+    ];
+    for (cs_id, parents) in rows {
+        let row = ChangesetInsert {
+            repo_id: REPO_ZERO,
+            cs_id,
+            parents,
+        };
+        changesets
+            .add(ctx.clone(), row)
+            .wait()
+            .expect("Adding new entry failed");
+    }
+
+    let (min_id, max_id) = changesets
+        .get_changesets_ids_bounds(REPO_ZERO)
+        .wait()
+        .expect("Getting bounds failed");
+    let (min_id, max_id) = (min_id.unwrap(), max_id.unwrap());
+    let entries = changesets
+        .get_entries_in_range(REPO_ZERO, min_id, max_id + 1)
+        .wait()
+        .expect("Getting range failed");
+    assert_eq!(
+        entries,
+        vec![
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: ONES_CSID,
+                parents: vec![],
+                gen: 1,
+            },
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: TWOS_CSID,
+                parents: vec![ONES_CSID],
+                gen: 2,
+            },
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: THREES_CSID,
+                parents: vec![TWOS_CSID, ONES_CSID],
+                gen: 3,
+            },
+        ]
+    );
+    let entries = changesets
+        .get_entries_in_range(REPO_ZERO, min_id, max_id)
+        .wait()
+        .expect("Getting range failed");
+    assert_eq!(entries.len(), 2);
+
+    let updated = changesets
+        .update_generation_number(REPO_ZERO, THREES_CSID, 7)
+        .wait()
+        .expect("Updating generation failed");
+    assert!(updated);
+    let result = changesets
+        .get(ctx, REPO_ZERO, THREES_CSID)
+        .wait()
+        .expect("Get failed");
+    assert_eq!(result.map(|entry| entry.gen), Some(7));
+
+    let updated = changesets
+        .update_generation_number(REPO_ZERO, FOURS_CSID, 7)
+        .wait()
+        .expect("Updating generation failed");
+    assert!(!updated);
+}
+
 #[test]
 fn test_add_and_get() {
     async_unit::tokio_unit_test(|| {
@@ -499,3 +575,10 @@ fn test_get_many() {
         get_many(SqlChangesets::with_sqlite_in_memory().unwrap());
     });
 }
+
+#[test]
+fn test_entries_in_range_and_repair() {
+    async_unit::tokio_unit_test(|| {
+        entries_in_range_and_repair(SqlChangesets::with_sqlite_in_memory().unwrap());
+    });
+}
@@ -48,6 +48,11 @@ const CACHE_ARGS: &[(&str, &str)] = &[
         "changesets-cache-size",
         "override size of the changesets cache",
     ),
+    (
+        "changeset-fetcher-cache-size",
+        "override size of the parents and generation numbers cache shared by revsets, \
+         reachability and phases",
+    ),
     (
         "filenodes-cache-size",
         "override size of the filenodes cache",
@@ -60,6 +65,10 @@ const CACHE_ARGS: &[(&str, &str)] = &[
         "content-sha1-cache-size",
         "override size of the content SHA1 cache",
     ),
+    (
+        "response-cache-size",
+        "override size of the gettreepack and getfiles response cache",
+    ),
 ];
 
 pub struct MononokeApp {
@@ -297,16 +306,19 @@ where
     T: SqlConstructors,
 {
     let (_, config) = get_config(matches)?;
-    match config.repotype {
-        RepoType::BlobFiles(ref data_dir)
-        | RepoType::BlobRocks(ref data_dir)
-        | RepoType::BlobSqlite(ref data_dir) => T::with_sqlite_path(data_dir.join(name)),
-        RepoType::BlobRemote { ref db_address, .. } => {
-            let myrouter_port =
-                parse_myrouter_port(matches).expect("myrouter port provided is not provided");
-            Ok(T::with_myrouter(&db_address, myrouter_port))
-        }
-    }
+    open_sql_with_config(matches, &config, name)
+}
+
+/// Like `open_sql`, for the repo of `config` rather than the one of --repo-id
+pub fn open_sql_with_config<T>(
+    matches: &ArgMatches,
+    config: &RepoConfig,
+    name: &'static str,
+) -> Result<T>
+where
+    T: SqlConstructors,
+{
+    T::with_repo_type(&config.repotype, try_parse_myrouter_port(matches)?, name)
 }
 
 pub fn open_sql_changesets(matches: &ArgMatches) -> Result<SqlChangesets> {
@@ -393,6 +405,9 @@ pub fn add_cachelib_args<'a, 'b>(app: App<'a, 'b>, hide_advanced_args: bool) ->
     .arg(Arg::from_usage(
             "--with-content-sha1-cache  '[Mononoke API Server only] enable content SHA1 cache'"
     ))
+    .arg(Arg::from_usage(
+            "--with-response-cache  '[Mononoke server only] enable gettreepack and getfiles response cache'"
+    ))
     .args_from_usage(
         r#"
         --do-not-init-cachelib 'do not init cachelib (useful for tests)'
@@ -493,6 +508,11 @@ pub fn init_cachelib<'a>(matches: &ArgMatches<'a>) {
         get_usize(matches, "changesets-cache-size", available_space / 20),
     )
     .unwrap();
+    cachelib::get_or_create_pool(
+        "changeset_fetcher",
+        get_usize(matches, "changeset-fetcher-cache-size", available_space / 20),
+    )
+    .unwrap();
     cachelib::get_or_create_pool(
         "filenodes",
         get_usize(matches, "filenodes-cache-size", available_space / 20),
@@ -512,6 +532,14 @@ pub fn init_cachelib<'a>(matches: &ArgMatches<'a>) {
         .unwrap();
     }
 
+    if matches.is_present("with-response-cache") {
+        cachelib::get_or_create_pool(
+            "response-fragments",
+            get_usize(matches, "response-cache-size", available_space / 20),
+        )
+        .unwrap();
+    }
+
     cachelib::get_or_create_pool(
         "blobstore-blobs",
         get_usize(
@@ -560,15 +588,18 @@ fn open_repo_internal<'a>(
     info!(logger, "using repo \"{}\" repoid {:?}", reponame, repo_id);
     let logger = match config.repotype {
         RepoType::BlobFiles(ref data_dir) => {
-            setup_repo_dir(&data_dir, create).expect("Setting up file blobrepo failed");
+            try_boxfuture!(setup_repo_dir(&data_dir, create)
+                .with_context(|_| "Setting up file blobrepo failed"));
             logger.new(o!["BlobRepo:Files" => data_dir.to_string_lossy().into_owned()])
         }
         RepoType::BlobRocks(ref data_dir) => {
-            setup_repo_dir(&data_dir, create).expect("Setting up rocksdb blobrepo failed");
+            try_boxfuture!(setup_repo_dir(&data_dir, create)
+                .with_context(|_| "Setting up rocksdb blobrepo failed"));
             logger.new(o!["BlobRepo:Rocksdb" => data_dir.to_string_lossy().into_owned()])
         }
         RepoType::BlobSqlite(ref data_dir) => {
-            setup_repo_dir(&data_dir, create).expect("Setting up sqlite blobrepo failed");
+            try_boxfuture!(setup_repo_dir(&data_dir, create)
+                .with_context(|_| "Setting up sqlite blobrepo failed"));
             logger.new(o!["BlobRepo:Sqlite" => data_dir.to_string_lossy().into_owned()])
         }
         RepoType::BlobRemote {
@@ -577,7 +608,7 @@ fn open_repo_internal<'a>(
         } => logger.new(o!["BlobRepo:Remote" => format!("{:?}", blobstores_args)]),
     };
 
-    let myrouter_port = parse_myrouter_port(matches);
+    let myrouter_port = try_boxfuture!(try_parse_myrouter_port(matches));
     open_blobrepo(
         logger.clone(),
         config.repotype.clone(),
@@ -600,6 +631,8 @@ pub fn parse_blobstore_args<'a>(matches: &ArgMatches<'a>) -> RemoteBlobstoreArgs
                 .ok()
                 .and_then(NonZeroUsize::new)
                 .expect("Provided mysql-blobstore-shard-num must be int larger than 0"),
+            hedging: None,
+            compression_level: None,
         }),
         None => RemoteBlobstoreArgs::Manifold(ManifoldArgs {
             bucket: matches.value_of("manifold-bucket").unwrap().to_string(),
@@ -609,12 +642,19 @@ pub fn parse_blobstore_args<'a>(matches: &ArgMatches<'a>) -> RemoteBlobstoreArgs
 }
 
 pub fn parse_myrouter_port<'a>(matches: &ArgMatches<'a>) -> Option<u16> {
+    try_parse_myrouter_port(matches).expect("Provided --myrouter-port is not u16")
+}
+
+/// Like `parse_myrouter_port`, but fails instead of panicking if the port is invalid
+pub fn try_parse_myrouter_port<'a>(matches: &ArgMatches<'a>) -> Result<Option<u16>> {
     match matches.value_of("myrouter-port") {
-        Some(port) => Some(
-            port.parse::<u16>()
-                .expect("Provided --myrouter-port is not u16"),
-        ),
-        None => None,
+        Some(port) => {
+            let port = port
+                .parse::<u16>()
+                .with_context(|_| format!("--myrouter-port {} is not a port", port))?;
+            Ok(Some(port))
+        }
+        None => Ok(None),
     }
 }
 
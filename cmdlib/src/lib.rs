@@ -43,3 +43,4 @@ extern crate scuba_ext;
 extern crate sshrelay;
 
 pub mod args;
+pub mod startup;
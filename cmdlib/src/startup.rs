@@ -0,0 +1,150 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Reporting of the errors that keep a binary from starting.
+//!
+//! Startup goes through all of its configuration and initialization before giving up, so that a
+//! single run reports every problem rather than the first one. The exit code tells a bad
+//! configuration, which restarting won't fix, from a failed initialization, which it might.
+
+use std::fmt;
+use std::process;
+
+use failure::{Error, Result, SlogKVError};
+use slog::Logger;
+
+use metaconfig_parser::RepoConfigs;
+use metaconfig_types::RepoType;
+
+/// Exit code of a binary that failed after it started
+pub const EXIT_RUNTIME_ERROR: i32 = 1;
+/// Exit code of a binary whose arguments or config files are invalid
+pub const EXIT_CONFIG_ERROR: i32 = 2;
+/// Exit code of a binary that couldn't set up what its config asks for
+pub const EXIT_INIT_ERROR: i32 = 3;
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum StartupStage {
+    /// Parsing arguments and config files
+    Config,
+    /// Setting up runtimes, connections, TLS and the like from the config
+    Init,
+}
+
+impl StartupStage {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupStage::Config => EXIT_CONFIG_ERROR,
+            StartupStage::Init => EXIT_INIT_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for StartupStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartupStage::Config => write!(f, "config"),
+            StartupStage::Init => write!(f, "init"),
+        }
+    }
+}
+
+/// Errors collected while starting up
+#[derive(Debug, Default)]
+pub struct StartupErrors {
+    errors: Vec<(StartupStage, String, Error)>,
+}
+
+impl StartupErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the error of `res` in parsing the config, if it has one
+    pub fn config<T, E: Into<Error>>(
+        &mut self,
+        context: impl Into<String>,
+        res: std::result::Result<T, E>,
+    ) -> Option<T> {
+        self.check(StartupStage::Config, context, res)
+    }
+
+    /// Record the error of `res` in initializing, if it has one
+    pub fn init<T, E: Into<Error>>(
+        &mut self,
+        context: impl Into<String>,
+        res: std::result::Result<T, E>,
+    ) -> Option<T> {
+        self.check(StartupStage::Init, context, res)
+    }
+
+    pub fn check<T, E: Into<Error>>(
+        &mut self,
+        stage: StartupStage,
+        context: impl Into<String>,
+        res: std::result::Result<T, E>,
+    ) -> Option<T> {
+        match res {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.errors.push((stage, context.into(), err.into()));
+                None
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Exit code for the errors: the one of the earliest stage that failed
+    pub fn exit_code(&self) -> i32 {
+        self.errors
+            .iter()
+            .map(|(stage, _, _)| *stage)
+            .min()
+            .map(|stage| stage.exit_code())
+            .unwrap_or(EXIT_INIT_ERROR)
+    }
+
+    /// Log all the errors and exit
+    pub fn exit(self, logger: &Logger) -> ! {
+        let exit_code = self.exit_code();
+        let count = self.errors.len();
+        for (stage, context, err) in self.errors {
+            crit!(logger, "{} error while {}", stage, context; SlogKVError(err));
+        }
+        crit!(
+            logger,
+            "failed to start up with {} errors, exiting with {}",
+            count,
+            exit_code
+        );
+        process::exit(exit_code);
+    }
+}
+
+/// Check that the myrouter port is set if an enabled repo has its databases behind MyRouter
+pub fn check_myrouter_port(configs: &RepoConfigs, myrouter_port: Option<u16>) -> Result<()> {
+    let mut remote_repos: Vec<_> = configs
+        .repos
+        .iter()
+        .filter(|(_, config)| config.enabled)
+        .filter(|(_, config)| match config.repotype {
+            RepoType::BlobRemote { .. } => true,
+            _ => false,
+        })
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if myrouter_port.is_none() && !remote_repos.is_empty() {
+        remote_repos.sort();
+        bail_msg!(
+            "--myrouter-port is required by remote repos {}",
+            remote_repos.join(", ")
+        );
+    }
+    Ok(())
+}
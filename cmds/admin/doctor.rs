@@ -0,0 +1,503 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Checks that a configured repo is fit to be served: its config parses, its storage can be
+//! written and read, and its bookmarks, skiplist and derived data are in order. The report of
+//! the checks is meant to gate deployments: the command exits with an error if a check fails.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use clap::{App, ArgMatches};
+use cloned::cloned;
+use failure_ext::{format_err, Error, Result};
+use futures::{future, stream, Future, Stream};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use serde_derive::Serialize;
+use slog::Logger;
+
+use blobrepo::BlobRepo;
+use blobrepo_factory::open_blobrepo;
+use blobstore::{Blobstore, BlobstoreBytes};
+use bookmarks::Bookmark;
+use changesets::SqlChangesets;
+use cmdlib::args;
+use context::CoreContext;
+use metaconfig_types::RepoConfig;
+use mononoke_types::{ChangesetId, DateTime, RepositoryId};
+use mutable_counters::{MutableCounters, SqlMutableCounters};
+use skiplist::deserialize_skiplist_map;
+
+const CONFIG: &'static str = "config";
+const REPO: &'static str = "repo";
+const BLOBSTORE: &'static str = "blobstore";
+const SQL: &'static str = "sql";
+const BOOKMARKS: &'static str = "bookmarks";
+const SKIPLIST: &'static str = "skiplist";
+const DERIVED_DATA: &'static str = "derived-data";
+
+/// Blobstores can't delete keys, so every run overwrites the same probe key
+const PROBE_KEY: &'static str = "doctor.probe";
+/// Counter read to check the mutable_counters table. It doesn't have to exist
+const PROBE_COUNTER: &'static str = "doctor.probe";
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("check that a repo is fit to be served, and report passed, warning and failed checks")
+        .args_from_usage(
+            r#"
+            [REPO]            'name of the repo to check, the repo of --repo-id if omitted'
+            --json            'if provided the report will be json'
+            --fail-on-warn    'exit with an error if a check warns, not only if one fails'
+            "#,
+        )
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    message: String,
+}
+
+impl Check {
+    fn new<S: Into<String>>(name: &'static str, status: Status, message: S) -> Self {
+        Self {
+            name,
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    repo: Option<String>,
+    status: Status,
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn new(repo: Option<String>, checks: Vec<Check>) -> Self {
+        let status = checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(Status::Pass);
+        Self {
+            repo,
+            status,
+            checks,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "{}  {:<12}  {}", check.status, check.name, check.message)?;
+        }
+        match self.repo {
+            Some(ref repo) => write!(f, "{}: {}", repo, self.status),
+            None => write!(f, "{}", self.status),
+        }
+    }
+}
+
+pub fn handle_command<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let json = sub_m.is_present("json");
+    let fail_on_warn = sub_m.is_present("fail-on-warn");
+
+    let checks = match find_config(matches, sub_m.value_of("REPO")) {
+        Ok((name, config)) => {
+            let config_check = if config.enabled {
+                Check::new(
+                    CONFIG,
+                    Status::Pass,
+                    format!("repo {} (id {}) is configured", name, config.repoid),
+                )
+            } else {
+                Check::new(
+                    CONFIG,
+                    Status::Warn,
+                    format!("repo {} (id {}) is disabled", name, config.repoid),
+                )
+            };
+            run_checks(ctx, matches, logger, config)
+                .map(move |mut checks| {
+                    checks.insert(0, config_check);
+                    (Some(name), checks)
+                })
+                .boxify()
+        }
+        Err(err) => future::ok((
+            None,
+            vec![Check::new(CONFIG, Status::Fail, err.to_string())],
+        ))
+        .boxify(),
+    };
+
+    checks
+        .map(move |(repo, checks)| {
+            let report = Report::new(repo, checks);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            } else {
+                println!("{}", report);
+            }
+            let failed = match report.status {
+                Status::Pass => false,
+                Status::Warn => fail_on_warn,
+                Status::Fail => true,
+            };
+            if failed {
+                ::std::process::exit(1);
+            }
+        })
+        .boxify()
+}
+
+/// The config of repo `name`, or of the repo of --repo-id if None
+pub(crate) fn find_config<'a>(
+    matches: &ArgMatches<'a>,
+    name: Option<&str>,
+) -> Result<(String, RepoConfig)> {
+    match name {
+        Some(name) => {
+            let mut configs = args::read_configs(matches)?;
+            configs
+                .repos
+                .remove(name)
+                .map(|config| (name.to_string(), config))
+                .ok_or_else(|| format_err!("repo {} is not configured", name))
+        }
+        None => args::get_config(matches),
+    }
+}
+
+// Run a check, which fails if its future does
+fn run_check<F>(name: &'static str, check: F) -> BoxFuture<Check, Error>
+where
+    F: Future<Item = (Status, String), Error = Error> + Send + 'static,
+{
+    check
+        .then(move |res| {
+            let (status, message) = res.unwrap_or_else(|err| (Status::Fail, err.to_string()));
+            Ok(Check::new(name, status, message))
+        })
+        .boxify()
+}
+
+fn run_checks<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    logger: Logger,
+    config: RepoConfig,
+) -> BoxFuture<Vec<Check>, Error> {
+    let repo_id = RepositoryId::new(config.repoid);
+    let sql_stores = args::open_sql_with_config::<SqlChangesets>(matches, &config, "changesets")
+        .and_then(|changesets| {
+            args::open_sql_with_config::<SqlMutableCounters>(matches, &config, "mutable_counters")
+                .map(|counters| (changesets, counters))
+        });
+    let skiplist_key = config.skiplist_index_blobstore_key.clone();
+    let myrouter_port = args::parse_myrouter_port(matches);
+
+    open_blobrepo(logger, config.repotype, repo_id, myrouter_port)
+        .then(move |res| match res {
+            Ok(repo) => {
+                let checks = vec![
+                    run_check(BLOBSTORE, check_blobstore(ctx.clone(), repo.clone())),
+                    run_check(SQL, check_sql(ctx.clone(), repo_id, sql_stores)),
+                ];
+                future::join_all(checks)
+                    .join(run_tip_checks(ctx, repo, skiplist_key))
+                    .map(|(mut checks, tip_checks)| {
+                        checks.extend(tip_checks);
+                        checks
+                    })
+                    .left_future()
+            }
+            Err(err) => future::ok(vec![Check::new(
+                REPO,
+                Status::Fail,
+                format!("can't open the repo: {}", err),
+            )])
+            .right_future(),
+        })
+        .boxify()
+}
+
+// The checks that look at the changesets that bookmarks point to
+fn run_tip_checks(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    skiplist_key: Option<String>,
+) -> BoxFuture<Vec<Check>, Error> {
+    repo.get_bonsai_bookmarks(ctx.clone())
+        .collect()
+        .then(move |res| match res {
+            Ok(tips) => {
+                let checks = vec![
+                    run_check(
+                        BOOKMARKS,
+                        check_bookmarks(ctx.clone(), repo.clone(), tips.clone()),
+                    ),
+                    run_check(
+                        SKIPLIST,
+                        check_skiplist(ctx.clone(), repo.clone(), skiplist_key, tips.clone()),
+                    ),
+                    run_check(DERIVED_DATA, check_derived_data(ctx, repo, tips)),
+                ];
+                future::join_all(checks).left_future()
+            }
+            Err(err) => {
+                let message = format!("can't list bookmarks: {}", err);
+                future::ok(
+                    [BOOKMARKS, SKIPLIST, DERIVED_DATA]
+                        .iter()
+                        .map(|name| Check::new(*name, Status::Fail, message.clone()))
+                        .collect(),
+                )
+                .right_future()
+            }
+        })
+        .boxify()
+}
+
+fn check_blobstore(ctx: CoreContext, repo: BlobRepo) -> BoxFuture<(Status, String), Error> {
+    let blobstore = repo.get_blobstore();
+    let probe = format!("mononoke admin doctor probe {}", DateTime::now());
+    let value = BlobstoreBytes::from_bytes(probe.into_bytes());
+
+    blobstore
+        .put(ctx.clone(), PROBE_KEY.to_string(), value.clone())
+        .and_then(move |()| blobstore.get(ctx, PROBE_KEY.to_string()))
+        .map(move |read| match read {
+            Some(ref read) if *read == value => {
+                (Status::Pass, format!("wrote and read back {}", PROBE_KEY))
+            }
+            Some(_) => (
+                Status::Fail,
+                format!("read back something else than was written to {}", PROBE_KEY),
+            ),
+            None => (
+                Status::Fail,
+                format!("{} is missing right after it was written", PROBE_KEY),
+            ),
+        })
+        .boxify()
+}
+
+// SQL schemas have no version to compare to, so the check reads the tables: reads fail if a
+// table is missing or its columns don't match the queries
+fn check_sql(
+    ctx: CoreContext,
+    repo_id: RepositoryId,
+    sql_stores: Result<(SqlChangesets, SqlMutableCounters)>,
+) -> BoxFuture<(Status, String), Error> {
+    let (changesets, counters) = try_boxfuture!(sql_stores);
+
+    changesets
+        .get_changesets_ids_bounds(repo_id)
+        .join(counters.get_counter(ctx, repo_id, PROBE_COUNTER))
+        .map(|_| {
+            (
+                Status::Pass,
+                "the changesets and mutable_counters tables can be read".to_string(),
+            )
+        })
+        .boxify()
+}
+
+fn check_bookmarks(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    tips: Vec<(Bookmark, ChangesetId)>,
+) -> BoxFuture<(Status, String), Error> {
+    if tips.is_empty() {
+        return future::ok((Status::Warn, "the repo has no bookmarks".to_string())).boxify();
+    }
+
+    let count = tips.len();
+    stream::iter_ok(tips)
+        .map(move |(bookmark, cs_id)| {
+            repo.changeset_exists_by_bonsai(ctx.clone(), cs_id)
+                .map(move |exists| (bookmark, exists))
+        })
+        .buffered(100)
+        .filter_map(|(bookmark, exists)| if exists { None } else { Some(bookmark) })
+        .collect()
+        .map(move |unknown| {
+            if unknown.is_empty() {
+                (
+                    Status::Pass,
+                    format!("all {} bookmarks point to known changesets", count),
+                )
+            } else {
+                (
+                    Status::Fail,
+                    format!(
+                        "bookmarks point to unknown changesets: {}",
+                        join_bookmarks(&unknown)
+                    ),
+                )
+            }
+        })
+        .boxify()
+}
+
+// The skiplist is fresh if it indexes all tips. It only indexes the changesets that existed when
+// it was built, so pushes make it go stale.
+fn check_skiplist(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    key: Option<String>,
+    tips: Vec<(Bookmark, ChangesetId)>,
+) -> BoxFuture<(Status, String), Error> {
+    let key = match key {
+        Some(key) => key,
+        None => {
+            return future::ok((
+                Status::Warn,
+                "no skiplist is configured, ancestry queries will be slow".to_string(),
+            ))
+            .boxify();
+        }
+    };
+
+    repo.get_blobstore()
+        .get(ctx, key.clone())
+        .and_then(move |bytes| {
+            let bytes = bytes.ok_or_else(|| format_err!("skiplist {} is missing", key))?;
+            let skiplist = deserialize_skiplist_map(bytes.into_bytes())
+                .map_err(|err| format_err!("skiplist {} can't be read: {}", key, err))?;
+
+            let stale: Vec<_> = tips
+                .into_iter()
+                .filter(|(_, cs_id)| !skiplist.contains_key(cs_id))
+                .map(|(bookmark, _)| bookmark)
+                .collect();
+            if stale.is_empty() {
+                Ok((
+                    Status::Pass,
+                    format!(
+                        "skiplist {} indexes {} changesets, including all bookmarks",
+                        key,
+                        skiplist.len()
+                    ),
+                ))
+            } else {
+                Ok((
+                    Status::Warn,
+                    format!(
+                        "skiplist {} is stale, it doesn't index bookmarks {}: rebuild it with \
+                         `skiplist build`",
+                        key,
+                        join_bookmarks(&stale)
+                    ),
+                ))
+            }
+        })
+        .boxify()
+}
+
+// Data derived from the changesets that bookmarks point to is derived on demand if it's
+// missing, which makes the first requests for it slow
+fn check_derived_data(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    tips: Vec<(Bookmark, ChangesetId)>,
+) -> BoxFuture<(Status, String), Error> {
+    if tips.is_empty() {
+        return future::ok((Status::Pass, "there are no bookmarks to check".to_string())).boxify();
+    }
+
+    let cs_ids: Vec<_> = tips.iter().map(|(_, cs_id)| *cs_id).collect();
+    let hg_mapping = repo
+        .get_hg_bonsai_mapping(ctx.clone(), cs_ids)
+        .map(|mapping| {
+            mapping
+                .into_iter()
+                .map(|(_, cs_id)| cs_id)
+                .collect::<HashSet<_>>()
+        });
+    let filenodes = stream::iter_ok(tips.clone())
+        .map({
+            cloned!(ctx, repo);
+            move |(bookmark, cs_id)| {
+                derived_filenodes::is_derived(ctx.clone(), repo.clone(), cs_id)
+                    .map(move |derived| (bookmark, derived))
+            }
+        })
+        .buffered(100)
+        .filter_map(|(bookmark, derived)| if derived { None } else { Some(bookmark) })
+        .collect();
+
+    hg_mapping
+        .join(filenodes)
+        .map(move |(with_hg, without_filenodes)| {
+            let without_hg: Vec<_> = tips
+                .into_iter()
+                .filter(|(_, cs_id)| !with_hg.contains(cs_id))
+                .map(|(bookmark, _)| bookmark)
+                .collect();
+
+            let mut missing = vec![];
+            if !without_hg.is_empty() {
+                missing.push(format!(
+                    "no hg changeset for bookmarks {}",
+                    join_bookmarks(&without_hg)
+                ));
+            }
+            if !without_filenodes.is_empty() {
+                missing.push(format!(
+                    "no filenodes for bookmarks {}",
+                    join_bookmarks(&without_filenodes)
+                ));
+            }
+            if missing.is_empty() {
+                (
+                    Status::Pass,
+                    "hg changesets and filenodes are derived for all bookmarks".to_string(),
+                )
+            } else {
+                (Status::Warn, missing.join("; "))
+            }
+        })
+        .boxify()
+}
+
+fn join_bookmarks(bookmarks: &[Bookmark]) -> String {
+    let names: Vec<_> = bookmarks
+        .iter()
+        .map(|bookmark| bookmark.to_string())
+        .collect();
+    names.join(", ")
+}
@@ -0,0 +1,256 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Verification of the generation numbers of the changesets table, which all reachability
+//! queries rely on. The generation number of a changeset is recomputed from its parents and
+//! compared with the stored one.
+//!
+//! A full check walks the table in the order changesets were added, so that parents are
+//! recomputed before their children, and a wrong generation number is reported at every
+//! changeset it spreads to. A sampled check only compares the sampled changesets with the stored
+//! generation numbers of their parents. With --repair, the wrong rows are overwritten. Caches of
+//! changesets aren't invalidated, so servers may serve the old values until they expire.
+
+use std::collections::{HashMap, HashSet};
+
+use clap::{App, ArgMatches};
+use cloned::cloned;
+use failure_ext::{format_err, Error};
+use futures::future::{self, loop_fn, Loop};
+use futures::{stream, Future, Stream};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use slog::{info, warn, Logger};
+
+use changesets::{ChangesetEntry, Changesets, SqlChangesets};
+use cmdlib::args;
+use context::CoreContext;
+use mononoke_types::{ChangesetId, RepositoryId};
+
+use crate::doctor::find_config;
+
+/// Changesets fetched from the table at once
+const CHUNK_SIZE: u64 = 1000;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("check the generation numbers of the changesets of a repo against their parents")
+        .args_from_usage(
+            r#"
+            [REPO]                      'name of the repo to check, the repo of --repo-id if omitted'
+            --sample-percent [PERCENT]  'only check this percent of the changesets, picked at random'
+            --repair                    'overwrite the generation numbers that are wrong'
+            "#,
+        )
+}
+
+/// A changeset whose stored generation number isn't the one recomputed from its parents
+struct WrongGeneration {
+    cs_id: ChangesetId,
+    stored: u64,
+    expected: u64,
+}
+
+#[derive(Default)]
+struct Counts {
+    checked: u64,
+    wrong: u64,
+    repaired: u64,
+}
+
+pub fn handle_command<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let (name, config) = try_boxfuture!(find_config(matches, sub_m.value_of("REPO")));
+    let repo_id = RepositoryId::new(config.repoid);
+    let sample_percent = match sub_m.value_of("sample-percent") {
+        Some(percent) => match percent.parse::<u32>() {
+            Ok(percent) if percent >= 1 && percent <= 100 => Some(percent),
+            _ => {
+                return future::err(format_err!(
+                    "--sample-percent must be between 1 and 100, not {}",
+                    percent
+                ))
+                .boxify();
+            }
+        },
+        None => None,
+    };
+    let repair = sub_m.is_present("repair");
+    let changesets: SqlChangesets =
+        try_boxfuture!(args::open_sql_with_config(matches, &config, "changesets"));
+
+    info!(
+        logger,
+        "checking the generation numbers of repo {}", name;
+        "sample_percent" => sample_percent.unwrap_or(100),
+        "repair" => repair
+    );
+
+    changesets
+        .get_changesets_ids_bounds(repo_id)
+        .and_then({
+            cloned!(logger);
+            move |bounds| {
+                let (min_id, max_id) = match bounds {
+                    (Some(min_id), Some(max_id)) => (min_id, max_id),
+                    _ => return future::ok(Counts::default()).left_future(),
+                };
+                // Recomputed generation numbers of a full check, which children are checked against
+                let recomputed: HashMap<ChangesetId, u64> = HashMap::new();
+                loop_fn(
+                    (min_id, recomputed, Counts::default()),
+                    move |(start, recomputed, mut counts)| {
+                        if start > max_id {
+                            return future::ok(Loop::Break(counts)).left_future();
+                        }
+                        let end = start + CHUNK_SIZE;
+                        cloned!(ctx, changesets, logger);
+                        check_chunk(
+                            ctx,
+                            changesets.clone(),
+                            repo_id,
+                            start,
+                            end,
+                            recomputed,
+                            sample_percent,
+                        )
+                        .and_then(move |(recomputed, checked, wrong)| {
+                            counts.checked += checked;
+                            counts.wrong += wrong.len() as u64;
+                            for wrong in &wrong {
+                                warn!(
+                                    logger,
+                                    "{} has generation number {}, expected {}",
+                                    wrong.cs_id,
+                                    wrong.stored,
+                                    wrong.expected
+                                );
+                            }
+                            let repairs = if repair { wrong } else { vec![] };
+                            stream::iter_ok(repairs)
+                                .and_then(move |wrong| {
+                                    changesets.update_generation_number(
+                                        repo_id,
+                                        wrong.cs_id,
+                                        wrong.expected,
+                                    )
+                                })
+                                .fold(counts, |mut counts, updated| {
+                                    if updated {
+                                        counts.repaired += 1;
+                                    }
+                                    Ok::<_, Error>(counts)
+                                })
+                                .map(move |counts| Loop::Continue((end, recomputed, counts)))
+                        })
+                        .right_future()
+                    },
+                )
+                .right_future()
+            }
+        })
+        .and_then(move |counts| {
+            info!(
+                logger,
+                "checked {} changesets of repo {}: {} wrong, {} repaired",
+                counts.checked,
+                name,
+                counts.wrong,
+                counts.repaired
+            );
+            if counts.wrong > counts.repaired {
+                Err(format_err!(
+                    "{} changesets have wrong generation numbers",
+                    counts.wrong - counts.repaired
+                ))
+            } else {
+                Ok(())
+            }
+        })
+        .boxify()
+}
+
+/// Check the changesets with ids in [start, end). Returns the recomputed generation numbers to
+/// check the next chunk of a full check with, the number of changesets checked and the wrong ones.
+fn check_chunk(
+    ctx: CoreContext,
+    changesets: SqlChangesets,
+    repo_id: RepositoryId,
+    start: u64,
+    end: u64,
+    recomputed: HashMap<ChangesetId, u64>,
+    sample_percent: Option<u32>,
+) -> impl Future<Item = (HashMap<ChangesetId, u64>, u64, Vec<WrongGeneration>), Error = Error> {
+    changesets
+        .get_entries_in_range(repo_id, start, end)
+        .and_then(move |entries| {
+            // Generation numbers that the checked changesets are compared against: the
+            // recomputed ones for a full check, the stored ones for a sampled check
+            let (mut known, entries): (HashMap<_, _>, Vec<ChangesetEntry>) = match sample_percent {
+                None => (recomputed, entries),
+                Some(percent) => {
+                    let stored = entries
+                        .iter()
+                        .map(|entry| (entry.cs_id, entry.gen))
+                        .collect();
+                    let sampled = entries
+                        .into_iter()
+                        .filter(|_| rand::random::<u32>() % 100 < percent)
+                        .collect();
+                    (stored, sampled)
+                }
+            };
+            let in_chunk: HashSet<_> = entries.iter().map(|entry| entry.cs_id).collect();
+            let missing: HashSet<_> = entries
+                .iter()
+                .flat_map(|entry| entry.parents.iter().cloned())
+                .filter(|parent| !known.contains_key(parent) && !in_chunk.contains(parent))
+                .collect();
+
+            changesets
+                .get_many(ctx, repo_id, missing.into_iter().collect())
+                .and_then(move |parents| {
+                    for parent in parents {
+                        known.entry(parent.cs_id).or_insert(parent.gen);
+                    }
+                    let checked = entries.len() as u64;
+                    let mut wrong = vec![];
+                    for entry in entries {
+                        let mut expected = 1;
+                        for parent in &entry.parents {
+                            match known.get(parent) {
+                                Some(gen) => expected = ::std::cmp::max(expected, gen + 1),
+                                None => {
+                                    return Err(format_err!(
+                                        "parent {} of {} is missing",
+                                        parent,
+                                        entry.cs_id
+                                    ));
+                                }
+                            }
+                        }
+                        if sample_percent.is_none() {
+                            known.insert(entry.cs_id, expected);
+                        }
+                        if entry.gen != expected {
+                            wrong.push(WrongGeneration {
+                                cs_id: entry.cs_id,
+                                stored: entry.gen,
+                                expected,
+                            });
+                        }
+                    }
+                    let recomputed = if sample_percent.is_none() {
+                        known
+                    } else {
+                        HashMap::new()
+                    };
+                    Ok((recomputed, checked, wrong))
+                })
+        })
+}
@@ -8,6 +8,16 @@
 
 
 mod bookmarks_manager;
+mod doctor;
+mod gen_check;
+mod migrate;
+mod overrides;
+mod post_commit_hooks;
+mod rewrite_repo_paths;
+mod shard_manifests;
+mod skiplist;
+mod snapshot;
+mod storage_report;
 
 use cloned::cloned;
 use serde_derive::Serialize;
@@ -17,14 +27,14 @@ use blobstore::Blobstore;
 use bonsai_utils::{bonsai_diff, BonsaiDiffResult};
 use bookmarks::{Bookmark, Bookmarks};
 use cacheblob::{new_memcache_blobstore, CacheBlobstoreExt};
-use changeset_fetcher::ChangesetFetcher;
-use changesets::{ChangesetEntry, Changesets, SqlChangesets};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use cmdlib::args;
+use content_refs::{ContentRefs, SqlContentRefs};
 use context::CoreContext;
 use dbbookmarks::SqlBookmarks;
+use derived_filenodes::derive_filenodes;
 use failure_ext::{err_msg, format_err, Error, Result};
-use futures::future::{self, loop_fn, ok, Loop};
+use futures::future::{self, ok};
 use futures::prelude::*;
 use futures::stream::iter_ok;
 use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
@@ -36,17 +46,15 @@ use mercurial_types::{
 };
 use metaconfig_types::RemoteBlobstoreArgs;
 use mononoke_types::{
-    BlobstoreBytes, BlobstoreValue, BonsaiChangeset, ChangesetId, DateTime, FileChange,
-    FileContents, Generation, RepositoryId,
+    BlobstoreBytes, BlobstoreValue, BonsaiChangeset, ChangesetId, ContentId, DateTime, FileChange,
+    FileContents, RepositoryId,
 };
 use mutable_counters::{MutableCounters, SqlMutableCounters};
 use prefixblob::PrefixBlobstore;
 use revset::RangeNodeStream;
-use rust_thrift::compact_protocol;
-use skiplist::{deserialize_skiplist_map, SkiplistIndex, SkiplistNodeType};
 use slog::{debug, info, warn, Logger};
 use std::borrow::Borrow;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io;
 use std::str::FromStr;
@@ -54,7 +62,10 @@ use std::sync::Arc;
 
 const BLOBSTORE_FETCH: &'static str = "blobstore-fetch";
 const BONSAI_FETCH: &'static str = "bonsai-fetch";
+const FILENODES: &'static str = "filenodes";
+const FILENODES_DERIVE: &'static str = "derive";
 const CONTENT_FETCH: &'static str = "content-fetch";
+const CONTENT_REFS: &'static str = "content-refs";
 const BOOKMARKS: &'static str = "bookmarks";
 const SKIPLIST: &'static str = "skiplist";
 const HASH_CONVERT: &'static str = "convert";
@@ -64,8 +75,15 @@ const HG_CHANGESET_RANGE: &'static str = "range";
 const HG_SYNC_BUNDLE: &'static str = "hg-sync-bundle";
 const HG_SYNC_REMAINS: &'static str = "remains";
 const HG_SYNC_LAST_PROCESSED: &'static str = "last-processed";
-const SKIPLIST_BUILD: &'static str = "build";
-const SKIPLIST_READ: &'static str = "read";
+const DOCTOR: &'static str = "doctor";
+const MIGRATE: &'static str = "migrate";
+const SHARD_MANIFESTS: &'static str = "shard-manifests";
+const STORAGE_REPORT: &'static str = "storage-report";
+const GEN_CHECK: &'static str = "gen-check";
+const SNAPSHOT: &'static str = "snapshot";
+const POST_COMMIT_HOOKS: &'static str = "post-commit-hooks";
+const OVERRIDES: &'static str = "overrides";
+const REWRITE_REPO_PATHS: &'static str = "rewrite-repo-paths";
 
 fn setup_app<'a, 'b>() -> App<'a, 'b> {
     let blobstore_fetch = SubCommand::with_name(BLOBSTORE_FETCH)
@@ -105,6 +123,13 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
              <PATH>            'path to fetch'",
         );
 
+    let content_refs = SubCommand::with_name(CONTENT_REFS)
+        .about("lists the files and commits that reference a content, as recorded by pushes")
+        .args_from_usage(
+            "<CONTENT_ID>       'content to look up'
+             --limit [LIMIT]    'maximum number of references to list, 100 if omitted'",
+        );
+
     let bonsai_fetch = SubCommand::with_name(BONSAI_FETCH)
         .about("fetches content of the file or manifest from blobrepo")
         .args_from_usage(
@@ -131,20 +156,13 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 ),
         );
 
-    let skiplist = SubCommand::with_name(SKIPLIST)
-        .about("commands to build or read skiplist indexes")
+    let filenodes = SubCommand::with_name(FILENODES)
+        .about("commands to derive filenodes from bonsai changesets")
         .subcommand(
-            SubCommand::with_name(SKIPLIST_BUILD)
-                .about("build skiplist index")
+            SubCommand::with_name(FILENODES_DERIVE)
+                .about("store the filenodes of a changeset and of its ancestors that lack them")
                 .args_from_usage(
-                    "<BLOBSTORE_KEY>  'Blobstore key where to store the built skiplist'",
-                ),
-        )
-        .subcommand(
-            SubCommand::with_name(SKIPLIST_READ)
-                .about("read skiplist index")
-                .args_from_usage(
-                    "<BLOBSTORE_KEY>  'Blobstore key from where to read the skiplist'",
+                    "<HG_CHANGESET_OR_BOOKMARK>  'changeset to derive filenodes up to'",
                 ),
         );
 
@@ -200,13 +218,70 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
         .subcommand(blobstore_fetch)
         .subcommand(bonsai_fetch)
         .subcommand(content_fetch)
+        .subcommand(content_refs)
         .subcommand(bookmarks_manager::prepare_command(SubCommand::with_name(
             BOOKMARKS,
         )))
         .subcommand(hg_changeset)
-        .subcommand(skiplist)
+        .subcommand(filenodes)
+        .subcommand(skiplist::prepare_command(SubCommand::with_name(SKIPLIST)))
         .subcommand(convert)
         .subcommand(hg_sync)
+        .subcommand(doctor::prepare_command(SubCommand::with_name(DOCTOR)))
+        .subcommand(migrate::prepare_command(SubCommand::with_name(MIGRATE)))
+        .subcommand(shard_manifests::prepare_command(SubCommand::with_name(
+            SHARD_MANIFESTS,
+        )))
+        .subcommand(storage_report::prepare_command(SubCommand::with_name(
+            STORAGE_REPORT,
+        )))
+        .subcommand(gen_check::prepare_command(SubCommand::with_name(GEN_CHECK)))
+        .subcommand(snapshot::prepare_command(SubCommand::with_name(SNAPSHOT)))
+        .subcommand(post_commit_hooks::prepare_command(SubCommand::with_name(
+            POST_COMMIT_HOOKS,
+        )))
+        .subcommand(overrides::prepare_command(SubCommand::with_name(OVERRIDES)))
+        .subcommand(rewrite_repo_paths::prepare_command(SubCommand::with_name(
+            REWRITE_REPO_PATHS,
+        )))
+}
+
+fn list_content_refs<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    repo_id: RepositoryId,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let content_id = try_boxfuture!(ContentId::from_str(sub_m.value_of("CONTENT_ID").unwrap()));
+    let limit = match sub_m.value_of("limit") {
+        Some(limit) => try_boxfuture!(limit.parse::<u64>()),
+        None => 100,
+    };
+
+    let (_, config) = try_boxfuture!(args::get_config(matches));
+    if config.content_refs.is_none() {
+        warn!(
+            logger,
+            "the repo doesn't record content references, only earlier ones are listed"
+        );
+    }
+    let content_refs: SqlContentRefs = try_boxfuture!(args::open_sql(matches, "content_refs"));
+
+    content_refs
+        .get(ctx, repo_id, content_id, limit)
+        .map(move |refs| {
+            if refs.is_empty() {
+                println!("no reference to {} is recorded", content_id);
+            }
+            for content_ref in refs {
+                println!(
+                    "{} {} {}",
+                    content_ref.changeset_id, content_ref.filenode, content_ref.path
+                );
+            }
+        })
+        .boxify()
 }
 
 fn fetch_content_from_manifest(
@@ -466,192 +541,6 @@ fn hg_changeset_diff(
         })
 }
 
-fn fetch_all_changesets(
-    ctx: CoreContext,
-    repo_id: RepositoryId,
-    sqlchangesets: Arc<SqlChangesets>,
-) -> impl Future<Item = Vec<ChangesetEntry>, Error = Error> {
-    let num_sql_fetches = 10000;
-    sqlchangesets
-        .get_changesets_ids_bounds(repo_id.clone())
-        .map(move |(maybe_lower_bound, maybe_upper_bound)| {
-            let lower_bound = maybe_lower_bound.expect("changesets table is empty");
-            let upper_bound = maybe_upper_bound.expect("changesets table is empty");
-            let step = (upper_bound - lower_bound) / num_sql_fetches;
-            let step = ::std::cmp::max(100, step);
-
-            iter_ok(
-                (lower_bound..upper_bound)
-                    .step_by(step as usize)
-                    .map(move |i| (i, i + step)),
-            )
-        })
-        .flatten_stream()
-        .and_then(move |(lower_bound, upper_bound)| {
-            sqlchangesets
-                .get_list_bs_cs_id_in_range(repo_id, lower_bound, upper_bound)
-                .collect()
-                .and_then({
-                    cloned!(ctx, sqlchangesets);
-                    move |ids| {
-                        sqlchangesets
-                            .get_many(ctx, repo_id, ids)
-                            .map(|v| iter_ok(v.into_iter()))
-                    }
-                })
-        })
-        .flatten()
-        .collect()
-}
-
-#[derive(Clone)]
-struct InMemoryChangesetFetcher {
-    fetched_changesets: Arc<HashMap<ChangesetId, ChangesetEntry>>,
-    inner: Arc<dyn ChangesetFetcher>,
-}
-
-impl ChangesetFetcher for InMemoryChangesetFetcher {
-    fn get_generation_number(
-        &self,
-        ctx: CoreContext,
-        cs_id: ChangesetId,
-    ) -> BoxFuture<Generation, Error> {
-        match self.fetched_changesets.get(&cs_id) {
-            Some(cs_entry) => ok(Generation::new(cs_entry.gen)).boxify(),
-            None => self.inner.get_generation_number(ctx, cs_id),
-        }
-    }
-
-    fn get_parents(
-        &self,
-        ctx: CoreContext,
-        cs_id: ChangesetId,
-    ) -> BoxFuture<Vec<ChangesetId>, Error> {
-        match self.fetched_changesets.get(&cs_id) {
-            Some(cs_entry) => ok(cs_entry.parents.clone()).boxify(),
-            None => self.inner.get_parents(ctx, cs_id),
-        }
-    }
-}
-
-fn build_skiplist_index<S: ToString>(
-    ctx: CoreContext,
-    repo: BlobRepo,
-    key: S,
-    logger: Logger,
-    sql_changesets: SqlChangesets,
-) -> BoxFuture<(), Error> {
-    let blobstore = repo.get_blobstore();
-    // skiplist will jump up to 2^9 changesets
-    let skiplist_depth = 10;
-    // Index all changesets
-    let max_index_depth = 20000000000;
-    let skiplist_index = SkiplistIndex::with_skip_edge_count(skiplist_depth);
-    let key = key.to_string();
-
-    let cs_fetcher = fetch_all_changesets(ctx.clone(), repo.get_repoid(), Arc::new(sql_changesets))
-        .map({
-            let changeset_fetcher = repo.get_changeset_fetcher();
-            move |fetched_changesets| {
-                let fetched_changesets: HashMap<_, _> = fetched_changesets
-                    .into_iter()
-                    .map(|cs_entry| (cs_entry.cs_id, cs_entry))
-                    .collect();
-                InMemoryChangesetFetcher {
-                    fetched_changesets: Arc::new(fetched_changesets),
-                    inner: changeset_fetcher,
-                }
-            }
-        });
-
-    repo.get_bonsai_heads_maybe_stale(ctx.clone())
-        .collect()
-        .join(cs_fetcher)
-        .and_then({
-            cloned!(ctx);
-            move |(heads, cs_fetcher)| {
-                loop_fn(
-                    (heads.into_iter(), skiplist_index),
-                    move |(mut heads, skiplist_index)| match heads.next() {
-                        Some(head) => {
-                            let f = skiplist_index.add_node(
-                                ctx.clone(),
-                                Arc::new(cs_fetcher.clone()),
-                                head,
-                                max_index_depth,
-                            );
-
-                            f.map(move |()| Loop::Continue((heads, skiplist_index)))
-                                .boxify()
-                        }
-                        None => ok(Loop::Break(skiplist_index)).boxify(),
-                    },
-                )
-            }
-        })
-        .inspect({
-            cloned!(logger);
-            move |skiplist_index| {
-                info!(
-                    logger,
-                    "build {} skiplist nodes",
-                    skiplist_index.indexed_node_count()
-                );
-            }
-        })
-        .map(|skiplist_index| {
-            // We store only latest skip entry (i.e. entry with the longest jump)
-            // This saves us storage space
-            let mut thrift_merge_graph = HashMap::new();
-            for (cs_id, skiplist_node_type) in skiplist_index.get_all_skip_edges() {
-                let skiplist_node_type = if let SkiplistNodeType::SkipEdges(skip_edges) =
-                    skiplist_node_type
-                {
-                    SkiplistNodeType::SkipEdges(skip_edges.last().cloned().into_iter().collect())
-                } else {
-                    skiplist_node_type
-                };
-
-                thrift_merge_graph.insert(cs_id.into_thrift(), skiplist_node_type.to_thrift());
-            }
-
-            compact_protocol::serialize(&thrift_merge_graph)
-        })
-        .and_then({
-            cloned!(ctx);
-            move |bytes| {
-                debug!(logger, "storing {} bytes", bytes.len());
-                blobstore.put(ctx, key, BlobstoreBytes::from_bytes(bytes))
-            }
-        })
-        .boxify()
-}
-
-fn read_skiplist_index<S: ToString>(
-    ctx: CoreContext,
-    repo: BlobRepo,
-    key: S,
-    logger: Logger,
-) -> BoxFuture<(), Error> {
-    repo.get_blobstore()
-        .get(ctx, key.to_string())
-        .and_then(move |maybebytes| {
-            match maybebytes {
-                Some(bytes) => {
-                    debug!(logger, "received {} bytes from blobstore", bytes.len());
-                    let bytes = bytes.into_bytes();
-                    let skiplist_map = try_boxfuture!(deserialize_skiplist_map(bytes));
-                    info!(logger, "skiplist graph has {} entries", skiplist_map.len());
-                }
-                None => {
-                    println!("not found map");
-                }
-            };
-            ok(()).boxify()
-        })
-        .boxify()
-}
-
 const LATEST_REPLAYED_REQUEST_KEY: &'static str = "latest-replayed-request";
 
 fn process_hg_sync_subcommand<'a>(
@@ -783,14 +672,14 @@ fn main() -> Result<()> {
                 (None, true) => blobstore.get(ctx, key.clone()).boxify(),
                 (Some(mode), false) => {
                     let blobstore =
-                        new_memcache_blobstore(blobstore, "manifold", manifold_args.bucket)
+                        new_memcache_blobstore(blobstore, "manifold", manifold_args.bucket, None)
                             .unwrap();
                     let blobstore = PrefixBlobstore::new(blobstore, repo_id.prefix());
                     get_cache(ctx.clone(), &blobstore, key.clone(), mode)
                 }
                 (Some(mode), true) => {
                     let blobstore =
-                        new_memcache_blobstore(blobstore, "manifold", manifold_args.bucket)
+                        new_memcache_blobstore(blobstore, "manifold", manifold_args.bucket, None)
                             .unwrap();
                     get_cache(ctx.clone(), &blobstore, key.clone(), mode)
                 }
@@ -895,6 +784,9 @@ fn main() -> Result<()> {
                                     .expect("non-utf8 file content");
                                 println!("{}", content);
                             }
+                            FileContents::Tombstone(tombstone) => {
+                                println!("Tombstone: {}", tombstone.reason());
+                            }
                         },
                         Content::Tree(mf) => {
                             let entries: Vec<_> = mf.list().collect();
@@ -927,6 +819,11 @@ fn main() -> Result<()> {
                 })
                 .boxify()
         }
+        (CONTENT_REFS, Some(sub_m)) => {
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            list_content_refs(ctx, &matches, sub_m, repo_id, logger)
+        }
         (BOOKMARKS, Some(sub_m)) => {
             args::init_cachelib(&matches);
             // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
@@ -934,6 +831,52 @@ fn main() -> Result<()> {
             let repo_fut = args::open_repo(&logger, &matches).boxify();
             bookmarks_manager::handle_command(ctx, repo_fut, sub_m, logger)
         }
+        (DOCTOR, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            doctor::handle_command(ctx, &matches, sub_m, logger)
+        }
+        (MIGRATE, Some(sub_m)) => migrate::handle_command(&matches, sub_m, logger),
+        (SHARD_MANIFESTS, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            shard_manifests::handle_command(ctx, &matches, sub_m, logger)
+        }
+        (STORAGE_REPORT, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            storage_report::handle_command(ctx, &matches, sub_m, logger)
+        }
+        (GEN_CHECK, Some(sub_m)) => {
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            gen_check::handle_command(ctx, &matches, sub_m, logger)
+        }
+        (SNAPSHOT, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            snapshot::handle_command(ctx, &matches, sub_m, logger)
+        }
+        (POST_COMMIT_HOOKS, Some(sub_m)) => {
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            post_commit_hooks::handle_command(ctx, &matches, sub_m, logger)
+        }
+        (OVERRIDES, Some(sub_m)) => {
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            overrides::handle_command(ctx, &matches, sub_m, logger)
+        }
+        (REWRITE_REPO_PATHS, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            rewrite_repo_paths::handle_command(ctx, &matches, sub_m, logger)
+        }
         (HG_CHANGESET, Some(sub_m)) => match sub_m.subcommand() {
             (HG_CHANGESET_DIFF, Some(sub_m)) => {
                 // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
@@ -1028,33 +971,29 @@ fn main() -> Result<()> {
             }
         },
         (HG_SYNC_BUNDLE, Some(sub_m)) => process_hg_sync_subcommand(sub_m, &matches, repo_id, logger.clone()),
-        (SKIPLIST, Some(sub_m)) => match sub_m.subcommand() {
-            (SKIPLIST_BUILD, Some(sub_m)) => {
-                let key = sub_m
-                    .value_of("BLOBSTORE_KEY")
-                    .expect("blobstore key is not specified")
-                    .to_string();
-
-                args::init_cachelib(&matches);
-                let ctx = CoreContext::test_mock();
-                let sql_changesets = args::open_sql_changesets(&matches);
-                let repo = args::open_repo(&logger, &matches);
-                repo.join(sql_changesets)
-                    .and_then(move |(repo, sql_changesets)| {
-                        build_skiplist_index(ctx, repo, key, logger, sql_changesets)
-                    })
-                    .boxify()
-            }
-            (SKIPLIST_READ, Some(sub_m)) => {
-                let key = sub_m
-                    .value_of("BLOBSTORE_KEY")
-                    .expect("blobstore key is not specified")
+        (FILENODES, Some(sub_m)) => match sub_m.subcommand() {
+            (FILENODES_DERIVE, Some(sub_m)) => {
+                let rev = sub_m
+                    .value_of("HG_CHANGESET_OR_BOOKMARK")
+                    .unwrap()
                     .to_string();
 
                 args::init_cachelib(&matches);
                 let ctx = CoreContext::test_mock();
+                cloned!(logger);
                 args::open_repo(&logger, &matches)
-                    .and_then(move |repo| read_skiplist_index(ctx.clone(), repo, key, logger))
+                    .and_then(move |repo| {
+                        resolve_hg_rev(ctx.clone(), &repo, &rev)
+                            .and_then({
+                                cloned!(ctx, repo);
+                                move |hg_cs| repo.get_bonsai_from_hg(ctx, hg_cs)
+                            })
+                            .and_then(move |maybe_bonsai| {
+                                maybe_bonsai.ok_or(err_msg(format!("bonsai not found for {}", rev)))
+                            })
+                            .and_then(move |cs_id| derive_filenodes(ctx, repo, cs_id))
+                    })
+                    .map(move |count| info!(logger, "derived filenodes of {} changesets", count))
                     .boxify()
             }
             _ => {
@@ -1062,6 +1001,12 @@ fn main() -> Result<()> {
                 ::std::process::exit(1);
             }
         },
+        (SKIPLIST, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            // TODO(T37478150, luk) This is not a test case, fix it up in future diffs
+            let ctx = CoreContext::test_mock();
+            skiplist::handle_command(ctx, &matches, sub_m, logger)
+        }
         (HASH_CONVERT, Some(sub_m)) => {
             let source_hash = sub_m.value_of("HASH").unwrap().to_string();
             let source = sub_m.value_of("from").unwrap().to_string();
@@ -0,0 +1,438 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches};
+use failure_ext::{format_err, Error, Result};
+use futures::{future, stream, Future, Stream};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use slog::{info, warn, Logger};
+
+use audit_log::SqlAuditLog;
+use bonsai_hg_mapping::SqlBonsaiHgMapping;
+use changesets::SqlChangesets;
+use cmdlib::args;
+use content_refs::SqlContentRefs;
+use dbbookmarks::SqlBookmarks;
+use hg_derivation_queue::SqlHgDerivationQueue;
+use hooks::{SqlHookResultStore, SqlPostCommitQueue};
+use metaconfig_types::{RemoteBlobstoreArgs, RepoConfig, RepoType};
+use phases::SqlPhases;
+use push_usage::SqlPushUsageStore;
+use repo_client::{SqlResumablePullStore, SqlTreePopularity};
+use repo_maintenance::SqlMaintenanceStore;
+use repo_overrides::SqlOverridesStore;
+use sql_ext::migrations::{
+    apply_sqlite, check_baseline, migration_script, pending_migrations, record_script, Dialect,
+    Migration, SqlMigrations, SqlSchemaVersions,
+};
+use sql_ext::{remote_myrouter_port, SqlConstructors};
+use sqlblob::Sqlblob;
+use storage_usage::SqlStorageUsage;
+
+const STORE_ARG: &'static str = "store";
+const DRY_RUN_ARG: &'static str = "dry-run";
+const BASELINE_ARG: &'static str = "baseline";
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("apply the pending migrations of the schema of a sql store of the repo")
+        .arg(
+            Arg::with_name(STORE_ARG)
+                .long(STORE_ARG)
+                .takes_value(true)
+                .possible_values(&[
+                    SqlAuditLog::STORE,
+                    SqlBonsaiHgMapping::STORE,
+                    SqlBookmarks::STORE,
+                    SqlChangesets::STORE,
+                    SqlContentRefs::STORE,
+                    SqlHgDerivationQueue::STORE,
+                    SqlHookResultStore::STORE,
+                    SqlMaintenanceStore::STORE,
+                    SqlOverridesStore::STORE,
+                    SqlPhases::STORE,
+                    SqlPostCommitQueue::STORE,
+                    SqlPushUsageStore::STORE,
+                    SqlResumablePullStore::STORE,
+                    SqlStorageUsage::STORE,
+                    SqlTreePopularity::STORE,
+                    Sqlblob::STORE,
+                ])
+                .required(true)
+                .help("store to migrate"),
+        )
+        .arg(
+            Arg::with_name(DRY_RUN_ARG)
+                .long(DRY_RUN_ARG)
+                .help("print the pending migrations instead of applying them"),
+        )
+        .arg(
+            Arg::with_name(BASELINE_ARG)
+                .long(BASELINE_ARG)
+                .takes_value(true)
+                .value_name("VERSION")
+                .help(
+                    "record the migrations up to VERSION as applied without running them, for \
+                     schemas that were created by hand",
+                ),
+        )
+        .after_help(
+            "MyRouter only runs the queries built into Mononoke, so migrations of MySQL stores \
+             are printed as a script to run with the mysql client.",
+        )
+}
+
+pub fn handle_command<'a>(
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let dry_run = sub_m.is_present(DRY_RUN_ARG);
+    let baseline = match sub_m.value_of(BASELINE_ARG) {
+        Some(version) => Some(try_boxfuture!(version.parse::<u64>())),
+        None => None,
+    };
+
+    // The names the databases of the stores are opened with, see blobrepo_factory
+    match sub_m.value_of(STORE_ARG) {
+        Some(SqlBonsaiHgMapping::STORE) => {
+            migrate::<SqlBonsaiHgMapping>(matches, "bonsai_hg_mapping", dry_run, baseline, logger)
+        }
+        Some(SqlBookmarks::STORE) => {
+            migrate::<SqlBookmarks>(matches, "books", dry_run, baseline, logger)
+        }
+        Some(SqlChangesets::STORE) => {
+            migrate::<SqlChangesets>(matches, "changesets", dry_run, baseline, logger)
+        }
+        Some(SqlPhases::STORE) => {
+            migrate::<SqlPhases>(matches, "phases", dry_run, baseline, logger)
+        }
+        Some(SqlAuditLog::STORE) => {
+            migrate::<SqlAuditLog>(matches, "audit_log", dry_run, baseline, logger)
+        }
+        Some(SqlContentRefs::STORE) => {
+            migrate::<SqlContentRefs>(matches, "content_refs", dry_run, baseline, logger)
+        }
+        Some(SqlHgDerivationQueue::STORE) => migrate::<SqlHgDerivationQueue>(
+            matches,
+            "hg_derivation_queue",
+            dry_run,
+            baseline,
+            logger,
+        ),
+        Some(SqlHookResultStore::STORE) => {
+            migrate::<SqlHookResultStore>(matches, "hook_results", dry_run, baseline, logger)
+        }
+        Some(SqlMaintenanceStore::STORE) => migrate_write_lock_db::<SqlMaintenanceStore>(
+            matches,
+            "maintenance",
+            dry_run,
+            baseline,
+            logger,
+        ),
+        Some(SqlOverridesStore::STORE) => {
+            migrate::<SqlOverridesStore>(matches, "repo_overrides", dry_run, baseline, logger)
+        }
+        Some(SqlPostCommitQueue::STORE) => {
+            migrate::<SqlPostCommitQueue>(matches, "post_commit_queue", dry_run, baseline, logger)
+        }
+        Some(SqlPushUsageStore::STORE) => {
+            migrate::<SqlPushUsageStore>(matches, "push_usage", dry_run, baseline, logger)
+        }
+        Some(SqlResumablePullStore::STORE) => {
+            migrate::<SqlResumablePullStore>(matches, "resumable_pulls", dry_run, baseline, logger)
+        }
+        Some(SqlStorageUsage::STORE) => {
+            migrate::<SqlStorageUsage>(matches, "storage_usage", dry_run, baseline, logger)
+        }
+        Some(SqlTreePopularity::STORE) => {
+            migrate::<SqlTreePopularity>(matches, "tree_popularity", dry_run, baseline, logger)
+        }
+        Some(Sqlblob::STORE) => migrate_sqlblob(matches, dry_run, baseline, logger),
+        _ => {
+            println!("{}", sub_m.usage());
+            ::std::process::exit(1);
+        }
+    }
+}
+
+/// A database that a store is in, and the path of its file if it is an SQLite one
+struct Database {
+    name: String,
+    sqlite_path: Option<PathBuf>,
+    versions: SqlSchemaVersions,
+}
+
+impl Database {
+    fn sqlite(path: PathBuf) -> Result<Self> {
+        if !path.exists() {
+            return Err(format_err!("there is no database at {}", path.display()));
+        }
+        Ok(Self {
+            name: path.display().to_string(),
+            versions: SqlSchemaVersions::with_sqlite_path(&path)?,
+            sqlite_path: Some(path),
+        })
+    }
+
+    fn mysql(name: String, port: u16) -> Self {
+        Self {
+            versions: SqlSchemaVersions::with_myrouter(&name, port),
+            name,
+            sqlite_path: None,
+        }
+    }
+}
+
+fn migrate<'a, T: SqlMigrations>(
+    matches: &ArgMatches<'a>,
+    db_name: &'static str,
+    dry_run: bool,
+    baseline: Option<u64>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let (_, config) = try_boxfuture!(args::get_config(matches));
+    let (dialect, database) = match config.repotype {
+        RepoType::BlobFiles(ref data_dir)
+        | RepoType::BlobRocks(ref data_dir)
+        | RepoType::BlobSqlite(ref data_dir) => (
+            Dialect::Sqlite,
+            try_boxfuture!(Database::sqlite(data_dir.join(db_name))),
+        ),
+        RepoType::BlobRemote { ref db_address, .. } => (
+            Dialect::Mysql,
+            Database {
+                name: db_address.clone(),
+                sqlite_path: None,
+                versions: try_boxfuture!(args::open_sql_with_config(matches, &config, db_name)),
+            },
+        ),
+    };
+    migrate_databases::<T>(vec![database], dialect, dry_run, baseline, logger)
+}
+
+/// Like `migrate`, for stores that remote repos keep in the database of their write lock
+fn migrate_write_lock_db<'a, T: SqlMigrations>(
+    matches: &ArgMatches<'a>,
+    db_name: &'static str,
+    dry_run: bool,
+    baseline: Option<u64>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let (_, config) = try_boxfuture!(args::get_config(matches));
+    match config.repotype {
+        RepoType::BlobRemote {
+            ref write_lock_db_address,
+            ..
+        } => {
+            let port = try_boxfuture!(
+                args::try_parse_myrouter_port(matches).and_then(remote_myrouter_port)
+            );
+            let database = Database::mysql(write_lock_db_address.clone(), port);
+            migrate_databases::<T>(vec![database], Dialect::Mysql, dry_run, baseline, logger)
+        }
+        _ => migrate::<T>(matches, db_name, dry_run, baseline, logger),
+    }
+}
+
+/// Sqlblob stores blobs over many shards, each a database with the whole schema
+fn migrate_sqlblob<'a>(
+    matches: &ArgMatches<'a>,
+    dry_run: bool,
+    baseline: Option<u64>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let (_, config) = try_boxfuture!(args::get_config(matches));
+    let (dialect, databases) = try_boxfuture!(sqlblob_databases(matches, &config));
+    if databases.is_empty() {
+        return future::err(format_err!("the repo doesn't store its blobs in sqlblob")).boxify();
+    }
+    migrate_databases::<Sqlblob>(databases, dialect, dry_run, baseline, logger)
+}
+
+fn sqlblob_databases<'a>(
+    matches: &ArgMatches<'a>,
+    config: &RepoConfig,
+) -> Result<(Dialect, Vec<Database>)> {
+    match config.repotype {
+        RepoType::BlobSqlite(ref data_dir) => {
+            let databases: Result<Vec<_>> = Sqlblob::sqlite_shard_paths(data_dir.join("blobs"))
+                .into_iter()
+                .map(Database::sqlite)
+                .collect();
+            Ok((Dialect::Sqlite, databases?))
+        }
+        RepoType::BlobFiles(_) | RepoType::BlobRocks(_) => Ok((Dialect::Sqlite, vec![])),
+        RepoType::BlobRemote {
+            ref blobstores_args,
+            ..
+        } => {
+            let port = remote_myrouter_port(args::try_parse_myrouter_port(matches)?)?;
+            let mut shardmaps = vec![];
+            collect_sqlblob_shardmaps(blobstores_args, &mut shardmaps);
+            let databases = shardmaps
+                .into_iter()
+                .flat_map(|(shardmap, shard_num)| {
+                    (1..=shard_num).map(move |shard_id| format!("{}.{}", shardmap, shard_id))
+                })
+                .map(|name| Database::mysql(name, port))
+                .collect();
+            Ok((Dialect::Mysql, databases))
+        }
+    }
+}
+
+fn collect_sqlblob_shardmaps(args: &RemoteBlobstoreArgs, shardmaps: &mut Vec<(String, usize)>) {
+    match args {
+        RemoteBlobstoreArgs::Mysql(args) => {
+            shardmaps.push((args.shardmap.clone(), args.shard_num.get()))
+        }
+        RemoteBlobstoreArgs::Multiplexed { blobstores, .. } => {
+            for args in blobstores.values() {
+                collect_sqlblob_shardmaps(args, shardmaps);
+            }
+        }
+        RemoteBlobstoreArgs::Manifold(_) | RemoteBlobstoreArgs::Gluster(_) => {}
+    }
+}
+
+/// Migrate the store `T` in each of `databases`, one after the other
+fn migrate_databases<T: SqlMigrations>(
+    databases: Vec<Database>,
+    dialect: Dialect,
+    dry_run: bool,
+    baseline: Option<u64>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let store = T::STORE;
+    let migrations = T::migrations();
+    if let Some(version) = baseline {
+        try_boxfuture!(check_baseline(store, migrations, version));
+    }
+    if dialect == Dialect::Mysql {
+        println!("{}", SqlSchemaVersions::schema(dialect));
+    }
+
+    stream::iter_ok(databases)
+        .for_each(move |database| {
+            migrate_database(
+                store, migrations, database, dialect, dry_run, baseline, &logger,
+            )
+        })
+        .boxify()
+}
+
+fn migrate_database(
+    store: &'static str,
+    migrations: &'static [Migration],
+    database: Database,
+    dialect: Dialect,
+    dry_run: bool,
+    baseline: Option<u64>,
+    logger: &Logger,
+) -> BoxFuture<(), Error> {
+    let Database {
+        name,
+        sqlite_path,
+        versions,
+    } = database;
+
+    versions
+        .get_applied(store)
+        .then({
+            let logger = logger.clone();
+            move |res| match res {
+                Ok(applied) => Ok(applied),
+                // The script creates schema_version if it is missing
+                Err(err) if dialect == Dialect::Mysql => {
+                    warn!(
+                        logger,
+                        "can't read schema_version, assuming no migration was applied: {}", err
+                    );
+                    Ok(vec![])
+                }
+                Err(err) => Err(err),
+            }
+        })
+        .and_then({
+            let logger = logger.clone();
+            move |applied| {
+                let pending = pending_migrations(store, migrations, &applied)?;
+                if pending.is_empty() {
+                    info!(
+                        logger,
+                        "{} in {} is at version {}, there is nothing to migrate",
+                        store,
+                        name,
+                        migrations.len()
+                    );
+                    return Ok(());
+                }
+
+                let mut scripts = vec![];
+                for migration in pending {
+                    let script = match baseline {
+                        Some(version) if migration.version <= version => {
+                            info!(
+                                logger,
+                                "{} migration {} is recorded in {} without running it: {}",
+                                store,
+                                migration.version,
+                                name,
+                                migration.description
+                            );
+                            record_script(store, migration, dialect)
+                        }
+                        _ => {
+                            info!(
+                                logger,
+                                "{} migration {} in {}: {}",
+                                store,
+                                migration.version,
+                                name,
+                                migration.description
+                            );
+                            migration_script(store, migration, dialect)
+                        }
+                    };
+                    scripts.push(script);
+                }
+
+                match sqlite_path {
+                    Some(ref path) if !dry_run => {
+                        apply_sqlite(path, &scripts)?;
+                        info!(
+                            logger,
+                            "{} in {} migrated to version {}",
+                            store,
+                            name,
+                            migrations.len()
+                        );
+                    }
+                    _ => {
+                        if dialect == Dialect::Mysql && !dry_run {
+                            info!(
+                                logger,
+                                "run this script with the mysql client on {} to migrate {}",
+                                name,
+                                store
+                            );
+                        }
+                        if dialect == Dialect::Sqlite {
+                            println!("{}", SqlSchemaVersions::schema(dialect));
+                        }
+                        println!("-- {}", name);
+                        for script in scripts {
+                            println!("{}", script);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        })
+        .boxify()
+}
@@ -0,0 +1,84 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Overrides of the tunables of a repo. Servers poll them, so a change takes effect on all
+//! servers of the repo within a poll interval, without restarting them.
+
+use clap::{App, ArgMatches, SubCommand};
+use failure_ext::{format_err, Error};
+use futures::{future, Future};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use slog::{info, Logger};
+
+use cmdlib::args;
+use context::CoreContext;
+use repo_overrides::{OverridesStore, SqlOverridesStore};
+
+const LIST: &'static str = "list";
+const SET: &'static str = "set";
+const UNSET: &'static str = "unset";
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("list and change the overrides of the tunables of a repo")
+        .subcommand(SubCommand::with_name(LIST).about("list the overridden tunables"))
+        .subcommand(
+            SubCommand::with_name(SET)
+                .about("override a tunable")
+                .args_from_usage(
+                    r#"
+                    <NAME>   'name of the tunable'
+                    <VALUE>  'value to override it with'
+                    "#,
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(UNSET)
+                .about("remove the override of a tunable, so that its configured value is used")
+                .args_from_usage("<NAME>  'name of the tunable'"),
+        )
+}
+
+pub fn handle_command<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let repo_id = args::get_repo_id(matches);
+    let store: SqlOverridesStore = try_boxfuture!(args::open_sql(matches, "repo_overrides"));
+    let name = |sub_m: &ArgMatches| {
+        sub_m
+            .value_of("NAME")
+            .expect("name is not specified")
+            .to_string()
+    };
+
+    match sub_m.subcommand() {
+        (LIST, Some(_)) => store
+            .get_all(ctx, repo_id)
+            .map(|overrides| {
+                for (name, value) in overrides {
+                    println!("{}={}", name, value);
+                }
+            })
+            .boxify(),
+        (SET, Some(sub_m)) => {
+            let name = name(sub_m);
+            let value = sub_m
+                .value_of("VALUE")
+                .expect("value is not specified")
+                .to_string();
+            info!(logger, "overriding {} with {}", name, value);
+            store.set(ctx, repo_id, name, value).boxify()
+        }
+        (UNSET, Some(sub_m)) => {
+            let name = name(sub_m);
+            info!(logger, "removing the override of {}", name);
+            store.remove(ctx, repo_id, name).boxify()
+        }
+        _ => future::err(format_err!("unknown subcommand")).boxify(),
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Inspection of the queue of post-commit hooks of a repo. Hooks that kept failing on a
+//! changeset are given up on and stay in the queue; retrying them makes the workers of the
+//! servers run them again, with a fresh budget of attempts.
+
+use std::collections::HashSet;
+
+use clap::{App, ArgMatches, SubCommand};
+use failure_ext::{format_err, Error};
+use futures::{future, Future};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use slog::{info, Logger};
+
+use cmdlib::args;
+use context::CoreContext;
+use hooks::{PostCommitQueue, PostCommitQueueEntry, SqlPostCommitQueue};
+use mononoke_types::{DateTime, RepositoryId, Timestamp};
+
+const LIST: &'static str = "list";
+const RETRY: &'static str = "retry";
+
+/// Entries looked at when picking the ones to retry
+const RETRY_SCAN_LIMIT: usize = 100000;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("inspect and retry the queued post-commit hooks of a repo")
+        .subcommand(
+            SubCommand::with_name(LIST)
+                .about("list the oldest queued post-commit hooks")
+                .args_from_usage(
+                    r#"
+                    --limit [LIMIT]  'number of entries to list, 100 if omitted'
+                    --given-up       'only list the hooks that were given up on'
+                    "#,
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(RETRY)
+                .about("run queued post-commit hooks again as soon as possible")
+                .args_from_usage(
+                    r#"
+                    [ID]...     'ids of the entries to retry, as listed'
+                    --given-up  'retry every hook that was given up on'
+                    "#,
+                ),
+        )
+}
+
+pub fn handle_command<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let repo_id = args::get_repo_id(matches);
+    let queue: SqlPostCommitQueue = try_boxfuture!(args::open_sql(matches, "post_commit_queue"));
+
+    match sub_m.subcommand() {
+        (LIST, Some(sub_m)) => {
+            let limit = match sub_m.value_of("limit") {
+                Some(limit) => try_boxfuture!(limit.parse::<usize>()),
+                None => 100,
+            };
+            let given_up = sub_m.is_present("given-up");
+            list(ctx, queue, repo_id, limit, given_up)
+        }
+        (RETRY, Some(sub_m)) => {
+            let ids: Result<HashSet<u64>, _> = sub_m
+                .values_of("ID")
+                .into_iter()
+                .flatten()
+                .map(|id| id.parse::<u64>())
+                .collect();
+            let ids = try_boxfuture!(ids);
+            let given_up = sub_m.is_present("given-up");
+            if ids.is_empty() && !given_up {
+                return future::err(format_err!("no ID and no --given-up, nothing to do")).boxify();
+            }
+            retry(ctx, queue, repo_id, ids, given_up, logger)
+        }
+        _ => future::err(format_err!("unknown subcommand")).boxify(),
+    }
+}
+
+fn list(
+    ctx: CoreContext,
+    queue: SqlPostCommitQueue,
+    repo_id: RepositoryId,
+    limit: usize,
+    given_up: bool,
+) -> BoxFuture<(), Error> {
+    let fetch_limit = if given_up { RETRY_SCAN_LIMIT } else { limit };
+    queue
+        .list(ctx, repo_id, fetch_limit)
+        .map(move |entries| {
+            let entries = entries
+                .into_iter()
+                .filter(|entry| !given_up || entry.next_attempt.is_none())
+                .take(limit);
+            for entry in entries {
+                println!("{}", describe(&entry));
+            }
+        })
+        .boxify()
+}
+
+fn describe(entry: &PostCommitQueueEntry) -> String {
+    let next_attempt = match entry.next_attempt {
+        Some(next_attempt) => format!("next attempt at {}", DateTime::from(next_attempt)),
+        None => "given up".to_string(),
+    };
+    let mut line = format!(
+        "{} {} {} queued at {}, {} failed attempts, {}",
+        entry.id.unwrap_or_default(),
+        entry.hook_name,
+        entry.cs_id,
+        entry.timestamp,
+        entry.attempts,
+        next_attempt,
+    );
+    if let Some(ref last_error) = entry.last_error {
+        line.push_str(&format!(": {}", last_error));
+    }
+    line
+}
+
+fn retry(
+    ctx: CoreContext,
+    queue: SqlPostCommitQueue,
+    repo_id: RepositoryId,
+    ids: HashSet<u64>,
+    given_up: bool,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    queue
+        .list(ctx.clone(), repo_id, RETRY_SCAN_LIMIT)
+        .and_then(move |entries| {
+            let now = Timestamp::now();
+            let entries: Vec<_> = entries
+                .into_iter()
+                .filter(|entry| {
+                    entry.id.map_or(false, |id| ids.contains(&id))
+                        || (given_up && entry.next_attempt.is_none())
+                })
+                .map(|entry| PostCommitQueueEntry {
+                    attempts: 0,
+                    next_attempt: Some(now),
+                    ..entry
+                })
+                .collect();
+            let count = entries.len();
+            queue
+                .update(ctx, entries)
+                .map(move |()| info!(logger, "{} post-commit hooks will be retried", count))
+        })
+        .boxify()
+}
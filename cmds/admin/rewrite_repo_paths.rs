@@ -0,0 +1,120 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Rewriting of blobs that hold RepoPaths in the legacy bincode encoding into the versioned
+//! thrift envelope. Readers accept both encodings, so this can run in the background while
+//! servers keep serving the repo. Blobs that are already in the thrift envelope are left alone,
+//! so it is safe to run again over the same keys.
+
+use clap::{App, ArgMatches};
+use cloned::cloned;
+use failure_ext::{format_err, Error};
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::{info, Logger};
+
+use blobstore::{Blobstore, BlobstoreBytes};
+use cmdlib::args;
+use context::CoreContext;
+use mononoke_types::{RepoPath, RepoPathEncoding};
+
+/// Blobs read and rewritten at once
+const CONCURRENCY: usize = 100;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("rewrite blobs of serialized RepoPaths from the legacy bincode encoding to thrift")
+        .args_from_usage(
+            r#"
+            <KEY>...     'blobstore keys of the serialized RepoPaths to rewrite'
+            --dry-run    'only count the blobs that would be rewritten'
+            "#,
+        )
+}
+
+#[derive(Default)]
+struct Counts {
+    checked: u64,
+    missing: u64,
+    legacy: u64,
+}
+
+pub fn handle_command<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let keys: Vec<String> = sub_m
+        .values_of("KEY")
+        .expect("keys are not specified")
+        .map(|key| key.to_string())
+        .collect();
+    let dry_run = sub_m.is_present("dry-run");
+
+    args::open_repo(&logger, matches)
+        .and_then(move |repo| {
+            let blobstore = repo.get_blobstore();
+            stream::iter_ok(keys)
+                .map(move |key| {
+                    cloned!(ctx, blobstore);
+                    rewrite_repo_path(ctx, blobstore, key, dry_run)
+                })
+                .buffer_unordered(CONCURRENCY)
+                .fold(Counts::default(), |mut counts, encoding| {
+                    counts.checked += 1;
+                    match encoding {
+                        None => counts.missing += 1,
+                        Some(RepoPathEncoding::LegacyBincode) => counts.legacy += 1,
+                        Some(RepoPathEncoding::Thrift) => {}
+                    }
+                    Ok::<_, Error>(counts)
+                })
+        })
+        .map(move |counts| {
+            info!(
+                logger,
+                "checked {} blobs, {} missing, {} {}",
+                counts.checked,
+                counts.missing,
+                counts.legacy,
+                if dry_run { "to rewrite" } else { "rewritten" },
+            );
+        })
+        .boxify()
+}
+
+/// Rewrite the RepoPath stored at `key` if it is in the legacy encoding, and return the encoding
+/// it was in, or None if there is no such blob.
+fn rewrite_repo_path<B: Blobstore + Clone>(
+    ctx: CoreContext,
+    blobstore: B,
+    key: String,
+    dry_run: bool,
+) -> BoxFuture<Option<RepoPathEncoding>, Error> {
+    blobstore
+        .get(ctx.clone(), key.clone())
+        .and_then(move |value| {
+            let value = match value {
+                Some(value) => value,
+                None => return future::ok(None).left_future(),
+            };
+            let (path, encoding) = match RepoPath::deserialize_with_encoding(value.as_bytes()) {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    return future::err(format_err!("{} is not a RepoPath: {}", key, err))
+                        .left_future();
+                }
+            };
+            match encoding {
+                RepoPathEncoding::LegacyBincode if !dry_run => blobstore
+                    .put(ctx, key, BlobstoreBytes::from_bytes(path.serialize()))
+                    .map(move |()| Some(encoding))
+                    .right_future(),
+                _ => future::ok(Some(encoding)).left_future(),
+            }
+        })
+        .boxify()
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Sharding of the large manifests that are stored flat, e.g. because they were uploaded before
+//! sharding was configured for the repo.
+
+use clap::{App, ArgMatches};
+use cloned::cloned;
+use failure_ext::{format_err, Error};
+use futures::prelude::*;
+use futures::{future, stream};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use slog::{info, Logger};
+
+use blobrepo::BlobRepo;
+use cmdlib::args;
+use context::CoreContext;
+use mercurial_types::{Changeset, HgManifestId, Type};
+use metaconfig_types::ManifestShardingParams;
+
+use crate::resolve_hg_rev;
+
+const REV_ARG: &'static str = "HG_CHANGESET_OR_BOOKMARK";
+
+/// Subtrees of a manifest that are sharded at the same time
+const SUBTREE_CONCURRENCY: usize = 10;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("store the large manifests of a commit as shards, as configured for the repo")
+        .args_from_usage("<HG_CHANGESET_OR_BOOKMARK>    'commit whose manifests to shard'")
+}
+
+pub fn handle_command<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let (_, config) = try_boxfuture!(args::get_config(matches));
+    let params = match config.manifest_sharding {
+        Some(params) => params,
+        None => {
+            return future::err(format_err!(
+                "manifest sharding isn't configured for the repo"
+            ))
+            .boxify();
+        }
+    };
+    let rev = sub_m.value_of(REV_ARG).unwrap().to_string();
+
+    args::open_repo(&logger, matches)
+        .and_then(move |repo| {
+            resolve_hg_rev(ctx.clone(), &repo, &rev)
+                .and_then({
+                    cloned!(ctx, repo);
+                    move |cs_id| repo.get_changeset_by_changesetid(ctx, cs_id)
+                })
+                .and_then(move |cs| shard_tree(ctx, repo, cs.manifestid(), params))
+        })
+        .map(move |(visited, sharded)| {
+            info!(
+                logger,
+                "sharded {} of the {} manifests of the commit", sharded, visited
+            );
+        })
+        .boxify()
+}
+
+/// Shard `manifest_id` and the manifests below it. Returns how many manifests were visited and
+/// how many of them were sharded.
+fn shard_tree(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    manifest_id: HgManifestId,
+    params: ManifestShardingParams,
+) -> BoxFuture<(u64, u64), Error> {
+    repo.shard_manifest(ctx.clone(), manifest_id, params)
+        .join(repo.get_manifest_by_nodeid(ctx.clone(), manifest_id))
+        .and_then(move |(sharded, manifest)| {
+            let subtrees: Vec<_> = manifest
+                .list()
+                .filter(|entry| entry.get_type() == Type::Tree)
+                .map(|entry| HgManifestId::new(entry.get_hash().into_nodehash()))
+                .collect();
+            stream::iter_ok(subtrees)
+                .map(move |subtree| shard_tree(ctx.clone(), repo.clone(), subtree, params))
+                .buffer_unordered(SUBTREE_CONCURRENCY)
+                .fold((1, sharded as u64), |(visited, sharded), (v, s)| {
+                    Ok::<_, Error>((visited + v, sharded + s))
+                })
+        })
+        .boxify()
+}
@@ -0,0 +1,361 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Building of the skiplist index of a repo, which servers and the apiserver load from the
+//! blobstore at startup to answer ancestry queries without walking the whole history.
+//!
+//! The index is built from the changesets table, read in full into memory first, and covers the
+//! ancestors of all bookmarks. Only the longest skip edge of every node is stored.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use clap::{App, ArgMatches, SubCommand};
+use cloned::cloned;
+use failure_ext::{format_err, Error};
+use futures::future::{self, loop_fn, ok, Loop};
+use futures::prelude::*;
+use futures::stream::iter_ok;
+use futures_ext::{BoxFuture, FutureExt};
+use slog::{debug, info, Logger};
+
+use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use changeset_fetcher::ChangesetFetcher;
+use changesets::{ChangesetEntry, Changesets, SqlChangesets};
+use cmdlib::args;
+use context::CoreContext;
+use mononoke_types::{BlobstoreBytes, ChangesetId, Generation, RepositoryId};
+use skiplist::{deserialize_skiplist_map, SkiplistIndex, SkiplistNodeType};
+
+const BUILD: &'static str = "build";
+const READ: &'static str = "read";
+
+/// Skip edges per node while building: the longest ones jump up to 2^9 changesets
+const SKIP_EDGE_COUNT: u32 = 10;
+
+/// Ancestors of a head indexed at most, large enough to index all of them
+const MAX_INDEX_DEPTH: u64 = 20000000000;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("commands to build or read skiplist indexes")
+        .subcommand(
+            SubCommand::with_name(BUILD)
+                .about("build the skiplist index of the repo and store it in its blobstore")
+                .args_from_usage(
+                    "<BLOBSTORE_KEY>  'Blobstore key where to store the built skiplist'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(READ)
+                .about("read a skiplist index and print how much of the repo it covers")
+                .args_from_usage(
+                    "<BLOBSTORE_KEY>  'Blobstore key from where to read the skiplist'",
+                ),
+        )
+}
+
+pub fn handle_command<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let (build, sub_m) = match sub_m.subcommand() {
+        (BUILD, Some(sub_m)) => (true, sub_m),
+        (READ, Some(sub_m)) => (false, sub_m),
+        _ => return future::err(format_err!("unknown subcommand")).boxify(),
+    };
+    let key = sub_m
+        .value_of("BLOBSTORE_KEY")
+        .expect("blobstore key is not specified")
+        .to_string();
+
+    let sql_changesets = args::open_sql_changesets(matches);
+    args::open_repo(&logger, matches)
+        .join(sql_changesets)
+        .and_then(move |(repo, sql_changesets)| {
+            let changesets = fetch_all_changesets(ctx.clone(), repo.get_repoid(), sql_changesets)
+                .map(|changesets| {
+                    let changesets: HashMap<_, _> = changesets
+                        .into_iter()
+                        .map(|cs_entry| (cs_entry.cs_id, cs_entry))
+                        .collect();
+                    Arc::new(changesets)
+                });
+            if build {
+                changesets
+                    .and_then(move |changesets| build_index(ctx, repo, key, logger, changesets))
+                    .boxify()
+            } else {
+                changesets
+                    .and_then(move |changesets| read_index(ctx, repo, key, logger, changesets))
+                    .boxify()
+            }
+        })
+        .boxify()
+}
+
+fn fetch_all_changesets(
+    ctx: CoreContext,
+    repo_id: RepositoryId,
+    sqlchangesets: SqlChangesets,
+) -> impl Future<Item = Vec<ChangesetEntry>, Error = Error> {
+    let sqlchangesets = Arc::new(sqlchangesets);
+    let num_sql_fetches = 10000;
+    sqlchangesets
+        .get_changesets_ids_bounds(repo_id.clone())
+        .map(move |(maybe_lower_bound, maybe_upper_bound)| {
+            let lower_bound = maybe_lower_bound.expect("changesets table is empty");
+            let upper_bound = maybe_upper_bound.expect("changesets table is empty");
+            let step = (upper_bound - lower_bound) / num_sql_fetches;
+            let step = ::std::cmp::max(100, step);
+
+            iter_ok(
+                (lower_bound..upper_bound)
+                    .step_by(step as usize)
+                    .map(move |i| (i, i + step)),
+            )
+        })
+        .flatten_stream()
+        .and_then(move |(lower_bound, upper_bound)| {
+            sqlchangesets
+                .get_list_bs_cs_id_in_range(repo_id, lower_bound, upper_bound)
+                .collect()
+                .and_then({
+                    cloned!(ctx, sqlchangesets);
+                    move |ids| {
+                        sqlchangesets
+                            .get_many(ctx, repo_id, ids)
+                            .map(|v| iter_ok(v.into_iter()))
+                    }
+                })
+        })
+        .flatten()
+        .collect()
+}
+
+#[derive(Clone)]
+struct InMemoryChangesetFetcher {
+    fetched_changesets: Arc<HashMap<ChangesetId, ChangesetEntry>>,
+    inner: Arc<dyn ChangesetFetcher>,
+}
+
+impl ChangesetFetcher for InMemoryChangesetFetcher {
+    fn get_generation_number(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> BoxFuture<Generation, Error> {
+        match self.fetched_changesets.get(&cs_id) {
+            Some(cs_entry) => ok(Generation::new(cs_entry.gen)).boxify(),
+            None => self.inner.get_generation_number(ctx, cs_id),
+        }
+    }
+
+    fn get_parents(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> BoxFuture<Vec<ChangesetId>, Error> {
+        match self.fetched_changesets.get(&cs_id) {
+            Some(cs_entry) => ok(cs_entry.parents.clone()).boxify(),
+            None => self.inner.get_parents(ctx, cs_id),
+        }
+    }
+}
+
+/// How much of a repo a skiplist index covers
+#[derive(Debug, Default, PartialEq)]
+struct SkiplistStats {
+    /// Changesets of the repo
+    changesets: usize,
+    /// Indexed changesets
+    nodes: usize,
+    /// Edges of the nodes, skip edges and parent edges alike
+    edges: usize,
+    /// Nodes that only have edges to their parents, e.g. merges
+    parent_edge_nodes: usize,
+    /// Most generations one edge skips over
+    max_depth: u64,
+}
+
+impl SkiplistStats {
+    fn new(
+        skiplist: &HashMap<ChangesetId, SkiplistNodeType>,
+        changesets: &HashMap<ChangesetId, ChangesetEntry>,
+    ) -> Self {
+        let mut stats = Self {
+            changesets: changesets.len(),
+            nodes: skiplist.len(),
+            ..Self::default()
+        };
+        for (cs_id, node) in skiplist {
+            let edges = match node {
+                SkiplistNodeType::SkipEdges(edges) => edges,
+                SkiplistNodeType::ParentEdges(edges) => {
+                    stats.parent_edge_nodes += 1;
+                    edges
+                }
+            };
+            stats.edges += edges.len();
+            if let Some(cs_entry) = changesets.get(cs_id) {
+                for (_, generation) in edges {
+                    let depth = cs_entry.gen.saturating_sub(generation.value());
+                    stats.max_depth = cmp::max(stats.max_depth, depth);
+                }
+            }
+        }
+        stats
+    }
+}
+
+impl fmt::Display for SkiplistStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "changesets: {}", self.changesets)?;
+        writeln!(f, "indexed nodes: {}", self.nodes)?;
+        writeln!(f, "edges: {}", self.edges)?;
+        writeln!(f, "parent edge nodes: {}", self.parent_edge_nodes)?;
+        write!(f, "max depth of an edge: {}", self.max_depth)
+    }
+}
+
+fn build_index(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    key: String,
+    logger: Logger,
+    changesets: Arc<HashMap<ChangesetId, ChangesetEntry>>,
+) -> BoxFuture<(), Error> {
+    let blobstore = repo.get_blobstore();
+    let skiplist_index = SkiplistIndex::with_skip_edge_count(SKIP_EDGE_COUNT);
+    let cs_fetcher = InMemoryChangesetFetcher {
+        fetched_changesets: changesets.clone(),
+        inner: repo.get_changeset_fetcher(),
+    };
+
+    repo.get_bonsai_heads_maybe_stale(ctx.clone())
+        .collect()
+        .and_then({
+            cloned!(ctx);
+            move |heads| {
+                loop_fn(
+                    (heads.into_iter(), skiplist_index),
+                    move |(mut heads, skiplist_index)| match heads.next() {
+                        Some(head) => {
+                            let f = skiplist_index.add_node(
+                                ctx.clone(),
+                                Arc::new(cs_fetcher.clone()),
+                                head,
+                                MAX_INDEX_DEPTH,
+                            );
+
+                            f.map(move |()| Loop::Continue((heads, skiplist_index)))
+                                .boxify()
+                        }
+                        None => ok(Loop::Break(skiplist_index)).boxify(),
+                    },
+                )
+            }
+        })
+        .map({
+            cloned!(logger);
+            move |skiplist_index| {
+                info!(
+                    logger,
+                    "built {} skiplist nodes",
+                    skiplist_index.indexed_node_count()
+                );
+                let bytes = skiplist_index.serialize();
+                println!(
+                    "{}",
+                    SkiplistStats::new(&skiplist_index.get_all_skip_edges(), &changesets)
+                );
+                bytes
+            }
+        })
+        .and_then({
+            cloned!(ctx);
+            move |bytes| {
+                debug!(logger, "storing {} bytes", bytes.len());
+                blobstore.put(ctx, key, BlobstoreBytes::from_bytes(bytes))
+            }
+        })
+        .boxify()
+}
+
+fn read_index(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    key: String,
+    logger: Logger,
+    changesets: Arc<HashMap<ChangesetId, ChangesetEntry>>,
+) -> BoxFuture<(), Error> {
+    repo.get_blobstore()
+        .get(ctx, key.clone())
+        .and_then(move |maybebytes| match maybebytes {
+            Some(bytes) => {
+                debug!(logger, "received {} bytes from blobstore", bytes.len());
+                let skiplist_map = deserialize_skiplist_map(bytes.into_bytes())?;
+                println!("{}", SkiplistStats::new(&skiplist_map, &changesets));
+                Ok(())
+            }
+            None => Err(format_err!("no skiplist index under {}", key)),
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mononoke_types_mocks::changesetid::{ONES_CSID, THREES_CSID, TWOS_CSID};
+
+    fn entry(cs_id: ChangesetId, gen: u64) -> ChangesetEntry {
+        ChangesetEntry {
+            repo_id: RepositoryId::new(0),
+            cs_id,
+            parents: vec![],
+            gen,
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let changesets: HashMap<_, _> = vec![
+            (ONES_CSID, entry(ONES_CSID, 1)),
+            (TWOS_CSID, entry(TWOS_CSID, 2)),
+            (THREES_CSID, entry(THREES_CSID, 10)),
+        ]
+        .into_iter()
+        .collect();
+        let skiplist: HashMap<_, _> = vec![
+            (
+                THREES_CSID,
+                SkiplistNodeType::SkipEdges(vec![(ONES_CSID, Generation::new(1))]),
+            ),
+            (
+                TWOS_CSID,
+                SkiplistNodeType::ParentEdges(vec![(ONES_CSID, Generation::new(1))]),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            SkiplistStats::new(&skiplist, &changesets),
+            SkiplistStats {
+                changesets: 3,
+                nodes: 2,
+                edges: 2,
+                parent_edge_nodes: 1,
+                max_depth: 9,
+            }
+        );
+    }
+}
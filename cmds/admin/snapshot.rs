@@ -0,0 +1,541 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Snapshots of repos, e.g. for audits. `export` writes the blobs that some bookmarks lead to,
+//! and the SQL rows of their changesets, to an archive that `import` loads into another repo,
+//! which may be in a fresh Mononoke instance.
+//!
+//! An archive is a directory, to be copied or tarred as it is:
+//! - `blobs/<key>`: the blobs, named by their blobstore key
+//! - `MANIFEST`: a `<key> <size> <hash>` line for each blob, where the hash is the blake2 of the
+//!   blob. A line is only appended once its blob is written, so an export that was interrupted
+//!   resumes from the blobs that it lists. Imports check every blob against it.
+//! - `metadata.json`: the bookmarks, and the changesets with their parents, generations and hg
+//!   changesets. It is written last, so an archive without it is an unfinished export.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use cloned::cloned;
+use failure_ext::{bail_msg, format_err, Error, Result};
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, Logger};
+
+use blobrepo::BlobRepo;
+use blobrepo_factory::open_blobrepo;
+use blobstore::Blobstore;
+use bonsai_hg_mapping::{BonsaiHgMapping, BonsaiHgMappingEntry, SqlBonsaiHgMapping};
+use bookmarks::{Bookmark, BookmarkUpdateReason};
+use changesets::{ChangesetInsert, Changesets, SqlChangesets};
+use cmdlib::args;
+use context::CoreContext;
+use derived_filenodes::derive_filenodes;
+use mercurial_types::HgChangesetId;
+use mononoke_types::hash::Context;
+use mononoke_types::{BlobstoreBytes, ChangesetId, RepositoryId};
+use storage_usage::{all_ancestors, walk_blobs, BlobVisitor};
+
+const EXPORT: &'static str = "export";
+const IMPORT: &'static str = "import";
+
+const BLOBS_DIR: &'static str = "blobs";
+const MANIFEST: &'static str = "MANIFEST";
+const METADATA: &'static str = "metadata.json";
+
+/// Key of the blake2 hashes of the manifest
+const HASH_KEY: &'static [u8] = b"snapshot";
+
+/// Blobs and rows fetched or stored at the same time
+const CONCURRENCY: usize = 100;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("export a snapshot of a repo to an archive, or import one into a repo")
+        .subcommand(
+            SubCommand::with_name(EXPORT)
+                .about(
+                    "export the blobs and changesets that bookmarks lead to, resuming the \
+                     export already in DIR if there is one",
+                )
+                .args_from_usage("<DIR>  'directory of the archive'")
+                .arg(
+                    Arg::with_name("bookmark")
+                        .long("bookmark")
+                        .short("b")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("bookmark to export, all of them if omitted"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(IMPORT)
+                .about(
+                    "import an archive into a repo after checking it against its manifest. \
+                     Importing again resumes an import that failed.",
+                )
+                .args_from_usage("<DIR>  'directory of the archive'"),
+        )
+}
+
+pub fn handle_command<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    match sub_m.subcommand() {
+        (EXPORT, Some(sub_m)) => export(ctx, matches, sub_m, logger),
+        (IMPORT, Some(sub_m)) => import(ctx, matches, sub_m, logger),
+        _ => {
+            println!("{}", sub_m.usage());
+            ::std::process::exit(1);
+        }
+    }
+}
+
+/// What an archive holds besides its blobs
+#[derive(Debug, Deserialize, Serialize)]
+struct Metadata {
+    /// The changeset of each bookmark
+    bookmarks: BTreeMap<String, String>,
+    /// The changesets that the bookmarks lead to, parents first
+    changesets: Vec<ChangesetMetadata>,
+    /// The number of blobs in the manifest
+    blobs: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ChangesetMetadata {
+    cs_id: String,
+    parents: Vec<String>,
+    generation: u64,
+    /// None if the hg changeset wasn't generated
+    hg_cs_id: Option<String>,
+}
+
+/// A line of the manifest
+struct ManifestEntry {
+    key: String,
+    size: usize,
+    hash: String,
+}
+
+fn blob_hash(blob: &[u8]) -> String {
+    let mut context = Context::new(HASH_KEY);
+    context.update(blob);
+    context.finish().to_hex().to_string()
+}
+
+/// The entries of the manifest in `dir`, and the length of the manifest up to the last complete
+/// line. A line that isn't complete was being written when an export was interrupted.
+fn read_manifest(dir: &Path) -> Result<(Vec<ManifestEntry>, u64)> {
+    let path = dir.join(MANIFEST);
+    if !path.exists() {
+        return Ok((vec![], 0));
+    }
+
+    let mut reader = BufReader::new(File::open(&path)?);
+    let mut entries = vec![];
+    let mut len = 0;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        if !line.ends_with('\n') {
+            break;
+        }
+        let fields: Vec<_> = line.trim_end().split(' ').collect();
+        match fields.as_slice() {
+            [key, size, hash] => entries.push(ManifestEntry {
+                key: key.to_string(),
+                size: size.parse()?,
+                hash: hash.to_string(),
+            }),
+            _ => bail_msg!("bad line in {}: {:?}", path.display(), line),
+        }
+        len += line.len() as u64;
+        line.clear();
+    }
+    Ok((entries, len))
+}
+
+/// An archive being exported. Blobs already in its manifest aren't written again.
+struct ArchiveWriter {
+    dir: PathBuf,
+    written: Mutex<(HashSet<String>, File)>,
+}
+
+impl ArchiveWriter {
+    fn open(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(dir.join(BLOBS_DIR))?;
+        // The archive changes, so any metadata of an earlier export is out of date
+        let metadata = dir.join(METADATA);
+        if metadata.exists() {
+            fs::remove_file(metadata)?;
+        }
+
+        let (entries, len) = read_manifest(&dir)?;
+        let manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(MANIFEST))?;
+        manifest.set_len(len)?;
+
+        let keys = entries.into_iter().map(|entry| entry.key).collect();
+        Ok(Self {
+            dir,
+            written: Mutex::new((keys, manifest)),
+        })
+    }
+
+    fn len(&self) -> usize {
+        let written = self.written.lock().expect("lock poisoned");
+        written.0.len()
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        let written = self.written.lock().expect("lock poisoned");
+        written.0.contains(key)
+    }
+
+    /// Write the metadata, which completes the archive. Returns the number of blobs.
+    fn finish(
+        &self,
+        bookmarks: BTreeMap<String, String>,
+        changesets: Vec<ChangesetMetadata>,
+    ) -> Result<usize> {
+        let metadata = Metadata {
+            bookmarks,
+            changesets,
+            blobs: self.len(),
+        };
+        let tmp = self.dir.join(format!("{}.tmp", METADATA));
+        fs::write(&tmp, serde_json::to_vec_pretty(&metadata)?)?;
+        fs::rename(tmp, self.dir.join(METADATA))?;
+        Ok(metadata.blobs)
+    }
+}
+
+impl BlobVisitor for ArchiveWriter {
+    fn needs(&self, key: &str) -> bool {
+        !self.contains(key)
+    }
+
+    fn visit(&self, key: &str, blob: &BlobstoreBytes) -> Result<()> {
+        if self.contains(key) {
+            return Ok(());
+        }
+        let blob = blob.as_bytes();
+        fs::write(self.dir.join(BLOBS_DIR).join(key), blob)?;
+
+        let line = format!("{} {} {}\n", key, blob.len(), blob_hash(blob));
+        let mut written = self.written.lock().expect("lock poisoned");
+        written.1.write_all(line.as_bytes())?;
+        written.0.insert(key.to_string());
+        Ok(())
+    }
+}
+
+/// The bookmarks named `names`, or all of them
+fn resolve_bookmarks(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    names: Option<Vec<String>>,
+) -> BoxFuture<Vec<(Bookmark, ChangesetId)>, Error> {
+    match names {
+        Some(names) => stream::iter_result(names.into_iter().map(Bookmark::new))
+            .and_then(move |bookmark| {
+                repo.get_bonsai_bookmark(ctx.clone(), &bookmark).and_then(
+                    move |cs_id| match cs_id {
+                        Some(cs_id) => Ok((bookmark, cs_id)),
+                        None => Err(format_err!("bookmark {} does not exist", bookmark)),
+                    },
+                )
+            })
+            .collect()
+            .boxify(),
+        None => repo.get_bonsai_bookmarks(ctx).collect().boxify(),
+    }
+}
+
+/// The metadata of `changesets`, parents first
+fn changeset_metadata(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    changesets: HashSet<ChangesetId>,
+) -> impl Future<Item = Vec<ChangesetMetadata>, Error = Error> {
+    stream::iter_ok(changesets)
+        .map(move |cs_id| {
+            let parents = repo.get_changeset_parents_by_bonsai(ctx.clone(), cs_id);
+            let generation = repo
+                .get_generation_number_by_bonsai(ctx.clone(), cs_id)
+                .and_then(move |generation| {
+                    generation.ok_or_else(|| format_err!("changeset {} has no generation", cs_id))
+                });
+            let hg_cs_id = repo.get_hg_bonsai_mapping(ctx.clone(), cs_id);
+            parents
+                .join3(generation, hg_cs_id)
+                .map(move |(parents, generation, hg_cs_id)| ChangesetMetadata {
+                    cs_id: cs_id.to_string(),
+                    parents: parents.iter().map(|parent| parent.to_string()).collect(),
+                    generation: generation.value(),
+                    hg_cs_id: hg_cs_id
+                        .into_iter()
+                        .next()
+                        .map(|(hg_cs_id, _)| hg_cs_id.to_string()),
+                })
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect()
+        .map(|mut changesets| {
+            changesets.sort_by_key(|cs| cs.generation);
+            changesets
+        })
+}
+
+fn export<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let dir = PathBuf::from(sub_m.value_of("DIR").unwrap());
+    let names = sub_m
+        .values_of("bookmark")
+        .map(|names| names.map(|name| name.to_string()).collect());
+    let (name, config) = try_boxfuture!(args::get_config(matches));
+    let repo_id = RepositoryId::new(config.repoid);
+    let myrouter_port = args::parse_myrouter_port(matches);
+
+    let archive = Arc::new(try_boxfuture!(ArchiveWriter::open(dir.clone())));
+    info!(
+        logger,
+        "exporting repo {} to {}, where {} blobs were exported already",
+        name,
+        dir.display(),
+        archive.len()
+    );
+
+    open_blobrepo(logger.clone(), config.repotype, repo_id, myrouter_port)
+        .and_then({
+            cloned!(ctx);
+            move |repo| resolve_bookmarks(ctx, repo.clone(), names).map(move |b| (repo, b))
+        })
+        .and_then(move |(repo, bookmarks)| {
+            let heads = bookmarks.iter().map(|(_, cs_id)| *cs_id).collect();
+            let bookmarks = bookmarks
+                .into_iter()
+                .map(|(bookmark, cs_id)| (bookmark.to_string(), cs_id.to_string()))
+                .collect();
+            all_ancestors(ctx.clone(), repo.clone(), heads).and_then(move |changesets| {
+                let blobs = walk_blobs(
+                    ctx.clone(),
+                    repo.clone(),
+                    changesets.clone(),
+                    archive.clone(),
+                );
+                blobs
+                    .join(changeset_metadata(ctx, repo, changesets))
+                    .and_then(move |((), changesets)| archive.finish(bookmarks, changesets))
+            })
+        })
+        .map(move |blobs| info!(logger, "exported {} blobs", blobs))
+        .boxify()
+}
+
+/// Read the blob of `entry` from the archive in `dir`, and check it
+fn read_blob(dir: &Path, entry: &ManifestEntry) -> Result<BlobstoreBytes> {
+    let blob = fs::read(dir.join(BLOBS_DIR).join(&entry.key))?;
+    if blob.len() != entry.size || blob_hash(&blob) != entry.hash {
+        bail_msg!("blob {} does not match the manifest", entry.key);
+    }
+    Ok(BlobstoreBytes::from_bytes(blob))
+}
+
+fn read_metadata(dir: &Path) -> Result<Metadata> {
+    let path = dir.join(METADATA);
+    if !path.exists() {
+        bail_msg!(
+            "{} has no {}, the export is unfinished",
+            dir.display(),
+            METADATA
+        );
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+fn import<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let dir = PathBuf::from(sub_m.value_of("DIR").unwrap());
+    let metadata = try_boxfuture!(read_metadata(&dir));
+    let (entries, _) = try_boxfuture!(read_manifest(&dir));
+    if entries.len() != metadata.blobs {
+        return future::err(format_err!(
+            "{} lists {} blobs rather than {}",
+            MANIFEST,
+            entries.len(),
+            metadata.blobs
+        ))
+        .boxify();
+    }
+
+    let (name, config) = try_boxfuture!(args::get_config(matches));
+    let repo_id = RepositoryId::new(config.repoid);
+    let myrouter_port = args::parse_myrouter_port(matches);
+    let changesets: SqlChangesets =
+        try_boxfuture!(args::open_sql_with_config(matches, &config, "changesets"));
+    let mapping: SqlBonsaiHgMapping = try_boxfuture!(args::open_sql_with_config(
+        matches,
+        &config,
+        "bonsai_hg_mapping"
+    ));
+    info!(
+        logger,
+        "importing {} blobs and {} changesets from {} into repo {}",
+        entries.len(),
+        metadata.changesets.len(),
+        dir.display(),
+        name
+    );
+
+    let Metadata {
+        bookmarks,
+        changesets: changeset_rows,
+        ..
+    } = metadata;
+    let bookmarks: Vec<_> = try_boxfuture!(bookmarks
+        .into_iter()
+        .map(|(bookmark, cs_id)| Ok((Bookmark::new(bookmark)?, ChangesetId::from_str(&cs_id)?)))
+        .collect::<Result<_>>());
+
+    open_blobrepo(logger.clone(), config.repotype, repo_id, myrouter_port)
+        .and_then({
+            cloned!(ctx, logger);
+            move |repo| {
+                stream::iter_ok(entries)
+                    .map({
+                        cloned!(ctx, repo);
+                        move |entry| {
+                            read_blob(&dir, &entry).into_future().and_then({
+                                cloned!(ctx, repo);
+                                move |blob| repo.get_blobstore().put(ctx, entry.key, blob)
+                            })
+                        }
+                    })
+                    .buffer_unordered(CONCURRENCY)
+                    .for_each(|()| Ok(()))
+                    .and_then(move |()| {
+                        info!(logger, "imported the blobs");
+                        import_changesets(ctx, repo_id, changesets, mapping, changeset_rows)
+                    })
+                    .map(move |()| repo)
+            }
+        })
+        .and_then({
+            cloned!(ctx, bookmarks);
+            move |repo| {
+                let mut transaction = repo.update_bookmark_transaction(ctx);
+                for (bookmark, cs_id) in &bookmarks {
+                    try_boxfuture!(transaction.force_set(
+                        bookmark,
+                        *cs_id,
+                        BookmarkUpdateReason::ManualMove
+                    ));
+                }
+                transaction
+                    .commit()
+                    .and_then(|committed| {
+                        if !committed {
+                            bail_msg!("failed to set the bookmarks");
+                        }
+                        Ok(())
+                    })
+                    .map(move |()| repo)
+                    .boxify()
+            }
+        })
+        .and_then(move |repo| {
+            // Filenodes are derived from the changesets rather than exported
+            stream::iter_ok(bookmarks).for_each(move |(_, cs_id)| {
+                derive_filenodes(ctx.clone(), repo.clone(), cs_id).map(|_| ())
+            })
+        })
+        .map(move |()| info!(logger, "imported the changesets and bookmarks"))
+        .boxify()
+}
+
+/// Add the rows of `rows` to the changesets and bonsai hg mapping tables. Changesets of the same
+/// generation are added together, after their parents.
+fn import_changesets(
+    ctx: CoreContext,
+    repo_id: RepositoryId,
+    changesets: SqlChangesets,
+    mapping: SqlBonsaiHgMapping,
+    rows: Vec<ChangesetMetadata>,
+) -> impl Future<Item = (), Error = Error> {
+    let mut generations: BTreeMap<u64, Vec<ChangesetMetadata>> = BTreeMap::new();
+    for row in rows {
+        generations.entry(row.generation).or_default().push(row);
+    }
+    let changesets = Arc::new(changesets);
+    let mapping = Arc::new(mapping);
+
+    stream::iter_ok(generations.into_iter().map(|(_, rows)| rows)).for_each(move |rows| {
+        cloned!(ctx, changesets, mapping);
+        stream::iter_ok(rows)
+            .map(move |row| {
+                cloned!(ctx, changesets, mapping);
+                parse_changeset_row(repo_id, row)
+                    .into_future()
+                    .and_then(move |(insert, entry)| {
+                        let mapping = match entry {
+                            Some(entry) => {
+                                mapping.add(ctx.clone(), entry).map(|_| ()).left_future()
+                            }
+                            None => future::ok(()).right_future(),
+                        };
+                        changesets.add(ctx, insert).join(mapping)
+                    })
+            })
+            .buffer_unordered(CONCURRENCY)
+            .for_each(|_| Ok(()))
+    })
+}
+
+fn parse_changeset_row(
+    repo_id: RepositoryId,
+    row: ChangesetMetadata,
+) -> Result<(ChangesetInsert, Option<BonsaiHgMappingEntry>)> {
+    let cs_id = ChangesetId::from_str(&row.cs_id)?;
+    let parents = row
+        .parents
+        .iter()
+        .map(|parent| ChangesetId::from_str(parent))
+        .collect::<Result<_>>()?;
+    let entry = match row.hg_cs_id {
+        Some(hg_cs_id) => Some(BonsaiHgMappingEntry {
+            repo_id,
+            hg_cs_id: HgChangesetId::from_str(&hg_cs_id)?,
+            bcs_id: cs_id,
+        }),
+        None => None,
+    };
+    let insert = ChangesetInsert {
+        repo_id,
+        cs_id,
+        parents,
+    };
+    Ok((insert, entry))
+}
@@ -0,0 +1,162 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Reports of the storage that a repo uses, by blob type. With --measure, the repo is walked and
+//! a new snapshot is recorded, which is meant to be run periodically. Otherwise the latest
+//! snapshot is reported.
+
+use std::fmt;
+use std::sync::Arc;
+
+use clap::{App, ArgMatches};
+use cloned::cloned;
+use failure_ext::{format_err, Error};
+use futures::Future;
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use serde_derive::Serialize;
+use slog::{info, Logger};
+
+use blobrepo_factory::open_blobrepo;
+use cmdlib::args;
+use context::CoreContext;
+use dbbookmarks::SqlBookmarks;
+use mononoke_types::{DateTime, RepositoryId};
+use storage_usage::{measure_repo_usage, SqlStorageUsage, StorageUsageSnapshot, StorageUsageStore};
+
+use crate::doctor::find_config;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("report the blobs and bytes that a repo stores, by blob type")
+        .args_from_usage(
+            r#"
+            [REPO]       'name of the repo to report, the repo of --repo-id if omitted'
+            --measure    'walk the repo and record a new snapshot, rather than report the latest one'
+            --json       'if provided the report will be json'
+            "#,
+        )
+}
+
+#[derive(Debug, Serialize)]
+struct BlobTypeReport {
+    blob_type: String,
+    blobs: u64,
+    bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    repo: String,
+    taken_at: String,
+    blob_types: Vec<BlobTypeReport>,
+    total_blobs: u64,
+    total_bytes: u64,
+}
+
+impl Report {
+    fn new(repo: String, snapshot: StorageUsageSnapshot) -> Self {
+        let total = snapshot.total();
+        Self {
+            repo,
+            taken_at: snapshot.taken_at.to_string(),
+            blob_types: snapshot
+                .by_type
+                .into_iter()
+                .map(|(blob_type, usage)| BlobTypeReport {
+                    blob_type,
+                    blobs: usage.blobs,
+                    bytes: usage.bytes,
+                })
+                .collect(),
+            total_blobs: total.blobs,
+            total_bytes: total.bytes,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} at {}", self.repo, self.taken_at)?;
+        writeln!(f, "{:<16}  {:>12}  {:>16}", "TYPE", "BLOBS", "BYTES")?;
+        for usage in &self.blob_types {
+            writeln!(
+                f,
+                "{:<16}  {:>12}  {:>16}",
+                usage.blob_type, usage.blobs, usage.bytes
+            )?;
+        }
+        write!(
+            f,
+            "{:<16}  {:>12}  {:>16}",
+            "total", self.total_blobs, self.total_bytes
+        )
+    }
+}
+
+pub fn handle_command<'a>(
+    ctx: CoreContext,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let json = sub_m.is_present("json");
+    let (name, config) = try_boxfuture!(find_config(matches, sub_m.value_of("REPO")));
+    let repo_id = RepositoryId::new(config.repoid);
+    let store: SqlStorageUsage = try_boxfuture!(args::open_sql_with_config(
+        matches,
+        &config,
+        "storage_usage"
+    ));
+
+    let snapshot = if sub_m.is_present("measure") {
+        let bookmarks: SqlBookmarks =
+            try_boxfuture!(args::open_sql_with_config(matches, &config, "bookmarks"));
+        let myrouter_port = args::parse_myrouter_port(matches);
+        info!(logger, "measuring the storage of repo {}", name);
+
+        open_blobrepo(logger, config.repotype, repo_id, myrouter_port)
+            .and_then({
+                cloned!(ctx);
+                move |repo| measure_repo_usage(ctx, repo, Arc::new(bookmarks))
+            })
+            .and_then(move |by_type| {
+                let snapshot = StorageUsageSnapshot {
+                    repo_id,
+                    taken_at: DateTime::now(),
+                    by_type,
+                };
+                store
+                    .add_snapshot(ctx, snapshot.clone())
+                    .map(move |()| snapshot)
+            })
+            .left_future()
+    } else {
+        store
+            .get_latest_snapshot(ctx, repo_id)
+            .and_then({
+                cloned!(name);
+                move |snapshot| {
+                    snapshot.ok_or_else(|| {
+                        format_err!(
+                            "no storage usage snapshot of repo {}, take one with --measure",
+                            name
+                        )
+                    })
+                }
+            })
+            .right_future()
+    };
+
+    snapshot
+        .map(move |snapshot| {
+            let report = Report::new(name, snapshot);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            } else {
+                println!("{}", report);
+            }
+        })
+        .boxify()
+}
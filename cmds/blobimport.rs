@@ -30,6 +30,7 @@ use phases::SqlPhases;
 use tracing::{trace_args, Traced};
 
 use cmdlib::args;
+use cmdlib::startup::{StartupErrors, EXIT_RUNTIME_ERROR};
 use mercurial_types::HgNodeHash;
 
 fn setup_app<'a, 'b>() -> App<'a, 'b> {
@@ -68,14 +69,21 @@ fn main() -> Result<()> {
 
     args::init_cachelib(&matches);
 
+    let mut errors = StartupErrors::new();
+
     let revlogrepo_path = matches
         .value_of("INPUT")
         .expect("input is not specified")
         .into();
 
     let changeset = match matches.value_of("changeset") {
-        None => None,
-        Some(hash) => Some(HgNodeHash::from_str(hash)?),
+        None => Some(None),
+        Some(hash) => errors
+            .config(
+                format!("parsing --changeset {}", hash),
+                HgNodeHash::from_str(hash),
+            )
+            .map(Some),
     };
 
     let skip = if !matches.is_present("skip") {
@@ -92,7 +100,19 @@ fn main() -> Result<()> {
 
     let no_bookmark = matches.is_present("no-bookmark");
 
-    let phases_store = Arc::new(args::open_sql::<SqlPhases>(&matches, "phases")?);
+    let phases_store = errors.init(
+        "opening the phases store",
+        args::open_sql::<SqlPhases>(&matches, "phases"),
+    );
+
+    let runtime = errors.init("creating the tokio runtime", tokio::runtime::Runtime::new());
+
+    let (changeset, phases_store, mut runtime) = match (changeset, phases_store, runtime) {
+        (Some(changeset), Some(phases_store), Some(runtime)) => {
+            (changeset, Arc::new(phases_store), runtime)
+        }
+        _ => errors.exit(ctx.logger()),
+    };
 
     let blobimport = args::create_repo(&ctx.logger(), &matches).and_then(move |repo| {
         let blobrepo = Arc::new(repo.clone());
@@ -113,13 +133,12 @@ fn main() -> Result<()> {
             cloned!(ctx);
             move |err| {
                 error!(ctx.logger(), "error while blobimporting"; SlogKVError(err));
-                ::std::process::exit(1);
+                ::std::process::exit(EXIT_RUNTIME_ERROR);
             }
         })
         .then(move |result| args::upload_and_show_trace(ctx).then(move |_| result))
     });
 
-    let mut runtime = tokio::runtime::Runtime::new()?;
     let result = runtime.block_on(blobimport);
     // Let the runtime finish remaining work - uploading logs etc
     runtime.shutdown_on_idle();
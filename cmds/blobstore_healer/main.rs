@@ -78,12 +78,17 @@ fn maybe_schedule_healer_for_repo(
                     blobstores.insert(id, blobstore);
                 }
                 RemoteBlobstoreArgs::Mysql(args) => {
-                    let blobstore: Arc<Blobstore> = Arc::new(Sqlblob::with_myrouter(
+                    let blobstore = Sqlblob::with_myrouter(
                         RepositoryId::new(config.repoid),
                         args.shardmap,
                         myrouter_port,
                         args.shard_num,
-                    ));
+                    );
+                    // Blobs are healed compressed the same way as they're written by servers
+                    let blobstore: Arc<Blobstore> = match args.compression_level {
+                        Some(level) => Arc::new(blobstore.with_compressed_chunks(level)),
+                        None => Arc::new(blobstore),
+                    };
                     blobstores.insert(id, ok(blobstore).boxify());
                 }
                 RemoteBlobstoreArgs::Multiplexed { .. } => {
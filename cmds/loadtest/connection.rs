@@ -0,0 +1,323 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Connections to a server, over TLS like hgcli's, that send requests one at a time and read
+//! their responses to the end without keeping them.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Bytes, BytesMut};
+use failure_ext::{bail_msg, format_err, Error, Result};
+use futures::{try_ready, Async, Future, Poll, Sink, Stream};
+use openssl::ssl::{SslConnector, SslMethod};
+use tokio::net::TcpStream;
+use tokio_io::codec::{FramedRead, FramedWrite};
+use tokio_io::io::{ReadHalf, WriteHalf};
+use tokio_io::AsyncRead;
+use tokio_openssl::{SslConnectorExt, SslStream};
+use users::get_current_username;
+use uuid::Uuid;
+
+use secure_utils::{build_identity, read_x509};
+use sshrelay::{Preamble, SshDecoder, SshEncoder, SshEnvVars, SshMsg, SshStream};
+
+use crate::workload::{Request, ResponseKind};
+
+const BUNDLE2_MAGIC: &[u8] = b"HG20";
+
+/// What connections are opened with
+pub struct ConnectionParams {
+    pub addr: SocketAddr,
+    pub connector: SslConnector,
+    pub common_name: String,
+    pub repo: String,
+}
+
+impl ConnectionParams {
+    pub fn new(
+        addr: SocketAddr,
+        cert: &str,
+        private_key: &str,
+        ca_pem: &str,
+        common_name: &str,
+        repo: &str,
+    ) -> Result<Self> {
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        let pkcs12 = build_identity(cert.to_owned(), private_key.to_owned())?;
+        connector.set_certificate(&pkcs12.cert)?;
+        connector.set_private_key(&pkcs12.pkey)?;
+        connector.cert_store_mut().add_cert(read_x509(ca_pem)?)?;
+
+        Ok(Self {
+            addr,
+            connector: connector.build(),
+            common_name: common_name.to_owned(),
+            repo: repo.to_owned(),
+        })
+    }
+}
+
+pub struct Connection {
+    sink: FramedWrite<WriteHalf<SslStream<TcpStream>>, SshEncoder>,
+    reader: ResponseReader,
+}
+
+impl Connection {
+    /// Connect to the server and send the preamble
+    pub fn open(params: Arc<ConnectionParams>) -> impl Future<Item = Self, Error = Error> {
+        let addr = params.addr;
+        TcpStream::connect(&addr)
+            .map_err(move |err| format_err!("connecting to {} failed: {}", addr, err))
+            .and_then({
+                let params = params.clone();
+                move |socket| {
+                    params
+                        .connector
+                        .connect_async(&params.common_name, socket)
+                        .map_err(|err| format_err!("TLS handshake failed: {}", err))
+                }
+            })
+            .and_then(move |socket| {
+                let (socket_read, socket_write) = socket.split();
+                let sink = FramedWrite::new(socket_write, SshEncoder::new());
+                let reader = ResponseReader::new(FramedRead::new(socket_read, SshDecoder::new()));
+
+                let username = get_current_username().and_then(|os_str| os_str.into_string().ok());
+                let preamble = Preamble::new(
+                    params.repo.clone(),
+                    Uuid::new_v4(),
+                    username,
+                    None,
+                    SshEnvVars::new_from_env(),
+                );
+                sink.send(SshMsg::new(SshStream::Preamble(preamble), Bytes::new()))
+                    .from_err()
+                    .map(move |sink| Connection { sink, reader })
+            })
+    }
+
+    /// Send `request` and read its response. Returns the connection to send the next request on
+    /// and the number of bytes of the response.
+    pub fn send(self, request: &Request) -> impl Future<Item = (Self, u64), Error = Error> {
+        let Connection { sink, reader } = self;
+        let parser = Parser::new(request.response);
+        sink.send(SshMsg::new(SshStream::Stdin, request.payload.clone()))
+            .from_err()
+            .and_then(move |sink| {
+                ReadResponse {
+                    start: reader.received,
+                    reader: Some(reader),
+                    parser,
+                }
+                .map(move |(reader, bytes)| (Connection { sink, reader }, bytes))
+            })
+    }
+}
+
+/// The stdout of the server, as it's received
+struct ResponseReader {
+    stream: FramedRead<ReadHalf<SslStream<TcpStream>>, SshDecoder>,
+    buf: BytesMut,
+    /// Bytes of stdout received on the connection
+    received: u64,
+    /// What the server wrote to stderr, to explain why it closed the connection
+    stderr: Vec<u8>,
+}
+
+impl ResponseReader {
+    fn new(stream: FramedRead<ReadHalf<SslStream<TcpStream>>, SshDecoder>) -> Self {
+        Self {
+            stream,
+            buf: BytesMut::new(),
+            received: 0,
+            stderr: vec![],
+        }
+    }
+
+    /// Add more of stdout to the buffer
+    fn poll_fill(&mut self) -> Poll<(), Error> {
+        loop {
+            let msg = match try_ready!(self.stream.poll()) {
+                Some(msg) => msg,
+                None => bail_msg!(
+                    "the server closed the connection: {}",
+                    String::from_utf8_lossy(&self.stderr).trim()
+                ),
+            };
+            match msg.stream() {
+                SshStream::Stdout => {
+                    let data = msg.data();
+                    self.received += data.len() as u64;
+                    self.buf.extend_from_slice(&data);
+                    return Ok(Async::Ready(()));
+                }
+                SshStream::Stderr => self.stderr.extend_from_slice(&msg.data()),
+                _ => {}
+            }
+        }
+    }
+}
+
+struct ReadResponse {
+    reader: Option<ResponseReader>,
+    parser: Parser,
+    start: u64,
+}
+
+impl Future for ReadResponse {
+    type Item = (ResponseReader, u64);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            {
+                let reader = self.reader.as_mut().expect("polled after completion");
+                if !self.parser.parse(&mut reader.buf)? {
+                    try_ready!(reader.poll_fill());
+                    continue;
+                }
+                if !reader.buf.is_empty() {
+                    bail_msg!("{} unexpected bytes after the response", reader.buf.len());
+                }
+            }
+            let reader = self.reader.take().expect("polled after completion");
+            let bytes = reader.received - self.start;
+            return Ok(Async::Ready((reader, bytes)));
+        }
+    }
+}
+
+/// Where a bundle2 stream is at. Everything but the sizes is skipped.
+#[derive(Clone, Copy, Debug)]
+enum Bundle2State {
+    Magic,
+    /// Reading the size of this
+    Size(Bundle2Size),
+    /// Skipping this many bytes, then reading the size of this
+    Skip(usize, Bundle2Size),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Bundle2Size {
+    Params,
+    PartHeader,
+    Chunk,
+}
+
+/// Finds the end of a response, discarding what it consumes
+enum Parser {
+    Framed {
+        /// Responses left to read
+        remaining: usize,
+        /// Bytes left of the current response, once its length was read
+        left: Option<usize>,
+    },
+    Bundle2(Bundle2State),
+}
+
+impl Parser {
+    fn new(kind: ResponseKind) -> Self {
+        match kind {
+            ResponseKind::Framed(count) => Parser::Framed {
+                remaining: count,
+                left: None,
+            },
+            ResponseKind::Bundle2 => Parser::Bundle2(Bundle2State::Magic),
+        }
+    }
+
+    /// Consume what can be consumed of `buf`. Returns true once the response is complete.
+    fn parse(&mut self, buf: &mut BytesMut) -> Result<bool> {
+        loop {
+            match self {
+                Parser::Framed { remaining: 0, .. } => return Ok(true),
+                Parser::Framed { remaining, left } => match *left {
+                    None => {
+                        let newline = match buf.iter().position(|byte| *byte == b'\n') {
+                            Some(newline) => newline,
+                            None => return Ok(false),
+                        };
+                        let line = buf.split_to(newline + 1);
+                        let len = String::from_utf8_lossy(&line[..newline]).parse::<usize>();
+                        match len {
+                            Ok(len) => *left = Some(len),
+                            Err(_) => bail_msg!("bad response length {:?}", &line[..newline]),
+                        }
+                    }
+                    Some(len) => {
+                        let skipped = skip(buf, len);
+                        if skipped < len {
+                            *left = Some(len - skipped);
+                            return Ok(false);
+                        }
+                        *left = None;
+                        *remaining -= 1;
+                    }
+                },
+                Parser::Bundle2(state) => {
+                    *state = match *state {
+                        Bundle2State::Magic => {
+                            if buf.len() < BUNDLE2_MAGIC.len() {
+                                return Ok(false);
+                            }
+                            let magic = buf.split_to(BUNDLE2_MAGIC.len());
+                            if magic.as_ref() != BUNDLE2_MAGIC {
+                                bail_msg!("bad bundle2 magic {:?}", magic);
+                            }
+                            Bundle2State::Size(Bundle2Size::Params)
+                        }
+                        Bundle2State::Size(of) => match (of, read_size(buf)?) {
+                            (_, None) => return Ok(false),
+                            (Bundle2Size::Params, Some(size)) => {
+                                Bundle2State::Skip(size, Bundle2Size::PartHeader)
+                            }
+                            (Bundle2Size::PartHeader, Some(0)) => return Ok(true),
+                            (Bundle2Size::PartHeader, Some(size)) => {
+                                Bundle2State::Skip(size, Bundle2Size::Chunk)
+                            }
+                            (Bundle2Size::Chunk, Some(0)) => {
+                                Bundle2State::Size(Bundle2Size::PartHeader)
+                            }
+                            (Bundle2Size::Chunk, Some(size)) => {
+                                Bundle2State::Skip(size, Bundle2Size::Chunk)
+                            }
+                        },
+                        Bundle2State::Skip(len, next) => {
+                            let skipped = skip(buf, len);
+                            if skipped < len {
+                                *state = Bundle2State::Skip(len - skipped, next);
+                                return Ok(false);
+                            }
+                            Bundle2State::Size(next)
+                        }
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Discard up to `len` bytes of `buf`, returning how many were discarded
+fn skip(buf: &mut BytesMut, len: usize) -> usize {
+    let skipped = ::std::cmp::min(len, buf.len());
+    buf.advance(skipped);
+    skipped
+}
+
+/// Read a bundle2 size, a big-endian i32. Negative sizes mark interrupts, which aren't expected
+/// from the server.
+fn read_size(buf: &mut BytesMut) -> Result<Option<usize>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let size = BigEndian::read_i32(&buf.split_to(4));
+    if size < 0 {
+        bail_msg!("unexpected bundle2 size {}", size);
+    }
+    Ok(Some(size as usize))
+}
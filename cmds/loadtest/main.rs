@@ -0,0 +1,248 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Load test of a running server. Connections are opened one after the other over the ramp-up,
+//! and each sends requests picked from a workload file by the weights of their commands, one at
+//! a time, until the test is over. The latencies, errors and bytes of the responses are reported
+//! per command every interval, so that they can be told apart as the concurrency grows, and for
+//! the whole test at the end.
+
+#![deny(warnings)]
+
+mod connection;
+mod stats;
+mod workload;
+
+use std::fs;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use clap::{App, ArgMatches};
+use cloned::cloned;
+use failure_ext::{format_err, Error, Result, ResultExt};
+use futures::future::{self, join_all, loop_fn, Loop};
+use futures::{Future, Stream};
+use futures_ext::FutureExt;
+use slog::{info, warn, Logger};
+use tokio::timer::{Delay, Interval};
+
+use cmdlib::args;
+
+use crate::connection::{Connection, ConnectionParams};
+use crate::stats::Stats;
+use crate::workload::{parse_workload, Workload};
+
+/// How long a connection waits after a failed request before it reconnects
+const ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    let app = args::MononokeApp {
+        safe_writes: false,
+        hide_advanced_args: true,
+        local_instances: false,
+        default_glog: true,
+    };
+    app.build("load test")
+        .version("0.0.0")
+        .about("Replay a mix of wireproto requests against a running server")
+        .args_from_usage(
+            r#"
+            <SERVER>                        'address of the server, as ip:port'
+            --repo-name <NAME>              'name of the repo to send the requests to'
+            --cert <PATH>                   'certificate to connect with'
+            --private-key <PATH>            'private key of the certificate'
+            --ca-pem <PATH>                 'certificate of the authority that signs the server certificate'
+            --common-name <NAME>            'common name of the server certificate'
+            --workload <PATH>               'file of the requests to replay'
+            --mix [MIX]                     'weights of the commands to send, e.g. getfiles=5,gettreepack=2,known=1; all the commands of the workload weigh the same by default'
+            --concurrency [CONNECTIONS]     'connections sending requests at once, 10 by default'
+            --ramp-up [SECS]                'seconds over which connections are opened, 0 by default'
+            --duration [SECS]               'seconds to send requests for after the ramp-up, 60 by default'
+            --report-interval [SECS]        'seconds between reports, 10 by default'
+        "#,
+        )
+}
+
+#[derive(Default)]
+struct Recorder {
+    /// Requests since the last report
+    interval: Stats,
+    /// Requests before the last report
+    total: Stats,
+    connections: usize,
+}
+
+impl Recorder {
+    /// Move the requests since the last report to the total, and return them
+    fn take_interval(&mut self) -> Stats {
+        let interval = mem::replace(&mut self.interval, Stats::default());
+        self.total.merge(&interval);
+        interval
+    }
+}
+
+fn get_path<'a>(matches: &ArgMatches<'a>, key: &str) -> String {
+    matches
+        .value_of(key)
+        .unwrap_or_else(|| panic!("--{} is required", key))
+        .to_string()
+}
+
+fn main() -> Result<()> {
+    let matches = setup_app().get_matches();
+    let logger = args::get_logger(&matches);
+
+    let server = matches.value_of("SERVER").expect("no server");
+    let addr: SocketAddr = server
+        .parse()
+        .with_context(|_| format!("bad server address {}", server))?;
+    let params = Arc::new(ConnectionParams::new(
+        addr,
+        &get_path(&matches, "cert"),
+        &get_path(&matches, "private-key"),
+        &get_path(&matches, "ca-pem"),
+        &get_path(&matches, "common-name"),
+        &get_path(&matches, "repo-name"),
+    )?);
+
+    let workload_path = get_path(&matches, "workload");
+    let workload = fs::read_to_string(&workload_path)
+        .with_context(|_| format!("cannot read {}", workload_path))?;
+    let workload = Arc::new(Workload::new(
+        parse_workload(&workload)?,
+        matches.value_of("mix"),
+    )?);
+
+    let concurrency = args::get_usize(&matches, "concurrency", 10);
+    if concurrency == 0 {
+        return Err(format_err!("--concurrency must be at least 1"));
+    }
+    let ramp_up = Duration::from_secs(args::get_u64(&matches, "ramp-up", 0));
+    let duration = Duration::from_secs(args::get_u64(&matches, "duration", 60));
+    let report_interval = Duration::from_secs(args::get_u64(&matches, "report-interval", 10));
+    if report_interval == Duration::from_secs(0) {
+        return Err(format_err!("--report-interval must be at least 1"));
+    }
+
+    let recorder = Arc::new(Mutex::new(Recorder::default()));
+    let start = Instant::now();
+    let end = start + ramp_up + duration;
+    info!(
+        logger,
+        "sending requests to {} over {} connections for {}s",
+        addr,
+        concurrency,
+        (ramp_up + duration).as_secs()
+    );
+
+    let connections = (0..concurrency).map(|index| {
+        let opened_at = start + ramp_up * index as u32 / concurrency as u32;
+        cloned!(logger, params, recorder, workload);
+        Delay::new(opened_at).from_err().and_then(move |()| {
+            recorder.lock().expect("lock poisoned").connections += 1;
+            run_connection(logger, params, workload, recorder, end)
+        })
+    });
+
+    let reports = Interval::new(start + report_interval, report_interval)
+        .take_while(move |_| Ok(Instant::now() < end))
+        .from_err()
+        .for_each({
+            cloned!(logger, recorder);
+            move |_| {
+                let (interval, connections) = {
+                    let mut recorder = recorder.lock().expect("lock poisoned");
+                    (recorder.take_interval(), recorder.connections)
+                };
+                info!(
+                    logger,
+                    "{} connections: {}",
+                    connections,
+                    interval.total().summary(report_interval)
+                );
+                for (command, stats) in interval.commands() {
+                    info!(logger, "  {}: {}", command, stats.summary(report_interval));
+                }
+                Ok(())
+            }
+        });
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(join_all(connections).join(reports))?;
+    let elapsed = start.elapsed();
+
+    let total = {
+        let mut recorder = recorder.lock().expect("lock poisoned");
+        recorder.take_interval();
+        mem::replace(&mut recorder.total, Stats::default())
+    };
+    info!(
+        logger,
+        "all requests in {}s: {}",
+        elapsed.as_secs(),
+        total.total().summary(elapsed)
+    );
+    for (command, stats) in total.commands() {
+        info!(logger, "{}: {}", command, stats.summary(elapsed));
+        for line in stats.histogram() {
+            info!(logger, "  {}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Send requests over a connection until `end`. Failed requests are counted as errors and the
+/// connection is opened again.
+fn run_connection(
+    logger: Logger,
+    params: Arc<ConnectionParams>,
+    workload: Arc<Workload>,
+    recorder: Arc<Mutex<Recorder>>,
+    end: Instant,
+) -> impl Future<Item = (), Error = Error> {
+    loop_fn(None, move |connection: Option<Connection>| {
+        if Instant::now() >= end {
+            return future::ok(Loop::Break(())).left_future();
+        }
+        let request = workload.pick(&mut rand::thread_rng()).clone();
+        let command = request.command;
+
+        let connection = match connection {
+            Some(connection) => future::ok(connection).left_future(),
+            None => Connection::open(params.clone()).right_future(),
+        };
+        cloned!(logger, recorder);
+        connection
+            .and_then(move |connection| {
+                let sent = Instant::now();
+                connection
+                    .send(&request)
+                    .map(move |(connection, bytes)| (connection, bytes, sent.elapsed()))
+            })
+            .then(move |res| match res {
+                Ok((connection, bytes, latency)) => {
+                    let mut recorder = recorder.lock().expect("lock poisoned");
+                    recorder.interval.record(command, latency, bytes);
+                    future::ok(Loop::Continue(Some(connection))).left_future()
+                }
+                Err(err) => {
+                    recorder
+                        .lock()
+                        .expect("lock poisoned")
+                        .interval
+                        .record_error(command);
+                    warn!(logger, "{} failed: {}", command, err);
+                    Delay::new(Instant::now() + ERROR_BACKOFF)
+                        .from_err()
+                        .map(|()| Loop::Continue(None))
+                        .right_future()
+                }
+            })
+            .right_future()
+    })
+}
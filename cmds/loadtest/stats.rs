@@ -0,0 +1,154 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Latencies, errors and bytes of the requests of a load test, per command.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::workload::Command;
+
+/// Upper bounds of the buckets of latency histograms, in milliseconds. Slower requests go to a
+/// last bucket.
+const BUCKETS_MS: &[u64] = &[
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000, 20000, 50000,
+];
+
+#[derive(Clone, Default)]
+pub struct CommandStats {
+    /// Latencies of the successful requests, in microseconds
+    latencies: Vec<u64>,
+    errors: u64,
+    bytes: u64,
+}
+
+impl CommandStats {
+    fn merge(&mut self, other: &CommandStats) {
+        self.latencies.extend_from_slice(&other.latencies);
+        self.errors += other.errors;
+        self.bytes += other.bytes;
+    }
+
+    fn sorted_latencies(&self) -> Vec<u64> {
+        let mut latencies = self.latencies.clone();
+        latencies.sort_unstable();
+        latencies
+    }
+
+    /// A line of counts, throughput over `elapsed` and latency percentiles
+    pub fn summary(&self, elapsed: Duration) -> String {
+        let secs = duration_secs(elapsed).max(0.001);
+        let latencies = self.sorted_latencies();
+        format!(
+            "{} ok, {} errors, {:.1} req/s, {:.2} MiB/s, p50 {} p90 {} p99 {} max {}",
+            latencies.len(),
+            self.errors,
+            latencies.len() as f64 / secs,
+            self.bytes as f64 / secs / (1024.0 * 1024.0),
+            format_latency(percentile(&latencies, 50.0)),
+            format_latency(percentile(&latencies, 90.0)),
+            format_latency(percentile(&latencies, 99.0)),
+            format_latency(latencies.last().cloned()),
+        )
+    }
+
+    /// Lines of a histogram of the latencies, of the buckets up to the slowest request
+    pub fn histogram(&self) -> Vec<String> {
+        let mut counts = vec![0u64; BUCKETS_MS.len() + 1];
+        for latency in &self.latencies {
+            let bucket = BUCKETS_MS
+                .iter()
+                .position(|bound| *latency <= bound * 1000)
+                .unwrap_or(BUCKETS_MS.len());
+            counts[bucket] += 1;
+        }
+        let last = match counts.iter().rposition(|count| *count > 0) {
+            Some(last) => last,
+            None => return vec![],
+        };
+        let max = counts.iter().cloned().max().unwrap_or(0);
+        let total = self.latencies.len() as f64;
+
+        counts[..=last]
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| {
+                let bound = match BUCKETS_MS.get(bucket) {
+                    Some(bound) => format!("<= {}ms", bound),
+                    None => format!("> {}ms", BUCKETS_MS[BUCKETS_MS.len() - 1]),
+                };
+                format!(
+                    "{:>10} {:>8} {:>5.1}% {}",
+                    bound,
+                    count,
+                    *count as f64 * 100.0 / total,
+                    "#".repeat((count * 40 / max) as usize)
+                )
+            })
+            .collect()
+    }
+}
+
+/// The stats of the requests of each command
+#[derive(Clone, Default)]
+pub struct Stats {
+    commands: BTreeMap<Command, CommandStats>,
+}
+
+impl Stats {
+    pub fn record(&mut self, command: Command, latency: Duration, bytes: u64) {
+        let stats = self.commands.entry(command).or_default();
+        stats.latencies.push(duration_micros(latency));
+        stats.bytes += bytes;
+    }
+
+    pub fn record_error(&mut self, command: Command) {
+        self.commands.entry(command).or_default().errors += 1;
+    }
+
+    pub fn merge(&mut self, other: &Stats) {
+        for (command, stats) in &other.commands {
+            self.commands.entry(*command).or_default().merge(stats);
+        }
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item = (&Command, &CommandStats)> {
+        self.commands.iter()
+    }
+
+    /// The stats of all commands together
+    pub fn total(&self) -> CommandStats {
+        let mut total = CommandStats::default();
+        for stats in self.commands.values() {
+            total.merge(stats);
+        }
+        total
+    }
+}
+
+/// The latency that `percent` percent of `sorted` are at most
+fn percentile(sorted: &[u64], percent: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.max(1) - 1])
+}
+
+fn format_latency(micros: Option<u64>) -> String {
+    match micros {
+        Some(micros) => format!("{:.1}ms", micros as f64 / 1000.0),
+        None => "-".to_string(),
+    }
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn duration_micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
@@ -0,0 +1,289 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The requests that a load test replays, and the mix of commands it picks them by.
+//!
+//! A workload file has a request per line, with nodes in hex:
+//!
+//! ```text
+//! known NODE...
+//! getbundle HEAD[,HEAD...] [COMMON[,COMMON...]]
+//! gettreepack MFNODE[,MFNODE...] [BASEMFNODE[,BASEMFNODE...]]
+//! getfiles FILENODE PATH [FILENODE PATH...]
+//! ```
+//!
+//! Empty lines and lines starting with `#` are ignored. Paths can't contain whitespace.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use failure_ext::{bail_msg, format_err, Error, Result, ResultExt};
+use rand::Rng;
+
+use mercurial_types::HgNodeHash;
+
+/// The commands that a load test sends
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Command {
+    Getbundle,
+    Gettreepack,
+    Getfiles,
+    Known,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Getbundle => "getbundle",
+            Command::Gettreepack => "gettreepack",
+            Command::Getfiles => "getfiles",
+            Command::Known => "known",
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "getbundle" => Ok(Command::Getbundle),
+            "gettreepack" => Ok(Command::Gettreepack),
+            "getfiles" => Ok(Command::Getfiles),
+            "known" => Ok(Command::Known),
+            _ => bail_msg!("unknown command {}", name),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// How the server responds to a request
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResponseKind {
+    /// This many responses, each prefixed by its length
+    Framed(usize),
+    /// A bundle2 stream
+    Bundle2,
+}
+
+/// A request of the workload, encoded as it's sent to the server
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub command: Command,
+    pub payload: Bytes,
+    pub response: ResponseKind,
+}
+
+/// The requests of a workload, picked by the weights of their commands
+pub struct Workload {
+    requests: BTreeMap<Command, Vec<Request>>,
+    mix: Vec<(Command, u32)>,
+    total_weight: u32,
+}
+
+impl Workload {
+    /// `mix` is a comma-separated list of weights, e.g. `getfiles=5,gettreepack=2,known=1`.
+    /// Commands that aren't in it aren't sent. Without it, all the commands of the workload weigh
+    /// the same.
+    pub fn new(requests: Vec<Request>, mix: Option<&str>) -> Result<Self> {
+        let mut by_command: BTreeMap<Command, Vec<Request>> = BTreeMap::new();
+        for request in requests {
+            by_command
+                .entry(request.command)
+                .or_insert_with(Vec::new)
+                .push(request);
+        }
+
+        let mix = match mix {
+            Some(mix) => parse_mix(mix)?,
+            None => by_command.keys().map(|command| (*command, 1)).collect(),
+        };
+        for (command, _) in &mix {
+            if !by_command.contains_key(command) {
+                bail_msg!(
+                    "the mix has {} but the workload has no {} requests",
+                    command,
+                    command
+                );
+            }
+        }
+        let total_weight = mix.iter().map(|(_, weight)| weight).sum();
+        if total_weight == 0 {
+            bail_msg!("the mix has no command with a weight");
+        }
+
+        Ok(Self {
+            requests: by_command,
+            mix,
+            total_weight,
+        })
+    }
+
+    /// A request picked at random, of a command picked by its weight
+    pub fn pick<R: Rng>(&self, rng: &mut R) -> &Request {
+        let mut point = rng.gen_range(0, self.total_weight);
+        let command = self
+            .mix
+            .iter()
+            .find(|(_, weight)| {
+                if point < *weight {
+                    true
+                } else {
+                    point -= weight;
+                    false
+                }
+            })
+            .map(|(command, _)| *command)
+            .expect("point is less than the total weight");
+        let requests = &self.requests[&command];
+        &requests[rng.gen_range(0, requests.len())]
+    }
+}
+
+fn parse_mix(mix: &str) -> Result<Vec<(Command, u32)>> {
+    mix.split(',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let command = parts.next().unwrap_or("").parse()?;
+            let weight = parts
+                .next()
+                .ok_or_else(|| format_err!("mix entry {} has no weight", entry))?
+                .parse::<u32>()
+                .with_context(|_| format!("bad weight in mix entry {}", entry))?;
+            Ok((command, weight))
+        })
+        .collect()
+}
+
+/// Parse the requests of a workload file
+pub fn parse_workload(text: &str) -> Result<Vec<Request>> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            let request = parse_request(line)
+                .with_context(|_| format!("bad request on line {}", index + 1))?;
+            Ok(request)
+        })
+        .collect()
+}
+
+fn parse_request(line: &str) -> Result<Request> {
+    let mut words = line.split_whitespace();
+    let command: Command = words.next().unwrap_or("").parse()?;
+    let args: Vec<_> = words.collect();
+
+    let request = match command {
+        Command::Known => {
+            let nodes = parse_nodes(&args)?;
+            Request {
+                command,
+                payload: encode_command("known", &[], &[("nodes", nodes.join(" ").into_bytes())]),
+                response: ResponseKind::Framed(1),
+            }
+        }
+        Command::Getbundle => {
+            if args.is_empty() || args.len() > 2 {
+                bail_msg!("getbundle takes heads and optionally common nodes");
+            }
+            let heads = parse_nodes(&args[0].split(',').collect::<Vec<_>>())?;
+            let common = match args.get(1) {
+                Some(common) => parse_nodes(&common.split(',').collect::<Vec<_>>())?,
+                None => vec![],
+            };
+            Request {
+                command,
+                payload: encode_command(
+                    "getbundle",
+                    &[
+                        ("heads", heads.join(" ").into_bytes()),
+                        ("common", common.join(" ").into_bytes()),
+                        ("bundlecaps", b"HG20".to_vec()),
+                    ],
+                    &[],
+                ),
+                response: ResponseKind::Bundle2,
+            }
+        }
+        Command::Gettreepack => {
+            if args.is_empty() || args.len() > 2 {
+                bail_msg!("gettreepack takes manifest nodes and optionally base manifest nodes");
+            }
+            let mfnodes = parse_nodes(&args[0].split(',').collect::<Vec<_>>())?;
+            let basemfnodes = match args.get(1) {
+                Some(base) => parse_nodes(&base.split(',').collect::<Vec<_>>())?,
+                None => vec![],
+            };
+            Request {
+                command,
+                payload: encode_command(
+                    "gettreepack",
+                    &[
+                        ("rootdir", vec![]),
+                        ("mfnodes", mfnodes.join(" ").into_bytes()),
+                        ("basemfnodes", basemfnodes.join(" ").into_bytes()),
+                        ("directories", vec![]),
+                    ],
+                    &[],
+                ),
+                response: ResponseKind::Bundle2,
+            }
+        }
+        Command::Getfiles => {
+            if args.is_empty() || args.len() % 2 != 0 {
+                bail_msg!("getfiles takes pairs of file nodes and paths");
+            }
+            let mut payload = b"getfiles\n".to_vec();
+            for pair in args.chunks(2) {
+                let node = parse_node(pair[0])?;
+                payload.extend_from_slice(node.as_bytes());
+                payload.extend_from_slice(pair[1].as_bytes());
+                payload.push(b'\n');
+            }
+            payload.push(b'\n');
+            Request {
+                command,
+                payload: Bytes::from(payload),
+                response: ResponseKind::Framed(args.len() / 2),
+            }
+        }
+    };
+    Ok(request)
+}
+
+fn parse_node(node: &str) -> Result<String> {
+    let hash = HgNodeHash::from_str(node).with_context(|_| format!("bad node {}", node))?;
+    Ok(hash.to_hex().to_string())
+}
+
+fn parse_nodes(nodes: &[&str]) -> Result<Vec<String>> {
+    if nodes.is_empty() {
+        bail_msg!("no nodes");
+    }
+    nodes.iter().map(|node| parse_node(node)).collect()
+}
+
+/// Encode a command of the ssh protocol: its name, the number of arguments of `*`, those
+/// arguments and then the named arguments. Each argument is `<name> <length>\n<value>`.
+fn encode_command(name: &str, star: &[(&str, Vec<u8>)], named: &[(&str, Vec<u8>)]) -> Bytes {
+    let mut out = vec![];
+    write!(out, "{}\n* {}\n", name, star.len()).expect("write to vec failed");
+    for (key, value) in star.iter().chain(named) {
+        write!(out, "{} {}\n", key, value.len()).expect("write to vec failed");
+        out.extend_from_slice(value);
+    }
+    Bytes::from(out)
+}
@@ -0,0 +1,136 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bookmarks;
+extern crate clap;
+extern crate cmdlib;
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate repo_gen;
+#[macro_use]
+extern crate slog;
+extern crate tokio;
+
+use clap::{App, ArgMatches};
+use failure::{err_msg, Result};
+use futures::{Future, IntoFuture};
+use futures_ext::FutureExt;
+
+use bookmarks::{Bookmark, BookmarkUpdateReason};
+use cmdlib::args;
+use repo_gen::{generate_repo, RepoShape};
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    let app = args::MononokeApp {
+        safe_writes: false,
+        hide_advanced_args: false,
+        local_instances: true,
+        default_glog: true,
+    };
+    app.build("synthetic repo generator")
+        .version("0.0.0")
+        .about(
+            "Generate commits of a configurable shape into a repo, the same ones for the same seed",
+        )
+        .args_from_usage(
+            r#"
+            --seed [SEED]                       'seed of the generated commits, 0 by default'
+            --commits [COMMITS]                 'number of commits to generate'
+            --files-per-commit [FILES]          'files that each commit adds or modifies'
+            --modify-probability [PROBABILITY]  'probability that a changed file already exists'
+            --files-per-dir [FILES]             'files in a directory before the next one is used'
+            --dirs-per-dir [DIRS]               'subdirectories of a directory'
+            --median-file-size [BYTES]          'median size of files'
+            --file-size-sigma [SIGMA]           'spread of file sizes, the stddev of their log'
+            --max-file-size [BYTES]             'largest size of files'
+            --branch-probability [PROBABILITY]  'probability that a commit starts a branch'
+            --merge-probability [PROBABILITY]   'probability that a commit merges two branches'
+            --bookmark [BOOKMARK]               'bookmark to set to the last generated commit'
+        "#,
+        )
+}
+
+fn get_f64<'a>(matches: &ArgMatches<'a>, key: &str, default: f64) -> f64 {
+    matches
+        .value_of(key)
+        .map(|val| {
+            val.parse::<f64>()
+                .unwrap_or_else(|_| panic!("{} must be a number", key))
+        })
+        .unwrap_or(default)
+}
+
+fn get_shape<'a>(matches: &ArgMatches<'a>) -> RepoShape {
+    let default = RepoShape::default();
+    RepoShape {
+        commits: args::get_usize(matches, "commits", default.commits),
+        files_per_commit: args::get_usize(matches, "files-per-commit", default.files_per_commit),
+        modify_probability: get_f64(matches, "modify-probability", default.modify_probability),
+        files_per_dir: args::get_usize(matches, "files-per-dir", default.files_per_dir),
+        dirs_per_dir: args::get_usize(matches, "dirs-per-dir", default.dirs_per_dir),
+        median_file_size: args::get_u64(matches, "median-file-size", default.median_file_size),
+        file_size_sigma: get_f64(matches, "file-size-sigma", default.file_size_sigma),
+        max_file_size: args::get_u64(matches, "max-file-size", default.max_file_size),
+        branch_probability: get_f64(matches, "branch-probability", default.branch_probability),
+        merge_probability: get_f64(matches, "merge-probability", default.merge_probability),
+    }
+}
+
+fn main() -> Result<()> {
+    let matches = setup_app().get_matches();
+
+    let ctx = args::get_core_context(&matches);
+    let logger = ctx.logger().clone();
+
+    args::init_cachelib(&matches);
+
+    let shape = get_shape(&matches);
+    shape.verify()?;
+    let seed = args::get_u64(&matches, "seed", 0);
+    let bookmark = match matches.value_of("bookmark") {
+        Some(bookmark) => Some(Bookmark::new(bookmark)?),
+        None => None,
+    };
+
+    let generate = args::create_repo(&logger, &matches).and_then(move |repo| {
+        generate_repo(ctx.clone(), repo.clone(), shape, seed).and_then(move |generated| {
+            info!(
+                logger,
+                "generated {} commits with {} heads",
+                generated.changesets.len(),
+                generated.heads.len()
+            );
+            match (bookmark, generated.changesets.last().cloned()) {
+                (Some(bookmark), Some(last)) => {
+                    let mut transaction = repo.update_bookmark_transaction(ctx);
+                    transaction
+                        .force_set(&bookmark, last, BookmarkUpdateReason::ManualMove)
+                        .into_future()
+                        .and_then(move |()| transaction.commit())
+                        .and_then(move |committed| {
+                            if committed {
+                                info!(logger, "{} set to {}", bookmark, last);
+                                Ok(())
+                            } else {
+                                Err(err_msg("setting the bookmark failed"))
+                            }
+                        })
+                        .left_future()
+                }
+                _ => Ok(()).into_future().right_future(),
+            }
+        })
+    });
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(generate);
+    // Let the runtime finish remaining work - uploading logs etc
+    runtime.shutdown_on_idle();
+    result
+}
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS schema_version (
+  store VARCHAR(255) NOT NULL,
+  version INTEGER NOT NULL,
+  description VARCHAR(255) NOT NULL,
+  applied_at BIGINT NOT NULL,
+  PRIMARY KEY (store, version)
+);
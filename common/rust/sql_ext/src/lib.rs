@@ -4,14 +4,22 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+#[macro_use]
 extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate metaconfig_types;
+#[macro_use]
 extern crate sql;
 
 use std::path::Path;
 
 use failure::prelude::*;
+use metaconfig_types::RepoType;
 use sql::{myrouter, Connection, rusqlite::Connection as SqliteConnection};
 
+pub mod migrations;
+
 pub struct SqlConnections {
     pub write_connection: Connection,
     pub read_connection: Connection,
@@ -108,6 +116,43 @@ pub trait SqlConstructors: Sized {
         let _ = con.execute_batch(Self::get_up_query());
         with_sqlite(con)
     }
+
+    /// Open the store of a repo: the sqlite database `name` in the data directory of a local
+    /// repo, or the database of a remote one
+    fn with_repo_type(repotype: &RepoType, myrouter_port: Option<u16>, name: &str) -> Result<Self> {
+        match repotype {
+            RepoType::BlobFiles(data_dir)
+            | RepoType::BlobRocks(data_dir)
+            | RepoType::BlobSqlite(data_dir) => Self::with_sqlite_path(data_dir.join(name)),
+            RepoType::BlobRemote { db_address, .. } => Ok(Self::with_myrouter(
+                db_address,
+                remote_myrouter_port(myrouter_port)?,
+            )),
+        }
+    }
+
+    /// Like `with_repo_type`, for stores that remote repos keep next to their write lock
+    fn with_repo_type_write_lock_db(
+        repotype: &RepoType,
+        myrouter_port: Option<u16>,
+        name: &str,
+    ) -> Result<Self> {
+        match repotype {
+            RepoType::BlobRemote {
+                write_lock_db_address,
+                ..
+            } => Ok(Self::with_myrouter(
+                write_lock_db_address,
+                remote_myrouter_port(myrouter_port)?,
+            )),
+            _ => Self::with_repo_type(repotype, myrouter_port, name),
+        }
+    }
+}
+
+/// The port of MyRouter, which remote repos need to connect to their databases
+pub fn remote_myrouter_port(myrouter_port: Option<u16>) -> Result<u16> {
+    myrouter_port.ok_or_else(|| err_msg("myrouter_port not provided for BlobRemote repo"))
 }
 
 fn with_sqlite<T: SqlConstructors>(con: SqliteConnection) -> Result<T> {
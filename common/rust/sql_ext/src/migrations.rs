@@ -0,0 +1,342 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Versioned schema migrations of the sql stores.
+//!
+//! Every store lists the migrations of its schema, embedded in its crate, and the
+//! `schema_version` table of a database records which of them were applied to it. A migration is
+//! applied together with its `schema_version` row, so that both are committed or neither is.
+//!
+//! Only the queries built into the binary can run through MyRouter, so migrations are applied
+//! directly to SQLite databases, while for MySQL they are turned into a script to run with the
+//! mysql client.
+
+use std::fmt;
+use std::path::Path;
+
+use failure::prelude::*;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use sql::{rusqlite::Connection as SqliteConnection, Connection};
+
+use SqlConstructors;
+
+/// SQL dialect a migration is written in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Dialect {
+    Sqlite,
+    Mysql,
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dialect::Sqlite => write!(f, "sqlite"),
+            Dialect::Mysql => write!(f, "mysql"),
+        }
+    }
+}
+
+/// A change to the schema of a store, in both dialects
+#[derive(Clone, Copy, Debug)]
+pub struct Migration {
+    pub version: u64,
+    pub description: &'static str,
+    pub sqlite: &'static str,
+    pub mysql: &'static str,
+}
+
+impl Migration {
+    pub fn sql(&self, dialect: Dialect) -> &'static str {
+        match dialect {
+            Dialect::Sqlite => self.sqlite,
+            Dialect::Mysql => self.mysql,
+        }
+    }
+}
+
+/// Sql stores whose schema is managed by migrations
+pub trait SqlMigrations {
+    /// Name of the store in `schema_version`
+    const STORE: &'static str;
+
+    /// Migrations of the schema of the store, by increasing version starting from 1. Applied
+    /// migrations must never change, so their SQL is written out here rather than included from
+    /// the schema files, which follow the latest version.
+    fn migrations() -> &'static [Migration];
+}
+
+/// A migration recorded in `schema_version`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AppliedMigration {
+    pub version: u64,
+    pub description: String,
+}
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(
+        display = "migrations of {} are not numbered 1, 2, 3...: found {} at {}",
+        _0, _1, _2
+    )]
+    BadVersion(&'static str, u64, usize),
+    #[fail(
+        display = "{} has migration {} applied, which this binary doesn't know about: the \
+                   database is newer than the code",
+        _0, _1
+    )]
+    UnknownVersion(&'static str, u64),
+    #[fail(display = "{} has no migration {} to baseline at", _0, _1)]
+    BadBaseline(&'static str, u64),
+}
+
+queries! {
+    read SelectAppliedMigrations(store: str) -> (u64, String) {
+        "SELECT version, description
+         FROM schema_version
+         WHERE store = {store}
+         ORDER BY version"
+    }
+}
+
+/// The `schema_version` table of a database
+#[derive(Clone)]
+pub struct SqlSchemaVersions {
+    read_master_connection: Connection,
+}
+
+impl SqlConstructors for SqlSchemaVersions {
+    fn from_connections(
+        _write_connection: Connection,
+        _read_connection: Connection,
+        read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            read_master_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-schema-version.sql")
+    }
+}
+
+impl SqlSchemaVersions {
+    /// Schema of the `schema_version` table, which has to be created before any migration runs
+    pub fn schema(dialect: Dialect) -> &'static str {
+        match dialect {
+            Dialect::Sqlite => include_str!("../schemas/sqlite-schema-version.sql"),
+            Dialect::Mysql => include_str!("../schemas/mysql-schema-version.sql"),
+        }
+    }
+
+    pub fn get_applied(&self, store: &str) -> BoxFuture<Vec<AppliedMigration>, Error> {
+        SelectAppliedMigrations::query(&self.read_master_connection, store)
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(version, description)| AppliedMigration {
+                        version,
+                        description,
+                    })
+                    .collect()
+            })
+            .boxify()
+    }
+}
+
+/// The migrations of `store` that are not in `applied`. Fails if `applied` has migrations that
+/// `migrations` doesn't, as the code would run against a schema it doesn't know.
+pub fn pending_migrations<'a>(
+    store: &'static str,
+    migrations: &'a [Migration],
+    applied: &[AppliedMigration],
+) -> Result<Vec<&'a Migration>> {
+    for (idx, migration) in migrations.iter().enumerate() {
+        if migration.version != idx as u64 + 1 {
+            return Err(ErrorKind::BadVersion(store, migration.version, idx).into());
+        }
+    }
+
+    let latest = migrations.len() as u64;
+    if let Some(unknown) = applied.iter().find(|applied| applied.version > latest) {
+        return Err(ErrorKind::UnknownVersion(store, unknown.version).into());
+    }
+
+    Ok(migrations
+        .iter()
+        .filter(|migration| {
+            !applied
+                .iter()
+                .any(|applied| applied.version == migration.version)
+        })
+        .collect())
+}
+
+/// Check that a store can be baselined at `version`, i.e. that its migrations up to `version`
+/// can be recorded as applied without running them, because the schema was created by hand.
+pub fn check_baseline(store: &'static str, migrations: &[Migration], version: u64) -> Result<()> {
+    if version == 0 || version > migrations.len() as u64 {
+        return Err(ErrorKind::BadBaseline(store, version).into());
+    }
+    Ok(())
+}
+
+/// The SQL that records `migration` of `store` as applied
+pub fn record_script(store: &str, migration: &Migration, dialect: Dialect) -> String {
+    let now = match dialect {
+        Dialect::Sqlite => "CAST(strftime('%s', 'now') AS INTEGER)",
+        Dialect::Mysql => "UNIX_TIMESTAMP()",
+    };
+    format!(
+        "INSERT INTO schema_version (store, version, description, applied_at) \
+         VALUES ({}, {}, {}, {});\n",
+        quote(store),
+        migration.version,
+        quote(migration.description),
+        now
+    )
+}
+
+/// The SQL that applies `migration` of `store` and records it as applied
+pub fn migration_script(store: &str, migration: &Migration, dialect: Dialect) -> String {
+    let mut script = format!(
+        "-- {} migration {}: {}\n",
+        store, migration.version, migration.description
+    );
+    script.push_str("BEGIN;\n");
+    script.push_str(migration.sql(dialect).trim_right());
+    script.push('\n');
+    script.push_str(&record_script(store, migration, dialect));
+    script.push_str("COMMIT;\n");
+    script
+}
+
+/// Run migration scripts against an SQLite database, in order. Every script commits on its own,
+/// so on failure the database has the migrations before the failed one.
+pub fn apply_sqlite<P: AsRef<Path>>(path: P, scripts: &[String]) -> Result<()> {
+    let con = SqliteConnection::open(path)?;
+    con.execute_batch(SqlSchemaVersions::schema(Dialect::Sqlite))?;
+    for script in scripts {
+        if let Err(err) = con.execute_batch(script) {
+            // The failed statement left the transaction open
+            let _ = con.execute_batch("ROLLBACK;");
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "create table",
+            sqlite: "CREATE TABLE test (id INTEGER NOT NULL);",
+            mysql: "CREATE TABLE test (id INT NOT NULL);",
+        },
+        Migration {
+            version: 2,
+            description: "add the owner's name",
+            sqlite: "ALTER TABLE test ADD COLUMN name VARCHAR(255);",
+            mysql: "ALTER TABLE test ADD COLUMN name VARCHAR(255);",
+        },
+    ];
+
+    fn applied(version: u64) -> AppliedMigration {
+        AppliedMigration {
+            version,
+            description: MIGRATIONS[version as usize - 1].description.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_pending_migrations() {
+        let pending = pending_migrations("test", MIGRATIONS, &[]).unwrap();
+        assert_eq!(
+            pending.iter().map(|m| m.version).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        let pending = pending_migrations("test", MIGRATIONS, &[applied(1)]).unwrap();
+        assert_eq!(
+            pending.iter().map(|m| m.version).collect::<Vec<_>>(),
+            vec![2]
+        );
+
+        let pending = pending_migrations("test", MIGRATIONS, &[applied(1), applied(2)]).unwrap();
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_pending_migrations_newer_database() {
+        let newer = AppliedMigration {
+            version: 3,
+            description: "from the future".to_string(),
+        };
+        assert!(pending_migrations("test", MIGRATIONS, &[applied(1), applied(2), newer]).is_err());
+    }
+
+    #[test]
+    fn test_pending_migrations_bad_versions() {
+        let mut migrations = MIGRATIONS.to_vec();
+        migrations[1].version = 3;
+        assert!(pending_migrations("test", &migrations, &[]).is_err());
+    }
+
+    #[test]
+    fn test_check_baseline() {
+        assert!(check_baseline("test", MIGRATIONS, 0).is_err());
+        assert!(check_baseline("test", MIGRATIONS, 1).is_ok());
+        assert!(check_baseline("test", MIGRATIONS, 2).is_ok());
+        assert!(check_baseline("test", MIGRATIONS, 3).is_err());
+    }
+
+    #[test]
+    fn test_scripts_run() {
+        let con = SqliteConnection::open_in_memory().unwrap();
+        con.execute_batch(SqlSchemaVersions::schema(Dialect::Sqlite))
+            .unwrap();
+        for migration in MIGRATIONS {
+            con.execute_batch(&migration_script("test", migration, Dialect::Sqlite))
+                .unwrap();
+        }
+        con.execute_batch("INSERT INTO test (id, name) VALUES (1, 'one');")
+            .unwrap();
+
+        // Versions can only be recorded once
+        assert!(con
+            .execute_batch(&record_script("test", &MIGRATIONS[0], Dialect::Sqlite))
+            .is_err());
+    }
+
+    #[test]
+    fn test_failed_script_rolls_back() {
+        let con = SqliteConnection::open_in_memory().unwrap();
+        con.execute_batch(SqlSchemaVersions::schema(Dialect::Sqlite))
+            .unwrap();
+        let broken = Migration {
+            version: 1,
+            description: "broken",
+            sqlite: "CREATE TABLE test (id INTEGER NOT NULL); CREATE TABLE test (id INTEGER);",
+            mysql: "",
+        };
+        assert!(con
+            .execute_batch(&migration_script("test", &broken, Dialect::Sqlite))
+            .is_err());
+        con.execute_batch("ROLLBACK;").unwrap();
+
+        // Neither the table nor the version were committed
+        con.execute_batch(&migration_script("test", &MIGRATIONS[0], Dialect::Sqlite))
+            .unwrap();
+    }
+}
@@ -0,0 +1,8 @@
+CREATE TABLE `content_refs` (
+  `repo_id` INT UNSIGNED NOT NULL,
+  `content_id` BINARY(32) NOT NULL,
+  `path` VARBINARY(4096) NOT NULL,
+  `filenode` BINARY(20) NOT NULL,
+  `changeset_id` BINARY(32) NOT NULL,
+  UNIQUE KEY `content_refs_ref` (`repo_id`, `content_id`, `filenode`, `changeset_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,97 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use context::CoreContext;
+use failure::Error;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use metaconfig_types::ContentRefsParams;
+use mononoke_types::{ContentId, RepositoryId};
+
+use {ContentRef, ContentRefs};
+
+/// The content references of one repo, recorded within the bounds of its config
+#[derive(Clone)]
+pub struct ContentRefsIndex {
+    repo_id: RepositoryId,
+    params: ContentRefsParams,
+    store: Arc<ContentRefs>,
+}
+
+impl ContentRefsIndex {
+    pub fn new(repo_id: RepositoryId, params: ContentRefsParams, store: Arc<ContentRefs>) -> Self {
+        Self {
+            repo_id,
+            params,
+            store,
+        }
+    }
+
+    /// Record the references of a push. References past the bounds of the config are dropped,
+    /// and the number of references that were kept is returned.
+    pub fn record(&self, ctx: CoreContext, refs: Vec<ContentRef>) -> BoxFuture<u64, Error> {
+        let mut seen = HashSet::new();
+        let mut refs: Vec<_> = refs
+            .into_iter()
+            .filter(|r| seen.insert(r.clone()))
+            .collect();
+        let max_refs_per_push = self.params.max_refs_per_push as usize;
+        if refs.len() > max_refs_per_push {
+            warn!(
+                ctx.logger(),
+                "only recording {} of the {} content references of the push",
+                max_refs_per_push,
+                refs.len()
+            );
+            refs.truncate(max_refs_per_push);
+        }
+        if refs.is_empty() {
+            return future::ok(0).boxify();
+        }
+
+        let content_ids: Vec<_> = refs
+            .iter()
+            .map(|r| r.content_id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let max_refs_per_content = self.params.max_refs_per_content;
+        let repo_id = self.repo_id;
+        let store = self.store.clone();
+        self.store
+            .count(ctx.clone(), repo_id, content_ids)
+            .and_then(move |mut counts| {
+                let refs: Vec<_> = refs
+                    .into_iter()
+                    .filter(|r| {
+                        let count = counts.entry(r.content_id).or_insert(0);
+                        if *count < max_refs_per_content {
+                            *count += 1;
+                            true
+                        } else {
+                            false
+                        }
+                    })
+                    .collect();
+                let recorded = refs.len() as u64;
+                store.add(ctx, repo_id, refs).map(move |()| recorded)
+            })
+            .boxify()
+    }
+
+    /// Up to `limit` files and commits that reference `content_id`
+    pub fn get(
+        &self,
+        ctx: CoreContext,
+        content_id: ContentId,
+        limit: u64,
+    ) -> BoxFuture<Vec<ContentRef>, Error> {
+        self.store.get(ctx, self.repo_id, content_id, limit)
+    }
+}
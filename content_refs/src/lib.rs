@@ -0,0 +1,267 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! What references a content: the files and commits that point at each content of a repo.
+//!
+//! Answering "this blob is bad, what uses it?" otherwise means walking every manifest of the
+//! repo. Pushes record a reference for every file they add, up to the per-repo bounds in
+//! `ContentRefsParams`, as every reference is a row in SQL.
+
+#![deny(warnings)]
+
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+
+extern crate context;
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate metaconfig_types;
+extern crate mononoke_types;
+#[macro_use]
+extern crate sql;
+extern crate sql_ext;
+#[macro_use]
+extern crate stats;
+
+mod index;
+
+use context::CoreContext;
+use failure::Error;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{HgFileNodeId, MPath};
+use mononoke_types::{ChangesetId, ContentId, RepositoryId};
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+pub use sql_ext::SqlConstructors;
+use stats::Timeseries;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub use index::ContentRefsIndex;
+
+define_stats! {
+    prefix = "mononoke.content_refs";
+    adds: timeseries(RATE, SUM),
+    gets: timeseries(RATE, SUM),
+}
+
+/// A file of a commit whose content is `content_id`
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ContentRef {
+    pub content_id: ContentId,
+    pub path: MPath,
+    pub filenode: HgFileNodeId,
+    pub changeset_id: ChangesetId,
+}
+
+pub trait ContentRefs: Send + Sync {
+    /// Record `refs`. References that are already recorded are ignored
+    fn add(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        refs: Vec<ContentRef>,
+    ) -> BoxFuture<(), Error>;
+
+    /// Up to `limit` references to `content_id`
+    fn get(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        content_id: ContentId,
+        limit: u64,
+    ) -> BoxFuture<Vec<ContentRef>, Error>;
+
+    /// How many references to each of `content_ids` are recorded. Contents without any
+    /// reference are left out
+    fn count(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        content_ids: Vec<ContentId>,
+    ) -> BoxFuture<HashMap<ContentId, u64>, Error>;
+}
+
+impl ContentRefs for Arc<ContentRefs> {
+    fn add(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        refs: Vec<ContentRef>,
+    ) -> BoxFuture<(), Error> {
+        (**self).add(ctx, repo_id, refs)
+    }
+
+    fn get(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        content_id: ContentId,
+        limit: u64,
+    ) -> BoxFuture<Vec<ContentRef>, Error> {
+        (**self).get(ctx, repo_id, content_id, limit)
+    }
+
+    fn count(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        content_ids: Vec<ContentId>,
+    ) -> BoxFuture<HashMap<ContentId, u64>, Error> {
+        (**self).count(ctx, repo_id, content_ids)
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlContentRefs {
+    write_connection: Connection,
+    read_connection: Connection,
+}
+
+queries! {
+    write InsertRefs(values: (
+        repo_id: RepositoryId,
+        content_id: ContentId,
+        path: Vec<u8>,
+        filenode: HgFileNodeId,
+        changeset_id: ChangesetId,
+    )) {
+        insert_or_ignore,
+        "{insert_or_ignore}
+         INTO content_refs (repo_id, content_id, path, filenode, changeset_id)
+         VALUES {values}"
+    }
+
+    read SelectRefs(repo_id: RepositoryId, content_id: ContentId, limit: u64) -> (
+        Vec<u8>,
+        HgFileNodeId,
+        ChangesetId,
+    ) {
+        "SELECT path, filenode, changeset_id
+         FROM content_refs
+         WHERE repo_id = {repo_id} AND content_id = {content_id}
+         LIMIT {limit}"
+    }
+
+    read CountRefs(repo_id: RepositoryId, >list content_ids: ContentId) -> (ContentId, u64) {
+        "SELECT content_id, COUNT(*)
+         FROM content_refs
+         WHERE repo_id = {repo_id} AND content_id IN {content_ids}
+         GROUP BY content_id"
+    }
+}
+
+impl SqlConstructors for SqlContentRefs {
+    fn from_connections(
+        write_connection: Connection,
+        read_connection: Connection,
+        _read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-content-refs.sql")
+    }
+}
+
+impl SqlMigrations for SqlContentRefs {
+    const STORE: &'static str = "content_refs";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the content_refs table",
+            sqlite: "CREATE TABLE `content_refs` (
+                       `repo_id` INT UNSIGNED NOT NULL,
+                       `content_id` BINARY(32) NOT NULL,
+                       `path` VARBINARY(4096) NOT NULL,
+                       `filenode` BINARY(20) NOT NULL,
+                       `changeset_id` BINARY(32) NOT NULL,
+                       UNIQUE (`repo_id`, `content_id`, `filenode`, `changeset_id`)
+                     );",
+            mysql: "CREATE TABLE `content_refs` (
+                      `repo_id` INT UNSIGNED NOT NULL,
+                      `content_id` BINARY(32) NOT NULL,
+                      `path` VARBINARY(4096) NOT NULL,
+                      `filenode` BINARY(20) NOT NULL,
+                      `changeset_id` BINARY(32) NOT NULL,
+                      UNIQUE KEY `content_refs_ref` (`repo_id`, `content_id`, `filenode`, `changeset_id`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+impl ContentRefs for SqlContentRefs {
+    fn add(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        refs: Vec<ContentRef>,
+    ) -> BoxFuture<(), Error> {
+        STATS::adds.add_value(1);
+
+        if refs.is_empty() {
+            return future::ok(()).boxify();
+        }
+        let paths: Vec<_> = refs.iter().map(|r| r.path.to_vec()).collect();
+        let values: Vec<_> = refs
+            .iter()
+            .zip(paths.iter())
+            .map(|(r, path)| (&repo_id, &r.content_id, path, &r.filenode, &r.changeset_id))
+            .collect();
+        InsertRefs::query(&self.write_connection, &values[..])
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn get(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        content_id: ContentId,
+        limit: u64,
+    ) -> BoxFuture<Vec<ContentRef>, Error> {
+        STATS::gets.add_value(1);
+
+        SelectRefs::query(&self.read_connection, &repo_id, &content_id, &limit)
+            .and_then(move |rows| {
+                rows.into_iter()
+                    .map(|(path, filenode, changeset_id)| {
+                        Ok(ContentRef {
+                            content_id,
+                            path: MPath::new(path)?,
+                            filenode,
+                            changeset_id,
+                        })
+                    })
+                    .collect()
+            })
+            .boxify()
+    }
+
+    fn count(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        content_ids: Vec<ContentId>,
+    ) -> BoxFuture<HashMap<ContentId, u64>, Error> {
+        STATS::gets.add_value(1);
+
+        if content_ids.is_empty() {
+            return future::ok(HashMap::new()).boxify();
+        }
+        CountRefs::query(&self.read_connection, &repo_id, &content_ids[..])
+            .map(|rows| rows.into_iter().collect())
+            .boxify()
+    }
+}
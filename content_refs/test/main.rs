@@ -0,0 +1,141 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the index of content references.
+
+#![deny(warnings)]
+
+extern crate content_refs;
+extern crate context;
+extern crate mercurial_types;
+extern crate mercurial_types_mocks;
+extern crate metaconfig_types;
+extern crate mononoke_types;
+extern crate mononoke_types_mocks;
+extern crate tokio;
+
+use std::sync::Arc;
+
+use content_refs::{ContentRef, ContentRefs, ContentRefsIndex, SqlConstructors, SqlContentRefs};
+use context::CoreContext;
+use mercurial_types::{HgFileNodeId, MPath};
+use mercurial_types_mocks::nodehash::{ONES_FNID, THREES_FNID, TWOS_FNID};
+use metaconfig_types::ContentRefsParams;
+use mononoke_types::{ChangesetId, ContentId};
+use mononoke_types_mocks::changesetid::{ONES_CSID, TWOS_CSID};
+use mononoke_types_mocks::contentid::{ONES_CTID, TWOS_CTID};
+use mononoke_types_mocks::repo::{REPO_ONE, REPO_ZERO};
+use tokio::runtime::Runtime;
+
+fn content_ref(
+    content_id: ContentId,
+    path: &str,
+    filenode: HgFileNodeId,
+    changeset_id: ChangesetId,
+) -> ContentRef {
+    ContentRef {
+        content_id,
+        path: MPath::new(path).unwrap(),
+        filenode,
+        changeset_id,
+    }
+}
+
+#[test]
+fn test_add_and_get() {
+    let mut rt = Runtime::new().unwrap();
+
+    let ctx = CoreContext::test_mock();
+    let store = SqlContentRefs::with_sqlite_in_memory().unwrap();
+
+    let first = content_ref(ONES_CTID, "dir/a", ONES_FNID, ONES_CSID);
+    let second = content_ref(ONES_CTID, "b", TWOS_FNID, TWOS_CSID);
+    let other = content_ref(TWOS_CTID, "c", THREES_FNID, TWOS_CSID);
+    rt.block_on(store.add(
+        ctx.clone(),
+        REPO_ZERO,
+        vec![first.clone(), second.clone(), other.clone()],
+    ))
+    .expect("Adding refs failed");
+    // References that are already recorded are ignored
+    rt.block_on(store.add(ctx.clone(), REPO_ZERO, vec![first.clone()]))
+        .expect("Adding refs again failed");
+
+    let mut refs = rt
+        .block_on(store.get(ctx.clone(), REPO_ZERO, ONES_CTID, 10))
+        .expect("Getting refs failed");
+    refs.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(refs, vec![second.clone(), first.clone()]);
+
+    let refs = rt
+        .block_on(store.get(ctx.clone(), REPO_ZERO, ONES_CTID, 1))
+        .expect("Getting refs failed");
+    assert_eq!(refs.len(), 1);
+
+    let refs = rt
+        .block_on(store.get(ctx.clone(), REPO_ONE, ONES_CTID, 10))
+        .expect("Getting refs failed");
+    assert!(refs.is_empty());
+
+    let counts = rt
+        .block_on(store.count(ctx.clone(), REPO_ZERO, vec![ONES_CTID, TWOS_CTID]))
+        .expect("Counting refs failed");
+    assert_eq!(counts.get(&ONES_CTID), Some(&2));
+    assert_eq!(counts.get(&TWOS_CTID), Some(&1));
+}
+
+#[test]
+fn test_index_bounds() {
+    let mut rt = Runtime::new().unwrap();
+
+    let ctx = CoreContext::test_mock();
+    let store = Arc::new(SqlContentRefs::with_sqlite_in_memory().unwrap());
+    let index = ContentRefsIndex::new(
+        REPO_ZERO,
+        ContentRefsParams {
+            max_refs_per_content: 2,
+            max_refs_per_push: 3,
+        },
+        store.clone(),
+    );
+
+    // Only the first references of the push are recorded
+    let recorded = rt
+        .block_on(index.record(
+            ctx.clone(),
+            vec![
+                content_ref(ONES_CTID, "a", ONES_FNID, ONES_CSID),
+                content_ref(TWOS_CTID, "b", TWOS_FNID, ONES_CSID),
+                content_ref(TWOS_CTID, "b", TWOS_FNID, ONES_CSID),
+                content_ref(TWOS_CTID, "c", THREES_FNID, ONES_CSID),
+                content_ref(TWOS_CTID, "d", ONES_FNID, TWOS_CSID),
+            ],
+        ))
+        .expect("Recording refs failed");
+    assert_eq!(recorded, 3);
+
+    // Contents that have as many references as allowed get no more
+    let recorded = rt
+        .block_on(index.record(
+            ctx.clone(),
+            vec![
+                content_ref(ONES_CTID, "e", TWOS_FNID, TWOS_CSID),
+                content_ref(ONES_CTID, "f", THREES_FNID, TWOS_CSID),
+                content_ref(TWOS_CTID, "g", THREES_FNID, TWOS_CSID),
+            ],
+        ))
+        .expect("Recording refs failed");
+    assert_eq!(recorded, 1);
+
+    let refs = rt
+        .block_on(index.get(ctx.clone(), ONES_CTID, 10))
+        .expect("Getting refs failed");
+    assert_eq!(refs.len(), 2);
+    let refs = rt
+        .block_on(index.get(ctx.clone(), TWOS_CTID, 10))
+        .expect("Getting refs failed");
+    assert_eq!(refs.len(), 2);
+}
@@ -0,0 +1,410 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Filenodes derived from bonsai changesets.
+//!
+//! Commits that didn't come from Mercurial (git imports, commits created through the API) have
+//! no filenodes until something stores them, and without filenodes getfiles can't tell the
+//! linknodes of their files. Deriving filenodes generates them from the hg manifests of a
+//! changeset and of its parents: every entry of the manifest that is new compared to all parents
+//! gets a filenode, with its copy info and with the changeset as linknode.
+//!
+//! A changeset is marked as derived in the blobstore once its filenodes are stored, and its
+//! ancestors are always derived before it. A changeset being derived therefore means that all
+//! its ancestors are, which is what lets derivation stop walking history at the first derived
+//! changeset.
+
+#![deny(warnings)]
+
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use failure_ext::Error;
+use futures::future::{self, loop_fn, Loop};
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use slog::debug;
+
+use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use cloned::cloned;
+use context::CoreContext;
+use filenodes::FilenodeInfo;
+use mercurial_types::manifest::EmptyManifest;
+use mercurial_types::manifest_utils::{changed_entry_stream, ChangedEntry, EntryStatus};
+use mercurial_types::{
+    Changeset, Entry, HgChangesetId, HgFileNodeId, HgManifestId, HgNodeHash, MPath, Manifest,
+    RepoPath, Type,
+};
+use mononoke_types::{BlobstoreBytes, ChangesetId};
+
+/// How many changesets have their filenodes generated at once
+const DERIVE_CONCURRENCY: usize = 10;
+
+/// How many entries of a changeset have their filenode fetched at once
+const ENTRY_CONCURRENCY: usize = 100;
+
+fn derived_key(cs_id: ChangesetId) -> String {
+    format!("derivedfilenodes.bonsai.{}", cs_id)
+}
+
+/// Whether the filenodes of a changeset, and of all its ancestors, are stored
+pub fn is_derived(ctx: CoreContext, repo: BlobRepo, cs_id: ChangesetId) -> BoxFuture<bool, Error> {
+    repo.get_blobstore()
+        .is_present(ctx, derived_key(cs_id))
+        .boxify()
+}
+
+/// The filenodes of all entries that a changeset adds or modifies, including its root manifest.
+/// The hg changeset is derived first if needed.
+pub fn generate_all_filenodes(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    cs_id: ChangesetId,
+) -> BoxFuture<Vec<FilenodeInfo>, Error> {
+    repo.get_hg_from_bonsai_changeset(ctx.clone(), cs_id)
+        .and_then({
+            cloned!(ctx, repo);
+            move |linknode| {
+                repo.get_changeset_by_changesetid(ctx, linknode)
+                    .map(move |cs| (linknode, cs))
+            }
+        })
+        .and_then({
+            cloned!(ctx, repo);
+            move |(linknode, cs)| {
+                let (p1, p2) = cs.parents().get_nodes();
+                let parents = p1.into_iter().chain(p2).map({
+                    cloned!(ctx, repo);
+                    move |p| {
+                        repo.get_changeset_by_changesetid(ctx.clone(), HgChangesetId::new(p))
+                            .map(|cs| cs.manifestid())
+                    }
+                });
+                future::join_all(parents).map(move |parents| (linknode, cs.manifestid(), parents))
+            }
+        })
+        .and_then(move |(linknode, manifestid, parents)| {
+            let root = if parents.contains(&manifestid) {
+                future::ok(vec![]).left_future()
+            } else {
+                root_filenode(ctx.clone(), repo.clone(), manifestid, linknode)
+                    .map(|root| vec![root])
+                    .right_future()
+            };
+
+            new_entries(ctx.clone(), repo.clone(), manifestid, parents)
+                .map(move |(path, entry)| entry_filenode(ctx.clone(), &repo, path, entry, linknode))
+                .buffered(ENTRY_CONCURRENCY)
+                .collect()
+                .join(root)
+                .map(|(mut filenodes, root)| {
+                    filenodes.extend(root);
+                    filenodes
+                })
+        })
+        .boxify()
+}
+
+fn root_filenode(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    manifestid: HgManifestId,
+    linknode: HgChangesetId,
+) -> BoxFuture<FilenodeInfo, Error> {
+    repo.get_root_entry(manifestid)
+        .get_parents(ctx)
+        .map(move |parents| {
+            let (p1, p2) = parents.get_nodes();
+            FilenodeInfo {
+                path: RepoPath::RootPath,
+                filenode: HgFileNodeId::new(manifestid.into_nodehash()),
+                p1: p1.map(HgFileNodeId::new),
+                p2: p2.map(HgFileNodeId::new),
+                copyfrom: None,
+                linknode,
+            }
+        })
+        .boxify()
+}
+
+fn entry_filenode(
+    ctx: CoreContext,
+    repo: &BlobRepo,
+    path: MPath,
+    entry: Box<Entry + Sync>,
+    linknode: HgChangesetId,
+) -> BoxFuture<FilenodeInfo, Error> {
+    let filenode = HgFileNodeId::new(entry.get_hash().into_nodehash());
+    match entry.get_type() {
+        // Only files have copy info, which is in their envelope
+        Type::File(_) => repo
+            .get_filenode_from_envelope(ctx, &RepoPath::FilePath(path), filenode, linknode)
+            .boxify(),
+        Type::Tree => entry
+            .get_parents(ctx)
+            .map(move |parents| {
+                let (p1, p2) = parents.get_nodes();
+                FilenodeInfo {
+                    path: RepoPath::DirectoryPath(path),
+                    filenode,
+                    p1: p1.map(HgFileNodeId::new),
+                    p2: p2.map(HgFileNodeId::new),
+                    copyfrom: None,
+                    linknode,
+                }
+            })
+            .boxify(),
+    }
+}
+
+// The entries of the manifest that are in none of the parent manifests, with their path
+fn new_entries(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    manifestid: HgManifestId,
+    parents: Vec<HgManifestId>,
+) -> BoxStream<(MPath, Box<Entry + Sync>), Error> {
+    let manifests = future::join_all(
+        Some(manifestid)
+            .into_iter()
+            .chain(parents)
+            .map({
+                cloned!(ctx);
+                move |id| repo.get_manifest_by_nodeid(ctx.clone(), id)
+            })
+            .collect::<Vec<_>>(),
+    );
+
+    manifests
+        .map(move |mut manifests| {
+            let root = manifests.remove(0);
+            let mut parents = manifests.into_iter();
+            match (parents.next(), parents.next()) {
+                (None, _) => changed_entry_stream(ctx.clone(), &root, &EmptyManifest {}, None)
+                    .filter_map(new_entry)
+                    .boxify(),
+                (Some(p1), None) => changed_entry_stream(ctx.clone(), &root, &p1, None)
+                    .filter_map(new_entry)
+                    .boxify(),
+                (Some(p1), Some(p2)) => {
+                    // An entry that p2 already has is not new, even if p1 doesn't have it
+                    let new_in_p2 = changed_entry_stream(ctx.clone(), &root, &p2, None)
+                        .filter_map(new_entry)
+                        .map(|(path, entry)| (path, entry.get_hash().into_nodehash()))
+                        .collect()
+                        .map(|new_in_p2| new_in_p2.into_iter().collect::<HashSet<_>>());
+                    let new_in_p1 =
+                        changed_entry_stream(ctx.clone(), &root, &p1, None).filter_map(new_entry);
+
+                    new_in_p2
+                        .map(move |new_in_p2| {
+                            new_in_p1.filter(move |(path, entry)| {
+                                let hash: HgNodeHash = entry.get_hash().into_nodehash();
+                                new_in_p2.contains(&(path.clone(), hash))
+                            })
+                        })
+                        .flatten_stream()
+                        .boxify()
+                }
+            }
+        })
+        .flatten_stream()
+        .boxify()
+}
+
+fn new_entry(changed: ChangedEntry) -> Option<(MPath, Box<Entry + Sync>)> {
+    let path = changed.get_full_path()?;
+    match changed.status {
+        EntryStatus::Added(entry)
+        | EntryStatus::Modified {
+            to_entry: entry, ..
+        } => Some((path, entry)),
+        EntryStatus::Deleted(_) => None,
+    }
+}
+
+/// Store the filenodes of a changeset and of all its ancestors that don't have them yet, oldest
+/// first. Returns the number of changesets whose filenodes were stored.
+pub fn derive_filenodes(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    cs_id: ChangesetId,
+) -> BoxFuture<usize, Error> {
+    underived_ancestors(ctx.clone(), repo.clone(), cs_id)
+        .and_then(move |underived| {
+            let count = underived.len();
+            stream::iter_ok(underived)
+                .map({
+                    cloned!(ctx, repo);
+                    move |cs_id| {
+                        generate_all_filenodes(ctx.clone(), repo.clone(), cs_id)
+                            .map(move |filenodes| (cs_id, filenodes))
+                    }
+                })
+                .buffered(DERIVE_CONCURRENCY)
+                // Changesets are marked in order, so that a marked changeset never has unmarked
+                // ancestors
+                .for_each(move |(cs_id, filenodes)| {
+                    debug!(
+                        ctx.logger(),
+                        "storing {} filenodes of {}",
+                        filenodes.len(),
+                        cs_id
+                    );
+                    let linknode = filenodes.first().map(|filenode| filenode.linknode);
+                    repo.get_filenodes()
+                        .add_filenodes(
+                            ctx.clone(),
+                            stream::iter_ok(filenodes).boxify(),
+                            repo.get_repoid(),
+                        )
+                        .and_then({
+                            cloned!(ctx, repo);
+                            move |()| {
+                                let marker = linknode
+                                    .map(|linknode| Bytes::from(linknode.to_hex().as_bytes()))
+                                    .unwrap_or_default();
+                                repo.get_blobstore().put(
+                                    ctx,
+                                    derived_key(cs_id),
+                                    BlobstoreBytes::from_bytes(marker),
+                                )
+                            }
+                        })
+                })
+                .map(move |()| count)
+        })
+        .boxify()
+}
+
+// The ancestors of a changeset (including itself) that are not derived, sorted by generation
+fn underived_ancestors(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    cs_id: ChangesetId,
+) -> BoxFuture<Vec<ChangesetId>, Error> {
+    let fetcher = repo.get_changeset_fetcher();
+    let mut visited = HashSet::new();
+    visited.insert(cs_id);
+
+    loop_fn(
+        (vec![cs_id], visited, vec![]),
+        move |(mut to_visit, mut visited, mut underived)| match to_visit.pop() {
+            Some(cs_id) => is_derived(ctx.clone(), repo.clone(), cs_id)
+                .and_then({
+                    cloned!(ctx, fetcher);
+                    move |derived| {
+                        if derived {
+                            return future::ok(None).left_future();
+                        }
+                        fetcher
+                            .get_generation_number(ctx.clone(), cs_id)
+                            .join(fetcher.get_parents(ctx, cs_id))
+                            .map(Some)
+                            .right_future()
+                    }
+                })
+                .map(move |found| {
+                    if let Some((generation, parents)) = found {
+                        underived.push((generation, cs_id));
+                        for parent in parents {
+                            if visited.insert(parent) {
+                                to_visit.push(parent);
+                            }
+                        }
+                    }
+                    Loop::Continue((to_visit, visited, underived))
+                })
+                .left_future(),
+            None => {
+                underived.sort();
+                let underived = underived.into_iter().map(|(_, cs_id)| cs_id).collect();
+                future::ok(Loop::Break(underived)).right_future()
+            }
+        },
+    )
+    .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use fixtures::{linear, merge_uneven};
+    use tokio::runtime::Runtime;
+
+    fn bonsai(rt: &mut Runtime, repo: &BlobRepo, hg_cs_id: &str) -> ChangesetId {
+        let ctx = CoreContext::test_mock();
+        let hg_cs_id = HgChangesetId::from_str(hg_cs_id).unwrap();
+        rt.block_on(repo.get_bonsai_from_hg(ctx, hg_cs_id))
+            .unwrap()
+            .unwrap()
+    }
+
+    // The fixtures store filenodes when they derive hg changesets, which the generated
+    // filenodes must match
+    fn check_filenodes(rt: &mut Runtime, repo: &BlobRepo, cs_id: ChangesetId) {
+        let ctx = CoreContext::test_mock();
+        let filenodes = rt
+            .block_on(generate_all_filenodes(ctx.clone(), repo.clone(), cs_id))
+            .unwrap();
+        assert!(!filenodes.is_empty());
+        for filenode in filenodes {
+            let stored = rt
+                .block_on(repo.get_filenode_opt(ctx.clone(), &filenode.path, filenode.filenode))
+                .unwrap();
+            assert_eq!(stored, Some(filenode));
+        }
+    }
+
+    #[test]
+    fn generated_filenodes() {
+        let mut rt = Runtime::new().unwrap();
+
+        let repo = linear::getrepo(None);
+        for hg_cs_id in &[
+            // The root commit
+            "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536",
+            "79a13814c5ce7330173ec04d279bf95ab3f652fb",
+        ] {
+            let cs_id = bonsai(&mut rt, &repo, hg_cs_id);
+            check_filenodes(&mut rt, &repo, cs_id);
+        }
+
+        // A merge
+        let repo = merge_uneven::getrepo(None);
+        let cs_id = bonsai(&mut rt, &repo, "d0b4845e349f338361208f170ec13120080da37f");
+        check_filenodes(&mut rt, &repo, cs_id);
+    }
+
+    #[test]
+    fn derive_ancestors_first() {
+        let mut rt = Runtime::new().unwrap();
+        let repo = linear::getrepo(None);
+        let ctx = CoreContext::test_mock();
+        let middle = bonsai(&mut rt, &repo, "0ed509bf086fadcb8a8a5384dc3b550729b0fc17");
+        let head = bonsai(&mut rt, &repo, "79a13814c5ce7330173ec04d279bf95ab3f652fb");
+
+        let derive = |rt: &mut Runtime, cs_id| {
+            rt.block_on(derive_filenodes(ctx.clone(), repo.clone(), cs_id))
+                .unwrap()
+        };
+        assert_eq!(derive(&mut rt, middle), 7);
+        assert!(rt
+            .block_on(is_derived(ctx.clone(), repo.clone(), middle))
+            .unwrap());
+        assert!(!rt
+            .block_on(is_derived(ctx.clone(), repo.clone(), head))
+            .unwrap());
+
+        // Only the changesets above the derived one are left
+        assert_eq!(derive(&mut rt, head), 4);
+        assert_eq!(derive(&mut rt, head), 0);
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Publishes typed events about a repo - landed commits, moved bookmarks, lock changes - for
+//! sync jobs and indexers to consume.
+//!
+//! Every event is serialized as a JSON object with an `event` field naming its type, and handed
+//! to a transport: scribe inside Facebook, Kafka or stdout elsewhere.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate stats;
+
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use failure_ext::{format_err, Error};
+use futures::{Future, IntoFuture};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use scribe::ScribeClient;
+use scribe_cxx::ScribeCxxClient;
+use serde_derive::Serialize;
+use serde_json;
+use stats::Timeseries;
+
+use metaconfig_types::EventBusParams;
+use mononoke_types::{ChangesetId, Generation, RepositoryId};
+
+define_stats! {
+    prefix = "mononoke.event_bus";
+    published: timeseries(RATE, SUM),
+    failed: timeseries(RATE, SUM),
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RepoEvent {
+    /// A commit became reachable from a public bookmark
+    CommitLanded {
+        repo_id: RepositoryId,
+        changeset_id: ChangesetId,
+        generation: Generation,
+        parents: Vec<ChangesetId>,
+        bookmark: String,
+    },
+    /// A bookmark was created (`from` is None), moved, or deleted (`to` is None)
+    BookmarkMoved {
+        repo_id: RepositoryId,
+        bookmark: String,
+        from: Option<ChangesetId>,
+        to: Option<ChangesetId>,
+    },
+    /// The repo stopped accepting writes
+    RepoLocked {
+        repo_id: RepositoryId,
+        reason: String,
+    },
+    /// The repo started accepting writes again
+    RepoUnlocked { repo_id: RepositoryId },
+}
+
+impl RepoEvent {
+    pub fn repo_id(&self) -> RepositoryId {
+        match *self {
+            RepoEvent::CommitLanded { repo_id, .. }
+            | RepoEvent::BookmarkMoved { repo_id, .. }
+            | RepoEvent::RepoLocked { repo_id, .. }
+            | RepoEvent::RepoUnlocked { repo_id } => repo_id,
+        }
+    }
+}
+
+/// Delivers serialized events.
+pub trait EventTransport: Send + Sync {
+    /// Send `payload`. Transports that partition their data use `key` to keep the events of a
+    /// repo in order.
+    fn send(&self, key: String, payload: String) -> BoxFuture<(), Error>;
+}
+
+pub struct ScribeTransport<C: ScribeClient + Send + Sync> {
+    client: C,
+    category: String,
+}
+
+impl ScribeTransport<ScribeCxxClient> {
+    pub fn new_with_default_scribe(category: String) -> Self {
+        Self::new(ScribeCxxClient::new(), category)
+    }
+}
+
+impl<C: ScribeClient + Send + Sync> ScribeTransport<C> {
+    pub fn new(client: C, category: String) -> Self {
+        Self { client, category }
+    }
+}
+
+impl<C: ScribeClient + Send + Sync> EventTransport for ScribeTransport<C> {
+    fn send(&self, _key: String, payload: String) -> BoxFuture<(), Error> {
+        self.client
+            .offer(&self.category, &payload)
+            .into_future()
+            .from_err()
+            .boxify()
+    }
+}
+
+pub struct KafkaTransport {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl KafkaTransport {
+    pub fn new(brokers: &str, topic: String) -> Result<Self, Error> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "5000")
+            .create()?;
+        Ok(Self { producer, topic })
+    }
+}
+
+impl EventTransport for KafkaTransport {
+    fn send(&self, key: String, payload: String) -> BoxFuture<(), Error> {
+        let record = FutureRecord::to(&self.topic).key(&key).payload(&payload);
+        self.producer
+            .send(record, 0)
+            .map_err(|_| format_err!("kafka producer went away"))
+            .and_then(|result| result.map(|_| ()).map_err(|(err, _)| err.into()))
+            .boxify()
+    }
+}
+
+pub struct StdoutTransport;
+
+impl EventTransport for StdoutTransport {
+    fn send(&self, _key: String, payload: String) -> BoxFuture<(), Error> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        writeln!(stdout, "{}", payload)
+            .and_then(|()| stdout.flush())
+            .into_future()
+            .from_err()
+            .boxify()
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    transport: Option<Arc<EventTransport>>,
+}
+
+impl EventBus {
+    pub fn new(params: Option<EventBusParams>) -> Result<Self, Error> {
+        let transport: Option<Arc<EventTransport>> = match params {
+            Some(EventBusParams::Scribe { category }) => {
+                Some(Arc::new(ScribeTransport::new_with_default_scribe(category)))
+            }
+            Some(EventBusParams::Kafka { brokers, topic }) => {
+                Some(Arc::new(KafkaTransport::new(&brokers, topic)?))
+            }
+            Some(EventBusParams::Stdout) => Some(Arc::new(StdoutTransport)),
+            None => None,
+        };
+        Ok(Self { transport })
+    }
+
+    pub fn with_transport(transport: Arc<EventTransport>) -> Self {
+        Self {
+            transport: Some(transport),
+        }
+    }
+
+    /// An event bus that drops all events.
+    pub fn discard() -> Self {
+        Self { transport: None }
+    }
+
+    pub fn publish(&self, event: RepoEvent) -> BoxFuture<(), Error> {
+        let transport = match self.transport {
+            Some(ref transport) => transport.clone(),
+            None => return Ok(()).into_future().boxify(),
+        };
+        let payload = try_boxfuture!(serde_json::to_string(&event));
+        transport
+            .send(event.repo_id().id().to_string(), payload)
+            .then(|result| {
+                match result {
+                    Ok(()) => STATS::published.add_value(1),
+                    Err(_) => STATS::failed.add_value(1),
+                }
+                result
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use mononoke_types_mocks::changesetid::{ONES_CSID, TWOS_CSID};
+    use serde_json::json;
+
+    #[derive(Default)]
+    struct RecordingTransport {
+        sent: Mutex<Vec<(String, String)>>,
+    }
+
+    impl EventTransport for RecordingTransport {
+        fn send(&self, key: String, payload: String) -> BoxFuture<(), Error> {
+            self.sent.lock().unwrap().push((key, payload));
+            Ok(()).into_future().boxify()
+        }
+    }
+
+    #[test]
+    fn publish_serializes_tagged_events() {
+        let transport = Arc::new(RecordingTransport::default());
+        let bus = EventBus::with_transport(transport.clone());
+
+        let event = RepoEvent::BookmarkMoved {
+            repo_id: RepositoryId::new(3),
+            bookmark: "master".to_string(),
+            from: Some(ONES_CSID),
+            to: Some(TWOS_CSID),
+        };
+        bus.publish(event).wait().unwrap();
+
+        let sent = transport.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        let (ref key, ref payload) = sent[0];
+        assert_eq!(key, "3");
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(
+            payload,
+            json!({
+                "event": "bookmark_moved",
+                "repo_id": 3,
+                "bookmark": "master",
+                "from": ONES_CSID.to_string(),
+                "to": TWOS_CSID.to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn discard_publishes_nothing() {
+        let bus = EventBus::discard();
+        let event = RepoEvent::RepoUnlocked {
+            repo_id: RepositoryId::new(0),
+        };
+        assert!(bus.publish(event).wait().is_ok());
+    }
+}
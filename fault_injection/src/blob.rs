@@ -0,0 +1,120 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+
+use blobstore::Blobstore;
+use cloned::cloned;
+use context::CoreContext;
+use failure_ext::Error;
+use futures_ext::BoxFuture;
+use mononoke_types::BlobstoreBytes;
+
+use crate::FaultInjector;
+
+/// A blobstore whose operations fail or are delayed as the injector decides. Operations are
+/// named "get", "put", "is_present" and "assert_present", and keys are blobstore keys.
+#[derive(Debug)]
+pub struct FaultInjectBlobstore {
+    blobstore: Arc<Blobstore>,
+    injector: Arc<FaultInjector>,
+}
+
+impl FaultInjectBlobstore {
+    pub fn new(blobstore: Arc<Blobstore>, injector: Arc<FaultInjector>) -> Self {
+        Self {
+            blobstore,
+            injector,
+        }
+    }
+}
+
+impl Blobstore for FaultInjectBlobstore {
+    fn get(&self, ctx: CoreContext, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+        let get = {
+            cloned!(self.blobstore, key);
+            move || blobstore.get(ctx, key)
+        };
+        self.injector.inject("get", &key, get)
+    }
+
+    fn put(&self, ctx: CoreContext, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
+        let put = {
+            cloned!(self.blobstore, key);
+            move || blobstore.put(ctx, key, value)
+        };
+        self.injector.inject("put", &key, put)
+    }
+
+    fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<bool, Error> {
+        let is_present = {
+            cloned!(self.blobstore, key);
+            move || blobstore.is_present(ctx, key)
+        };
+        self.injector.inject("is_present", &key, is_present)
+    }
+
+    fn assert_present(&self, ctx: CoreContext, key: String) -> BoxFuture<(), Error> {
+        let assert_present = {
+            cloned!(self.blobstore, key);
+            move || blobstore.assert_present(ctx, key)
+        };
+        self.injector.inject("assert_present", &key, assert_present)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::Future;
+    use memblob::EagerMemblob;
+    use metaconfig_types::{FaultMode, FaultRule};
+    use regex::Regex;
+
+    #[test]
+    fn lost_puts_are_written() {
+        let ctx = CoreContext::test_mock();
+        let inner: Arc<Blobstore> = Arc::new(EagerMemblob::new());
+        let blobstore = FaultInjectBlobstore::new(
+            inner.clone(),
+            Arc::new(FaultInjector::new(
+                vec![FaultRule {
+                    key_regex: Some(Regex::new("^lost\\.").unwrap()),
+                    operations: vec!["put".to_string()],
+                    error_percentage: 100,
+                    fail_first: 0,
+                    mode: FaultMode::LostResponse,
+                    latency_ms: 0,
+                }],
+                None,
+            )),
+        );
+        let value = BlobstoreBytes::from_bytes(&b"value"[..]);
+
+        assert!(blobstore
+            .put(ctx.clone(), "lost.key".to_string(), value.clone())
+            .wait()
+            .is_err());
+        assert!(blobstore
+            .put(ctx.clone(), "kept.key".to_string(), value.clone())
+            .wait()
+            .is_ok());
+
+        // Both puts reached the inner blobstore, and reads are not affected
+        assert_eq!(
+            blobstore
+                .get(ctx.clone(), "lost.key".to_string())
+                .wait()
+                .unwrap(),
+            Some(value.clone())
+        );
+        assert!(inner
+            .is_present(ctx, "kept.key".to_string())
+            .wait()
+            .unwrap());
+    }
+}
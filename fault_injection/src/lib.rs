@@ -0,0 +1,233 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Makes a repo's storage fail and slow down on purpose, so that tests can exercise error paths.
+//!
+//! Which operations fail is configured with `FaultRule`s: an operation uses the first rule that
+//! matches its name and key, and fails either at random or because it's one of the first
+//! attempts on its key. Failing operations either never reach the store or reach it and lose
+//! their response. Randomness is seeded, so a run can be reproduced.
+//!
+//! The SQL stores are wrapped at the level of their traits rather than of their connections:
+//! connections are opaque, and a store operation is the unit that callers retry anyway.
+
+#![deny(warnings)]
+
+mod blob;
+mod sql;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use failure_ext::Error;
+use futures::future::{err, lazy, ok};
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use metaconfig_types::{FaultMode, FaultRule};
+use mononoke_errors::{Categorize, ErrorCategory};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+use tokio::timer::Delay;
+
+pub use crate::blob::FaultInjectBlobstore;
+pub use crate::sql::{FaultInjectBonsaiHgMapping, FaultInjectChangesets, FaultInjectFilenodes};
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "injected fault in {} of {}", _0, _1)]
+    InjectedFault(String, String),
+}
+
+impl Categorize for ErrorKind {
+    fn category(&self) -> Option<ErrorCategory> {
+        // Injected faults stand for backend outages, which callers are expected to retry
+        match self {
+            ErrorKind::InjectedFault(..) => Some(ErrorCategory::Transient),
+        }
+    }
+}
+
+/// Decides which operations fail, following a list of rules
+pub struct FaultInjector {
+    rules: Vec<FaultRule>,
+    rng: Mutex<StdRng>,
+    // Number of operations seen so far, per rule and key. Only kept for rules with `fail_first`
+    attempts: Mutex<HashMap<(usize, String), usize>>,
+}
+
+impl FaultInjector {
+    pub fn new(rules: Vec<FaultRule>, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(thread_rng()).expect("failed to seed rng"),
+        };
+        Self {
+            rules,
+            rng: Mutex::new(rng),
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `operation` on `key` with `run`, unless a rule makes it fail. `run` is not called
+    /// at all if the operation fails without reaching the store.
+    pub fn inject<T, F>(&self, operation: &str, key: &str, run: F) -> BoxFuture<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> BoxFuture<T, Error> + Send + 'static,
+    {
+        let (latency, failure) = match self.decide(operation, key) {
+            Some(decision) => decision,
+            None => return run(),
+        };
+
+        let error = ErrorKind::InjectedFault(operation.to_string(), key.to_string());
+        let delay = if latency == Duration::from_millis(0) {
+            ok(()).left_future()
+        } else {
+            lazy(move || Delay::new(Instant::now() + latency))
+                .map_err(Error::from)
+                .right_future()
+        };
+
+        delay
+            .and_then(move |()| match failure {
+                None => run(),
+                Some(FaultMode::Error) => err(error.into()).boxify(),
+                Some(FaultMode::LostResponse) => run().then(move |_| Err(error.into())).boxify(),
+            })
+            .boxify()
+    }
+
+    /// The latency of an operation, and how it fails if it does. None if no rule matches it
+    fn decide(&self, operation: &str, key: &str) -> Option<(Duration, Option<FaultMode>)> {
+        let (index, rule) = self.rules.iter().enumerate().find(|(_, rule)| {
+            (rule.operations.is_empty() || rule.operations.iter().any(|op| op == operation))
+                && rule
+                    .key_regex
+                    .as_ref()
+                    .map_or(true, |regex| regex.is_match(key))
+        })?;
+
+        let early_attempt = rule.fail_first > 0 && {
+            let mut attempts = self.attempts.lock().expect("lock poisoned");
+            let attempt = attempts.entry((index, key.to_string())).or_insert(0);
+            *attempt += 1;
+            *attempt <= rule.fail_first
+        };
+        let fails = early_attempt
+            || (rule.error_percentage > 0
+                && self.rng.lock().expect("lock poisoned").gen_range(0, 100)
+                    < rule.error_percentage);
+
+        Some((
+            Duration::from_millis(rule.latency_ms),
+            if fails { Some(rule.mode) } else { None },
+        ))
+    }
+}
+
+impl fmt::Debug for FaultInjector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FaultInjector")
+            .field("rules", &self.rules)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use regex::Regex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn rule(operations: &[&str], key_regex: Option<&str>) -> FaultRule {
+        FaultRule {
+            key_regex: key_regex.map(|regex| Regex::new(regex).unwrap()),
+            operations: operations.iter().map(|op| op.to_string()).collect(),
+            error_percentage: 0,
+            fail_first: 0,
+            mode: FaultMode::Error,
+            latency_ms: 0,
+        }
+    }
+
+    fn run(injector: &FaultInjector, operation: &str, key: &str, calls: &Arc<AtomicUsize>) -> bool {
+        let calls = calls.clone();
+        injector
+            .inject(operation, key, move || {
+                calls.fetch_add(1, Ordering::Relaxed);
+                ok(()).boxify()
+            })
+            .wait()
+            .is_ok()
+    }
+
+    #[test]
+    fn first_attempts_fail() {
+        let injector = FaultInjector::new(
+            vec![FaultRule {
+                fail_first: 2,
+                ..rule(&["put"], Some("^flaky"))
+            }],
+            Some(0),
+        );
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        assert!(!run(&injector, "put", "flaky1", &calls));
+        assert!(!run(&injector, "put", "flaky1", &calls));
+        assert!(run(&injector, "put", "flaky1", &calls));
+        // Counted per key
+        assert!(!run(&injector, "put", "flaky2", &calls));
+        // Other keys and operations are not affected
+        assert!(run(&injector, "put", "stable", &calls));
+        assert!(run(&injector, "get", "flaky1", &calls));
+        // Failing operations never reached the store
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn lost_responses() {
+        let injector = FaultInjector::new(
+            vec![FaultRule {
+                error_percentage: 100,
+                mode: FaultMode::LostResponse,
+                ..rule(&[], None)
+            }],
+            Some(0),
+        );
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        assert!(!run(&injector, "put", "key", &calls));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn seeded_runs_are_reproducible() {
+        let failures = || {
+            let injector = FaultInjector::new(
+                vec![FaultRule {
+                    error_percentage: 50,
+                    ..rule(&[], None)
+                }],
+                Some(42),
+            );
+            let calls = Arc::new(AtomicUsize::new(0));
+            (0..100)
+                .map(|i| run(&injector, "get", &format!("key{}", i), &calls))
+                .collect::<Vec<_>>()
+        };
+
+        let first = failures();
+        assert_eq!(first, failures());
+        assert!(first.iter().any(|ok| *ok));
+        assert!(first.iter().any(|ok| !*ok));
+    }
+}
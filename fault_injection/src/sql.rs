@@ -0,0 +1,174 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Wrappers of the SQL stores of a repo. Operations are named after the store and the method,
+//! e.g. "changesets.add" or "filenodes.get_all", and keys are changeset ids or paths.
+
+use std::fmt::Display;
+use std::sync::Arc;
+
+use bonsai_hg_mapping::{BonsaiHgMapping, BonsaiHgMappingEntry, BonsaiOrHgChangesetIds};
+use changesets::{ChangesetEntry, ChangesetInsert, Changesets};
+use cloned::cloned;
+use context::CoreContext;
+use failure_ext::Error;
+use filenodes::{FilenodeInfo, Filenodes};
+use futures_ext::{BoxFuture, BoxStream};
+use mercurial_types::{HgFileNodeId, RepoPath};
+use mononoke_types::{ChangesetId, RepositoryId};
+
+use crate::FaultInjector;
+
+fn join_keys<T: Display>(ids: &[T]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub struct FaultInjectChangesets {
+    changesets: Arc<Changesets>,
+    injector: Arc<FaultInjector>,
+}
+
+impl FaultInjectChangesets {
+    pub fn new(changesets: Arc<Changesets>, injector: Arc<FaultInjector>) -> Self {
+        Self {
+            changesets,
+            injector,
+        }
+    }
+}
+
+impl Changesets for FaultInjectChangesets {
+    fn add(&self, ctx: CoreContext, cs: ChangesetInsert) -> BoxFuture<bool, Error> {
+        let key = cs.cs_id.to_string();
+        cloned!(self.changesets);
+        self.injector
+            .inject("changesets.add", &key, move || changesets.add(ctx, cs))
+    }
+
+    fn get(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        cs_id: ChangesetId,
+    ) -> BoxFuture<Option<ChangesetEntry>, Error> {
+        cloned!(self.changesets);
+        self.injector
+            .inject("changesets.get", &cs_id.to_string(), move || {
+                changesets.get(ctx, repo_id, cs_id)
+            })
+    }
+
+    fn get_many(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        cs_ids: Vec<ChangesetId>,
+    ) -> BoxFuture<Vec<ChangesetEntry>, Error> {
+        let key = join_keys(&cs_ids);
+        cloned!(self.changesets);
+        self.injector.inject("changesets.get_many", &key, move || {
+            changesets.get_many(ctx, repo_id, cs_ids)
+        })
+    }
+}
+
+pub struct FaultInjectBonsaiHgMapping {
+    mapping: Arc<BonsaiHgMapping>,
+    injector: Arc<FaultInjector>,
+}
+
+impl FaultInjectBonsaiHgMapping {
+    pub fn new(mapping: Arc<BonsaiHgMapping>, injector: Arc<FaultInjector>) -> Self {
+        Self { mapping, injector }
+    }
+}
+
+impl BonsaiHgMapping for FaultInjectBonsaiHgMapping {
+    fn add(&self, ctx: CoreContext, entry: BonsaiHgMappingEntry) -> BoxFuture<bool, Error> {
+        let key = entry.bcs_id.to_string();
+        cloned!(self.mapping);
+        self.injector
+            .inject("bonsai_hg_mapping.add", &key, move || {
+                mapping.add(ctx, entry)
+            })
+    }
+
+    fn get(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        cs_id: BonsaiOrHgChangesetIds,
+    ) -> BoxFuture<Vec<BonsaiHgMappingEntry>, Error> {
+        let key = match cs_id {
+            BonsaiOrHgChangesetIds::Bonsai(ref ids) => join_keys(ids),
+            BonsaiOrHgChangesetIds::Hg(ref ids) => join_keys(ids),
+        };
+        cloned!(self.mapping);
+        self.injector
+            .inject("bonsai_hg_mapping.get", &key, move || {
+                mapping.get(ctx, repo_id, cs_id)
+            })
+    }
+}
+
+pub struct FaultInjectFilenodes {
+    filenodes: Arc<Filenodes>,
+    injector: Arc<FaultInjector>,
+}
+
+impl FaultInjectFilenodes {
+    pub fn new(filenodes: Arc<Filenodes>, injector: Arc<FaultInjector>) -> Self {
+        Self {
+            filenodes,
+            injector,
+        }
+    }
+}
+
+impl Filenodes for FaultInjectFilenodes {
+    fn add_filenodes(
+        &self,
+        ctx: CoreContext,
+        info: BoxStream<FilenodeInfo, Error>,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<(), Error> {
+        cloned!(self.filenodes);
+        // Filenodes are added in batches, which have no key
+        self.injector.inject("filenodes.add", "", move || {
+            filenodes.add_filenodes(ctx, info, repo_id)
+        })
+    }
+
+    fn get_filenode(
+        &self,
+        ctx: CoreContext,
+        path: &RepoPath,
+        filenode: HgFileNodeId,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Option<FilenodeInfo>, Error> {
+        cloned!(self.filenodes, path);
+        self.injector
+            .inject("filenodes.get", &path.to_string(), move || {
+                filenodes.get_filenode(ctx, &path, filenode, repo_id)
+            })
+    }
+
+    fn get_all_filenodes(
+        &self,
+        ctx: CoreContext,
+        path: &RepoPath,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Vec<FilenodeInfo>, Error> {
+        cloned!(self.filenodes, path);
+        self.injector
+            .inject("filenodes.get_all", &path.to_string(), move || {
+                filenodes.get_all_filenodes(ctx, &path, repo_id)
+            })
+    }
+}
@@ -29,9 +29,12 @@ mod errors;
 
 use context::CoreContext;
 use failure::prelude::*;
-use futures::{future::join_all, Future, IntoFuture, Stream};
+use futures::{
+    future::{self, join_all},
+    stream, Future, IntoFuture, Stream,
+};
 use futures_ext::{BoxFuture, BoxStream, FutureExt};
-use sql::{rusqlite::Connection as SqliteConnection, Connection};
+use sql::{rusqlite::Connection as SqliteConnection, Connection, Transaction};
 use stats::Timeseries;
 
 use filenodes::{FilenodeInfo, Filenodes};
@@ -58,6 +61,7 @@ define_stats! {
     gets_master: timeseries(RATE, SUM),
     range_gets: timeseries(RATE, SUM),
     adds: timeseries(RATE, SUM),
+    add_batches: timeseries(RATE, SUM),
 }
 
 queries! {
@@ -244,8 +248,11 @@ impl Filenodes for SqlFilenodes {
 
         filenodes
             .chunks(DEFAULT_INSERT_CHUNK_SIZE)
+            .map(|filenodes| stream::iter_ok(split_by_changeset(filenodes)))
+            .flatten()
             .and_then(move |filenodes| {
                 STATS::adds.add_value(filenodes.len() as i64);
+                STATS::add_batches.add_value(1);
 
                 let filenodes: Vec<_> = filenodes
                     .into_iter()
@@ -255,10 +262,7 @@ impl Filenodes for SqlFilenodes {
                     })
                     .collect();
 
-                ensure_paths_exists(&write_connection, repo_id, &filenodes).and_then({
-                    cloned!(write_connection);
-                    move |()| insert_filenodes(&write_connection, repo_id, &filenodes)
-                })
+                insert_filenodes(&write_connection, repo_id, filenodes)
             })
             .for_each(|()| Ok(()))
             .boxify()
@@ -332,119 +336,144 @@ impl Filenodes for SqlFilenodes {
     }
 }
 
-fn ensure_paths_exists(
-    connections: &Vec<Connection>,
-    repo_id: RepositoryId,
-    filenodes: &Vec<(FilenodeInfo, PathWithHash)>,
-) -> impl Future<Item = (), Error = Error> {
-    let mut path_rows: Vec<Vec<_>> = connections.iter().map(|_| Vec::new()).collect();
-    for &(_, ref pwh) in filenodes {
-        path_rows[pwh.shard_number(connections.len())].push((&repo_id, &pwh.path_bytes, &pwh.hash));
+/// Split a chunk of filenodes into runs that belong to the same changeset, so that a single
+/// transaction never covers more than one changeset.
+fn split_by_changeset(filenodes: Vec<FilenodeInfo>) -> Vec<Vec<FilenodeInfo>> {
+    let mut groups: Vec<Vec<FilenodeInfo>> = Vec::new();
+    for filenode in filenodes {
+        let same_changeset = match groups.last().and_then(|group| group.last()) {
+            Some(last) => last.linknode == filenode.linknode,
+            None => false,
+        };
+        if same_changeset {
+            groups
+                .last_mut()
+                .expect("groups can't be empty here")
+                .push(filenode);
+        } else {
+            groups.push(vec![filenode]);
+        }
     }
-
-    let futures: Vec<_> = connections
-        .iter()
-        .enumerate()
-        .filter_map(|(shard, connection)| {
-            if path_rows[shard].len() != 0 {
-                Some(InsertPaths::query(&connection.clone(), &path_rows[shard]))
-            } else {
-                None
-            }
-        })
-        .collect();
-    join_all(futures).map(|_| ())
+    groups
 }
 
+/// A filenode to insert, together with the hashed path of its copy source, if it has one.
+type FilenodeRow = (
+    FilenodeInfo,
+    PathWithHash,
+    Option<(PathWithHash, HgFileNodeId)>,
+);
+
 fn insert_filenodes(
     connections: &Vec<Connection>,
     repo_id: RepositoryId,
-    filenodes: &Vec<(FilenodeInfo, PathWithHash)>,
+    filenodes: Vec<(FilenodeInfo, PathWithHash)>,
 ) -> impl Future<Item = (), Error = Error> {
-    let mut filenode_rows: Vec<Vec<_>> = connections.iter().map(|_| Vec::new()).collect();
-    let mut copydata_rows: Vec<Vec<_>> = connections.iter().map(|_| Vec::new()).collect();
-    for &(ref filenode, ref pwh) in filenodes {
-        filenode_rows[pwh.shard_number(connections.len())].push((
-            &repo_id,
-            &pwh.hash,
-            &pwh.is_tree,
-            &filenode.filenode,
-            &filenode.linknode,
-            &filenode.p1,
-            &filenode.p2,
-            if filenode.copyfrom.is_some() {
-                &1i8
-            } else {
-                &0i8
-            },
-        ));
-
-        if let Some(ref copyinfo) = filenode.copyfrom {
-            let (ref frompath, ref fromnode) = copyinfo;
-            let from_pwh = PathWithHash::from_repo_path(frompath);
-            if from_pwh.is_tree != pwh.is_tree {
-                return Err(ErrorKind::InvalidCopy(filenode.path.clone(), frompath.clone()).into())
+    let mut shard_rows: Vec<Vec<FilenodeRow>> = connections.iter().map(|_| Vec::new()).collect();
+    for (filenode, pwh) in filenodes {
+        let copyfrom = match filenode.copyfrom {
+            Some((ref frompath, fromnode)) => {
+                let from_pwh = PathWithHash::from_repo_path(frompath);
+                if from_pwh.is_tree != pwh.is_tree {
+                    return Err(
+                        ErrorKind::InvalidCopy(filenode.path.clone(), frompath.clone()).into(),
+                    )
                     .into_future()
                     .left_future();
+                }
+                Some((from_pwh, fromnode))
             }
-            copydata_rows[pwh.shard_number(connections.len())].push((
-                &repo_id,
-                &pwh.hash,
-                &filenode.filenode,
-                &pwh.is_tree,
-                from_pwh.hash,
-                fromnode,
-            ));
-        }
+            None => None,
+        };
+        let shard = pwh.shard_number(connections.len());
+        shard_rows[shard].push((filenode, pwh, copyfrom));
     }
 
-    let copydata_rows: Vec<Vec<_>> = copydata_rows
+    let futures: Vec<_> = connections
         .iter()
-        .map(|shard| {
-            shard
-                .iter()
-                .map(
-                    |&(repo_id, tohash, tonode, is_tree, ref fromhash, fromnode)| {
-                        (repo_id, tohash, tonode, is_tree, fromhash, fromnode)
-                    },
-                )
-                .collect()
-        })
+        .zip(shard_rows)
+        .filter(|(_, rows)| !rows.is_empty())
+        .map(|(connection, rows)| insert_shard_filenodes(connection, repo_id, rows))
         .collect();
 
-    let copyinfo_futures: Vec<_> = connections
-        .iter()
-        .enumerate()
-        .filter_map(|(shard, connection)| {
-            if copydata_rows[shard].len() != 0 {
-                Some(InsertFixedcopyinfo::query(
-                    &connection.clone(),
-                    &copydata_rows[shard],
-                ))
-            } else {
-                None
+    join_all(futures).map(|_| ()).right_future()
+}
+
+/// Insert filenodes that all live in the same shard. Paths, filenodes and copy information are
+/// written in a single transaction, so a filenode that has copy information is never visible
+/// without it.
+fn insert_shard_filenodes(
+    connection: &Connection,
+    repo_id: RepositoryId,
+    rows: Vec<FilenodeRow>,
+) -> impl Future<Item = (), Error = Error> {
+    let rows = Arc::new(rows);
+
+    connection
+        .start_transaction()
+        .and_then({
+            cloned!(rows);
+            move |transaction| {
+                let path_rows: Vec<_> = rows
+                    .iter()
+                    .map(|&(_, ref pwh, _)| (&repo_id, &pwh.path_bytes, &pwh.hash))
+                    .collect();
+                InsertPaths::query_with_transaction(transaction, &path_rows[..])
             }
         })
-        .collect();
-    let filenode_futures: Vec<_> = connections
-        .iter()
-        .enumerate()
-        .filter_map(|(shard, connection)| {
-            if filenode_rows[shard].len() != 0 {
-                Some(InsertFilenodes::query(
-                    &connection.clone(),
-                    &filenode_rows[shard],
-                ))
-            } else {
-                None
+        .and_then({
+            cloned!(rows);
+            move |(transaction, _)| {
+                let filenode_rows: Vec<_> = rows
+                    .iter()
+                    .map(|&(ref filenode, ref pwh, ref copyfrom)| {
+                        (
+                            &repo_id,
+                            &pwh.hash,
+                            &pwh.is_tree,
+                            &filenode.filenode,
+                            &filenode.linknode,
+                            &filenode.p1,
+                            &filenode.p2,
+                            if copyfrom.is_some() { &1i8 } else { &0i8 },
+                        )
+                    })
+                    .collect();
+                InsertFilenodes::query_with_transaction(transaction, &filenode_rows[..])
             }
         })
+        .and_then(move |(transaction, _)| insert_copyinfo(transaction, repo_id, &rows))
+        .and_then(|transaction| transaction.commit())
+}
+
+fn insert_copyinfo(
+    transaction: Transaction,
+    repo_id: RepositoryId,
+    rows: &Vec<FilenodeRow>,
+) -> impl Future<Item = Transaction, Error = Error> {
+    let copyinfo_rows: Vec<_> = rows
+        .iter()
+        .filter_map(|&(ref filenode, ref pwh, ref copyfrom)| {
+            copyfrom.as_ref().map(|&(ref from_pwh, ref fromnode)| {
+                (
+                    &repo_id,
+                    &pwh.hash,
+                    &filenode.filenode,
+                    &pwh.is_tree,
+                    &from_pwh.hash,
+                    fromnode,
+                )
+            })
+        })
         .collect();
 
-    join_all(filenode_futures)
-        .join(join_all(copyinfo_futures))
-        .map(|_| ())
-        .right_future()
+    if copyinfo_rows.is_empty() {
+        future::ok(transaction).left_future()
+    } else {
+        InsertFixedcopyinfo::query_with_transaction(transaction, &copyinfo_rows[..])
+            .map(|(transaction, _)| transaction)
+            .right_future()
+    }
 }
 
 fn select_filenode(
@@ -449,6 +449,83 @@ macro_rules! filenodes_tests {
                 }).expect("test failed");
             }
 
+            #[test]
+            fn insert_many_files_from_several_changesets() {
+                async_unit::tokio_unit_test(|| -> Result<_, !> {
+                    let ctx = CoreContext::test_mock();
+                    let filenodes = &$create_db();
+
+                    // Enough filenodes to span several insert batches, from two changesets,
+                    // some of them copied.
+                    let mut to_insert = vec![copied_from_filenode()];
+                    for i in 0..250 {
+                        let copyfrom = if i % 10 == 0 {
+                            Some((RepoPath::file("copiedfrom").unwrap(), ONES_FNID))
+                        } else {
+                            None
+                        };
+                        to_insert.push(FilenodeInfo {
+                            path: RepoPath::file(format!("dir/file{}", i).as_str()).unwrap(),
+                            filenode: THREES_FNID,
+                            p1: None,
+                            p2: None,
+                            copyfrom,
+                            linknode: if i < 120 { TWOS_CSID } else { THREES_CSID },
+                        });
+                    }
+
+                    do_add_filenodes(ctx.clone(), filenodes, to_insert.clone(), REPO_ZERO);
+
+                    for filenode in to_insert {
+                        assert_filenode(
+                            ctx.clone(),
+                            filenodes,
+                            &filenode.path.clone(),
+                            filenode.filenode,
+                            REPO_ZERO,
+                            filenode,
+                        );
+                    }
+                    Ok(())
+                }).expect("test failed");
+            }
+
+            #[test]
+            fn insert_invalid_copy_writes_nothing() {
+                async_unit::tokio_unit_test(|| -> Result<_, !> {
+                    let ctx = CoreContext::test_mock();
+                    let filenodes = &$create_db();
+
+                    let invalid_copy = FilenodeInfo {
+                        path: RepoPath::file("copiedto").unwrap(),
+                        filenode: TWOS_FNID,
+                        p1: None,
+                        p2: None,
+                        copyfrom: Some((RepoPath::dir("copiedfrom").unwrap(), ONES_FNID)),
+                        linknode: TWOS_CSID,
+                    };
+
+                    let stream = futures::stream::iter_ok(vec![
+                        file_b_first_filenode(),
+                        invalid_copy,
+                    ])
+                    .boxify();
+                    assert!(filenodes
+                        .add_filenodes(ctx.clone(), stream, REPO_ZERO)
+                        .wait()
+                        .is_err());
+
+                    assert_no_filenode(
+                        ctx.clone(),
+                        filenodes,
+                        &RepoPath::file("b").unwrap(),
+                        TWOS_FNID,
+                        REPO_ZERO,
+                    );
+                    Ok(())
+                }).expect("test failed");
+            }
+
             #[test]
             fn insert_copied_file_to_different_repo() {
                 async_unit::tokio_unit_test(|| -> Result<_, !> {
@@ -0,0 +1,8 @@
+CREATE TABLE `hg_derivation_queue` (
+  `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `cs_id` VARBINARY(32) NOT NULL,
+  `add_timestamp` BIGINT NOT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `hg_derivation_queue_cs` (`repo_id`, `cs_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
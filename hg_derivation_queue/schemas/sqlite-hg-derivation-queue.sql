@@ -0,0 +1,7 @@
+CREATE TABLE `hg_derivation_queue` (
+  `id` INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `cs_id` VARBINARY(32) NOT NULL,
+  `add_timestamp` BIGINT NOT NULL,
+  UNIQUE (`repo_id`, `cs_id`)
+);
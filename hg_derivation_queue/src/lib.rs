@@ -0,0 +1,373 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Durable queue of bonsai changesets whose hg changesets and filenodes haven't been derived yet.
+//!
+//! With deferred derivation a push is acknowledged as soon as its bonsai changesets are saved and
+//! the bookmark is moved. The changesets are added to this queue, and background workers derive
+//! them later. Readers don't have to wait for the workers: `get_hg_from_bonsai_changeset` derives
+//! a missing hg changeset on demand, and the worker then finds it already done.
+
+#![deny(warnings)]
+
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate blobrepo;
+extern crate cloned;
+extern crate context;
+extern crate futures_ext;
+extern crate mononoke_types;
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate sql;
+extern crate sql_ext;
+#[macro_use]
+extern crate stats;
+extern crate tokio;
+
+use blobrepo::BlobRepo;
+use cloned::cloned;
+use context::CoreContext;
+use failure::{format_err, Error};
+use futures::future::{self, loop_fn, Loop};
+use futures::{stream, Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use mononoke_types::{ChangesetId, DateTime, RepositoryId, Timestamp};
+use slog::Logger;
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+pub use sql_ext::SqlConstructors;
+use stats::Timeseries;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// How many changesets a worker takes from the queue at once.
+const WORKER_BATCH_SIZE: usize = 100;
+
+define_stats! {
+    prefix = "mononoke.hg_derivation_queue";
+    adds: timeseries(RATE, SUM),
+    iters: timeseries(RATE, SUM),
+    dels: timeseries(RATE, SUM),
+    derived: timeseries(RATE, SUM),
+    worker_errors: timeseries(RATE, SUM),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct HgDerivationQueueEntry {
+    pub repo_id: RepositoryId,
+    pub cs_id: ChangesetId,
+    pub timestamp: DateTime,
+    pub id: Option<u64>,
+}
+
+impl HgDerivationQueueEntry {
+    pub fn new(repo_id: RepositoryId, cs_id: ChangesetId, timestamp: DateTime) -> Self {
+        Self {
+            repo_id,
+            cs_id,
+            timestamp,
+            id: None,
+        }
+    }
+}
+
+pub trait HgDerivationQueue: Send + Sync {
+    /// Add entries to the queue. Changesets that are already queued are ignored.
+    fn add(&self, ctx: CoreContext, entries: Vec<HgDerivationQueueEntry>) -> BoxFuture<(), Error>;
+
+    /// Returns at most `limit` of the oldest entries for the repo, oldest first.
+    fn iter(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        limit: usize,
+    ) -> BoxFuture<Vec<HgDerivationQueueEntry>, Error>;
+
+    fn del(&self, ctx: CoreContext, entries: Vec<HgDerivationQueueEntry>) -> BoxFuture<(), Error>;
+
+    /// The number of entries queued for the repo, and when the oldest of them was added.
+    fn get_backlog(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<(u64, Option<DateTime>), Error>;
+}
+
+impl HgDerivationQueue for Arc<HgDerivationQueue> {
+    fn add(&self, ctx: CoreContext, entries: Vec<HgDerivationQueueEntry>) -> BoxFuture<(), Error> {
+        (**self).add(ctx, entries)
+    }
+
+    fn iter(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        limit: usize,
+    ) -> BoxFuture<Vec<HgDerivationQueueEntry>, Error> {
+        (**self).iter(ctx, repo_id, limit)
+    }
+
+    fn del(&self, ctx: CoreContext, entries: Vec<HgDerivationQueueEntry>) -> BoxFuture<(), Error> {
+        (**self).del(ctx, entries)
+    }
+
+    fn get_backlog(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<(u64, Option<DateTime>), Error> {
+        (**self).get_backlog(ctx, repo_id)
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlHgDerivationQueue {
+    write_connection: Connection,
+    read_connection: Connection,
+    read_master_connection: Connection,
+}
+
+queries! {
+    write InsertEntries(values: (
+        repo_id: RepositoryId,
+        cs_id: ChangesetId,
+        timestamp: Timestamp,
+    )) {
+        insert_or_ignore,
+        "{insert_or_ignore}
+         INTO hg_derivation_queue (repo_id, cs_id, add_timestamp)
+         VALUES {values}"
+    }
+
+    write DeleteEntry(id: u64) {
+        none,
+        "DELETE FROM hg_derivation_queue
+         WHERE id = {id}"
+    }
+
+    read GetOldestEntries(repo_id: RepositoryId, limit: usize) -> (
+        RepositoryId,
+        ChangesetId,
+        Timestamp,
+        u64,
+    ) {
+        "SELECT repo_id, cs_id, add_timestamp, id
+         FROM hg_derivation_queue
+         WHERE repo_id = {repo_id}
+         ORDER BY id ASC
+         LIMIT {limit}"
+    }
+
+    read GetBacklog(repo_id: RepositoryId) -> (u64, Option<Timestamp>) {
+        "SELECT COUNT(*), MIN(add_timestamp)
+         FROM hg_derivation_queue
+         WHERE repo_id = {repo_id}"
+    }
+}
+
+impl SqlConstructors for SqlHgDerivationQueue {
+    fn from_connections(
+        write_connection: Connection,
+        read_connection: Connection,
+        read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_connection,
+            read_master_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-hg-derivation-queue.sql")
+    }
+}
+
+impl SqlMigrations for SqlHgDerivationQueue {
+    const STORE: &'static str = "hg_derivation_queue";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the hg_derivation_queue table",
+            sqlite: "CREATE TABLE `hg_derivation_queue` (
+                       `id` INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                       `repo_id` INT UNSIGNED NOT NULL,
+                       `cs_id` VARBINARY(32) NOT NULL,
+                       `add_timestamp` BIGINT NOT NULL,
+                       UNIQUE (`repo_id`, `cs_id`)
+                     );",
+            mysql: "CREATE TABLE `hg_derivation_queue` (
+                      `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+                      `repo_id` INT UNSIGNED NOT NULL,
+                      `cs_id` VARBINARY(32) NOT NULL,
+                      `add_timestamp` BIGINT NOT NULL,
+                      PRIMARY KEY (`id`),
+                      UNIQUE KEY `hg_derivation_queue_cs` (`repo_id`, `cs_id`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+impl HgDerivationQueue for SqlHgDerivationQueue {
+    fn add(
+        &self,
+        _ctx: CoreContext,
+        entries: Vec<HgDerivationQueueEntry>,
+    ) -> BoxFuture<(), Error> {
+        STATS::adds.add_value(entries.len() as i64);
+        if entries.is_empty() {
+            return future::ok(()).boxify();
+        }
+
+        let rows: Vec<_> = entries
+            .into_iter()
+            .map(|entry| (entry.repo_id, entry.cs_id, Timestamp::from(entry.timestamp)))
+            .collect();
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|(repo_id, cs_id, timestamp)| (repo_id, cs_id, timestamp))
+            .collect();
+
+        InsertEntries::query(&self.write_connection, &rows[..])
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn iter(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        limit: usize,
+    ) -> BoxFuture<Vec<HgDerivationQueueEntry>, Error> {
+        STATS::iters.add_value(1);
+
+        GetOldestEntries::query(&self.read_master_connection, &repo_id, &limit)
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(repo_id, cs_id, timestamp, id)| HgDerivationQueueEntry {
+                        repo_id,
+                        cs_id,
+                        timestamp: timestamp.into(),
+                        id: Some(id),
+                    })
+                    .collect()
+            })
+            .boxify()
+    }
+
+    fn del(
+        &self,
+        _ctx: CoreContext,
+        entries: Vec<HgDerivationQueueEntry>,
+    ) -> BoxFuture<(), Error> {
+        STATS::dels.add_value(1);
+
+        let ids: Result<Vec<u64>, Error> = entries
+            .into_iter()
+            .map(|entry| {
+                entry.id.ok_or_else(|| {
+                    format_err!("HgDerivationQueueEntry must contain `id` to be able to delete it")
+                })
+            })
+            .collect();
+        ids.into_future()
+            .and_then({
+                cloned!(self.write_connection);
+                move |ids| {
+                    future::join_all(ids.into_iter().map({
+                        cloned!(write_connection);
+                        move |id| DeleteEntry::query(&write_connection, &id)
+                    }))
+                }
+            })
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn get_backlog(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<(u64, Option<DateTime>), Error> {
+        GetBacklog::query(&self.read_connection, &repo_id)
+            .map(|rows| {
+                rows.into_iter()
+                    .next()
+                    .map_or((0, None), |(count, oldest)| {
+                        (count, oldest.map(DateTime::from))
+                    })
+            })
+            .boxify()
+    }
+}
+
+/// Derive hg changesets and filenodes for up to `limit` of the oldest queued changesets, and
+/// remove them from the queue. Returns the number of changesets processed.
+pub fn derive_queued_changesets(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    queue: Arc<HgDerivationQueue>,
+    limit: usize,
+) -> BoxFuture<usize, Error> {
+    queue
+        .iter(ctx.clone(), repo.get_repoid(), limit)
+        .and_then(move |entries| {
+            let count = entries.len();
+            // Entries come out in the order they were queued, so parents are normally derived
+            // before their children. If not, deriving a child derives its parents too.
+            stream::iter_ok(entries.clone())
+                .for_each({
+                    cloned!(ctx, repo);
+                    move |entry| {
+                        repo.get_hg_from_bonsai_changeset(ctx.clone(), entry.cs_id)
+                            .map(|_| STATS::derived.add_value(1))
+                    }
+                })
+                .and_then(move |()| queue.del(ctx, entries))
+                .map(move |()| count)
+        })
+        .boxify()
+}
+
+/// Process the queue for `repo` forever. When the queue is empty, or processing fails, the
+/// worker waits for `poll_interval` before trying again.
+pub fn run_derivation_worker(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    queue: Arc<HgDerivationQueue>,
+    logger: Logger,
+    poll_interval: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    loop_fn((), move |()| {
+        derive_queued_changesets(ctx.clone(), repo.clone(), queue.clone(), WORKER_BATCH_SIZE).then(
+            {
+                cloned!(logger);
+                move |result| {
+                    let idle = match result {
+                        Ok(count) => count == 0,
+                        Err(err) => {
+                            STATS::worker_errors.add_value(1);
+                            warn!(logger, "failed to derive queued hg changesets: {:?}", err);
+                            true
+                        }
+                    };
+                    if idle {
+                        Delay::new(Instant::now() + poll_interval)
+                            .then(|_| Ok(Loop::Continue(())))
+                            .left_future()
+                    } else {
+                        future::ok(Loop::Continue(())).right_future()
+                    }
+                }
+            },
+        )
+    })
+}
@@ -0,0 +1,90 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the hg derivation queue.
+
+#![deny(warnings)]
+
+extern crate context;
+extern crate futures;
+extern crate hg_derivation_queue;
+extern crate mononoke_types;
+extern crate mononoke_types_mocks;
+extern crate tokio;
+
+use context::CoreContext;
+use hg_derivation_queue::{
+    HgDerivationQueue, HgDerivationQueueEntry, SqlConstructors, SqlHgDerivationQueue,
+};
+use mononoke_types::{DateTime, RepositoryId};
+use mononoke_types_mocks::changesetid::{ONES_CSID, THREES_CSID, TWOS_CSID};
+
+#[test]
+fn test_simple() {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    let ctx = CoreContext::test_mock();
+    let queue = SqlHgDerivationQueue::with_sqlite_in_memory().unwrap();
+    let repo_id = RepositoryId::new(137);
+    let other_repo_id = RepositoryId::new(138);
+
+    let t0 = DateTime::from_rfc3339("2019-03-01T12:00:00.00Z").unwrap();
+    let t1 = DateTime::from_rfc3339("2019-03-01T12:01:00.00Z").unwrap();
+    let entry0 = HgDerivationQueueEntry::new(repo_id, ONES_CSID, t0);
+    let entry1 = HgDerivationQueueEntry::new(repo_id, TWOS_CSID, t0);
+    let entry2 = HgDerivationQueueEntry::new(repo_id, THREES_CSID, t1);
+    let other_entry = HgDerivationQueueEntry::new(other_repo_id, ONES_CSID, t1);
+
+    // add
+    rt.block_on(queue.add(ctx.clone(), vec![entry0.clone(), entry1.clone()]))
+        .expect("Adding entries failed");
+    rt.block_on(queue.add(ctx.clone(), vec![entry2.clone(), entry0.clone()]))
+        .expect("Adding an already queued changeset should be ignored");
+    rt.block_on(queue.add(ctx.clone(), vec![other_entry.clone()]))
+        .expect("Adding an entry for another repo failed");
+
+    // backlog
+    let backlog = rt
+        .block_on(queue.get_backlog(ctx.clone(), repo_id))
+        .expect("Getting the backlog failed");
+    assert_eq!(backlog, (3, Some(t0)));
+
+    // iter returns the oldest entries first
+    let entries = rt
+        .block_on(queue.iter(ctx.clone(), repo_id, 2))
+        .expect("Iter failed");
+    let cs_ids: Vec<_> = entries.iter().map(|entry| entry.cs_id).collect();
+    assert_eq!(cs_ids, vec![ONES_CSID, TWOS_CSID]);
+
+    // del
+    rt.block_on(queue.del(ctx.clone(), entries))
+        .expect("Deleting entries failed");
+    let entries = rt
+        .block_on(queue.iter(ctx.clone(), repo_id, 100))
+        .expect("Iter failed");
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].cs_id, THREES_CSID);
+    assert_eq!(entries[0].timestamp, t1);
+
+    // other repos are unaffected
+    let entries = rt
+        .block_on(queue.iter(ctx.clone(), other_repo_id, 100))
+        .expect("Iter failed");
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].cs_id, ONES_CSID);
+
+    let backlog = rt
+        .block_on(queue.get_backlog(ctx.clone(), repo_id))
+        .expect("Getting the backlog failed");
+    assert_eq!(backlog, (1, Some(t1)));
+    let backlog = rt
+        .block_on(queue.get_backlog(ctx.clone(), RepositoryId::new(139)))
+        .expect("Getting the backlog failed");
+    assert_eq!(backlog, (0, None));
+
+    // entries without ids can't be deleted
+    assert!(rt.block_on(queue.del(ctx.clone(), vec![entry2])).is_err());
+}
@@ -126,13 +126,17 @@ impl<'a> StdioRelay<'a> {
                 .map(|hostname| hostname.to_owned())
         };
 
-        let preamble = Preamble::new(
+        let mut preamble = Preamble::new(
             self.repo.to_owned(),
             session_uuid.clone(),
             unix_username,
             source_hostname,
             SshEnvVars::new_from_env(),
         );
+        // Automation declares that its requests can wait behind interactive ones
+        if let Ok(qos) = var("MONONOKE_QOS") {
+            preamble.misc.insert("qos".to_owned(), qos);
+        }
 
         scuba_logger.add_preamble(&preamble);
 
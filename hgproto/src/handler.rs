@@ -5,6 +5,7 @@
 // GNU General Public License version 2 or any later version.
 
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use failure::FutureFailureErrorExt;
@@ -38,9 +39,27 @@ struct HgProtoHandlerInner<H, Dec, Enc> {
     reqdec: Dec,
     respenc: Enc,
     wireproto_calls: Arc<Mutex<Vec<String>>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Counts a request as in flight until its responses are sent, or dropped
+struct InFlightRequest(Arc<AtomicUsize>);
+
+impl InFlightRequest {
+    fn new(in_flight: &Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightRequest(in_flight.clone())
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl HgProtoHandler {
+    /// `in_flight` is kept up to date with the number of requests that are being handled
     pub fn new<'a, In, H, Dec, Enc>(
         ctx: CoreContext,
         input: In,
@@ -48,6 +67,7 @@ impl HgProtoHandler {
         reqdec: Dec,
         respenc: Enc,
         wireproto_calls: Arc<Mutex<Vec<String>>>,
+        in_flight: Arc<AtomicUsize>,
         hook_manager: Arc<HookManager>,
     ) -> Self
     where
@@ -63,6 +83,7 @@ impl HgProtoHandler {
             reqdec,
             respenc,
             wireproto_calls,
+            in_flight,
         });
 
         HgProtoHandler {
@@ -123,12 +144,17 @@ where
                                 .into())
                             }),
                             Some(req) => {
+                                let in_flight = InFlightRequest::new(&handler.in_flight);
                                 let (resps, remainder) =
                                     handle_request(req, remainder, handler.clone());
                                 Either::B(ok((
                                     Some(
                                         resps
-                                            .map(move |resp| handler.respenc.encode(resp))
+                                            .map(move |resp| {
+                                                // Held until the responses are all sent
+                                                let _ = &in_flight;
+                                                handler.respenc.encode(resp)
+                                            })
                                             .flatten()
                                             .boxify(),
                                     ),
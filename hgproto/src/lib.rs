@@ -158,6 +158,13 @@ pub struct GetbundleArgs {
     pub listkeys: Vec<Vec<u8>>,
     /// phases: Boolean indicating whether phases data is requested
     pub phases: bool,
+    /// resumable: Boolean indicating whether the client wants a token to resume this pull if it's
+    /// interrupted
+    pub resumable: bool,
+    /// Token of an interrupted pull to resume, instead of finding what the client is missing
+    pub resumetoken: Option<String>,
+    /// Number of changesets of the resumed pull that the client already has
+    pub resumefrom: usize,
 }
 
 impl Debug for GetbundleArgs {
@@ -182,6 +189,9 @@ impl Debug for GetbundleArgs {
             .field("bundlecaps", &bcaps)
             .field("listkeys", &listkeys)
             .field("phases", &self.phases)
+            .field("resumable", &self.resumable)
+            .field("resumetoken", &self.resumetoken)
+            .field("resumefrom", &self.resumefrom)
             .finish()
     }
 }
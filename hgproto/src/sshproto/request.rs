@@ -81,6 +81,16 @@ named!(
     })
 );
 
+/// Assumption: input is complete
+/// We can't use 'integer' defined above as it reads until a non digit character
+named!(
+    number<usize>,
+    map_res!(take_while1!(is_digit), |s| -> Result<usize> {
+        let s = str::from_utf8(s)?;
+        Ok(usize::from_str(s)?)
+    })
+);
+
 named!(
     batch_param_comma_separated<Bytes>,
     map_res!(
@@ -544,6 +554,9 @@ fn parse_with_params(
                 bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?.into_iter().collect(),
                 listkeys: parseval_default(&kv, "listkeys", commavalues)?,
                 phases: parseval_default(&kv, "phases", boolean)?,
+                resumable: parseval_default(&kv, "resumable", boolean)?,
+                resumetoken: parseval_option(&kv, "resumetoken", utf8_string_complete)?,
+                resumefrom: parseval_default(&kv, "resumefrom", number)?,
             })))
         | command!("heads", Heads, parse_params, {})
         | command!("hello", Hello, parse_params, {})
@@ -1246,6 +1259,9 @@ mod test_parse {
                 bundlecaps: hashset![],
                 listkeys: vec![],
                 phases: false,
+                resumable: false,
+                resumetoken: None,
+                resumefrom: 0,
             })),
         );
 
@@ -1273,6 +1289,34 @@ mod test_parse {
                 bundlecaps: hashset![b"cap1".to_vec(), b"CAP2".to_vec(), b"cap3".to_vec()],
                 listkeys: vec![b"key1".to_vec(), b"key2".to_vec()],
                 phases: true,
+                resumable: false,
+                resumetoken: None,
+                resumefrom: 0,
+            })),
+        );
+
+        // resuming an interrupted pull
+        let inp = "getbundle\n\
+                   * 4\n\
+                   heads 40\n\
+                   1111111111111111111111111111111111111111\
+                   resumable 1\n\
+                   1\
+                   resumetoken 8\n\
+                   abcd-123\
+                   resumefrom 3\n\
+                   120";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: vec![hash_ones()],
+                common: vec![],
+                bundlecaps: hashset![],
+                listkeys: vec![],
+                phases: false,
+                resumable: true,
+                resumetoken: Some("abcd-123".to_string()),
+                resumefrom: 120,
             })),
         );
     }
@@ -11,6 +11,7 @@ use bytes::{Bytes, BytesMut};
 use futures::{stream, Stream};
 use futures_ext::StreamExt;
 use itertools::Itertools;
+use mercurial_types::percent_encode;
 
 use handler::OutputStream;
 use {batch, Response, SingleResponse};
@@ -154,10 +155,15 @@ fn encode_cmd(response: SingleResponse) -> Bytes {
             bytes.freeze()
         }
 
-        Branchmap(_res) => {
-            // We have no plans to support mercurial branches and hence no plans for branchmap,
-            // so just return fake response.
-            Bytes::new()
+        Branchmap(branches) => {
+            let mut out = Vec::new();
+
+            for (branch, heads) in branches.into_iter().sorted_by(|a, b| a.0.cmp(&b.0)) {
+                write!(out, "{} ", percent_encode(&branch)).expect("write to vec failed");
+                separated(&mut out, heads, " ").expect("write to vec failed");
+            }
+
+            Bytes::from(out)
         }
 
         StreamOutShallow(res) => res,
@@ -167,3 +173,23 @@ fn encode_cmd(response: SingleResponse) -> Bytes {
         r => panic!("Response for {:?} unimplemented", r),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+
+    #[test]
+    fn test_encode_branchmap() {
+        let branches = hashmap! {
+            "default".to_string() => hashset! { ONES_HASH },
+            "a b".to_string() => hashset! { TWOS_HASH },
+        };
+        let expected = format!("a%20b {}\ndefault {}\n", TWOS_HASH, ONES_HASH);
+        assert_eq!(
+            encode_cmd(SingleResponse::Branchmap(branches)),
+            Bytes::from(expected)
+        );
+    }
+}
@@ -8,23 +8,27 @@
 
 #![deny(warnings)]
 
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
 use blobrepo::{BlobRepo, HgBlobChangeset};
 use bytes::Bytes;
 use cloned::cloned;
 use context::CoreContext;
 use failure_ext::Error;
 use futures::{finished, Future, Stream};
-use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
 use hooks::{ChangedFileType, ChangesetStore, FileContentStore};
-use mercurial_types::manifest_utils;
+use mercurial_types::manifest_utils::{self, ChangedEntry, Pruner};
 use mercurial_types::{
     manifest::get_empty_manifest, Changeset, HgChangesetId, HgFileNodeId, MPath,
 };
-use mononoke_types::{FileContents, FileType};
+use metaconfig_types::HookPathFilter;
+use mononoke_types::{ContentId, FileType};
+use uuid::Uuid;
 
-// TODO this can cache file content locally to prevent unnecessary lookup of changeset,
-// manifest and walk of manifest each time
-// It's likely that multiple hooks will want to see the same content for the same changeset
+/// Looks up every file in the manifest of its changeset each time it's asked for. See
+/// `MemoizingFileContentStore` for a store that remembers the files it found.
 pub struct BlobRepoFileContentStore {
     pub repo: BlobRepo,
 }
@@ -45,6 +49,29 @@ fn find_file_in_repo(
         })
 }
 
+/// Prunes the changed entries that no path filter is interested in: files that don't match any,
+/// and directories that can't contain a file that does, so that they aren't walked
+#[derive(Clone)]
+struct PathFilterPruner {
+    path_filters: Vec<HookPathFilter>,
+}
+
+impl Pruner for PathFilterPruner {
+    fn keep(&mut self, entry: &ChangedEntry) -> bool {
+        let path = match entry.get_full_path() {
+            Some(path) => String::from_utf8_lossy(&path.to_vec()).into_owned(),
+            None => return true,
+        };
+        if entry.status.is_tree() {
+            self.path_filters
+                .iter()
+                .any(|filter| filter.may_match_below(&path))
+        } else {
+            self.path_filters.iter().any(|filter| filter.matches(&path))
+        }
+    }
+}
+
 impl FileContentStore for BlobRepoFileContentStore {
     fn get_file_content(
         &self,
@@ -58,7 +85,7 @@ impl FileContentStore for BlobRepoFileContentStore {
                 move |opt| match opt {
                     Some((_, hash)) => repo
                         .get_file_content(ctx, hash)
-                        .map(|FileContents::Bytes(bytes)| Some(bytes))
+                        .map(|contents| Some(contents.into_bytes()))
                         .left_future(),
                     None => finished(None).right_future(),
                 }
@@ -93,6 +120,23 @@ impl FileContentStore for BlobRepoFileContentStore {
             })
             .boxify()
     }
+
+    fn get_file_content_id(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<ContentId>, Error> {
+        find_file_in_repo(ctx.clone(), self.repo.clone(), changesetid, path)
+            .and_then({
+                cloned!(self.repo);
+                move |opt| match opt {
+                    Some((_, hash)) => repo.get_file_content_id(ctx, hash).map(Some).left_future(),
+                    None => finished(None).right_future(),
+                }
+            })
+            .boxify()
+    }
 }
 
 impl BlobRepoFileContentStore {
@@ -101,6 +145,202 @@ impl BlobRepoFileContentStore {
     }
 }
 
+/// What remembering a file lookup is counted as against the size cap, on top of the path
+const LOOKUP_BYTES: usize = 64;
+
+type FileLookup = Option<(FileType, HgFileNodeId)>;
+
+/// What was found, by the session it was found for
+#[derive(Default)]
+struct Memo {
+    lookups: HashMap<(Uuid, HgChangesetId, MPath), FileLookup>,
+    contents: HashMap<(Uuid, HgFileNodeId), Bytes>,
+    bytes: usize,
+}
+
+impl Memo {
+    /// Forget everything if remembering `bytes` more would go over `max_bytes`
+    fn make_room(&mut self, bytes: usize, max_bytes: usize) -> bool {
+        if self.bytes + bytes > max_bytes {
+            self.lookups.clear();
+            self.contents.clear();
+            self.bytes = 0;
+        }
+        bytes <= max_bytes
+    }
+}
+
+/// A file content store that remembers which filenode a path is in a changeset, and the content
+/// of the filenodes it fetched. Hooks run on the same few changesets and often read the same
+/// files, so most of their requests during a push are answered without walking a manifest or
+/// fetching a blob again. What it remembers is only seen by the session it was fetched for, i.e.
+/// by the hooks of one push, so that content replaced by a tombstone since an earlier push isn't
+/// served from its memo. Once it remembers more than `max_bytes`, it forgets everything and
+/// starts over.
+#[derive(Clone)]
+pub struct MemoizingFileContentStore {
+    repo: BlobRepo,
+    max_bytes: usize,
+    memo: Arc<Mutex<Memo>>,
+}
+
+impl MemoizingFileContentStore {
+    pub fn new(repo: BlobRepo, max_bytes: usize) -> Self {
+        Self {
+            repo,
+            max_bytes,
+            memo: Arc::new(Mutex::new(Memo::default())),
+        }
+    }
+
+    fn find_file(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<FileLookup, Error> {
+        let key = (*ctx.session(), changesetid, path);
+        let memoized = self
+            .memo
+            .lock()
+            .expect("lock poisoned")
+            .lookups
+            .get(&key)
+            .cloned();
+        if let Some(lookup) = memoized {
+            return finished(lookup).boxify();
+        }
+
+        let (_, changesetid, path) = key.clone();
+        let this = self.clone();
+        find_file_in_repo(ctx, self.repo.clone(), changesetid, path)
+            .map(move |lookup| {
+                let mut memo = this.memo.lock().expect("lock poisoned");
+                let bytes = LOOKUP_BYTES + key.2.len();
+                if memo.make_room(bytes, this.max_bytes) {
+                    memo.lookups.insert(key, lookup);
+                    memo.bytes += bytes;
+                }
+                lookup
+            })
+            .boxify()
+    }
+
+    fn get_content(&self, ctx: CoreContext, filenode: HgFileNodeId) -> BoxFuture<Bytes, Error> {
+        let key = (*ctx.session(), filenode);
+        let memoized = self
+            .memo
+            .lock()
+            .expect("lock poisoned")
+            .contents
+            .get(&key)
+            .cloned();
+        if let Some(content) = memoized {
+            return finished(content).boxify();
+        }
+
+        let this = self.clone();
+        self.repo
+            .get_file_content(ctx, filenode)
+            .map(move |content| {
+                let content = content.into_bytes();
+                let mut memo = this.memo.lock().expect("lock poisoned");
+                if memo.make_room(content.len(), this.max_bytes) {
+                    memo.contents.insert(key, content.clone());
+                    memo.bytes += content.len();
+                }
+                content
+            })
+            .boxify()
+    }
+}
+
+impl FileContentStore for MemoizingFileContentStore {
+    fn get_file_content(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        self.find_file(ctx.clone(), changesetid, path)
+            .and_then({
+                let this = self.clone();
+                move |lookup| match lookup {
+                    Some((_, filenode)) => this.get_content(ctx, filenode).map(Some).left_future(),
+                    None => finished(None).right_future(),
+                }
+            })
+            .boxify()
+    }
+
+    fn get_file_type(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<FileType>, Error> {
+        self.find_file(ctx, changesetid, path)
+            .map(|lookup| lookup.map(|(file_type, _)| file_type))
+            .boxify()
+    }
+
+    fn get_file_size(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<u64>, Error> {
+        self.find_file(ctx.clone(), changesetid, path)
+            .and_then({
+                let this = self.clone();
+                move |lookup| match lookup {
+                    Some((_, filenode)) => {
+                        let memoized = this
+                            .memo
+                            .lock()
+                            .expect("lock poisoned")
+                            .contents
+                            .get(&(*ctx.session(), filenode))
+                            .map(|content| content.len() as u64);
+                        match memoized {
+                            Some(size) => finished(Some(size)).left_future(),
+                            // Not worth fetching the content for, as hooks that check sizes
+                            // usually do so to avoid reading large files
+                            None => this
+                                .repo
+                                .get_file_size(ctx, filenode)
+                                .map(Some)
+                                .right_future(),
+                        }
+                        .left_future()
+                    }
+                    None => finished(None).right_future(),
+                }
+            })
+            .boxify()
+    }
+
+    fn get_file_content_id(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<ContentId>, Error> {
+        self.find_file(ctx.clone(), changesetid, path)
+            .and_then({
+                cloned!(self.repo);
+                move |lookup| match lookup {
+                    Some((_, filenode)) => repo
+                        .get_file_content_id(ctx, filenode)
+                        .map(Some)
+                        .left_future(),
+                    None => finished(None).right_future(),
+                }
+            })
+            .boxify()
+    }
+}
+
 impl ChangesetStore for BlobRepoChangesetStore {
     fn get_changeset_by_changesetid(
         &self,
@@ -114,6 +354,7 @@ impl ChangesetStore for BlobRepoChangesetStore {
         &self,
         ctx: CoreContext,
         changesetid: HgChangesetId,
+        path_filters: Option<Vec<HookPathFilter>>,
     ) -> BoxFuture<Vec<(String, ChangedFileType)>, Error> {
         cloned!(self.repo);
         self.repo
@@ -141,7 +382,22 @@ impl ChangesetStore for BlobRepoChangesetStore {
                 }
             })
             .and_then(move |(mf, p_mf)| {
-                manifest_utils::changed_file_stream(ctx, &mf, &p_mf, None)
+                let changed_files = match path_filters {
+                    Some(path_filters) => manifest_utils::changed_entry_stream_with_pruner(
+                        ctx,
+                        &mf,
+                        &p_mf,
+                        None,
+                        PathFilterPruner { path_filters },
+                        None,
+                    )
+                    .filter(|changed_entry| !changed_entry.status.is_tree())
+                    .left_stream(),
+                    None => {
+                        manifest_utils::changed_file_stream(ctx, &mf, &p_mf, None).right_stream()
+                    }
+                };
+                changed_files
                     .map(|changed_entry| {
                         let path = changed_entry
                             .get_full_path()
@@ -7,33 +7,50 @@
 #![deny(warnings)]
 
 use bookmarks::Bookmark;
+use bytes::Bytes;
 use context::CoreContext;
 use failure_ext::Error;
 use fixtures::many_files_dirs;
-use futures::future::finished;
-use futures::Future;
+use futures::future::{finished, join_all};
 use futures::{stream, Stream};
+use futures::{Future, IntoFuture};
 use futures_ext::{BoxFuture, FutureExt};
 use hooks::{
-    hook_loader::load_hooks, ChangedFileType, ErrorKind, FileHookExecutionID, Hook, HookChangeset,
-    HookChangesetParents, HookContext, HookExecution, HookFile, HookManager, HookRejectionInfo,
+    file_content_id,
+    hook_loader::{load_hooks, load_hooks_with_registry},
+    hook_registry::HookRegistry,
+    run_queued_post_commit_hooks, ChangedFileType, ErrorKind, FileHookExecutionID, Hook,
+    HookChangeset, HookChangesetParents, HookContext, HookExecution, HookFile, HookManager,
+    HookRejectionInfo, HookResultCache, HookResultStore, InMemoryStateProvider, PostCommitQueue,
+    PostCommitQueueEntry, PostCommitRetries, SqlHookResultStore, SqlPostCommitQueue,
+};
+use hooks::{FileContentStore, InMemoryChangesetStore, InMemoryFileContentStore};
+use hooks_content_stores::{
+    BlobRepoChangesetStore, BlobRepoFileContentStore, MemoizingFileContentStore,
 };
-use hooks::{InMemoryChangesetStore, InMemoryFileContentStore};
-use hooks_content_stores::{BlobRepoChangesetStore, BlobRepoFileContentStore};
 use maplit::{hashmap, hashset};
 use mercurial_types::{HgChangesetId, MPath};
 use metaconfig_types::{
-    BookmarkOrRegex, BookmarkParams, Bundle2ReplayParams, HookParams, HookType, RepoConfig,
-    RepoReadOnly, RepoType,
+    BookmarkOrRegex, BookmarkParams, Bundle2ReplayParams, HookBypass, HookConfig, HookLimits,
+    HookParams, HookPathFilter, HookType, RepoConfig, RepoReadOnly, RepoType,
 };
-use mononoke_types::FileType;
+use mononoke_types::{DateTime, FileType, RepositoryId};
 use regex::Regex;
+use scuba_ext::ScubaSampleBuilder;
 use slog::{o, Logger};
 use slog::{Discard, Drain};
+use sql::{rusqlite::Connection as SqliteConnection, Connection};
+use sql_ext::migrations::SqlMigrations;
+use sql_ext::SqlConstructors;
+use sshrelay::SshEnvVars;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::TraceContext;
+use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 struct FnChangesetHook {
@@ -66,6 +83,52 @@ fn always_rejecting_changeset_hook() -> Box<Hook<HookChangeset>> {
     Box::new(FnChangesetHook::new(f))
 }
 
+#[derive(Clone, Debug)]
+struct CountingChangesetHook {
+    runs: Arc<AtomicUsize>,
+    version: Option<String>,
+}
+
+impl Hook<HookChangeset> for CountingChangesetHook {
+    fn run(
+        &self,
+        _ctx: CoreContext,
+        _context: HookContext<HookChangeset>,
+    ) -> BoxFuture<HookExecution, Error> {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        finished(default_rejection()).boxify()
+    }
+
+    fn version(&self) -> Option<String> {
+        self.version.clone()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct StateFetchingChangesetHook {
+    fetches: usize,
+}
+
+impl Hook<HookChangeset> for StateFetchingChangesetHook {
+    fn run(
+        &self,
+        ctx: CoreContext,
+        context: HookContext<HookChangeset>,
+    ) -> BoxFuture<HookExecution, Error> {
+        let fetches: Vec<_> = (0..self.fetches)
+            .map(|_| context.state.fetch(ctx.clone(), "owners/dir1".to_string()))
+            .collect();
+        join_all(fetches)
+            .then(|res| match res {
+                Ok(ref states) if states.iter().all(|state| state == "Stanislau Hlebik") => {
+                    Ok(HookExecution::Accepted)
+                }
+                _ => Ok(default_rejection()),
+            })
+            .boxify()
+    }
+}
+
 #[derive(Clone, Debug)]
 struct ContextMatchingChangesetHook {
     expected_context: HookContext<HookChangeset>,
@@ -282,6 +345,44 @@ fn always_accepting_file_hook() -> Box<Hook<HookFile>> {
     Box::new(FnFileHook::new(f))
 }
 
+#[derive(Clone, Debug)]
+struct CountingFileHook {
+    runs: Arc<AtomicUsize>,
+    version: Option<String>,
+}
+
+impl Hook<HookFile> for CountingFileHook {
+    fn run(
+        &self,
+        _ctx: CoreContext,
+        _context: HookContext<HookFile>,
+    ) -> BoxFuture<HookExecution, Error> {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        finished(default_rejection()).boxify()
+    }
+
+    fn version(&self) -> Option<String> {
+        self.version.clone()
+    }
+}
+
+/// A file hook that never finishes, so that it always times out
+#[derive(Clone, Debug)]
+struct HangingFileHook {
+    runs: Arc<AtomicUsize>,
+}
+
+impl Hook<HookFile> for HangingFileHook {
+    fn run(
+        &self,
+        _ctx: CoreContext,
+        _context: HookContext<HookFile>,
+    ) -> BoxFuture<HookExecution, Error> {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        futures::future::empty().boxify()
+    }
+}
+
 fn always_rejecting_file_hook() -> Box<Hook<HookFile>> {
     let f: fn(HookContext<HookFile>) -> HookExecution = |_| default_rejection();
     Box::new(FnFileHook::new(f))
@@ -532,6 +633,7 @@ fn test_changeset_hook_context() {
             hook_name: "hook1".into(),
             config: Default::default(),
             data,
+            state: Default::default(),
         };
         let hooks: HashMap<String, Box<Hook<HookChangeset>>> = hashmap! {
             "hook1".to_string() => context_matching_changeset_hook(expected_context)
@@ -827,6 +929,70 @@ fn test_file_hooks_paths_mix() {
     });
 }
 
+#[test]
+fn test_file_hooks_path_filters() {
+    async_unit::tokio_unit_test(move || {
+        let ctx = CoreContext::test_mock();
+        for inmem in vec![true, false] {
+            let mut hook_manager = setup_hook_manager(
+                hashmap! {"bm1".to_string() => vec!["hook1".to_string(), "hook2".to_string()]},
+                hashmap! {},
+                inmem,
+            );
+            let config = HookConfig {
+                path_filters: vec![HookPathFilter::prefix("dir1/subdir1/subsubdir2")],
+                ..Default::default()
+            };
+            hook_manager.register_file_hook("hook1", always_rejecting_file_hook().into(), config);
+            let config = HookConfig {
+                path_filters: vec![HookPathFilter::glob("**/file_1").unwrap()],
+                ..Default::default()
+            };
+            hook_manager.register_file_hook("hook2", always_accepting_file_hook().into(), config);
+
+            let res = hook_manager
+                .run_file_hooks_for_bookmark(
+                    ctx.clone(),
+                    default_changeset_id(),
+                    &Bookmark::new("bm1").unwrap(),
+                    None,
+                )
+                .wait()
+                .unwrap();
+            let mut res: Vec<_> = res
+                .into_iter()
+                .map(|(exec_id, exec)| (exec_id.hook_name, exec_id.file.path, exec))
+                .collect();
+            res.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+            assert_eq!(
+                res,
+                vec![
+                    (
+                        "hook1".to_string(),
+                        "dir1/subdir1/subsubdir2/file_1".to_string(),
+                        default_rejection(),
+                    ),
+                    (
+                        "hook1".to_string(),
+                        "dir1/subdir1/subsubdir2/file_2".to_string(),
+                        default_rejection(),
+                    ),
+                    (
+                        "hook2".to_string(),
+                        "dir1/subdir1/subsubdir1/file_1".to_string(),
+                        HookExecution::Accepted,
+                    ),
+                    (
+                        "hook2".to_string(),
+                        "dir1/subdir1/subsubdir2/file_1".to_string(),
+                        HookExecution::Accepted,
+                    ),
+                ]
+            );
+        }
+    });
+}
+
 #[test]
 fn test_file_hook_contains_string() {
     async_unit::tokio_unit_test(|| {
@@ -1005,6 +1171,473 @@ fn test_with_blob_store() {
     });
 }
 
+#[test]
+fn test_hook_bypass_allowed_users() {
+    async_unit::tokio_unit_test(|| {
+        let run_hook = |user: Option<&str>, pushvar: &'static str| {
+            let session = Uuid::new_v4();
+            let ctx = CoreContext::new(
+                session,
+                Logger::root(Discard {}.ignore_res(), o!()),
+                ScubaSampleBuilder::with_discard(),
+                None,
+                TraceContext::new(session, Instant::now()),
+                user.map(|user| user.to_string()),
+                SshEnvVars::default(),
+            );
+            let config = HookConfig {
+                bypass: Some(HookBypass::Pushvar {
+                    name: "BYPASS_REVIEW".into(),
+                    value: "true".into(),
+                }),
+                bypass_allowed_users: vec!["releng".into()],
+                ..Default::default()
+            };
+            let mut hook_manager = hook_manager_inmem();
+            hook_manager.register_changeset_hook(
+                "hook1",
+                always_rejecting_changeset_hook().into(),
+                config,
+            );
+            hook_manager.set_hooks_for_bookmark(
+                Bookmark::new("bm1").unwrap().into(),
+                vec!["hook1".to_string()],
+            );
+            let pushvars = hashmap! {"BYPASS_REVIEW".to_string() => Bytes::from(pushvar)};
+            hook_manager
+                .run_changeset_hooks_for_bookmark(
+                    ctx,
+                    default_changeset_id(),
+                    &Bookmark::new("bm1").unwrap(),
+                    Some(pushvars),
+                )
+                .wait()
+                .unwrap()
+                .len()
+        };
+
+        assert_eq!(run_hook(Some("releng"), "true"), 0);
+        assert_eq!(run_hook(Some("releng"), "false"), 1);
+        // Only the allowed users may bypass the hook
+        assert_eq!(run_hook(Some("alice"), "true"), 1);
+        assert_eq!(run_hook(None, "true"), 1);
+    });
+}
+
+#[test]
+fn test_post_commit_queue() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let queue = SqlPostCommitQueue::with_sqlite_in_memory().unwrap();
+        let repo_id = RepositoryId::new(0);
+        let other_repo_id = RepositoryId::new(1);
+        let t0 = DateTime::from_rfc3339("2019-03-01T12:00:00.00Z").unwrap();
+        let t1 = DateTime::from_rfc3339("2019-03-01T12:01:00.00Z").unwrap();
+        let entry = |repo_id, hook_name: &str| {
+            PostCommitQueueEntry::new(repo_id, hook_name.to_string(), default_changeset_id(), t0)
+        };
+
+        queue
+            .add(
+                ctx.clone(),
+                vec![entry(repo_id, "hook1"), entry(repo_id, "hook2")],
+            )
+            .wait()
+            .unwrap();
+        // Hooks that are already queued for a changeset are ignored
+        queue
+            .add(
+                ctx.clone(),
+                vec![entry(repo_id, "hook1"), entry(other_repo_id, "hook1")],
+            )
+            .wait()
+            .unwrap();
+
+        let due = queue
+            .due(ctx.clone(), repo_id, t1.into(), 10)
+            .wait()
+            .unwrap();
+        let names: Vec<_> = due.iter().map(|entry| entry.hook_name.as_str()).collect();
+        assert_eq!(names, vec!["hook1", "hook2"]);
+
+        // Entries that were given up on are listed, but aren't due
+        let given_up = PostCommitQueueEntry {
+            attempts: 3,
+            next_attempt: None,
+            last_error: Some("unavailable".to_string()),
+            ..due[0].clone()
+        };
+        queue
+            .update(ctx.clone(), vec![given_up.clone()])
+            .wait()
+            .unwrap();
+        let due = queue
+            .due(ctx.clone(), repo_id, t1.into(), 10)
+            .wait()
+            .unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].hook_name, "hook2");
+        let listed = queue.list(ctx.clone(), repo_id, 10).wait().unwrap();
+        assert_eq!(listed[0], given_up);
+
+        // An entry is claimed by a single worker until its lease ends, or it is released
+        let lease = Duration::from_secs(60);
+        let claim = |claimed_by: &str, now: DateTime| {
+            queue
+                .claim(
+                    ctx.clone(),
+                    repo_id,
+                    now.into(),
+                    10,
+                    claimed_by.to_string(),
+                    lease,
+                )
+                .wait()
+                .unwrap()
+        };
+        assert_eq!(claim("worker1", t1), due);
+        assert_eq!(claim("worker2", t1), vec![]);
+        assert_eq!(
+            queue
+                .due(ctx.clone(), repo_id, t1.into(), 10)
+                .wait()
+                .unwrap(),
+            vec![]
+        );
+        let t2 = DateTime::from_rfc3339("2019-03-01T12:02:01.00Z").unwrap();
+        assert_eq!(claim("worker2", t2), due);
+        queue.update(ctx.clone(), due.clone()).wait().unwrap();
+        assert_eq!(claim("worker1", t1), due);
+
+        queue.del(ctx.clone(), due).wait().unwrap();
+        let listed = queue.list(ctx.clone(), repo_id, 10).wait().unwrap();
+        assert_eq!(listed, vec![given_up]);
+        // Entries without ids can't be changed
+        assert!(queue
+            .del(ctx.clone(), vec![entry(repo_id, "hook1")])
+            .wait()
+            .is_err());
+    });
+}
+
+#[test]
+fn test_post_commit_queue_migrations() {
+    async_unit::tokio_unit_test(|| {
+        // The schema that the migrations build has everything the queue uses
+        let con = SqliteConnection::open_in_memory().unwrap();
+        for migration in SqlPostCommitQueue::migrations() {
+            con.execute_batch(migration.sqlite).unwrap();
+        }
+        let con = Connection::with_sqlite(con);
+        let queue = SqlPostCommitQueue::from_connections(con.clone(), con.clone(), con);
+
+        let ctx = CoreContext::test_mock();
+        let repo_id = RepositoryId::new(0);
+        let t0 = DateTime::from_rfc3339("2019-03-01T12:00:00.00Z").unwrap();
+        let entry =
+            PostCommitQueueEntry::new(repo_id, "hook".to_string(), default_changeset_id(), t0);
+        queue.add(ctx.clone(), vec![entry]).wait().unwrap();
+        let claimed = queue
+            .claim(
+                ctx.clone(),
+                repo_id,
+                t0.into(),
+                10,
+                "worker".to_string(),
+                Duration::from_secs(60),
+            )
+            .wait()
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+        queue.update(ctx, claimed).wait().unwrap();
+    });
+}
+
+#[test]
+fn test_run_queued_post_commit_hooks() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let repo_id = RepositoryId::new(0);
+        let bookmark = Bookmark::new("bm1").unwrap();
+        let queue: Arc<PostCommitQueue> =
+            Arc::new(SqlPostCommitQueue::with_sqlite_in_memory().unwrap());
+        let mut hook_manager = hook_manager_inmem();
+        hook_manager.register_post_commit_hook(
+            "accepting",
+            always_accepting_changeset_hook().into(),
+            Default::default(),
+        );
+        hook_manager.register_post_commit_hook(
+            "rejecting",
+            always_rejecting_changeset_hook().into(),
+            Default::default(),
+        );
+        hook_manager.set_hooks_for_bookmark(
+            bookmark.clone().into(),
+            vec!["accepting".to_string(), "rejecting".to_string()],
+        );
+        hook_manager.set_post_commit_queue(queue.clone());
+        let hook_manager = Arc::new(hook_manager);
+
+        // Post-commit hooks don't run before the push succeeds
+        let res = hook_manager
+            .run_changeset_hooks_for_bookmark(ctx.clone(), default_changeset_id(), &bookmark, None)
+            .wait()
+            .unwrap();
+        assert!(res.is_empty());
+
+        hook_manager
+            .queue_post_commit_hooks(
+                ctx.clone(),
+                repo_id,
+                vec![default_changeset_id()],
+                &bookmark,
+            )
+            .wait()
+            .unwrap();
+        let retries = PostCommitRetries {
+            max_attempts: 2,
+            initial_backoff: Duration::from_secs(0),
+        };
+        let run = || {
+            run_queued_post_commit_hooks(
+                ctx.clone(),
+                hook_manager.clone(),
+                queue.clone(),
+                repo_id,
+                10,
+                retries,
+            )
+            .wait()
+            .unwrap()
+        };
+        let list = || queue.list(ctx.clone(), repo_id, 10).wait().unwrap();
+
+        // The accepting hook is done, and the rejecting one is retried
+        assert_eq!(run(), 2);
+        let entries = list();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].hook_name, "rejecting");
+        assert_eq!(entries[0].attempts, 1);
+        assert_eq!(entries[0].last_error, Some("desc".to_string()));
+        assert!(entries[0].next_attempt.is_some());
+
+        // until it's given up on
+        assert_eq!(run(), 1);
+        let entries = list();
+        assert_eq!(entries[0].attempts, 2);
+        assert_eq!(entries[0].next_attempt, None);
+        assert_eq!(run(), 0);
+    });
+}
+
+#[test]
+fn test_file_hook_timeouts_not_memoized() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut hook_manager = hook_manager_inmem();
+        let config = HookConfig {
+            limits: HookLimits {
+                timeout_ms: Some(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let hook = HangingFileHook { runs: runs.clone() };
+        hook_manager.register_file_hook("hook1", Arc::new(hook), config);
+        hook_manager.set_hooks_for_bookmark(
+            Bookmark::new("bm1").unwrap().into(),
+            vec!["hook1".to_string()],
+        );
+        let run_hook = || {
+            let res = hook_manager
+                .run_file_hooks_for_bookmark(
+                    ctx.clone(),
+                    default_changeset_id(),
+                    &Bookmark::new("bm1").unwrap(),
+                    None,
+                )
+                .wait()
+                .unwrap();
+            for (_, execution) in res {
+                match execution {
+                    HookExecution::Rejected(_) => {}
+                    HookExecution::Accepted => panic!("a hook that timed out accepted a file"),
+                }
+            }
+            runs.load(Ordering::Relaxed)
+        };
+
+        // The hook runs again on the same files, as it may be within its limits this time
+        assert_eq!(run_hook(), 3);
+        assert_eq!(run_hook(), 6);
+    });
+}
+
+#[test]
+fn test_cached_hook_results() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let store: Arc<HookResultStore> =
+            Arc::new(SqlHookResultStore::with_sqlite_in_memory().unwrap());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let run_hook = |version: Option<&str>, config: HookConfig| {
+            let result_cache = HookResultCache::new(store.clone(), 60);
+            let mut hook_manager = hook_manager_inmem_with_result_cache(Some(result_cache));
+            let hook = CountingFileHook {
+                runs: runs.clone(),
+                version: version.map(|version| version.to_string()),
+            };
+            hook_manager.register_file_hook("hook1", Arc::new(hook), config);
+            hook_manager.set_hooks_for_bookmark(
+                Bookmark::new("bm1").unwrap().into(),
+                vec!["hook1".to_string()],
+            );
+            let res = hook_manager
+                .run_file_hooks_for_bookmark(
+                    ctx.clone(),
+                    default_changeset_id(),
+                    &Bookmark::new("bm1").unwrap(),
+                    None,
+                )
+                .wait()
+                .unwrap();
+            assert_eq!(res.len(), 3);
+            for (_, execution) in res {
+                assert_eq!(execution, default_rejection());
+            }
+            runs.load(Ordering::Relaxed)
+        };
+
+        // The hook runs once per file of the changeset
+        assert_eq!(run_hook(Some("v1"), Default::default()), 3);
+        assert_eq!(run_hook(Some("v1"), Default::default()), 3);
+        // Changing the code or the config of the hook invalidates its results
+        assert_eq!(run_hook(Some("v2"), Default::default()), 6);
+        let config = HookConfig {
+            ints: hashmap! {"max_size".to_string() => 10},
+            ..Default::default()
+        };
+        assert_eq!(run_hook(Some("v2"), config.clone()), 9);
+        assert_eq!(run_hook(Some("v2"), config), 9);
+        // Hooks without a version are never cached
+        assert_eq!(run_hook(None, Default::default()), 12);
+        assert_eq!(run_hook(None, Default::default()), 15);
+    });
+}
+
+#[test]
+fn test_file_content_id_depends_on_change() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let cs_id = default_changeset_id();
+        let path = "dir/file";
+        let mut content_store = InMemoryFileContentStore::new();
+        content_store.insert((cs_id, to_mpath(path)), (FileType::Regular, "eels".into()));
+        let content_store: Arc<FileContentStore> = Arc::new(content_store);
+        let content_id = |ty| {
+            let file = HookFile::new(path.to_string(), content_store.clone(), cs_id, ty);
+            file_content_id(ctx.clone(), &file).wait().unwrap()
+        };
+
+        // Hooks can tell added files from modified ones, so their outcomes may differ
+        assert_eq!(
+            content_id(ChangedFileType::Added),
+            content_id(ChangedFileType::Added)
+        );
+        assert_ne!(
+            content_id(ChangedFileType::Added),
+            content_id(ChangedFileType::Modified)
+        );
+    });
+}
+
+#[test]
+fn test_changeset_hook_results_not_cached() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let store: Arc<HookResultStore> =
+            Arc::new(SqlHookResultStore::with_sqlite_in_memory().unwrap());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let run_hook = || {
+            let result_cache = HookResultCache::new(store.clone(), 60);
+            let mut hook_manager = hook_manager_inmem_with_result_cache(Some(result_cache));
+            let hook = CountingChangesetHook {
+                runs: runs.clone(),
+                version: Some("v1".to_string()),
+            };
+            hook_manager.register_changeset_hook("hook1", Arc::new(hook), Default::default());
+            hook_manager.set_hooks_for_bookmark(
+                Bookmark::new("bm1").unwrap().into(),
+                vec!["hook1".to_string()],
+            );
+            let res = hook_manager
+                .run_changeset_hooks_for_bookmark(
+                    ctx.clone(),
+                    default_changeset_id(),
+                    &Bookmark::new("bm1").unwrap(),
+                    None,
+                )
+                .wait()
+                .unwrap();
+            assert_eq!(res[0].1, default_rejection());
+            runs.load(Ordering::Relaxed)
+        };
+
+        // Changeset hooks see the parents and the pusher of a changeset, so they always run
+        assert_eq!(run_hook(), 1);
+        assert_eq!(run_hook(), 2);
+    });
+}
+
+#[test]
+fn test_hook_state() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let run_hook = |fetches: usize, provider: Option<InMemoryStateProvider>| {
+            let mut hook_manager = hook_manager_inmem();
+            if let Some(provider) = provider {
+                hook_manager.set_state_provider(Arc::new(provider));
+            }
+            let config = HookConfig {
+                limits: HookLimits {
+                    max_state_fetches: Some(2),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let hook = StateFetchingChangesetHook { fetches };
+            hook_manager.register_changeset_hook("hook1", Arc::new(hook), config);
+            hook_manager.set_hooks_for_bookmark(
+                Bookmark::new("bm1").unwrap().into(),
+                vec!["hook1".to_string()],
+            );
+            let res = hook_manager
+                .run_changeset_hooks_for_bookmark(
+                    ctx.clone(),
+                    default_changeset_id(),
+                    &Bookmark::new("bm1").unwrap(),
+                    None,
+                )
+                .wait()
+                .unwrap();
+            res[0].1.clone()
+        };
+        let provider = || {
+            let mut provider = InMemoryStateProvider::new();
+            provider.insert("owners/dir1", "Stanislau Hlebik");
+            Some(provider)
+        };
+
+        assert_eq!(run_hook(2, provider()), HookExecution::Accepted);
+        // Fetches over the budget of the hook fail
+        assert_eq!(run_hook(3, provider()), default_rejection());
+        // So do fetches on a hook manager without a provider
+        assert_eq!(run_hook(1, None), default_rejection());
+    });
+}
+
 fn run_changeset_hooks(
     ctx: CoreContext,
     bookmark_name: &str,
@@ -1147,6 +1780,10 @@ fn hook_manager_blobrepo() -> HookManager {
 }
 
 fn hook_manager_inmem() -> HookManager {
+    hook_manager_inmem_with_result_cache(None)
+}
+
+fn hook_manager_inmem_with_result_cache(result_cache: Option<HookResultCache>) -> HookManager {
     let ctx = CoreContext::test_mock();
     let repo = many_files_dirs::getrepo(None);
     // Load up an in memory store with a single commit from the many_files_dirs store
@@ -1171,11 +1808,12 @@ fn hook_manager_inmem() -> HookManager {
         (FileType::Regular, "eels".into()),
     );
     let logger = Logger::root(Discard {}.ignore_res(), o!());
-    HookManager::new(
+    HookManager::new_with_result_cache(
         ctx,
         Box::new(changeset_store),
         Arc::new(content_store),
         Default::default(),
+        result_cache,
         logger,
     )
 }
@@ -1202,10 +1840,69 @@ fn default_repo_config() -> RepoConfig {
         hash_validation_percentage: 0,
         readonly: RepoReadOnly::ReadWrite,
         skiplist_index_blobstore_key: None,
+        skiplist_refresh: None,
         bundle2_replay_params: Bundle2ReplayParams::default(),
+        webhooks: vec![],
+        event_bus: None,
+        scratch_namespace: None,
+        tree_prefetch: None,
+        audit: None,
+        response_cache: None,
+        fault_injection: None,
+        session_limits: Default::default(),
+        resumable_pull: None,
+        response_size_limits: HashMap::new(),
+        qos: Default::default(),
+        push_quota: Default::default(),
+        content_refs: None,
+        manifest_sharding: None,
+        wireproto_caps: Default::default(),
+        copy_info_check: None,
+        quarantine: None,
+        path_acls: vec![],
+        phases_admin_identities: vec![],
+        pull_through: None,
     }
 }
 
+#[test]
+fn test_memoizing_file_content_store() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let repo = many_files_dirs::getrepo(None);
+        let store = BlobRepoFileContentStore::new(repo.clone());
+        let cs_id = default_changeset_id();
+        let paths = vec![
+            to_mpath("dir1/subdir1/subsubdir1/file_1"),
+            to_mpath("dir1/subdir1/subsubdir2/file_2"),
+            to_mpath("dir1/subdir1"),
+            to_mpath("no/such/file"),
+        ];
+
+        let read = |store: &FileContentStore, path: &MPath| {
+            let content = store.get_file_content(ctx.clone(), cs_id, path.clone());
+            let file_type = store.get_file_type(ctx.clone(), cs_id, path.clone());
+            let size = store.get_file_size(ctx.clone(), cs_id, path.clone());
+            (content, file_type, size).into_future().wait().unwrap()
+        };
+
+        // With a cap too small to remember any content, and with one that fits everything
+        for max_bytes in vec![100, 1024 * 1024] {
+            let memoizing = MemoizingFileContentStore::new(repo.clone(), max_bytes);
+            for _ in 0..2 {
+                for path in &paths {
+                    assert_eq!(read(&memoizing, path), read(&store, path));
+                }
+            }
+        }
+        let (content, file_type, size) = read(&store, &paths[0]);
+        assert!(content.is_some());
+        assert!(file_type.is_some());
+        assert_eq!(size, content.map(|content| content.len() as u64));
+        assert_eq!(read(&store, &paths[2]), (None, None, None));
+    });
+}
+
 #[test]
 fn test_load_hooks() {
     async_unit::tokio_unit_test(|| {
@@ -1324,3 +2021,97 @@ fn test_load_hooks_bad_rust_hook() {
         };
     });
 }
+
+#[test]
+fn test_load_hooks_with_registry() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let mut config = default_repo_config();
+        config.bookmarks = vec![BookmarkParams {
+            bookmark: Bookmark::new("bm1").unwrap().into(),
+            hooks: vec!["rust:only_file_1".into(), "rust:block_large_files".into()],
+            only_fast_forward: false,
+        }];
+        let mut large_files_config = HookConfig::default();
+        large_files_config.ints.insert("max_size".into(), 9);
+        config.hooks = vec![
+            // Compiled hooks run on what they are written for, whatever the config says
+            HookParams {
+                name: "rust:only_file_1".into(),
+                code: None,
+                hook_type: HookType::PerChangeset,
+                config: Default::default(),
+            },
+            HookParams {
+                name: "rust:block_large_files".into(),
+                code: None,
+                hook_type: HookType::PerAddedOrModifiedFile,
+                config: large_files_config,
+            },
+        ];
+
+        let mut registry = HookRegistry::with_builtin_hooks();
+        registry.register_file_hook("only_file_1", |_| {
+            let paths = hashset! {"dir1/subdir1/subsubdir1/file_1".to_string()};
+            Ok(path_matching_file_hook(paths).into())
+        });
+        let mut hm = hook_manager_inmem();
+        load_hooks_with_registry(&mut hm, config, &registry).unwrap();
+
+        let res = hm
+            .run_file_hooks_for_bookmark(
+                ctx,
+                default_changeset_id(),
+                &Bookmark::new("bm1").unwrap(),
+                None,
+            )
+            .wait()
+            .unwrap();
+        let accepted: HashMap<_, _> = res
+            .into_iter()
+            .map(|(id, exec)| {
+                let accepted = match exec {
+                    HookExecution::Accepted => true,
+                    HookExecution::Rejected(_) => false,
+                };
+                ((id.hook_name, id.file.path), accepted)
+            })
+            .collect();
+        let expected = hashmap! {
+            ("rust:only_file_1", "dir1/subdir1/subsubdir1/file_1") => true,
+            ("rust:only_file_1", "dir1/subdir1/subsubdir2/file_1") => false,
+            ("rust:only_file_1", "dir1/subdir1/subsubdir2/file_2") => false,
+            ("rust:block_large_files", "dir1/subdir1/subsubdir1/file_1") => true,
+            ("rust:block_large_files", "dir1/subdir1/subsubdir2/file_1") => false,
+            ("rust:block_large_files", "dir1/subdir1/subsubdir2/file_2") => true,
+        };
+        let expected: HashMap<_, _> = expected
+            .into_iter()
+            .map(|((hook, path), accepted)| ((hook.to_string(), path.to_string()), accepted))
+            .collect();
+        assert_eq!(accepted, expected);
+    });
+}
+
+#[test]
+fn test_load_hooks_bad_rust_hook_config() {
+    async_unit::tokio_unit_test(|| {
+        let mut config = default_repo_config();
+        config.hooks = vec![HookParams {
+            name: "rust:block_large_files".into(),
+            code: None,
+            hook_type: HookType::PerAddedOrModifiedFile,
+            config: Default::default(),
+        }];
+
+        let mut hm = hook_manager_blobrepo();
+
+        match load_hooks(&mut hm, config)
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidHookConfig(_)) => (),
+            _ => assert!(false, "Unexpected err type"),
+        };
+    });
+}
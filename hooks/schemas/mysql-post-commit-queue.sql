@@ -0,0 +1,17 @@
+CREATE TABLE `post_commit_queue` (
+  `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `hook_name` VARCHAR(255) NOT NULL,
+  `cs_id` VARBINARY(20) NOT NULL,
+  `add_timestamp` BIGINT NOT NULL,
+  `attempts` INT UNSIGNED NOT NULL DEFAULT 0,
+  -- NULL once the hook was given up on, until it is retried by hand
+  `next_attempt` BIGINT,
+  `last_error` TEXT,
+  -- The worker running the hook, until when it may
+  `claimed_by` VARCHAR(255),
+  `claimed_until` BIGINT,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `post_commit_queue_entry` (`repo_id`, `hook_name`, `cs_id`),
+  KEY `post_commit_queue_next_attempt` (`repo_id`, `next_attempt`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,12 @@
+CREATE TABLE `hook_results` (
+  `hook_name` VARCHAR(255) NOT NULL,
+  `hook_version` VARCHAR(64) NOT NULL,
+  `content_id` VARCHAR(64) NOT NULL,
+  -- NULL if the hook accepted the content
+  `rejection` TEXT,
+  `long_rejection` TEXT,
+  `expires` BIGINT NOT NULL,
+  PRIMARY KEY (`hook_name`, `hook_version`, `content_id`)
+);
+
+CREATE INDEX `hook_results_expires` ON `hook_results` (`expires`);
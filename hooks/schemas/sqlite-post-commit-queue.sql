@@ -0,0 +1,17 @@
+CREATE TABLE `post_commit_queue` (
+  `id` INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `hook_name` VARCHAR(255) NOT NULL,
+  `cs_id` VARBINARY(20) NOT NULL,
+  `add_timestamp` BIGINT NOT NULL,
+  `attempts` INT UNSIGNED NOT NULL DEFAULT 0,
+  -- NULL once the hook was given up on, until it is retried by hand
+  `next_attempt` BIGINT,
+  `last_error` TEXT,
+  -- The worker running the hook, until when it may
+  `claimed_by` VARCHAR(255),
+  `claimed_until` BIGINT,
+  UNIQUE (`repo_id`, `hook_name`, `cs_id`)
+);
+
+CREATE INDEX `post_commit_queue_next_attempt` ON `post_commit_queue` (`repo_id`, `next_attempt`);
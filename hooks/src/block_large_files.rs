@@ -0,0 +1,67 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A file hook that rejects files over a size
+
+#![deny(warnings)]
+
+use super::errors::*;
+use super::{Hook, HookContext, HookExecution, HookFile, HookRejectionInfo};
+use context::CoreContext;
+use failure::Error;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use metaconfig_types::HookConfig;
+
+const MAX_SIZE_CONFIG: &str = "max_size";
+
+/// Rejects added or modified files larger than the `max_size` int of its config, in bytes
+pub struct BlockLargeFilesHook {
+    max_size: u64,
+}
+
+impl BlockLargeFilesHook {
+    pub fn new(config: &HookConfig) -> Result<Self, Error> {
+        match config.ints.get(MAX_SIZE_CONFIG) {
+            Some(max_size) if *max_size >= 0 => Ok(Self {
+                max_size: *max_size as u64,
+            }),
+            _ => Err(ErrorKind::InvalidHookConfig(format!(
+                "{} must be set to a number of bytes",
+                MAX_SIZE_CONFIG
+            ))
+            .into()),
+        }
+    }
+}
+
+impl Hook<HookFile> for BlockLargeFilesHook {
+    fn run(
+        &self,
+        ctx: CoreContext,
+        context: HookContext<HookFile>,
+    ) -> BoxFuture<HookExecution, Error> {
+        let max_size = self.max_size;
+        let path = context.data.path.clone();
+        context
+            .data
+            .len(ctx)
+            .map(move |size| {
+                if size <= max_size {
+                    HookExecution::Accepted
+                } else {
+                    HookExecution::Rejected(HookRejectionInfo::new(
+                        "File too large".to_string(),
+                        format!(
+                            "{} is {} bytes, more than the limit of {} bytes",
+                            path, size, max_size
+                        ),
+                    ))
+                }
+            })
+            .boxify()
+    }
+}
@@ -33,4 +33,12 @@ pub enum ErrorKind {
 
     #[fail(display = "invalid rust hook: {}", _0)]
     InvalidRustHook(String),
+    #[fail(display = "invalid hook config: {}", _0)]
+    InvalidHookConfig(String),
+
+    #[fail(display = "Hook '{}' exceeded its {}", _0, _1)]
+    HookLimitExceeded(String, String),
+
+    #[fail(display = "Failed to fetch hook state at {}: {}", _0, _1)]
+    HookStateFetchFailed(String, String),
 }
@@ -8,31 +8,48 @@
 
 #![deny(warnings)]
 
+use super::hook_registry::{HookRegistry, RustHook};
 use super::lua_hook::LuaHook;
-use super::{Hook, HookChangeset, HookManager};
+use super::HookManager;
 use errors::*;
-use facebook::rust_hooks::check_unittests::CheckUnittestsHook;
-use facebook::rust_hooks::ensure_valid_email::EnsureValidEmailHook;
-use facebook::rust_hooks::verify_integrity::VerifyIntegrityHook;
 use failure::Error;
 use metaconfig_types::{HookType, RepoConfig};
 use std::collections::HashSet;
 use std::sync::Arc;
 
+/// Load the hooks of `config`, with the compiled hooks that ship with the server
 pub fn load_hooks(hook_manager: &mut HookManager, config: RepoConfig) -> Result<(), Error> {
+    load_hooks_with_registry(hook_manager, config, &HookRegistry::with_builtin_hooks())
+}
+
+/// Load the hooks of `config`. Hooks named `rust:<name>` are the hooks of `registry`, and
+/// the others are Lua hooks with the code from the config.
+pub fn load_hooks_with_registry(
+    hook_manager: &mut HookManager,
+    config: RepoConfig,
+    registry: &HookRegistry,
+) -> Result<(), Error> {
     let mut hook_set = HashSet::new();
     for hook in config.hooks {
         let name = hook.name;
         if name.starts_with("rust:") {
             let rust_name = &name[5..];
-            let rust_name = rust_name.to_string();
-            let rust_hook: Arc<Hook<HookChangeset>> = match rust_name.as_ref() {
-                "check_unittests" => Arc::new(CheckUnittestsHook::new(&hook.config)?),
-                "verify_integrity" => Arc::new(VerifyIntegrityHook::new()),
-                "ensure_valid_email" => Arc::new(EnsureValidEmailHook::new(&hook.config)),
-                _ => return Err(ErrorKind::InvalidRustHook(name.clone()).into()),
-            };
-            hook_manager.register_changeset_hook(&name, rust_hook, hook.config)
+            match registry.create(rust_name, &hook.config) {
+                // A compiled changeset hook runs after the push if the config asks for it
+                Some(Ok(RustHook::Changeset(rust_hook)))
+                    if hook.hook_type == HookType::PostCommit =>
+                {
+                    hook_manager.register_post_commit_hook(&name, rust_hook, hook.config)
+                }
+                Some(Ok(RustHook::Changeset(rust_hook))) => {
+                    hook_manager.register_changeset_hook(&name, rust_hook, hook.config)
+                }
+                Some(Ok(RustHook::File(rust_hook))) => {
+                    hook_manager.register_file_hook(&name, rust_hook, hook.config)
+                }
+                Some(Err(err)) => return Err(err),
+                None => return Err(ErrorKind::InvalidRustHook(name.clone()).into()),
+            }
         } else {
             let lua_hook = LuaHook::new(name.clone(), hook.code.clone().unwrap());
             match hook.hook_type {
@@ -42,6 +59,9 @@ pub fn load_hooks(hook_manager: &mut HookManager, config: RepoConfig) -> Result<
                 HookType::PerChangeset => {
                     hook_manager.register_changeset_hook(&name, Arc::new(lua_hook), hook.config)
                 }
+                HookType::PostCommit => {
+                    hook_manager.register_post_commit_hook(&name, Arc::new(lua_hook), hook.config)
+                }
             }
         }
         hook_set.insert(name);
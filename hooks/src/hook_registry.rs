@@ -0,0 +1,96 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The compiled hooks that repo configs can refer to, next to the hooks written in Lua
+
+#![deny(warnings)]
+
+use super::block_large_files::BlockLargeFilesHook;
+use super::{Hook, HookChangeset, HookFile};
+use facebook::rust_hooks::check_unittests::CheckUnittestsHook;
+use facebook::rust_hooks::ensure_valid_email::EnsureValidEmailHook;
+use facebook::rust_hooks::verify_integrity::VerifyIntegrityHook;
+use failure::Error;
+use metaconfig_types::HookConfig;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type ChangesetHookFactory =
+    Box<Fn(&HookConfig) -> Result<Arc<Hook<HookChangeset>>, Error> + Send + Sync>;
+type FileHookFactory = Box<Fn(&HookConfig) -> Result<Arc<Hook<HookFile>>, Error> + Send + Sync>;
+
+/// A compiled hook, as created from its config
+pub enum RustHook {
+    Changeset(Arc<Hook<HookChangeset>>),
+    File(Arc<Hook<HookFile>>),
+}
+
+enum RustHookFactory {
+    Changeset(ChangesetHookFactory),
+    File(FileHookFactory),
+}
+
+/// Compiled hooks by name. Repo configs refer to them as `rust:<name>`, and whether they run on
+/// changesets or on files is up to the hook rather than to the config.
+#[derive(Default)]
+pub struct HookRegistry {
+    hooks: HashMap<String, RustHookFactory>,
+}
+
+impl HookRegistry {
+    /// A registry without any hooks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry with the hooks that ship with the server
+    pub fn with_builtin_hooks() -> Self {
+        let mut registry = Self::new();
+        registry.register_changeset_hook("check_unittests", |config| {
+            Ok(Arc::new(CheckUnittestsHook::new(config)?))
+        });
+        registry.register_changeset_hook("verify_integrity", |_| {
+            Ok(Arc::new(VerifyIntegrityHook::new()))
+        });
+        registry.register_changeset_hook("ensure_valid_email", |config| {
+            Ok(Arc::new(EnsureValidEmailHook::new(config)))
+        });
+        registry.register_file_hook("block_large_files", |config| {
+            Ok(Arc::new(BlockLargeFilesHook::new(config)?))
+        });
+        registry
+    }
+
+    /// Make the changeset hook that `factory` creates from its config available as
+    /// `rust:<name>`. Replaces any hook registered under the same name.
+    pub fn register_changeset_hook<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&HookConfig) -> Result<Arc<Hook<HookChangeset>>, Error> + Send + Sync + 'static,
+    {
+        self.hooks.insert(
+            name.to_string(),
+            RustHookFactory::Changeset(Box::new(factory)),
+        );
+    }
+
+    /// Make the file hook that `factory` creates from its config available as `rust:<name>`.
+    /// Replaces any hook registered under the same name.
+    pub fn register_file_hook<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&HookConfig) -> Result<Arc<Hook<HookFile>>, Error> + Send + Sync + 'static,
+    {
+        self.hooks
+            .insert(name.to_string(), RustHookFactory::File(Box::new(factory)));
+    }
+
+    /// The hook registered as `name`, created from `config`. None if there is no such hook.
+    pub fn create(&self, name: &str, config: &HookConfig) -> Option<Result<RustHook, Error>> {
+        self.hooks.get(name).map(|factory| match factory {
+            RustHookFactory::Changeset(factory) => factory(config).map(RustHook::Changeset),
+            RustHookFactory::File(factory) => factory(config).map(RustHook::File),
+        })
+    }
+}
@@ -18,6 +18,9 @@ g__hook_start_base = function(info, arg, setup)
   ctx.regex_match = function(pattern, s)
     return coroutine.yield(g__regex_match(pattern, s))
   end
+  ctx.fetch_state = function(url)
+    return coroutine.yield(g__fetch_state(url))
+  end
   ctx.info=info
   setup(arg, ctx)
 
@@ -58,5 +58,8 @@ g__hook_start = function(info, arg)
     ctx.is_valid_reviewer = function(user)
       return coroutine.yield(g__is_valid_reviewer(user))
     end
+    ctx.has_commit_flag = function(flag)
+      return coroutine.yield(g__has_commit_flag(flag))
+    end
   end)
 end
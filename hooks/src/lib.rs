@@ -55,19 +55,31 @@ extern crate serde_derive;
 extern crate serde;
 #[macro_use]
 extern crate slog;
+#[macro_use]
+extern crate sql;
+extern crate sql_ext;
+#[macro_use]
+extern crate stats;
 extern crate itertools;
 extern crate tempdir;
+extern crate tokio;
 
 extern crate context;
 extern crate srclient;
 extern crate thrift;
 
+mod block_large_files;
 pub mod errors;
 mod facebook;
 pub mod hook_loader;
+pub mod hook_registry;
+mod limits;
 pub mod lua_hook;
 mod phabricator_message_parser;
+mod post_commit;
+mod result_cache;
 pub mod rust_hook;
+mod state;
 
 use aclchecker::{AclChecker, Identity};
 use asyncmemo::{Asyncmemo, Filler, Weight};
@@ -79,11 +91,29 @@ pub use errors::*;
 use failure::{err_msg, Error, FutureFailureErrorExt};
 use futures::{failed, finished, Future, IntoFuture};
 use futures_ext::{BoxFuture, FutureExt};
-use mercurial_types::{manifest_utils::EntryStatus, Changeset, HgChangesetId, HgParents, MPath};
-use metaconfig_types::{BookmarkOrRegex, HookBypass, HookConfig, HookManagerParams};
-use mononoke_types::FileType;
+pub use limits::HookLimiter;
+use mercurial_types::{
+    manifest_utils::EntryStatus, Changeset, HgChangesetId, HgParents, MPath, TextDecoding,
+};
+use metaconfig_types::{
+    BookmarkOrRegex, HookBypass, HookConfig, HookManagerParams, HookPathFilter,
+};
+use mononoke_types::{
+    BlobstoreValue, CommitFlags, ContentId, DateTime, FileContents, FileType, RepositoryId,
+};
+pub use post_commit::{
+    run_post_commit_worker, run_queued_post_commit_hooks, PostCommitQueue, PostCommitQueueEntry,
+    PostCommitRetries, SqlPostCommitQueue,
+};
 use regex::Regex;
+pub use result_cache::{
+    file_content_id, hook_version, HookResultCache, HookResultKey, HookResultStore,
+    SqlHookResultStore,
+};
 use slog::Logger;
+pub use state::{
+    CachingStateProvider, HookState, HookStateProvider, HttpStateProvider, InMemoryStateProvider,
+};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
@@ -93,6 +123,8 @@ use std::sync::{Arc, Mutex};
 
 type ChangesetHooks = HashMap<String, (Arc<Hook<HookChangeset>>, HookConfig)>;
 type FileHooks = Arc<Mutex<HashMap<String, (Arc<Hook<HookFile>>, HookConfig)>>>;
+type Limiters = Arc<Mutex<HashMap<String, Arc<HookLimiter>>>>;
+type StateProvider = Arc<Mutex<Option<Arc<HookStateProvider>>>>;
 type Cache = Asyncmemo<HookCacheFiller>;
 
 /// Manages hooks and allows them to be installed and uninstalled given a name
@@ -102,12 +134,17 @@ pub struct HookManager {
     cache: Cache,
     changeset_hooks: ChangesetHooks,
     file_hooks: FileHooks,
+    post_commit_hooks: ChangesetHooks,
+    post_commit_queue: Option<Arc<PostCommitQueue>>,
     bookmark_hooks: HashMap<Bookmark, Vec<String>>,
     regex_hooks: Vec<(Regex, Vec<String>)>,
     changeset_store: Box<ChangesetStore>,
     content_store: Arc<FileContentStore>,
     logger: Logger,
     reviewers_acl_checker: Arc<Option<AclChecker>>,
+    limiters: Limiters,
+    state_provider: StateProvider,
+    text_decoding: TextDecoding,
 }
 
 impl HookManager {
@@ -117,13 +154,37 @@ impl HookManager {
         content_store: Arc<FileContentStore>,
         hook_manager_params: HookManagerParams,
         logger: Logger,
+    ) -> HookManager {
+        Self::new_with_result_cache(
+            ctx,
+            changeset_store,
+            content_store,
+            hook_manager_params,
+            None,
+            logger,
+        )
+    }
+
+    /// If `result_cache` is set, outcomes of file hooks are reused across pushes of the same file
+    pub fn new_with_result_cache(
+        ctx: CoreContext,
+        changeset_store: Box<ChangesetStore>,
+        content_store: Arc<FileContentStore>,
+        hook_manager_params: HookManagerParams,
+        result_cache: Option<HookResultCache>,
+        logger: Logger,
     ) -> HookManager {
         let changeset_hooks = HashMap::new();
         let file_hooks = Arc::new(Mutex::new(HashMap::new()));
+        let limiters = Arc::new(Mutex::new(HashMap::new()));
+        let state_provider = Arc::new(Mutex::new(None));
 
         let filler = HookCacheFiller {
             ctx,
             file_hooks: file_hooks.clone(),
+            result_cache,
+            limiters: limiters.clone(),
+            state_provider: state_provider.clone(),
         };
         let cache = Asyncmemo::with_limits(
             "hooks",
@@ -132,6 +193,12 @@ impl HookManager {
             hook_manager_params.weightlimit,
         );
 
+        let text_decoding = if hook_manager_params.strict_utf8 {
+            TextDecoding::Strict
+        } else {
+            TextDecoding::Lossy
+        };
+
         let reviewers_acl_checker = if !hook_manager_params.disable_acl_checker {
             let identity = Identity::from_groupname(facebook::REVIEWERS_ACL_GROUP_NAME);
 
@@ -153,21 +220,37 @@ impl HookManager {
             cache,
             changeset_hooks,
             file_hooks,
+            post_commit_hooks: HashMap::new(),
+            post_commit_queue: None,
             bookmark_hooks: HashMap::new(),
             regex_hooks: Vec::new(),
             changeset_store,
             content_store,
             logger,
             reviewers_acl_checker: Arc::new(reviewers_acl_checker),
+            limiters,
+            state_provider,
+            text_decoding,
         }
     }
 
+    /// Let hooks fetch external state from `provider`. Without one, their fetches fail.
+    pub fn set_state_provider(&mut self, provider: Arc<HookStateProvider>) {
+        *self.state_provider.lock().unwrap() = Some(provider);
+    }
+
+    /// Queue post-commit hooks in `queue`. Without one, they never run.
+    pub fn set_post_commit_queue(&mut self, queue: Arc<PostCommitQueue>) {
+        self.post_commit_queue = Some(queue);
+    }
+
     pub fn register_changeset_hook(
         &mut self,
         hook_name: &str,
         hook: Arc<Hook<HookChangeset>>,
         config: HookConfig,
     ) {
+        self.register_limiter(hook_name, &config);
         self.changeset_hooks
             .insert(hook_name.to_string(), (hook, config));
     }
@@ -178,10 +261,32 @@ impl HookManager {
         hook: Arc<Hook<HookFile>>,
         config: HookConfig,
     ) {
+        self.register_limiter(hook_name, &config);
         let mut hooks = self.file_hooks.lock().unwrap();
         hooks.insert(hook_name.to_string(), (hook, config));
     }
 
+    pub fn register_post_commit_hook(
+        &mut self,
+        hook_name: &str,
+        hook: Arc<Hook<HookChangeset>>,
+        config: HookConfig,
+    ) {
+        self.register_limiter(hook_name, &config);
+        self.post_commit_hooks
+            .insert(hook_name.to_string(), (hook, config));
+    }
+
+    fn register_limiter(&self, hook_name: &str, config: &HookConfig) {
+        let limiter = HookLimiter::new(
+            hook_name.to_string(),
+            config.limits.clone(),
+            self.logger.clone(),
+        );
+        let mut limiters = self.limiters.lock().unwrap();
+        limiters.insert(hook_name.to_string(), Arc::new(limiter));
+    }
+
     pub fn set_hooks_for_bookmark(&mut self, bookmark: BookmarkOrRegex, hooks: Vec<String>) {
         match bookmark {
             BookmarkOrRegex::Bookmark(bookmark) => {
@@ -209,6 +314,13 @@ impl HookManager {
             .collect()
     }
 
+    pub fn post_commit_hook_names(&self) -> HashSet<String> {
+        self.post_commit_hooks
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
     fn hooks_for_bookmark(&self, bookmark: &Bookmark) -> HashSet<String> {
         let mut hooks: HashSet<_> = match self.bookmark_hooks.get(bookmark) {
             Some(hooks) => hooks.clone().into_iter().collect(),
@@ -265,16 +377,27 @@ impl HookManager {
             })
             .collect();
         let hooks = try_boxfuture!(hooks);
-        self.get_hook_changeset(ctx.clone(), changeset_id)
+        let path_filters = path_filters_of(hooks.iter().map(|(_, (_, config))| config));
+        let limiters = self.limiters.clone();
+        let state_provider = self.state_provider.lock().unwrap().clone();
+        let identities = ctx.user_identities();
+        self.get_hook_changeset(ctx.clone(), changeset_id, path_filters)
             .and_then({
                 move |hcs| {
                     let hooks = HookManager::filter_bypassed_hooks(
                         hooks,
                         &hcs.comments,
                         maybe_pushvars.as_ref(),
+                        &identities,
                     );
 
-                    HookManager::run_changeset_hooks_for_changeset(ctx, hcs.clone(), hooks.clone())
+                    HookManager::run_changeset_hooks_for_changeset(
+                        ctx,
+                        hcs.clone(),
+                        hooks.clone(),
+                        limiters,
+                        state_provider,
+                    )
                 }
             })
             .map(move |res| {
@@ -297,13 +420,31 @@ impl HookManager {
         ctx: CoreContext,
         changeset: HookChangeset,
         hooks: Vec<(String, Arc<Hook<HookChangeset>>, HookConfig)>,
+        limiters: Limiters,
+        state_provider: Option<Arc<HookStateProvider>>,
     ) -> BoxFuture<Vec<(String, HookExecution)>, Error> {
         let v: Vec<BoxFuture<(String, HookExecution), _>> = hooks
             .iter()
             .map(move |(hook_name, hook, config)| {
-                let hook_context: HookContext<HookChangeset> =
-                    HookContext::new(hook_name.clone(), config.clone(), changeset.clone());
-                HookManager::run_changeset_hook(ctx.clone(), hook.clone(), hook_context)
+                let limiter = match limiters.lock().unwrap().get(hook_name) {
+                    Some(limiter) => limiter.clone(),
+                    None => {
+                        let err = ErrorKind::NoSuchHook(hook_name.clone());
+                        return failed(Error::from(err)).boxify();
+                    }
+                };
+                let state = HookState::new(
+                    hook_name.clone(),
+                    state_provider.clone(),
+                    config.limits.max_state_fetches,
+                );
+                let hook_context: HookContext<HookChangeset> = HookContext::new(
+                    hook_name.clone(),
+                    config.clone(),
+                    changeset.with_files_matching(&config.path_filters),
+                    state,
+                );
+                HookManager::run_changeset_hook(ctx.clone(), hook.clone(), hook_context, limiter)
             })
             .collect();
         futures::future::join_all(v).boxify()
@@ -313,9 +454,22 @@ impl HookManager {
         ctx: CoreContext,
         hook: Arc<Hook<HookChangeset>>,
         hook_context: HookContext<HookChangeset>,
+        limiter: Arc<HookLimiter>,
     ) -> BoxFuture<(String, HookExecution), Error> {
         let hook_name = hook_context.hook_name.clone();
-        hook.run(ctx, hook_context)
+        let content_store = hook_context.data.content_store.clone();
+        limiter
+            .run(content_store, move |content_store| {
+                let HookContext {
+                    hook_name,
+                    config,
+                    data,
+                    state,
+                } = hook_context;
+                let data = data.with_content_store(content_store);
+                hook.run(ctx, HookContext::new(hook_name, config, data, state))
+            })
+            .then(move |res| limiter.outcome(res))
             .map({
                 cloned!(hook_name);
                 move |he| (hook_name, he)
@@ -325,6 +479,80 @@ impl HookManager {
             .boxify()
     }
 
+    // Post-commit hooks
+
+    fn post_commit_hooks_for_bookmark(&self, bookmark: &Bookmark) -> Vec<String> {
+        let mut hooks: Vec<_> = self
+            .hooks_for_bookmark(bookmark)
+            .into_iter()
+            .filter(|name| self.post_commit_hooks.contains_key(name))
+            .collect();
+        hooks.sort();
+        hooks
+    }
+
+    /// Whether changesets pushed to `bookmark` have post-commit hooks to queue
+    pub fn has_post_commit_hooks(&self, bookmark: &Bookmark) -> bool {
+        self.post_commit_queue.is_some()
+            && !self.post_commit_hooks_for_bookmark(bookmark).is_empty()
+    }
+
+    /// Queue the post-commit hooks of `bookmark` to run on `changesets`, which were pushed to it
+    pub fn queue_post_commit_hooks(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        changesets: Vec<HgChangesetId>,
+        bookmark: &Bookmark,
+    ) -> BoxFuture<(), Error> {
+        let hooks = self.post_commit_hooks_for_bookmark(bookmark);
+        let queue = match self.post_commit_queue {
+            Some(ref queue) if !hooks.is_empty() => queue.clone(),
+            _ => return finished(()).boxify(),
+        };
+        let now = DateTime::now();
+        let entries = changesets
+            .into_iter()
+            .flat_map(|cs_id| {
+                hooks.iter().map(move |hook_name| {
+                    PostCommitQueueEntry::new(repo_id, hook_name.clone(), cs_id, now)
+                })
+            })
+            .collect();
+        queue.add(ctx, entries)
+    }
+
+    /// Run the post-commit hook `hook_name` on a changeset. Outcomes of post-commit hooks are
+    /// never cached, as running them is what matters.
+    pub fn run_post_commit_hook(
+        &self,
+        ctx: CoreContext,
+        changeset_id: HgChangesetId,
+        hook_name: &str,
+    ) -> BoxFuture<HookExecution, Error> {
+        let (hook, config) = match self.post_commit_hooks.get(hook_name) {
+            Some(hook) => hook.clone(),
+            None => return failed(ErrorKind::NoSuchHook(hook_name.to_string()).into()).boxify(),
+        };
+        let path_filters = path_filters_of(Some(&config).into_iter());
+        let hooks = vec![(hook_name.to_string(), hook, config)];
+        let limiters = self.limiters.clone();
+        let state_provider = self.state_provider.lock().unwrap().clone();
+        self.get_hook_changeset(ctx.clone(), changeset_id, path_filters)
+            .and_then(move |hcs| {
+                HookManager::run_changeset_hooks_for_changeset(
+                    ctx,
+                    hcs,
+                    hooks,
+                    None,
+                    limiters,
+                    state_provider,
+                )
+            })
+            .map(|mut res| res.pop().expect("one hook ran").1)
+            .boxify()
+    }
+
     // File hooks
 
     pub fn run_file_hooks_for_bookmark(
@@ -373,20 +601,28 @@ impl HookManager {
             "Running file hooks for changeset id {:?}", changeset_id
         );
         let cache = self.cache.clone();
-        self.get_hook_changeset(ctx.clone(), changeset_id)
+        let limiters = self.limiters.clone();
+        let path_filters = path_filters_of(hooks.iter().map(|(_, (_, config))| config));
+        let identities = ctx.user_identities();
+        self.get_hook_changeset(ctx.clone(), changeset_id, path_filters)
             .and_then(move |hcs| {
                 let hooks = HookManager::filter_bypassed_hooks(
                     hooks.clone(),
                     &hcs.comments,
                     maybe_pushvars.as_ref(),
+                    &identities,
                 );
-                let hooks = hooks.into_iter().map(|(name, _, _)| name).collect();
+                let hooks = hooks
+                    .into_iter()
+                    .map(|(name, _, config)| (name, config.path_filters))
+                    .collect();
 
                 HookManager::run_file_hooks_for_changeset(
                     changeset_id,
                     hcs.clone(),
                     hooks,
                     cache,
+                    limiters,
                     logger,
                 )
             })
@@ -396,8 +632,9 @@ impl HookManager {
     fn run_file_hooks_for_changeset(
         changeset_id: HgChangesetId,
         changeset: HookChangeset,
-        hooks: Vec<String>,
+        hooks: Vec<(String, Vec<HookPathFilter>)>,
         cache: Cache,
+        limiters: Limiters,
         logger: Logger,
     ) -> BoxFuture<Vec<(FileHookExecutionID, HookExecution)>, Error> {
         let v: Vec<BoxFuture<Vec<(FileHookExecutionID, HookExecution)>, _>> = changeset
@@ -406,15 +643,24 @@ impl HookManager {
             // Do not run file hooks for deleted files
             .filter_map(move |file| {
                 match file.ty {
-                    ChangedFileType::Added | ChangedFileType::Modified => Some(
-                        HookManager::run_file_hooks(
+                    ChangedFileType::Added | ChangedFileType::Modified => {
+                        let hooks: Vec<_> = hooks
+                            .iter()
+                            .filter(|(_, path_filters)| matches_any(path_filters, &file.path))
+                            .map(|(name, _)| name.clone())
+                            .collect();
+                        if hooks.is_empty() {
+                            return None;
+                        }
+                        Some(HookManager::run_file_hooks(
                             changeset_id,
                             file.clone(),
-                            hooks.clone(),
+                            hooks,
                             cache.clone(),
+                            limiters.clone(),
                             logger.clone(),
-                        )
-                    ),
+                        ))
+                    }
                     ChangedFileType::Deleted => None,
                 }
             })
@@ -429,6 +675,7 @@ impl HookManager {
         file: HookFile,
         hooks: Vec<String>,
         cache: Cache,
+        limiters: Limiters,
         logger: Logger,
     ) -> BoxFuture<Vec<(FileHookExecutionID, HookExecution)>, Error> {
         let v: Vec<BoxFuture<(FileHookExecutionID, HookExecution), _>> = hooks
@@ -441,6 +688,7 @@ impl HookManager {
                         file: file.clone(),
                     },
                     cache.clone(),
+                    limiters.clone(),
                     logger.clone(),
                 )
             })
@@ -448,44 +696,59 @@ impl HookManager {
         futures::future::join_all(v).boxify()
     }
 
+    /// Runs that go over a limit fail in the cache, so that they aren't memoized, and are turned
+    /// into their outcome here
     fn run_file_hook(
         key: FileHookExecutionID,
         cache: Cache,
+        limiters: Limiters,
         logger: Logger,
     ) -> BoxFuture<(FileHookExecutionID, HookExecution), Error> {
         debug!(logger, "Running file hook {:?}", key);
         let hook_name = key.hook_name.clone();
+        let limiter = limiters.lock().unwrap().get(&hook_name).cloned();
         cache
             .get(key.clone())
+            .then(move |res| match limiter {
+                Some(limiter) => limiter.outcome(res),
+                None => res,
+            })
             .map(|he| (key, he))
             .with_context(move |_| format!("while executing hook {}", hook_name))
             .from_err()
             .boxify()
     }
 
+    /// The changeset as hooks see it. Only the changed files that match `path_filters`, if set,
+    /// are listed
     fn get_hook_changeset(
         &self,
         ctx: CoreContext,
         changeset_id: HgChangesetId,
+        path_filters: Option<Vec<HookPathFilter>>,
     ) -> BoxFuture<HookChangeset, Error> {
         let content_store = self.content_store.clone();
         let hg_changeset = self
             .changeset_store
             .get_changeset_by_changesetid(ctx.clone(), changeset_id);
-        let changed_files = self.changeset_store.get_changed_files(ctx, changeset_id);
+        let changed_files = self
+            .changeset_store
+            .get_changed_files(ctx, changeset_id, path_filters);
         let reviewers_acl_checker = self.reviewers_acl_checker.clone();
+        let text_decoding = self.text_decoding;
         Box::new((hg_changeset, changed_files).into_future().and_then(
             move |(changeset, changed_files)| {
-                let author = str::from_utf8(changeset.user())?.into();
+                let author = changeset.user_text(text_decoding)?.text;
                 let files = changed_files
                     .into_iter()
                     .map(|(path, ty)| {
                         HookFile::new(path, content_store.clone(), changeset_id.clone(), ty)
                     })
                     .collect();
-                let comments = str::from_utf8(changeset.comments())?.into();
+                let comments = changeset.comments_text(text_decoding)?.text;
                 let parents = HookChangesetParents::from(changeset.parents());
-                Ok(HookChangeset::new(
+                let flags = CommitFlags::from_extras(changeset.extra())?;
+                let hcs = HookChangeset::new(
                     author,
                     files,
                     comments,
@@ -493,29 +756,39 @@ impl HookManager {
                     changeset_id,
                     content_store,
                     reviewers_acl_checker,
-                ))
+                );
+                Ok(hcs.with_flags(flags))
             },
         ))
     }
 
+    /// The hooks that aren't bypassed. A hook is bypassed if its bypass is in the commit message
+    /// or the pushvars, and `identities` include one of the users allowed to bypass it, if it
+    /// restricts who may.
     fn filter_bypassed_hooks<T: Clone>(
         hooks: Vec<(String, (T, HookConfig))>,
         commit_msg: &String,
         maybe_pushvars: Option<&HashMap<String, Bytes>>,
+        identities: &[String],
     ) -> Vec<(String, T, HookConfig)> {
         hooks
             .clone()
             .into_iter()
             .filter_map(|(hook_name, (hook, config))| {
+                let may_bypass = config.bypass_allowed_users.is_empty()
+                    || config
+                        .bypass_allowed_users
+                        .iter()
+                        .any(|user| identities.contains(user));
                 let maybe_bypassed_hook = match config.bypass {
-                    Some(ref bypass) => {
+                    Some(ref bypass) if may_bypass => {
                         if HookManager::is_hook_bypassed(bypass, commit_msg, maybe_pushvars) {
                             None
                         } else {
                             Some(())
                         }
                     }
-                    None => Some(()),
+                    _ => Some(()),
                 };
                 maybe_bypassed_hook.map(move |()| (hook_name, hook, config))
             })
@@ -555,6 +828,12 @@ where
         ctx: CoreContext,
         hook_context: HookContext<T>,
     ) -> BoxFuture<HookExecution, Error>;
+
+    /// Identifies the code of the hook. Outcomes of file hooks with a version are cached across
+    /// pushes of the same file, until their code or config change
+    fn version(&self) -> Option<String> {
+        None
+    }
 }
 
 /// Represents a changeset - more user friendly than the blob changeset
@@ -565,11 +844,33 @@ pub struct HookChangeset {
     pub files: Vec<HookFile>,
     pub comments: String,
     pub parents: HookChangesetParents,
+    /// Flags set in the extras of the changeset
+    pub flags: CommitFlags,
     content_store: Arc<FileContentStore>,
     changeset_id: HgChangesetId,
     reviewers_acl_checker: Arc<Option<AclChecker>>,
 }
 
+/// The path filters to list the changed files of a changeset with, so that all the hooks of
+/// `configs` see the files they're interested in. None if some of them see every file.
+fn path_filters_of<'a>(
+    configs: impl Iterator<Item = &'a HookConfig>,
+) -> Option<Vec<HookPathFilter>> {
+    let mut path_filters = vec![];
+    for config in configs {
+        if config.path_filters.is_empty() {
+            return None;
+        }
+        path_filters.extend(config.path_filters.iter().cloned());
+    }
+    Some(path_filters)
+}
+
+/// Whether `path` matches one of `path_filters`, or there are none
+fn matches_any(path_filters: &[HookPathFilter], path: &str) -> bool {
+    path_filters.is_empty() || path_filters.iter().any(|filter| filter.matches(path))
+}
+
 impl fmt::Debug for HookChangeset {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -700,9 +1001,28 @@ impl HookFile {
             .boxify()
     }
 
+    pub fn content_id(&self, ctx: CoreContext) -> BoxFuture<ContentId, Error> {
+        let path = try_boxfuture!(MPath::new(self.path.as_bytes()));
+        cloned!(self.changeset_id);
+        self.content_store
+            .get_file_content_id(ctx, changeset_id, path.clone())
+            .and_then(move |opt| {
+                opt.ok_or(ErrorKind::MissingFile(changeset_id, path.into()).into())
+            })
+            .boxify()
+    }
+
     pub fn changed_file_type(&self) -> ChangedFileType {
         self.ty.clone()
     }
+
+    /// The same file, with its content fetched from `content_store`
+    pub(crate) fn with_content_store(self, content_store: Arc<FileContentStore>) -> Self {
+        HookFile {
+            content_store,
+            ..self
+        }
+    }
 }
 
 impl HookChangeset {
@@ -720,18 +1040,47 @@ impl HookChangeset {
             files,
             comments,
             parents,
+            flags: CommitFlags::default(),
             content_store,
             changeset_id,
             reviewers_acl_checker,
         }
     }
 
+    pub fn with_flags(self, flags: CommitFlags) -> Self {
+        HookChangeset { flags, ..self }
+    }
+
+    /// The changeset as seen by a hook with `path_filters`
+    fn with_files_matching(&self, path_filters: &[HookPathFilter]) -> Self {
+        let mut changeset = self.clone();
+        changeset
+            .files
+            .retain(|file| matches_any(path_filters, &file.path));
+        changeset
+    }
+
     pub fn file_content(&self, ctx: CoreContext, path: String) -> BoxFuture<Option<Bytes>, Error> {
         let path = try_boxfuture!(MPath::new(path.as_bytes()));
         self.content_store
             .get_file_content(ctx, self.changeset_id, path.clone())
             .boxify()
     }
+
+    /// The same changeset, with its content and the content of its files fetched from
+    /// `content_store`
+    pub(crate) fn with_content_store(self, content_store: Arc<FileContentStore>) -> Self {
+        let files = self
+            .files
+            .into_iter()
+            .map(|file| file.with_content_store(content_store.clone()))
+            .collect();
+        HookChangeset {
+            files,
+            content_store,
+            ..self
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -778,10 +1127,13 @@ pub trait ChangesetStore: Send + Sync {
         changesetid: HgChangesetId,
     ) -> BoxFuture<HgBlobChangeset, Error>;
 
+    /// The files changed by `changesetid`. If `path_filters` are set, only the files that match
+    /// one of them are listed, and directories that can't contain any aren't walked
     fn get_changed_files(
         &self,
         ctx: CoreContext,
         changesetid: HgChangesetId,
+        path_filters: Option<Vec<HookPathFilter>>,
     ) -> BoxFuture<Vec<(String, ChangedFileType)>, Error>;
 }
 
@@ -807,12 +1159,15 @@ impl ChangesetStore for InMemoryChangesetStore {
         &self,
         _ctx: CoreContext,
         changesetid: HgChangesetId,
+        path_filters: Option<Vec<HookPathFilter>>,
     ) -> BoxFuture<Vec<(String, ChangedFileType)>, Error> {
+        let path_filters = path_filters.unwrap_or_default();
         match self.map.get(&changesetid) {
             Some(cs) => Box::new(finished(
                 cs.files()
                     .into_iter()
                     .map(|arr| String::from_utf8_lossy(&arr.to_vec()).into_owned())
+                    .filter(|path| matches_any(&path_filters, path))
                     .map(|path| (path, ChangedFileType::Added))
                     .collect(),
             )),
@@ -856,6 +1211,14 @@ pub trait FileContentStore: Send + Sync {
         changesetid: HgChangesetId,
         path: MPath,
     ) -> BoxFuture<Option<u64>, Error>;
+
+    /// The id of the content of a file, which is known without fetching the content
+    fn get_file_content_id(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<ContentId>, Error>;
 }
 
 #[derive(Clone)]
@@ -901,6 +1264,19 @@ impl FileContentStore for InMemoryFileContentStore {
             .map(|(_, bytes)| bytes.len() as u64);
         finished(opt).boxify()
     }
+
+    fn get_file_content_id(
+        &self,
+        _ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<ContentId>, Error> {
+        let opt = self
+            .map
+            .get(&(changesetid, path.clone()))
+            .map(|(_, bytes)| FileContents::new_bytes(bytes.clone()).into_blob().id().clone());
+        finished(opt).boxify()
+    }
 }
 
 impl InMemoryFileContentStore {
@@ -918,6 +1294,9 @@ impl InMemoryFileContentStore {
 struct HookCacheFiller {
     ctx: CoreContext,
     file_hooks: FileHooks,
+    result_cache: Option<HookResultCache>,
+    limiters: Limiters,
+    state_provider: StateProvider,
 }
 
 impl Filler for HookCacheFiller {
@@ -929,11 +1308,56 @@ impl Filler for HookCacheFiller {
         match hooks.get(&key.hook_name) {
             Some(arc_hook) => {
                 let arc_hook = arc_hook.clone();
-                let hook_context: HookContext<HookFile> =
-                    HookContext::new(key.hook_name.clone(), arc_hook.1.clone(), key.file.clone());
-                arc_hook.0.run(self.ctx.clone(), hook_context)
+                let limiter = match self.limiters.lock().unwrap().get(&key.hook_name) {
+                    Some(limiter) => limiter.clone(),
+                    None => {
+                        let err = ErrorKind::NoSuchHook(key.hook_name.clone());
+                        return failed(Error::from(err)).boxify();
+                    }
+                };
+                let hook_name = key.hook_name.clone();
+                let config = arc_hook.1.clone();
+                let file = key.file.clone();
+                let state = HookState::new(
+                    hook_name.clone(),
+                    self.state_provider.lock().unwrap().clone(),
+                    config.limits.max_state_fetches,
+                );
+
+                // Runs that go over a limit fail, so that they are never cached, as they may be
+                // within it next time
+                let run = {
+                    cloned!(self.ctx, arc_hook, limiter);
+                    move || {
+                        let content_store = file.content_store.clone();
+                        limiter.run(content_store, move |content_store| {
+                            let file = file.with_content_store(content_store);
+                            let hook_context = HookContext::new(hook_name, config, file, state);
+                            arc_hook.0.run(ctx, hook_context)
+                        })
+                    }
+                };
+                let execution = match (self.result_cache.clone(), arc_hook.0.version()) {
+                    (Some(result_cache), Some(code_version)) => {
+                        let ctx = self.ctx.clone();
+                        let hook_name = key.hook_name.clone();
+                        let hook_version = hook_version(&code_version, &arc_hook.1);
+                        file_content_id(ctx.clone(), &key.file)
+                            .and_then(move |content_id| {
+                                let key = HookResultKey {
+                                    hook_name,
+                                    hook_version,
+                                    content_id,
+                                };
+                                result_cache.get_or_run(ctx, key, run)
+                            })
+                            .boxify()
+                    }
+                    _ => run(),
+                };
+                execution
             }
-            None => panic!("Can't find hook {}", key.hook_name), // TODO
+            None => failed(ErrorKind::NoSuchHook(key.hook_name.clone()).into()).boxify(),
         }
     }
 }
@@ -986,17 +1410,20 @@ where
     pub hook_name: String,
     pub config: HookConfig,
     pub data: T,
+    /// External state the hook may fetch
+    pub state: HookState,
 }
 
 impl<T> HookContext<T>
 where
     T: Clone,
 {
-    fn new(hook_name: String, config: HookConfig, data: T) -> HookContext<T> {
+    fn new(hook_name: String, config: HookConfig, data: T, state: HookState) -> HookContext<T> {
         HookContext {
             hook_name,
             config,
             data,
+            state,
         }
     }
 }
@@ -0,0 +1,419 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Limits on the resources that runs of a hook use: how long they take, how many of them run at
+//! the same time and how much file content they fetch. A run that goes over a limit either
+//! rejects the changeset or accepts it with a warning, depending on the config of the hook.
+//!
+//! Timeouts only interrupt hooks while they wait for data, not while they compute.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use context::CoreContext;
+use failure::{err_msg, Error};
+use futures::sync::oneshot;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{HgChangesetId, MPath};
+use metaconfig_types::{HookLimitAction, HookLimits};
+use mononoke_types::{ContentId, FileType};
+use slog::Logger;
+use stats::Timeseries;
+use tokio::util::FutureExt as TokioFutureExt;
+
+use super::{FileContentStore, HookExecution, HookRejectionInfo};
+use errors::ErrorKind;
+
+define_stats! {
+    prefix = "mononoke.hooks.limits";
+    timeouts: timeseries(RATE, SUM),
+    content_limit_exceeded: timeseries(RATE, SUM),
+    waits_for_slot: timeseries(RATE, SUM),
+}
+
+/// Enforces the limits of a hook on all its runs
+pub struct HookLimiter {
+    hook_name: String,
+    limits: HookLimits,
+    slots: Option<Arc<Slots>>,
+    logger: Logger,
+}
+
+impl HookLimiter {
+    pub fn new(hook_name: String, limits: HookLimits, logger: Logger) -> Self {
+        let slots = limits
+            .max_concurrency
+            .map(|max_concurrency| Arc::new(Slots::new(max_concurrency)));
+        Self {
+            hook_name,
+            limits,
+            slots,
+            logger,
+        }
+    }
+
+    /// Run a hook within the limits. `run` gets the store that the hook must fetch file content
+    /// from. Runs that go over a limit fail with `HookLimitExceeded`.
+    pub fn run<F>(
+        &self,
+        content_store: Arc<FileContentStore>,
+        run: F,
+    ) -> BoxFuture<HookExecution, Error>
+    where
+        F: FnOnce(Arc<FileContentStore>) -> BoxFuture<HookExecution, Error> + Send + 'static,
+    {
+        let slot = match self.slots {
+            Some(ref slots) => Slots::acquire(slots).map(Some).left_future(),
+            None => future::ok(None).right_future(),
+        };
+        let hook_name = self.hook_name.clone();
+        let limits = self.limits.clone();
+
+        slot.and_then(move |slot| {
+            let (content_store, counter) = match limits.max_content_bytes {
+                Some(max_bytes) => {
+                    let counter = Arc::new(ContentCounter::new(max_bytes));
+                    let content_store: Arc<FileContentStore> = Arc::new(LimitedContentStore {
+                        inner: content_store,
+                        counter: counter.clone(),
+                    });
+                    (content_store, Some(counter))
+                }
+                None => (content_store, None),
+            };
+
+            let execution = match limits.timeout_ms {
+                Some(timeout_ms) => {
+                    cloned!(hook_name);
+                    run(content_store)
+                        .timeout(Duration::from_millis(timeout_ms))
+                        .map_err(move |err| {
+                            if err.is_elapsed() {
+                                STATS::timeouts.add_value(1);
+                                let limit = format!("time limit of {}ms", timeout_ms);
+                                return Error::from(ErrorKind::HookLimitExceeded(hook_name, limit));
+                            }
+                            err.into_inner()
+                                .unwrap_or_else(|| err_msg("timer failed while running hook"))
+                        })
+                        .left_future()
+                }
+                None => run(content_store).right_future(),
+            };
+
+            execution.then(move |res| {
+                // Other runs may start once this one is over
+                drop(slot);
+                match counter {
+                    Some(ref counter) if counter.exceeded() => {
+                        STATS::content_limit_exceeded.add_value(1);
+                        let limit = format!("content limit of {} bytes", counter.max_bytes);
+                        Err(ErrorKind::HookLimitExceeded(hook_name, limit).into())
+                    }
+                    _ => res,
+                }
+            })
+        })
+        .boxify()
+    }
+
+    /// The outcome of a run, given that runs which went over a limit reject the changeset or
+    /// accept it with a warning
+    pub fn outcome(&self, res: Result<HookExecution, Error>) -> Result<HookExecution, Error> {
+        let err = match res {
+            Ok(execution) => return Ok(execution),
+            Err(err) => err,
+        };
+        match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::HookLimitExceeded(..)) => {}
+            _ => return Err(err),
+        }
+
+        match self.limits.on_exceeded {
+            HookLimitAction::Reject => Ok(HookExecution::Rejected(HookRejectionInfo::new(
+                err.to_string(),
+                format!(
+                    "{}. The hook could not check the changeset in time, please try again or \
+                     contact the owners of the hook",
+                    err
+                ),
+            ))),
+            HookLimitAction::Warn => {
+                warn!(self.logger, "{}, accepting the changeset anyway", err);
+                Ok(HookExecution::Accepted)
+            }
+        }
+    }
+}
+
+// Free slots to run a hook in, and runs waiting for one
+struct Slots {
+    state: Mutex<SlotsState>,
+}
+
+struct SlotsState {
+    free: usize,
+    waiting: VecDeque<oneshot::Sender<Slot>>,
+}
+
+impl Slots {
+    fn new(max: usize) -> Self {
+        Self {
+            state: Mutex::new(SlotsState {
+                free: max,
+                waiting: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn acquire(slots: &Arc<Self>) -> BoxFuture<Slot, Error> {
+        let mut state = slots.state.lock().expect("lock poisoned");
+        if state.free > 0 {
+            state.free -= 1;
+            return future::ok(Slot {
+                slots: Some(slots.clone()),
+            })
+            .boxify();
+        }
+
+        STATS::waits_for_slot.add_value(1);
+        let (sender, receiver) = oneshot::channel();
+        state.waiting.push_back(sender);
+        receiver
+            .map_err(|_| err_msg("hook slots were dropped"))
+            .boxify()
+    }
+
+    fn release(slots: &Arc<Self>) {
+        let mut state = slots.state.lock().expect("lock poisoned");
+        let mut slot = Slot {
+            slots: Some(slots.clone()),
+        };
+        // Runs that stopped waiting have dropped their receiver, and give the slot back
+        while let Some(waiting) = state.waiting.pop_front() {
+            match waiting.send(slot) {
+                Ok(()) => return,
+                Err(unsent) => slot = unsent,
+            }
+        }
+        slot.slots = None;
+        state.free += 1;
+    }
+}
+
+// A slot to run a hook in, given back when dropped
+struct Slot {
+    slots: Option<Arc<Slots>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(slots) = self.slots.take() {
+            Slots::release(&slots);
+        }
+    }
+}
+
+// Bytes of file content fetched by a run
+struct ContentCounter {
+    max_bytes: u64,
+    fetched: AtomicUsize,
+    exceeded: AtomicBool,
+}
+
+impl ContentCounter {
+    fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            fetched: AtomicUsize::new(0),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    // Whether fetching `len` more bytes is within the limit
+    fn fetch(&self, len: usize) -> bool {
+        let fetched = self.fetched.fetch_add(len, Ordering::Relaxed) + len;
+        if fetched as u64 > self.max_bytes {
+            self.exceeded.store(true, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+}
+
+// Hooks may swallow errors from fetches, so the run is failed after it's over as well
+struct LimitedContentStore {
+    inner: Arc<FileContentStore>,
+    counter: Arc<ContentCounter>,
+}
+
+impl FileContentStore for LimitedContentStore {
+    fn get_file_content(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        let counter = self.counter.clone();
+        self.inner
+            .get_file_content(ctx, changesetid, path)
+            .and_then(move |content| {
+                let len = content.as_ref().map_or(0, |content| content.len());
+                if counter.fetch(len) {
+                    Ok(content)
+                } else {
+                    Err(err_msg("hook fetched too much file content"))
+                }
+            })
+            .boxify()
+    }
+
+    fn get_file_type(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<FileType>, Error> {
+        self.inner.get_file_type(ctx, changesetid, path)
+    }
+
+    fn get_file_size(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<u64>, Error> {
+        self.inner.get_file_size(ctx, changesetid, path)
+    }
+
+    fn get_file_content_id(
+        &self,
+        ctx: CoreContext,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<ContentId>, Error> {
+        self.inner.get_file_content_id(ctx, changesetid, path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+    use futures::future::{empty, join_all};
+    use slog::{Discard, Drain};
+    use tokio::timer::Delay;
+
+    use std::str::FromStr;
+    use std::time::Instant;
+
+    use InMemoryFileContentStore;
+
+    fn hook_limiter(limits: HookLimits) -> HookLimiter {
+        HookLimiter::new(
+            "hook".to_string(),
+            limits,
+            Logger::root(Discard {}.ignore_res(), o!()),
+        )
+    }
+
+    fn content_store() -> Arc<FileContentStore> {
+        Arc::new(InMemoryFileContentStore::new())
+    }
+
+    #[test]
+    fn slow_runs_time_out() {
+        async_unit::tokio_unit_test(|| {
+            let limiter = hook_limiter(HookLimits {
+                timeout_ms: Some(10),
+                ..Default::default()
+            });
+            let res = limiter.run(content_store(), |_| empty().boxify()).wait();
+            assert_matches!(limiter.outcome(res), Ok(HookExecution::Rejected(_)));
+
+            let limiter = hook_limiter(HookLimits {
+                timeout_ms: Some(10),
+                on_exceeded: HookLimitAction::Warn,
+                ..Default::default()
+            });
+            let res = limiter.run(content_store(), |_| empty().boxify()).wait();
+            assert_matches!(limiter.outcome(res), Ok(HookExecution::Accepted));
+        });
+    }
+
+    #[test]
+    fn runs_wait_for_a_slot() {
+        async_unit::tokio_unit_test(|| {
+            let limiter = hook_limiter(HookLimits {
+                max_concurrency: Some(2),
+                ..Default::default()
+            });
+            // Runs going on, and the most that went on at the same time
+            let running = Arc::new(Mutex::new((0, 0)));
+            let runs: Vec<_> = (0..6)
+                .map(|_| {
+                    cloned!(running);
+                    limiter.run(content_store(), move |_| {
+                        {
+                            let mut running = running.lock().unwrap();
+                            running.0 += 1;
+                            running.1 = running.1.max(running.0);
+                        }
+                        Delay::new(Instant::now() + Duration::from_millis(10))
+                            .then(move |_| {
+                                running.lock().unwrap().0 -= 1;
+                                Ok(HookExecution::Accepted)
+                            })
+                            .boxify()
+                    })
+                })
+                .collect();
+
+            assert_eq!(join_all(runs).wait().unwrap().len(), 6);
+            assert_eq!(running.lock().unwrap().1, 2);
+        });
+    }
+
+    #[test]
+    fn runs_fetching_too_much_content_fail() {
+        async_unit::tokio_unit_test(|| {
+            let cs_id =
+                HgChangesetId::from_str("d261bc7900818dea7c86935b3fb17a33b2e3a6b4").unwrap();
+            let path = MPath::new("file").unwrap();
+            let mut store = InMemoryFileContentStore::new();
+            store.insert(
+                (cs_id, path.clone()),
+                (FileType::Regular, Bytes::from("0123456789")),
+            );
+            let store: Arc<FileContentStore> = Arc::new(store);
+            let limiter = hook_limiter(HookLimits {
+                max_content_bytes: Some(15),
+                ..Default::default()
+            });
+
+            let fetch_twice = move |store: Arc<FileContentStore>| {
+                let ctx = CoreContext::test_mock();
+                store
+                    .get_file_content(ctx.clone(), cs_id, path.clone())
+                    .and_then(move |_| store.get_file_content(ctx, cs_id, path))
+                    // Hooks may ignore failed fetches
+                    .then(|_| Ok(HookExecution::Accepted))
+                    .boxify()
+            };
+            let res = limiter.run(store, fetch_twice).wait();
+            assert_matches!(limiter.outcome(res), Ok(HookExecution::Rejected(_)));
+        });
+    }
+}
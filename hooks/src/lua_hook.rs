@@ -11,7 +11,7 @@
 use super::errors::*;
 use super::{
     phabricator_message_parser::PhabricatorMessage, ChangedFileType, Hook, HookChangeset,
-    HookChangesetParents, HookContext, HookExecution, HookFile, HookRejectionInfo,
+    HookChangesetParents, HookContext, HookExecution, HookFile, HookRejectionInfo, HookState,
 };
 use aclchecker::Identity;
 use context::CoreContext;
@@ -26,7 +26,7 @@ use hlua::{
 use hlua_futures::{AnyFuture, LuaCoroutine, LuaCoroutineBuilder};
 use linked_hash_map::LinkedHashMap;
 use metaconfig_types::HookConfig;
-use mononoke_types::FileType;
+use mononoke_types::{hash, FileType};
 use regex::{Regex, RegexBuilder};
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, RwLock};
@@ -176,15 +176,26 @@ impl Hook<HookChangeset> for LuaHook {
             })
         };
 
+        let has_commit_flag = {
+            let flags = context.data.flags;
+            function1(move |flag: String| -> Result<AnyFuture, Error> {
+                Ok(AnyFuture::new(ok(AnyLuaValue::LuaBoolean(
+                    flags.is_set(&flag),
+                ))))
+            })
+        };
+
         let mut lua = Lua::new();
         lua.openlibs();
         add_configs_lua(&mut lua, context.clone());
         add_regex_match_lua(&mut lua);
+        add_state_lua(&mut lua, ctx.clone(), context.state.clone());
         lua.set("g__contains_string", contains_string);
         lua.set("g__file_len", file_len);
         lua.set("g__file_content", file_content);
         lua.set("g__parse_commit_msg", parse_commit_msg);
         lua.set("g__is_valid_reviewer", is_valid_reviewer);
+        lua.set("g__has_commit_flag", has_commit_flag);
         let res: Result<(), Error> = lua
             .execute::<()>(&code)
             .map_err(|e| ErrorKind::HookParseError(e.to_string()).into());
@@ -217,6 +228,10 @@ impl Hook<HookChangeset> for LuaHook {
 
         self.convert_coroutine_res(builder.create((hook_info, files)))
     }
+
+    fn version(&self) -> Option<String> {
+        Some(self.code_version(HOOK_START_CODE_CS))
+    }
 }
 
 impl Hook<HookFile> for LuaHook {
@@ -295,6 +310,7 @@ impl Hook<HookFile> for LuaHook {
         lua.openlibs();
         add_configs_lua(&mut lua, context.clone());
         add_regex_match_lua(&mut lua);
+        add_state_lua(&mut lua, ctx.clone(), context.state.clone());
         lua.set("g__contains_string", contains_string);
         lua.set("g__file_len", file_len);
         lua.set("g__file_content", file_content);
@@ -325,6 +341,10 @@ impl Hook<HookFile> for LuaHook {
         };
         self.convert_coroutine_res(builder.create((HashMap::<&str, String, _>::new(), data)))
     }
+
+    fn version(&self) -> Option<String> {
+        Some(self.code_version(HOOK_START_CODE_FILE))
+    }
 }
 
 impl LuaHook {
@@ -332,6 +352,15 @@ impl LuaHook {
         LuaHook { name, code }
     }
 
+    // Hash of the code that runs, including the code that starts the hook
+    fn code_version(&self, start_code: &str) -> String {
+        let mut context = hash::Context::new(b"luahook");
+        context.update(start_code);
+        context.update(HOOK_START_CODE_BASE);
+        context.update(&self.code);
+        context.finish().to_hex().to_string()
+    }
+
     fn convert_coroutine_res(
         &self,
         res: Result<
@@ -371,6 +400,9 @@ fn add_configs_lua<T: Clone>(lua: &mut Lua, context: HookContext<T>) {
         strings,
         ints,
         bypass: _,
+        bypass_allowed_users: _,
+        limits: _,
+        path_filters: _,
     } = context.config;
     lua.set("g__config_strings", strings);
     lua.set("g__config_ints", ints);
@@ -391,6 +423,19 @@ fn add_regex_match_lua(lua: &mut Lua) {
     )
 }
 
+fn add_state_lua(lua: &mut Lua, ctx: CoreContext, state: HookState) {
+    lua.set(
+        "g__fetch_state",
+        function1(move |url: String| -> Result<AnyFuture, Error> {
+            let future = state
+                .fetch(ctx.clone(), url)
+                .map_err(|err| LuaError::ExecutionError(format!("failed to fetch state: {}", err)))
+                .map(|state| AnyLuaValue::LuaAnyString(AnyLuaString(state.to_vec())));
+            Ok(AnyFuture::new(future))
+        }),
+    )
+}
+
 fn cached_regex_match(
     pattern: String,
     string: String,
@@ -428,6 +473,7 @@ fn cached_regex_match(
 mod test {
     use super::super::{
         ChangedFileType, HookChangeset, HookChangesetParents, InMemoryFileContentStore,
+        InMemoryStateProvider,
     };
     use super::*;
     use aclchecker::AclChecker;
@@ -435,6 +481,7 @@ mod test {
     use bytes::Bytes;
     use futures::Future;
     use mercurial_types::HgChangesetId;
+    use mononoke_types::CommitFlags;
     use std::str::FromStr;
     use std::sync::Arc;
 
@@ -601,6 +648,69 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_cs_hook_fetch_state() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let mut provider = InMemoryStateProvider::new();
+            provider.insert("owners/dir", "some-author");
+            let state = HookState::new("testhook".into(), Some(Arc::new(provider)), None);
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return ctx.fetch_state('owners/dir') == ctx.info.author_unixname\n\
+                 end",
+            );
+            let changeset = default_changeset();
+            assert_matches!(
+                run_changeset_hook_with_state(ctx.clone(), code, changeset, state),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_fetch_missing_state() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let state = HookState::new(
+                "testhook".into(),
+                Some(Arc::new(InMemoryStateProvider::new())),
+                None,
+            );
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return ctx.fetch_state('owners/dir') == nil\n\
+                 end",
+            );
+            let changeset = default_changeset();
+            assert_matches!(
+                run_changeset_hook_with_state(ctx.clone(), code, changeset, state),
+                Err(_)
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_commit_flags() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let changeset = default_changeset().with_flags(CommitFlags {
+                import: true,
+                ..Default::default()
+            });
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return ctx.has_commit_flag('mononoke:import') and\n\
+                 not ctx.has_commit_flag('mononoke:skip-date-rewrite')\n\
+                 end",
+            );
+            assert_matches!(
+                run_changeset_hook(ctx.clone(), code, changeset),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
     #[test]
     fn test_cs_hook_rejected_short_and_long_desc() {
         async_unit::tokio_unit_test(|| {
@@ -1509,8 +1619,11 @@ end"#;
 
         let context = context_construct(HookConfig {
             bypass: None,
+            bypass_allowed_users: vec![],
             strings: hashmap! { "test".to_string() => "val".to_string() },
             ints: hashmap! { "test".to_string() => 44 },
+            limits: Default::default(),
+            path_filters: vec![],
         });
         assert_matches!(
             hook.run(ctx.clone(), context).wait(),
@@ -1519,8 +1632,11 @@ end"#;
 
         let context = context_construct(HookConfig {
             bypass: None,
+            bypass_allowed_users: vec![],
             strings: hashmap! {},
             ints: hashmap! {},
+            limits: Default::default(),
+            path_filters: vec![],
         });
         assert_rejected(
             hook.run(ctx.clone(), context).wait(),
@@ -1529,11 +1645,14 @@ end"#;
 
         let context = context_construct(HookConfig {
             bypass: None,
+            bypass_allowed_users: vec![],
             strings: hashmap! {
                 "test".to_string() => "val".to_string(),
                 "test2".to_string() => "val2".to_string(),
             },
             ints: hashmap! {},
+            limits: Default::default(),
+            path_filters: vec![],
         });
         assert_rejected(
             hook.run(ctx.clone(), context).wait(),
@@ -1542,18 +1661,24 @@ end"#;
 
         let context = context_construct(HookConfig {
             bypass: None,
+            bypass_allowed_users: vec![],
             strings: hashmap! { "test".to_string() => "val".to_string() },
             ints: hashmap! {},
+            limits: Default::default(),
+            path_filters: vec![],
         });
         assert_rejected(hook.run(ctx.clone(), context).wait(), "missing ints config");
 
         let context = context_construct(HookConfig {
             bypass: None,
+            bypass_allowed_users: vec![],
             strings: hashmap! { "test".to_string() => "val".to_string() },
             ints: hashmap! {
                 "test".to_string() => 44,
                 "test2".to_string() => 44,
             },
+            limits: Default::default(),
+            path_filters: vec![],
         });
         assert_rejected(
             hook.run(ctx.clone(), context).wait(),
@@ -1567,7 +1692,12 @@ end"#;
             let ctx = CoreContext::test_mock();
 
             run_test_for_config_reading(ctx, |conf| {
-                HookContext::new("testhook".into(), conf, default_changeset())
+                HookContext::new(
+                    "testhook".into(),
+                    conf,
+                    default_changeset(),
+                    Default::default(),
+                )
             });
         });
     }
@@ -1578,7 +1708,12 @@ end"#;
             let ctx = CoreContext::test_mock();
 
             run_test_for_config_reading(ctx, |conf| {
-                HookContext::new("testhook".into(), conf, default_hook_added_file())
+                HookContext::new(
+                    "testhook".into(),
+                    conf,
+                    default_hook_added_file(),
+                    Default::default(),
+                )
             });
         });
     }
@@ -1587,9 +1722,18 @@ end"#;
         ctx: CoreContext,
         code: String,
         changeset: HookChangeset,
+    ) -> Result<HookExecution, Error> {
+        run_changeset_hook_with_state(ctx, code, changeset, Default::default())
+    }
+
+    fn run_changeset_hook_with_state(
+        ctx: CoreContext,
+        code: String,
+        changeset: HookChangeset,
+        state: HookState,
     ) -> Result<HookExecution, Error> {
         let hook = LuaHook::new(String::from("testhook"), code.to_string());
-        let context = HookContext::new(hook.name.clone(), Default::default(), changeset);
+        let context = HookContext::new(hook.name.clone(), Default::default(), changeset, state);
         hook.run(ctx, context).wait()
     }
 
@@ -1599,7 +1743,12 @@ end"#;
         hook_file: HookFile,
     ) -> Result<HookExecution, Error> {
         let hook = LuaHook::new(String::from("testhook"), code.to_string());
-        let context = HookContext::new(hook.name.clone(), Default::default(), hook_file);
+        let context = HookContext::new(
+            hook.name.clone(),
+            Default::default(),
+            hook_file,
+            Default::default(),
+        );
         hook.run(ctx, context).wait()
     }
 
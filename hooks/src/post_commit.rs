@@ -0,0 +1,604 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Post-commit hooks run once a push succeeded, and never block it. The push only adds its
+//! changesets to a durable queue, one entry per post-commit hook, and background workers run the
+//! hooks later. A worker claims the entries it runs for a while, so that workers of other servers
+//! don't run them too. A hook that rejects a changeset or fails is retried with exponential
+//! backoff, and given up on after too many attempts. Entries that were given up on stay in the
+//! queue, so that they can be inspected and retried by hand.
+
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use context::CoreContext;
+use failure::Error;
+use futures::future::{self, loop_fn, Loop};
+use futures::{stream, Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::HgChangesetId;
+use mononoke_types::{DateTime, RepositoryId, Timestamp};
+use slog::Logger;
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+use sql_ext::SqlConstructors;
+use stats::Timeseries;
+use tokio::timer::Delay;
+
+use super::{HookExecution, HookManager};
+
+/// How many entries a worker takes from the queue at once.
+const WORKER_BATCH_SIZE: usize = 100;
+const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+/// How long a worker has to run the hooks of the entries it claimed, before other workers may
+/// claim them again. Hooks time out long before that.
+const CLAIM_LEASE: Duration = Duration::from_secs(600);
+
+define_stats! {
+    prefix = "mononoke.hooks.post_commit";
+    queued: timeseries(RATE, SUM),
+    accepted: timeseries(RATE, SUM),
+    failures: timeseries(RATE, SUM),
+    given_up: timeseries(RATE, SUM),
+    worker_errors: timeseries(RATE, SUM),
+    lost_claims: timeseries(RATE, SUM),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PostCommitQueueEntry {
+    pub repo_id: RepositoryId,
+    pub hook_name: String,
+    pub cs_id: HgChangesetId,
+    pub timestamp: DateTime,
+    /// Runs of the hook on the changeset that failed so far
+    pub attempts: u32,
+    /// When the hook runs next, None if it was given up on
+    pub next_attempt: Option<Timestamp>,
+    /// Why the last run failed
+    pub last_error: Option<String>,
+    pub id: Option<u64>,
+}
+
+impl PostCommitQueueEntry {
+    pub fn new(
+        repo_id: RepositoryId,
+        hook_name: String,
+        cs_id: HgChangesetId,
+        timestamp: DateTime,
+    ) -> Self {
+        Self {
+            repo_id,
+            hook_name,
+            cs_id,
+            timestamp,
+            attempts: 0,
+            next_attempt: Some(timestamp.into()),
+            last_error: None,
+            id: None,
+        }
+    }
+}
+
+pub trait PostCommitQueue: Send + Sync {
+    /// Add entries to the queue. Hooks that are already queued for a changeset are ignored.
+    fn add(&self, ctx: CoreContext, entries: Vec<PostCommitQueueEntry>) -> BoxFuture<(), Error>;
+
+    /// Returns at most `limit` of the oldest entries for the repo whose next attempt is due at
+    /// `now`, and that nobody claimed, oldest first.
+    fn due(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        now: Timestamp,
+        limit: usize,
+    ) -> BoxFuture<Vec<PostCommitQueueEntry>, Error>;
+
+    /// Like `due`, but also claims the entries for `claimed_by` until `lease` after `now`. Only
+    /// the entries that were claimed are returned, so that an entry that two workers try to claim
+    /// at once is only returned to one of them. `update` and `del` release the claims.
+    fn claim(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        now: Timestamp,
+        limit: usize,
+        claimed_by: String,
+        lease: Duration,
+    ) -> BoxFuture<Vec<PostCommitQueueEntry>, Error>;
+
+    /// Returns at most `limit` of the oldest entries for the repo, including the ones that were
+    /// given up on, oldest first.
+    fn list(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        limit: usize,
+    ) -> BoxFuture<Vec<PostCommitQueueEntry>, Error>;
+
+    /// Store the attempts, next attempt and last error of entries that are already queued, and
+    /// release their claims.
+    fn update(&self, ctx: CoreContext, entries: Vec<PostCommitQueueEntry>) -> BoxFuture<(), Error>;
+
+    fn del(&self, ctx: CoreContext, entries: Vec<PostCommitQueueEntry>) -> BoxFuture<(), Error>;
+}
+
+#[derive(Clone)]
+pub struct SqlPostCommitQueue {
+    write_connection: Connection,
+    read_connection: Connection,
+    read_master_connection: Connection,
+}
+
+queries! {
+    write InsertEntries(values: (
+        repo_id: RepositoryId,
+        hook_name: String,
+        cs_id: HgChangesetId,
+        timestamp: Timestamp,
+        next_attempt: Timestamp,
+    )) {
+        insert_or_ignore,
+        "{insert_or_ignore}
+         INTO post_commit_queue (repo_id, hook_name, cs_id, add_timestamp, next_attempt)
+         VALUES {values}"
+    }
+
+    write UpdateEntry(
+        id: u64,
+        attempts: u32,
+        next_attempt: Option<Timestamp>,
+        last_error: Option<String>,
+    ) {
+        none,
+        "UPDATE post_commit_queue
+         SET attempts = {attempts}, next_attempt = {next_attempt}, last_error = {last_error},
+             claimed_by = NULL, claimed_until = NULL
+         WHERE id = {id}"
+    }
+
+    write ClaimEntry(id: u64, claimed_by: String, claimed_until: Timestamp, now: Timestamp) {
+        none,
+        "UPDATE post_commit_queue
+         SET claimed_by = {claimed_by}, claimed_until = {claimed_until}
+         WHERE id = {id} AND (claimed_until IS NULL OR claimed_until <= {now})"
+    }
+
+    write DeleteEntry(id: u64) {
+        none,
+        "DELETE FROM post_commit_queue
+         WHERE id = {id}"
+    }
+
+    read GetDueEntries(repo_id: RepositoryId, now: Timestamp, limit: usize) -> (
+        RepositoryId,
+        String,
+        HgChangesetId,
+        Timestamp,
+        u32,
+        Option<Timestamp>,
+        Option<String>,
+        u64,
+    ) {
+        "SELECT repo_id, hook_name, cs_id, add_timestamp, attempts, next_attempt, last_error, id
+         FROM post_commit_queue
+         WHERE repo_id = {repo_id} AND next_attempt <= {now}
+           AND (claimed_until IS NULL OR claimed_until <= {now})
+         ORDER BY id ASC
+         LIMIT {limit}"
+    }
+
+    read GetEntries(repo_id: RepositoryId, limit: usize) -> (
+        RepositoryId,
+        String,
+        HgChangesetId,
+        Timestamp,
+        u32,
+        Option<Timestamp>,
+        Option<String>,
+        u64,
+    ) {
+        "SELECT repo_id, hook_name, cs_id, add_timestamp, attempts, next_attempt, last_error, id
+         FROM post_commit_queue
+         WHERE repo_id = {repo_id}
+         ORDER BY id ASC
+         LIMIT {limit}"
+    }
+}
+
+impl SqlConstructors for SqlPostCommitQueue {
+    fn from_connections(
+        write_connection: Connection,
+        read_connection: Connection,
+        read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_connection,
+            read_master_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-post-commit-queue.sql")
+    }
+}
+
+impl SqlMigrations for SqlPostCommitQueue {
+    const STORE: &'static str = "post_commit_queue";
+
+    fn migrations() -> &'static [Migration] {
+        &[
+            Migration {
+                version: 1,
+                description: "create the post_commit_queue table",
+                sqlite: "CREATE TABLE `post_commit_queue` (
+                           `id` INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                           `repo_id` INT UNSIGNED NOT NULL,
+                           `hook_name` VARCHAR(255) NOT NULL,
+                           `cs_id` VARBINARY(20) NOT NULL,
+                           `add_timestamp` BIGINT NOT NULL,
+                           `attempts` INT UNSIGNED NOT NULL DEFAULT 0,
+                           `next_attempt` BIGINT,
+                           `last_error` TEXT,
+                           UNIQUE (`repo_id`, `hook_name`, `cs_id`)
+                         );
+
+                         CREATE INDEX `post_commit_queue_next_attempt` ON `post_commit_queue` (`repo_id`, `next_attempt`);",
+                mysql: "CREATE TABLE `post_commit_queue` (
+                          `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+                          `repo_id` INT UNSIGNED NOT NULL,
+                          `hook_name` VARCHAR(255) NOT NULL,
+                          `cs_id` VARBINARY(20) NOT NULL,
+                          `add_timestamp` BIGINT NOT NULL,
+                          `attempts` INT UNSIGNED NOT NULL DEFAULT 0,
+                          `next_attempt` BIGINT,
+                          `last_error` TEXT,
+                          PRIMARY KEY (`id`),
+                          UNIQUE KEY `post_commit_queue_entry` (`repo_id`, `hook_name`, `cs_id`),
+                          KEY `post_commit_queue_next_attempt` (`repo_id`, `next_attempt`)
+                        ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+            },
+            Migration {
+                version: 2,
+                description: "add the claims of the workers running the hooks",
+                sqlite: "ALTER TABLE `post_commit_queue` ADD COLUMN `claimed_by` VARCHAR(255);
+                         ALTER TABLE `post_commit_queue` ADD COLUMN `claimed_until` BIGINT;",
+                mysql: "ALTER TABLE `post_commit_queue`
+                          ADD COLUMN `claimed_by` VARCHAR(255) AFTER `last_error`,
+                          ADD COLUMN `claimed_until` BIGINT AFTER `claimed_by`;",
+            },
+        ]
+    }
+}
+
+fn entry_from_row(
+    (repo_id, hook_name, cs_id, timestamp, attempts, next_attempt, last_error, id): (
+        RepositoryId,
+        String,
+        HgChangesetId,
+        Timestamp,
+        u32,
+        Option<Timestamp>,
+        Option<String>,
+        u64,
+    ),
+) -> PostCommitQueueEntry {
+    PostCommitQueueEntry {
+        repo_id,
+        hook_name,
+        cs_id,
+        timestamp: timestamp.into(),
+        attempts,
+        next_attempt,
+        last_error,
+        id: Some(id),
+    }
+}
+
+fn entry_ids(
+    entries: Vec<PostCommitQueueEntry>,
+) -> Result<Vec<(u64, PostCommitQueueEntry)>, Error> {
+    entries
+        .into_iter()
+        .map(|entry| match entry.id {
+            Some(id) => Ok((id, entry)),
+            None => Err(format_err!(
+                "PostCommitQueueEntry must contain `id` to be able to change it"
+            )),
+        })
+        .collect()
+}
+
+impl PostCommitQueue for SqlPostCommitQueue {
+    fn add(&self, _ctx: CoreContext, entries: Vec<PostCommitQueueEntry>) -> BoxFuture<(), Error> {
+        STATS::queued.add_value(entries.len() as i64);
+        if entries.is_empty() {
+            return future::ok(()).boxify();
+        }
+
+        let rows: Vec<_> = entries
+            .into_iter()
+            .map(|entry| {
+                let next_attempt = entry.next_attempt.unwrap_or_else(Timestamp::now);
+                (
+                    entry.repo_id,
+                    entry.hook_name,
+                    entry.cs_id,
+                    Timestamp::from(entry.timestamp),
+                    next_attempt,
+                )
+            })
+            .collect();
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|(repo_id, hook_name, cs_id, timestamp, next_attempt)| {
+                (repo_id, hook_name, cs_id, timestamp, next_attempt)
+            })
+            .collect();
+
+        InsertEntries::query(&self.write_connection, &rows[..])
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn due(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        now: Timestamp,
+        limit: usize,
+    ) -> BoxFuture<Vec<PostCommitQueueEntry>, Error> {
+        GetDueEntries::query(&self.read_master_connection, &repo_id, &now, &limit)
+            .map(|rows| rows.into_iter().map(entry_from_row).collect())
+            .boxify()
+    }
+
+    fn claim(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        now: Timestamp,
+        limit: usize,
+        claimed_by: String,
+        lease: Duration,
+    ) -> BoxFuture<Vec<PostCommitQueueEntry>, Error> {
+        let claimed_until = after(now, lease);
+        self.due(ctx, repo_id, now, limit)
+            .and_then({
+                cloned!(self.write_connection);
+                move |entries| {
+                    let claims = entries.into_iter().filter_map(move |entry| {
+                        let id = entry.id?;
+                        let claim = ClaimEntry::query(
+                            &write_connection,
+                            &id,
+                            &claimed_by,
+                            &claimed_until,
+                            &now,
+                        )
+                        .map(move |result| {
+                            if result.affected_rows() == 1 {
+                                Some(entry)
+                            } else {
+                                // Another worker claimed it since it was read
+                                STATS::lost_claims.add_value(1);
+                                None
+                            }
+                        });
+                        Some(claim)
+                    });
+                    future::join_all(claims)
+                }
+            })
+            .map(|entries| entries.into_iter().filter_map(|entry| entry).collect())
+            .boxify()
+    }
+
+    fn list(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        limit: usize,
+    ) -> BoxFuture<Vec<PostCommitQueueEntry>, Error> {
+        GetEntries::query(&self.read_connection, &repo_id, &limit)
+            .map(|rows| rows.into_iter().map(entry_from_row).collect())
+            .boxify()
+    }
+
+    fn update(
+        &self,
+        _ctx: CoreContext,
+        entries: Vec<PostCommitQueueEntry>,
+    ) -> BoxFuture<(), Error> {
+        entry_ids(entries)
+            .into_future()
+            .and_then({
+                cloned!(self.write_connection);
+                move |entries| {
+                    future::join_all(entries.into_iter().map(move |(id, entry)| {
+                        UpdateEntry::query(
+                            &write_connection,
+                            &id,
+                            &entry.attempts,
+                            &entry.next_attempt,
+                            &entry.last_error,
+                        )
+                    }))
+                }
+            })
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn del(&self, _ctx: CoreContext, entries: Vec<PostCommitQueueEntry>) -> BoxFuture<(), Error> {
+        entry_ids(entries)
+            .into_future()
+            .and_then({
+                cloned!(self.write_connection);
+                move |entries| {
+                    future::join_all(
+                        entries
+                            .into_iter()
+                            .map(move |(id, _)| DeleteEntry::query(&write_connection, &id)),
+                    )
+                }
+            })
+            .map(|_| ())
+            .boxify()
+    }
+}
+
+/// How failing post-commit hooks are retried
+#[derive(Clone, Copy, Debug)]
+pub struct PostCommitRetries {
+    /// Runs of a hook on a changeset before it is given up on
+    pub max_attempts: u32,
+    /// The wait before the first retry. It doubles after every failed retry.
+    pub initial_backoff: Duration,
+}
+
+impl Default for PostCommitRetries {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+        }
+    }
+}
+
+impl PostCommitRetries {
+    /// When a hook that failed `attempts` times on a changeset runs next, None if it is given up
+    /// on
+    pub fn next_attempt(&self, attempts: u32, now: Timestamp) -> Option<Timestamp> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let backoff = self.initial_backoff * 2u32.pow(cmp::min(attempts.saturating_sub(1), 16));
+        Some(after(now, backoff))
+    }
+}
+
+fn after(timestamp: Timestamp, duration: Duration) -> Timestamp {
+    let nanos = duration.as_secs() as i64 * 1_000_000_000 + duration.subsec_nanos() as i64;
+    Timestamp::from_timestamp_nanos(timestamp.timestamp_nanos() + nanos)
+}
+
+/// Claim up to `limit` of the oldest due entries for the repo, and run their hooks. Entries whose
+/// hook accepts the changeset are removed from the queue, the others are released and retried
+/// later. Returns the number of entries processed.
+pub fn run_queued_post_commit_hooks(
+    ctx: CoreContext,
+    hook_manager: Arc<HookManager>,
+    queue: Arc<PostCommitQueue>,
+    repo_id: RepositoryId,
+    limit: usize,
+    retries: PostCommitRetries,
+) -> BoxFuture<usize, Error> {
+    let claimed_by = ctx.session().to_string();
+    queue
+        .claim(
+            ctx.clone(),
+            repo_id,
+            Timestamp::now(),
+            limit,
+            claimed_by,
+            CLAIM_LEASE,
+        )
+        .and_then(move |entries| {
+            let count = entries.len();
+            stream::iter_ok(entries)
+                .for_each(move |entry| {
+                    hook_manager
+                        .run_post_commit_hook(ctx.clone(), entry.cs_id, &entry.hook_name)
+                        .then({
+                            cloned!(ctx, queue);
+                            move |res| match res {
+                                Ok(HookExecution::Accepted) => {
+                                    STATS::accepted.add_value(1);
+                                    queue.del(ctx, vec![entry])
+                                }
+                                Ok(HookExecution::Rejected(info)) => {
+                                    record_failure(ctx, queue, retries, entry, info.description)
+                                }
+                                Err(err) => {
+                                    record_failure(ctx, queue, retries, entry, format!("{:?}", err))
+                                }
+                            }
+                        })
+                })
+                .map(move |()| count)
+        })
+        .boxify()
+}
+
+fn record_failure(
+    ctx: CoreContext,
+    queue: Arc<PostCommitQueue>,
+    retries: PostCommitRetries,
+    mut entry: PostCommitQueueEntry,
+    error: String,
+) -> BoxFuture<(), Error> {
+    STATS::failures.add_value(1);
+    entry.attempts += 1;
+    entry.next_attempt = retries.next_attempt(entry.attempts, Timestamp::now());
+    if entry.next_attempt.is_none() {
+        STATS::given_up.add_value(1);
+        error!(
+            ctx.logger(),
+            "post-commit hook {} failed on {} after {} attempts, giving up: {}",
+            entry.hook_name,
+            entry.cs_id,
+            entry.attempts,
+            error
+        );
+    }
+    entry.last_error = Some(error);
+    queue.update(ctx, vec![entry])
+}
+
+/// Run the post-commit hooks queued for the repo forever. When nothing is due, or processing
+/// fails, the worker waits for `poll_interval` before trying again.
+pub fn run_post_commit_worker(
+    ctx: CoreContext,
+    hook_manager: Arc<HookManager>,
+    queue: Arc<PostCommitQueue>,
+    repo_id: RepositoryId,
+    logger: Logger,
+    poll_interval: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    loop_fn((), move |()| {
+        run_queued_post_commit_hooks(
+            ctx.clone(),
+            hook_manager.clone(),
+            queue.clone(),
+            repo_id,
+            WORKER_BATCH_SIZE,
+            PostCommitRetries::default(),
+        )
+        .then({
+            cloned!(logger);
+            move |result| {
+                let idle = match result {
+                    Ok(count) => count == 0,
+                    Err(err) => {
+                        STATS::worker_errors.add_value(1);
+                        warn!(logger, "failed to run queued post-commit hooks: {:?}", err);
+                        true
+                    }
+                };
+                if idle {
+                    Delay::new(Instant::now() + poll_interval)
+                        .then(|_| Ok(Loop::Continue(())))
+                        .left_future()
+                } else {
+                    future::ok(Loop::Continue(())).right_future()
+                }
+            }
+        })
+    })
+}
@@ -0,0 +1,323 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Caching of file hook outcomes across pushes. Rebased commits usually change the same files
+//! the same way as the commits they were rebased from, so a file hook that already ran on a file
+//! doesn't run again.
+//!
+//! Outcomes are keyed by the name of the hook, its version and the identity of the file it ran
+//! on. The version of a hook covers both its code and its config, so changing either of them
+//! invalidates its outcomes. Only hooks that know the version of their code are cached: Rust
+//! hooks change with the server binary, and don't. Changeset hooks are never cached, as they see
+//! the parents of a changeset and who pushed it, which differ from one push to the next.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use context::CoreContext;
+use failure::Error;
+use futures::{future, Future, IntoFuture};
+use futures_ext::{BoxFuture, FutureExt};
+use metaconfig_types::HookConfig;
+use mononoke_types::{hash, DateTime, Timestamp};
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+use sql_ext::SqlConstructors;
+use stats::Timeseries;
+
+use super::{ChangedFileType, HookExecution, HookFile, HookRejectionInfo};
+
+define_stats! {
+    prefix = "mononoke.hooks.result_cache";
+    hits: timeseries(RATE, SUM),
+    misses: timeseries(RATE, SUM),
+    store_failures: timeseries(RATE, SUM),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HookResultKey {
+    pub hook_name: String,
+    /// See `hook_version`
+    pub hook_version: String,
+    /// See `file_content_id`
+    pub content_id: String,
+}
+
+pub trait HookResultStore: Send + Sync {
+    /// The outcome stored for `key`, if it hasn't expired at `now`
+    fn get(
+        &self,
+        ctx: CoreContext,
+        key: HookResultKey,
+        now: Timestamp,
+    ) -> BoxFuture<Option<HookExecution>, Error>;
+
+    /// Store the outcome for `key` until `expires`, replacing any previous one
+    fn set(
+        &self,
+        ctx: CoreContext,
+        key: HookResultKey,
+        execution: HookExecution,
+        expires: Timestamp,
+    ) -> BoxFuture<(), Error>;
+}
+
+#[derive(Clone)]
+pub struct HookResultCache {
+    store: Arc<HookResultStore>,
+    ttl_secs: u64,
+}
+
+impl HookResultCache {
+    /// Outcomes are kept in `store` for `ttl_secs`
+    pub fn new(store: Arc<HookResultStore>, ttl_secs: u64) -> Self {
+        Self { store, ttl_secs }
+    }
+
+    /// The outcome stored for `key`, or the outcome of `run` which is then stored. The cache
+    /// failing doesn't fail the hook, it only runs it.
+    pub(crate) fn get_or_run<F>(
+        &self,
+        ctx: CoreContext,
+        key: HookResultKey,
+        run: F,
+    ) -> BoxFuture<HookExecution, Error>
+    where
+        F: FnOnce() -> BoxFuture<HookExecution, Error> + Send + 'static,
+    {
+        let now = Timestamp::from(DateTime::new(ctx.now()));
+        let expires = Timestamp::from_timestamp_nanos(
+            now.timestamp_nanos() + self.ttl_secs as i64 * 1_000_000_000,
+        );
+        let store = self.store.clone();
+
+        self.store
+            .get(ctx.clone(), key.clone(), now)
+            .then(|res| {
+                if res.is_err() {
+                    STATS::store_failures.add_value(1);
+                }
+                Ok(res.unwrap_or(None))
+            })
+            .and_then(move |cached| match cached {
+                Some(execution) => {
+                    STATS::hits.add_value(1);
+                    future::ok(execution).left_future()
+                }
+                None => {
+                    STATS::misses.add_value(1);
+                    run()
+                        .and_then(move |execution| {
+                            store
+                                .set(ctx, key, execution.clone(), expires)
+                                .then(move |res| {
+                                    if res.is_err() {
+                                        STATS::store_failures.add_value(1);
+                                    }
+                                    Ok(execution)
+                                })
+                        })
+                        .right_future()
+                }
+            })
+            .boxify()
+    }
+}
+
+/// The version of a hook whose code is at `code_version` and that runs with `config`
+pub fn hook_version(code_version: &str, config: &HookConfig) -> String {
+    let mut context = hash::Context::new(b"hookversion");
+    add_field(&mut context, code_version);
+    add_field(&mut context, format!("{:?}", config.bypass));
+    // The maps are sorted, so that the version doesn't depend on their order
+    let strings: BTreeMap<_, _> = config.strings.iter().collect();
+    add_field(&mut context, format!("{:?}", strings));
+    let ints: BTreeMap<_, _> = config.ints.iter().collect();
+    add_field(&mut context, format!("{:?}", ints));
+    // Path filters change which files the hook sees. They're left out when there are none, so
+    // that the versions of hooks without filters are the same as before filters existed
+    if !config.path_filters.is_empty() {
+        add_field(&mut context, format!("{:?}", config.path_filters));
+    }
+    context.finish().to_hex().to_string()
+}
+
+/// The identity of a file, as seen by file hooks: its path, type, whether it was added or
+/// modified, and the id of its content. The content itself isn't fetched, so that large files
+/// cost no more than small ones.
+pub fn file_content_id(ctx: CoreContext, file: &HookFile) -> BoxFuture<String, Error> {
+    let path = file.path.clone();
+    let change = match file.changed_file_type() {
+        ChangedFileType::Added => "added",
+        ChangedFileType::Deleted => "deleted",
+        ChangedFileType::Modified => "modified",
+    };
+    (file.file_type(ctx.clone()), file.content_id(ctx))
+        .into_future()
+        .map(move |(file_type, content_id)| {
+            let mut context = hash::Context::new(b"hookfile");
+            add_field(&mut context, path);
+            add_field(&mut context, format!("{:?}", file_type));
+            add_field(&mut context, change);
+            add_field(&mut context, content_id);
+            context.finish().to_hex().to_string()
+        })
+        .boxify()
+}
+
+// Fields are prefixed with their length, so that moving bytes from one to the next changes the
+// hash
+fn add_field<T: AsRef<[u8]>>(context: &mut hash::Context, field: T) {
+    let field = field.as_ref();
+    context.update((field.len() as u64).to_le_bytes());
+    context.update(field);
+}
+
+queries! {
+    write ReplaceResults(values: (
+        hook_name: String,
+        hook_version: String,
+        content_id: String,
+        rejection: Option<String>,
+        long_rejection: Option<String>,
+        expires: Timestamp,
+    )) {
+        none,
+        "REPLACE INTO hook_results
+         (hook_name, hook_version, content_id, rejection, long_rejection, expires)
+         VALUES {values}"
+    }
+
+    write DeleteExpiredResults(now: Timestamp) {
+        none,
+        "DELETE FROM hook_results WHERE expires <= {now}"
+    }
+
+    read SelectResult(hook_name: String, hook_version: String, content_id: String, now: Timestamp)
+        -> (Option<String>, Option<String>) {
+        "SELECT rejection, long_rejection FROM hook_results
+         WHERE hook_name = {hook_name} AND hook_version = {hook_version}
+           AND content_id = {content_id} AND expires > {now}"
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlHookResultStore {
+    write_connection: Connection,
+    read_connection: Connection,
+}
+
+impl SqlConstructors for SqlHookResultStore {
+    fn from_connections(
+        write_connection: Connection,
+        read_connection: Connection,
+        _read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-hook-results.sql")
+    }
+}
+
+impl SqlMigrations for SqlHookResultStore {
+    const STORE: &'static str = "hook_results";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the hook_results table",
+            sqlite: "CREATE TABLE `hook_results` (
+                       `hook_name` VARCHAR(255) NOT NULL,
+                       `hook_version` VARCHAR(64) NOT NULL,
+                       `content_id` VARCHAR(64) NOT NULL,
+                       `rejection` TEXT,
+                       `long_rejection` TEXT,
+                       `expires` BIGINT NOT NULL,
+                       PRIMARY KEY (`hook_name`, `hook_version`, `content_id`)
+                     );
+
+                     CREATE INDEX `hook_results_expires` ON `hook_results` (`expires`);",
+            mysql: "CREATE TABLE `hook_results` (
+                      `hook_name` VARCHAR(255) NOT NULL,
+                      `hook_version` VARCHAR(64) NOT NULL,
+                      `content_id` VARCHAR(64) NOT NULL,
+                      `rejection` TEXT,
+                      `long_rejection` TEXT,
+                      `expires` BIGINT NOT NULL,
+                      PRIMARY KEY (`hook_name`, `hook_version`, `content_id`),
+                      KEY `hook_results_expires` (`expires`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+impl HookResultStore for SqlHookResultStore {
+    fn get(
+        &self,
+        _ctx: CoreContext,
+        key: HookResultKey,
+        now: Timestamp,
+    ) -> BoxFuture<Option<HookExecution>, Error> {
+        // Outcomes that are not replicated yet are only missed once, the hook runs again
+        SelectResult::query(
+            &self.read_connection,
+            &key.hook_name,
+            &key.hook_version,
+            &key.content_id,
+            &now,
+        )
+        .map(|rows| {
+            rows.into_iter()
+                .next()
+                .map(|(rejection, long_rejection)| match rejection {
+                    None => HookExecution::Accepted,
+                    Some(description) => HookExecution::Rejected(HookRejectionInfo::new(
+                        description,
+                        long_rejection.unwrap_or_default(),
+                    )),
+                })
+        })
+        .boxify()
+    }
+
+    fn set(
+        &self,
+        ctx: CoreContext,
+        key: HookResultKey,
+        execution: HookExecution,
+        expires: Timestamp,
+    ) -> BoxFuture<(), Error> {
+        let (rejection, long_rejection) = match execution {
+            HookExecution::Accepted => (None, None),
+            HookExecution::Rejected(info) => (Some(info.description), Some(info.long_description)),
+        };
+
+        // Expired outcomes are only cleaned up when new ones come in
+        let now = Timestamp::from(DateTime::new(ctx.now()));
+        let write_connection = self.write_connection.clone();
+        DeleteExpiredResults::query(&self.write_connection, &now)
+            .and_then(move |_| {
+                ReplaceResults::query(
+                    &write_connection,
+                    &[(
+                        &key.hook_name,
+                        &key.hook_version,
+                        &key.content_id,
+                        &rejection,
+                        &long_rejection,
+                        &expires,
+                    )],
+                )
+            })
+            .map(|_| ())
+            .boxify()
+    }
+}
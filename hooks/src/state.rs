@@ -0,0 +1,334 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Access of hooks to state kept outside of the repo, e.g. by services that know who owns which
+//! files. Hooks fetch such state through a `HookStateProvider` that the server injects into the
+//! hook manager, rather than with clients of their own, so that responses are cached across runs
+//! and tests can mock the services.
+//!
+//! Each run of a hook gets a `HookState`, which limits the number of fetches of the run.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use context::CoreContext;
+use failure::{err_msg, Error};
+use futures::{future, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use hyper::client::HttpConnector;
+use hyper::{Client, Uri};
+use hyper_tls::HttpsConnector;
+use linked_hash_map::LinkedHashMap;
+use stats::Timeseries;
+
+use errors::ErrorKind;
+
+define_stats! {
+    prefix = "mononoke.hooks.state";
+    fetches: timeseries(RATE, SUM),
+    cache_hits: timeseries(RATE, SUM),
+    budget_exceeded: timeseries(RATE, SUM),
+}
+
+pub trait HookStateProvider: Send + Sync {
+    /// The state at `url`. Fails if there is none.
+    fn fetch(&self, ctx: CoreContext, url: String) -> BoxFuture<Bytes, Error>;
+}
+
+/// Fetches state with HTTP GET requests. Responses that are not a success are errors.
+pub struct HttpStateProvider {
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl HttpStateProvider {
+    pub fn new() -> Result<Self, Error> {
+        let connector = HttpsConnector::new(1)?;
+        Ok(Self {
+            client: Client::builder().build(connector),
+        })
+    }
+}
+
+impl HookStateProvider for HttpStateProvider {
+    fn fetch(&self, _ctx: CoreContext, url: String) -> BoxFuture<Bytes, Error> {
+        let uri: Uri = try_boxfuture!(url.parse());
+        self.client
+            .get(uri)
+            .from_err()
+            .and_then(move |response| {
+                let status = response.status();
+                if status.is_success() {
+                    response
+                        .into_body()
+                        .concat2()
+                        .from_err()
+                        .map(|body| body.into_bytes())
+                        .left_future()
+                } else {
+                    let err = ErrorKind::HookStateFetchFailed(url, status.to_string());
+                    future::err(err.into()).right_future()
+                }
+            })
+            .boxify()
+    }
+}
+
+/// Keeps the successful fetches of another provider for some time, so that runs of hooks on
+/// the many changesets of a push don't fetch the same state over and over
+pub struct CachingStateProvider {
+    inner: Arc<HookStateProvider>,
+    ttl: Duration,
+    capacity: usize,
+    // Ordered from the least to the most recently fetched
+    cache: Arc<Mutex<LinkedHashMap<String, (Instant, Bytes)>>>,
+}
+
+impl CachingStateProvider {
+    /// Fetches are kept for `ttl`, and only the `capacity` most recent ones are kept
+    pub fn new(inner: Arc<HookStateProvider>, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            inner,
+            ttl,
+            capacity,
+            cache: Arc::new(Mutex::new(LinkedHashMap::new())),
+        }
+    }
+}
+
+impl HookStateProvider for CachingStateProvider {
+    fn fetch(&self, ctx: CoreContext, url: String) -> BoxFuture<Bytes, Error> {
+        {
+            let mut cache = self.cache.lock().expect("lock poisoned");
+            match cache.get(&url) {
+                Some((fetched, state)) if fetched.elapsed() < self.ttl => {
+                    STATS::cache_hits.add_value(1);
+                    return future::ok(state.clone()).boxify();
+                }
+                Some(_) => {
+                    cache.remove(&url);
+                }
+                None => {}
+            }
+        }
+
+        let cache = self.cache.clone();
+        let capacity = self.capacity;
+        self.inner
+            .fetch(ctx, url.clone())
+            .map(move |state| {
+                let mut cache = cache.lock().expect("lock poisoned");
+                cache.insert(url, (Instant::now(), state.clone()));
+                while cache.len() > capacity {
+                    cache.pop_front();
+                }
+                state
+            })
+            .boxify()
+    }
+}
+
+/// State kept in memory, for tests
+pub struct InMemoryStateProvider {
+    states: HashMap<String, Bytes>,
+}
+
+impl InMemoryStateProvider {
+    pub fn new() -> Self {
+        Self {
+            states: HashMap::new(),
+        }
+    }
+
+    pub fn insert<T: Into<Bytes>>(&mut self, url: &str, state: T) {
+        self.states.insert(url.to_string(), state.into());
+    }
+}
+
+impl HookStateProvider for InMemoryStateProvider {
+    fn fetch(&self, _ctx: CoreContext, url: String) -> BoxFuture<Bytes, Error> {
+        match self.states.get(&url) {
+            Some(state) => future::ok(state.clone()).boxify(),
+            None => {
+                let err = ErrorKind::HookStateFetchFailed(url, "not found".to_string());
+                future::err(err.into()).boxify()
+            }
+        }
+    }
+}
+
+/// The state that a single run of a hook may fetch
+#[derive(Clone)]
+pub struct HookState {
+    hook_name: String,
+    provider: Option<Arc<HookStateProvider>>,
+    max_fetches: Option<u64>,
+    fetches: Arc<AtomicUsize>,
+}
+
+impl HookState {
+    /// The run fails its fetches if there is no `provider`, and once it made `max_fetches`
+    pub fn new(
+        hook_name: String,
+        provider: Option<Arc<HookStateProvider>>,
+        max_fetches: Option<u64>,
+    ) -> Self {
+        Self {
+            hook_name,
+            provider,
+            max_fetches,
+            fetches: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn fetch(&self, ctx: CoreContext, url: String) -> BoxFuture<Bytes, Error> {
+        let provider = match self.provider {
+            Some(ref provider) => provider,
+            None => {
+                let err = err_msg("hooks can't fetch state on this server");
+                return future::err(err).boxify();
+            }
+        };
+
+        let fetches = self.fetches.fetch_add(1, Ordering::SeqCst) as u64;
+        if let Some(max_fetches) = self.max_fetches {
+            if fetches >= max_fetches {
+                STATS::budget_exceeded.add_value(1);
+                let limit = format!("budget of {} state fetches", max_fetches);
+                let err = ErrorKind::HookLimitExceeded(self.hook_name.clone(), limit);
+                return future::err(err.into()).boxify();
+            }
+        }
+
+        STATS::fetches.add_value(1);
+        provider.fetch(ctx, url)
+    }
+}
+
+impl Default for HookState {
+    /// State of a run that can't fetch any
+    fn default() -> Self {
+        Self::new(String::new(), None, None)
+    }
+}
+
+impl fmt::Debug for HookState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "HookState fetches: {}, max_fetches: {:?}",
+            self.fetches.load(Ordering::SeqCst),
+            self.max_fetches
+        )
+    }
+}
+
+impl PartialEq for HookState {
+    // Every run has its own state, which doesn't make their contexts differ
+    fn eq(&self, _other: &HookState) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+
+    // Counts the fetches that reach it
+    struct CountingStateProvider {
+        inner: InMemoryStateProvider,
+        fetches: AtomicUsize,
+    }
+
+    impl HookStateProvider for CountingStateProvider {
+        fn fetch(&self, ctx: CoreContext, url: String) -> BoxFuture<Bytes, Error> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            self.inner.fetch(ctx, url)
+        }
+    }
+
+    fn counting_provider() -> Arc<CountingStateProvider> {
+        let mut inner = InMemoryStateProvider::new();
+        inner.insert("owners/a", "alice");
+        inner.insert("owners/b", "bob");
+        Arc::new(CountingStateProvider {
+            inner,
+            fetches: AtomicUsize::new(0),
+        })
+    }
+
+    #[test]
+    fn fetches_are_cached() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let counting = counting_provider();
+            let provider = CachingStateProvider::new(counting.clone(), Duration::from_secs(60), 1);
+            let fetch = |url: &str| provider.fetch(ctx.clone(), url.to_string()).wait();
+
+            assert_eq!(fetch("owners/a").unwrap(), Bytes::from("alice"));
+            assert_eq!(fetch("owners/a").unwrap(), Bytes::from("alice"));
+            assert_eq!(counting.fetches.load(Ordering::SeqCst), 1);
+
+            // Only the most recent fetch is kept
+            assert_eq!(fetch("owners/b").unwrap(), Bytes::from("bob"));
+            assert_eq!(fetch("owners/a").unwrap(), Bytes::from("alice"));
+            assert_eq!(counting.fetches.load(Ordering::SeqCst), 3);
+
+            // Failed fetches are not kept
+            assert!(fetch("owners/c").is_err());
+            assert!(fetch("owners/c").is_err());
+            assert_eq!(counting.fetches.load(Ordering::SeqCst), 5);
+        });
+    }
+
+    #[test]
+    fn fetches_expire() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let counting = counting_provider();
+            let provider = CachingStateProvider::new(counting.clone(), Duration::from_secs(0), 10);
+
+            for _ in 0..2 {
+                let state = provider.fetch(ctx.clone(), "owners/a".to_string()).wait();
+                assert_eq!(state.unwrap(), Bytes::from("alice"));
+            }
+            assert_eq!(counting.fetches.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn runs_have_a_budget_of_fetches() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let state = HookState::new("hook".to_string(), Some(counting_provider()), Some(2));
+            let fetch = |url: &str| state.fetch(ctx.clone(), url.to_string()).wait();
+
+            assert!(fetch("owners/a").is_ok());
+            assert!(fetch("owners/b").is_ok());
+            let err = fetch("owners/a").unwrap_err();
+            assert_matches!(
+                err.downcast_ref::<ErrorKind>(),
+                Some(ErrorKind::HookLimitExceeded(..))
+            );
+
+            // Other runs have their own budget
+            let state = HookState::new("hook".to_string(), Some(counting_provider()), Some(2));
+            assert!(state
+                .fetch(ctx.clone(), "owners/a".to_string())
+                .wait()
+                .is_ok());
+
+            // Without a provider, nothing can be fetched
+            let state = HookState::default();
+            assert!(state.fetch(ctx, "owners/a".to_string()).wait().is_err());
+        });
+    }
+}
@@ -21,8 +21,7 @@ use futures::{Async, IntoFuture, Poll, Stream};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use mercurial_types::{
-    fncache_fsencode, simple_fsencode, HgChangesetId, HgManifestId, HgNodeHash, MPath,
-    MPathElement, RepoPath,
+    HgChangesetId, HgManifestId, HgNodeHash, MPath, MPathElement, RepoPath, StoreEncoding,
 };
 use stockbookmarks::StockBookmarks;
 
@@ -141,6 +140,8 @@ impl FromStr for StoreRequired {
 ///  - the manifest: .hg/store/00manifest.[di]
 ///  - the tree manifests: .hg/store/00manifesttree.[di] and .hg/store/meta/.../00manifest.i
 ///  - per-file histories: .hg/store/data/.../<file>.[di]
+///
+/// Repos without the 'store' requirement keep the revlogs right under .hg instead of .hg/store.
 #[derive(Debug, Clone)]
 pub struct RevlogRepo {
     basepath: PathBuf,                          // path to .hg directory
@@ -184,10 +185,6 @@ impl RevlogRepo {
         options: RevlogRepoOptions,
     ) -> Result<RevlogRepo> {
         let base = base.into();
-        let store = base.as_path().join("store");
-
-        let changelog =
-            Revlog::from_idx_with_data(store.join("00changelog.i"), None as Option<String>)?;
 
         let mut requirements = HashSet::new();
         let file = fs::File::open(base.join("requires")).context("Can't open `requires`")?;
@@ -195,6 +192,10 @@ impl RevlogRepo {
             requirements.insert(line.context("Line read failed")?.parse()?);
         }
 
+        let store = store_encoding(&requirements).store_path(&base);
+        let changelog =
+            Revlog::from_idx_with_data(store.join("00changelog.i"), None as Option<String>)?;
+
         let mut store_requirements = HashSet::new();
         if requirements.contains(&Required::StoreRequirements) {
             let store_requirements_file = store.join("requires");
@@ -338,7 +339,7 @@ impl RevlogRepo {
             self.fsencode_path(&elements)
         };
 
-        let store_path = self.basepath.join("store");
+        let store_path = store_encoding(&self.requirements).store_path(&self.basepath);
         Revlog::from_idx_with_data(
             store_path.join(index_path),
             Some(store_path.join(data_path)),
@@ -346,23 +347,22 @@ impl RevlogRepo {
     }
 
     fn fsencode_path(&self, elements: &[MPathElement]) -> PathBuf {
-        // Mercurial has a complicated logic of path encoding.
-        // Code below matches core Mercurial logic from the commit
-        // 75013952d8d9608f73cd45f68405fbd6ec112bf2 from file mercurial/store.py from the function
-        // store(). The only caveat is that basicstore is not yet implemented
-        if self.requirements.contains(&Required::Store) {
-            if self.requirements.contains(&Required::Fncache) {
-                let dotencode = self.requirements.contains(&Required::Dotencode);
-                fncache_fsencode(&elements, dotencode)
-            } else {
-                simple_fsencode(&elements)
-            }
-        } else {
-            unimplemented!();
-        }
+        store_encoding(&self.requirements).fsencode(elements)
     }
 }
 
+// Mercurial has a complicated logic of path encoding.
+// StoreEncoding matches core Mercurial logic from the commit
+// 75013952d8d9608f73cd45f68405fbd6ec112bf2 from file mercurial/store.py from the function
+// store().
+fn store_encoding(requirements: &HashSet<Required>) -> StoreEncoding {
+    StoreEncoding::new(
+        requirements.contains(&Required::Store),
+        requirements.contains(&Required::Fncache),
+        requirements.contains(&Required::Dotencode),
+    )
+}
+
 pub struct ChangesetStream(RevlogIter);
 
 impl ChangesetStream {
@@ -15,7 +15,10 @@ pub const CG_PART_VERSION_HEADER_NAME: &'static str = "cgversion";
 pub enum Section {
     Changeset,
     Manifest,
+    /// End of the directory tree manifests of a version 03 changegroup. Root tree manifests are
+    /// sent in the Manifest section.
     Treemanifest,
+    TreemanifestDir(MPath),
     Filelog(MPath),
 }
 
@@ -50,17 +53,21 @@ pub struct CgDeltaChunk {
 mod test {
     use std::io::Cursor;
 
-    use futures::{Future, Stream};
+    use bytes::BytesMut;
+    use futures::{stream, Future, Stream};
     use quickcheck::rand;
     use quickcheck::{QuickCheck, StdGen, TestResult};
     use tokio;
-    use tokio_codec::{FramedRead, FramedWrite};
+    use tokio_codec::{Decoder, FramedRead, FramedWrite};
 
     use futures_ext::StreamLayeredExt;
+    use mercurial_types::NULL_HASH;
+    use mercurial_types_mocks::nodehash::{FOURS_HASH, ONES_HASH, THREES_HASH, TWOS_HASH};
     use partial_io::{GenWouldBlock, PartialAsyncRead, PartialAsyncWrite, PartialWithErrors};
 
     use chunk::{ChunkDecoder, ChunkEncoder};
     use context::CoreContext;
+    use errors::*;
     use quickcheck_types::CgPartSequence;
 
     use super::*;
@@ -101,6 +108,49 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_treemanifest_roundtrip() {
+        let chunk = |node| CgDeltaChunk {
+            node,
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            base: NULL_HASH,
+            linknode: ONES_HASH,
+            delta: Delta::new_fulltext(b"tree".to_vec()),
+            flags: Some(0),
+        };
+        let dir = MPath::new("dir").unwrap();
+        let subdir = MPath::new("dir/sub").unwrap();
+        let parts = vec![
+            Part::SectionEnd(Section::Changeset),
+            Part::CgChunk(Section::Manifest, chunk(TWOS_HASH)),
+            Part::SectionEnd(Section::Manifest),
+            Part::CgChunk(Section::TreemanifestDir(dir.clone()), chunk(THREES_HASH)),
+            Part::SectionEnd(Section::TreemanifestDir(dir)),
+            Part::CgChunk(Section::TreemanifestDir(subdir.clone()), chunk(FOURS_HASH)),
+            Part::SectionEnd(Section::TreemanifestDir(subdir)),
+            Part::SectionEnd(Section::Treemanifest),
+            Part::End,
+        ];
+
+        let chunks = packer::CgPacker::new(stream::iter_ok::<_, Error>(parts.clone()))
+            .collect()
+            .wait()
+            .unwrap();
+        let mut buf = BytesMut::new();
+        for chunk in chunks {
+            buf.extend_from_slice(&chunk.into_bytes().expect("expected normal chunk"));
+        }
+
+        let mut unpacker =
+            unpacker::CgUnpacker::new(CoreContext::test_mock(), unpacker::CgVersion::Cg3Version);
+        let mut decoded = vec![];
+        while let Some(part) = unpacker.decode(&mut buf).unwrap() {
+            decoded.push(part);
+        }
+        assert_eq!(decoded, parts);
+    }
+
     fn roundtrip(
         seq: CgPartSequence,
         write_ops: PartialWithErrors<GenWouldBlock>,
@@ -92,9 +92,17 @@ impl ChunkBuilder {
         );
         // Changeset and manifest sections are implicitly encoded, so we don't
         // need to do anything there.
-        // TODO: will need to encode tree manifests here as well
-        if let &Section::Filelog(ref f) = section {
-            let f_vec = f.to_vec();
+        let name = match section {
+            &Section::Filelog(ref f) => Some(f.to_vec()),
+            // Directory names end with a slash
+            &Section::TreemanifestDir(ref dir) => {
+                let mut dir_vec = dir.to_vec();
+                dir_vec.push(b'/');
+                Some(dir_vec)
+            }
+            _ => None,
+        };
+        if let Some(f_vec) = name {
             // Note that the filename length must include the four bytes for itself.
             BigEndian::write_i32(&mut self.inner[0..], (f_vec.len() + 4) as i32);
             self.inner.put_slice(f_vec.as_slice());
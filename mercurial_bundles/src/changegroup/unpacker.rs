@@ -180,16 +180,15 @@ impl CgUnpacker {
                     State::Manifest,
                 )),
             },
-            State::Treemanifest => match Self::decode_chunk(buf, version)? {
-                None => Ok((None, State::Treemanifest)),
-                Some(CgChunk::Empty) => Ok((
+            State::Treemanifest => match Self::decode_dirname(buf)? {
+                DecodeRes::None => Ok((None, State::Treemanifest)),
+                DecodeRes::Some(dir) => Self::decode_treemanifest_chunk(buf, dir, version),
+                DecodeRes::End => Ok((
                     Some(Part::SectionEnd(Section::Treemanifest)),
                     State::Filename,
                 )),
-                Some(CgChunk::Delta(_)) => {
-                    Err(ErrorKind::CgDecode("Empty TreeManifest has expected".into()).into())
-                }
             },
+            State::TreemanifestDir(dir) => Self::decode_treemanifest_chunk(buf, dir, version),
             State::Filename => {
                 let filename = Self::decode_filename(buf)?;
                 match filename {
@@ -221,6 +220,24 @@ impl CgUnpacker {
         }
     }
 
+    fn decode_treemanifest_chunk(
+        buf: &mut BytesMut,
+        dir: MPath,
+        version: &CgVersion,
+    ) -> Result<(Option<Part>, State)> {
+        match Self::decode_chunk(buf, version)? {
+            None => Ok((None, State::TreemanifestDir(dir))),
+            Some(CgChunk::Empty) => Ok((
+                Some(Part::SectionEnd(Section::TreemanifestDir(dir))),
+                State::Treemanifest,
+            )),
+            Some(CgChunk::Delta(chunk)) => Ok((
+                Some(Part::CgChunk(Section::TreemanifestDir(dir.clone()), chunk)),
+                State::TreemanifestDir(dir),
+            )),
+        }
+    }
+
     fn decode_chunk(buf: &mut BytesMut, version: &CgVersion) -> Result<Option<CgChunk>> {
         if buf.len() < 4 {
             return Ok(None);
@@ -312,6 +329,40 @@ impl CgUnpacker {
         })?;
         Ok(DecodeRes::Some(filename))
     }
+
+    /// Directory names of tree manifest groups are encoded like filenames, with a trailing slash.
+    fn decode_dirname(buf: &mut BytesMut) -> Result<DecodeRes<MPath>> {
+        if buf.len() < 4 {
+            return Ok(DecodeRes::None);
+        }
+        let dirname_len = buf.peek_i32();
+        if dirname_len == 0 {
+            let _ = buf.split_to(4);
+            return Ok(DecodeRes::End);
+        }
+        // dirname_len includes the 4 bytes for the length field, and the name has at least the
+        // trailing slash.
+        if dirname_len < 5 {
+            let msg = format!("invalid directory name length {}", dirname_len);
+            bail_err!(ErrorKind::CgDecode(msg));
+        }
+        let dirname_len = dirname_len as usize;
+        if buf.len() < dirname_len {
+            return Ok(DecodeRes::None);
+        }
+        let _ = buf.split_to(4);
+        let mut dirname = buf.split_to(dirname_len - 4);
+        if dirname.last() != Some(&b'/') {
+            let msg = format!("directory name {:?} doesn't end with a slash", dirname);
+            bail_err!(ErrorKind::CgDecode(msg));
+        }
+        let dirname_len = dirname.len();
+        let dir = dirname.drain_path(dirname_len - 1).with_context(|_| {
+            let msg = format!("invalid directory name of length {}", dirname_len);
+            ErrorKind::CgDecode(msg)
+        })?;
+        Ok(DecodeRes::Some(dir))
+    }
 }
 
 enum DecodeRes<T> {
@@ -330,6 +381,7 @@ enum State {
     Changeset,
     Manifest,
     Treemanifest,
+    TreemanifestDir(MPath),
     Filename,
     Filelog(MPath),
     End,
@@ -341,3 +393,41 @@ impl State {
         mem::replace(self, State::Invalid)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_dirname(bytes: &[u8]) -> Result<DecodeRes<MPath>> {
+        CgUnpacker::decode_dirname(&mut BytesMut::from(bytes))
+    }
+
+    #[test]
+    fn test_decode_dirname() {
+        match decode_dirname(b"\0\0\0\x08foo/") {
+            Ok(DecodeRes::Some(dir)) => assert_eq!(dir, MPath::new("foo").unwrap()),
+            Ok(_) => panic!("directory name not decoded"),
+            Err(err) => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_decode_dirname_too_short() {
+        for dirname in &[
+            &b"\0\0\0\x01"[..],
+            &b"\0\0\0\x02a"[..],
+            &b"\0\0\0\x03ab"[..],
+            &b"\0\0\0\x04abc"[..],
+            &b"\xff\xff\xff\xffabc"[..],
+        ] {
+            match decode_dirname(dirname) {
+                Ok(_) => panic!("unexpected success for {:?}", dirname),
+                Err(err) => match err_downcast_ref!(err, err: ErrorKind => err) {
+                    Some(&ErrorKind::CgDecode(..)) => (),
+                    Some(bad) => panic!("Bad ErrorKind {:?}", bad),
+                    None => panic!("Unexpected error {:?}", err),
+                },
+            }
+        }
+    }
+}
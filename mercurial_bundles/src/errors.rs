@@ -34,6 +34,8 @@ pub enum ErrorKind {
     BundleUnknownPartParams(PartHeaderType, Vec<String>),
     #[fail(display = "error while generating listkey part")]
     ListkeyGeneration,
+    #[fail(display = "phase-heads decode error: {}", _0)]
+    PhaseHeadsDecode(String),
     #[fail(display = "error while generating phase-heads part")]
     PhaseHeadsGeneration,
 }
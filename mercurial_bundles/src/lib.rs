@@ -64,6 +64,7 @@ mod part_header;
 mod part_inner;
 mod part_outer;
 pub mod parts;
+mod phases;
 mod pushrebase;
 mod quickcheck_types;
 mod stream_start;
@@ -87,7 +88,7 @@ use std::fmt;
 use futures_ext::{BoxFuture, BoxStream};
 
 pub use bundle2_encode::Bundle2EncodeBuilder;
-pub use part_header::{PartHeader, PartHeaderType};
+pub use part_header::{PartHeader, PartHeaderBuilder, PartHeaderType};
 pub use types::StreamHeader;
 
 pub enum Bundle2Item {
@@ -103,6 +104,10 @@ pub enum Bundle2Item {
     Replycaps(PartHeader, BoxFuture<capabilities::Capabilities, Error>),
     Pushkey(PartHeader, BoxFuture<(), Error>),
     Pushvars(PartHeader, BoxFuture<(), Error>),
+    PhaseHeads(
+        PartHeader,
+        BoxStream<(mercurial_types::HgChangesetId, mercurial_types::HgPhase), Error>,
+    ),
 }
 
 impl Bundle2Item {
@@ -149,6 +154,9 @@ impl fmt::Debug for Bundle2Item {
             &Replycaps(ref header, _) => write!(f, "Bundle2Item::Replycaps({:?}, ...)", header),
             &Pushkey(ref header, _) => write!(f, "Bundle2Item::Pushkey({:?}, ...)", header),
             &Pushvars(ref header, _) => write!(f, "Bundle2Item::Pushvars({:?}, ...)", header),
+            &PhaseHeads(ref header, _) => {
+                write!(f, "Bundle2Item::PhaseHeads({:?}, ...)", header)
+            }
         }
     }
 }
@@ -57,6 +57,8 @@ pub enum PartHeaderType {
     /// Used in communicating phases between Mononoke and clients
     /// Pushkey / Listkeys are not used to communicate phases
     PhaseHeads,
+    /// Mononoke-specific advisory part, with a token that resumes a pull if it's interrupted
+    ResumeToken,
     // RemoteChangegroup,       // We don't wish to support this functionality
     // CheckBookmarks,          // TODO Do we want to support this?
     // CheckHeads,              // TODO Do we want to support this?
@@ -94,6 +96,7 @@ impl PartHeaderType {
             "reply:pushkey" => Ok(ReplyPushkey),
             "pushvars" => Ok(Pushvars),
             "phase-heads" => Ok(PhaseHeads),
+            "mononoke:resumetoken" => Ok(ResumeToken),
             bad => bail_msg!("unknown header type {}", bad),
         }
     }
@@ -116,6 +119,7 @@ impl PartHeaderType {
             Pushvars => "pushvars",
             ReplyPushkey => "reply:pushkey",
             PhaseHeads => "phase-heads",
+            ResumeToken => "mononoke:resumetoken",
         }
     }
 }
@@ -25,6 +25,7 @@ use futures_ext::{StreamExt, StreamLayeredExt};
 use infinitepush;
 use part_header::{PartHeader, PartHeaderType};
 use part_outer::{OuterFrame, OuterStream};
+use phases;
 use pushrebase;
 use wirepack;
 use Bundle2Item;
@@ -40,15 +41,18 @@ lazy_static! {
         // probably) be renamed T26385545. 'bookprevnode' and 'pushbackbookmarks' will be
         // removed T26384190.
         m.insert(PartHeaderType::B2xInfinitepush, hashset!{
-            "pushbackbookmarks", "cgversion", "bookmark", "bookprevnode", "create", "force"});
+            "pushbackbookmarks", "cgversion", "bookmark", "bookprevnode", "create", "force",
+            "treemanifest"});
         m.insert(PartHeaderType::B2xInfinitepushBookmarks, hashset!{});
         m.insert(PartHeaderType::B2xCommonHeads, hashset!{});
-        m.insert(PartHeaderType::B2xRebase, hashset!{"onto", "newhead", "cgversion", "obsmarkerversions"});
+        m.insert(PartHeaderType::B2xRebase, hashset!{
+            "onto", "newhead", "cgversion", "obsmarkerversions", "treemanifest"});
         m.insert(PartHeaderType::B2xRebasePack, hashset!{"version", "cache", "category"});
         m.insert(PartHeaderType::B2xTreegroup2, hashset!{"version", "cache", "category"});
         m.insert(PartHeaderType::Replycaps, hashset!{});
         m.insert(PartHeaderType::Pushkey, hashset!{ "namespace", "key", "old", "new" });
         m.insert(PartHeaderType::Pushvars, hashset!{});
+        m.insert(PartHeaderType::PhaseHeads, hashset!{});
         m
     };
 }
@@ -192,6 +196,10 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             let empty = wrapped_stream.decode(EmptyUnpacker).for_each(|_| Ok(()));
             Bundle2Item::Pushvars(header, Box::new(empty))
         }
+        &PartHeaderType::PhaseHeads => {
+            let heads_stream = wrapped_stream.decode(phases::PhaseHeadsUnpacker::new());
+            Bundle2Item::PhaseHeads(header, Box::new(heads_stream))
+        }
         _ => panic!("TODO: make this an error"),
     };
 
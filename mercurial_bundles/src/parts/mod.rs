@@ -81,6 +81,15 @@ where
     Ok(builder)
 }
 
+/// A token the client can send back in getbundle to resume this pull if it's interrupted. Clients
+/// that don't know about resumable pulls ignore it.
+pub fn resume_token_part(token: String) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::advisory(PartHeaderType::ResumeToken)?;
+    builder.add_aparam("token", token)?;
+
+    Ok(builder)
+}
+
 pub fn changegroup_part<S>(changelogentries: S) -> Result<PartEncodeBuilder>
 where
     S: Stream<Item = (HgNodeHash, HgBlobNode), Error = Error> + Send + 'static,
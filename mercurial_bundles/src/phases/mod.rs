@@ -0,0 +1,85 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Codecs for the phase-heads part
+
+use bytes::BytesMut;
+use mercurial_types::{HgChangesetId, HgPhase};
+use tokio_codec::Decoder;
+
+use errors::*;
+use utils::BytesExt;
+
+/// Decodes the payload of a phase-heads part: a sequence of entries, each being a big-endian u32
+/// phase followed by a 20 bytes node.
+#[derive(Debug)]
+pub struct PhaseHeadsUnpacker {}
+
+impl PhaseHeadsUnpacker {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Decoder for PhaseHeadsUnpacker {
+    type Item = (HgChangesetId, HgPhase);
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if buf.len() < 4 + 20 {
+            return Ok(None);
+        }
+        let phase = match buf.drain_u32() {
+            0 => HgPhase::Public,
+            1 => HgPhase::Draft,
+            phase => bail_err!(ErrorKind::PhaseHeadsDecode(format!(
+                "unsupported phase: {}",
+                phase
+            ))),
+        };
+        let node = buf.drain_node();
+        Ok(Some((HgChangesetId::new(node), phase)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use byteorder::{BigEndian, WriteBytesExt};
+    use mercurial_types_mocks::nodehash::{ONES_CSID, TWOS_CSID};
+
+    #[test]
+    fn decode_phase_heads() {
+        let mut payload = vec![];
+        payload.write_u32::<BigEndian>(0).unwrap();
+        payload.extend_from_slice(ONES_CSID.as_ref());
+        payload.write_u32::<BigEndian>(1).unwrap();
+        payload.extend_from_slice(TWOS_CSID.as_ref());
+
+        let mut unpacker = PhaseHeadsUnpacker::new();
+        let mut buf = BytesMut::from(payload);
+        assert_eq!(
+            unpacker.decode(&mut buf).unwrap(),
+            Some((ONES_CSID, HgPhase::Public))
+        );
+        assert_eq!(
+            unpacker.decode(&mut buf).unwrap(),
+            Some((TWOS_CSID, HgPhase::Draft))
+        );
+        assert_eq!(unpacker.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_secret_phase() {
+        let mut payload = vec![];
+        payload.write_u32::<BigEndian>(2).unwrap();
+        payload.extend_from_slice(ONES_CSID.as_ref());
+
+        let mut buf = BytesMut::from(payload);
+        assert!(PhaseHeadsUnpacker::new().decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,37 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Applying bdiff deltas read from a revlog to a text must fail rather than panic.
+//!
+//! The input is the text, a zero byte, then deltas: each of them is its start, its length and
+//! the length of its content, as one byte each, followed by the content.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate mercurial_types;
+
+use mercurial_types::bdiff::{self, Delta};
+
+fuzz_target!(|data: &[u8]| {
+    let split = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    let (text, rest) = data.split_at(split);
+    let mut rest = if rest.is_empty() { rest } else { &rest[1..] };
+
+    let mut deltas = vec![];
+    while rest.len() >= 3 {
+        let start = rest[0] as usize;
+        let end = start + rest[1] as usize;
+        let content_len = ::std::cmp::min(rest[2] as usize, rest.len() - 3);
+        deltas.push(Delta {
+            start,
+            end,
+            content: rest[3..3 + content_len].to_vec(),
+        });
+        rest = &rest[3 + content_len..];
+    }
+    let _ = bdiff::apply(text, &deltas);
+});
@@ -0,0 +1,46 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Applying a delta that a client sent to a text must fail rather than panic.
+//!
+//! The input is the text, a zero byte, then fragments of a delta: each of them is its start,
+//! its length and the length of its content, as one byte each, followed by the content.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate mercurial_types;
+
+use mercurial_types::delta::{self, Delta, Fragment};
+
+fn fragments(mut data: &[u8]) -> Vec<Fragment> {
+    let mut frags = vec![];
+    while data.len() >= 3 {
+        let start = data[0] as usize;
+        let end = start + data[1] as usize;
+        let content_len = ::std::cmp::min(data[2] as usize, data.len() - 3);
+        frags.push(Fragment {
+            start,
+            end,
+            content: data[3..3 + content_len].to_vec(),
+        });
+        data = &data[3 + content_len..];
+    }
+    frags
+}
+
+fuzz_target!(|data: &[u8]| {
+    let split = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    let (text, rest) = data.split_at(split);
+    let rest = if rest.is_empty() { rest } else { &rest[1..] };
+
+    // Fragments out of order are only caught when applying them
+    let mut delta = Delta::default();
+    for frag in fragments(rest) {
+        delta.push(frag);
+    }
+    let _ = delta::apply(text, &delta);
+});
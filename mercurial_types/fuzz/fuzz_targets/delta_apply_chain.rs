@@ -0,0 +1,44 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Folding a chain of deltas that a client sent, and applying it to a text, must fail rather
+//! than panic.
+//!
+//! The input is the text, then deltas, each of them after a zero byte. A delta is fragments,
+//! each of them its start, its length and the length of its content, as one byte each, followed
+//! by the content. Deltas that `Delta::new` rejects are left out of the chain.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate mercurial_types;
+
+use mercurial_types::delta::{self, Delta, Fragment};
+
+fn fragments(mut data: &[u8]) -> Vec<Fragment> {
+    let mut frags = vec![];
+    while data.len() >= 3 {
+        let start = data[0] as usize;
+        let end = start + data[1] as usize;
+        let content_len = ::std::cmp::min(data[2] as usize, data.len() - 3);
+        frags.push(Fragment {
+            start,
+            end,
+            content: data[3..3 + content_len].to_vec(),
+        });
+        data = &data[3 + content_len..];
+    }
+    frags
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut parts = data.split(|b| *b == 0);
+    let text = parts.next().unwrap_or(&[]);
+    let deltas: Vec<_> = parts
+        .filter_map(|part| Delta::new(fragments(part)).ok())
+        .collect();
+    let _ = delta::apply_chain(text, deltas);
+});
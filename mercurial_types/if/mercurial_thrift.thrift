@@ -21,6 +21,17 @@ struct HgChangesetEnvelope {
 // Manifest contents are expected to generally be small, so they're stored
 // inline in the envelope. There's also no real dedup possible between native
 // Mononoke data structures and these ones.
+//
+// The contents of very large directories are instead split into shards, each
+// holding the entries of a range of names and stored as a separate blob.
+struct HgManifestShard {
+  // Name of the first entry of the shard. Shards are in name order, and
+  // each one ends where the next one starts.
+  1: required mononoke_types_thrift.MPathElement first_name,
+  // Hash of the entries of the shard, which are stored under this hash.
+  2: required mononoke_types_thrift.Blake2 id,
+}
+
 struct HgManifestEnvelope {
   1: required HgNodeHash node_id,
   2: optional HgNodeHash p1,
@@ -31,7 +42,10 @@ struct HgManifestEnvelope {
   // is stored to allow that to happen.
   4: required HgNodeHash computed_node_id,
   // These contents are exactly as they would be serialized by Mercurial.
+  // Either contents or shards is set.
   5: optional binary contents,
+  // Concatenated, the entries of the shards are the contents.
+  6: optional list<HgManifestShard> shards,
 }
 
 struct HgFileEnvelope {
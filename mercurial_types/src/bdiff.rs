@@ -4,6 +4,8 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use errors::*;
+
 /// A single delta in a revlog or bundle.
 ///
 /// The range from `start`-`end` is replaced with the `content`.
@@ -14,21 +16,22 @@ pub struct Delta {
     pub content: Vec<u8>, // need to own because of compression
 }
 
-fn snip<T>(start: usize, end: usize, slice: &[T]) -> &[T] {
-    let (h, _) = slice.split_at(end);
-    let (_, t) = h.split_at(start);
-    t
-}
-
-/// Apply a set of `Delta`s to an input text, returning the result.
-pub fn apply(text: &[u8], deltas: &[Delta]) -> Vec<u8> {
+/// Apply a set of `Delta`s to an input text, returning the result. `Delta`s that are out of
+/// order or out of the bounds of the text are `InvalidDelta` errors.
+pub fn apply(text: &[u8], deltas: &[Delta]) -> Result<Vec<u8>> {
     let mut chunks = Vec::with_capacity(deltas.len() * 2);
     let mut off = 0;
 
     for d in deltas {
-        assert!(off <= d.start);
+        if d.start < off || d.end < d.start {
+            let msg = format!(
+                "delta {}..{} is out of order at offset {}",
+                d.start, d.end, off
+            );
+            bail_err!(ErrorKind::InvalidDelta(msg));
+        }
         if off < d.start {
-            chunks.push(snip(off, d.start, text));
+            chunks.push(text_range(text, off, d.start)?);
         }
         if d.content.len() > 0 {
             chunks.push(d.content.as_ref())
@@ -36,14 +39,28 @@ pub fn apply(text: &[u8], deltas: &[Delta]) -> Vec<u8> {
         off = d.end;
     }
     if off < text.len() {
-        chunks.push(snip(off, text.len(), text));
+        chunks.push(&text[off..]);
+    } else if off > text.len() {
+        text_range(text, off, off)?;
     }
 
     let mut ret = Vec::new();
     for s in chunks {
         ret.extend_from_slice(s);
     }
-    ret
+    Ok(ret)
+}
+
+fn text_range(text: &[u8], start: usize, end: usize) -> Result<&[u8]> {
+    text.get(start..end).ok_or_else(|| {
+        ErrorKind::InvalidDelta(format!(
+            "the range {}..{} is out of bounds for text of {} bytes",
+            start,
+            end,
+            text.len()
+        ))
+        .into()
+    })
 }
 
 #[cfg(test)]
@@ -60,7 +77,7 @@ mod test {
         };
         let deltas = [delta; 1];
 
-        let res = apply(text, &deltas[..]);
+        let res = apply(text, &deltas[..]).unwrap();
         assert_eq!(&res[..], b"aaaa\nxxxx\ncccc\n");
     }
 
@@ -80,7 +97,7 @@ mod test {
             },
         ];
 
-        let res = apply(text, &deltas[..]);
+        let res = apply(text, &deltas[..]).unwrap();
         assert_eq!(&res[..], b"aaaabbbb\ncccc\ndddd\n");
     }
 
@@ -93,7 +110,7 @@ mod test {
             content: (&b"zzzz\nyyyy\nxxxx\n"[..]).into(),
         }];
 
-        let res = apply(text, &deltas[..]);
+        let res = apply(text, &deltas[..]).unwrap();
         assert_eq!(&res[..], b"zzzz\nyyyy\nxxxx\n");
     }
 
@@ -118,7 +135,7 @@ mod test {
             },
         ];
 
-        let res = apply(text, &deltas[..]);
+        let res = apply(text, &deltas[..]).unwrap();
         assert_eq!(&res[..], b"zzzz\nyyyy\nxxxx\n");
     }
 
@@ -131,7 +148,7 @@ mod test {
             content: (&b"bbbbcccc"[..]).into(),
         }];
 
-        let res = apply(text, &deltas[..]);
+        let res = apply(text, &deltas[..]).unwrap();
         assert_eq!(&res[..], b"aaaa\nbbbbcccc");
     }
 
@@ -144,8 +161,32 @@ mod test {
             content: (&b""[..]).into(),
         }];
 
-        let res = apply(text, &deltas[..]);
+        let res = apply(text, &deltas[..]).unwrap();
         assert_eq!(&res[..], b"aaaa\ncccc\n");
     }
 
+    #[test]
+    fn test_malformed() {
+        let text = b"aaaa";
+        let out_of_bounds = [Delta {
+            start: 5,
+            end: 10,
+            content: vec![],
+        }];
+        assert!(apply(text, &out_of_bounds[..]).is_err());
+
+        let out_of_order = [
+            Delta {
+                start: 2,
+                end: 3,
+                content: vec![],
+            },
+            Delta {
+                start: 1,
+                end: 2,
+                content: vec![],
+            },
+        ];
+        assert!(apply(text, &out_of_order[..]).is_err());
+    }
 }
@@ -5,12 +5,49 @@
 // GNU General Public License version 2 or any later version.
 
 use std::collections::BTreeMap;
+use std::str;
 
 use mononoke_types::{DateTime, MPath};
 
 use blobnode::HgParents;
+use errors::{ErrorKind, Result};
 use nodehash::HgManifestId;
 
+/// How the text of a changeset, i.e. its user and comments, is decoded. Mercurial stores it as
+/// bytes, and the commits imported from old repos aren't always UTF-8.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TextDecoding {
+    /// Text that isn't UTF-8 is an error
+    Strict,
+    /// Bytes that aren't UTF-8 are replaced with U+FFFD
+    Lossy,
+}
+
+/// Text decoded with a `TextDecoding`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DecodedText {
+    pub text: String,
+    /// The bytes of the text if they aren't UTF-8, in which case `text` only approximates them
+    pub raw: Option<Vec<u8>>,
+}
+
+impl TextDecoding {
+    /// Decode the bytes of `field`, which names it in errors
+    pub fn decode(self, field: &str, bytes: &[u8]) -> Result<DecodedText> {
+        match str::from_utf8(bytes) {
+            Ok(text) => Ok(DecodedText {
+                text: text.to_string(),
+                raw: None,
+            }),
+            Err(_) if self == TextDecoding::Lossy => Ok(DecodedText {
+                text: String::from_utf8_lossy(bytes).into_owned(),
+                raw: Some(bytes.to_vec()),
+            }),
+            Err(err) => Err(ErrorKind::NonUtf8Text(field.to_string(), err.to_string()).into()),
+        }
+    }
+}
+
 pub trait Changeset: Send + 'static {
     fn manifestid(&self) -> HgManifestId;
     fn user(&self) -> &[u8];
@@ -21,6 +58,14 @@ pub trait Changeset: Send + 'static {
     // XXX Change this to return p1 and p2 directly.
     fn parents(&self) -> HgParents;
 
+    fn user_text(&self, decoding: TextDecoding) -> Result<DecodedText> {
+        decoding.decode("user", self.user())
+    }
+
+    fn comments_text(&self, decoding: TextDecoding) -> Result<DecodedText> {
+        decoding.decode("comments", self.comments())
+    }
+
     fn boxed(self) -> Box<Changeset>
     where
         Self: Sized,
@@ -58,3 +103,25 @@ impl Changeset for Box<Changeset> {
         (**self).parents()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_text() {
+        let utf8 = "h\u{e9}llo".as_bytes();
+        let latin1 = b"h\xe9llo";
+
+        for decoding in &[TextDecoding::Strict, TextDecoding::Lossy] {
+            let decoded = decoding.decode("user", utf8).unwrap();
+            assert_eq!(decoded.text, "h\u{e9}llo");
+            assert_eq!(decoded.raw, None);
+        }
+
+        assert!(TextDecoding::Strict.decode("user", latin1).is_err());
+        let decoded = TextDecoding::Lossy.decode("user", latin1).unwrap();
+        assert_eq!(decoded.text, "h\u{fffd}llo");
+        assert_eq!(decoded.raw, Some(latin1.to_vec()));
+    }
+}
@@ -207,25 +207,35 @@ fn arbitrary_frag_content<G: Gen>(g: &mut G) -> Vec<u8> {
     v
 }
 
-/// Apply a Delta to an input text, returning the result.
+/// Apply a Delta to an input text, returning the result. Deltas that don't fit the text, which
+/// clients may send, are `InvalidDelta` errors.
 pub fn apply(text: &[u8], delta: &Delta) -> Result<Vec<u8>> {
     let mut chunks = Vec::with_capacity(delta.frags.len() * 2);
     let mut off = 0;
 
     for frag in &delta.frags {
-        ensure_msg!(
-            off <= frag.start,
-            "Invalid delta, fragment start is less than current offset ({} < {})",
-            frag.start,
-            off
-        );
+        if frag.start < off {
+            let msg = format!(
+                "fragment start is less than current offset ({} < {})",
+                frag.start, off
+            );
+            bail_err!(ErrorKind::InvalidDelta(msg));
+        }
+        if frag.end < frag.start {
+            let msg = format!(
+                "fragment end is less than its start ({} < {})",
+                frag.end, frag.start
+            );
+            bail_err!(ErrorKind::InvalidDelta(msg));
+        }
         if off < frag.start {
             chunks.push(text.get(off..frag.start).ok_or_else(|| {
-                format_err!(
-                    "Invalid delta, the range {}..{} is out of bounds for provided text",
+                ErrorKind::InvalidDelta(format!(
+                    "the range {}..{} is out of bounds for text of {} bytes",
                     off,
-                    frag.start
-                )
+                    frag.start,
+                    text.len()
+                ))
             })?);
         }
         if frag.content.len() > 0 {
@@ -236,11 +246,12 @@ pub fn apply(text: &[u8], delta: &Delta) -> Result<Vec<u8>> {
     if off < text.len() {
         chunks.push(&text[off..text.len()]);
     } else if off > text.len() {
-        bail_msg!(format!(
-            "Invalid delta, fragment is referencing out of bounds content: {} > {}",
+        let msg = format!(
+            "fragment is referencing out of bounds content: {} > {}",
             off,
             text.len()
-        ));
+        );
+        bail_err!(ErrorKind::InvalidDelta(msg));
     }
 
     let size = chunks.iter().map(|c| c.len()).sum::<usize>();
@@ -256,7 +267,7 @@ pub fn apply(text: &[u8], delta: &Delta) -> Result<Vec<u8>> {
 pub fn apply_chain<I: IntoIterator<Item = Delta>>(text: &[u8], deltas: I) -> Result<Vec<u8>> {
     let mut res = Vec::from(text);
 
-    let (wrapped_deltas, data) = wrap_deltas(deltas);
+    let (wrapped_deltas, data) = wrap_deltas(deltas)?;
 
     if wrapped_deltas.len() == 0 {
         Ok(res)
@@ -376,6 +387,28 @@ mod tests {
         fn fragment_shrink(fragment: Fragment) -> bool {
             fragment.shrink().take(100).all(|f| f.verify().is_ok())
         }
+
+        // Deltas come from clients, so applying any of them to any text must fail rather than
+        // panic
+        fn apply_any_fragments(text: Vec<u8>, frags: Vec<(usize, usize, Vec<u8>)>) -> bool {
+            let frags = frags
+                .into_iter()
+                .map(|(start, end, content)| Fragment { start, end, content })
+                .collect();
+            let _ = apply(&text, &Delta { frags });
+            true
+        }
+
+        fn apply_chain_any_deltas(text: Vec<u8>, deltas: Vec<Delta>) -> bool {
+            let _ = apply_chain(&text, deltas);
+            true
+        }
+
+        fn apply_fitting_delta(text: Vec<u8>, delta: Delta) -> bool {
+            // Fragments that end within the text always apply
+            let fits = delta.frags.last().map_or(true, |frag| frag.end <= text.len());
+            apply(&text, &delta).is_ok() == fits
+        }
     }
 
     #[test]
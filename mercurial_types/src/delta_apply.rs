@@ -6,6 +6,7 @@
 
 use bytes::Bytes;
 use std::cmp;
+use std::i32;
 
 use errors::*;
 
@@ -15,17 +16,29 @@ use super::delta::{Delta, Fragment};
 * Algorithm is taken from fbcode/scm/hg/mercurial/mpatch.c
 */
 
+/// Offsets and lengths of fragments, and of their content all together, are at most this, so that
+/// folding deltas can't overflow
+const MAX_OFFSET: i64 = i32::MAX as i64;
+
+fn to_offset(value: usize) -> Result<i64> {
+    if value as u64 > MAX_OFFSET as u64 {
+        let msg = format!("offset {} is larger than {}", value, MAX_OFFSET);
+        bail_err!(ErrorKind::InvalidDelta(msg));
+    }
+    Ok(value as i64)
+}
+
 /// Wrap all Fragments and return FragmentWrapperIterator.
 /// Gather all contents hold fragments contents in one vector.
 pub fn wrap_deltas<I: IntoIterator<Item = Delta>>(
     deltas: I,
-) -> (Vec<FragmentWrapperIterator>, Bytes) {
+) -> Result<(Vec<FragmentWrapperIterator>, Bytes)> {
     let mut wrapped_deltas = Vec::new();
     let mut data = Bytes::new();
     let mut content_offset = 0;
 
     for delta in deltas {
-        let wrapped_delta = FragmentWrapperIterator::new(&delta, content_offset as i32);
+        let wrapped_delta = FragmentWrapperIterator::new(&delta, to_offset(content_offset)?)?;
         for frag in delta.fragments() {
             data.extend_from_slice(frag.content.as_slice());
             content_offset += frag.content.len();
@@ -33,17 +46,18 @@ pub fn wrap_deltas<I: IntoIterator<Item = Delta>>(
 
         wrapped_deltas.push(wrapped_delta);
     }
+    to_offset(content_offset)?;
 
-    (wrapped_deltas, data)
+    Ok((wrapped_deltas, data))
 }
 
 // Fragment Wrapper, it does not have actual data, only references to real data
 #[derive(Clone, Eq, Debug, PartialEq, Ord, PartialOrd, HeapSizeOf)]
 pub struct FragmentWrapper {
-    pub start: i32,
-    pub end: i32,
-    pub len: i32,
-    pub content_start: i32,
+    pub start: i64,
+    pub end: i64,
+    pub len: i64,
+    pub content_start: i64,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, HeapSizeOf, Default)]
@@ -54,29 +68,30 @@ pub struct FragmentWrapperIterator {
 }
 
 impl FragmentWrapperIterator {
-    pub fn new(delta: &Delta, content_offset: i32) -> FragmentWrapperIterator {
+    pub fn new(delta: &Delta, content_offset: i64) -> Result<FragmentWrapperIterator> {
         // Convert Delta to Vec<FragmentWrapper>, using global offset of the content in Content Bytes
         let mut frag_wrappers = Vec::new();
         let mut offset = content_offset;
 
         for frag in delta.fragments() {
+            let len = to_offset(frag.content_length())?;
             let frag_wrapper = FragmentWrapper {
-                start: frag.start as i32,
-                end: frag.end as i32,
-                len: frag.content_length() as i32,
-                content_start: offset as i32,
+                start: to_offset(frag.start)?,
+                end: to_offset(frag.end)?,
+                len,
+                content_start: offset,
             };
-            offset += frag.content_length() as i32;
+            offset += len;
             frag_wrappers.push(frag_wrapper);
         }
 
-        FragmentWrapperIterator {
+        Ok(FragmentWrapperIterator {
             frags: frag_wrappers,
             cur_pointer: 0,
-        }
+        })
     }
 
-    pub fn content_length(&self) -> i32 {
+    pub fn content_length(&self) -> i64 {
         let mut size = 0;
         for frag in self.frags.as_slice() {
             size += frag.len;
@@ -116,8 +131,19 @@ impl FragmentWrapperIterator {
         let mut frags = Vec::new();
 
         for frag_wrapper in self.frags.as_slice() {
+            // Deltas that don't fit the text they apply to fold into fragments out of bounds
+            let content_end = frag_wrapper.content_start + frag_wrapper.len;
+            if frag_wrapper.start < 0
+                || frag_wrapper.end < 0
+                || frag_wrapper.len < 0
+                || frag_wrapper.content_start < 0
+                || content_end > data.len() as i64
+            {
+                let msg = format!("folded into invalid fragment {:?}", frag_wrapper);
+                bail_err!(ErrorKind::InvalidDelta(msg));
+            }
             let content_start = frag_wrapper.content_start as usize;
-            let content_end = (frag_wrapper.content_start + frag_wrapper.len) as usize;
+            let content_end = content_end as usize;
 
             let frag = Fragment {
                 start: frag_wrapper.start as usize,
@@ -167,9 +193,9 @@ fn combine(
 fn gather(
     dst: &mut FragmentWrapperIterator,
     src: &mut FragmentWrapperIterator,
-    cut: i32,
-    mut offset: i32,
-) -> i32 {
+    cut: i64,
+    mut offset: i64,
+) -> i64 {
     while !src.end() {
         let frag = src.current_fragment().clone();
 
@@ -213,7 +239,7 @@ fn gather(
 }
 
 /// Delete all fragments from src until cut
-fn discard(src: &mut FragmentWrapperIterator, cut: i32, mut offset: i32) -> i32 {
+fn discard(src: &mut FragmentWrapperIterator, cut: i64, mut offset: i64) -> i64 {
     while !src.end() {
         let frag = src.current_fragment().clone();
 
@@ -14,11 +14,44 @@ use quickcheck::{empty_shrinker, Arbitrary, Gen};
 
 use rust_thrift::compact_protocol;
 
+use mononoke_types::hash::Blake2;
+use mononoke_types::MPathElement;
+
 use super::HgEnvelopeBlob;
 use errors::*;
 use nodehash::HgNodeHash;
 use thrift;
 
+/// A range of the entries of a sharded manifest, stored as a separate blob.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HgManifestShard {
+    /// Name of the first entry of the shard
+    pub first_name: MPathElement,
+    /// Hash of the entries of the shard
+    pub id: Blake2,
+}
+
+impl HgManifestShard {
+    /// Key of the entries of the shard in the blob store
+    pub fn blobstore_key(&self) -> String {
+        format!("hgmanifestshard.blake2.{}", self.id.to_hex())
+    }
+
+    fn from_thrift(shard: thrift::HgManifestShard) -> Result<Self> {
+        Ok(Self {
+            first_name: MPathElement::new(shard.first_name.0)?,
+            id: Blake2::from_bytes(shard.id.0)?,
+        })
+    }
+
+    fn into_thrift(self) -> thrift::HgManifestShard {
+        thrift::HgManifestShard {
+            first_name: thrift::MPathElement(self.first_name.to_bytes()),
+            id: thrift::Blake2(self.id.as_ref().to_vec()),
+        }
+    }
+}
+
 /// A mutable representation of a Mercurial manifest node.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct HgManifestEnvelopeMut {
@@ -26,7 +59,11 @@ pub struct HgManifestEnvelopeMut {
     pub p1: Option<HgNodeHash>,
     pub p2: Option<HgNodeHash>,
     pub computed_node_id: HgNodeHash,
+    /// Empty if the manifest is sharded
     pub contents: Bytes,
+    /// Empty unless the manifest is sharded, in which case the contents are the concatenated
+    /// entries of the shards
+    pub shards: Vec<HgManifestShard>,
 }
 
 impl HgManifestEnvelopeMut {
@@ -42,7 +79,16 @@ impl fmt::Display for HgManifestEnvelopeMut {
         writeln!(f, "p2: {}", HgNodeHash::display_opt(self.p2.as_ref()))?;
         writeln!(f, "computed node id: {}", self.computed_node_id)?;
         // TODO: (rain1) T30973227 parse contents and print out in a better fashion
-        writeln!(f, "contents: {:?}", self.contents)
+        if self.shards.is_empty() {
+            writeln!(f, "contents: {:?}", self.contents)
+        } else {
+            writeln!(f, "shards:")?;
+            for shard in &self.shards {
+                let first_name = String::from_utf8_lossy(shard.first_name.as_bytes());
+                writeln!(f, "  {} {}", first_name, shard.id)?;
+            }
+            Ok(())
+        }
     }
 }
 
@@ -55,16 +101,25 @@ pub struct HgManifestEnvelope {
 impl HgManifestEnvelope {
     pub(crate) fn from_thrift(fe: thrift::HgManifestEnvelope) -> Result<Self> {
         let catch_block = || {
+            let shards = fe
+                .shards
+                .unwrap_or_default()
+                .into_iter()
+                .map(HgManifestShard::from_thrift)
+                .collect::<Result<Vec<_>>>()?;
+            let contents = match fe.contents {
+                Some(contents) => Bytes::from(contents),
+                None if !shards.is_empty() => Bytes::new(),
+                None => return Err(err_msg("missing contents field")),
+            };
             Ok(Self {
                 inner: HgManifestEnvelopeMut {
                     node_id: HgNodeHash::from_thrift(fe.node_id)?,
                     p1: HgNodeHash::from_thrift_opt(fe.p1)?,
                     p2: HgNodeHash::from_thrift_opt(fe.p2)?,
                     computed_node_id: HgNodeHash::from_thrift(fe.computed_node_id)?,
-                    contents: Bytes::from(
-                        fe.contents
-                            .ok_or_else(|| err_msg("missing contents field"))?,
-                    ),
+                    contents,
+                    shards,
                 },
             })
         };
@@ -102,12 +157,23 @@ impl HgManifestEnvelope {
         self.inner.computed_node_id
     }
 
-    /// The manifest contents as raw bytes.
+    /// The manifest contents as raw bytes. Empty if the manifest is sharded.
     #[inline]
     pub fn contents(&self) -> &Bytes {
         &self.inner.contents
     }
 
+    /// The shards of the manifest contents, empty unless the manifest is sharded.
+    #[inline]
+    pub fn shards(&self) -> &[HgManifestShard] {
+        &self.inner.shards
+    }
+
+    #[inline]
+    pub fn is_sharded(&self) -> bool {
+        !self.inner.shards.is_empty()
+    }
+
     /// Convert into a mutable representation.
     #[inline]
     pub fn into_mut(self) -> HgManifestEnvelopeMut {
@@ -116,12 +182,23 @@ impl HgManifestEnvelope {
 
     pub(crate) fn into_thrift(self) -> thrift::HgManifestEnvelope {
         let inner = self.inner;
+        let (contents, shards) = if inner.shards.is_empty() {
+            (Some(inner.contents.to_vec()), None)
+        } else {
+            let shards = inner
+                .shards
+                .into_iter()
+                .map(HgManifestShard::into_thrift)
+                .collect();
+            (None, Some(shards))
+        };
         thrift::HgManifestEnvelope {
             node_id: inner.node_id.into_thrift(),
             p1: inner.p1.map(HgNodeHash::into_thrift),
             p2: inner.p2.map(HgNodeHash::into_thrift),
             computed_node_id: inner.computed_node_id.into_thrift(),
-            contents: Some(inner.contents.to_vec()),
+            contents,
+            shards,
         }
     }
 
@@ -151,6 +228,7 @@ impl Arbitrary for HgManifestEnvelope {
                 // Might want to do that.
                 computed_node_id: Arbitrary::arbitrary(g),
                 contents: Bytes::from(Vec::arbitrary(g)),
+                shards: vec![],
             },
         }
     }
@@ -189,6 +267,7 @@ mod test {
             computed_node_id: thrift::HgNodeHash(thrift::Sha1(vec![1; 20])),
             // contents must be present
             contents: None,
+            shards: None,
         };
 
         HgManifestEnvelope::from_thrift(thrift_me.clone())
@@ -199,4 +278,35 @@ mod test {
 
         HgManifestEnvelope::from_thrift(thrift_me).expect_err("unexpected OK -- wrong hash length");
     }
+
+    #[test]
+    fn sharded_roundtrip() {
+        let node_id = HgNodeHash::from_static_str("1111111111111111111111111111111111111111")
+            .expect("valid hash");
+        let me = HgManifestEnvelopeMut {
+            node_id,
+            p1: None,
+            p2: None,
+            computed_node_id: node_id,
+            contents: Bytes::new(),
+            shards: vec![
+                HgManifestShard {
+                    first_name: MPathElement::new(b"a".to_vec()).unwrap(),
+                    id: Blake2::from_byte_array([1; 32]),
+                },
+                HgManifestShard {
+                    first_name: MPathElement::new(b"m".to_vec()).unwrap(),
+                    id: Blake2::from_byte_array([2; 32]),
+                },
+            ],
+        }
+        .freeze();
+
+        let thrift_me = me.clone().into_thrift();
+        assert_eq!(thrift_me.contents, None);
+        let me2 = HgManifestEnvelope::from_blob(me.clone().into_blob())
+            .expect("sharded roundtrips should be valid");
+        assert!(me2.is_sharded());
+        assert_eq!(me, me2);
+    }
 }
@@ -12,7 +12,7 @@ mod manifest_envelope;
 
 pub use self::changeset_envelope::{HgChangesetEnvelope, HgChangesetEnvelopeMut};
 pub use self::file_envelope::{HgFileEnvelope, HgFileEnvelopeMut};
-pub use self::manifest_envelope::{HgManifestEnvelope, HgManifestEnvelopeMut};
+pub use self::manifest_envelope::{HgManifestEnvelope, HgManifestEnvelopeMut, HgManifestShard};
 
 use mononoke_types::BlobstoreBytes;
 
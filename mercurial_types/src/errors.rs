@@ -12,12 +12,16 @@ pub enum ErrorKind {
     InvalidSha1Input(String),
     #[fail(display = "invalid fragment list: {}", _0)]
     InvalidFragmentList(String),
+    #[fail(display = "invalid delta: {}", _0)]
+    InvalidDelta(String),
     #[fail(display = "invalid Thrift structure '{}': {}", _0, _1)]
     InvalidThrift(String, String),
     #[fail(display = "error while deserializing blob for '{}'", _0)]
     BlobDeserializeError(String),
     #[fail(display = "imposssible to parse unknown rev flags")]
     UnknownRevFlags,
+    #[fail(display = "changeset {} is not UTF-8: {}", _0, _1)]
+    NonUtf8Text(String, String),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
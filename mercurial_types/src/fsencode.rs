@@ -7,7 +7,7 @@
 use std::cmp;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use hash::Sha1;
 
@@ -82,6 +82,77 @@ pub fn simple_fsencode(elements: &[MPathElement]) -> PathBuf {
     }
 }
 
+/// Perform the mapping to a filesystem path used in a .hg directory
+/// Assumes that this path is a file.
+/// This encoding is used when the repo has no 'store' requirement: only directories are encoded,
+/// so that they don't clash with the revlogs of the files they are named after.
+pub fn basic_fsencode(elements: &[MPathElement]) -> PathBuf {
+    let mut path = elements.iter().rev();
+    let file = path.next();
+    let directory_elements = path.rev();
+
+    if let Some(basename) = file {
+        let mut ret: PathBuf = directory_elements
+            .map(|elem| OsStr::from_bytes(&direncode(elem.as_bytes())).to_os_string())
+            .collect();
+        ret.push(OsStr::from_bytes(basename.as_bytes()));
+        ret
+    } else {
+        PathBuf::new()
+    }
+}
+
+/// Encode the directories of a '/'-separated path relative to the store, as core mercurial
+/// store.encodedir() does: `foo.i/bar.i` becomes `foo.i.hg/bar.i`.
+/// Streaming clones name the files they send with this encoding, whatever the encoding of the
+/// store is.
+pub fn encodedir(path: &[u8]) -> Vec<u8> {
+    let mut elements: Vec<_> = path.split(|c| *c == b'/').collect();
+    let file = elements.pop();
+    let mut ret: Vec<_> = elements.into_iter().map(direncode).collect();
+    ret.extend(file.map(Vec::from));
+    ret.join(&b'/')
+}
+
+/// How the revlogs of a repo are named in its store, as set by the requirements of the repo.
+/// Matches the choice made by the store() function of core mercurial store.py.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum StoreEncoding {
+    /// No 'store' requirement: revlogs are right under .hg, see `basic_fsencode`
+    Basic,
+    /// 'store' without 'fncache', see `simple_fsencode`
+    Simple,
+    /// 'store' and 'fncache', with 'dotencode' or not, see `fncache_fsencode`
+    Hybrid { dotencode: bool },
+}
+
+impl StoreEncoding {
+    pub fn new(store: bool, fncache: bool, dotencode: bool) -> Self {
+        match (store, fncache) {
+            (false, _) => StoreEncoding::Basic,
+            (true, false) => StoreEncoding::Simple,
+            (true, true) => StoreEncoding::Hybrid { dotencode },
+        }
+    }
+
+    /// The file of the revlog at `elements`, relative to the store
+    pub fn fsencode(&self, elements: &[MPathElement]) -> PathBuf {
+        match *self {
+            StoreEncoding::Basic => basic_fsencode(elements),
+            StoreEncoding::Simple => simple_fsencode(elements),
+            StoreEncoding::Hybrid { dotencode } => fncache_fsencode(elements, dotencode),
+        }
+    }
+
+    /// The store of the repo whose .hg directory is `hg_path`
+    pub fn store_path(&self, hg_path: &Path) -> PathBuf {
+        match *self {
+            StoreEncoding::Basic => hg_path.to_path_buf(),
+            StoreEncoding::Simple | StoreEncoding::Hybrid { .. } => hg_path.join("store"),
+        }
+    }
+}
+
 static HEX: &[u8] = b"0123456789abcdef";
 
 fn hexenc(byte: u8, out: &mut Vec<u8>) {
@@ -239,25 +310,21 @@ fn auxencode<E: AsRef<[u8]>>(elem: E, dotencode: bool) -> Vec<u8> {
     ret
 }
 
-/// Returns file extension with period; leading periods are ignored
+/// Returns file extension with period; leading periods are ignored, as in Python's
+/// os.path.splitext()
 ///
 /// # Examples
 /// ```
 /// assert_eq(get_extension(b".foo"), b"");
+/// assert_eq(get_extension(b"..foo"), b"");
 /// assert_eq(get_extension(b"bar.foo"), b".foo");
 /// assert_eq(get_extension(b"foo."), b".");
 ///
 /// ```
 fn get_extension(basename: &[u8]) -> &[u8] {
-    let idx = basename
-        .iter()
-        .enumerate()
-        .rev()
-        .find(|&(_, c)| *c == b'.')
-        .map(|(idx, _)| idx);
-    match idx {
-        None | Some(0) => b"",
-        Some(idx) => &basename[idx..],
+    match basename.iter().rposition(|c| *c == b'.') {
+        Some(idx) if basename[..idx].iter().any(|c| *c != b'.') => &basename[idx..],
+        _ => b"",
     }
 }
 
@@ -351,8 +418,9 @@ fn hashencode(dirs: Vec<Vec<u8>>, file: &[u8], dotencode: bool) -> PathBuf {
     res.push(get_extension(&basename));
 
     let filler = {
+        // Long extensions may leave no space for the filler at all
         let current_len = res.iter().map(|elem| elem.len()).sum::<usize>();
-        let spaceleft = MAXSTOREPATHLEN - current_len;
+        let spaceleft = MAXSTOREPATHLEN.saturating_sub(current_len);
         let size = cmp::min(basename.len(), spaceleft);
         &basename[..size]
     };
@@ -476,6 +544,104 @@ mod test {
         assert_eq!(get_extension(b"foo"), b"");
         assert_eq!(get_extension(b"foo.txt"), b".txt");
         assert_eq!(get_extension(b"foo.bar.blat"), b".blat");
+        assert_eq!(get_extension(b"..foo"), b"");
+        assert_eq!(get_extension(b"..foo.bar"), b".bar");
+        assert_eq!(get_extension(b".foo."), b".");
+    }
+
+    #[test]
+    fn fsencode_hashed_extensions() {
+        // Leading periods are not an extension
+        let mut toencode = b"data/".to_vec();
+        toencode.extend(vec![b'x'; 120]);
+        toencode.extend_from_slice(b"/..foo");
+        check_fsencode(
+            &toencode,
+            "dh/xxxxxxxx/..foobdacd8d286b82e13a670f62a48993947c72fb34a",
+        );
+
+        // An extension too long to leave space for any of the basename
+        let mut toencode = b"data/foo/a.".to_vec();
+        toencode.extend(vec![b'x'; 130]);
+        let mut expected = "dh/foo/f154fb39d76c2d1134708e979574e9c3b38238ad.".to_string();
+        expected.extend(vec!['x'; 130]);
+        check_fsencode(&toencode, &expected);
+    }
+
+    #[test]
+    fn fsencode_windows_without_dotencode() {
+        check_fsencode(b"data/.foo/ bar/baz.", "data/.foo/ bar/baz~2e");
+        check_fsencode(b"data/aux.txt/COM1/nul", "data/au~78.txt/_c_o_m1/nu~6c");
+    }
+
+    fn check_basic_fsencode(path: &[u8], expected: &str) {
+        let mut elements = vec![];
+        let path = &MPath::new(path).unwrap();
+        elements.extend(path.into_iter().cloned());
+
+        assert_eq!(basic_fsencode(&elements), PathBuf::from(expected));
+    }
+
+    #[test]
+    fn test_basic_fsencode() {
+        check_basic_fsencode(
+            b"data/foo.i/bar.d/baz.hg/x.i",
+            "data/foo.i.hg/bar.d.hg/baz.hg.hg/x.i",
+        );
+        check_basic_fsencode(b"data/HELLO/wo:rld.i", "data/HELLO/wo:rld.i");
+    }
+
+    #[test]
+    fn test_encodedir() {
+        assert_eq!(
+            encodedir(b"data/foo.i/bar.d/baz.hg/x.i"),
+            b"data/foo.i.hg/bar.d.hg/baz.hg.hg/x.i".to_vec()
+        );
+        assert_eq!(encodedir(b"00changelog.i"), b"00changelog.i".to_vec());
+        assert_eq!(encodedir(b"data/.i"), b"data/.i".to_vec());
+    }
+
+    #[test]
+    fn test_store_encoding() {
+        let elements: Vec<_> = MPath::new(b"data/foo.d/.Bar.i")
+            .unwrap()
+            .into_iter()
+            .collect();
+        let hg_path = Path::new("repo/.hg");
+
+        let basic = StoreEncoding::new(false, true, true);
+        assert_eq!(basic, StoreEncoding::Basic);
+        assert_eq!(
+            basic.fsencode(&elements),
+            PathBuf::from("data/foo.d.hg/.Bar.i")
+        );
+        assert_eq!(basic.store_path(hg_path), PathBuf::from("repo/.hg"));
+
+        let simple = StoreEncoding::new(true, false, true);
+        assert_eq!(simple, StoreEncoding::Simple);
+        assert_eq!(
+            simple.fsencode(&elements),
+            PathBuf::from("data/foo.d.hg/._bar.i")
+        );
+        assert_eq!(simple.store_path(hg_path), PathBuf::from("repo/.hg/store"));
+
+        let hybrid = StoreEncoding::new(true, true, false);
+        assert_eq!(hybrid, StoreEncoding::Hybrid { dotencode: false });
+        assert_eq!(
+            hybrid.fsencode(&elements),
+            PathBuf::from("data/foo.d.hg/._bar.i")
+        );
+
+        let dotencode = StoreEncoding::new(true, true, true);
+        assert_eq!(dotencode, StoreEncoding::Hybrid { dotencode: true });
+        assert_eq!(
+            dotencode.fsencode(&elements),
+            PathBuf::from("data/foo.d.hg/~2e_bar.i")
+        );
+        assert_eq!(
+            dotencode.store_path(hg_path),
+            PathBuf::from("repo/.hg/store")
+        );
     }
 
     #[test]
@@ -566,5 +732,4 @@ mod test {
         let expected = "_a/Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y::Y/_z_z_z/_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x_x";
         check_simple_fsencode(toencode, expected);
     }
-
 }
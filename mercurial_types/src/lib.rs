@@ -50,7 +50,6 @@ extern crate abomonation;
 extern crate abomonation_derive;
 extern crate ascii;
 extern crate asyncmemo;
-extern crate bincode;
 #[macro_use]
 extern crate bitflags;
 extern crate bytes;
@@ -108,15 +107,15 @@ pub mod utils;
 
 pub use blob::HgBlob;
 pub use blobnode::{HgBlobNode, HgParents};
-pub use changeset::Changeset;
+pub use changeset::{Changeset, DecodedText, TextDecoding};
 pub use delta::Delta;
 pub use envelope::{
     HgChangesetEnvelope, HgChangesetEnvelopeMut, HgFileEnvelope, HgFileEnvelopeMut,
-    HgManifestEnvelope, HgManifestEnvelopeMut,
+    HgManifestEnvelope, HgManifestEnvelopeMut, HgManifestShard,
 };
 pub use errors::{Error, ErrorKind};
 pub use flags::{parse_rev_flags, RevFlags};
-pub use fsencode::{fncache_fsencode, simple_fsencode};
+pub use fsencode::{basic_fsencode, encodedir, fncache_fsencode, simple_fsencode, StoreEncoding};
 pub use manifest::{Entry, Manifest, Type};
 // Re-exports from mononoke_types. Eventually these should go away and everything should depend
 // directly on mononoke_types;
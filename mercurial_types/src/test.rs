@@ -608,3 +608,33 @@ fn test_fsencode_from_core_hg() {
     let expected = "dh/12345678/12345678/12345678/12345678/12345678/12345678/12345678/12345/-xxxxx93352aa50377751d9e5ebdf52da1e6e69a6887a6.i";
     check_fsencode_with_dotencode(&toencode[..], expected);
 }
+
+// Windows edge cases that the test-hybridencode corpus above doesn't cover: reserved names and
+// leading or trailing periods and spaces in hashed paths, and extensions that don't fit in a
+// hashed path. Expected values are the output of core hg store._hybridencode(path, True).
+#[test]
+fn test_fsencode_windows_edge_cases() {
+    let toencode = b"data/.foo/ bar/baz.";
+    let expected = "data/~2efoo/~20bar/baz~2e";
+    check_fsencode_with_dotencode(&toencode[..], expected);
+
+    let toencode = b"data/yyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyy/nul.";
+    let expected = "data/yyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyy/nu~6c~2e";
+    check_fsencode_with_dotencode(&toencode[..], expected);
+
+    let toencode = b"data/yyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyy/COM1.TXT";
+    let expected = "data/yyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyy/_c_o_m1._t_x_t";
+    check_fsencode_with_dotencode(&toencode[..], expected);
+
+    let toencode = b"data/ con/.prn/lpt1 /aux. /zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz.txt";
+    let expected = "dh/~20con/~2eprn/lpt1~20/au~78.~2/zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz576e319b32b5a550ddbd8ace5870bda546f5f468.txt";
+    check_fsencode_with_dotencode(&toencode[..], expected);
+
+    let toencode = b"data/xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx/..foo";
+    let expected = "dh/xxxxxxxx/~2e.foobdacd8d286b82e13a670f62a48993947c72fb34a.foo";
+    check_fsencode_with_dotencode(&toencode[..], expected);
+
+    let toencode = b"data/vvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvv/file.eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee";
+    let expected = "dh/vvvvvvvv/d2b24e897726d6e18df6a11dcd5829c31992f4ac.eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee";
+    check_fsencode_with_dotencode(&toencode[..], expected);
+}
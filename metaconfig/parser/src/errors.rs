@@ -29,4 +29,7 @@ pub enum ErrorKind {
     /// Too many bypass options for a hook
     #[fail(display = "Only one bypass option is allowed. Hook: {}", _0)]
     TooManyBypassOptions(String),
+    /// Users allowed to bypass a hook that can't be bypassed
+    #[fail(display = "bypass_allowed_users needs a bypass. Hook: {}", _0)]
+    AllowedUsersWithoutBypass(String),
 }
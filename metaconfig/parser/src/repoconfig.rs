@@ -11,10 +11,17 @@ use bookmarks::Bookmark;
 use errors::*;
 use failure::ResultExt;
 use metaconfig_types::{
-    BlobstoreId, BookmarkOrRegex, BookmarkParams, Bundle2ReplayParams, CacheWarmupParams,
-    GlusterArgs, HookBypass, HookConfig, HookManagerParams, HookParams, HookType, LfsParams,
-    ManifoldArgs, MysqlBlobstoreArgs, PushrebaseParams, RemoteBlobstoreArgs, RepoConfig,
-    RepoReadOnly, RepoType,
+    AuditParams, AuditSink, BlobstoreCacheTtls, BlobstoreId, BlobstoreScrubAction, BookmarkOrRegex,
+    BookmarkParams, Bundle2ReplayParams, CacheWarmupParams, CommitField, CommitRewriter,
+    ContentRefsParams, CopyInfoCheckParams, EventBusParams, FaultInjectionParams, FaultMode,
+    FaultRule, GlusterArgs, HedgingParams, HookBypass, HookConfig, HookLimitAction, HookLimits,
+    HookManagerParams, HookParams, HookPathFilter, HookType, LfsParams, ManifestShardingParams,
+    ManifoldArgs, MysqlBlobstoreArgs, PathAclParams, PullThroughParams, PushQuotaLimits,
+    PushQuotaParams, PushQuotaTeam, PushrebaseParams, QosLimits, QosParams, QuarantineParams,
+    ReadReplicaParams, RemoteBlobstoreArgs, RepoConfig, RepoReadOnly, RepoType,
+    ResponseCacheParams, ResumablePullParams, ScratchNamespace, SessionLimits,
+    SkiplistRefreshParams, TreePrefetchParams, WebhookParams, WireprotoCapsChanges,
+    WireprotoCapsOverride, WireprotoCapsParams, RESPONSE_SIZE_LIMITED_COMMANDS,
 };
 use regex::Regex;
 use std::collections::HashMap;
@@ -107,10 +114,27 @@ impl RepoConfigs {
 
         let mut all_hook_params = vec![];
         for raw_hook_config in hooks {
+            let bypass = RepoConfigs::get_bypass(raw_hook_config.clone())?;
+            let bypass_allowed_users = raw_hook_config
+                .bypass_allowed_users
+                .clone()
+                .unwrap_or_default();
+            if bypass.is_none() && !bypass_allowed_users.is_empty() {
+                return Err(ErrorKind::AllowedUsersWithoutBypass(raw_hook_config.name).into());
+            }
             let config = HookConfig {
-                bypass: RepoConfigs::get_bypass(raw_hook_config.clone())?,
+                bypass,
+                bypass_allowed_users,
                 strings: raw_hook_config.config_strings.unwrap_or_default(),
                 ints: raw_hook_config.config_ints.unwrap_or_default(),
+                limits: HookLimits {
+                    timeout_ms: raw_hook_config.timeout_ms,
+                    max_concurrency: raw_hook_config.max_concurrency,
+                    max_content_bytes: raw_hook_config.max_content_bytes,
+                    max_state_fetches: raw_hook_config.max_state_fetches,
+                    on_exceeded: raw_hook_config.on_limit_exceeded.unwrap_or_default(),
+                },
+                path_filters: RepoConfigs::get_path_filters(&raw_hook_config)?,
             };
 
             let hook_params = if raw_hook_config.name.starts_with("rust:") {
@@ -158,6 +182,19 @@ impl RepoConfigs {
         ))
     }
 
+    fn get_path_filters(raw_hook_config: &RawHookConfig) -> Result<Vec<HookPathFilter>> {
+        let mut path_filters: Vec<_> = raw_hook_config
+            .path_prefixes
+            .iter()
+            .flatten()
+            .map(|prefix| HookPathFilter::prefix(prefix))
+            .collect();
+        for glob in raw_hook_config.path_globs.iter().flatten() {
+            path_filters.push(HookPathFilter::glob(glob)?);
+        }
+        Ok(path_filters)
+    }
+
     fn get_bypass(raw_hook_config: RawHookConfig) -> Result<Option<HookBypass>> {
         let bypass_commit_message = raw_hook_config
             .bypass_commit_string
@@ -215,6 +252,23 @@ impl RepoConfigs {
                     "xdb tier for the write lock db was not specified".into(),
                 ))?;
 
+                let hedging = match this.blobstore_hedging {
+                    Some(hedging) => {
+                        if hedging.delay_percentile == 0 || hedging.delay_percentile >= 100 {
+                            return Err(ErrorKind::InvalidConfig(
+                                "blobstore hedging delay percentile must be between 1 and 99"
+                                    .into(),
+                            )
+                            .into());
+                        }
+                        Some(HedgingParams {
+                            delay_percentile: hedging.delay_percentile,
+                            min_delay_ms: hedging.min_delay_ms.unwrap_or(0),
+                        })
+                    }
+                    None => None,
+                };
+
                 let mut blobstores = HashMap::new();
                 for blobstore in remote_blobstores {
                     let args = match blobstore.blobstore_type {
@@ -264,9 +318,20 @@ impl RepoConfigs {
                                      than 0"
                                         .into(),
                                 ))?;
+                            let compression_level = match blobstore.mysql_compression_level {
+                                Some(level) if level < 1 || level > 22 => {
+                                    return Err(ErrorKind::InvalidConfig(
+                                        "mysql compression level must be between 1 and 22".into(),
+                                    )
+                                    .into());
+                                }
+                                level => level,
+                            };
                             RemoteBlobstoreArgs::Mysql(MysqlBlobstoreArgs {
                                 shardmap,
                                 shard_num,
+                                hedging: hedging.clone(),
+                                compression_level,
                             })
                         }
                     };
@@ -278,21 +343,47 @@ impl RepoConfigs {
                     }
                 }
 
+                let write_quorum = this.blobstore_write_quorum.unwrap_or(1);
+                if write_quorum == 0 || write_quorum > blobstores.len() {
+                    return Err(ErrorKind::InvalidConfig(
+                        "blobstore write quorum must be between 1 and the number of blobstores"
+                            .into(),
+                    )
+                    .into());
+                }
+
                 let blobstores_args = if blobstores.len() == 1 {
                     let (_, args) = blobstores.into_iter().next().unwrap();
                     args
                 } else {
                     RemoteBlobstoreArgs::Multiplexed {
                         scuba_table: this.blobstore_scuba_table,
+                        hedging,
+                        write_quorum,
+                        scrub_action: this.blobstore_scrub_action,
                         blobstores,
                     }
                 };
 
+                let read_replicas = this.read_replicas.map(|raw| ReadReplicaParams {
+                    standby_db_addresses: raw.standby_db_addresses,
+                    failure_threshold: raw.failure_threshold.unwrap_or(3),
+                    reresolve_interval_secs: raw.reresolve_interval_secs.unwrap_or(60),
+                });
+
+                let blobstore_cache_ttls =
+                    this.blobstore_cache_ttls.map(|raw| BlobstoreCacheTtls {
+                        default_ttl_secs: raw.default_ttl_secs,
+                        ttl_secs_by_type: raw.ttl_secs_by_type.unwrap_or_default(),
+                    });
+
                 RepoType::BlobRemote {
                     blobstores_args,
                     db_address,
                     filenode_shards: this.filenode_shards,
                     write_lock_db_address,
+                    read_replicas,
+                    blobstore_cache_ttls,
                 }
             }
         };
@@ -305,11 +396,21 @@ impl RepoConfigs {
         let cache_warmup = this.cache_warmup.map(|cache_warmup| CacheWarmupParams {
             bookmark: Bookmark::new(cache_warmup.bookmark).expect("bookmark name must be ascii"),
             commit_limit: cache_warmup.commit_limit.unwrap_or(200000),
+            hot_bookmarks: cache_warmup
+                .hot_bookmarks
+                .unwrap_or(vec![])
+                .into_iter()
+                .map(|name| Bookmark::new(name).expect("bookmark name must be ascii"))
+                .collect(),
+            filenodes_commit_limit: cache_warmup.filenodes_commit_limit.unwrap_or(0),
         });
         let hook_manager_params = this.hook_manager_params.map(|params| HookManagerParams {
             entrylimit: params.entrylimit,
             weightlimit: params.weightlimit,
             disable_acl_checker: params.disable_acl_checker,
+            result_cache_ttl_secs: params.result_cache_ttl_secs,
+            state_cache_ttl_secs: params.state_cache_ttl_secs,
+            strict_utf8: params.strict_utf8,
         });
         let bookmarks = match this.bookmarks {
             Some(bookmarks) => {
@@ -354,7 +455,37 @@ impl RepoConfigs {
                     rewritedates: raw.rewritedates.unwrap_or(default.rewritedates),
                     recursion_limit: raw.recursion_limit.unwrap_or(default.recursion_limit),
                     commit_scribe_category: raw.commit_scribe_category,
-                    block_merges: raw.block_merges.unwrap_or(default.block_merges)
+                    block_merges: raw.block_merges.unwrap_or(default.block_merges),
+                    defer_hg_derivation: raw
+                        .defer_hg_derivation
+                        .unwrap_or(default.defer_hg_derivation),
+                    commit_rewriters: raw
+                        .commit_rewriters
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|raw| match raw {
+                            RawCommitRewriter::StripLocalMarkers { markers } => {
+                                CommitRewriter::StripLocalMarkers { markers }
+                            }
+                            RawCommitRewriter::MapUsernames { map } => {
+                                CommitRewriter::MapUsernames { map }
+                            }
+                            RawCommitRewriter::LandedFromTrailer { trailer } => {
+                                CommitRewriter::LandedFromTrailer {
+                                    trailer: trailer.unwrap_or_else(|| "Landed-From".to_string()),
+                                }
+                            }
+                            RawCommitRewriter::RegexReplace {
+                                field,
+                                regex,
+                                replacement,
+                            } => CommitRewriter::RegexReplace {
+                                field,
+                                regex: regex.0,
+                                replacement,
+                            },
+                        })
+                        .collect(),
                 }
             })
             .unwrap_or_default();
@@ -366,6 +497,215 @@ impl RepoConfigs {
             })
             .unwrap_or_default();
 
+        let webhooks = this
+            .webhooks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|raw| {
+                let names = raw
+                    .bookmarks
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|name| Bookmark::new(name).map(BookmarkOrRegex::Bookmark));
+                let regexes = raw
+                    .bookmark_regexes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|regex| Ok(BookmarkOrRegex::Regex(regex.0)));
+                let bookmarks = names.chain(regexes).collect::<Result<_>>()?;
+                Ok(WebhookParams {
+                    url: raw.url,
+                    bookmarks,
+                    secret: raw.secret,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let event_bus = this.event_bus.map(|raw| match raw {
+            RawEventBusParams::Scribe { category } => EventBusParams::Scribe { category },
+            RawEventBusParams::Kafka { brokers, topic } => EventBusParams::Kafka { brokers, topic },
+            RawEventBusParams::Stdout => EventBusParams::Stdout,
+        });
+
+        let scratch_namespace = this
+            .scratch_namespace
+            .map(|regex| ScratchNamespace::new(regex.0));
+
+        let tree_prefetch = this.tree_prefetch.map(|raw| TreePrefetchParams {
+            children_limit: raw.children_limit.unwrap_or(10),
+            size_budget: raw.size_budget,
+        });
+
+        let audit = this.audit.map(|raw| AuditParams {
+            sink: match raw.sink {
+                RawAuditSink::Sql => AuditSink::Sql,
+                RawAuditSink::Scribe { category } => AuditSink::Scribe { category },
+            },
+            sample_percentage: raw.sample_percentage.unwrap_or(100),
+        });
+
+        let response_cache = this.response_cache.map(|raw| ResponseCacheParams {
+            use_blobstore: raw.use_blobstore.unwrap_or(false),
+            blobstore_ttl_secs: raw.blobstore_ttl_secs.unwrap_or(7 * 24 * 60 * 60),
+        });
+
+        let fault_injection = this.fault_injection.map(|raw| {
+            let rules = |raw: Option<Vec<RawFaultRule>>| {
+                raw.unwrap_or_default()
+                    .into_iter()
+                    .map(|raw| FaultRule {
+                        key_regex: raw.key_regex.map(|regex| regex.0),
+                        operations: raw.operations.unwrap_or_default(),
+                        error_percentage: raw.error_percentage.unwrap_or(0),
+                        fail_first: raw.fail_first.unwrap_or(0),
+                        mode: raw.mode.unwrap_or(FaultMode::Error),
+                        latency_ms: raw.latency_ms.unwrap_or(0),
+                    })
+                    .collect()
+            };
+            FaultInjectionParams {
+                seed: raw.seed,
+                blobstore: rules(raw.blobstore),
+                sql: rules(raw.sql),
+            }
+        });
+
+        let session_limits = this
+            .session_limits
+            .map(|raw| SessionLimits {
+                idle_timeout_secs: raw.idle_timeout_secs,
+                keepalive_interval_secs: raw.keepalive_interval_secs,
+                max_session_duration_secs: raw.max_session_duration_secs,
+                max_sessions: raw.max_sessions,
+            })
+            .unwrap_or_default();
+
+        let resumable_pull = this.resumable_pull.map(|raw| ResumablePullParams {
+            ttl_secs: raw.ttl_secs.unwrap_or(600),
+            max_resumes: raw.max_resumes.unwrap_or(3),
+        });
+
+        let response_size_limits = this.response_size_limits.unwrap_or_default();
+        for command in response_size_limits.keys() {
+            if !RESPONSE_SIZE_LIMITED_COMMANDS.contains(&command.as_str()) {
+                return Err(ErrorKind::InvalidConfig(format!(
+                    "responses to {} can't be limited in size, only to {}",
+                    command,
+                    RESPONSE_SIZE_LIMITED_COMMANDS.join(", ")
+                ))
+                .into());
+            }
+        }
+
+        let qos = this
+            .qos
+            .map(|raw| {
+                let limits = |raw: Option<RawQosLimits>| {
+                    raw.map(|raw| QosLimits {
+                        max_concurrency: raw.max_concurrency,
+                        timeout_secs: raw.timeout_secs,
+                    })
+                    .unwrap_or_default()
+                };
+                QosParams {
+                    batch_identities: raw.batch_identities.unwrap_or_default(),
+                    interactive: limits(raw.interactive),
+                    batch: limits(raw.batch),
+                }
+            })
+            .unwrap_or_default();
+
+        let push_quota = this
+            .push_quota
+            .map(|raw| {
+                let default = PushQuotaParams::default();
+                PushQuotaParams {
+                    window_days: raw.window_days.unwrap_or(default.window_days),
+                    default_limits: PushQuotaLimits {
+                        soft_limit_bytes: raw.soft_limit_bytes,
+                        hard_limit_bytes: raw.hard_limit_bytes,
+                    },
+                    teams: raw
+                        .teams
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|team| PushQuotaTeam {
+                            name: team.name,
+                            members: team.members,
+                            limits: PushQuotaLimits {
+                                soft_limit_bytes: team.soft_limit_bytes,
+                                hard_limit_bytes: team.hard_limit_bytes,
+                            },
+                        })
+                        .collect(),
+                }
+            })
+            .unwrap_or_default();
+
+        let content_refs = this.content_refs.map(|raw| ContentRefsParams {
+            max_refs_per_content: raw.max_refs_per_content.unwrap_or(1000),
+            max_refs_per_push: raw.max_refs_per_push.unwrap_or(10000),
+        });
+
+        let manifest_sharding = this.manifest_sharding.map(|raw| ManifestShardingParams {
+            threshold_bytes: raw.threshold_bytes.unwrap_or(1024 * 1024),
+            shard_size_bytes: raw.shard_size_bytes.unwrap_or(256 * 1024),
+        });
+
+        let wireproto_caps = this
+            .wireproto_caps
+            .map(|raw| WireprotoCapsParams {
+                default: raw
+                    .default
+                    .map(RawWireprotoCapsChanges::into_changes)
+                    .unwrap_or_default(),
+                overrides: raw
+                    .overrides
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|raw| WireprotoCapsOverride {
+                        identities: raw.identities,
+                        changes: raw.changes.into_changes(),
+                    })
+                    .collect(),
+            })
+            .unwrap_or_default();
+
+        let copy_info_check = this.copy_info_check.map(|raw| CopyInfoCheckParams {
+            fail_on_mismatch: raw.fail_on_mismatch.unwrap_or(false),
+        });
+
+        let quarantine = this.quarantine.map(|raw| QuarantineParams {
+            ttl_secs: raw.ttl_secs.unwrap_or(300),
+        });
+
+        let pull_through = this.pull_through.map(|raw| PullThroughParams {
+            upstream_url: raw.upstream_url,
+            upstream_repo: raw.upstream_repo,
+            negative_cache_ttl_secs: raw.negative_cache_ttl_secs.unwrap_or(60),
+            prefetch_parents_depth: raw.prefetch_parents_depth.unwrap_or(10),
+            sync_interval_secs: raw.sync_interval_secs.unwrap_or(60),
+        });
+
+        let path_acls = this
+            .path_acls
+            .unwrap_or_default()
+            .into_iter()
+            .map(|raw| {
+                let path = raw.path.trim_matches('/').to_string();
+                if path.is_empty() {
+                    return Err(ErrorKind::InvalidConfig(
+                        "path ACLs can't restrict the root of the repo".into(),
+                    )
+                    .into());
+                }
+                Ok(PathAclParams {
+                    path,
+                    identities: raw.identities,
+                })
+            })
+            .collect::<Result<_>>()?;
+
         let lfs = match this.lfs {
             Some(lfs_params) => LfsParams {
                 threshold: lfs_params.threshold,
@@ -382,6 +722,11 @@ impl RepoConfigs {
         };
 
         let skiplist_index_blobstore_key = this.skiplist_index_blobstore_key;
+        let skiplist_refresh = this.skiplist_refresh.map(|raw| SkiplistRefreshParams {
+            bookmark_poll_interval_secs: raw.bookmark_poll_interval_secs.unwrap_or(30),
+            max_index_depth: raw.max_index_depth.unwrap_or(100_000),
+            snapshot_interval_secs: raw.snapshot_interval_secs,
+        });
         Ok(RepoConfig {
             enabled,
             repotype,
@@ -398,7 +743,28 @@ impl RepoConfigs {
             hash_validation_percentage,
             readonly,
             skiplist_index_blobstore_key,
+            skiplist_refresh,
             bundle2_replay_params,
+            webhooks,
+            event_bus,
+            scratch_namespace,
+            tree_prefetch,
+            audit,
+            response_cache,
+            fault_injection,
+            session_limits,
+            resumable_pull,
+            response_size_limits,
+            qos,
+            push_quota,
+            content_refs,
+            manifest_sharding,
+            wireproto_caps,
+            copy_info_check,
+            quarantine,
+            path_acls,
+            phases_admin_identities: this.phases_admin_identities.unwrap_or_default(),
+            pull_through,
         })
     }
 }
@@ -413,8 +779,13 @@ struct RawRepoConfig {
     db_address: Option<String>,
     write_lock_db_address: Option<String>,
     filenode_shards: Option<usize>,
+    read_replicas: Option<RawReadReplicaParams>,
+    blobstore_cache_ttls: Option<RawBlobstoreCacheTtls>,
     scuba_table: Option<String>,
     blobstore_scuba_table: Option<String>,
+    blobstore_hedging: Option<RawHedgingConfig>,
+    blobstore_write_quorum: Option<usize>,
+    blobstore_scrub_action: Option<BlobstoreScrubAction>,
     delay_mean: Option<u64>,
     delay_stddev: Option<u64>,
     cache_warmup: Option<RawCacheWarmupConfig>,
@@ -427,14 +798,56 @@ struct RawRepoConfig {
     readonly: Option<bool>,
     hook_manager_params: Option<HookManagerParams>,
     skiplist_index_blobstore_key: Option<String>,
+    skiplist_refresh: Option<RawSkiplistRefreshParams>,
     remote_blobstore: Option<Vec<RawRemoteBlobstoreConfig>>,
     bundle2_replay_params: Option<RawBundle2ReplayParams>,
+    webhooks: Option<Vec<RawWebhookConfig>>,
+    event_bus: Option<RawEventBusParams>,
+    scratch_namespace: Option<RawRegex>,
+    tree_prefetch: Option<RawTreePrefetchParams>,
+    audit: Option<RawAuditParams>,
+    response_cache: Option<RawResponseCacheParams>,
+    fault_injection: Option<RawFaultInjectionParams>,
+    session_limits: Option<RawSessionLimits>,
+    resumable_pull: Option<RawResumablePullParams>,
+    response_size_limits: Option<HashMap<String, u64>>,
+    qos: Option<RawQosParams>,
+    push_quota: Option<RawPushQuotaParams>,
+    content_refs: Option<RawContentRefsParams>,
+    manifest_sharding: Option<RawManifestShardingParams>,
+    wireproto_caps: Option<RawWireprotoCapsParams>,
+    copy_info_check: Option<RawCopyInfoCheckParams>,
+    quarantine: Option<RawQuarantineParams>,
+    path_acls: Option<Vec<RawPathAclParams>>,
+    phases_admin_identities: Option<Vec<String>>,
+    pull_through: Option<RawPullThroughParams>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct RawReadReplicaParams {
+    standby_db_addresses: Vec<String>,
+    failure_threshold: Option<usize>,
+    reresolve_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct RawBlobstoreCacheTtls {
+    default_ttl_secs: Option<u64>,
+    ttl_secs_by_type: Option<HashMap<String, u64>>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct RawCacheWarmupConfig {
     bookmark: String,
     commit_limit: Option<usize>,
+    hot_bookmarks: Option<Vec<String>>,
+    filenodes_commit_limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct RawHedgingConfig {
+    delay_percentile: u8,
+    min_delay_ms: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -471,8 +884,16 @@ struct RawHookConfig {
     hook_type: HookType,
     bypass_commit_string: Option<String>,
     bypass_pushvar: Option<String>,
+    bypass_allowed_users: Option<Vec<String>>,
     config_strings: Option<HashMap<String, String>>,
     config_ints: Option<HashMap<String, i32>>,
+    timeout_ms: Option<u64>,
+    max_concurrency: Option<usize>,
+    max_content_bytes: Option<u64>,
+    max_state_fetches: Option<u64>,
+    on_limit_exceeded: Option<HookLimitAction>,
+    path_prefixes: Option<Vec<String>>,
+    path_globs: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -489,6 +910,8 @@ struct RawRemoteBlobstoreConfig {
     // required mysql arguments
     mysql_shardmap: Option<String>,
     mysql_shard_num: Option<i32>,
+    // optional mysql arguments
+    mysql_compression_level: Option<i32>,
 }
 
 /// Types of repositories supported
@@ -521,6 +944,43 @@ struct RawPushrebaseParams {
     recursion_limit: Option<usize>,
     commit_scribe_category: Option<String>,
     block_merges: Option<bool>,
+    defer_hg_derivation: Option<bool>,
+    commit_rewriters: Option<Vec<RawCommitRewriter>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RawCommitRewriter {
+    StripLocalMarkers {
+        markers: Vec<String>,
+    },
+    MapUsernames {
+        map: HashMap<String, String>,
+    },
+    LandedFromTrailer {
+        trailer: Option<String>,
+    },
+    RegexReplace {
+        field: CommitField,
+        regex: RawRegex,
+        replacement: String,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct RawWebhookConfig {
+    url: String,
+    bookmarks: Option<Vec<String>>,
+    bookmark_regexes: Option<Vec<RawRegex>>,
+    secret: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RawEventBusParams {
+    Scribe { category: String },
+    Kafka { brokers: String, topic: String },
+    Stdout,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -528,6 +988,167 @@ struct RawLfsParams {
     threshold: Option<u64>,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct RawTreePrefetchParams {
+    children_limit: Option<usize>,
+    size_budget: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawResponseCacheParams {
+    use_blobstore: Option<bool>,
+    blobstore_ttl_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawFaultInjectionParams {
+    seed: Option<u64>,
+    blobstore: Option<Vec<RawFaultRule>>,
+    sql: Option<Vec<RawFaultRule>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawFaultRule {
+    key_regex: Option<RawRegex>,
+    operations: Option<Vec<String>>,
+    error_percentage: Option<usize>,
+    fail_first: Option<usize>,
+    mode: Option<FaultMode>,
+    latency_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawSessionLimits {
+    idle_timeout_secs: Option<u64>,
+    keepalive_interval_secs: Option<u64>,
+    max_session_duration_secs: Option<u64>,
+    max_sessions: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawQosParams {
+    batch_identities: Option<Vec<String>>,
+    interactive: Option<RawQosLimits>,
+    batch: Option<RawQosLimits>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawQosLimits {
+    max_concurrency: Option<usize>,
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawPushQuotaParams {
+    window_days: Option<u64>,
+    soft_limit_bytes: Option<u64>,
+    hard_limit_bytes: Option<u64>,
+    teams: Option<Vec<RawPushQuotaTeam>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawPushQuotaTeam {
+    name: String,
+    members: Vec<String>,
+    soft_limit_bytes: Option<u64>,
+    hard_limit_bytes: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawContentRefsParams {
+    max_refs_per_content: Option<u64>,
+    max_refs_per_push: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawManifestShardingParams {
+    threshold_bytes: Option<u64>,
+    shard_size_bytes: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawCopyInfoCheckParams {
+    fail_on_mismatch: Option<bool>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawQuarantineParams {
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawPullThroughParams {
+    upstream_url: String,
+    upstream_repo: String,
+    negative_cache_ttl_secs: Option<u64>,
+    prefetch_parents_depth: Option<usize>,
+    sync_interval_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawPathAclParams {
+    path: String,
+    identities: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawWireprotoCapsParams {
+    default: Option<RawWireprotoCapsChanges>,
+    overrides: Option<Vec<RawWireprotoCapsOverride>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawWireprotoCapsChanges {
+    enable: Option<Vec<String>>,
+    disable: Option<Vec<String>>,
+    enable_bundle2: Option<Vec<String>>,
+    disable_bundle2: Option<Vec<String>>,
+}
+
+impl RawWireprotoCapsChanges {
+    fn into_changes(self) -> WireprotoCapsChanges {
+        WireprotoCapsChanges {
+            enable: self.enable.unwrap_or_default(),
+            disable: self.disable.unwrap_or_default(),
+            enable_bundle2: self.enable_bundle2.unwrap_or_default(),
+            disable_bundle2: self.disable_bundle2.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawWireprotoCapsOverride {
+    identities: Vec<String>,
+    #[serde(flatten)]
+    changes: RawWireprotoCapsChanges,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawSkiplistRefreshParams {
+    bookmark_poll_interval_secs: Option<u64>,
+    max_index_depth: Option<u64>,
+    snapshot_interval_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawResumablePullParams {
+    ttl_secs: Option<u64>,
+    max_resumes: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawAuditParams {
+    sink: RawAuditSink,
+    sample_percentage: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RawAuditSink {
+    Sql,
+    Scribe { category: String },
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct RawBundle2ReplayParams {
     preserve_raw_bundle2: Option<bool>,
@@ -551,14 +1172,34 @@ mod test {
             repoid=0
             scuba_table="scuba_table"
             blobstore_scuba_table="blobstore_scuba_table"
+            blobstore_write_quorum=2
+            blobstore_scrub_action="ReportOnly"
             skiplist_index_blobstore_key="skiplist_key"
+            scratch_namespace="^scratch/.+$"
+            phases_admin_identities=["svc-release"]
+            [read_replicas]
+            standby_db_addresses=["standby_db_address"]
+            reresolve_interval_secs=30
+            [blobstore_cache_ttls]
+            default_ttl_secs=86400
+            [blobstore_cache_ttls.ttl_secs_by_type]
+            hgmanifest=3600
+            content=0
             [cache_warmup]
             bookmark="master"
             commit_limit=100
+            hot_bookmarks=["release", "stable"]
+            filenodes_commit_limit=50
+            [blobstore_hedging]
+            delay_percentile=90
+            min_delay_ms=10
             [hook_manager_params]
             entrylimit=1234
             weightlimit=4321
             disable_acl_checker=false
+            result_cache_ttl_secs=3600
+            state_cache_ttl_secs=60
+            strict_utf8=true
             [[remote_blobstore]]
             blobstore_id=0
             blobstore_type="manifold"
@@ -589,7 +1230,15 @@ mod test {
             path="./hooks/hook2.lua"
             hook_type="PerChangeset"
             bypass_pushvar="pushvar=pushval"
+            bypass_allowed_users=["releng", "svc-releng"]
             config_strings={ conf1 = "val1", conf2 = "val2" }
+            timeout_ms=500
+            max_concurrency=4
+            max_content_bytes=1048576
+            max_state_fetches=10
+            on_limit_exceeded="Warn"
+            path_prefixes=["configerator/"]
+            path_globs=["**/*.cconf"]
             [[hooks]]
             name="rust:rusthook"
             hook_type="PerChangeset"
@@ -597,10 +1246,99 @@ mod test {
             [pushrebase]
             rewritedates = false
             recursion_limit = 1024
+            [[pushrebase.commit_rewriters]]
+            type = "strip_local_markers"
+            markers = ["Local-Only:"]
+            [[pushrebase.commit_rewriters]]
+            type = "map_usernames"
+            map = { "jdoe" = "John Doe <jdoe@example.com>" }
+            [[pushrebase.commit_rewriters]]
+            type = "regex_replace"
+            field = "message"
+            regex = "^\\[WIP\\] "
+            replacement = ""
+            [[pushrebase.commit_rewriters]]
+            type = "landed_from_trailer"
             [lfs]
             threshold = 1000
+            [tree_prefetch]
+            size_budget = 1048576
+            [audit]
+            sink = { type = "scribe", category = "mononoke_audit" }
+            sample_percentage = 10
+            [response_cache]
+            use_blobstore = true
+            blobstore_ttl_secs = 86400
+            [fault_injection]
+            seed = 42
+            [[fault_injection.blobstore]]
+            key_regex = "^repo0000\\.content\\."
+            operations = ["get"]
+            error_percentage = 5
+            latency_ms = 100
+            [[fault_injection.sql]]
+            operations = ["changesets.add"]
+            fail_first = 1
+            mode = "lost_response"
+            [session_limits]
+            idle_timeout_secs = 600
+            keepalive_interval_secs = 30
+            max_sessions = 500
+            [resumable_pull]
+            ttl_secs = 300
+            [qos]
+            batch_identities = ["svc-ci"]
+            [qos.batch]
+            max_concurrency = 20
+            timeout_secs = 3600
+            [push_quota]
+            window_days = 7
+            soft_limit_bytes = 1073741824
+            [[push_quota.teams]]
+            name = "infra"
+            members = ["alice", "bob"]
+            soft_limit_bytes = 10737418240
+            hard_limit_bytes = 21474836480
+            [content_refs]
+            max_refs_per_content = 100
+            [manifest_sharding]
+            threshold_bytes = 4194304
+            [skiplist_refresh]
+            snapshot_interval_secs = 3600
+            [wireproto_caps.default]
+            disable = ["stream-preferred"]
+            [[wireproto_caps.overrides]]
+            identities = ["canary-client"]
+            enable = ["lfs"]
+            enable_bundle2 = ["changegroup=03"]
+            [copy_info_check]
+            fail_on_mismatch = true
+            [quarantine]
+            ttl_secs = 120
+            [pull_through]
+            upstream_url = "https://mononoke.example.com"
+            upstream_repo = "fbsource"
+            prefetch_parents_depth = 5
+            sync_interval_secs = 10
+            [[path_acls]]
+            path = "/secret/"
+            identities = ["alice", "svc-secret"]
+            [response_size_limits]
+            getbundle = 10737418240
+            gettreepack = 1073741824
             [bundle2_replay_params]
             preserve_raw_bundle2 = true
+            [[webhooks]]
+            url="https://ci.example.com/push"
+            bookmarks=["master"]
+            bookmark_regexes=["release/.*"]
+            secret="s3cr3t"
+            [[webhooks]]
+            url="https://chat.example.com/push"
+            [event_bus]
+            type="kafka"
+            brokers="kafka1:9092,kafka2:9092"
+            topic="mononoke-events"
         "#;
         let www_content = r#"
             path="/tmp/www"
@@ -650,6 +1388,12 @@ mod test {
         );
         let blobstores_args = RemoteBlobstoreArgs::Multiplexed {
             scuba_table: Some("blobstore_scuba_table".to_string()),
+            hedging: Some(HedgingParams {
+                delay_percentile: 90,
+                min_delay_ms: 10,
+            }),
+            write_quorum: 2,
+            scrub_action: Some(BlobstoreScrubAction::ReportOnly),
             blobstores,
         };
 
@@ -663,6 +1407,18 @@ mod test {
                     blobstores_args,
                     filenode_shards: None,
                     write_lock_db_address: "write_lock_db_address".into(),
+                    read_replicas: Some(ReadReplicaParams {
+                        standby_db_addresses: vec!["standby_db_address".into()],
+                        failure_threshold: 3,
+                        reresolve_interval_secs: 30,
+                    }),
+                    blobstore_cache_ttls: Some(BlobstoreCacheTtls {
+                        default_ttl_secs: Some(86400),
+                        ttl_secs_by_type: hashmap! {
+                            "hgmanifest".to_string() => 3600,
+                            "content".to_string() => 0,
+                        },
+                    }),
                 },
                 generation_cache_size: 1024 * 1024,
                 repoid: 0,
@@ -670,11 +1426,19 @@ mod test {
                 cache_warmup: Some(CacheWarmupParams {
                     bookmark: Bookmark::new("master").unwrap(),
                     commit_limit: 100,
+                    hot_bookmarks: vec![
+                        Bookmark::new("release").unwrap(),
+                        Bookmark::new("stable").unwrap(),
+                    ],
+                    filenodes_commit_limit: 50,
                 }),
                 hook_manager_params: Some(HookManagerParams {
                     entrylimit: 1234,
                     weightlimit: 4321,
                     disable_acl_checker: false,
+                    result_cache_ttl_secs: Some(3600),
+                    state_cache_ttl_secs: Some(60),
+                    strict_utf8: true,
                 }),
                 bookmarks: vec![
                     BookmarkParams {
@@ -699,8 +1463,11 @@ mod test {
                         hook_type: HookType::PerAddedOrModifiedFile,
                         config: HookConfig {
                             bypass: Some(HookBypass::CommitMessage("@allow_hook1".into())),
+                            bypass_allowed_users: vec![],
                             strings: hashmap! {},
                             ints: hashmap! {},
+                            limits: Default::default(),
+                            path_filters: vec![],
                         },
                     },
                     HookParams {
@@ -712,11 +1479,26 @@ mod test {
                                 name: "pushvar".into(),
                                 value: "pushval".into(),
                             }),
+                            bypass_allowed_users: vec![
+                                "releng".to_string(),
+                                "svc-releng".to_string(),
+                            ],
                             strings: hashmap! {
                                 "conf1".into() => "val1".into(),
                                 "conf2".into() => "val2".into(),
                             },
                             ints: hashmap! {},
+                            limits: HookLimits {
+                                timeout_ms: Some(500),
+                                max_concurrency: Some(4),
+                                max_content_bytes: Some(1048576),
+                                max_state_fetches: Some(10),
+                                on_exceeded: HookLimitAction::Warn,
+                            },
+                            path_filters: vec![
+                                HookPathFilter::prefix("configerator"),
+                                HookPathFilter::glob("**/*.cconf").unwrap(),
+                            ],
                         },
                     },
                     HookParams {
@@ -725,10 +1507,13 @@ mod test {
                         hook_type: HookType::PerChangeset,
                         config: HookConfig {
                             bypass: None,
+                            bypass_allowed_users: vec![],
                             strings: hashmap! {},
                             ints: hashmap! {
                                 "int1".into() => 44,
                             },
+                            limits: Default::default(),
+                            path_filters: vec![],
                         },
                     },
                 ],
@@ -737,6 +1522,25 @@ mod test {
                     recursion_limit: 1024,
                     commit_scribe_category: None,
                     block_merges: false,
+                    defer_hg_derivation: false,
+                    commit_rewriters: vec![
+                        CommitRewriter::StripLocalMarkers {
+                            markers: vec!["Local-Only:".into()],
+                        },
+                        CommitRewriter::MapUsernames {
+                            map: hashmap! {
+                                "jdoe".into() => "John Doe <jdoe@example.com>".into(),
+                            },
+                        },
+                        CommitRewriter::RegexReplace {
+                            field: CommitField::Message,
+                            regex: Regex::new("^\\[WIP\\] ").unwrap(),
+                            replacement: "".into(),
+                        },
+                        CommitRewriter::LandedFromTrailer {
+                            trailer: "Landed-From".into(),
+                        },
+                    ],
                 },
                 lfs: LfsParams {
                     threshold: Some(1000),
@@ -745,9 +1549,142 @@ mod test {
                 hash_validation_percentage: 0,
                 readonly: RepoReadOnly::ReadWrite,
                 skiplist_index_blobstore_key: Some("skiplist_key".into()),
+                skiplist_refresh: Some(SkiplistRefreshParams {
+                    bookmark_poll_interval_secs: 30,
+                    max_index_depth: 100_000,
+                    snapshot_interval_secs: Some(3600),
+                }),
                 bundle2_replay_params: Bundle2ReplayParams {
                     preserve_raw_bundle2: true,
                 },
+                webhooks: vec![
+                    WebhookParams {
+                        url: "https://ci.example.com/push".into(),
+                        bookmarks: vec![
+                            Bookmark::new("master").unwrap().into(),
+                            Regex::new("release/.*").unwrap().into(),
+                        ],
+                        secret: Some("s3cr3t".into()),
+                    },
+                    WebhookParams {
+                        url: "https://chat.example.com/push".into(),
+                        bookmarks: vec![],
+                        secret: None,
+                    },
+                ],
+                event_bus: Some(EventBusParams::Kafka {
+                    brokers: "kafka1:9092,kafka2:9092".into(),
+                    topic: "mononoke-events".into(),
+                }),
+                scratch_namespace: Some(ScratchNamespace::new(Regex::new("^scratch/.+$").unwrap())),
+                tree_prefetch: Some(TreePrefetchParams {
+                    children_limit: 10,
+                    size_budget: 1048576,
+                }),
+                audit: Some(AuditParams {
+                    sink: AuditSink::Scribe {
+                        category: "mononoke_audit".into(),
+                    },
+                    sample_percentage: 10,
+                }),
+                response_cache: Some(ResponseCacheParams {
+                    use_blobstore: true,
+                    blobstore_ttl_secs: 86400,
+                }),
+                fault_injection: Some(FaultInjectionParams {
+                    seed: Some(42),
+                    blobstore: vec![FaultRule {
+                        key_regex: Some(Regex::new("^repo0000\\.content\\.").unwrap()),
+                        operations: vec!["get".into()],
+                        error_percentage: 5,
+                        fail_first: 0,
+                        mode: FaultMode::Error,
+                        latency_ms: 100,
+                    }],
+                    sql: vec![FaultRule {
+                        key_regex: None,
+                        operations: vec!["changesets.add".into()],
+                        error_percentage: 0,
+                        fail_first: 1,
+                        mode: FaultMode::LostResponse,
+                        latency_ms: 0,
+                    }],
+                }),
+                session_limits: SessionLimits {
+                    idle_timeout_secs: Some(600),
+                    keepalive_interval_secs: Some(30),
+                    max_session_duration_secs: None,
+                    max_sessions: Some(500),
+                },
+                resumable_pull: Some(ResumablePullParams {
+                    ttl_secs: 300,
+                    max_resumes: 3,
+                }),
+                response_size_limits: hashmap! {
+                    "getbundle".to_string() => 10737418240,
+                    "gettreepack".to_string() => 1073741824,
+                },
+                qos: QosParams {
+                    batch_identities: vec!["svc-ci".to_string()],
+                    interactive: QosLimits::default(),
+                    batch: QosLimits {
+                        max_concurrency: Some(20),
+                        timeout_secs: Some(3600),
+                    },
+                },
+                push_quota: PushQuotaParams {
+                    window_days: 7,
+                    default_limits: PushQuotaLimits {
+                        soft_limit_bytes: Some(1073741824),
+                        hard_limit_bytes: None,
+                    },
+                    teams: vec![PushQuotaTeam {
+                        name: "infra".to_string(),
+                        members: vec!["alice".to_string(), "bob".to_string()],
+                        limits: PushQuotaLimits {
+                            soft_limit_bytes: Some(10737418240),
+                            hard_limit_bytes: Some(21474836480),
+                        },
+                    }],
+                },
+                content_refs: Some(ContentRefsParams {
+                    max_refs_per_content: 100,
+                    max_refs_per_push: 10000,
+                }),
+                manifest_sharding: Some(ManifestShardingParams {
+                    threshold_bytes: 4194304,
+                    shard_size_bytes: 262144,
+                }),
+                wireproto_caps: WireprotoCapsParams {
+                    default: WireprotoCapsChanges {
+                        disable: vec!["stream-preferred".to_string()],
+                        ..Default::default()
+                    },
+                    overrides: vec![WireprotoCapsOverride {
+                        identities: vec!["canary-client".to_string()],
+                        changes: WireprotoCapsChanges {
+                            enable: vec!["lfs".to_string()],
+                            enable_bundle2: vec!["changegroup=03".to_string()],
+                            ..Default::default()
+                        },
+                    }],
+                },
+                copy_info_check: Some(CopyInfoCheckParams {
+                    fail_on_mismatch: true,
+                }),
+                quarantine: Some(QuarantineParams { ttl_secs: 120 }),
+                path_acls: vec![PathAclParams {
+                    path: "secret".to_string(),
+                    identities: vec!["alice".to_string(), "svc-secret".to_string()],
+                }],
+                phases_admin_identities: vec!["svc-release".to_string()],
+                pull_through: Some(PullThroughParams {
+                    upstream_url: "https://mononoke.example.com".to_string(),
+                    upstream_repo: "fbsource".to_string(),
+                    negative_cache_ttl_secs: 60,
+                    prefetch_parents_depth: 5,
+                    sync_interval_secs: 10,
+                }),
             },
         );
         repos.insert(
@@ -768,7 +1705,28 @@ mod test {
                 hash_validation_percentage: 0,
                 readonly: RepoReadOnly::ReadWrite,
                 skiplist_index_blobstore_key: None,
+                skiplist_refresh: None,
                 bundle2_replay_params: Bundle2ReplayParams::default(),
+                webhooks: vec![],
+                event_bus: None,
+                scratch_namespace: None,
+                tree_prefetch: None,
+                audit: None,
+                response_cache: None,
+                fault_injection: None,
+                session_limits: Default::default(),
+                resumable_pull: None,
+                response_size_limits: HashMap::new(),
+                qos: Default::default(),
+                push_quota: Default::default(),
+                content_refs: None,
+                manifest_sharding: None,
+                wireproto_caps: Default::default(),
+                copy_info_check: None,
+                quarantine: None,
+                path_acls: vec![],
+                phases_admin_identities: vec![],
+                pull_through: None,
             },
         );
         assert_eq!(
@@ -850,5 +1808,54 @@ mod test {
 
         let res = RepoConfigs::read_configs(tmp_dir.path());
         assert!(res.is_err());
+
+        // Users allowed to bypass a hook without a bypass
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [[bookmarks]]
+            name="master"
+            [[bookmarks.hooks]]
+            hook_name="hook1"
+            [[hooks]]
+            name="hook1"
+            path="common/hooks/hook1.lua"
+            hook_type="PerAddedOrModifiedFile"
+            bypass_allowed_users=["releng"]
+        "#;
+
+        let paths = btreemap! {
+            "common/hooks/hook1.lua" => hook1_content,
+            "repos/fbsource/server.toml" => content,
+        };
+
+        let tmp_dir = TempDir::new("mononoke_test_config").unwrap();
+
+        for (path, content) in paths {
+            let file_path = Path::new(path);
+            let dir = file_path.parent().unwrap();
+            create_dir_all(tmp_dir.path().join(dir)).unwrap();
+            write(tmp_dir.path().join(file_path), content).unwrap();
+        }
+
+        let res = RepoConfigs::read_configs(tmp_dir.path());
+        assert!(res.is_err());
+
+        // Size limit for a command that isn't limited
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [response_size_limits]
+            gettreepacks = 1073741824
+        "#;
+
+        let tmp_dir = TempDir::new("mononoke_test_config").unwrap();
+        create_dir_all(tmp_dir.path().join("repos/fbsource")).unwrap();
+        write(tmp_dir.path().join("repos/fbsource/server.toml"), content).unwrap();
+
+        let res = RepoConfigs::read_configs(tmp_dir.path());
+        assert!(res.is_err());
     }
 }
@@ -51,6 +51,21 @@ pub struct MysqlBlobstoreArgs {
     pub shardmap: String,
     /// Number of shards in the Mysql shardmap
     pub shard_num: NonZeroUsize,
+    /// If set, reads from replicas that are slow to answer are also sent to the master
+    pub hedging: Option<HedgingParams>,
+    /// If set, the chunks of blobs too large to be stored whole are compressed with zstd at this
+    /// level
+    pub compression_level: Option<i32>,
+}
+
+/// Configuration for hedged blobstore reads: a read that takes longer than most reads do is also
+/// sent to another replica, and whichever answers first wins.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HedgingParams {
+    /// Percentile (1 to 99) of recent read latencies after which a read is hedged
+    pub delay_percentile: u8,
+    /// Reads are never hedged sooner than this many milliseconds after they were sent
+    pub min_delay_ms: u64,
 }
 
 /// Configuration of a single repository
@@ -87,10 +102,67 @@ pub struct RepoConfig {
     pub hook_manager_params: Option<HookManagerParams>,
     /// Skiplist blobstore key (used to make revset faster)
     pub skiplist_index_blobstore_key: Option<String>,
+    /// If set, servers keep the skiplist index up to date as commits land
+    pub skiplist_refresh: Option<SkiplistRefreshParams>,
     /// Params fro the bunle2 replay
     pub bundle2_replay_params: Bundle2ReplayParams,
+    /// Webhooks notified about bookmark moves
+    pub webhooks: Vec<WebhookParams>,
+    /// Where events about the repo (landed commits, moved bookmarks, ...) are published. If
+    /// None, events are not published
+    pub event_bus: Option<EventBusParams>,
+    /// Scratch (infinitepush) bookmarks of this repo. If None, all bookmarks are publishing
+    pub scratch_namespace: Option<ScratchNamespace>,
+    /// If set, gettreepack responses include the trees clients usually ask for next
+    pub tree_prefetch: Option<TreePrefetchParams>,
+    /// If set, reads from this repo are recorded in an audit log
+    pub audit: Option<AuditParams>,
+    /// If set, gettreepack and getfiles response fragments are cached
+    pub response_cache: Option<ResponseCacheParams>,
+    /// If set, the repo's storage fails and slows down on purpose. Only for test servers, which
+    /// are built with the `fault_injection` feature
+    pub fault_injection: Option<FaultInjectionParams>,
+    /// Limits on how long wireproto sessions of this repo may stay open
+    pub session_limits: SessionLimits,
+    /// If set, clients can resume pulls that were interrupted
+    pub resumable_pull: Option<ResumablePullParams>,
+    /// Maximum number of bytes in the response to a wireproto command, by command name, which
+    /// is one of `RESPONSE_SIZE_LIMITED_COMMANDS`. Responses to commands that aren't listed have
+    /// no limit
+    pub response_size_limits: HashMap<String, u64>,
+    /// Separate limits for interactive and batch traffic to this repo
+    pub qos: QosParams,
+    /// Quotas on the bytes that users and teams push to this repo
+    pub push_quota: PushQuotaParams,
+    /// If set, pushes record which files and commits reference each content
+    pub content_refs: Option<ContentRefsParams>,
+    /// If set, large directory manifests are stored split into shards
+    pub manifest_sharding: Option<ManifestShardingParams>,
+    /// Changes to the wireproto capabilities the repo advertises
+    pub wireproto_caps: WireprotoCapsParams,
+    /// If set, getfiles sends the copy information of files, checked against the filenodes table
+    pub copy_info_check: Option<CopyInfoCheckParams>,
+    /// If set, requests that crash servers or hit corrupt data are rejected for a while
+    pub quarantine: Option<QuarantineParams>,
+    /// Paths that only some identities may read. Everyone may read the other paths
+    pub path_acls: Vec<PathAclParams>,
+    /// Users (or service identities) who may make any commit public. Others may only make
+    /// public the commits that are ancestors of publishing bookmarks
+    pub phases_admin_identities: Vec<String>,
+    /// If set, blobs missing from the storage of this repo are fetched from an upstream server
+    pub pull_through: Option<PullThroughParams>,
 }
 
+/// The wireproto commands whose responses can be limited in size, the ones that send file and
+/// tree contents
+pub const RESPONSE_SIZE_LIMITED_COMMANDS: &[&str] = &[
+    "getbundle",
+    "gettreepack",
+    "getfiles",
+    "getpackv1",
+    "stream_out_shallow",
+];
+
 impl RepoConfig {
     /// Returns a db address that is referenced in this config or None if there is none
     pub fn get_db_address(&self) -> Option<&str> {
@@ -118,6 +190,11 @@ pub struct CacheWarmupParams {
     /// Max number to fetch during commit warmup. If not set in the config, then set to a default
     /// value.
     pub commit_limit: usize,
+    /// Other frequently used bookmarks. Only the root manifests of their tips are fetched.
+    pub hot_bookmarks: Vec<Bookmark>,
+    /// Number of the most recent commits of `bookmark` to fetch file filenodes for. Zero
+    /// disables this part of the warmup.
+    pub filenodes_commit_limit: usize,
 }
 
 /// Configuration for the hook manager
@@ -131,6 +208,19 @@ pub struct HookManagerParams {
 
     /// Wether to disable the acl checker or not (intended for testing purposes)
     pub disable_acl_checker: bool,
+
+    /// If set, outcomes of file hooks are stored in the database for this many seconds, and
+    /// reused when the same file is pushed again
+    pub result_cache_ttl_secs: Option<u64>,
+
+    /// If set, hooks may fetch external state over HTTP, and responses are kept for this many
+    /// seconds
+    pub state_cache_ttl_secs: Option<u64>,
+
+    /// Whether hooks fail on commit messages and authors that aren't UTF-8, rather than see them
+    /// with their invalid bytes replaced
+    #[serde(default)]
+    pub strict_utf8: bool,
 }
 
 impl Default for HookManagerParams {
@@ -139,6 +229,9 @@ impl Default for HookManagerParams {
             entrylimit: 1024 * 1024,
             weightlimit: 100 * 1024 * 1024, // 100Mb
             disable_acl_checker: false,
+            result_cache_ttl_secs: None,
+            state_cache_ttl_secs: None,
+            strict_utf8: false,
         }
     }
 }
@@ -187,6 +280,35 @@ impl From<Regex> for BookmarkOrRegex {
     }
 }
 
+/// Bookmarks matching this regex are scratch bookmarks. Unlike publishing bookmarks, they are
+/// not sent to clients that list or pull bookmarks, and are only served when asked for by name.
+#[derive(Debug, Clone)]
+pub struct ScratchNamespace(Regex);
+
+impl ScratchNamespace {
+    /// Create a namespace from a regex
+    pub fn new(regex: Regex) -> Self {
+        ScratchNamespace(regex)
+    }
+
+    /// Checks whether a given Bookmark is a scratch bookmark
+    pub fn matches_bookmark(&self, bookmark: &Bookmark) -> bool {
+        self.0.is_match(&bookmark.to_string())
+    }
+
+    /// The regex of the namespace
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl PartialEq for ScratchNamespace {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str().eq(other.as_str())
+    }
+}
+impl Eq for ScratchNamespace {}
+
 /// Configuration for a bookmark
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct BookmarkParams {
@@ -205,6 +327,8 @@ pub enum HookType {
     PerChangeset,
     /// A hook that runs on a file in a changeset
     PerAddedOrModifiedFile,
+    /// A hook that runs on the whole changeset once it was pushed, without blocking the push
+    PostCommit,
 }
 
 /// Hook bypass
@@ -226,10 +350,144 @@ pub enum HookBypass {
 pub struct HookConfig {
     /// An optional way to bypass a hook
     pub bypass: Option<HookBypass>,
+    /// The identities that may bypass the hook, anyone if empty
+    pub bypass_allowed_users: Vec<String>,
     /// Map of config to it's value. Values here are strings
     pub strings: HashMap<String, String>,
     /// Map of config to it's value. Values here are integers
     pub ints: HashMap<String, i32>,
+    /// Limits on the resources each run of the hook may use
+    pub limits: HookLimits,
+    /// Changed files the hook is interested in. If there are any, the hook only sees the changed
+    /// files that match one of them
+    pub path_filters: Vec<HookPathFilter>,
+}
+
+/// Changed files a hook is interested in
+#[derive(Debug, Clone)]
+pub enum HookPathFilter {
+    /// Files at or below a path, e.g. "configerator" or "configerator/source"
+    Prefix(String),
+    /// Files whose path matches a glob. `*` and `?` match within a path element, `**` matches
+    /// any number of path elements
+    Glob {
+        /// The glob as configured
+        pattern: String,
+        /// The regex the glob was translated to
+        regex: Regex,
+    },
+}
+
+impl HookPathFilter {
+    /// Filter on the files at or below `prefix`
+    pub fn prefix(prefix: &str) -> Self {
+        HookPathFilter::Prefix(prefix.trim_matches('/').to_string())
+    }
+
+    /// Filter on the files matching the glob `pattern`
+    pub fn glob(pattern: &str) -> Result<Self, regex::Error> {
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        regex.push_str("(.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Ok(HookPathFilter::Glob {
+            pattern: pattern.to_string(),
+            regex: Regex::new(&regex)?,
+        })
+    }
+
+    /// Whether the file at `path` matches the filter
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            HookPathFilter::Prefix(prefix) => is_path_prefix(prefix, path),
+            HookPathFilter::Glob { regex, .. } => regex.is_match(path),
+        }
+    }
+
+    /// Whether some file below the directory `dir` could match the filter. Directories below
+    /// which nothing can match are skipped when looking for changed files
+    pub fn may_match_below(&self, dir: &str) -> bool {
+        let literal = match self {
+            HookPathFilter::Prefix(prefix) => prefix.as_str(),
+            HookPathFilter::Glob { pattern, .. } => {
+                // The directories of the glob before its first wildcard
+                let wildcard = pattern.find(|c| c == '*' || c == '?');
+                let literal = &pattern[..wildcard.unwrap_or(pattern.len())];
+                match literal.rfind('/') {
+                    Some(slash) => &literal[..slash],
+                    None => "",
+                }
+            }
+        };
+        is_path_prefix(literal, dir) || is_path_prefix(dir, literal)
+    }
+}
+
+/// Whether `path` is `prefix` or below it
+fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    prefix.is_empty()
+        || (path.starts_with(prefix)
+            && (path.len() == prefix.len() || path.as_bytes()[prefix.len()] == b'/'))
+}
+
+impl PartialEq for HookPathFilter {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (HookPathFilter::Prefix(p1), HookPathFilter::Prefix(p2)) => p1 == p2,
+            (
+                HookPathFilter::Glob { pattern: p1, .. },
+                HookPathFilter::Glob { pattern: p2, .. },
+            ) => p1 == p2,
+            _ => false,
+        }
+    }
+}
+impl Eq for HookPathFilter {}
+
+/// Limits on the resources a run of a hook may use. No limit is enforced if None
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct HookLimits {
+    /// Maximum time a run of the hook may take, in milliseconds
+    pub timeout_ms: Option<u64>,
+    /// Maximum number of runs of the hook at the same time, other runs wait for one to finish
+    pub max_concurrency: Option<usize>,
+    /// Maximum number of bytes of file content a run of the hook may fetch
+    pub max_content_bytes: Option<u64>,
+    /// Maximum number of fetches of external state a run of the hook may make. Fetches over
+    /// the budget fail
+    pub max_state_fetches: Option<u64>,
+    /// What happens to a run that goes over a limit
+    pub on_exceeded: HookLimitAction,
+}
+
+/// What happens to a run of a hook that goes over one of its limits
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+pub enum HookLimitAction {
+    /// The hook rejects the changeset
+    Reject,
+    /// The hook accepts the changeset, and a warning is logged
+    Warn,
+}
+
+impl Default for HookLimitAction {
+    fn default() -> Self {
+        HookLimitAction::Reject
+    }
 }
 
 /// Configuration for a hook
@@ -256,6 +514,11 @@ pub struct PushrebaseParams {
     pub commit_scribe_category: Option<String>,
     /// Block merge commits
     pub block_merges: bool,
+    /// Acknowledge the push once the rebased bonsai changesets are saved and the bookmark is
+    /// moved, and derive hg changesets and filenodes for them in the background
+    pub defer_hg_derivation: bool,
+    /// Rewriters applied in order to the messages and authors of rebased commits
+    pub commit_rewriters: Vec<CommitRewriter>,
 }
 
 impl Default for PushrebaseParams {
@@ -265,10 +528,250 @@ impl Default for PushrebaseParams {
             recursion_limit: 16384, // this number is fairly arbirary
             commit_scribe_category: None,
             block_merges: false,
+            defer_hg_derivation: false,
+            commit_rewriters: vec![],
         }
     }
 }
 
+/// Field of a commit that a rewriter can change
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitField {
+    /// The commit message
+    Message,
+    /// The author, and the committer if there is one
+    Author,
+}
+
+/// A step of the pipeline that rewrites commits landed by pushrebase
+#[derive(Debug, Clone)]
+pub enum CommitRewriter {
+    /// Remove the message lines that start with one of the markers, e.g. lines that local
+    /// extensions add to commits that were never meant to be published
+    StripLocalMarkers {
+        /// Prefixes of the lines to remove
+        markers: Vec<String>,
+    },
+    /// Replace authors (and committers) by the names they are mapped to. Others are unchanged
+    MapUsernames {
+        /// Map from the name used by the client to the name to land with
+        map: HashMap<String, String>,
+    },
+    /// Append a trailer with the id of the commit that was pushed
+    LandedFromTrailer {
+        /// Name of the trailer, e.g. "Landed-From"
+        trailer: String,
+    },
+    /// Replace all matches of a regex in a field. The replacement can refer to capture groups
+    RegexReplace {
+        /// Field to rewrite
+        field: CommitField,
+        /// Regex to look for
+        regex: Regex,
+        /// What matches are replaced with
+        replacement: String,
+    },
+}
+
+impl PartialEq for CommitRewriter {
+    fn eq(&self, other: &Self) -> bool {
+        use CommitRewriter::*;
+
+        match (self, other) {
+            (StripLocalMarkers { markers: m1 }, StripLocalMarkers { markers: m2 }) => m1 == m2,
+            (MapUsernames { map: m1 }, MapUsernames { map: m2 }) => m1 == m2,
+            (LandedFromTrailer { trailer: t1 }, LandedFromTrailer { trailer: t2 }) => t1 == t2,
+            (
+                RegexReplace {
+                    field: f1,
+                    regex: r1,
+                    replacement: rep1,
+                },
+                RegexReplace {
+                    field: f2,
+                    regex: r2,
+                    replacement: rep2,
+                },
+            ) => f1 == f2 && r1.as_str() == r2.as_str() && rep1 == rep2,
+            _ => false,
+        }
+    }
+}
+impl Eq for CommitRewriter {}
+
+/// Configuration for a webhook that is sent push events
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WebhookParams {
+    /// URL the events are POSTed to
+    pub url: String,
+    /// Only moves of matching bookmarks are sent. If empty, moves of all bookmarks are sent
+    pub bookmarks: Vec<BookmarkOrRegex>,
+    /// If set, events are signed with HMAC-SHA256 using this secret
+    pub secret: Option<String>,
+}
+
+impl WebhookParams {
+    /// Checks whether moves of a given Bookmark should be sent to this webhook
+    pub fn matches(&self, bookmark: &Bookmark) -> bool {
+        self.bookmarks.is_empty() || self.bookmarks.iter().any(|bm| bm.matches(bookmark))
+    }
+}
+
+/// Where repo events are published
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EventBusParams {
+    /// Log events to a scribe category
+    Scribe {
+        /// Scribe category
+        category: String,
+    },
+    /// Produce events to a Kafka topic
+    Kafka {
+        /// Comma-separated list of bootstrap brokers
+        brokers: String,
+        /// Kafka topic
+        topic: String,
+    },
+    /// Write events to stdout, one per line
+    Stdout,
+}
+
+/// Prefetching of trees in gettreepack responses
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TreePrefetchParams {
+    /// How many of the most requested child directories of the requested directory are sent
+    pub children_limit: usize,
+    /// Max total size in bytes of the prefetched trees in one response
+    pub size_budget: usize,
+}
+
+/// Caching of the trees and files sent in gettreepack and getfiles responses
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ResponseCacheParams {
+    /// Whether cached fragments are also stored in the repo's blobstore, on top of cachelib
+    pub use_blobstore: bool,
+    /// How long fragments stay in the blobstore, after which they are filled again
+    pub blobstore_ttl_secs: u64,
+}
+
+/// Checks of the copy information that getfiles sends. The copy-from metadata of every file is
+/// sent in its content, after checking that the filenodes table and the file's envelope agree on
+/// it. Mismatches are logged to scuba
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CopyInfoCheckParams {
+    /// Whether a mismatch fails the request. If false, the copy information of the envelope is
+    /// sent, as it's the one that the hash of the file covers
+    pub fail_on_mismatch: bool,
+}
+
+/// Quarantine of poison-pill requests. Requests that crash the server or fail because of
+/// corrupt data are rejected by all servers for a while, instead of being served again
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct QuarantineParams {
+    /// Seconds during which a quarantined request is rejected
+    pub ttl_secs: u64,
+}
+
+/// Upstream of a repo whose missing blobs are fetched from a central server, and kept
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PullThroughParams {
+    /// Url of the apiserver of the central server, e.g. "https://mononoke.example.com"
+    pub upstream_url: String,
+    /// Name of the repo on the central server
+    pub upstream_repo: String,
+    /// Seconds during which a blob that upstream doesn't have isn't asked for again
+    pub negative_cache_ttl_secs: u64,
+    /// Generations of ancestors fetched in the background when a changeset is fetched
+    pub prefetch_parents_depth: usize,
+    /// Seconds between copies of the bookmarks of upstream, with the changesets they need
+    pub sync_interval_secs: u64,
+}
+
+/// A path of a repo, and everything under it, that only some identities may read
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PathAclParams {
+    /// The restricted file or directory, e.g. "secret" or "tools/secret.py"
+    pub path: String,
+    /// Users (or service identities) who may read it
+    pub identities: Vec<String>,
+}
+
+/// Auditing of reads from a repo
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AuditParams {
+    /// Where audit records are written
+    pub sink: AuditSink,
+    /// Percent of reads that are recorded
+    pub sample_percentage: usize,
+}
+
+/// Faults injected into the storage of a repo, to test how errors are handled
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FaultInjectionParams {
+    /// Seed of the random choice of failing operations, for reproducible runs. If None, a
+    /// random seed is used
+    pub seed: Option<u64>,
+    /// Faults of blobstore operations, whose keys are blobstore keys
+    pub blobstore: Vec<FaultRule>,
+    /// Faults of operations on the SQL stores, whose keys are changeset ids or paths
+    pub sql: Vec<FaultRule>,
+}
+
+/// How some operations fail. Operations use the first rule that matches them
+#[derive(Debug, Clone)]
+pub struct FaultRule {
+    /// Only operations on keys matching this regex are affected. If None, all keys are
+    pub key_regex: Option<Regex>,
+    /// Names of the affected operations, e.g. "get" or "put". If empty, all operations are
+    pub operations: Vec<String>,
+    /// Percent of the affected operations that fail
+    pub error_percentage: usize,
+    /// The first this many affected operations on every key fail, whatever the percentage.
+    /// Models transient errors that go away when retried
+    pub fail_first: usize,
+    /// Whether failing operations are done before the error is returned
+    pub mode: FaultMode,
+    /// Affected operations, failing or not, are delayed by this many milliseconds
+    pub latency_ms: u64,
+}
+
+impl PartialEq for FaultRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.key_regex.as_ref().map(Regex::as_str) == other.key_regex.as_ref().map(Regex::as_str)
+            && self.operations == other.operations
+            && self.error_percentage == other.error_percentage
+            && self.fail_first == other.fail_first
+            && self.mode == other.mode
+            && self.latency_ms == other.latency_ms
+    }
+}
+impl Eq for FaultRule {}
+
+/// What a failing operation does before it fails
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultMode {
+    /// Nothing, the operation fails without reaching the store
+    Error,
+    /// The operation is done, but its result is lost and an error is returned instead, like a
+    /// write whose acknowledgement timed out
+    LostResponse,
+}
+
+/// Where audit records are written. Audit records never go to the perf logging tables, as
+/// they may be retained and accessed under different rules.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AuditSink {
+    /// The audit_log table of the repo's database
+    Sql,
+    /// Log records to a scribe category
+    Scribe {
+        /// Scribe category
+        category: String,
+    },
+}
+
 /// LFS configuration options
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct LfsParams {
@@ -282,6 +785,171 @@ impl Default for LfsParams {
     }
 }
 
+/// Limits on the lifetime of wireproto sessions, so that clients that went away don't keep
+/// holding server resources. Sessions over a limit are closed by the server
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct SessionLimits {
+    /// Seconds a session may go without a request or a response. If None, idle sessions stay open
+    pub idle_timeout_secs: Option<u64>,
+    /// Seconds between keepalives sent to clients of idle sessions. If None, none are sent
+    pub keepalive_interval_secs: Option<u64>,
+    /// Seconds a session may stay open in total, even if it is busy. If None, there is no limit
+    pub max_session_duration_secs: Option<u64>,
+    /// Sessions of the repo a server serves at the same time, so that clients of one repo can't
+    /// take all the connections of a server shared with other repos. If None, there is no limit
+    pub max_sessions: Option<usize>,
+}
+
+/// Classes of traffic, served from separate pools so that batch traffic (e.g. CI fetching many
+/// repos at once) doesn't slow down developers waiting on their own requests
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum QosClass {
+    /// A person is waiting for the response
+    Interactive,
+    /// Automation that can wait
+    Batch,
+}
+
+/// Limits on the requests of one class of traffic
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct QosLimits {
+    /// Requests of the class that may be served at the same time, others wait for their turn.
+    /// If None, there is no limit
+    pub max_concurrency: Option<usize>,
+    /// Seconds a request of the class may take, including the time it waits for its turn. If
+    /// None, the default of the request is used
+    pub timeout_secs: Option<u64>,
+}
+
+/// How traffic to a repo is classified, and the limits of each class. Clients may declare the
+/// class of their requests; requests that don't are batch if they come from one of
+/// `batch_identities`, and interactive otherwise
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct QosParams {
+    /// Users (or service identities) whose requests are batch unless they declare otherwise
+    pub batch_identities: Vec<String>,
+    /// Limits on interactive requests
+    pub interactive: QosLimits,
+    /// Limits on batch requests
+    pub batch: QosLimits,
+}
+
+/// Quotas on the bytes pushed to a repo, counted over the last `window_days` days. A push that
+/// takes its pusher over the soft limit is accepted with a warning, one that would take it over
+/// the hard limit is rejected
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PushQuotaParams {
+    /// Days over which pushed bytes count against the quota
+    pub window_days: u64,
+    /// Limits of each user that isn't a member of a team
+    pub default_limits: PushQuotaLimits,
+    /// Teams, whose members share one quota
+    pub teams: Vec<PushQuotaTeam>,
+}
+
+impl Default for PushQuotaParams {
+    fn default() -> Self {
+        Self {
+            window_days: 30,
+            default_limits: PushQuotaLimits::default(),
+            teams: vec![],
+        }
+    }
+}
+
+/// Limits on the bytes pushed over the quota window. If None, there is no limit
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct PushQuotaLimits {
+    /// Pushes over this are accepted with a warning
+    pub soft_limit_bytes: Option<u64>,
+    /// Pushes over this are rejected
+    pub hard_limit_bytes: Option<u64>,
+}
+
+/// Users who share one push quota
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PushQuotaTeam {
+    pub name: String,
+    pub members: Vec<String>,
+    pub limits: PushQuotaLimits,
+}
+
+/// Index from file contents to the files and commits that reference them, filled in by pushes.
+/// Every pushed file adds a row, so how many are recorded is bounded
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ContentRefsParams {
+    /// References to a content past this many aren't recorded, so that common contents, like
+    /// the empty file, don't fill the index
+    pub max_refs_per_content: u64,
+    /// References past this many in one push aren't recorded
+    pub max_refs_per_push: u64,
+}
+
+/// Storage of the manifests of large directories as several blobs, each holding a range of the
+/// entries of the directory. It doesn't change the manifests that clients see
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ManifestShardingParams {
+    /// Manifests whose contents are larger than this many bytes are sharded
+    pub threshold_bytes: u64,
+    /// Shards are cut once they hold at least this many bytes of entries
+    pub shard_size_bytes: u64,
+}
+
+/// Changes to the capabilities the server advertises to the clients of a repo, so that new
+/// capabilities can be rolled out repo by repo, and to canary clients before the others
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct WireprotoCapsParams {
+    /// Changes for every client of the repo
+    pub default: WireprotoCapsChanges,
+    /// Changes for some clients, applied in order on top of the default ones
+    pub overrides: Vec<WireprotoCapsOverride>,
+}
+
+/// Capabilities to advertise on top of, or to remove from, the ones the server advertises
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct WireprotoCapsChanges {
+    /// Wireproto capabilities as they are advertised, e.g. `lfs` or `unbundle=HG10UN`
+    pub enable: Vec<String>,
+    /// Names of wireproto capabilities not to advertise
+    pub disable: Vec<String>,
+    /// Bundle2 capabilities with their values, e.g. `changegroup=03`. The values are added to
+    /// the ones the capability already has
+    pub enable_bundle2: Vec<String>,
+    /// Names of bundle2 capabilities not to advertise
+    pub disable_bundle2: Vec<String>,
+}
+
+/// Changes to the capabilities advertised to some users (or service identities)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WireprotoCapsOverride {
+    pub identities: Vec<String>,
+    pub changes: WireprotoCapsChanges,
+}
+
+/// How a server keeps the skiplist index it loaded at startup up to date. The commits that
+/// bookmarks move to are indexed as they're seen, and the index may be stored back, so that
+/// servers that start later load a recent one
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SkiplistRefreshParams {
+    /// Seconds between two checks for bookmarks that moved
+    pub bookmark_poll_interval_secs: u64,
+    /// How far from a bookmark commits are indexed. Indexing stops earlier at indexed commits
+    pub max_index_depth: u64,
+    /// Seconds between two stores of the index under `skiplist_index_blobstore_key`. If None,
+    /// the index is never stored
+    pub snapshot_interval_secs: Option<u64>,
+}
+
+/// Resumption of interrupted pulls. The commits a pull sends are remembered for a while, so
+/// that a client can ask for the ones it didn't get yet
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ResumablePullParams {
+    /// Seconds during which an interrupted pull can be resumed
+    pub ttl_secs: u64,
+    /// How many times a pull can be resumed
+    pub max_resumes: usize,
+}
+
 /// Remote blobstore arguments
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum RemoteBlobstoreArgs {
@@ -295,11 +963,29 @@ pub enum RemoteBlobstoreArgs {
     Multiplexed {
         /// Scuba table for tracking performance of blobstore operations
         scuba_table: Option<String>,
+        /// If set, reads from one blobstore that are slow to answer are also sent to the others
+        hedging: Option<HedgingParams>,
+        /// How many blobstores must store a blob for a put to succeed. The others are healed
+        /// from the sync queue if they fail or fall behind
+        write_quorum: usize,
+        /// If set, reads compare the blobs of all blobstores, to find the ones that lost or
+        /// corrupted a blob
+        scrub_action: Option<BlobstoreScrubAction>,
         /// Multiplexed blobstores
         blobstores: HashMap<BlobstoreId, RemoteBlobstoreArgs>,
     },
 }
 
+/// What reads from a multiplexed blobstore do with the blobstores whose blob differs from the
+/// one that most blobstores have
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+pub enum BlobstoreScrubAction {
+    /// They are logged
+    ReportOnly,
+    /// They are logged, and their blob is overwritten with the one that most blobstores have
+    Repair,
+}
+
 impl From<ManifoldArgs> for RemoteBlobstoreArgs {
     fn from(manifold_args: ManifoldArgs) -> Self {
         RemoteBlobstoreArgs::Manifold(manifold_args)
@@ -375,9 +1061,37 @@ pub enum RepoType {
         filenode_shards: Option<usize>,
         /// Address of the SQL database used to lock writes to a repo.
         write_lock_db_address: String,
+        /// If present, standby replicas of the SQL database that reads fail over to
+        read_replicas: Option<ReadReplicaParams>,
+        /// If present, how long blobs are cached, by key type. Otherwise blobs are cached until
+        /// evicted.
+        blobstore_cache_ttls: Option<BlobstoreCacheTtls>,
     },
 }
 
+/// Warm standby replicas of the SQL database of a remote repo. Reads go to the first healthy
+/// replica, starting with `db_address`; writes always go to `db_address`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReadReplicaParams {
+    /// Addresses of the standby replicas, in the order reads fail over to them
+    pub standby_db_addresses: Vec<String>,
+    /// How many reads in a row have to fail before a replica is considered unhealthy
+    pub failure_threshold: usize,
+    /// How often connections to the replicas are rebuilt. Unhealthy replicas are tried again
+    /// after that
+    pub reresolve_interval_secs: u64,
+}
+
+/// How long the blobs of a remote repo are cached in cachelib and memcache, by the type of their
+/// key, e.g. `hgmanifest` or `content`
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct BlobstoreCacheTtls {
+    /// TTL of the types that aren't in `ttl_secs_by_type`. If absent, they aren't cached.
+    pub default_ttl_secs: Option<u64>,
+    /// TTLs of key types. A TTL of 0 means the type isn't cached.
+    pub ttl_secs_by_type: HashMap<String, u64>,
+}
+
 /// Params fro the bunle2 replay
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
 pub struct Bundle2ReplayParams {
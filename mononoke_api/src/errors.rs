@@ -12,4 +12,6 @@ pub enum ErrorKind {
     NotFound(String),
     #[fail(display = "{} is invalid", _0)]
     InvalidInput(String),
+    #[fail(display = "too many levels of symbolic links at {}", _0)]
+    SymlinkLoop(String),
 }
@@ -0,0 +1,223 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Whether many paths exist at a changeset, for build tools that check thousands of paths at a
+// time.
+//
+// Looking each path up on its own fetches the manifests of their common directories over and
+// over. Instead the paths are grouped by directory as they are walked down, so that every
+// manifest on the way is fetched once, whatever the number of paths under it.
+
+use std::collections::BTreeMap;
+
+use failure::Error;
+use futures::{stream, Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use context::CoreContext;
+use mercurial_types::{Changeset, Entry, HgChangesetId, HgManifestId, Type};
+use mononoke_types::{MPath, MPathElement};
+
+/// How many subdirectories of a directory are walked at once
+const WALK_CONCURRENCY: usize = 100;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PathEntry {
+    pub ttype: Type,
+    /// Size of the file, None for directories
+    pub size: Option<u64>,
+}
+
+impl PathEntry {
+    fn tree() -> Self {
+        Self {
+            ttype: Type::Tree,
+            size: None,
+        }
+    }
+}
+
+// A path still to be looked up, as the index of the path in the request and the elements of the
+// path below the directory being walked
+type PendingPath = (usize, Vec<MPathElement>);
+
+/// The entries of `paths` (the root of the repo if None) at a changeset, in the same order. The
+/// entry of a path that doesn't exist is None.
+pub fn get_path_entries(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    changesetid: HgChangesetId,
+    paths: Vec<Option<MPath>>,
+) -> BoxFuture<Vec<Option<PathEntry>>, Error> {
+    let count = paths.len();
+    let mut root = Vec::new();
+    let mut pending = Vec::new();
+    for (index, path) in paths.into_iter().enumerate() {
+        match path {
+            Some(path) => pending.push((index, path.into_iter().collect())),
+            None => root.push(index),
+        }
+    }
+
+    repo.get_changeset_by_changesetid(ctx.clone(), changesetid)
+        .and_then(move |changeset| {
+            if pending.is_empty() {
+                return Ok(vec![]).into_future().left_future();
+            }
+            get_entries_in_directory(ctx, repo, changeset.manifestid(), pending).right_future()
+        })
+        .map(move |found| {
+            let mut entries = vec![None; count];
+            for index in root {
+                entries[index] = Some(PathEntry::tree());
+            }
+            for (index, entry) in found {
+                entries[index] = entry;
+            }
+            entries
+        })
+        .boxify()
+}
+
+fn get_entries_in_directory(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    manifestid: HgManifestId,
+    pending: Vec<PendingPath>,
+) -> BoxFuture<Vec<(usize, Option<PathEntry>)>, Error> {
+    // Paths are grouped by their first element, into the ones that end there and the ones that
+    // go further down
+    let mut groups: BTreeMap<MPathElement, (Vec<usize>, Vec<PendingPath>)> = BTreeMap::new();
+    for (index, elements) in pending {
+        let mut elements = elements.into_iter();
+        let first = match elements.next() {
+            Some(first) => first,
+            None => continue,
+        };
+        let rest: Vec<_> = elements.collect();
+        let group = groups.entry(first).or_insert_with(|| (vec![], vec![]));
+        if rest.is_empty() {
+            group.0.push(index);
+        } else {
+            group.1.push((index, rest));
+        }
+    }
+
+    repo.get_manifest_by_nodeid(ctx.clone(), manifestid)
+        .and_then(move |manifest| {
+            let lookups = groups.into_iter().map(move |(element, (ending, below))| {
+                let entry = match manifest.lookup(&element) {
+                    Some(entry) => entry,
+                    None => {
+                        let missing = ending.into_iter().chain(below.into_iter().map(|p| p.0));
+                        let missing: Vec<_> = missing.map(|index| (index, None)).collect();
+                        return Ok(missing).into_future().boxify();
+                    }
+                };
+
+                let ttype = entry.get_type();
+                let here = if ending.is_empty() {
+                    Ok(vec![]).into_future().boxify()
+                } else {
+                    let size = match ttype {
+                        Type::Tree => Ok(None).into_future().boxify(),
+                        Type::File(_) => entry
+                            .get_size(ctx.clone())
+                            .map(|size| size.map(|size| size as u64))
+                            .boxify(),
+                    };
+                    size.map(move |size| {
+                        let entry = PathEntry { ttype, size };
+                        ending
+                            .into_iter()
+                            .map(|index| (index, Some(entry.clone())))
+                            .collect()
+                    })
+                    .boxify()
+                };
+
+                // Nothing is below a file
+                let further = match ttype {
+                    Type::Tree if !below.is_empty() => {
+                        let manifestid = HgManifestId::new(entry.get_hash().into_nodehash());
+                        get_entries_in_directory(ctx.clone(), repo.clone(), manifestid, below)
+                    }
+                    _ => {
+                        let missing: Vec<_> = below.into_iter().map(|p| (p.0, None)).collect();
+                        Ok(missing).into_future().boxify()
+                    }
+                };
+
+                here.join(further)
+                    .map(|(mut here, further)| {
+                        here.extend(further);
+                        here
+                    })
+                    .boxify()
+            });
+
+            stream::iter_ok(lookups)
+                .buffer_unordered(WALK_CONCURRENCY)
+                .concat2()
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use fixtures::many_files_dirs;
+    use mononoke_types::FileType;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn path_entries() {
+        let mut rt = Runtime::new().unwrap();
+        let repo = many_files_dirs::getrepo(None);
+        let ctx = CoreContext::test_mock();
+        // The last commit before dir1 was replaced with a file
+        let changesetid =
+            HgChangesetId::from_str("d261bc7900818dea7c86935b3fb17a33b2e3a6b4").unwrap();
+
+        let paths = vec![
+            Some("dir1/subdir1/file_1"),
+            None,
+            Some("nonexistent"),
+            Some("dir1"),
+            Some("dir1/file_1_in_dir1/below_a_file"),
+            Some("dir1/subdir1/file_1"),
+            Some("dir1/nonexistent/file"),
+        ];
+        let paths = paths
+            .into_iter()
+            .map(|path| path.map(|path| MPath::new(path).unwrap()))
+            .collect();
+        let entries = rt
+            .block_on(get_path_entries(ctx, repo, changesetid, paths))
+            .unwrap();
+
+        let file = PathEntry {
+            ttype: Type::File(FileType::Regular),
+            size: Some(9),
+        };
+        assert_eq!(
+            entries,
+            vec![
+                Some(file.clone()),
+                Some(PathEntry::tree()),
+                None,
+                Some(PathEntry::tree()),
+                None,
+                Some(file),
+                None,
+            ]
+        );
+    }
+}
@@ -7,6 +7,11 @@
 #![deny(warnings)]
 
 pub mod errors;
+pub mod exists;
+pub mod merge_conflicts;
+pub mod sizes;
+pub mod submodules;
+pub mod symlinks;
 
 use failure::Error;
 use futures::Future;
@@ -0,0 +1,382 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Which files would conflict if two changesets were merged, for merge queues that want to know
+// before they try.
+//
+// Both changesets are compared with their merge base, the closest common ancestor, and a file
+// conflicts if it was changed differently on the two sides. Nothing is written to the repo.
+// Conflicts are reported per file: whether the changes of a file could be merged line by line
+// is up to the client.
+
+use std::collections::BTreeMap;
+
+use failure::Error;
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use bonsai_utils::{bonsai_diff, BonsaiDiffResult};
+use cloned::cloned;
+use context::CoreContext;
+use mercurial_types::{Changeset, Entry, HgChangesetId, HgEntryId, HgFileNodeId, Type};
+use mononoke_types::{ChangesetId, ContentId, FileType, MPath};
+use revset::greatest_common_ancestor;
+
+use crate::exists::get_path_entries;
+
+/// How many content ids of files changed on both sides are fetched at once
+const CONTENT_ID_CONCURRENCY: usize = 100;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConflictKind {
+    /// The file was in the merge base and both sides changed it differently
+    BothModified,
+    /// The file wasn't in the merge base and both sides added it with a different content or type
+    AddAdd,
+    /// One side deleted the file and the other one changed it
+    DeleteModify,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileConflict {
+    pub path: MPath,
+    pub kind: ConflictKind,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MergeConflicts {
+    /// None if the changesets have no common ancestor
+    pub merge_base: Option<ChangesetId>,
+    /// Ordered by path
+    pub conflicts: Vec<FileConflict>,
+}
+
+/// How one side of the merge changed a file since the merge base
+#[derive(Clone, Copy)]
+enum Change {
+    Changed(FileType, HgEntryId),
+    Deleted,
+}
+
+/// The merge base of `left` and `right` and the files that conflict between them.
+pub fn find_merge_conflicts(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    left: ChangesetId,
+    right: ChangesetId,
+) -> BoxFuture<MergeConflicts, Error> {
+    greatest_common_ancestor(
+        ctx.clone(),
+        repo.get_changeset_fetcher_for("revsets"),
+        vec![left, right],
+    )
+    .into_future()
+    .map(|(merge_base, _)| merge_base)
+    .map_err(|(err, _)| err)
+    .and_then({
+        cloned!(ctx, repo);
+        move |merge_base| {
+            let base_hg = match merge_base {
+                Some(merge_base) => repo
+                    .get_hg_from_bonsai_changeset(ctx.clone(), merge_base)
+                    .map(Some)
+                    .left_future(),
+                None => Ok(None).into_future().right_future(),
+            };
+            base_hg.map(move |base_hg| (merge_base, base_hg))
+        }
+    })
+    .and_then(move |(merge_base, base_hg)| {
+        let changes_since_base = {
+            cloned!(ctx, repo);
+            move |cs_id| get_changes_since(ctx.clone(), repo.clone(), base_hg, cs_id)
+        };
+        changes_since_base(left)
+            .join(changes_since_base(right))
+            .and_then(move |(left, right)| classify_conflicts(ctx, repo, base_hg, left, right))
+            .map(move |conflicts| MergeConflicts {
+                merge_base,
+                conflicts,
+            })
+    })
+    .boxify()
+}
+
+fn get_root_entry(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    changesetid: HgChangesetId,
+) -> impl Future<Item = Box<Entry + Sync>, Error = Error> {
+    repo.get_changeset_by_changesetid(ctx, changesetid)
+        .map(move |cs| Box::new(repo.get_root_entry(cs.manifestid())) as Box<_>)
+}
+
+/// Files changed between `base`, the empty repo if None, and `cs_id`
+fn get_changes_since(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    base: Option<HgChangesetId>,
+    cs_id: ChangesetId,
+) -> BoxFuture<BTreeMap<MPath, Change>, Error> {
+    let base_entry = match base {
+        Some(base) => get_root_entry(ctx.clone(), repo.clone(), base)
+            .map(Some)
+            .left_future(),
+        None => Ok(None).into_future().right_future(),
+    };
+    let entry = repo
+        .get_hg_from_bonsai_changeset(ctx.clone(), cs_id)
+        .and_then({
+            cloned!(ctx, repo);
+            move |hg_cs_id| get_root_entry(ctx, repo, hg_cs_id)
+        });
+
+    entry
+        .join(base_entry)
+        .map(move |(entry, base_entry)| bonsai_diff(ctx, entry, base_entry, None))
+        .flatten_stream()
+        .fold(BTreeMap::new(), |mut changes, diff| {
+            match diff {
+                BonsaiDiffResult::Changed(path, ft, entry_id)
+                | BonsaiDiffResult::ChangedReusedId(path, ft, entry_id) => {
+                    changes.insert(path, Change::Changed(ft, entry_id));
+                }
+                BonsaiDiffResult::Deleted(path) => {
+                    changes.insert(path, Change::Deleted);
+                }
+            }
+            Ok::<_, Error>(changes)
+        })
+        .boxify()
+}
+
+fn classify_conflicts(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    base: Option<HgChangesetId>,
+    left: BTreeMap<MPath, Change>,
+    right: BTreeMap<MPath, Change>,
+) -> BoxFuture<Vec<FileConflict>, Error> {
+    let mut conflicts = BTreeMap::new();
+    let mut changed_on_both = vec![];
+    for (path, left_change) in left {
+        let right_change = match right.get(&path) {
+            Some(right_change) => *right_change,
+            None => continue,
+        };
+        match (left_change, right_change) {
+            (Change::Deleted, Change::Deleted) => {}
+            (Change::Deleted, Change::Changed(..)) | (Change::Changed(..), Change::Deleted) => {
+                conflicts.insert(path, ConflictKind::DeleteModify);
+            }
+            (Change::Changed(left_ft, left_id), Change::Changed(right_ft, right_id)) => {
+                if left_ft != right_ft || left_id != right_id {
+                    changed_on_both.push((path, left_ft, left_id, right_ft, right_id));
+                }
+            }
+        }
+    }
+
+    if changed_on_both.is_empty() {
+        return future::ok(conflicts_by_path(conflicts)).boxify();
+    }
+
+    // Different file nodes may still have the same content, e.g. if both sides made the same
+    // change, so it's the contents that are compared
+    let content_id = {
+        cloned!(ctx, repo);
+        move |entry_id: HgEntryId| -> BoxFuture<ContentId, Error> {
+            repo.get_file_content_id(ctx.clone(), HgFileNodeId::new(entry_id.into_nodehash()))
+                .boxify()
+        }
+    };
+    let differing = stream::iter_ok(changed_on_both)
+        .map(move |(path, left_ft, left_id, right_ft, right_id)| {
+            content_id(left_id)
+                .join(content_id(right_id))
+                .map(move |(left, right)| (path, left_ft != right_ft || left != right))
+        })
+        .buffered(CONTENT_ID_CONCURRENCY)
+        .filter_map(|(path, differs)| if differs { Some(path) } else { None })
+        .collect();
+
+    differing
+        .and_then(move |differing| {
+            // Whether a file changed on both sides was modified or added depends on whether it's
+            // in the merge base
+            let in_base = match base {
+                Some(base) => {
+                    let paths = differing.iter().cloned().map(Some).collect();
+                    get_path_entries(ctx, repo, base, paths)
+                        .map(|entries| {
+                            entries
+                                .into_iter()
+                                .map(|entry| match entry {
+                                    Some(entry) => entry.ttype != Type::Tree,
+                                    None => false,
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                        .left_future()
+                }
+                None => Ok(vec![false; differing.len()])
+                    .into_future()
+                    .right_future(),
+            };
+            in_base.map(move |in_base| {
+                for (path, in_base) in differing.into_iter().zip(in_base) {
+                    let kind = if in_base {
+                        ConflictKind::BothModified
+                    } else {
+                        ConflictKind::AddAdd
+                    };
+                    conflicts.insert(path, kind);
+                }
+                conflicts_by_path(conflicts)
+            })
+        })
+        .boxify()
+}
+
+fn conflicts_by_path(conflicts: BTreeMap<MPath, ConflictKind>) -> Vec<FileConflict> {
+    conflicts
+        .into_iter()
+        .map(|(path, kind)| FileConflict { path, kind })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+    use fixtures::linear;
+    use maplit::btreemap;
+    use tests_utils::{create_commit, store_files};
+
+    fn conflict(path: &str, kind: ConflictKind) -> FileConflict {
+        FileConflict {
+            path: MPath::new(path).unwrap(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn merge_conflicts() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let repo = linear::getrepo(None);
+
+            let base = create_commit(
+                ctx.clone(),
+                repo.clone(),
+                vec![],
+                store_files(
+                    ctx.clone(),
+                    btreemap! {
+                        "both_modified" => Some("base"),
+                        "same_change" => Some("base"),
+                        "delete_modify" => Some("base"),
+                        "both_deleted" => Some("base"),
+                        "left_only" => Some("base"),
+                    },
+                    repo.clone(),
+                ),
+            );
+            let left = create_commit(
+                ctx.clone(),
+                repo.clone(),
+                vec![base],
+                store_files(
+                    ctx.clone(),
+                    btreemap! {
+                        "both_modified" => Some("left"),
+                        "same_change" => Some("changed"),
+                        "delete_modify" => None,
+                        "both_deleted" => None,
+                        "left_only" => Some("left"),
+                        "add_add" => Some("left"),
+                        "same_add" => Some("added"),
+                    },
+                    repo.clone(),
+                ),
+            );
+            let right = create_commit(
+                ctx.clone(),
+                repo.clone(),
+                vec![base],
+                store_files(
+                    ctx.clone(),
+                    btreemap! {
+                        "both_modified" => Some("right"),
+                        "same_change" => Some("changed"),
+                        "delete_modify" => Some("right"),
+                        "both_deleted" => None,
+                        "add_add" => Some("right"),
+                        "same_add" => Some("added"),
+                    },
+                    repo.clone(),
+                ),
+            );
+
+            let result = find_merge_conflicts(ctx.clone(), repo.clone(), left, right)
+                .wait()
+                .unwrap();
+            assert_eq!(result.merge_base, Some(base));
+            assert_eq!(
+                result.conflicts,
+                vec![
+                    conflict("add_add", ConflictKind::AddAdd),
+                    conflict("both_modified", ConflictKind::BothModified),
+                    conflict("delete_modify", ConflictKind::DeleteModify),
+                ]
+            );
+
+            // A descendant has no conflict with its ancestor
+            let result = find_merge_conflicts(ctx.clone(), repo.clone(), left, base)
+                .wait()
+                .unwrap();
+            assert_eq!(result.merge_base, Some(base));
+            assert!(result.conflicts.is_empty());
+        })
+    }
+
+    #[test]
+    fn merge_conflicts_without_merge_base() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let repo = linear::getrepo(None);
+
+            let left = create_commit(
+                ctx.clone(),
+                repo.clone(),
+                vec![],
+                store_files(
+                    ctx.clone(),
+                    btreemap! {"a" => Some("left"), "b" => Some("same")},
+                    repo.clone(),
+                ),
+            );
+            let right = create_commit(
+                ctx.clone(),
+                repo.clone(),
+                vec![],
+                store_files(
+                    ctx.clone(),
+                    btreemap! {"a" => Some("right"), "b" => Some("same")},
+                    repo.clone(),
+                ),
+            );
+
+            let result = find_merge_conflicts(ctx.clone(), repo.clone(), left, right)
+                .wait()
+                .unwrap();
+            assert_eq!(result.merge_base, None);
+            assert_eq!(result.conflicts, vec![conflict("a", ConflictKind::AddAdd)]);
+        })
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Total size and number of files under a path, so that build tools can budget a checkout
+// without walking every file in it.
+//
+// Every directory gets a summary that is derived once and stored in the blobstore, keyed by its
+// manifest hash (much like fsnodes). Manifests are content addressed, so a summary never goes
+// stale and is shared by every revision in which the directory is unchanged. A query is then a
+// walk down the manifests to the path plus one blobstore fetch, instead of a walk of the whole
+// subtree.
+
+use std::ops::Add;
+
+use failure::Error;
+use futures::future::{loop_fn, Loop};
+use futures::{stream, Future, IntoFuture, Stream};
+use futures_ext::{try_boxfuture, BoxFuture, FutureExt};
+use serde_derive::{Deserialize, Serialize};
+
+use blobrepo::BlobRepo;
+use blobstore::{Blobstore, BlobstoreBytes};
+use cloned::cloned;
+use context::CoreContext;
+use mercurial_types::{Changeset, Entry, HgChangesetId, HgManifestId, Type};
+use mononoke_types::MPath;
+
+use crate::errors::ErrorKind;
+
+/// How many entries of a directory are summarized at once when deriving its summary
+const DERIVE_CONCURRENCY: usize = 100;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PathSummary {
+    /// Sum of the sizes of all files under the path
+    pub total_size: u64,
+    /// Number of files under the path
+    pub file_count: u64,
+    /// Number of directories under the path, not counting the path itself
+    pub dir_count: u64,
+}
+
+impl PathSummary {
+    fn file(size: u64) -> Self {
+        Self {
+            total_size: size,
+            file_count: 1,
+            dir_count: 0,
+        }
+    }
+
+    /// The summary of a directory, as seen from its parent directory.
+    fn subdirectory(self) -> Self {
+        Self {
+            dir_count: self.dir_count + 1,
+            ..self
+        }
+    }
+}
+
+impl Add for PathSummary {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            total_size: self.total_size + other.total_size,
+            file_count: self.file_count + other.file_count,
+            dir_count: self.dir_count + other.dir_count,
+        }
+    }
+}
+
+fn summary_key(manifestid: HgManifestId) -> String {
+    format!("directorysummary.hgmanifest.{}", manifestid)
+}
+
+/// Fetch the summary of a directory, deriving it (and the summaries of all directories under it
+/// that don't have one yet) if needed.
+pub fn get_directory_summary(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    manifestid: HgManifestId,
+) -> BoxFuture<PathSummary, Error> {
+    let key = summary_key(manifestid);
+    repo.get_blobstore()
+        .get(ctx.clone(), key.clone())
+        .and_then(move |maybe_bytes| match maybe_bytes {
+            Some(bytes) => serde_json::from_slice(bytes.as_bytes())
+                .map_err(Error::from)
+                .into_future()
+                .left_future(),
+            None => derive_directory_summary(ctx, repo, manifestid, key).right_future(),
+        })
+        .boxify()
+}
+
+fn derive_directory_summary(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    manifestid: HgManifestId,
+    key: String,
+) -> impl Future<Item = PathSummary, Error = Error> {
+    repo.get_manifest_by_nodeid(ctx.clone(), manifestid)
+        .and_then({
+            cloned!(ctx, repo);
+            move |manifest| {
+                let summaries = manifest.list().map(move |entry| match entry.get_type() {
+                    Type::Tree => {
+                        let manifestid = HgManifestId::new(entry.get_hash().into_nodehash());
+                        get_directory_summary(ctx.clone(), repo.clone(), manifestid)
+                            .map(PathSummary::subdirectory)
+                            .left_future()
+                    }
+                    Type::File(_) => entry
+                        .get_size(ctx.clone())
+                        .map(|size| PathSummary::file(size.unwrap_or(0) as u64))
+                        .right_future(),
+                });
+
+                stream::iter_ok(summaries)
+                    .buffer_unordered(DERIVE_CONCURRENCY)
+                    .fold(PathSummary::default(), |total, summary| {
+                        Ok::<_, Error>(total + summary)
+                    })
+            }
+        })
+        .and_then(move |summary| {
+            let bytes = try_boxfuture!(serde_json::to_vec(&summary));
+            repo.get_blobstore()
+                .put(ctx, key, BlobstoreBytes::from_bytes(bytes))
+                .map(move |()| summary)
+                .boxify()
+        })
+}
+
+/// Summarize the files under `path` (the whole repo if None) at a changeset. If `path` is a
+/// file, the summary is that of the file alone.
+pub fn get_path_summary(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    changesetid: HgChangesetId,
+    path: Option<MPath>,
+) -> BoxFuture<PathSummary, Error> {
+    let not_found = {
+        cloned!(path);
+        move || ErrorKind::NotFound(path.map(|p| p.to_string()).unwrap_or("/".to_string()))
+    };
+    let elements = path
+        .map(|path| path.into_iter().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    repo.get_changeset_by_changesetid(ctx.clone(), changesetid)
+        .and_then({
+            cloned!(ctx, repo);
+            move |changeset| {
+                loop_fn(
+                    (changeset.manifestid(), elements.into_iter()),
+                    move |(manifestid, mut rest)| {
+                        let element = match rest.next() {
+                            Some(element) => element,
+                            None => {
+                                return Ok(Loop::Break(Some(get_directory_summary(
+                                    ctx.clone(),
+                                    repo.clone(),
+                                    manifestid,
+                                ))))
+                                .into_future()
+                                .left_future();
+                            }
+                        };
+
+                        cloned!(ctx);
+                        repo.get_manifest_by_nodeid(ctx.clone(), manifestid)
+                            .map(move |manifest| match manifest.lookup(&element) {
+                                Some(ref entry) if entry.get_type() == Type::Tree => {
+                                    let manifestid =
+                                        HgManifestId::new(entry.get_hash().into_nodehash());
+                                    Loop::Continue((manifestid, rest))
+                                }
+                                Some(ref entry) if rest.len() == 0 => Loop::Break(Some(
+                                    entry
+                                        .get_size(ctx)
+                                        .map(|size| PathSummary::file(size.unwrap_or(0) as u64))
+                                        .boxify(),
+                                )),
+                                _ => Loop::Break(None),
+                            })
+                            .right_future()
+                    },
+                )
+            }
+        })
+        .and_then(move |summary| summary.ok_or_else(|| not_found().into()))
+        .flatten()
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use fixtures::many_files_dirs;
+    use tokio::runtime::Runtime;
+
+    fn summarize(rt: &mut Runtime, repo: &BlobRepo, path: Option<&str>) -> PathSummary {
+        let ctx = CoreContext::test_mock();
+        // The last commit before dir1 was replaced with a file
+        let changesetid =
+            HgChangesetId::from_str("d261bc7900818dea7c86935b3fb17a33b2e3a6b4").unwrap();
+        let path = path.map(|path| MPath::new(path).unwrap());
+        rt.block_on(get_path_summary(ctx, repo.clone(), changesetid, path))
+            .unwrap()
+    }
+
+    #[test]
+    fn path_summaries() {
+        let mut rt = Runtime::new().unwrap();
+        let repo = many_files_dirs::getrepo(None);
+
+        let expected = PathSummary {
+            total_size: 67,
+            file_count: 9,
+            dir_count: 5,
+        };
+        assert_eq!(summarize(&mut rt, &repo, None), expected);
+        // The second time around the summary is read back from the blobstore
+        assert_eq!(summarize(&mut rt, &repo, None), expected);
+
+        let expected = PathSummary {
+            total_size: 54,
+            file_count: 6,
+            dir_count: 3,
+        };
+        assert_eq!(summarize(&mut rt, &repo, Some("dir1")), expected);
+
+        assert_eq!(
+            summarize(&mut rt, &repo, Some("dir1/subdir1/file_1")),
+            PathSummary::file(9)
+        );
+    }
+
+    #[test]
+    fn missing_path() {
+        let mut rt = Runtime::new().unwrap();
+        let repo = many_files_dirs::getrepo(None);
+        let ctx = CoreContext::test_mock();
+        let changesetid =
+            HgChangesetId::from_str("d261bc7900818dea7c86935b3fb17a33b2e3a6b4").unwrap();
+
+        for path in &["nonexistent", "dir1/file_1_in_dir1/below_a_file"] {
+            let path = Some(MPath::new(path).unwrap());
+            let result = rt.block_on(get_path_summary(
+                ctx.clone(),
+                repo.clone(),
+                changesetid,
+                path,
+            ));
+            assert!(result.is_err());
+        }
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Git submodules have no manifest representation in Mercurial. Repositories converted from git
+// (hg-git style) record them in a `.hgsubstate` file at the root of the repository instead,
+// one "<commit hash> <path>" pair per line.
+
+use std::collections::BTreeMap;
+
+use failure::Error;
+use futures::Future;
+
+use blobrepo::BlobRepo;
+use context::CoreContext;
+use mercurial_types::manifest::Content;
+use mercurial_types::{Changeset, HgChangesetId};
+use mononoke_types::MPath;
+
+use crate::errors::ErrorKind;
+
+pub const HGSUBSTATE: &str = ".hgsubstate";
+
+/// Parse the content of a `.hgsubstate` file into a map from submodule path to the commit
+/// it is pinned to.
+pub fn parse_hgsubstate(content: &[u8]) -> Result<BTreeMap<MPath, String>, Error> {
+    let mut submodules = BTreeMap::new();
+
+    for line in content.split(|c| *c == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, |c| *c == b' ');
+        let (commit, path) = match (parts.next(), parts.next()) {
+            (Some(commit), Some(path)) if !path.is_empty() => (commit, path),
+            _ => {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "malformed {} line: {}",
+                    HGSUBSTATE,
+                    String::from_utf8_lossy(line)
+                ))
+                .into());
+            }
+        };
+
+        let commit = String::from_utf8(commit.to_vec())?;
+        submodules.insert(MPath::new(path)?, commit);
+    }
+
+    Ok(submodules)
+}
+
+/// Fetch the submodules recorded at a changeset. Repositories without a `.hgsubstate` file
+/// simply have no submodules.
+pub fn get_submodules(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    changesetid: HgChangesetId,
+) -> impl Future<Item = BTreeMap<MPath, String>, Error = Error> {
+    let path = MPath::new(HGSUBSTATE).expect(".hgsubstate is a valid path");
+
+    repo.get_changeset_by_changesetid(ctx.clone(), changesetid)
+        .and_then(move |changeset| {
+            repo.find_path_in_manifest(ctx, Some(path), changeset.manifestid())
+        })
+        .and_then(|content| match content {
+            Some(Content::File(content)) | Some(Content::Executable(content)) => {
+                parse_hgsubstate(content.as_bytes())
+            }
+            _ => Ok(BTreeMap::new()),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let content = b"0123456789abcdef0123456789abcdef01234567 third-party/foo\n\
+                        fedcba9876543210fedcba9876543210fedcba98 bar\n";
+        let submodules = parse_hgsubstate(content).unwrap();
+
+        assert_eq!(submodules.len(), 2);
+        assert_eq!(
+            submodules.get(&MPath::new("third-party/foo").unwrap()),
+            Some(&"0123456789abcdef0123456789abcdef01234567".to_string())
+        );
+        assert_eq!(
+            submodules.get(&MPath::new("bar").unwrap()),
+            Some(&"fedcba9876543210fedcba9876543210fedcba98".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert!(parse_hgsubstate(b"0123456789abcdef\n").is_err());
+        assert!(parse_hgsubstate(b"").unwrap().is_empty());
+    }
+}
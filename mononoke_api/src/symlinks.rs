@@ -0,0 +1,192 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::{HashSet, VecDeque};
+
+use failure::Error;
+use futures::future::{loop_fn, Loop};
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use context::CoreContext;
+use mercurial_types::manifest::Content;
+use mercurial_types::{Changeset, HgChangesetId, HgManifestId};
+use mononoke_types::{MPath, MPathElement};
+
+use crate::errors::ErrorKind;
+
+/// Same limit Linux uses for ELOOP: a path that requires more symlink expansions than this is
+/// treated as a loop even if no exact cycle was detected.
+pub const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Resolve the content of a symlink living in `dir` to a repo path. `None` means the root of
+/// the repository. Absolute targets and targets escaping the root cannot be represented inside
+/// the repo and are rejected.
+pub fn resolve_symlink_target(dir: Option<&MPath>, target: &[u8]) -> Result<Option<MPath>, Error> {
+    if target.starts_with(b"/") {
+        return Err(ErrorKind::InvalidInput(format!(
+            "symlink target {} points outside of the repository",
+            String::from_utf8_lossy(target)
+        ))
+        .into());
+    }
+
+    let mut elements: Vec<MPathElement> = MPath::iter_opt(dir).cloned().collect();
+    for component in target.split(|c| *c == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                if elements.pop().is_none() {
+                    return Err(ErrorKind::InvalidInput(format!(
+                        "symlink target {} points outside of the repository",
+                        String::from_utf8_lossy(target)
+                    ))
+                    .into());
+                }
+            }
+            component => elements.push(MPathElement::new(component.to_vec())?),
+        }
+    }
+
+    Ok(MPath::join_opt(None, &elements))
+}
+
+struct Walk {
+    resolved: Option<MPath>,
+    remaining: VecDeque<MPathElement>,
+    hops: usize,
+    seen: HashSet<(MPath, Vec<MPathElement>)>,
+}
+
+/// Like `get_content_by_path`, but symlinks found on the way (including the final component)
+/// are expanded. Returns the fully resolved path along with its content.
+///
+/// Cycles are detected exactly by remembering every (symlink, remaining suffix) state visited,
+/// and the total number of expansions is additionally capped by `MAX_SYMLINK_HOPS`.
+pub fn get_content_by_path_following_symlinks(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    changesetid: HgChangesetId,
+    path: Option<MPath>,
+) -> impl Future<Item = (Option<MPath>, Content), Error = Error> {
+    repo.get_changeset_by_changesetid(ctx.clone(), changesetid)
+        .map(|changeset| changeset.manifestid())
+        .and_then(move |manifestid| {
+            let walk = Walk {
+                resolved: None,
+                remaining: MPath::into_iter_opt(path.clone()).collect(),
+                hops: 0,
+                seen: HashSet::new(),
+            };
+
+            loop_fn(walk, move |walk| {
+                step(ctx.clone(), repo.clone(), manifestid, path.clone(), walk)
+            })
+        })
+}
+
+fn step(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    manifestid: HgManifestId,
+    original: Option<MPath>,
+    mut walk: Walk,
+) -> BoxFuture<Loop<(Option<MPath>, Content), Walk>, Error> {
+    let element = match walk.remaining.pop_front() {
+        Some(element) => element,
+        None => {
+            // Nothing left to traverse: fetch whatever the resolved path points at.
+            let resolved = walk.resolved;
+            return repo
+                .find_path_in_manifest(ctx, resolved.clone(), manifestid)
+                .and_then(move |content| {
+                    content
+                        .map(|content| Loop::Break((resolved, content)))
+                        .ok_or_else(|| not_found(original.as_ref()))
+                })
+                .boxify();
+        }
+    };
+
+    let current = MPath::join_opt_element(walk.resolved.as_ref(), &element);
+    repo.find_path_in_manifest(ctx, Some(current.clone()), manifestid)
+        .and_then(move |content| match content {
+            None => Err(not_found(original.as_ref())),
+            Some(Content::Symlink(target)) => {
+                let remaining: Vec<_> = walk.remaining.iter().cloned().collect();
+                if !walk.seen.insert((current.clone(), remaining)) {
+                    return Err(ErrorKind::SymlinkLoop(current.to_string()).into());
+                }
+                walk.hops += 1;
+                if walk.hops > MAX_SYMLINK_HOPS {
+                    return Err(ErrorKind::SymlinkLoop(current.to_string()).into());
+                }
+
+                let target = resolve_symlink_target(walk.resolved.as_ref(), target.as_bytes())?;
+                for element in MPath::into_iter_opt(target).rev() {
+                    walk.remaining.push_front(element);
+                }
+                walk.resolved = None;
+                Ok(Loop::Continue(walk))
+            }
+            Some(Content::Tree(_)) => {
+                walk.resolved = Some(current);
+                Ok(Loop::Continue(walk))
+            }
+            Some(content) => {
+                if walk.remaining.is_empty() {
+                    Ok(Loop::Break((Some(current), content)))
+                } else {
+                    // A regular file cannot have children.
+                    Err(not_found(original.as_ref()))
+                }
+            }
+        })
+        .boxify()
+}
+
+fn not_found(path: Option<&MPath>) -> Error {
+    ErrorKind::NotFound(MPath::display_opt(path).to_string()).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(p: &str) -> MPath {
+        MPath::new(p).unwrap()
+    }
+
+    #[test]
+    fn relative_target() {
+        assert_eq!(
+            resolve_symlink_target(Some(&path("a/b")), b"c/d").unwrap(),
+            Some(path("a/b/c/d"))
+        );
+        assert_eq!(
+            resolve_symlink_target(Some(&path("a/b")), b"../c").unwrap(),
+            Some(path("a/c"))
+        );
+        assert_eq!(
+            resolve_symlink_target(Some(&path("a")), b"./b//c/.").unwrap(),
+            Some(path("a/b/c"))
+        );
+    }
+
+    #[test]
+    fn target_at_root() {
+        assert_eq!(resolve_symlink_target(Some(&path("a")), b"..").unwrap(), None);
+        assert_eq!(resolve_symlink_target(None, b"x").unwrap(), Some(path("x")));
+    }
+
+    #[test]
+    fn target_outside_repo() {
+        assert!(resolve_symlink_target(None, b"..").is_err());
+        assert!(resolve_symlink_target(Some(&path("a")), b"../../b").is_err());
+        assert!(resolve_symlink_target(Some(&path("a")), b"/etc/passwd").is_err());
+    }
+}
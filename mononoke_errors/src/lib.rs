@@ -0,0 +1,195 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Categories of errors, shared by all crates.
+//!
+//! Errors cross crate boundaries as `failure::Error`s, with the typed errors of the crates they
+//! went through somewhere in their chain of causes. Code that reacts to errors of other crates,
+//! retry layers in particular, asks a `Categorizer` what kind of failure an error is rather than
+//! knowing the error types of every crate. Crates implement `Categorize` for their error kinds,
+//! and export a categorizer that knows their kinds and the kinds of the crates they depend on.
+
+#![deny(warnings)]
+
+use std::io;
+
+use failure_ext::{Context, Error, Fail};
+
+/// What kind of failure an error is
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ErrorCategory {
+    /// Something that was asked for doesn't exist, e.g. a missing blob or changeset
+    NotFound,
+    /// Stored data is invalid, e.g. a blob that can't be deserialized
+    Corrupt,
+    /// A backend failed in a way that may go away on retry, e.g. a timeout or a lost connection
+    Transient,
+    /// The request can't be served as it is, e.g. a push with inconsistent hashes
+    InvalidRequest,
+    /// A bug, or a state the code doesn't expect
+    Internal,
+}
+
+impl ErrorCategory {
+    /// Whether retrying the operation that failed may succeed
+    pub fn is_transient(self) -> bool {
+        self == ErrorCategory::Transient
+    }
+}
+
+/// Typed errors that know their category
+pub trait Categorize {
+    /// None if the category of the error is the one of its cause, e.g. for errors that only add
+    /// context
+    fn category(&self) -> Option<ErrorCategory>;
+}
+
+impl Categorize for io::Error {
+    fn category(&self) -> Option<ErrorCategory> {
+        use std::io::ErrorKind::*;
+
+        match self.kind() {
+            NotFound => Some(ErrorCategory::NotFound),
+            InvalidData | UnexpectedEof => Some(ErrorCategory::Corrupt),
+            InvalidInput => Some(ErrorCategory::InvalidRequest),
+            TimedOut | Interrupted | WouldBlock | ConnectionRefused | ConnectionReset
+            | ConnectionAborted | NotConnected | BrokenPipe | AddrNotAvailable => {
+                Some(ErrorCategory::Transient)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn downcast_category<E: Fail + Categorize>(fail: &Fail) -> Option<ErrorCategory> {
+    match fail.downcast_ref::<E>() {
+        Some(err) => err.category(),
+        // Errors added with `context` or `chain_err` are wrapped in a `Context`
+        None => fail
+            .downcast_ref::<Context<E>>()
+            .and_then(|context| context.get_context().category()),
+    }
+}
+
+/// Finds the category of errors from the typed errors in their chain of causes. The outermost
+/// typed error that has a category decides.
+#[derive(Clone)]
+pub struct Categorizer {
+    kinds: Vec<fn(&Fail) -> Option<ErrorCategory>>,
+    default: ErrorCategory,
+}
+
+impl Categorizer {
+    /// A categorizer that only knows `io::Error`. Errors without any known cause are in the
+    /// `default` category
+    pub fn new(default: ErrorCategory) -> Self {
+        Self {
+            kinds: vec![downcast_category::<io::Error>],
+            default,
+        }
+    }
+
+    /// Also know the errors of type `E`
+    pub fn with<E: Fail + Categorize>(mut self) -> Self {
+        self.kinds.push(downcast_category::<E>);
+        self
+    }
+
+    /// Put errors without any known cause in the `default` category
+    pub fn with_default(self, default: ErrorCategory) -> Self {
+        Self { default, ..self }
+    }
+
+    /// The category of `err`
+    pub fn categorize(&self, err: &Error) -> ErrorCategory {
+        err.iter_chain()
+            .filter_map(|cause| self.kinds.iter().filter_map(|kind| kind(cause)).next())
+            .next()
+            .unwrap_or(self.default)
+    }
+
+    /// Whether retrying the operation that failed with `err` may succeed
+    pub fn is_transient(&self, err: &Error) -> bool {
+        self.categorize(err).is_transient()
+    }
+}
+
+impl Default for Categorizer {
+    fn default() -> Self {
+        Self::new(ErrorCategory::Internal)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use failure_ext::{err_msg, ResultExt};
+
+    #[derive(Debug, Fail)]
+    enum TestError {
+        #[fail(display = "blob {} is missing", _0)]
+        Missing(String),
+        #[fail(display = "while fetching {}", _0)]
+        WhileFetching(String),
+    }
+
+    impl Categorize for TestError {
+        fn category(&self) -> Option<ErrorCategory> {
+            match self {
+                TestError::Missing(_) => Some(ErrorCategory::NotFound),
+                TestError::WhileFetching(_) => None,
+            }
+        }
+    }
+
+    fn categorizer() -> Categorizer {
+        Categorizer::default().with::<TestError>()
+    }
+
+    #[test]
+    fn typed_errors() {
+        let err: Error = TestError::Missing("blob".into()).into();
+        assert_eq!(categorizer().categorize(&err), ErrorCategory::NotFound);
+        // Unknown error types are in the default category
+        assert_eq!(
+            Categorizer::default().categorize(&err),
+            ErrorCategory::Internal
+        );
+    }
+
+    #[test]
+    fn causes_decide_for_context() {
+        let res: Result<(), Error> =
+            Err(io::Error::new(io::ErrorKind::TimedOut, "timed out").into());
+        let err: Error = res
+            .context(TestError::WhileFetching("blob".into()))
+            .unwrap_err()
+            .into();
+        assert!(categorizer().is_transient(&err));
+
+        let res: Result<(), Error> = Err(err_msg("connection lost"));
+        let err: Error = res
+            .context(TestError::WhileFetching("blob".into()))
+            .unwrap_err()
+            .into();
+        assert_eq!(categorizer().categorize(&err), ErrorCategory::Internal);
+        assert!(categorizer()
+            .with_default(ErrorCategory::Transient)
+            .is_transient(&err));
+    }
+
+    #[test]
+    fn outermost_category_wins() {
+        let res: Result<(), Error> =
+            Err(io::Error::new(io::ErrorKind::TimedOut, "timed out").into());
+        let err: Error = res
+            .context(TestError::Missing("blob".into()))
+            .unwrap_err()
+            .into();
+        assert_eq!(categorizer().categorize(&err), ErrorCategory::NotFound);
+    }
+}
@@ -57,6 +57,12 @@ union RepoPath {
   3: MPath FilePath,
 }
 
+// Serialized RepoPaths are wrapped in this so that their encoding can change
+// without breaking readers of older blobs. Add a variant for every new version.
+union RepoPathEnvelope {
+  1: RepoPath V1,
+}
+
 // Parent ordering
 // ---------------
 // "Ordered" parents means that behavior will change if the order of parents
@@ -114,6 +120,13 @@ struct DateTime {
 
 union FileContents {
   1: binary Bytes,
+  2: Tombstone Tombstone,
+}
+
+// Content that was intentionally removed (e.g. redacted, or left out of a
+// partial import). It is stored under the ID of the content it replaces.
+struct Tombstone {
+  1: string reason,
 }
 
 union RawBundle2 {
@@ -22,6 +22,9 @@ use typed_hash::{ChangesetId, ChangesetIdContext};
 /// A struct callers can use to build up a `BonsaiChangeset`.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct BonsaiChangesetMut {
+    /// Parents in order. There can be more than two, e.g. for octopus merges imported from git.
+    /// Only the first two become parents of the derived hg changeset, so `file_changes` must
+    /// include every file whose content comes from one of the other parents.
     pub parents: Vec<ChangesetId>,
     pub author: String,
     pub author_date: DateTime,
@@ -227,8 +230,7 @@ impl BlobstoreValue for BonsaiChangeset {
 
 impl Arbitrary for BonsaiChangeset {
     fn arbitrary<G: Gen>(g: &mut G) -> Self {
-        // In the future Mononoke would like to support changesets with more parents than 2.
-        // Start testing that now.
+        // Changesets can have more than two parents (octopus merges).
         let size = g.size();
         let num_parents = g.gen_range(0, 8);
         let parents: Vec<_> = (0..num_parents)
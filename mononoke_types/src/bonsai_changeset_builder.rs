@@ -0,0 +1,300 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::BTreeMap;
+
+use bonsai_changeset::{BonsaiChangeset, BonsaiChangesetMut};
+use datetime::DateTime;
+use errors::*;
+use file_change::FileChange;
+use path::MPath;
+use typed_hash::ChangesetId;
+
+/// Incrementally build a `BonsaiChangeset`.
+///
+/// Unlike filling in a `BonsaiChangesetMut` by hand, mistakes (a path changed twice, copy
+/// information referring to a changeset that isn't a parent, duplicate parents) are remembered
+/// as they happen and reported by `freeze`, with the offending path or parent in the error.
+#[derive(Debug)]
+pub struct BonsaiChangesetBuilder {
+    parents: Vec<ChangesetId>,
+    author: String,
+    author_date: DateTime,
+    committer: Option<String>,
+    committer_date: Option<DateTime>,
+    message: String,
+    extra: BTreeMap<String, Vec<u8>>,
+    file_changes: BTreeMap<MPath, Option<FileChange>>,
+    errors: Vec<ErrorKind>,
+}
+
+impl BonsaiChangesetBuilder {
+    pub fn new<S: Into<String>>(author: S, author_date: DateTime) -> Self {
+        Self {
+            parents: vec![],
+            author: author.into(),
+            author_date,
+            committer: None,
+            committer_date: None,
+            message: String::new(),
+            extra: BTreeMap::new(),
+            file_changes: BTreeMap::new(),
+            errors: vec![],
+        }
+    }
+
+    /// Add a parent. The order in which parents are added is significant.
+    pub fn add_parent(&mut self, parent: ChangesetId) -> &mut Self {
+        if self.parents.contains(&parent) {
+            self.errors.push(ErrorKind::DuplicateParent(parent));
+        } else {
+            self.parents.push(parent);
+        }
+        self
+    }
+
+    /// Replace the parents added so far, along with the duplicates found among them.
+    pub fn set_parents<I: IntoIterator<Item = ChangesetId>>(&mut self, parents: I) -> &mut Self {
+        self.parents.clear();
+        self.errors.retain(|err| match err {
+            ErrorKind::DuplicateParent(_) => false,
+            _ => true,
+        });
+        for parent in parents {
+            self.add_parent(parent);
+        }
+        self
+    }
+
+    pub fn set_author<S: Into<String>>(&mut self, author: S) -> &mut Self {
+        self.author = author.into();
+        self
+    }
+
+    pub fn set_author_date(&mut self, author_date: DateTime) -> &mut Self {
+        self.author_date = author_date;
+        self
+    }
+
+    pub fn set_committer<S: Into<String>>(&mut self, committer: S, date: DateTime) -> &mut Self {
+        self.committer = Some(committer.into());
+        self.committer_date = Some(date);
+        self
+    }
+
+    pub fn set_message<S: Into<String>>(&mut self, message: S) -> &mut Self {
+        self.message = message.into();
+        self
+    }
+
+    pub fn add_extra<K: Into<String>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) -> &mut Self {
+        self.extra.insert(key.into(), value.into());
+        self
+    }
+
+    /// Record a file being added or modified. Changes are kept sorted by path, so they can be
+    /// added in any order.
+    pub fn add_file_change(&mut self, path: MPath, change: FileChange) -> &mut Self {
+        self.insert_file_change(path, Some(change))
+    }
+
+    /// Record a file being deleted.
+    pub fn delete_file(&mut self, path: MPath) -> &mut Self {
+        self.insert_file_change(path, None)
+    }
+
+    fn insert_file_change(&mut self, path: MPath, change: Option<FileChange>) -> &mut Self {
+        if self.file_changes.contains_key(&path) {
+            self.errors.push(ErrorKind::DuplicateFileChange(path));
+        } else {
+            self.file_changes.insert(path, change);
+        }
+        self
+    }
+
+    /// Validate everything recorded so far and build the changeset. The first problem found is
+    /// returned; problems recorded while building are reported before those found by
+    /// `BonsaiChangesetMut::verify`.
+    pub fn freeze(self) -> Result<BonsaiChangeset> {
+        if let Some(err) = self.errors.into_iter().next() {
+            return Err(err.into());
+        }
+
+        for (path, change) in &self.file_changes {
+            let copy_from = change.as_ref().and_then(|change| change.copy_from());
+            if let Some((copy_from_path, copy_from_cs)) = copy_from {
+                if !self.parents.contains(copy_from_cs) {
+                    return Err(ErrorKind::UnknownCopyFromParent(
+                        path.clone(),
+                        copy_from_path.clone(),
+                        *copy_from_cs,
+                    )
+                    .into());
+                }
+            }
+        }
+
+        BonsaiChangesetMut {
+            parents: self.parents,
+            author: self.author,
+            author_date: self.author_date,
+            committer: self.committer,
+            committer_date: self.committer_date,
+            message: self.message,
+            extra: self.extra,
+            file_changes: self.file_changes,
+        }
+        .freeze()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use file_change::FileType;
+    use typed_hash::ContentId;
+
+    fn file_change(copy_from: Option<(MPath, ChangesetId)>) -> FileChange {
+        FileChange::new(
+            ContentId::from_byte_array([1; 32]),
+            FileType::Regular,
+            42,
+            copy_from,
+        )
+    }
+
+    fn builder() -> BonsaiChangesetBuilder {
+        BonsaiChangesetBuilder::new("author", DateTime::from_timestamp(0, 0).unwrap())
+    }
+
+    #[test]
+    fn build() {
+        let p1 = ChangesetId::from_byte_array([3; 32]);
+        let mut builder = builder();
+        builder
+            .add_parent(p1)
+            .set_message("message")
+            .add_file_change(MPath::new("b").unwrap(), file_change(None))
+            .delete_file(MPath::new("a").unwrap())
+            .add_file_change(
+                MPath::new("c").unwrap(),
+                file_change(Some((MPath::new("a").unwrap(), p1))),
+            );
+        let cs = builder.freeze().expect("changeset should be valid");
+
+        assert_eq!(cs.parents().collect::<Vec<_>>(), vec![p1]);
+        assert_eq!(cs.message(), "message");
+        let paths: Vec<_> = cs.file_changes().map(|(path, _)| path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                MPath::new("a").unwrap(),
+                MPath::new("b").unwrap(),
+                MPath::new("c").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn same_as_mut() {
+        let mut builder = builder();
+        builder.set_message("message");
+        let built = builder.freeze().unwrap();
+
+        let by_hand = BonsaiChangesetMut {
+            parents: vec![],
+            author: "author".into(),
+            author_date: DateTime::from_timestamp(0, 0).unwrap(),
+            committer: None,
+            committer_date: None,
+            message: "message".into(),
+            extra: BTreeMap::new(),
+            file_changes: BTreeMap::new(),
+        }
+        .freeze()
+        .unwrap();
+
+        assert_eq!(built, by_hand);
+    }
+
+    #[test]
+    fn duplicate_file_change() {
+        let mut builder = builder();
+        builder
+            .add_file_change(MPath::new("a").unwrap(), file_change(None))
+            .delete_file(MPath::new("a").unwrap());
+        let err = builder.freeze().unwrap_err();
+
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::DuplicateFileChange(path)) => assert_eq!(path, MPath::new("a").unwrap()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_parent() {
+        let p1 = ChangesetId::from_byte_array([3; 32]);
+        let mut builder = builder();
+        builder.add_parent(p1).add_parent(p1);
+
+        match builder.freeze().unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::DuplicateParent(parent)) => assert_eq!(parent, p1),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn set_parents_replaces_duplicates() {
+        let p1 = ChangesetId::from_byte_array([3; 32]);
+        let p2 = ChangesetId::from_byte_array([4; 32]);
+        let mut replaced = builder();
+        replaced
+            .add_parent(p1)
+            .add_parent(p1)
+            .set_parents(vec![p1, p2]);
+        let cs = replaced
+            .freeze()
+            .expect("duplicate parent should be forgotten");
+        assert_eq!(cs.parents().collect::<Vec<_>>(), vec![p1, p2]);
+
+        let mut duplicated = builder();
+        duplicated.set_parents(vec![p2, p2]);
+        match duplicated.freeze().unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::DuplicateParent(parent)) => assert_eq!(parent, p2),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn copy_from_unknown_parent() {
+        let unknown = ChangesetId::from_byte_array([4; 32]);
+        let mut builder = builder();
+        builder.add_file_change(
+            MPath::new("b").unwrap(),
+            file_change(Some((MPath::new("a").unwrap(), unknown))),
+        );
+
+        match builder.freeze().unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::UnknownCopyFromParent(path, from, parent)) => {
+                assert_eq!(path, MPath::new("b").unwrap());
+                assert_eq!(from, MPath::new("a").unwrap());
+                assert_eq!(parent, unknown);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn path_conflict() {
+        let mut builder = builder();
+        builder
+            .add_file_change(MPath::new("a").unwrap(), file_change(None))
+            .add_file_change(MPath::new("a/b").unwrap(), file_change(None));
+
+        assert!(builder.freeze().is_err());
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Flags that automation sets in the extras of the commits it pushes, to change how the server
+//! handles them. Flags are the extras whose key starts with `mononoke:`, and are set if their
+//! value is `1`. Only the flags below are recognized: other keys in the namespace are rejected
+//! when pushed, so that a typo doesn't silently do nothing.
+
+use std::collections::BTreeMap;
+
+use errors::*;
+
+/// Extras whose key starts with this are commit flags
+pub const COMMIT_FLAG_PREFIX: &str = "mononoke:";
+/// Pushrebase keeps the date of the commit, even if the repo rewrites dates
+pub const SKIP_DATE_REWRITE: &str = "mononoke:skip-date-rewrite";
+/// The commit is imported from another repo, and pushrebase lands it as it was pushed
+pub const IMPORT: &str = "mononoke:import";
+
+const KNOWN_FLAGS: &[&str] = &[SKIP_DATE_REWRITE, IMPORT];
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CommitFlags {
+    pub skip_date_rewrite: bool,
+    pub import: bool,
+}
+
+impl CommitFlags {
+    /// The flags set in `extras`. Fails if an extra is in the namespace of flags but isn't a
+    /// known flag, or if the value of a flag is neither `0` nor `1`.
+    pub fn from_extras<I, K, V>(extras: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut flags = CommitFlags::default();
+        for (key, value) in extras {
+            let key = String::from_utf8_lossy(key.as_ref());
+            if !key.starts_with(COMMIT_FLAG_PREFIX) {
+                continue;
+            }
+            let flag = match &*key {
+                SKIP_DATE_REWRITE => &mut flags.skip_date_rewrite,
+                IMPORT => &mut flags.import,
+                _ => {
+                    let msg = "unknown flag".to_string();
+                    return Err(ErrorKind::InvalidCommitFlag(key.into_owned(), msg).into());
+                }
+            };
+            *flag = match value.as_ref() {
+                b"1" => true,
+                b"0" => false,
+                _ => {
+                    let msg = "value must be 0 or 1".to_string();
+                    return Err(ErrorKind::InvalidCommitFlag(key.into_owned(), msg).into());
+                }
+            };
+        }
+        Ok(flags)
+    }
+
+    /// Whether the flag with key `key` is set
+    pub fn is_set(&self, key: &str) -> bool {
+        match key {
+            SKIP_DATE_REWRITE => self.skip_date_rewrite,
+            IMPORT => self.import,
+            _ => false,
+        }
+    }
+}
+
+/// Remove the extras that are in the namespace of flags but aren't known flags
+pub fn strip_unknown_commit_flags(extras: &mut BTreeMap<String, Vec<u8>>) {
+    let unknown: Vec<_> = extras
+        .keys()
+        .filter(|key| key.starts_with(COMMIT_FLAG_PREFIX) && !KNOWN_FLAGS.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    for key in unknown {
+        extras.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flags_from_extras() {
+        let extras = btreemap! {
+            "mononoke:import" => "1",
+            "mononoke:skip-date-rewrite" => "0",
+            "amend_source" => "whatever",
+        };
+        let flags = CommitFlags::from_extras(extras).unwrap();
+        assert_eq!(
+            flags,
+            CommitFlags {
+                skip_date_rewrite: false,
+                import: true,
+            }
+        );
+        assert!(flags.is_set(IMPORT));
+        assert!(!flags.is_set(SKIP_DATE_REWRITE));
+
+        assert!(CommitFlags::from_extras(btreemap! {"mononoke:imprt" => "1"}).is_err());
+        assert!(CommitFlags::from_extras(btreemap! {"mononoke:import" => "yes"}).is_err());
+    }
+
+    #[test]
+    fn unknown_flags_are_stripped() {
+        let mut extras = btreemap! {
+            "mononoke:import".to_string() => b"1".to_vec(),
+            "mononoke:imprt".to_string() => b"1".to_vec(),
+            "amend_source".to_string() => b"whatever".to_vec(),
+        };
+        strip_unknown_commit_flags(&mut extras);
+        assert_eq!(
+            extras,
+            btreemap! {
+                "mononoke:import".to_string() => b"1".to_vec(),
+                "amend_source".to_string() => b"whatever".to_vec(),
+            }
+        );
+    }
+}
@@ -6,6 +6,7 @@
 
 pub use failure::{Error, ResultExt};
 
+use typed_hash::ChangesetId;
 use MPath;
 
 #[derive(Debug, Fail)]
@@ -31,6 +32,18 @@ pub enum ErrorKind {
     NotPathConflictFree(MPath, MPath),
     #[fail(display = "invalid bonsai changeset: {}", _0)]
     InvalidBonsaiChangeset(String),
+    #[fail(display = "path '{}' is changed more than once", _0)]
+    DuplicateFileChange(MPath),
+    #[fail(display = "parent {} is specified more than once", _0)]
+    DuplicateParent(ChangesetId),
+    #[fail(
+        display = "copy information for path '{}' (from '{}') has parent {} which isn't \
+                   recognized",
+        _0, _1, _2
+    )]
+    UnknownCopyFromParent(MPath, MPath, ChangesetId),
+    #[fail(display = "invalid commit flag '{}': {}", _0, _1)]
+    InvalidCommitFlag(String, String),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
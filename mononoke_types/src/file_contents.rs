@@ -22,6 +22,47 @@ use typed_hash::{ContentId, ContentIdContext};
 #[derive(Clone, Eq, PartialEq)]
 pub enum FileContents {
     Bytes(Bytes),
+    /// The content was intentionally removed. Readers see a placeholder instead.
+    Tombstone(Tombstone),
+}
+
+/// A marker for content that is intentionally absent, e.g. because it was redacted or left out
+/// of a partial import.
+///
+/// A tombstone is stored under the ID of the content it replaces, so use `Tombstone::into_blob`
+/// rather than `BlobstoreValue::into_blob` to write one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tombstone {
+    reason: String,
+    placeholder: Bytes,
+}
+
+impl Tombstone {
+    pub fn new<S: Into<String>>(reason: S) -> Self {
+        let reason = reason.into();
+        let placeholder = format!("This file is not available: {}\n", reason).into();
+        Self {
+            reason,
+            placeholder,
+        }
+    }
+
+    /// Why the content was removed.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// The data served in place of the removed content.
+    pub fn placeholder(&self) -> &Bytes {
+        &self.placeholder
+    }
+
+    /// Create a blob that replaces the content with ID `replaces`.
+    pub fn into_blob(self, replaces: ContentId) -> ContentBlob {
+        let thrift = FileContents::Tombstone(self).into_thrift();
+        let data = compact_protocol::serialize(&thrift);
+        Blob::new(replaces, data)
+    }
 }
 
 impl FileContents {
@@ -29,9 +70,32 @@ impl FileContents {
         FileContents::Bytes(b.into())
     }
 
+    pub fn new_tombstone<S: Into<String>>(reason: S) -> Self {
+        FileContents::Tombstone(Tombstone::new(reason))
+    }
+
+    #[inline]
+    pub fn is_tombstone(&self) -> bool {
+        match self {
+            FileContents::Bytes(_) => false,
+            FileContents::Tombstone(_) => true,
+        }
+    }
+
+    /// The tombstone marker, if this content was intentionally removed.
+    pub fn tombstone(&self) -> Option<&Tombstone> {
+        match self {
+            FileContents::Bytes(_) => None,
+            FileContents::Tombstone(tombstone) => Some(tombstone),
+        }
+    }
+
     pub(crate) fn from_thrift(fc: thrift::FileContents) -> Result<Self> {
         match fc {
             thrift::FileContents::Bytes(bytes) => Ok(FileContents::Bytes(bytes.into())),
+            thrift::FileContents::Tombstone(tombstone) => {
+                Ok(FileContents::Tombstone(Tombstone::new(tombstone.reason)))
+            }
             thrift::FileContents::UnknownField(x) => bail_err!(ErrorKind::InvalidThrift(
                 "FileContents".into(),
                 format!("unknown file contents field: {}", x)
@@ -39,29 +103,30 @@ impl FileContents {
         }
     }
 
+    /// The size of the data. For tombstones, this is the size of the placeholder.
     pub fn size(&self) -> usize {
-        match *self {
-            FileContents::Bytes(ref bytes) => bytes.len(),
-        }
+        self.as_bytes().len()
     }
 
     /// Whether this starts with a particular string.
     #[inline]
     pub fn starts_with(&self, needle: &[u8]) -> bool {
-        match self {
-            FileContents::Bytes(b) => b.starts_with(needle),
-        }
+        self.as_bytes().starts_with(needle)
     }
 
+    /// The data. For tombstones, this is the placeholder.
     pub fn into_bytes(self) -> Bytes {
         match self {
             FileContents::Bytes(bytes) => bytes,
+            FileContents::Tombstone(tombstone) => tombstone.placeholder,
         }
     }
 
+    /// The data. For tombstones, this is the placeholder.
     pub fn as_bytes(&self) -> &Bytes {
         match self {
             FileContents::Bytes(bytes) => &bytes,
+            FileContents::Tombstone(tombstone) => tombstone.placeholder(),
         }
     }
 
@@ -69,6 +134,11 @@ impl FileContents {
         match self {
             // TODO (T26959816) -- allow Thrift to represent binary as Bytes
             FileContents::Bytes(bytes) => thrift::FileContents::Bytes(bytes.to_vec()),
+            FileContents::Tombstone(tombstone) => {
+                thrift::FileContents::Tombstone(thrift::Tombstone {
+                    reason: tombstone.reason,
+                })
+            }
         }
     }
 
@@ -104,13 +174,20 @@ impl Debug for FileContents {
             FileContents::Bytes(ref bytes) => {
                 write!(f, "FileContents::Bytes(length {})", bytes.len())
             }
+            FileContents::Tombstone(ref tombstone) => {
+                write!(f, "FileContents::Tombstone(reason {:?})", tombstone.reason())
+            }
         }
     }
 }
 
 impl Arbitrary for FileContents {
     fn arbitrary<G: Gen>(g: &mut G) -> Self {
-        FileContents::new_bytes(Vec::arbitrary(g))
+        if g.gen_weighted_bool(10) {
+            FileContents::new_tombstone(String::arbitrary(g))
+        } else {
+            FileContents::new_bytes(Vec::arbitrary(g))
+        }
     }
 
     fn shrink(&self) -> Box<Iterator<Item = Self>> {
@@ -138,6 +215,21 @@ mod test {
         }
     }
 
+    #[test]
+    fn tombstone_blob() {
+        let replaces = ContentId::from_byte_array([1; 32]);
+        let blob = Tombstone::new("redacted").into_blob(replaces);
+        assert_eq!(*blob.id(), replaces);
+
+        let fc = FileContents::from_blob(blob).expect("tombstone blob should be valid");
+        assert!(fc.is_tombstone());
+        assert_eq!(fc.tombstone().map(|t| t.reason()), Some("redacted"));
+        assert_eq!(
+            fc.as_bytes().as_ref(),
+            &b"This file is not available: redacted\n"[..]
+        );
+    }
+
     #[test]
     fn bad_thrift() {
         let thrift_fc = thrift::FileContents::UnknownField(-1);
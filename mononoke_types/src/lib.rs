@@ -16,6 +16,7 @@ extern crate abomonation;
 extern crate abomonation_derive;
 extern crate ascii;
 extern crate asyncmemo;
+#[cfg(test)]
 extern crate bincode;
 extern crate blake2;
 extern crate bytes;
@@ -44,6 +45,8 @@ extern crate mononoke_types_thrift;
 
 pub mod blob;
 pub mod bonsai_changeset;
+pub mod bonsai_changeset_builder;
+pub mod commit_flags;
 pub mod datetime;
 pub mod errors;
 pub mod file_change;
@@ -54,15 +57,18 @@ pub mod path;
 pub mod rawbundle2;
 pub mod repo;
 pub mod sql_types;
+mod thrift_compat;
 pub mod typed_hash;
 
 pub use blob::{Blob, BlobstoreBytes, BlobstoreValue, ChangesetBlob, ContentBlob, RawBundle2Blob};
 pub use bonsai_changeset::{BonsaiChangeset, BonsaiChangesetMut};
+pub use bonsai_changeset_builder::BonsaiChangesetBuilder;
+pub use commit_flags::CommitFlags;
 pub use datetime::{DateTime, Timestamp};
 pub use file_change::{FileChange, FileType};
-pub use file_contents::FileContents;
+pub use file_contents::{FileContents, Tombstone};
 pub use generation::Generation;
-pub use path::{check_case_conflicts, MPath, MPathElement, RepoPath};
+pub use path::{check_case_conflicts, MPath, MPathElement, RepoPath, RepoPathEncoding};
 pub use rawbundle2::RawBundle2;
 pub use repo::RepositoryId;
 pub use typed_hash::{ChangesetId, ContentId, MononokeId, RawBundle2Id};
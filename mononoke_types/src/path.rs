@@ -14,11 +14,11 @@ use std::mem;
 use std::slice::Iter;
 
 use asyncmemo::Weight;
-use bincode;
 use failure::{chain::*, err_msg};
 use heapsize::HeapSizeOf;
 
 use quickcheck::{Arbitrary, Gen};
+use rust_thrift::compact_protocol;
 
 use bonsai_changeset::BonsaiChangeset;
 use errors::*;
@@ -47,6 +47,15 @@ pub enum RepoPath {
     FilePath(MPath),
 }
 
+/// Encodings of serialized RepoPaths that `RepoPath::deserialize` reads
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RepoPathEncoding {
+    /// The versioned thrift envelope that `RepoPath::serialize` writes
+    Thrift,
+    /// The bincode encoding of older versions, which should be rewritten
+    LegacyBincode,
+}
+
 impl RepoPath {
     #[inline]
     pub fn root() -> Self {
@@ -133,16 +142,91 @@ impl RepoPath {
         }
     }
 
-    /// Serialize this RepoPath into a string. This shouldn't (yet) be considered stable if the
-    /// definition of RepoPath changes.
+    /// Serialize this RepoPath in a versioned thrift envelope
     pub fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).expect("serialize for RepoPath cannot fail")
+        let envelope = thrift::RepoPathEnvelope::V1(self.clone().into_thrift());
+        compact_protocol::serialize(&envelope).to_vec()
     }
 
-    /// Serialize this RepoPath into a writer. This shouldn't (yet) be considered stable if the
-    /// definition of RepoPath changes.
+    /// Serialize this RepoPath into a writer in a versioned thrift envelope
     pub fn serialize_into<W: Write>(&self, writer: &mut W) -> Result<()> {
-        Ok(bincode::serialize_into(writer, self)?)
+        Ok(writer.write_all(&self.serialize())?)
+    }
+
+    /// Deserialize a RepoPath that `serialize`, or an older version of it, wrote
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        Self::deserialize_with_encoding(data).map(|(path, _)| path)
+    }
+
+    /// Deserialize a RepoPath and tell which encoding it was in, so that callers can rewrite
+    /// legacy data. The thrift envelope is tried first: bincode data never decodes to a known
+    /// envelope version, as it starts with a zero byte or a bool field.
+    pub fn deserialize_with_encoding(data: &[u8]) -> Result<(Self, RepoPathEncoding)> {
+        match Self::deserialize_envelope(data) {
+            Ok(path) => Ok((path, RepoPathEncoding::Thrift)),
+            Err(err) => match Self::deserialize_bincode(data) {
+                Some(path) => Ok((path, RepoPathEncoding::LegacyBincode)),
+                None => Err(err),
+            },
+        }
+    }
+
+    fn deserialize_envelope(data: &[u8]) -> Result<Self> {
+        let envelope = compact_protocol::deserialize(data)
+            .chain_err(ErrorKind::BlobDeserializeError("RepoPath".into()))?;
+        match envelope {
+            thrift::RepoPathEnvelope::V1(path) => Self::from_thrift(path),
+            thrift::RepoPathEnvelope::UnknownField(unknown) => bail_msg!(
+                "Unknown field encountered when parsing thrift::RepoPathEnvelope: {}",
+                unknown,
+            ),
+        }
+    }
+
+    /// Decode the bincode encoding that RepoPaths were serialized with before the thrift
+    /// envelope: a little endian u32 variant index, then for non-root paths a u64 count of
+    /// elements, each a u64 length followed by its bytes.
+    fn deserialize_bincode(mut data: &[u8]) -> Option<Self> {
+        fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            if data.len() < len {
+                return None;
+            }
+            let (head, tail) = data.split_at(len);
+            *data = tail;
+            Some(head)
+        }
+
+        fn take_uint(data: &mut &[u8], size: usize) -> Option<u64> {
+            let bytes = take(data, size)?;
+            Some(
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, byte| (value << 8) | u64::from(*byte)),
+            )
+        }
+
+        fn take_mpath(data: &mut &[u8]) -> Option<thrift::MPath> {
+            let count = take_uint(data, 8)?;
+            let mut elements = Vec::new();
+            for _ in 0..count {
+                let len = take_uint(data, 8)?;
+                let element = take(data, len as usize)?;
+                elements.push(thrift::MPathElement(element.to_vec()));
+            }
+            Some(thrift::MPath(elements))
+        }
+
+        let path = match take_uint(&mut data, 4)? {
+            0 => thrift::RepoPath::RootPath(false),
+            1 => thrift::RepoPath::DirectoryPath(take_mpath(&mut data)?),
+            2 => thrift::RepoPath::FilePath(take_mpath(&mut data)?),
+            _ => return None,
+        };
+        if !data.is_empty() {
+            return None;
+        }
+        Self::from_thrift(path).ok()
     }
 
     pub fn from_thrift(path: thrift::RepoPath) -> Result<Self> {
@@ -890,6 +974,19 @@ mod test {
             p == p2
         }
 
+        fn repo_path_serialize_roundtrip(p: RepoPath) -> bool {
+            let (p2, encoding) = RepoPath::deserialize_with_encoding(&p.serialize())
+                .expect("deserializing a serialized RepoPath should always work");
+            p == p2 && encoding == RepoPathEncoding::Thrift
+        }
+
+        fn repo_path_legacy_bincode(p: RepoPath) -> bool {
+            let data = bincode::serialize(&p).expect("serialize for RepoPath cannot fail");
+            let (p2, encoding) = RepoPath::deserialize_with_encoding(&data)
+                .expect("deserializing a legacy RepoPath should always work");
+            p == p2 && encoding == RepoPathEncoding::LegacyBincode
+        }
+
         fn path_thrift_roundtrip(p: MPath) -> bool {
             let thrift_path = p.clone().into_thrift();
             let p2 = MPath::from_thrift(thrift_path)
@@ -12,7 +12,7 @@ use sql::mysql_async::{
     prelude::{ConvIr, FromValue},
     FromValueError, Value,
 };
-use typed_hash::ChangesetId;
+use typed_hash::{ChangesetId, ContentId};
 
 type FromValueResult<T> = ::std::result::Result<T, FromValueError>;
 
@@ -45,6 +45,35 @@ impl FromValue for ChangesetId {
     type Intermediate = Blake2;
 }
 
+impl From<ContentId> for Value {
+    fn from(id: ContentId) -> Self {
+        Value::Bytes(id.as_ref().into())
+    }
+}
+
+impl ConvIr<ContentId> for Blake2 {
+    fn new(v: Value) -> FromValueResult<Self> {
+        match v {
+            Value::Bytes(bytes) => {
+                Blake2::from_bytes(&bytes).map_err(move |_| FromValueError(Value::Bytes(bytes)))
+            }
+            v => Err(FromValueError(v)),
+        }
+    }
+
+    fn commit(self) -> ContentId {
+        ContentId::new(self)
+    }
+
+    fn rollback(self) -> Value {
+        Value::Bytes(self.as_ref().into())
+    }
+}
+
+impl FromValue for ContentId {
+    type Intermediate = Blake2;
+}
+
 impl From<Timestamp> for Value {
     fn from(ts: Timestamp) -> Self {
         Value::Int(ts.timestamp_nanos())
@@ -0,0 +1,210 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Guards against the Thrift schema in `if/mononoke_types_thrift.thrift` drifting away from the
+//! Rust types in this crate.
+//!
+//! Each function below destructures or matches a generated Thrift type exhaustively, without a
+//! `..` or `_` catch-all. If a field or union variant is added to the schema, the corresponding
+//! function stops compiling. Whoever adds the field then has to come here, and on the way
+//! update the `from_thrift`/`into_thrift` conversions so that the new field isn't silently
+//! dropped on the floor.
+//!
+//! None of these functions are meant to be called.
+
+use thrift;
+
+fn bonsai_changeset(tc: &thrift::BonsaiChangeset) {
+    let thrift::BonsaiChangeset {
+        parents: _,
+        author: _,
+        author_date: _,
+        committer: _,
+        committer_date: _,
+        message: _,
+        extra: _,
+        file_changes: _,
+    } = *tc;
+}
+
+fn date_time(dt: &thrift::DateTime) {
+    let thrift::DateTime {
+        timestamp_secs: _,
+        tz_offset_secs: _,
+    } = *dt;
+}
+
+fn file_change_opt(fc_opt: &thrift::FileChangeOpt) {
+    let thrift::FileChangeOpt { change: _ } = *fc_opt;
+}
+
+fn file_change(fc: &thrift::FileChange) {
+    let thrift::FileChange {
+        content_id: _,
+        file_type: _,
+        size: _,
+        copy_from: _,
+    } = *fc;
+}
+
+fn copy_info(ci: &thrift::CopyInfo) {
+    let thrift::CopyInfo { file: _, cs_id: _ } = *ci;
+}
+
+fn id_type(id: &thrift::IdType) {
+    match *id {
+        thrift::IdType::Blake2(_) => {}
+        thrift::IdType::UnknownField(_) => {}
+    }
+}
+
+fn repo_path(path: &thrift::RepoPath) {
+    match *path {
+        thrift::RepoPath::RootPath(_) => {}
+        thrift::RepoPath::DirectoryPath(_) => {}
+        thrift::RepoPath::FilePath(_) => {}
+        thrift::RepoPath::UnknownField(_) => {}
+    }
+}
+
+fn file_contents(fc: &thrift::FileContents) {
+    match *fc {
+        thrift::FileContents::Bytes(_) => {}
+        thrift::FileContents::Tombstone(_) => {}
+        thrift::FileContents::UnknownField(_) => {}
+    }
+}
+
+fn tombstone(t: &thrift::Tombstone) {
+    let thrift::Tombstone { reason: _ } = *t;
+}
+
+fn raw_bundle2(rb: &thrift::RawBundle2) {
+    match *rb {
+        thrift::RawBundle2::Bytes(_) => {}
+        thrift::RawBundle2::UnknownField(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use bincode;
+    use rust_thrift::compact_protocol;
+
+    use bonsai_changeset::{BonsaiChangeset, BonsaiChangesetMut};
+    use datetime::DateTime;
+    use file_change::{FileChange, FileType};
+    use path::MPath;
+    use typed_hash::{ChangesetId, ContentId};
+
+    /// An empty `binary` field with ID 100, which no struct in the schema uses, followed by the
+    /// struct's stop byte. The field header is in the long form (type byte followed by the
+    /// zigzag-encoded field ID), so it doesn't depend on the ID of the preceding field.
+    const UNKNOWN_FIELD: &[u8] = &[0x08, 0xc8, 0x01, 0x00, 0x00];
+
+    /// Simulate an older reader seeing data written by a newer writer: append a field this
+    /// schema doesn't know about to a serialized struct.
+    fn with_unknown_field<T: AsRef<[u8]>>(data: T) -> Vec<u8> {
+        let mut data = data.as_ref().to_vec();
+        assert_eq!(data.pop(), Some(0x00), "struct must end with a stop byte");
+        data.extend_from_slice(UNKNOWN_FIELD);
+        data
+    }
+
+    quickcheck! {
+        fn bonsai_changeset_ignores_unknown_fields(cs: BonsaiChangeset) -> bool {
+            let data = compact_protocol::serialize(&cs.clone().into_thrift());
+            let thrift_cs = compact_protocol::deserialize(with_unknown_field(data).as_slice())
+                .expect("unknown fields should be skipped");
+            let cs2 = BonsaiChangeset::from_thrift(thrift_cs)
+                .expect("changeset with unknown fields should still be valid");
+            cs == cs2
+        }
+
+        fn file_change_ignores_unknown_fields(fc: FileChange) -> bool {
+            let data = compact_protocol::serialize(&fc.clone().into_thrift());
+            let thrift_fc = compact_protocol::deserialize(with_unknown_field(data).as_slice())
+                .expect("unknown fields should be skipped");
+            let fc2 = FileChange::from_thrift(thrift_fc, &MPath::new("foo").unwrap())
+                .expect("file change with unknown fields should still be valid");
+            fc == fc2
+        }
+
+        fn datetime_ignores_unknown_fields(dt: DateTime) -> bool {
+            let data = compact_protocol::serialize(&dt.into_thrift());
+            let thrift_dt = compact_protocol::deserialize(with_unknown_field(data).as_slice())
+                .expect("unknown fields should be skipped");
+            let dt2 = DateTime::from_thrift(thrift_dt)
+                .expect("datetime with unknown fields should still be valid");
+            dt == dt2 && dt.tz_offset_secs() == dt2.tz_offset_secs()
+        }
+
+        fn changesetid_serde_roundtrip(id: ChangesetId) -> bool {
+            // IDs are serialized with serde as hex strings.
+            let data = bincode::serialize(&id).expect("serializing an ID cannot fail");
+            let hex: String = bincode::deserialize(&data).expect("IDs serialize as strings");
+            ChangesetId::from_str(&hex).expect("serialized ID should parse") == id
+        }
+
+        fn contentid_serde_roundtrip(id: ContentId) -> bool {
+            let data = bincode::serialize(&id).expect("serializing an ID cannot fail");
+            let hex: String = bincode::deserialize(&data).expect("IDs serialize as strings");
+            ContentId::from_str(&hex).expect("serialized ID should parse") == id
+        }
+    }
+
+    #[test]
+    fn datetime_fixed_bytes() {
+        // timestamp_secs = 1 (zigzag 0x02), tz_offset_secs = -3600 (zigzag 7199 = 0x9f 0x38).
+        let fixture: &[u8] = &[0x16, 0x02, 0x15, 0x9f, 0x38, 0x00];
+        let dt = DateTime::from_timestamp(1, -3600).unwrap();
+
+        assert_eq!(
+            compact_protocol::serialize(&dt.into_thrift()).as_ref(),
+            fixture
+        );
+        let dt2 = DateTime::from_thrift(compact_protocol::deserialize(fixture).unwrap())
+            .expect("fixture should deserialize");
+        assert_eq!(dt2.timestamp_secs(), 1);
+        assert_eq!(dt2.tz_offset_secs(), -3600);
+    }
+
+    #[test]
+    fn bonsai_changeset_fixed_blob_with_unknown_field() {
+        // Data written by a newer schema must decode to the same changeset.
+        let cs = BonsaiChangesetMut {
+            parents: vec![ChangesetId::from_byte_array([3; 32])],
+            author: "foo".into(),
+            author_date: DateTime::from_timestamp(1234567890, 36800).unwrap(),
+            committer: None,
+            committer_date: None,
+            message: "Commit message".into(),
+            extra: BTreeMap::new(),
+            file_changes: btreemap![
+                MPath::new("a/b").unwrap() => Some(FileChange::new(
+                    ContentId::from_byte_array([1; 32]),
+                    FileType::Symlink,
+                    42,
+                    None,
+                )),
+                MPath::new("c/d").unwrap() => None,
+            ],
+        }
+        .freeze()
+        .expect("fixed bonsai changeset must be valid");
+
+        let data = with_unknown_field(compact_protocol::serialize(&cs.clone().into_thrift()));
+        let thrift_cs = compact_protocol::deserialize(data.as_slice()).unwrap();
+        let cs2 = BonsaiChangeset::from_thrift(thrift_cs)
+            .expect("changeset with unknown fields should still be valid");
+
+        assert_eq!(cs, cs2);
+        // Re-serializing drops the unknown field, so the ID is that of the original changeset.
+        assert_eq!(cs.get_changeset_id(), cs2.get_changeset_id());
+    }
+}
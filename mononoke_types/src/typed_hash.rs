@@ -8,7 +8,6 @@ use std::fmt::{self, Display};
 use std::str::FromStr;
 
 use ascii::{AsciiStr, AsciiString};
-use asyncmemo;
 use quickcheck::{empty_shrinker, Arbitrary, Gen};
 use serde;
 
@@ -151,12 +150,6 @@ macro_rules! impl_typed_hash {
             }
         }
 
-        impl asyncmemo::Weight for $typed {
-            fn get_weight(&self) -> usize {
-                ::std::mem::size_of::<Blake2>()
-            }
-        }
-
         impl MononokeId for $typed {
             type Value = $value_type;
 
@@ -250,6 +243,13 @@ mod test {
                 .expect("converting a valid Thrift structure should always work");
             h == sh
         }
+
+        fn rawbundle2id_thrift_roundtrip(h: RawBundle2Id) -> bool {
+            let v = h.into_thrift();
+            let sh = RawBundle2Id::from_thrift(v)
+                .expect("converting a valid Thrift structure should always work");
+            h == sh
+        }
     }
 
     #[test]
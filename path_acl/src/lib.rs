@@ -0,0 +1,165 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Read permissions on parts of a repo.
+//!
+//! A path ACL restricts a file or directory, and everything under it, to some identities. Other
+//! identities can't read the contents of the files and trees it covers, whatever way they ask
+//! for them. The names and hashes of restricted entries still show up in the trees above them
+//! and in the file lists of changesets, as those are hashed and can't be changed without
+//! breaking the clients that verify them.
+//!
+//! Hashes are not bound to paths, so an identity that can't read everything may only fetch a
+//! node by path if the node is known to be at that path. Servers check that against the
+//! filenodes of the repo before they serve the node.
+
+#![deny(warnings)]
+
+use std::sync::Arc;
+
+use failure_ext::Error;
+use mercurial_types::manifest_utils::{ChangedEntry, Pruner};
+use mercurial_types::MPath;
+use metaconfig_types::PathAclParams;
+
+/// A restricted path, and who may read it
+struct PathAcl {
+    path: MPath,
+    identities: Vec<String>,
+}
+
+/// The path ACLs of a repo
+#[derive(Clone, Default)]
+pub struct PathAcls {
+    acls: Arc<Vec<PathAcl>>,
+}
+
+impl PathAcls {
+    pub fn new(params: &[PathAclParams]) -> Result<Self, Error> {
+        let acls = params
+            .iter()
+            .map(|params| {
+                Ok(PathAcl {
+                    path: MPath::new(&params.path)?,
+                    identities: params.identities.clone(),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self {
+            acls: Arc::new(acls),
+        })
+    }
+
+    /// What a client that is all of `identities` may read. A path restricted by several ACLs
+    /// may only be read by identities that are in all of them.
+    pub fn access<'a, I>(&self, identities: I) -> PathAccess
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let identities: Vec<_> = identities.into_iter().collect();
+        let denied = self
+            .acls
+            .iter()
+            .filter(|acl| {
+                !acl.identities
+                    .iter()
+                    .any(|identity| identities.contains(&identity.as_str()))
+            })
+            .map(|acl| acl.path.clone())
+            .collect();
+        PathAccess {
+            denied: Arc::new(denied),
+        }
+    }
+}
+
+/// The paths a client can't read
+#[derive(Clone, Debug, Default)]
+pub struct PathAccess {
+    denied: Arc<Vec<MPath>>,
+}
+
+impl PathAccess {
+    /// Whether every path of the repo may be read
+    pub fn is_unrestricted(&self) -> bool {
+        self.denied.is_empty()
+    }
+
+    /// Whether the file or tree at `path` may be read. The root of the repo may always be
+    /// read, but the entries it lists may not.
+    pub fn can_read(&self, path: Option<&MPath>) -> bool {
+        match path {
+            Some(path) => !self.denied.iter().any(|denied| denied.is_prefix_of(path)),
+            None => true,
+        }
+    }
+
+    /// Prunes the entries that can't be read from a walk of manifests, along with everything
+    /// under them
+    pub fn pruner(&self) -> PathAccessPruner {
+        PathAccessPruner {
+            access: self.clone(),
+        }
+    }
+}
+
+/// Keeps the entries of a walk of manifests that can be read
+#[derive(Clone)]
+pub struct PathAccessPruner {
+    access: PathAccess,
+}
+
+impl Pruner for PathAccessPruner {
+    fn keep(&mut self, entry: &ChangedEntry) -> bool {
+        self.access.can_read(entry.get_full_path().as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(path: &str) -> Option<MPath> {
+        Some(MPath::new(path).unwrap())
+    }
+
+    fn acls() -> PathAcls {
+        PathAcls::new(&[
+            PathAclParams {
+                path: "secret".to_string(),
+                identities: vec!["alice".to_string(), "bob".to_string()],
+            },
+            PathAclParams {
+                path: "secret/hr".to_string(),
+                identities: vec!["bob".to_string()],
+            },
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_access() {
+        let acls = acls();
+
+        let everyone = acls.access(vec!["carol"]);
+        assert!(!everyone.is_unrestricted());
+        assert!(everyone.can_read(None));
+        assert!(everyone.can_read(path("src/main.rs").as_ref()));
+        assert!(everyone.can_read(path("secrets").as_ref()));
+        assert!(!everyone.can_read(path("secret").as_ref()));
+        assert!(!everyone.can_read(path("secret/plans.txt").as_ref()));
+
+        let alice = acls.access(vec!["alice"]);
+        assert!(alice.can_read(path("secret/plans.txt").as_ref()));
+        assert!(!alice.can_read(path("secret/hr/salaries.txt").as_ref()));
+
+        let bob = acls.access(vec!["bob", "svc-bob"]);
+        assert!(bob.is_unrestricted());
+        assert!(bob.can_read(path("secret/hr/salaries.txt").as_ref()));
+
+        assert!(PathAcls::default().access(vec![]).is_unrestricted());
+    }
+}
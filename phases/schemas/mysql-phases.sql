@@ -0,0 +1,6 @@
+CREATE TABLE phases (
+  repo_id INT UNSIGNED NOT NULL,
+  cs_id VARBINARY(32) NOT NULL,
+  phase ENUM('Draft', 'Public') NOT NULL,
+  PRIMARY KEY (repo_id, cs_id)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -232,7 +232,7 @@ impl Phases for CachingHintPhases {
                                     phases_reachability_hint
                                         .get_all(
                                             ctx,
-                                            repo.get_changeset_fetcher(),
+                                            repo.get_changeset_fetcher_for("phases"),
                                             not_found_in_db,
                                             public_heads.clone(),
                                         )
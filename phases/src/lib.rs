@@ -62,6 +62,7 @@ use sql::mysql_async::{
     FromValueError, Value,
 };
 use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
 pub use sql_ext::SqlConstructors;
 
 type FromValueResult<T> = ::std::result::Result<T, FromValueError>;
@@ -255,6 +256,30 @@ impl SqlConstructors for SqlPhases {
     }
 }
 
+impl SqlMigrations for SqlPhases {
+    const STORE: &'static str = "phases";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the phases table",
+            sqlite: "CREATE TABLE phases (
+                       repo_id INTEGER(11) NOT NULL,
+                       cs_id VARBINARY(32) NOT NULL,
+                       --There is no enum type in SQLite
+                       phase TEXT NOT NULL,
+                       PRIMARY KEY (repo_id, cs_id)
+                     );",
+            mysql: "CREATE TABLE phases (
+                      repo_id INT UNSIGNED NOT NULL,
+                      cs_id VARBINARY(32) NOT NULL,
+                      phase ENUM('Draft', 'Public') NOT NULL,
+                      PRIMARY KEY (repo_id, cs_id)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
 impl Phases for SqlPhases {
     /// Add a new entry to the phases sql table. Returns true if a new changeset was inserted or the phase has been changed,
     /// returns false if the phase hasn't been changed for the changeset.
@@ -479,7 +504,7 @@ impl Phases for HintPhases {
                             phases_reachability_hint
                                 .get_all(
                                     ctx,
-                                    repo.get_changeset_fetcher(),
+                                    repo.get_changeset_fetcher_for("phases"),
                                     not_found_in_db,
                                     public_heads.clone(),
                                 )
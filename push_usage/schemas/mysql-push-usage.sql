@@ -0,0 +1,11 @@
+CREATE TABLE `push_usage` (
+  `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `identity` VARCHAR(255) NOT NULL,
+  `pushed_at` BIGINT NOT NULL,
+  `bytes` BIGINT UNSIGNED NOT NULL,
+  `files` BIGINT UNSIGNED NOT NULL,
+  PRIMARY KEY (`id`),
+  KEY `push_usage_repo_identity_time` (`repo_id`, `identity`, `pushed_at`),
+  KEY `push_usage_repo_time` (`repo_id`, `pushed_at`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,11 @@
+CREATE TABLE `push_usage` (
+  `id` INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `identity` VARCHAR(255) NOT NULL,
+  `pushed_at` BIGINT NOT NULL,
+  `bytes` BIGINT UNSIGNED NOT NULL,
+  `files` BIGINT UNSIGNED NOT NULL
+);
+
+CREATE INDEX `push_usage_repo_identity_time` ON `push_usage` (`repo_id`, `identity`, `pushed_at`);
+CREATE INDEX `push_usage_repo_time` ON `push_usage` (`repo_id`, `pushed_at`);
@@ -0,0 +1,302 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Who grows a repo: the bytes and files that each user pushes to it, and quotas on them.
+//!
+//! Every push is recorded in SQL under the user that pushed it. Quotas are checked against what
+//! the user, or their team, pushed over the last days: a push over the soft limit is accepted
+//! with a warning, a push over the hard limit is rejected.
+
+#![deny(warnings)]
+
+extern crate chrono;
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate context;
+extern crate futures_ext;
+extern crate metaconfig_types;
+extern crate mononoke_types;
+#[macro_use]
+extern crate sql;
+extern crate sql_ext;
+#[macro_use]
+extern crate stats;
+
+mod quota;
+
+use context::CoreContext;
+use failure::Error;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use mononoke_types::{DateTime, RepositoryId, Timestamp};
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+pub use sql_ext::SqlConstructors;
+use stats::Timeseries;
+use std::sync::Arc;
+
+pub use quota::{PushQuota, QuotaCheck, TeamUsage, UsageReport};
+
+define_stats! {
+    prefix = "mononoke.push_usage";
+    adds: timeseries(RATE, SUM),
+    gets: timeseries(RATE, SUM),
+}
+
+/// What one push added to a repo
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PushUsage {
+    pub repo_id: RepositoryId,
+    pub identity: String,
+    pub pushed_at: DateTime,
+    /// Bytes of file content, including the content of LFS files
+    pub bytes: u64,
+    /// File revisions
+    pub files: u64,
+}
+
+/// What one user pushed to a repo over some time
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IdentityUsage {
+    pub identity: String,
+    pub bytes: u64,
+    pub files: u64,
+    pub pushes: u64,
+}
+
+pub trait PushUsageStore: Send + Sync {
+    fn add(&self, ctx: CoreContext, usage: PushUsage) -> BoxFuture<(), Error>;
+
+    /// What each user pushed to the repo since `since`, the ones who pushed the most bytes first
+    fn get_usage(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        since: DateTime,
+    ) -> BoxFuture<Vec<IdentityUsage>, Error>;
+
+    /// The bytes that `identities` pushed to the repo since `since`, all together
+    fn get_pushed_bytes(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        identities: Vec<String>,
+        since: DateTime,
+    ) -> BoxFuture<u64, Error>;
+
+    /// When the last push to the repo was, if there was one
+    fn get_last_push(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Option<DateTime>, Error>;
+}
+
+impl PushUsageStore for Arc<PushUsageStore> {
+    fn add(&self, ctx: CoreContext, usage: PushUsage) -> BoxFuture<(), Error> {
+        (**self).add(ctx, usage)
+    }
+
+    fn get_usage(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        since: DateTime,
+    ) -> BoxFuture<Vec<IdentityUsage>, Error> {
+        (**self).get_usage(ctx, repo_id, since)
+    }
+
+    fn get_pushed_bytes(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        identities: Vec<String>,
+        since: DateTime,
+    ) -> BoxFuture<u64, Error> {
+        (**self).get_pushed_bytes(ctx, repo_id, identities, since)
+    }
+
+    fn get_last_push(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Option<DateTime>, Error> {
+        (**self).get_last_push(ctx, repo_id)
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlPushUsageStore {
+    write_connection: Connection,
+    read_connection: Connection,
+}
+
+queries! {
+    write InsertUsage(values: (
+        repo_id: RepositoryId,
+        identity: str,
+        pushed_at: Timestamp,
+        bytes: u64,
+        files: u64,
+    )) {
+        none,
+        "INSERT INTO push_usage (repo_id, identity, pushed_at, bytes, files)
+         VALUES {values}"
+    }
+
+    read SelectUsage(repo_id: RepositoryId, since: Timestamp) -> (String, u64, u64, u64) {
+        "SELECT identity, SUM(bytes), SUM(files), COUNT(*)
+         FROM push_usage
+         WHERE repo_id = {repo_id} AND pushed_at >= {since}
+         GROUP BY identity
+         ORDER BY SUM(bytes) DESC, identity"
+    }
+
+    read SelectPushedBytes(
+        repo_id: RepositoryId,
+        since: Timestamp,
+        >list identities: String
+    ) -> (u64) {
+        "SELECT COALESCE(SUM(bytes), 0)
+         FROM push_usage
+         WHERE repo_id = {repo_id} AND pushed_at >= {since} AND identity IN {identities}"
+    }
+
+    read SelectLastPush(repo_id: RepositoryId) -> (Timestamp) {
+        "SELECT pushed_at
+         FROM push_usage
+         WHERE repo_id = {repo_id}
+         ORDER BY pushed_at DESC
+         LIMIT 1"
+    }
+}
+
+impl SqlConstructors for SqlPushUsageStore {
+    fn from_connections(
+        write_connection: Connection,
+        read_connection: Connection,
+        _read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-push-usage.sql")
+    }
+}
+
+impl SqlMigrations for SqlPushUsageStore {
+    const STORE: &'static str = "push_usage";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the push_usage table",
+            sqlite: "CREATE TABLE `push_usage` (
+                       `id` INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                       `repo_id` INT UNSIGNED NOT NULL,
+                       `identity` VARCHAR(255) NOT NULL,
+                       `pushed_at` BIGINT NOT NULL,
+                       `bytes` BIGINT UNSIGNED NOT NULL,
+                       `files` BIGINT UNSIGNED NOT NULL
+                     );
+
+                     CREATE INDEX `push_usage_repo_identity_time` ON `push_usage` (`repo_id`, `identity`, `pushed_at`);
+                     CREATE INDEX `push_usage_repo_time` ON `push_usage` (`repo_id`, `pushed_at`);",
+            mysql: "CREATE TABLE `push_usage` (
+                      `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+                      `repo_id` INT UNSIGNED NOT NULL,
+                      `identity` VARCHAR(255) NOT NULL,
+                      `pushed_at` BIGINT NOT NULL,
+                      `bytes` BIGINT UNSIGNED NOT NULL,
+                      `files` BIGINT UNSIGNED NOT NULL,
+                      PRIMARY KEY (`id`),
+                      KEY `push_usage_repo_identity_time` (`repo_id`, `identity`, `pushed_at`),
+                      KEY `push_usage_repo_time` (`repo_id`, `pushed_at`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+impl PushUsageStore for SqlPushUsageStore {
+    fn add(&self, _ctx: CoreContext, usage: PushUsage) -> BoxFuture<(), Error> {
+        STATS::adds.add_value(1);
+
+        let pushed_at = Timestamp::from(usage.pushed_at);
+        InsertUsage::query(
+            &self.write_connection,
+            &[(
+                &usage.repo_id,
+                usage.identity.as_str(),
+                &pushed_at,
+                &usage.bytes,
+                &usage.files,
+            )],
+        )
+        .map(|_| ())
+        .boxify()
+    }
+
+    fn get_usage(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        since: DateTime,
+    ) -> BoxFuture<Vec<IdentityUsage>, Error> {
+        STATS::gets.add_value(1);
+
+        SelectUsage::query(&self.read_connection, &repo_id, &Timestamp::from(since))
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(identity, bytes, files, pushes)| IdentityUsage {
+                        identity,
+                        bytes,
+                        files,
+                        pushes,
+                    })
+                    .collect()
+            })
+            .boxify()
+    }
+
+    fn get_pushed_bytes(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        identities: Vec<String>,
+        since: DateTime,
+    ) -> BoxFuture<u64, Error> {
+        STATS::gets.add_value(1);
+
+        if identities.is_empty() {
+            return future::ok(0).boxify();
+        }
+        SelectPushedBytes::query(
+            &self.read_connection,
+            &repo_id,
+            &Timestamp::from(since),
+            &identities[..],
+        )
+        .map(|rows| rows.into_iter().next().map_or(0, |(bytes,)| bytes))
+        .boxify()
+    }
+
+    fn get_last_push(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Option<DateTime>, Error> {
+        STATS::gets.add_value(1);
+
+        SelectLastPush::query(&self.read_connection, &repo_id)
+            .map(|rows| rows.into_iter().next().map(|(pushed_at,)| pushed_at.into()))
+            .boxify()
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+
+use chrono::Duration;
+use context::CoreContext;
+use failure::Error;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use metaconfig_types::{PushQuotaLimits, PushQuotaParams};
+use mononoke_types::{DateTime, RepositoryId};
+
+use {IdentityUsage, PushUsage, PushUsageStore};
+
+/// How a push compares to the quota of its pusher
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum QuotaCheck {
+    /// The push fits in the quota, or there is no quota
+    WithinQuota,
+    /// The push goes over the soft limit. It is accepted, and the message warns the pusher
+    OverSoftLimit(String),
+    /// The push goes over the hard limit, and is rejected with the message
+    OverHardLimit(String),
+}
+
+/// What a team pushed over the quota window, and its limits
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TeamUsage {
+    pub name: String,
+    pub bytes: u64,
+    pub files: u64,
+    pub pushes: u64,
+    pub limits: PushQuotaLimits,
+}
+
+/// What users and teams pushed to a repo over the quota window
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UsageReport {
+    pub window_days: u64,
+    /// Users, the ones who pushed the most bytes first
+    pub identities: Vec<IdentityUsage>,
+    pub teams: Vec<TeamUsage>,
+}
+
+/// The push quotas of a repo, and the store of the pushes they are checked against
+#[derive(Clone)]
+pub struct PushQuota {
+    repo_id: RepositoryId,
+    params: PushQuotaParams,
+    store: Arc<PushUsageStore>,
+}
+
+impl PushQuota {
+    pub fn new(repo_id: RepositoryId, params: PushQuotaParams, store: Arc<PushUsageStore>) -> Self {
+        Self {
+            repo_id,
+            params,
+            store,
+        }
+    }
+
+    /// The user that pushes in `ctx`, if known: the first of their identities
+    pub fn identity(ctx: &CoreContext) -> Option<String> {
+        ctx.user_identities().into_iter().next()
+    }
+
+    /// Compare a push of `bytes` by `identity` to the quota of `identity`
+    pub fn check(
+        &self,
+        ctx: CoreContext,
+        identity: &str,
+        bytes: u64,
+    ) -> BoxFuture<QuotaCheck, Error> {
+        let (owner, members, limits) = self.quota_of(identity);
+        if limits.soft_limit_bytes.is_none() && limits.hard_limit_bytes.is_none() {
+            return future::ok(QuotaCheck::WithinQuota).boxify();
+        }
+
+        let since = self.window_start(&ctx);
+        let window_days = self.params.window_days;
+        self.store
+            .get_pushed_bytes(ctx, self.repo_id, members, since)
+            .map(move |pushed| check_limits(&owner, limits, window_days, pushed, bytes))
+            .boxify()
+    }
+
+    /// Record that `identity` pushed `bytes` in `files` file revisions
+    pub fn record(
+        &self,
+        ctx: CoreContext,
+        identity: String,
+        bytes: u64,
+        files: u64,
+    ) -> BoxFuture<(), Error> {
+        let usage = PushUsage {
+            repo_id: self.repo_id,
+            identity,
+            pushed_at: DateTime::new(ctx.now()),
+            bytes,
+            files,
+        };
+        self.store.add(ctx, usage)
+    }
+
+    /// What each user and team pushed over the quota window
+    pub fn report(&self, ctx: CoreContext) -> BoxFuture<UsageReport, Error> {
+        let since = self.window_start(&ctx);
+        let params = self.params.clone();
+        self.store
+            .get_usage(ctx, self.repo_id, since)
+            .map(move |identities| {
+                let teams = params
+                    .teams
+                    .iter()
+                    .map(|team| {
+                        let mut usage = TeamUsage {
+                            name: team.name.clone(),
+                            bytes: 0,
+                            files: 0,
+                            pushes: 0,
+                            limits: team.limits,
+                        };
+                        for member in identities
+                            .iter()
+                            .filter(|member| team.members.contains(&member.identity))
+                        {
+                            usage.bytes += member.bytes;
+                            usage.files += member.files;
+                            usage.pushes += member.pushes;
+                        }
+                        usage
+                    })
+                    .collect();
+                UsageReport {
+                    window_days: params.window_days,
+                    identities,
+                    teams,
+                }
+            })
+            .boxify()
+    }
+
+    // Who owns the quota of `identity` (its team, or itself), the users who share it and its
+    // limits
+    fn quota_of(&self, identity: &str) -> (String, Vec<String>, PushQuotaLimits) {
+        let team = self
+            .params
+            .teams
+            .iter()
+            .find(|team| team.members.iter().any(|member| member == identity));
+        match team {
+            Some(team) => (
+                format!("team {}", team.name),
+                team.members.clone(),
+                team.limits,
+            ),
+            None => (
+                identity.to_string(),
+                vec![identity.to_string()],
+                self.params.default_limits,
+            ),
+        }
+    }
+
+    fn window_start(&self, ctx: &CoreContext) -> DateTime {
+        DateTime::new(ctx.now() - Duration::days(self.params.window_days as i64))
+    }
+}
+
+fn check_limits(
+    owner: &str,
+    limits: PushQuotaLimits,
+    window_days: u64,
+    pushed: u64,
+    bytes: u64,
+) -> QuotaCheck {
+    let total = pushed + bytes;
+    if let Some(hard_limit) = limits.hard_limit_bytes {
+        if total > hard_limit {
+            return QuotaCheck::OverHardLimit(format!(
+                "{} pushed {} bytes over the last {} days, this push of {} bytes would go over \
+                 the hard limit of {} bytes",
+                owner, pushed, window_days, bytes, hard_limit
+            ));
+        }
+    }
+    if let Some(soft_limit) = limits.soft_limit_bytes {
+        if total > soft_limit {
+            return QuotaCheck::OverSoftLimit(format!(
+                "{} pushed {} bytes over the last {} days, over the soft limit of {} bytes",
+                owner, total, window_days, soft_limit
+            ));
+        }
+    }
+    QuotaCheck::WithinQuota
+}
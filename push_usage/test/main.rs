@@ -0,0 +1,187 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for push usage accounting and quotas.
+
+#![deny(warnings)]
+
+extern crate chrono;
+extern crate context;
+extern crate metaconfig_types;
+extern crate mononoke_types;
+extern crate push_usage;
+extern crate tokio;
+
+use std::sync::Arc;
+
+use context::{CoreContext, MockTimeUuidProvider};
+use metaconfig_types::{PushQuotaLimits, PushQuotaParams, PushQuotaTeam};
+use mononoke_types::{DateTime, RepositoryId};
+use push_usage::{
+    IdentityUsage, PushQuota, PushUsage, PushUsageStore, QuotaCheck, SqlConstructors,
+    SqlPushUsageStore, TeamUsage,
+};
+use tokio::runtime::Runtime;
+
+fn usage(repo_id: RepositoryId, identity: &str, pushed_at: &str, bytes: u64) -> PushUsage {
+    PushUsage {
+        repo_id,
+        identity: identity.to_string(),
+        pushed_at: DateTime::from_rfc3339(pushed_at).unwrap(),
+        bytes,
+        files: 1,
+    }
+}
+
+#[test]
+fn test_usage() {
+    let mut rt = Runtime::new().unwrap();
+
+    let ctx = CoreContext::test_mock();
+    let store = SqlPushUsageStore::with_sqlite_in_memory().unwrap();
+    let repo_id = RepositoryId::new(137);
+    let other_repo_id = RepositoryId::new(138);
+
+    let pushes = vec![
+        usage(repo_id, "alice", "2019-03-01T12:00:00.00Z", 100),
+        usage(repo_id, "alice", "2019-03-02T12:00:00.00Z", 50),
+        usage(repo_id, "bob", "2019-03-02T12:00:00.00Z", 200),
+        usage(repo_id, "carol", "2019-02-01T12:00:00.00Z", 1000),
+        usage(other_repo_id, "alice", "2019-03-02T12:00:00.00Z", 1000),
+    ];
+    for push in pushes {
+        rt.block_on(store.add(ctx.clone(), push))
+            .expect("Adding usage failed");
+    }
+
+    let since = DateTime::from_rfc3339("2019-03-01T00:00:00.00Z").unwrap();
+    let usage = rt
+        .block_on(store.get_usage(ctx.clone(), repo_id, since))
+        .expect("Getting usage failed");
+    assert_eq!(
+        usage,
+        vec![
+            IdentityUsage {
+                identity: "bob".to_string(),
+                bytes: 200,
+                files: 1,
+                pushes: 1,
+            },
+            IdentityUsage {
+                identity: "alice".to_string(),
+                bytes: 150,
+                files: 2,
+                pushes: 2,
+            },
+        ]
+    );
+
+    let pushed_bytes = |rt: &mut Runtime, identities: Vec<&str>| {
+        let identities = identities.into_iter().map(String::from).collect();
+        rt.block_on(store.get_pushed_bytes(ctx.clone(), repo_id, identities, since))
+            .expect("Getting pushed bytes failed")
+    };
+    assert_eq!(pushed_bytes(&mut rt, vec!["alice", "bob"]), 350);
+    // pushes before `since` don't count
+    assert_eq!(pushed_bytes(&mut rt, vec!["carol"]), 0);
+    assert_eq!(pushed_bytes(&mut rt, vec![]), 0);
+
+    let last_push = rt
+        .block_on(store.get_last_push(ctx.clone(), repo_id))
+        .expect("Getting the last push failed");
+    assert_eq!(
+        last_push,
+        Some(DateTime::from_rfc3339("2019-03-02T12:00:00.00Z").unwrap())
+    );
+    let last_push = rt
+        .block_on(store.get_last_push(ctx.clone(), RepositoryId::new(139)))
+        .expect("Getting the last push failed");
+    assert_eq!(last_push, None);
+}
+
+#[test]
+fn test_quota() {
+    let mut rt = Runtime::new().unwrap();
+
+    let now = chrono::DateTime::parse_from_rfc3339("2019-03-10T12:00:00+00:00").unwrap();
+    let ctx =
+        CoreContext::test_mock().with_time_uuid_provider(Arc::new(MockTimeUuidProvider::new(now)));
+    let store = Arc::new(SqlPushUsageStore::with_sqlite_in_memory().unwrap());
+    let repo_id = RepositoryId::new(137);
+
+    let team_limits = PushQuotaLimits {
+        soft_limit_bytes: Some(1000),
+        hard_limit_bytes: Some(2000),
+    };
+    let quota = PushQuota::new(
+        repo_id,
+        PushQuotaParams {
+            window_days: 7,
+            default_limits: PushQuotaLimits {
+                soft_limit_bytes: Some(100),
+                hard_limit_bytes: None,
+            },
+            teams: vec![PushQuotaTeam {
+                name: "infra".to_string(),
+                members: vec!["alice".to_string(), "bob".to_string()],
+                limits: team_limits,
+            }],
+        },
+        store.clone(),
+    );
+
+    // Out of the window
+    rt.block_on(store.add(
+        ctx.clone(),
+        usage(repo_id, "alice", "2019-03-01T12:00:00.00Z", 10000),
+    ))
+    .unwrap();
+    rt.block_on(quota.record(ctx.clone(), "alice".to_string(), 800, 3))
+        .unwrap();
+    rt.block_on(quota.record(ctx.clone(), "bob".to_string(), 700, 2))
+        .unwrap();
+
+    let check = |rt: &mut Runtime, identity: &str, bytes: u64| {
+        rt.block_on(quota.check(ctx.clone(), identity, bytes))
+            .expect("Checking quota failed")
+    };
+    // Team members share the quota of the team
+    match check(&mut rt, "bob", 100) {
+        QuotaCheck::OverSoftLimit(msg) => assert!(msg.contains("team infra"), msg),
+        other => panic!("unexpected {:?}", other),
+    }
+    match check(&mut rt, "alice", 600) {
+        QuotaCheck::OverHardLimit(msg) => assert!(msg.contains("hard limit of 2000"), msg),
+        other => panic!("unexpected {:?}", other),
+    }
+    // Others have the default limits, which have no hard limit
+    assert_eq!(check(&mut rt, "carol", 100), QuotaCheck::WithinQuota);
+    match check(&mut rt, "carol", 101) {
+        QuotaCheck::OverSoftLimit(msg) => assert!(msg.starts_with("carol"), msg),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let report = rt.block_on(quota.report(ctx.clone())).unwrap();
+    assert_eq!(report.window_days, 7);
+    assert_eq!(
+        report
+            .identities
+            .iter()
+            .map(|usage| (usage.identity.as_str(), usage.bytes))
+            .collect::<Vec<_>>(),
+        vec![("alice", 800), ("bob", 700)]
+    );
+    assert_eq!(
+        report.teams,
+        vec![TeamUsage {
+            name: "infra".to_string(),
+            bytes: 1500,
+            files: 5,
+            pushes: 2,
+            limits: team_limits,
+        }]
+    );
+}
@@ -0,0 +1,426 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Quality of service for the traffic to a repo.
+//!
+//! Requests are either interactive, when a person waits for them, or batch, when automation
+//! does. Each class is served from its own pool, with its own limit on the requests served at
+//! the same time and its own timeout, so that a flood of batch requests queues up in the batch
+//! pool instead of slowing down interactive ones.
+//!
+//! Clients may declare the class of their requests (the `qos` entry of the wireproto preamble,
+//! the `x-mononoke-qos` header of the apiserver). Requests that don't are classified by the
+//! identity they come from, if the server knows it, and are interactive otherwise.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate stats;
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use failure_ext::{err_msg, Error};
+use futures::sync::oneshot;
+use futures::{future, Async, Future, Poll, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use metaconfig_types::{QosClass, QosLimits, QosParams};
+use stats::Timeseries;
+
+/// Header of apiserver requests that declares their class
+pub const QOS_HEADER: &str = "x-mononoke-qos";
+
+define_stats_struct! {
+    QosStats("mononoke.qos.{}", class: String),
+    requests: timeseries(RATE, SUM),
+    waits_for_slot: timeseries(RATE, SUM),
+}
+
+/// The class named `name`, as declared by clients
+pub fn parse_qos_class(name: &str) -> Option<QosClass> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "interactive" => Some(QosClass::Interactive),
+        "batch" => Some(QosClass::Batch),
+        _ => None,
+    }
+}
+
+fn class_name(class: QosClass) -> &'static str {
+    match class {
+        QosClass::Interactive => "interactive",
+        QosClass::Batch => "batch",
+    }
+}
+
+/// The pools that the traffic to one repo is served from
+pub struct QosPools {
+    batch_identities: HashSet<String>,
+    interactive: QosPool,
+    batch: QosPool,
+}
+
+impl QosPools {
+    pub fn new(params: &QosParams) -> Self {
+        Self {
+            batch_identities: params.batch_identities.iter().cloned().collect(),
+            interactive: QosPool::new(QosClass::Interactive, &params.interactive),
+            batch: QosPool::new(QosClass::Batch, &params.batch),
+        }
+    }
+
+    /// The class of a request that declared `declared`, and that comes from `identities`.
+    /// Declarations that aren't a known class are ignored.
+    pub fn classify<'a, I>(&self, declared: Option<&str>, identities: I) -> QosClass
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        if let Some(class) = declared.and_then(parse_qos_class) {
+            return class;
+        }
+        if identities
+            .into_iter()
+            .any(|identity| self.batch_identities.contains(identity))
+        {
+            return QosClass::Batch;
+        }
+        QosClass::Interactive
+    }
+
+    /// The pool that requests of `class` are served from
+    pub fn pool(&self, class: QosClass) -> QosPool {
+        match class {
+            QosClass::Interactive => self.interactive.clone(),
+            QosClass::Batch => self.batch.clone(),
+        }
+    }
+}
+
+impl Default for QosPools {
+    /// Pools without limits, for servers that don't configure any
+    fn default() -> Self {
+        Self::new(&QosParams::default())
+    }
+}
+
+/// The pool of one class of requests. Clones share the same pool.
+#[derive(Clone)]
+pub struct QosPool {
+    class: QosClass,
+    slots: Option<Arc<Slots>>,
+    timeout: Option<Duration>,
+    stats: Arc<QosStats>,
+}
+
+impl QosPool {
+    fn new(class: QosClass, limits: &QosLimits) -> Self {
+        Self {
+            class,
+            slots: limits
+                .max_concurrency
+                .map(|max_concurrency| Arc::new(Slots::new(max_concurrency))),
+            timeout: limits.timeout_secs.map(Duration::from_secs),
+            stats: Arc::new(QosStats::new(class_name(class).to_string())),
+        }
+    }
+
+    pub fn class(&self) -> QosClass {
+        self.class
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class)
+    }
+
+    /// The time a request served from this pool may take, if the pool sets one
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The time a request served from this pool may take, `default` if the pool sets none
+    pub fn timeout_or(&self, default: Duration) -> Duration {
+        self.timeout.unwrap_or(default)
+    }
+
+    /// Whether the pool is shedding load: all its slots are taken, so new requests wait for one
+    pub fn is_saturated(&self) -> bool {
+        match self.slots {
+            Some(ref slots) => slots.state.lock().expect("lock poisoned").free == 0,
+            None => false,
+        }
+    }
+
+    /// Run `future` once the pool has a free slot, which it holds until it's done
+    pub fn run<F>(&self, future: F) -> BoxFuture<F::Item, Error>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        self.acquire()
+            .and_then(move |slot| {
+                future.then(move |res| {
+                    drop(slot);
+                    res
+                })
+            })
+            .boxify()
+    }
+
+    /// Run `stream` once the pool has a free slot, which it holds until the stream ends or is
+    /// dropped
+    pub fn run_stream<S>(&self, stream: S) -> BoxStream<S::Item, Error>
+    where
+        S: Stream<Error = Error> + Send + 'static,
+        S::Item: Send + 'static,
+    {
+        self.acquire()
+            .map(move |slot| WithSlot { stream, slot })
+            .flatten_stream()
+            .boxify()
+    }
+
+    fn acquire(&self) -> BoxFuture<Option<Slot>, Error> {
+        self.stats.requests.add_value(1);
+        match self.slots {
+            Some(ref slots) => Slots::acquire(slots, &self.stats).map(Some).boxify(),
+            None => future::ok(None).boxify(),
+        }
+    }
+}
+
+// A stream that gives its slot back once it's over
+struct WithSlot<S> {
+    stream: S,
+    slot: Option<Slot>,
+}
+
+impl<S: Stream> Stream for WithSlot<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let res = self.stream.poll();
+        match res {
+            Ok(Async::Ready(None)) | Err(_) => {
+                self.slot.take();
+            }
+            _ => {}
+        }
+        res
+    }
+}
+
+// Free slots of a pool, and requests waiting for one
+struct Slots {
+    state: Mutex<SlotsState>,
+}
+
+struct SlotsState {
+    free: usize,
+    waiting: VecDeque<oneshot::Sender<Slot>>,
+}
+
+impl Slots {
+    fn new(max: usize) -> Self {
+        Self {
+            state: Mutex::new(SlotsState {
+                free: max,
+                waiting: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn acquire(slots: &Arc<Self>, stats: &QosStats) -> BoxFuture<Slot, Error> {
+        let mut state = slots.state.lock().expect("lock poisoned");
+        if state.free > 0 {
+            state.free -= 1;
+            return future::ok(Slot {
+                slots: Some(slots.clone()),
+            })
+            .boxify();
+        }
+
+        stats.waits_for_slot.add_value(1);
+        let (sender, receiver) = oneshot::channel();
+        state.waiting.push_back(sender);
+        receiver
+            .map_err(|_| err_msg("qos slots were dropped"))
+            .boxify()
+    }
+
+    fn release(slots: &Arc<Self>) {
+        let mut state = slots.state.lock().expect("lock poisoned");
+        let mut slot = Slot {
+            slots: Some(slots.clone()),
+        };
+        // Requests that stopped waiting have dropped their receiver, and give the slot back
+        while let Some(waiting) = state.waiting.pop_front() {
+            match waiting.send(slot) {
+                Ok(()) => return,
+                Err(unsent) => slot = unsent,
+            }
+        }
+        slot.slots = None;
+        state.free += 1;
+    }
+}
+
+// A slot of a pool, given back when dropped
+struct Slot {
+    slots: Option<Arc<Slots>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(slots) = self.slots.take() {
+            Slots::release(&slots);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cmp;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    use cloned::cloned;
+    use futures::stream;
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+
+    fn params() -> QosParams {
+        QosParams {
+            batch_identities: vec!["svc-ci".to_string()],
+            interactive: QosLimits::default(),
+            batch: QosLimits {
+                max_concurrency: Some(2),
+                timeout_secs: Some(3600),
+            },
+        }
+    }
+
+    #[test]
+    fn classify() {
+        let pools = QosPools::new(&params());
+
+        assert_eq!(pools.classify(None, vec!["alice"]), QosClass::Interactive);
+        assert_eq!(
+            pools.classify(None, vec!["alice", "svc-ci"]),
+            QosClass::Batch
+        );
+        assert_eq!(
+            pools.classify(Some("batch"), vec!["alice"]),
+            QosClass::Batch
+        );
+        assert_eq!(
+            pools.classify(Some("Interactive"), vec!["svc-ci"]),
+            QosClass::Interactive
+        );
+        // Unknown classes fall back to the identity
+        assert_eq!(
+            pools.classify(Some("urgent"), vec!["svc-ci"]),
+            QosClass::Batch
+        );
+    }
+
+    #[test]
+    fn timeouts() {
+        let pools = QosPools::new(&params());
+        let default = Duration::from_secs(60);
+        assert_eq!(
+            pools.pool(QosClass::Batch).timeout_or(default),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            pools.pool(QosClass::Interactive).timeout_or(default),
+            default
+        );
+    }
+
+    #[test]
+    fn concurrency_is_limited_per_class() {
+        let pools = QosPools::new(&params());
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(Mutex::new(0));
+
+        let request = |pool: QosPool| {
+            cloned!(running, max_running);
+            pool.run(future::lazy(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                {
+                    let mut max_running = max_running.lock().unwrap();
+                    *max_running = cmp::max(*max_running, now);
+                }
+                Delay::new(Instant::now() + Duration::from_millis(20))
+                    .map_err(Error::from)
+                    .map(move |()| {
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+            }))
+        };
+
+        let batch: Vec<_> = (0..6)
+            .map(|_| request(pools.pool(QosClass::Batch)))
+            .collect();
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::join_all(batch)).unwrap();
+        assert_eq!(*max_running.lock().unwrap(), 2);
+
+        // Interactive requests don't wait for batch ones
+        *max_running.lock().unwrap() = 0;
+        let interactive: Vec<_> = (0..6)
+            .map(|_| request(pools.pool(QosClass::Interactive)))
+            .collect();
+        rt.block_on(future::join_all(interactive)).unwrap();
+        assert_eq!(*max_running.lock().unwrap(), 6);
+    }
+
+    #[test]
+    fn streams_hold_their_slot_until_they_end() {
+        let pools = QosPools::new(&QosParams {
+            batch: QosLimits {
+                max_concurrency: Some(1),
+                timeout_secs: None,
+            },
+            ..params()
+        });
+        let pool = pools.pool(QosClass::Batch);
+
+        let first = pool.run_stream(stream::iter_ok(vec![1, 2, 3]));
+        let second = pool.run_stream(stream::iter_ok(vec![4]));
+        let mut rt = Runtime::new().unwrap();
+        // The second stream only starts once the first one is over
+        let res = rt.block_on(second.select(first).collect()).unwrap();
+        assert_eq!(res, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn saturation() {
+        let pools = QosPools::new(&QosParams {
+            batch: QosLimits {
+                max_concurrency: Some(1),
+                timeout_secs: None,
+            },
+            ..params()
+        });
+        let pool = pools.pool(QosClass::Batch);
+        let interactive = pools.pool(QosClass::Interactive);
+        let mut rt = Runtime::new().unwrap();
+
+        assert!(!pool.is_saturated());
+        // The request takes the only slot of the batch pool. Pools without limits are never
+        // saturated.
+        let request = pool.run(future::lazy({
+            cloned!(pool);
+            move || Ok::<_, Error>((pool.is_saturated(), interactive.is_saturated()))
+        }));
+        assert_eq!(rt.block_on(request).unwrap(), (true, false));
+        assert!(!pool.is_saturated());
+    }
+}
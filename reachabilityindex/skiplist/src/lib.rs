@@ -6,7 +6,8 @@
 
 extern crate failure_ext as failure;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::iter;
 use std::sync::Arc;
 
 use bytes::Bytes;
@@ -390,6 +391,25 @@ impl SkiplistIndex {
     pub fn indexed_node_count(&self) -> usize {
         self.skip_list_edges.mapping.len()
     }
+
+    /// Serialize the index to store it in the blobstore, see `deserialize_skiplist_map`
+    pub fn serialize(&self) -> Bytes {
+        // We store only latest skip entry (i.e. entry with the longest jump)
+        // This saves us storage space
+        let mut thrift_merge_graph = HashMap::new();
+        for (cs_id, skiplist_node_type) in self.get_all_skip_edges() {
+            let skiplist_node_type =
+                if let SkiplistNodeType::SkipEdges(skip_edges) = skiplist_node_type {
+                    SkiplistNodeType::SkipEdges(skip_edges.last().cloned().into_iter().collect())
+                } else {
+                    skiplist_node_type
+                };
+
+            thrift_merge_graph.insert(cs_id.into_thrift(), skiplist_node_type.to_thrift());
+        }
+
+        compact_protocol::serialize(&thrift_merge_graph)
+    }
 }
 
 impl ReachabilityIndex for SkiplistIndex {
@@ -429,6 +449,186 @@ impl ReachabilityIndex for SkiplistIndex {
             })
             .boxify()
     }
+
+    fn query_reachability_many(
+        &self,
+        ctx: CoreContext,
+        changeset_fetcher: Arc<ChangesetFetcher>,
+        pairs: Vec<(ChangesetId, ChangesetId)>,
+    ) -> BoxFuture<Vec<bool>, Error> {
+        cloned!(self.skip_list_edges);
+        let nodes: HashSet<_> = pairs
+            .iter()
+            .flat_map(|&(desc_hash, anc_hash)| vec![desc_hash, anc_hash])
+            .collect();
+        changesets_with_generation_numbers(
+            ctx.clone(),
+            changeset_fetcher.clone(),
+            nodes.into_iter().collect(),
+        )
+        .and_then(move |gens| {
+            let gens: HashMap<_, _> = gens.into_iter().collect();
+            // All queries walk from their descendant in the same frontier, so that ancestors
+            // shared by several descendants are only visited once
+            let mut frontier = LabeledFrontier::default();
+            let mut anc_gens = BTreeSet::new();
+            for (desc_hash, anc_hash) in &pairs {
+                let (desc_gen, anc_gen) = (gens[desc_hash], gens[anc_hash]);
+                if anc_gen <= desc_gen {
+                    frontier.insert(*desc_hash, desc_gen, iter::once(*desc_hash));
+                    anc_gens.insert(anc_gen);
+                }
+            }
+
+            walk_to_generations(
+                ctx,
+                changeset_fetcher,
+                skip_list_edges,
+                frontier,
+                anc_gens.into_iter().collect(),
+            )
+            .map(move |reached| {
+                pairs
+                    .into_iter()
+                    .map(|(desc_hash, anc_hash)| {
+                        reached
+                            .get(&gens[&anc_hash])
+                            .and_then(|cs_ids| cs_ids.get(&anc_hash))
+                            .map_or(false, |descs| descs.contains(&desc_hash))
+                    })
+                    .collect()
+            })
+        })
+        .boxify()
+    }
+}
+
+// Frontier of the walks of several reachability queries at once. Every node is labeled with the
+// descendants whose walks reached it.
+#[derive(Default)]
+struct LabeledFrontier {
+    gen_map: BTreeMap<Generation, HashMap<ChangesetId, HashSet<ChangesetId>>>,
+}
+
+impl LabeledFrontier {
+    fn insert(
+        &mut self,
+        node: ChangesetId,
+        gen: Generation,
+        labels: impl IntoIterator<Item = ChangesetId>,
+    ) {
+        self.gen_map
+            .entry(gen)
+            .or_insert_with(HashMap::new)
+            .entry(node)
+            .or_insert_with(HashSet::new)
+            .extend(labels);
+    }
+
+    fn get(&self, gen: Generation) -> Option<&HashMap<ChangesetId, HashSet<ChangesetId>>> {
+        self.gen_map.get(&gen)
+    }
+
+    fn max_gen(&self) -> Option<Generation> {
+        self.gen_map.keys().next_back().cloned()
+    }
+
+    fn remove_max_gen(&mut self) -> Option<HashMap<ChangesetId, HashSet<ChangesetId>>> {
+        let max_gen = self.max_gen()?;
+        self.gen_map.remove(&max_gen)
+    }
+}
+
+// The edges of the index to move `cs_id` along without going below `gen`: its best skip edge,
+// or the edges to its parents. None if the index doesn't have any
+fn indexed_edges(
+    skip_edges: &SkiplistEdgeMapping,
+    cs_id: ChangesetId,
+    gen: Generation,
+) -> Option<Vec<(ChangesetId, Generation)>> {
+    let entry = skip_edges.mapping.get(&cs_id)?;
+    match &*entry {
+        SkiplistNodeType::SkipEdges(edges) => edges
+            .iter()
+            .take_while(|edge_pair| edge_pair.1 >= gen)
+            .last()
+            .map(|edge_pair| vec![*edge_pair]),
+        SkiplistNodeType::ParentEdges(edges) => Some(edges.clone()),
+    }
+}
+
+// Walks `frontier` down to each of `generations`, sorted from lowest to highest, and returns the
+// labeled nodes of the frontier at each of them. Like process_frontier, but never skips below the
+// highest generation left to reach, so that the frontier is complete at every one of them.
+fn walk_to_generations(
+    ctx: CoreContext,
+    changeset_fetcher: Arc<ChangesetFetcher>,
+    skip_edges: Arc<SkiplistEdgeMapping>,
+    frontier: LabeledFrontier,
+    generations: Vec<Generation>,
+) -> impl Future<Item = HashMap<Generation, HashMap<ChangesetId, HashSet<ChangesetId>>>, Error = Error>
+{
+    loop_fn(
+        (frontier, generations, HashMap::new()),
+        move |(mut frontier, mut generations, mut reached)| {
+            let target = match generations.last() {
+                Some(gen) => *gen,
+                None => return ok(Loop::Break(reached)).left_future(),
+            };
+            match frontier.max_gen() {
+                Some(val) if val > target => {
+                    let all_cs_ids = frontier.remove_max_gen().unwrap();
+                    let mut no_skiplist_edges = vec![];
+                    for (cs_id, labels) in all_cs_ids {
+                        match indexed_edges(&skip_edges, cs_id, target) {
+                            Some(edges) => {
+                                for (node, gen) in edges {
+                                    frontier.insert(node, gen, labels.iter().cloned());
+                                }
+                            }
+                            None => no_skiplist_edges.push((cs_id, labels)),
+                        }
+                    }
+
+                    let parents_futs = no_skiplist_edges.into_iter().map({
+                        cloned!(ctx, changeset_fetcher);
+                        move |(cs_id, labels)| {
+                            changeset_fetcher
+                                .get_parents(ctx.clone(), cs_id)
+                                .and_then({
+                                    cloned!(ctx, changeset_fetcher);
+                                    move |parents| {
+                                        changesets_with_generation_numbers(
+                                            ctx,
+                                            changeset_fetcher,
+                                            parents,
+                                        )
+                                    }
+                                })
+                                .map(move |parents| (parents, labels))
+                        }
+                    });
+                    join_all(parents_futs)
+                        .map(move |all_parents| {
+                            for (parents, labels) in all_parents {
+                                for (node, gen) in parents {
+                                    frontier.insert(node, gen, labels.iter().cloned());
+                                }
+                            }
+                            Loop::Continue((frontier, generations, reached))
+                        })
+                        .right_future()
+                }
+                _ => {
+                    generations.pop();
+                    if let Some(cs_ids) = frontier.get(target) {
+                        reached.insert(target, cs_ids.clone());
+                    }
+                    ok(Loop::Continue((frontier, generations, reached))).left_future()
+                }
+            }
+        },
+    )
 }
 
 // Take all changesets from `all_cs_ids` that have skiplist edges in `skip_edges` and moves them.
@@ -709,6 +909,41 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_serialize() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let repo = Arc::new(linear::getrepo(None));
+            let sli = SkiplistIndex::new();
+            let master_node = string_to_bonsai(
+                ctx.clone(),
+                &repo,
+                "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157",
+            );
+            sli.add_node(ctx.clone(), repo.get_changeset_fetcher(), master_node, 100)
+                .wait()
+                .unwrap();
+
+            let map = deserialize_skiplist_map(sli.serialize()).unwrap();
+            assert_eq!(map.len(), sli.indexed_node_count());
+            // Only the longest skip edge of each node is kept
+            let longest_edge = sli.get_skip_edges(master_node).unwrap().last().cloned();
+            match map.get(&master_node) {
+                Some(SkiplistNodeType::SkipEdges(edges)) => {
+                    assert_eq!(edges.clone(), longest_edge.into_iter().collect::<Vec<_>>())
+                }
+                _ => panic!("master should have skip edges"),
+            }
+
+            // Indexing continues from a deserialized index
+            let sli = SkiplistIndex::new_with_skiplist_graph(map);
+            sli.add_node(ctx.clone(), repo.get_changeset_fetcher(), master_node, 100)
+                .wait()
+                .unwrap();
+            assert!(sli.is_node_indexed(master_node));
+        });
+    }
+
     #[test]
     fn test_skip_edges_reach_end_in_linear() {
         async_unit::tokio_unit_test(|| {
@@ -2205,6 +2440,90 @@ mod test {
         assert!(run_future(runtime, f).unwrap());
     }
 
+    fn test_query_reachability_many(
+        runtime: &mut tokio::runtime::Runtime,
+        ctx: CoreContext,
+        repo: Arc<BlobRepo>,
+        sli: SkiplistIndex,
+    ) {
+        let f = repo
+            .get_bonsai_bookmark(ctx.clone(), &Bookmark::new("master").unwrap())
+            .and_then({
+                cloned!(ctx, repo);
+                move |maybe_cs_id| {
+                    AncestorsNodeStream::new(
+                        ctx,
+                        &repo.get_changeset_fetcher(),
+                        maybe_cs_id.unwrap(),
+                    )
+                    .collect()
+                }
+            })
+            .and_then({
+                cloned!(ctx, repo);
+                move |cs_ids| {
+                    join_all(cs_ids.into_iter().map({
+                        move |cs| {
+                            // Including the node itself, which every node reaches
+                            AncestorsNodeStream::new(
+                                ctx.clone(),
+                                &repo.get_changeset_fetcher(),
+                                cs.clone(),
+                            )
+                            .collect()
+                            .map(move |ancestors| (cs, HashSet::<_>::from_iter(ancestors)))
+                        }
+                    }))
+                }
+            })
+            .and_then(move |cs_and_ancestors| {
+                let cs_ancestor_map: HashMap<ChangesetId, HashSet<ChangesetId>> =
+                    cs_and_ancestors.into_iter().collect();
+
+                // Every pair in both directions, in a single batch
+                let mut pairs = vec![];
+                let mut expected = vec![];
+                for desc in cs_ancestor_map.keys() {
+                    for anc in cs_ancestor_map.keys() {
+                        pairs.push((*desc, *anc));
+                        expected.push(cs_ancestor_map.get(desc).unwrap().contains(anc));
+                    }
+                }
+                sli.query_reachability_many(ctx, repo.get_changeset_fetcher(), pairs)
+                    .map(move |actual| actual == expected)
+            });
+
+        assert!(run_future(runtime, f).unwrap());
+    }
+
+    fn test_query_reachability_many_merge_uneven(
+        runtime: &mut tokio::runtime::Runtime,
+        ctx: CoreContext,
+        repo: Arc<BlobRepo>,
+        sli: SkiplistIndex,
+    ) {
+        test_query_reachability_many(runtime, ctx, repo, sli)
+    }
+
+    fn test_reachability_many_unshared_merge(
+        runtime: &mut tokio::runtime::Runtime,
+        ctx: CoreContext,
+        repo: Arc<BlobRepo>,
+        sli: SkiplistIndex,
+    ) {
+        test_query_reachability_many(runtime, ctx, repo, sli)
+    }
+
+    #[test]
+    fn test_query_reachability_many_empty() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let ctx = CoreContext::test_mock();
+        let repo = Arc::new(linear::getrepo(None));
+        let sli = SkiplistIndex::new();
+        let f = sli.query_reachability_many(ctx, repo.get_changeset_fetcher(), vec![]);
+        assert!(run_future(&mut runtime, f).unwrap().is_empty());
+    }
+
     fn test_is_ancestor_merge_uneven(
         runtime: &mut tokio::runtime::Runtime,
         ctx: CoreContext,
@@ -2233,4 +2552,6 @@ mod test {
     skiplist_test!(process_frontier_on_wide_branch, branch_wide);
     skiplist_test!(test_is_ancestor_merge_uneven, merge_uneven);
     skiplist_test!(test_is_ancestor_unshared_merge_even, unshared_merge_even);
+    skiplist_test!(test_query_reachability_many_merge_uneven, merge_uneven);
+    skiplist_test!(test_reachability_many_unshared_merge, unshared_merge_even);
 }
@@ -10,7 +10,8 @@ use std::iter::{Extend, FromIterator};
 use std::sync::Arc;
 
 use failure_ext::Error;
-use futures_ext::BoxFuture;
+use futures::future::join_all;
+use futures_ext::{BoxFuture, FutureExt};
 
 use changeset_fetcher::ChangesetFetcher;
 use context::CoreContext;
@@ -127,6 +128,22 @@ pub trait ReachabilityIndex {
         src: ChangesetId,
         dst: ChangesetId,
     ) -> BoxFuture<bool, Error>;
+
+    /// Return a Future for whether the src node of every pair can reach its dst node, in the
+    /// order of the pairs. Indexes that can share work between the queries should override this
+    fn query_reachability_many(
+        &self,
+        ctx: CoreContext,
+        repo: Arc<ChangesetFetcher>,
+        pairs: Vec<(ChangesetId, ChangesetId)>,
+    ) -> BoxFuture<Vec<bool>, Error> {
+        join_all(
+            pairs
+                .into_iter()
+                .map(|(src, dst)| self.query_reachability(ctx.clone(), repo.clone(), src, dst)),
+        )
+        .boxify()
+    }
 }
 
 /// Trait for any method supporting computing an "LCA hint"
@@ -8,7 +8,9 @@
 
 use std::{
     collections::{HashMap, HashSet, VecDeque},
+    fmt,
     io::{Cursor, Write},
+    str::FromStr,
 };
 
 use blobrepo::BlobRepo;
@@ -18,16 +20,16 @@ use context::CoreContext;
 use failure::{Error, Fail, Fallible};
 use filenodes::FilenodeInfo;
 use futures::{future::ok, stream, Future, IntoFuture, Stream};
-use futures_ext::{select_all, BoxFuture, BoxStream, FutureExt, StreamExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use lz4_pyframe;
-use maplit::hashset;
 use mercurial::file::File;
 use mercurial_types::{
-    HgBlobNode, HgFileHistoryEntry, HgFileNodeId, HgParents, MPath, RepoPath, RevFlags, NULL_CSID,
-    NULL_HASH,
+    HgBlobNode, HgFileHistoryEntry, HgFileNodeId, HgNodeHash, HgParents, MPath, RepoPath, RevFlags,
+    NULL_CSID, NULL_HASH,
 };
-use metaconfig_types::LfsParams;
+use metaconfig_types::{CopyInfoCheckParams, LfsParams};
 use mononoke_types::FileContents;
+use scuba_ext::ScubaSampleBuilderExt;
 use tracing::{trace_args, Traced};
 
 const METAKEYFLAG: &str = "f";
@@ -46,10 +48,107 @@ pub enum ErrorKind {
         expected: HgFileNodeId,
         actual: HgFileNodeId,
     },
+    #[fail(display = "Data corruption for {}: {} is its own ancestor", path, node)]
+    HistoryCycle { path: RepoPath, node: HgFileNodeId },
+    #[fail(display = "invalid history cursor: {}", _0)]
+    InvalidHistoryCursor(String),
+    #[fail(
+        display = "Copy info mismatch for {} {}: filenodes table {:?}, envelope {:?}",
+        path, node, filenodes, envelope
+    )]
+    CopyInfoMismatch {
+        path: RepoPath,
+        node: HgFileNodeId,
+        filenodes: Option<(RepoPath, HgFileNodeId)>,
+        envelope: Option<(RepoPath, HgFileNodeId)>,
+    },
+}
+
+/// Limits on how much of the history of a file is walked
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HistoryLimits {
+    /// Most generations of ancestors below the start of the walk
+    pub max_depth: Option<u32>,
+    /// Most entries
+    pub max_entries: Option<usize>,
+}
+
+/// Where a walk of the history of a file continues from: the filenodes it has yet to visit.
+///
+/// A walk continued from a cursor doesn't remember what the walk before it visited, so
+/// histories with merges may list some entries again.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistoryCursor {
+    nodes: Vec<HgFileNodeId>,
+}
+
+impl HistoryCursor {
+    /// The start of a walk of the history of `nodes`
+    pub fn new(nodes: Vec<HgFileNodeId>) -> Self {
+        Self { nodes }
+    }
+}
+
+impl fmt::Display for HistoryCursor {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let nodes: Vec<_> = self.nodes.iter().map(|node| node.to_string()).collect();
+        write!(fmt, "{}", nodes.join(","))
+    }
+}
+
+impl FromStr for HistoryCursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let nodes = s
+            .split(',')
+            .map(|node| {
+                HgNodeHash::from_str(node)
+                    .map(HgFileNodeId::new)
+                    .map_err(|_| ErrorKind::InvalidHistoryCursor(s.to_string()).into())
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { nodes })
+    }
+}
+
+/// Part of the history of a file
+#[derive(Debug)]
+pub struct FileHistoryPage {
+    pub entries: Vec<HgFileHistoryEntry>,
+    /// Where the rest of the history continues from, if the walk stopped at its limits
+    pub next: Option<HistoryCursor>,
+}
+
+enum HistoryStep {
+    Entry(HgFileHistoryEntry),
+    Stopped(HistoryCursor),
+}
+
+impl HistoryStep {
+    fn into_entry(self) -> Option<HgFileHistoryEntry> {
+        match self {
+            HistoryStep::Entry(entry) => Some(entry),
+            HistoryStep::Stopped(_) => None,
+        }
+    }
+}
+
+struct HistoryWalk {
+    /// Nodes to visit, with their depth below the start of the walk
+    nodes: VecDeque<(HgFileNodeId, u32)>,
+    seen_nodes: HashSet<HgFileNodeId>,
+    /// Parents of the visited nodes
+    parents: HashMap<HgFileNodeId, Vec<HgFileNodeId>>,
+    entries: usize,
+    done: bool,
 }
 
 /// Remotefilelog blob consists of file content in `node` revision and all the history
 /// of the file up to `node`
+///
+/// With `copy_info_check`, the content starts with the copy-from metadata of the file, checked
+/// against the filenodes table (see `get_checked_copy_info`). LFS pointers are sent as they are.
 pub fn create_remotefilelog_blob(
     ctx: CoreContext,
     repo: BlobRepo,
@@ -57,6 +156,7 @@ pub fn create_remotefilelog_blob(
     path: MPath,
     lfs_params: LfsParams,
     validate_hash: bool,
+    copy_info_check: Option<CopyInfoCheckParams>,
 ) -> BoxFuture<Bytes, Error> {
     let trace_args = trace_args!("node" => node.to_string(), "path" => path.to_string());
 
@@ -68,6 +168,18 @@ pub fn create_remotefilelog_blob(
         lfs_params,
         validate_hash,
     )
+    .and_then({
+        cloned!(ctx, repo, path);
+        move |(raw_content, meta_key_flag)| match copy_info_check {
+            Some(check) if meta_key_flag == RevFlags::REVIDX_DEFAULT_FLAGS => {
+                get_checked_copy_info(ctx, repo, node, RepoPath::FilePath(path), check)
+                    .and_then(move |copyfrom| add_copy_metadata(raw_content, copyfrom))
+                    .map(move |raw_content| (raw_content, meta_key_flag))
+                    .left_future()
+            }
+            _ => ok((raw_content, meta_key_flag)).right_future(),
+        }
+    })
     .and_then(move |(raw_content, meta_key_flag)| {
         encode_remotefilelog_file_content(raw_content, meta_key_flag)
     })
@@ -93,11 +205,12 @@ pub fn create_remotefilelog_blob(
                 get_file_history_using_prefetched(
                     ctx.clone(),
                     repo,
-                    node,
+                    HistoryCursor::new(vec![node]),
                     path,
-                    None,
+                    HistoryLimits::default(),
                     prefetched_filenodes,
                 )
+                .filter_map(HistoryStep::into_entry)
                 .collect()
                 .traced(ctx.trace(), "fetching non-prefetched history", trace_args)
             }
@@ -149,6 +262,75 @@ fn validate_content(
     }
 }
 
+/// The copy information of `node`, from the filenodes table, checked against the one in its
+/// envelope. A mismatch is logged to scuba, and fails with `CopyInfoMismatch` if
+/// `check.fail_on_mismatch`. Otherwise the copy information of the envelope is returned, as it's
+/// the one that the hash of `node` covers.
+fn get_checked_copy_info(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    node: HgFileNodeId,
+    repopath: RepoPath,
+    check: CopyInfoCheckParams,
+) -> impl Future<Item = Option<(MPath, HgFileNodeId)>, Error = Error> {
+    get_maybe_draft_filenode(ctx.clone(), repo.clone(), repopath.clone(), node)
+        .and_then({
+            cloned!(ctx, repopath);
+            move |filenode| {
+                repo.get_filenode_from_envelope(ctx, &repopath, node, filenode.linknode)
+                    .map(move |envelope| (filenode.copyfrom, envelope.copyfrom))
+            }
+        })
+        .and_then(move |(filenodes, envelope)| {
+            let copyfrom = if filenodes == envelope {
+                filenodes
+            } else {
+                let describe = |copyfrom: &Option<(RepoPath, HgFileNodeId)>| match copyfrom {
+                    Some((path, node)) => format!("{} {}", path, node),
+                    None => "none".to_string(),
+                };
+                ctx.scuba()
+                    .clone()
+                    .add("path", repopath.to_string())
+                    .add("filenode", node.to_string())
+                    .add("filenodes_copyfrom", describe(&filenodes))
+                    .add("envelope_copyfrom", describe(&envelope))
+                    .log_with_msg("Copy info mismatch", None);
+                if check.fail_on_mismatch {
+                    return Err(ErrorKind::CopyInfoMismatch {
+                        path: repopath,
+                        node,
+                        filenodes,
+                        envelope,
+                    }
+                    .into());
+                }
+                envelope
+            };
+            match copyfrom {
+                Some((RepoPath::FilePath(frompath), fromnode)) => Ok(Some((frompath, fromnode))),
+                Some((frompath, _)) => {
+                    Err(ErrorKind::InconsistentCopyInfo(repopath, frompath).into())
+                }
+                None => Ok(None),
+            }
+        })
+}
+
+/// `content` with the hg metadata that records where it was copied from in front of it
+fn add_copy_metadata(
+    content: FileContents,
+    copyfrom: Option<(MPath, HgFileNodeId)>,
+) -> Result<FileContents, Error> {
+    let mut out: Vec<u8> = vec![];
+    File::generate_metadata(copyfrom.as_ref(), &content, &mut out)?;
+    if out.is_empty() {
+        return Ok(content);
+    }
+    out.extend_from_slice(content.as_bytes());
+    Ok(FileContents::Bytes(Bytes::from(out)))
+}
+
 /// Get the raw content of a file or content hash in the case of LFS files.
 /// Can also optionally validate a hash hg filenode
 pub fn get_raw_content(
@@ -177,7 +359,9 @@ pub fn get_raw_content(
                     (
                         repo.get_file_content(ctx, node)
                             .and_then(move |content| {
-                                if validate_hash {
+                                // Tombstones are served as a placeholder, which can't match the
+                                // filenode hash.
+                                if validate_hash && !content.is_tombstone() {
                                     validate_content(&content, filenode_info, repopath, node)
                                         .map(|()| content)
                                 } else {
@@ -229,37 +413,64 @@ fn encode_remotefilelog_file_content(
         .map(|_| writer.into_inner())
 }
 
-/// Get ancestors of all filenodes
-/// Current implementation might be inefficient because it might re-fetch the same filenode a few
-/// times
+/// Get ancestors of all filenodes, each of them once, up to `limits`
 pub fn get_unordered_file_history_for_multiple_nodes(
     ctx: CoreContext,
     repo: BlobRepo,
     filenodes: HashSet<HgFileNodeId>,
     path: &MPath,
+    limits: HistoryLimits,
 ) -> impl Stream<Item = HgFileHistoryEntry, Error = Error> {
-    select_all(
-        filenodes.into_iter().map(|filenode| {
-            get_file_history(ctx.clone(), repo.clone(), filenode, path.clone(), None)
-        }),
-    )
-    .filter({
-        let mut used_filenodes = HashSet::new();
-        move |entry| used_filenodes.insert(entry.filenode().clone())
-    })
+    let start = HistoryCursor::new(filenodes.into_iter().collect());
+    walk_history(ctx, repo, path.clone(), start, limits).filter_map(HistoryStep::into_entry)
 }
 
-/// Get the history of the file corresponding to the given filenode and path.
+/// Get the history of the file corresponding to the given filenode and path, up to `limits`.
 pub fn get_file_history(
     ctx: CoreContext,
     repo: BlobRepo,
     filenode: HgFileNodeId,
     path: MPath,
-    max_depth: Option<u32>,
+    limits: HistoryLimits,
 ) -> impl Stream<Item = HgFileHistoryEntry, Error = Error> {
+    let start = HistoryCursor::new(vec![filenode]);
+    walk_history(ctx, repo, path, start, limits).filter_map(HistoryStep::into_entry)
+}
+
+/// Get the history of the file at `path` from `start` up to `limits`, and where to continue it
+/// from if it goes beyond them.
+pub fn get_file_history_page(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    path: MPath,
+    start: HistoryCursor,
+    limits: HistoryLimits,
+) -> impl Future<Item = FileHistoryPage, Error = Error> {
+    walk_history(ctx, repo, path, start, limits).fold(
+        FileHistoryPage {
+            entries: vec![],
+            next: None,
+        },
+        |mut page, step| -> Result<_, Error> {
+            match step {
+                HistoryStep::Entry(entry) => page.entries.push(entry),
+                HistoryStep::Stopped(cursor) => page.next = Some(cursor),
+            }
+            Ok(page)
+        },
+    )
+}
+
+fn walk_history(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    path: MPath,
+    start: HistoryCursor,
+    limits: HistoryLimits,
+) -> impl Stream<Item = HistoryStep, Error = Error> {
     prefetch_history(ctx.clone(), repo.clone(), path.clone())
         .map(move |prefetched| {
-            get_file_history_using_prefetched(ctx, repo, filenode, path, max_depth, prefetched)
+            get_file_history_using_prefetched(ctx, repo, start, path, limits, prefetched)
         })
         .flatten_stream()
 }
@@ -282,76 +493,120 @@ fn prefetch_history(
 
 /// Get the history of the file at the specified path, using the given
 /// prefetched history map as a cache to speed up the operation.
+///
+/// Ancestors are walked breadth first, so the walk stops at `max_depth` once the next node to
+/// visit is that deep. A node that is its own ancestor fails the walk with `HistoryCycle`,
+/// rather than being visited again.
 fn get_file_history_using_prefetched(
     ctx: CoreContext,
     repo: BlobRepo,
-    startnode: HgFileNodeId,
+    start: HistoryCursor,
     path: MPath,
-    max_depth: Option<u32>,
+    limits: HistoryLimits,
     prefetched_history: HashMap<HgFileNodeId, FilenodeInfo>,
-) -> BoxStream<HgFileHistoryEntry, Error> {
-    if startnode == HgFileNodeId::new(NULL_HASH) {
-        return stream::empty().boxify();
-    }
-
+) -> BoxStream<HistoryStep, Error> {
+    let null = HgFileNodeId::new(NULL_HASH);
     let mut startstate = VecDeque::new();
-    startstate.push_back(startnode);
-    let seen_nodes = hashset! {startnode};
+    let mut seen_nodes = HashSet::new();
+    for node in start.nodes {
+        if node != null && seen_nodes.insert(node) {
+            startstate.push_back((node, 0));
+        }
+    }
     let path = RepoPath::FilePath(path);
 
-    stream::unfold(
-        (startstate, seen_nodes, 0),
-        move |(mut nodes, mut seen_nodes, depth): (
-            VecDeque<HgFileNodeId>,
-            HashSet<HgFileNodeId>,
-            u32,
-        )| {
-            match max_depth {
-                Some(max_depth) if depth >= max_depth => return None,
-                _ => {}
-            }
+    let state = HistoryWalk {
+        nodes: startstate,
+        seen_nodes,
+        parents: HashMap::new(),
+        entries: 0,
+        done: false,
+    };
+    stream::unfold(state, move |mut state: HistoryWalk| {
+        if state.done {
+            return None;
+        }
+        let (node, depth) = *state.nodes.front()?;
+        let over_depth = limits.max_depth.map_or(false, |max| depth >= max);
+        let over_entries = limits.max_entries.map_or(false, |max| state.entries >= max);
+        if over_depth || over_entries {
+            state.done = true;
+            let cursor = HistoryCursor::new(state.nodes.iter().map(|(node, _)| *node).collect());
+            return Some(ok((HistoryStep::Stopped(cursor), state)).left_future());
+        }
+        state.nodes.pop_front();
 
-            let node = nodes.pop_front()?;
+        let filenode_fut = if let Some(filenode) = prefetched_history.get(&node) {
+            ok(filenode.clone()).left_future()
+        } else {
+            get_maybe_draft_filenode(ctx.clone(), repo.clone(), path.clone(), node).right_future()
+        };
 
-            let filenode_fut = if let Some(filenode) = prefetched_history.get(&node) {
-                ok(filenode.clone()).left_future()
-            } else {
-                get_maybe_draft_filenode(ctx.clone(), repo.clone(), path.clone(), node)
-                    .right_future()
-            };
+        let history = filenode_fut.and_then(move |filenode| {
+            let p1 = filenode.p1.map(|p| p.into_nodehash());
+            let p2 = filenode.p2.map(|p| p.into_nodehash());
+            let parents = HgParents::new(p1, p2);
 
-            let history = filenode_fut.and_then(move |filenode| {
-                let p1 = filenode.p1.map(|p| p.into_nodehash());
-                let p2 = filenode.p2.map(|p| p.into_nodehash());
-                let parents = HgParents::new(p1, p2);
+            let linknode = filenode.linknode;
 
-                let linknode = filenode.linknode;
+            let copyfrom = match filenode.copyfrom {
+                Some((RepoPath::FilePath(frompath), node)) => Some((frompath, node)),
+                Some((frompath, _)) => {
+                    return Err(ErrorKind::InconsistentCopyInfo(filenode.path, frompath).into());
+                }
+                None => None,
+            };
 
-                let copyfrom = match filenode.copyfrom {
-                    Some((RepoPath::FilePath(frompath), node)) => Some((frompath, node)),
-                    Some((frompath, _)) => {
-                        return Err(ErrorKind::InconsistentCopyInfo(filenode.path, frompath).into());
+            let entry = HgFileHistoryEntry::new(node, parents, linknode, copyfrom);
+
+            let parents: Vec<_> = parents.into_iter().map(HgFileNodeId::new).collect();
+            for parent in &parents {
+                if !state.seen_nodes.insert(*parent) {
+                    // Seen before, either through another child or because it's an ancestor
+                    // of `node` too
+                    if is_ancestor(&state.parents, node, *parent) {
+                        return Err(ErrorKind::HistoryCycle {
+                            path: filenode.path,
+                            node,
+                        }
+                        .into());
                     }
-                    None => None,
-                };
-
-                let entry = HgFileHistoryEntry::new(node, parents, linknode, copyfrom);
-
-                nodes.extend(
-                    parents
-                        .into_iter()
-                        .map(HgFileNodeId::new)
-                        .filter(|p| seen_nodes.insert(*p)),
-                );
-                Ok((entry, (nodes, seen_nodes, depth + 1)))
-            });
+                    continue;
+                }
+                state.nodes.push_back((*parent, depth + 1));
+            }
+            state.parents.insert(node, parents);
+            state.entries += 1;
+            Ok((HistoryStep::Entry(entry), state))
+        });
 
-            Some(history)
-        },
-    )
+        Some(history.right_future())
+    })
     .boxify()
 }
 
+// Whether `ancestor` is an ancestor of `descendant` (or `descendant` itself), as far as the
+// walk visited them
+fn is_ancestor(
+    parents: &HashMap<HgFileNodeId, Vec<HgFileNodeId>>,
+    ancestor: HgFileNodeId,
+    descendant: HgFileNodeId,
+) -> bool {
+    let mut visited = HashSet::new();
+    let mut to_visit = vec![descendant];
+    while let Some(node) = to_visit.pop() {
+        if node == ancestor {
+            return true;
+        }
+        if visited.insert(node) {
+            if let Some(node_parents) = parents.get(&node) {
+                to_visit.extend(node_parents.iter().cloned());
+            }
+        }
+    }
+    false
+}
+
 /// Convert file history into bytes as expected in Mercurial's loose file format.
 fn serialize_history(history: Vec<HgFileHistoryEntry>) -> Fallible<Vec<u8>> {
     let approximate_history_entry_size = 81;
@@ -387,3 +642,51 @@ fn get_maybe_draft_filenode(
         }
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use maplit::hashmap;
+    use mercurial_types_mocks::nodehash::{ONES_FNID, THREES_FNID, TWOS_FNID};
+
+    #[test]
+    fn history_cursor_roundtrip() {
+        let cursor = HistoryCursor::new(vec![ONES_FNID, TWOS_FNID]);
+        let parsed: HistoryCursor = cursor.to_string().parse().unwrap();
+        assert_eq!(parsed, cursor);
+
+        assert!("".parse::<HistoryCursor>().is_err());
+        assert!("1111,zzzz".parse::<HistoryCursor>().is_err());
+    }
+
+    #[test]
+    fn copy_metadata() {
+        let content = FileContents::Bytes(Bytes::from(&b"content"[..]));
+        assert_eq!(add_copy_metadata(content.clone(), None).unwrap(), content);
+
+        let copyfrom = (MPath::new("dir/from").unwrap(), ONES_FNID);
+        let copied = add_copy_metadata(content, Some(copyfrom)).unwrap();
+        assert_eq!(
+            copied.as_bytes().as_ref(),
+            &b"\x01\ncopy: dir/from\ncopyrev: 1111111111111111111111111111111111111111\n\x01\ncontent"[..]
+        );
+    }
+
+    #[test]
+    fn ancestors_of_visited_nodes() {
+        // 3 -> 2 -> 1
+        let parents = hashmap! {
+            THREES_FNID => vec![TWOS_FNID],
+            TWOS_FNID => vec![ONES_FNID],
+        };
+        assert!(is_ancestor(&parents, ONES_FNID, THREES_FNID));
+        assert!(is_ancestor(&parents, TWOS_FNID, TWOS_FNID));
+        assert!(!is_ancestor(&parents, THREES_FNID, ONES_FNID));
+
+        // 1 -> 3 closes a cycle
+        let mut parents = parents;
+        parents.insert(ONES_FNID, vec![THREES_FNID]);
+        assert!(is_ancestor(&parents, THREES_FNID, ONES_FNID));
+    }
+}
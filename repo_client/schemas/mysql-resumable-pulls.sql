@@ -0,0 +1,9 @@
+CREATE TABLE `resumable_pulls` (
+  `repo_id` INT UNSIGNED NOT NULL,
+  `token` VARCHAR(64) NOT NULL,
+  `commits` LONGBLOB NOT NULL,
+  `expires` BIGINT NOT NULL,
+  `resumes` INT UNSIGNED NOT NULL DEFAULT 0,
+  PRIMARY KEY (`repo_id`, `token`),
+  KEY `resumable_pulls_repo_expires` (`repo_id`, `expires`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,9 @@
+CREATE TABLE `tree_popularity` (
+  `repo_id` INT UNSIGNED NOT NULL,
+  `parent` VARBINARY(4096) NOT NULL,
+  -- Paths are too long for a key, so they are looked up by hash
+  `parent_hash` BINARY(32) AS (UNHEX(SHA2(`parent`, 256))) STORED,
+  `name` VARBINARY(255) NOT NULL,
+  `count` BIGINT NOT NULL DEFAULT 0,
+  UNIQUE KEY `tree_popularity_tree` (`repo_id`, `parent_hash`, `name`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,10 @@
+CREATE TABLE `resumable_pulls` (
+  `repo_id` INT UNSIGNED NOT NULL,
+  `token` VARCHAR(64) NOT NULL,
+  `commits` LONGBLOB NOT NULL,
+  `expires` BIGINT NOT NULL,
+  `resumes` INT UNSIGNED NOT NULL DEFAULT 0,
+  PRIMARY KEY (`repo_id`, `token`)
+);
+
+CREATE INDEX `resumable_pulls_repo_expires` ON `resumable_pulls` (`repo_id`, `expires`);
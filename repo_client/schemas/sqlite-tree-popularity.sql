@@ -0,0 +1,7 @@
+CREATE TABLE `tree_popularity` (
+  `repo_id` INT UNSIGNED NOT NULL,
+  `parent` VARBINARY(4096) NOT NULL,
+  `name` VARBINARY(255) NOT NULL,
+  `count` BIGINT NOT NULL DEFAULT 0,
+  PRIMARY KEY (`repo_id`, `parent`, `name`)
+);
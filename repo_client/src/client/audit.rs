@@ -0,0 +1,51 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use audit_log::{AuditRecord, Auditor};
+use context::CoreContext;
+use futures::Future;
+use mononoke_types::{DateTime, RepositoryId};
+use request_logging::{RequestLog, RequestSink, ResponseStats};
+use serde_json;
+use tokio;
+
+/// Writes an audit record for every request with a streamed response, i.e. for every read of
+/// file, tree or commit data
+pub struct AuditSink {
+    repo_id: RepositoryId,
+    auditor: Auditor,
+}
+
+impl AuditSink {
+    pub fn new(repo_id: RepositoryId, auditor: Auditor) -> Self {
+        Self { repo_id, auditor }
+    }
+}
+
+impl RequestSink for AuditSink {
+    fn request_finished(&self, ctx: &CoreContext, request: &RequestLog, stats: ResponseStats) {
+        if let ResponseStats::Future(_) = stats {
+            return;
+        }
+
+        let identity = ctx.user_identities().into_iter().next();
+        let record = AuditRecord {
+            repo_id: self.repo_id,
+            timestamp: DateTime::new(ctx.now()),
+            identity,
+            client: ctx.ssh_env_vars().ssh_client.clone(),
+            command: request.command().to_string(),
+            accessed: request.args().cloned().unwrap_or(serde_json::Value::Null),
+            bytes_served: request.response_bytes(),
+        };
+        let logger = ctx.logger().clone();
+        tokio::spawn(
+            self.auditor
+                .audit(ctx.clone(), record)
+                .map_err(move |err| warn!(logger, "failed to write audit record: {:?}", err)),
+        );
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use mercurial_types::percent_encode;
+use metaconfig_types::{WireprotoCapsChanges, WireprotoCapsParams};
+
+fn wireprotocaps() -> Vec<String> {
+    vec![
+        "batch".to_string(),
+        "branchmap".to_string(),
+        "clienttelemetry".to_string(),
+        "lookup".to_string(),
+        "known".to_string(),
+        "getbundle".to_string(),
+        "unbundle=HG10GZ,HG10BZ,HG10UN".to_string(),
+        "gettreepack".to_string(),
+        "remotefilelog".to_string(),
+        "pushkey".to_string(),
+        "stream-preferred".to_string(),
+        "stream_option".to_string(),
+        "streamreqs=generaldelta,lz4revlog,revlogv1".to_string(),
+        "treeonly".to_string(),
+        "knownnodes".to_string(),
+    ]
+}
+
+fn bundle2caps() -> Vec<(String, Vec<String>)> {
+    let caps = vec![
+        ("HG20", vec![]),
+        // Note that "listkeys" is *NOT* returned as a bundle2 capability; that's because there's
+        // a race that can happen. Here's how:
+        // 1. The client does discovery to figure out which heads are missing.
+        // 2. At this point, a frequently updated bookmark (say "master") moves forward.
+        // 3. The client requests the heads discovered in step 1 + the latest value of master.
+        // 4. The server returns changesets up to those heads, plus the latest version of master.
+        //
+        // master doesn't point to a commit that will exist on the client at the end of the pull,
+        // so the client ignores it.
+        //
+        // The workaround here is to force bookmarks to be sent before discovery happens. Disabling
+        // the listkeys capabilities causes the Mercurial client to do that.
+        //
+        // A better fix might be to snapshot and maintain the bookmark state on the server at the
+        // start of discovery.
+        //
+        // The best fix here would be to change the protocol to represent bookmark pulls
+        // atomically.
+        //
+        // Some other notes:
+        // * Stock Mercurial doesn't appear to have this problem. @rain1 hasn't verified why, but
+        //   believes it's because bookmarks get loaded up into memory before discovery and then
+        //   don't get reloaded for the duration of the process. (In Mononoke, this is the
+        //   "snapshot and maintain the bookmark state" approach mentioned above.)
+        // * There's no similar race with pushes updating bookmarks, so "pushkey" is still sent
+        //   as a capability.
+        // * To repro the race, run test-bookmark-race.t with the following line enabled.
+
+        // ("listkeys", vec![]),
+        ("changegroup", vec!["02"]),
+        ("b2x:infinitepush", vec![]),
+        ("b2x:infinitepushscratchbookmarks", vec![]),
+        ("pushkey", vec![]),
+        ("treemanifestserver", vec!["True"]),
+        ("b2x:rebase", vec![]),
+        ("b2x:rebasepackpart", vec![]),
+        ("phases", vec!["heads"]),
+    ];
+
+    caps.into_iter()
+        .map(|(key, values)| {
+            let values = values.into_iter().map(|value| value.to_string()).collect();
+            (key.to_string(), values)
+        })
+        .collect()
+}
+
+/// The name of a capability like `unbundle=HG10GZ,HG10UN`, and its values
+fn split_capability(cap: &str) -> (&str, Vec<String>) {
+    let mut parts = cap.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let values = match parts.next() {
+        Some(values) => values.split(',').map(|value| value.to_string()).collect(),
+        None => vec![],
+    };
+    (name, values)
+}
+
+/// The capabilities advertised to one client: the ones the server supports, with the changes
+/// configured for the repo and for the identities of the client applied.
+pub struct Capabilities {
+    wireproto: Vec<String>,
+    bundle2: Vec<(String, Vec<String>)>,
+}
+
+impl Capabilities {
+    pub fn for_client<'a, I>(params: &WireprotoCapsParams, identities: I) -> Self
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut caps = Capabilities {
+            wireproto: wireprotocaps(),
+            bundle2: bundle2caps(),
+        };
+        caps.apply(&params.default);
+
+        let identities: Vec<_> = identities.into_iter().collect();
+        for caps_override in &params.overrides {
+            let applies = caps_override
+                .identities
+                .iter()
+                .any(|identity| identities.contains(&identity.as_str()));
+            if applies {
+                caps.apply(&caps_override.changes);
+            }
+        }
+        caps
+    }
+
+    fn apply(&mut self, changes: &WireprotoCapsChanges) {
+        for cap in &changes.disable {
+            self.wireproto
+                .retain(|existing| split_capability(existing).0 != cap.as_str());
+        }
+        for cap in &changes.enable {
+            let name = split_capability(cap).0;
+            self.wireproto
+                .retain(|existing| split_capability(existing).0 != name);
+            self.wireproto.push(cap.clone());
+        }
+
+        for name in &changes.disable_bundle2 {
+            self.bundle2.retain(|(existing, _)| existing != name);
+        }
+        for cap in &changes.enable_bundle2 {
+            let (name, values) = split_capability(cap);
+            match self
+                .bundle2
+                .iter_mut()
+                .find(|(existing, _)| existing == name)
+            {
+                Some((_, existing_values)) => {
+                    for value in values {
+                        if !existing_values.contains(&value) {
+                            existing_values.push(value);
+                        }
+                    }
+                }
+                None => self.bundle2.push((name.to_string(), values)),
+            }
+        }
+    }
+
+    /// The wireproto capabilities to send in reply to hello, including the bundle2 ones
+    pub fn into_wireproto(self) -> Vec<String> {
+        let bundle2: Vec<_> = self
+            .bundle2
+            .into_iter()
+            .map(|(name, values)| {
+                if values.is_empty() {
+                    name
+                } else {
+                    format!("{}={}", name, values.join(","))
+                }
+            })
+            .collect();
+
+        let mut caps = self.wireproto;
+        caps.push(format!("bundle2={}", percent_encode(&bundle2.join("\n"))));
+        caps
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use metaconfig_types::WireprotoCapsOverride;
+
+    fn params() -> WireprotoCapsParams {
+        WireprotoCapsParams {
+            default: WireprotoCapsChanges {
+                disable: vec!["stream-preferred".to_string()],
+                ..Default::default()
+            },
+            overrides: vec![WireprotoCapsOverride {
+                identities: vec!["canary".to_string()],
+                changes: WireprotoCapsChanges {
+                    enable: vec!["lfs".to_string(), "unbundle=HG10UN".to_string()],
+                    enable_bundle2: vec!["changegroup=03".to_string(), "narrow".to_string()],
+                    disable_bundle2: vec!["b2x:rebase".to_string()],
+                    ..Default::default()
+                },
+            }],
+        }
+    }
+
+    #[test]
+    fn unconfigured() {
+        let caps = Capabilities::for_client(&Default::default(), vec!["alice"]);
+        assert_eq!(caps.wireproto, wireprotocaps());
+        assert_eq!(caps.bundle2, bundle2caps());
+    }
+
+    #[test]
+    fn default_changes() {
+        let caps = Capabilities::for_client(&params(), vec!["alice"]);
+        assert!(!caps.wireproto.contains(&"stream-preferred".to_string()));
+        assert!(!caps.wireproto.contains(&"lfs".to_string()));
+        assert_eq!(caps.bundle2, bundle2caps());
+    }
+
+    #[test]
+    fn overrides() {
+        let caps = Capabilities::for_client(&params(), vec!["alice", "canary"]);
+        assert!(!caps.wireproto.contains(&"stream-preferred".to_string()));
+        assert!(caps.wireproto.contains(&"lfs".to_string()));
+        // Enabling a capability that's already advertised replaces its values
+        let unbundle: Vec<_> = caps
+            .wireproto
+            .iter()
+            .filter(|cap| cap.starts_with("unbundle"))
+            .collect();
+        assert_eq!(unbundle, vec!["unbundle=HG10UN"]);
+
+        let changegroup = caps.bundle2.iter().find(|(name, _)| name == "changegroup");
+        assert_eq!(
+            changegroup,
+            Some(&(
+                "changegroup".to_string(),
+                vec!["02".to_string(), "03".to_string()]
+            ))
+        );
+        assert!(caps.bundle2.contains(&("narrow".to_string(), vec![])));
+        assert!(!caps.bundle2.iter().any(|(name, _)| name == "b2x:rebase"));
+    }
+
+    #[test]
+    fn bundle2_encoding() {
+        let caps = Capabilities {
+            wireproto: vec!["lookup".to_string()],
+            bundle2: vec![
+                ("HG20".to_string(), vec![]),
+                (
+                    "changegroup".to_string(),
+                    vec!["02".to_string(), "03".to_string()],
+                ),
+            ],
+        };
+        assert_eq!(
+            caps.into_wireproto(),
+            vec![
+                "lookup".to_string(),
+                format!("bundle2={}", percent_encode("HG20\nchangegroup=02,03")),
+            ]
+        );
+    }
+}
@@ -13,49 +13,89 @@ use context::CoreContext;
 use errors::*;
 use failure::err_msg;
 use fbwhoami::FbWhoAmI;
-use futures::future::ok;
+use futures::future::{ok, Loop};
 use futures::{future, stream, stream::empty, Async, Future, IntoFuture, Poll, Stream};
 use futures_ext::{select_all, BoxFuture, BoxStream, FutureExt, StreamExt, StreamTimeoutError};
-use futures_stats::{StreamStats, Timed, TimedStreamTrait};
+use futures_stats::{Timed, TimedStreamTrait};
 use hgproto::{self, GetbundleArgs, GettreepackArgs, HgCommandRes, HgCommands};
 use hooks::HookManager;
 use itertools::Itertools;
 use mercurial_bundles::{create_bundle_stream, parts, wirepack, Bundle2Item};
+use mercurial_types::manifest::Content;
 use mercurial_types::manifest_utils::{
-    changed_entry_stream_with_pruner, CombinatorPruner, DeletedPruner, EntryStatus, FilePruner,
-    Pruner, VisitedPruner,
+    ChangedEntry, CombinatorPruner, DeletedPruner, EntryStatus, FilePruner, Pruner, VisitedPruner,
 };
 use mercurial_types::{
-    convert_parents_to_remotefilelog_format, percent_encode, Delta, Entry, HgBlobNode,
-    HgChangesetId, HgFileNodeId, HgManifestId, HgNodeHash, MPath, RepoPath, Type, NULL_CSID,
-    NULL_HASH,
+    convert_parents_to_remotefilelog_format, encodedir, Delta, Entry, HgBlobNode, HgChangesetId,
+    HgEntryId, HgFileNodeId, HgManifestId, HgNodeHash, MPath, Manifest, RepoPath, Type, NULL_HASH,
 };
 use metaconfig_types::{LfsParams, RepoReadOnly};
 use mononoke_repo::{MononokeRepo, SqlStreamingCloneConfig};
+use mononoke_types::ChangesetId;
+use path_acl::PathAccess;
 use percent_encoding;
 use phases::{Phase, Phases};
+use qos::QosPool;
+use quarantine::Quarantine;
 use rand::{self, Rng};
 use reachabilityindex::LeastCommonAncestorsHint;
 use remotefilelog::{
-    self, create_remotefilelog_blob, get_unordered_file_history_for_multiple_nodes,
+    self, create_remotefilelog_blob, get_file_history_page, HistoryCursor, HistoryLimits,
 };
-use scribe::ScribeClient;
-use scuba_ext::{ScribeClientImplementation, ScubaSampleBuilder, ScubaSampleBuilderExt};
+use request_logging::{
+    FutureLoggingExt, RequestLoggerBuilder, RequestSinks, ResponseStats, ScribeSink, ScubaSink,
+    StreamLoggingExt,
+};
+use response_cache::{CachedTree, ResponseCache};
+use scuba_ext::ScubaSampleBuilderExt;
 use serde_json;
-use stats::Histogram;
+use stats::{Histogram, Timeseries};
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::mem;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use streaming_clone::RevlogStreamingChunks;
 use time_ext::DurationExt;
+use tokio;
 use tokio::timer::timeout::Error as TimeoutError;
 use tokio::util::FutureExt as TokioFutureExt;
 use tracing::Traced;
+use tree_popularity::TreePrefetch;
+
+mod audit;
+mod capabilities;
+mod session;
+mod telemetry;
+
+use self::audit::AuditSink;
+use self::capabilities::Capabilities;
+use self::session::SessionCapabilities;
+use self::telemetry::{ServerTelemetry, SERVER_TELEMETRY_CAP};
 
 const MAX_NODES_TO_LOG: usize = 5;
+/// Number of prefetched trees fetched at once. Kept low, as the fetches in flight when the
+/// prefetch goes over budget are wasted.
+const PREFETCH_CONCURRENCY: usize = 10;
+
+/// The most draft commits that listkeys walks to find the roots of the draft commits. Past
+/// that, the commits left to walk are listed as roots instead.
+const MAX_DRAFT_COMMITS: usize = 10_000;
+
+/// The most history entries getpackv1 sends for a file. Clients fetch the history beyond that
+/// when they need it.
+const GETPACKV1_MAX_HISTORY_ENTRIES: usize = 100_000;
+
+/// How many files getfiles and getpackv1 fetch at once, unless it's overridden
+const DEFAULT_FETCH_BUFFER_SIZE: usize = 100;
+
+// Tunables that can be overridden without restarting the server
+const HASH_VALIDATION_PERCENTAGE: &str = "hash_validation_percentage";
+const GETFILES_BUFFER_SIZE: &str = "getfiles_buffer_size";
+const GETPACKV1_BUFFER_SIZE: &str = "getpackv1_buffer_size";
 
 define_stats! {
     prefix = "mononoke.repo_client";
@@ -65,6 +105,7 @@ define_stats! {
         histogram(500, 0, 20_000, AVG, SUM, COUNT; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
     getfiles_ms:
         histogram(500, 0, 20_000, AVG, SUM, COUNT; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
+    responses_too_large: timeseries(RATE, SUM),
 }
 
 mod ops {
@@ -77,6 +118,7 @@ mod ops {
     pub static KNOWN: &str = "known";
     pub static KNOWNNODES: &str = "knownnodes";
     pub static BETWEEN: &str = "between";
+    pub static BRANCHMAP: &str = "branchmap";
     pub static GETBUNDLE: &str = "getbundle";
     pub static GETTREEPACK: &str = "gettreepack";
     pub static GETFILES: &str = "getfiles";
@@ -123,81 +165,6 @@ fn process_stream_timeout_error(err: StreamTimeoutError) -> Error {
     }
 }
 
-fn wireprotocaps() -> Vec<String> {
-    vec![
-        "clienttelemetry".to_string(),
-        "lookup".to_string(),
-        "known".to_string(),
-        "getbundle".to_string(),
-        "unbundle=HG10GZ,HG10BZ,HG10UN".to_string(),
-        "gettreepack".to_string(),
-        "remotefilelog".to_string(),
-        "pushkey".to_string(),
-        "stream-preferred".to_string(),
-        "stream_option".to_string(),
-        "streamreqs=generaldelta,lz4revlog,revlogv1".to_string(),
-        "treeonly".to_string(),
-        "knownnodes".to_string(),
-    ]
-}
-
-fn bundle2caps() -> String {
-    let caps = vec![
-        ("HG20", vec![]),
-        // Note that "listkeys" is *NOT* returned as a bundle2 capability; that's because there's
-        // a race that can happen. Here's how:
-        // 1. The client does discovery to figure out which heads are missing.
-        // 2. At this point, a frequently updated bookmark (say "master") moves forward.
-        // 3. The client requests the heads discovered in step 1 + the latest value of master.
-        // 4. The server returns changesets up to those heads, plus the latest version of master.
-        //
-        // master doesn't point to a commit that will exist on the client at the end of the pull,
-        // so the client ignores it.
-        //
-        // The workaround here is to force bookmarks to be sent before discovery happens. Disabling
-        // the listkeys capabilities causes the Mercurial client to do that.
-        //
-        // A better fix might be to snapshot and maintain the bookmark state on the server at the
-        // start of discovery.
-        //
-        // The best fix here would be to change the protocol to represent bookmark pulls
-        // atomically.
-        //
-        // Some other notes:
-        // * Stock Mercurial doesn't appear to have this problem. @rain1 hasn't verified why, but
-        //   believes it's because bookmarks get loaded up into memory before discovery and then
-        //   don't get reloaded for the duration of the process. (In Mononoke, this is the
-        //   "snapshot and maintain the bookmark state" approach mentioned above.)
-        // * There's no similar race with pushes updating bookmarks, so "pushkey" is still sent
-        //   as a capability.
-        // * To repro the race, run test-bookmark-race.t with the following line enabled.
-
-        // ("listkeys", vec![]),
-        ("changegroup", vec!["02"]),
-        ("b2x:infinitepush", vec![]),
-        ("b2x:infinitepushscratchbookmarks", vec![]),
-        ("pushkey", vec![]),
-        ("treemanifestserver", vec!["True"]),
-        ("b2x:rebase", vec![]),
-        ("b2x:rebasepackpart", vec![]),
-        ("phases", vec!["heads"]),
-    ];
-
-    let mut encodedcaps = vec![];
-
-    for &(ref key, ref value) in &caps {
-        let encodedkey = key.to_string();
-        if value.len() > 0 {
-            let encodedvalue = value.join(",");
-            encodedcaps.push([encodedkey, encodedvalue].join("="));
-        } else {
-            encodedcaps.push(encodedkey)
-        }
-    }
-
-    percent_encode(&encodedcaps.join("\n"))
-}
-
 #[derive(Clone)]
 pub struct RepoClient {
     repo: MononokeRepo,
@@ -209,99 +176,22 @@ pub struct RepoClient {
     phases_hint: Arc<Phases>,
     // Whether to save raw bundle2 content into the blobstore
     preserve_raw_bundle2: bool,
-}
-
-// Logs wireproto requests both to scuba and scribe.
-// Scuba logs are used for analysis of performance of both shadow and prod Mononoke tiers
-// Scribe logs are used for replaying prod wireproto requests on shadow tier. So
-// Scribe logging should be disabled on shadow tier.
-struct WireprotoLogger {
-    scuba_logger: ScubaSampleBuilder,
-    scribe_client: Arc<ScribeClientImplementation>,
-    // This scribe category main purpose is to tail the prod requests and replay them
-    // on shadow tier.
-    wireproto_scribe_category: Option<String>,
-    wireproto_command: &'static str,
-    args: Option<serde_json::Value>,
-    reponame: String,
-}
-
-impl WireprotoLogger {
-    fn new(
-        scuba_logger: ScubaSampleBuilder,
-        wireproto_command: &'static str,
-        args: Option<serde_json::Value>,
-        wireproto_scribe_category: Option<String>,
-        reponame: String,
-    ) -> Self {
-        let mut logger = Self {
-            scuba_logger,
-            scribe_client: Arc::new(ScribeClientImplementation::new()),
-            wireproto_scribe_category,
-            wireproto_command,
-            args: args.clone(),
-            reponame,
-        };
-        logger.scuba_logger.add("command", logger.wireproto_command);
-
-        if let Some(args) = args.clone() {
-            if let Ok(args) = serde_json::to_string(&args) {
-                logger.add_trimmed_scuba_field("command_args", args);
-            }
-        }
-        logger.args = args;
-
-        logger.scuba_logger.log_with_msg("Start processing", None);
-        logger
-    }
-
-    fn set_args(&mut self, args: Option<serde_json::Value>) {
-        self.args = args;
-    }
-
-    fn add_trimmed_scuba_field(&mut self, args_name: &str, args: String) {
-        // Scuba does not support columns that are too long, we have to trim it
-        let limit = ::std::cmp::min(args.len(), 1000);
-        self.scuba_logger.add(args_name, &args[..limit]);
-    }
-
-    fn add_perf_counters_from_ctx(&mut self, key: &str, ctx: CoreContext) {
-        if let Ok(counters) = serde_json::to_string(&ctx.perf_counters()) {
-            self.add_trimmed_scuba_field(key, counters);
-        }
-    }
-
-    fn finish_stream_wireproto_processing(&mut self, stats: &StreamStats, ctx: CoreContext) {
-        self.scuba_logger
-            .add_stream_stats(&stats)
-            .log_with_msg("Command processed", None);
-
-        if let Some(ref wireproto_scribe_category) = self.wireproto_scribe_category {
-            let mut builder = ScubaSampleBuilder::with_discard();
-            builder.add_common_server_data();
-            match self.args {
-                Some(ref args) => {
-                    builder.add("args", args.to_string());
-                }
-                None => {
-                    builder.add("args", "");
-                }
-            };
-            builder.add("command", self.wireproto_command);
-            builder.add("duration", stats.completion_time.as_millis_unchecked());
-            builder.add("source_control_server_type", "mononoke");
-            builder.add("mononoke_session_uuid", ctx.session().to_string());
-            builder.add("reponame", self.reponame.as_str());
-
-            // We can't really do anything with the errors, so let's ignore it
-            let sample = builder.get_sample();
-            if let Ok(sample_json) = sample.to_json() {
-                let _ = self
-                    .scribe_client
-                    .offer(&wireproto_scribe_category, &sample_json.to_string());
-            }
-        }
-    }
+    // What the client told us it supports earlier in this connection
+    session: SessionCapabilities,
+    // Maximum number of bytes in the response to a command, by command
+    response_size_limits: Arc<HashMap<String, u64>>,
+    // The pool that the expensive commands of this session are served from
+    qos: QosPool,
+    // Where requests that crash the server or hit corrupt data are put, if anywhere
+    quarantine: Option<Arc<Quarantine>>,
+    // Where the requests of this session are logged
+    sinks: RequestSinks,
+    // The paths the client may read
+    path_access: PathAccess,
+    // The tier of the server, for clienttelemetry
+    server_tier: Option<String>,
+    // The heads that getbundle served in this session, whose draft roots listkeys lists
+    served_heads: Arc<Mutex<HashSet<HgChangesetId>>>,
 }
 
 impl RepoClient {
@@ -312,7 +202,20 @@ impl RepoClient {
         lca_hint: Arc<LeastCommonAncestorsHint>,
         phases_hint: Arc<Phases>,
         preserve_raw_bundle2: bool,
+        response_size_limits: Arc<HashMap<String, u64>>,
+        qos: QosPool,
+        quarantine: Option<Arc<Quarantine>>,
+        path_access: PathAccess,
+        server_tier: Option<String>,
     ) -> Self {
+        let mut sinks = RequestSinks::new()
+            .with_sink(Arc::new(ScubaSink))
+            .with_sink(Arc::new(ScribeSink::new(repo.reponame().clone())));
+        if let Some(auditor) = repo.auditor().clone() {
+            let repo_id = repo.blobrepo().get_repoid();
+            sinks = sinks.with_sink(Arc::new(AuditSink::new(repo_id, auditor)));
+        }
+
         RepoClient {
             repo,
             ctx,
@@ -320,70 +223,111 @@ impl RepoClient {
             lca_hint,
             phases_hint,
             preserve_raw_bundle2,
+            session: SessionCapabilities::new(),
+            response_size_limits,
+            qos,
+            quarantine,
+            sinks,
+            path_access,
+            server_tier,
+            served_heads: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
-    fn prepared_ctx(&self, op: &str, args: Option<String>) -> CoreContext {
-        self.ctx.with_scuba_initialization(|mut scuba_logger| {
-            scuba_logger.add("command", op);
-
-            if let Some(args) = args {
-                scuba_logger.add("command_args", args);
-            }
-
-            scuba_logger.log_with_msg("Start processing", None);
-            scuba_logger
-        })
+    fn request_logger(&self, command: &'static str) -> RequestLoggerBuilder {
+        self.sinks.request(&self.ctx, command)
     }
 
     fn create_bundle(&self, args: GetbundleArgs) -> Result<BoxStream<Bytes, Error>> {
         let blobrepo = self.repo.blobrepo();
         let mut bundle2_parts = vec![];
 
-        let mut use_phases = args.phases;
-        if use_phases {
-            for cap in args.bundlecaps {
-                if let Some((cap_name, caps)) = parse_utf8_getbundle_caps(&cap) {
-                    if cap_name != "bundle2" {
-                        continue;
-                    }
-                    if let Some(phases) = caps.get("phases") {
-                        use_phases = phases.contains("heads");
-                        break;
-                    }
+        for cap in &args.bundlecaps {
+            if let Some((cap_name, caps)) = parse_utf8_getbundle_caps(cap) {
+                if cap_name == "bundle2" {
+                    self.session.negotiate(caps);
                 }
             }
         }
 
-        bundle2_parts.append(&mut bundle2_resolver::create_getbundle_response(
-            self.ctx.clone(),
-            blobrepo.clone(),
-            args.common
-                .into_iter()
-                .map(|head| HgChangesetId::new(head))
-                .collect(),
-            args.heads
-                .into_iter()
-                .map(|head| HgChangesetId::new(head))
-                .collect(),
-            self.lca_hint.clone(),
-            if use_phases {
-                Some(self.phases_hint.clone())
-            } else {
-                None
-            },
-        )?);
+        // The phases capability may come from an earlier getbundle of the session
+        let use_phases = args.phases
+            && match self.session.get("phases") {
+                Some(phases) => phases.contains("heads"),
+                None => true,
+            };
+
+        let heads: Vec<_> = args
+            .heads
+            .into_iter()
+            .map(|head| HgChangesetId::new(head))
+            .collect();
+        self.served_heads
+            .lock()
+            .expect("lock poisoned")
+            .extend(heads.iter().cloned());
+        let repo_id = blobrepo.get_repoid();
+
+        let commits = match args.resumetoken {
+            Some(token) => {
+                // The client already found what it's missing, in the pull it resumes
+                let resumable_pull = self
+                    .repo
+                    .resumable_pull()
+                    .as_ref()
+                    .ok_or(ErrorKind::ResumablePullDisabled)?;
+                resumable_pull.resume(self.ctx.clone(), repo_id, token, args.resumefrom)
+            }
+            None => {
+                let commits = bundle2_resolver::find_commits_to_send(
+                    self.ctx.clone(),
+                    blobrepo.clone(),
+                    args.common
+                        .into_iter()
+                        .map(|head| HgChangesetId::new(head))
+                        .collect(),
+                    heads.clone(),
+                    self.lca_hint.clone(),
+                )?;
+                match self.repo.resumable_pull() {
+                    Some(resumable_pull) if args.resumable => {
+                        let (token, commits) =
+                            resumable_pull.save(self.ctx.clone(), repo_id, commits);
+                        // The token goes first, so that the client has it even if the
+                        // changegroup is interrupted
+                        bundle2_parts.push(parts::resume_token_part(token)?);
+                        commits
+                    }
+                    _ => commits,
+                }
+            }
+        };
+
+        bundle2_parts.append(
+            &mut bundle2_resolver::create_getbundle_response_for_commits(
+                self.ctx.clone(),
+                blobrepo.clone(),
+                heads,
+                commits,
+                if use_phases {
+                    Some(self.phases_hint.clone())
+                } else {
+                    None
+                },
+            )?,
+        );
 
         // listkeys bookmarks part is added separately.
 
         // XXX Note that listkeys is NOT returned as a bundle2 capability -- see comment in
-        // bundle2caps() for why.
+        // capabilities::bundle2caps() for why.
 
         // TODO: generalize this to other listkey types
         // (note: just calling &b"bookmarks"[..] doesn't work because https://fburl.com/0p0sq6kp)
         if args.listkeys.contains(&b"bookmarks".to_vec()) {
-            let items = blobrepo
-                .get_bookmarks_maybe_stale(self.ctx.clone())
+            let items = self
+                .repo
+                .get_publishing_bookmarks_maybe_stale(self.ctx.clone())
                 .map(|(name, cs)| {
                     let hash: Vec<u8> = cs.into_nodehash().to_hex().into();
                     (name.to_string(), hash)
@@ -407,17 +351,30 @@ impl RepoClient {
             return stream::once(Err(err_msg("directories param is not supported"))).boxify();
         }
 
-        // TODO(stash): T25850889 only one basemfnodes is used. That means that trees that client
-        // already has can be sent to the client.
-        let basemfnode = params.basemfnodes.get(0).cloned().unwrap_or(NULL_HASH);
-
         let rootpath = if params.rootdir.is_empty() {
             None
         } else {
             Some(try_boxstream!(MPath::new(params.rootdir)))
         };
 
-        let default_pruner = CombinatorPruner::new(FilePruner, DeletedPruner);
+        // Fails the response before anything is sent if the client can't read what it asks for
+        let root_repo_path = match rootpath {
+            Some(ref path) => RepoPath::DirectoryPath(path.clone()),
+            None => RepoPath::RootPath,
+        };
+        let access_checks = future::join_all(
+            params
+                .mfnodes
+                .iter()
+                .map(|mfnode| self.check_path_access(root_repo_path.clone(), *mfnode))
+                .collect::<Vec<_>>(),
+        );
+
+        // Trees that the client can't read are neither sent nor walked into
+        let default_pruner = CombinatorPruner::new(
+            CombinatorPruner::new(FilePruner, DeletedPruner),
+            self.path_access.pruner(),
+        );
 
         let changed_entries = if params.mfnodes.len() > 1 {
             let visited_pruner = VisitedPruner::new();
@@ -426,7 +383,7 @@ impl RepoClient {
                     self.ctx.clone(),
                     self.repo.blobrepo(),
                     *manifest_id,
-                    basemfnode,
+                    &params.basemfnodes,
                     rootpath.clone(),
                     CombinatorPruner::new(default_pruner.clone(), visited_pruner.clone()),
                     fetchdepth,
@@ -439,7 +396,7 @@ impl RepoClient {
                     self.ctx.clone(),
                     self.repo.blobrepo(),
                     *mfnode,
-                    basemfnode,
+                    &params.basemfnodes,
                     rootpath.clone(),
                     default_pruner,
                     fetchdepth,
@@ -448,15 +405,23 @@ impl RepoClient {
             }
         };
 
-        let validate_hash = rand::random::<usize>() % 100 < self.hash_validation_percentage;
+        let validate_hash = rand::random::<usize>() % 100 < self.hash_validation_percentage();
+        // Shared with the prefetch, so that it doesn't send trees that are already sent
+        let used_hashes = Arc::new(Mutex::new(HashSet::new()));
         let changed_entries = changed_entries
             .filter({
-                let mut used_hashes = HashSet::new();
-                move |entry| used_hashes.insert(entry.0.get_hash())
+                cloned!(used_hashes);
+                move |entry| {
+                    used_hashes
+                        .lock()
+                        .expect("lock poisoned")
+                        .insert(entry.0.get_hash())
+                }
             })
             .map({
                 cloned!(self.ctx);
                 let blobrepo = self.repo.blobrepo().clone();
+                let response_cache = self.repo.response_cache().clone();
                 move |(entry, basepath)| {
                     ctx.perf_counters()
                         .increment_counter("gettreepack_num_treepacks");
@@ -466,33 +431,238 @@ impl RepoClient {
                         entry,
                         basepath,
                         validate_hash,
+                        response_cache.as_ref(),
                     )
                 }
             });
 
-        let part = parts::treepack_part(changed_entries);
+        let prefetched_entries = match self.repo.tree_prefetch() {
+            Some(prefetch) => self.prefetch_popular_trees(
+                prefetch.clone(),
+                rootpath,
+                params.mfnodes.clone(),
+                used_hashes,
+                validate_hash,
+            ),
+            None => empty().boxify(),
+        };
+
+        let part = parts::treepack_part(changed_entries.chain(prefetched_entries));
         // Mercurial currently hangs while trying to read compressed bundles over the wire:
         // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
         // TODO: possibly enable compression support once this is fixed.
         let compression = None;
-        part.into_future()
+        access_checks
+            .and_then(move |_| part)
             .map(move |part| create_bundle_stream(vec![part], compression))
             .flatten_stream()
             .boxify()
     }
 
-    fn wireproto_logger(
+    /// Trees of the directories under `rootpath` that clients asked for most, in the manifests
+    /// `mfnodes`. Trees that are in `used_hashes` are already sent and are skipped. Stops before
+    /// the prefetched trees go over the size budget.
+    ///
+    /// Also records that `rootpath` was asked for, to keep the popularity up to date.
+    fn prefetch_popular_trees(
+        &self,
+        prefetch: TreePrefetch,
+        rootpath: Option<MPath>,
+        mfnodes: Vec<HgNodeHash>,
+        used_hashes: Arc<Mutex<HashSet<HgEntryId>>>,
+        validate_hash: bool,
+    ) -> BoxStream<BoxFuture<parts::TreepackPartInput, Error>, Error> {
+        let TreePrefetch { params, popularity } = prefetch;
+        let ctx = self.ctx.clone();
+        let logger = ctx.logger().clone();
+        let blobrepo = self.repo.blobrepo().clone();
+        let repo_id = blobrepo.get_repoid();
+        let response_cache = self.repo.response_cache().clone();
+        let path_access = self.path_access.clone();
+
+        // Prefetching is best effort: the response mustn't wait for, or fail because of, the
+        // popularity store.
+        if let Some(ref path) = rootpath {
+            tokio::spawn(
+                popularity
+                    .record(ctx.clone(), repo_id, path.clone())
+                    .map_err({
+                        cloned!(logger);
+                        move |err| warn!(logger, "failed to record tree popularity: {:?}", err)
+                    }),
+            );
+        }
+
+        let entries = popularity
+            .popular_children(
+                ctx.clone(),
+                repo_id,
+                rootpath.clone(),
+                params.children_limit,
+            )
+            .or_else(move |err| {
+                warn!(logger, "failed to get popular trees: {:?}", err);
+                Ok(vec![])
+            })
+            .map({
+                cloned!(ctx, blobrepo);
+                move |names| {
+                    stream::iter_ok(mfnodes)
+                        .map(move |mfnode| {
+                            blobrepo.get_manifest_by_nodeid(ctx.clone(), HgManifestId::new(mfnode))
+                        })
+                        .buffered(10)
+                        .map(move |manifest| {
+                            let entries: Vec<_> = names
+                                .iter()
+                                .filter_map(|name| manifest.lookup(name))
+                                .filter(|entry| entry.get_type() == Type::Tree)
+                                .filter(|entry| {
+                                    let path = MPath::join_element_opt(
+                                        rootpath.as_ref(),
+                                        entry.get_name(),
+                                    );
+                                    path_access.can_read(path.as_ref())
+                                })
+                                .map(|entry| (entry, rootpath.clone()))
+                                .collect();
+                            stream::iter_ok(entries)
+                        })
+                        .flatten()
+                }
+            })
+            .flatten_stream()
+            .filter(move |entry| {
+                used_hashes
+                    .lock()
+                    .expect("lock poisoned")
+                    .insert(entry.0.get_hash())
+            });
+
+        fetch_within_budget(entries, params.size_budget, move |(entry, basepath)| {
+            ctx.perf_counters()
+                .increment_counter("gettreepack_num_prefetched_treepacks");
+            fetch_treepack_part_input(
+                ctx.clone(),
+                &blobrepo,
+                entry,
+                basepath,
+                validate_hash,
+                response_cache.as_ref(),
+            )
+        })
+        .map(|input| future::ok(input).boxify())
+        .boxify()
+    }
+
+    /// Percent of returned entries to validate the hashes of: the configured one, unless it's
+    /// overridden.
+    fn hash_validation_percentage(&self) -> usize {
+        let percentage = self
+            .repo
+            .overrides()
+            .get_or(HASH_VALIDATION_PERCENTAGE, self.hash_validation_percentage);
+        cmp::min(percentage, 100)
+    }
+
+    /// How many files of a request to fetch at once, as overridden by tunable `name`
+    fn fetch_buffer_size(&self, name: &str) -> usize {
+        let size = self
+            .repo
+            .overrides()
+            .get_or(name, DEFAULT_FETCH_BUFFER_SIZE);
+        cmp::max(size, 1)
+    }
+
+    /// LFS settings for the files sent to this client. Clients that told us they can't read LFS
+    /// pointers get the full contents of large files.
+    fn lfs_params(&self) -> LfsParams {
+        match self.session.enabled("lfs") {
+            Some(false) => LfsParams::default(),
+            _ => self.repo.lfs_params().clone(),
+        }
+    }
+
+    /// Fails a response once it gets larger than the limit for `command`, so that a single
+    /// request can't make us send most of the repo. Responses aren't truncated instead, as
+    /// clients can't make sense of partial bundles or packs. `args` are logged on failure.
+    fn limit_response_size(
         &self,
-        wireproto_command: &'static str,
+        command: &'static str,
         args: Option<serde_json::Value>,
-    ) -> WireprotoLogger {
-        WireprotoLogger::new(
-            self.ctx.scuba().clone(),
-            wireproto_command,
-            args,
-            self.ctx.wireproto_scribe_category().clone(),
-            self.repo.reponame().clone(),
-        )
+    ) -> impl FnMut(Bytes) -> Result<Bytes> + Send + 'static {
+        let limit = self.response_size_limits.get(command).cloned();
+        response_size_limiter(self.ctx.clone(), command, limit, args)
+    }
+
+    /// Serve `response` from the QoS pool of this session, which it may have to wait for. The
+    /// response fails if it isn't over within the timeout of the pool, `default_timeout` if the
+    /// pool has none.
+    fn qos_limited<S>(&self, response: S, default_timeout: Duration) -> BoxStream<S::Item, Error>
+    where
+        S: Stream<Error = Error> + Send + 'static,
+        S::Item: Send + 'static,
+    {
+        self.qos
+            .run_stream(response)
+            .whole_stream_timeout(self.qos.timeout_or(default_timeout))
+            .map_err(process_stream_timeout_error)
+            .boxify()
+    }
+
+    /// Fails unless the client may read `node` at `path`. Nodes aren't bound to paths, so
+    /// clients that can't read the whole repo may only fetch nodes that are known to be at the
+    /// paths they ask for them at.
+    fn check_path_access(&self, path: RepoPath, node: HgNodeHash) -> BoxFuture<(), Error> {
+        if self.path_access.is_unrestricted() {
+            return future::ok(()).boxify();
+        }
+        if !self.path_access.can_read(path.mpath()) {
+            return future::err(ErrorKind::PathAccessDenied(path).into()).boxify();
+        }
+        self.repo
+            .blobrepo()
+            .get_linknode_opt(self.ctx.clone(), &path, HgFileNodeId::new(node))
+            .and_then(move |linknode| match linknode {
+                Some(_) => Ok(()),
+                None => Err(ErrorKind::NodeNotAtPath(path, node).into()),
+            })
+            .boxify()
+    }
+
+    /// Reject `response` if its request is quarantined, and quarantine the request if serving
+    /// it crashes the server or hits corrupt data
+    fn quarantined<S>(
+        &self,
+        command: &'static str,
+        args: &serde_json::Value,
+        response: S,
+    ) -> BoxStream<S::Item, Error>
+    where
+        S: Stream<Error = Error> + Send + 'static,
+        S::Item: Send + 'static,
+    {
+        match self.quarantine {
+            Some(ref quarantine) => quarantine.guard(&self.ctx, command, args, response),
+            None => response.boxify(),
+        }
+    }
+
+    /// Like `quarantined`, for requests that are answered with a single response
+    fn quarantined_future<F>(
+        &self,
+        command: &'static str,
+        args: &serde_json::Value,
+        response: F,
+    ) -> BoxFuture<F::Item, Error>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        match self.quarantine {
+            Some(ref quarantine) => quarantine.guard_future(&self.ctx, command, args, response),
+            None => response.boxify(),
+        }
     }
 }
 
@@ -552,12 +722,16 @@ impl HgCommands for RepoClient {
             }
         }
 
-        let mut scuba_logger = self.prepared_ctx(ops::BETWEEN, None).scuba().clone();
+        let request_logger = self.request_logger(ops::BETWEEN).start();
+        let args = json!(pairs
+            .iter()
+            .map(|(top, bottom)| vec![top.to_string(), bottom.to_string()])
+            .collect::<Vec<_>>());
 
         // TODO(jsgf): do pairs in parallel?
         // TODO: directly return stream of streams
         cloned!(self.ctx, self.repo);
-        stream::iter_ok(pairs.into_iter())
+        let between = stream::iter_ok(pairs.into_iter())
             .and_then(move |(top, bottom)| {
                 let mut f = 1;
                 ParentStream::new(ctx.clone(), &repo, top, bottom)
@@ -573,45 +747,58 @@ impl HgCommands for RepoClient {
                     .map(|(_, v)| v)
                     .collect()
             })
-            .collect()
+            .collect();
+
+        self.quarantined_future(ops::BETWEEN, &args, between)
             .timeout(timeout_duration())
             .map_err(process_timeout_error)
             .traced(self.ctx.trace(), ops::BETWEEN, trace_args!())
-            .timed(move |stats, _| {
-                scuba_logger
-                    .add_future_stats(&stats)
-                    .log_with_msg("Command processed", None);
-                Ok(())
-            })
-            .boxify()
+            .log_request(request_logger)
     }
 
     // @wireprotocommand('clienttelemetry')
-    fn clienttelemetry(&self, _args: HashMap<Vec<u8>, Vec<u8>>) -> HgCommandRes<String> {
+    fn clienttelemetry(&self, args: HashMap<Vec<u8>, Vec<u8>>) -> HgCommandRes<String> {
         info!(self.ctx.logger(), "clienttelemetry");
+        self.session.negotiate_telemetry(&args);
 
         let fallback_hostname = "<no hostname found>";
-        let hostname = match FbWhoAmI::new() {
-            Ok(fbwhoami) => fbwhoami.get_name().unwrap_or(fallback_hostname).to_string(),
-            Err(_) => fallback_hostname.to_string(),
+        let fbwhoami = FbWhoAmI::new().ok();
+        let hostname = fbwhoami
+            .as_ref()
+            .and_then(|fbwhoami| fbwhoami.get_name())
+            .unwrap_or(fallback_hostname)
+            .to_string();
+        let region = fbwhoami
+            .as_ref()
+            .and_then(|fbwhoami| fbwhoami.get_region())
+            .map(|region| region.to_string());
+
+        let mut hints = vec![];
+        if self.repo.resumable_pull().is_some() {
+            hints.push("resumablepull");
+        }
+        if !self.path_access.is_unrestricted() {
+            hints.push("restrictedpaths");
+        }
+        let telemetry = ServerTelemetry {
+            hostname,
+            region,
+            tier: self.server_tier.clone(),
+            qos_class: self.qos.class_name(),
+            shedding_load: self.qos.is_saturated(),
+            session_id: self.ctx.session().to_string(),
+            hints,
         };
+        // Older clients only know how to show a hostname
+        let detailed = self.session.enabled(SERVER_TELEMETRY_CAP) == Some(true);
 
-        let mut scuba_logger = self
-            .prepared_ctx(ops::CLIENTTELEMETRY, None)
-            .scuba()
-            .clone();
+        let request_logger = self.request_logger(ops::CLIENTTELEMETRY).start();
 
-        future::ok(hostname)
+        future::ok(telemetry.encode(detailed))
             .timeout(timeout_duration())
             .map_err(process_timeout_error)
             .traced(self.ctx.trace(), ops::CLIENTTELEMETRY, trace_args!())
-            .timed(move |stats, _| {
-                scuba_logger
-                    .add_future_stats(&stats)
-                    .log_with_msg("Command processed", None);
-                Ok(())
-            })
-            .boxify()
+            .log_request(request_logger)
     }
 
     // @wireprotocommand('heads')
@@ -619,24 +806,43 @@ impl HgCommands for RepoClient {
         // Get a stream of heads and collect them into a HashSet
         // TODO: directly return stream of heads
         info!(self.ctx.logger(), "heads");
-        let mut scuba_logger = self.prepared_ctx(ops::HEADS, None).scuba().clone();
+        let request_logger = self.request_logger(ops::HEADS).start();
 
-        self.repo
+        let heads = self
+            .repo
             .blobrepo()
             .get_heads_maybe_stale(self.ctx.clone())
             .collect()
             .map(|v| v.into_iter().collect())
-            .from_err()
+            .from_err();
+
+        self.quarantined_future(ops::HEADS, &json!([]), heads)
             .timeout(timeout_duration())
             .map_err(process_timeout_error)
             .traced(self.ctx.trace(), ops::HEADS, trace_args!())
-            .timed(move |stats, _| {
-                scuba_logger
-                    .add_future_stats(&stats)
-                    .log_with_msg("Command processed", None);
-                Ok(())
-            })
-            .boxify()
+            .log_request(request_logger)
+    }
+
+    // @wireprotocommand('branchmap')
+    fn branchmap(&self) -> HgCommandRes<HashMap<String, HashSet<HgNodeHash>>> {
+        // Mononoke has no named branches, so all heads are on the default branch, as they are in
+        // a Mercurial repo that only uses bookmarks
+        info!(self.ctx.logger(), "branchmap");
+        let request_logger = self.request_logger(ops::BRANCHMAP).start();
+
+        let branchmap = self
+            .repo
+            .blobrepo()
+            .get_heads_maybe_stale(self.ctx.clone())
+            .collect()
+            .map(|heads| hashmap! { "default".to_string() => heads.into_iter().collect() })
+            .from_err();
+
+        self.quarantined_future(ops::BRANCHMAP, &json!([]), branchmap)
+            .timeout(timeout_duration())
+            .map_err(process_timeout_error)
+            .traced(self.ctx.trace(), ops::BRANCHMAP, trace_args!())
+            .log_request(request_logger)
     }
 
     // @wireprotocommand('lookup', 'key')
@@ -644,7 +850,7 @@ impl HgCommands for RepoClient {
         info!(self.ctx.logger(), "lookup: {:?}", key);
         // TODO(stash): T25928839 lookup should support prefixes
         let repo = self.repo.blobrepo().clone();
-        let mut scuba_logger = self.prepared_ctx(ops::LOOKUP, None).scuba().clone();
+        let request_logger = self.request_logger(ops::LOOKUP).start();
 
         fn generate_resp_buf(success: bool, message: &[u8]) -> Bytes {
             let mut buf = BytesMut::with_capacity(message.len() + 3);
@@ -659,6 +865,8 @@ impl HgCommands for RepoClient {
             buf.freeze()
         }
 
+        // Scratch bookmarks are left out of listkeys and pulls, so looking them up by their exact
+        // name is the only way for clients to find them.
         fn check_bookmark_exists(
             ctx: CoreContext,
             repo: BlobRepo,
@@ -700,17 +908,11 @@ impl HgCommands for RepoClient {
                 .boxify(),
         };
 
-        lookup_fut
+        self.quarantined_future(ops::LOOKUP, &json!([key]), lookup_fut)
             .timeout(timeout_duration())
             .map_err(process_timeout_error)
             .traced(self.ctx.trace(), ops::LOOKUP, trace_args!())
-            .timed(move |stats, _| {
-                scuba_logger
-                    .add_future_stats(&stats)
-                    .log_with_msg("Command processed", None);
-                Ok(())
-            })
-            .boxify()
+            .log_request(request_logger)
     }
 
     // @wireprotocommand('known', 'nodes *'), but the '*' is ignored
@@ -726,15 +928,16 @@ impl HgCommands for RepoClient {
         }
         let blobrepo = self.repo.blobrepo().clone();
 
-        let mut scuba_logger = self.prepared_ctx(ops::KNOWN, None).scuba().clone();
+        let mut request_logger = self.request_logger(ops::KNOWN).start();
 
+        let args = json!([format_nodes_list(&nodes)]);
         let nodes: Vec<_> = nodes.into_iter().map(HgChangesetId::new).collect();
         let nodes_len = nodes.len();
 
         let phases_hint = self.phases_hint.clone();
 
         cloned!(self.ctx);
-        blobrepo
+        let known = blobrepo
             .get_hg_bonsai_mapping(ctx.clone(), nodes.clone())
             .map(|hg_bcs_mapping| {
                 let mut bcs_ids = vec![];
@@ -769,24 +972,18 @@ impl HgCommands for RepoClient {
                     .into_iter()
                     .map(move |node| found_hg_changesets.contains(&node))
                     .collect::<Vec<_>>()
-            })
+            });
+
+        self.quarantined_future(ops::KNOWN, &args, known)
             .timeout(timeout_duration())
             .map_err(process_timeout_error)
             .traced(self.ctx.trace(), ops::KNOWN, trace_args!())
             .timed(move |stats, known_nodes| {
                 if let Ok(known) = known_nodes {
-                    let extra_context = json!({
-                        "num_known": known.len(),
-                        "num_unknown": nodes_len - known.len(),
-                    })
-                    .to_string();
-
-                    scuba_logger.add("extra_context", extra_context);
+                    request_logger.add_extra_context("num_known", known.len());
+                    request_logger.add_extra_context("num_unknown", nodes_len - known.len());
                 }
-
-                scuba_logger
-                    .add_future_stats(&stats)
-                    .log_with_msg("Command processed", None);
+                request_logger.finish(ResponseStats::Future(&stats));
                 Ok(())
             })
             .boxify()
@@ -795,11 +992,12 @@ impl HgCommands for RepoClient {
     fn knownnodes(&self, nodes: Vec<HgChangesetId>) -> HgCommandRes<Vec<bool>> {
         let blobrepo = self.repo.blobrepo().clone();
 
-        let mut scuba_logger = self.prepared_ctx(ops::KNOWNNODES, None).scuba().clone();
+        let mut request_logger = self.request_logger(ops::KNOWNNODES).start();
 
         let nodes_len = nodes.len();
+        let args = json!([nodes.iter().map(|node| node.to_string()).join(" ")]);
 
-        blobrepo
+        let known = blobrepo
             .get_hg_bonsai_mapping(self.ctx.clone(), nodes.clone())
             .map(|hg_bcs_mapping| {
                 let hg_bcs_mapping: HashMap<_, _> = hg_bcs_mapping.into_iter().collect();
@@ -807,24 +1005,18 @@ impl HgCommands for RepoClient {
                     .into_iter()
                     .map(move |node| hg_bcs_mapping.contains_key(&node))
                     .collect::<Vec<_>>()
-            })
+            });
+
+        self.quarantined_future(ops::KNOWNNODES, &args, known)
             .timeout(timeout_duration())
             .map_err(process_timeout_error)
             .traced(self.ctx.trace(), ops::KNOWNNODES, trace_args!())
             .timed(move |stats, known_nodes| {
                 if let Ok(known) = known_nodes {
-                    let extra_context = json!({
-                        "num_known": known.len(),
-                        "num_unknown": nodes_len - known.len(),
-                    })
-                    .to_string();
-
-                    scuba_logger.add("extra_context", extra_context);
+                    request_logger.add_extra_context("num_known", known.len());
+                    request_logger.add_extra_context("num_unknown", nodes_len - known.len());
                 }
-
-                scuba_logger
-                    .add_future_stats(&stats)
-                    .log_with_msg("Command processed", None);
+                request_logger.finish(ResponseStats::Future(&stats));
                 Ok(())
             })
             .boxify()
@@ -841,58 +1033,81 @@ impl HgCommands for RepoClient {
             "listkeys": format_utf8_bytes_list(&args.listkeys),
         });
         let value = json!(vec![value]);
-        let mut wireproto_logger = self.wireproto_logger(ops::GETBUNDLE, Some(value));
-        cloned!(self.ctx);
+        let limit_response_size = self.limit_response_size(ops::GETBUNDLE, Some(value.clone()));
+        let mut request_logger = self
+            .request_logger(ops::GETBUNDLE)
+            .args(value.clone())
+            .start();
 
-        match self.create_bundle(args) {
+        let bundle = match self.create_bundle(args) {
             Ok(res) => res.boxify(),
             Err(err) => stream::once(Err(err)).boxify(),
-        }
-        .whole_stream_timeout(timeout_duration())
-        .map_err(process_stream_timeout_error)
-        .traced(self.ctx.trace(), ops::GETBUNDLE, trace_args!())
-        .timed(move |stats, _| {
-            STATS::getbundle_ms.add_value(stats.completion_time.as_millis_unchecked() as i64);
-            wireproto_logger.add_perf_counters_from_ctx("extra_context", ctx.clone());
-            wireproto_logger.finish_stream_wireproto_processing(&stats, ctx);
-            Ok(())
-        })
-        .boxify()
+        };
+        let bundle = self.qos_limited(bundle, timeout_duration());
+
+        self.quarantined(ops::GETBUNDLE, &value, bundle)
+            .traced(self.ctx.trace(), ops::GETBUNDLE, trace_args!())
+            .inspect(request_logger.count_response_bytes())
+            .and_then(limit_response_size)
+            .timed(move |stats, _| {
+                STATS::getbundle_ms.add_value(stats.completion_time.as_millis_unchecked() as i64);
+                request_logger.finish(ResponseStats::Stream(&stats));
+                Ok(())
+            })
+            .boxify()
     }
 
     // @wireprotocommand('hello')
     fn hello(&self) -> HgCommandRes<HashMap<String, Vec<String>>> {
         info!(self.ctx.logger(), "Hello -> capabilities");
 
-        let mut res = HashMap::new();
-        let mut caps = wireprotocaps();
-        caps.push(format!("bundle2={}", bundle2caps()));
-        res.insert("capabilities".to_string(), caps);
+        // Capabilities may be enabled for some clients first, e.g. canaries
+        let identities = self.ctx.user_identities();
+        let mut caps = Capabilities::for_client(
+            self.repo.wireproto_caps(),
+            identities.iter().map(|identity| identity.as_str()),
+        )
+        .into_wireproto();
+        if self.repo.resumable_pull().is_some() {
+            caps.push("resumablepull".to_string());
+        }
 
-        let mut scuba_logger = self.prepared_ctx(ops::HELLO, None).scuba().clone();
+        let request_logger = self.request_logger(ops::HELLO).start();
+        let logger = self.ctx.logger().clone();
 
-        future::ok(res)
+        // Tell clients about ongoing maintenance up front, so that they can warn before a push
+        // rather than fail it. Reads still work, so failing to check mustn't fail the hello.
+        self.repo
+            .maintenance(self.ctx.clone())
+            .then(move |maybe_window| {
+                match maybe_window {
+                    Ok(Some(window)) => caps.push(format!(
+                        "maintenance={}",
+                        percent_encoding::utf8_percent_encode(
+                            &window.client_message(),
+                            percent_encoding::DEFAULT_ENCODE_SET,
+                        )
+                    )),
+                    Ok(None) => {}
+                    Err(err) => warn!(logger, "failed to check for maintenance: {:?}", err),
+                }
+                let mut res = HashMap::new();
+                res.insert("capabilities".to_string(), caps);
+                Ok(res)
+            })
             .timeout(timeout_duration())
             .map_err(process_timeout_error)
             .traced(self.ctx.trace(), ops::HELLO, trace_args!())
-            .timed(move |stats, _| {
-                scuba_logger
-                    .add_future_stats(&stats)
-                    .log_with_msg("Command processed", None);
-                Ok(())
-            })
-            .boxify()
+            .log_request(request_logger)
     }
 
     // @wireprotocommand('listkeys', 'namespace')
     fn listkeys(&self, namespace: String) -> HgCommandRes<HashMap<Vec<u8>, Vec<u8>>> {
         info!(self.ctx.logger(), "listkeys: {}", namespace);
-        if namespace == "bookmarks" {
-            let mut scuba_logger = self.prepared_ctx(ops::LISTKEYS, None).scuba().clone();
-
-            self.repo
-                .blobrepo()
-                .get_bookmarks_maybe_stale(self.ctx.clone())
+        let keys = match namespace.as_str() {
+            "bookmarks" => self
+                .repo
+                .get_publishing_bookmarks_maybe_stale(self.ctx.clone())
                 .map(|(name, cs)| {
                     let hash: Vec<u8> = cs.into_nodehash().to_hex().into();
                     (name, hash)
@@ -904,23 +1119,95 @@ impl HgCommands for RepoClient {
                         .map(|(name, value)| (Vec::from(name.to_string()), value));
                     HashMap::from_iter(bookiter)
                 })
-                .timeout(timeout_duration())
-                .map_err(process_timeout_error)
-                .traced(self.ctx.trace(), ops::LISTKEYS, trace_args!())
-                .timed(move |stats, _| {
-                    scuba_logger
-                        .add_future_stats(&stats)
-                        .log_with_msg("Command processed", None);
-                    Ok(())
-                })
-                .boxify()
-        } else {
-            info!(
-                self.ctx.logger(),
-                "unsupported listkeys namespace: {}", namespace
-            );
-            future::ok(HashMap::new()).boxify()
-        }
+                .boxify(),
+            "phases" => {
+                // Mercurial lists the roots of the draft commits it serves, and whether it's
+                // publishing, i.e. whether all the commits it serves are public. Only the commits
+                // that this session served can be draft on the client, so only the ancestors of
+                // the heads it served are walked.
+                let publishing = self.repo.is_publishing();
+                let served_heads: Vec<_> = self
+                    .served_heads
+                    .lock()
+                    .expect("lock poisoned")
+                    .iter()
+                    .cloned()
+                    .collect();
+                if publishing || served_heads.is_empty() {
+                    let mut phases = HashMap::new();
+                    if publishing {
+                        phases.insert(b"publishing".to_vec(), b"True".to_vec());
+                    }
+                    future::ok(phases).boxify()
+                } else {
+                    let blobrepo = self.repo.blobrepo().clone();
+                    let is_scratch = {
+                        cloned!(self.repo);
+                        move |bookmark: &Bookmark| repo.is_scratch_bookmark(bookmark)
+                    };
+                    let public_heads = blobrepo
+                        .get_bonsai_bookmarks_maybe_stale(self.ctx.clone())
+                        .filter(move |(bookmark, _)| !is_scratch(bookmark))
+                        .map(|(_, cs_id)| cs_id)
+                        .collect();
+                    let heads = future::join_all(served_heads.into_iter().map({
+                        cloned!(self.ctx, blobrepo);
+                        move |head| blobrepo.get_bonsai_from_hg(ctx.clone(), head)
+                    }));
+                    public_heads
+                        .join(heads)
+                        .and_then({
+                            cloned!(self.ctx, self.phases_hint);
+                            move |(public_heads, heads)| {
+                                draft_roots(
+                                    ctx.clone(),
+                                    blobrepo.clone(),
+                                    phases_hint,
+                                    heads.into_iter().flatten().collect(),
+                                    Arc::new(public_heads.into_iter().collect()),
+                                    MAX_DRAFT_COMMITS,
+                                )
+                                .and_then(move |roots| {
+                                    future::join_all(roots.into_iter().map(move |root| {
+                                        blobrepo.get_hg_from_bonsai_changeset(ctx.clone(), root)
+                                    }))
+                                })
+                            }
+                        })
+                        .map(|roots| {
+                            roots
+                                .into_iter()
+                                .map(|root| {
+                                    let hash: Vec<u8> = root.into_nodehash().to_hex().into();
+                                    (hash, b"1".to_vec())
+                                })
+                                .collect()
+                        })
+                        .boxify()
+                }
+            }
+            "namespaces" => {
+                let namespaces: HashMap<_, _> = ["bookmarks", "namespaces", "phases"]
+                    .iter()
+                    .map(|namespace| (namespace.as_bytes().to_vec(), vec![]))
+                    .collect();
+                future::ok(namespaces).boxify()
+            }
+            _ => {
+                info!(
+                    self.ctx.logger(),
+                    "unsupported listkeys namespace: {}", namespace
+                );
+                return future::ok(HashMap::new()).boxify();
+            }
+        };
+
+        let request_logger = self.request_logger(ops::LISTKEYS).start();
+        self.quarantined_future(ops::LISTKEYS, &json!([namespace]), keys)
+            .timeout(timeout_duration())
+            .map_err(process_timeout_error)
+            .traced(self.ctx.trace(), ops::LISTKEYS, trace_args!())
+            .log_request(request_logger)
     }
 
     // @wireprotocommand('unbundle')
@@ -933,12 +1220,30 @@ impl HgCommands for RepoClient {
     ) -> HgCommandRes<Bytes> {
         let client = self.clone();
         self.repo
-            .readonly()
-            // Assume read only if we have an error.
-            .or_else(|_| ok(RepoReadOnly::ReadOnly("Failed to fetch repo lock status".to_string())))
+            .maintenance(self.ctx.clone())
+            .and_then(|maybe_window| match maybe_window {
+                Some(window) => Err(ErrorKind::RepoInMaintenance(window.client_message()).into()),
+                None => Ok(()),
+            })
+            .and_then({
+                cloned!(self.repo);
+                move |()| {
+                    repo.readonly()
+                        // Assume read only if we have an error.
+                        .or_else(|_| {
+                            ok(RepoReadOnly::ReadOnly(
+                                "Failed to fetch repo lock status".to_string(),
+                            ))
+                        })
+                }
+            })
             .and_then(move |read_write| {
-                let ctx = client.prepared_ctx(ops::UNBUNDLE, None);
-                let mut scuba_logger = ctx.scuba().clone();
+                // Samples that the push logs are about the unbundle as well
+                let ctx = client.ctx.with_scuba_initialization(|mut scuba_logger| {
+                    scuba_logger.add("command", ops::UNBUNDLE);
+                    scuba_logger
+                });
+                let request_logger = client.sinks.request(&ctx, ops::UNBUNDLE).start();
 
                 let res = bundle2_resolver::resolve(
                     ctx.with_logger_kv(o!("command" => "unbundle")),
@@ -950,22 +1255,20 @@ impl HgCommands for RepoClient {
                     hook_manager,
                     client.lca_hint.clone(),
                     client.phases_hint.clone(),
+                    client.repo.hg_derivation_queue().clone(),
+                    client.repo.webhook_dispatcher().clone(),
+                    client.repo.event_bus().clone(),
                     read_write,
                     maybe_full_content,
+                    client.repo.push_quota().clone(),
+                    client.repo.content_refs().cloned(),
+                    client.repo.phases_admin_identities().clone(),
                 );
 
                 res.timeout(timeout_duration())
                     .map_err(process_timeout_error)
                     .traced(client.ctx.trace(), ops::UNBUNDLE, trace_args!())
-                    .timed(move |stats, _| {
-                        if let Ok(counters) = serde_json::to_string(&ctx.perf_counters()) {
-                            scuba_logger.add("extra_context", counters);
-                        }
-                        scuba_logger
-                            .add_future_stats(&stats)
-                            .log_with_msg("Command processed", None);
-                        Ok(())
-                    })
+                    .log_request(request_logger)
             })
             .boxify()
     }
@@ -979,11 +1282,12 @@ impl HgCommands for RepoClient {
             "directories": format_utf8_bytes_list(&params.directories),
         });
         let args = json!(vec![args]);
-        let mut wireproto_logger = self.wireproto_logger(ops::GETTREEPACK, Some(args));
+        let limit_response_size = self.limit_response_size(ops::GETTREEPACK, Some(args.clone()));
+        let treepack = self.qos_limited(self.gettreepack_untimed(params), timeout_duration());
+        let treepack = self.quarantined(ops::GETTREEPACK, &args, treepack);
+        let mut request_logger = self.request_logger(ops::GETTREEPACK).args(args).start();
 
-        self.gettreepack_untimed(params)
-            .whole_stream_timeout(timeout_duration())
-            .map_err(process_stream_timeout_error)
+        treepack
             .traced(self.ctx.trace(), ops::GETTREEPACK, trace_args!())
             .inspect({
                 cloned!(self.ctx);
@@ -992,15 +1296,12 @@ impl HgCommands for RepoClient {
                         .add_to_counter("gettreepack_response_size", bytes.len() as i64);
                 }
             })
-            .timed({
-                cloned!(self.ctx);
-                move |stats, _| {
-                    STATS::gettreepack_ms
-                        .add_value(stats.completion_time.as_millis_unchecked() as i64);
-                    wireproto_logger.add_perf_counters_from_ctx("extra_context", ctx.clone());
-                    wireproto_logger.finish_stream_wireproto_processing(&stats, ctx);
-                    Ok(())
-                }
+            .inspect(request_logger.count_response_bytes())
+            .and_then(limit_response_size)
+            .timed(move |stats, _| {
+                STATS::gettreepack_ms.add_value(stats.completion_time.as_millis_unchecked() as i64);
+                request_logger.finish(ResponseStats::Stream(&stats));
+                Ok(())
             })
             .boxify()
     }
@@ -1009,16 +1310,17 @@ impl HgCommands for RepoClient {
     fn getfiles(&self, params: BoxStream<(HgNodeHash, MPath), Error>) -> BoxStream<Bytes, Error> {
         info!(self.ctx.logger(), "getfiles");
 
-        let mut wireproto_logger = self.wireproto_logger(ops::GETFILES, None);
+        let mut request_logger = self.request_logger(ops::GETFILES).start();
         let this = self.clone();
-        // TODO(stash): make it configurable
-        let getfiles_buffer_size = 100;
+        let getfiles_buffer_size = self.fetch_buffer_size(GETFILES_BUFFER_SIZE);
         // We buffer all parameters in memory so that we can log them.
         // That shouldn't be a problem because requests are quite small
         let getfiles_params = Arc::new(Mutex::new(vec![]));
 
-        let validate_hash = rand::random::<usize>() % 100 < self.hash_validation_percentage;
-        params
+        let validate_hash = rand::random::<usize>() % 100 < self.hash_validation_percentage();
+        let lfs_params = self.lfs_params();
+        let copy_info_check = self.repo.copy_info_check();
+        let files = params
             .map({
                 cloned!(getfiles_params);
                 move |param| {
@@ -1031,15 +1333,37 @@ impl HgCommands for RepoClient {
                 cloned!(self.ctx);
                 move |(node, path)| {
                     let repo = this.repo.clone();
-                    create_remotefilelog_blob(
+                    let access_check =
+                        this.check_path_access(RepoPath::FilePath(path.clone()), node);
+                    let blob = create_remotefilelog_blob(
                         ctx.clone(),
                         repo.blobrepo().clone(),
                         HgFileNodeId::new(node),
                         path.clone(),
-                        repo.lfs_params().clone(),
+                        lfs_params.clone(),
                         validate_hash,
-                    )
-                    .traced(
+                        copy_info_check,
+                    );
+                    let blob = match repo.response_cache() {
+                        Some(response_cache) => response_cache.get_or_fill_file(
+                            ctx.clone(),
+                            node,
+                            &path,
+                            &lfs_params,
+                            copy_info_check.is_some(),
+                            blob,
+                            repo.blobrepo()
+                                .is_file_content_tombstoned(ctx.clone(), HgFileNodeId::new(node))
+                                .boxify(),
+                        ),
+                        None => blob,
+                    };
+                    let blob = access_check.and_then(move |()| blob);
+                    // Each file is quarantined on its own, as the files of a request are only
+                    // known once they have all been read
+                    let args = json!([node.to_string(), path.to_string()]);
+                    let blob = this.quarantined_future(ops::GETFILES, &args, blob);
+                    blob.traced(
                         this.ctx.trace(),
                         ops::GETFILES,
                         trace_args!("node" => node.to_string(), "path" =>  path.to_string()),
@@ -1069,8 +1393,10 @@ impl HgCommands for RepoClient {
                         .set_max_counter("getfiles_max_file_size", len);
                 }
             })
-            .whole_stream_timeout(getfiles_timeout_duration())
-            .map_err(process_stream_timeout_error)
+            .inspect(request_logger.count_response_bytes())
+            .and_then(self.limit_response_size(ops::GETFILES, None));
+
+        self.qos_limited(files, getfiles_timeout_duration())
             .timed({
                 cloned!(self.ctx);
                 move |stats, _| {
@@ -1089,9 +1415,8 @@ impl HgCommands for RepoClient {
                     ctx.perf_counters()
                         .add_to_counter("getfiles_num_files", stats.count as i64);
 
-                    wireproto_logger.set_args(Some(json! {encoded_params}));
-                    wireproto_logger.add_perf_counters_from_ctx("extra_context", ctx.clone());
-                    wireproto_logger.finish_stream_wireproto_processing(&stats, ctx);
+                    request_logger.set_args(json! {encoded_params});
+                    request_logger.finish(ResponseStats::Stream(&stats));
                     Ok(())
                 }
             })
@@ -1101,7 +1426,7 @@ impl HgCommands for RepoClient {
     // @wireprotocommand('stream_out_shallow')
     fn stream_out_shallow(&self) -> BoxStream<Bytes, Error> {
         info!(self.ctx.logger(), "{}", ops::STREAMOUTSHALLOW);
-        let mut wireproto_logger = self.wireproto_logger(ops::STREAMOUTSHALLOW, None);
+        let mut request_logger = self.request_logger(ops::STREAMOUTSHALLOW).start();
         let changelog = match self.repo.streaming_clone() {
             None => Ok(RevlogStreamingChunks::new()).into_future().left_future(),
             Some(SqlStreamingCloneConfig {
@@ -1113,7 +1438,7 @@ impl HgCommands for RepoClient {
                 .right_future(),
         };
 
-        changelog
+        let response = changelog
             .map({
                 let ctx = self.ctx.clone();
                 move |chunk| {
@@ -1179,42 +1504,41 @@ impl HgCommands for RepoClient {
                     response_header.push(header.into_bytes().into());
                     let response = stream::iter_ok(response_header);
 
+                    // Files are named by their path in the store, with only directories
+                    // encoded, as core Mercurial's streaming clone does
                     fn build_file_stream(
-                        name: &str,
+                        name: &[u8],
                         size: usize,
                         data: Vec<BoxFuture<Bytes, Error>>,
                     ) -> impl Stream<Item = Bytes, Error = Error> + Send {
-                        let header = format!("{}\0{}\n", name, size);
+                        let mut header = encodedir(name);
+                        header.push(b'\0');
+                        header.extend_from_slice(format!("{}\n", size).as_bytes());
 
-                        stream::once(Ok(header.into_bytes().into()))
+                        stream::once(Ok(header.into()))
                             .chain(stream::iter_ok(data.into_iter()).buffered(100))
                     }
 
                     response
                         .chain(build_file_stream(
-                            "00changelog.i",
+                            b"00changelog.i",
                             changelog_chunks.index_size,
                             changelog_chunks.index_blobs,
                         ))
                         .chain(build_file_stream(
-                            "00changelog.d",
+                            b"00changelog.d",
                             changelog_chunks.data_size,
                             changelog_chunks.data_blobs,
                         ))
                 }
             })
-            .flatten_stream()
-            .whole_stream_timeout(timeout_duration())
-            .map_err(process_stream_timeout_error)
-            .timed({
-                let ctx = self.ctx.clone();
-                move |stats, _| {
-                    wireproto_logger.add_perf_counters_from_ctx("extra_context", ctx.clone());
-                    wireproto_logger.finish_stream_wireproto_processing(&stats, ctx);
-                    Ok(())
-                }
-            })
-            .boxify()
+            .flatten_stream();
+
+        let response = self.quarantined(ops::STREAMOUTSHALLOW, &json!([]), response);
+        self.qos_limited(response, timeout_duration())
+            .inspect(request_logger.count_response_bytes())
+            .and_then(self.limit_response_size(ops::STREAMOUTSHALLOW, None))
+            .log_request(request_logger)
     }
 
     // @wireprotocommand('getpackv1')
@@ -1223,22 +1547,22 @@ impl HgCommands for RepoClient {
         params: BoxStream<(MPath, Vec<HgFileNodeId>), Error>,
     ) -> BoxStream<Bytes, Error> {
         info!(self.ctx.logger(), "{}", ops::GETPACKV1);
-        let mut wireproto_logger = self.wireproto_logger(ops::GETPACKV1, None);
+        let mut request_logger = self.request_logger(ops::GETPACKV1).start();
+        let this = self.clone();
 
-        // TODO(stash): make it configurable
-        let getpackv1_buffer_size = 100;
+        let getpackv1_buffer_size = self.fetch_buffer_size(GETPACKV1_BUFFER_SIZE);
         // We buffer all parameters in memory so that we can log them.
         // That shouldn't be a problem because requests are quite small
         let getpackv1_params = Arc::new(Mutex::new(vec![]));
         let ctx = self.ctx.clone();
         let repo = self.repo.blobrepo().clone();
         let validate_hash =
-            rand::thread_rng().gen_ratio(self.hash_validation_percentage as u32, 100);
+            rand::thread_rng().gen_ratio(self.hash_validation_percentage() as u32, 100);
 
         // Let's fetch the whole request before responding.
         // That's prevents deadlocks, because hg client doesn't start reading the response
         // before all the arguments were sent.
-        let s = params
+        let files = params
             .collect()
             .map(|v| stream::iter_ok(v.into_iter()))
             .flatten_stream()
@@ -1249,13 +1573,48 @@ impl HgCommands for RepoClient {
                         let mut getpackv1_params = getpackv1_params.lock().unwrap();
                         getpackv1_params.push((path.clone(), filenodes.clone()));
                     }
-                    let history = get_unordered_file_history_for_multiple_nodes(
+                    let access_checks: Vec<_> = filenodes
+                        .iter()
+                        .map(|filenode| {
+                            let path = RepoPath::FilePath(path.clone());
+                            this.check_path_access(path, filenode.into_nodehash())
+                        })
+                        .collect();
+                    let args = json!([
+                        path.to_string(),
+                        filenodes
+                            .iter()
+                            .map(|filenode| filenode.to_string())
+                            .join(" "),
+                    ]);
+                    let start: HashSet<_> = filenodes.iter().cloned().collect();
+                    let limits = HistoryLimits {
+                        max_depth: None,
+                        max_entries: Some(GETPACKV1_MAX_HISTORY_ENTRIES),
+                    };
+                    let history = get_file_history_page(
                         ctx.clone(),
                         repo.clone(),
-                        filenodes.clone().into_iter().collect(),
-                        &path,
+                        path.clone(),
+                        HistoryCursor::new(start.into_iter().collect()),
+                        limits,
                     )
-                    .collect();
+                    .map({
+                        cloned!(ctx, path);
+                        move |page| {
+                            if page.next.is_some() {
+                                warn!(
+                                    ctx.logger(),
+                                    "history of {} truncated to {} entries",
+                                    path,
+                                    page.entries.len()
+                                );
+                                ctx.perf_counters()
+                                    .increment_counter("getpackv1_truncated_histories");
+                            }
+                            page.entries
+                        }
+                    });
 
                     let mut contents = vec![];
                     for filenode in filenodes {
@@ -1271,14 +1630,17 @@ impl HgCommands for RepoClient {
                         let fut = fut.map(move |(content, _)| (filenode, content));
                         contents.push(fut);
                     }
-                    future::join_all(contents)
-                        .join(history)
-                        .map(move |(contents, history)| (path, contents, history))
+                    let pack = future::join_all(access_checks)
+                        .and_then(move |_| future::join_all(contents).join(history))
+                        .map(move |(contents, history)| (path, contents, history));
+                    // Like getfiles, each file is quarantined on its own
+                    this.quarantined_future(ops::GETPACKV1, &args, pack)
                 }
             })
-            .buffered(getpackv1_buffer_size)
-            .whole_stream_timeout(getfiles_timeout_duration())
-            .map_err(process_stream_timeout_error)
+            .buffered(getpackv1_buffer_size);
+
+        let s = self
+            .qos_limited(files, getfiles_timeout_duration())
             .map({
                 cloned!(ctx);
                 move |(path, contents, history)| {
@@ -1333,6 +1695,8 @@ impl HgCommands for RepoClient {
                         .add_to_counter("getpackv1_response_size", len);
                 }
             })
+            .inspect(request_logger.count_response_bytes())
+            .and_then(self.limit_response_size(ops::GETPACKV1, None))
             .timed({
                 cloned!(self.ctx);
                 move |stats, _| {
@@ -1355,9 +1719,8 @@ impl HgCommands for RepoClient {
                     ctx.perf_counters()
                         .add_to_counter("getpackv1_num_files", encoded_params.len() as i64);
 
-                    wireproto_logger.set_args(Some(json! {encoded_params}));
-                    wireproto_logger.add_perf_counters_from_ctx("extra_context", ctx.clone());
-                    wireproto_logger.finish_stream_wireproto_processing(&stats, ctx);
+                    request_logger.set_args(json! {encoded_params});
+                    request_logger.finish(ResponseStats::Stream(&stats));
                     Ok(())
                 }
             })
@@ -1370,67 +1733,310 @@ impl HgCommands for RepoClient {
     }
 }
 
+/// The roots of the draft commits among `heads` and their ancestors: the draft commits whose
+/// parents are all public. The phases of commits are looked up in `phases`, with `public_heads`
+/// as the heads of the public commits. Once more than `max_drafts` draft commits are visited,
+/// the walk stops and the draft commits left to walk are returned as roots too: the client then
+/// sees their draft ancestors as public, which is less wrong than failing the whole listkeys.
+fn draft_roots(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    phases: Arc<Phases>,
+    heads: Vec<ChangesetId>,
+    public_heads: Arc<HashSet<ChangesetId>>,
+    max_drafts: usize,
+) -> BoxFuture<Vec<ChangesetId>, Error> {
+    let drafts = {
+        cloned!(ctx, repo);
+        move |cs_ids: Vec<ChangesetId>| {
+            phases
+                .get_all_with_bookmarks(
+                    ctx.clone(),
+                    repo.clone(),
+                    cs_ids.clone(),
+                    Some(public_heads.clone()),
+                )
+                .map(move |phases| {
+                    cs_ids
+                        .into_iter()
+                        .filter(|cs_id| phases.calculated.get(cs_id) != Some(&Phase::Public))
+                        .collect::<HashSet<_>>()
+                })
+        }
+    };
+
+    let heads: HashSet<_> = heads.into_iter().collect();
+    drafts(heads.into_iter().collect())
+        .and_then(move |head_drafts| {
+            let visited = head_drafts.clone();
+            future::loop_fn(
+                (head_drafts, visited, vec![]),
+                move |(drafts_to_walk, mut visited, mut roots)| {
+                    if drafts_to_walk.is_empty() {
+                        return future::ok(Loop::Break(roots)).left_future();
+                    }
+                    if visited.len() > max_drafts {
+                        warn!(
+                            ctx.logger(),
+                            "more than {} draft commits to find the roots of, listing {} \
+                             unwalked ones as roots",
+                            max_drafts,
+                            drafts_to_walk.len()
+                        );
+                        roots.extend(drafts_to_walk);
+                        return future::ok(Loop::Break(roots)).left_future();
+                    }
+
+                    let parents = future::join_all(drafts_to_walk.into_iter().map(|cs_id| {
+                        repo.get_changeset_parents_by_bonsai(ctx.clone(), cs_id)
+                            .map(move |parents| (cs_id, parents))
+                    }));
+                    cloned!(drafts);
+                    parents
+                        .and_then(move |parents| {
+                            let all_parents: HashSet<_> = parents
+                                .iter()
+                                .flat_map(|(_, cs_parents)| cs_parents.iter().cloned())
+                                .collect();
+                            drafts(all_parents.into_iter().collect()).map(move |draft_parents| {
+                                for (cs_id, cs_parents) in parents {
+                                    if cs_parents.iter().all(|p| !draft_parents.contains(p)) {
+                                        roots.push(cs_id);
+                                    }
+                                }
+                                let next: HashSet<_> = draft_parents
+                                    .into_iter()
+                                    .filter(|cs_id| visited.insert(*cs_id))
+                                    .collect();
+                                Loop::Continue((next, visited, roots))
+                            })
+                        })
+                        .right_future()
+                },
+            )
+        })
+        .boxify()
+}
+
+/// The trees of `mfid` that the client doesn't have, with the path of the directory they are
+/// in. The client has the trees of all of `basemfids`, so a tree that any of them has at the same
+/// path is neither sent nor walked into: the manifests are walked together, and only into the
+/// directories that differ from all the bases. `pruner` sees the other trees as modified from
+/// the tree at the same path in the first base that has one, or as added if none has.
 fn get_changed_manifests_stream(
     ctx: CoreContext,
     repo: &BlobRepo,
     mfid: HgNodeHash,
-    basemfid: HgNodeHash,
+    basemfids: &[HgNodeHash],
     rootpath: Option<MPath>,
     pruner: impl Pruner + Send + Clone + 'static,
     max_depth: usize,
 ) -> BoxStream<(Box<Entry + Sync>, Option<MPath>), Error> {
-    let mfid = HgManifestId::new(mfid);
-    let manifest = repo.get_manifest_by_nodeid(ctx.clone(), mfid).traced(
-        ctx.trace(),
-        "fetch rootmf",
-        trace_args!(),
-    );
-    let basemfid = HgManifestId::new(basemfid);
-    let basemanifest = repo.get_manifest_by_nodeid(ctx.clone(), basemfid).traced(
-        ctx.trace(),
-        "fetch baserootmf",
-        trace_args!(),
-    );
-
-    let entry: Box<Entry + Sync> = Box::new(repo.get_root_entry(mfid));
+    let entry: Box<Entry + Sync> = Box::new(repo.get_root_entry(HgManifestId::new(mfid)));
     let root_entry_stream = stream::once(Ok((entry, rootpath.clone())));
 
     if max_depth == 1 {
         return root_entry_stream.boxify();
     }
 
+    let manifest = repo
+        .get_manifest_by_nodeid(ctx.clone(), HgManifestId::new(mfid))
+        .traced(ctx.trace(), "fetch rootmf", trace_args!());
+    let basemanifests = future::join_all(
+        basemfids
+            .iter()
+            .map(|basemfid| repo.get_manifest_by_nodeid(ctx.clone(), HgManifestId::new(*basemfid)))
+            .collect::<Vec<_>>(),
+    )
+    .traced(ctx.trace(), "fetch baserootmfs", trace_args!());
+
     let changed_entries = manifest
-        .join(basemanifest)
+        .join(basemanifests)
         .map({
-            cloned!(ctx, rootpath);
-            move |(mf, basemf)| {
-                changed_entry_stream_with_pruner(
+            cloned!(ctx);
+            move |(manifest, basemanifests)| {
+                changed_trees_from_bases(
                     ctx,
-                    &mf,
-                    &basemf,
                     rootpath,
+                    manifest,
+                    basemanifests,
                     pruner,
-                    Some(max_depth),
+                    1,
+                    max_depth,
                 )
             }
         })
         .flatten_stream();
 
-    let changed_entries = changed_entries.map(move |entry_status| match entry_status.status {
-        EntryStatus::Added(to_entry) | EntryStatus::Modified { to_entry, .. } => {
-            assert!(
-                to_entry.get_type() == Type::Tree,
-                "FilePruner should have removed file entries"
-            );
-            (to_entry, entry_status.dirname)
+    // Append root manifest as well
+    changed_entries.chain(root_entry_stream).boxify()
+}
+
+/// The trees in `manifest`, the directory at `dirname`, that none of `basemanifests`, the same
+/// directory in each base, has, followed by the trees in them that the bases don't have either,
+/// down to `max_depth`
+fn changed_trees_from_bases(
+    ctx: CoreContext,
+    dirname: Option<MPath>,
+    manifest: Box<Manifest + Sync>,
+    basemanifests: Vec<Box<Manifest + Sync>>,
+    mut pruner: impl Pruner + Send + Clone + 'static,
+    depth: usize,
+    max_depth: usize,
+) -> BoxStream<(Box<Entry + Sync>, Option<MPath>), Error> {
+    let mut changed = vec![];
+    for entry in manifest.list() {
+        if !entry.get_type().is_tree() {
+            continue;
         }
-        EntryStatus::Deleted(..) => {
-            panic!("DeletedPruner should have removed deleted entries");
+        let mut basetrees = match entry.get_name() {
+            Some(name) => basemanifests
+                .iter()
+                .filter_map(|basemanifest| basemanifest.lookup(name))
+                .filter(|baseentry| baseentry.get_type().is_tree())
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        if basetrees
+            .iter()
+            .any(|basetree| basetree.get_hash() == entry.get_hash())
+        {
+            continue;
         }
-    });
 
-    // Append root manifest as well
-    changed_entries.chain(root_entry_stream).boxify()
+        let changed_entry = if basetrees.is_empty() {
+            ChangedEntry::new_added(dirname.clone(), entry)
+        } else {
+            let basetree = basetrees.remove(0);
+            ChangedEntry::new_modified(dirname.clone(), entry, basetree)
+        };
+        if !pruner.keep(&changed_entry) {
+            continue;
+        }
+        match changed_entry.status {
+            EntryStatus::Added(entry) => changed.push((entry, basetrees)),
+            EntryStatus::Modified {
+                to_entry,
+                from_entry,
+            } => {
+                basetrees.insert(0, from_entry);
+                changed.push((to_entry, basetrees));
+            }
+            EntryStatus::Deleted(..) => unreachable!("only added and modified trees are walked"),
+        }
+    }
+
+    select_all(changed.into_iter().map(move |(entry, basetrees)| {
+        let subtrees = if depth < max_depth {
+            let path = MPath::join_element_opt(dirname.as_ref(), entry.get_name());
+            let manifest = entry.get_content(ctx.clone()).and_then(tree_content);
+            let basemanifests = future::join_all(
+                basetrees
+                    .iter()
+                    .map(|basetree| basetree.get_content(ctx.clone()).and_then(tree_content))
+                    .collect::<Vec<_>>(),
+            );
+            manifest
+                .join(basemanifests)
+                .map({
+                    cloned!(ctx, pruner);
+                    move |(manifest, basemanifests)| {
+                        changed_trees_from_bases(
+                            ctx,
+                            path,
+                            manifest,
+                            basemanifests,
+                            pruner,
+                            depth + 1,
+                            max_depth,
+                        )
+                    }
+                })
+                .flatten_stream()
+                .boxify()
+        } else {
+            empty().boxify()
+        };
+        stream::once(Ok((entry, dirname.clone()))).chain(subtrees)
+    }))
+    .boxify()
+}
+
+fn tree_content(content: Content) -> Result<Box<Manifest + Sync>> {
+    match content {
+        Content::Tree(manifest) => Ok(manifest),
+        _ => Err(err_msg("tree entry has file content")),
+    }
+}
+
+/// Fetches `entries` in order, until the fetched trees go over `size_budget`. The tree that goes
+/// over it isn't returned. Trees are only known not to fit once fetched, so no fetch is started
+/// after that, and the ones in flight are dropped.
+fn fetch_within_budget<S, F>(
+    entries: S,
+    size_budget: usize,
+    mut fetch: F,
+) -> BoxStream<parts::TreepackPartInput, Error>
+where
+    S: Stream<Error = Error> + Send + 'static,
+    F: FnMut(S::Item) -> BoxFuture<parts::TreepackPartInput, Error> + Send + 'static,
+{
+    let over_budget = Arc::new(AtomicBool::new(false));
+    entries
+        .take_while({
+            cloned!(over_budget);
+            move |_| Ok(!over_budget.load(Ordering::Relaxed))
+        })
+        .map(move |entry| fetch(entry))
+        .buffered(PREFETCH_CONCURRENCY)
+        .take_while({
+            let mut size = 0;
+            move |input| {
+                size += input.content.len();
+                if size > size_budget {
+                    over_budget.store(true, Ordering::Relaxed);
+                }
+                Ok(size <= size_budget)
+            }
+        })
+        .boxify()
+}
+
+/// Passes the chunks of a response to `command` through, and fails once they add up to more
+/// than `limit` bytes. No limit if `limit` is None.
+fn response_size_limiter(
+    ctx: CoreContext,
+    command: &'static str,
+    limit: Option<u64>,
+    args: Option<serde_json::Value>,
+) -> impl FnMut(Bytes) -> Result<Bytes> + Send + 'static {
+    let mut size = 0;
+    let mut exceeded = false;
+    move |bytes| {
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return Ok(bytes),
+        };
+        size += bytes.len() as u64;
+        if size <= limit {
+            return Ok(bytes);
+        }
+
+        // Only log once, the stream stops at the first error anyway
+        if !exceeded {
+            exceeded = true;
+            STATS::responses_too_large.add_value(1);
+            let args = args.as_ref().map(|args| args.to_string());
+            warn!(ctx.logger(), "Response to {} is too large", command;
+                "limit" => limit, "args" => args.clone());
+            ctx.scuba()
+                .clone()
+                .add("command", command)
+                .add("response_size_limit", limit)
+                .log_with_msg("Response too large", args);
+        }
+        Err(ErrorKind::ResponseTooLarge(command.to_string(), limit).into())
+    }
 }
 
 fn fetch_treepack_part_input(
@@ -1439,6 +2045,7 @@ fn fetch_treepack_part_input(
     entry: Box<Entry + Sync>,
     basepath: Option<MPath>,
     validate_content: bool,
+    response_cache: Option<&ResponseCache>,
 ) -> BoxFuture<parts::TreepackPartInput, Error> {
     let path = MPath::join_element_opt(basepath.as_ref(), entry.get_name());
     let repo_path = match path {
@@ -1492,6 +2099,7 @@ fn fetch_treepack_part_input(
         );
 
     let validate_content = if validate_content {
+        cloned!(ctx);
         entry
             .get_raw_content(ctx.clone())
             .join(entry.get_parents(ctx.clone()))
@@ -1523,24 +2131,39 @@ fn fetch_treepack_part_input(
         future::ok(()).right_future()
     };
 
-    parents
+    let tree = parents
         .join(linknode_fut)
         .join(content_fut)
         .join(validate_content)
         .map(|(val, ())| val)
         .map(move |((parents, linknode_opt), content)| {
             let (p1, p2) = parents.get_nodes();
-            parts::TreepackPartInput {
-                node: node.into_nodehash(),
+            CachedTree {
                 p1,
                 p2,
+                linknode: linknode_opt.map(|linknode| linknode.into_nodehash()),
                 content,
-                name: entry.get_name().cloned(),
-                linknode: linknode_opt.unwrap_or(NULL_CSID).into_nodehash(),
-                basepath,
             }
         })
-        .boxify()
+        .boxify();
+
+    let tree = match response_cache {
+        Some(response_cache) => {
+            response_cache.get_or_fill_tree(ctx, node.into_nodehash(), &repo_path, tree)
+        }
+        None => tree,
+    };
+
+    tree.map(move |tree| parts::TreepackPartInput {
+        node: node.into_nodehash(),
+        p1: tree.p1,
+        p2: tree.p2,
+        content: tree.content,
+        name: entry.get_name().cloned(),
+        linknode: tree.linknode.unwrap_or(NULL_HASH),
+        basepath,
+    })
+    .boxify()
 }
 
 /// getbundle capabilities have tricky format.
@@ -1590,6 +2213,192 @@ fn parse_utf8_getbundle_caps(caps: &[u8]) -> Option<(String, HashMap<String, Has
 mod tests {
     use super::*;
 
+    use fixtures::linear;
+    use mercurial_types::FileType;
+    use mercurial_types_mocks::manifest::MockManifest;
+    use mercurial_types_mocks::nodehash::{
+        FIVES_HASH, FOURS_HASH, ONES_HASH, SEVENS_HASH, SIXES_HASH, THREES_HASH, TWOS_HASH,
+    };
+    use phases::{HintPhases, SqlConstructors, SqlPhases};
+    use skiplist::SkiplistIndex;
+    use std::sync::atomic::AtomicUsize;
+
+    fn mock_manifest(files: &[&str], dirs: &[(&str, HgNodeHash)]) -> MockManifest {
+        let files = files
+            .iter()
+            .map(|path| (*path, (FileType::Regular, "", HgEntryId::new(NULL_HASH))));
+        let dirs = dirs
+            .iter()
+            .map(|(path, hash)| (*path, HgEntryId::new(*hash)));
+        MockManifest::from_path_hashes(files, dirs).unwrap()
+    }
+
+    fn changed_tree_paths(manifest: &MockManifest, basemanifests: &[&MockManifest]) -> Vec<String> {
+        let basemanifests = basemanifests
+            .iter()
+            .map(|basemanifest| (*basemanifest).clone().boxed())
+            .collect();
+        let mut paths: Vec<_> = changed_trees_from_bases(
+            CoreContext::test_mock(),
+            None,
+            manifest.clone().boxed(),
+            basemanifests,
+            CombinatorPruner::new(FilePruner, DeletedPruner),
+            1,
+            10,
+        )
+        .map(|(entry, dirname)| {
+            let path = MPath::join_element_opt(dirname.as_ref(), entry.get_name()).unwrap();
+            String::from_utf8(path.to_vec()).unwrap()
+        })
+        .collect()
+        .wait()
+        .unwrap();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_listkeys_phases_draft_roots() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let ctx = CoreContext::test_mock();
+        let repo = linear::getrepo(None);
+        let phases: Arc<Phases> = Arc::new(HintPhases::new(
+            Arc::new(SqlPhases::with_sqlite_in_memory().unwrap()),
+            Arc::new(SkiplistIndex::new()),
+        ));
+        let mut bonsai = |hg: &str| {
+            let hg = HgChangesetId::from_str(hg).unwrap();
+            rt.block_on(repo.get_bonsai_from_hg(ctx.clone(), hg))
+                .unwrap()
+                .unwrap()
+        };
+        let public_head = bonsai("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b");
+        let draft_root = bonsai("0ed509bf086fadcb8a8a5384dc3b550729b0fc17");
+        let draft = bonsai("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157");
+        let draft_head = bonsai("79a13814c5ce7330173ec04d279bf95ab3f652fb");
+
+        let mut roots = |heads, max_drafts| {
+            rt.block_on(draft_roots(
+                ctx.clone(),
+                repo.clone(),
+                phases.clone(),
+                heads,
+                Arc::new(hashset! {public_head}),
+                max_drafts,
+            ))
+            .unwrap()
+        };
+        assert_eq!(roots(vec![draft_head, draft], 100), vec![draft_root]);
+        assert_eq!(roots(vec![draft_root], 100), vec![draft_root]);
+        assert!(roots(vec![public_head], 100).is_empty());
+        assert!(roots(vec![], 100).is_empty());
+        // Past the limit, the drafts left to walk are listed as roots
+        assert_eq!(roots(vec![draft_head], 0), vec![draft_head]);
+    }
+
+    /// A fetched tree of 10 bytes
+    fn fetched_tree() -> BoxFuture<parts::TreepackPartInput, Error> {
+        future::ok(parts::TreepackPartInput {
+            node: ONES_HASH,
+            p1: None,
+            p2: None,
+            content: Bytes::from(vec![0; 10]),
+            name: None,
+            linknode: NULL_HASH,
+            basepath: None,
+        })
+        .boxify()
+    }
+
+    #[test]
+    fn test_response_size_limit() {
+        let ctx = CoreContext::test_mock();
+        let chunk = || Bytes::from(vec![0; 4]);
+
+        let mut limiter = response_size_limiter(ctx.clone(), ops::GETFILES, Some(10), None);
+        assert_eq!(limiter(chunk()).unwrap(), chunk());
+        assert_eq!(limiter(chunk()).unwrap(), chunk());
+        let err = limiter(chunk()).unwrap_err();
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::ResponseTooLarge(command, limit)) => {
+                assert_eq!((command.as_str(), limit), (ops::GETFILES, 10))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Once over, the response stays over
+        assert!(limiter(Bytes::new()).is_err());
+
+        let mut unlimited = response_size_limiter(ctx, ops::GETFILES, None, None);
+        for _ in 0..10 {
+            assert_eq!(unlimited(chunk()).unwrap(), chunk());
+        }
+    }
+
+    #[test]
+    fn test_prefetch_stops_fetching_over_budget() {
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let fetch = {
+            cloned!(fetched);
+            move |_| {
+                fetched.fetch_add(1, Ordering::Relaxed);
+                fetched_tree()
+            }
+        };
+        let prefetched = fetch_within_budget(stream::iter_ok(0..100), 35, fetch)
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(prefetched.len(), 3);
+        // Only the fetches already in flight when the budget was reached are wasted
+        assert!(fetched.load(Ordering::Relaxed) <= 3 + PREFETCH_CONCURRENCY);
+
+        let prefetched = fetch_within_budget(stream::iter_ok(0..3), 35, |_| fetched_tree())
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(prefetched.len(), 3);
+    }
+
+    #[test]
+    fn test_gettreepack_multiple_bases() {
+        let manifest = mock_manifest(
+            &["a/f", "b/f", "c/d/f", "e/f"],
+            &[
+                ("a", ONES_HASH),
+                ("b", TWOS_HASH),
+                ("c", THREES_HASH),
+                ("c/d", FOURS_HASH),
+                ("e", FIVES_HASH),
+            ],
+        );
+        // The first base has a, and a file where e is
+        let base1 = mock_manifest(&["a/f", "b/g", "e"], &[("a", ONES_HASH), ("b", SIXES_HASH)]);
+        // The second base has b and c/d, but another c
+        let base2 = mock_manifest(
+            &["b/f", "c/d/f", "c/g"],
+            &[("b", TWOS_HASH), ("c", SEVENS_HASH), ("c/d", FOURS_HASH)],
+        );
+
+        assert_eq!(
+            changed_tree_paths(&manifest, &[]),
+            vec!["a", "b", "c", "c/d", "e"]
+        );
+        assert_eq!(
+            changed_tree_paths(&manifest, &[&base1]),
+            vec!["b", "c", "c/d", "e"]
+        );
+        assert_eq!(
+            changed_tree_paths(&manifest, &[&base1, &base2]),
+            vec!["c", "e"]
+        );
+        assert_eq!(
+            changed_tree_paths(&manifest, &[&base2, &base1]),
+            vec!["c", "e"]
+        );
+        assert!(changed_tree_paths(&manifest, &[&manifest]).is_empty());
+    }
+
     #[test]
     fn test_parsing_caps_simple() {
         assert_eq!(
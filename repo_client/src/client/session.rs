@@ -0,0 +1,112 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use super::telemetry::SERVER_TELEMETRY_CAP;
+
+/// The capabilities that change what is sent to the client, which are the only ones remembered.
+/// Bundles are never compressed, as Mercurial hangs reading compressed bundles over the wire, and
+/// trees are sent in the only format there is, so neither is negotiated.
+const SESSION_CAPS: &[&str] = &["lfs", "phases", SERVER_TELEMETRY_CAP];
+
+/// Capabilities the client of a connection told us about, in the bundle2 caps of getbundle or in
+/// clienttelemetry. They are remembered for the rest of the connection, so that every command
+/// picks the same encodings, including commands that carry no capabilities themselves.
+#[derive(Clone, Default)]
+pub struct SessionCapabilities {
+    caps: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+}
+
+impl SessionCapabilities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember capabilities the client sent. Capabilities sent again replace the old ones, and
+    /// unknown ones are ignored.
+    pub fn negotiate(&self, caps: HashMap<String, HashSet<String>>) {
+        let known = caps
+            .into_iter()
+            .filter(|(name, _)| SESSION_CAPS.contains(&name.as_str()));
+        self.caps.lock().expect("lock poisoned").extend(known);
+    }
+
+    /// Remember the capabilities among the arguments of a clienttelemetry call. They have one
+    /// value each, and the other arguments are telemetry like the client's hostname.
+    pub fn negotiate_telemetry(&self, args: &HashMap<Vec<u8>, Vec<u8>>) {
+        let caps = args
+            .iter()
+            .map(|(key, value)| {
+                let value = String::from_utf8_lossy(value).into_owned();
+                let mut values = HashSet::new();
+                if !value.is_empty() {
+                    values.insert(value);
+                }
+                (String::from_utf8_lossy(key).into_owned(), values)
+            })
+            .collect();
+        self.negotiate(caps)
+    }
+
+    /// Values of a capability, None if the client never mentioned it.
+    pub fn get(&self, name: &str) -> Option<HashSet<String>> {
+        self.caps.lock().expect("lock poisoned").get(name).cloned()
+    }
+
+    /// Whether the client has a boolean capability like `treeonly=True`. A capability without
+    /// values counts as enabled, one with only false-ish values as disabled. None if the client
+    /// never mentioned it.
+    pub fn enabled(&self, name: &str) -> Option<bool> {
+        self.get(name).map(|values| {
+            values.is_empty()
+                || values
+                    .iter()
+                    .any(|value| match value.to_lowercase().as_str() {
+                        "false" | "0" | "no" => false,
+                        _ => true,
+                    })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capabilities_are_remembered() {
+        let session = SessionCapabilities::new();
+        assert_eq!(session.get("phases"), None);
+        assert_eq!(session.enabled("lfs"), None);
+
+        session.negotiate(hashmap! {
+            "phases".to_string() => hashset!{"heads".to_string()},
+            "treeonly".to_string() => hashset!{"True".to_string()},
+        });
+        // Clones share the session
+        let clone = session.clone();
+        clone.negotiate_telemetry(&hashmap! {
+            b"lfs".to_vec() => b"False".to_vec(),
+            b"servertelemetry".to_vec() => b"".to_vec(),
+            b"hostname".to_vec() => b"devbig".to_vec(),
+        });
+
+        assert_eq!(session.get("phases"), Some(hashset! {"heads".to_string()}));
+        assert_eq!(session.enabled("lfs"), Some(false));
+        assert_eq!(session.enabled(SERVER_TELEMETRY_CAP), Some(true));
+        // Only the capabilities that change responses are remembered
+        assert_eq!(session.enabled("treeonly"), None);
+        assert_eq!(session.get("hostname"), None);
+
+        // Later negotiation overrides earlier one
+        session.negotiate(hashmap! {
+            "phases".to_string() => hashset!{},
+        });
+        assert_eq!(clone.get("phases"), Some(hashset! {}));
+    }
+}
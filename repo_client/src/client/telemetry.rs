@@ -0,0 +1,87 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use mercurial_types::percent_encode;
+
+/// The clienttelemetry argument of clients that understand the detailed reply. Other clients
+/// only get the hostname of the server, which is all that they know how to show.
+pub const SERVER_TELEMETRY_CAP: &str = "servertelemetry";
+
+/// What the server tells a client about itself and about the session in reply to
+/// clienttelemetry
+pub struct ServerTelemetry {
+    pub hostname: String,
+    pub region: Option<String>,
+    pub tier: Option<String>,
+    pub qos_class: &'static str,
+    /// Whether the pool of the session is full, so that its requests queue up
+    pub shedding_load: bool,
+    /// For users to quote in bug reports
+    pub session_id: String,
+    /// Features of the repo that change what the client gets
+    pub hints: Vec<&'static str>,
+}
+
+impl ServerTelemetry {
+    /// The hostname alone, or one `key=value` line per field with percent-encoded values if
+    /// `detailed`
+    pub fn encode(&self, detailed: bool) -> String {
+        if !detailed {
+            return self.hostname.clone();
+        }
+
+        let mut fields = vec![("hostname", self.hostname.clone())];
+        if let Some(ref region) = self.region {
+            fields.push(("region", region.clone()));
+        }
+        if let Some(ref tier) = self.tier {
+            fields.push(("tier", tier.clone()));
+        }
+        let load = if self.shedding_load {
+            "shedding"
+        } else {
+            "normal"
+        };
+        fields.push(("qos_class", self.qos_class.to_string()));
+        fields.push(("load", load.to_string()));
+        fields.push(("session_id", self.session_id.clone()));
+        fields.push(("hints", self.hints.join(",")));
+
+        fields
+            .into_iter()
+            .map(|(key, value)| format!("{}={}\n", key, percent_encode(&value)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let telemetry = ServerTelemetry {
+            hostname: "mononoke01.example.com".to_string(),
+            region: None,
+            tier: Some("mononoke.prod".to_string()),
+            qos_class: "interactive",
+            shedding_load: true,
+            session_id: "1234".to_string(),
+            hints: vec!["resumablepull", "restrictedpaths"],
+        };
+
+        assert_eq!(telemetry.encode(false), "mononoke01.example.com");
+        assert_eq!(
+            telemetry.encode(true),
+            "hostname=mononoke01.example.com\n\
+             tier=mononoke.prod\n\
+             qos_class=interactive\n\
+             load=shedding\n\
+             session_id=1234\n\
+             hints=resumablepull%2Crestrictedpaths\n"
+        );
+    }
+}
@@ -6,7 +6,11 @@
 
 pub use failure::{Error, Result, ResultExt};
 
+use std::time::Duration;
+
+use bundle2_resolver;
 use mercurial_types::{HgNodeHash, RepoPath};
+use mononoke_errors::{Categorize, Categorizer, ErrorCategory};
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
@@ -19,4 +23,67 @@ pub enum ErrorKind {
         expected: HgNodeHash,
         actual: HgNodeHash,
     },
+    #[fail(display = "{}", _0)]
+    RepoInMaintenance(String),
+    #[fail(display = "Session closed after being idle for {:?}", _0)]
+    SessionIdle(Duration),
+    #[fail(display = "Session closed after being open for {:?}", _0)]
+    SessionTooLong(Duration),
+    #[fail(display = "Session closed because a keepalive could not be sent")]
+    KeepaliveFailed,
+    #[fail(
+        display = "Pull {} cannot be resumed: it expired, was resumed too many times or never existed",
+        _0
+    )]
+    CannotResumePull(String),
+    #[fail(
+        display = "Pull {} cannot be resumed from changeset {}, it only has {}",
+        _0, _1, _2
+    )]
+    InvalidResumeOffset(String, usize, usize),
+    #[fail(display = "Pulls from this repo cannot be resumed")]
+    ResumablePullDisabled,
+    #[fail(
+        display = "The response to {} is larger than {} bytes, please narrow your request",
+        _0, _1
+    )]
+    ResponseTooLarge(String, u64),
+    #[fail(
+        display = "Request {} is quarantined as it crashed or failed badly on a server, please try again later",
+        _0
+    )]
+    RequestQuarantined(String),
+    #[fail(display = "You don't have permission to read {}", _0)]
+    PathAccessDenied(RepoPath),
+    #[fail(
+        display = "{} is not known at {}, clients that can't read the whole repo may only fetch nodes at their paths",
+        _1, _0
+    )]
+    NodeNotAtPath(RepoPath, HgNodeHash),
+}
+
+impl Categorize for ErrorKind {
+    fn category(&self) -> Option<ErrorCategory> {
+        use self::ErrorKind::*;
+
+        match self {
+            DataCorruption { .. } => Some(ErrorCategory::Corrupt),
+            // The client can reconnect and try again
+            RepoInMaintenance(_) | SessionIdle(_) | SessionTooLong(_) | KeepaliveFailed => {
+                Some(ErrorCategory::Transient)
+            }
+            CannotResumePull(_) => Some(ErrorCategory::NotFound),
+            InvalidResumeOffset(..)
+            | ResumablePullDisabled
+            | ResponseTooLarge(..)
+            | RequestQuarantined(_)
+            | PathAccessDenied(_)
+            | NodeNotAtPath(..) => Some(ErrorCategory::InvalidRequest),
+        }
+    }
+}
+
+/// Knows the errors of wireproto commands, and of the repo and pushes they go to
+pub fn error_categorizer() -> Categorizer {
+    bundle2_resolver::error_categorizer().with::<ErrorKind>()
 }
@@ -9,11 +9,15 @@
 //! State for a single source control Repo
 
 extern crate bytes;
+extern crate cachelib;
+extern crate caching_ext;
 #[macro_use]
 extern crate cloned;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate fbwhoami;
+#[cfg(test)]
+extern crate fixtures;
 #[macro_use]
 extern crate futures;
 #[macro_use]
@@ -22,10 +26,13 @@ extern crate futures_stats;
 extern crate itertools;
 #[macro_use]
 extern crate maplit;
+extern crate memcache;
 extern crate percent_encoding;
 extern crate prefixblob;
+extern crate push_usage;
 extern crate rand;
-extern crate scribe;
+#[cfg(test)]
+extern crate regex;
 extern crate scribe_cxx;
 #[macro_use]
 extern crate serde_json;
@@ -37,25 +44,44 @@ extern crate time_ext;
 #[macro_use]
 extern crate tracing;
 
+extern crate audit_log;
 extern crate blobrepo;
 extern crate blobstore;
 extern crate bookmarks;
 extern crate bundle2_resolver;
+extern crate content_refs;
 extern crate context;
+extern crate event_bus;
 extern crate filenodes;
+extern crate hg_derivation_queue;
 extern crate hgproto;
 extern crate hooks;
 extern crate lz4_pyframe;
 extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
+#[cfg(test)]
+extern crate mercurial_types_mocks;
+#[cfg(test)]
+extern crate memblob;
 extern crate metaconfig_types;
+extern crate mononoke_errors;
 extern crate mononoke_types;
+#[cfg(test)]
+extern crate mononoke_types_mocks;
+extern crate path_acl;
 extern crate phases;
+extern crate qos;
 extern crate reachabilityindex;
+extern crate repo_maintenance;
+extern crate repo_overrides;
 extern crate remotefilelog;
+extern crate request_logging;
 extern crate revset;
 extern crate scuba_ext;
+#[cfg(test)]
+extern crate skiplist;
+extern crate webhook_dispatcher;
 #[macro_use]
 extern crate sql;
 extern crate sql_ext;
@@ -65,9 +91,20 @@ extern crate tokio;
 mod client;
 mod errors;
 mod mononoke_repo;
+mod quarantine;
 mod read_write;
+mod response_cache;
+mod resumable_pull;
+mod session_limits;
+mod tree_popularity;
 
 pub use client::RepoClient;
+pub use errors::error_categorizer;
 pub use mononoke_repo::{streaming_clone, MononokeRepo};
+pub use quarantine::{install_quarantine_panic_hook, Quarantine, RequestSignature};
 pub use read_write::RepoReadWriteFetcher;
+pub use response_cache::ResponseCache;
+pub use resumable_pull::{ResumablePull, ResumablePullStore, SqlResumablePullStore};
+pub use session_limits::{limit_session, SessionActivity};
 pub use streaming_clone::SqlStreamingChunksFetcher;
+pub use tree_popularity::{SqlTreePopularity, TreePopularity, TreePrefetch};
@@ -4,20 +4,36 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use audit_log::Auditor;
 use blobrepo::BlobRepo;
 use blobstore::Blobstore;
+use bookmarks::Bookmark;
+use content_refs::ContentRefsIndex;
+use context::CoreContext;
 use errors::*;
-use futures_ext::BoxFuture;
+use event_bus::{EventBus, RepoEvent};
+use futures::{Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use hg_derivation_queue::HgDerivationQueue;
 use hooks::HookManager;
+use mercurial_types::HgChangesetId;
 use metaconfig_types::{
-    BookmarkOrRegex, BookmarkParams, LfsParams, PushrebaseParams, RepoReadOnly,
+    BookmarkOrRegex, BookmarkParams, CopyInfoCheckParams, LfsParams, PushrebaseParams,
+    RepoReadOnly, ScratchNamespace, WireprotoCapsParams,
 };
-use mononoke_types::RepositoryId;
+use mononoke_types::{DateTime, RepositoryId};
 use prefixblob::PrefixBlobstore;
+use push_usage::PushQuota;
 use read_write::RepoReadWriteFetcher;
+use repo_maintenance::{MaintenanceStore, MaintenanceWindow};
+use repo_overrides::RepoOverrides;
+use response_cache::ResponseCache;
+use resumable_pull::ResumablePull;
 use std::fmt::{self, Debug};
 use std::sync::Arc;
 use streaming_clone::SqlStreamingChunksFetcher;
+use tree_popularity::TreePrefetch;
+use webhook_dispatcher::WebhookDispatcher;
 
 #[derive(Clone)]
 pub struct SqlStreamingCloneConfig {
@@ -36,6 +52,21 @@ pub struct MononokeRepo {
     lfs_params: LfsParams,
     reponame: String,
     readonly_fetcher: RepoReadWriteFetcher,
+    hg_derivation_queue: Option<Arc<HgDerivationQueue>>,
+    webhook_dispatcher: Arc<WebhookDispatcher>,
+    event_bus: EventBus,
+    maintenance_store: Arc<MaintenanceStore>,
+    overrides: RepoOverrides,
+    scratch_namespace: Option<ScratchNamespace>,
+    tree_prefetch: Option<TreePrefetch>,
+    auditor: Option<Auditor>,
+    response_cache: Option<ResponseCache>,
+    resumable_pull: Option<ResumablePull>,
+    push_quota: PushQuota,
+    content_refs: Option<ContentRefsIndex>,
+    wireproto_caps: WireprotoCapsParams,
+    copy_info_check: Option<CopyInfoCheckParams>,
+    phases_admin_identities: Vec<String>,
 }
 
 impl MononokeRepo {
@@ -49,6 +80,21 @@ impl MononokeRepo {
         lfs_params: LfsParams,
         reponame: String,
         readonly_fetcher: RepoReadWriteFetcher,
+        hg_derivation_queue: Option<Arc<HgDerivationQueue>>,
+        webhook_dispatcher: Arc<WebhookDispatcher>,
+        event_bus: EventBus,
+        maintenance_store: Arc<MaintenanceStore>,
+        overrides: RepoOverrides,
+        scratch_namespace: Option<ScratchNamespace>,
+        tree_prefetch: Option<TreePrefetch>,
+        auditor: Option<Auditor>,
+        response_cache: Option<ResponseCache>,
+        resumable_pull: Option<ResumablePull>,
+        push_quota: PushQuota,
+        content_refs: Option<ContentRefsIndex>,
+        wireproto_caps: WireprotoCapsParams,
+        copy_info_check: Option<CopyInfoCheckParams>,
+        phases_admin_identities: Vec<String>,
     ) -> Self {
         let fastforward_only_bookmarks = bookmark_params
             .into_iter()
@@ -69,6 +115,21 @@ impl MononokeRepo {
             lfs_params,
             reponame,
             readonly_fetcher,
+            hg_derivation_queue,
+            webhook_dispatcher,
+            event_bus,
+            maintenance_store,
+            overrides,
+            scratch_namespace,
+            tree_prefetch,
+            auditor,
+            response_cache,
+            resumable_pull,
+            push_quota,
+            content_refs,
+            wireproto_caps,
+            copy_info_check,
+            phases_admin_identities,
         }
     }
 
@@ -101,9 +162,150 @@ impl MononokeRepo {
         &self.reponame
     }
 
+    /// Set if hg changesets for pushrebased commits are derived in the background
+    pub fn hg_derivation_queue(&self) -> &Option<Arc<HgDerivationQueue>> {
+        &self.hg_derivation_queue
+    }
+
+    pub fn webhook_dispatcher(&self) -> &Arc<WebhookDispatcher> {
+        &self.webhook_dispatcher
+    }
+
+    pub fn event_bus(&self) -> &EventBus {
+        &self.event_bus
+    }
+
+    /// Set if gettreepack responses include popular trees that the client didn't ask for yet
+    pub fn tree_prefetch(&self) -> &Option<TreePrefetch> {
+        &self.tree_prefetch
+    }
+
+    /// Set if reads from the repo are recorded in an audit log
+    pub fn auditor(&self) -> &Option<Auditor> {
+        &self.auditor
+    }
+
+    /// Set if the trees and files sent to clients are cached
+    pub fn response_cache(&self) -> &Option<ResponseCache> {
+        &self.response_cache
+    }
+
+    /// Set if interrupted pulls from the repo can be resumed
+    pub fn resumable_pull(&self) -> &Option<ResumablePull> {
+        &self.resumable_pull
+    }
+
+    /// Changes to the capabilities advertised to the clients of the repo
+    pub fn wireproto_caps(&self) -> &WireprotoCapsParams {
+        &self.wireproto_caps
+    }
+
+    /// Set if getfiles sends copy information checked against the filenodes table
+    pub fn copy_info_check(&self) -> Option<CopyInfoCheckParams> {
+        self.copy_info_check
+    }
+
+    /// Who may make any commit public with a phases pushkey
+    pub fn phases_admin_identities(&self) -> &Vec<String> {
+        &self.phases_admin_identities
+    }
+
+    /// Whether `bookmark` is a scratch bookmark. Commits only reachable from scratch bookmarks
+    /// stay draft.
+    pub fn is_scratch_bookmark(&self, bookmark: &Bookmark) -> bool {
+        is_scratch_bookmark(self.scratch_namespace.as_ref(), bookmark)
+    }
+
+    /// Whether the repo is publishing in the Mercurial sense: every commit pushed to it becomes
+    /// public. Repos with scratch bookmarks aren't, as commits pushed to those stay draft.
+    pub fn is_publishing(&self) -> bool {
+        self.scratch_namespace.is_none()
+    }
+
+    /// All bookmarks except scratch ones, which are only served when asked for by name.
+    pub fn get_publishing_bookmarks_maybe_stale(
+        &self,
+        ctx: CoreContext,
+    ) -> BoxStream<(Bookmark, HgChangesetId), Error> {
+        publishing_bookmarks(ctx, self.blobrepo.clone(), self.scratch_namespace.clone())
+    }
+
+    pub fn push_quota(&self) -> &PushQuota {
+        &self.push_quota
+    }
+
+    pub fn content_refs(&self) -> Option<&ContentRefsIndex> {
+        self.content_refs.as_ref()
+    }
+
+    /// The tunables of the repo that are overridden without a restart
+    pub fn overrides(&self) -> &RepoOverrides {
+        &self.overrides
+    }
+
+    /// The maintenance window the repo is in right now, if any. Writes are refused during
+    /// maintenance, while reads keep working.
+    pub fn maintenance(&self, ctx: CoreContext) -> BoxFuture<Option<MaintenanceWindow>, Error> {
+        let now = DateTime::new(ctx.now());
+        self.maintenance_store
+            .get_active(ctx, self.blobrepo.get_repoid(), now)
+    }
+
     pub fn readonly(&self) -> BoxFuture<RepoReadOnly, Error> {
         self.readonly_fetcher.readonly()
     }
+
+    /// Locks the repo for writes, or unlocks it, and publishes the change to the event bus. The
+    /// lock state is changed by then, so a failure to publish is only logged.
+    pub fn set_readonly(&self, ctx: CoreContext, readonly: RepoReadOnly) -> BoxFuture<(), Error> {
+        let event = match readonly {
+            RepoReadOnly::ReadOnly(ref reason) => RepoEvent::RepoLocked {
+                repo_id: self.blobrepo.get_repoid(),
+                reason: reason.clone(),
+            },
+            RepoReadOnly::ReadWrite => RepoEvent::RepoUnlocked {
+                repo_id: self.blobrepo.get_repoid(),
+            },
+        };
+        cloned!(self.event_bus);
+        self.readonly_fetcher
+            .set_readonly(&readonly)
+            .and_then(move |()| {
+                event_bus.publish(event).then(move |res| {
+                    if let Err(err) = res {
+                        warn!(ctx.logger(), "failed to publish lock change: {:?}", err);
+                    }
+                    Ok(())
+                })
+            })
+            .boxify()
+    }
+}
+
+fn is_scratch_bookmark(scratch_namespace: Option<&ScratchNamespace>, bookmark: &Bookmark) -> bool {
+    match scratch_namespace {
+        Some(namespace) => namespace.matches_bookmark(bookmark),
+        None => false,
+    }
+}
+
+/// Scratch bookmarks are dropped before their changesets are converted to hg, as there can be
+/// many more of them than publishing bookmarks.
+fn publishing_bookmarks(
+    ctx: CoreContext,
+    blobrepo: BlobRepo,
+    scratch_namespace: Option<ScratchNamespace>,
+) -> BoxStream<(Bookmark, HgChangesetId), Error> {
+    blobrepo
+        .get_bonsai_bookmarks_maybe_stale(ctx.clone())
+        .filter(move |(bookmark, _)| !is_scratch_bookmark(scratch_namespace.as_ref(), bookmark))
+        .map(move |(bookmark, cs_id)| {
+            blobrepo
+                .get_hg_from_bonsai_changeset(ctx.clone(), cs_id)
+                .map(move |hg_cs_id| (bookmark, hg_cs_id))
+        })
+        .buffer_unordered(100)
+        .boxify()
 }
 
 pub fn streaming_clone(
@@ -127,3 +329,78 @@ impl Debug for MononokeRepo {
         write!(fmt, "MononokeRepo({:#?})", self.blobrepo.get_repoid())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use bookmarks::BookmarkUpdateReason;
+    use fixtures::linear;
+    use regex::Regex;
+
+    fn namespace() -> ScratchNamespace {
+        ScratchNamespace::new(Regex::new("^scratch/.+$").unwrap())
+    }
+
+    fn bookmark(name: &str) -> Bookmark {
+        Bookmark::new(name).unwrap()
+    }
+
+    #[test]
+    fn test_is_scratch_bookmark() {
+        let namespace = namespace();
+        assert!(is_scratch_bookmark(
+            Some(&namespace),
+            &bookmark("scratch/feature")
+        ));
+        assert!(!is_scratch_bookmark(Some(&namespace), &bookmark("master")));
+        assert!(!is_scratch_bookmark(Some(&namespace), &bookmark("scratch")));
+        assert!(!is_scratch_bookmark(None, &bookmark("scratch/feature")));
+    }
+
+    #[test]
+    fn test_publishing_bookmarks() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let ctx = CoreContext::test_mock();
+        let repo = linear::getrepo(None);
+
+        let master = HgChangesetId::from_str("79a13814c5ce7330173ec04d279bf95ab3f652fb").unwrap();
+        let draft = HgChangesetId::from_str("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").unwrap();
+        let draft_cs_id = rt
+            .block_on(repo.get_bonsai_from_hg(ctx.clone(), draft))
+            .unwrap()
+            .unwrap();
+        let mut txn = repo.update_bookmark_transaction(ctx.clone());
+        txn.force_set(
+            &bookmark("scratch/feature"),
+            draft_cs_id,
+            BookmarkUpdateReason::TestMove {
+                bundle_replay_data: None,
+            },
+        )
+        .unwrap();
+        rt.block_on(txn.commit()).unwrap();
+
+        let mut bookmarks = |scratch_namespace| {
+            let bookmarks = publishing_bookmarks(ctx.clone(), repo.clone(), scratch_namespace);
+            rt.block_on(bookmarks.collect())
+                .unwrap()
+                .into_iter()
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(
+            bookmarks(Some(namespace())),
+            hashset! {(bookmark("master"), master)}
+        );
+        assert_eq!(
+            bookmarks(None),
+            hashset! {
+                (bookmark("master"), master),
+                (bookmark("scratch/feature"), draft),
+            }
+        );
+    }
+}
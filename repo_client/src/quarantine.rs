@@ -0,0 +1,435 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Quarantine of poison-pill requests: requests that crash the server that serves them, or that
+//! fail because of corrupt data. The signature of such a request, a hash of its command and
+//! arguments, is put on a denylist in memcache for a short while. Retries of the request are
+//! then rejected by every server with an error that says so, instead of taking the servers down
+//! one after the other while the request is investigated.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::panic;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use caching_ext::MemcacheHandler;
+use context::CoreContext;
+use futures::{Future, Poll, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use memcache::{KeyGen, MemcacheClient};
+use metaconfig_types::QuarantineParams;
+use mononoke_errors::ErrorCategory;
+use mononoke_types::hash::{Blake2, Context};
+use mononoke_types::RepositoryId;
+use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
+use serde_json;
+use stats::Timeseries;
+
+use errors::*;
+
+// Memcache constants, should be changed when we want to invalidate memcache
+// entries
+const MC_CODEVER: u32 = 0;
+const MC_SITEVER: u32 = 0;
+
+/// How long a panicking thread waits for the request it was serving to be quarantined
+const PANIC_QUARANTINE_TIMEOUT: Duration = Duration::from_secs(1);
+
+define_stats! {
+    prefix = "mononoke.repo_client.quarantine";
+    quarantined_panic: timeseries(RATE, SUM),
+    quarantined_error: timeseries(RATE, SUM),
+    rejected: timeseries(RATE, SUM),
+    memcache_errors: timeseries(RATE, SUM),
+}
+
+thread_local! {
+    /// The request whose response this thread is polling, to quarantine if the thread panics
+    static SERVING: RefCell<Option<Arc<Suspect>>> = RefCell::new(None);
+}
+
+/// A hash of the command and arguments of a request
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RequestSignature {
+    command: &'static str,
+    hash: Blake2,
+}
+
+impl RequestSignature {
+    pub fn new(command: &'static str, args: &serde_json::Value) -> Self {
+        let mut context = Context::new(b"quarantine");
+        context.update(command.as_bytes());
+        context.update(b"\0");
+        context.update(args.to_string().as_bytes());
+        Self {
+            command,
+            hash: context.finish(),
+        }
+    }
+}
+
+impl fmt::Display for RequestSignature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.command, self.hash)
+    }
+}
+
+/// The denylist of the requests to a repo
+#[derive(Clone)]
+pub struct Quarantine {
+    repo_id: RepositoryId,
+    memcache: MemcacheHandler,
+    keygen: KeyGen,
+    ttl: Duration,
+}
+
+impl Quarantine {
+    pub fn new(repo_id: RepositoryId, params: &QuarantineParams) -> Self {
+        Self::with_memcache(repo_id, params, MemcacheClient::new().into())
+    }
+
+    pub fn new_test(repo_id: RepositoryId, params: &QuarantineParams) -> Self {
+        Self::with_memcache(repo_id, params, MemcacheHandler::create_mock())
+    }
+
+    fn with_memcache(
+        repo_id: RepositoryId,
+        params: &QuarantineParams,
+        memcache: MemcacheHandler,
+    ) -> Self {
+        Self {
+            repo_id,
+            memcache,
+            keygen: KeyGen::new("scm.mononoke.quarantine", MC_CODEVER, MC_SITEVER),
+            ttl: Duration::from_secs(params.ttl_secs),
+        }
+    }
+
+    fn key(&self, signature: &RequestSignature) -> String {
+        self.keygen
+            .key(format!("{}.{}", self.repo_id.prefix(), signature))
+    }
+
+    /// Whether `signature` is on the denylist. Requests are served if memcache can't tell.
+    pub fn is_quarantined(
+        &self,
+        signature: &RequestSignature,
+    ) -> impl Future<Item = bool, Error = Error> {
+        self.memcache
+            .get(self.key(signature))
+            .then(|res| match res {
+                Ok(value) => Ok(value.is_some()),
+                Err(()) => {
+                    STATS::memcache_errors.add_value(1);
+                    Ok(false)
+                }
+            })
+    }
+
+    /// Put `signature` on the denylist for the TTL of the quarantine
+    pub fn add(
+        &self,
+        signature: &RequestSignature,
+        mut scuba: ScubaSampleBuilder,
+        reason: String,
+    ) -> impl Future<Item = (), Error = Error> {
+        scuba
+            .add("command", signature.command)
+            .add("request_signature", signature.to_string())
+            .add("quarantine_ttl_secs", self.ttl.as_secs())
+            .log_with_msg("Request quarantined", Some(reason));
+        self.memcache
+            .set_with_ttl(
+                self.key(signature),
+                Bytes::from(signature.command),
+                self.ttl,
+            )
+            .then(|res| {
+                if res.is_err() {
+                    STATS::memcache_errors.add_value(1);
+                }
+                Ok(())
+            })
+    }
+
+    /// Serve `response` to a request of `command` with `args`, unless the request is on the
+    /// denylist. The request is put on it if the server panics while polling `response`, or if
+    /// `response` fails because of corrupt data.
+    pub fn guard<S>(
+        &self,
+        ctx: &CoreContext,
+        command: &'static str,
+        args: &serde_json::Value,
+        response: S,
+    ) -> BoxStream<S::Item, Error>
+    where
+        S: Stream<Error = Error> + Send + 'static,
+        S::Item: Send + 'static,
+    {
+        let suspect = Arc::new(Suspect {
+            quarantine: self.clone(),
+            signature: RequestSignature::new(command, args),
+            scuba: ctx.scuba().clone(),
+        });
+        let mut scuba = ctx.scuba().clone();
+
+        self.is_quarantined(&suspect.signature)
+            .and_then(move |quarantined| {
+                if quarantined {
+                    STATS::rejected.add_value(1);
+                    let signature = suspect.signature.to_string();
+                    scuba
+                        .add("command", command)
+                        .add("request_signature", signature.clone())
+                        .log_with_msg("Quarantined request rejected", None);
+                    return Err(ErrorKind::RequestQuarantined(signature).into());
+                }
+                Ok(Guarded {
+                    inner: response,
+                    suspect,
+                    quarantined: false,
+                })
+            })
+            .flatten_stream()
+            .boxify()
+    }
+
+    /// Like `guard`, for requests that are answered with a single response
+    pub fn guard_future<F>(
+        &self,
+        ctx: &CoreContext,
+        command: &'static str,
+        args: &serde_json::Value,
+        response: F,
+    ) -> BoxFuture<F::Item, Error>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        self.guard(ctx, command, args, response.into_stream())
+            .into_future()
+            .map(|(item, _)| item.expect("a future resolves to exactly one item"))
+            .map_err(|(err, _)| err)
+            .boxify()
+    }
+}
+
+/// A request that is being served, and the quarantine it goes to if serving it goes wrong
+struct Suspect {
+    quarantine: Quarantine,
+    signature: RequestSignature,
+    scuba: ScubaSampleBuilder,
+}
+
+struct Guarded<S> {
+    inner: S,
+    suspect: Arc<Suspect>,
+    quarantined: bool,
+}
+
+/// Restores the request that the thread was serving before, even if polling panics
+struct RestoreServing(Option<Arc<Suspect>>);
+
+impl Drop for RestoreServing {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        let _ = SERVING.try_with(|serving| *serving.borrow_mut() = previous);
+    }
+}
+
+impl<S: Stream<Error = Error>> Stream for Guarded<S> {
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let previous = SERVING.with(|serving| serving.replace(Some(self.suspect.clone())));
+        let _restore = RestoreServing(previous);
+
+        let res = self.inner.poll();
+        if let Err(ref err) = res {
+            if !self.quarantined && error_categorizer().categorize(err) == ErrorCategory::Corrupt {
+                self.quarantined = true;
+                STATS::quarantined_error.add_value(1);
+                let suspect = &self.suspect;
+                ::tokio::spawn(
+                    suspect
+                        .quarantine
+                        .add(
+                            &suspect.signature,
+                            suspect.scuba.clone(),
+                            format!("{}", err),
+                        )
+                        .discard(),
+                );
+            }
+        }
+        res
+    }
+}
+
+/// Chain a panic hook that quarantines the request that the panicking thread was serving to the
+/// panic hook that is set. Call it after the panic handler of the process is set up, as the
+/// handler may abort the process once it's called.
+pub fn install_quarantine_panic_hook() {
+    // The panicking thread may be the one that would serve the memcache request, so requests
+    // are quarantined from a thread of their own, and the hook gives up waiting on it after
+    // PANIC_QUARANTINE_TIMEOUT
+    let (sender, receiver) = mpsc::channel::<(Arc<Suspect>, String, mpsc::Sender<()>)>();
+    thread::Builder::new()
+        .name("quarantine".to_string())
+        .spawn(move || {
+            for (suspect, reason, done) in receiver {
+                let _ = suspect
+                    .quarantine
+                    .add(&suspect.signature, suspect.scuba.clone(), reason)
+                    .wait();
+                let _ = done.send(());
+            }
+        })
+        .expect("failed to spawn the quarantine thread");
+    let sender = Mutex::new(sender);
+
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let suspect = SERVING
+            .try_with(|serving| serving.borrow().clone())
+            .ok()
+            .and_then(|suspect| suspect);
+        if let Some(suspect) = suspect {
+            STATS::quarantined_panic.add_value(1);
+            let (done_sender, done) = mpsc::channel();
+            let sent = sender
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .send((suspect, info.to_string(), done_sender));
+            if sent.is_ok() {
+                let _ = done.recv_timeout(PANIC_QUARANTINE_TIMEOUT);
+            }
+        }
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::{stream, IntoFuture};
+    use mercurial_types::RepoPath;
+    use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+    use tokio::runtime::Runtime;
+
+    fn quarantine() -> Quarantine {
+        Quarantine::new_test(RepositoryId::new(0), &QuarantineParams { ttl_secs: 60 })
+    }
+
+    fn serve(
+        quarantine: &Quarantine,
+        args: &serde_json::Value,
+        response: BoxStream<Bytes, Error>,
+    ) -> Result<Vec<Bytes>> {
+        let ctx = CoreContext::test_mock();
+        let response = quarantine.guard(&ctx, "getbundle", args, response);
+        Runtime::new().unwrap().block_on(response.collect())
+    }
+
+    #[test]
+    fn test_signature() {
+        let args = json!({"heads": "1111"});
+        assert_eq!(
+            RequestSignature::new("getbundle", &args),
+            RequestSignature::new("getbundle", &args)
+        );
+        assert_ne!(
+            RequestSignature::new("getbundle", &args),
+            RequestSignature::new("gettreepack", &args)
+        );
+        assert_ne!(
+            RequestSignature::new("getbundle", &args),
+            RequestSignature::new("getbundle", &json!({"heads": "2222"}))
+        );
+    }
+
+    #[test]
+    fn test_corrupt_requests_are_quarantined() {
+        let quarantine = quarantine();
+        let args = json!({"heads": "1111"});
+        let corrupt = || -> BoxStream<Bytes, Error> {
+            stream::once(Err(ErrorKind::DataCorruption {
+                path: RepoPath::RootPath,
+                expected: ONES_HASH,
+                actual: TWOS_HASH,
+            }
+            .into()))
+            .boxify()
+        };
+
+        let err = serve(&quarantine, &args, corrupt()).unwrap_err();
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::DataCorruption { .. }) => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let err = serve(&quarantine, &args, stream::once(Ok(Bytes::new())).boxify()).unwrap_err();
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::RequestQuarantined(_)) => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        // Other requests are still served
+        let other = json!({"heads": "2222"});
+        let res = serve(&quarantine, &other, stream::once(Ok(Bytes::new())).boxify());
+        assert_eq!(res.unwrap(), vec![Bytes::new()]);
+    }
+
+    #[test]
+    fn test_guard_future() {
+        let quarantine = quarantine();
+        let ctx = CoreContext::test_mock();
+        let args = json!(["1111"]);
+        let mut runtime = Runtime::new().unwrap();
+
+        let response = quarantine.guard_future(&ctx, "lookup", &args, Ok(1).into_future());
+        assert_eq!(runtime.block_on(response).unwrap(), 1);
+
+        let corrupt = Err::<u32, Error>(
+            ErrorKind::DataCorruption {
+                path: RepoPath::RootPath,
+                expected: ONES_HASH,
+                actual: TWOS_HASH,
+            }
+            .into(),
+        );
+        let response = quarantine.guard_future(&ctx, "lookup", &args, corrupt.into_future());
+        assert!(runtime.block_on(response).is_err());
+
+        let response = quarantine.guard_future(&ctx, "lookup", &args, Ok(1).into_future());
+        match runtime
+            .block_on(response)
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::RequestQuarantined(_)) => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_other_errors_are_not_quarantined() {
+        let quarantine = quarantine();
+        let args = json!({"heads": "1111"});
+
+        let failing = stream::once(Err(ErrorKind::ResumablePullDisabled.into())).boxify();
+        assert!(serve(&quarantine, &args, failing).is_err());
+
+        let res = serve(&quarantine, &args, stream::once(Ok(Bytes::new())).boxify());
+        assert_eq!(res.unwrap(), vec![Bytes::new()]);
+    }
+}
@@ -4,8 +4,8 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use failure::Error;
-use futures::future::ok;
+use failure::{err_msg, Error};
+use futures::future::{err, ok};
 use futures::Future;
 use futures_ext::{BoxFuture, FutureExt};
 use sql::Connection;
@@ -68,11 +68,18 @@ queries! {
         "SELECT state FROM repo_lock
         WHERE repo = {repo_name}"
     }
+
+    write SetReadWriteStatus(values: (repo_name: String, state: HgMononokeReadWrite)) {
+        none,
+        "REPLACE INTO repo_lock(repo, state)
+        VALUES {values}"
+    }
 }
 
 #[derive(Clone)]
 pub struct RepoReadWriteFetcher {
     read_connection: Option<Connection>,
+    write_connection: Option<Connection>,
     readonly_config: RepoReadOnly,
     repo_name: String,
 }
@@ -89,6 +96,7 @@ impl RepoReadWriteFetcher {
 
         Self {
             read_connection: Some(builder.build_read_only()),
+            write_connection: Some(builder.build_read_write()),
             readonly_config,
             repo_name,
         }
@@ -99,6 +107,7 @@ impl RepoReadWriteFetcher {
             readonly_config,
             repo_name,
             read_connection: None,
+            write_connection: None,
         }
     }
 
@@ -129,6 +138,27 @@ impl RepoReadWriteFetcher {
             ok(self.readonly_config.clone()).boxify()
         }
     }
+
+    /// Locks the repo for writes, or unlocks it. Only the state is stored, so the repo reads as
+    /// locked in DB whatever the reason it was locked with.
+    pub fn set_readonly(&self, readonly: &RepoReadOnly) -> BoxFuture<(), Error> {
+        let write_connection = match self.write_connection {
+            Some(ref write_connection) => write_connection,
+            None => {
+                return err(err_msg(
+                    "the lock state of this repo is only set by its config",
+                ))
+                .boxify();
+            }
+        };
+        let state = match readonly {
+            RepoReadOnly::ReadOnly(_) => HgMononokeReadWrite::NoWrite,
+            RepoReadOnly::ReadWrite => HgMononokeReadWrite::MononokeWrite,
+        };
+        SetReadWriteStatus::query(write_connection, &[(&self.repo_name, &state)])
+            .map(|_| ())
+            .boxify()
+    }
 }
 
 #[cfg(test)]
@@ -159,7 +189,8 @@ mod test {
             Ok(Self {
                 readonly_config,
                 repo_name,
-                read_connection: Some(con),
+                read_connection: Some(con.clone()),
+                write_connection: Some(con),
             })
         }
 
@@ -226,6 +257,32 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_set_readonly_with_sqlite() {
+        let fetcher = RepoReadWriteFetcher::with_sqlite(ReadWrite, "repo".to_string()).unwrap();
+
+        fetcher.set_readonly(&ReadWrite).wait().unwrap();
+        assert_eq!(fetcher.readonly().wait().unwrap(), ReadWrite);
+
+        fetcher
+            .set_readonly(&ReadOnly("maintenance".to_string()))
+            .wait()
+            .unwrap();
+        assert_eq!(
+            fetcher.readonly().wait().unwrap(),
+            ReadOnly(DB_MSG.to_string())
+        );
+    }
+
+    #[test]
+    fn test_set_readonly_no_sqlite() {
+        let fetcher = RepoReadWriteFetcher::new(ReadWrite, "repo".to_string());
+        assert!(fetcher
+            .set_readonly(&ReadOnly(CONFIG_MSG.to_string()))
+            .wait()
+            .is_err());
+    }
+
     #[test]
     fn test_readwrite_with_sqlite_other_repo() {
         let fetcher = RepoReadWriteFetcher::with_sqlite(ReadWrite, "repo".to_string()).unwrap();
@@ -0,0 +1,533 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Caches the parts of gettreepack and getfiles responses that are the same for every client:
+//! the treepack entry of a tree and the remotefilelog blob of a file. After a big landing, many
+//! clients ask for the same trees and files, and without a cache each of them pays for fetching
+//! parents, linknodes and history again.
+//!
+//! Keys are made of the node, the path and the format of the cached value. Nodes are content
+//! addressed, and a node's linknode and history never change, so entries never need to be
+//! invalidated when the repo changes. Trees whose linknode isn't known yet are not cached, as
+//! their linknode is only missing until it's stored. Anything that changes how a value is encoded
+//! must bump `FORMAT_VERSION`.
+//!
+//! The content of a file can be replaced by a tombstone after its blob was cached though, so
+//! whether the content of a file is tombstoned is checked on every read. Cached blobs of
+//! tombstoned content are not served, and blobs are not cached while their content is
+//! tombstoned.
+//!
+//! Values that are stored in the blobstore expire after a TTL, past which they are misses and are
+//! overwritten when filled again, so that the fragments of trees and files that nobody asks for
+//! anymore don't have to be kept around. All their keys start with `responsecache.`.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use blobstore::Blobstore;
+use bytes::{Buf, BufMut, Bytes, BytesMut, IntoBuf};
+use cachelib::LruCachePool;
+use context::CoreContext;
+use failure::Error;
+use filenodes::blake2_path_hash;
+use futures::{Future, IntoFuture};
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{HgNodeHash, MPath, RepoPath, NULL_HASH};
+use metaconfig_types::LfsParams;
+use mononoke_types::BlobstoreBytes;
+use stats::Timeseries;
+use tokio;
+
+/// Version of the encoding of cached values, part of every key
+const FORMAT_VERSION: u32 = 2;
+
+define_stats! {
+    prefix = "mononoke.repo_client.response_cache";
+    cachelib_hits: timeseries(RATE, SUM),
+    blobstore_hits: timeseries(RATE, SUM),
+    misses: timeseries(RATE, SUM),
+    fill_failures: timeseries(RATE, SUM),
+    tombstoned: timeseries(RATE, SUM),
+}
+
+/// The parts of a treepack entry that are expensive to compute
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CachedTree {
+    pub p1: Option<HgNodeHash>,
+    pub p2: Option<HgNodeHash>,
+    /// None if the linknode isn't stored yet
+    pub linknode: Option<HgNodeHash>,
+    pub content: Bytes,
+}
+
+impl CachedTree {
+    fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(3 * 20 + self.content.len());
+        for node in &[
+            self.p1.unwrap_or(NULL_HASH),
+            self.p2.unwrap_or(NULL_HASH),
+            self.linknode.unwrap_or(NULL_HASH),
+        ] {
+            buf.put_slice(node.as_bytes());
+        }
+        buf.put_slice(&self.content);
+        buf.freeze()
+    }
+
+    fn decode(mut bytes: Bytes) -> Result<Self, Error> {
+        if bytes.len() < 3 * 20 {
+            bail_msg!("cached tree is too short: {} bytes", bytes.len());
+        }
+        let p1 = HgNodeHash::from_bytes(&bytes.split_to(20))?;
+        let p2 = HgNodeHash::from_bytes(&bytes.split_to(20))?;
+        let linknode = HgNodeHash::from_bytes(&bytes.split_to(20))?;
+        let non_null = |node| if node == NULL_HASH { None } else { Some(node) };
+        Ok(Self {
+            p1: non_null(p1),
+            p2: non_null(p2),
+            linknode: non_null(linknode),
+            content: bytes,
+        })
+    }
+}
+
+/// A cache of response fragments shared by all clients of a repo. Values live in cachelib, and
+/// optionally in the blobstore too so that they survive restarts and are shared across servers.
+#[derive(Clone)]
+pub struct ResponseCache {
+    cache_pool: Option<LruCachePool>,
+    blobstore: Option<Arc<Blobstore>>,
+    blobstore_ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(
+        cache_pool: Option<LruCachePool>,
+        blobstore: Option<Arc<Blobstore>>,
+        blobstore_ttl: Duration,
+    ) -> Self {
+        Self {
+            cache_pool,
+            blobstore,
+            blobstore_ttl,
+        }
+    }
+
+    pub fn get_or_fill_tree(
+        &self,
+        ctx: CoreContext,
+        node: HgNodeHash,
+        path: &RepoPath,
+        fill: BoxFuture<CachedTree, Error>,
+    ) -> BoxFuture<CachedTree, Error> {
+        let key = format!(
+            "responsecache.v{}.tree.{}.{}",
+            FORMAT_VERSION,
+            node,
+            path_key(path.mpath())
+        );
+        let fill = fill
+            .map(|tree| (tree.encode(), tree.linknode.is_some()))
+            .boxify();
+        self.get_or_fill(ctx, key, fill, Ok(false).into_future().boxify())
+            .and_then(CachedTree::decode)
+            .boxify()
+    }
+
+    /// The remotefilelog blob of a file. Blobs depend on the repo's LFS settings and on whether
+    /// they carry copy information, which are part of the key. `tombstoned` tells whether the
+    /// content of the file is tombstoned now.
+    pub fn get_or_fill_file(
+        &self,
+        ctx: CoreContext,
+        node: HgNodeHash,
+        path: &MPath,
+        lfs_params: &LfsParams,
+        copy_info: bool,
+        fill: BoxFuture<Bytes, Error>,
+        tombstoned: BoxFuture<bool, Error>,
+    ) -> BoxFuture<Bytes, Error> {
+        let lfs = match lfs_params.threshold {
+            Some(threshold) => format!("lfs{}", threshold),
+            None => "nolfs".to_string(),
+        };
+        let copy_info = if copy_info { "copyinfo" } else { "nocopyinfo" };
+        let key = format!(
+            "responsecache.v{}.file.{}.{}.{}.{}",
+            FORMAT_VERSION,
+            lfs,
+            copy_info,
+            node,
+            path_key(Some(path))
+        );
+        let fill = fill.map(|bytes| (bytes, true)).boxify();
+        self.get_or_fill(ctx, key, fill, tombstoned)
+    }
+
+    /// `fill` computes the value on a miss, and tells whether it may be cached. Values are
+    /// neither served from the cache nor cached while `tombstoned` is true, or can't be told.
+    fn get_or_fill(
+        &self,
+        ctx: CoreContext,
+        key: String,
+        fill: BoxFuture<(Bytes, bool), Error>,
+        tombstoned: BoxFuture<bool, Error>,
+    ) -> BoxFuture<Bytes, Error> {
+        let from_cachelib = match self.cache_pool {
+            Some(ref cache_pool) => cache_pool.get(&key).unwrap_or(None),
+            None => None,
+        };
+
+        // The flag tells whether the value came from cachelib
+        let cached = match (from_cachelib, &self.blobstore) {
+            // A blobstore failure is just a miss, the value can always be computed again
+            (None, Some(blobstore)) => blobstore
+                .get(ctx.clone(), key.clone())
+                .then({
+                    let now = ctx.now().timestamp();
+                    move |res| {
+                        let bytes = res.unwrap_or(None).map(|bytes| bytes.into_bytes());
+                        let bytes = bytes.and_then(|bytes| unexpired(bytes, now));
+                        Ok::<_, Error>(bytes.map(|bytes| (bytes, false)))
+                    }
+                })
+                .left_future(),
+            (from_cachelib, _) => Ok(from_cachelib.map(|bytes| (bytes, true)))
+                .into_future()
+                .right_future(),
+        };
+        let tombstoned = tombstoned.then(|res| Ok::<_, Error>(res.unwrap_or(true)));
+
+        let this = self.clone();
+        cached
+            .join(tombstoned)
+            .and_then(move |(cached, tombstoned)| match cached {
+                _ if tombstoned => {
+                    STATS::tombstoned.add_value(1);
+                    fill.map(|(bytes, _)| bytes).boxify()
+                }
+                Some((bytes, from_cachelib)) => {
+                    if from_cachelib {
+                        STATS::cachelib_hits.add_value(1);
+                    } else {
+                        STATS::blobstore_hits.add_value(1);
+                        this.put_in_cachelib(&key, bytes.clone());
+                    }
+                    Ok(bytes).into_future().boxify()
+                }
+                None => {
+                    STATS::misses.add_value(1);
+                    fill.map(move |(bytes, cacheable)| {
+                        if cacheable {
+                            this.put_in_cachelib(&key, bytes.clone());
+                            this.put_in_blobstore(ctx, key, bytes.clone());
+                        }
+                        bytes
+                    })
+                    .boxify()
+                }
+            })
+            .boxify()
+    }
+
+    fn put_in_cachelib(&self, key: &str, bytes: Bytes) {
+        if let Some(ref cache_pool) = self.cache_pool {
+            // Values too big for cachelib are only kept in the blobstore
+            let _ = cache_pool.set(key, bytes);
+        }
+    }
+
+    fn put_in_blobstore(&self, ctx: CoreContext, key: String, bytes: Bytes) {
+        if let Some(ref blobstore) = self.blobstore {
+            let expiry = ctx.now().timestamp() as u64 + self.blobstore_ttl.as_secs();
+            let mut value = BytesMut::with_capacity(8 + bytes.len());
+            value.put_u64_be(expiry);
+            value.put_slice(&bytes);
+            // Filling the cache mustn't slow down or fail the response
+            tokio::spawn(
+                blobstore
+                    .put(ctx, key, BlobstoreBytes::from_bytes(value.freeze()))
+                    .map_err(|_| STATS::fill_failures.add_value(1)),
+            );
+        }
+    }
+}
+
+/// The value of a blobstore entry, which starts with the unix time it expires at, unless it
+/// expired by `now`
+fn unexpired(mut bytes: Bytes, now: i64) -> Option<Bytes> {
+    if bytes.len() < 8 {
+        return None;
+    }
+    let expiry = bytes.split_to(8).into_buf().get_u64_be();
+    if now < 0 || now as u64 >= expiry {
+        None
+    } else {
+        Some(bytes)
+    }
+}
+
+fn path_key(path: Option<&MPath>) -> String {
+    match path {
+        Some(path) => blake2_path_hash(&path.to_vec()).to_hex().to_string(),
+        None => "root".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use context::MockTimeUuidProvider;
+    use failure::err_msg;
+    use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+    use mononoke_types::DateTime;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn mock_ctx() -> (CoreContext, Arc<MockTimeUuidProvider>) {
+        let start = *DateTime::from_timestamp(1000, 0).unwrap().as_chrono();
+        let time = Arc::new(MockTimeUuidProvider::new(start));
+        let ctx = CoreContext::test_mock().with_time_uuid_provider(time.clone());
+        (ctx, time)
+    }
+
+    fn not_tombstoned() -> BoxFuture<bool, Error> {
+        Ok(false).into_future().boxify()
+    }
+
+    #[test]
+    fn tree_encoding() {
+        let tree = CachedTree {
+            p1: Some(ONES_HASH),
+            p2: None,
+            linknode: Some(TWOS_HASH),
+            content: Bytes::from(&b"content"[..]),
+        };
+        assert_eq!(CachedTree::decode(tree.encode()).unwrap(), tree);
+
+        let root = CachedTree {
+            p1: None,
+            p2: None,
+            linknode: Some(ONES_HASH),
+            content: Bytes::new(),
+        };
+        assert_eq!(CachedTree::decode(root.encode()).unwrap(), root);
+
+        let no_linknode = CachedTree {
+            linknode: None,
+            ..root
+        };
+        assert_eq!(
+            CachedTree::decode(no_linknode.encode()).unwrap(),
+            no_linknode
+        );
+
+        assert!(CachedTree::decode(Bytes::from(&b"short"[..])).is_err());
+    }
+
+    #[test]
+    fn cache_tiers() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (ctx, _) = mock_ctx();
+        let blobstore: Arc<Blobstore> = Arc::new(memblob::EagerMemblob::new());
+        let cache = ResponseCache::new(None, Some(blobstore), TTL);
+        let path = MPath::new("dir/file").unwrap();
+        let lfs_params = LfsParams::default();
+
+        let filled = rt
+            .block_on(cache.get_or_fill_file(
+                ctx.clone(),
+                ONES_HASH,
+                &path,
+                &lfs_params,
+                false,
+                Ok(Bytes::from(&b"blob"[..])).into_future().boxify(),
+                not_tombstoned(),
+            ))
+            .unwrap();
+        assert_eq!(filled, Bytes::from(&b"blob"[..]));
+
+        // Wait for the blobstore fill to land
+        rt.shutdown_on_idle().wait().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // The second time around the value comes from the blobstore, not from the fill
+        let cached = rt
+            .block_on(
+                cache.get_or_fill_file(
+                    ctx.clone(),
+                    ONES_HASH,
+                    &path,
+                    &lfs_params,
+                    false,
+                    Err(err_msg("not expected to be called"))
+                        .into_future()
+                        .boxify(),
+                    not_tombstoned(),
+                ),
+            )
+            .unwrap();
+        assert_eq!(cached, filled);
+
+        // Blobs with copy information don't share it either
+        let res = rt.block_on(cache.get_or_fill_file(
+            ctx.clone(),
+            ONES_HASH,
+            &path,
+            &lfs_params,
+            true,
+            Err(err_msg("fill")).into_future().boxify(),
+            not_tombstoned(),
+        ));
+        assert!(res.is_err());
+
+        // Other LFS settings don't share the cached blob
+        let lfs_params = LfsParams {
+            threshold: Some(10),
+        };
+        let res = rt.block_on(cache.get_or_fill_file(
+            ctx,
+            ONES_HASH,
+            &path,
+            &lfs_params,
+            false,
+            Err(err_msg("fill")).into_future().boxify(),
+            not_tombstoned(),
+        ));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn blobstore_ttl() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (ctx, time) = mock_ctx();
+        let at = |secs| *DateTime::from_timestamp(secs, 0).unwrap().as_chrono();
+        let blobstore: Arc<Blobstore> = Arc::new(memblob::EagerMemblob::new());
+        let cache = ResponseCache::new(None, Some(blobstore), TTL);
+        let path = MPath::new("file").unwrap();
+        let lfs_params = LfsParams::default();
+        let get = |fill: BoxFuture<Bytes, Error>| {
+            cache.get_or_fill_file(
+                ctx.clone(),
+                ONES_HASH,
+                &path,
+                &lfs_params,
+                false,
+                fill,
+                not_tombstoned(),
+            )
+        };
+
+        rt.block_on(get(Ok(Bytes::from(&b"old"[..])).into_future().boxify()))
+            .unwrap();
+        rt.shutdown_on_idle().wait().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        time.set_now(at(1059));
+        let cached = rt.block_on(get(Err(err_msg("fill")).into_future().boxify()));
+        assert_eq!(cached.unwrap(), Bytes::from(&b"old"[..]));
+
+        // Once expired, the value is filled again
+        time.set_now(at(1060));
+        let filled = rt.block_on(get(Ok(Bytes::from(&b"new"[..])).into_future().boxify()));
+        assert_eq!(filled.unwrap(), Bytes::from(&b"new"[..]));
+    }
+
+    #[test]
+    fn tree_without_linknode_not_cached() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (ctx, _) = mock_ctx();
+        let blobstore: Arc<Blobstore> = Arc::new(memblob::EagerMemblob::new());
+        let cache = ResponseCache::new(None, Some(blobstore), TTL);
+        let tree = CachedTree {
+            p1: None,
+            p2: None,
+            linknode: None,
+            content: Bytes::from(&b"content"[..]),
+        };
+
+        let filled = rt
+            .block_on(cache.get_or_fill_tree(
+                ctx.clone(),
+                ONES_HASH,
+                &RepoPath::RootPath,
+                Ok(tree.clone()).into_future().boxify(),
+            ))
+            .unwrap();
+        assert_eq!(filled, tree);
+        rt.shutdown_on_idle().wait().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // Once the linknode is known, the tree is filled with it
+        let tree = CachedTree {
+            linknode: Some(TWOS_HASH),
+            ..tree
+        };
+        let filled = rt
+            .block_on(cache.get_or_fill_tree(
+                ctx,
+                ONES_HASH,
+                &RepoPath::RootPath,
+                Ok(tree.clone()).into_future().boxify(),
+            ))
+            .unwrap();
+        assert_eq!(filled, tree);
+    }
+
+    #[test]
+    fn tombstoned_file_not_served_from_cache() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (ctx, _) = mock_ctx();
+        let blobstore: Arc<Blobstore> = Arc::new(memblob::EagerMemblob::new());
+        let cache = ResponseCache::new(None, Some(blobstore), TTL);
+        let path = MPath::new("file").unwrap();
+        let lfs_params = LfsParams::default();
+        let get = |fill: &'static [u8], tombstoned: bool| {
+            cache.get_or_fill_file(
+                ctx.clone(),
+                ONES_HASH,
+                &path,
+                &lfs_params,
+                false,
+                Ok(Bytes::from(fill)).into_future().boxify(),
+                Ok(tombstoned).into_future().boxify(),
+            )
+        };
+
+        rt.block_on(get(b"secret", false)).unwrap();
+        rt.shutdown_on_idle().wait().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(
+            rt.block_on(get(b"unexpected", false)).unwrap(),
+            Bytes::from(&b"secret"[..])
+        );
+
+        // Once the content is tombstoned, the placeholder is served, and isn't cached
+        assert_eq!(
+            rt.block_on(get(b"placeholder", true)).unwrap(),
+            Bytes::from(&b"placeholder"[..])
+        );
+        rt.shutdown_on_idle().wait().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(
+            rt.block_on(get(b"placeholder", true)).unwrap(),
+            Bytes::from(&b"placeholder"[..])
+        );
+
+        // and a failure to tell is the same as a tombstone
+        let res = rt.block_on(cache.get_or_fill_file(
+            ctx.clone(),
+            ONES_HASH,
+            &path,
+            &lfs_params,
+            false,
+            Ok(Bytes::from(&b"placeholder"[..])).into_future().boxify(),
+            Err(err_msg("unavailable")).into_future().boxify(),
+        ));
+        assert_eq!(res.unwrap(), Bytes::from(&b"placeholder"[..]));
+    }
+}
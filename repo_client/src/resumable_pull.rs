@@ -0,0 +1,321 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Resumption of interrupted pulls. A resumable pull remembers the commits it sends under a
+//! token for a while; a pull that resumes it with the token and the number of commits the client
+//! already got sends the remaining ones, without finding which commits the client is missing
+//! again.
+
+use std::sync::Arc;
+
+use context::CoreContext;
+use failure::Error;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use metaconfig_types::ResumablePullParams;
+use mononoke_types::{ChangesetId, DateTime, RepositoryId, Timestamp};
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+use sql_ext::SqlConstructors;
+
+use errors::ErrorKind;
+
+// Commits are stored as their concatenated hashes
+const CHANGESET_ID_LEN: usize = 32;
+
+pub trait ResumablePullStore: Send + Sync {
+    /// Remember the commits sent by a pull under `token`, until `expires`.
+    fn save(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        token: String,
+        commits: Vec<ChangesetId>,
+        expires: Timestamp,
+    ) -> BoxFuture<(), Error>;
+
+    /// The commits sent by the pull with `token`, if it hasn't expired at `now` and was resumed
+    /// less than `max_resumes` times. Successful calls count as resuming the pull.
+    fn resume(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        token: String,
+        now: Timestamp,
+        max_resumes: usize,
+    ) -> BoxFuture<Option<Vec<ChangesetId>>, Error>;
+}
+
+/// Everything getbundle needs to make pulls resumable
+#[derive(Clone)]
+pub struct ResumablePull {
+    pub params: ResumablePullParams,
+    pub store: Arc<ResumablePullStore>,
+}
+
+impl ResumablePull {
+    /// Remember the commits a pull sends under a new token. Returns the token, and the commits
+    /// once they are remembered.
+    pub fn save(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        commits: BoxFuture<Vec<ChangesetId>, Error>,
+    ) -> (String, BoxFuture<Vec<ChangesetId>, Error>) {
+        let token = ctx.new_uuid().to_string();
+        let expires = Timestamp::from_timestamp_nanos(
+            now(&ctx).timestamp_nanos() + self.params.ttl_secs as i64 * 1_000_000_000,
+        );
+        let store = self.store.clone();
+
+        let commits = commits
+            .and_then({
+                cloned!(token);
+                move |commits| {
+                    store
+                        .save(ctx, repo_id, token, commits.clone(), expires)
+                        .map(move |()| commits)
+                }
+            })
+            .boxify();
+        (token, commits)
+    }
+
+    /// The commits of the pull with `token` that the client doesn't have yet, given that it got
+    /// the first `from` of them.
+    pub fn resume(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        token: String,
+        from: usize,
+    ) -> BoxFuture<Vec<ChangesetId>, Error> {
+        let now = now(&ctx);
+        self.store
+            .resume(ctx, repo_id, token.clone(), now, self.params.max_resumes)
+            .and_then(move |commits| match commits {
+                Some(ref commits) if from > commits.len() => {
+                    Err(ErrorKind::InvalidResumeOffset(token, from, commits.len()).into())
+                }
+                Some(mut commits) => Ok(commits.split_off(from)),
+                None => Err(ErrorKind::CannotResumePull(token).into()),
+            })
+            .boxify()
+    }
+}
+
+fn now(ctx: &CoreContext) -> Timestamp {
+    Timestamp::from(DateTime::new(ctx.now()))
+}
+
+queries! {
+    write InsertPull(values: (
+        repo_id: RepositoryId,
+        token: String,
+        commits: Vec<u8>,
+        expires: Timestamp,
+    )) {
+        none,
+        "INSERT INTO resumable_pulls (repo_id, token, commits, expires) VALUES {values}"
+    }
+
+    write DeleteExpiredPulls(repo_id: RepositoryId, now: Timestamp) {
+        none,
+        "DELETE FROM resumable_pulls WHERE repo_id = {repo_id} AND expires <= {now}"
+    }
+
+    write BumpResumes(repo_id: RepositoryId, token: String, now: Timestamp, max_resumes: usize) {
+        none,
+        "UPDATE resumable_pulls SET resumes = resumes + 1
+         WHERE repo_id = {repo_id} AND token = {token}
+           AND expires > {now} AND resumes < {max_resumes}"
+    }
+
+    read SelectCommits(repo_id: RepositoryId, token: String) -> (Vec<u8>) {
+        "SELECT commits FROM resumable_pulls WHERE repo_id = {repo_id} AND token = {token}"
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlResumablePullStore {
+    write_connection: Connection,
+    read_master_connection: Connection,
+}
+
+impl SqlConstructors for SqlResumablePullStore {
+    fn from_connections(
+        write_connection: Connection,
+        _read_connection: Connection,
+        read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_master_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-resumable-pulls.sql")
+    }
+}
+
+impl SqlMigrations for SqlResumablePullStore {
+    const STORE: &'static str = "resumable_pulls";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the resumable_pulls table",
+            sqlite: "CREATE TABLE `resumable_pulls` (
+                       `repo_id` INT UNSIGNED NOT NULL,
+                       `token` VARCHAR(64) NOT NULL,
+                       `commits` LONGBLOB NOT NULL,
+                       `expires` BIGINT NOT NULL,
+                       `resumes` INT UNSIGNED NOT NULL DEFAULT 0,
+                       PRIMARY KEY (`repo_id`, `token`)
+                     );
+
+                     CREATE INDEX `resumable_pulls_repo_expires` ON `resumable_pulls` (`repo_id`, `expires`);",
+            mysql: "CREATE TABLE `resumable_pulls` (
+                      `repo_id` INT UNSIGNED NOT NULL,
+                      `token` VARCHAR(64) NOT NULL,
+                      `commits` LONGBLOB NOT NULL,
+                      `expires` BIGINT NOT NULL,
+                      `resumes` INT UNSIGNED NOT NULL DEFAULT 0,
+                      PRIMARY KEY (`repo_id`, `token`),
+                      KEY `resumable_pulls_repo_expires` (`repo_id`, `expires`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+impl ResumablePullStore for SqlResumablePullStore {
+    fn save(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        token: String,
+        commits: Vec<ChangesetId>,
+        expires: Timestamp,
+    ) -> BoxFuture<(), Error> {
+        let mut blob = Vec::with_capacity(commits.len() * CHANGESET_ID_LEN);
+        for commit in &commits {
+            blob.extend_from_slice(commit.as_ref());
+        }
+
+        // Expired pulls are only cleaned up when new ones come in
+        let write_connection = self.write_connection.clone();
+        DeleteExpiredPulls::query(&self.write_connection, &repo_id, &now(&ctx))
+            .and_then(move |_| {
+                InsertPull::query(&write_connection, &[(&repo_id, &token, &blob, &expires)])
+            })
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn resume(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        token: String,
+        now: Timestamp,
+        max_resumes: usize,
+    ) -> BoxFuture<Option<Vec<ChangesetId>>, Error> {
+        // Reads go to the master, the pull was saved moments ago
+        let read_master_connection = self.read_master_connection.clone();
+        BumpResumes::query(&self.write_connection, &repo_id, &token, &now, &max_resumes)
+            .and_then(move |result| {
+                if result.affected_rows() != 1 {
+                    return future::ok(None).left_future();
+                }
+                SelectCommits::query(&read_master_connection, &repo_id, &token)
+                    .and_then(|rows| match rows.into_iter().next() {
+                        Some((blob,)) => blob
+                            .chunks(CHANGESET_ID_LEN)
+                            .map(ChangesetId::from_bytes)
+                            .collect::<Result<Vec<_>, _>>()
+                            .map(Some),
+                        None => Ok(None),
+                    })
+                    .right_future()
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use context::MockTimeUuidProvider;
+    use mononoke_types_mocks::changesetid::{ONES_CSID, THREES_CSID, TWOS_CSID};
+
+    fn resumable_pull(max_resumes: usize) -> ResumablePull {
+        ResumablePull {
+            params: ResumablePullParams {
+                ttl_secs: 60,
+                max_resumes,
+            },
+            store: Arc::new(SqlResumablePullStore::with_sqlite_in_memory().unwrap()),
+        }
+    }
+
+    fn saved(pull: &ResumablePull, ctx: &CoreContext, commits: Vec<ChangesetId>) -> String {
+        let (token, commits) = pull.save(
+            ctx.clone(),
+            RepositoryId::new(1),
+            future::ok(commits).boxify(),
+        );
+        commits.wait().unwrap();
+        token
+    }
+
+    #[test]
+    fn resume_from_offset() {
+        let ctx = CoreContext::test_mock();
+        let pull = resumable_pull(2);
+        let token = saved(&pull, &ctx, vec![ONES_CSID, TWOS_CSID, THREES_CSID]);
+        let resume = |from| {
+            pull.resume(ctx.clone(), RepositoryId::new(1), token.clone(), from)
+                .wait()
+        };
+
+        assert_eq!(resume(1).unwrap(), vec![TWOS_CSID, THREES_CSID]);
+        // Offsets past the end of the pull are rejected, but still count as resumptions
+        assert!(resume(4).is_err());
+        // Resumed too many times
+        assert!(resume(3).is_err());
+
+        // Tokens are per repo
+        assert!(pull
+            .resume(ctx.clone(), RepositoryId::new(2), token.clone(), 0)
+            .wait()
+            .is_err());
+    }
+
+    #[test]
+    fn pulls_expire() {
+        let at = |secs| *DateTime::from_timestamp(secs, 0).unwrap().as_chrono();
+        let time = Arc::new(MockTimeUuidProvider::new(at(1000)));
+        let ctx = CoreContext::test_mock().with_time_uuid_provider(time.clone());
+        let pull = resumable_pull(10);
+        let token = saved(&pull, &ctx, vec![ONES_CSID]);
+
+        time.set_now(at(1059));
+        assert_eq!(
+            pull.resume(ctx.clone(), RepositoryId::new(1), token.clone(), 0)
+                .wait()
+                .unwrap(),
+            vec![ONES_CSID]
+        );
+
+        time.set_now(at(1061));
+        assert!(pull
+            .resume(ctx.clone(), RepositoryId::new(1), token, 0)
+            .wait()
+            .is_err());
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Limits on the lifetime of wireproto sessions. A session is reaped when it has been idle for
+//! too long, when it has been open for too long, or when a keepalive can't reach its client.
+//! Sessions are not idle while they are handling a request, however long it takes.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, loop_fn, Loop};
+use futures::{Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use metaconfig_types::SessionLimits;
+use stats::Timeseries;
+use tokio::timer::{Delay, Interval};
+
+use errors::*;
+
+define_stats! {
+    prefix = "mononoke.repo_client.session";
+    reaped_idle: timeseries(RATE, SUM),
+    reaped_max_duration: timeseries(RATE, SUM),
+    reaped_keepalive_failed: timeseries(RATE, SUM),
+    keepalives_sent: timeseries(RATE, SUM),
+}
+
+/// When a session last sent or received data, and how many of its requests are being handled.
+/// Clones share the same session.
+#[derive(Clone)]
+pub struct SessionActivity {
+    last: Arc<Mutex<Instant>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl SessionActivity {
+    pub fn new() -> Self {
+        Self {
+            last: Arc::new(Mutex::new(Instant::now())),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The count of requests being handled, for the protocol handler to keep up to date
+    pub fn in_flight_requests(&self) -> Arc<AtomicUsize> {
+        self.in_flight.clone()
+    }
+
+    /// Record that the session sent or received data
+    pub fn touch(&self) {
+        *self.last.lock().expect("lock poisoned") = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.last.lock().expect("lock poisoned")
+    }
+
+    fn is_handling_request(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) > 0
+    }
+}
+
+impl Default for SessionActivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `session` within `limits`, failing it if it goes over one of them. `keepalive` is called
+/// whenever the session has been idle for a keepalive interval; if it fails, the client is
+/// assumed to be gone and the session is reaped as well.
+pub fn limit_session<F, K>(
+    session: F,
+    activity: SessionActivity,
+    limits: &SessionLimits,
+    keepalive: K,
+) -> BoxFuture<(), Error>
+where
+    F: Future<Item = (), Error = Error> + Send + 'static,
+    K: FnMut() -> BoxFuture<(), Error> + Send + 'static,
+{
+    limit_session_durations(
+        session,
+        activity,
+        limits.idle_timeout_secs.map(Duration::from_secs),
+        limits.keepalive_interval_secs.map(Duration::from_secs),
+        limits.max_session_duration_secs.map(Duration::from_secs),
+        keepalive,
+    )
+}
+
+fn limit_session_durations<F, K>(
+    session: F,
+    activity: SessionActivity,
+    idle_timeout: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    max_duration: Option<Duration>,
+    mut keepalive: K,
+) -> BoxFuture<(), Error>
+where
+    F: Future<Item = (), Error = Error> + Send + 'static,
+    K: FnMut() -> BoxFuture<(), Error> + Send + 'static,
+{
+    // Each watchdog only ever resolves with the error that ends the session
+    let mut watchdogs: Vec<BoxFuture<(), Error>> = vec![];
+
+    if let Some(idle_timeout) = idle_timeout {
+        cloned!(activity);
+        watchdogs.push(
+            loop_fn((), move |()| {
+                // A request that takes a while to answer doesn't make its session idle
+                let deadline = if activity.is_handling_request() {
+                    Instant::now() + idle_timeout
+                } else {
+                    activity.last() + idle_timeout
+                };
+                if Instant::now() >= deadline {
+                    STATS::reaped_idle.add_value(1);
+                    return future::err(ErrorKind::SessionIdle(idle_timeout).into()).left_future();
+                }
+                // The session may have been active while we waited, in which case the deadline
+                // moved and we wait again
+                Delay::new(deadline)
+                    .map(|()| Loop::Continue(()))
+                    .from_err()
+                    .right_future()
+            })
+            .boxify(),
+        );
+    }
+
+    if let Some(max_duration) = max_duration {
+        watchdogs.push(
+            Delay::new(Instant::now() + max_duration)
+                .from_err()
+                .and_then(move |()| {
+                    STATS::reaped_max_duration.add_value(1);
+                    Err(ErrorKind::SessionTooLong(max_duration).into())
+                })
+                .boxify(),
+        );
+    }
+
+    if let Some(keepalive_interval) = keepalive_interval {
+        watchdogs.push(
+            Interval::new(Instant::now() + keepalive_interval, keepalive_interval)
+                .from_err()
+                .for_each(move |_| {
+                    if activity.last().elapsed() < keepalive_interval {
+                        return future::ok(()).left_future();
+                    }
+                    STATS::keepalives_sent.add_value(1);
+                    keepalive()
+                        .map_err(|err| {
+                            STATS::reaped_keepalive_failed.add_value(1);
+                            err.context(ErrorKind::KeepaliveFailed).into()
+                        })
+                        .right_future()
+                })
+                .boxify(),
+        );
+    }
+
+    if watchdogs.is_empty() {
+        return session.boxify();
+    }
+
+    let watchdog = future::select_all(watchdogs)
+        .map(|_| ())
+        .map_err(|(err, _, _)| err);
+
+    session
+        .select(watchdog)
+        .map(|_| ())
+        .map_err(|(err, _)| err)
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+
+    fn counting_keepalive(count: &Arc<AtomicUsize>) -> impl FnMut() -> BoxFuture<(), Error> {
+        let count = count.clone();
+        move || {
+            count.fetch_add(1, Ordering::Relaxed);
+            future::ok(()).boxify()
+        }
+    }
+
+    fn run(session: BoxFuture<(), Error>) -> Result<()> {
+        Runtime::new().unwrap().block_on(session)
+    }
+
+    #[test]
+    fn finished_sessions_are_not_reaped() {
+        let session = limit_session_durations(
+            future::ok(()),
+            SessionActivity::new(),
+            Some(Duration::from_millis(10)),
+            Some(Duration::from_millis(10)),
+            Some(Duration::from_millis(10)),
+            counting_keepalive(&Arc::new(AtomicUsize::new(0))),
+        );
+        assert!(run(session).is_ok());
+    }
+
+    #[test]
+    fn idle_sessions_are_kept_alive_then_reaped() {
+        let keepalives = Arc::new(AtomicUsize::new(0));
+        let session = limit_session_durations(
+            future::empty(),
+            SessionActivity::new(),
+            Some(Duration::from_millis(300)),
+            Some(Duration::from_millis(50)),
+            None,
+            counting_keepalive(&keepalives),
+        );
+
+        match run(session).unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::SessionIdle(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(keepalives.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn sessions_handling_requests_are_not_reaped() {
+        let activity = SessionActivity::new();
+        // A request that sends nothing until it completes
+        activity.in_flight_requests().fetch_add(1, Ordering::SeqCst);
+        let request = Delay::new(Instant::now() + Duration::from_millis(300)).from_err();
+        let session = limit_session_durations(
+            request,
+            activity,
+            Some(Duration::from_millis(50)),
+            Some(Duration::from_millis(10)),
+            None,
+            counting_keepalive(&Arc::new(AtomicUsize::new(0))),
+        );
+        assert!(run(session).is_ok());
+    }
+
+    #[test]
+    fn busy_sessions_are_reaped_after_max_duration() {
+        let activity = SessionActivity::new();
+        let keepalives = Arc::new(AtomicUsize::new(0));
+        // A session that keeps sending data
+        let busy = Interval::new_interval(Duration::from_millis(10))
+            .from_err()
+            .for_each({
+                cloned!(activity);
+                move |_| {
+                    activity.touch();
+                    Ok(())
+                }
+            });
+        let session = limit_session_durations(
+            busy,
+            activity,
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(300)),
+            counting_keepalive(&keepalives),
+        );
+
+        match run(session).unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::SessionTooLong(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(keepalives.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn failed_keepalives_reap_sessions() {
+        let session = limit_session_durations(
+            future::empty(),
+            SessionActivity::new(),
+            None,
+            Some(Duration::from_millis(10)),
+            None,
+            || future::err(format_err!("client went away")).boxify(),
+        );
+        assert!(run(session).is_err());
+    }
+}
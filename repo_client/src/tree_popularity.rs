@@ -0,0 +1,224 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! How often clients ask for each directory of a repo in gettreepack. Used to send the trees
+//! clients are likely to ask for next along with the ones they asked for.
+
+use std::sync::Arc;
+
+use context::CoreContext;
+use failure::Error;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{MPath, MPathElement};
+use metaconfig_types::TreePrefetchParams;
+use mononoke_types::RepositoryId;
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+use sql_ext::SqlConstructors;
+
+pub trait TreePopularity: Send + Sync {
+    /// Record that a client asked for the tree at `path`.
+    fn record(&self, ctx: CoreContext, repo_id: RepositoryId, path: MPath) -> BoxFuture<(), Error>;
+
+    /// Returns at most `limit` names of the directories directly under `parent` (the root if
+    /// None) that clients asked for most, most popular first.
+    fn popular_children(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        parent: Option<MPath>,
+        limit: usize,
+    ) -> BoxFuture<Vec<MPathElement>, Error>;
+}
+
+/// Everything gettreepack needs to prefetch trees
+#[derive(Clone)]
+pub struct TreePrefetch {
+    pub params: TreePrefetchParams,
+    pub popularity: Arc<TreePopularity>,
+}
+
+queries! {
+    write AddCounts(values: (repo_id: RepositoryId, parent: Vec<u8>, name: Vec<u8>, count: u64)) {
+        none,
+        mysql(
+            "INSERT INTO tree_popularity (repo_id, parent, name, count) VALUES {values}
+             ON DUPLICATE KEY UPDATE count = count + VALUES(count)"
+        )
+        sqlite(
+            "INSERT INTO tree_popularity (repo_id, parent, name, count) VALUES {values}
+             ON CONFLICT (repo_id, parent, name) DO UPDATE SET count = count + excluded.count"
+        )
+    }
+
+    read SelectPopularChildren(repo_id: RepositoryId, parent: Vec<u8>, limit: usize) -> (Vec<u8>) {
+        // MySQL keys the table on the hash of the parent, as paths are too long for a key
+        mysql(
+            "SELECT name FROM tree_popularity
+             WHERE repo_id = {repo_id} AND parent_hash = UNHEX(SHA2({parent}, 256))
+             ORDER BY count DESC
+             LIMIT {limit}"
+        )
+        sqlite(
+            "SELECT name FROM tree_popularity
+             WHERE repo_id = {repo_id} AND parent = {parent}
+             ORDER BY count DESC
+             LIMIT {limit}"
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlTreePopularity {
+    write_connection: Connection,
+    read_connection: Connection,
+}
+
+impl SqlConstructors for SqlTreePopularity {
+    fn from_connections(
+        write_connection: Connection,
+        read_connection: Connection,
+        _read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-tree-popularity.sql")
+    }
+}
+
+impl SqlMigrations for SqlTreePopularity {
+    const STORE: &'static str = "tree_popularity";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the tree_popularity table",
+            sqlite: "CREATE TABLE `tree_popularity` (
+                       `repo_id` INT UNSIGNED NOT NULL,
+                       `parent` VARBINARY(4096) NOT NULL,
+                       `name` VARBINARY(255) NOT NULL,
+                       `count` BIGINT NOT NULL DEFAULT 0,
+                       PRIMARY KEY (`repo_id`, `parent`, `name`)
+                     );",
+            mysql: "CREATE TABLE `tree_popularity` (
+                      `repo_id` INT UNSIGNED NOT NULL,
+                      `parent` VARBINARY(4096) NOT NULL,
+                      `parent_hash` BINARY(32) AS (UNHEX(SHA2(`parent`, 256))) STORED,
+                      `name` VARBINARY(255) NOT NULL,
+                      `count` BIGINT NOT NULL DEFAULT 0,
+                      UNIQUE KEY `tree_popularity_tree` (`repo_id`, `parent_hash`, `name`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+fn parent_key(parent: Option<&MPath>) -> Vec<u8> {
+    parent.map(MPath::to_vec).unwrap_or_default()
+}
+
+impl TreePopularity for SqlTreePopularity {
+    fn record(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        path: MPath,
+    ) -> BoxFuture<(), Error> {
+        let (parent, name) = path.split_dirname();
+        let parent = parent_key(parent.as_ref());
+        let name = name.to_bytes();
+
+        // A single upsert, so that recording costs one write per request
+        AddCounts::query(&self.write_connection, &[(&repo_id, &parent, &name, &1)])
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn popular_children(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        parent: Option<MPath>,
+        limit: usize,
+    ) -> BoxFuture<Vec<MPathElement>, Error> {
+        SelectPopularChildren::query(
+            &self.read_connection,
+            &repo_id,
+            &parent_key(parent.as_ref()),
+            &limit,
+        )
+        .and_then(|rows| {
+            rows.into_iter()
+                .map(|(name,)| MPathElement::new(name))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(p: &str) -> MPath {
+        MPath::new(p).unwrap()
+    }
+
+    fn names(names: &[&str]) -> Vec<MPathElement> {
+        names
+            .iter()
+            .map(|name| MPathElement::new(name.as_bytes().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_popular_children() {
+        let ctx = CoreContext::test_mock();
+        let popularity = SqlTreePopularity::with_sqlite_in_memory().unwrap();
+        let repo_id = RepositoryId::new(1);
+        let other_repo_id = RepositoryId::new(2);
+
+        for p in &["a", "b", "b", "c", "c", "c", "a/x", "a/y", "a/y"] {
+            popularity
+                .record(ctx.clone(), repo_id, path(p))
+                .wait()
+                .unwrap();
+        }
+        popularity
+            .record(ctx.clone(), other_repo_id, path("a"))
+            .wait()
+            .unwrap();
+
+        let children = popularity
+            .popular_children(ctx.clone(), repo_id, None, 2)
+            .wait()
+            .unwrap();
+        assert_eq!(children, names(&["c", "b"]));
+
+        let children = popularity
+            .popular_children(ctx.clone(), repo_id, Some(path("a")), 10)
+            .wait()
+            .unwrap();
+        assert_eq!(children, names(&["y", "x"]));
+
+        let children = popularity
+            .popular_children(ctx.clone(), other_repo_id, None, 10)
+            .wait()
+            .unwrap();
+        assert_eq!(children, names(&["a"]));
+
+        let children = popularity
+            .popular_children(ctx.clone(), repo_id, Some(path("b")), 10)
+            .wait()
+            .unwrap();
+        assert_eq!(children, vec![]);
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Synthetic repos of a configurable shape, for benchmarks and load tests.
+//!
+//! Commits are generated from a seed and a `RepoShape`: how many commits, how many files each
+//! changes, how files are spread over directories, how large they are and how often history
+//! branches and merges. The same seed and shape always generate the same commits, so repos can be
+//! regenerated rather than stored. Only bonsai changesets are saved, hg changesets are derived
+//! when they're first asked for.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+
+extern crate blobrepo;
+extern crate bytes;
+#[macro_use]
+extern crate cloned;
+extern crate context;
+#[macro_use]
+extern crate futures_ext;
+extern crate mononoke_types;
+extern crate rand;
+
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::future::{self, loop_fn, Loop};
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use rand::distributions::{Alphanumeric, Distribution, LogNormal};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use blobrepo::{get_sha256_alias, save_bonsai_changesets, BlobRepo};
+use context::CoreContext;
+use mononoke_types::{
+    BlobstoreValue, BonsaiChangeset, BonsaiChangesetBuilder, ChangesetId, ContentBlob, DateTime,
+    FileChange, FileContents, FileType, MPath,
+};
+
+/// Commits saved at once
+const BATCH_SIZE: usize = 100;
+/// Contents uploaded concurrently
+const UPLOAD_CONCURRENCY: usize = 100;
+/// Author date of the first commit; each commit is a second after the previous one
+const START_TIMESTAMP: i64 = 1_500_000_000;
+/// Generated files are lines of this many bytes, newline included
+const LINE_LENGTH: usize = 80;
+
+/// The shape of a generated repo
+#[derive(Clone, Debug)]
+pub struct RepoShape {
+    /// Commits to generate
+    pub commits: usize,
+    /// Files that each commit adds or modifies. Merges only resolve the files that differ
+    /// between their parents.
+    pub files_per_commit: usize,
+    /// Probability that a file that a commit changes is an existing one rather than a new one
+    pub modify_probability: f64,
+    /// New files go to a directory until it has this many, then to the next directory
+    pub files_per_dir: usize,
+    /// Directories have at most this many subdirectories, so trees get deeper as they grow
+    pub dirs_per_dir: usize,
+    /// Median size of files in bytes. Sizes are log-normally distributed around it.
+    pub median_file_size: u64,
+    /// Spread of file sizes, as the standard deviation of their logarithm. With 0, all files
+    /// are of the median size.
+    pub file_size_sigma: f64,
+    /// Files are never larger than this many bytes
+    pub max_file_size: u64,
+    /// Probability that a commit starts a new branch rather than extending the one it's on
+    pub branch_probability: f64,
+    /// Probability that a commit merges two branches, when there are several
+    pub merge_probability: f64,
+}
+
+impl Default for RepoShape {
+    fn default() -> Self {
+        Self {
+            commits: 100,
+            files_per_commit: 5,
+            modify_probability: 0.5,
+            files_per_dir: 20,
+            dirs_per_dir: 10,
+            median_file_size: 4096,
+            file_size_sigma: 1.5,
+            max_file_size: 10 * 1024 * 1024,
+            branch_probability: 0.05,
+            merge_probability: 0.05,
+        }
+    }
+}
+
+impl RepoShape {
+    pub fn verify(&self) -> Result<()> {
+        let probabilities = vec![
+            ("modify_probability", self.modify_probability),
+            ("branch_probability", self.branch_probability),
+            ("merge_probability", self.merge_probability),
+        ];
+        for (name, probability) in probabilities {
+            if probability.is_nan() || probability < 0.0 || probability > 1.0 {
+                bail_msg!("{} must be between 0 and 1, not {}", name, probability);
+            }
+        }
+        if self.files_per_dir == 0 {
+            bail_msg!("files_per_dir must be at least 1");
+        }
+        if self.dirs_per_dir < 2 {
+            bail_msg!("dirs_per_dir must be at least 2");
+        }
+        if self.median_file_size == 0 {
+            bail_msg!("median_file_size must be at least 1");
+        }
+        if self.file_size_sigma.is_nan() || self.file_size_sigma < 0.0 {
+            bail_msg!("file_size_sigma must not be negative");
+        }
+        Ok(())
+    }
+}
+
+/// The commits of a generated repo
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GeneratedRepo {
+    /// All the generated commits, parents before children
+    pub changesets: Vec<ChangesetId>,
+    /// The commits that no other commit has as a parent
+    pub heads: Vec<ChangesetId>,
+}
+
+/// Generate commits into `repo`. The same `seed` and `shape` generate the same commits.
+pub fn generate_repo(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    shape: RepoShape,
+    seed: u64,
+) -> BoxFuture<GeneratedRepo, Error> {
+    try_boxfuture!(shape.verify());
+    let generator = Generator::new(shape, seed);
+
+    loop_fn(
+        (generator, vec![]),
+        move |(mut generator, mut changesets)| {
+            let batch = match generator.next_batch(BATCH_SIZE) {
+                Ok(batch) => batch,
+                Err(err) => return future::err(err).left_future(),
+            };
+            if batch.is_empty() {
+                let heads = generator.heads.iter().map(|(cs_id, _)| *cs_id).collect();
+                return future::ok(Loop::Break(GeneratedRepo { changesets, heads })).left_future();
+            }
+
+            let mut bonsais = Vec::with_capacity(batch.len());
+            let mut contents = vec![];
+            for (bcs, commit_contents) in batch {
+                changesets.push(bcs.get_changeset_id());
+                bonsais.push(bcs);
+                contents.extend(commit_contents);
+            }
+            info!(
+                ctx.logger(),
+                "generated {} of {} commits",
+                changesets.len(),
+                generator.shape.commits
+            );
+
+            stream::iter_ok(contents)
+                .map({
+                    cloned!(ctx, repo);
+                    move |(blob, alias)| repo.upload_blob(ctx.clone(), blob, alias)
+                })
+                .buffer_unordered(UPLOAD_CONCURRENCY)
+                .for_each(|_| Ok(()))
+                .and_then({
+                    cloned!(ctx, repo);
+                    move |()| save_bonsai_changesets(bonsais, ctx, repo)
+                })
+                .map(move |()| Loop::Continue((generator, changesets)))
+                .right_future()
+        },
+    )
+    .boxify()
+}
+
+/// The files of a commit, as the changes that set their current contents
+#[derive(Clone, Default)]
+struct Files {
+    /// In the order they were added, so that picking one at random is deterministic
+    paths: Vec<MPath>,
+    changes: HashMap<MPath, FileChange>,
+}
+
+impl Files {
+    fn set(&mut self, path: MPath, change: FileChange) {
+        if self.changes.insert(path.clone(), change).is_none() {
+            self.paths.push(path);
+        }
+    }
+}
+
+struct Generator {
+    shape: RepoShape,
+    rng: StdRng,
+    file_sizes: LogNormal,
+    /// The commits that nothing has as a parent yet, and their files
+    heads: Vec<(ChangesetId, Files)>,
+    /// Files added so far, which numbers new files
+    files: usize,
+    /// Commits generated so far
+    commits: usize,
+}
+
+impl Generator {
+    fn new(shape: RepoShape, seed: u64) -> Self {
+        let file_sizes =
+            LogNormal::new((shape.median_file_size as f64).ln(), shape.file_size_sigma);
+        Self {
+            shape,
+            rng: StdRng::seed_from_u64(seed),
+            file_sizes,
+            heads: vec![],
+            files: 0,
+            commits: 0,
+        }
+    }
+
+    /// Up to `size` more commits, with the contents they add
+    fn next_batch(
+        &mut self,
+        size: usize,
+    ) -> Result<Vec<(BonsaiChangeset, Vec<(ContentBlob, String)>)>> {
+        let mut batch = vec![];
+        while batch.len() < size && self.commits < self.shape.commits {
+            batch.push(self.next_commit()?);
+        }
+        Ok(batch)
+    }
+
+    fn next_commit(&mut self) -> Result<(BonsaiChangeset, Vec<(ContentBlob, String)>)> {
+        let mut file_changes = BTreeMap::new();
+        let mut contents = vec![];
+
+        let merge = self.heads.len() >= 2 && self.rng.gen_bool(self.shape.merge_probability);
+        let (parents, files) = if merge {
+            let (p1, mut files) = self.take_head();
+            let (p2, p2_files) = self.take_head();
+            for path in p2_files.paths {
+                let p2_change = &p2_files.changes[&path];
+                match files.changes.get(&path).cloned() {
+                    None => files.set(path, p2_change.clone()),
+                    // Resolve in favour of p1
+                    Some(p1_change) => {
+                        if p1_change != *p2_change {
+                            file_changes.insert(path, p1_change);
+                        }
+                    }
+                }
+            }
+            (vec![p1, p2], files)
+        } else {
+            let (parents, mut files) = if self.heads.is_empty() {
+                (vec![], Files::default())
+            } else if self.rng.gen_bool(self.shape.branch_probability) {
+                let index = self.rng.gen_range(0, self.heads.len());
+                let (parent, files) = self.heads[index].clone();
+                (vec![parent], files)
+            } else {
+                let (parent, files) = self.take_head();
+                (vec![parent], files)
+            };
+
+            for _ in 0..self.shape.files_per_commit {
+                let path = if !files.paths.is_empty()
+                    && self.rng.gen_bool(self.shape.modify_probability)
+                {
+                    files.paths[self.rng.gen_range(0, files.paths.len())].clone()
+                } else {
+                    self.new_path()?
+                };
+                let bytes = self.file_contents();
+                let alias = get_sha256_alias(&bytes);
+                let size = bytes.len() as u64;
+                let blob = FileContents::Bytes(bytes).into_blob();
+                let change = FileChange::new(*blob.id(), FileType::Regular, size, None);
+                contents.push((blob, alias));
+                file_changes.insert(path.clone(), change.clone());
+                files.set(path, change);
+            }
+            (parents, files)
+        };
+
+        let author_date = DateTime::from_timestamp(START_TIMESTAMP + self.commits as i64, 0)?;
+        let mut builder = BonsaiChangesetBuilder::new("repo_gen", author_date);
+        builder
+            .set_parents(parents)
+            .set_message(format!("generated commit {}", self.commits));
+        for (path, change) in file_changes {
+            builder.add_file_change(path, change);
+        }
+        let bcs = builder.freeze()?;
+
+        self.heads.push((bcs.get_changeset_id(), files));
+        self.commits += 1;
+        Ok((bcs, contents))
+    }
+
+    /// Remove a head picked at random, to be the parent of the next commit
+    fn take_head(&mut self) -> (ChangesetId, Files) {
+        let index = self.rng.gen_range(0, self.heads.len());
+        self.heads.swap_remove(index)
+    }
+
+    /// The path of the next new file. Directory `n` is at the path of the digits of `n` in base
+    /// `dirs_per_dir`, e.g. `d1/d0/d3`.
+    fn new_path(&mut self) -> Result<MPath> {
+        let file = self.files;
+        self.files += 1;
+
+        let mut dir = file / self.shape.files_per_dir;
+        let mut path = vec![];
+        loop {
+            path.push(format!("d{}", dir % self.shape.dirs_per_dir));
+            dir /= self.shape.dirs_per_dir;
+            if dir == 0 {
+                break;
+            }
+        }
+        path.reverse();
+        path.push(format!("f{}", file));
+        MPath::new(path.join("/"))
+    }
+
+    /// Contents of a new file, lines of random text of a random size
+    fn file_contents(&mut self) -> Bytes {
+        let size = self.file_sizes.sample(&mut self.rng).round() as u64;
+        let size = ::std::cmp::min(size, self.shape.max_file_size) as usize;
+        let mut contents = Vec::with_capacity(size);
+        while contents.len() < size {
+            if contents.len() % LINE_LENGTH == LINE_LENGTH - 1 {
+                contents.push(b'\n');
+            } else {
+                contents.push(self.rng.sample(Alphanumeric) as u8);
+            }
+        }
+        Bytes::from(contents)
+    }
+}
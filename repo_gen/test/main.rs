@@ -0,0 +1,102 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the generation of synthetic repos.
+
+#![deny(warnings)]
+
+extern crate blobrepo;
+extern crate blobrepo_factory;
+extern crate context;
+extern crate futures;
+extern crate repo_gen;
+extern crate tokio;
+
+use blobrepo::BlobRepo;
+use blobrepo_factory::new_memblob_empty;
+use context::CoreContext;
+use futures::{future, stream, Stream};
+use repo_gen::{generate_repo, GeneratedRepo, RepoShape};
+use tokio::runtime::Runtime;
+
+fn shape() -> RepoShape {
+    RepoShape {
+        commits: 150,
+        files_per_commit: 3,
+        files_per_dir: 4,
+        dirs_per_dir: 3,
+        median_file_size: 100,
+        max_file_size: 1000,
+        branch_probability: 0.3,
+        merge_probability: 0.3,
+        ..RepoShape::default()
+    }
+}
+
+fn generate(rt: &mut Runtime, shape: RepoShape, seed: u64) -> (BlobRepo, GeneratedRepo) {
+    let ctx = CoreContext::test_mock();
+    let repo = new_memblob_empty(None, None).expect("cannot create empty repo");
+    let generated = rt
+        .block_on(generate_repo(ctx, repo.clone(), shape, seed))
+        .expect("generating the repo failed");
+    (repo, generated)
+}
+
+#[test]
+fn test_deterministic() {
+    let mut rt = Runtime::new().unwrap();
+
+    let (_, first) = generate(&mut rt, shape(), 1);
+    let (_, again) = generate(&mut rt, shape(), 1);
+    let (_, other) = generate(&mut rt, shape(), 2);
+
+    assert_eq!(first.changesets.len(), 150);
+    assert_eq!(first, again);
+    assert_ne!(first.changesets, other.changesets);
+}
+
+#[test]
+fn test_shape() {
+    let mut rt = Runtime::new().unwrap();
+    let ctx = CoreContext::test_mock();
+
+    let (repo, generated) = generate(&mut rt, shape(), 1);
+
+    let parents = rt
+        .block_on(
+            stream::iter_ok(generated.changesets.clone())
+                .and_then({
+                    let repo = repo.clone();
+                    let ctx = ctx.clone();
+                    move |cs_id| repo.get_bonsai_changeset(ctx.clone(), cs_id)
+                })
+                .map(|bcs| bcs.parents().count())
+                .collect(),
+        )
+        .expect("fetching the changesets failed");
+    assert_eq!(parents.iter().filter(|parents| **parents == 0).count(), 1);
+    assert!(parents.iter().any(|parents| *parents == 2));
+    assert!(!generated.heads.is_empty());
+
+    // Merges resolve their conflicts, so hg changesets can be derived for all of history
+    rt.block_on(future::join_all(generated.heads.into_iter().map(
+        move |head| repo.get_hg_from_bonsai_changeset(ctx.clone(), head),
+    )))
+    .expect("deriving the hg changesets failed");
+}
+
+#[test]
+fn test_invalid_shape() {
+    let mut rt = Runtime::new().unwrap();
+    let ctx = CoreContext::test_mock();
+    let repo = new_memblob_empty(None, None).expect("cannot create empty repo");
+
+    let shape = RepoShape {
+        merge_probability: 1.5,
+        ..RepoShape::default()
+    };
+    assert!(rt.block_on(generate_repo(ctx, repo, shape, 0)).is_err());
+}
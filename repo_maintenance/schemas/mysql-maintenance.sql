@@ -0,0 +1,9 @@
+CREATE TABLE `maintenance_windows` (
+  `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `start_timestamp` BIGINT NOT NULL,
+  `end_timestamp` BIGINT NOT NULL,
+  `message` TEXT NOT NULL,
+  PRIMARY KEY (`id`),
+  KEY `maintenance_windows_repo_end` (`repo_id`, `end_timestamp`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,9 @@
+CREATE TABLE `maintenance_windows` (
+  `id` INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  `repo_id` INT UNSIGNED NOT NULL,
+  `start_timestamp` BIGINT NOT NULL,
+  `end_timestamp` BIGINT NOT NULL,
+  `message` TEXT NOT NULL
+);
+
+CREATE INDEX `maintenance_windows_repo_end` ON `maintenance_windows` (`repo_id`, `end_timestamp`);
@@ -0,0 +1,215 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Scheduled maintenance windows for repos.
+//!
+//! While a window is active the repo keeps serving reads, but pushes are refused with the
+//! window's message and the time writes are expected to come back. Windows are stored in SQL, so
+//! that operators can schedule one for all servers of a repo at once.
+
+#![deny(warnings)]
+
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate context;
+extern crate futures_ext;
+extern crate mononoke_types;
+#[macro_use]
+extern crate sql;
+extern crate sql_ext;
+#[macro_use]
+extern crate stats;
+
+use context::CoreContext;
+use failure::Error;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use mononoke_types::{DateTime, RepositoryId, Timestamp};
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+pub use sql_ext::SqlConstructors;
+use stats::Timeseries;
+use std::sync::Arc;
+
+define_stats! {
+    prefix = "mononoke.repo_maintenance";
+    adds: timeseries(RATE, SUM),
+    gets: timeseries(RATE, SUM),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MaintenanceWindow {
+    pub repo_id: RepositoryId,
+    pub start: DateTime,
+    pub end: DateTime,
+    /// Text shown to users whose writes are refused
+    pub message: String,
+}
+
+impl MaintenanceWindow {
+    pub fn new(repo_id: RepositoryId, start: DateTime, end: DateTime, message: String) -> Self {
+        Self {
+            repo_id,
+            start,
+            end,
+            message,
+        }
+    }
+
+    /// The message for clients, including when writes are expected to resume.
+    pub fn client_message(&self) -> String {
+        format!(
+            "{} (scheduled maintenance, writes expected to resume at {})",
+            self.message,
+            self.end.as_chrono().to_rfc3339(),
+        )
+    }
+}
+
+pub trait MaintenanceStore: Send + Sync {
+    fn add(&self, ctx: CoreContext, window: MaintenanceWindow) -> BoxFuture<(), Error>;
+
+    /// Returns the window of the repo that covers `now`. If windows overlap, the one that ends
+    /// last is returned, so that clients get the latest ETA.
+    fn get_active(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        now: DateTime,
+    ) -> BoxFuture<Option<MaintenanceWindow>, Error>;
+}
+
+impl MaintenanceStore for Arc<MaintenanceStore> {
+    fn add(&self, ctx: CoreContext, window: MaintenanceWindow) -> BoxFuture<(), Error> {
+        (**self).add(ctx, window)
+    }
+
+    fn get_active(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        now: DateTime,
+    ) -> BoxFuture<Option<MaintenanceWindow>, Error> {
+        (**self).get_active(ctx, repo_id, now)
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlMaintenanceStore {
+    write_connection: Connection,
+    read_connection: Connection,
+}
+
+queries! {
+    write InsertWindow(values: (
+        repo_id: RepositoryId,
+        start_timestamp: Timestamp,
+        end_timestamp: Timestamp,
+        message: str,
+    )) {
+        none,
+        "INSERT INTO maintenance_windows (repo_id, start_timestamp, end_timestamp, message)
+         VALUES {values}"
+    }
+
+    read GetActiveWindow(repo_id: RepositoryId, now: Timestamp) -> (
+        Timestamp,
+        Timestamp,
+        String,
+    ) {
+        "SELECT start_timestamp, end_timestamp, message
+         FROM maintenance_windows
+         WHERE repo_id = {repo_id}
+           AND start_timestamp <= {now}
+           AND end_timestamp > {now}
+         ORDER BY end_timestamp DESC
+         LIMIT 1"
+    }
+}
+
+impl SqlConstructors for SqlMaintenanceStore {
+    fn from_connections(
+        write_connection: Connection,
+        read_connection: Connection,
+        _read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-maintenance.sql")
+    }
+}
+
+impl SqlMigrations for SqlMaintenanceStore {
+    const STORE: &'static str = "maintenance";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the maintenance_windows table",
+            sqlite: "CREATE TABLE `maintenance_windows` (
+                       `id` INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                       `repo_id` INT UNSIGNED NOT NULL,
+                       `start_timestamp` BIGINT NOT NULL,
+                       `end_timestamp` BIGINT NOT NULL,
+                       `message` TEXT NOT NULL
+                     );
+
+                     CREATE INDEX `maintenance_windows_repo_end` ON `maintenance_windows` (`repo_id`, `end_timestamp`);",
+            mysql: "CREATE TABLE `maintenance_windows` (
+                      `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+                      `repo_id` INT UNSIGNED NOT NULL,
+                      `start_timestamp` BIGINT NOT NULL,
+                      `end_timestamp` BIGINT NOT NULL,
+                      `message` TEXT NOT NULL,
+                      PRIMARY KEY (`id`),
+                      KEY `maintenance_windows_repo_end` (`repo_id`, `end_timestamp`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+impl MaintenanceStore for SqlMaintenanceStore {
+    fn add(&self, _ctx: CoreContext, window: MaintenanceWindow) -> BoxFuture<(), Error> {
+        STATS::adds.add_value(1);
+
+        let start = Timestamp::from(window.start);
+        let end = Timestamp::from(window.end);
+        InsertWindow::query(
+            &self.write_connection,
+            &[(&window.repo_id, &start, &end, window.message.as_str())],
+        )
+        .map(|_| ())
+        .boxify()
+    }
+
+    fn get_active(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        now: DateTime,
+    ) -> BoxFuture<Option<MaintenanceWindow>, Error> {
+        STATS::gets.add_value(1);
+
+        GetActiveWindow::query(&self.read_connection, &repo_id, &Timestamp::from(now))
+            .map(move |rows| {
+                rows.into_iter()
+                    .next()
+                    .map(|(start, end, message)| MaintenanceWindow {
+                        repo_id,
+                        start: start.into(),
+                        end: end.into(),
+                        message,
+                    })
+            })
+            .boxify()
+    }
+}
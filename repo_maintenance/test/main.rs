@@ -0,0 +1,63 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the maintenance window store.
+
+#![deny(warnings)]
+
+extern crate context;
+extern crate futures;
+extern crate mononoke_types;
+extern crate repo_maintenance;
+extern crate tokio;
+
+use context::CoreContext;
+use mononoke_types::{DateTime, RepositoryId};
+use repo_maintenance::{
+    MaintenanceStore, MaintenanceWindow, SqlConstructors, SqlMaintenanceStore,
+};
+
+#[test]
+fn test_active_window() {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    let ctx = CoreContext::test_mock();
+    let store = SqlMaintenanceStore::with_sqlite_in_memory().unwrap();
+    let repo_id = RepositoryId::new(137);
+    let other_repo_id = RepositoryId::new(138);
+
+    let t0 = DateTime::from_rfc3339("2019-03-01T12:00:00.00Z").unwrap();
+    let t1 = DateTime::from_rfc3339("2019-03-01T13:00:00.00Z").unwrap();
+    let t2 = DateTime::from_rfc3339("2019-03-01T14:00:00.00Z").unwrap();
+    let t3 = DateTime::from_rfc3339("2019-03-01T15:00:00.00Z").unwrap();
+
+    let short = MaintenanceWindow::new(repo_id, t0, t2, "short".to_string());
+    let long = MaintenanceWindow::new(repo_id, t1, t3, "long".to_string());
+    let other = MaintenanceWindow::new(other_repo_id, t0, t3, "other".to_string());
+    for window in vec![short.clone(), long.clone(), other] {
+        rt.block_on(store.add(ctx.clone(), window))
+            .expect("Adding window failed");
+    }
+
+    let active = |rt: &mut tokio::runtime::Runtime, now: &str| {
+        rt.block_on(store.get_active(
+            ctx.clone(),
+            repo_id,
+            DateTime::from_rfc3339(now).unwrap(),
+        ))
+        .expect("Getting active window failed")
+    };
+
+    // no window has started yet
+    assert_eq!(active(&mut rt, "2019-03-01T11:59:00.00Z"), None);
+    // start is inclusive
+    assert_eq!(active(&mut rt, "2019-03-01T12:00:00.00Z"), Some(short));
+    // overlapping windows return the one ending last
+    assert_eq!(active(&mut rt, "2019-03-01T13:30:00.00Z"), Some(long.clone()));
+    assert_eq!(active(&mut rt, "2019-03-01T14:30:00.00Z"), Some(long));
+    // end is exclusive
+    assert_eq!(active(&mut rt, "2019-03-01T15:00:00.00Z"), None);
+}
@@ -0,0 +1,6 @@
+CREATE TABLE `repo_config_overrides` (
+  `repo_id` INT UNSIGNED NOT NULL,
+  `name` VARCHAR(255) NOT NULL,
+  `value` TEXT NOT NULL,
+  PRIMARY KEY (`repo_id`, `name`)
+);
@@ -0,0 +1,326 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Overrides of the tunables of repos, that take effect without restarting servers.
+//!
+//! Tunables such as the share of hashes to validate or buffer sizes are read from the repo
+//! config when servers start. An override stored in SQL replaces the value of one tunable of one
+//! repo: servers poll the overrides of their repos, log the ones that changed, and read the
+//! latest ones they polled whenever they need a tunable. Overrides are stored as strings and
+//! parsed by whoever reads them; an override that doesn't parse is ignored.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate cloned;
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate tokio;
+
+extern crate context;
+extern crate futures_ext;
+extern crate mononoke_types;
+#[macro_use]
+extern crate sql;
+extern crate sql_ext;
+#[macro_use]
+extern crate stats;
+
+use context::CoreContext;
+use failure::Error;
+use futures::{Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use mononoke_types::RepositoryId;
+use slog::Logger;
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+pub use sql_ext::SqlConstructors;
+use stats::Timeseries;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+
+define_stats! {
+    prefix = "mononoke.repo_overrides";
+    sets: timeseries(RATE, SUM),
+    polls: timeseries(RATE, SUM),
+    poll_errors: timeseries(RATE, SUM),
+    changes: timeseries(RATE, SUM),
+}
+
+pub trait OverridesStore: Send + Sync {
+    /// Overrides tunable `name` of the repo with `value`, replacing its previous override
+    fn set(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        name: String,
+        value: String,
+    ) -> BoxFuture<(), Error>;
+
+    /// Removes the override of tunable `name` of the repo, if any
+    fn remove(&self, ctx: CoreContext, repo_id: RepositoryId, name: String)
+        -> BoxFuture<(), Error>;
+
+    /// All the overrides of the repo, by tunable name
+    fn get_all(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<BTreeMap<String, String>, Error>;
+}
+
+impl OverridesStore for Arc<OverridesStore> {
+    fn set(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        name: String,
+        value: String,
+    ) -> BoxFuture<(), Error> {
+        (**self).set(ctx, repo_id, name, value)
+    }
+
+    fn remove(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        name: String,
+    ) -> BoxFuture<(), Error> {
+        (**self).remove(ctx, repo_id, name)
+    }
+
+    fn get_all(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<BTreeMap<String, String>, Error> {
+        (**self).get_all(ctx, repo_id)
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlOverridesStore {
+    write_connection: Connection,
+    read_connection: Connection,
+}
+
+queries! {
+    write ReplaceOverrides(values: (repo_id: RepositoryId, name: str, value: str)) {
+        none,
+        "REPLACE INTO repo_config_overrides (repo_id, name, value) VALUES {values}"
+    }
+
+    write DeleteOverride(repo_id: RepositoryId, name: str) {
+        none,
+        "DELETE FROM repo_config_overrides
+         WHERE repo_id = {repo_id} AND name = {name}"
+    }
+
+    read SelectOverrides(repo_id: RepositoryId) -> (String, String) {
+        "SELECT name, value
+         FROM repo_config_overrides
+         WHERE repo_id = {repo_id}"
+    }
+}
+
+impl SqlConstructors for SqlOverridesStore {
+    fn from_connections(
+        write_connection: Connection,
+        read_connection: Connection,
+        _read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-repo-overrides.sql")
+    }
+}
+
+impl SqlMigrations for SqlOverridesStore {
+    const STORE: &'static str = "repo_overrides";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the repo_config_overrides table",
+            sqlite: "CREATE TABLE `repo_config_overrides` (
+                       `repo_id` INT UNSIGNED NOT NULL,
+                       `name` VARCHAR(255) NOT NULL,
+                       `value` TEXT NOT NULL,
+                       PRIMARY KEY (`repo_id`, `name`)
+                     );",
+            mysql: "CREATE TABLE `repo_config_overrides` (
+                      `repo_id` INT UNSIGNED NOT NULL,
+                      `name` VARCHAR(255) NOT NULL,
+                      `value` TEXT NOT NULL,
+                      PRIMARY KEY (`repo_id`, `name`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+impl OverridesStore for SqlOverridesStore {
+    fn set(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        name: String,
+        value: String,
+    ) -> BoxFuture<(), Error> {
+        STATS::sets.add_value(1);
+
+        ReplaceOverrides::query(
+            &self.write_connection,
+            &[(&repo_id, name.as_str(), value.as_str())],
+        )
+        .map(|_| ())
+        .boxify()
+    }
+
+    fn remove(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+        name: String,
+    ) -> BoxFuture<(), Error> {
+        STATS::sets.add_value(1);
+
+        DeleteOverride::query(&self.write_connection, &repo_id, name.as_str())
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn get_all(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<BTreeMap<String, String>, Error> {
+        SelectOverrides::query(&self.read_connection, &repo_id)
+            .map(|rows| rows.into_iter().collect())
+            .boxify()
+    }
+}
+
+/// The overrides of one repo as of the last time they were polled, for the code that needs a
+/// tunable to read it without waiting for the store. Clones share the same overrides.
+#[derive(Clone)]
+pub struct RepoOverrides {
+    repo_id: RepositoryId,
+    store: Arc<OverridesStore>,
+    values: Arc<RwLock<BTreeMap<String, String>>>,
+    logger: Logger,
+}
+
+impl RepoOverrides {
+    /// Overrides of `repo_id` in `store`, none until they are first polled
+    pub fn new(repo_id: RepositoryId, store: Arc<OverridesStore>, logger: Logger) -> Self {
+        Self {
+            repo_id,
+            store,
+            values: Arc::new(RwLock::new(BTreeMap::new())),
+            logger,
+        }
+    }
+
+    /// The override of tunable `name`, if it's set and parses as a `T`
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        let values = self.values.read().expect("lock poisoned");
+        values.get(name).and_then(|value| value.parse().ok())
+    }
+
+    /// The override of tunable `name`, or `default` if it's not overridden
+    pub fn get_or<T: FromStr>(&self, name: &str, default: T) -> T {
+        self.get(name).unwrap_or(default)
+    }
+
+    /// All the overrides, by tunable name, as they were last polled
+    pub fn get_all(&self) -> BTreeMap<String, String> {
+        self.values.read().expect("lock poisoned").clone()
+    }
+
+    /// Polls the overrides from the store, and logs the ones that were set, changed or removed
+    /// since the previous poll.
+    pub fn refresh(&self, ctx: CoreContext) -> BoxFuture<(), Error> {
+        STATS::polls.add_value(1);
+
+        cloned!(self.values, self.logger);
+        self.store
+            .get_all(ctx, self.repo_id)
+            .map(move |new_values| {
+                let mut values = values.write().expect("lock poisoned");
+                for (name, old, new) in changes(&values, &new_values) {
+                    STATS::changes.add_value(1);
+                    info!(
+                        logger,
+                        "override of {} changed from {} to {}",
+                        name,
+                        old.unwrap_or("(none)"),
+                        new.unwrap_or("(none)")
+                    );
+                }
+                *values = new_values;
+            })
+            .boxify()
+    }
+
+    /// Polls the overrides every `interval`, for as long as the server runs. Failed polls are
+    /// logged and keep the overrides that were polled before.
+    pub fn refresh_periodically(
+        self,
+        ctx: CoreContext,
+        interval: Duration,
+    ) -> impl Future<Item = (), Error = ()> {
+        let logger = self.logger.clone();
+        Interval::new(Instant::now() + interval, interval)
+            .map_err({
+                cloned!(logger);
+                move |err| error!(logger, "overrides poll timer failed: {}", err)
+            })
+            .for_each(move |_| {
+                self.refresh(ctx.clone()).then({
+                    cloned!(logger);
+                    move |result| {
+                        if let Err(err) = result {
+                            STATS::poll_errors.add_value(1);
+                            warn!(logger, "failed to poll overrides: {:?}", err);
+                        }
+                        Ok(())
+                    }
+                })
+            })
+    }
+}
+
+/// The tunables whose override differs between `old` and `new`, with both overrides
+fn changes<'a>(
+    old: &'a BTreeMap<String, String>,
+    new: &'a BTreeMap<String, String>,
+) -> Vec<(&'a str, Option<&'a str>, Option<&'a str>)> {
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old = old.get(name).map(String::as_str);
+            let new = new.get(name).map(String::as_str);
+            if old != new {
+                Some((name.as_str(), old, new))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
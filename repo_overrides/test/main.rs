@@ -0,0 +1,99 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the repo config overrides store.
+
+#![deny(warnings)]
+
+extern crate context;
+extern crate mononoke_types;
+extern crate repo_overrides;
+#[macro_use]
+extern crate slog;
+extern crate tokio;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use context::CoreContext;
+use mononoke_types::RepositoryId;
+use repo_overrides::{OverridesStore, RepoOverrides, SqlConstructors, SqlOverridesStore};
+use slog::{Discard, Drain, Logger};
+
+#[test]
+fn test_set_and_remove() {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    let ctx = CoreContext::test_mock();
+    let store = SqlOverridesStore::with_sqlite_in_memory().unwrap();
+    let repo_id = RepositoryId::new(137);
+    let other_repo_id = RepositoryId::new(138);
+
+    let set = |rt: &mut tokio::runtime::Runtime, repo_id, name: &str, value: &str| {
+        rt.block_on(store.set(ctx.clone(), repo_id, name.to_string(), value.to_string()))
+            .expect("Setting override failed")
+    };
+    set(&mut rt, repo_id, "hash_validation_percentage", "10");
+    set(&mut rt, repo_id, "getfiles_buffer_size", "50");
+    set(&mut rt, other_repo_id, "hash_validation_percentage", "20");
+    // a new value replaces the previous one
+    set(&mut rt, repo_id, "hash_validation_percentage", "30");
+
+    let mut expected = BTreeMap::new();
+    expected.insert("getfiles_buffer_size".to_string(), "50".to_string());
+    expected.insert("hash_validation_percentage".to_string(), "30".to_string());
+    assert_eq!(
+        rt.block_on(store.get_all(ctx.clone(), repo_id)).unwrap(),
+        expected
+    );
+
+    rt.block_on(store.remove(ctx.clone(), repo_id, "getfiles_buffer_size".to_string()))
+        .expect("Removing override failed");
+    expected.remove("getfiles_buffer_size");
+    assert_eq!(
+        rt.block_on(store.get_all(ctx.clone(), repo_id)).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn test_repo_overrides() {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    let ctx = CoreContext::test_mock();
+    let store = Arc::new(SqlOverridesStore::with_sqlite_in_memory().unwrap());
+    let repo_id = RepositoryId::new(137);
+    let logger = Logger::root(Discard {}.ignore_res(), o!());
+    let overrides = RepoOverrides::new(repo_id, store.clone(), logger);
+
+    rt.block_on(store.set(ctx.clone(), repo_id, "size".to_string(), "50".to_string()))
+        .unwrap();
+    rt.block_on(store.set(
+        ctx.clone(),
+        repo_id,
+        "toggle".to_string(),
+        "true".to_string(),
+    ))
+    .unwrap();
+
+    // nothing is overridden until the overrides are polled
+    assert_eq!(overrides.get::<usize>("size"), None);
+    assert_eq!(overrides.get_or("size", 100usize), 100);
+
+    rt.block_on(overrides.refresh(ctx.clone())).unwrap();
+    assert_eq!(overrides.get::<usize>("size"), Some(50));
+    assert!(overrides.get_or("toggle", false));
+    // overrides that don't parse are ignored
+    assert_eq!(overrides.get_or("toggle", 100usize), 100);
+    assert_eq!(overrides.get_or("unset", 100usize), 100);
+
+    rt.block_on(store.remove(ctx.clone(), repo_id, "size".to_string()))
+        .unwrap();
+    // clones share the overrides that are polled
+    rt.block_on(overrides.clone().refresh(ctx.clone())).unwrap();
+    assert_eq!(overrides.get::<usize>("size"), None);
+    assert_eq!(overrides.get_all().len(), 1);
+}
@@ -0,0 +1,314 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Logging of the requests that a server handles. A request is logged to a set of sinks when it
+//! starts, and again when its response is over, with the stats of the response and a snapshot
+//! of the perf counters of its context.
+//!
+//! ```ignore
+//! let logger = sinks.request(&ctx, "getbundle").args(args).start();
+//! response.log_request(logger)
+//! ```
+
+#![deny(warnings)]
+
+mod sinks;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use futures_stats::{FutureStats, StreamStats, Timed, TimedStreamTrait};
+use serde_json::{Map, Value};
+
+use context::CoreContext;
+
+pub use crate::sinks::{CaptureSink, CapturedRequest, RequestStage, ScribeSink, ScubaSink};
+
+/// The stats of the response to a request
+#[derive(Clone, Copy)]
+pub enum ResponseStats<'a> {
+    Future(&'a FutureStats),
+    Stream(&'a StreamStats),
+}
+
+impl<'a> ResponseStats<'a> {
+    pub fn completion_time(&self) -> Duration {
+        match self {
+            ResponseStats::Future(stats) => stats.completion_time,
+            ResponseStats::Stream(stats) => stats.completion_time,
+        }
+    }
+}
+
+/// A request, as its sinks see it
+#[derive(Clone, Debug)]
+pub struct RequestLog {
+    command: &'static str,
+    args: Option<Value>,
+    extra_context: Map<String, Value>,
+    response_bytes: Arc<AtomicUsize>,
+}
+
+impl RequestLog {
+    pub fn command(&self) -> &'static str {
+        self.command
+    }
+
+    pub fn args(&self) -> Option<&Value> {
+        self.args.as_ref()
+    }
+
+    /// Perf counters and values that the command added about the request. Only set once the
+    /// request is over.
+    pub fn extra_context(&self) -> &Map<String, Value> {
+        &self.extra_context
+    }
+
+    /// Bytes of the response that were counted with `RequestLogger::count_response_bytes`
+    pub fn response_bytes(&self) -> u64 {
+        self.response_bytes.load(Ordering::Relaxed) as u64
+    }
+}
+
+/// Where requests are logged to
+pub trait RequestSink: Send + Sync {
+    /// The request was received
+    fn request_started(&self, _ctx: &CoreContext, _request: &RequestLog) {}
+
+    /// The response to the request is over, whether it succeeded or not
+    fn request_finished(&self, ctx: &CoreContext, request: &RequestLog, stats: ResponseStats);
+}
+
+/// The sinks that the requests of a server are logged to
+#[derive(Clone, Default)]
+pub struct RequestSinks {
+    sinks: Vec<Arc<dyn RequestSink>>,
+}
+
+impl RequestSinks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sink(mut self, sink: Arc<dyn RequestSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Start building the logger of a request of `command`
+    pub fn request(&self, ctx: &CoreContext, command: &'static str) -> RequestLoggerBuilder {
+        RequestLoggerBuilder {
+            ctx: ctx.clone(),
+            sinks: self.clone(),
+            command,
+            args: None,
+        }
+    }
+}
+
+pub struct RequestLoggerBuilder {
+    ctx: CoreContext,
+    sinks: RequestSinks,
+    command: &'static str,
+    args: Option<Value>,
+}
+
+impl RequestLoggerBuilder {
+    /// The arguments of the request. Commands whose arguments are streamed can set them when
+    /// the request is over instead, with `RequestLogger::set_args`.
+    pub fn args(mut self, args: Value) -> Self {
+        self.args = Some(args);
+        self
+    }
+
+    /// Log that the request started
+    pub fn start(self) -> RequestLogger {
+        let logger = RequestLogger {
+            ctx: self.ctx,
+            sinks: self.sinks,
+            log: RequestLog {
+                command: self.command,
+                args: self.args,
+                extra_context: Map::new(),
+                response_bytes: Arc::new(AtomicUsize::new(0)),
+            },
+        };
+        for sink in &logger.sinks.sinks {
+            sink.request_started(&logger.ctx, &logger.log);
+        }
+        logger
+    }
+}
+
+/// The logger of a request that started
+pub struct RequestLogger {
+    ctx: CoreContext,
+    sinks: RequestSinks,
+    log: RequestLog,
+}
+
+impl RequestLogger {
+    pub fn set_args(&mut self, args: Value) {
+        self.log.args = Some(args);
+    }
+
+    /// Add `value` to the extra context of the request, next to the perf counters
+    pub fn add_extra_context<V: Into<Value>>(&mut self, key: &str, value: V) {
+        self.log.extra_context.insert(key.to_string(), value.into());
+    }
+
+    /// Counts the bytes of a response
+    pub fn count_response_bytes(&self) -> impl Fn(&Bytes) + Send + 'static {
+        let response_bytes = self.log.response_bytes.clone();
+        move |bytes| {
+            response_bytes.fetch_add(bytes.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Log that the response is over. The perf counters of the context are added to the extra
+    /// context, unless the command added values of the same names.
+    pub fn finish(&mut self, stats: ResponseStats) {
+        if let Ok(Value::Object(counters)) = serde_json::to_value(self.ctx.perf_counters()) {
+            for (name, value) in counters {
+                self.log.extra_context.entry(name).or_insert(value);
+            }
+        }
+        for sink in &self.sinks.sinks {
+            sink.request_finished(&self.ctx, &self.log, stats);
+        }
+    }
+}
+
+pub trait FutureLoggingExt: Future + Sized {
+    /// Log the request that this is the response to once it's over
+    fn log_request(self, mut logger: RequestLogger) -> BoxFuture<Self::Item, Self::Error>
+    where
+        Self: Send + 'static,
+        Self::Item: Send + 'static,
+        Self::Error: Send + 'static,
+    {
+        self.timed(move |stats, _| {
+            logger.finish(ResponseStats::Future(&stats));
+            Ok(())
+        })
+        .boxify()
+    }
+}
+
+impl<F: Future> FutureLoggingExt for F {}
+
+pub trait StreamLoggingExt: Stream + Sized {
+    /// Log the request that this is the response to once it's over
+    fn log_request(self, mut logger: RequestLogger) -> BoxStream<Self::Item, Self::Error>
+    where
+        Self: Send + 'static,
+        Self::Item: Send + 'static,
+        Self::Error: Send + 'static,
+    {
+        self.timed(move |stats, _| {
+            logger.finish(ResponseStats::Stream(&stats));
+            Ok(())
+        })
+        .boxify()
+    }
+}
+
+impl<S: Stream> StreamLoggingExt for S {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use chrono::DateTime;
+    use futures::{future, stream};
+    use scuba_ext::ScubaSampleBuilder;
+    use serde_json::json;
+    use tokio::runtime::Runtime;
+
+    use context::MockTimeUuidProvider;
+
+    fn sinks() -> (RequestSinks, Arc<CaptureSink>) {
+        let capture = Arc::new(CaptureSink::new());
+        (RequestSinks::new().with_sink(capture.clone()), capture)
+    }
+
+    #[test]
+    fn test_future_request() {
+        let (sinks, capture) = sinks();
+        let ctx = CoreContext::test_mock();
+        ctx.perf_counters().set_counter("blobs_fetched", 3);
+
+        let logger = sinks
+            .request(&ctx, "heads")
+            .args(json!({"verbose": true}))
+            .start();
+        let requests = capture.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].stage, RequestStage::Started);
+        assert_eq!(requests[0].command, "heads");
+        assert_eq!(requests[0].args, Some(json!({"verbose": true})));
+
+        let response = future::ok::<_, ()>(42).log_request(logger);
+        assert_eq!(Runtime::new().unwrap().block_on(response), Ok(42));
+        let requests = capture.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].stage, RequestStage::Finished);
+        assert_eq!(requests[1].extra_context["blobs_fetched"], json!(3));
+    }
+
+    #[test]
+    fn test_stream_request() {
+        let (sinks, capture) = sinks();
+        let ctx = CoreContext::test_mock();
+        ctx.perf_counters().set_counter("num_known", 1);
+
+        let mut logger = sinks.request(&ctx, "getfiles").start();
+        let count = logger.count_response_bytes();
+        logger.set_args(json!(["file"]));
+        // Values of the command win over perf counters
+        logger.add_extra_context("num_known", 2);
+
+        let response = stream::iter_ok::<_, ()>(vec![Bytes::from("ab"), Bytes::from("cde")])
+            .inspect(count)
+            .log_request(logger);
+        let res = Runtime::new().unwrap().block_on(response.collect());
+        assert_eq!(res.unwrap().len(), 2);
+
+        let requests = capture.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].args, None);
+        let finished = &requests[1];
+        assert_eq!(finished.stage, RequestStage::Finished);
+        assert_eq!(finished.args, Some(json!(["file"])));
+        assert_eq!(finished.response_bytes, 5);
+        assert_eq!(finished.streamed, true);
+        assert_eq!(finished.extra_context["num_known"], json!(2));
+    }
+
+    #[test]
+    fn test_scuba_sample_frozen() {
+        let now = DateTime::parse_from_rfc3339("2019-03-01T12:00:00+01:00").unwrap();
+        let ctx = CoreContext::test_mock()
+            .with_scuba_initialization(|_| ScubaSampleBuilder::with_discard())
+            .with_time_uuid_provider(Arc::new(MockTimeUuidProvider::new(now)));
+
+        let mut logger = sinks().0.request(&ctx, "known").start();
+        logger.set_args(json!(["abc"]));
+        let sample = ScubaSink::sample(&ctx, &logger.log)
+            .get_sample()
+            .to_json()
+            .unwrap();
+        assert_eq!(sample["int"]["time"], json!(now.timestamp()));
+        assert_eq!(sample["normal"]["command"], json!("known"));
+        assert_eq!(sample["normal"]["command_args"], json!("[\"abc\"]"));
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Mutex;
+
+use scribe::ScribeClient;
+use scuba_ext::{ScribeClientImplementation, ScubaSampleBuilder, ScubaSampleBuilderExt};
+use serde_json::{Map, Value};
+use time_ext::DurationExt;
+
+use context::CoreContext;
+
+use crate::{RequestLog, RequestSink, ResponseStats};
+
+/// Scuba does not support columns that are too long
+const MAX_SCUBA_FIELD_LEN: usize = 1000;
+
+fn add_trimmed(scuba: &mut ScubaSampleBuilder, key: &str, value: String) {
+    let mut limit = ::std::cmp::min(value.len(), MAX_SCUBA_FIELD_LEN);
+    while !value.is_char_boundary(limit) {
+        limit -= 1;
+    }
+    scuba.add(key, &value[..limit]);
+}
+
+/// Logs requests to the scuba table of their context, as "Start processing" and
+/// "Command processed" samples. Samples are timed by the clock of the context, so that tests can
+/// assert on them.
+pub struct ScubaSink;
+
+impl ScubaSink {
+    pub(crate) fn sample(ctx: &CoreContext, request: &RequestLog) -> ScubaSampleBuilder {
+        let mut scuba = ctx.scuba().clone();
+        scuba.add("time", ctx.now().timestamp());
+        scuba.add("command", request.command());
+        if let Some(args) = request.args() {
+            add_trimmed(&mut scuba, "command_args", args.to_string());
+        }
+        scuba
+    }
+}
+
+impl RequestSink for ScubaSink {
+    fn request_started(&self, ctx: &CoreContext, request: &RequestLog) {
+        Self::sample(ctx, request).log_with_msg("Start processing", None);
+    }
+
+    fn request_finished(&self, ctx: &CoreContext, request: &RequestLog, stats: ResponseStats) {
+        let mut scuba = Self::sample(ctx, request);
+        if !request.extra_context().is_empty() {
+            let extra_context = Value::Object(request.extra_context().clone()).to_string();
+            add_trimmed(&mut scuba, "extra_context", extra_context);
+        }
+        match stats {
+            ResponseStats::Future(stats) => scuba.add_future_stats(stats),
+            ResponseStats::Stream(stats) => scuba.add_stream_stats(stats),
+        };
+        scuba.log_with_msg("Command processed", None);
+    }
+}
+
+/// Writes requests with streamed responses to the wireproto scribe category of their context,
+/// if it has one, so that they can be replayed on shadow tiers. Scribe logging should be
+/// disabled on shadow tiers.
+pub struct ScribeSink {
+    reponame: String,
+    client: ScribeClientImplementation,
+}
+
+impl ScribeSink {
+    pub fn new(reponame: String) -> Self {
+        Self {
+            reponame,
+            client: ScribeClientImplementation::new(),
+        }
+    }
+}
+
+impl RequestSink for ScribeSink {
+    fn request_finished(&self, ctx: &CoreContext, request: &RequestLog, stats: ResponseStats) {
+        let category = match ctx.wireproto_scribe_category() {
+            Some(category) => category,
+            None => return,
+        };
+        if let ResponseStats::Future(_) = stats {
+            return;
+        }
+
+        let mut builder = ScubaSampleBuilder::with_discard();
+        builder.add_common_server_data();
+        match request.args() {
+            Some(args) => builder.add("args", args.to_string()),
+            None => builder.add("args", ""),
+        };
+        builder.add("command", request.command());
+        builder.add("duration", stats.completion_time().as_millis_unchecked());
+        builder.add("source_control_server_type", "mononoke");
+        builder.add("mononoke_session_uuid", ctx.session().to_string());
+        builder.add("reponame", self.reponame.as_str());
+
+        // We can't really do anything with the errors, so let's ignore it
+        if let Ok(sample_json) = builder.get_sample().to_json() {
+            let _ = self.client.offer(category, &sample_json.to_string());
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RequestStage {
+    Started,
+    Finished,
+}
+
+/// A request that a `CaptureSink` saw
+#[derive(Clone, Debug)]
+pub struct CapturedRequest {
+    pub stage: RequestStage,
+    pub command: &'static str,
+    pub args: Option<Value>,
+    pub extra_context: Map<String, Value>,
+    pub response_bytes: u64,
+    /// Whether the response was a stream. Only set once the request is over.
+    pub streamed: bool,
+}
+
+/// Keeps the requests it sees in memory, for tests
+#[derive(Default)]
+pub struct CaptureSink {
+    requests: Mutex<Vec<CapturedRequest>>,
+}
+
+impl CaptureSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The requests seen so far, once when they started and once when they were over
+    pub fn requests(&self) -> Vec<CapturedRequest> {
+        self.requests.lock().expect("lock poisoned").clone()
+    }
+
+    fn capture(&self, stage: RequestStage, request: &RequestLog, streamed: bool) {
+        self.requests
+            .lock()
+            .expect("lock poisoned")
+            .push(CapturedRequest {
+                stage,
+                command: request.command(),
+                args: request.args().cloned(),
+                extra_context: request.extra_context().clone(),
+                response_bytes: request.response_bytes(),
+                streamed,
+            });
+    }
+}
+
+impl RequestSink for CaptureSink {
+    fn request_started(&self, _ctx: &CoreContext, request: &RequestLog) {
+        self.capture(RequestStage::Started, request, false);
+    }
+
+    fn request_finished(&self, _ctx: &CoreContext, request: &RequestLog, stats: ResponseStats) {
+        let streamed = match stats {
+            ResponseStats::Future(_) => false,
+            ResponseStats::Stream(_) => true,
+        };
+        self.capture(RequestStage::Finished, request, streamed);
+    }
+}
@@ -8,20 +8,23 @@
 // Have a Vec of current generation nodes - as they're output, push their parents onto the next
 // generation Vec. Once current generation Vec is empty, rotate.
 
+use std::cmp;
 use std::collections::hash_set::IntoIter;
 use std::collections::{BTreeMap, HashSet};
+use std::iter::{self, FromIterator};
 use std::sync::Arc;
 
 use failure::prelude::*;
 
-use futures::future::Future;
+use futures::future::{loop_fn, ok, Future, Loop};
 use futures::stream::{iter_ok, Stream};
 use futures::{Async, Poll};
-use futures_ext::StreamExt;
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
 
 use changeset_fetcher::ChangesetFetcher;
 use context::CoreContext;
 use mononoke_types::{ChangesetId, Generation};
+use reachabilityindex::{LeastCommonAncestorsHint, NodeFrontier};
 use UniqueHeap;
 
 use errors::*;
@@ -174,6 +177,63 @@ where
     Box::new(common_ancestors(ctx, changeset_fetcher, nodes).take(1))
 }
 
+/// Like `greatest_common_ancestor` of `left` and `right`, but using `lca_hint` to skip over the
+/// ancestors that can't be common rather than walking all of them. Of several common ancestors
+/// with the highest generation number, the smallest one is returned.
+pub fn greatest_common_ancestor_with_hint(
+    ctx: CoreContext,
+    changeset_fetcher: Arc<ChangesetFetcher>,
+    lca_hint: Arc<LeastCommonAncestorsHint>,
+    left: ChangesetId,
+    right: ChangesetId,
+) -> BoxFuture<Option<ChangesetId>, Error> {
+    let frontier = {
+        cloned!(ctx, changeset_fetcher);
+        move |cs_id| {
+            changeset_fetcher
+                .get_generation_number(ctx.clone(), cs_id)
+                .map(move |gen| NodeFrontier::from_iter(iter::once((cs_id, gen))))
+        }
+    };
+
+    frontier(left)
+        .join(frontier(right))
+        .and_then(move |frontiers| {
+            loop_fn(frontiers, move |(left, right)| {
+                let (left_gen, right_gen) = match (left.max_gen(), right.max_gen()) {
+                    (Some(left_gen), Some(right_gen)) => (left_gen, right_gen),
+                    _ => return ok(Loop::Break(None)).left_future(),
+                };
+                // All the ancestors of a frontier that have its generation or less are ancestors
+                // of its nodes, so the common ancestors with that generation are in both
+                let gen = if left_gen == right_gen {
+                    let common = left
+                        .get(&left_gen)
+                        .into_iter()
+                        .flatten()
+                        .filter(|cs_id| right.get(&right_gen).map_or(false, |r| r.contains(cs_id)))
+                        .min();
+                    if let Some(common) = common {
+                        return ok(Loop::Break(Some(*common))).left_future();
+                    }
+                    match left_gen.value().checked_sub(1) {
+                        Some(gen) => Generation::new(gen),
+                        None => return ok(Loop::Break(None)).left_future(),
+                    }
+                } else {
+                    cmp::min(left_gen, right_gen)
+                };
+
+                lca_hint
+                    .lca_hint(ctx.clone(), changeset_fetcher.clone(), left, gen)
+                    .join(lca_hint.lca_hint(ctx.clone(), changeset_fetcher.clone(), right, gen))
+                    .map(Loop::Continue)
+                    .right_future()
+            })
+        })
+        .boxify()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -183,7 +243,9 @@ mod test {
     use fixtures::unshared_merge_uneven;
     use revset_test_helper::assert_changesets_sequence;
     use revset_test_helper::string_to_bonsai;
+    use skiplist::SkiplistIndex;
     use tests::TestChangesetFetcher;
+    use tests_utils::{create_commit, store_files};
 
     #[test]
     fn linear_ancestors() {
@@ -407,6 +469,71 @@ mod test {
         });
     }
 
+    #[test]
+    fn greatest_common_ancestor_with_hint_merge_uneven() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let repo = Arc::new(merge_uneven::getrepo(None));
+            let changeset_fetcher: Arc<ChangesetFetcher> =
+                Arc::new(TestChangesetFetcher::new(repo.clone()));
+            let lca_hint: Arc<LeastCommonAncestorsHint> = Arc::new(SkiplistIndex::new());
+
+            let gca = |left, right| {
+                greatest_common_ancestor_with_hint(
+                    ctx.clone(),
+                    changeset_fetcher.clone(),
+                    lca_hint.clone(),
+                    string_to_bonsai(&repo, left),
+                    string_to_bonsai(&repo, right),
+                )
+                .wait()
+                .unwrap()
+            };
+
+            // Different branches
+            assert_eq!(
+                gca(
+                    "4f7f3fd428bec1a48f9314414b063c706d9c1aed",
+                    "3cda5c78aa35f0f5b09780d971197b51cad4613a"
+                ),
+                Some(string_to_bonsai(
+                    &repo,
+                    "15c40d0abc36d47fb51c8eaec51ac7aad31f669c"
+                ))
+            );
+            // Same branch, in either order
+            for (left, right) in vec![
+                (
+                    "4f7f3fd428bec1a48f9314414b063c706d9c1aed",
+                    "264f01429683b3dd8042cb3979e8bf37007118bc",
+                ),
+                (
+                    "264f01429683b3dd8042cb3979e8bf37007118bc",
+                    "4f7f3fd428bec1a48f9314414b063c706d9c1aed",
+                ),
+            ] {
+                assert_eq!(
+                    gca(left, right),
+                    Some(string_to_bonsai(
+                        &repo,
+                        "4f7f3fd428bec1a48f9314414b063c706d9c1aed"
+                    ))
+                );
+            }
+            // A changeset with itself
+            assert_eq!(
+                gca(
+                    "3cda5c78aa35f0f5b09780d971197b51cad4613a",
+                    "3cda5c78aa35f0f5b09780d971197b51cad4613a"
+                ),
+                Some(string_to_bonsai(
+                    &repo,
+                    "3cda5c78aa35f0f5b09780d971197b51cad4613a"
+                ))
+            );
+        });
+    }
+
     #[test]
     fn all_common_ancestors_different_branches() {
         async_unit::tokio_unit_test(|| {
@@ -464,4 +591,51 @@ mod test {
             );
         });
     }
+
+    #[test]
+    fn octopus_merge_ancestors() {
+        async_unit::tokio_unit_test(|| {
+            let ctx = CoreContext::test_mock();
+            let repo = Arc::new(linear::getrepo(None));
+            let changeset_fetcher: Arc<ChangesetFetcher> =
+                Arc::new(TestChangesetFetcher::new(repo.clone()));
+
+            let head = string_to_bonsai(&repo, "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157");
+            let branches: Vec<_> = ["octopus_1", "octopus_2", "octopus_3"]
+                .iter()
+                .map(|path| {
+                    create_commit(
+                        ctx.clone(),
+                        (*repo).clone(),
+                        vec![head],
+                        store_files(ctx.clone(), btreemap! {*path => Some(*path)}, (*repo).clone()),
+                    )
+                })
+                .collect();
+            let octopus =
+                create_commit(ctx.clone(), (*repo).clone(), branches.clone(), btreemap! {});
+
+            let ancestors: HashSet<_> =
+                AncestorsNodeStream::new(ctx.clone(), &changeset_fetcher, octopus)
+                    .collect()
+                    .wait()
+                    .unwrap()
+                    .into_iter()
+                    .collect();
+            let head_ancestors: HashSet<_> =
+                AncestorsNodeStream::new(ctx.clone(), &changeset_fetcher, head)
+                    .collect()
+                    .wait()
+                    .unwrap()
+                    .into_iter()
+                    .collect();
+
+            let expected: HashSet<_> = head_ancestors
+                .into_iter()
+                .chain(branches.into_iter())
+                .chain(Some(octopus))
+                .collect();
+            assert_eq!(ancestors, expected);
+        });
+    }
 }
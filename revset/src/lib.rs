@@ -29,6 +29,8 @@ extern crate reachabilityindex;
 extern crate revset_test_helper;
 #[cfg(test)]
 extern crate skiplist;
+#[cfg(test)]
+extern crate tests_utils;
 extern crate uniqueheap;
 
 use futures::stream::Stream;
@@ -56,7 +58,10 @@ mod validation;
 pub use validation::ValidateNodeStream;
 
 mod ancestors;
-pub use ancestors::{common_ancestors, greatest_common_ancestor, AncestorsNodeStream};
+pub use ancestors::{
+    common_ancestors, greatest_common_ancestor, greatest_common_ancestor_with_hint,
+    AncestorsNodeStream,
+};
 
 mod ancestorscombinators;
 pub use ancestorscombinators::DifferenceOfUnionsOfAncestorsNodeStream;
@@ -5,6 +5,7 @@
 // GNU General Public License version 2 or any later version.
 
 extern crate chashmap;
+extern crate chrono;
 extern crate scuba_ext;
 extern crate serde;
 extern crate sshrelay;
@@ -14,8 +15,10 @@ extern crate tracing;
 extern crate uuid;
 
 use chashmap::CHashMap;
+use chrono::{DateTime, FixedOffset, Local};
 use serde::{Serialize, Serializer};
-use std::sync::Arc;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
 
 use scuba_ext::ScubaSampleBuilder;
 use slog::{Logger, OwnedKV, SendSyncRefUnwindSafeKV};
@@ -83,6 +86,66 @@ impl PerfCounters {
     }
 }
 
+/// Where a request gets the current time and new uuids from. Code that logs or stores either
+/// should ask the `CoreContext` instead of the system, so that tests can make them reproducible.
+pub trait TimeUuidProvider: Debug + Send + Sync {
+    fn now(&self) -> DateTime<FixedOffset>;
+    fn new_uuid(&self) -> Uuid;
+}
+
+/// The system clock, in the local timezone, and random uuids
+#[derive(Debug)]
+pub struct SystemTimeUuidProvider;
+
+impl TimeUuidProvider for SystemTimeUuidProvider {
+    fn now(&self) -> DateTime<FixedOffset> {
+        let now = Local::now();
+        now.with_timezone(now.offset())
+    }
+
+    fn new_uuid(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// A clock that only moves when told to, and uuids that are numbered from 1
+#[derive(Debug)]
+pub struct MockTimeUuidProvider {
+    now: Mutex<DateTime<FixedOffset>>,
+    last_uuid: Mutex<u64>,
+}
+
+impl MockTimeUuidProvider {
+    pub fn new(now: DateTime<FixedOffset>) -> Self {
+        Self {
+            now: Mutex::new(now),
+            last_uuid: Mutex::new(0),
+        }
+    }
+
+    pub fn set_now(&self, now: DateTime<FixedOffset>) {
+        *self.now.lock().expect("lock poisoned") = now;
+    }
+
+    pub fn advance(&self, duration: chrono::Duration) {
+        let mut now = self.now.lock().expect("lock poisoned");
+        *now = *now + duration;
+    }
+}
+
+impl TimeUuidProvider for MockTimeUuidProvider {
+    fn now(&self) -> DateTime<FixedOffset> {
+        *self.now.lock().expect("lock poisoned")
+    }
+
+    fn new_uuid(&self) -> Uuid {
+        let mut last_uuid = self.last_uuid.lock().expect("lock poisoned");
+        *last_uuid += 1;
+        Uuid::parse_str(&format!("00000000-0000-0000-0000-{:012x}", *last_uuid))
+            .expect("invalid mock uuid")
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Inner {
     session: Uuid,
@@ -95,6 +158,7 @@ struct Inner {
     perf_counters: PerfCounters,
     user_unix_name: Option<String>,
     ssh_env_vars: SshEnvVars,
+    time_uuid: Arc<TimeUuidProvider>,
 }
 
 impl CoreContext {
@@ -117,6 +181,7 @@ impl CoreContext {
                 perf_counters: PerfCounters::new(),
                 user_unix_name,
                 ssh_env_vars,
+                time_uuid: Arc::new(SystemTimeUuidProvider),
             }),
         }
     }
@@ -135,6 +200,7 @@ impl CoreContext {
                 perf_counters: self.inner.perf_counters.clone(),
                 user_unix_name: self.inner.user_unix_name.clone(),
                 ssh_env_vars: self.inner.ssh_env_vars.clone(),
+                time_uuid: self.inner.time_uuid.clone(),
             }),
         }
     }
@@ -153,10 +219,21 @@ impl CoreContext {
                 perf_counters: self.inner.perf_counters.clone(),
                 user_unix_name: self.inner.user_unix_name.clone(),
                 ssh_env_vars: self.inner.ssh_env_vars.clone(),
+                time_uuid: self.inner.time_uuid.clone(),
             }),
         }
     }
 
+    /// Use another source of time and uuids, e.g. a `MockTimeUuidProvider` in tests. The
+    /// session uuid is unchanged.
+    pub fn with_time_uuid_provider(&self, time_uuid: Arc<TimeUuidProvider>) -> Self {
+        let mut inner = (*self.inner).clone();
+        inner.time_uuid = time_uuid;
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
     pub fn test_mock() -> Self {
         Self::new(
             Uuid::new_v4(),
@@ -193,4 +270,73 @@ impl CoreContext {
     pub fn ssh_env_vars(&self) -> &SshEnvVars {
         &self.inner.ssh_env_vars
     }
+    /// The identities of the user: their unix name first, then the principals of their ssh
+    /// certificate, which it lists separated by commas
+    pub fn user_identities(&self) -> Vec<String> {
+        self.inner
+            .user_unix_name
+            .iter()
+            .chain(self.inner.ssh_env_vars.ssh_cert_principals.iter())
+            .flat_map(|identities| identities.split(','))
+            .map(|identity| identity.trim())
+            .filter(|identity| !identity.is_empty())
+            .map(|identity| identity.to_string())
+            .collect()
+    }
+    pub fn now(&self) -> DateTime<FixedOffset> {
+        self.inner.time_uuid.now()
+    }
+    pub fn new_uuid(&self) -> Uuid {
+        self.inner.time_uuid.new_uuid()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mock_time_and_uuids() {
+        let start = DateTime::parse_from_rfc3339("2019-03-01T12:00:00+01:00").unwrap();
+        let provider = Arc::new(MockTimeUuidProvider::new(start));
+        let ctx = CoreContext::test_mock().with_time_uuid_provider(provider.clone());
+
+        assert_eq!(ctx.now(), start);
+        assert_eq!(ctx.now(), start);
+        provider.advance(chrono::Duration::seconds(10));
+        assert_eq!(ctx.clone().now(), start + chrono::Duration::seconds(10));
+
+        assert_eq!(
+            ctx.new_uuid().to_string(),
+            "00000000-0000-0000-0000-000000000001"
+        );
+        assert_eq!(
+            ctx.new_uuid().to_string(),
+            "00000000-0000-0000-0000-000000000002"
+        );
+
+        // Derived contexts share the provider
+        let derived = ctx.with_logger_kv(o!("key" => "value"));
+        provider.set_now(start);
+        assert_eq!(derived.now(), start);
+    }
+
+    #[test]
+    fn user_identities() {
+        let ssh_env_vars = SshEnvVars {
+            ssh_cert_principals: Some("releng, svc-deploy,".to_string()),
+            ..SshEnvVars::default()
+        };
+        let ctx = CoreContext::new(
+            Uuid::new_v4(),
+            Logger::root(::slog::Discard, o!()),
+            ScubaSampleBuilder::with_discard(),
+            None,
+            TraceContext::default(),
+            Some("alice".to_string()),
+            ssh_env_vars,
+        );
+        assert_eq!(ctx.user_identities(), vec!["alice", "releng", "svc-deploy"]);
+        assert!(CoreContext::test_mock().user_identities().is_empty());
+    }
 }
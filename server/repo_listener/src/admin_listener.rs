@@ -0,0 +1,114 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Commands for operators to change the repos a server serves while it runs. Each line sent to
+//! the admin address is a command, answered with the lines of its output followed by `ok`, or with
+//! `error: <why>` if it failed:
+//!
+//!   list            the loaded repos, each with its state and number of open sessions
+//!   load <repo>     loads a repo with its current config, answered once it serves sessions
+//!   drain <repo>    refuses new sessions of a repo, open ones are served until they end
+//!   unload <repo>   drains a repo, answered once its sessions ended and it's unloaded
+//!   lock <repo> <reason>
+//!                   refuses pushes to a repo on every server, for the reason given
+//!   unlock <repo>   accepts pushes to a repo again
+//!
+//! Whoever can connect to the admin address controls the repos of the server, so it can only be
+//! a loopback address.
+
+use std::io;
+use std::net::SocketAddr;
+
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+use tokio;
+use tokio::net::TcpListener;
+use tokio_codec::{Framed, LinesCodec};
+
+use errors::*;
+use metaconfig_types::RepoReadOnly;
+use repo_registry::RepoRegistry;
+
+pub fn admin_listener(
+    sockname: String,
+    root_log: Logger,
+    registry: RepoRegistry,
+) -> BoxFuture<(), Error> {
+    let addr: SocketAddr = try_boxfuture!(sockname.parse());
+    if !addr.ip().is_loopback() {
+        return future::err(format_err!(
+            "admin commands can only be listened to on a loopback address, not {}",
+            addr
+        ))
+        .boxify();
+    }
+    let listener = try_boxfuture!(TcpListener::bind(&addr));
+    info!(root_log, "Listening to admin commands on {}", addr);
+
+    listener
+        .incoming()
+        .from_err()
+        .for_each(move |sock| {
+            cloned!(root_log, registry);
+            let (replies, commands) = Framed::new(sock, LinesCodec::new()).split();
+            let replies = commands
+                .and_then(move |command| {
+                    info!(root_log, "Admin command: {}", command);
+                    run_command(&registry, &command).then(|res| {
+                        let lines = match res {
+                            Ok(mut lines) => {
+                                lines.push("ok".to_string());
+                                lines
+                            }
+                            Err(err) => vec![format!("error: {}", err)],
+                        };
+                        Ok::<_, io::Error>(stream::iter_ok(lines))
+                    })
+                })
+                .flatten()
+                .forward(replies);
+            // Commands wait for repos to load or unload, so connections don't block each other
+            tokio::spawn(replies.map(|_| ()).map_err(|_| ()));
+            Ok(())
+        })
+        .boxify()
+}
+
+fn run_command(registry: &RepoRegistry, command: &str) -> BoxFuture<Vec<String>, Error> {
+    let words: Vec<_> = command.split_whitespace().collect();
+    match words.as_slice() {
+        ["list"] => {
+            let repos = registry
+                .list()
+                .into_iter()
+                .map(|(reponame, state, sessions)| format!("{} {} {}", reponame, state, sessions))
+                .collect();
+            future::ok(repos).boxify()
+        }
+        ["load", reponame] => registry
+            .load(reponame.to_string())
+            .map(|()| vec![])
+            .boxify(),
+        ["drain", reponame] => future::result(registry.drain(reponame))
+            .map(|()| vec![])
+            .boxify(),
+        ["unload", reponame] => registry
+            .unload(reponame.to_string())
+            .map(|()| vec![])
+            .boxify(),
+        ["unlock", reponame] => registry
+            .set_readonly(reponame, RepoReadOnly::ReadWrite)
+            .map(|()| vec![])
+            .boxify(),
+        // The reason a repo is locked for is the rest of the line
+        _ if words.len() > 2 && words[0] == "lock" => registry
+            .set_readonly(words[1], RepoReadOnly::ReadOnly(words[2..].join(" ")))
+            .map(|()| vec![])
+            .boxify(),
+        _ => future::err(format_err!("unknown command: {}", command)).boxify(),
+    }
+}
@@ -4,7 +4,6 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
 use std::sync::{
@@ -31,7 +30,7 @@ use tokio_timer;
 use sshrelay::{SenderBytesWrite, SshDecoder, SshEncoder, SshMsg, SshStream, Stdio};
 
 use errors::*;
-use repo_handlers::RepoHandler;
+use repo_registry::RepoRegistry;
 use request_handler::request_handler;
 
 const CHUNK_SIZE: usize = 10000;
@@ -45,11 +44,10 @@ lazy_static! {
 pub fn connection_acceptor(
     sockname: String,
     root_log: Logger,
-    repo_handlers: HashMap<String, RepoHandler>,
+    registry: RepoRegistry,
     tls_acceptor: SslAcceptor,
     terminate_process: &'static AtomicBool,
 ) -> BoxFuture<(), Error> {
-    let repo_handlers = Arc::new(repo_handlers);
     let tls_acceptor = Arc::new(tls_acceptor);
     let listener = listener(sockname)
         .expect("failed to create listener")
@@ -58,10 +56,10 @@ pub fn connection_acceptor(
     TakeUntilNotSet::new(listener.boxify(), terminate_process)
         .for_each(move |sock| {
             // Accept the request without blocking the listener
-            cloned!(root_log, repo_handlers, tls_acceptor);
+            cloned!(root_log, registry, tls_acceptor);
             OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
             tokio::spawn(future::lazy(move || {
-                accept(sock, root_log, repo_handlers, tls_acceptor).then(|res| {
+                accept(sock, root_log, registry, tls_acceptor).then(|res| {
                     OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
                     res
                 })
@@ -84,7 +82,7 @@ pub fn connection_acceptor(
 fn accept(
     sock: TcpStream,
     root_log: Logger,
-    repo_handlers: Arc<HashMap<String, RepoHandler>>,
+    registry: RepoRegistry,
     tls_acceptor: Arc<SslAcceptor>,
 ) -> impl Future<Item = (), Error = ()> {
     let addr = sock.peer_addr();
@@ -123,11 +121,10 @@ fn accept(
             }
         }))
         .and_then(move |(stdio, addr)| {
-            repo_handlers
-                .get(&stdio.preamble.reponame)
-                .cloned()
-                .ok_or_else(|| {
-                    error!(root_log, "Unknown repo: {}", stdio.preamble.reponame);
+            registry
+                .open_session(&stdio.preamble.reponame)
+                .map_err(|err| {
+                    error!(root_log, "Refused session: {}", err);
                     let tmp_conn_logger = {
                         let stderr_write = SenderBytesWrite {
                             chan: stdio.stderr.clone().wait(),
@@ -137,14 +134,17 @@ fn accept(
                         let drain = KVFilter::new(drain, Level::Critical);
                         Logger::root(drain.ignore_res(), o!())
                     };
-                    error!(
-                        tmp_conn_logger,
-                        "Requested repo \"{}\" does not exist or disabled", stdio.preamble.reponame
-                    )
+                    error!(tmp_conn_logger, "{}", err)
                 })
                 .into_future()
-                .and_then(move |handler| {
-                    request_handler(handler.clone(), stdio, addr, handler.repo.hook_manager())
+                .and_then(move |session| {
+                    let handler = session.handler.clone();
+                    let hook_manager = handler.repo.hook_manager();
+                    // The session stays open until the request handler is done with it
+                    request_handler(handler, stdio, addr, hook_manager).then(move |res| {
+                        drop(session);
+                        res
+                    })
                 })
         })
 }
@@ -12,4 +12,14 @@ pub enum ErrorKind {
     NoConnectionPreamble,
     #[fail(display = "connection error while reading preamble")]
     ConnectionError,
+    #[fail(display = "Requested repo \"{}\" does not exist or disabled", _0)]
+    UnknownRepo(String),
+    #[fail(display = "repo {} is being unloaded", _0)]
+    RepoDraining(String),
+    #[fail(display = "repo {} already serves its maximum of {} sessions", _0, _1)]
+    TooManySessions(String, usize),
+    #[fail(display = "repo {} is already loaded or being loaded", _0)]
+    RepoAlreadyLoaded(String),
+    #[fail(display = "repo {} is not enabled in its config", _0)]
+    RepoNotEnabled(String),
 }
@@ -14,8 +14,10 @@ extern crate blobrepo;
 extern crate blobrepo_factory;
 extern crate blobstore;
 extern crate bytes;
+extern crate cachelib;
 #[macro_use]
 extern crate cloned;
+extern crate content_refs;
 extern crate context;
 #[macro_use]
 extern crate failure_ext as failure;
@@ -34,6 +36,7 @@ extern crate slog_ext;
 extern crate slog_kvfilter;
 extern crate slog_term;
 extern crate sql;
+extern crate sql_ext;
 #[macro_use]
 extern crate stats;
 extern crate time_ext;
@@ -46,62 +49,110 @@ extern crate tokio_timer;
 extern crate tracing;
 extern crate uuid;
 
+extern crate audit_log;
 extern crate cache_warmup;
+extern crate event_bus;
+extern crate hg_derivation_queue;
 extern crate hgproto;
 extern crate hooks;
 extern crate hooks_content_stores;
+extern crate metaconfig_parser;
 extern crate metaconfig_types;
 extern crate mononoke_types;
+extern crate path_acl;
 extern crate phases;
+extern crate push_usage;
+extern crate qos;
 extern crate reachabilityindex;
 extern crate skiplist;
 extern crate ready_state;
 extern crate repo_client;
+extern crate repo_maintenance;
+extern crate repo_overrides;
 extern crate scribe;
 extern crate scuba_ext;
 extern crate sshrelay;
+extern crate webhook_dispatcher;
 
+mod admin_listener;
 mod connection_acceptor;
 mod errors;
+mod pull_through_sync;
 mod repo_handlers;
+mod repo_registry;
 mod request_handler;
+mod skiplist_refresh;
 
-use futures::Future;
+use futures::{future, Future};
 use futures_ext::{BoxFuture, FutureExt};
 use openssl::ssl::SslAcceptor;
 use slog::Logger;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
 
 use metaconfig_types::RepoConfig;
 
+use admin_listener::admin_listener;
 use connection_acceptor::connection_acceptor;
 use errors::*;
 use repo_handlers::repo_handlers;
+use repo_registry::RepoRegistry;
 
+pub use repo_client::install_quarantine_panic_hook;
+
+/// Serves `repos` on `sockname`. If `admin_sockname` is set, repos can be loaded from the configs
+/// in `config_path`, drained and unloaded with commands sent to it while the server runs.
 pub fn create_repo_listeners(
     repos: impl IntoIterator<Item = (String, RepoConfig)>,
+    config_path: PathBuf,
     myrouter_port: Option<u16>,
+    server_tier: Option<String>,
     root_log: &Logger,
     sockname: &str,
+    admin_sockname: Option<&str>,
     tls_acceptor: SslAcceptor,
     terminate_process: &'static AtomicBool,
 ) -> (BoxFuture<(), Error>, ready_state::ReadyState) {
     let sockname = String::from(sockname);
+    let admin_sockname = admin_sockname.map(String::from);
     let root_log = root_log.clone();
     let mut ready = ready_state::ReadyStateBuilder::new();
 
     (
-        repo_handlers(repos, myrouter_port, &root_log, &mut ready)
-            .and_then(move |handlers| {
-                connection_acceptor(
-                    sockname,
-                    root_log,
-                    handlers,
-                    tls_acceptor,
-                    terminate_process,
-                )
-            })
-            .boxify(),
+        repo_handlers(
+            repos,
+            myrouter_port,
+            server_tier.clone(),
+            &root_log,
+            &mut ready,
+        )
+        .and_then(move |handlers| {
+            let registry = RepoRegistry::new(
+                handlers,
+                config_path,
+                myrouter_port,
+                server_tier,
+                root_log.clone(),
+            );
+            let admin = match admin_sockname {
+                Some(admin_sockname) => {
+                    admin_listener(admin_sockname, root_log.clone(), registry.clone()).left_future()
+                }
+                None => future::empty().right_future(),
+            };
+            // The server stops when it's asked to, whether or not it listens to admin commands
+            connection_acceptor(
+                sockname,
+                root_log,
+                registry,
+                tls_acceptor,
+                terminate_process,
+            )
+            .select(admin)
+            .map(|((), _)| ())
+            .map_err(|(err, _)| err)
+        })
+        .boxify(),
         ready.freeze(),
     )
 }
@@ -0,0 +1,54 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Polling of the upstream of a repo whose blobs are pulled through from it, so that the
+//! bookmarks served here follow the ones upstream.
+
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use slog::Logger;
+use stats::Timeseries;
+use tokio::timer::Interval;
+
+use blobrepo::PullThroughSync;
+use context::CoreContext;
+
+define_stats! {
+    prefix = "mononoke.pull_through_sync";
+    sync_errors: timeseries(RATE, SUM),
+}
+
+/// Run `sync` every `interval`, starting right away, as a server that just started has none of
+/// the bookmarks of upstream. Runs for as long as the server does.
+pub fn sync_periodically(
+    ctx: CoreContext,
+    sync: PullThroughSync,
+    interval: Duration,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), interval)
+        .map_err({
+            cloned!(logger);
+            move |err| error!(logger, "pull-through sync timer failed: {}", err)
+        })
+        .for_each(move |_| {
+            sync.sync(ctx.clone()).then({
+                cloned!(logger);
+                move |result| {
+                    match result {
+                        Ok(0) => {}
+                        Ok(count) => debug!(logger, "moved {} bookmarks to upstream", count),
+                        Err(err) => {
+                            STATS::sync_errors.add_value(1);
+                            warn!(logger, "failed to sync with upstream: {:?}", err);
+                        }
+                    }
+                    Ok(())
+                }
+            })
+        })
+}
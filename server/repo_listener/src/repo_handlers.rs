@@ -6,30 +6,91 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use failure::prelude::*;
 use futures::{
-    future::{self, ok},
+    future::{self, ok, Shared},
+    sync::oneshot,
     Future,
 };
 use futures_ext::{BoxFuture, FutureExt};
 use slog::Logger;
 use sql::myrouter;
+use sql_ext::remote_myrouter_port;
 
-use blobrepo_factory::open_blobrepo;
-use blobstore::Blobstore;
+use audit_log::Auditor;
+use blobrepo_factory::open_blobrepo_with_replicas;
+use blobstore::{Blobstore, RetryPolicy};
 use cache_warmup::cache_warmup;
-use context::CoreContext;
-use hooks::{hook_loader::load_hooks, HookManager};
-use hooks_content_stores::{BlobRepoChangesetStore, BlobRepoFileContentStore};
-use metaconfig_types::{RepoConfig, RepoType};
+use content_refs::{ContentRefs, ContentRefsIndex, SqlContentRefs};
+use context::{CoreContext, SystemTimeUuidProvider, TimeUuidProvider};
+use event_bus::EventBus;
+use hg_derivation_queue::{run_derivation_worker, HgDerivationQueue, SqlHgDerivationQueue};
+use hooks::{
+    hook_loader::load_hooks, run_post_commit_worker, CachingStateProvider, HookManager,
+    HookResultCache, HookResultStore, HttpStateProvider, PostCommitQueue, SqlHookResultStore,
+    SqlPostCommitQueue,
+};
+use hooks_content_stores::{BlobRepoChangesetStore, MemoizingFileContentStore};
+use metaconfig_types::{RepoConfig, RepoType, SessionLimits};
 use mononoke_types::RepositoryId;
+use path_acl::PathAcls;
 use phases::{CachingHintPhases, HintPhases, Phases, SqlConstructors, SqlPhases};
+use pull_through_sync::sync_periodically;
+use push_usage::{PushQuota, PushUsageStore, SqlPushUsageStore};
+use qos::QosPools;
 use reachabilityindex::LeastCommonAncestorsHint;
-use ready_state::ReadyStateBuilder;
-use repo_client::{streaming_clone, MononokeRepo, RepoReadWriteFetcher};
+use ready_state::{ReadyHandle, ReadyStateBuilder};
+use repo_client::{
+    streaming_clone, MononokeRepo, Quarantine, RepoReadWriteFetcher, ResponseCache, ResumablePull,
+    ResumablePullStore, SqlResumablePullStore, SqlTreePopularity, TreePopularity, TreePrefetch,
+};
+use repo_maintenance::{MaintenanceStore, SqlMaintenanceStore};
+use repo_overrides::{OverridesStore, RepoOverrides, SqlOverridesStore};
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 use skiplist::{deserialize_skiplist_map, SkiplistIndex};
+use skiplist_refresh::{index_new_heads_periodically, store_snapshots_periodically};
+use webhook_dispatcher::WebhookDispatcher;
+
+/// How long an idle hg derivation worker waits before checking the queue again.
+const HG_DERIVATION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long an idle post-commit hook worker waits before checking the queue again.
+const POST_COMMIT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the overrides of the tunables of a repo are polled.
+const OVERRIDES_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Number of responses of external services that hooks of a repo keep.
+const HOOK_STATE_CACHE_CAPACITY: usize = 10000;
+/// Bytes of file lookups and content that the hooks of a repo keep in memory.
+const HOOK_CONTENT_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+/// A repo that the server loaded, with what its sessions are served with
+pub struct LoadedRepo {
+    pub handler: RepoHandler,
+    unload: oneshot::Sender<()>,
+}
+
+impl LoadedRepo {
+    /// Stops the background tasks of the repo
+    pub fn unload(self) {
+        let _ = self.unload.send(());
+    }
+}
+
+/// Signals that the repo background tasks run for was unloaded
+#[derive(Clone)]
+struct Unloaded(Shared<oneshot::Receiver<()>>);
+
+impl Unloaded {
+    /// Runs `task` until it's done or the repo is unloaded
+    fn spawn<F>(&self, task: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        let unloaded = self.0.clone().then(|_| Ok(()));
+        tokio::spawn(task.select(unloaded).then(|_| Ok(())));
+    }
+}
 
 #[derive(Clone)]
 pub struct RepoHandler {
@@ -41,14 +102,23 @@ pub struct RepoHandler {
     pub lca_hint: Arc<LeastCommonAncestorsHint>,
     pub phases_hint: Arc<Phases>,
     pub preserve_raw_bundle2: bool,
+    pub session_limits: SessionLimits,
+    pub response_size_limits: Arc<HashMap<String, u64>>,
+    pub qos: Arc<QosPools>,
+    pub quarantine: Option<Arc<Quarantine>>,
+    pub path_acls: PathAcls,
+    pub server_tier: Option<String>,
+    /// Where the sessions of the repo get the current time and new uuids from
+    pub time_uuid: Arc<TimeUuidProvider>,
 }
 
 pub fn repo_handlers(
     repos: impl IntoIterator<Item = (String, RepoConfig)>,
     myrouter_port: Option<u16>,
+    server_tier: Option<String>,
     root_log: &Logger,
     ready: &mut ReadyStateBuilder,
-) -> BoxFuture<HashMap<String, RepoHandler>, Error> {
+) -> BoxFuture<HashMap<String, LoadedRepo>, Error> {
     // compute eagerly to avoid lifetime issues
     let repos: Vec<_> = repos
         .into_iter()
@@ -59,184 +129,487 @@ pub fn repo_handlers(
             config.enabled
         })
         .map(|(reponame, config)| {
-            info!(
+            let ready_handle = ready.create_handle(reponame.as_ref());
+            repo_handler(
+                reponame.clone(),
+                config,
+                myrouter_port,
+                server_tier.clone(),
                 root_log,
-                "Start warming for repo {}, type {:?}", reponame, config.repotype
-            );
-            // TODO(T37478150, luk): this is not a test use case, need to address this later
-            let ctx = CoreContext::test_mock();
-            let ensure_myrouter_ready = match config.get_db_address() {
-                None => future::ok(()).left_future(),
-                Some(db_address) => {
-                    let myrouter_port = try_boxfuture!(myrouter_port.ok_or_else(|| format_err!(
-                        "No port for MyRouter provided, but repo {} needs to connect do db {}",
-                        reponame,
-                        db_address
+                ready_handle,
+            )
+            .map(move |repo| (reponame, repo))
+        })
+        .collect();
+
+    future::join_all(repos)
+        .map(|repos| repos.into_iter().collect())
+        .boxify()
+}
+
+/// Opens a repo, and once it's warmed up marks `ready_handle` as ready and starts the background
+/// tasks of the repo, which run until it's unloaded
+pub fn repo_handler(
+    reponame: String,
+    config: RepoConfig,
+    myrouter_port: Option<u16>,
+    server_tier: Option<String>,
+    root_log: &Logger,
+    ready_handle: ReadyHandle,
+) -> BoxFuture<LoadedRepo, Error> {
+    info!(
+        root_log,
+        "Start warming for repo {}, type {:?}", reponame, config.repotype
+    );
+    // TODO(T37478150, luk): this is not a test use case, need to address this later
+    let ctx = CoreContext::test_mock();
+    let ensure_myrouter_ready = match config.get_db_address() {
+        None => future::ok(()).left_future(),
+        Some(db_address) => {
+            let myrouter_port = try_boxfuture!(myrouter_port.ok_or_else(|| format_err!(
+                "No port for MyRouter provided, but repo {} needs to connect do db {}",
+                reponame,
+                db_address
+            )));
+            myrouter::wait_for_myrouter(myrouter_port, db_address).right_future()
+        }
+    };
+
+    let root_log = root_log.clone();
+    let logger = root_log.new(o!("repo" => reponame.clone()));
+    let repoid = RepositoryId::new(config.repoid);
+    open_blobrepo_with_replicas(
+        logger.clone(),
+        config.repotype.clone(),
+        repoid,
+        myrouter_port,
+    )
+    .and_then(move |(blobrepo, replica_manager)| {
+        let (blobrepo, pull_through_sync) = match config.pull_through {
+            Some(ref params) => {
+                info!(
+                    root_log,
+                    "Pulling the missing blobs of {} from {}", reponame, params.upstream_url
+                );
+                let (blobrepo, sync) = try_boxfuture!(blobrepo.with_pull_through(params));
+                let interval = Duration::from_secs(params.sync_interval_secs);
+                (blobrepo, Some((sync, interval)))
+            }
+            None => (blobrepo, None),
+        };
+        let blobrepo = match config.fault_injection {
+            #[cfg(feature = "fault_injection")]
+            Some(ref params) => {
+                warn!(
+                    root_log,
+                    "Injecting faults into the storage of {}", reponame
+                );
+                blobrepo.with_fault_injection(params)
+            }
+            #[cfg(not(feature = "fault_injection"))]
+            Some(_) => {
+                return future::err(format_err!(
+                    "repo {} injects faults, but the server is built without fault injection",
+                    reponame
+                ))
+                .boxify();
+            }
+            None => blobrepo,
+        };
+        // A single flaky put shouldn't fail a whole push
+        let blobrepo = blobrepo.with_put_retries(RetryPolicy::default());
+        let blobrepo = match config.manifest_sharding {
+            Some(params) => blobrepo.with_manifest_sharding(params),
+            None => blobrepo,
+        };
+
+        let hook_manager_params = match config.hook_manager_params.clone() {
+            Some(hook_manager_params) => hook_manager_params,
+            None => Default::default(),
+        };
+
+        let hook_result_cache = match hook_manager_params.result_cache_ttl_secs {
+            Some(ttl_secs) => {
+                let store: Arc<HookResultStore> =
+                    Arc::new(try_boxfuture!(SqlHookResultStore::with_repo_type(
+                        &config.repotype,
+                        myrouter_port,
+                        "hook_results"
                     )));
-                    myrouter::wait_for_myrouter(myrouter_port, db_address).right_future()
-                }
+                Some(HookResultCache::new(store, ttl_secs))
+            }
+            None => None,
+        };
+
+        let hook_state_cache_ttl_secs = hook_manager_params.state_cache_ttl_secs;
+        let mut hook_manager = HookManager::new_with_result_cache(
+            ctx.clone(),
+            Box::new(BlobRepoChangesetStore::new(blobrepo.clone())),
+            Arc::new(MemoizingFileContentStore::new(
+                blobrepo.clone(),
+                HOOK_CONTENT_CACHE_BYTES,
+            )),
+            hook_manager_params,
+            hook_result_cache,
+            logger,
+        );
+        // Don't start the TLS machinery for the many repos whose hooks don't fetch state
+        if let Some(ttl_secs) = hook_state_cache_ttl_secs {
+            let provider = CachingStateProvider::new(
+                Arc::new(try_boxfuture!(HttpStateProvider::new())),
+                Duration::from_secs(ttl_secs),
+                HOOK_STATE_CACHE_CAPACITY,
+            );
+            hook_manager.set_state_provider(Arc::new(provider));
+        }
+
+        info!(root_log, "Loading hooks");
+        try_boxfuture!(load_hooks(&mut hook_manager, config.clone()));
+
+        // Only repos with post-commit hooks get a queue for them
+        let post_commit_queue: Option<Arc<PostCommitQueue>> =
+            if hook_manager.post_commit_hook_names().is_empty() {
+                None
+            } else {
+                Some(Arc::new(try_boxfuture!(
+                    SqlPostCommitQueue::with_repo_type(
+                        &config.repotype,
+                        myrouter_port,
+                        "post_commit_queue"
+                    )
+                )))
             };
+        if let Some(ref queue) = post_commit_queue {
+            hook_manager.set_post_commit_queue(queue.clone());
+        }
 
-            let ready_handle = ready.create_handle(reponame.as_ref());
+        let streaming_clone = match config.repotype {
+            RepoType::BlobRemote { ref db_address, .. } => Some(try_boxfuture!(streaming_clone(
+                blobrepo.clone(),
+                &db_address,
+                try_boxfuture!(remote_myrouter_port(myrouter_port)),
+                repoid
+            ))),
+            _ => None,
+        };
+
+        let read_write_fetcher = match config.repotype {
+            RepoType::BlobRemote {
+                ref write_lock_db_address,
+                ..
+            } => RepoReadWriteFetcher::with_myrouter(
+                config.readonly.clone(),
+                reponame.clone(),
+                write_lock_db_address,
+                try_boxfuture!(remote_myrouter_port(myrouter_port)),
+            ),
+            _ => RepoReadWriteFetcher::new(config.readonly.clone(), reponame.clone()),
+        };
 
-            let root_log = root_log.clone();
-            let logger = root_log.new(o!("repo" => reponame.clone()));
-            let repoid = RepositoryId::new(config.repoid);
-            open_blobrepo(
-                logger.clone(),
-                config.repotype.clone(),
-                repoid,
+        let hg_derivation_queue: Option<Arc<HgDerivationQueue>> =
+            if config.pushrebase.defer_hg_derivation {
+                Some(Arc::new(try_boxfuture!(
+                    SqlHgDerivationQueue::with_repo_type(
+                        &config.repotype,
+                        myrouter_port,
+                        "hg_derivation_queue"
+                    )
+                )))
+            } else {
+                None
+            };
+
+        // Maintenance windows live next to the repo lock, as both control writes
+        let maintenance_store: Arc<MaintenanceStore> = Arc::new(try_boxfuture!(
+            SqlMaintenanceStore::with_repo_type_write_lock_db(
+                &config.repotype,
                 myrouter_port,
+                "maintenance"
             )
-            .and_then(move |blobrepo| {
-                let hook_manager_params = match config.hook_manager_params.clone() {
-                    Some(hook_manager_params) => hook_manager_params,
-                    None => Default::default(),
-                };
-
-                let mut hook_manager = HookManager::new(
-                    ctx.clone(),
-                    Box::new(BlobRepoChangesetStore::new(blobrepo.clone())),
-                    Arc::new(BlobRepoFileContentStore::new(blobrepo.clone())),
-                    hook_manager_params,
-                    logger,
-                );
+        ));
+
+        let overrides_store: Arc<OverridesStore> = Arc::new(try_boxfuture!(
+            SqlOverridesStore::with_repo_type(&config.repotype, myrouter_port, "repo_overrides")
+        ));
+        let overrides = RepoOverrides::new(
+            repoid,
+            overrides_store,
+            root_log.new(o!("repo" => reponame.clone())),
+        );
+
+        let push_usage_store: Arc<PushUsageStore> = Arc::new(try_boxfuture!(
+            SqlPushUsageStore::with_repo_type(&config.repotype, myrouter_port, "push_usage")
+        ));
+        let push_quota = PushQuota::new(repoid, config.push_quota.clone(), push_usage_store);
 
-                info!(root_log, "Loading hooks");
-                try_boxfuture!(load_hooks(&mut hook_manager, config.clone()));
-
-                let streaming_clone = match config.repotype {
-                    RepoType::BlobRemote { ref db_address, .. } => {
-                        Some(try_boxfuture!(streaming_clone(
-                            blobrepo.clone(),
-                            &db_address,
-                            myrouter_port.expect("myrouter_port not provided for BlobRemote repo"),
-                            repoid
-                        )))
+        let content_refs = match config.content_refs {
+            Some(params) => {
+                let store: Arc<ContentRefs> = Arc::new(try_boxfuture!(
+                    SqlContentRefs::with_repo_type(&config.repotype, myrouter_port, "content_refs")
+                ));
+                Some(ContentRefsIndex::new(repoid, params, store))
+            }
+            None => None,
+        };
+
+        let tree_prefetch = match config.tree_prefetch.clone() {
+            Some(params) => {
+                let popularity: Arc<TreePopularity> =
+                    Arc::new(try_boxfuture!(SqlTreePopularity::with_repo_type(
+                        &config.repotype,
+                        myrouter_port,
+                        "tree_popularity"
+                    )));
+                Some(TreePrefetch { params, popularity })
+            }
+            None => None,
+        };
+
+        let resumable_pull = match config.resumable_pull {
+            Some(params) => {
+                let store: Arc<ResumablePullStore> =
+                    Arc::new(try_boxfuture!(SqlResumablePullStore::with_repo_type(
+                        &config.repotype,
+                        myrouter_port,
+                        "resumable_pulls"
+                    )));
+                Some(ResumablePull { params, store })
+            }
+            None => None,
+        };
+
+        let auditor = match config.audit {
+            Some(ref params) => Some(try_boxfuture!(Auditor::new(
+                params,
+                &config.repotype,
+                myrouter_port
+            ))),
+            None => None,
+        };
+
+        let response_cache = config.response_cache.map(|params| {
+            let blobstore: Option<Arc<Blobstore>> = if params.use_blobstore {
+                Some(Arc::new(blobrepo.get_blobstore()))
+            } else {
+                None
+            };
+            ResponseCache::new(
+                cachelib::get_pool("response-fragments"),
+                blobstore,
+                Duration::from_secs(params.blobstore_ttl_secs),
+            )
+        });
+
+        let webhook_dispatcher = Arc::new(try_boxfuture!(WebhookDispatcher::new(
+            config.webhooks.clone()
+        )));
+        let event_bus = try_boxfuture!(EventBus::new(config.event_bus.clone()));
+
+        let repo = MononokeRepo::new(
+            blobrepo,
+            &config.pushrebase,
+            config.bookmarks.clone(),
+            Arc::new(hook_manager),
+            streaming_clone,
+            config.lfs.clone(),
+            reponame.clone(),
+            read_write_fetcher,
+            hg_derivation_queue,
+            webhook_dispatcher,
+            event_bus,
+            maintenance_store,
+            overrides.clone(),
+            config.scratch_namespace.clone(),
+            tree_prefetch,
+            auditor,
+            response_cache,
+            resumable_pull,
+            push_quota,
+            content_refs,
+            config.wireproto_caps.clone(),
+            config.copy_info_check,
+            config.phases_admin_identities.clone(),
+        );
+
+        let listen_log = root_log.new(o!("repo" => reponame.clone()));
+        let mut scuba_logger = ScubaSampleBuilder::with_opt_table(config.scuba_table.clone());
+        scuba_logger.add_common_server_data();
+        let hash_validation_percentage = config.hash_validation_percentage.clone();
+        let wireproto_scribe_category = config.wireproto_scribe_category.clone();
+        let preserve_raw_bundle2 = config.bundle2_replay_params.preserve_raw_bundle2.clone();
+        let session_limits = config.session_limits;
+        let response_size_limits = Arc::new(config.response_size_limits.clone());
+        let qos = Arc::new(QosPools::new(&config.qos));
+        let quarantine = config
+            .quarantine
+            .map(|params| Arc::new(Quarantine::new(repoid, &params)));
+        let path_acls = try_boxfuture!(PathAcls::new(&config.path_acls));
+
+        let skiplist_refresh = config.skiplist_refresh;
+        let skiplist_key = config.skiplist_index_blobstore_key.clone();
+        let skip_index = match config.skiplist_index_blobstore_key.clone() {
+            Some(skiplist_index_blobstore_key) => {
+                let blobstore = repo.blobrepo().get_blobstore();
+                blobstore
+                    .get(ctx.clone(), skiplist_index_blobstore_key)
+                    .and_then(|maybebytes| {
+                        let map = match maybebytes {
+                            Some(bytes) => {
+                                let bytes = bytes.into_bytes();
+                                try_boxfuture!(deserialize_skiplist_map(bytes))
+                            }
+                            None => HashMap::new(),
+                        };
+                        ok(Arc::new(SkiplistIndex::new_with_skiplist_graph(map))).boxify()
+                    })
+                    .left_future()
+            }
+            None => ok(Arc::new(SkiplistIndex::new())).right_future(),
+        };
+
+        let repotype = config.repotype.clone();
+        let phases_store = Arc::new(try_boxfuture!(SqlPhases::with_repo_type(
+            &repotype,
+            myrouter_port,
+            "phases"
+        )));
+
+        // TODO (T32873881): Arc<BlobRepo> should become BlobRepo
+        let initial_warmup = ensure_myrouter_ready.and_then({
+            cloned!(ctx, reponame, listen_log, overrides);
+            let blobrepo = repo.blobrepo().clone();
+            move |()| {
+                // Serve with the overrides from the start, or with the configured
+                // tunables until the overrides can be polled
+                let initial_overrides = overrides.refresh(ctx.clone()).then({
+                    cloned!(listen_log);
+                    move |result| {
+                        if let Err(err) = result {
+                            warn!(listen_log, "failed to poll overrides: {:?}", err);
+                        }
+                        Ok(())
                     }
-                    _ => None,
-                };
-
-                let read_write_fetcher = match config.repotype {
-                    RepoType::BlobRemote {
-                        ref write_lock_db_address,
-                        ..
-                    } => RepoReadWriteFetcher::with_myrouter(
-                        config.readonly.clone(),
-                        reponame.clone(),
-                        write_lock_db_address,
-                        myrouter_port.expect("myrouter_port not provided for BlobRemote repo"),
-                    ),
-                    _ => RepoReadWriteFetcher::new(config.readonly.clone(), reponame.clone()),
-                };
-
-                let repo = MononokeRepo::new(
-                    blobrepo,
-                    &config.pushrebase,
-                    config.bookmarks.clone(),
-                    Arc::new(hook_manager),
-                    streaming_clone,
-                    config.lfs.clone(),
-                    reponame.clone(),
-                    read_write_fetcher,
-                );
+                });
+                cache_warmup(ctx, blobrepo, config.cache_warmup, listen_log)
+                    .chain_err(format!("while warming up cache for repo: {}", reponame))
+                    .from_err()
+                    .join(initial_overrides)
+                    .map(|((), ())| ())
+            }
+        });
+
+        // The skiplist doesn't depend on the warmed up caches, so load it concurrently.
+        ready_handle
+            .wait_for(
+                initial_warmup
+                    .join(skip_index)
+                    .map(|((), skip_index)| skip_index),
+            )
+            .map({
+                cloned!(root_log);
+                move |skip_index| {
+                    info!(root_log, "Repo warmup for {} complete", reponame);
+
+                    let (unload, unloaded) = oneshot::channel();
+                    let unloaded = Unloaded(unloaded.shared());
 
-                let listen_log = root_log.new(o!("repo" => reponame.clone()));
-                let mut scuba_logger =
-                    ScubaSampleBuilder::with_opt_table(config.scuba_table.clone());
-                scuba_logger.add_common_server_data();
-                let hash_validation_percentage = config.hash_validation_percentage.clone();
-                let wireproto_scribe_category = config.wireproto_scribe_category.clone();
-                let preserve_raw_bundle2 =
-                    config.bundle2_replay_params.preserve_raw_bundle2.clone();
-
-                let skip_index = match config.skiplist_index_blobstore_key.clone() {
-                    Some(skiplist_index_blobstore_key) => {
-                        let blobstore = repo.blobrepo().get_blobstore();
-                        blobstore
-                            .get(ctx.clone(), skiplist_index_blobstore_key)
-                            .and_then(|maybebytes| {
-                                let map = match maybebytes {
-                                    Some(bytes) => {
-                                        let bytes = bytes.into_bytes();
-                                        try_boxfuture!(deserialize_skiplist_map(bytes))
-                                    }
-                                    None => HashMap::new(),
-                                };
-                                ok(Arc::new(SkiplistIndex::new_with_skiplist_graph(map))).boxify()
-                            })
-                            .left_future()
+                    if let Some(replica_manager) = replica_manager {
+                        unloaded.spawn(replica_manager.reresolve_periodically().map_err({
+                            cloned!(listen_log);
+                            move |err| error!(listen_log, "Re-resolving replicas failed: {}", err)
+                        }));
                     }
-                    None => ok(Arc::new(SkiplistIndex::new())).right_future(),
-                };
-
-                let repotype = config.repotype.clone();
-
-                // TODO (T32873881): Arc<BlobRepo> should become BlobRepo
-                let initial_warmup = ensure_myrouter_ready.and_then({
-                    cloned!(ctx, reponame, listen_log);
-                    let blobrepo = repo.blobrepo().clone();
-                    move |()| {
-                        cache_warmup(ctx, blobrepo, config.cache_warmup, listen_log)
-                            .chain_err(format!("while warming up cache for repo: {}", reponame))
-                            .from_err()
+
+                    if let Some(queue) = repo.hg_derivation_queue().clone() {
+                        unloaded.spawn(run_derivation_worker(
+                            ctx.clone(),
+                            repo.blobrepo().clone(),
+                            queue,
+                            listen_log.clone(),
+                            HG_DERIVATION_POLL_INTERVAL,
+                        ));
                     }
-                });
 
-                ready_handle
-                    .wait_for(initial_warmup.and_then(|()| skip_index))
-                    .map({
-                        cloned!(root_log);
-                        move |skip_index| {
-                            info!(root_log, "Repo warmup for {} complete", reponame);
-
-                            // initialize phases hint from the skip index
-                            let phases_hint: Arc<Phases> = match repotype {
-                                RepoType::BlobFiles(ref data_dir)
-                                | RepoType::BlobRocks(ref data_dir)
-                                | RepoType::BlobSqlite(ref data_dir) => {
-                                    let storage = Arc::new(
-                                        SqlPhases::with_sqlite_path(data_dir.join("phases"))
-                                            .expect("unable to initialize sqlite db for phases"),
-                                    );
-                                    Arc::new(HintPhases::new(storage, skip_index.clone()))
-                                }
-                                RepoType::BlobRemote { ref db_address, .. } => {
-                                    let storage = Arc::new(SqlPhases::with_myrouter(
-                                        &db_address,
-                                        myrouter_port.expect(
-                                            "myrouter_port not provided for BlobRemote repo",
-                                        ),
-                                    ));
-                                    Arc::new(CachingHintPhases::new(storage, skip_index.clone()))
-                                }
-                            };
-
-                            // initialize lca hint from the skip index
-                            let lca_hint: Arc<LeastCommonAncestorsHint> = skip_index;
-
-                            (
-                                reponame,
-                                RepoHandler {
-                                    logger: listen_log,
-                                    scuba: scuba_logger,
-                                    wireproto_scribe_category,
-                                    repo,
-                                    hash_validation_percentage,
-                                    lca_hint,
-                                    phases_hint,
-                                    preserve_raw_bundle2,
-                                },
-                            )
+                    if let Some(queue) = post_commit_queue {
+                        unloaded.spawn(run_post_commit_worker(
+                            ctx.clone(),
+                            repo.hook_manager(),
+                            queue,
+                            repoid,
+                            listen_log.clone(),
+                            POST_COMMIT_POLL_INTERVAL,
+                        ));
+                    }
+
+                    unloaded.spawn(
+                        overrides.refresh_periodically(ctx.clone(), OVERRIDES_POLL_INTERVAL),
+                    );
+
+                    if let Some((sync, interval)) = pull_through_sync {
+                        unloaded.spawn(sync_periodically(
+                            ctx.clone(),
+                            sync,
+                            interval,
+                            listen_log.clone(),
+                        ));
+                    }
+
+                    if let Some(params) = skiplist_refresh {
+                        unloaded.spawn(index_new_heads_periodically(
+                            ctx.clone(),
+                            repo.blobrepo().clone(),
+                            skip_index.clone(),
+                            params,
+                            listen_log.clone(),
+                        ));
+                        if let (Some(key), Some(interval_secs)) =
+                            (skiplist_key, params.snapshot_interval_secs)
+                        {
+                            unloaded.spawn(store_snapshots_periodically(
+                                ctx,
+                                repo.blobrepo().clone(),
+                                skip_index.clone(),
+                                key,
+                                Duration::from_secs(interval_secs),
+                                listen_log.clone(),
+                            ));
                         }
-                    })
-                    .boxify()
+                    }
+
+                    // initialize phases hint from the skip index
+                    let phases_hint: Arc<Phases> = match repotype {
+                        RepoType::BlobRemote { .. } => {
+                            Arc::new(CachingHintPhases::new(phases_store, skip_index.clone()))
+                        }
+                        _ => Arc::new(HintPhases::new(phases_store, skip_index.clone())),
+                    };
+
+                    // initialize lca hint from the skip index
+                    let lca_hint: Arc<LeastCommonAncestorsHint> = skip_index;
+
+                    LoadedRepo {
+                        handler: RepoHandler {
+                            logger: listen_log,
+                            scuba: scuba_logger,
+                            wireproto_scribe_category,
+                            repo,
+                            hash_validation_percentage,
+                            lca_hint,
+                            phases_hint,
+                            preserve_raw_bundle2,
+                            session_limits,
+                            response_size_limits,
+                            qos,
+                            quarantine,
+                            path_acls,
+                            server_tier,
+                            time_uuid: Arc::new(SystemTimeUuidProvider),
+                        },
+                        unload,
+                    }
+                }
             })
             .boxify()
-        })
-        .collect();
-
-    future::join_all(repos)
-        .map(|repos| repos.into_iter().collect())
-        .boxify()
+    })
+    .boxify()
 }
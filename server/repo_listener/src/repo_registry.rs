@@ -0,0 +1,292 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The repos a server serves, by name.
+//!
+//! The enabled repos of the configs are loaded when the server starts, and repos can be loaded,
+//! drained and unloaded while it runs: a repo is added without restarting the server, and a repo
+//! that misbehaves is taken out without dropping the sessions of the others. A drained repo
+//! refuses new sessions and serves its open ones until they end. Unloading a repo drains it, then
+//! removes it once it has no sessions left, which stops its background tasks.
+//!
+//! Repos don't share the resources their sessions are served with: each repo caps its sessions
+//! with its session limits, queues its requests in its own QoS pools with their own timeouts, and
+//! has its own caches.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+use tokio_timer;
+use tracing::TraceContext;
+
+use context::CoreContext;
+use metaconfig_parser::RepoConfigs;
+use metaconfig_types::RepoReadOnly;
+use ready_state::ReadyStateBuilder;
+use scuba_ext::ScubaSampleBuilder;
+use sshrelay::SshEnvVars;
+
+use errors::*;
+use repo_handlers::{repo_handler, LoadedRepo, RepoHandler};
+
+/// How often a repo that is being unloaded is checked for open sessions
+const UNLOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RepoState {
+    /// New sessions are accepted
+    Serving,
+    /// New sessions are refused, open ones are served until they end
+    Draining,
+}
+
+impl fmt::Display for RepoState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RepoState::Serving => write!(f, "serving"),
+            RepoState::Draining => write!(f, "draining"),
+        }
+    }
+}
+
+struct RegisteredRepo {
+    repo: LoadedRepo,
+    state: RepoState,
+    sessions: Arc<AtomicUsize>,
+}
+
+impl RegisteredRepo {
+    fn new(repo: LoadedRepo) -> Self {
+        Self {
+            repo,
+            state: RepoState::Serving,
+            sessions: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+struct Repos {
+    loaded: HashMap<String, RegisteredRepo>,
+    /// Repos that are being opened and warmed up, which can't be loaded again meanwhile
+    loading: HashSet<String>,
+}
+
+/// A session of a repo, counted as open until it's dropped
+pub struct Session {
+    pub handler: RepoHandler,
+    sessions: Arc<AtomicUsize>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The repos of a server. Clones share the same repos.
+#[derive(Clone)]
+pub struct RepoRegistry {
+    repos: Arc<Mutex<Repos>>,
+    /// Where the configs of repos that are loaded at runtime are read from
+    config_path: PathBuf,
+    myrouter_port: Option<u16>,
+    server_tier: Option<String>,
+    root_log: Logger,
+}
+
+impl RepoRegistry {
+    pub fn new(
+        loaded: HashMap<String, LoadedRepo>,
+        config_path: PathBuf,
+        myrouter_port: Option<u16>,
+        server_tier: Option<String>,
+        root_log: Logger,
+    ) -> Self {
+        let loaded = loaded
+            .into_iter()
+            .map(|(reponame, repo)| (reponame, RegisteredRepo::new(repo)))
+            .collect();
+        Self {
+            repos: Arc::new(Mutex::new(Repos {
+                loaded,
+                loading: HashSet::new(),
+            })),
+            config_path,
+            myrouter_port,
+            server_tier,
+            root_log,
+        }
+    }
+
+    /// Opens a session of repo `reponame`, unless it isn't serving or already serves its maximum
+    /// of sessions
+    pub fn open_session(&self, reponame: &str) -> Result<Session> {
+        let repos = self.repos.lock().expect("lock poisoned");
+        let repo = repos
+            .loaded
+            .get(reponame)
+            .ok_or_else(|| ErrorKind::UnknownRepo(reponame.to_string()))?;
+        if repo.state == RepoState::Draining {
+            return Err(ErrorKind::RepoDraining(reponame.to_string()).into());
+        }
+        if let Some(max_sessions) = repo.repo.handler.session_limits.max_sessions {
+            if repo.sessions.load(Ordering::Relaxed) >= max_sessions {
+                return Err(ErrorKind::TooManySessions(reponame.to_string(), max_sessions).into());
+            }
+        }
+        repo.sessions.fetch_add(1, Ordering::Relaxed);
+        Ok(Session {
+            handler: repo.repo.handler.clone(),
+            sessions: repo.sessions.clone(),
+        })
+    }
+
+    /// The loaded repos, with their state and number of open sessions, sorted by name
+    pub fn list(&self) -> Vec<(String, RepoState, usize)> {
+        let repos = self.repos.lock().expect("lock poisoned");
+        let mut list: Vec<_> = repos
+            .loaded
+            .iter()
+            .map(|(reponame, repo)| {
+                (
+                    reponame.clone(),
+                    repo.state,
+                    repo.sessions.load(Ordering::Relaxed),
+                )
+            })
+            .collect();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list
+    }
+
+    /// Loads repo `reponame` with its current config, which is read again for it. The repo
+    /// serves sessions once it's warmed up.
+    pub fn load(&self, reponame: String) -> BoxFuture<(), Error> {
+        let mut configs = try_boxfuture!(RepoConfigs::read_configs(&self.config_path));
+        let config = try_boxfuture!(configs
+            .repos
+            .remove(&reponame)
+            .ok_or_else(|| ErrorKind::UnknownRepo(reponame.clone())));
+        if !config.enabled {
+            return future::err(ErrorKind::RepoNotEnabled(reponame).into()).boxify();
+        }
+        {
+            let mut repos = self.repos.lock().expect("lock poisoned");
+            if repos.loaded.contains_key(&reponame) || !repos.loading.insert(reponame.clone()) {
+                return future::err(ErrorKind::RepoAlreadyLoaded(reponame).into()).boxify();
+            }
+        }
+
+        info!(self.root_log, "Loading repo {}", reponame);
+        // Repos loaded at runtime don't hold back the readiness of the server
+        let ready_handle = ReadyStateBuilder::new().create_handle(reponame.as_str());
+        let repos = self.repos.clone();
+        repo_handler(
+            reponame.clone(),
+            config,
+            self.myrouter_port,
+            self.server_tier.clone(),
+            &self.root_log,
+            ready_handle,
+        )
+        .then(move |result| {
+            let mut repos = repos.lock().expect("lock poisoned");
+            repos.loading.remove(&reponame);
+            repos.loaded.insert(reponame, RegisteredRepo::new(result?));
+            Ok(())
+        })
+        .boxify()
+    }
+
+    /// Refuses new sessions of repo `reponame`, while its open sessions are served until they end
+    pub fn drain(&self, reponame: &str) -> Result<()> {
+        let mut repos = self.repos.lock().expect("lock poisoned");
+        let repo = repos
+            .loaded
+            .get_mut(reponame)
+            .ok_or_else(|| ErrorKind::UnknownRepo(reponame.to_string()))?;
+        if repo.state != RepoState::Draining {
+            info!(self.root_log, "Draining repo {}", reponame);
+            repo.state = RepoState::Draining;
+        }
+        Ok(())
+    }
+
+    /// Locks repo `reponame` for writes, or unlocks it. The lock state is stored in the database
+    /// it's read from, so every server of the repo sees the change.
+    pub fn set_readonly(&self, reponame: &str, readonly: RepoReadOnly) -> BoxFuture<(), Error> {
+        let handler = {
+            let repos = self.repos.lock().expect("lock poisoned");
+            match repos.loaded.get(reponame) {
+                Some(repo) => repo.repo.handler.clone(),
+                None => {
+                    return future::err(ErrorKind::UnknownRepo(reponame.to_string()).into())
+                        .boxify();
+                }
+            }
+        };
+        info!(self.root_log, "Setting repo {} to {:?}", reponame, readonly);
+
+        let session_uuid = handler.time_uuid.new_uuid();
+        let ctx = CoreContext::new(
+            session_uuid,
+            self.root_log.clone(),
+            ScubaSampleBuilder::with_discard(),
+            None,
+            TraceContext::new(session_uuid, Instant::now()),
+            None,
+            SshEnvVars::default(),
+        )
+        .with_time_uuid_provider(handler.time_uuid);
+        handler.repo.set_readonly(ctx, readonly)
+    }
+
+    /// Drains repo `reponame`, and unloads it once all its sessions ended
+    pub fn unload(&self, reponame: String) -> BoxFuture<(), Error> {
+        try_boxfuture!(self.drain(&reponame));
+        let sessions = {
+            let repos = self.repos.lock().expect("lock poisoned");
+            match repos.loaded.get(&reponame) {
+                Some(repo) => repo.sessions.clone(),
+                None => return future::err(ErrorKind::UnknownRepo(reponame).into()).boxify(),
+            }
+        };
+
+        let repos = self.repos.clone();
+        let root_log = self.root_log.clone();
+        stream::repeat(())
+            .take_while({
+                cloned!(sessions);
+                move |()| Ok(sessions.load(Ordering::Relaxed) != 0)
+            })
+            .for_each(|()| tokio_timer::sleep(UNLOAD_POLL_INTERVAL).from_err())
+            .map(move |()| {
+                let mut repos = repos.lock().expect("lock poisoned");
+                // Another unload may have removed the repo while this one waited, and the repo
+                // may have been loaded again since: only the repo that was drained is removed
+                let drained = match repos.loaded.get(&reponame) {
+                    Some(repo) => Arc::ptr_eq(&repo.sessions, &sessions),
+                    None => false,
+                };
+                if drained {
+                    if let Some(repo) = repos.loaded.remove(&reponame) {
+                        repo.repo.unload();
+                        info!(root_log, "Unloaded repo {}", reponame);
+                    }
+                }
+            })
+            .boxify()
+    }
+}
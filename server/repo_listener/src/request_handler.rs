@@ -9,8 +9,10 @@ use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
+use bytes::Bytes;
 use failure::{prelude::*, SlogKVError};
 use futures::{Future, Sink, Stream};
+use futures_ext::FutureExt;
 use futures_stats::Timed;
 use slog::{self, Drain, Level, Logger};
 use slog_ext::SimpleFormatWithError;
@@ -22,7 +24,7 @@ use tracing::{TraceContext, Traced};
 use uuid::Uuid;
 
 use hgproto::{sshproto, HgProtoHandler};
-use repo_client::RepoClient;
+use repo_client::{limit_session, RepoClient, SessionActivity};
 use scuba_ext::ScubaSampleBuilderExt;
 use sshrelay::{SenderBytesWrite, SshEnvVars, Stdio};
 
@@ -37,6 +39,9 @@ define_stats! {
         histogram(500, 0, 100_000, AVG, SUM, COUNT; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
 }
 
+/// Sent on stderr of sessions that have been quiet for a keepalive interval
+const KEEPALIVE_MESSAGE: &[u8] = b"keepalive\n";
+
 pub fn request_handler(
     RepoHandler {
         logger,
@@ -47,12 +52,18 @@ pub fn request_handler(
         lca_hint,
         phases_hint,
         preserve_raw_bundle2,
+        session_limits,
+        response_size_limits,
+        qos,
+        quarantine,
+        path_acls,
+        server_tier,
+        time_uuid,
     }: RepoHandler,
     stdio: Stdio,
     addr: SocketAddr,
     hook_manager: Arc<HookManager>,
 ) -> impl Future<Item = (), Error = ()> {
-    let mut scuba_logger = scuba;
     let Stdio {
         stdin,
         stdout,
@@ -67,7 +78,7 @@ pub fn request_handler(
     {
         Some(session_uuid) => session_uuid,
         None => {
-            let session_uuid = Uuid::new_v4();
+            let session_uuid = time_uuid.new_uuid();
             preamble
                 .misc
                 .insert("session_uuid".to_owned(), format!("{}", session_uuid));
@@ -75,6 +86,25 @@ pub fn request_handler(
         }
     };
 
+    // Requests and responses both count as activity of the session
+    let activity = SessionActivity::new();
+    let stdin = stdin.inspect({
+        cloned!(activity);
+        move |_| activity.touch()
+    });
+    // Keepalives are lines on stderr, which clients print as remote output
+    let keepalive = {
+        cloned!(stderr);
+        move || {
+            stderr
+                .clone()
+                .send(Bytes::from_static(KEEPALIVE_MESSAGE))
+                .map(|_| ())
+                .from_err()
+                .boxify()
+        }
+    };
+
     // Info per wireproto command within this session
     let wireproto_calls = Arc::new(Mutex::new(Vec::new()));
     let trace = TraceContext::new(session_uuid, Instant::now());
@@ -105,24 +135,37 @@ pub fn request_handler(
         Logger::root(drain, o!("session_uuid" => format!("{}", session_uuid)))
     };
 
-    let mut scuba_logger = {
-        scuba_logger
-            .add_preamble(&preamble)
-            .add("client_ip", addr.to_string());
-        scuba_logger
-    };
-
-    scuba_logger.log_with_msg("Connection established", None);
-
     let ctx = CoreContext::new(
         session_uuid,
         conn_log,
-        scuba_logger.clone(),
+        scuba,
         wireproto_scribe_category,
         trace.clone(),
         preamble.misc.get("unix_username").cloned(),
         SshEnvVars::from_map(&preamble.misc),
+    )
+    .with_time_uuid_provider(time_uuid);
+
+    // Requests of automation are batch ones by default
+    let identities = ctx.user_identities();
+    let identities = || identities.iter().map(|identity| identity.as_str());
+    let qos_class = qos.classify(
+        preamble.misc.get("qos").map(|qos| qos.as_str()),
+        identities(),
     );
+    let qos_pool = qos.pool(qos_class);
+    let path_access = path_acls.access(identities());
+
+    let ctx = ctx.with_scuba_initialization(|mut scuba| {
+        scuba
+            .add_preamble(&preamble)
+            .add("client_ip", addr.to_string())
+            .add("qos_class", qos_pool.class_name());
+        scuba
+    });
+    let mut scuba_logger = ctx.scuba().clone();
+
+    scuba_logger.log_with_msg("Connection established", None);
 
     // Construct a hg protocol handler
     let proto_handler = HgProtoHandler::new(
@@ -135,18 +178,29 @@ pub fn request_handler(
             lca_hint,
             phases_hint,
             preserve_raw_bundle2,
+            response_size_limits,
+            qos_pool,
+            quarantine,
+            path_access,
+            server_tier,
         ),
         sshproto::HgSshCommandDecode,
         sshproto::HgSshCommandEncode,
         wireproto_calls.clone(),
+        activity.in_flight_requests(),
         hook_manager,
     );
 
     // send responses back
     let endres = proto_handler
         .map_err(Error::from)
+        .inspect({
+            cloned!(activity);
+            move |_| activity.touch()
+        })
         .forward(stdout)
         .map(|_| ());
+    let endres = limit_session(endres, activity, &session_limits, keepalive);
 
     // If we got an error at this point, then catch it and print a message
     endres
@@ -0,0 +1,150 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Refresh of the skiplist index that a server loads at startup. Commits that land after the
+//! index was built aren't in it, so least common ancestor queries about them walk the graph
+//! commit by commit. The commits that bookmarks move to are indexed as the bookmarks are
+//! polled, and the index may be stored back periodically for the servers that start later.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::prelude::*;
+use futures::{stream, Future, Stream};
+use slog::Logger;
+use stats::Timeseries;
+use tokio::timer::Interval;
+
+use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use context::CoreContext;
+use metaconfig_types::SkiplistRefreshParams;
+use mononoke_types::BlobstoreBytes;
+use skiplist::SkiplistIndex;
+
+define_stats! {
+    prefix = "mononoke.skiplist_refresh";
+    indexed_heads: timeseries(RATE, SUM),
+    index_errors: timeseries(RATE, SUM),
+    snapshots: timeseries(RATE, SUM),
+    snapshot_errors: timeseries(RATE, SUM),
+}
+
+/// Index the heads of `repo` that `skip_index` doesn't know about yet. Returns how many there
+/// were.
+fn index_new_heads(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    skip_index: Arc<SkiplistIndex>,
+    max_index_depth: u64,
+) -> impl Future<Item = usize, Error = Error> {
+    let changeset_fetcher = repo.get_changeset_fetcher_for("skiplist");
+    repo.get_bonsai_heads_maybe_stale(ctx.clone())
+        .filter({
+            cloned!(skip_index);
+            move |head| !skip_index.is_node_indexed(*head)
+        })
+        .collect()
+        .and_then(move |heads| {
+            let count = heads.len();
+            // One head at a time, so that the commits they share are only indexed once
+            stream::iter_ok(heads)
+                .for_each(move |head| {
+                    skip_index.add_node(
+                        ctx.clone(),
+                        changeset_fetcher.clone(),
+                        head,
+                        max_index_depth,
+                    )
+                })
+                .map(move |()| count)
+        })
+}
+
+/// Index the commits that the bookmarks of `repo` move to, as configured by `params`. Runs for
+/// as long as the server does.
+pub fn index_new_heads_periodically(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    skip_index: Arc<SkiplistIndex>,
+    params: SkiplistRefreshParams,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()> {
+    let interval = Duration::from_secs(params.bookmark_poll_interval_secs);
+    Interval::new(Instant::now() + interval, interval)
+        .map_err({
+            cloned!(logger);
+            move |err| error!(logger, "skiplist refresh timer failed: {}", err)
+        })
+        .for_each(move |_| {
+            index_new_heads(
+                ctx.clone(),
+                repo.clone(),
+                skip_index.clone(),
+                params.max_index_depth,
+            )
+            .then({
+                cloned!(logger);
+                move |result| {
+                    match result {
+                        Ok(0) => {}
+                        Ok(count) => {
+                            STATS::indexed_heads.add_value(count as i64);
+                            debug!(logger, "indexed {} new heads in the skiplist", count);
+                        }
+                        Err(err) => {
+                            STATS::index_errors.add_value(1);
+                            warn!(
+                                logger,
+                                "failed to index new heads in the skiplist: {:?}", err
+                            );
+                        }
+                    }
+                    Ok(())
+                }
+            })
+        })
+}
+
+/// Store `skip_index` under `key` in the blobstore of `repo` every `interval`, where servers
+/// load it from when they start. Runs for as long as the server does.
+pub fn store_snapshots_periodically(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    skip_index: Arc<SkiplistIndex>,
+    key: String,
+    interval: Duration,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()> {
+    let blobstore = repo.get_blobstore();
+    Interval::new(Instant::now() + interval, interval)
+        .map_err({
+            cloned!(logger);
+            move |err| error!(logger, "skiplist snapshot timer failed: {}", err)
+        })
+        .for_each(move |_| {
+            let bytes = skip_index.serialize();
+            let size = bytes.len();
+            blobstore
+                .put(ctx.clone(), key.clone(), BlobstoreBytes::from_bytes(bytes))
+                .then({
+                    cloned!(logger);
+                    move |result| {
+                        match result {
+                            Ok(()) => {
+                                STATS::snapshots.add_value(1);
+                                info!(logger, "stored a skiplist snapshot of {} bytes", size);
+                            }
+                            Err(err) => {
+                                STATS::snapshot_errors.add_value(1);
+                                warn!(logger, "failed to store a skiplist snapshot: {:?}", err);
+                            }
+                        }
+                        Ok(())
+                    }
+                })
+        })
+}
@@ -40,9 +40,11 @@ extern crate repo_listener;
 mod monitoring;
 
 use clap::{App, ArgMatches};
+use cmdlib::startup::{check_myrouter_port, StartupErrors, EXIT_RUNTIME_ERROR};
 use failure::SlogKVError;
 use futures::Future;
 use metaconfig_parser::RepoConfigs;
+use openssl::ssl::SslAcceptor;
 use slog::{Drain, Level, Logger};
 use slog_glog_fmt::{kv_categorizer, kv_defaults, GlogFormat};
 use slog_logview::LogViewDrain;
@@ -69,9 +71,12 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
             <cpath>      -P, --config_path [PATH]           'path to the config files'
 
                           --listening-host-port <PATH>           'tcp address to listen to in format `host:port`'
+                          --admin-host-port [PATH]               'loopback tcp address to listen to for commands that load, drain and unload repos, in format `host:port`'
 
             -p, --thrift_port [PORT] 'if provided the thrift server will start on this port'
 
+                          --tier-name [NAME]                    'tier of the server, reported to the clients that ask for it'
+
             <cert>        --cert [PATH]                         'path to a file with certificate'
             <private_key> --private-key [PATH]                  'path to a file with private key'
             <ca_pem>      --ca-pem [PATH]                       'path to a file with CA certificate'
@@ -111,10 +116,34 @@ fn setup_logger<'a>(matches: &ArgMatches<'a>) -> Logger {
     )
 }
 
+fn get_config_path<'a>(matches: &ArgMatches<'a>) -> PathBuf {
+    PathBuf::from(matches.value_of("cpath").unwrap())
+}
+
 fn get_config<'a>(matches: &ArgMatches<'a>) -> Result<RepoConfigs> {
     // TODO: This needs to cope with blob repos, too
-    let cpath = PathBuf::from(matches.value_of("cpath").unwrap());
-    RepoConfigs::read_configs(cpath)
+    RepoConfigs::read_configs(get_config_path(matches))
+}
+
+fn build_tls_acceptor<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<SslAcceptor> {
+    let cert = matches.value_of("cert").unwrap().to_string();
+    let private_key = matches.value_of("private_key").unwrap().to_string();
+    let ca_pem = matches.value_of("ca_pem").unwrap().to_string();
+    let ticket_seed = matches
+        .value_of("ssl-ticket-seeds")
+        .unwrap_or(secure_utils::fb_tls::SEED_PATH)
+        .to_string();
+
+    let ssl = secure_utils::SslConfig {
+        cert,
+        private_key,
+        ca_pem,
+    };
+
+    let acceptor = secure_utils::build_tls_acceptor_builder(ssl.clone())?;
+    let acceptor =
+        secure_utils::fb_tls::tls_acceptor_builder(logger.clone(), ssl, acceptor, ticket_seed)?;
+    Ok(acceptor.build())
 }
 
 fn main() {
@@ -122,51 +151,60 @@ fn main() {
     let root_log = setup_logger(&matches);
 
     panichandler::set_panichandler(panichandler::Fate::Abort);
+    // Must come after the panic handler, which aborts the process
+    repo_listener::install_quarantine_panic_hook();
 
     cmdlib::args::init_cachelib(&matches);
 
     fn run_server<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<!> {
         info!(root_log, "Starting up");
 
-        let stats_aggregation = stats::schedule_stats_aggregation()
-            .expect("failed to create stats aggregation scheduler");
-
-        let mut runtime = Runtime::new()?;
-
-        let config = get_config(&matches)?;
-        let cert = matches.value_of("cert").unwrap().to_string();
-        let private_key = matches.value_of("private_key").unwrap().to_string();
-        let ca_pem = matches.value_of("ca_pem").unwrap().to_string();
-        let ticket_seed = matches
-            .value_of("ssl-ticket-seeds")
-            .unwrap_or(secure_utils::fb_tls::SEED_PATH)
-            .to_string();
+        let mut errors = StartupErrors::new();
 
-        let ssl = secure_utils::SslConfig {
-            cert,
-            private_key,
-            ca_pem,
-        };
-
-        let myrouter_port = cmdlib::args::parse_myrouter_port(&matches);
+        let config = errors.config("reading the repo configs", get_config(&matches));
+        let myrouter_port = errors.config(
+            "parsing the myrouter port",
+            cmdlib::args::try_parse_myrouter_port(&matches),
+        );
+        if let (Some(config), Some(myrouter_port)) = (&config, myrouter_port) {
+            errors.config(
+                "checking the myrouter port",
+                check_myrouter_port(config, myrouter_port),
+            );
+        }
+        let stats_aggregation = errors.init(
+            "scheduling stats aggregation",
+            stats::schedule_stats_aggregation(),
+        );
+        let runtime = errors.init("creating the tokio runtime", Runtime::new());
+        let acceptor = errors.init(
+            "building the tls acceptor",
+            build_tls_acceptor(root_log, &matches),
+        );
 
-        let mut acceptor = secure_utils::build_tls_acceptor_builder(ssl.clone())
-            .expect("failed to build tls acceptor");
-        acceptor = secure_utils::fb_tls::tls_acceptor_builder(
-            root_log.clone(),
-            ssl.clone(),
-            acceptor,
-            ticket_seed,
-        ).expect("failed to build fb_tls acceptor");
+        let (config, myrouter_port, stats_aggregation, mut runtime, acceptor) =
+            match (config, myrouter_port, stats_aggregation, runtime, acceptor) {
+                (
+                    Some(config),
+                    Some(myrouter_port),
+                    Some(stats_aggregation),
+                    Some(runtime),
+                    Some(acceptor),
+                ) => (config, myrouter_port, stats_aggregation, runtime, acceptor),
+                _ => errors.exit(root_log),
+            };
 
         let (repo_listeners, ready) = repo_listener::create_repo_listeners(
             config.repos.into_iter(),
+            get_config_path(&matches),
             myrouter_port,
+            matches.value_of("tier-name").map(|tier| tier.to_string()),
             root_log,
             matches
                 .value_of("listening-host-port")
                 .expect("listening path must be specified"),
-            acceptor.build(),
+            matches.value_of("admin-host-port"),
+            acceptor,
             &TERMINATE_PROCESS,
         );
 
@@ -199,7 +237,7 @@ fn main() {
         Ok(_) => panic!("unexpected success"),
         Err(e) => {
             crit!(root_log, "Server fatal error"; SlogKVError(e));
-            std::process::exit(1);
+            std::process::exit(EXIT_RUNTIME_ERROR);
         }
     }
 }
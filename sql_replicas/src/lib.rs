@@ -0,0 +1,474 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Failover of reads between replicas of the SQL database of a repo.
+//!
+//! Every store (changesets, filenodes...) is connected to the primary database and to all its
+//! warm standby replicas upfront, so that failing over doesn't need new connections. Reads go to
+//! the first healthy replica, and fail over to the next ones when they fail transiently; a
+//! replica whose reads failed too many times in a row is unhealthy, and is only used when all
+//! others are. Reads that fail for other reasons, e.g. because of inconsistent data, would fail
+//! the same way on every replica and aren't retried.
+//! Writes always go to the primary.
+//!
+//! Connections to the replicas are rebuilt periodically, which picks up endpoints that moved and
+//! gives unhealthy replicas another chance.
+//!
+//! Like fault injection, failover wraps the traits of the stores rather than their connections:
+//! connections are opaque, and a store operation is the unit that can be retried elsewhere.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate stats;
+
+mod stores;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use cloned::cloned;
+use failure_ext::Error;
+use futures::future::{self, loop_fn, Loop};
+use futures::{Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use metaconfig_types::ReadReplicaParams;
+use mononoke_errors::{Categorizer, ErrorCategory};
+use stats::Timeseries;
+use tokio::timer::Interval;
+
+pub use crate::stores::{ReplicatedBonsaiHgMapping, ReplicatedChangesets, ReplicatedFilenodes};
+
+define_stats_struct! {
+    ReplicaStats("mononoke.sql_replicas.{}", name: String),
+    failovers: timeseries(RATE, SUM),
+    marked_unhealthy: timeseries(RATE, SUM),
+    reresolves: timeseries(RATE, SUM),
+}
+
+/// The state of one replica of a store
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplicaStatus {
+    pub address: String,
+    /// Whether this is the primary database, which writes go to
+    pub primary: bool,
+    pub healthy: bool,
+    /// Number of reads in a row that failed
+    pub consecutive_failures: usize,
+    /// The error of the last failed read, even if reads succeeded since
+    pub last_error: Option<String>,
+}
+
+struct Replica<T: ?Sized> {
+    address: String,
+    store: Arc<T>,
+    consecutive_failures: usize,
+    last_error: Option<String>,
+}
+
+/// A store connected to all the replicas of the database, the primary being the first one
+pub struct ReplicaSet<T: ?Sized> {
+    failure_threshold: usize,
+    connect: Box<Fn(&str) -> Arc<T> + Send + Sync>,
+    replicas: Arc<RwLock<Vec<Replica<T>>>>,
+    stats: Arc<ReplicaStats>,
+    categorizer: Categorizer,
+}
+
+impl<T: ?Sized + Send + Sync + 'static> ReplicaSet<T> {
+    /// `connect` creates the store for a replica, given its address
+    pub fn new<C>(name: &str, addresses: &[String], failure_threshold: usize, connect: C) -> Self
+    where
+        C: Fn(&str) -> Arc<T> + Send + Sync + 'static,
+    {
+        assert!(!addresses.is_empty(), "a replica set needs a primary");
+        let replicas = addresses
+            .iter()
+            .map(|address| Replica {
+                address: address.clone(),
+                store: connect(address),
+                consecutive_failures: 0,
+                last_error: None,
+            })
+            .collect();
+
+        Self {
+            failure_threshold,
+            connect: Box::new(connect),
+            replicas: Arc::new(RwLock::new(replicas)),
+            stats: Arc::new(ReplicaStats::new(name.to_string())),
+            // Errors of the SQL client aren't typed, and are mostly about connections
+            categorizer: Categorizer::new(ErrorCategory::Transient)
+                .with::<changesets::ErrorKind>()
+                .with::<bonsai_hg_mapping::ErrorKind>(),
+        }
+    }
+
+    /// The store connected to the primary, for writes
+    pub fn primary(&self) -> Arc<T> {
+        self.replicas.read().expect("lock poisoned")[0]
+            .store
+            .clone()
+    }
+
+    /// Run `read` on the healthy replicas in order until it succeeds, then on the unhealthy ones.
+    /// Fails with the error of the last replica if it fails on all of them, or as soon as it fails
+    /// with an error that isn't transient.
+    pub fn read<R, F>(&self, read: F) -> BoxFuture<R, Error>
+    where
+        R: Send + 'static,
+        F: Fn(Arc<T>) -> BoxFuture<R, Error> + Send + 'static,
+    {
+        let candidates = self.candidates();
+        cloned!(self.replicas, self.stats, self.categorizer);
+        let failure_threshold = self.failure_threshold;
+
+        loop_fn(
+            (candidates.into_iter(), None),
+            move |(mut candidates, last_error): (_, Option<Error>)| match candidates.next() {
+                None => {
+                    future::err(last_error.expect("replica sets are never empty")).left_future()
+                }
+                Some((index, store)) => {
+                    if last_error.is_some() {
+                        stats.failovers.add_value(1);
+                    }
+                    cloned!(replicas, stats, categorizer);
+                    read(store)
+                        .then(move |res| {
+                            let mut replicas = replicas.write().expect("lock poisoned");
+                            let replica = &mut replicas[index];
+                            match res {
+                                Ok(res) => {
+                                    replica.consecutive_failures = 0;
+                                    Ok(Loop::Break(res))
+                                }
+                                Err(err) if !categorizer.is_transient(&err) => Err(err),
+                                Err(err) => {
+                                    replica.consecutive_failures += 1;
+                                    replica.last_error = Some(err.to_string());
+                                    if replica.consecutive_failures == failure_threshold {
+                                        stats.marked_unhealthy.add_value(1);
+                                    }
+                                    Ok(Loop::Continue((candidates, Some(err))))
+                                }
+                            }
+                        })
+                        .right_future()
+                }
+            },
+        )
+        .boxify()
+    }
+
+    // Indexes and stores of the replicas, healthy ones first
+    fn candidates(&self) -> Vec<(usize, Arc<T>)> {
+        let replicas = self.replicas.read().expect("lock poisoned");
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = replicas
+            .iter()
+            .enumerate()
+            .partition(|(_, replica)| replica.consecutive_failures < self.failure_threshold);
+        healthy
+            .into_iter()
+            .chain(unhealthy)
+            .map(|(index, replica)| (index, replica.store.clone()))
+            .collect()
+    }
+}
+
+/// A replica set of any store, as seen by the `ReplicaManager`
+trait ManagedReplicaSet: Send + Sync {
+    fn topology(&self) -> Vec<ReplicaStatus>;
+
+    fn reresolve(&self);
+}
+
+impl<T: ?Sized + Send + Sync + 'static> ManagedReplicaSet for ReplicaSet<T> {
+    fn topology(&self) -> Vec<ReplicaStatus> {
+        self.replicas
+            .read()
+            .expect("lock poisoned")
+            .iter()
+            .enumerate()
+            .map(|(index, replica)| ReplicaStatus {
+                address: replica.address.clone(),
+                primary: index == 0,
+                healthy: replica.consecutive_failures < self.failure_threshold,
+                consecutive_failures: replica.consecutive_failures,
+                last_error: replica.last_error.clone(),
+            })
+            .collect()
+    }
+
+    fn reresolve(&self) {
+        self.stats.reresolves.add_value(1);
+        // Connect outside of the lock, reads keep using the old stores meanwhile
+        let addresses: Vec<_> = self
+            .replicas
+            .read()
+            .expect("lock poisoned")
+            .iter()
+            .map(|replica| replica.address.clone())
+            .collect();
+        let stores: Vec<_> = addresses
+            .iter()
+            .map(|address| (self.connect)(address))
+            .collect();
+
+        let mut replicas = self.replicas.write().expect("lock poisoned");
+        for (replica, store) in replicas.iter_mut().zip(stores) {
+            replica.store = store;
+            replica.consecutive_failures = 0;
+        }
+    }
+}
+
+/// All the replica sets of a repo, i.e. the stores connected to its primary database and to its
+/// standby replicas
+pub struct ReplicaManager {
+    addresses: Vec<String>,
+    failure_threshold: usize,
+    reresolve_interval: Duration,
+    sets: BTreeMap<String, Arc<ManagedReplicaSet>>,
+}
+
+impl ReplicaManager {
+    pub fn new(db_address: String, params: &ReadReplicaParams) -> Self {
+        let mut addresses = vec![db_address];
+        addresses.extend(params.standby_db_addresses.iter().cloned());
+        Self {
+            addresses,
+            failure_threshold: params.failure_threshold,
+            reresolve_interval: Duration::from_secs(params.reresolve_interval_secs),
+            sets: BTreeMap::new(),
+        }
+    }
+
+    /// Connect the store called `name` to all replicas, `connect` creating the store for one
+    /// replica given its address
+    pub fn replica_set<T, C>(&mut self, name: &str, connect: C) -> Arc<ReplicaSet<T>>
+    where
+        T: ?Sized + Send + Sync + 'static,
+        C: Fn(&str) -> Arc<T> + Send + Sync + 'static,
+    {
+        let set = Arc::new(ReplicaSet::new(
+            name,
+            &self.addresses,
+            self.failure_threshold,
+            connect,
+        ));
+        self.sets.insert(name.to_string(), set.clone());
+        set
+    }
+
+    /// The state of the replicas of every store, by store name
+    pub fn topology(&self) -> BTreeMap<String, Vec<ReplicaStatus>> {
+        self.sets
+            .iter()
+            .map(|(name, set)| (name.clone(), set.topology()))
+            .collect()
+    }
+
+    /// Rebuild the connections to all replicas of all stores, and consider them all healthy
+    /// again
+    pub fn reresolve(&self) {
+        for set in self.sets.values() {
+            set.reresolve();
+        }
+    }
+
+    /// Rebuild the connections every re-resolve interval. Never resolves successfully
+    pub fn reresolve_periodically(&self) -> BoxFuture<(), Error> {
+        let sets: Vec<_> = self.sets.values().cloned().collect();
+        Interval::new(
+            Instant::now() + self.reresolve_interval,
+            self.reresolve_interval,
+        )
+        .from_err()
+        .for_each(move |_| {
+            for set in &sets {
+                set.reresolve();
+            }
+            Ok(())
+        })
+        .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use failure_ext::err_msg;
+
+    #[derive(Clone, Default)]
+    struct Cluster {
+        down: Arc<Mutex<HashSet<String>>>,
+        reads: Arc<Mutex<Vec<String>>>,
+        connects: Arc<AtomicUsize>,
+    }
+
+    impl Cluster {
+        fn set_down(&self, address: &str, down: bool) {
+            let mut set = self.down.lock().unwrap();
+            if down {
+                set.insert(address.to_string());
+            } else {
+                set.remove(address);
+            }
+        }
+
+        fn take_reads(&self) -> Vec<String> {
+            self.reads.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    struct FakeStore {
+        address: String,
+        cluster: Cluster,
+    }
+
+    impl FakeStore {
+        fn get(&self) -> BoxFuture<String, Error> {
+            self.cluster
+                .reads
+                .lock()
+                .unwrap()
+                .push(self.address.clone());
+            if self.cluster.down.lock().unwrap().contains(&self.address) {
+                future::err(err_msg("replica is down")).boxify()
+            } else {
+                future::ok(self.address.clone()).boxify()
+            }
+        }
+    }
+
+    fn replica_manager(cluster: &Cluster) -> (ReplicaManager, Arc<ReplicaSet<FakeStore>>) {
+        let mut manager = ReplicaManager::new(
+            "primary".to_string(),
+            &ReadReplicaParams {
+                standby_db_addresses: vec!["standby1".to_string(), "standby2".to_string()],
+                failure_threshold: 2,
+                reresolve_interval_secs: 60,
+            },
+        );
+        let set = manager.replica_set("fake", {
+            cloned!(cluster);
+            move |address| {
+                cluster.connects.fetch_add(1, Ordering::Relaxed);
+                Arc::new(FakeStore {
+                    address: address.to_string(),
+                    cluster: cluster.clone(),
+                })
+            }
+        });
+        (manager, set)
+    }
+
+    fn read(set: &ReplicaSet<FakeStore>) -> Result<String, Error> {
+        set.read(|store| store.get()).wait()
+    }
+
+    #[test]
+    fn reads_fail_over_to_healthy_replicas() {
+        let cluster = Cluster::default();
+        let (manager, set) = replica_manager(&cluster);
+
+        assert_eq!(read(&set).unwrap(), "primary");
+        assert_eq!(cluster.take_reads(), vec!["primary"]);
+
+        cluster.set_down("primary", true);
+        assert_eq!(read(&set).unwrap(), "standby1");
+        assert_eq!(read(&set).unwrap(), "standby1");
+        assert_eq!(cluster.take_reads().len(), 4);
+
+        // The primary failed twice in a row, reads go straight to the standby now
+        assert_eq!(read(&set).unwrap(), "standby1");
+        assert_eq!(cluster.take_reads(), vec!["standby1"]);
+
+        let topology = manager.topology();
+        let primary = &topology["fake"][0];
+        assert!(primary.primary);
+        assert!(!primary.healthy);
+        assert_eq!(primary.consecutive_failures, 2);
+        assert!(primary.last_error.is_some());
+        assert!(topology["fake"][1..].iter().all(|replica| replica.healthy));
+
+        // Writes still go to the primary
+        assert_eq!(set.primary().address, "primary");
+    }
+
+    #[test]
+    fn unhealthy_replicas_are_used_as_a_last_resort() {
+        let cluster = Cluster::default();
+        let (_manager, set) = replica_manager(&cluster);
+
+        cluster.set_down("primary", true);
+        for _ in 0..2 {
+            read(&set).unwrap();
+        }
+        cluster.take_reads();
+
+        cluster.set_down("primary", false);
+        cluster.set_down("standby1", true);
+        cluster.set_down("standby2", true);
+        assert_eq!(read(&set).unwrap(), "primary");
+        assert_eq!(
+            cluster.take_reads(),
+            vec!["standby1", "standby2", "primary"]
+        );
+
+        cluster.set_down("primary", true);
+        assert!(read(&set).is_err());
+    }
+
+    #[test]
+    fn reads_do_not_fail_over_on_errors_that_are_not_transient() {
+        let cluster = Cluster::default();
+        let (manager, set) = replica_manager(&cluster);
+
+        let res = set
+            .read(|store| {
+                let missing: Error = io::Error::new(io::ErrorKind::NotFound, "no such row").into();
+                store
+                    .get()
+                    .and_then(move |_| Err::<String, _>(missing))
+                    .boxify()
+            })
+            .wait();
+        assert!(res.is_err());
+        assert_eq!(cluster.take_reads(), vec!["primary"]);
+        assert_eq!(manager.topology()["fake"][0].consecutive_failures, 0);
+    }
+
+    #[test]
+    fn reresolving_reconnects_and_retries_unhealthy_replicas() {
+        let cluster = Cluster::default();
+        let (manager, set) = replica_manager(&cluster);
+        assert_eq!(cluster.connects.load(Ordering::Relaxed), 3);
+
+        cluster.set_down("primary", true);
+        for _ in 0..2 {
+            read(&set).unwrap();
+        }
+        cluster.set_down("primary", false);
+        cluster.take_reads();
+
+        manager.reresolve();
+        assert_eq!(cluster.connects.load(Ordering::Relaxed), 6);
+        assert!(manager.topology()["fake"]
+            .iter()
+            .all(|replica| replica.healthy));
+        assert_eq!(read(&set).unwrap(), "primary");
+        assert_eq!(cluster.take_reads(), vec!["primary"]);
+    }
+}
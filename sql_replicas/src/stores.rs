@@ -0,0 +1,127 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The SQL stores of a repo, reading from a replica set and writing to its primary.
+
+use std::sync::Arc;
+
+use bonsai_hg_mapping::{BonsaiHgMapping, BonsaiHgMappingEntry, BonsaiOrHgChangesetIds};
+use changesets::{ChangesetEntry, ChangesetInsert, Changesets};
+use cloned::cloned;
+use context::CoreContext;
+use failure_ext::Error;
+use filenodes::{FilenodeInfo, Filenodes};
+use futures_ext::{BoxFuture, BoxStream};
+use mercurial_types::{HgFileNodeId, RepoPath};
+use mononoke_types::{ChangesetId, RepositoryId};
+
+use crate::ReplicaSet;
+
+pub struct ReplicatedChangesets {
+    replicas: Arc<ReplicaSet<Changesets>>,
+}
+
+impl ReplicatedChangesets {
+    pub fn new(replicas: Arc<ReplicaSet<Changesets>>) -> Self {
+        Self { replicas }
+    }
+}
+
+impl Changesets for ReplicatedChangesets {
+    fn add(&self, ctx: CoreContext, cs: ChangesetInsert) -> BoxFuture<bool, Error> {
+        self.replicas.primary().add(ctx, cs)
+    }
+
+    fn get(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        cs_id: ChangesetId,
+    ) -> BoxFuture<Option<ChangesetEntry>, Error> {
+        self.replicas
+            .read(move |changesets| changesets.get(ctx.clone(), repo_id, cs_id))
+    }
+
+    fn get_many(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        cs_ids: Vec<ChangesetId>,
+    ) -> BoxFuture<Vec<ChangesetEntry>, Error> {
+        self.replicas
+            .read(move |changesets| changesets.get_many(ctx.clone(), repo_id, cs_ids.clone()))
+    }
+}
+
+pub struct ReplicatedBonsaiHgMapping {
+    replicas: Arc<ReplicaSet<BonsaiHgMapping>>,
+}
+
+impl ReplicatedBonsaiHgMapping {
+    pub fn new(replicas: Arc<ReplicaSet<BonsaiHgMapping>>) -> Self {
+        Self { replicas }
+    }
+}
+
+impl BonsaiHgMapping for ReplicatedBonsaiHgMapping {
+    fn add(&self, ctx: CoreContext, entry: BonsaiHgMappingEntry) -> BoxFuture<bool, Error> {
+        self.replicas.primary().add(ctx, entry)
+    }
+
+    fn get(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+        cs_id: BonsaiOrHgChangesetIds,
+    ) -> BoxFuture<Vec<BonsaiHgMappingEntry>, Error> {
+        self.replicas
+            .read(move |mapping| mapping.get(ctx.clone(), repo_id, cs_id.clone()))
+    }
+}
+
+pub struct ReplicatedFilenodes {
+    replicas: Arc<ReplicaSet<Filenodes>>,
+}
+
+impl ReplicatedFilenodes {
+    pub fn new(replicas: Arc<ReplicaSet<Filenodes>>) -> Self {
+        Self { replicas }
+    }
+}
+
+impl Filenodes for ReplicatedFilenodes {
+    fn add_filenodes(
+        &self,
+        ctx: CoreContext,
+        info: BoxStream<FilenodeInfo, Error>,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<(), Error> {
+        self.replicas.primary().add_filenodes(ctx, info, repo_id)
+    }
+
+    fn get_filenode(
+        &self,
+        ctx: CoreContext,
+        path: &RepoPath,
+        filenode: HgFileNodeId,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Option<FilenodeInfo>, Error> {
+        cloned!(path);
+        self.replicas
+            .read(move |filenodes| filenodes.get_filenode(ctx.clone(), &path, filenode, repo_id))
+    }
+
+    fn get_all_filenodes(
+        &self,
+        ctx: CoreContext,
+        path: &RepoPath,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Vec<FilenodeInfo>, Error> {
+        cloned!(path);
+        self.replicas
+            .read(move |filenodes| filenodes.get_all_filenodes(ctx.clone(), &path, repo_id))
+    }
+}
@@ -0,0 +1,8 @@
+CREATE TABLE `storage_usage` (
+  `repo_id` INT UNSIGNED NOT NULL,
+  `taken_at` BIGINT NOT NULL,
+  `blob_type` VARCHAR(64) NOT NULL,
+  `blobs` BIGINT UNSIGNED NOT NULL,
+  `bytes` BIGINT UNSIGNED NOT NULL,
+  PRIMARY KEY (`repo_id`, `taken_at`, `blob_type`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
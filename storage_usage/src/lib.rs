@@ -0,0 +1,260 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! How much storage a repo uses: the number of blobs of each type and their bytes, for capacity
+//! planning.
+//!
+//! Usage is measured by walking the repo from its heads (see `measure_repo_usage`), and each
+//! measurement is recorded in SQL as a snapshot, so that the growth of a repo can be followed
+//! from one snapshot to the next.
+//!
+//! The walk itself is available to tools that need the blobs of a repo rather than their sizes
+//! (see `walk_blobs`).
+
+#![deny(warnings)]
+
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate bookmarks;
+#[macro_use]
+extern crate cloned;
+extern crate context;
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate mononoke_types;
+#[macro_use]
+extern crate sql;
+extern crate sql_ext;
+#[macro_use]
+extern crate stats;
+
+mod walk;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use context::CoreContext;
+use failure::Error;
+use futures::{future, Future, IntoFuture};
+use futures_ext::{BoxFuture, FutureExt};
+use mononoke_types::{DateTime, RepositoryId, Timestamp};
+use sql::Connection;
+use sql_ext::migrations::{Migration, SqlMigrations};
+pub use sql_ext::SqlConstructors;
+use stats::Timeseries;
+
+pub use walk::{all_ancestors, measure_repo_usage, walk_blobs, BlobVisitor};
+
+define_stats! {
+    prefix = "mononoke.storage_usage";
+    adds: timeseries(RATE, SUM),
+    gets: timeseries(RATE, SUM),
+}
+
+/// The blobs of one type and their total size
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlobTypeUsage {
+    pub blobs: u64,
+    pub bytes: u64,
+}
+
+/// The storage that a repo used when the snapshot was taken
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StorageUsageSnapshot {
+    pub repo_id: RepositoryId,
+    pub taken_at: DateTime,
+    /// Usage by the type of the blobs, which is the prefix of their keys, e.g. `changeset`,
+    /// `hgmanifest` or `content`
+    pub by_type: BTreeMap<String, BlobTypeUsage>,
+}
+
+impl StorageUsageSnapshot {
+    /// The usage of all the blobs of the repo
+    pub fn total(&self) -> BlobTypeUsage {
+        self.by_type
+            .values()
+            .fold(BlobTypeUsage::default(), |total, usage| BlobTypeUsage {
+                blobs: total.blobs + usage.blobs,
+                bytes: total.bytes + usage.bytes,
+            })
+    }
+}
+
+pub trait StorageUsageStore: Send + Sync {
+    fn add_snapshot(
+        &self,
+        ctx: CoreContext,
+        snapshot: StorageUsageSnapshot,
+    ) -> BoxFuture<(), Error>;
+
+    /// The latest snapshot of the repo, None if none was taken
+    fn get_latest_snapshot(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Option<StorageUsageSnapshot>, Error>;
+}
+
+impl StorageUsageStore for Arc<StorageUsageStore> {
+    fn add_snapshot(
+        &self,
+        ctx: CoreContext,
+        snapshot: StorageUsageSnapshot,
+    ) -> BoxFuture<(), Error> {
+        (**self).add_snapshot(ctx, snapshot)
+    }
+
+    fn get_latest_snapshot(
+        &self,
+        ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Option<StorageUsageSnapshot>, Error> {
+        (**self).get_latest_snapshot(ctx, repo_id)
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlStorageUsage {
+    write_connection: Connection,
+    read_connection: Connection,
+}
+
+queries! {
+    write InsertUsage(values: (
+        repo_id: RepositoryId,
+        taken_at: Timestamp,
+        blob_type: str,
+        blobs: u64,
+        bytes: u64,
+    )) {
+        none,
+        "INSERT INTO storage_usage (repo_id, taken_at, blob_type, blobs, bytes)
+         VALUES {values}"
+    }
+
+    read SelectLatestTakenAt(repo_id: RepositoryId) -> (Timestamp) {
+        "SELECT taken_at
+         FROM storage_usage
+         WHERE repo_id = {repo_id}
+         ORDER BY taken_at DESC
+         LIMIT 1"
+    }
+
+    read SelectSnapshot(repo_id: RepositoryId, taken_at: Timestamp) -> (String, u64, u64) {
+        "SELECT blob_type, blobs, bytes
+         FROM storage_usage
+         WHERE repo_id = {repo_id} AND taken_at = {taken_at}"
+    }
+}
+
+impl SqlConstructors for SqlStorageUsage {
+    fn from_connections(
+        write_connection: Connection,
+        read_connection: Connection,
+        _read_master_connection: Connection,
+    ) -> Self {
+        Self {
+            write_connection,
+            read_connection,
+        }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-storage-usage.sql")
+    }
+}
+
+impl SqlMigrations for SqlStorageUsage {
+    const STORE: &'static str = "storage_usage";
+
+    fn migrations() -> &'static [Migration] {
+        &[Migration {
+            version: 1,
+            description: "create the storage_usage table",
+            sqlite: "CREATE TABLE `storage_usage` (
+                       `repo_id` INT UNSIGNED NOT NULL,
+                       `taken_at` BIGINT NOT NULL,
+                       `blob_type` VARCHAR(64) NOT NULL,
+                       `blobs` BIGINT UNSIGNED NOT NULL,
+                       `bytes` BIGINT UNSIGNED NOT NULL,
+                       PRIMARY KEY (`repo_id`, `taken_at`, `blob_type`)
+                     );",
+            mysql: "CREATE TABLE `storage_usage` (
+                      `repo_id` INT UNSIGNED NOT NULL,
+                      `taken_at` BIGINT NOT NULL,
+                      `blob_type` VARCHAR(64) NOT NULL,
+                      `blobs` BIGINT UNSIGNED NOT NULL,
+                      `bytes` BIGINT UNSIGNED NOT NULL,
+                      PRIMARY KEY (`repo_id`, `taken_at`, `blob_type`)
+                    ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;",
+        }]
+    }
+}
+
+impl StorageUsageStore for SqlStorageUsage {
+    fn add_snapshot(
+        &self,
+        _ctx: CoreContext,
+        snapshot: StorageUsageSnapshot,
+    ) -> BoxFuture<(), Error> {
+        STATS::adds.add_value(1);
+
+        // An empty repo has nothing to record
+        if snapshot.by_type.is_empty() {
+            return future::ok(()).boxify();
+        }
+        let taken_at = Timestamp::from(snapshot.taken_at);
+        let rows: Vec<_> = snapshot
+            .by_type
+            .iter()
+            .map(|(blob_type, usage)| {
+                (
+                    &snapshot.repo_id,
+                    &taken_at,
+                    blob_type.as_str(),
+                    &usage.blobs,
+                    &usage.bytes,
+                )
+            })
+            .collect();
+        InsertUsage::query(&self.write_connection, &rows[..])
+            .map(|_| ())
+            .boxify()
+    }
+
+    fn get_latest_snapshot(
+        &self,
+        _ctx: CoreContext,
+        repo_id: RepositoryId,
+    ) -> BoxFuture<Option<StorageUsageSnapshot>, Error> {
+        STATS::gets.add_value(1);
+
+        cloned!(self.read_connection);
+        SelectLatestTakenAt::query(&read_connection, &repo_id)
+            .and_then(move |rows| match rows.into_iter().next() {
+                Some((taken_at,)) => SelectSnapshot::query(&read_connection, &repo_id, &taken_at)
+                    .map(move |rows| {
+                        let by_type = rows
+                            .into_iter()
+                            .map(|(blob_type, blobs, bytes)| {
+                                (blob_type, BlobTypeUsage { blobs, bytes })
+                            })
+                            .collect();
+                        Some(StorageUsageSnapshot {
+                            repo_id,
+                            taken_at: DateTime::from(taken_at),
+                            by_type,
+                        })
+                    })
+                    .left_future(),
+                None => Ok(None).into_future().right_future(),
+            })
+            .boxify()
+    }
+}
@@ -0,0 +1,380 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Walks of the blobs that a repo is made of, from some of its changesets. The blob stores can't
+//! list their keys, so only the blobs that the repo refers to are found.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use failure::{err_msg, Error};
+use futures::future::{self, loop_fn, Loop};
+use futures::{stream, Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use bookmarks::{BookmarkUpdateReason, Bookmarks};
+use context::CoreContext;
+use mercurial_types::{
+    Changeset, HgFileEnvelope, HgFileNodeId, HgManifestEnvelope, HgManifestId, Type,
+};
+use mononoke_types::{BlobstoreBytes, ChangesetId, MononokeId, RawBundle2Id};
+
+use BlobTypeUsage;
+
+/// Blobs fetched at the same time
+const FETCH_CONCURRENCY: usize = 100;
+
+/// What a walk does with the blobs that it finds
+pub trait BlobVisitor: Send + Sync + 'static {
+    /// Whether the walk should fetch the blob of `key` if nothing else depends on its contents,
+    /// i.e. if it's a file content, a manifest shard or a raw bundle. The others are fetched
+    /// anyway, to find the blobs that they refer to.
+    fn needs(&self, _key: &str) -> bool {
+        true
+    }
+
+    /// Called once for each blob that the walk fetches
+    fn visit(&self, key: &str, blob: &BlobstoreBytes) -> Result<(), Error>;
+}
+
+/// A walk in progress. Blobs can be referred to more than once, e.g. the same content by several
+/// file nodes, but each key is only fetched once.
+#[derive(Clone)]
+struct Walk {
+    seen: Arc<Mutex<HashSet<String>>>,
+    visitor: Arc<dyn BlobVisitor>,
+}
+
+impl Walk {
+    fn new(visitor: Arc<dyn BlobVisitor>) -> Self {
+        Self {
+            seen: Arc::new(Mutex::new(HashSet::new())),
+            visitor,
+        }
+    }
+
+    /// Whether `key` wasn't reached before
+    fn first_visit(&self, key: &str) -> bool {
+        let mut seen = self.seen.lock().expect("lock poisoned");
+        seen.insert(key.to_string())
+    }
+}
+
+/// Fetch the blob of `key` and visit it. Returns None if it was reached already.
+fn visit(
+    ctx: CoreContext,
+    repo: &BlobRepo,
+    walk: Walk,
+    key: String,
+) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+    if !walk.first_visit(&key) {
+        return future::ok(None).boxify();
+    }
+    repo.get_blobstore()
+        .get(ctx, key.clone())
+        .and_then(move |blob| match blob {
+            Some(blob) => {
+                walk.visitor.visit(&key, &blob)?;
+                Ok(Some(blob))
+            }
+            None => Err(err_msg(format!("blob {} is missing", key))),
+        })
+        .boxify()
+}
+
+/// Like `visit`, for a blob whose contents the walk doesn't need
+fn visit_leaf(ctx: CoreContext, repo: &BlobRepo, walk: Walk, key: String) -> BoxFuture<(), Error> {
+    if !walk.visitor.needs(&key) {
+        return future::ok(()).boxify();
+    }
+    visit(ctx, repo, walk, key).map(|_| ()).boxify()
+}
+
+/// The usage by type of the blobs measured so far
+#[derive(Clone, Default)]
+struct Tally(Arc<Mutex<BTreeMap<String, BlobTypeUsage>>>);
+
+impl Tally {
+    fn into_usage(self) -> BTreeMap<String, BlobTypeUsage> {
+        let tally = self.0.lock().expect("lock poisoned");
+        tally.clone()
+    }
+}
+
+impl BlobVisitor for Tally {
+    fn visit(&self, key: &str, blob: &BlobstoreBytes) -> Result<(), Error> {
+        let blob_type = key.split('.').next().unwrap_or("").to_string();
+        let mut tally = self.0.lock().expect("lock poisoned");
+        let usage = tally.entry(blob_type).or_default();
+        usage.blobs += 1;
+        usage.bytes += blob.len() as u64;
+        Ok(())
+    }
+}
+
+/// Measure the blobs that `repo` is made of, by type: the bonsai and hg changesets, manifests
+/// and file nodes of the commits that its heads lead to, the contents of their files, and the raw
+/// bundles of the pushes in the bookmark update log. Blobs that nothing refers to, e.g. the ones
+/// uploaded by pushes that failed, aren't counted.
+///
+/// Every blob is fetched to be measured, so this reads the whole repo.
+pub fn measure_repo_usage(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    bookmarks: Arc<Bookmarks>,
+) -> BoxFuture<BTreeMap<String, BlobTypeUsage>, Error> {
+    let tally = Tally::default();
+    let walk = Walk::new(Arc::new(tally.clone()));
+
+    let commits = repo
+        .get_bonsai_heads_maybe_stale(ctx.clone())
+        .collect()
+        .and_then({
+            cloned!(ctx, repo);
+            move |heads| all_ancestors(ctx, repo, heads)
+        })
+        .and_then({
+            cloned!(ctx, repo, walk);
+            move |changesets| walk_changesets(ctx, repo, walk, changesets)
+        });
+    let bundles = walk_raw_bundles(ctx, repo, bookmarks, walk);
+
+    commits
+        .join(bundles)
+        .map(move |_| tally.into_usage())
+        .boxify()
+}
+
+/// Visit the blobs of `changesets`: their bonsai and hg changesets, and the manifests, file
+/// nodes and contents of the hg changesets. Each blob is visited once, however many changesets
+/// refer to it.
+pub fn walk_blobs(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    changesets: HashSet<ChangesetId>,
+    visitor: Arc<dyn BlobVisitor>,
+) -> BoxFuture<(), Error> {
+    walk_changesets(ctx, repo, Walk::new(visitor), changesets).boxify()
+}
+
+fn walk_changesets(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    walk: Walk,
+    changesets: HashSet<ChangesetId>,
+) -> impl Future<Item = (), Error = Error> {
+    stream::iter_ok(changesets)
+        .map({
+            cloned!(ctx, repo, walk);
+            move |cs_id| visit_changeset(ctx.clone(), repo.clone(), walk.clone(), cs_id)
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .filter_map(|root_manifest| root_manifest)
+        .collect()
+        .and_then(move |root_manifests| visit_manifests(ctx, repo, walk, root_manifests))
+}
+
+/// `heads` and all their ancestors
+pub fn all_ancestors(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    heads: Vec<ChangesetId>,
+) -> BoxFuture<HashSet<ChangesetId>, Error> {
+    loop_fn((heads, HashSet::new()), move |(queue, mut seen)| {
+        let new: Vec<_> = queue.into_iter().filter(|cs| seen.insert(*cs)).collect();
+        if new.is_empty() {
+            return future::ok(Loop::Break(seen)).left_future();
+        }
+        stream::iter_ok(new)
+            .map({
+                cloned!(ctx, repo);
+                move |cs_id| repo.get_changeset_parents_by_bonsai(ctx.clone(), cs_id)
+            })
+            .buffer_unordered(FETCH_CONCURRENCY)
+            .concat2()
+            .map(move |parents| Loop::Continue((parents, seen)))
+            .right_future()
+    })
+    .boxify()
+}
+
+/// Visit the bonsai and hg changesets of `cs_id`. Returns its root manifest, or None if the
+/// hg changeset wasn't generated.
+fn visit_changeset(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    walk: Walk,
+    cs_id: ChangesetId,
+) -> impl Future<Item = Option<HgManifestId>, Error = Error> {
+    let bonsai = visit(ctx.clone(), &repo, walk.clone(), cs_id.blobstore_key());
+    let hg = repo
+        .get_hg_bonsai_mapping(ctx.clone(), cs_id)
+        .and_then(move |mapping| match mapping.into_iter().next() {
+            Some((hg_cs_id, _)) => visit(ctx.clone(), &repo, walk, hg_cs_id.blobstore_key())
+                .and_then(move |_| repo.get_changeset_by_changesetid(ctx, hg_cs_id))
+                .map(|cs| Some(cs.manifestid()))
+                .left_future(),
+            None => Ok(None).into_future().right_future(),
+        });
+    bonsai.join(hg).map(|(_, root_manifest)| root_manifest)
+}
+
+/// Visit `roots` and the manifests, file nodes and contents below them
+fn visit_manifests(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    walk: Walk,
+    roots: Vec<HgManifestId>,
+) -> impl Future<Item = (), Error = Error> {
+    // Manifests are visited one level at a time, so that the subtrees that the commits share are
+    // only listed once
+    loop_fn(
+        (roots, HashSet::new(), HashSet::new()),
+        move |(queue, mut seen_manifests, mut seen_filenodes)| {
+            let manifests: Vec<_> = queue
+                .into_iter()
+                .filter(|id| seen_manifests.insert(*id))
+                .collect();
+            if manifests.is_empty() {
+                return future::ok(Loop::Break(())).left_future();
+            }
+            cloned!(ctx, repo, walk);
+            stream::iter_ok(manifests)
+                .map({
+                    cloned!(ctx, repo, walk);
+                    move |id| visit_manifest(ctx.clone(), repo.clone(), walk.clone(), id)
+                })
+                .buffer_unordered(FETCH_CONCURRENCY)
+                .collect()
+                .and_then(move |children| {
+                    let mut subtrees = vec![];
+                    let mut filenodes = vec![];
+                    for (trees, files) in children {
+                        subtrees.extend(trees);
+                        filenodes.extend(files.into_iter().filter(|id| seen_filenodes.insert(*id)));
+                    }
+                    visit_filenodes(ctx, repo, walk, filenodes)
+                        .map(move |()| Loop::Continue((subtrees, seen_manifests, seen_filenodes)))
+                })
+                .right_future()
+        },
+    )
+}
+
+/// Visit manifest `id` and its shards if it's sharded. Returns its subtrees and files.
+fn visit_manifest(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    walk: Walk,
+    id: HgManifestId,
+) -> impl Future<Item = (Vec<HgManifestId>, Vec<HgFileNodeId>), Error = Error> {
+    let shards = visit(ctx.clone(), &repo, walk.clone(), id.blobstore_key()).and_then({
+        cloned!(ctx, repo);
+        move |blob| {
+            let shard_keys: Vec<_> = match blob {
+                Some(blob) => HgManifestEnvelope::from_blob(blob.into())?
+                    .shards()
+                    .iter()
+                    .map(|shard| shard.blobstore_key())
+                    .collect(),
+                None => vec![],
+            };
+            Ok(stream::iter_ok(shard_keys)
+                .map(move |key| visit_leaf(ctx.clone(), &repo, walk.clone(), key))
+                .buffer_unordered(FETCH_CONCURRENCY)
+                .for_each(|_| Ok(())))
+        }
+    });
+    let entries = repo.get_manifest_by_nodeid(ctx, id).map(|manifest| {
+        let mut trees = vec![];
+        let mut files = vec![];
+        for entry in manifest.list() {
+            let hash = entry.get_hash().into_nodehash();
+            match entry.get_type() {
+                Type::Tree => trees.push(HgManifestId::new(hash)),
+                Type::File(_) => files.push(HgFileNodeId::new(hash)),
+            }
+        }
+        (trees, files)
+    });
+    shards.flatten().join(entries).map(|((), entries)| entries)
+}
+
+/// Visit `filenodes` and their contents
+fn visit_filenodes(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    walk: Walk,
+    filenodes: Vec<HgFileNodeId>,
+) -> impl Future<Item = (), Error = Error> {
+    stream::iter_ok(filenodes)
+        .map({
+            cloned!(ctx, repo, walk);
+            move |id| {
+                visit(ctx.clone(), &repo, walk.clone(), id.blobstore_key()).and_then(|blob| {
+                    match blob {
+                        Some(blob) => {
+                            HgFileEnvelope::from_blob(blob.into()).map(|e| Some(e.content_id()))
+                        }
+                        None => Ok(None),
+                    }
+                })
+            }
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .filter_map(|content_id| content_id)
+        .map(move |content_id| {
+            visit_leaf(ctx.clone(), &repo, walk.clone(), content_id.blobstore_key())
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .for_each(|_| Ok(()))
+}
+
+fn bundle_handle(reason: &BookmarkUpdateReason) -> Option<&str> {
+    use bookmarks::BookmarkUpdateReason::*;
+    match reason {
+        Pushrebase { bundle_replay_data }
+        | Push { bundle_replay_data }
+        | TestMove { bundle_replay_data } => bundle_replay_data
+            .as_ref()
+            .map(|data| data.bundle_handle.as_str()),
+        Blobimport | ManualMove => None,
+    }
+}
+
+/// Visit the raw bundles that the bookmark update log of the repo refers to
+fn walk_raw_bundles(
+    ctx: CoreContext,
+    repo: BlobRepo,
+    bookmarks: Arc<Bookmarks>,
+    walk: Walk,
+) -> impl Future<Item = (), Error = Error> {
+    let repo_id = repo.get_repoid();
+    loop_fn(0, move |id| {
+        cloned!(ctx, repo, walk);
+        bookmarks
+            .read_next_bookmark_log_entry(ctx.clone(), id, repo_id)
+            .and_then(move |entry| {
+                let entry = match entry {
+                    Some(entry) => entry,
+                    None => return future::ok(Loop::Break(())).left_future(),
+                };
+                let next = Loop::Continue(entry.id as u64);
+                match bundle_handle(&entry.reason) {
+                    Some(handle) => RawBundle2Id::from_str(handle)
+                        .into_future()
+                        .and_then(move |bundle_id| {
+                            visit_leaf(ctx, &repo, walk, bundle_id.blobstore_key())
+                        })
+                        .map(move |_| next)
+                        .right_future(),
+                    None => future::ok(next).left_future(),
+                }
+            })
+    })
+}
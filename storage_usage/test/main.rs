@@ -0,0 +1,166 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for storage usage snapshots and their measurement.
+
+#![deny(warnings)]
+
+extern crate async_unit;
+extern crate context;
+extern crate dbbookmarks;
+extern crate failure_ext;
+extern crate fixtures;
+extern crate futures;
+#[macro_use]
+extern crate maplit;
+extern crate mononoke_types;
+extern crate storage_usage;
+extern crate tokio;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use context::CoreContext;
+use dbbookmarks::SqlBookmarks;
+use failure_ext::Error;
+use fixtures::linear;
+use futures::{Future, Stream};
+use mononoke_types::{BlobstoreBytes, DateTime, RepositoryId};
+use storage_usage::{
+    all_ancestors, measure_repo_usage, walk_blobs, BlobTypeUsage, BlobVisitor, SqlConstructors,
+    SqlStorageUsage, StorageUsageSnapshot, StorageUsageStore,
+};
+use tokio::runtime::Runtime;
+
+fn usage(blobs: u64, bytes: u64) -> BlobTypeUsage {
+    BlobTypeUsage { blobs, bytes }
+}
+
+#[test]
+fn test_latest_snapshot() {
+    let mut rt = Runtime::new().unwrap();
+
+    let ctx = CoreContext::test_mock();
+    let store = SqlStorageUsage::with_sqlite_in_memory().unwrap();
+    let repo_id = RepositoryId::new(137);
+    let other_repo_id = RepositoryId::new(138);
+
+    let latest = rt
+        .block_on(store.get_latest_snapshot(ctx.clone(), repo_id))
+        .expect("Getting snapshot failed");
+    assert_eq!(latest, None);
+
+    let older = StorageUsageSnapshot {
+        repo_id,
+        taken_at: DateTime::from_rfc3339("2019-03-01T12:00:00.00Z").unwrap(),
+        by_type: btreemap! {
+            "changeset".to_string() => usage(10, 1000),
+            "content".to_string() => usage(20, 100_000),
+        },
+    };
+    let newer = StorageUsageSnapshot {
+        repo_id,
+        taken_at: DateTime::from_rfc3339("2019-03-02T12:00:00.00Z").unwrap(),
+        by_type: btreemap! {
+            "changeset".to_string() => usage(12, 1200),
+            "content".to_string() => usage(25, 150_000),
+            "rawbundle2".to_string() => usage(1, 5000),
+        },
+    };
+    let other_repo = StorageUsageSnapshot {
+        repo_id: other_repo_id,
+        taken_at: DateTime::from_rfc3339("2019-03-03T12:00:00.00Z").unwrap(),
+        by_type: btreemap! {"changeset".to_string() => usage(1, 100)},
+    };
+    for snapshot in vec![older, newer.clone(), other_repo] {
+        rt.block_on(store.add_snapshot(ctx.clone(), snapshot))
+            .expect("Adding snapshot failed");
+    }
+
+    let latest = rt
+        .block_on(store.get_latest_snapshot(ctx.clone(), repo_id))
+        .expect("Getting snapshot failed");
+    assert_eq!(latest, Some(newer));
+    assert_eq!(latest.unwrap().total(), usage(38, 156_200));
+}
+
+#[test]
+fn test_measure_repo_usage() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let repo = linear::getrepo(None);
+        let bookmarks = Arc::new(SqlBookmarks::with_sqlite_in_memory().unwrap());
+
+        let by_type = measure_repo_usage(ctx, repo, bookmarks)
+            .wait()
+            .expect("Measuring usage failed");
+
+        let blob_types: Vec<_> = by_type.keys().map(|blob_type| blob_type.as_str()).collect();
+        assert_eq!(
+            blob_types,
+            vec![
+                "changeset",
+                "content",
+                "hgchangeset",
+                "hgfilenode",
+                "hgmanifest"
+            ]
+        );
+        // Every commit of the fixture has both a bonsai and an hg changeset
+        assert_eq!(by_type["changeset"].blobs, by_type["hgchangeset"].blobs);
+        for usage in by_type.values() {
+            assert!(usage.blobs > 0);
+            assert!(usage.bytes > 0);
+        }
+    })
+}
+
+/// Records the keys of the blobs it visits, and doesn't need the file contents
+#[derive(Default)]
+struct KeyRecorder(Mutex<Vec<String>>);
+
+impl BlobVisitor for KeyRecorder {
+    fn needs(&self, key: &str) -> bool {
+        !key.starts_with("content.")
+    }
+
+    fn visit(&self, key: &str, _blob: &BlobstoreBytes) -> Result<(), Error> {
+        self.0.lock().unwrap().push(key.to_string());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_walk_blobs() {
+    async_unit::tokio_unit_test(|| {
+        let ctx = CoreContext::test_mock();
+        let repo = linear::getrepo(None);
+        let recorder = Arc::new(KeyRecorder::default());
+
+        let changesets = repo
+            .get_bonsai_heads_maybe_stale(ctx.clone())
+            .collect()
+            .and_then({
+                let (ctx, repo) = (ctx.clone(), repo.clone());
+                move |heads| all_ancestors(ctx, repo, heads)
+            })
+            .wait()
+            .expect("Listing changesets failed");
+        walk_blobs(ctx, repo, changesets.clone(), recorder.clone())
+            .wait()
+            .expect("Walking blobs failed");
+
+        let keys = recorder.0.lock().unwrap().clone();
+        let unique: HashSet<_> = keys.iter().collect();
+        assert_eq!(unique.len(), keys.len(), "a blob was visited twice");
+        let count = |prefix: &str| keys.iter().filter(|key| key.starts_with(prefix)).count();
+        assert_eq!(count("changeset."), changesets.len());
+        assert_eq!(count("hgchangeset."), changesets.len());
+        assert!(count("hgmanifest.") > 0);
+        assert!(count("hgfilenode.") > 0);
+        assert_eq!(count("content."), 0);
+    })
+}
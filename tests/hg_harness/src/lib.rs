@@ -0,0 +1,395 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Runs a Mononoke server in the test process, and real hg clients against it.
+//!
+//! The server listens on a free port of localhost, and stores its repo in a temporary
+//! directory, with either the fileblob or the sqlblob backend and sqlite for metadata. Clients
+//! reach it the way they do in the integration tests, through `dummyssh` and `hgcli`, so every
+//! request goes through the whole wireproto stack. Tests then look at the repo from both ends:
+//! with hg in the working copies of the clients, and with a `BlobRepo` opened on the storage of
+//! the server.
+//!
+//! The binaries are found through the same environment variables as the integration tests:
+//! `MONONOKE_HGCLI`, `DUMMYSSH`, and `TESTDIR` for the directory of the test certificates. `HG`
+//! is the hg binary, `hg` from `PATH` if it's not set. The tests of the harness are skipped when
+//! any of the others isn't set.
+
+#![deny(warnings)]
+
+#[cfg(test)]
+mod test;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use blobrepo::BlobRepo;
+use blobrepo_factory::open_blobrepo;
+use bookmarks::Bookmark;
+use context::CoreContext;
+use failure_ext::{bail_msg, err_msg, format_err, Result};
+use futures::Future;
+use mercurial_types::HgChangesetId;
+use metaconfig_parser::RepoConfigs;
+use metaconfig_types::RepoType;
+use mononoke_types::RepositoryId;
+use slog::{o, Discard, Drain, Logger};
+use tempdir::TempDir;
+use tokio::runtime::Runtime;
+
+/// The name that clients reach the repo of the server by
+pub const REPO_NAME: &str = "repo";
+
+const REPO_ID: i32 = 0;
+
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Where the repo of a `TestServer` is stored
+#[derive(Clone, Copy, Debug)]
+pub enum Backend {
+    /// Blobs in files, metadata in sqlite
+    Files,
+    /// Blobs and metadata in sqlite
+    Sqlite,
+}
+
+impl Backend {
+    fn repotype(&self) -> &'static str {
+        match self {
+            Backend::Files => "blob:files",
+            Backend::Sqlite => "blob:sqlite",
+        }
+    }
+}
+
+/// The binaries and certificates that the harness runs clients with
+#[derive(Clone, Debug)]
+struct TestEnv {
+    hg: PathBuf,
+    hgcli: PathBuf,
+    dummyssh: PathBuf,
+    testdir: PathBuf,
+}
+
+impl TestEnv {
+    fn from_env() -> Result<Self> {
+        fn var(name: &str) -> Result<PathBuf> {
+            env::var_os(name)
+                .map(PathBuf::from)
+                .ok_or_else(|| format_err!("{} is not set", name))
+        }
+
+        Ok(Self {
+            hg: var("HG").unwrap_or_else(|_| PathBuf::from("hg")),
+            hgcli: var("MONONOKE_HGCLI")?,
+            dummyssh: var("DUMMYSSH")?,
+            testdir: var("TESTDIR")?,
+        })
+    }
+}
+
+/// A Mononoke server serving one empty repo, until it's dropped
+pub struct TestServer {
+    env: TestEnv,
+    tmp: TempDir,
+    port: u16,
+    admin_port: u16,
+    backend: Backend,
+    runtime: Option<Runtime>,
+    terminate: &'static AtomicBool,
+}
+
+impl TestServer {
+    /// Start a server with the repo stored by `backend`. `extra_config` is added to the
+    /// `server.toml` of the repo.
+    pub fn start(backend: Backend, extra_config: &str) -> Result<Self> {
+        let env = TestEnv::from_env()?;
+        let tmp = TempDir::new("hg_harness")?;
+        let config_dir = tmp.path().join("mononoke-config");
+        let repo_config_dir = config_dir.join("repos").join(REPO_NAME);
+        fs::create_dir_all(&repo_config_dir)?;
+        let mut server_toml = File::create(repo_config_dir.join("server.toml"))?;
+        write!(
+            server_toml,
+            "path=\"{}\"\nrepotype=\"{}\"\nrepoid={}\nenabled=true\n\
+             hash_validation_percentage=100\n{}\n\
+             [hook_manager_params]\nentrylimit=1048576\nweightlimit=104857600\n\
+             disable_acl_checker=true\n",
+            tmp.path().join(REPO_NAME).display(),
+            backend.repotype(),
+            REPO_ID,
+            extra_config,
+        )?;
+        let configs = RepoConfigs::read_configs(&config_dir)?;
+        write_hgrc(&env, tmp.path())?;
+
+        let port = free_port()?;
+        let admin_port = free_port()?;
+        let acceptor = tls_acceptor(&env.testdir)?;
+        let terminate = Box::leak(Box::new(AtomicBool::new(false)));
+        let (listeners, _ready) = repo_listener::create_repo_listeners(
+            configs.repos.into_iter(),
+            config_dir,
+            None,
+            None,
+            &logger(),
+            &format!("[::1]:{}", port),
+            Some(&format!("[::1]:{}", admin_port)),
+            acceptor,
+            terminate,
+        );
+        let mut runtime = Runtime::new()?;
+        runtime.spawn(listeners.map_err(|err| panic!("server failed: {:?}", err)));
+
+        let server = Self {
+            env,
+            tmp,
+            port,
+            admin_port,
+            backend,
+            runtime: Some(runtime),
+            terminate,
+        };
+        server.wait_until_listening()?;
+        Ok(server)
+    }
+
+    fn wait_until_listening(&self) -> Result<()> {
+        let addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), self.port);
+        let start = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            if start.elapsed() > SERVER_START_TIMEOUT {
+                bail_msg!("server did not start listening on {}", addr);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    /// The repo of the server, opened on its storage
+    pub fn repo(&self) -> Result<BlobRepo> {
+        let path = self.tmp.path().join(REPO_NAME);
+        let repotype = match self.backend {
+            Backend::Files => RepoType::BlobFiles(path),
+            Backend::Sqlite => RepoType::BlobSqlite(path),
+        };
+        open_blobrepo(logger(), repotype, RepositoryId::new(REPO_ID), None).wait()
+    }
+
+    /// Where `bookmark` points to on the server
+    pub fn bookmark(&self, bookmark: &str) -> Result<Option<HgChangesetId>> {
+        let ctx = CoreContext::test_mock();
+        self.repo()?
+            .get_bookmark(ctx, &Bookmark::new(bookmark)?)
+            .wait()
+    }
+
+    /// Whether the server has the changeset
+    pub fn has_changeset(&self, changeset: HgChangesetId) -> Result<bool> {
+        let ctx = CoreContext::test_mock();
+        self.repo()?.changeset_exists(ctx, changeset).wait()
+    }
+
+    /// A new empty hg repo in `name` that pushes to and pulls from the server
+    pub fn init_client(&self, name: &str) -> Result<HgClient> {
+        let client = HgClient {
+            server: self,
+            path: self.tmp.path().join(name),
+        };
+        client.run_in(self.tmp.path(), &["init", name])?;
+        client.set_default_path()?;
+        Ok(client)
+    }
+
+    /// A clone of the repo of the server in `name`
+    pub fn clone_client(&self, name: &str) -> Result<HgClient> {
+        let client = HgClient {
+            server: self,
+            path: self.tmp.path().join(name),
+        };
+        let url = self.url();
+        client.run_in(self.tmp.path(), &["clone", "-q", "--shallow", &url, name])?;
+        Ok(client)
+    }
+
+    /// Send an admin command to the server, and return the lines it answered before `ok`
+    pub fn admin(&self, command: &str) -> Result<Vec<String>> {
+        let addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), self.admin_port);
+        let mut stream = TcpStream::connect(addr)?;
+        writeln!(stream, "{}", command)?;
+        let mut lines = Vec::new();
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line == "ok" {
+                return Ok(lines);
+            }
+            if line.starts_with("error: ") {
+                bail_msg!(
+                    "admin command {} failed: {}",
+                    command,
+                    &line["error: ".len()..]
+                );
+            }
+            lines.push(line);
+        }
+        bail_msg!(
+            "server closed the admin connection before answering {}",
+            command
+        )
+    }
+
+    fn url(&self) -> String {
+        format!("ssh://user@dummy/{}", REPO_NAME)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.terminate.store(true, Ordering::Relaxed);
+        if let Some(runtime) = self.runtime.take() {
+            let _ = runtime.shutdown_now().wait();
+        }
+    }
+}
+
+/// A working copy of hg, with the server as its default path
+pub struct HgClient<'a> {
+    server: &'a TestServer,
+    path: PathBuf,
+}
+
+impl<'a> HgClient<'a> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Run hg in the working copy, and return what it printed
+    pub fn hg(&self, args: &[&str]) -> Result<String> {
+        self.run_in(&self.path, args)
+    }
+
+    /// Write `content` to `file` and commit it with the message `message`. Returns the new
+    /// commit.
+    pub fn commit_file(&self, file: &str, content: &str, message: &str) -> Result<HgChangesetId> {
+        let path = self.path.join(file);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, content)?;
+        self.hg(&["commit", "-A", "-m", message])?;
+        self.node(".")
+    }
+
+    /// The commit that `rev` resolves to
+    pub fn node(&self, rev: &str) -> Result<HgChangesetId> {
+        let node = self.hg(&["log", "-r", rev, "-T", "{node}"])?;
+        HgChangesetId::from_str(node.trim())
+    }
+
+    /// The first parent of `rev`
+    pub fn p1(&self, rev: &str) -> Result<HgChangesetId> {
+        let node = self.hg(&["log", "-r", rev, "-T", "{p1node}"])?;
+        HgChangesetId::from_str(node.trim())
+    }
+
+    /// The content of `file` at `rev`
+    pub fn cat(&self, rev: &str, file: &str) -> Result<String> {
+        self.hg(&["cat", "-r", rev, file])
+    }
+
+    /// Push `rev` to `bookmark` of the server, creating the bookmark if `create`
+    pub fn push(&self, rev: &str, bookmark: &str, create: bool) -> Result<String> {
+        let mut args = vec!["push", "-r", rev, "--to", bookmark];
+        if create {
+            args.push("--create");
+        }
+        self.hg(&args)
+    }
+
+    fn set_default_path(&self) -> Result<()> {
+        let mut hgrc = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.path.join(".hg").join("hgrc"))?;
+        write!(hgrc, "[paths]\ndefault={}\n", self.server.url())?;
+        Ok(())
+    }
+
+    fn run_in(&self, cwd: &Path, args: &[&str]) -> Result<String> {
+        let env = &self.server.env;
+        let tmp = self.server.tmp.path();
+        let output = Command::new(&env.hg)
+            .args(args)
+            .current_dir(cwd)
+            .env("HGRCPATH", tmp.join("hgrc"))
+            .env("HGPLAIN", "1")
+            .env("HGUSER", "test")
+            .env("HGENCODING", "utf-8")
+            .env("TESTTMP", tmp)
+            .env("TESTDIR", &env.testdir)
+            .env("MONONOKE_SOCKET", self.server.port.to_string())
+            .output()?;
+        if !output.status.success() {
+            bail_msg!(
+                "hg {} failed with {}:\n{}{}",
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr),
+            );
+        }
+        String::from_utf8(output.stdout).map_err(|_| err_msg("hg printed non-utf8 output"))
+    }
+}
+
+/// The config that all the clients of a server share, which `HGRCPATH` points to
+fn write_hgrc(env: &TestEnv, tmp: &Path) -> Result<()> {
+    let mut hgrc = File::create(tmp.join("hgrc"))?;
+    write!(
+        hgrc,
+        "[ui]\nssh={dummyssh}\nremotecmd={hgcli}\n\
+         [extensions]\ntreemanifest=\nremotefilelog=\nfastmanifest=\nremotenames=\n\
+         pushrebase=\nrebase=\n\
+         [treemanifest]\nsendtrees=True\ntreeonly=True\n\
+         [remotefilelog]\nreponame={reponame}\ncachepath={cachepath}\nshallowtrees=True\n",
+        dummyssh = env.dummyssh.display(),
+        hgcli = env.hgcli.display(),
+        reponame = REPO_NAME,
+        cachepath = tmp.join("cachepath").display(),
+    )?;
+    Ok(())
+}
+
+fn free_port() -> Result<u16> {
+    let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+fn tls_acceptor(testdir: &Path) -> Result<openssl::ssl::SslAcceptor> {
+    let cert = testdir.join("testcert.crt").display().to_string();
+    let ssl = secure_utils::SslConfig {
+        cert: cert.clone(),
+        private_key: testdir.join("testcert.key").display().to_string(),
+        ca_pem: cert,
+    };
+    let ticket_seeds = testdir.join("server.pem.seeds").display().to_string();
+    let acceptor = secure_utils::build_tls_acceptor_builder(ssl.clone())?;
+    let acceptor =
+        secure_utils::fb_tls::tls_acceptor_builder(logger(), ssl, acceptor, ticket_seeds)?;
+    Ok(acceptor.build())
+}
+
+fn logger() -> Logger {
+    Logger::root(Discard {}.ignore_res(), o!())
+}
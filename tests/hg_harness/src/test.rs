@@ -0,0 +1,166 @@
+// Copyright (c) 2019-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use super::*;
+
+const MASTER: &str = "master_bookmark";
+
+/// Whether the binaries the clients need are there. Tests return early without them, as
+/// `cargo test` runs outside of the integration test environment that provides them.
+fn env_is_set() -> bool {
+    match TestEnv::from_env() {
+        Ok(_) => true,
+        Err(err) => {
+            eprintln!("skipping: {}", err);
+            false
+        }
+    }
+}
+
+/// A server whose master bookmark points to a first commit, and the client that pushed it
+fn server_with_root(backend: Backend) -> TestServer {
+    let server = TestServer::start(backend, "").unwrap();
+    {
+        let client = server.init_client("seed").unwrap();
+        let root = client.commit_file("a", "a\n", "root").unwrap();
+        client.push(".", MASTER, true).unwrap();
+        assert_eq!(server.bookmark(MASTER).unwrap(), Some(root));
+    }
+    server
+}
+
+fn clone_and_pull(backend: Backend) {
+    let server = server_with_root(backend);
+    let pusher = server.clone_client("pusher").unwrap();
+    let puller = server.clone_client("puller").unwrap();
+    let root = server.bookmark(MASTER).unwrap().unwrap();
+    assert_eq!(puller.node(MASTER).unwrap(), root);
+    assert_eq!(puller.cat(MASTER, "a").unwrap(), "a\n");
+
+    pusher.hg(&["update", MASTER]).unwrap();
+    let b = pusher.commit_file("dir/b", "b\n", "b").unwrap();
+    pusher.push(".", MASTER, false).unwrap();
+    assert_eq!(server.bookmark(MASTER).unwrap(), Some(b));
+    assert!(server.has_changeset(b).unwrap());
+
+    puller.hg(&["pull"]).unwrap();
+    assert_eq!(puller.node(MASTER).unwrap(), b);
+    assert_eq!(puller.p1(MASTER).unwrap(), root);
+    assert_eq!(puller.cat(MASTER, "dir/b").unwrap(), "b\n");
+}
+
+#[test]
+fn test_clone_and_pull_files() {
+    if !env_is_set() {
+        return;
+    }
+    clone_and_pull(Backend::Files);
+}
+
+#[test]
+fn test_clone_and_pull_sqlite() {
+    if !env_is_set() {
+        return;
+    }
+    clone_and_pull(Backend::Sqlite);
+}
+
+#[test]
+fn test_pushrebase() {
+    if !env_is_set() {
+        return;
+    }
+    let server = server_with_root(Backend::Files);
+    let first = server.clone_client("first").unwrap();
+    let second = server.clone_client("second").unwrap();
+    first.hg(&["update", MASTER]).unwrap();
+    second.hg(&["update", MASTER]).unwrap();
+
+    let b = first.commit_file("b", "b\n", "b").unwrap();
+    first.push(".", MASTER, false).unwrap();
+
+    // The server rebases the commit onto b, so the commit that lands isn't the one pushed
+    let c = second.commit_file("c", "c\n", "c").unwrap();
+    second.push(".", MASTER, false).unwrap();
+    let landed = server.bookmark(MASTER).unwrap().unwrap();
+    assert_ne!(landed, c);
+
+    second.hg(&["pull"]).unwrap();
+    assert_eq!(second.node(MASTER).unwrap(), landed);
+    assert_eq!(second.p1(MASTER).unwrap(), b);
+    assert_eq!(second.cat(MASTER, "b").unwrap(), "b\n");
+    assert_eq!(second.cat(MASTER, "c").unwrap(), "c\n");
+}
+
+#[test]
+fn test_rebase_then_push() {
+    if !env_is_set() {
+        return;
+    }
+    let server = server_with_root(Backend::Sqlite);
+    let first = server.clone_client("first").unwrap();
+    let second = server.clone_client("second").unwrap();
+    first.hg(&["update", MASTER]).unwrap();
+    second.hg(&["update", MASTER]).unwrap();
+
+    let b = first.commit_file("b", "b\n", "b").unwrap();
+    first.push(".", MASTER, false).unwrap();
+
+    // Rebased by the client, the commit lands as it is
+    second.commit_file("c", "c\n", "c").unwrap();
+    second.hg(&["pull"]).unwrap();
+    second.hg(&["rebase", "-d", MASTER]).unwrap();
+    let c = second.node(".").unwrap();
+    assert_eq!(second.p1(".").unwrap(), b);
+    second.push(".", MASTER, false).unwrap();
+    assert_eq!(server.bookmark(MASTER).unwrap(), Some(c));
+
+    first.hg(&["pull"]).unwrap();
+    assert_eq!(first.node(MASTER).unwrap(), c);
+    assert_eq!(first.cat(MASTER, "c").unwrap(), "c\n");
+}
+
+/// The loaded repos of the server, each with its state
+fn repo_states(server: &TestServer) -> Vec<String> {
+    server
+        .admin("list")
+        .unwrap()
+        .into_iter()
+        .map(|line| line.rsplitn(2, ' ').last().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn test_drain_unload_and_load() {
+    if !env_is_set() {
+        return;
+    }
+    let server = server_with_root(Backend::Files);
+    let root = server.bookmark(MASTER).unwrap().unwrap();
+    assert_eq!(repo_states(&server), vec![format!("{} serving", REPO_NAME)]);
+
+    // A drained repo refuses new sessions
+    server.admin(&format!("drain {}", REPO_NAME)).unwrap();
+    assert_eq!(
+        repo_states(&server),
+        vec![format!("{} draining", REPO_NAME)]
+    );
+    assert!(server.clone_client("drained").is_err());
+
+    server.admin(&format!("unload {}", REPO_NAME)).unwrap();
+    assert!(repo_states(&server).is_empty());
+    assert!(server.admin(&format!("drain {}", REPO_NAME)).is_err());
+
+    // Loaded again from its config, the repo serves what was pushed to it before
+    server.admin(&format!("load {}", REPO_NAME)).unwrap();
+    assert!(server.admin(&format!("load {}", REPO_NAME)).is_err());
+    assert_eq!(repo_states(&server), vec![format!("{} serving", REPO_NAME)]);
+    let client = server.clone_client("reloaded").unwrap();
+    assert_eq!(client.node(MASTER).unwrap(), root);
+
+    assert!(server.admin("load no_such_repo").is_err());
+    assert!(server.admin("no_such_command").is_err());
+}
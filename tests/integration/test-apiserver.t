@@ -116,6 +116,27 @@ observe that copy info is present in output
   $ sslcurl -i $APISERVER/sup/raw/ 2> /dev/null | grep 404
   HTTP/* 404 * (glob)
 
+test repo status
+  $ sslcurl $APISERVER/repo/status
+  {"maintenance":null,"overrides":{}} (no-eol)
+
+  $ sqlite3 "$TESTTMP/repo/maintenance" "INSERT INTO maintenance_windows (repo_id, start_timestamp, end_timestamp, message) VALUES (0, 0, 4102444800000000000, 'moving storage')"
+  $ sslcurl $APISERVER/repo/status
+  {"maintenance":{"start":"1970-01-01T00:00:00+00:00","end":"2100-01-01T00:00:00+00:00","message":"moving storage"},"overrides":{}} (no-eol)
+
+  $ sqlite3 "$TESTTMP/repo/maintenance" "DELETE FROM maintenance_windows"
+  $ sslcurl $APISERVER/repo/status
+  {"maintenance":null,"overrides":{}} (no-eol)
+
+test repo health
+  $ sslcurl $APISERVER/repo/health
+  {"last_push":null,"hg_sync":null,"hg_derivation":{"entries":0,"oldest":null,"lag_secs":0},"blobstore_sync":null} (no-eol)
+
+  $ sqlite3 "$TESTTMP/repo/hg_derivation_queue" "INSERT INTO hg_derivation_queue (repo_id, cs_id, add_timestamp) VALUES (0, X'$(printf '%064d' 0)', 0)"
+  $ sslcurl $APISERVER/repo/health | jq -c '.hg_derivation | {entries, oldest}'
+  {"entries":1,"oldest":"1970-01-01T00:00:00+00:00"}
+  $ sqlite3 "$TESTTMP/repo/hg_derivation_queue" "DELETE FROM hg_derivation_queue"
+
 test reachability in basic repo
   $ sslcurl $APISERVER/repo/is_ancestor/$COMMIT1/$COMMIT2
   true (no-eol)
@@ -162,6 +183,68 @@ test reachability response on nonexistent nodes
   CommitHash("1234567890123456789012345678901234567890") is not found
   404
 
+test batched reachability
+  $ sslcurl -d "{\"pairs\": [{\"ancestor\": \"$COMMIT1\", \"descendant\": \"$COMMITB2\"}, {\"ancestor\": \"$COMMITB2\", \"descendant\": \"$COMMITB1\"}, {\"ancestor\": \"$COMMIT2\", \"descendant\": \"$COMMIT2\"}]}" -H "Content-Type: application/json" -X POST $APISERVER/repo/is_ancestor_batch | jq -c '.[].is_ancestor'
+  true
+  false
+  true
+
+  $ sslcurl -w "\n%{http_code}" -d '{"pairs": [{"ancestor": "1234567890123456789012345678901234567890", "descendant": "1234567890123456789012345678901234567890"}]}' -H "Content-Type: application/json" -X POST $APISERVER/repo/is_ancestor_batch | extract_json_error
+  CommitHash("1234567890123456789012345678901234567890") is not found
+  404
+
+test common ancestor
+  $ sslcurl $APISERVER/repo/common_ancestor/$COMMITB1/$COMMITB2 | jq -r '.ancestor == "'$COMMIT2'"'
+  true
+
+  $ sslcurl $APISERVER/repo/common_ancestor/$COMMIT1/$COMMITB2 | jq -r '.ancestor == "'$COMMIT1'"'
+  true
+
+  $ sslcurl -w "\n%{http_code}" $APISERVER/repo/common_ancestor/$COMMIT1/1234567890123456789012345678901234567890 | extract_json_error
+  CommitHash("1234567890123456789012345678901234567890") is not found
+  404
+
+test range
+  $ sslcurl $APISERVER/repo/range/$COMMIT1/$COMMITB2 | jq -r '[.[].hash] == ["'$COMMITB2'", "'$COMMIT2'", "'$COMMIT1'"]'
+  true
+
+  $ sslcurl $APISERVER/repo/range/$COMMIT1/$COMMITB2 | jq -c '[.[].generation]'
+  [3,2,1]
+
+  $ sslcurl "$APISERVER/repo/range/$COMMIT1/$COMMITB2?limit=1" | jq -r '[.[].hash] == ["'$COMMITB2'"]'
+  true
+
+  $ sslcurl $APISERVER/repo/range/$COMMITB1/$COMMITB2 | jq -c '.'
+  []
+
+test bookmark contains commit
+  $ sslcurl $APISERVER/repo/contains/$COMMITB2_BOOKMARK/$COMMIT1
+  true (no-eol)
+
+  $ sslcurl $APISERVER/repo/contains/$COMMITB2_BOOKMARK/$COMMITB2
+  true (no-eol)
+
+  $ sslcurl $APISERVER/repo/contains/$COMMITB2_BOOKMARK/$COMMITB1
+  false (no-eol)
+
+  $ sslcurl $APISERVER/repo/contains/$COMMITB2_BOOKMARK/$FORWARD_SLASH_BM_HASH
+  false (no-eol)
+
+  $ sslcurl $APISERVER/repo/contains/$ENCODED_FORWARD_SLASH_BM/$COMMITB2
+  true (no-eol)
+
+answers are cached, asking again gives the same answer
+  $ sslcurl $APISERVER/repo/contains/$COMMITB2_BOOKMARK/$COMMITB1
+  false (no-eol)
+
+  $ sslcurl -w "\n%{http_code}" $APISERVER/repo/contains/nonexistent/$COMMIT1 | extract_json_error
+  nonexistent is not a valid bookmark
+  400
+
+  $ sslcurl -w "\n%{http_code}" $APISERVER/repo/contains/$COMMITB2_BOOKMARK/1234567890123456789012345678901234567890 | extract_json_error
+  1234567890123456789012345678901234567890 is not found
+  404
+
 test folder list
   $ sslcurl $APISERVER/repo/list/$COMMIT2/folder | tee output | jq .
   [
@@ -193,6 +276,36 @@ test list a file
   test-rename is not a directory
   400
 
+test path sizes
+  $ sslcurl $APISERVER/repo/sizes/$COMMIT2/folder | jq -c .
+  [{"path":"folder","total_size":6,"file_count":1,"dir_count":1}]
+
+  $ sslcurl $APISERVER/repo/sizes/$COMMIT2/folder/subfolder/.keep | jq -c .
+  [{"path":"folder/subfolder/.keep","total_size":6,"file_count":1,"dir_count":0}]
+
+  $ sslcurl -d '{"paths": ["", "link", "folder/subfolder"]}' -H "Content-Type: application/json" -X POST $APISERVER/repo/sizes/$COMMIT2 | tee output | jq -c '.[1:]'
+  [{"path":"link","total_size":4,"file_count":1,"dir_count":0},{"path":"folder/subfolder","total_size":6,"file_count":1,"dir_count":0}]
+  $ cat output | jq -c '.[0] | [.path, .total_size - '"$(wc -c < "$TESTTMP/repo-hg/test-rename")"', .file_count, .dir_count]'
+  ["",10,3,2]
+
+  $ sslcurl -w "\n%{http_code}" $APISERVER/repo/sizes/$COMMIT2/nonexist | extract_json_error
+  nonexist is not found
+  404
+
+test paths exist
+  $ sslcurl -d '{"paths": ["", "folder", "folder/subfolder/.keep", "link", "nonexist", "link/below"]}' -H "Content-Type: application/json" -X POST $APISERVER/repo/exists/$COMMIT2 | jq -c '.[]'
+  {"path":"","exists":true,"type":"tree"}
+  {"path":"folder","exists":true,"type":"tree"}
+  {"path":"folder/subfolder/.keep","exists":true,"type":"file","size":6}
+  {"path":"link","exists":true,"type":"symlink","size":4}
+  {"path":"nonexist","exists":false}
+  {"path":"link/below","exists":false}
+
+test openapi document
+  $ sslcurl $APISERVER/openapi.json | jq -r '.openapi, .paths["/{repo}/exists/{changeset}"].post.summary'
+  3.0.2
+  Whether many paths exist at a changeset
+
 test get blob by hash
   $ sslcurl $APISERVER/repo/blob/$BLOBHASH > output
   $ diff output - <<< $TEST_CONTENT
@@ -209,6 +322,31 @@ test get blob by hash
   0000000000000000000000000000000000000001 is not found
   404
 
+test multiget
+  $ cat > $TESTTMP/multiget.py <<EOF
+  > import msgpack, sys
+  > for entry in msgpack.Unpacker(sys.stdin, raw=False):
+  >     fields = ("status", "key", "type", "size", "message")
+  >     print(" ".join(str(entry[field]) for field in fields if field in entry))
+  > EOF
+
+  $ sslcurl -d '{"paths": ["link", "folder/subfolder/.keep", "nonexist", "folder"]}' -H "Content-Type: application/json" -X POST $APISERVER/repo/multiget/$COMMIT2 | python $TESTTMP/multiget.py
+  ok link symlink 4
+  ok folder/subfolder/.keep file 6
+  error nonexist nonexist is not found
+  error folder folder is not found
+
+  $ sslcurl -d '{"paths": ["link", "folder/subfolder/.keep", "link", "nonexist"], "max_bytes": 8}' -H "Content-Type: application/json" -X POST $APISERVER/repo/multiget/$COMMIT2 | python $TESTTMP/multiget.py
+  ok link symlink 4
+  over_budget folder/subfolder/.keep
+  over_budget link
+  over_budget nonexist
+
+  $ KEEP_CONTENT_ID=$(sslcurl -d '{"paths": ["folder/subfolder/.keep"]}' -H "Content-Type: application/json" -X POST $APISERVER/repo/multiget/$COMMIT2 | python -c 'import msgpack, sys; print(next(msgpack.Unpacker(sys.stdin, raw=False))["content_id"])')
+  $ sslcurl -d "{\"content_ids\": [\"$KEEP_CONTENT_ID\", \"0000\"]}" -H "Content-Type: application/json" -X POST $APISERVER/repo/multiget/$COMMIT2 | python $TESTTMP/multiget.py
+  ok * 6 (glob)
+  error 0000 * (glob)
+
 test get tree
   $ sslcurl $APISERVER/repo/tree/$TREEHASH | jq .
   [
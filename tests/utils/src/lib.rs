@@ -18,7 +18,7 @@ use blobrepo::{save_bonsai_changesets, BlobRepo};
 use bytes::Bytes;
 use context::CoreContext;
 use futures::future::Future;
-use mononoke_types::{BonsaiChangesetMut, ChangesetId, DateTime, FileChange, FileContents,
+use mononoke_types::{BonsaiChangesetBuilder, ChangesetId, DateTime, FileChange, FileContents,
                      FileType, MPath};
 
 use std::collections::BTreeMap;
@@ -71,23 +71,13 @@ pub fn create_commit(
     parents: Vec<ChangesetId>,
     file_changes: BTreeMap<MPath, Option<FileChange>>,
 ) -> ChangesetId {
-    let bcs = BonsaiChangesetMut {
-        parents: parents,
-        author: "author".to_string(),
-        author_date: DateTime::from_timestamp(0, 0).unwrap(),
-        committer: None,
-        committer_date: None,
-        message: "message".to_string(),
-        extra: btreemap!{},
+    create_commit_with_date(
+        ctx,
+        repo,
+        parents,
         file_changes,
-    }.freeze()
-        .unwrap();
-
-    let bcs_id = bcs.get_changeset_id();
-    save_bonsai_changesets(vec![bcs], ctx, repo.clone())
-        .wait()
-        .unwrap();
-    bcs_id
+        DateTime::from_timestamp(0, 0).unwrap(),
+    )
 }
 
 pub fn create_commit_with_date(
@@ -97,18 +87,15 @@ pub fn create_commit_with_date(
     file_changes: BTreeMap<MPath, Option<FileChange>>,
     author_date: DateTime,
 ) -> ChangesetId {
-    let bcs = BonsaiChangesetMut {
-        parents,
-        author: "author".to_string(),
-        author_date,
-        committer: None,
-        committer_date: None,
-        message: "message".to_string(),
-        extra: btreemap! {},
-        file_changes,
+    let mut builder = BonsaiChangesetBuilder::new("author", author_date);
+    builder.set_parents(parents).set_message("message");
+    for (path, file_change) in file_changes {
+        match file_change {
+            Some(file_change) => builder.add_file_change(path, file_change),
+            None => builder.delete_file(path),
+        };
     }
-    .freeze()
-    .unwrap();
+    let bcs = builder.freeze().unwrap();
 
     let bcs_id = bcs.get_changeset_id();
     save_bonsai_changesets(vec![bcs], ctx, repo.clone())